@@ -55,7 +55,7 @@ use core::mem::transmute;
 use core::{mem, slice};
 
 use uefi::prelude::*;
-use uefi::proto::console::gop::GraphicsOutput;
+use uefi::proto::console::gop::{GraphicsOutput, PixelFormat as GopPixelFormat};
 use uefi::table::boot::{AllocateType, MemoryDescriptor, MemoryType};
 use uefi::table::cfg::{ACPI2_GUID, ACPI_GUID};
 
@@ -506,19 +506,39 @@ pub extern "C" fn uefi_start(handle: uefi::Handle, st: SystemTable<Boot>) -> Sta
             let gop = &mut *gop.get();
 
             let mut frame_buffer = gop.frame_buffer();
-            let frame_buf_ptr = frame_buffer.as_mut_ptr();
+            let frame_buf_paddr = PAddr::from(frame_buffer.as_mut_ptr() as u64);
             let size = frame_buffer.size();
-            let _frame_buf_paddr = PAddr::from(frame_buf_ptr as u64);
-
-            kernel_args.frame_buffer = Some(core::slice::from_raw_parts_mut(
-                frame_buf_ptr.add(KERNEL_OFFSET),
+            let mode = gop.current_mode_info();
+            let (width, height) = mode.resolution();
+
+            let format = match mode.pixel_format() {
+                GopPixelFormat::Rgb => Some(PixelFormat::Rgb),
+                GopPixelFormat::Bgr => Some(PixelFormat::Bgr),
+                GopPixelFormat::Bitmask => mode.pixel_bitmask().map(|mask| PixelFormat::Bitmask {
+                    red: mask.red,
+                    green: mask.green,
+                    blue: mask.blue,
+                }),
+                // We can't draw on it directly
+                GopPixelFormat::BltOnly => None,
+            };
+            kernel_args.framebuffer = format.map(|format| Framebuffer {
+                paddr: frame_buf_paddr,
                 size,
-            ));
-            kernel_args.mode_info = Some(gop.current_mode_info());
+                width,
+                height,
+                stride: mode.stride(),
+                format,
+            });
         } else {
-            kernel_args.frame_buffer = None;
-            kernel_args.mode_info = None;
+            kernel_args.framebuffer = None;
         }
+        kernel_args.protocol = BootProtocol::Uefi;
+        // The kernel calls the runtime services (`SystemTable` is just a
+        // pointer to the table)
+        kernel_args.uefi_system_table =
+            PAddr::from(mem::transmute_copy::<SystemTable<Boot>, u64>(&st));
+        kernel_args.boot_cpus = arrayvec::ArrayVec::new();
 
         info!(
             "Kernel will start to execute from: {:p}",
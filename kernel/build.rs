@@ -12,6 +12,8 @@ fn main() {
         env::set_var("CC", "gcc");
         println!("cargo:rerun-if-changed=src/arch/x86_64/start_ap.S");
         println!("cargo:rerun-if-changed=src/arch/x86_64/exec.S");
+        println!("cargo:rerun-if-changed=src/arch/x86_64/kexec.S");
+        println!("cargo:rerun-if-changed=src/arch/x86_64/hypervisor/vmx.S");
         println!("cargo:rerun-if-changed=src/arch/x86_64/acpi_printf.c");
         println!("cargo:rerun-if-changed=src/arch/x86_64/acpi_printf.h");
 
@@ -25,6 +27,8 @@ fn main() {
             .flag("-fPIC")
             .file("src/arch/x86_64/start_ap.S")
             .file("src/arch/x86_64/exec.S")
+            .file("src/arch/x86_64/kexec.S")
+            .file("src/arch/x86_64/hypervisor/vmx.S")
             .file("src/arch/x86_64/acpi_printf.c")
             .pic(true)
             .warnings(true)
@@ -0,0 +1,138 @@
+#!/usr/bin/python3
+
+# Copyright © 2021 VMware, Inc. All Rights Reserved.
+# SPDX-License-Identifier: Apache-2.0 OR MIT
+
+#
+# Extracts a crash dump the kernel wrote on panic (see
+# `kernel/src/arch/x86_64/crashdump.rs` for the format).
+#
+# Example, for `crashdump=nvme0` with the drive in `nvme.img`:
+#   python3 crashdump.py nvme.img --kernel ../target/x86_64-nrk/release/nrk
+#
+
+import argparse
+import os
+import struct
+import subprocess
+import sys
+
+MAGIC = b"NRKDUMP1"
+VERSION = 1
+HEADER = struct.Struct("<8sIIQQIIQ")
+SECTION = struct.Struct("<IIQ")
+MEMORY_REGION = struct.Struct("<IIQQQ")
+CHUNK = 4096
+
+# Size of the region at the end of a block device (if no block is given)
+REGION_SIZE = 4 * 1024 * 1024
+
+SECTION_MESSAGE = 1
+SECTION_REGISTERS = 2
+SECTION_STACK = 3
+SECTION_LOG = 4
+SECTION_MEMORY_MAP = 5
+
+REGISTERS = ["rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10",
+             "r11", "r12", "r13", "r14", "r15", "rip", "rflags", "cs", "ss", "cr2", "cr3"]
+
+MEMORY_TYPES = ["reserved", "loader code", "loader data", "boot services code",
+                "boot services data", "runtime services code", "runtime services data",
+                "conventional", "unusable", "acpi reclaim", "acpi nvs", "mmio",
+                "mmio port space", "pal code", "persistent memory"]
+
+parser = argparse.ArgumentParser()
+parser.add_argument("image", help="Disk image, block device or pmem backing file")
+parser.add_argument("--offset", type=lambda x: int(x, 0), default=None,
+                    help="Where the dump starts in bytes (default: the last 4 MiB)")
+parser.add_argument("--lba", type=lambda x: int(x, 0), default=None,
+                    help="Where the dump starts in blocks (crashdump='<dev>:<lba>')")
+parser.add_argument("--block-size", type=int, default=512,
+                    help="Block size of the device (for --lba)")
+parser.add_argument("--kernel", default=None,
+                    help="Kernel ELF binary, resolves addresses with addr2line")
+parser.add_argument("--stack", default=None,
+                    help="Write the raw stack of the panicking core to this file")
+
+
+def symbolize(kernel, elf_offset, addr):
+    if kernel is None or addr == 0:
+        return ""
+    try:
+        out = subprocess.run(["addr2line", "-f", "-C", "-e", kernel, hex(addr - elf_offset)],
+                             capture_output=True, text=True, check=True).stdout.split("\n")
+        return " {} ({})".format(out[0], out[1])
+    except (OSError, subprocess.CalledProcessError):
+        return ""
+
+
+def read_dump(image, offset):
+    image.seek(offset)
+    header = image.read(CHUNK)
+    (magic, version, sections, length, elf_offset, panicked, cores, tsc) = \
+        HEADER.unpack_from(header)
+    if magic != MAGIC:
+        sys.exit("No crash dump at offset {:#x}".format(offset))
+    if version != VERSION:
+        sys.exit("Unsupported crash dump version {}".format(version))
+
+    data = image.read(length)
+    dump = {"elf_offset": elf_offset, "panicked": panicked, "cores": cores, "tsc": tsc,
+            "sections": []}
+    pos = 0
+    for _ in range(sections):
+        (kind, core, size) = SECTION.unpack_from(data, pos)
+        pos += SECTION.size
+        dump["sections"].append((kind, core, data[pos:pos + size]))
+        pos += (size + 7) & ~7
+    return dump
+
+
+def print_dump(dump, args):
+    print("Core {} panicked ({} cores, tsc {}, kernel ELF at {:#x})".format(
+        dump["panicked"], dump["cores"], dump["tsc"], dump["elf_offset"]))
+
+    for (kind, core, payload) in dump["sections"]:
+        if kind == SECTION_MESSAGE:
+            print("\nPanic: {}".format(payload.decode(errors="replace")))
+        elif kind == SECTION_REGISTERS:
+            values = struct.unpack("<{}Q".format(len(REGISTERS)), payload)
+            print("\nRegisters of core {}:".format(core))
+            for i in range(0, len(REGISTERS), 4):
+                print("  " + "  ".join("{:>6} = {:#018x}".format(name, value) for (name, value)
+                                       in zip(REGISTERS[i:i + 4], values[i:i + 4])))
+            rip = values[REGISTERS.index("rip")]
+            print("  at {:#x}{}".format(rip, symbolize(args.kernel, dump["elf_offset"], rip)))
+        elif kind == SECTION_STACK:
+            (low,) = struct.unpack_from("<Q", payload)
+            stack = payload[8:]
+            print("\nStack of core {}: {} bytes at {:#x}".format(core, len(stack), low))
+            if args.stack:
+                with open(args.stack, "wb") as f:
+                    f.write(stack)
+        elif kind == SECTION_LOG:
+            print("\nKernel log:")
+            print(payload.decode(errors="replace"))
+        elif kind == SECTION_MEMORY_MAP:
+            print("\nMemory map:")
+            for i in range(0, len(payload), MEMORY_REGION.size):
+                (ty, _pad, start, pages, attributes) = \
+                    MEMORY_REGION.unpack_from(payload, i)
+                name = MEMORY_TYPES[ty] if ty < len(MEMORY_TYPES) else str(ty)
+                print("  {:#014x} - {:#014x} {:<22} {:#x}".format(
+                    start, start + pages * 4096, name, attributes))
+        else:
+            print("\nUnknown section {} ({} bytes)".format(kind, len(payload)))
+
+
+if __name__ == '__main__':
+    args = parser.parse_args()
+    with open(args.image, "rb") as image:
+        if args.offset is not None:
+            offset = args.offset
+        elif args.lba is not None:
+            offset = args.lba * args.block_size
+        else:
+            size = image.seek(0, os.SEEK_END)
+            offset = size - REGION_SIZE
+        print_dump(read_dump(image, offset), args)
@@ -59,6 +59,9 @@ USR_PATH = (SCRIPT_PATH / '..').resolve() / 'usr'
 UEFI_TARGET = "{}-uefi".format(ARCH)
 KERNEL_TARGET = "{}-nrk".format(ARCH)
 USER_TARGET = "{}-nrk-none".format(ARCH)
+# Programs that use Rust's std (see doc/src/userspace/RustStd.md)
+USER_STD_TARGET = "{}-nrk-std".format(ARCH)
+USER_STD_MODULES = ["stdtest"]
 USER_RUSTFLAGS = "-Clink-arg=-zmax-page-size=0x200000"
 
 #
@@ -80,6 +83,10 @@ parser.add_argument("--ufeatures", type=str, nargs='+', default=[],
                     help="Cargo features to enable (in user-space, use module_name:feature_name syntax to specify module specific features, e.g. init:print-test).")
 parser.add_argument('-m', '--mods', nargs='+', default=['init'],
                     help='User-space modules to be included in build & deployment', required=False)
+parser.add_argument("--files", type=str, nargs='+', default=[],
+                    help="Other files to hand to the kernel as modules (e.g., a syscall.trace to replay).", required=False)
+parser.add_argument("--initrd", action="store_true", default=False,
+                    help="Pack the user-space modules (into /bin) and --files (into /) in a cpio initrd instead of handing them to the kernel as modules.", required=False)
 parser.add_argument("--cmd", type=str,
                     help="Command line arguments passed to the kernel.")
 parser.add_argument("--machine",
@@ -92,6 +99,10 @@ parser.add_argument("--qemu-cores", type=int,
                     help="How many cores (will get evenly divided among nodes).", default=1)
 parser.add_argument("--qemu-memory", type=str,
                     help="How much total memory in MiB (will get evenly divided among nodes).", default=1024)
+parser.add_argument("--qemu-numa", type=str, nargs='+', default=None,
+                    help="NUMA layout as <cores>:<MiB> for every node, either can be 0 except on node 0 (e.g., 2:512 0:256 1:0). Overrides --qemu-nodes/cores/memory.", required=False)
+parser.add_argument("--qemu-numa-distance", type=str, nargs='+', default=[],
+                    help="Distances between NUMA nodes as <src>-<dst>=<val> (e.g., 0-1=20 1-0=30), the other direction is the same if not given.", required=False)
 parser.add_argument("--qemu-affinity", action="store_true", default=False,
                     help="Pin QEMU instance to dedicated host cores.")
 parser.add_argument("--qemu-prealloc", action="store_true", default=False,
@@ -102,6 +113,8 @@ parser.add_argument("--qemu-settings", type=str,
                     help="Pass additional generic QEMU arguments.")
 parser.add_argument("--qemu-monitor", action="store_true",
                     help="Launch the QEMU monitor (for qemu)")
+parser.add_argument("--gdb-stub", action="store_true",
+                    help="Connect COM2 to 127.0.0.1:55556 for the kernel's GDB stub (needs --kfeatures gdb)")
 parser.add_argument("--pvrdma", action="store_true",
                     help="Add para-virtual RDMA device (for qemu)", default=False)
 parser.add_argument("-d", "--qemu-debug-cpu", action="store_true",
@@ -125,7 +138,8 @@ NRK_EXIT_CODES = {
     6: "[FAIL] Unexpected Page Fault.",
     7: "[FAIL] Unexpected process exit code when running a user-space test.",
     8: "[FAIL] Unexpected exception during kernel initialization.",
-    9: "[FAIL] Got unrecoverable error (machine check, double fault)."
+    9: "[FAIL] Got unrecoverable error (machine check, double fault).",
+    10: "[FAIL] A watchdog detected a stuck core."
 }
 
 
@@ -192,11 +206,13 @@ def build_user_libraries(args):
                 xargo(*build_args)
 
 
+def user_target(module):
+    "The target a user-space program is built for"
+    return USER_STD_TARGET if module in USER_STD_MODULES else USER_TARGET
+
+
 def build_userspace(args):
     "Builds user-space programs"
-    build_args_default = ['build', '--target', USER_TARGET]
-    build_args_default += CARGO_DEFAULT_ARGS
-
     for module in args.mods:
         if not (USR_PATH / module).exists():
             log("User module {} not found, skipping.".format(module))
@@ -204,7 +220,8 @@ def build_userspace(args):
         with local.cwd(USR_PATH / module):
             with local.env(RUSTFLAGS=USER_RUSTFLAGS):
                 with local.env(RUST_TARGET_PATH=USR_PATH.absolute()):
-                    build_args = build_args_default.copy()
+                    build_args = ['build', '--target', user_target(module)]
+                    build_args += CARGO_DEFAULT_ARGS
                     for feature in args.ufeatures:
                         if ':' in feature:
                             mod_part, feature_part = feature.split(':')
@@ -220,6 +237,32 @@ def build_userspace(args):
                     xargo(*build_args)
 
 
+def write_initrd(path, entries):
+    """
+    Writes a cpio archive (newc format) with the files in `entries`
+    (pairs of path in the archive and file to add).
+    """
+    def entry(name, mode, data):
+        # Fields (in hex): ino, mode, uid, gid, nlink, mtime, filesize,
+        # devmajor, devminor, rdevmajor, rdevminor, namesize, check
+        fields = [0, mode, 0, 0, 1, 0, len(data), 0, 0, 0, 0, len(name) + 1, 0]
+        header = b"070701" + b"".join(b"%08X" % f for f in fields)
+        name = header + name.encode() + b"\0"
+        name += b"\0" * (-len(name) % 4)
+        return name + data + b"\0" * (-len(data) % 4)
+
+    archive = b""
+    for directory in sorted({os.path.dirname(name) for name, _ in entries} - {""}):
+        archive += entry(directory, 0o040755, b"")
+    for name, file in entries:
+        with open(file, 'rb') as f:
+            archive += entry(name, 0o100755, f.read())
+    archive += entry("TRAILER!!!", 0, b"")
+
+    with open(path, 'wb') as f:
+        f.write(archive)
+
+
 def deploy(args):
     """
     Deploys everything that got built to the UEFI ESP directory
@@ -230,7 +273,8 @@ def deploy(args):
     # Clean up / create ESP dir structure
     debug_release = 'release' if args.release else 'debug'
     uefi_build_path = TARGET_PATH / UEFI_TARGET / debug_release
-    user_build_path = TARGET_PATH / USER_TARGET / debug_release
+    def user_build_path(module):
+        return TARGET_PATH / user_target(module) / debug_release
     kernel_build_path = TARGET_PATH / KERNEL_TARGET / debug_release
 
     # Clean and create_esp dir:
@@ -255,23 +299,33 @@ def deploy(args):
         else:
             cmdfile.write('./kernel')
 
-    deployed = []
-    # Deploy user-modules
+    # User-modules
+    binaries = []
     for module in args.mods:
-        if not (user_build_path / module).is_file():
+        if not (user_build_path(module) / module).is_file():
             log("[WARN] Module not found: {}".format(module))
             continue
         if module != "rkapps":
-            shutil.copy2(user_build_path / module, esp_path)
-            deployed.append(module)
+            binaries.append(user_build_path(module) / module)
         else:
             # TODO(ugly): Special handling of the rkapps module
             # (they end up being built as multiple .bin binaries)
-            to_copy = [app for app in user_build_path.glob(
-                "*.bin") if app.is_file()]
-            deployed.extend([f.name for f in to_copy])
-            for app in to_copy:
-                shutil.copy2(app, esp_path)
+            binaries.extend([app for app in user_build_path(module).glob(
+                "*.bin") if app.is_file()])
+    files = [pathlib.Path(file) for file in args.files]
+
+    deployed = []
+    if args.initrd:
+        entries = [('bin/' + b.name, b) for b in binaries]
+        entries += [(f.name, f) for f in files]
+        write_initrd(esp_path / 'initrd', entries)
+        deployed.append('initrd')
+    else:
+        # Deploy user-modules and other files (the bootloader loads them
+        # all as modules)
+        for module in binaries + files:
+            shutil.copy2(module, esp_path)
+            deployed.append(module.name)
 
     # Write kernel cmd-line file in ESP dir
     with open(esp_path / 'boot.php', 'w') as boot_file:
@@ -357,23 +411,56 @@ def run_qemu(args):
 
     host_numa_nodes_list = query_host_numa()
     num_host_numa_nodes = len(host_numa_nodes_list)
-    if args.qemu_nodes and args.qemu_nodes > 0 and args.qemu_cores > 1:
+
+    def memory_backend(node, size):
+        prealloc = "on" if args.qemu_prealloc else "off"
+        large_pages = ",hugetlb=on,hugetlbsize=2M" if args.qemu_large_pages else ""
+        backend = "memory-backend-ram" if not args.qemu_large_pages else "memory-backend-memfd"
+        # This is untested, not sure it works
+        #assert args.pvrdma and not args.qemu_default_args
+        return ['-object', '{},id=nmem{},merge=off,dump=on,prealloc={},size={}M,host-nodes={},policy=bind{},share=on'.format(
+            backend, node, prealloc, int(size), 0 if num_host_numa_nodes == 0 else host_numa_nodes_list[node % num_host_numa_nodes], large_pages)]
+
+    if args.qemu_numa:
+        # Every node gets its own number of cores and memory, cores are
+        # numbered in node order
+        layout = [tuple(int(n) for n in node.split(':'))
+                  for node in args.qemu_numa]
+        assert layout[0][0] > 0 and layout[0][1] > 0, "Node 0 needs cores and memory (to boot)"
+        args.qemu_cores = sum(cores for (cores, _memory) in layout)
+        args.qemu_memory = sum(memory for (_cores, memory) in layout)
+
+        core = 0
+        for node, (cores, memory) in enumerate(layout):
+            if memory > 0:
+                qemu_default_args += memory_backend(node, memory)
+                qemu_default_args += ['-numa',
+                                      "node,memdev=nmem{},nodeid={}".format(node, node)]
+            else:
+                qemu_default_args += ['-numa', "node,nodeid={}".format(node)]
+            for _i in range(0, cores):
+                qemu_default_args += ["-numa", "cpu,node-id={},socket-id=0,core-id={}".format(
+                    node, core)]
+                core += 1
+        for distance in args.qemu_numa_distance:
+            (nodes, val) = distance.split('=')
+            (src, dst) = nodes.split('-')
+            qemu_default_args += ['-numa',
+                                  "dist,src={},dst={},val={}".format(src, dst, val)]
+    elif args.qemu_nodes and args.qemu_nodes > 0 and args.qemu_cores > 1:
         for node in range(0, args.qemu_nodes):
             mem_per_node = int(args.qemu_memory) / args.qemu_nodes
-            prealloc = "on" if args.qemu_prealloc else "off"
-            large_pages = ",hugetlb=on,hugetlbsize=2M" if args.qemu_large_pages else ""
-            backend = "memory-backend-ram" if not args.qemu_large_pages else "memory-backend-memfd"
-            # This is untested, not sure it works
-            #assert args.pvrdma and not args.qemu_default_args
-            qemu_default_args += ['-object', '{},id=nmem{},merge=off,dump=on,prealloc={},size={}M,host-nodes={},policy=bind{},share=on'.format(
-                backend, node, prealloc, int(mem_per_node), 0 if num_host_numa_nodes == 0 else host_numa_nodes_list[node % num_host_numa_nodes], large_pages)]
+            qemu_default_args += memory_backend(node, mem_per_node)
 
             qemu_default_args += ['-numa',
                                   "node,memdev=nmem{},nodeid={}".format(node, node)]
             qemu_default_args += ["-numa", "cpu,node-id={},socket-id={}".format(
                 node, node)]
 
-    if args.qemu_cores and args.qemu_cores > 1 and args.qemu_nodes:
+    if args.qemu_numa:
+        qemu_default_args += ["-smp", "{},sockets=1,cores={}".format(
+            args.qemu_cores, args.qemu_cores)]
+    elif args.qemu_cores and args.qemu_cores > 1 and args.qemu_nodes:
         qemu_default_args += ["-smp", "{},sockets={},maxcpus={}".format(
             args.qemu_cores, args.qemu_nodes, args.qemu_cores)]
     else:
@@ -394,6 +481,9 @@ def run_qemu(args):
         qemu_default_args += ['-monitor',
                               'telnet:127.0.0.1:55555,server,nowait']
 
+    if args.gdb_stub:
+        qemu_default_args += ['-serial', 'tcp:127.0.0.1:55556,server,nowait']
+
     # Name threads on host for `qemu_affinity.py` to find it
     qemu_default_args += ['-name', 'nrk,debug-threads=on']
 
@@ -0,0 +1,104 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The multiple APIC description table.
+
+use alloc::vec::Vec;
+
+use fallible_collections::vec::FallibleVec;
+use log::trace;
+
+use super::{entries, u16_at, u32_at, u64_at, HEADER_SIZE};
+use crate::error::KError;
+
+const LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const INTERRUPT_OVERRIDE: u8 = 2;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const LOCAL_X2APIC: u8 = 9;
+
+/// The processor is usable.
+const FLAG_ENABLED: u32 = 1 << 0;
+
+/// A processor (a hardware thread).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Cpu {
+    /// ACPI processor UID (matches the processor objects in the namespace).
+    pub uid: u32,
+    pub apic_id: u32,
+    /// The firmware didn't disable it (it may still be offline).
+    pub enabled: bool,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    /// First global system interrupt the IO-APIC handles.
+    pub gsi_base: u32,
+}
+
+/// An ISA interrupt that isn't identity-mapped to a GSI.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// MPS INTI flags (polarity and trigger mode).
+    pub flags: u16,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub cpus: Vec<Cpu>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    pub const SIGNATURE: &'static [u8; 4] = b"APIC";
+    /// Local APIC address and flags follow the header.
+    const ENTRIES: usize = HEADER_SIZE + 8;
+
+    pub fn parse(table: &[u8]) -> Result<Madt, KError> {
+        if table.len() < Madt::ENTRIES {
+            return Err(KError::InvalidAcpiTable);
+        }
+
+        let mut madt = Madt {
+            local_apic_address: u32_at(table, HEADER_SIZE) as u64,
+            cpus: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+        };
+
+        for (kind, entry) in entries(table, Madt::ENTRIES) {
+            match (kind, entry.len()) {
+                (LOCAL_APIC, 8) => madt.cpus.try_push(Cpu {
+                    uid: entry[2] as u32,
+                    apic_id: entry[3] as u32,
+                    enabled: u32_at(entry, 4) & FLAG_ENABLED != 0,
+                })?,
+                (LOCAL_X2APIC, 16) => madt.cpus.try_push(Cpu {
+                    uid: u32_at(entry, 12),
+                    apic_id: u32_at(entry, 4),
+                    enabled: u32_at(entry, 8) & FLAG_ENABLED != 0,
+                })?,
+                (IO_APIC, 12) => madt.io_apics.try_push(IoApic {
+                    id: entry[2],
+                    address: u32_at(entry, 4),
+                    gsi_base: u32_at(entry, 8),
+                })?,
+                (INTERRUPT_OVERRIDE, 10) => madt.overrides.try_push(InterruptOverride {
+                    irq: entry[3],
+                    gsi: u32_at(entry, 4),
+                    flags: u16_at(entry, 8),
+                })?,
+                (LOCAL_APIC_ADDRESS_OVERRIDE, 12) => madt.local_apic_address = u64_at(entry, 4),
+                (kind, len) => trace!("Ignoring MADT entry type {} ({} bytes)", kind, len),
+            }
+        }
+
+        Ok(madt)
+    }
+}
@@ -0,0 +1,419 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Native parser for the ACPI tables that describe the machine.
+//!
+//! ACPICA (see `arch::acpi`) interprets the AML namespace, but the static
+//! tables we need early are simple enough to read ourselves: `init` finds
+//! the RSDP (from the bootloader or by scanning the BIOS areas), walks the
+//! XSDT/RSDT and parses
+//!
+//! - the MADT for local (x2)APIC IDs, IO-APICs and interrupt overrides,
+//! - the SRAT for the NUMA node of every processor and memory range,
+//! - the SLIT for the distances between NUMA nodes.
+//!
+//! The result is a `Platform` that the memory allocators and
+//! `System::topology` use.
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use fallible_collections::vec::FallibleVec;
+use log::{debug, info, warn};
+use spin::Once;
+
+use crate::error::KError;
+
+mod madt;
+mod numa;
+#[cfg(test)]
+mod test;
+
+pub use madt::{InterruptOverride, Madt};
+pub use numa::{Slit, Srat};
+
+/// Size of the header every system description table starts with.
+const HEADER_SIZE: usize = 36;
+
+/// Where the BIOS keeps the (real-mode) segment of the extended BIOS data
+/// area.
+const EBDA_SEGMENT_POINTER: u64 = 0x40e;
+/// The BIOS read-only memory area that may hold the RSDP.
+const BIOS_AREA: (u64, u64) = (0xe_0000, 0x10_0000);
+
+/// A way to read physical memory (the tables live wherever the firmware
+/// put them).
+pub trait PhysicalMemory {
+    fn read(&self, paddr: u64, buf: &mut [u8]);
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Iterates over the `(type, bytes)` of the variable-length entries that
+/// follow the fixed part (`start` bytes) of a MADT or SRAT.
+fn entries(table: &[u8], start: usize) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut offset = start;
+    core::iter::from_fn(move || {
+        if offset + 2 > table.len() {
+            return None;
+        }
+        let (kind, len) = (table[offset], table[offset + 1] as usize);
+        if len < 2 || offset + len > table.len() {
+            warn!("Truncated ACPI table entry at offset {}", offset);
+            return None;
+        }
+        let entry = &table[offset..offset + len];
+        offset += len;
+        Some((kind, entry))
+    })
+}
+
+/// The root system description pointer.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Rsdp {
+    pub revision: u8,
+    pub rsdt: u32,
+    /// Only in ACPI 2.0 and later.
+    pub xsdt: Option<u64>,
+}
+
+impl Rsdp {
+    const SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
+    /// Size of the ACPI 1.0 structure (covered by the first checksum).
+    const V1_SIZE: usize = 20;
+    const V2_SIZE: usize = 36;
+
+    /// Reads and validates the RSDP at `paddr`.
+    pub fn read(mem: &dyn PhysicalMemory, paddr: u64) -> Option<Rsdp> {
+        let mut bytes = [0; Rsdp::V2_SIZE];
+        mem.read(paddr, &mut bytes);
+        Rsdp::parse(&bytes)
+    }
+
+    fn parse(bytes: &[u8; Rsdp::V2_SIZE]) -> Option<Rsdp> {
+        if &bytes[0..8] != Rsdp::SIGNATURE || !checksum_ok(&bytes[..Rsdp::V1_SIZE]) {
+            return None;
+        }
+
+        let revision = bytes[15];
+        let xsdt = if revision >= 2 && checksum_ok(&bytes[..]) {
+            Some(u64_at(bytes, 24)).filter(|xsdt| *xsdt != 0)
+        } else {
+            None
+        };
+        Some(Rsdp {
+            revision,
+            rsdt: u32_at(bytes, 16),
+            xsdt,
+        })
+    }
+
+    /// Finds the RSDP, trying the addresses in `hints` (what UEFI told the
+    /// bootloader, 0 if unknown) first, then the places a BIOS puts it.
+    pub fn find(mem: &dyn PhysicalMemory, hints: &[u64]) -> Option<Rsdp> {
+        for hint in hints.iter().filter(|paddr| **paddr != 0) {
+            match Rsdp::read(mem, *hint) {
+                Some(rsdp) => return Some(rsdp),
+                None => warn!("No valid RSDP at {:#x}", hint),
+            }
+        }
+
+        let mut ebda = [0; 2];
+        mem.read(EBDA_SEGMENT_POINTER, &mut ebda);
+        let ebda = (u16::from_le_bytes(ebda) as u64) << 4;
+        let areas = [(ebda, ebda + 1024), BIOS_AREA];
+
+        // The RSDP is 16-byte aligned
+        let mut bytes = [0; Rsdp::V2_SIZE];
+        for (start, end) in areas.iter().filter(|(start, _)| *start != 0) {
+            for paddr in (*start..*end).step_by(16) {
+                mem.read(paddr, &mut bytes);
+                if let Some(rsdp) = Rsdp::parse(&bytes) {
+                    debug!("Found RSDP at {:#x}", paddr);
+                    return Some(rsdp);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Reads the table at `paddr` (header included), checks its signature and
+/// checksum.
+fn read_table(mem: &dyn PhysicalMemory, paddr: u64, signature: &[u8]) -> Result<Vec<u8>, KError> {
+    let mut header = [0; HEADER_SIZE];
+    mem.read(paddr, &mut header);
+    if &header[0..4] != signature {
+        return Err(KError::InvalidAcpiTable);
+    }
+
+    let len = u32_at(&header, 4) as usize;
+    if len < HEADER_SIZE {
+        return Err(KError::InvalidAcpiTable);
+    }
+    let mut table = Vec::try_with_capacity(len)?;
+    table.resize(len, 0);
+    mem.read(paddr, &mut table);
+    if !checksum_ok(&table) {
+        warn!(
+            "Bad checksum for ACPI table {}",
+            core::str::from_utf8(signature).unwrap_or("????")
+        );
+        return Err(KError::InvalidAcpiTable);
+    }
+
+    Ok(table)
+}
+
+/// The tables the root table (XSDT or RSDT) points to.
+pub struct Tables<'a> {
+    mem: &'a dyn PhysicalMemory,
+    addresses: Vec<u64>,
+}
+
+impl<'a> Tables<'a> {
+    pub fn new(mem: &'a dyn PhysicalMemory, rsdp: &Rsdp) -> Result<Tables<'a>, KError> {
+        let (root, signature, entry_size) = match rsdp.xsdt {
+            Some(xsdt) => (read_table(mem, xsdt, b"XSDT")?, b"XSDT", 8),
+            None => (read_table(mem, rsdp.rsdt as u64, b"RSDT")?, b"RSDT", 4),
+        };
+        debug!(
+            "ACPI {} has {} entries",
+            core::str::from_utf8(signature).unwrap_or("????"),
+            (root.len() - HEADER_SIZE) / entry_size
+        );
+
+        let mut addresses = Vec::new();
+        for entry in root[HEADER_SIZE..].chunks_exact(entry_size) {
+            let paddr = if entry_size == 8 {
+                u64_at(entry, 0)
+            } else {
+                u32_at(entry, 0) as u64
+            };
+            addresses.try_push(paddr)?;
+        }
+
+        Ok(Tables { mem, addresses })
+    }
+
+    /// Returns the first table with `signature`.
+    pub fn find(&self, signature: &[u8; 4]) -> Result<Vec<u8>, KError> {
+        for paddr in self.addresses.iter() {
+            let mut found = [0; 4];
+            self.mem.read(*paddr, &mut found);
+            if &found == signature {
+                return read_table(self.mem, *paddr, signature);
+            }
+        }
+
+        Err(KError::AcpiTableNotFound)
+    }
+}
+
+/// A range of physical memory and its NUMA node.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MemoryRange {
+    pub base: u64,
+    pub length: u64,
+    pub node: usize,
+    pub hotpluggable: bool,
+}
+
+/// A NUMA node.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Node {
+    /// Dense node id (we number the proximity domains the SRAT uses in
+    /// ascending order).
+    pub id: usize,
+    pub proximity_domain: u32,
+    /// APIC IDs of the node's (enabled) processors.
+    pub apic_ids: Vec<u32>,
+    /// Bytes of (enabled, not hot-pluggable) memory.
+    pub memory: u64,
+}
+
+/// Everything we learned about the machine from the ACPI tables.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Platform {
+    pub madt: Madt,
+    /// Empty if there is no SRAT (the machine is a single node then).
+    pub nodes: Vec<Node>,
+    pub memory: Vec<MemoryRange>,
+    /// `distances[a * nodes.len() + b]` is the relative distance from node
+    /// `a` to node `b` (10 is local).
+    pub distances: Vec<u8>,
+}
+
+impl Platform {
+    /// Parses the MADT, SRAT and SLIT.
+    ///
+    /// Only the MADT is required, without an SRAT every processor and all
+    /// memory is on one node and without a SLIT we assume distance 20
+    /// between different nodes (like Linux does).
+    pub fn parse(mem: &dyn PhysicalMemory, rsdp: &Rsdp) -> Result<Platform, KError> {
+        let tables = Tables::new(mem, rsdp)?;
+        let madt = Madt::parse(&tables.find(Madt::SIGNATURE)?)?;
+
+        let srat = match tables.find(Srat::SIGNATURE) {
+            Ok(table) => Some(Srat::parse(&table)?),
+            Err(KError::AcpiTableNotFound) => None,
+            Err(e) => return Err(e),
+        };
+        let slit = match tables.find(Slit::SIGNATURE) {
+            Ok(table) => Some(Slit::parse(&table)?),
+            Err(KError::AcpiTableNotFound) => None,
+            Err(e) => return Err(e),
+        };
+
+        Platform::from_tables(madt, srat, slit)
+    }
+
+    fn from_tables(madt: Madt, srat: Option<Srat>, slit: Option<Slit>) -> Result<Platform, KError> {
+        let mut platform = Platform {
+            madt,
+            nodes: Vec::new(),
+            memory: Vec::new(),
+            distances: Vec::new(),
+        };
+        let srat = match srat {
+            Some(srat) => srat,
+            None => return Ok(platform),
+        };
+
+        let mut domains: Vec<u32> = Vec::new();
+        let domains_used = srat
+            .processors
+            .iter()
+            .filter(|p| p.enabled)
+            .map(|p| p.proximity_domain)
+            .chain(
+                srat.memory
+                    .iter()
+                    .filter(|m| m.enabled)
+                    .map(|m| m.proximity_domain),
+            );
+        for domain in domains_used {
+            if !domains.contains(&domain) {
+                domains.try_push(domain)?;
+            }
+        }
+        domains.sort_unstable();
+
+        for (id, domain) in domains.iter().enumerate() {
+            let mut apic_ids = Vec::new();
+            for p in srat.processors.iter() {
+                let known = platform
+                    .madt
+                    .cpus
+                    .iter()
+                    .any(|c| c.apic_id == p.apic_id && c.enabled);
+                if p.enabled && p.proximity_domain == *domain && known {
+                    apic_ids.try_push(p.apic_id)?;
+                }
+            }
+            platform.nodes.try_push(Node {
+                id,
+                proximity_domain: *domain,
+                apic_ids,
+                memory: 0,
+            })?;
+        }
+
+        for m in srat.memory.iter().filter(|m| m.enabled && m.length > 0) {
+            let node = domains
+                .iter()
+                .position(|d| *d == m.proximity_domain)
+                .unwrap();
+            if !m.hotpluggable {
+                platform.nodes[node].memory += m.length;
+            }
+            platform.memory.try_push(MemoryRange {
+                base: m.base,
+                length: m.length,
+                node,
+                hotpluggable: m.hotpluggable,
+            })?;
+        }
+
+        let n = domains.len();
+        platform.distances = Vec::try_with_capacity(n * n)?;
+        for from in domains.iter() {
+            for to in domains.iter() {
+                let distance = slit
+                    .as_ref()
+                    .and_then(|slit| slit.distance(*from, *to))
+                    .unwrap_or(if from == to { 10 } else { 20 });
+                platform.distances.push(distance);
+            }
+        }
+
+        Ok(platform)
+    }
+
+    /// Returns the node of the processor with `apic_id` (None if we don't
+    /// have NUMA information).
+    pub fn node_of(&self, apic_id: u32) -> Option<usize> {
+        self.nodes
+            .iter()
+            .find(|node| node.apic_ids.contains(&apic_id))
+            .map(|node| node.id)
+    }
+
+    /// Relative distance between two nodes (10 for the same node).
+    pub fn distance(&self, from: usize, to: usize) -> u8 {
+        let n = self.nodes.len();
+        if from >= n || to >= n {
+            return if from == to { 10 } else { 20 };
+        }
+        self.distances[from * n + to]
+    }
+}
+
+static PLATFORM: Once<Platform> = Once::new();
+
+/// Finds and parses the ACPI tables (see the module documentation).
+pub fn init(mem: &dyn PhysicalMemory, rsdp_hints: &[u64]) -> Result<(), KError> {
+    let rsdp = Rsdp::find(mem, rsdp_hints).ok_or(KError::AcpiUnavailable)?;
+    let platform = Platform::parse(mem, &rsdp)?;
+
+    info!(
+        "ACPI: {} processors, {} IO-APICs, {} NUMA nodes",
+        platform.madt.cpus.iter().filter(|c| c.enabled).count(),
+        platform.madt.io_apics.len(),
+        platform.nodes.len()
+    );
+    for node in platform.nodes.iter() {
+        debug!(
+            "ACPI: node {} (domain {}) has {} processors and {} MiB",
+            node.id,
+            node.proximity_domain,
+            node.apic_ids.len(),
+            node.memory >> 20
+        );
+    }
+
+    PLATFORM.call_once(|| platform);
+    Ok(())
+}
+
+/// What `init` found (None if it wasn't called or failed).
+pub fn platform() -> Option<&'static Platform> {
+    PLATFORM.get()
+}
@@ -0,0 +1,130 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The system resource affinity table (which processors and memory belong
+//! to which proximity domain) and the system locality information table
+//! (how far apart the domains are).
+
+use alloc::vec::Vec;
+
+use fallible_collections::vec::FallibleVec;
+use log::trace;
+
+use super::{entries, u32_at, u64_at, HEADER_SIZE};
+use crate::error::KError;
+
+const PROCESSOR_AFFINITY: u8 = 0;
+const MEMORY_AFFINITY: u8 = 1;
+const X2APIC_AFFINITY: u8 = 2;
+
+const FLAG_ENABLED: u32 = 1 << 0;
+const FLAG_HOTPLUGGABLE: u32 = 1 << 1;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ProcessorAffinity {
+    pub apic_id: u32,
+    pub proximity_domain: u32,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MemoryAffinity {
+    pub base: u64,
+    pub length: u64,
+    pub proximity_domain: u32,
+    pub enabled: bool,
+    pub hotpluggable: bool,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Srat {
+    pub processors: Vec<ProcessorAffinity>,
+    pub memory: Vec<MemoryAffinity>,
+}
+
+impl Srat {
+    pub const SIGNATURE: &'static [u8; 4] = b"SRAT";
+    /// 12 reserved bytes follow the header.
+    const ENTRIES: usize = HEADER_SIZE + 12;
+
+    pub fn parse(table: &[u8]) -> Result<Srat, KError> {
+        if table.len() < Srat::ENTRIES {
+            return Err(KError::InvalidAcpiTable);
+        }
+
+        let mut srat = Srat {
+            processors: Vec::new(),
+            memory: Vec::new(),
+        };
+
+        for (kind, entry) in entries(table, Srat::ENTRIES) {
+            match (kind, entry.len()) {
+                (PROCESSOR_AFFINITY, 16) => {
+                    // Bits 8-31 of the domain are at the end of the entry
+                    let domain_high = u32_at(entry, 8) >> 8;
+                    srat.processors.try_push(ProcessorAffinity {
+                        apic_id: entry[3] as u32,
+                        proximity_domain: domain_high << 8 | entry[2] as u32,
+                        enabled: u32_at(entry, 4) & FLAG_ENABLED != 0,
+                    })?
+                }
+                (X2APIC_AFFINITY, 24) => srat.processors.try_push(ProcessorAffinity {
+                    apic_id: u32_at(entry, 8),
+                    proximity_domain: u32_at(entry, 4),
+                    enabled: u32_at(entry, 12) & FLAG_ENABLED != 0,
+                })?,
+                (MEMORY_AFFINITY, 40) => {
+                    let flags = u32_at(entry, 28);
+                    srat.memory.try_push(MemoryAffinity {
+                        base: u64_at(entry, 8),
+                        length: u64_at(entry, 16),
+                        proximity_domain: u32_at(entry, 2),
+                        enabled: flags & FLAG_ENABLED != 0,
+                        hotpluggable: flags & FLAG_HOTPLUGGABLE != 0,
+                    })?
+                }
+                (kind, len) => trace!("Ignoring SRAT entry type {} ({} bytes)", kind, len),
+            }
+        }
+
+        Ok(srat)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Slit {
+    localities: usize,
+    matrix: Vec<u8>,
+}
+
+impl Slit {
+    pub const SIGNATURE: &'static [u8; 4] = b"SLIT";
+    const MATRIX: usize = HEADER_SIZE + 8;
+
+    pub fn parse(table: &[u8]) -> Result<Slit, KError> {
+        if table.len() < Slit::MATRIX {
+            return Err(KError::InvalidAcpiTable);
+        }
+        let localities = u64_at(table, HEADER_SIZE) as usize;
+        let size = localities
+            .checked_mul(localities)
+            .ok_or(KError::InvalidAcpiTable)?;
+        if table.len() - Slit::MATRIX < size {
+            return Err(KError::InvalidAcpiTable);
+        }
+
+        let mut matrix = Vec::try_with_capacity(size)?;
+        matrix.extend_from_slice(&table[Slit::MATRIX..Slit::MATRIX + size]);
+        Ok(Slit { localities, matrix })
+    }
+
+    /// Distance between two proximity domains (None if the table doesn't
+    /// cover them).
+    pub fn distance(&self, from: u32, to: u32) -> Option<u8> {
+        let (from, to) = (from as usize, to as usize);
+        if from >= self.localities || to >= self.localities {
+            return None;
+        }
+        Some(self.matrix[from * self.localities + to])
+    }
+}
@@ -0,0 +1,327 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Tests the ACPI table parser against made-up tables.
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::madt::{Cpu, IoApic};
+use super::*;
+
+/// Physical memory with a few blobs in it (reads as zeroes elsewhere).
+#[derive(Default)]
+struct FakeMemory {
+    blobs: BTreeMap<u64, Vec<u8>>,
+}
+
+impl FakeMemory {
+    fn add(&mut self, paddr: u64, blob: Vec<u8>) {
+        self.blobs.insert(paddr, blob);
+    }
+}
+
+impl PhysicalMemory for FakeMemory {
+    fn read(&self, paddr: u64, buf: &mut [u8]) {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let addr = paddr + i as u64;
+            *byte = self
+                .blobs
+                .range(..=addr)
+                .next_back()
+                .and_then(|(base, blob)| blob.get((addr - base) as usize).copied())
+                .unwrap_or(0);
+        }
+    }
+}
+
+fn fix_checksum(bytes: &mut [u8], at: usize) {
+    bytes[at] = 0;
+    let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    bytes[at] = 0u8.wrapping_sub(sum);
+}
+
+fn table(signature: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut t = vec![0; HEADER_SIZE];
+    t[0..4].copy_from_slice(signature);
+    t[8] = 1;
+    t.extend_from_slice(body);
+    let len = t.len() as u32;
+    t[4..8].copy_from_slice(&len.to_le_bytes());
+    fix_checksum(&mut t, 9);
+    t
+}
+
+fn rsdp(rsdt: u32, xsdt: Option<u64>) -> Vec<u8> {
+    let mut r = vec![0; 36];
+    r[0..8].copy_from_slice(b"RSD PTR ");
+    r[16..20].copy_from_slice(&rsdt.to_le_bytes());
+    if let Some(xsdt) = xsdt {
+        r[15] = 2;
+        r[20..24].copy_from_slice(&36u32.to_le_bytes());
+        r[24..32].copy_from_slice(&xsdt.to_le_bytes());
+        fix_checksum(&mut r[..20], 8);
+        fix_checksum(&mut r, 32);
+    } else {
+        fix_checksum(&mut r[..20], 8);
+        r.truncate(20);
+    }
+    r
+}
+
+fn madt() -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+    body.extend_from_slice(&1u32.to_le_bytes());
+    // Local APICs 0, 1 and 2 (disabled)
+    body.extend_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]);
+    body.extend_from_slice(&[0, 8, 1, 1, 1, 0, 0, 0]);
+    body.extend_from_slice(&[0, 8, 2, 2, 0, 0, 0, 0]);
+    // x2APIC 0x100
+    body.extend_from_slice(&[9, 16, 0, 0]);
+    body.extend_from_slice(&0x100u32.to_le_bytes());
+    body.extend_from_slice(&1u32.to_le_bytes());
+    body.extend_from_slice(&3u32.to_le_bytes());
+    // IO-APIC
+    body.extend_from_slice(&[1, 12, 4, 0]);
+    body.extend_from_slice(&0xfec0_0000u32.to_le_bytes());
+    body.extend_from_slice(&0u32.to_le_bytes());
+    // IRQ 0 -> GSI 2
+    body.extend_from_slice(&[2, 10, 0, 0]);
+    body.extend_from_slice(&2u32.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // Something we don't know
+    body.extend_from_slice(&[0x7f, 4, 0, 0]);
+    table(b"APIC", &body)
+}
+
+fn memory_affinity(domain: u32, base: u64, length: u64, flags: u32) -> Vec<u8> {
+    let mut e = vec![1, 40];
+    e.extend_from_slice(&domain.to_le_bytes());
+    e.extend_from_slice(&[0, 0]);
+    e.extend_from_slice(&base.to_le_bytes());
+    e.extend_from_slice(&length.to_le_bytes());
+    e.extend_from_slice(&[0; 4]);
+    e.extend_from_slice(&flags.to_le_bytes());
+    e.extend_from_slice(&[0; 8]);
+    e
+}
+
+/// Two nodes: domain 7 (APIC 0 and 1, first 2 GiB) and domain 3 (x2APIC
+/// 0x100, next 2 GiB plus hot-pluggable memory).
+fn srat() -> Vec<u8> {
+    let mut body = vec![0; 12];
+    for apic_id in 0..2 {
+        body.extend_from_slice(&[0, 16, 7, apic_id, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+    body.extend_from_slice(&[2, 24, 0, 0]);
+    body.extend_from_slice(&3u32.to_le_bytes());
+    body.extend_from_slice(&0x100u32.to_le_bytes());
+    body.extend_from_slice(&1u32.to_le_bytes());
+    body.extend_from_slice(&[0; 8]);
+    body.extend(memory_affinity(7, 0, 2 << 30, 1));
+    body.extend(memory_affinity(3, 2 << 30, 2 << 30, 1));
+    body.extend(memory_affinity(3, 8 << 30, 1 << 30, 0b11));
+    // Disabled
+    body.extend(memory_affinity(5, 16 << 30, 1 << 30, 0));
+    table(b"SRAT", &body)
+}
+
+fn slit() -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&8u64.to_le_bytes());
+    let mut matrix = vec![0; 64];
+    for from in 0..8 {
+        for to in 0..8 {
+            matrix[from * 8 + to] = if from == to {
+                10
+            } else {
+                20 + from as u8 + to as u8
+            };
+        }
+    }
+    body.extend(matrix);
+    table(b"SLIT", &body)
+}
+
+/// A machine with RSDP in the BIOS area and an XSDT.
+fn machine() -> FakeMemory {
+    let mut mem = FakeMemory::default();
+    mem.add(0xe_0040, rsdp(0, Some(0x7000_0000)));
+
+    let mut xsdt = Vec::new();
+    for paddr in [0x7000_1000u64, 0x7000_2000, 0x7000_3000].iter() {
+        xsdt.extend_from_slice(&paddr.to_le_bytes());
+    }
+    mem.add(0x7000_0000, table(b"XSDT", &xsdt));
+    mem.add(0x7000_1000, madt());
+    mem.add(0x7000_2000, srat());
+    mem.add(0x7000_3000, slit());
+    mem
+}
+
+#[test]
+fn rsdp_is_found() {
+    let mut mem = machine();
+    assert_eq!(
+        Rsdp::find(&mem, &[]),
+        Some(Rsdp {
+            revision: 2,
+            rsdt: 0,
+            xsdt: Some(0x7000_0000)
+        })
+    );
+
+    // The hint wins (the bogus one is skipped)
+    mem.add(0x1000, rsdp(0x6000_0000, None));
+    assert_eq!(
+        Rsdp::find(&mem, &[0, 0x2000, 0x1000]),
+        Some(Rsdp {
+            revision: 0,
+            rsdt: 0x6000_0000,
+            xsdt: None
+        })
+    );
+
+    // Within the EBDA
+    let mut mem = FakeMemory::default();
+    mem.add(0x40e, vec![0x00, 0x9f]);
+    mem.add(0x9_f020, rsdp(0x6000_0000, None));
+    assert_eq!(Rsdp::find(&mem, &[]).map(|r| r.rsdt), Some(0x6000_0000));
+
+    assert_eq!(Rsdp::find(&FakeMemory::default(), &[]), None);
+}
+
+#[test]
+fn bad_checksum_is_rejected() {
+    let mut mem = machine();
+    let mut broken = madt();
+    broken[40] ^= 0xff;
+    mem.add(0x7000_1000, broken);
+
+    let rsdp = Rsdp::find(&mem, &[]).unwrap();
+    let tables = Tables::new(&mem, &rsdp).unwrap();
+    assert_eq!(tables.find(b"APIC"), Err(KError::InvalidAcpiTable));
+    assert_eq!(tables.find(b"HPET"), Err(KError::AcpiTableNotFound));
+}
+
+#[test]
+fn madt_is_parsed() {
+    let madt = Madt::parse(&madt()).unwrap();
+    assert_eq!(madt.local_apic_address, 0xfee0_0000);
+    assert_eq!(
+        madt.cpus,
+        vec![
+            Cpu {
+                uid: 0,
+                apic_id: 0,
+                enabled: true
+            },
+            Cpu {
+                uid: 1,
+                apic_id: 1,
+                enabled: true
+            },
+            Cpu {
+                uid: 2,
+                apic_id: 2,
+                enabled: false
+            },
+            Cpu {
+                uid: 3,
+                apic_id: 0x100,
+                enabled: true
+            },
+        ]
+    );
+    assert_eq!(
+        madt.io_apics,
+        vec![IoApic {
+            id: 4,
+            address: 0xfec0_0000,
+            gsi_base: 0
+        }]
+    );
+    assert_eq!(
+        madt.overrides,
+        vec![InterruptOverride {
+            irq: 0,
+            gsi: 2,
+            flags: 0
+        }]
+    );
+
+    assert_eq!(
+        Madt::parse(&table(b"APIC", &[])),
+        Err(KError::InvalidAcpiTable)
+    );
+}
+
+#[test]
+fn platform_has_numa_nodes() {
+    let mem = machine();
+    let rsdp = Rsdp::find(&mem, &[]).unwrap();
+    let platform = Platform::parse(&mem, &rsdp).unwrap();
+
+    assert_eq!(platform.nodes.len(), 2);
+    assert_eq!(platform.nodes[0].proximity_domain, 3);
+    assert_eq!(platform.nodes[0].apic_ids, vec![0x100]);
+    // Hot-pluggable memory doesn't count
+    assert_eq!(platform.nodes[0].memory, 2 << 30);
+    assert_eq!(platform.nodes[1].proximity_domain, 7);
+    assert_eq!(platform.nodes[1].apic_ids, vec![0, 1]);
+    assert_eq!(platform.nodes[1].memory, 2 << 30);
+
+    assert_eq!(platform.memory.len(), 3);
+    assert_eq!(
+        platform.memory[2],
+        MemoryRange {
+            base: 8 << 30,
+            length: 1 << 30,
+            node: 0,
+            hotpluggable: true
+        }
+    );
+
+    assert_eq!(platform.node_of(1), Some(1));
+    assert_eq!(platform.node_of(0x100), Some(0));
+    assert_eq!(platform.node_of(2), None);
+
+    // Distances come from the SLIT (indexed by proximity domain)
+    assert_eq!(platform.distance(0, 0), 10);
+    assert_eq!(platform.distance(0, 1), 30);
+    assert_eq!(platform.distance(1, 0), 30);
+    assert_eq!(platform.distance(1, 1), 10);
+}
+
+#[test]
+fn platform_without_srat_is_one_node() {
+    let mut mem = FakeMemory::default();
+    mem.add(0x1000, rsdp(0x2000, None));
+    mem.add(0x2000, table(b"RSDT", &0x3000u32.to_le_bytes()));
+    mem.add(0x3000, madt());
+
+    let rsdp = Rsdp::find(&mem, &[0x1000]).unwrap();
+    let platform = Platform::parse(&mem, &rsdp).unwrap();
+    assert_eq!(platform.madt.cpus.len(), 4);
+    assert!(platform.nodes.is_empty());
+    assert_eq!(platform.node_of(0), None);
+    assert_eq!(platform.distance(0, 0), 10);
+}
+
+#[test]
+fn slit_is_parsed() {
+    let slit = Slit::parse(&slit()).unwrap();
+    assert_eq!(slit.distance(3, 7), Some(30));
+    assert_eq!(slit.distance(8, 0), None);
+
+    // Claims more localities than it has
+    let mut body = Vec::new();
+    body.extend_from_slice(&4u64.to_le_bytes());
+    body.extend_from_slice(&[10, 20, 20, 10]);
+    assert_eq!(
+        Slit::parse(&table(b"SLIT", &body)),
+        Err(KError::InvalidAcpiTable)
+    );
+}
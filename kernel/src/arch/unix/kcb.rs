@@ -11,13 +11,14 @@ use arrayvec::ArrayVec;
 use cnr::{Replica as MlnrReplica, ReplicaToken as MlnrReplicaToken};
 use node_replication::{Replica, ReplicaToken};
 
+use crate::cmdline::KernelConfig;
 use crate::cnrfs::MlnrKernelNode;
 use crate::error::KError;
 use crate::nr::KernelNode;
 use crate::nrproc::NrProcess;
 use crate::process::{Pid, MAX_PROCESSES};
 use crate::{
-    kcb::{ArchSpecificKcb, BootloaderArguments, Kcb},
+    kcb::{ArchSpecificKcb, Kcb},
     memory::mcache::TCacheSp,
 };
 
@@ -31,19 +32,30 @@ static KERNEL_ARGS: KernelArgs = KernelArgs::new();
 static mut KCB: Kcb<ArchKcb> = {
     Kcb::new(
         &[],
-        BootloaderArguments::new("info", "init", "init", "init"),
+        KernelConfig::new(),
         TCacheSp::new(0),
         ArchKcb::new(&KERNEL_ARGS),
         0,
     )
 };
 
+/// Whether `init_core` set up the KCB of this thread.
+#[thread_local]
+static mut KCB_READY: bool = false;
+
 pub fn try_get_kcb<'a>() -> Option<&'a mut Kcb<ArchKcb>> {
-    unsafe { Some(&mut KCB) }
+    Some(get_kcb())
 }
 
+/// The KCB of the current thread (set up on first use).
 pub fn get_kcb<'a>() -> &'a mut Kcb<ArchKcb> {
-    unsafe { &mut KCB }
+    unsafe {
+        if !KCB_READY {
+            KCB_READY = true;
+            super::init_core(&mut KCB);
+        }
+        &mut KCB
+    }
 }
 
 /// Initialize the KCB in the system.
@@ -74,6 +86,10 @@ impl ArchKcb {
         }
     }
 
+    pub fn set_init_vspace(&mut self, vspace: VSpace) {
+        self.init_vspace = Some(RefCell::new(vspace));
+    }
+
     pub fn init_vspace(&self) -> RefMut<VSpace> {
         let ivp = self.init_vspace.as_ref().unwrap();
         ivp.borrow_mut()
@@ -87,29 +103,9 @@ impl ArchKcb {
         0
     }
 
-    pub fn max_threads(&self) -> usize {
-        0
-    }
-
-    #[allow(clippy::boxed_local)]
-    pub fn swap_current_executor(
-        &mut self,
-        _current_executor: Box<UnixThread>,
-    ) -> Option<Box<UnixThread>> {
-        None
-    }
-
     pub fn has_executor(&self) -> bool {
         self.current_executor.is_some()
     }
-
-    pub fn current_executor(&self) -> Result<&UnixThread, KError> {
-        let p = self
-            .current_executor
-            .as_ref()
-            .ok_or(KError::ProcessNotSet)?;
-        Ok(p)
-    }
 }
 
 impl ArchSpecificKcb for ArchKcb {
@@ -121,6 +117,10 @@ impl ArchSpecificKcb for ArchKcb {
         0
     }
 
+    fn max_threads(&self) -> usize {
+        0
+    }
+
     fn node(&self) -> usize {
         atopology::MACHINE_TOPOLOGY
             .current_thread()
@@ -132,6 +132,22 @@ impl ArchSpecificKcb for ArchKcb {
         Err(KError::ProcessNotSet)
     }
 
+    fn current_executor(&self) -> Result<&UnixThread, KError> {
+        let p = self
+            .current_executor
+            .as_ref()
+            .ok_or(KError::ProcessNotSet)?;
+        Ok(p)
+    }
+
+    #[allow(clippy::boxed_local)]
+    fn swap_current_executor(
+        &mut self,
+        _current_executor: Box<UnixThread>,
+    ) -> Option<Box<UnixThread>> {
+        None
+    }
+
     #[allow(clippy::type_complexity)] // fix this once `associated_type_defaults` works
     fn process_table(
         &self,
@@ -4,15 +4,23 @@
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
 
 use arrayvec::ArrayVec;
 use ctor::ctor;
-use log::{debug, info};
+use log::{debug, error, info, warn};
 use node_replication::{Log, Replica};
+use spin::Once;
 use x86::current::paging::HUGE_PAGE_SIZE;
 
-use crate::memory::mcache::TCacheSp;
-use crate::memory::{GlobalMemory, GrowBackend, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+use crate::arch_interface::Arch;
+use crate::error::KError;
+use crate::kcb::Kcb;
+use crate::memory::mcache::TCache;
+use crate::memory::vspace::MapAction;
+use crate::memory::{
+    GlobalMemory, GrowBackend, PAddr, PhysicalPageProvider, VAddr, BASE_PAGE_SIZE, LARGE_PAGE_SIZE,
+};
 use crate::nr::{KernelNode, Op};
 use crate::{xmain, ExitReason};
 
@@ -21,7 +29,9 @@ pub mod irq;
 pub mod kcb;
 pub mod memory;
 pub mod process;
+pub mod rng;
 pub mod timer;
+pub mod user_access;
 pub mod vspace;
 
 pub use bootloader_shared::*;
@@ -29,6 +39,50 @@ pub use bootloader_shared::*;
 pub const MAX_NUMA_NODES: usize = 12;
 pub const MAX_CORES: usize = 192;
 
+/// The backend that runs the kernel as a process (see
+/// `crate::arch_interface`).
+pub struct Unix;
+
+/// The backend the kernel runs on.
+pub type Platform = Unix;
+
+impl Arch for Unix {
+    type Kcb = kcb::ArchKcb;
+
+    const DEFAULT_TIMER_DEADLINE: Duration = timer::DEFAULT_TIMER_DEADLINE;
+
+    fn online_cores() -> usize {
+        1
+    }
+
+    fn halt() -> ! {
+        halt()
+    }
+
+    fn enable_irqs() {
+        irq::enable()
+    }
+
+    fn disable_irqs() {
+        irq::disable()
+    }
+
+    fn set_timer(deadline: Duration) {
+        timer::set(deadline)
+    }
+
+    fn map_kernel(vbase: VAddr, pregion: (PAddr, usize), action: MapAction) -> Result<(), KError> {
+        kcb::get_kcb()
+            .arch
+            .init_vspace()
+            .map_generic(vbase, pregion, action, true)
+    }
+
+    fn advance_fs_replica() {
+        advance_fs_replica()
+    }
+}
+
 pub fn halt() -> ! {
     unsafe { libc::exit(0) };
 }
@@ -37,6 +91,19 @@ pub fn advance_fs_replica() {
     unimplemented!("eager_advance_fs_replica not implemented for unix");
 }
 
+/// What all cores (threads of the process) share.
+struct Machine {
+    global_memory: &'static GlobalMemory,
+    replica: Arc<Replica<'static, KernelNode>>,
+}
+
+static MACHINE: Once<Machine> = Once::new();
+
+/// Base pages a core gets from the global memory when it starts.
+const TCACHE_BASE_PAGES: usize = 64;
+/// Large pages a core gets from the global memory when it starts.
+const TCACHE_LARGE_PAGES: usize = 8;
+
 static INITIALIZED: AtomicBool = AtomicBool::new(false);
 
 #[ctor]
@@ -50,54 +117,106 @@ fn init_setup() {
     // Note anything lower than Info is currently broken
     // because macros in mem management will do a recursive
     // allocation and this stuff is not reentrant...
-    let _r = klogger::init("info");
+    let _r = crate::console::init("info");
 
     lazy_static::initialize(&rawtime::WALL_TIME_ANCHOR);
     lazy_static::initialize(&rawtime::BOOT_TIME_ANCHOR);
-
-    // Allocate 32 MiB and add it to our heap
-    let mut tc = TCacheSp::new(0);
-    let mut mm = memory::MemoryMapper::default();
+    if let Ok(now) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        crate::time::init(now, "host");
+    }
 
     // avoids unused code warnings
     let _stack = crate::stack::OwnedStack::new(LARGE_PAGE_SIZE);
 
-    for _i in 0..64 {
-        let frame = mm
-            .allocate_frame(BASE_PAGE_SIZE)
-            .expect("We don't have vRAM available");
-        tc.grow_base_pages(&[frame]).expect("Can't add base-page");
-    }
-
-    for _i in 0..5 {
-        let frame = mm
-            .allocate_frame(LARGE_PAGE_SIZE)
-            .expect("We don't have vRAM available");
-        tc.grow_large_pages(&[frame]).expect("Can't add large-page");
+    // Sets up the KCB of this thread (and the machine)
+    kcb::get_kcb();
+    debug!("Memory allocation should work at this point...");
+    if let Err(e) = rng::init() {
+        error!("Unable to seed entropy pool: {}", e);
     }
+}
 
+/// Sets up the memory and the replica all cores share.
+fn init_machine() -> Machine {
+    let mut mm = memory::MemoryMapper::default();
     let frame = mm
         .allocate_frame(2 * HUGE_PAGE_SIZE)
         .expect("We don't have vRAM available");
     let mut annotated_regions = ArrayVec::new();
     annotated_regions.push(frame);
     let global_memory = unsafe { Box::new(GlobalMemory::new(annotated_regions).unwrap()) };
-    let global_memory_static: &'static GlobalMemory = Box::leak(global_memory);
-
-    // Construct the Kcb so we can access these things later on in the code
-    kcb::get_kcb().set_global_memory(global_memory_static);
-    debug!("Memory allocation should work at this point...");
 
     let log: Arc<Log<Op>> = Arc::try_new(Log::<Op>::new(LARGE_PAGE_SIZE))
         .expect("Not enough memory to initialize system");
-    let bsp_replica = Replica::<KernelNode>::new(&log);
-    let local_ridx = bsp_replica
-        .register()
-        .expect("Failed to register with Replica.");
+    Machine {
+        global_memory: Box::leak(global_memory),
+        replica: Replica::<KernelNode>::new(&log),
+    }
+}
+
+/// Sets up the KCB of a core like the x86-64 backend does for its cores.
+///
+/// Every thread is a core for us, with a KCB of its own. The test harness
+/// runs every test in a thread, so tests get a working KCB (memory
+/// allocation, the replica) without doing anything.
+pub(crate) fn init_core(kcb: &mut Kcb<kcb::ArchKcb>) {
+    let machine = MACHINE.call_once(init_machine);
+
+    // The early memory manager gets a few pages, the tcache refills
+    // itself from the global memory
+    let mut mm = memory::MemoryMapper::default();
     {
-        let kcb = kcb::get_kcb();
-        kcb.setup_node_replication(bsp_replica.clone(), local_ridx);
+        let mut emanager = kcb.emanager();
+        for _i in 0..64 {
+            let frame = mm
+                .allocate_frame(BASE_PAGE_SIZE)
+                .expect("We don't have vRAM available");
+            emanager
+                .grow_base_pages(&[frame])
+                .expect("Can't add base-page");
+        }
+        for _i in 0..5 {
+            let frame = mm
+                .allocate_frame(LARGE_PAGE_SIZE)
+                .expect("We don't have vRAM available");
+            emanager
+                .grow_large_pages(&[frame])
+                .expect("Can't add large-page");
+        }
+    }
+    // The x86-64 cores refill their tcache in the allocator, we don't have
+    // our own allocator so it starts out with some memory instead
+    let mut tcache = TCache::new(kcb.node);
+    {
+        let mut ncache = machine.global_memory.node_caches[kcb.node as usize].lock();
+        for _i in 0..TCACHE_BASE_PAGES {
+            let frame = ncache
+                .allocate_base_page()
+                .expect("Not enough memory for the tcache");
+            tcache
+                .grow_base_pages(&[frame])
+                .expect("Can't add base-page");
+        }
+        for _i in 0..TCACHE_LARGE_PAGES {
+            let frame = ncache
+                .allocate_large_page()
+                .expect("Not enough memory for the tcache");
+            tcache
+                .grow_large_pages(&[frame])
+                .expect("Can't add large-page");
+        }
+    }
+    kcb.set_global_memory(machine.global_memory);
+    kcb.set_physical_memory_manager(tcache);
+    kcb.arch.set_init_vspace(vspace::VSpace::new());
+
+    // A replica only takes so many threads, the ones after that run
+    // without one (and get `ReplicaNotSet`)
+    match machine.replica.register() {
+        Some(token) => kcb.setup_node_replication(machine.replica.clone(), token),
+        None => warn!("Can't register with the replica, too many threads"),
     }
+    kcb.allocate_extensions();
 }
 
 #[start]
@@ -114,3 +233,207 @@ pub fn start(_argc: isize, _argv: *const *const u8) -> isize {
 
     ExitReason::ReturnFromMain as isize
 }
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::kcb::MemManager;
+    use crate::memory::vspace::AddressSpace;
+    use crate::memory::{AllocatorStatistics, Frame};
+
+    /// Every thread gets a KCB that can allocate memory.
+    #[test]
+    fn kcb_per_thread() {
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    let kcb = kcb::get_kcb();
+                    assert!(kcb.physical_memory.gmanager.is_some());
+                    assert!(kcb.replica.is_some());
+
+                    let mut mem_manager = kcb.mem_manager();
+                    let base = mem_manager.allocate_base_page().expect("No base page");
+                    assert_eq!(base.size(), BASE_PAGE_SIZE);
+                    let large = mem_manager.allocate_large_page().expect("No large page");
+                    assert_eq!(large.size(), LARGE_PAGE_SIZE);
+                    assert!(large.is_large_page_aligned());
+
+                    mem_manager.release_base_page(base).expect("Can't release");
+                    mem_manager
+                        .release_large_page(large)
+                        .expect("Can't release");
+                    (base, large)
+                })
+            })
+            .collect();
+
+        let frames: Vec<(Frame, Frame)> = threads
+            .into_iter()
+            .map(|t| t.join().expect("Thread failed"))
+            .collect();
+        // The tcaches are filled from the same global memory
+        for (i, (base, large)) in frames.iter().enumerate() {
+            for (other_base, other_large) in frames.iter().skip(i + 1) {
+                assert_ne!(base.base, other_base.base);
+                assert_ne!(large.base, other_large.base);
+            }
+        }
+    }
+
+    /// The scheduler state is reachable through the replica of the KCB.
+    #[test]
+    fn replica_scheduling() {
+        let pid = KernelNode::allocate_pid("unix-test").expect("Can't allocate pid");
+        assert!(KernelNode::processes()
+            .expect("Can't read processes")
+            .iter()
+            .any(|(p, entry)| *p == pid && entry.binary == "unix-test"));
+        assert_eq!(KernelNode::process(pid).map(|e| e.binary), Ok("unix-test"));
+
+        let gtid = KernelNode::allocate_core_to_process(pid, VAddr::from(0x1000u64), None, None)
+            .expect("Can't allocate a core");
+        let core = KernelNode::current_process(gtid).expect("Core isn't allocated");
+        assert_eq!(core.pid, pid);
+        assert_eq!(core.entry_point, VAddr::from(0x1000u64));
+        assert_eq!(
+            KernelNode::core_node(gtid),
+            Ok(atopology::MACHINE_TOPOLOGY
+                .threads()
+                .find(|t| t.id == gtid)
+                .and_then(|t| t.node_id)
+                .unwrap_or(0))
+        );
+
+        KernelNode::release_core_from_process(pid, gtid).expect("Can't release core");
+        KernelNode::free_pid(pid).expect("Can't free pid");
+        assert_eq!(KernelNode::free_pid(pid), Err(KError::NoProcessFoundForPid));
+        assert_eq!(KernelNode::process(pid), Err(KError::NoProcessFoundForPid));
+    }
+
+    /// The init vspace remembers what we map.
+    #[test]
+    fn init_vspace() {
+        let kcb = kcb::get_kcb();
+        let frame = kcb
+            .mem_manager()
+            .allocate_base_page()
+            .expect("No base page");
+
+        let mut vspace = kcb.arch.init_vspace();
+        let va = VAddr::from(0xffff_0000u64);
+        vspace
+            .map_frame(va, frame, MapAction::ReadWriteKernel)
+            .expect("Can't map");
+        assert_eq!(
+            vspace.resolve(va),
+            Ok((frame.base, MapAction::ReadWriteKernel))
+        );
+        assert_eq!(
+            vspace.map_frame(va, frame, MapAction::ReadKernel),
+            Err(KError::AlreadyMapped {
+                base: VAddr::from(0xffff_0000u64)
+            })
+        );
+        assert_eq!(vspace.unmap(va).expect("Can't unmap").frame, frame);
+        assert_eq!(vspace.resolve(va), Err(KError::NotMapped));
+        drop(vspace);
+
+        kcb.mem_manager()
+            .release_base_page(frame)
+            .expect("Can't release");
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    enum FrameAction {
+        AllocateBase,
+        AllocateLarge,
+        /// Releases the frame at this index (modulo what we hold).
+        Release(usize),
+    }
+
+    fn frame_actions() -> impl Strategy<Value = Vec<FrameAction>> {
+        proptest::collection::vec(
+            prop_oneof![
+                Just(FrameAction::AllocateBase),
+                Just(FrameAction::AllocateLarge),
+                any::<usize>().prop_map(FrameAction::Release),
+            ],
+            0..256,
+        )
+    }
+
+    proptest! {
+        /// The memory manager of the KCB hands out frames that don't overlap
+        /// and runs out exactly when it should.
+        #[test]
+        fn mem_manager_model(ops in frame_actions()) {
+            let kcb = kcb::get_kcb();
+            let mut mem_manager = kcb.mem_manager();
+            let free_base = mem_manager.free_base_pages();
+            let free_large = mem_manager.free_large_pages();
+
+            let mut held: Vec<Frame> = Vec::new();
+            for op in ops {
+                match op {
+                    FrameAction::AllocateBase => {
+                        let holding = held.iter().filter(|f| f.size() == BASE_PAGE_SIZE).count();
+                        match mem_manager.allocate_base_page() {
+                            Ok(frame) => {
+                                prop_assert_eq!(frame.size(), BASE_PAGE_SIZE);
+                                prop_assert!(!overlaps(&held, frame));
+                                held.push(frame);
+                            }
+                            Err(_e) => prop_assert_eq!(holding, free_base),
+                        }
+                    }
+                    FrameAction::AllocateLarge => {
+                        let holding = held.iter().filter(|f| f.size() == LARGE_PAGE_SIZE).count();
+                        match mem_manager.allocate_large_page() {
+                            Ok(frame) => {
+                                prop_assert_eq!(frame.size(), LARGE_PAGE_SIZE);
+                                prop_assert!(!overlaps(&held, frame));
+                                held.push(frame);
+                            }
+                            Err(_e) => prop_assert_eq!(holding, free_large),
+                        }
+                    }
+                    FrameAction::Release(idx) => {
+                        if !held.is_empty() {
+                            let frame = held.swap_remove(idx % held.len());
+                            release(&mut *mem_manager, frame);
+                        }
+                    }
+                }
+
+                let base = held.iter().filter(|f| f.size() == BASE_PAGE_SIZE).count();
+                prop_assert_eq!(mem_manager.free_base_pages(), free_base - base);
+                prop_assert_eq!(mem_manager.free_large_pages(), free_large - (held.len() - base));
+            }
+
+            for frame in held {
+                release(&mut *mem_manager, frame);
+            }
+            prop_assert_eq!(mem_manager.free_base_pages(), free_base);
+            prop_assert_eq!(mem_manager.free_large_pages(), free_large);
+        }
+    }
+
+    fn overlaps(held: &[Frame], frame: Frame) -> bool {
+        held.iter()
+            .any(|f| frame.base < f.end() && f.base < frame.end())
+    }
+
+    fn release(mem_manager: &mut dyn MemManager, frame: Frame) {
+        if frame.size() == BASE_PAGE_SIZE {
+            mem_manager.release_base_page(frame).expect("Can't release");
+        } else {
+            mem_manager
+                .release_large_page(frame)
+                .expect("Can't release");
+        }
+    }
+}
@@ -6,11 +6,10 @@ use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use bootloader_shared::Module;
-use core::ops::{Deref, DerefMut};
 use x86::current::paging::PAddr;
 
 use arrayvec::ArrayVec;
-use kpi::process::FrameId;
+use kpi::process::{AddressLayout, Credentials, FrameId};
 use lazy_static::lazy_static;
 
 use node_replication::{Dispatch, Log, Replica};
@@ -31,6 +30,8 @@ use super::debug;
 use super::vspace::VSpace;
 use super::MAX_NUMA_NODES;
 
+pub use super::user_access::UserSlice;
+
 lazy_static! {
     pub static ref PROCESS_TABLE: ArrayVec<ArrayVec<Arc<Replica<'static, NrProcess<UnixProcess>>>, MAX_PROCESSES>, MAX_NUMA_NODES> = {
         // Want at least one replica...
@@ -50,6 +51,9 @@ lazy_static! {
 
             let da = DA::new().expect("Can't initialize process deterministic memory allocator");
             for node in 0..numa_nodes {
+                if !crate::numa::has_cores(node) {
+                    continue;
+                }
                 let kcb = kcb::get_kcb();
                 assert!(kcb.set_allocation_affinity(node as atopology::NodeId).is_ok());
 
@@ -57,106 +61,28 @@ lazy_static! {
 
 
                 let p = Box::try_new(UnixProcess::new(pid, da.clone()).expect("Can't create process during init")).expect("Not enough memory to initialize processes");
-                let nrp = NrProcess::new(p, da.clone());
+                let nrp = NrProcess::new(pid, p, da.clone());
 
                 numa_cache[node].push(Replica::<NrProcess<UnixProcess>>::with_data(&log, nrp));
 
                 debug_assert_eq!(kcb.arch.node(), 0, "Expect initialization to happen on node 0.");
                 assert!(kcb.set_allocation_affinity(0).is_ok());
             }
+
+            // Nodes without cores use the replica of the nearest node with cores
+            for node in 0..numa_nodes {
+                let other = crate::numa::replica_node(node);
+                if other != node {
+                    let replica = numa_cache[other][pid].clone();
+                    numa_cache[node].push(replica);
+                }
+            }
         }
 
         numa_cache
     };
 }
 
-/// TODO: This code is same as x86_64 process. Can we remove it?
-pub struct UserPtr<T> {
-    value: *mut T,
-}
-
-impl<T> UserPtr<T> {
-    pub fn new(pointer: *mut T) -> UserPtr<T> {
-        UserPtr { value: pointer }
-    }
-
-    pub fn vaddr(&self) -> VAddr {
-        VAddr::from(self.value as u64)
-    }
-}
-
-impl<T> Deref for UserPtr<T> {
-    type Target = T;
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.value }
-    }
-}
-
-impl<T> DerefMut for UserPtr<T> {
-    fn deref_mut(&mut self) -> &mut T {
-        unsafe { &mut *self.value }
-    }
-}
-
-pub struct UserValue<T> {
-    value: T,
-}
-
-impl<T> UserValue<T> {
-    pub fn new(pointer: T) -> UserValue<T> {
-        UserValue { value: pointer }
-    }
-
-    pub fn as_mut_ptr(&mut self) -> *mut T {
-        unsafe { core::mem::transmute(&self.value) }
-    }
-}
-
-impl<T> Deref for UserValue<T> {
-    type Target = T;
-    fn deref(&self) -> &T {
-        &self.value
-    }
-}
-
-impl<T> DerefMut for UserValue<T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.value
-    }
-}
-
-#[derive(Debug, Eq, PartialEq)]
-pub struct UserSlice<'a> {
-    pub buffer: &'a mut [u8],
-}
-
-impl<'a> UserSlice<'a> {
-    pub fn from_slice(buffer: &'a mut [u8]) -> Self {
-        UserSlice { buffer }
-    }
-
-    pub fn new(base: u64, len: usize) -> UserSlice<'a> {
-        let mut user_ptr = VAddr::from(base);
-        let slice_ptr = UserPtr::new(&mut user_ptr);
-        let user_slice: &mut [u8] =
-            unsafe { core::slice::from_raw_parts_mut(slice_ptr.as_mut_ptr(), len) };
-        UserSlice { buffer: user_slice }
-    }
-}
-
-impl<'a> Deref for UserSlice<'a> {
-    type Target = [u8];
-    fn deref(&self) -> &Self::Target {
-        &*self.buffer
-    }
-}
-
-impl<'a> DerefMut for UserSlice<'a> {
-    fn deref_mut(&mut self) -> &mut [u8] {
-        self.buffer
-    }
-}
-
 #[derive(Debug, Default)]
 pub struct UnixProcess {
     vspace: VSpace,
@@ -231,9 +157,13 @@ impl Process for UnixProcess {
     fn load(
         &mut self,
         _pid: Pid,
+        creds: Credentials,
+        layout: AddressLayout,
         _module: &Module,
         _writable_sections: Vec<Frame>,
     ) -> Result<(), KError> {
+        self.pinfo.creds = creds;
+        self.pinfo.layout = layout;
         self.vspace.map_frame(
             VAddr::from(0x2000_0000),
             Frame::new(PAddr::zero(), 0x0, 0x0),
@@ -285,7 +215,7 @@ impl Process for UnixProcess {
         Err(KError::InvalidFrameId)
     }
 
-    fn get_frame(&mut self, _frame_id: FrameId) -> Result<Frame, KError> {
+    fn get_frame(&self, _frame_id: FrameId) -> Result<Frame, KError> {
         Err(KError::InvalidFrameId)
     }
 
@@ -294,8 +224,8 @@ impl Process for UnixProcess {
     }
 }
 
-pub fn spawn(binary: &'static str) -> Result<Pid, KError> {
-    let pid = crate::process::make_process::<UnixProcess>(binary)?;
+pub fn spawn(binary: &'static str, creds: Credentials) -> Result<Pid, KError> {
+    let pid = crate::process::make_process::<UnixProcess>(binary, creds, None)?;
     crate::process::allocate_dispatchers::<UnixProcess>(pid)?;
     Ok(0)
 }
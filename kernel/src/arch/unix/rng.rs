@@ -0,0 +1,19 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Random numbers from the host.
+
+use rand::RngCore;
+
+use crate::entropy;
+use crate::error::KError;
+
+fn fill(buf: &mut [u8]) -> usize {
+    rand::thread_rng().fill_bytes(buf);
+    buf.len()
+}
+
+/// Registers the host as an entropy source.
+pub fn init() -> Result<(), KError> {
+    entropy::register_source("host", fill)
+}
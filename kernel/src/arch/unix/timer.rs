@@ -3,8 +3,10 @@
 
 //! Timer API
 
-/// Default when to raise the next timer irq (in rdtsc ticks)
-pub const DEFAULT_TIMER_DEADLINE: u64 = 2_000_000_000;
+use core::time::Duration;
+
+/// Default when to raise the next timer irq
+pub const DEFAULT_TIMER_DEADLINE: Duration = Duration::from_secs(1);
 
 /// Register a periodic timer to advance replica.
-pub fn set(_deadline: u64) {}
+pub fn set(_deadline: Duration) {}
@@ -0,0 +1,78 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Accesses to "user" memory on unix: it's all in our own address-space so
+//! these just copy (see the x86_64 version for the real thing).
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+
+use fallible_collections::FallibleVec;
+
+use crate::error::KError;
+use crate::memory::vspace::UserAccess;
+
+/// Longest string `copy_in_str` accepts (including the NUL byte).
+pub const MAX_STR_LEN: usize = 4096;
+
+/// Same interface as the x86_64 `UserSlice`, but it also derefs to the
+/// buffer for the tests.
+#[derive(Debug, Eq, PartialEq)]
+pub struct UserSlice<'a> {
+    pub buffer: &'a mut [u8],
+}
+
+impl<'a> UserSlice<'a> {
+    pub fn from_slice(buffer: &'a mut [u8]) -> Self {
+        UserSlice { buffer }
+    }
+
+    pub fn new(base: u64, len: usize, _access: UserAccess) -> Result<UserSlice<'a>, KError> {
+        let buffer = unsafe { core::slice::from_raw_parts_mut(base as *mut u8, len) };
+        Ok(UserSlice { buffer })
+    }
+
+    pub fn read_into(&self, offset: usize, dst: &mut [u8]) {
+        dst.copy_from_slice(&self.buffer[offset..offset + dst.len()]);
+    }
+
+    pub fn write_from(&mut self, offset: usize, src: &[u8]) {
+        self.buffer[offset..offset + src.len()].copy_from_slice(src);
+    }
+}
+
+impl<'a> Deref for UserSlice<'a> {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        &*self.buffer
+    }
+}
+
+impl<'a> DerefMut for UserSlice<'a> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buffer
+    }
+}
+
+pub fn copy_in(dst: &mut [u8], src: u64) -> Result<(), KError> {
+    unsafe { core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len()) };
+    Ok(())
+}
+
+pub fn copy_out(dst: u64, src: &[u8]) -> Result<(), KError> {
+    unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len()) };
+    Ok(())
+}
+
+pub fn copy_in_str(src: u64) -> Result<String, KError> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for i in 0..MAX_STR_LEN {
+        let b = unsafe { *((src + i as u64) as *const u8) };
+        if b == 0 {
+            return String::from_utf8(bytes).map_err(|_e| KError::NotSupported);
+        }
+        bytes.try_push(b)?;
+    }
+    Err(KError::NotSupported)
+}
@@ -1,21 +1,24 @@
 // Copyright © 2021 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-//! A dummy vspace implementation for the unix platform.
+//! The vspace of the unix platform.
+//!
+//! There is no MMU to program, so this only remembers the mappings (in the
+//! model address space the x86-64 page-tables are tested against).
 
 use alloc::boxed::Box;
 use core::fmt;
 use core::pin::Pin;
-use hashbrown::HashMap;
 
 use crate::error::KError;
-use crate::memory::vspace::{AddressSpace, MapAction, MappingInfo, TlbFlushHandle};
+use crate::memory::vspace::{AddressSpace, MapAction, TlbFlushHandle};
+use crate::memory::vspace_model::ModelAddressSpace;
 use crate::memory::Frame;
 
 use x86::bits64::paging::*;
 
 pub struct VSpace {
-    pub mappings: HashMap<core::ops::Range<usize>, MappingInfo>,
+    mappings: ModelAddressSpace,
     pub pml4: Pin<Box<PML4>>,
 }
 
@@ -34,7 +37,7 @@ impl fmt::Debug for VSpace {
 impl VSpace {
     pub fn new() -> VSpace {
         VSpace {
-            mappings: HashMap::new(),
+            mappings: ModelAddressSpace::default(),
             pml4: Box::pin(
                 [PML4Entry::new(PAddr::from(0x0u64), PML4Flags::empty()); PAGE_SIZE_ENTRIES],
             ),
@@ -54,30 +57,26 @@ impl VSpace {
 
 impl AddressSpace for VSpace {
     fn map_frame(&mut self, base: VAddr, frame: Frame, action: MapAction) -> Result<(), KError> {
-        let ma = MappingInfo::new(frame, action);
-        self.mappings.insert(ma.vrange(base), ma);
-        unimplemented!("map_frame");
+        self.mappings.map_frame(base, frame, action)
     }
 
-    fn map_memory_requirements(_base: VAddr, _frames: &[Frame]) -> usize {
-        unimplemented!("map_memory_requirements");
+    fn map_memory_requirements(base: VAddr, frames: &[Frame]) -> usize {
+        ModelAddressSpace::map_memory_requirements(base, frames)
     }
 
-    fn adjust(&mut self, _vaddr: VAddr, _rights: MapAction) -> Result<(VAddr, usize), KError> {
-        unimplemented!("adjust");
+    fn adjust(&mut self, vaddr: VAddr, rights: MapAction) -> Result<(VAddr, usize), KError> {
+        self.mappings.adjust(vaddr, rights)
     }
 
-    fn resolve(&self, _vaddr: VAddr) -> Result<(PAddr, MapAction), KError> {
-        unimplemented!("resolve");
+    fn resolve(&self, vaddr: VAddr) -> Result<(PAddr, MapAction), KError> {
+        self.mappings.resolve(vaddr)
     }
 
-    fn unmap(&mut self, _vaddr: VAddr) -> Result<TlbFlushHandle, KError> {
-        unimplemented!("unmap");
+    fn extent(&self, vaddr: VAddr) -> Result<(VAddr, usize), KError> {
+        self.mappings.extent(vaddr)
     }
-}
 
-impl Drop for VSpace {
-    fn drop(&mut self) {
-        panic!("Drop for VSpace!");
+    fn unmap(&mut self, vaddr: VAddr) -> Result<TlbFlushHandle, KError> {
+        self.mappings.unmap(vaddr)
     }
 }
@@ -17,7 +17,7 @@ use crate::kcb::Kcb;
 use crate::memory::vspace::MapAction;
 
 use super::kcb::{try_get_kcb, Arch86Kcb};
-use super::memory::{paddr_to_kernel_vaddr, PAddr};
+use super::memory::{paddr_to_kernel_vaddr, PAddr, VAddr};
 
 use x86::io;
 
@@ -43,7 +43,8 @@ pub extern "C" fn AcpiOsGetRootPointer() -> ACPI_PHYSICAL_ADDRESS {
 
     let (rsdp1_root, rsdp2_root) = try_get_kcb().map_or((None, None), |k: &mut Kcb<Arch86Kcb>| {
         let args = k.arch.kernel_args();
-        (Some(args.acpi1_rsdp), Some(args.acpi2_rsdp))
+        let known = |rsdp: PAddr| Some(rsdp).filter(|rsdp| rsdp.as_u64() != 0);
+        (known(args.acpi1_rsdp), known(args.acpi2_rsdp))
     });
 
     trace!("rsdp1 {:?} rsdp2: {:?}", rsdp1_root, rsdp2_root);
@@ -209,8 +210,12 @@ pub unsafe extern "C" fn AcpiOsFree(ptr: *mut u8) {
 #[linkage = "external"]
 pub extern "C" fn AcpiOsMapMemory(location: ACPI_PHYSICAL_ADDRESS, len: ACPI_SIZE) -> *mut c_void {
     trace!("AcpiOsMapMemory(loc = {:#x}, len = {})", location, len);
+    map_physical(PAddr::from(location), len as usize).as_mut_ptr::<c_void>()
+}
 
-    let p = PAddr::from(location);
+/// Makes sure `len` bytes at `p` are mapped in the kernel's physical
+/// memory window and returns where.
+fn map_physical(p: PAddr, len: usize) -> VAddr {
     let adjusted_len = (p - p.align_down_to_base_page().as_usize()) + len;
 
     use crate::round_up;
@@ -226,8 +231,25 @@ pub extern "C" fn AcpiOsMapMemory(location: ACPI_PHYSICAL_ADDRESS, len: ACPI_SIZ
             .expect("Can't map ACPI memory");
     });
 
-    let vaddr = paddr_to_kernel_vaddr(p);
-    vaddr.as_mut_ptr::<c_void>()
+    paddr_to_kernel_vaddr(p)
+}
+
+/// Lets the native table parser (`crate::acpi`) read physical memory.
+pub(crate) struct KernelMemory;
+
+impl crate::acpi::PhysicalMemory for KernelMemory {
+    fn read(&self, paddr: u64, buf: &mut [u8]) {
+        let src = map_physical(PAddr::from(paddr), buf.len());
+        unsafe { ptr::copy_nonoverlapping(src.as_ptr::<u8>(), buf.as_mut_ptr(), buf.len()) };
+    }
+}
+
+/// The RSDP addresses the bootloader got from UEFI (ACPI 2.0 first).
+pub(crate) fn rsdp_hints() -> [u64; 2] {
+    try_get_kcb().map_or([0, 0], |k: &mut Kcb<Arch86Kcb>| {
+        let args = k.arch.kernel_args();
+        [args.acpi2_rsdp.as_u64(), args.acpi1_rsdp.as_u64()]
+    })
 }
 
 #[no_mangle]
@@ -669,3 +691,139 @@ pub(crate) fn init() -> Result<(), ACPI_STATUS> {
 
     Ok(())
 }
+
+/// Finds the ECAM region of PCI segment 0 in the MCFG table.
+///
+/// Returns the physical base address and the first and last bus number
+/// the region decodes.
+pub(crate) fn mcfg() -> Option<(PAddr, u8, u8)> {
+    // Table header (36 bytes) followed by 8 reserved bytes
+    const ALLOCATIONS_OFFSET: usize = 44;
+    const ALLOCATION_SIZE: usize = 16;
+
+    unsafe {
+        let mut signature = *b"MCFG\0";
+        let mut table: *mut ACPI_TABLE_HEADER = ptr::null_mut();
+        let ret = AcpiGetTable(signature.as_mut_ptr() as *mut i8, 1, &mut table);
+        if ret != AE_OK || table.is_null() {
+            debug!("No MCFG table found: {:?}", ret);
+            return None;
+        }
+
+        let len = (*table).Length as usize;
+        let mut offset = ALLOCATIONS_OFFSET;
+        while offset + ALLOCATION_SIZE <= len {
+            let entry = (table as *const u8).add(offset);
+            let base = ptr::read_unaligned(entry as *const u64);
+            let segment = ptr::read_unaligned(entry.add(8) as *const u16);
+            let start_bus = *entry.add(10);
+            let end_bus = *entry.add(11);
+            if segment == 0 {
+                return Some((PAddr::from(base), start_bus, end_bus));
+            }
+            offset += ALLOCATION_SIZE;
+        }
+    }
+
+    None
+}
+
+/// Finds the base address of the first HPET block in the HPET table.
+pub(crate) fn hpet() -> Option<PAddr> {
+    // Table header (36 bytes), event timer block id (4 bytes), then a
+    // generic address structure whose address starts at byte 4
+    const ADDRESS_OFFSET: usize = 44;
+
+    unsafe {
+        let mut signature = *b"HPET\0";
+        let mut table: *mut ACPI_TABLE_HEADER = ptr::null_mut();
+        let ret = AcpiGetTable(signature.as_mut_ptr() as *mut i8, 1, &mut table);
+        if ret != AE_OK || table.is_null() {
+            debug!("No HPET table found: {:?}", ret);
+            return None;
+        }
+
+        if ((*table).Length as usize) < ADDRESS_OFFSET + 8 {
+            return None;
+        }
+        let base = ptr::read_unaligned((table as *const u8).add(ADDRESS_OFFSET) as *const u64);
+        Some(PAddr::from(base))
+    }
+}
+
+/// Suspend-to-RAM.
+const ACPI_STATE_S3: u8 = 3;
+/// The RTC alarm fixed event (wakes us up from S3).
+const ACPI_EVENT_RTC: u32 = 4;
+
+/// Does the firmware tell us how to enter S3 (the `\_S3` package)?
+pub(crate) fn can_suspend() -> bool {
+    let (mut type_a, mut type_b) = (0u8, 0u8);
+    unsafe { AcpiGetSleepTypeData(ACPI_STATE_S3, &mut type_a, &mut type_b) == AE_OK }
+}
+
+/// Runs the `_PTS` method, arms the RTC wake event and sets the address
+/// the firmware jumps to (in real mode) when we wake up.
+pub(crate) fn prepare_suspend(waking_vector: PAddr) -> Result<(), ACPI_STATUS> {
+    unsafe {
+        let ret = AcpiEnterSleepStatePrep(ACPI_STATE_S3);
+        if ret != AE_OK {
+            return Err(ret);
+        }
+        let ret = AcpiSetFirmwareWakingVector(waking_vector.as_u64() as ACPI_PHYSICAL_ADDRESS, 0);
+        if ret != AE_OK {
+            return Err(ret);
+        }
+        AcpiClearEvent(ACPI_EVENT_RTC);
+        let ret = AcpiEnableEvent(ACPI_EVENT_RTC, 0);
+        if ret != AE_OK {
+            return Err(ret);
+        }
+    }
+    Ok(())
+}
+
+/// Enters S3, this only returns if the firmware didn't suspend us (we
+/// come back through the waking vector otherwise).
+///
+/// Interrupts have to be off and the caches written back.
+pub(crate) fn enter_suspend() -> ACPI_STATUS {
+    unsafe { AcpiEnterSleepState(ACPI_STATE_S3) }
+}
+
+/// Runs the wake methods (`_SST`, `_WAK`) after we resumed (or failed to
+/// suspend) and disarms the RTC wake event again.
+pub(crate) fn leave_suspend() {
+    unsafe {
+        AcpiLeaveSleepStatePrep(ACPI_STATE_S3);
+        let ret = AcpiLeaveSleepState(ACPI_STATE_S3);
+        if ret != AE_OK {
+            error!("AcpiLeaveSleepState failed: {:?}", ret);
+        }
+        AcpiDisableEvent(ACPI_EVENT_RTC, 0);
+        AcpiClearEvent(ACPI_EVENT_RTC);
+        AcpiSetFirmwareWakingVector(0, 0);
+    }
+}
+
+/// Soft-off.
+const ACPI_STATE_S5: u8 = 5;
+
+/// Turns the machine off (S5), this only returns if that didn't work.
+///
+/// Interrupts have to be off.
+pub(crate) fn power_off() -> ACPI_STATUS {
+    unsafe {
+        let ret = AcpiEnterSleepStatePrep(ACPI_STATE_S5);
+        if ret != AE_OK {
+            return ret;
+        }
+        AcpiEnterSleepState(ACPI_STATE_S5)
+    }
+}
+
+/// Resets the machine with the reset register from the FADT, this only
+/// returns if there is none (or writing it didn't do anything).
+pub(crate) fn reset() -> ACPI_STATUS {
+    unsafe { AcpiReset() }
+}
@@ -0,0 +1,367 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Control-flow enforcement (CET): shadow stacks and indirect-branch
+//! tracking.
+//!
+//! What we use it for is decided by the `cet` command-line option
+//! (`cmdline::CetPolicy`), on CPUs that don't have it we go on without.
+//!
+//! # Kernel
+//! Every core gets a supervisor shadow stack (and one for each IST stack),
+//! they sit in the guarded-stack windows of the kernel address space (see
+//! `stack::map_guarded`). A supervisor shadow stack starts with a token
+//! that marks it busy while a core runs on it:
+//!
+//!  - `syscall` doesn't switch shadow stacks, `syscall_enter` (`exec.S`)
+//!    does it with `setssbsy` before it calls into rust. Interrupts from
+//!    user-space switch to it in hardware.
+//!  - We never return to user-space along the kernel call-chain, so the
+//!    `Ring3Resumer` first pops everything off the shadow stack
+//!    ([`leave_kernel_shadow_stack`]). `iretq` then releases the token,
+//!    before `sysretq` we release it with `clrssbsy`.
+//!  - The core starts using it in [`enable`], which must not return.
+//!
+//! `KCB.shadow_stack_token` (offset 16, for `exec.S`) is the address of the
+//! token or 0 if the core runs without shadow stacks.
+//!
+//! Indirect-branch tracking needs every target of an indirect call or jump
+//! to start with `endbr64`. The assembly entry points have one, for the
+//! rest the kernel has to be compiled with `-Z cf-protection=branch`, so
+//! the kernel only uses it with the `cet-ibt` feature.
+//!
+//! The GDB stub moves the kernel back to a breakpoint it hit (the shadow
+//! stack doesn't agree), we don't use kernel shadow stacks with it.
+//!
+//! # Processes
+//! With `cet=user` every executor gets a shadow stack in the executor
+//! memory of its process (mapped with `MapAction::ShadowStackUser`, the
+//! process can't make it writable), see `Ring3Executor`. The shadow-stack
+//! pointer and `IA32_U_CET` of an executor leave the core with it
+//! ([`switch`]).
+//!
+//! An upcall runs on the shadow stack of the code it interrupted: we push
+//! the frame an interrupt would have pushed for `enabled_state` and tell
+//! the process where it is (`VirtualCpu::upcall_ssp`). To resume
+//! `enabled_state`, vibrio pops everything up to it and `iretq`s.
+//!
+//! The upcall entry point comes from the (user-writable) vCPU area: with
+//! indirect-branch tracking it must start with `endbr64`, else the process
+//! faults with a #CP there.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use log::info;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+use x86::controlregs::{cr0, cr0_write, cr4, cr4_write, Cr0, Cr4};
+use x86::msr::{rdmsr, wrmsr};
+
+use crate::cmdline::CetPolicy;
+use crate::error::KError;
+use crate::memory::vspace::MapAction;
+use crate::memory::VAddr;
+use crate::stack::map_guarded;
+
+use super::kcb::get_kcb;
+
+const IA32_U_CET: u32 = 0x6a0;
+const IA32_S_CET: u32 = 0x6a2;
+const IA32_PL0_SSP: u32 = 0x6a4;
+const IA32_PL3_SSP: u32 = 0x6a7;
+const IA32_INTERRUPT_SSP_TABLE_ADDR: u32 = 0x6a8;
+
+/// `IA32_U_CET`/`IA32_S_CET`: Shadow stacks.
+const SH_STK_EN: u64 = 1 << 0;
+/// `IA32_U_CET`/`IA32_S_CET`: Indirect-branch tracking.
+const ENDBR_EN: u64 = 1 << 2;
+/// `IA32_U_CET`/`IA32_S_CET`: The next instruction must be an `endbr64`.
+const TRACKER: u64 = 1 << 11;
+
+/// CR4.CET (not in the `x86` crate yet).
+const CR4_CET: usize = 1 << 23;
+
+/// Size of the shadow stack for the kernel stacks of a core.
+const SHADOW_STACK_SIZE: usize = 8 * BASE_PAGE_SIZE;
+/// Size of the shadow stacks for the IST stacks of a core.
+const IST_SHADOW_STACK_SIZE: usize = 2 * BASE_PAGE_SIZE;
+/// The IST stacks we use (see `Arch86Kcb::set_interrupt_stacks`).
+const IST_STACKS: usize = 2;
+
+/// The CS of user-space (in shadow-stack frames).
+const USER_CS: u64 = 27;
+
+/// `IA32_U_CET` of executors (0 without user CET, set by `init`).
+static USER_CET: AtomicU64 = AtomicU64::new(0);
+/// Does the kernel use indirect-branch tracking (set by `init`)?
+static KERNEL_IBT: AtomicBool = AtomicBool::new(false);
+
+/// The shadow stacks of a core.
+#[derive(Debug)]
+pub struct ShadowStacks {
+    /// Address of the token of the shadow stack for the kernel stacks.
+    token: u64,
+    /// `IA32_INTERRUPT_SSP_TABLE`: the tokens for the IST stacks (entry
+    /// `i` is for IST `i`, 0 is unused).
+    ssp_table: Box<[u64; 8]>,
+}
+
+impl ShadowStacks {
+    fn new() -> Result<ShadowStacks, KError> {
+        let mut ssp_table = Box::try_new([0u64; 8])?;
+        for ist in 1..=IST_STACKS {
+            ssp_table[ist] = supervisor_shadow_stack(IST_SHADOW_STACK_SIZE)?;
+        }
+
+        Ok(ShadowStacks {
+            token: supervisor_shadow_stack(SHADOW_STACK_SIZE)?,
+            ssp_table,
+        })
+    }
+
+    /// Address of the token of the shadow stack for the kernel stacks.
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+}
+
+/// Maps a supervisor shadow stack, returns the address of its token.
+fn supervisor_shadow_stack(size: usize) -> Result<u64, KError> {
+    let (limit, frame) = map_guarded(size, MapAction::ShadowStackKernel)?;
+    let size = crate::round_up!(size, BASE_PAGE_SIZE);
+    let token = limit + size - 8usize;
+
+    // The shadow stack isn't writable, so we go through the direct map.
+    // A free token holds its own address.
+    unsafe {
+        let alias = frame.kernel_vaddr() + size - 8usize;
+        *alias.as_mut_ptr::<u64>() = token.as_u64();
+    }
+    Ok(token.as_u64())
+}
+
+/// Does the core have shadow stacks and indirect-branch tracking?
+fn supported() -> (bool, bool) {
+    let features = x86::cpuid::cpuid!(0x7, 0x0);
+    (features.ecx & (1 << 7) != 0, features.edx & (1 << 20) != 0)
+}
+
+/// Sets up CET on the current core (the kernel starts using it with
+/// [`enable`]).
+pub fn init() {
+    let kcb = get_kcb();
+    let policy = kcb.config.cet;
+    let (shstk, ibt) = supported();
+    let kernel_ibt = ibt && cfg!(feature = "cet-ibt");
+    let kernel_shstk = shstk && !super::gdb::ENABLED;
+    let core0 = kcb.arch.id() == 0;
+
+    if policy == CetPolicy::Off || !shstk {
+        if core0 {
+            info!(
+                "Control-flow enforcement: off ({:?}, CPU has it: {})",
+                policy, shstk
+            );
+        }
+        return;
+    }
+
+    unsafe {
+        // Shadow stacks are read-only, the kernel must not write them
+        cr0_write(cr0() | Cr0::CR0_WRITE_PROTECT);
+        cr4_write(cr4() | Cr4::from_bits_unchecked(CR4_CET));
+    }
+
+    if kernel_shstk {
+        let stacks = ShadowStacks::new().expect("Can't allocate the shadow stacks");
+        unsafe {
+            wrmsr(IA32_PL0_SSP, stacks.token);
+            wrmsr(
+                IA32_INTERRUPT_SSP_TABLE_ADDR,
+                stacks.ssp_table.as_ptr() as u64,
+            );
+        }
+        kcb.arch.set_shadow_stacks(stacks);
+    }
+
+    if policy == CetPolicy::User {
+        let mut user_cet = SH_STK_EN;
+        // The process has to be compiled for it too, same as the kernel
+        if kernel_ibt {
+            user_cet |= ENDBR_EN;
+        }
+        USER_CET.store(user_cet, Ordering::Relaxed);
+        unsafe { wrmsr(IA32_U_CET, user_cet) };
+    }
+
+    KERNEL_IBT.store(kernel_ibt, Ordering::Relaxed);
+    if core0 {
+        info!(
+            "Control-flow enforcement: {:?}, kernel shadow stacks {}, indirect-branch tracking {}",
+            policy, kernel_shstk, kernel_ibt
+        );
+    }
+}
+
+/// Starts running the current core on its shadow stack.
+///
+/// The caller must never return (the shadow stack starts out empty). We
+/// call it right before the core goes into `xmain` or the scheduler.
+#[inline(always)]
+pub unsafe fn enable() {
+    let kcb = get_kcb();
+    let token = kcb.arch.shadow_stack_token;
+    let mut s_cet = 0;
+    if KERNEL_IBT.load(Ordering::Relaxed) {
+        s_cet |= ENDBR_EN;
+    }
+    if token != 0 {
+        s_cet |= SH_STK_EN;
+    }
+    if s_cet == 0 {
+        return;
+    }
+
+    // Enabling and switching to the shadow stack has to happen without a
+    // `ret` in between
+    llvm_asm!("
+        wrmsr
+        testq %rdi, %rdi
+        jz 1f
+        setssbsy
+    1:
+        " ::
+        "{ecx}" (IA32_S_CET),
+        "{eax}" (s_cet as u32),
+        "{edx}" ((s_cet >> 32) as u32),
+        "{rdi}" (token)
+        : "memory" : "volatile");
+}
+
+/// Pops everything off the shadow stack of the core, and releases it if
+/// we go to user-space with `sysretq` (`iretq` releases it).
+///
+/// This has to be the last thing before `sysretq`/`iretq` (with no `ret`
+/// in between), see `Ring3Resumer`.
+#[inline(always)]
+pub unsafe fn leave_kernel_shadow_stack(sysret: bool) {
+    let token = get_kcb().arch.shadow_stack_token;
+    if token == 0 {
+        return;
+    }
+
+    // `incsspq` only takes 8 bits of its operand
+    llvm_asm!("
+        rdsspq %rcx
+        movq %rdi, %rax
+        subq %rcx, %rax
+        shrq $$3, %rax
+    1:
+        cmpq $$255, %rax
+        jbe 2f
+        movq $$255, %rcx
+        incsspq %rcx
+        subq $$255, %rax
+        jmp 1b
+    2:
+        incsspq %rax
+        testq %rsi, %rsi
+        jz 3f
+        clrssbsy (%rdi)
+    3:
+        " ::
+        "{rdi}" (token),
+        "{rsi}" (sysret as u64)
+        : "rax", "rcx", "memory" : "volatile");
+}
+
+/// `IA32_U_CET` of executors (0 if processes run without CET).
+pub fn user_cet() -> u64 {
+    USER_CET.load(Ordering::Relaxed)
+}
+
+/// Do processes run with shadow stacks?
+pub fn user_shadow_stacks() -> bool {
+    user_cet() & SH_STK_EN != 0
+}
+
+/// The shadow-stack state of an executor while it doesn't run on a core.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct UserState {
+    /// `IA32_PL3_SSP`
+    ssp: u64,
+    /// `IA32_U_CET` (the state of the indirect-branch tracker).
+    u_cet: u64,
+}
+
+/// Saves the state of the executor that leaves the core in `from` and loads
+/// the state of `to`.
+pub fn switch(from: Option<&mut UserState>, to: &UserState) {
+    if !user_shadow_stacks() {
+        return;
+    }
+
+    unsafe {
+        if let Some(from) = from {
+            from.ssp = rdmsr(IA32_PL3_SSP);
+            from.u_cet = rdmsr(IA32_U_CET);
+        }
+        wrmsr(IA32_PL3_SSP, to.ssp);
+        wrmsr(IA32_U_CET, to.u_cet);
+    }
+}
+
+/// Points the current core to the empty shadow stack at `top` (an
+/// executor starts running on it).
+pub fn start_user(top: VAddr) {
+    if !user_shadow_stacks() {
+        return;
+    }
+
+    unsafe {
+        wrmsr(IA32_PL3_SSP, top.as_u64());
+        wrmsr(IA32_U_CET, entry_cet());
+    }
+}
+
+/// Pushes the frame of an interrupt that hit `rip` on the shadow stack of
+/// the executor on the current core, returns the new shadow-stack pointer
+/// (for `VirtualCpu::upcall_ssp`).
+///
+/// `shadow_stack` is the user address of the shadow stack of the executor
+/// and its kernel alias, in case the frame doesn't fit it returns 0 (and
+/// the process will fault when it resumes `rip`).
+pub fn push_user_frame(rip: u64, shadow_stack: (VAddr, VAddr), size: usize) -> u64 {
+    if !user_shadow_stacks() {
+        return 0;
+    }
+
+    let (base, alias) = shadow_stack;
+    unsafe {
+        let ssp = rdmsr(IA32_PL3_SSP);
+        wrmsr(IA32_U_CET, entry_cet());
+        if ssp < base.as_u64() + 24 || ssp > base.as_u64() + size as u64 || ssp % 8 != 0 {
+            return 0;
+        }
+
+        // Same as an interrupt: CS, LIP and the SSP from before
+        let frame = ssp - 24;
+        let frame_alias = (alias + (frame - base.as_u64())).as_mut_ptr::<u64>();
+        *frame_alias = ssp;
+        *frame_alias.add(1) = rip;
+        *frame_alias.add(2) = USER_CS;
+        wrmsr(IA32_PL3_SSP, frame);
+        frame
+    }
+}
+
+/// `IA32_U_CET` when we go to an entry point of the process (with
+/// indirect-branch tracking, it must start with `endbr64`).
+fn entry_cet() -> u64 {
+    let user_cet = user_cet();
+    if user_cet & ENDBR_EN != 0 {
+        user_cet | TRACKER
+    } else {
+        user_cet
+    }
+}
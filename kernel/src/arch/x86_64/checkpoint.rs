@@ -0,0 +1,463 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Takes checkpoints of processes and restores them.
+//!
+//! `crate::checkpoint` has the image format, here we collect it from a
+//! process that's in the `Process::checkpoint` system call and build a new
+//! process from it for `Process::restore`.
+//!
+//! A checkpoint is taken on the only core of the process (so nothing changes
+//! its memory while we copy it) with all its pages resident. We skip what
+//! the kernel maps on its own: the time page of the vDSO and device
+//! mappings (the identity mappings from `map_device_frame`). Capabilities
+//! other than files aren't saved.
+//!
+//! The restored process gets a core on the node of the executor that made
+//! the checkpoint, `Ring3Executor::start` picks up the registers with
+//! [`take_resume`] and returns from the system call with `ret1` set.
+//!
+//! Restoring expects the same machine: the executors of a process (and so
+//! their vCPU areas and stacks) are where they were, if the topology is the
+//! same. A restore that fails half-way destroys the new process again (with
+//! its memory, files and pid). The restored process can't make system calls
+//! that its checkpoint or the caller of the restore weren't allowed to.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::mem::size_of;
+use core::{ptr, slice};
+
+use fallible_collections::{FallibleVec, FallibleVecGlobal};
+use kpi::filter::SyscallFilter;
+use kpi::io::{FileFlags, FileModes};
+use kpi::SystemCallError;
+use log::{info, warn};
+use spin::Mutex;
+use x86::msr::{rdmsr, wrmsr, IA32_KERNEL_GSBASE};
+
+use crate::cap::{Capability, Object};
+use crate::checkpoint::{self, Header, Image, Mapping, OpenFile};
+use crate::cnrfs::MlnrKernelNode;
+use crate::error::KError;
+use crate::fallible_string::TryString;
+use crate::memory::vspace::MapAction;
+use crate::memory::{Frame, KernelAllocator, VAddr, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+use crate::nr;
+use crate::nrproc::NrProcess;
+use crate::process::{allocate_dispatchers, make_process, Pid};
+use crate::syscall_filter;
+
+use super::kcb::get_kcb;
+use super::memory::paddr_to_kernel_vaddr;
+use super::process::{Ring3Process, INVALID_EXECUTOR_START};
+use super::{cet, swap, user_access, vdso, Module};
+
+/// MXCSR bits `fxrstor` accepts (the rest are reserved and fault).
+const MXCSR_MASK: u32 = 0xffff;
+
+/// Offset of MXCSR in the `fxsave` area.
+const MXCSR_OFFSET: usize = 24;
+
+/// Registers of restored processes that didn't start yet.
+static RESUME: Mutex<Vec<(Pid, kpi::arch::SaveArea)>> = Mutex::new(Vec::new());
+
+/// Finds the module or initrd program `name`, returns it with a name that
+/// `make_process` finds it with.
+fn find_binary(name: &str) -> Option<(&'static str, &'static Module)> {
+    get_kcb()
+        .arch
+        .kernel_args()
+        .modules
+        .iter()
+        .find(|module| module.name() == name)
+        .map(|module| (module.name(), module))
+        .or_else(|| crate::initrd::program(name))
+}
+
+/// The open files of `pid` (what it has file capabilities for).
+fn open_files(pid: Pid) -> Result<Vec<OpenFile>, KError> {
+    let mut files = Vec::new();
+    for (handle, capability) in NrProcess::<Ring3Process>::capabilities(pid)? {
+        match capability.object {
+            Object::File(fd) => match MlnrKernelNode::fd_info(pid, fd) {
+                Ok((path, flags, offset)) => files.try_push(OpenFile {
+                    handle,
+                    rights: capability.rights,
+                    path,
+                    flags,
+                    offset,
+                })?,
+                // Nobody can open it again
+                Err(KError::InvalidFile) => {
+                    warn!("Checkpoint of {} leaves out deleted file {}", pid, fd)
+                }
+                Err(e) => return Err(e),
+            },
+            object => warn!("Checkpoint of {} leaves out {:?}", pid, object),
+        }
+    }
+    Ok(files)
+}
+
+/// The registers `pid` continues with once it's restored: the system call
+/// returns 1.
+fn registers() -> Result<Vec<u8>, KError> {
+    let kcb = get_kcb();
+    let mut state = **kcb.arch.save_area.as_ref().ok_or(KError::ProcessNotSet)?;
+    // The user %gs is in the MSR while we're in the kernel
+    state.gs = unsafe { rdmsr(IA32_KERNEL_GSBASE) };
+    state.set_syscall_ret1(1);
+    state.set_syscall_ret2(0);
+    state.set_syscall_error_code(SystemCallError::Ok);
+
+    let bytes = unsafe {
+        slice::from_raw_parts(
+            &state as *const kpi::arch::SaveArea as *const u8,
+            size_of::<kpi::arch::SaveArea>(),
+        )
+    };
+    let mut registers = Vec::try_with_capacity(bytes.len())?;
+    registers.try_extend_from_slice(bytes)?;
+    Ok(registers)
+}
+
+/// Writes a checkpoint of `pid` (the current process) to `path`.
+pub fn take(pid: Pid, path: String) -> Result<(), KError> {
+    // We'd have to save (and restore) the shadow stacks too
+    if cet::user_shadow_stacks() {
+        return Err(KError::NotSupported);
+    }
+    if NrProcess::<Ring3Process>::active_cores(pid)?.len() != 1 {
+        return Err(KError::CheckpointNeedsOneCore);
+    }
+
+    let kcb = get_kcb();
+    let binary = nr::KernelNode::process(pid)?.binary;
+    let (_name, module) = find_binary(binary).ok_or(KError::BinaryNotFound { binary })?;
+    let pinfo = NrProcess::<Ring3Process>::pinfo(pid)?;
+
+    let header = Header {
+        binary: TryString::try_from(binary)?.into(),
+        binary_hash: checkpoint::hash(unsafe { module.as_slice() }),
+        creds: pinfo.creds,
+        layout: pinfo.layout,
+        eid: kcb.arch.current_executor()?.eid,
+        mmap_next: NrProcess::<Ring3Process>::mmap_next(pid)?.as_u64(),
+        filter: syscall_filter::current(pid),
+        registers: registers()?,
+    };
+    let files = open_files(pid)?;
+
+    // Nothing gets evicted until we wrote the contents
+    let _resident = swap::resident(pid)?;
+    // Every replica has its own dirty bits, with more than one we don't know
+    // which pages are clean
+    let save_all = atopology::MACHINE_TOPOLOGY.num_nodes() > 1;
+    let regions = NrProcess::<Ring3Process>::regions(pid)?;
+    let mut mappings = Vec::try_with_capacity(regions.len())?;
+    for region in regions.iter() {
+        let base = region.base.as_u64();
+        if base == vdso::TIME_PAGE || region.frame.base.as_u64() == base {
+            continue;
+        }
+        let size = region.frame.size();
+        let data = if region.dirty || save_all {
+            Some(unsafe { slice::from_raw_parts(region.frame.kernel_vaddr().as_ptr::<u8>(), size) })
+        } else {
+            None
+        };
+        mappings.try_push(Mapping {
+            base,
+            size: size as u64,
+            rights: region.rights,
+            anonymous: swap::is_anonymous(pid, base),
+            data,
+        })?;
+    }
+
+    let flags = FileFlags::O_WRONLY | FileFlags::O_CREAT | FileFlags::O_TRUNC;
+    let modes = FileModes::S_IRUSR | FileModes::S_IWUSR;
+    let fd = MlnrKernelNode::open(pid, path, flags.into(), modes.into())?;
+    let written = checkpoint::encode(&header, &files, &mappings, |chunk| {
+        let mut contents = Vec::try_with_capacity(chunk.len())?;
+        contents.try_extend_from_slice(chunk)?;
+        MlnrKernelNode::write(pid, fd, Arc::from(contents)).map(|_len| ())
+    });
+    MlnrKernelNode::unmap_fd(pid, fd)?;
+
+    info!(
+        "Checkpoint of {}: {} bytes, {} mappings, {} files",
+        pid,
+        written?,
+        mappings.len(),
+        files.len()
+    );
+    Ok(())
+}
+
+/// The registers of a checkpoint, so they're safe to `sysret` with.
+fn resume_state(registers: &[u8]) -> Result<kpi::arch::SaveArea, KError> {
+    if registers.len() != size_of::<kpi::arch::SaveArea>() {
+        return Err(KError::InvalidCheckpoint);
+    }
+    let mut state = kpi::arch::SaveArea::empty();
+    unsafe {
+        ptr::copy_nonoverlapping(
+            registers.as_ptr(),
+            &mut state as *mut kpi::arch::SaveArea as *mut u8,
+            registers.len(),
+        )
+    };
+
+    // A non-canonical %rip faults in the kernel after `sysretq`, a bad %fs
+    // or %gs when we write it
+    let user = |addr: u64| addr < kpi::KERNEL_BASE;
+    if !user(state.rip) || !user(state.fs) || !user(state.gs) {
+        return Err(KError::InvalidCheckpoint);
+    }
+    let mxcsr = &mut state.fxsave[MXCSR_OFFSET..MXCSR_OFFSET + 4];
+    let masked = u32::from_le_bytes([mxcsr[0], mxcsr[1], mxcsr[2], mxcsr[3]]) & MXCSR_MASK;
+    mxcsr.copy_from_slice(&masked.to_le_bytes());
+    Ok(state)
+}
+
+/// Allocates zeroed frames for `size` bytes at `base` (large pages if it
+/// fits them).
+fn allocate_frames(base: u64, size: u64) -> Result<Vec<Frame>, KError> {
+    let large = base % LARGE_PAGE_SIZE as u64 == 0 && size % LARGE_PAGE_SIZE as u64 == 0;
+    let page = if large {
+        LARGE_PAGE_SIZE
+    } else {
+        BASE_PAGE_SIZE
+    };
+
+    let mut frames = Vec::try_with_capacity(size as usize / page)?;
+    let allocated = (0..size as usize / page).try_for_each(|_| {
+        if large {
+            KernelAllocator::try_refill_tcache(0, 1)?;
+        } else {
+            KernelAllocator::try_refill_tcache(1, 0)?;
+        }
+        let mut frame = {
+            let mut pmanager = get_kcb().mem_manager();
+            if large {
+                pmanager.allocate_large_page()?
+            } else {
+                pmanager.allocate_base_page()?
+            }
+        };
+        unsafe { frame.zero() };
+        frames.try_push(frame).map_err(KError::from)
+    });
+
+    if let Err(e) = allocated {
+        release_frames(&frames);
+        return Err(e);
+    }
+    Ok(frames)
+}
+
+/// Gives `frames` back to the memory manager.
+fn release_frames(frames: &[Frame]) {
+    for frame in frames {
+        if let Err(e) = crate::memory::release_frame(*frame) {
+            warn!("Leaking {:?}: {}", frame, e);
+        }
+    }
+}
+
+/// Puts `mapping` into the new process `pid`, adds the frames it allocates
+/// to `frames`.
+fn restore_mapping(pid: Pid, mapping: &Mapping, frames: &mut Vec<Frame>) -> Result<(), KError> {
+    let base = VAddr::from(mapping.base);
+    match NrProcess::<Ring3Process>::mapping(pid, base) {
+        // The binary, the executors and the stacks are there already
+        Ok((_paddr, rights)) => {
+            if rights != mapping.rights {
+                let _handle = NrProcess::<Ring3Process>::adjust(pid, base, mapping.rights)?;
+            }
+            if let Some(data) = mapping.data {
+                for (offset, page) in data.chunks(BASE_PAGE_SIZE).enumerate() {
+                    let va = base + offset * BASE_PAGE_SIZE;
+                    let (paddr, _rights) = NrProcess::<Ring3Process>::mapping(pid, va)?;
+                    let kernel = paddr_to_kernel_vaddr(paddr);
+                    unsafe {
+                        ptr::copy_nonoverlapping(
+                            page.as_ptr(),
+                            kernel.as_mut_ptr::<u8>(),
+                            page.len(),
+                        )
+                    };
+                }
+            }
+        }
+        Err(KError::NotMapped) => {
+            let allocated = allocate_frames(mapping.base, mapping.size)?;
+            if let Err(e) = frames.try_reserve(allocated.len()) {
+                release_frames(&allocated);
+                return Err(e.into());
+            }
+            frames.extend_from_slice(&allocated);
+            if let Some(data) = mapping.data {
+                let mut data = data;
+                for frame in allocated.iter() {
+                    let (contents, rest) = data.split_at(frame.size());
+                    unsafe {
+                        ptr::copy_nonoverlapping(
+                            contents.as_ptr(),
+                            frame.kernel_vaddr().as_mut_ptr::<u8>(),
+                            contents.len(),
+                        )
+                    };
+                    data = rest;
+                }
+            }
+            NrProcess::<Ring3Process>::map_frames(pid, base, allocated, mapping.rights)?;
+        }
+        Err(e) => return Err(e),
+    }
+
+    // The next checkpoint has to save it again
+    if mapping.data.is_some() {
+        for offset in (0..mapping.size as usize).step_by(BASE_PAGE_SIZE) {
+            NrProcess::<Ring3Process>::set_dirty(pid, base + offset)?;
+        }
+    }
+    Ok(())
+}
+
+/// Restores the checkpoint at `image` (`len` bytes of the current process),
+/// returns the pid of the new process.
+pub fn restore(image: u64, len: usize) -> Result<Pid, KError> {
+    let kcb = get_kcb();
+    let caller = kcb.current_pid()?;
+
+    let mut data: Vec<u8> = Vec::try_with_capacity(len)?;
+    data.resize(len, 0);
+    user_access::copy_in(&mut data, image)?;
+    let image = checkpoint::decode(&data)?;
+    let header = &image.header;
+
+    // Only root restores processes that run as someone else
+    let creds = NrProcess::<Ring3Process>::credentials(caller)?;
+    if header.creds != creds && !creds.is_privileged() {
+        return Err(KError::NotPrivileged);
+    }
+    let (binary, module) = find_binary(&header.binary).ok_or(KError::InvalidCheckpoint)?;
+    if checkpoint::hash(unsafe { module.as_slice() }) != header.binary_hash {
+        return Err(KError::InvalidCheckpoint);
+    }
+    let state = resume_state(&header.registers)?;
+    RESUME.lock().try_reserve(1)?;
+
+    let pid = make_process::<Ring3Process>(binary, header.creds, Some(header.layout))?;
+    let mut frames = Vec::new();
+    if let Err(e) = populate(pid, &image, caller, state, &mut frames) {
+        discard(pid, &frames);
+        return Err(e);
+    }
+
+    // Not before, `discard` releases the frames we mapped (an eviction
+    // would swap them out). A new process has no anonymous mappings of its
+    // own, `restore_mapping` made these.
+    for mapping in image.mappings.iter().filter(|mapping| mapping.anonymous) {
+        if let Err(e) = swap::anonymous(pid, mapping.base..mapping.base + mapping.size) {
+            warn!("{:#x} of {} stays resident: {}", mapping.base, pid, e);
+        }
+    }
+
+    info!(
+        "Restored {} as {}: {} mappings, {} files",
+        header.binary,
+        pid,
+        image.mappings.len(),
+        image.files.len()
+    );
+    Ok(pid)
+}
+
+/// Puts the checkpoint `image` into the new process `pid` and gives it a
+/// core, adds the frames it allocates for mappings to `frames`.
+fn populate(
+    pid: Pid,
+    image: &Image,
+    caller: Pid,
+    state: kpi::arch::SaveArea,
+    frames: &mut Vec<Frame>,
+) -> Result<(), KError> {
+    let header = &image.header;
+    allocate_dispatchers::<Ring3Process>(pid)?;
+
+    for mapping in image.mappings.iter() {
+        restore_mapping(pid, mapping, frames)?;
+    }
+    let next = NrProcess::<Ring3Process>::mmap_next(pid)?.as_u64();
+    if header.mmap_next > next {
+        NrProcess::<Ring3Process>::reserve(pid, (header.mmap_next - next) as usize)?;
+    }
+    for file in image.files.iter() {
+        let fd = MlnrKernelNode::restore_fd(pid, file.path.clone(), file.flags, file.offset)?;
+        let capability = Capability::new(Object::File(fd), file.rights);
+        NrProcess::<Ring3Process>::insert_capability_at(pid, file.handle, capability)?;
+    }
+
+    // It gets neither more than it had nor more than the caller has
+    // (filters only lose bits)
+    if let Some(operations) = header.filter {
+        syscall_filter::restrict(pid, &SyscallFilter::from_operations(operations));
+    }
+    if let Some(operations) = syscall_filter::current(caller) {
+        syscall_filter::restrict(pid, &SyscallFilter::from_operations(operations));
+    }
+
+    RESUME.lock().try_push((pid, state))?;
+    let node = NrProcess::<Ring3Process>::prefer_executor(pid, header.eid)?;
+    nr::KernelNode::allocate_core_to_process(pid, INVALID_EXECUTOR_START, Some(node), None)?;
+    Ok(())
+}
+
+/// Removes process `pid` after a restore failed, releases `frames` (the ones
+/// `populate` allocated for its mappings).
+///
+/// It never got a core, so no TLB has its translations.
+fn discard(pid: Pid, frames: &[Frame]) {
+    RESUME.lock().retain(|(other, _state)| *other != pid);
+
+    match NrProcess::<Ring3Process>::destroy(pid) {
+        Ok(owned) => {
+            release_frames(&owned);
+            release_frames(frames);
+        }
+        Err(e) => {
+            // Its pid can't be loaded again either
+            warn!("Leaking {} after a failed restore: {}", pid, e);
+            return;
+        }
+    }
+    if let Err(e) = MlnrKernelNode::remove_process(pid) {
+        warn!("Can't close the files of {}: {}", pid, e);
+    }
+    if let Err(e) = nr::KernelNode::free_pid(pid) {
+        warn!("Can't free {} after a failed restore: {}", pid, e);
+    }
+}
+
+/// The registers restored process `pid` starts with (once).
+pub fn take_resume(pid: Pid) -> Option<kpi::arch::SaveArea> {
+    let mut resume = RESUME.lock();
+    let i = resume.iter().position(|(other, _state)| *other == pid)?;
+    Some(resume.swap_remove(i).1)
+}
+
+/// Makes the current core continue with `state` (see [`take_resume`]).
+pub fn load_resume(state: &kpi::arch::SaveArea) -> *const kpi::arch::SaveArea {
+    let kcb = get_kcb();
+    unsafe { wrmsr(IA32_KERNEL_GSBASE, state.gs) };
+    if let Some(save_area) = kcb.arch.save_area.as_mut() {
+        **save_area = *state;
+    }
+    kcb.arch.get_save_area_ptr()
+}
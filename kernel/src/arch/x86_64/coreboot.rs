@@ -5,9 +5,15 @@
 //!
 //! This code is closely intertwingled with the assembly code in `start_ap.S`,
 //! make sure these two files are and stay in sync.
+//!
+//! The BSP wakes up every enabled processor in the MADT one after the other
+//! (see `boot_app_cores`). Application cores mark themselves online once
+//! their KCB is set up and then wait in `rendezvous` until the BSP is done
+//! booting everyone.
 
 use alloc::sync::Arc;
-use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
 
 use apic::ApicDriver;
 use log::trace;
@@ -20,6 +26,7 @@ use crate::stack::Stack;
 
 use super::kcb;
 use super::memory::BASE_PAGE_SIZE;
+use super::MAX_CORES;
 
 /// The 16-bit segement where our bootstrap code is.
 const X86_64_REAL_MODE_SEGMENT: u16 = 0x0600;
@@ -236,40 +243,51 @@ unsafe fn get_boostrap_code_region() -> &'static mut [u8] {
     real_mode_destination
 }
 
-/// Wakes up (resets) a core by sending a sequence of IPIs (INIT, INIT deassert, STARTUP).
+/// Busy-waits for `duration` (interrupts are still off while we boot cores).
+fn spin_for(duration: Duration) {
+    let start = rawtime::Instant::now();
+    while start.elapsed() < duration {
+        core::hint::spin_loop();
+    }
+}
+
+/// Wakes up (resets) a core by sending a sequence of IPIs (INIT, INIT
+/// deassert, STARTUP, STARTUP).
 ///
 /// # Notes
-/// x86 specification technically requires to sleep between init and startup, but on most
-/// modern processors (Xeon Phi being an exception) this is not really necessary.
+/// This follows the universal start-up algorithm from the Intel SDM. The
+/// second STARTUP is ignored by a core that already left the wait-for-SIPI
+/// state.
 ///
 /// # Safety
 /// Can easily reset the wrong core (bad for memory safety).
 unsafe fn wakeup_core(core_id: ApicId) {
     let kcb = kcb::get_kcb();
 
-    // x86 core boot protocol, without sleeping:
     kcb.arch.apic().ipi_init(core_id);
     kcb.arch.apic().ipi_init_deassert();
+    spin_for(Duration::from_millis(10));
 
-    let start = rawtime::Instant::now();
-    while start.elapsed().as_millis() > 10 {}
-
+    kcb.arch.apic().ipi_startup(core_id, REAL_MODE_PAGE);
+    spin_for(Duration::from_micros(200));
     kcb.arch.apic().ipi_startup(core_id, REAL_MODE_PAGE);
 }
 
-/// Starts up the core identified by `core_id`, after initialization it begins
-/// to executing in `init_function` and uses `stack` as a stack.
+/// Puts the bootstrap code in place so that a core entering it in real
+/// mode begins executing in `init_function` on `stack`.
+///
+/// Returns the physical address of the bootstrap code (the firmware waking
+/// vector when we resume from suspend, see `suspend`).
 ///
 /// # Safety
-/// You're waking up a core that goes off and does random things
-/// (if not being careful), so this can be pretty bad for memory safety.
-pub unsafe fn initialize<A>(
-    core_id: x86::apic::ApicId,
+/// Same as `initialize`: whoever enters the code next goes off with
+/// `args` and `stack`.
+pub unsafe fn prepare<A>(
     init_function: fn(Arc<A>, &AtomicBool),
     args: Arc<A>,
     initialized: &AtomicBool,
     stack: &dyn Stack,
-) {
+) -> PAddr {
     // Make sure bootsrap code is at correct location in memory
     copy_bootstrap_code();
 
@@ -283,6 +301,94 @@ pub unsafe fn initialize<A>(
         stack.base() as u64,
     );
 
+    PAddr::from(REAL_MODE_BASE as u64)
+}
+
+/// Starts up the core identified by `core_id`, after initialization it begins
+/// to executing in `init_function` and uses `stack` as a stack.
+///
+/// # Safety
+/// You're waking up a core that goes off and does random things
+/// (if not being careful), so this can be pretty bad for memory safety.
+pub unsafe fn initialize<A>(
+    core_id: x86::apic::ApicId,
+    init_function: fn(Arc<A>, &AtomicBool),
+    args: Arc<A>,
+    initialized: &AtomicBool,
+    stack: &dyn Stack,
+) {
+    prepare(init_function, args, initialized, stack);
+
     // Send IPIs
     wakeup_core(core_id);
 }
+
+#[allow(clippy::declare_interior_mutable_const)]
+const OFFLINE: AtomicBool = AtomicBool::new(false);
+/// Hardware threads that finished their initialization (indexed by
+/// `atopology::ThreadId`).
+static ONLINE: [AtomicBool; MAX_CORES] = [OFFLINE; MAX_CORES];
+
+/// Set by the BSP once it booted all application cores.
+#[cfg(not(feature = "bsp-only"))]
+static RELEASED: AtomicBool = AtomicBool::new(false);
+
+/// Hardware threads to boot: the processors the MADT says are enabled
+/// (every thread in the topology if there is no MADT), except for the BSP.
+#[cfg(not(feature = "bsp-only"))]
+pub fn application_cores() -> impl Iterator<Item = &'static atopology::Thread> {
+    let bsp_thread = atopology::MACHINE_TOPOLOGY.current_thread();
+    let madt = crate::acpi::platform().map(|platform| &platform.madt);
+
+    atopology::MACHINE_TOPOLOGY.threads().filter(move |t| {
+        let apic_id = match t.apic_id() {
+            ApicId::XApic(id) => id as u32,
+            ApicId::X2Apic(id) => id,
+        };
+        t != &bsp_thread
+            && madt.map_or(true, |madt| {
+                madt.cpus
+                    .iter()
+                    .any(|cpu| cpu.enabled && cpu.apic_id == apic_id)
+            })
+    })
+}
+
+/// Records that `thread` is up and running.
+pub fn mark_online(thread: atopology::ThreadId) {
+    ONLINE[thread as usize].store(true, Ordering::Release);
+}
+
+/// Records that `thread` went away (see `hotplug`).
+pub fn mark_offline(thread: atopology::ThreadId) {
+    ONLINE[thread as usize].store(false, Ordering::Release);
+}
+
+pub fn is_online(thread: atopology::ThreadId) -> bool {
+    ONLINE
+        .get(thread as usize)
+        .map_or(false, |online| online.load(Ordering::Acquire))
+}
+
+/// How many hardware threads are online (including the BSP).
+#[cfg(not(feature = "bsp-only"))]
+pub fn online_cores() -> usize {
+    ONLINE
+        .iter()
+        .filter(|online| online.load(Ordering::Acquire))
+        .count()
+}
+
+/// Waits until the BSP booted all application cores.
+#[cfg(not(feature = "bsp-only"))]
+pub fn rendezvous() {
+    while !RELEASED.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+}
+
+/// Lets the application cores waiting in `rendezvous` continue.
+#[cfg(not(feature = "bsp-only"))]
+pub fn release() {
+    RELEASED.store(true, Ordering::Release);
+}
@@ -0,0 +1,245 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Frequency scaling with Enhanced SpeedStep (`crate::cpufreq` decides).
+//!
+//! A core asks for a multiple of the 100 MHz bus clock (the ratio) in
+//! `IA32_PERF_CTL` and `IA32_PERF_STATUS` has the one it runs at.
+//! `MSR_PLATFORM_INFO` has the lowest ratio and the highest one without
+//! turbo, `MSR_TURBO_RATIO_LIMIT` the highest one with a single core busy.
+//! We don't do ACPI `_PSS` (AMD) or hardware-controlled P-states: if the
+//! firmware turned on HWP the frequency is up to the hardware. In a virtual
+//! machine it's up to the host.
+//!
+//! The MSRs belong to the core, so a core only programs its own: `set` for
+//! another core records the frequency and kicks it, it applies it in
+//! `sample` (which interrupt handlers call).
+
+use core::time::Duration;
+
+use kpi::system::CoreFrequency;
+use log::info;
+use spin::{Mutex, Once};
+use x86::msr::{rdmsr, wrmsr};
+use x86::time::rdtsc;
+
+use super::kcb::get_kcb;
+use super::{futex, hypervisor, timer, tsc, MAX_CORES};
+use crate::cmdline::CpufreqPolicy;
+use crate::cpufreq::{self, Sampler, SAMPLE_INTERVAL};
+use crate::error::KError;
+use crate::time::clocksource::{duration_to_ticks, ticks_to_duration};
+
+const IA32_PERF_STATUS: u32 = 0x198;
+const IA32_PERF_CTL: u32 = 0x199;
+const MSR_PLATFORM_INFO: u32 = 0xce;
+const MSR_TURBO_RATIO_LIMIT: u32 = 0x1ad;
+const IA32_PM_ENABLE: u32 = 0x770;
+
+/// Keeps the core from going above the ratio in `IA32_PERF_CTL`.
+const PERF_CTL_TURBO_DISENGAGE: u64 = 1 << 32;
+
+const BUS_MHZ: u32 = 100;
+
+/// The ratios the cores have.
+#[derive(Debug, Copy, Clone)]
+struct Ratios {
+    min: u32,
+    base: u32,
+    max: u32,
+}
+
+impl Ratios {
+    fn detect() -> Option<Ratios> {
+        let est = x86::cpuid::cpuid!(0x1).ecx & (1 << 7) != 0;
+        if !est || hypervisor::present() {
+            return None;
+        }
+        let power = x86::cpuid::cpuid!(0x6).eax;
+        let hwp = power & (1 << 7) != 0;
+        if hwp && unsafe { rdmsr(IA32_PM_ENABLE) } & 0x1 != 0 {
+            return None;
+        }
+
+        let info = unsafe { rdmsr(MSR_PLATFORM_INFO) };
+        let base = ((info >> 8) & 0xff) as u32;
+        let min = ((info >> 40) & 0xff) as u32;
+        if min == 0 || min > base {
+            return None;
+        }
+        let turbo = power & (1 << 1) != 0;
+        let max = if turbo {
+            ((unsafe { rdmsr(MSR_TURBO_RATIO_LIMIT) } & 0xff) as u32).max(base)
+        } else {
+            base
+        };
+        Some(Ratios { min, base, max })
+    }
+}
+
+/// The ratios, `None` if we don't scale frequencies.
+static RATIOS: Once<Option<Ratios>> = Once::new();
+
+fn ratios() -> Result<Ratios, KError> {
+    RATIOS
+        .get()
+        .copied()
+        .flatten()
+        .ok_or(KError::CpufreqUnavailable)
+}
+
+/// Frequency scaling state of a core (in MHz).
+struct Core {
+    sampler: Sampler,
+    /// What a process pinned it at.
+    pinned: Option<u32>,
+    /// What we asked for last (0 if we didn't yet).
+    target: u32,
+    /// What it ran at when it last sampled.
+    current: u32,
+    /// The governor wants to sample again soon.
+    sample_soon: bool,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNTOUCHED: Mutex<Core> = Mutex::new(Core {
+    sampler: Sampler::new(),
+    pinned: None,
+    target: 0,
+    current: 0,
+    sample_soon: false,
+});
+static CORES: [Mutex<Core>; MAX_CORES] = [UNTOUCHED; MAX_CORES];
+
+/// Finds out if we can scale the frequency of the cores (on the first core
+/// that calls it).
+pub fn init() {
+    let kcb = get_kcb();
+    let ratios = RATIOS.call_once(|| match kcb.config.cpufreq {
+        CpufreqPolicy::Off => None,
+        _ => Ratios::detect(),
+    });
+    if kcb.arch.id() == 0 {
+        match ratios {
+            Some(r) => info!(
+                "cpufreq: {:?}, {}-{} MHz (base {} MHz)",
+                kcb.config.cpufreq,
+                r.min * BUS_MHZ,
+                r.max * BUS_MHZ,
+                r.base * BUS_MHZ
+            ),
+            None => info!("cpufreq: off ({:?})", kcb.config.cpufreq),
+        }
+    }
+}
+
+/// Programs the ratio for `mhz` on the current core.
+fn program(ratios: Ratios, mhz: u32) {
+    let ratio = (mhz / BUS_MHZ).clamp(ratios.min, ratios.max);
+    let mut ctl = (ratio as u64) << 8;
+    // Pinned at or below base, turbo would make runs less reproducible
+    if ratio <= ratios.base {
+        ctl |= PERF_CTL_TURBO_DISENGAGE;
+    }
+    unsafe { wrmsr(IA32_PERF_CTL, ctl) };
+}
+
+/// Sets the frequency of the current core: the one it's pinned at, or what
+/// the governor says for the load since the last sample.
+pub fn sample() {
+    let ratios = match ratios() {
+        Ok(ratios) => ratios,
+        Err(_e) => return,
+    };
+    let kcb = get_kcb();
+    let now = unsafe { rdtsc() };
+    let now_ns = ticks_to_duration(now, tsc::frequency()).as_nanos() as u64;
+    let (min, max) = (ratios.min * BUS_MHZ, ratios.max * BUS_MHZ);
+
+    let mut core = CORES[kcb.arch.id()].lock();
+    let load = core.sampler.sample(now_ns, kcb.stats.idle_ns());
+    let target = match (core.pinned, kcb.config.cpufreq) {
+        (Some(mhz), _) => Some(mhz),
+        (None, CpufreqPolicy::Performance) => Some(max),
+        (None, _) => load.map(|load| cpufreq::ondemand(load, min, max)),
+    };
+
+    let changed = match target {
+        Some(mhz) if mhz != core.target => {
+            program(ratios, mhz);
+            core.target = mhz;
+            true
+        }
+        _ => false,
+    };
+    if changed || load.is_some() {
+        let status = unsafe { rdmsr(IA32_PERF_STATUS) };
+        core.current = ((status >> 8) & 0xff) as u32 * BUS_MHZ;
+    }
+
+    // A busy core that runs slow may not take an interrupt for a while
+    core.sample_soon =
+        core.pinned.is_none() && kcb.config.cpufreq == CpufreqPolicy::Ondemand && core.target < max;
+    if core.sample_soon
+        && timer::next() > now + duration_to_ticks(SAMPLE_INTERVAL, tsc::frequency())
+    {
+        timer::set(SAMPLE_INTERVAL);
+    }
+}
+
+/// How soon the governor wants the timer of the current core to go off (if
+/// it does).
+pub fn next_sample() -> Option<Duration> {
+    let core = CORES[get_kcb().arch.id()].lock();
+    if core.sample_soon {
+        Some(SAMPLE_INTERVAL)
+    } else {
+        None
+    }
+}
+
+/// Fails unless `gtid` is a core of the machine.
+fn check_core(gtid: usize) -> Result<(), KError> {
+    if gtid < atopology::MACHINE_TOPOLOGY.num_threads() && gtid < MAX_CORES {
+        Ok(())
+    } else {
+        Err(KError::InvalidGlobalThreadId)
+    }
+}
+
+/// The frequency of core `gtid`.
+pub fn get(gtid: usize) -> Result<CoreFrequency, KError> {
+    let ratios = ratios()?;
+    check_core(gtid)?;
+    let core = CORES[gtid].lock();
+    Ok(CoreFrequency {
+        id: gtid,
+        current: core.current,
+        target: core.target,
+        min: ratios.min * BUS_MHZ,
+        base: ratios.base * BUS_MHZ,
+        max: ratios.max * BUS_MHZ,
+        pinned: core.pinned.is_some(),
+    })
+}
+
+/// Pins core `gtid` at `mhz` (`None` unpins it), the core switches the
+/// next time it samples.
+pub fn set(gtid: usize, mhz: Option<u32>) -> Result<(), KError> {
+    let ratios = ratios()?;
+    check_core(gtid)?;
+    if let Some(mhz) = mhz {
+        if mhz < ratios.min * BUS_MHZ || mhz >= (ratios.max + 1) * BUS_MHZ {
+            return Err(KError::InvalidFrequency);
+        }
+    }
+    // Rounded down to what we program
+    CORES[gtid].lock().pinned = mhz.map(|mhz| mhz / BUS_MHZ * BUS_MHZ);
+
+    if gtid == get_kcb().arch.id() {
+        sample();
+    } else {
+        futex::kick(gtid);
+    }
+    Ok(())
+}
@@ -0,0 +1,684 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Crash dumps for post-mortem analysis.
+//!
+//! With `crashdump=<target>` on the command line a panic writes a dump
+//! before the machine shuts down. The target is either a region at the end
+//! of a block device (`nvme0`, the last `REGION_SIZE` bytes), a region at a
+//! given block (`'nvme0:2048'`) or the first persistent memory range in the
+//! UEFI memory map (`pmem`). `crashdump.py` extracts it again.
+//!
+//! The dump has a one-page header followed by sections:
+//!
+//! ```text
+//! header:  magic "NRKDUMP1", version: u32, sections: u32, length: u64,
+//!          kernel_elf_offset: u64, panicked_core: u32, cores: u32, tsc: u64
+//! section: kind: u32, core: u32, length: u64, payload (padded to 8 bytes)
+//! ```
+//!
+//! All integers are little-endian. The header is written last, a dump that
+//! was cut short doesn't have a valid magic.
+//!
+//! Registers of the other cores are collected with an NMI, they stay
+//! parked in the NMI handler afterwards.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use klogger::sprintln;
+use log::info;
+use spin::{Mutex, Once};
+use uefi::table::boot::MemoryType;
+
+use crate::dmesg;
+use crate::drivers::block::{self, BlockDevice};
+use crate::error::KError;
+use crate::memory::vspace::MapAction;
+use crate::memory::{PAddr, BASE_PAGE_SIZE};
+
+use super::irq::InterruptFrame;
+use super::kcb::get_kcb;
+use super::{coreboot, watchdog, MAX_CORES};
+
+/// First bytes of a valid dump.
+pub const MAGIC: &[u8; 8] = b"NRKDUMP1";
+/// Version of the format described above.
+pub const VERSION: u32 = 1;
+
+/// Size of the region at the end of a block device (if no block is given).
+pub const REGION_SIZE: usize = 4 * 1024 * 1024;
+/// We write in chunks of this size (the header takes one).
+const CHUNK: usize = 4096;
+
+/// How much of the panicking stack we write.
+const STACK_SIZE: u64 = 64 * 1024;
+/// How long we wait for the other cores to save their registers.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The panic message (UTF-8).
+pub const SECTION_MESSAGE: u32 = 1;
+/// `REGISTERS` (as u64) of a core.
+pub const SECTION_REGISTERS: u32 = 2;
+/// The address of the lowest byte (u64) and the stack contents above it.
+pub const SECTION_STACK: u32 = 3;
+/// The kernel log ring (UTF-8).
+pub const SECTION_LOG: u32 = 4;
+/// UEFI memory map, entries are type: u32, pad: u32, start: u64,
+/// pages: u64, attributes: u64.
+pub const SECTION_MEMORY_MAP: u32 = 5;
+
+/// Registers in the order of a `SECTION_REGISTERS` payload.
+pub const REGISTERS: [&str; 22] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15", "rip", "rflags", "cs", "ss", "cr2", "cr3",
+];
+
+/// Register state of a core, see `REGISTERS`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Registers([u64; 22]);
+
+impl Registers {
+    fn from_frame(frame: &InterruptFrame) -> Registers {
+        let (cr2, cr3) = control_registers();
+        Registers([
+            frame.rax,
+            frame.rbx,
+            frame.rcx,
+            frame.rdx,
+            frame.rsi,
+            frame.rdi,
+            frame.rbp,
+            frame.rsp,
+            frame.r8,
+            frame.r9,
+            frame.r10,
+            frame.r11,
+            frame.r12,
+            frame.r13,
+            frame.r14,
+            frame.r15,
+            frame.rip,
+            frame.rflags,
+            frame.cs,
+            frame.ss,
+            cr2,
+            cr3,
+        ])
+    }
+
+    /// Where we are right now, we only know the registers that matter
+    /// for unwinding.
+    #[inline(always)]
+    fn current() -> Registers {
+        use x86::current::registers::{rbp, rip, rsp};
+
+        let (cr2, cr3) = control_registers();
+        let mut regs = Registers::default();
+        regs.0[6] = rbp();
+        regs.0[7] = rsp();
+        regs.0[16] = rip();
+        regs.0[20] = cr2;
+        regs.0[21] = cr3;
+        regs
+    }
+
+    fn rbp(&self) -> u64 {
+        self.0[6]
+    }
+
+    fn rsp(&self) -> u64 {
+        self.0[7]
+    }
+}
+
+#[cfg(target_os = "none")]
+fn control_registers() -> (u64, u64) {
+    unsafe { (x86::controlregs::cr2() as u64, x86::controlregs::cr3()) }
+}
+
+#[cfg(not(target_os = "none"))]
+fn control_registers() -> (u64, u64) {
+    (0, 0)
+}
+
+/// What `crashdump=` asked for.
+#[derive(Debug, PartialEq)]
+enum Spec<'a> {
+    Pmem,
+    Block { name: &'a str, lba: Option<u64> },
+}
+
+impl<'a> Spec<'a> {
+    fn parse(spec: &'a str) -> Result<Spec<'a>, KError> {
+        let mut parts = spec.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let lba = match parts.next() {
+            Some(lba) => Some(
+                lba.parse::<u64>()
+                    .map_err(|_| KError::InvalidCrashDumpTarget)?,
+            ),
+            None => None,
+        };
+
+        match (name, lba) {
+            ("", _) | ("pmem", Some(_)) => Err(KError::InvalidCrashDumpTarget),
+            ("pmem", None) => Ok(Spec::Pmem),
+            (name, lba) => Ok(Spec::Block { name, lba }),
+        }
+    }
+}
+
+/// Where the dump goes.
+trait Sink {
+    /// How many bytes fit.
+    fn capacity(&self) -> usize;
+
+    /// Writes `data` (a multiple of `CHUNK`) at `offset` (in bytes).
+    fn write(&self, offset: usize, data: &[u8]) -> Result<(), KError>;
+
+    /// Makes sure everything is on stable storage.
+    fn flush(&self) -> Result<(), KError>;
+}
+
+/// A region of a block device.
+struct BlockRegion {
+    dev: Arc<dyn BlockDevice>,
+    lba: u64,
+    blocks: u64,
+}
+
+impl BlockRegion {
+    fn new(dev: Arc<dyn BlockDevice>, lba: Option<u64>) -> Result<BlockRegion, KError> {
+        let bs = dev.block_size();
+        if CHUNK % bs != 0 {
+            return Err(KError::NotSupported);
+        }
+        let blocks = (REGION_SIZE / bs) as u64;
+        let lba = match lba {
+            Some(lba) => lba,
+            None => dev
+                .num_blocks()
+                .checked_sub(blocks)
+                .ok_or(KError::InvalidBlockRange)?,
+        };
+        block::check_range(&*dev, lba, REGION_SIZE)?;
+        Ok(BlockRegion { dev, lba, blocks })
+    }
+}
+
+impl Sink for BlockRegion {
+    fn capacity(&self) -> usize {
+        self.blocks as usize * self.dev.block_size()
+    }
+
+    fn write(&self, offset: usize, data: &[u8]) -> Result<(), KError> {
+        self.dev
+            .write(self.lba + (offset / self.dev.block_size()) as u64, data)
+    }
+
+    fn flush(&self) -> Result<(), KError> {
+        self.dev.flush()
+    }
+}
+
+/// Persistent memory (mapped in the kernel address space).
+struct Pmem {
+    base: PAddr,
+    size: usize,
+}
+
+impl Sink for Pmem {
+    fn capacity(&self) -> usize {
+        self.size
+    }
+
+    fn write(&self, offset: usize, data: &[u8]) -> Result<(), KError> {
+        let dst = (self.base.as_usize() + offset) as *mut u8;
+        unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len());
+            for line in (0..data.len()).step_by(64) {
+                core::arch::x86_64::_mm_clflush(dst.add(line));
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), KError> {
+        unsafe { core::arch::x86_64::_mm_sfence() };
+        Ok(())
+    }
+}
+
+/// Assembles the dump in `CHUNK`s and writes them to a `Sink`.
+struct Writer<'a> {
+    sink: &'a dyn Sink,
+    buffer: [u8; CHUNK],
+    /// Bytes in `buffer`.
+    fill: usize,
+    /// Where `buffer` goes.
+    offset: usize,
+    /// Completely written sections.
+    sections: u32,
+    /// Bytes in completely written sections.
+    length: u64,
+    /// Bytes of the current section.
+    pending: u64,
+}
+
+impl<'a> Writer<'a> {
+    fn new(sink: &'a dyn Sink) -> Writer<'a> {
+        Writer {
+            sink,
+            buffer: [0; CHUNK],
+            fill: 0,
+            offset: CHUNK,
+            sections: 0,
+            length: 0,
+            pending: 0,
+        }
+    }
+
+    fn put(&mut self, mut bytes: &[u8]) -> Result<(), KError> {
+        self.pending += bytes.len() as u64;
+        while !bytes.is_empty() {
+            let n = bytes.len().min(CHUNK - self.fill);
+            self.buffer[self.fill..self.fill + n].copy_from_slice(&bytes[..n]);
+            self.fill += n;
+            bytes = &bytes[n..];
+            if self.fill == CHUNK {
+                self.write_buffer()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_buffer(&mut self) -> Result<(), KError> {
+        if self.offset + CHUNK > self.sink.capacity() {
+            return Err(KError::CapacityOverflow);
+        }
+        self.buffer[self.fill..].fill(0);
+        self.sink.write(self.offset, &self.buffer)?;
+        self.offset += CHUNK;
+        self.fill = 0;
+        Ok(())
+    }
+
+    /// Starts a section with `length` bytes of payload (fails if it
+    /// doesn't fit anymore).
+    fn begin(&mut self, kind: u32, core: u32, length: usize) -> Result<(), KError> {
+        if self.offset + self.fill + 16 + length > self.sink.capacity() {
+            return Err(KError::CapacityOverflow);
+        }
+        self.pending = 0;
+        self.put(&kind.to_le_bytes())?;
+        self.put(&core.to_le_bytes())?;
+        self.put(&(length as u64).to_le_bytes())
+    }
+
+    fn end(&mut self) -> Result<(), KError> {
+        let padding = (8 - self.pending % 8) % 8;
+        self.put(&[0; 8][..padding as usize])?;
+        self.sections += 1;
+        self.length += self.pending;
+        Ok(())
+    }
+
+    fn section(&mut self, kind: u32, core: u32, payload: &[u8]) -> Result<(), KError> {
+        self.begin(kind, core, payload.len())?;
+        self.put(payload)?;
+        self.end()
+    }
+
+    fn registers(&mut self, core: usize, regs: &Registers) -> Result<(), KError> {
+        self.begin(SECTION_REGISTERS, core as u32, regs.0.len() * 8)?;
+        for reg in regs.0.iter() {
+            self.put(&reg.to_le_bytes())?;
+        }
+        self.end()
+    }
+
+    /// Writes what's left in the buffer and the header, sections that
+    /// didn't fit are left out.
+    fn finish(&mut self, header: &Header) -> Result<(), KError> {
+        if self.fill > 0 {
+            // Might fail if we're full, the section count excludes it
+            let _ = self.write_buffer();
+        }
+        self.sink.flush()?;
+
+        let mut page = [0u8; CHUNK];
+        header.encode(self.sections, self.length, &mut page);
+        self.sink.write(0, &page)?;
+        self.sink.flush()
+    }
+}
+
+/// What goes in the header (besides what the `Writer` counts).
+struct Header {
+    kernel_elf_offset: u64,
+    panicked_core: u32,
+    cores: u32,
+    tsc: u64,
+}
+
+impl Header {
+    fn encode(&self, sections: u32, length: u64, page: &mut [u8]) {
+        page[0..8].copy_from_slice(MAGIC);
+        page[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        page[12..16].copy_from_slice(&sections.to_le_bytes());
+        page[16..24].copy_from_slice(&length.to_le_bytes());
+        page[24..32].copy_from_slice(&self.kernel_elf_offset.to_le_bytes());
+        page[32..36].copy_from_slice(&self.panicked_core.to_le_bytes());
+        page[36..40].copy_from_slice(&self.cores.to_le_bytes());
+        page[40..48].copy_from_slice(&self.tsc.to_le_bytes());
+    }
+}
+
+/// Where we write the dump (set by `init`).
+static TARGET: Once<&'static (dyn Sink + Send + Sync)> = Once::new();
+
+/// We're collecting registers, NMIs are for us.
+static COLLECTING: AtomicBool = AtomicBool::new(false);
+/// The core that writes the dump.
+static PANICKED: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_SAVED: AtomicBool = AtomicBool::new(false);
+/// Cores that saved their registers in `SAVED_REGISTERS`.
+static SAVED: [AtomicBool; MAX_CORES] = [NOT_SAVED; MAX_CORES];
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_REGISTERS: Mutex<Registers> = Mutex::new(Registers([0; 22]));
+static SAVED_REGISTERS: [Mutex<Registers>; MAX_CORES] = [NO_REGISTERS; MAX_CORES];
+
+/// Finds the target `spec` (needs the block device drivers and the kernel
+/// address space) and starts keeping the end of the log.
+pub fn init(spec: &str) -> Result<(), KError> {
+    let target: &'static (dyn Sink + Send + Sync) = match Spec::parse(spec)? {
+        Spec::Pmem => {
+            let region = get_kcb()
+                .arch
+                .kernel_args()
+                .mm_iter
+                .iter()
+                .find(|r| r.ty == MemoryType::PERSISTENT_MEMORY)
+                .ok_or(KError::CrashDumpTargetNotFound)?;
+            let base = PAddr::from(region.phys_start);
+            let size = region.page_count as usize * BASE_PAGE_SIZE;
+            get_kcb()
+                .arch
+                .init_vspace()
+                .map_identity(base, size, MapAction::ReadWriteKernel)?;
+            Box::leak(Box::try_new(Pmem { base, size })?)
+        }
+        Spec::Block { name, lba } => {
+            let dev = block::get(name).ok_or(KError::CrashDumpTargetNotFound)?;
+            Box::leak(Box::try_new(BlockRegion::new(dev, lba)?)?)
+        }
+    };
+
+    TARGET.call_once(|| target);
+    info!(
+        "Crash dumps go to {} ({} KiB)",
+        spec,
+        target.capacity() / 1024
+    );
+    Ok(())
+}
+
+/// Called for every NMI, saves the registers and parks the core if we
+/// collect them for a dump (returns false if the NMI isn't ours).
+pub fn handle_nmi(frame: &InterruptFrame) -> bool {
+    if !COLLECTING.load(Ordering::Acquire) {
+        return false;
+    }
+    let core = get_kcb().arch.id();
+    if core == PANICKED.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    *SAVED_REGISTERS[core].lock() = Registers::from_frame(frame);
+    SAVED[core].store(true, Ordering::Release);
+    loop {
+        unsafe { x86::halt() };
+    }
+}
+
+/// Stops the other cores and waits until they saved their registers,
+/// returns the cores that did.
+fn collect_registers(me: usize) -> [bool; MAX_CORES] {
+    PANICKED.store(me, Ordering::Relaxed);
+    COLLECTING.store(true, Ordering::Release);
+
+    let mut asked = [false; MAX_CORES];
+    for thread in atopology::MACHINE_TOPOLOGY.threads() {
+        if thread.id != me && coreboot::is_online(thread.id) {
+            watchdog::send_nmi(thread.id);
+            asked[thread.id] = true;
+        }
+    }
+
+    let start = rawtime::Instant::now();
+    let done = || {
+        asked
+            .iter()
+            .zip(SAVED.iter())
+            .all(|(asked, saved)| !asked || saved.load(Ordering::Acquire))
+    };
+    while !done() && start.elapsed() < TIMEOUT {
+        core::hint::spin_loop();
+    }
+
+    let mut saved = [false; MAX_CORES];
+    for (core, s) in SAVED.iter().enumerate() {
+        saved[core] = s.load(Ordering::Acquire);
+    }
+    saved
+}
+
+/// The part of the stack at `regs` that has frames in it (as far as the
+/// frame pointers go, at most `STACK_SIZE`).
+fn stack_extent(regs: &Registers) -> (u64, usize) {
+    let low = regs.rsp();
+    let limit = low.saturating_add(STACK_SIZE);
+
+    let mut high = low;
+    let mut rbp = regs.rbp();
+    while rbp >= high && rbp.saturating_add(16) <= limit && rbp % 8 == 0 {
+        high = rbp + 16;
+        // Safe: between `rsp` and `limit` is our stack
+        rbp = unsafe { *(rbp as *const u64) };
+    }
+    (low, (high - low) as usize)
+}
+
+/// Writes a dump if we have a target, `message` describes the panic.
+///
+/// Called once (by the panic handler), other cores don't come back.
+#[inline(never)]
+pub fn write(message: &str) {
+    let target = match TARGET.get() {
+        Some(target) => *target,
+        None => return,
+    };
+    let regs = Registers::current();
+
+    let kcb = get_kcb();
+    let me = kcb.arch.id();
+    let saved = collect_registers(me);
+
+    let mut writer = Writer::new(target);
+    let r = (|| -> Result<(), KError> {
+        writer.section(SECTION_MESSAGE, me as u32, message.as_bytes())?;
+        writer.registers(me, &regs)?;
+        for (core, saved) in saved.iter().enumerate() {
+            if *saved {
+                let regs = *SAVED_REGISTERS[core].lock();
+                writer.registers(core, &regs)?;
+            }
+        }
+
+        let (low, len) = stack_extent(&regs);
+        writer.begin(SECTION_STACK, me as u32, 8 + len)?;
+        writer.put(&low.to_le_bytes())?;
+        // Safe: `stack_extent` stays within our stack
+        writer.put(unsafe { core::slice::from_raw_parts(low as *const u8, len) })?;
+        writer.end()?;
+
+        // We might have panicked while logging
+        dmesg::try_with_contents(|old, new| {
+            writer.begin(SECTION_LOG, me as u32, old.len() + new.len())?;
+            writer.put(old)?;
+            writer.put(new)?;
+            writer.end()
+        })
+        .unwrap_or(Ok(()))?;
+
+        let memory_map = &kcb.arch.kernel_args().mm_iter;
+        writer.begin(SECTION_MEMORY_MAP, me as u32, memory_map.len() * 32)?;
+        for region in memory_map.iter() {
+            writer.put(&region.ty.0.to_le_bytes())?;
+            writer.put(&0u32.to_le_bytes())?;
+            writer.put(&region.phys_start.to_le_bytes())?;
+            writer.put(&region.page_count.to_le_bytes())?;
+            writer.put(&region.att.bits().to_le_bytes())?;
+        }
+        writer.end()
+    })();
+    if let Err(e) = r {
+        sprintln!("[crashdump] dump incomplete: {}", e);
+    }
+
+    let header = Header {
+        kernel_elf_offset: kcb.arch.kernel_args().kernel_elf_offset.as_u64(),
+        panicked_core: me as u32,
+        cores: atopology::MACHINE_TOPOLOGY.num_threads() as u32,
+        tsc: unsafe { x86::time::rdtsc() },
+    };
+    match writer.finish(&header) {
+        Ok(()) => sprintln!(
+            "[crashdump] wrote {} sections ({} bytes)",
+            writer.sections,
+            writer.length
+        ),
+        Err(e) => sprintln!("[crashdump] unable to write dump: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    /// A sink in memory.
+    struct Memory(Mutex<Vec<u8>>);
+
+    impl Sink for Memory {
+        fn capacity(&self) -> usize {
+            self.0.lock().len()
+        }
+
+        fn write(&self, offset: usize, data: &[u8]) -> Result<(), KError> {
+            assert_eq!(data.len() % CHUNK, 0);
+            self.0.lock()[offset..offset + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), KError> {
+            Ok(())
+        }
+    }
+
+    fn header() -> Header {
+        Header {
+            kernel_elf_offset: 0x4000_0000,
+            panicked_core: 1,
+            cores: 2,
+            tsc: 7,
+        }
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ])
+    }
+
+    #[test]
+    fn parse_spec() {
+        assert_eq!(Spec::parse("pmem"), Ok(Spec::Pmem));
+        assert_eq!(
+            Spec::parse("nvme0"),
+            Ok(Spec::Block {
+                name: "nvme0",
+                lba: None
+            })
+        );
+        assert_eq!(
+            Spec::parse("sata0:2048"),
+            Ok(Spec::Block {
+                name: "sata0",
+                lba: Some(2048)
+            })
+        );
+        assert_eq!(Spec::parse(""), Err(KError::InvalidCrashDumpTarget));
+        assert_eq!(Spec::parse(":1"), Err(KError::InvalidCrashDumpTarget));
+        assert_eq!(Spec::parse("pmem:1"), Err(KError::InvalidCrashDumpTarget));
+        assert_eq!(Spec::parse("nvme0:x"), Err(KError::InvalidCrashDumpTarget));
+    }
+
+    #[test]
+    fn sections() {
+        let sink = Memory(Mutex::new(vec![0xff; 4 * CHUNK]));
+        let mut writer = Writer::new(&sink);
+        writer.section(SECTION_MESSAGE, 1, b"oops").unwrap();
+        let mut regs = Registers::default();
+        regs.0[16] = 0xdead;
+        writer.registers(0, &regs).unwrap();
+        writer.finish(&header()).unwrap();
+
+        let data = sink.0.lock();
+        assert_eq!(&data[0..8], MAGIC);
+        assert_eq!(u32_at(&data, 8), VERSION);
+        assert_eq!(u32_at(&data, 12), 2);
+        assert_eq!(u32_at(&data, 16), 16 + 4 + 4 + 16 + 22 * 8);
+        assert_eq!(u32_at(&data, 32), 1);
+        assert_eq!(u32_at(&data, 36), 2);
+
+        let s = CHUNK;
+        assert_eq!(u32_at(&data, s), SECTION_MESSAGE);
+        assert_eq!(u32_at(&data, s + 4), 1);
+        assert_eq!(u32_at(&data, s + 8), 4);
+        assert_eq!(&data[s + 16..s + 20], b"oops");
+        assert_eq!(&data[s + 20..s + 24], &[0; 4]);
+
+        let s = CHUNK + 24;
+        assert_eq!(u32_at(&data, s), SECTION_REGISTERS);
+        assert_eq!(u32_at(&data, s + 4), 0);
+        assert_eq!(u32_at(&data, s + 8), 22 * 8);
+        assert_eq!(u32_at(&data, s + 16 + 16 * 8), 0xdead);
+    }
+
+    #[test]
+    fn sections_that_dont_fit() {
+        let sink = Memory(Mutex::new(vec![0; 2 * CHUNK]));
+        let mut writer = Writer::new(&sink);
+        writer.section(SECTION_MESSAGE, 0, b"fits").unwrap();
+        assert_eq!(
+            writer.section(SECTION_LOG, 0, &[b'x'; CHUNK]),
+            Err(KError::CapacityOverflow)
+        );
+        writer.finish(&header()).unwrap();
+
+        let data = sink.0.lock();
+        assert_eq!(&data[0..8], MAGIC);
+        assert_eq!(u32_at(&data, 12), 1);
+        assert_eq!(u32_at(&data, 16), 16 + 8);
+    }
+}
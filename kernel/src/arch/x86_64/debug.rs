@@ -62,20 +62,32 @@ pub unsafe fn putb(b: u8) {
     io::outb(PORT2, b);
 }
 
+/// Reads a byte from the second serial port (if one arrived), it's reserved
+/// for the GDB stub (see `gdb`).
+pub fn com2_getb() -> Option<u8> {
+    unsafe {
+        if io::inb(PORT2 + 5) & 0x1 != 0 {
+            Some(io::inb(PORT2))
+        } else {
+            None
+        }
+    }
+}
+
+/// Writes a byte to the second serial port.
+pub fn com2_putb(b: u8) {
+    unsafe {
+        while (io::inb(PORT2 + 5) & 0x20) == 0 {}
+        io::outb(PORT2, b);
+    }
+}
+
 /// Shutdown the processor.
 ///
 /// Currently we only support the debug exit method from qemu, which conveniently
 /// allows us to supply an exit code for testing purposes.
 pub fn shutdown(val: ExitReason) -> ! {
-    unsafe {
-        // For QEMU with debug-exit,iobase=0xf4,iosize=0x04
-        // qemu will call: exit((val << 1) | 1);
-        io::outb(0xf4, val as u8);
-    }
-
-    // For CI run.py bare-metal execution, parses exit code
-    // (Do not change this line without adjusting run.py)
-    sprintln!("[shutdown-request] {}", val as u8);
+    request_exit(val as u8);
 
     // TODO(bare-metal): Do some ACPI magic to shutdown things
 
@@ -85,6 +97,21 @@ pub fn shutdown(val: ExitReason) -> ! {
     }
 }
 
+/// Tells whoever runs us that we're done and with which exit `code`.
+///
+/// Doesn't return under QEMU, does return on bare-metal machines.
+pub fn request_exit(code: u8) {
+    unsafe {
+        // For QEMU with debug-exit,iobase=0xf4,iosize=0x04
+        // qemu will call: exit((val << 1) | 1);
+        io::outb(0xf4, code);
+    }
+
+    // For CI run.py bare-metal execution, parses exit code
+    // (Do not change this line without adjusting run.py)
+    sprintln!("[shutdown-request] {}", code);
+}
+
 #[cfg(any(
     feature = "test-pfault-early",
     all(feature = "integration-test", feature = "test-pfault")
@@ -123,6 +150,15 @@ pub fn cause_double_fault() {
     }
 }
 
+/// Raises #MC without an error logged (the handler should notice and
+/// continue).
+#[cfg(all(feature = "integration-test", feature = "test-mce"))]
+pub fn cause_machine_check() {
+    unsafe {
+        x86::int!(18);
+    }
+}
+
 /// Verify that we're actually using the fault-stack
 /// as part of the test
 #[cfg(feature = "test-double-fault")]
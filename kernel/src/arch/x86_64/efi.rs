@@ -0,0 +1,518 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! UEFI runtime services: the firmware's clock, reset and variables.
+//!
+//! Our bootloader passes us the physical address of the UEFI system table.
+//! `init` maps the memory regions the firmware needs at runtime into the
+//! kernel's physical memory window (at `KERNEL_BASE` + physical address)
+//! and tells the firmware to use these addresses from now on
+//! (SetVirtualAddressMap). This has to happen while the bootloader's
+//! identity mapping is still there, the firmware runs the call at its
+//! physical address. Afterwards the services work in every address space
+//! as they all share the kernel half.
+//!
+//! The firmware isn't reentrant, `RUNTIME` serializes the calls.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::mem::size_of;
+use core::ptr;
+use core::time::Duration;
+
+use arrayvec::ArrayVec;
+use bootloader_shared::KernelArgs;
+use fallible_collections::vec::FallibleVec;
+use log::{debug, info};
+use uefi::table::boot::{MemoryAttribute, MemoryDescriptor, MemoryType};
+
+use crate::error::KError;
+use crate::memory::vspace::MapAction;
+use crate::mutex::Mutex;
+use crate::time::DateTime;
+
+use super::kcb::get_kcb;
+use super::memory::{paddr_to_kernel_vaddr, PAddr, BASE_PAGE_SIZE, KERNEL_BASE};
+
+/// "IBI SYST"
+const SYSTEM_TABLE_SIGNATURE: u64 = 0x5453_5953_2049_4249;
+/// "RUNTSERV"
+const RUNTIME_SERVICES_SIGNATURE: u64 = 0x5652_4553_544e_5552;
+/// Version of the memory descriptors we pass to SetVirtualAddressMap.
+const DESCRIPTOR_VERSION: u32 = 1;
+
+type Status = usize;
+
+const SUCCESS: Status = 0;
+const ERROR: Status = 1 << 63;
+const UNSUPPORTED: Status = ERROR | 3;
+const BUFFER_TOO_SMALL: Status = ERROR | 5;
+const NOT_FOUND: Status = ERROR | 14;
+
+/// `Time::time_zone` if the time is local time.
+const UNSPECIFIED_TIMEZONE: i16 = 0x07ff;
+
+/// Longest variable name we handle (in UCS-2 characters, with the NUL).
+pub const MAX_NAME_LEN: usize = 128;
+
+/// Variable attribute: keep the variable across resets.
+pub const NON_VOLATILE: u32 = 0x1;
+/// Variable attribute: the variable is there before ExitBootServices.
+pub const BOOTSERVICE_ACCESS: u32 = 0x2;
+/// Variable attribute: the variable is there after ExitBootServices.
+pub const RUNTIME_ACCESS: u32 = 0x4;
+
+/// Who a variable belongs to.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Guid {
+    data1: u32,
+    data2: u16,
+    data3: u16,
+    data4: [u8; 8],
+}
+
+impl Guid {
+    pub const fn new(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Guid {
+        Guid {
+            data1,
+            data2,
+            data3,
+            data4,
+        }
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let d = &self.data4;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            self.data1, self.data2, self.data3, d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]
+        )
+    }
+}
+
+/// The variables the spec defines (`BootOrder`, `PlatformLang`, ...).
+pub const GLOBAL_VARIABLE: Guid = Guid::new(
+    0x8be4_df61,
+    0x93ca,
+    0x11d2,
+    [0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c],
+);
+
+/// How to reset the machine.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum ResetType {
+    Cold = 0,
+    Warm = 1,
+    Shutdown = 2,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct Time {
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    _pad1: u8,
+    nanosecond: u32,
+    /// Minutes local time is ahead of UTC (`UNSPECIFIED_TIMEZONE` if we
+    /// don't know).
+    time_zone: i16,
+    daylight: u8,
+    _pad2: u8,
+}
+
+impl Time {
+    /// Time since the UNIX epoch (we take local time for UTC if the
+    /// firmware doesn't know the time zone).
+    fn since_epoch(&self) -> Duration {
+        let local = DateTime {
+            year: self.year as u32,
+            month: self.month as u32,
+            day: self.day as u32,
+            hour: self.hour as u32,
+            minute: self.minute as u32,
+            second: self.second as u32,
+        }
+        .unix_time() as i64;
+        // Local time = UTC + time zone (UEFI 2.7 and later)
+        let utc = if self.time_zone == UNSPECIFIED_TIMEZONE {
+            local
+        } else {
+            local - self.time_zone as i64 * 60
+        };
+        Duration::new(utc.max(0) as u64, self.nanosecond)
+    }
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct TimeCapabilities {
+    resolution: u32,
+    accuracy: u32,
+    sets_to_zero: u8,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct TableHeader {
+    signature: u64,
+    revision: u32,
+    header_size: u32,
+    crc32: u32,
+    _reserved: u32,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct SystemTable {
+    header: TableHeader,
+    firmware_vendor: u64,
+    firmware_revision: u32,
+    console_in_handle: u64,
+    console_in: u64,
+    console_out_handle: u64,
+    console_out: u64,
+    standard_error_handle: u64,
+    standard_error: u64,
+    runtime_services: u64,
+    boot_services: u64,
+    number_of_table_entries: usize,
+    configuration_table: u64,
+}
+
+/// The services we use (the others are just addresses).
+#[allow(dead_code)]
+#[repr(C)]
+struct RuntimeServices {
+    header: TableHeader,
+    get_time: extern "win64" fn(time: *mut Time, capabilities: *mut TimeCapabilities) -> Status,
+    set_time: usize,
+    get_wakeup_time: usize,
+    set_wakeup_time: usize,
+    set_virtual_address_map: extern "win64" fn(
+        map_size: usize,
+        descriptor_size: usize,
+        descriptor_version: u32,
+        map: *mut MemoryDescriptor,
+    ) -> Status,
+    convert_pointer: usize,
+    get_variable: extern "win64" fn(
+        name: *const u16,
+        guid: *const Guid,
+        attributes: *mut u32,
+        size: *mut usize,
+        data: *mut u8,
+    ) -> Status,
+    get_next_variable_name:
+        extern "win64" fn(name_size: *mut usize, name: *mut u16, guid: *mut Guid) -> Status,
+    set_variable: extern "win64" fn(
+        name: *const u16,
+        guid: *const Guid,
+        attributes: u32,
+        size: usize,
+        data: *const u8,
+    ) -> Status,
+    get_next_high_monotonic_count: usize,
+    reset_system: extern "win64" fn(kind: u32, status: Status, size: usize, data: *const u8),
+    update_capsule: usize,
+    query_capsule_capabilities: usize,
+    query_variable_info: usize,
+}
+
+static RUNTIME: Mutex<Option<&'static RuntimeServices>> = Mutex::new("efi_runtime", None);
+
+fn check(status: Status) -> Result<(), KError> {
+    match status {
+        SUCCESS => Ok(()),
+        NOT_FOUND => Err(KError::EfiVariableNotFound),
+        UNSUPPORTED => Err(KError::EfiUnavailable),
+        status => Err(KError::EfiError { status }),
+    }
+}
+
+/// The regions of `memory_map` the firmware needs at runtime, with the
+/// virtual addresses we map them at.
+fn runtime_regions(memory_map: &[MemoryDescriptor]) -> Result<Vec<MemoryDescriptor>, KError> {
+    let mut regions = Vec::new();
+    for region in memory_map
+        .iter()
+        .filter(|region| region.att.contains(MemoryAttribute::RUNTIME))
+    {
+        let mut region = *region;
+        region.virt_start = KERNEL_BASE + region.phys_start;
+        regions.try_push(region)?;
+    }
+    Ok(regions)
+}
+
+/// Is `paddr` in one of `regions`?
+fn contains(regions: &[MemoryDescriptor], paddr: u64) -> bool {
+    regions.iter().any(|region| {
+        paddr >= region.phys_start
+            && paddr < region.phys_start + region.page_count * BASE_PAGE_SIZE as u64
+    })
+}
+
+/// Maps the runtime regions and switches the firmware to them (needs the
+/// identity mapping of the bootloader).
+pub fn init(args: &KernelArgs) -> Result<(), KError> {
+    let system_table = args.uefi_system_table.as_u64();
+    if system_table == 0 {
+        return Err(KError::EfiUnavailable);
+    }
+
+    let mut regions = runtime_regions(&args.mm_iter)?;
+    if !contains(&regions, system_table) {
+        return Err(KError::EfiUnavailable);
+    }
+    {
+        let mut vspace = get_kcb().arch.init_vspace();
+        for region in regions.iter() {
+            let action = match region.ty {
+                MemoryType::RUNTIME_SERVICES_CODE => MapAction::ReadExecuteKernel,
+                _ => MapAction::ReadWriteKernel,
+            };
+            vspace.map_identity_with_offset(
+                PAddr::from(KERNEL_BASE),
+                PAddr::from(region.phys_start),
+                region.page_count as usize * BASE_PAGE_SIZE,
+                action,
+            )?;
+        }
+    }
+
+    let table = paddr_to_kernel_vaddr(PAddr::from(system_table)).as_ptr::<SystemTable>();
+    let runtime = unsafe {
+        if (*table).header.signature != SYSTEM_TABLE_SIGNATURE {
+            return Err(KError::EfiUnavailable);
+        }
+        let physical = ptr::read_volatile(&(*table).runtime_services);
+        if !contains(&regions, physical) {
+            return Err(KError::EfiUnavailable);
+        }
+        &*paddr_to_kernel_vaddr(PAddr::from(physical)).as_ptr::<RuntimeServices>()
+    };
+    if runtime.header.signature != RUNTIME_SERVICES_SIGNATURE {
+        return Err(KError::EfiUnavailable);
+    }
+
+    debug!("Switching {} UEFI runtime regions", regions.len());
+    check((runtime.set_virtual_address_map)(
+        regions.len() * size_of::<MemoryDescriptor>(),
+        size_of::<MemoryDescriptor>(),
+        DESCRIPTOR_VERSION,
+        regions.as_mut_ptr(),
+    ))?;
+
+    // The firmware changed the pointers in the tables to the virtual
+    // addresses we gave it
+    let runtime = unsafe {
+        let virtual_address = ptr::read_volatile(&(*table).runtime_services);
+        &*(virtual_address as *const RuntimeServices)
+    };
+    *RUNTIME.lock() = Some(runtime);
+    info!(
+        "UEFI runtime services (revision {:#x})",
+        runtime.header.revision
+    );
+
+    if let Err(e) = crate::procfs::register("/proc/efivars", proc_efivars) {
+        debug!("Unable to register /proc/efivars: {}", e);
+    }
+    Ok(())
+}
+
+fn with_runtime<T>(f: impl FnOnce(&RuntimeServices) -> T) -> Result<T, KError> {
+    let runtime = RUNTIME.lock();
+    let runtime = runtime.ok_or(KError::EfiUnavailable)?;
+    Ok(f(runtime))
+}
+
+/// Wall-clock time from the firmware (as time since the UNIX epoch).
+pub fn time() -> Result<Duration, KError> {
+    let mut time = Time::default();
+    let mut capabilities = TimeCapabilities::default();
+    check(with_runtime(|runtime| {
+        (runtime.get_time)(&mut time, &mut capabilities)
+    })?)?;
+    Ok(time.since_epoch())
+}
+
+/// Resets (or turns off) the machine, returns why that didn't work.
+pub fn reset(kind: ResetType) -> KError {
+    match with_runtime(|runtime| (runtime.reset_system)(kind as u32, SUCCESS, 0, ptr::null())) {
+        Ok(()) => KError::EfiError { status: SUCCESS },
+        Err(e) => e,
+    }
+}
+
+/// `name` as a NUL-terminated UCS-2 string.
+fn ucs2(name: &str) -> Result<ArrayVec<u16, MAX_NAME_LEN>, KError> {
+    let mut encoded = ArrayVec::new();
+    for c in name.encode_utf16().chain(core::iter::once(0)) {
+        // UCS-2 has no surrogates (characters outside the BMP)
+        if (0xd800..0xe000).contains(&c) {
+            return Err(KError::InvalidEfiVariableName);
+        }
+        encoded
+            .try_push(c)
+            .map_err(|_e| KError::InvalidEfiVariableName)?;
+    }
+    Ok(encoded)
+}
+
+/// The (NUL-terminated) UCS-2 string `name` as a string.
+fn from_ucs2(name: &[u16]) -> String {
+    let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+    core::char::decode_utf16(name[..len].iter().cloned())
+        .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// Reads variable `name` of `guid` into `data`, returns its size and
+/// attributes.
+///
+/// Fails with `EfiBufferTooSmall` (that has the size) if `data` can't hold
+/// it.
+pub fn variable(guid: &Guid, name: &str, data: &mut [u8]) -> Result<(usize, u32), KError> {
+    let name = ucs2(name)?;
+    let mut attributes = 0;
+    let mut size = data.len();
+    let status = with_runtime(|runtime| {
+        (runtime.get_variable)(
+            name.as_ptr(),
+            guid,
+            &mut attributes,
+            &mut size,
+            data.as_mut_ptr(),
+        )
+    })?;
+    if status == BUFFER_TOO_SMALL {
+        return Err(KError::EfiBufferTooSmall { needed: size });
+    }
+    check(status)?;
+    Ok((size, attributes))
+}
+
+/// Writes variable `name` of `guid` (no `data` deletes it).
+pub fn set_variable(guid: &Guid, name: &str, attributes: u32, data: &[u8]) -> Result<(), KError> {
+    let name = ucs2(name)?;
+    check(with_runtime(|runtime| {
+        (runtime.set_variable)(name.as_ptr(), guid, attributes, data.len(), data.as_ptr())
+    })?)
+}
+
+/// All variables there are (after ExitBootServices).
+pub fn variables() -> Result<Vec<(Guid, String)>, KError> {
+    let mut variables = Vec::new();
+    let mut name = [0u16; MAX_NAME_LEN];
+    let mut guid = Guid::new(0, 0, 0, [0; 8]);
+    loop {
+        let mut size = size_of::<[u16; MAX_NAME_LEN]>();
+        let status = with_runtime(|runtime| {
+            (runtime.get_next_variable_name)(&mut size, name.as_mut_ptr(), &mut guid)
+        })?;
+        match status {
+            NOT_FOUND => return Ok(variables),
+            BUFFER_TOO_SMALL => return Err(KError::InvalidEfiVariableName),
+            status => check(status)?,
+        }
+        variables.try_push((guid, from_ucs2(&name)))?;
+    }
+}
+
+/// Generates `/proc/efivars` (`<name>-<guid> <size>` for every variable).
+fn proc_efivars(out: &mut String) -> fmt::Result {
+    for (guid, name) in variables().map_err(|_e| fmt::Error)? {
+        let size = match variable(&guid, &name, &mut []) {
+            Ok((size, _attributes)) => size,
+            Err(KError::EfiBufferTooSmall { needed }) => needed,
+            Err(_e) => continue,
+        };
+        writeln!(out, "{}-{} {}", name, guid, size)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn guid_display() {
+        assert_eq!(
+            GLOBAL_VARIABLE.to_string(),
+            "8be4df61-93ca-11d2-aa0d-00e098032b8c"
+        );
+    }
+
+    #[test]
+    fn time_zones() {
+        let mut time = Time {
+            year: 2021,
+            month: 7,
+            day: 4,
+            hour: 23,
+            minute: 5,
+            second: 9,
+            nanosecond: 500,
+            time_zone: UNSPECIFIED_TIMEZONE,
+            ..Default::default()
+        };
+        assert_eq!(time.since_epoch(), Duration::new(1625439909, 500));
+
+        // Two hours ahead of UTC
+        time.time_zone = 120;
+        assert_eq!(time.since_epoch(), Duration::new(1625439909 - 7200, 500));
+        time.time_zone = -60;
+        assert_eq!(time.since_epoch(), Duration::new(1625439909 + 3600, 500));
+    }
+
+    #[test]
+    fn names() {
+        let name = ucs2("BootOrder").unwrap();
+        assert_eq!(name.len(), 10);
+        assert_eq!(name[9], 0);
+        assert_eq!(from_ucs2(&name), "BootOrder");
+        assert_eq!(from_ucs2(&[0x42, 0x6f]), "Bo");
+
+        assert_eq!(ucs2("🦀"), Err(KError::InvalidEfiVariableName));
+        let long = "x".repeat(MAX_NAME_LEN);
+        assert_eq!(ucs2(&long), Err(KError::InvalidEfiVariableName));
+        assert!(ucs2(&long[1..]).is_ok());
+    }
+
+    #[test]
+    fn only_runtime_regions() {
+        let mut code = MemoryDescriptor::default();
+        code.ty = MemoryType::RUNTIME_SERVICES_CODE;
+        code.phys_start = 0x7f00_0000;
+        code.page_count = 16;
+        code.att = MemoryAttribute::RUNTIME | MemoryAttribute::WRITE_BACK;
+        let mut conventional = MemoryDescriptor::default();
+        conventional.ty = MemoryType::CONVENTIONAL;
+        conventional.phys_start = 0x10_0000;
+        conventional.page_count = 256;
+        conventional.att = MemoryAttribute::WRITE_BACK;
+
+        let regions = runtime_regions(&[conventional, code]).unwrap();
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].phys_start, 0x7f00_0000);
+        assert_eq!(regions[0].virt_start, KERNEL_BASE + 0x7f00_0000);
+
+        assert!(contains(&regions, 0x7f00_0000 + 15 * 4096));
+        assert!(!contains(&regions, 0x7f00_0000 + 16 * 4096));
+        assert!(!contains(&regions, 0x10_0000));
+    }
+}
@@ -0,0 +1,280 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Futexes: lets a core of a process sleep in the kernel until another core
+//! of the process wakes it up.
+//!
+//! `FutexWait` checks that a 32-bit word in the process still has the value
+//! the caller expects and, if so, halts the core. `FutexWake` removes
+//! waiters of a word from the table and sends them an IPI.
+//!
+//! Any interrupt ends a wait: the interrupt handler calls `unpark`, which
+//! puts back the state of the system call, and returns to the process. So a
+//! wait can end without a wake-up (or time-out), callers have to check the
+//! word again.
+//!
+//! Other blocking system calls (doors, events and `Poll`) park and kick
+//! cores the same way. The timers of events (`crate::event`) and the
+//! interval timers of processes (`crate::itimer`) are checked here as well:
+//! on timer interrupts and before a core parks.
+
+use core::time::Duration;
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+use x86::time::rdtsc;
+
+use crate::error::KError;
+use crate::event::{EventId, EVENTS};
+use crate::itimer::TIMERS;
+use crate::process::{Pid, UserPtr};
+use crate::time::clocksource::{duration_to_ticks, ticks_to_duration};
+
+use super::kcb::{get_kcb, Arch86Kcb};
+use super::{timer, tsc, MAX_CORES};
+
+/// How many cores can wait on futexes at the same time.
+const MAX_WAITERS: usize = 256;
+
+/// A core that waits on the futex at `vaddr`.
+struct Waiter {
+    pid: Pid,
+    vaddr: u64,
+    core: usize,
+}
+
+/// Every core that waits, oldest first.
+static WAITERS: Mutex<ArrayVec<Waiter, MAX_WAITERS>> = Mutex::new(ArrayVec::new_const());
+
+/// A core halted in `wait`.
+struct Parked {
+    /// The state to return to the process with.
+    state: kpi::arch::SaveArea,
+    /// TSC value at which the wait times out.
+    deadline: Option<u64>,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_PARKED: Mutex<Option<Parked>> = Mutex::new(None);
+static PARKED: [Mutex<Option<Parked>>; MAX_CORES] = [NOT_PARKED; MAX_CORES];
+
+/// Cores that wait in `Poll` for a socket (or event) to become ready.
+static POLLERS: Mutex<ArrayVec<usize, MAX_CORES>> = Mutex::new(ArrayVec::new_const());
+
+/// Sleeps until another core calls `wake` for `vaddr` or `timeout` passed,
+/// if the word at `vaddr` is still `expected` (`KError::WouldBlock` if it
+/// isn't).
+pub fn wait(vaddr: u64, expected: u32, timeout: Option<Duration>) -> Result<(u64, u64), KError> {
+    if vaddr % 4 != 0 {
+        return Err(KError::FutexUnaligned);
+    }
+
+    let kcb = get_kcb();
+    let pid = kcb.current_pid()?;
+    let core = kcb.arch.id();
+
+    {
+        // `wake` can't happen between the check and our entry in the table
+        let mut waiters = WAITERS.lock();
+        if UserPtr::<u32>::new(vaddr)?.read()? != expected {
+            return Err(KError::WouldBlock);
+        }
+        waiters
+            .try_push(Waiter { pid, vaddr, core })
+            .map_err(|_| KError::TooManyFutexWaiters)?;
+    }
+
+    // Return with `Ok` unless `unpark` finds out we timed out
+    park(None, timeout)
+}
+
+/// Halts the core until the next interrupt (at the latest after `timeout`),
+/// the system call then returns to the process with `error` (or `Ok`).
+///
+/// The caller has to be registered wherever the core that wakes us up
+/// looks for it before it calls `park` (with interrupts still off, so the
+/// IPI of `kick` can't get lost).
+pub fn park(error: Option<KError>, timeout: Option<Duration>) -> Result<(u64, u64), KError> {
+    let kcb = get_kcb();
+    let core = kcb.arch.id();
+
+    let mut state = kcb
+        .arch
+        .save_area
+        .as_ref()
+        .map(|sa| **sa)
+        .ok_or(KError::NoExecutorForCore)?;
+    state.set_syscall_ret1(0);
+    state.set_syscall_ret2(0);
+    state.set_syscall_error_code(error.map_or(kpi::SystemCallError::Ok, |e| e.into()));
+
+    let now = unsafe { rdtsc() };
+    let deadline = timeout.map(|t| now.saturating_add(duration_to_ticks(t, tsc::frequency())));
+    *PARKED[core].lock() = Some(Parked { state, deadline });
+
+    // Don't sleep through the timer of an event (or an interval timer)
+    timer::set(
+        timeout
+            .into_iter()
+            .chain(next_timer(core, now))
+            .fold(timer::DEFAULT_TIMER_DEADLINE, Duration::min),
+    );
+    super::halt()
+}
+
+/// Wakes up `core` if it's parked.
+pub fn kick(core: usize) {
+    let apic_id = atopology::MACHINE_TOPOLOGY.threads[core].apic_id();
+    super::tlb::send_ipi_to_apic(apic_id);
+}
+
+/// Adds `n` to the counter of `event` and wakes up the cores that wait
+/// for it.
+pub fn signal_event(event: EventId, n: u64) -> Result<(), KError> {
+    let waiters = EVENTS.lock().signal(event, n)?;
+    for core in waiters {
+        kick(core);
+    }
+    // Events can be polled too
+    wake_pollers();
+    Ok(())
+}
+
+/// Makes `wake_pollers` kick `core`.
+///
+/// `Poll` calls this before it checks the sockets, otherwise a wake-up in
+/// between the check and `park` would get lost.
+pub fn add_poller(core: usize) {
+    let mut pollers = POLLERS.lock();
+    if !pollers.contains(&core) {
+        // Can't be full, every core is in there at most once
+        pollers.push(core);
+    }
+}
+
+/// Undoes `add_poller` (if `core` wasn't kicked already).
+pub fn remove_poller(core: usize) {
+    POLLERS.lock().retain(|c| *c != core);
+}
+
+/// Wakes up the cores that wait in `Poll`, sockets (or events) may be ready
+/// now.
+///
+/// The current core stays registered: it only gets here while it checks the
+/// sockets itself.
+pub fn wake_pollers() {
+    let core = get_kcb().arch.id();
+    let mut pollers = POLLERS.lock();
+    for other in pollers.iter().filter(|c| **c != core) {
+        kick(*other);
+    }
+    pollers.retain(|c| *c == core);
+}
+
+/// How long until the next event timer or interval timer of `core` (if
+/// there is one).
+fn next_timer(core: usize, now: u64) -> Option<Duration> {
+    let event = EVENTS.lock().next_deadline();
+    let interval = TIMERS.lock().next_deadline(core);
+    event
+        .into_iter()
+        .chain(interval)
+        .min()
+        .map(|d| ticks_to_duration(d.saturating_sub(now), tsc::frequency()))
+}
+
+/// Signals the events whose timers expired, counts the expirations of
+/// interval timers and makes sure the timer of this core goes off in time
+/// for the next one.
+///
+/// Expirations for this core that are still waiting for an upcall make the
+/// timer go off again after `timer::UPCALL_RETRY` (the process may have had
+/// upcalls disabled), and so does the frequency governor if it wants to
+/// sample the load soon.
+pub fn expire_timers() {
+    let now = unsafe { rdtsc() };
+    let core = get_kcb().arch.id();
+    let waiters = EVENTS.lock().expire(now);
+    let (expired, pending) = {
+        let mut timers = TIMERS.lock();
+        (timers.expire(now), timers.has_expired(core))
+    };
+    for other in waiters
+        .into_iter()
+        .chain(expired.into_iter().filter(|c| *c != core))
+    {
+        kick(other);
+    }
+
+    let retry = if pending {
+        Some(timer::UPCALL_RETRY)
+    } else {
+        None
+    };
+    if let Some(after) = next_timer(core, now)
+        .into_iter()
+        .chain(retry)
+        .chain(super::cpufreq::next_sample())
+        .min()
+    {
+        timer::set(after.min(timer::DEFAULT_TIMER_DEADLINE));
+    }
+}
+
+/// Wakes up (at most) `count` cores waiting on `vaddr`, returns how many
+/// we woke up.
+pub fn wake(vaddr: u64, count: u64) -> Result<(u64, u64), KError> {
+    if vaddr % 4 != 0 {
+        return Err(KError::FutexUnaligned);
+    }
+    let pid = get_kcb().current_pid()?;
+
+    let mut woken = 0;
+    let mut waiters = WAITERS.lock();
+    while woken < count {
+        match waiters
+            .iter()
+            .position(|w| w.pid == pid && w.vaddr == vaddr)
+        {
+            Some(idx) => {
+                let waiter = waiters.remove(idx);
+                kick(waiter.core);
+                woken += 1;
+            }
+            None => break,
+        }
+    }
+
+    Ok((woken, 0))
+}
+
+/// Ends the wait of the current core (if it waits) and puts the state of the
+/// system call that parked it back into the save area.
+///
+/// Returns true if the core was waiting (the caller should return to the
+/// process).
+pub fn unpark(kcb: &mut crate::kcb::Kcb<Arch86Kcb>) -> bool {
+    let core = kcb.arch.id();
+    let parked = match PARKED[core].lock().take() {
+        Some(parked) => parked,
+        None => return false,
+    };
+
+    let mut state = parked.state;
+    {
+        // Still in the table: nobody woke us up
+        let mut waiters = WAITERS.lock();
+        if let Some(idx) = waiters.iter().position(|w| w.core == core) {
+            waiters.remove(idx);
+            if parked.deadline.map_or(false, |d| unsafe { rdtsc() } >= d) {
+                state.set_syscall_error_code(KError::FutexTimeout.into());
+            }
+        }
+    }
+    POLLERS.lock().retain(|c| *c != core);
+
+    if let Some(sa) = kcb.arch.save_area.as_mut() {
+        **sa = state;
+    }
+    true
+}
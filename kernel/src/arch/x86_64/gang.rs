@@ -0,0 +1,175 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Gang scheduling: the cores of a process in gang mode leave user-space
+//! and go back to it together.
+//!
+//! A process has its cores to itself, but the kernel still interrupts them
+//! (e.g., the timer that advances the replicas). For a process whose cores
+//! synchronize a lot (like the rump network stack under SMP) that's the
+//! worst moment: the other cores spin on a lock the interrupted one holds.
+//! So in gang mode, the core that takes the timer interrupt first stops the
+//! rest of the gang (an IPI, they wait in the kernel), does its work and
+//! lets them go once it returns to the process. A core newly handed to the
+//! process doesn't start while the gang is stopped either.
+//!
+//! Which processes are in gang mode and which cores they have comes from
+//! the replicated process table (`nr::Op::SetGang`), only who currently
+//! stops a gang is local state.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use log::warn;
+
+use crate::error::KError;
+use crate::nr;
+use crate::prelude::*;
+use crate::process::{Pid, MAX_PROCESSES};
+
+use super::kcb::get_kcb;
+use super::{coreboot, hotplug, tlb};
+
+/// How long a core waits for the rest of its gang to stop (cores that are
+/// in a system call only stop once they return to user-space).
+const STOP_TIMEOUT: Duration = Duration::from_millis(1);
+/// How long a core of a gang waits to be let go (something is wrong if
+/// the kernel takes a core that long).
+const HOLD_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Nobody stops the gang.
+const NOBODY: usize = usize::MAX;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const RUNNING: AtomicUsize = AtomicUsize::new(NOBODY);
+/// The core that stops the gang of a process (`NOBODY` if it runs).
+static STOPPED_BY: [AtomicUsize; MAX_PROCESSES] = [RUNNING; MAX_PROCESSES];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NONE_HELD: AtomicUsize = AtomicUsize::new(0);
+/// How many cores of a gang wait for the one that stopped it.
+static HELD: [AtomicUsize; MAX_PROCESSES] = [NONE_HELD; MAX_PROCESSES];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_STOPS: AtomicU64 = AtomicU64::new(0);
+/// How often the gang of a process was stopped (for `/proc/gangs`).
+static STOPS: [AtomicU64; MAX_PROCESSES] = [NO_STOPS; MAX_PROCESSES];
+
+/// Puts process `pid` in gang mode (or takes it out).
+pub fn set(pid: Pid, gang: bool) -> Result<(), KError> {
+    if pid >= MAX_PROCESSES {
+        return Err(KError::NoProcessFoundForPid);
+    }
+    nr::KernelNode::set_gang(pid, gang)?;
+    if gang {
+        STOPS[pid].store(0, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Stops the other cores of the gang of the process on the current core
+/// before the kernel uses the core for a while.
+///
+/// Returns the process if we stopped its gang, pass it to `resume` before
+/// going back to user-space. `None` if the process isn't in gang mode (or
+/// another core of the gang stops it already, then we waited for that).
+pub fn stop() -> Option<Pid> {
+    let kcb = get_kcb();
+    let pid = kcb.current_pid().ok()?;
+    // Most processes aren't in gang mode, that's a lookup without the replica
+    if !nr::KernelNode::process(pid).ok()?.gang {
+        return None;
+    }
+    let cores = nr::KernelNode::gang(pid).ok()?;
+    if cores.len() < 2 {
+        return None;
+    }
+
+    let gtid = kcb.arch.id();
+    if STOPPED_BY[pid]
+        .compare_exchange(NOBODY, gtid, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        hold(pid);
+        return None;
+    }
+
+    let mut others = 0;
+    for thread in atopology::MACHINE_TOPOLOGY.threads() {
+        let member = cores.binary_search(&thread.id).is_ok();
+        if member && thread.id != gtid && coreboot::is_online(thread.id) {
+            hotplug::kick(thread.apic_id());
+            others += 1;
+        }
+    }
+
+    let start = rawtime::Instant::now();
+    while HELD[pid].load(Ordering::Acquire) < others && start.elapsed() < STOP_TIMEOUT {
+        // They might ask us for a TLB shootdown on the way
+        tlb::dequeue(gtid);
+        core::hint::spin_loop();
+    }
+    STOPS[pid].fetch_add(1, Ordering::Relaxed);
+
+    Some(pid)
+}
+
+/// Lets the gang we stopped with `stop` go again.
+pub fn resume(stopped: Option<Pid>) {
+    if let Some(pid) = stopped {
+        // Cores that arrive late don't count for the next stop
+        HELD[pid].store(0, Ordering::Release);
+        STOPPED_BY[pid].store(NOBODY, Ordering::Release);
+    }
+}
+
+/// Is another core stopping the gang of `pid`?
+fn stopping(pid: Pid) -> bool {
+    let by = STOPPED_BY[pid].load(Ordering::Acquire);
+    by != NOBODY && by != get_kcb().arch.id()
+}
+
+/// Waits until the core that stops the gang of `pid` lets it go.
+fn wait(pid: Pid) {
+    let gtid = get_kcb().arch.id();
+    let start = rawtime::Instant::now();
+    while stopping(pid) {
+        if start.elapsed() > HOLD_TIMEOUT {
+            warn!("Core #{} waited too long for the gang of {}", gtid, pid);
+            break;
+        }
+        tlb::dequeue(gtid);
+        core::hint::spin_loop();
+    }
+}
+
+/// Stops the current core (interrupted in user-space) if its gang is
+/// stopped, returns once the gang can go on.
+pub fn hold(pid: Pid) {
+    if stopping(pid) {
+        HELD[pid].fetch_add(1, Ordering::AcqRel);
+        wait(pid);
+    }
+}
+
+/// Waits before the current core starts running `pid` for the first time
+/// until the gang isn't stopped.
+pub fn join(pid: Pid) {
+    if pid < MAX_PROCESSES {
+        wait(pid);
+    }
+}
+
+/// Generates `/proc/gangs` (processes in gang mode, their cores and how
+/// often they were stopped).
+pub fn proc_gangs(out: &mut String) -> fmt::Result {
+    writeln!(out, "{:>5} {:>5} {:>10}", "pid", "cores", "stops")?;
+    for (pid, process) in nr::KernelNode::processes().map_err(|_e| fmt::Error)? {
+        if process.gang {
+            let stops = STOPS[pid].load(Ordering::Relaxed);
+            writeln!(out, "{:>5} {:>5} {:>10}", pid, process.cores, stops)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,576 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A GDB stub on the second serial port (COM2).
+//!
+//! Build the kernel with the `gdb` feature and attach with `target remote`
+//! to whatever COM2 is connected to (`run.py --gdb-stub` puts it on
+//! `127.0.0.1:55556`), the BSP waits for GDB as soon as it installed its
+//! KCB.
+//!
+//! Every core is a thread (thread-id = core + 1). When a core stops (an
+//! `int3`, breakpoint, watchpoint or single-step, GDB sent ^C, or the
+//! watchdog found it stuck) it takes the stub and stops all other cores
+//! with an NMI: they wait in `park` with their registers on their NMI
+//! stack. GDB can read and write the registers of every stopped core, and
+//! memory through that core's page-table, so we see the kernel as well as
+//! the process that ran on it.
+//!
+//! The stub doesn't allocate and doesn't take locks the rest of the kernel
+//! uses, it has to work when a core hangs with the allocator locked.
+//!
+//! Without the feature `isr.S` still has the entry points but the IDT
+//! doesn't route `#DB` and `#BP` here (see `irq::IdtTable`).
+
+use core::fmt::Write;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use arrayvec::ArrayString;
+use klogger::sprintln;
+use x86::controlregs::cr3;
+
+use crate::ExitReason;
+
+use super::irq::InterruptFrame;
+use super::kcb::get_kcb;
+use super::{coreboot, debug, watchdog, MAX_CORES};
+
+pub mod packet;
+mod target;
+
+use packet::{BreakpointKind, Command, Connection, Response, ThreadId, MAX_PACKET_SIZE};
+use target::Breakpoints;
+
+/// Whether the kernel was built with the stub.
+pub const ENABLED: bool = cfg!(feature = "gdb");
+
+/// The signals we report (GDB's numbers).
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// The errors we report (GDB ignores the numbers).
+const EFAULT: u8 = 14;
+const EINVAL: u8 = 22;
+
+/// Trap flag (single-step).
+const RFLAGS_TF: u64 = 1 << 8;
+/// Resume flag (ignore instruction breakpoints for one instruction).
+const RFLAGS_RF: u64 = 1 << 16;
+
+/// How long we wait for the other cores to stop.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const RUNNING: AtomicPtr<InterruptFrame> = AtomicPtr::new(ptr::null_mut());
+/// Registers of the stopped cores (null if a core runs).
+static FRAMES: [AtomicPtr<InterruptFrame>; MAX_CORES] = [RUNNING; MAX_CORES];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CR3: AtomicU64 = AtomicU64::new(0);
+/// Address-space of the stopped cores.
+static CR3S: [AtomicU64; MAX_CORES] = [NO_CR3; MAX_CORES];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_STOPPED: AtomicBool = AtomicBool::new(false);
+/// Cores the stub asked to stop.
+static STOP: [AtomicBool; MAX_CORES] = [NOT_STOPPED; MAX_CORES];
+
+/// Incremented every time the stub lets the cores go.
+static RESUMED: AtomicUsize = AtomicUsize::new(0);
+
+/// GDB sent ^C (we raise an `int3` to stop).
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// The core that talks to GDB.
+static STUB: spin::Mutex<Stub> = spin::Mutex::new(Stub::new());
+
+fn tid(core: usize) -> usize {
+    core + 1
+}
+
+/// The registers of `core` if it's stopped.
+fn stopped(core: usize) -> Option<&'static mut InterruptFrame> {
+    let frame = FRAMES.get(core)?.load(Ordering::SeqCst);
+    // Safe: The core waits in `park` (or runs the stub) until we resume
+    unsafe { frame.as_mut() }
+}
+
+/// COM2.
+struct Serial;
+
+impl Connection for Serial {
+    fn read(&mut self) -> u8 {
+        loop {
+            if let Some(b) = debug::com2_getb() {
+                return b;
+            }
+            // Nobody takes timer interrupts while we wait
+            #[cfg(target_os = "none")]
+            crate::drivers::i6300esb::pet();
+            core::hint::spin_loop();
+        }
+    }
+
+    fn poll(&mut self) -> Option<u8> {
+        debug::com2_getb()
+    }
+
+    fn write(&mut self, b: u8) {
+        debug::com2_putb(b)
+    }
+}
+
+/// Why the core that runs the stub stopped.
+#[derive(Clone, Copy)]
+enum Stop {
+    Signal(u8),
+    /// It hit one of our `int3`s.
+    Breakpoint,
+    /// A `#DB` with this DR6.
+    Debug(u64),
+}
+
+/// What GDB wants after it's done with a stop.
+enum Resume {
+    Continue,
+    Step(usize),
+    Detach,
+}
+
+struct Stub {
+    reply: Response,
+    breakpoints: Breakpoints,
+    /// GDB waits for a stop reply (after `c` or `s`).
+    running: bool,
+    /// The core that stopped.
+    current: usize,
+    /// The core for register and memory accesses (`Hg`).
+    general: usize,
+    /// The core that steps (`Hc`, `None` for the current one).
+    step: Option<usize>,
+}
+
+impl Stub {
+    const fn new() -> Stub {
+        Stub {
+            reply: Response::new(),
+            breakpoints: Breakpoints::new(),
+            // GDB asks with `?` when it attaches
+            running: false,
+            current: 0,
+            general: 0,
+            step: None,
+        }
+    }
+
+    /// The stopped core `tid` refers to (`Any` and `All` pick `default`).
+    fn core(tid: ThreadId, default: usize) -> Option<usize> {
+        match tid {
+            ThreadId::All | ThreadId::Any => Some(default),
+            ThreadId::Id(id) => id.checked_sub(1).filter(|core| stopped(*core).is_some()),
+        }
+    }
+
+    fn stop_reply(&mut self, stop: Stop) {
+        let r = &mut self.reply;
+        let signal = match stop {
+            Stop::Signal(signal) => signal,
+            Stop::Breakpoint | Stop::Debug(_) => SIGTRAP,
+        };
+        let _r = write!(r, "T{:02x}thread:{:x};", signal, tid(self.current));
+
+        match stop {
+            Stop::Signal(_) => {}
+            Stop::Breakpoint => r.push_str("swbreak:;"),
+            Stop::Debug(dr6) => match self.breakpoints.hit(dr6) {
+                Some((BreakpointKind::Hardware, _addr)) => r.push_str("hwbreak:;"),
+                Some((BreakpointKind::Write, addr)) => {
+                    let _r = write!(r, "watch:{:x};", addr);
+                }
+                Some((BreakpointKind::Read, addr)) => {
+                    let _r = write!(r, "rwatch:{:x};", addr);
+                }
+                Some((BreakpointKind::Access, addr)) => {
+                    let _r = write!(r, "awatch:{:x};", addr);
+                }
+                // Single-step
+                Some((BreakpointKind::Software, _addr)) | None => {}
+            },
+        }
+    }
+
+    /// Talks to GDB until it lets the cores run again.
+    fn session(&mut self, core: usize, stop: Stop) -> Resume {
+        let mut serial = Serial;
+        self.current = core;
+        self.general = core;
+        self.step = None;
+
+        if self.running {
+            self.running = false;
+            self.reply.clear();
+            self.stop_reply(stop);
+            packet::send(&mut serial, self.reply.as_bytes());
+        }
+
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        loop {
+            let packet = match packet::recv(&mut serial, &mut buf) {
+                Some(packet) => packet,
+                // ^C, but we're already stopped
+                None => continue,
+            };
+
+            self.reply.clear();
+            let resume = self.handle(Command::parse(packet), stop);
+            packet::send(&mut serial, self.reply.as_bytes());
+            if let Some(resume) = resume {
+                return resume;
+            }
+        }
+    }
+
+    /// Handles a command and writes the reply, returns `Some` if the cores
+    /// should run again.
+    fn handle(&mut self, command: Command, stop: Stop) -> Option<Resume> {
+        let frame = stopped(self.general).expect("the general thread is stopped");
+        let cr3 = CR3S[self.general].load(Ordering::SeqCst);
+        let r = &mut self.reply;
+
+        match command {
+            Command::StopReason => self.stop_reply(stop),
+            Command::ReadRegisters => target::read_registers(frame, r),
+            Command::WriteRegisters(hex) => {
+                if target::write_registers(frame, hex) {
+                    r.push_str("OK");
+                } else {
+                    r.error(EINVAL);
+                }
+            }
+            Command::ReadRegister(n) => {
+                if !target::read_register(frame, n, r) {
+                    r.error(EINVAL);
+                }
+            }
+            Command::WriteRegister(n, hex) => {
+                if target::write_register(frame, n, hex) {
+                    r.push_str("OK");
+                } else {
+                    r.error(EINVAL);
+                }
+            }
+            Command::ReadMemory(addr, len) => {
+                // Two hex digits per byte
+                let len = core::cmp::min(len, MAX_PACKET_SIZE / 2);
+                let mut chunk = [0u8; 64];
+                let mut read = 0;
+                while read < len {
+                    let want = core::cmp::min(chunk.len(), len - read);
+                    let got = target::read_memory(
+                        cr3,
+                        addr.wrapping_add(read as u64),
+                        &mut chunk[..want],
+                    );
+                    r.push_hex(&chunk[..got]);
+                    read += got;
+                    if got < want {
+                        break;
+                    }
+                }
+                if read == 0 && len > 0 {
+                    r.error(EFAULT);
+                }
+            }
+            Command::WriteMemory(addr, hex) => {
+                let mut chunk = [0u8; 64];
+                let mut written = 0;
+                let mut ok = true;
+                for digits in hex.chunks(chunk.len() * 2) {
+                    let len = packet::decode_hex(digits, &mut chunk).unwrap_or(0);
+                    ok = len > 0
+                        && target::write_memory(cr3, addr.wrapping_add(written), &chunk[..len]);
+                    if !ok {
+                        break;
+                    }
+                    written += len as u64;
+                }
+                if ok {
+                    r.push_str("OK");
+                } else {
+                    r.error(EFAULT);
+                }
+            }
+            Command::Continue(addr) => {
+                if let Some(addr) = addr {
+                    stopped(self.current).expect("we're stopped").rip = addr;
+                }
+                self.running = true;
+                return Some(Resume::Continue);
+            }
+            Command::Step(addr) => {
+                let core = self.step.unwrap_or(self.current);
+                if let Some(addr) = addr {
+                    stopped(core).expect("stepping core is stopped").rip = addr;
+                }
+                self.running = true;
+                return Some(Resume::Step(core));
+            }
+            Command::InsertBreakpoint(kind, addr, len) => {
+                let inserted = match kind {
+                    BreakpointKind::Software => self.breakpoints.insert_software(cr3, addr),
+                    _ => self.breakpoints.insert_hardware(kind, addr, len),
+                };
+                if inserted {
+                    r.push_str("OK");
+                } else {
+                    r.error(EINVAL);
+                }
+            }
+            Command::RemoveBreakpoint(kind, addr, _len) => {
+                let removed = match kind {
+                    BreakpointKind::Software => self.breakpoints.remove_software(addr),
+                    _ => self.breakpoints.remove_hardware(kind, addr),
+                };
+                if removed {
+                    r.push_str("OK");
+                } else {
+                    r.error(EINVAL);
+                }
+            }
+            Command::SetGeneralThread(tid) => match Stub::core(tid, self.current) {
+                Some(core) => {
+                    self.general = core;
+                    r.push_str("OK");
+                }
+                None => r.error(EINVAL),
+            },
+            Command::SetContinueThread(tid) => match tid {
+                ThreadId::All | ThreadId::Any => {
+                    self.step = None;
+                    r.push_str("OK");
+                }
+                ThreadId::Id(_) => match Stub::core(tid, self.current) {
+                    Some(core) => {
+                        self.step = Some(core);
+                        r.push_str("OK");
+                    }
+                    None => r.error(EINVAL),
+                },
+            },
+            Command::ThreadAlive(tid) => match Stub::core(tid, self.current) {
+                Some(_core) => r.push_str("OK"),
+                None => r.error(EINVAL),
+            },
+            Command::Supported => {
+                let _r = write!(r, "PacketSize={:x};swbreak+;hwbreak+", MAX_PACKET_SIZE);
+            }
+            // We didn't start anything
+            Command::Attached => r.push_str("1"),
+            Command::CurrentThread => {
+                let _r = write!(r, "QC{:x}", tid(self.current));
+            }
+            Command::FirstThreadInfo => {
+                r.push(b'm');
+                let cores = (0..MAX_CORES).filter(|core| stopped(*core).is_some());
+                for (i, core) in cores.enumerate() {
+                    if i > 0 {
+                        r.push(b',');
+                    }
+                    let _r = write!(r, "{:x}", tid(core));
+                }
+            }
+            Command::NextThreadInfo => r.push_str("l"),
+            Command::ThreadExtraInfo(tid) => match Stub::core(tid, self.current) {
+                Some(core) => {
+                    let mut info: ArrayString<64> = ArrayString::new();
+                    let place = match stopped(core) {
+                        Some(frame) if frame.in_user_space() => "user-space",
+                        _ => "kernel",
+                    };
+                    let _r = write!(info, "core {} ({})", core, place);
+                    r.push_hex(info.as_bytes());
+                }
+                None => r.error(EINVAL),
+            },
+            Command::Detach => {
+                self.breakpoints.clear();
+                r.push_str("OK");
+                return Some(Resume::Detach);
+            }
+            Command::Kill => {
+                sprintln!("[gdb] killed by the debugger");
+                debug::shutdown(ExitReason::Ok);
+            }
+            Command::Malformed => r.error(EINVAL),
+            Command::Unsupported => {}
+        }
+        None
+    }
+}
+
+/// Whether `int3` at `rip - 1` is one of our breakpoints (and not one in
+/// the code).
+///
+/// GDB may have removed it since we hit it (while another core had the
+/// stub), then there is the original instruction (and we have to execute
+/// it).
+fn hit_breakpoint(rip: u64) -> bool {
+    let addr = rip.wrapping_sub(1);
+    if target::is_placed(addr) {
+        return true;
+    }
+
+    let mut code = [0u8; 2];
+    // Safe: reads through the page-table don't change anything
+    let cr3 = unsafe { cr3() };
+    if target::read_memory(cr3, addr.wrapping_sub(1), &mut code) != code.len() {
+        return false;
+    }
+    // `int3` or `int $3`
+    code[1] != 0xcc && code != [0xcd, 0x03]
+}
+
+/// Called by `isr_handler_frame1` (see `isr.S`) and for breakpoints that
+/// aren't kprobes (see `kprobes::handle_breakpoint`).
+#[no_mangle]
+pub extern "C" fn handle_debug_exception(frame: &mut InterruptFrame) {
+    let stop = if frame.vector == 3 {
+        if INTERRUPTED.swap(false, Ordering::SeqCst) {
+            Stop::Signal(SIGINT)
+        } else if hit_breakpoint(frame.rip) {
+            // GDB wants to see the address of the breakpoint (we told it
+            // with `swbreak+`)
+            frame.rip -= 1;
+            Stop::Breakpoint
+        } else {
+            Stop::Signal(SIGTRAP)
+        }
+    } else {
+        frame.rflags &= !RFLAGS_TF;
+        Stop::Debug(target::take_dr6())
+    };
+
+    enter(frame, stop);
+}
+
+/// Parks the current core if the stub asked it to stop (`nmi::handle_nmi`
+/// calls this first), returns `false` if the NMI is for someone else.
+pub fn handle_nmi(frame: &mut InterruptFrame) -> bool {
+    let core = get_kcb().arch.id();
+    if !ENABLED || !STOP[core].load(Ordering::SeqCst) {
+        return false;
+    }
+    park(core, frame);
+    true
+}
+
+/// Stops a core the watchdog found stuck in the debugger (instead of
+/// shutting down).
+pub fn stuck(frame: &mut InterruptFrame) {
+    enter(frame, Stop::Signal(SIGINT));
+}
+
+/// Stops in the debugger if GDB sent ^C (the timer interrupt checks).
+pub fn poll() {
+    if ENABLED && Serial.poll() == Some(packet::INTERRUPT) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        unsafe { x86::int!(3) };
+    }
+}
+
+/// Stops until GDB attached and continues.
+pub fn wait_for_debugger() {
+    sprintln!("[gdb] waiting for a debugger on COM2");
+    unsafe { x86::int!(3) };
+}
+
+/// Makes the current core available to GDB, stops the others and runs the
+/// stub until GDB lets us go.
+fn enter(frame: &mut InterruptFrame, stop: Stop) {
+    let core = get_kcb().arch.id();
+    let mut stub = loop {
+        if let Some(stub) = STUB.try_lock() {
+            break stub;
+        }
+        // Another core has the stub, it's either about to send us an NMI or
+        // we just missed it (and try again).
+        if STOP[core].load(Ordering::SeqCst) {
+            return park(core, frame);
+        }
+        core::hint::spin_loop();
+    };
+
+    // Safe: Reading cr3 has no side-effects
+    CR3S[core].store(unsafe { cr3() }, Ordering::SeqCst);
+    FRAMES[core].store(frame as *mut _, Ordering::SeqCst);
+    stop_others(core);
+
+    let resume = stub.session(core, stop);
+    match resume {
+        Resume::Continue | Resume::Detach => {}
+        Resume::Step(core) => {
+            stopped(core).expect("stepping core is stopped").rflags |= RFLAGS_TF;
+        }
+    }
+    // Don't trigger the breakpoint we stopped at again
+    frame.rflags |= RFLAGS_RF;
+
+    for stop in STOP.iter() {
+        stop.store(false, Ordering::SeqCst);
+    }
+    // The cores didn't take timer interrupts for a while
+    watchdog::reset();
+    target::load_debug_registers();
+    FRAMES[core].store(ptr::null_mut(), Ordering::SeqCst);
+    RESUMED.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Sends an NMI to all other online cores and waits until they parked.
+fn stop_others(me: usize) {
+    let start = rawtime::Instant::now();
+    let others = || (0..MAX_CORES).filter(move |core| *core != me);
+
+    // Cores of the last stop might still be on their way out of `park`
+    while others().any(|core| stopped(core).is_some()) && start.elapsed() < TIMEOUT {
+        core::hint::spin_loop();
+    }
+
+    for core in others() {
+        if coreboot::is_online(core) {
+            STOP[core].store(true, Ordering::SeqCst);
+            watchdog::send_nmi(core);
+        }
+    }
+
+    let start = rawtime::Instant::now();
+    let pending =
+        || others().any(|core| STOP[core].load(Ordering::SeqCst) && stopped(core).is_none());
+    while pending() && start.elapsed() < TIMEOUT {
+        core::hint::spin_loop();
+    }
+    for core in others() {
+        if STOP[core].load(Ordering::SeqCst) && stopped(core).is_none() {
+            sprintln!("[gdb] core {} didn't stop", core);
+        }
+    }
+}
+
+/// Waits (with the registers in `frame`) until the stub lets the cores go.
+fn park(core: usize, frame: &mut InterruptFrame) {
+    let resumed = RESUMED.load(Ordering::SeqCst);
+    // The stub clears it before it increments `RESUMED`
+    if !STOP[core].load(Ordering::SeqCst) {
+        return;
+    }
+
+    // Safe: Reading cr3 has no side-effects
+    CR3S[core].store(unsafe { cr3() }, Ordering::SeqCst);
+    FRAMES[core].store(frame as *mut _, Ordering::SeqCst);
+    while RESUMED.load(Ordering::SeqCst) == resumed {
+        core::hint::spin_loop();
+    }
+    target::load_debug_registers();
+    FRAMES[core].store(ptr::null_mut(), Ordering::SeqCst);
+}
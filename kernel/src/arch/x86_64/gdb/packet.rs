@@ -0,0 +1,491 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Framing and parsing of GDB remote serial protocol packets.
+//!
+//! A packet is `$<data>#<checksum>` where the checksum is the sum of the
+//! data bytes modulo 256 (as two hex digits), the receiver answers every
+//! packet with `+` (or `-` to get it again). Nothing here allocates, the
+//! stub runs when the allocator (or anything else) might be stuck.
+//!
+//! # See also
+//!  - https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html
+
+use core::fmt;
+
+/// Largest packet we take (and what we tell GDB in `qSupported`).
+pub const MAX_PACKET_SIZE: usize = 4096;
+
+/// The byte GDB sends (outside of a packet) to stop the target.
+pub const INTERRUPT: u8 = 0x03;
+
+/// Where packets come from and go to.
+pub trait Connection {
+    /// Blocks until there is a byte.
+    fn read(&mut self) -> u8;
+
+    /// Returns a byte if one is there.
+    fn poll(&mut self) -> Option<u8>;
+
+    fn write(&mut self, b: u8);
+}
+
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+/// Parses a (big-endian) hex number.
+pub fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter()
+        .try_fold(0u64, |n, c| hex_digit(*c).map(|d| n << 4 | d as u64))
+}
+
+/// Decodes pairs of hex digits into `out`, returns how many bytes it wrote.
+pub fn decode_hex(s: &[u8], out: &mut [u8]) -> Option<usize> {
+    if s.len() % 2 != 0 || s.len() / 2 > out.len() {
+        return None;
+    }
+    for (i, pair) in s.chunks(2).enumerate() {
+        out[i] = hex_digit(pair[0])? << 4 | hex_digit(pair[1])?;
+    }
+    Some(s.len() / 2)
+}
+
+/// Waits for the next packet, acknowledges it and returns its data.
+///
+/// Returns `None` if GDB sent `INTERRUPT` instead.
+pub fn recv<'a, C: Connection>(conn: &mut C, buf: &'a mut [u8]) -> Option<&'a [u8]> {
+    loop {
+        match conn.read() {
+            b'$' => {}
+            INTERRUPT => return None,
+            // Acks of our last packet and noise
+            _ => continue,
+        }
+
+        let mut len = 0;
+        let mut overflow = false;
+        loop {
+            match conn.read() {
+                b'#' => break,
+                b if len < buf.len() => {
+                    buf[len] = b;
+                    len += 1;
+                }
+                _ => overflow = true,
+            }
+        }
+        let sum = [conn.read(), conn.read()];
+
+        let valid = parse_hex(&sum).map_or(false, |sum| sum as u8 == checksum(&buf[..len]));
+        if valid && !overflow {
+            conn.write(b'+');
+            return Some(&buf[..len]);
+        }
+        conn.write(b'-');
+    }
+}
+
+/// Sends `data` as a packet (until GDB acknowledges it).
+pub fn send<C: Connection>(conn: &mut C, data: &[u8]) {
+    let sum = checksum(data);
+    loop {
+        conn.write(b'$');
+        for b in data {
+            conn.write(*b);
+        }
+        conn.write(b'#');
+        conn.write(HEX[(sum >> 4) as usize]);
+        conn.write(HEX[(sum & 0xf) as usize]);
+
+        match conn.read() {
+            b'+' => return,
+            b'-' => continue,
+            // GDB doesn't talk to us before it got the packet, assume
+            // we're out of sync and it's ok.
+            _ => return,
+        }
+    }
+}
+
+/// A reply under construction.
+pub struct Response {
+    buf: [u8; MAX_PACKET_SIZE],
+    len: usize,
+}
+
+impl Response {
+    pub const fn new() -> Response {
+        Response {
+            buf: [0; MAX_PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn push(&mut self, b: u8) {
+        if self.len < self.buf.len() {
+            self.buf[self.len] = b;
+            self.len += 1;
+        }
+    }
+
+    pub fn push_str(&mut self, s: &str) {
+        for b in s.bytes() {
+            self.push(b);
+        }
+    }
+
+    /// Appends `bytes` as pairs of hex digits.
+    pub fn push_hex(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.push(HEX[(b >> 4) as usize]);
+            self.push(HEX[(b & 0xf) as usize]);
+        }
+    }
+
+    /// Appends an error reply (`Exx`).
+    pub fn error(&mut self, errno: u8) {
+        self.push(b'E');
+        self.push_hex(&[errno]);
+    }
+}
+
+impl fmt::Write for Response {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+/// A thread-id (we use one thread per core, see `gdb::tid`).
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ThreadId {
+    /// `-1`
+    All,
+    /// `0`
+    Any,
+    Id(usize),
+}
+
+impl ThreadId {
+    fn parse(s: &[u8]) -> Option<ThreadId> {
+        match s {
+            b"-1" => Some(ThreadId::All),
+            _ => match parse_hex(s)? {
+                0 => Some(ThreadId::Any),
+                id => Some(ThreadId::Id(id as usize)),
+            },
+        }
+    }
+}
+
+/// The breakpoint and watchpoint types of `Z` and `z`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum BreakpointKind {
+    Software = 0,
+    Hardware = 1,
+    Write = 2,
+    Read = 3,
+    Access = 4,
+}
+
+/// The packets we understand.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Command<'a> {
+    /// `?`
+    StopReason,
+    /// `g`
+    ReadRegisters,
+    /// `G XX...`
+    WriteRegisters(&'a [u8]),
+    /// `p n`
+    ReadRegister(usize),
+    /// `P n=XX...`
+    WriteRegister(usize, &'a [u8]),
+    /// `m addr,length`
+    ReadMemory(u64, usize),
+    /// `M addr,length:XX...`
+    WriteMemory(u64, &'a [u8]),
+    /// `c [addr]`
+    Continue(Option<u64>),
+    /// `s [addr]`
+    Step(Option<u64>),
+    /// `Z type,addr,kind`
+    InsertBreakpoint(BreakpointKind, u64, usize),
+    /// `z type,addr,kind`
+    RemoveBreakpoint(BreakpointKind, u64, usize),
+    /// `Hg thread-id`: the thread for register and memory accesses.
+    SetGeneralThread(ThreadId),
+    /// `Hc thread-id`: the thread that steps.
+    SetContinueThread(ThreadId),
+    /// `T thread-id`
+    ThreadAlive(ThreadId),
+    /// `qSupported`
+    Supported,
+    /// `qAttached`
+    Attached,
+    /// `qC`
+    CurrentThread,
+    /// `qfThreadInfo`
+    FirstThreadInfo,
+    /// `qsThreadInfo`
+    NextThreadInfo,
+    /// `qThreadExtraInfo,thread-id`
+    ThreadExtraInfo(ThreadId),
+    /// `D`
+    Detach,
+    /// `k`
+    Kill,
+    /// Anything else (we reply with an empty packet).
+    Unsupported,
+    /// Something we should understand but couldn't parse.
+    Malformed,
+}
+
+fn split(s: &[u8], sep: u8) -> Option<(&[u8], &[u8])> {
+    let at = s.iter().position(|b| *b == sep)?;
+    Some((&s[..at], &s[at + 1..]))
+}
+
+fn optional_addr(s: &[u8]) -> Option<Option<u64>> {
+    if s.is_empty() {
+        Some(None)
+    } else {
+        parse_hex(s).map(Some)
+    }
+}
+
+fn breakpoint(s: &[u8]) -> Option<(BreakpointKind, u64, usize)> {
+    let (ty, rest) = split(s, b',')?;
+    let (addr, kind) = split(rest, b',')?;
+    // Conditions and commands (`;...`) are for the target to evaluate, we
+    // don't claim to support them.
+    let kind = split(kind, b';').map_or(kind, |(kind, _)| kind);
+
+    let ty = match ty {
+        b"0" => BreakpointKind::Software,
+        b"1" => BreakpointKind::Hardware,
+        b"2" => BreakpointKind::Write,
+        b"3" => BreakpointKind::Read,
+        b"4" => BreakpointKind::Access,
+        _ => return None,
+    };
+    Some((ty, parse_hex(addr)?, parse_hex(kind)? as usize))
+}
+
+impl<'a> Command<'a> {
+    pub fn parse(packet: &'a [u8]) -> Command<'a> {
+        let (first, args) = match packet.split_first() {
+            Some((first, args)) => (*first, args),
+            None => return Command::Unsupported,
+        };
+
+        let parsed =
+            match first {
+                b'?' => Some(Command::StopReason),
+                b'g' => Some(Command::ReadRegisters),
+                b'G' => Some(Command::WriteRegisters(args)),
+                b'p' => parse_hex(args).map(|n| Command::ReadRegister(n as usize)),
+                b'P' => split(args, b'=').and_then(|(n, value)| {
+                    parse_hex(n).map(|n| Command::WriteRegister(n as usize, value))
+                }),
+                b'm' => split(args, b',').and_then(|(addr, len)| {
+                    Some(Command::ReadMemory(
+                        parse_hex(addr)?,
+                        parse_hex(len)? as usize,
+                    ))
+                }),
+                b'M' => split(args, b',').and_then(|(addr, rest)| {
+                    let (len, data) = split(rest, b':')?;
+                    let (addr, len) = (parse_hex(addr)?, parse_hex(len)? as usize);
+                    if data.len() != len * 2 {
+                        return None;
+                    }
+                    Some(Command::WriteMemory(addr, data))
+                }),
+                b'c' => optional_addr(args).map(Command::Continue),
+                b's' => optional_addr(args).map(Command::Step),
+                b'Z' => breakpoint(args)
+                    .map(|(ty, addr, kind)| Command::InsertBreakpoint(ty, addr, kind)),
+                b'z' => breakpoint(args)
+                    .map(|(ty, addr, kind)| Command::RemoveBreakpoint(ty, addr, kind)),
+                b'H' => match args.split_first() {
+                    Some((b'g', tid)) => ThreadId::parse(tid).map(Command::SetGeneralThread),
+                    Some((b'c', tid)) => ThreadId::parse(tid).map(Command::SetContinueThread),
+                    _ => return Command::Unsupported,
+                },
+                b'T' => ThreadId::parse(args).map(Command::ThreadAlive),
+                b'D' => Some(Command::Detach),
+                b'k' => Some(Command::Kill),
+                b'q' => {
+                    if args.starts_with(b"Supported") {
+                        Some(Command::Supported)
+                    } else if args.starts_with(b"Attached") {
+                        Some(Command::Attached)
+                    } else if args == b"C" {
+                        Some(Command::CurrentThread)
+                    } else if args == b"fThreadInfo" {
+                        Some(Command::FirstThreadInfo)
+                    } else if args == b"sThreadInfo" {
+                        Some(Command::NextThreadInfo)
+                    } else if let Some(tid) = args.strip_prefix(b"ThreadExtraInfo,") {
+                        ThreadId::parse(tid).map(Command::ThreadExtraInfo)
+                    } else {
+                        return Command::Unsupported;
+                    }
+                }
+                _ => return Command::Unsupported,
+            };
+
+        parsed.unwrap_or(Command::Malformed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::collections::VecDeque;
+    use alloc::vec::Vec;
+
+    struct Pipe {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl Pipe {
+        fn new(input: &[u8]) -> Pipe {
+            Pipe {
+                input: input.iter().copied().collect(),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Connection for Pipe {
+        fn read(&mut self) -> u8 {
+            self.input.pop_front().expect("read past the end")
+        }
+
+        fn poll(&mut self) -> Option<u8> {
+            self.input.pop_front()
+        }
+
+        fn write(&mut self, b: u8) {
+            self.output.push(b);
+        }
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(parse_hex(b"ffffffff81000000"), Some(0xffff_ffff_8100_0000));
+        assert_eq!(parse_hex(b"1A"), Some(0x1a));
+        assert_eq!(parse_hex(b""), None);
+        assert_eq!(parse_hex(b"1g"), None);
+        assert_eq!(parse_hex(b"11111111111111111"), None);
+
+        let mut out = [0u8; 4];
+        assert_eq!(decode_hex(b"deadbeef", &mut out), Some(4));
+        assert_eq!(out, [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(decode_hex(b"dea", &mut out), None);
+        assert_eq!(decode_hex(b"deadbeef00", &mut out), None);
+
+        let mut r = Response::new();
+        r.push_hex(&[0x0f, 0xa0]);
+        r.error(14);
+        assert_eq!(r.as_bytes(), b"0fa0E0e");
+    }
+
+    #[test]
+    fn framing() {
+        // A corrupted packet, the retransmission and an interrupt
+        let mut pipe = Pipe::new(b"+$g#00$g#67\x03");
+        let mut buf = [0u8; 16];
+        assert_eq!(recv(&mut pipe, &mut buf), Some(&b"g"[..]));
+        assert_eq!(pipe.output, b"-+");
+        assert_eq!(recv(&mut pipe, &mut buf), None);
+
+        // Too long for the buffer
+        let mut pipe = Pipe::new(b"$mmmm#b4$m#6d");
+        let mut buf = [0u8; 2];
+        assert_eq!(recv(&mut pipe, &mut buf), Some(&b"m"[..]));
+        assert_eq!(pipe.output, b"-+");
+
+        let mut pipe = Pipe::new(b"-+");
+        send(&mut pipe, b"OK");
+        assert_eq!(pipe.output, b"$OK#9a$OK#9a");
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(Command::parse(b"?"), Command::StopReason);
+        assert_eq!(Command::parse(b"p10"), Command::ReadRegister(16));
+        assert_eq!(
+            Command::parse(b"P10=0010000000000000"),
+            Command::WriteRegister(16, b"0010000000000000")
+        );
+        assert_eq!(
+            Command::parse(b"mffffffff81000000,8"),
+            Command::ReadMemory(0xffff_ffff_8100_0000, 8)
+        );
+        assert_eq!(
+            Command::parse(b"M1000,2:cc90"),
+            Command::WriteMemory(0x1000, b"cc90")
+        );
+        assert_eq!(Command::parse(b"M1000,2:cc"), Command::Malformed);
+        assert_eq!(Command::parse(b"c"), Command::Continue(None));
+        assert_eq!(Command::parse(b"s1000"), Command::Step(Some(0x1000)));
+        assert_eq!(
+            Command::parse(b"Z0,4000b0,1"),
+            Command::InsertBreakpoint(BreakpointKind::Software, 0x4000b0, 1)
+        );
+        assert_eq!(
+            Command::parse(b"z2,1000,8"),
+            Command::RemoveBreakpoint(BreakpointKind::Write, 0x1000, 8)
+        );
+        assert_eq!(Command::parse(b"Z5,1000,8"), Command::Malformed);
+        assert_eq!(
+            Command::parse(b"Hg2"),
+            Command::SetGeneralThread(ThreadId::Id(2))
+        );
+        assert_eq!(
+            Command::parse(b"Hc-1"),
+            Command::SetContinueThread(ThreadId::All)
+        );
+        assert_eq!(
+            Command::parse(b"Hg0"),
+            Command::SetGeneralThread(ThreadId::Any)
+        );
+        assert_eq!(
+            Command::parse(b"qSupported:multiprocess+;swbreak+"),
+            Command::Supported
+        );
+        assert_eq!(
+            Command::parse(b"qThreadExtraInfo,3"),
+            Command::ThreadExtraInfo(ThreadId::Id(3))
+        );
+        assert_eq!(Command::parse(b"vMustReplyEmpty"), Command::Unsupported);
+        assert_eq!(Command::parse(b""), Command::Unsupported);
+    }
+}
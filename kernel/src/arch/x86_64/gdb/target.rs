@@ -0,0 +1,497 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! What the stub does to the machine: registers of a stopped core, memory
+//! through its page-table, breakpoints and the debug registers.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::super::irq::InterruptFrame;
+use super::super::memory::{paddr_to_kernel_vaddr, PAddr};
+use super::packet::{decode_hex, BreakpointKind, Response};
+
+/// Registers in a `g` packet (the amd64 layout GDB uses without a target
+/// description): rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8-r15, rip
+/// (8 bytes each), eflags, cs, ss, ds, es, fs, gs (4 bytes each).
+pub const REGISTERS: usize = 24;
+
+fn register_size(n: usize) -> usize {
+    if n <= 16 {
+        8
+    } else {
+        4
+    }
+}
+
+/// Where register `n` lives in `frame` (`None` for the data segments, we
+/// don't save them).
+fn register(frame: &mut InterruptFrame, n: usize) -> Option<&mut u64> {
+    Some(match n {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        17 => &mut frame.rflags,
+        18 => &mut frame.cs,
+        19 => &mut frame.ss,
+        _ => return None,
+    })
+}
+
+/// Appends register `n` (little-endian hex) to `r`.
+pub fn read_register(frame: &mut InterruptFrame, n: usize, r: &mut Response) -> bool {
+    if n >= REGISTERS {
+        return false;
+    }
+    let value = register(frame, n).map_or(0, |reg| *reg);
+    r.push_hex(&value.to_le_bytes()[..register_size(n)]);
+    true
+}
+
+/// Sets register `n` from little-endian `hex`.
+///
+/// Writes to the segment registers are accepted (GDB writes all of them
+/// with `G`) and ignored, we'd fault on `iretq` with anything else.
+pub fn write_register(frame: &mut InterruptFrame, n: usize, hex: &[u8]) -> bool {
+    if n >= REGISTERS {
+        return false;
+    }
+    let mut bytes = [0u8; 8];
+    match decode_hex(hex, &mut bytes[..register_size(n)]) {
+        Some(len) if len == register_size(n) => {}
+        _ => return false,
+    }
+
+    let value = u64::from_le_bytes(bytes);
+    match (n, register(frame, n)) {
+        (18..=19, _) | (_, None) => {}
+        // Keep the reserved bit and the upper half
+        (17, Some(rflags)) => *rflags = (*rflags & !0xffff_ffff) | value | 0b10,
+        (_, Some(reg)) => *reg = value,
+    }
+    true
+}
+
+pub fn read_registers(frame: &mut InterruptFrame, r: &mut Response) {
+    for n in 0..REGISTERS {
+        read_register(frame, n, r);
+    }
+}
+
+pub fn write_registers(frame: &mut InterruptFrame, mut hex: &[u8]) -> bool {
+    // GDB may send fewer registers than we have
+    for n in 0..REGISTERS {
+        let len = register_size(n) * 2;
+        if hex.len() < len {
+            break;
+        }
+        if !write_register(frame, n, &hex[..len]) {
+            return false;
+        }
+        hex = &hex[len..];
+    }
+    hex.is_empty()
+}
+
+/// Bits of a page-table entry that hold the physical address.
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+const PRESENT: u64 = 1 << 0;
+/// A 1 GiB (PDPT) or 2 MiB (PD) mapping.
+const PAGE_SIZE: u64 = 1 << 7;
+
+fn is_canonical(vaddr: u64) -> bool {
+    ((vaddr << 16) as i64 >> 16) as u64 == vaddr
+}
+
+/// Translates `vaddr` with the 4-level page-table at `cr3`, `entry` reads
+/// entry `index` of the table at a physical address.
+///
+/// We don't use `vspace::PageTable` since the stub has to work with the
+/// tables of any process (and when the process replicas are locked).
+fn translate<F: Fn(u64, usize) -> u64>(cr3: u64, vaddr: u64, entry: F) -> Option<u64> {
+    if !is_canonical(vaddr) {
+        return None;
+    }
+
+    let mut table = cr3 & ADDRESS_MASK;
+    for level in (1..=4).rev() {
+        let shift = 12 + 9 * (level - 1);
+        let e = entry(table, ((vaddr >> shift) & 0x1ff) as usize);
+        if e & PRESENT == 0 {
+            return None;
+        }
+        if level == 1 || ((level == 2 || level == 3) && e & PAGE_SIZE != 0) {
+            let offset = (1u64 << shift) - 1;
+            return Some((e & ADDRESS_MASK & !offset) | (vaddr & offset));
+        }
+        table = e & ADDRESS_MASK;
+    }
+    unreachable!("level 1 always returns")
+}
+
+/// Where `vaddr` of the address-space `cr3` is in the kernel's physical
+/// memory mapping.
+fn kernel_alias(cr3: u64, vaddr: u64) -> Option<*mut u8> {
+    let paddr = translate(cr3, vaddr, |table, index| {
+        let table: *const u64 = paddr_to_kernel_vaddr(PAddr::from(table)).as_ptr();
+        // Safe: page-tables are in physical memory and have 512 entries
+        unsafe { *table.add(index) }
+    })?;
+    Some(paddr_to_kernel_vaddr(PAddr::from(paddr)).as_mut_ptr())
+}
+
+/// Reads `out.len()` bytes at `vaddr` of address-space `cr3`, returns how
+/// many we could read before we hit an unmapped page.
+pub fn read_memory(cr3: u64, vaddr: u64, out: &mut [u8]) -> usize {
+    for (i, b) in out.iter_mut().enumerate() {
+        match kernel_alias(cr3, vaddr.wrapping_add(i as u64)) {
+            // Safe: it's mapped
+            Some(ptr) => *b = unsafe { ptr.read_volatile() },
+            None => return i,
+        }
+    }
+    out.len()
+}
+
+/// Writes `data` to `vaddr` of address-space `cr3`.
+///
+/// This goes through the physical memory mapping so it works for
+/// read-only mappings (and kernel code) too.
+pub fn write_memory(cr3: u64, vaddr: u64, data: &[u8]) -> bool {
+    for (i, b) in data.iter().enumerate() {
+        match kernel_alias(cr3, vaddr.wrapping_add(i as u64)) {
+            // Safe: it's mapped and all other cores are stopped
+            Some(ptr) => unsafe { ptr.write_volatile(*b) },
+            None => return false,
+        }
+    }
+    true
+}
+
+/// How many `int3`s we place at most.
+const MAX_SOFTWARE_BREAKPOINTS: usize = 64;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNUSED: AtomicU64 = AtomicU64::new(0);
+/// Where our `int3`s are (0 for a free slot), for cores that hit one
+/// while another core holds the stub.
+static PLACED: [AtomicU64; MAX_SOFTWARE_BREAKPOINTS] = [UNUSED; MAX_SOFTWARE_BREAKPOINTS];
+/// DR0-DR3 and DR7, every core loads them when it leaves the stub.
+static DEBUG_REGISTERS: [AtomicU64; 5] = [UNUSED; 5];
+
+#[derive(Clone, Copy)]
+struct SoftwareBreakpoint {
+    cr3: u64,
+    addr: u64,
+    original: u8,
+}
+
+#[derive(Clone, Copy)]
+struct Watchpoint {
+    addr: u64,
+    kind: BreakpointKind,
+    len: usize,
+}
+
+/// Breakpoints and watchpoints GDB asked for.
+pub struct Breakpoints {
+    software: [Option<SoftwareBreakpoint>; MAX_SOFTWARE_BREAKPOINTS],
+    /// What goes into DR0-DR3.
+    hardware: [Option<Watchpoint>; 4],
+}
+
+impl Breakpoints {
+    pub const fn new() -> Breakpoints {
+        Breakpoints {
+            software: [None; MAX_SOFTWARE_BREAKPOINTS],
+            hardware: [None; 4],
+        }
+    }
+
+    /// Patches an `int3` at `addr` (of address-space `cr3`).
+    pub fn insert_software(&mut self, cr3: u64, addr: u64) -> bool {
+        if is_placed(addr) {
+            return true;
+        }
+        let (i, slot) = match self
+            .software
+            .iter_mut()
+            .enumerate()
+            .find(|(_i, slot)| slot.is_none())
+        {
+            Some(free) => free,
+            None => return false,
+        };
+
+        let mut original = [0u8; 1];
+        if read_memory(cr3, addr, &mut original) != 1 || !write_memory(cr3, addr, &[0xcc]) {
+            return false;
+        }
+        *slot = Some(SoftwareBreakpoint {
+            cr3,
+            addr,
+            original: original[0],
+        });
+        PLACED[i].store(addr, Ordering::SeqCst);
+        true
+    }
+
+    pub fn remove_software(&mut self, addr: u64) -> bool {
+        for (i, slot) in self.software.iter_mut().enumerate() {
+            if let Some(bp) = slot {
+                if bp.addr == addr {
+                    write_memory(bp.cr3, bp.addr, &[bp.original]);
+                    *slot = None;
+                    PLACED[i].store(0, Ordering::SeqCst);
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Takes a debug register for a hardware breakpoint (`len` is ignored
+    /// for those) or watchpoint.
+    pub fn insert_hardware(&mut self, kind: BreakpointKind, addr: u64, len: usize) -> bool {
+        let len = if kind == BreakpointKind::Hardware {
+            1
+        } else {
+            len
+        };
+        let aligned = matches!(len, 1 | 2 | 4 | 8) && addr % len as u64 == 0;
+        if !aligned || !is_canonical(addr) {
+            return false;
+        }
+
+        match self.hardware.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(Watchpoint { addr, kind, len });
+                self.publish();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove_hardware(&mut self, kind: BreakpointKind, addr: u64) -> bool {
+        for slot in self.hardware.iter_mut() {
+            if matches!(slot, Some(wp) if wp.addr == addr && wp.kind == kind) {
+                *slot = None;
+                self.publish();
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Removes everything (GDB detaches).
+    pub fn clear(&mut self) {
+        for (i, slot) in self.software.iter_mut().enumerate() {
+            if let Some(bp) = slot.take() {
+                write_memory(bp.cr3, bp.addr, &[bp.original]);
+                PLACED[i].store(0, Ordering::SeqCst);
+            }
+        }
+        self.hardware = [None; 4];
+        self.publish();
+    }
+
+    /// The breakpoint or watchpoint that triggered according to `dr6` (if
+    /// it was one of ours).
+    pub fn hit(&self, dr6: u64) -> Option<(BreakpointKind, u64)> {
+        self.hardware
+            .iter()
+            .enumerate()
+            .filter(|(i, _slot)| dr6 & (1 << i) != 0)
+            .find_map(|(_i, slot)| slot.map(|wp| (wp.kind, wp.addr)))
+    }
+
+    /// The DR7 value for our hardware breakpoints.
+    fn dr7(&self) -> u64 {
+        let mut dr7 = 0;
+        for (i, slot) in self.hardware.iter().enumerate() {
+            if let Some(wp) = slot {
+                // x86 can't watch only reads
+                let rw = match wp.kind {
+                    BreakpointKind::Software | BreakpointKind::Hardware => 0b00,
+                    BreakpointKind::Write => 0b01,
+                    BreakpointKind::Read | BreakpointKind::Access => 0b11,
+                };
+                let len = match wp.len {
+                    1 => 0b00,
+                    2 => 0b01,
+                    8 => 0b10,
+                    _ => 0b11,
+                };
+                dr7 |= 1 << (2 * i) | (rw | len << 2) << (16 + 4 * i);
+            }
+        }
+        dr7
+    }
+
+    fn publish(&self) {
+        for (i, slot) in self.hardware.iter().enumerate() {
+            DEBUG_REGISTERS[i].store(slot.map_or(0, |wp| wp.addr), Ordering::SeqCst);
+        }
+        DEBUG_REGISTERS[4].store(self.dr7(), Ordering::SeqCst);
+    }
+}
+
+/// Is there an `int3` of ours at `addr`?
+pub fn is_placed(addr: u64) -> bool {
+    addr != 0
+        && PLACED
+            .iter()
+            .any(|placed| placed.load(Ordering::SeqCst) == addr)
+}
+
+/// Programs the debug registers of the current core with the hardware
+/// breakpoints.
+pub fn load_debug_registers() {
+    let dr = |i: usize| DEBUG_REGISTERS[i].load(Ordering::SeqCst);
+    unsafe {
+        llvm_asm!("mov $0, %dr0" :: "r"(dr(0)) :: "volatile");
+        llvm_asm!("mov $0, %dr1" :: "r"(dr(1)) :: "volatile");
+        llvm_asm!("mov $0, %dr2" :: "r"(dr(2)) :: "volatile");
+        llvm_asm!("mov $0, %dr3" :: "r"(dr(3)) :: "volatile");
+        llvm_asm!("mov $0, %dr7" :: "r"(dr(4)) :: "volatile");
+    }
+}
+
+/// Reads and clears DR6 (the CPU never clears it).
+pub fn take_dr6() -> u64 {
+    let dr6: u64;
+    unsafe {
+        llvm_asm!("mov %dr6, $0" : "=r"(dr6) ::: "volatile");
+        llvm_asm!("mov $0, %dr6" :: "r"(0u64) :: "volatile");
+    }
+    dr6
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame() -> InterruptFrame {
+        InterruptFrame {
+            r15: 15,
+            r14: 14,
+            r13: 13,
+            r12: 12,
+            r11: 11,
+            r10: 10,
+            r9: 9,
+            r8: 8,
+            rbp: 6,
+            rdi: 5,
+            rsi: 4,
+            rdx: 3,
+            rcx: 2,
+            rbx: 1,
+            rax: 0,
+            vector: 3,
+            error: 0,
+            rip: 0xffff_ffff_8100_1000,
+            cs: 8,
+            rflags: 0x202,
+            rsp: 7,
+            ss: 0x10,
+        }
+    }
+
+    #[test]
+    fn registers() {
+        let mut f = frame();
+        let mut r = Response::new();
+        read_registers(&mut f, &mut r);
+        assert_eq!(r.as_bytes().len(), (17 * 8 + 7 * 4) * 2);
+        assert!(r
+            .as_bytes()
+            .starts_with(b"00000000000000000100000000000000"));
+
+        r.clear();
+        assert!(read_register(&mut f, 16, &mut r));
+        assert_eq!(r.as_bytes(), b"00100081ffffffff".as_ref());
+        assert!(!read_register(&mut f, REGISTERS, &mut r));
+
+        // `G` with what we sent for `g` changes nothing
+        r.clear();
+        read_registers(&mut f, &mut r);
+        let sent: alloc::vec::Vec<u8> = r.as_bytes().to_vec();
+        assert!(write_registers(&mut f, &sent));
+        assert_eq!(f.rip, 0xffff_ffff_8100_1000);
+        assert_eq!(f.r15, 15);
+
+        assert!(write_register(&mut f, 7, b"0010000000000000"));
+        assert_eq!(f.rsp, 0x1000);
+        assert!(write_register(&mut f, 17, b"00010000"));
+        assert_eq!(f.rflags, 0x102);
+        assert!(write_register(&mut f, 18, b"33000000"));
+        assert_eq!(f.cs, 8);
+        assert!(!write_register(&mut f, 7, b"0010"));
+    }
+
+    #[test]
+    fn page_walk() {
+        // PML4 at 0x1000 -> PDPT at 0x2000 -> PD at 0x3000 -> PT at 0x4000
+        let entry = |table: u64, index: usize| match (table, index) {
+            (0x1000, 0) => 0x2000 | PRESENT,
+            (0x1000, 511) => 0x5000 | PRESENT,
+            (0x2000, 0) => 0x3000 | PRESENT,
+            // 1 GiB page
+            (0x2000, 1) => 0x4000_0000 | PAGE_SIZE | PRESENT,
+            (0x3000, 0) => 0x4000 | PRESENT,
+            // 2 MiB page
+            (0x3000, 1) => 0x60_0000 | PAGE_SIZE | PRESENT,
+            (0x4000, 1) => 0x7000 | PRESENT | 1 << 63,
+            // Kernel half
+            (0x5000, 510) => 0x8000_0000 | PAGE_SIZE | PRESENT,
+            _ => 0,
+        };
+
+        assert_eq!(translate(0x1000, 0x1234, entry), Some(0x7234));
+        assert_eq!(translate(0x1000, 0x0, entry), None);
+        assert_eq!(translate(0x1000, 0x20_1234, entry), Some(0x60_1234));
+        assert_eq!(translate(0x1000, 0x4123_4567, entry), Some(0x4123_4567));
+        assert_eq!(
+            translate(0x1000, 0xffff_ffff_8000_1234, entry),
+            Some(0x8000_1234)
+        );
+        // Not canonical
+        assert_eq!(translate(0x1000, 0x0000_ffff_8000_1234, entry), None);
+    }
+
+    #[test]
+    fn debug_registers() {
+        let mut bps = Breakpoints::new();
+        assert!(bps.insert_hardware(BreakpointKind::Hardware, 0x1001, 1));
+        assert!(bps.insert_hardware(BreakpointKind::Write, 0x2000, 8));
+        assert!(!bps.insert_hardware(BreakpointKind::Write, 0x2004, 8));
+        assert!(!bps.insert_hardware(BreakpointKind::Access, 0x3000, 3));
+        assert_eq!(bps.dr7(), 0b1001_0000 << 16 | 0b0101);
+        assert_eq!(
+            bps.hit(0b10 | 1 << 14),
+            Some((BreakpointKind::Write, 0x2000))
+        );
+        assert_eq!(bps.hit(1 << 14), None);
+
+        assert!(bps.remove_hardware(BreakpointKind::Hardware, 0x1001));
+        assert!(!bps.remove_hardware(BreakpointKind::Hardware, 0x1001));
+        assert_eq!(bps.dr7(), 0b1001_0000 << 16 | 0b0100);
+        assert!(bps.insert_hardware(BreakpointKind::Read, 0x4000, 4));
+        assert_eq!(bps.dr7(), 0b1001_1111 << 16 | 0b0101);
+    }
+}
@@ -0,0 +1,270 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Takes cores offline at runtime and brings them back.
+//!
+//! A core that should go offline gets an IPI. If it doesn't run a process
+//! it parks right away (marks itself offline and halts with interrupts
+//! disabled), otherwise the process gets a `kpi::upcall::CORE_REVOKED`
+//! upcall and the core parks once the process hands it back with
+//! `Process::release_core`.
+//!
+//! The KCB of a parked core stays around (the core is still registered
+//! with the replicas, `/proc/replicas` just doesn't count it), bringing it
+//! back resets the core with INIT-SIPI-SIPI and reinstalls its KCB.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use apic::ApicDriver;
+use driverkit::DriverControl;
+use fallible_collections::FallibleVec;
+use log::{info, warn};
+use x86::apic::{
+    ApicId, DeliveryMode, DeliveryStatus, DestinationMode, DestinationShorthand, Icr, Level,
+    TriggerMode,
+};
+
+use crate::error::KError;
+use crate::kcb::Kcb;
+use crate::mutex::Mutex;
+use crate::stack::{OwnedStack, Stack};
+
+use super::gdt::GdtTable;
+use super::kcb::{get_kcb, Arch86Kcb};
+use super::memory::BASE_PAGE_SIZE;
+use super::{coreboot, irq, replicas, watchdog, MAX_CORES};
+
+/// How long we wait for a core to go offline (or come back).
+const TIMEOUT: Duration = Duration::from_secs(1);
+/// How often we remind a process that it should give back a core.
+const KICK_INTERVAL: Duration = Duration::from_millis(10);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_REQUEST: AtomicBool = AtomicBool::new(false);
+/// Cores that should park.
+static PARK: [AtomicBool; MAX_CORES] = [NO_REQUEST; MAX_CORES];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_PARKED: AtomicUsize = AtomicUsize::new(0);
+/// The KCBs of parked cores.
+static PARKED: [AtomicUsize; MAX_CORES] = [NOT_PARKED; MAX_CORES];
+
+/// Stacks we restarted cores on (reused the next time since a parked
+/// core doesn't come back to them).
+static STACKS: Mutex<Vec<(atopology::GlobalThreadId, OwnedStack)>> =
+    Mutex::new("hotplug_stacks", Vec::new());
+
+/// Serializes bringing cores back (they share the bootstrap code).
+static ONLINE_LOCK: Mutex<()> = Mutex::new("hotplug_online", ());
+
+/// Set by a restarted core once it's done initializing.
+static RESTARTED: AtomicBool = AtomicBool::new(false);
+
+fn thread(gtid: atopology::GlobalThreadId) -> Result<&'static atopology::Thread, KError> {
+    atopology::MACHINE_TOPOLOGY
+        .threads()
+        .find(|t| t.id == gtid)
+        .ok_or(KError::InvalidGlobalThreadId)
+}
+
+/// Makes the core with `apic_id` look at its requests (see
+/// `irq::TLB_WORK_PENDING`).
+pub(super) fn kick(apic_id: ApicId) {
+    let icr = Icr::for_x2apic(
+        irq::TLB_WORK_PENDING,
+        apic_id,
+        DestinationShorthand::NoShorthand,
+        DeliveryMode::Fixed,
+        DestinationMode::Physical,
+        DeliveryStatus::Idle,
+        Level::Assert,
+        TriggerMode::Edge,
+    );
+    unsafe { get_kcb().arch.apic().send_ipi(icr) };
+}
+
+/// Takes `gtid` offline (waits until it parked).
+///
+/// The first core of every NUMA node has to stay online because it keeps
+/// the node's replicas going.
+pub fn offline(gtid: atopology::GlobalThreadId) -> Result<(), KError> {
+    let thread = thread(gtid)?;
+    if !coreboot::is_online(gtid) {
+        return Err(KError::CoreOffline);
+    }
+    let first_on_node = thread
+        .node()
+        .and_then(|node| node.threads().next())
+        .map_or(gtid == 0, |first| first.id == gtid);
+    if first_on_node || gtid == get_kcb().arch.id() {
+        return Err(KError::CoreNotParkable);
+    }
+
+    request_park(gtid, thread)?;
+    info!("Core #{} is offline", gtid);
+    Ok(())
+}
+
+/// Asks `gtid` to park and waits until it did.
+fn request_park(gtid: atopology::GlobalThreadId, thread: &atopology::Thread) -> Result<(), KError> {
+    PARK[gtid].store(true, Ordering::Release);
+    let start = rawtime::Instant::now();
+    let mut kicked: Option<rawtime::Instant> = None;
+    while coreboot::is_online(gtid) {
+        if kicked.map_or(true, |at| at.elapsed() > KICK_INTERVAL) {
+            kick(thread.apic_id());
+            kicked = Some(rawtime::Instant::now());
+        }
+        if start.elapsed() > TIMEOUT {
+            // The request stays pending, the core parks whenever the
+            // process hands it back
+            return Err(KError::CoreBusy);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// Parks every core except the current one, including the ones that
+/// normally have to stay online (for `suspend` and `kexec`, nothing runs
+/// the replicas until the cores come back or we're gone).
+///
+/// Returns the cores it parked. If one of them doesn't park in time the
+/// ones that did come back and we return the error.
+pub fn offline_others() -> Result<Vec<atopology::GlobalThreadId>, KError> {
+    let current = get_kcb().arch.id();
+    let mut parked = Vec::try_with_capacity(atopology::MACHINE_TOPOLOGY.num_threads())?;
+    for thread in atopology::MACHINE_TOPOLOGY.threads() {
+        if thread.id == current || !coreboot::is_online(thread.id) {
+            continue;
+        }
+        if let Err(e) = request_park(thread.id, thread) {
+            // Don't leave the request around for later
+            PARK[thread.id].store(false, Ordering::Release);
+            online_all(&parked);
+            return Err(e);
+        }
+        parked.push(thread.id);
+    }
+
+    info!("Parked {} core(s)", parked.len());
+    Ok(parked)
+}
+
+/// Brings back the cores `offline_others` parked.
+pub fn online_all(cores: &[atopology::GlobalThreadId]) {
+    for gtid in cores {
+        if let Err(e) = online(*gtid) {
+            warn!("Can't bring core #{} back: {}", gtid, e);
+        }
+    }
+}
+
+/// Brings parked core `gtid` back.
+pub fn online(gtid: atopology::GlobalThreadId) -> Result<(), KError> {
+    let thread = thread(gtid)?;
+    let _guard = ONLINE_LOCK.lock();
+
+    let kcb = PARKED[gtid].load(Ordering::Acquire);
+    if kcb == 0 {
+        return Err(KError::CoreOnline);
+    }
+
+    let mut stacks = STACKS.lock();
+    if !stacks.iter().any(|(core, _stack)| *core == gtid) {
+        stacks.try_push((gtid, OwnedStack::new(BASE_PAGE_SIZE * 512)))?;
+    }
+    let stack = stacks
+        .iter()
+        .find(|(core, _stack)| *core == gtid)
+        .map(|(_core, stack)| stack as &dyn Stack)
+        .ok_or(KError::OutOfMemory)?;
+
+    RESTARTED.store(false, Ordering::SeqCst);
+    PARKED[gtid].store(0, Ordering::Release);
+    let arg = Arc::try_new(kcb)?;
+    unsafe {
+        coreboot::initialize(thread.apic_id(), restart_core, arg, &RESTARTED, stack);
+    }
+
+    let start = rawtime::Instant::now();
+    while !RESTARTED.load(Ordering::SeqCst) {
+        if start.elapsed() > TIMEOUT {
+            panic!("Core {:?} didn't come back...", thread.apic_id());
+        }
+        core::hint::spin_loop();
+    }
+
+    Ok(())
+}
+
+/// Should the current core park?
+pub fn pending() -> bool {
+    PARK[get_kcb().arch.id()].load(Ordering::Acquire)
+}
+
+/// Parks the current core (it must not run a process anymore).
+pub fn park() -> ! {
+    irq::disable();
+    watchdog::disarm();
+    // INIT doesn't get through in VMX operation
+    super::hypervisor::vmx::off();
+
+    let kcb = get_kcb();
+    debug_assert!(!kcb.arch.has_executor(), "Parking a core with a process");
+    let gtid = kcb.arch.id();
+    PARKED[gtid].store(kcb as *mut Kcb<Arch86Kcb> as usize, Ordering::Release);
+    PARK[gtid].store(false, Ordering::Release);
+    replicas::core_offline();
+    coreboot::mark_offline(gtid);
+
+    loop {
+        unsafe { x86::halt() };
+    }
+}
+
+/// Entry point (from `start_ap.S`) of a core that comes back.
+fn restart_core(kcb: Arc<usize>, initialized: &AtomicBool) {
+    reset_core(*kcb);
+
+    let gtid = get_kcb().arch.id();
+    replicas::core_online();
+    coreboot::mark_online(gtid);
+    initialized.store(true, Ordering::SeqCst);
+    info!("Core #{} is back online", gtid);
+
+    crate::scheduler::schedule()
+}
+
+/// Sets up the current core again after a reset (INIT or a suspend) and
+/// installs `kcb`, the (address of the) KCB it had before.
+pub(super) fn reset_core(kcb: usize) {
+    super::enable_sse();
+    super::enable_fsgsbase();
+    super::assert_required_cpu_features();
+    super::memory::init_pat();
+    super::syscall::enable_fast_syscalls();
+    irq::disable();
+    // In case we come back after a suspend (the TSC started over)
+    super::tsc::sync_core();
+
+    unsafe {
+        super::gdt::setup_early_gdt();
+        irq::setup_early_idt();
+    };
+
+    let kcb = unsafe { &mut *(kcb as *mut Kcb<Arch86Kcb>) };
+    // The reset didn't clear the busy flag of the TSS descriptor (and
+    // loading a busy TSS faults)
+    kcb.arch.gdt = GdtTable::new(&kcb.arch.tss);
+    kcb.install();
+    super::mce::init();
+    super::perf::init();
+    super::cpufreq::init();
+    super::user_access::init();
+
+    get_kcb().arch.apic().attach();
+}
@@ -0,0 +1,111 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The High Precision Event Timer (only its main counter, as a
+//! clocksource).
+
+use core::ptr;
+
+use log::info;
+use spin::Once;
+
+use crate::error::KError;
+use crate::memory::vspace::MapAction;
+use crate::time::clocksource::{self, ClockSource};
+
+use super::memory::PAddr;
+
+/// Size of the register block.
+const MMIO_SIZE: usize = 0x400;
+
+/// General capabilities and ID register.
+const GCAP_ID: usize = 0x0;
+/// General configuration register.
+const GEN_CONF: usize = 0x10;
+/// Main counter value register.
+const MAIN_COUNTER: usize = 0xf0;
+
+/// GCAP_ID: The main counter is 64 bits wide.
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+/// GEN_CONF: Start the main counter.
+const ENABLE_CNF: u64 = 1 << 0;
+
+/// The HPET ticks at most every 100 ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+static HPET: Once<Hpet> = Once::new();
+
+pub struct Hpet {
+    base: usize,
+    frequency: u64,
+    mask: u64,
+}
+
+impl Hpet {
+    fn read(&self, reg: usize) -> u64 {
+        unsafe { ptr::read_volatile((self.base + reg) as *const u64) }
+    }
+
+    fn write(&self, reg: usize, value: u64) {
+        unsafe { ptr::write_volatile((self.base + reg) as *mut u64, value) }
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn read(&self) -> u64 {
+        self.read(MAIN_COUNTER)
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn mask(&self) -> u64 {
+        self.mask
+    }
+
+    fn rating(&self) -> u32 {
+        // Slow to read but stable, better than a TSC that isn't invariant
+        250
+    }
+}
+
+/// Finds the HPET, starts its main counter and registers it as a
+/// clocksource.
+pub fn init() -> Result<(), KError> {
+    let base = super::acpi::hpet().ok_or(KError::NotSupported)?;
+    crate::kcb::get_kcb().arch.init_vspace().map_identity(
+        base,
+        MMIO_SIZE,
+        MapAction::ReadWriteKernel,
+    )?;
+
+    let hpet = Hpet {
+        base: base.as_usize(),
+        frequency: 0,
+        mask: 0,
+    };
+    let capabilities = hpet.read(GCAP_ID);
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        return Err(KError::NotSupported);
+    }
+    let mask = if capabilities & COUNT_SIZE_CAP != 0 {
+        u64::MAX
+    } else {
+        u32::MAX as u64
+    };
+    hpet.write(GEN_CONF, hpet.read(GEN_CONF) | ENABLE_CNF);
+
+    let hpet = HPET.call_once(|| Hpet {
+        frequency: 1_000_000_000_000_000 / period_fs,
+        mask,
+        ..hpet
+    });
+    info!("HPET at {:#x} runs at {} Hz", base, hpet.frequency);
+    clocksource::register(hpet)
+}
@@ -0,0 +1,175 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Time on Hyper-V (or hypervisors that offer its enlightenments, like KVM
+//! with `hv-time`).
+//!
+//! Hyper-V shares a reference TSC page with the guest: a scale and an
+//! offset that turn TSC values into the partition reference time (100 ns
+//! units since boot). It updates them when the guest moves to a host with
+//! a different TSC, so the page stays accurate where our own TSC
+//! calibration wouldn't. We register it as a clocksource and read the TSC
+//! frequency from a synthetic MSR if Hyper-V lets us.
+
+use core::arch::x86_64::__cpuid;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+
+use log::info;
+use spin::Once;
+use x86::msr::{rdmsr, wrmsr};
+
+use super::hypervisor;
+use crate::error::KError;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::BASE_PAGE_SIZE;
+use crate::time::clocksource::{self, ClockSource};
+
+const HV_X64_MSR_GUEST_OS_ID: u32 = 0x4000_0000;
+const HV_X64_MSR_TIME_REF_COUNT: u32 = 0x4000_0020;
+const HV_X64_MSR_REFERENCE_TSC: u32 = 0x4000_0021;
+const HV_X64_MSR_TSC_FREQUENCY: u32 = 0x4000_0022;
+/// Enables the reference TSC page.
+const REFERENCE_TSC_ENABLE: u64 = 1;
+
+/// Partition privileges (leaf base + 3, eax).
+const HV_MSR_TIME_REF_COUNT_AVAILABLE: u32 = 1 << 1;
+const HV_MSR_REFERENCE_TSC_AVAILABLE: u32 = 1 << 9;
+const HV_ACCESS_FREQUENCY_MSRS: u32 = 1 << 11;
+/// Features (leaf base + 3, edx).
+const HV_FEATURE_FREQUENCY_MSRS_AVAILABLE: u32 = 1 << 8;
+
+/// Hyper-V wants to know who we are before it enables the synthetic MSRs:
+/// an open-source OS (bit 63) of a type it doesn't know about.
+const GUEST_OS_ID: u64 = (1 << 63) | (0x7f << 56);
+
+/// The reference time counts in 100 ns units.
+const REFERENCE_TIME_FREQUENCY: u64 = 10_000_000;
+
+/// The start of the reference TSC page.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct ReferenceTsc {
+    /// 0 if the page is invalid (use the reference counter MSR instead),
+    /// changes whenever Hyper-V updates the page.
+    sequence: u32,
+    reserved: u32,
+    scale: u64,
+    offset: i64,
+}
+
+impl ReferenceTsc {
+    /// Reference time (in 100 ns units) at `tsc`.
+    fn time(&self, tsc: u64) -> u64 {
+        let scaled = ((tsc as u128 * self.scale as u128) >> 64) as u64;
+        scaled.wrapping_add(self.offset as u64)
+    }
+}
+
+static PAGE: Once<DmaBuffer> = Once::new();
+
+static REFERENCE_TSC: HypervTsc = HypervTsc;
+
+/// The reference TSC page as a clocksource.
+struct HypervTsc;
+
+impl ClockSource for HypervTsc {
+    fn name(&self) -> &'static str {
+        "hyperv-tsc"
+    }
+
+    fn read(&self) -> u64 {
+        let page = match PAGE.get() {
+            Some(page) => page.as_ptr::<ReferenceTsc>(),
+            None => return 0,
+        };
+        loop {
+            let sequence = unsafe { ptr::read_volatile(&(*page).sequence) };
+            if sequence == 0 {
+                return unsafe { rdmsr(HV_X64_MSR_TIME_REF_COUNT) };
+            }
+            fence(Ordering::Acquire);
+            let reference = unsafe { ptr::read_volatile(page) };
+            let now = reference.time(unsafe { x86::time::rdtsc() });
+            fence(Ordering::Acquire);
+            if unsafe { ptr::read_volatile(&(*page).sequence) } == sequence {
+                return now;
+            }
+        }
+    }
+
+    fn frequency(&self) -> u64 {
+        REFERENCE_TIME_FREQUENCY
+    }
+
+    fn rating(&self) -> u32 {
+        400
+    }
+}
+
+/// The privileges (eax) and features (edx) Hyper-V gives us.
+fn features() -> Option<(u32, u32)> {
+    let base = hypervisor::find(hypervisor::HYPERV)?;
+    if hypervisor::max_leaf(base) < base + 3 {
+        return None;
+    }
+    let leaf = unsafe { __cpuid(base + 3) };
+    Some((leaf.eax, leaf.edx))
+}
+
+/// Sets up the reference TSC page and registers it as a clocksource.
+pub fn init() -> Result<(), KError> {
+    let (privileges, _) = features().ok_or(KError::NotSupported)?;
+    let needed = HV_MSR_REFERENCE_TSC_AVAILABLE | HV_MSR_TIME_REF_COUNT_AVAILABLE;
+    if privileges & needed != needed {
+        return Err(KError::NotSupported);
+    }
+    if PAGE.is_completed() {
+        return Err(KError::AlreadyPresent);
+    }
+
+    let page = DmaBuffer::new(BASE_PAGE_SIZE)?;
+    let page = PAGE.call_once(|| page);
+    unsafe {
+        wrmsr(HV_X64_MSR_GUEST_OS_ID, GUEST_OS_ID);
+        wrmsr(
+            HV_X64_MSR_REFERENCE_TSC,
+            page.paddr().as_u64() | REFERENCE_TSC_ENABLE,
+        );
+    }
+    info!("Hyper-V reference TSC page at {:#x}", page.paddr());
+    clocksource::register(&REFERENCE_TSC)
+}
+
+/// The TSC frequency (in Hz) according to Hyper-V.
+pub fn tsc_frequency() -> Option<u64> {
+    let (privileges, features) = features()?;
+    if privileges & HV_ACCESS_FREQUENCY_MSRS == 0
+        || features & HV_FEATURE_FREQUENCY_MSRS_AVAILABLE == 0
+    {
+        return None;
+    }
+    let frequency = unsafe { rdmsr(HV_X64_MSR_TSC_FREQUENCY) };
+    if frequency != 0 {
+        Some(frequency)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reference_time() {
+        // A 2 GHz TSC: 200 ticks per 100 ns, scale is 2^64 / 200
+        let reference = ReferenceTsc {
+            sequence: 1,
+            scale: u64::MAX / 200,
+            offset: -50,
+            ..Default::default()
+        };
+        assert_eq!(reference.time(2_000_000_000), 10_000_000 - 1 - 50);
+    }
+}
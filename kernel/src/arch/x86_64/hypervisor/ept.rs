@@ -0,0 +1,189 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Extended page-tables: the guest-physical address space of a VM.
+//!
+//! Four levels like the page-tables of the host, the tables are base pages
+//! from the frame allocator and go back to it with the `Ept`. The frames
+//! they map belong to the VMM, the `Vm` holds a reference to them. Mappings
+//! are only ever added (a guest keeps its memory until the VM goes away), so
+//! we never have to `invept`.
+
+use alloc::vec::Vec;
+
+use fallible_collections::FallibleVec;
+use kpi::MemoryRights;
+use log::warn;
+use x86::bits64::paging::{BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+
+use crate::error::KError;
+use crate::memory::{paddr_to_kernel_vaddr, Frame};
+
+use super::vmx::zeroed_page;
+
+const READ: u64 = 1 << 0;
+const WRITE: u64 = 1 << 1;
+const EXECUTE: u64 = 1 << 2;
+/// Memory type of a leaf.
+const WRITE_BACK: u64 = 6 << 3;
+/// A leaf in a PD or PDPT.
+const LARGE: u64 = 1 << 7;
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Write-back tables with 4 levels (in the EPT pointer).
+const POINTER_FLAGS: u64 = 6 | 3 << 3;
+
+/// Guest-physical addresses end here.
+pub const MAX_GUEST_ADDRESS: u64 = 1 << 48;
+
+/// Index of `gpa` in a table at `level` (3 is the PML4, 0 a PT).
+fn index(gpa: u64, level: usize) -> usize {
+    ((gpa >> (12 + 9 * level)) & 0x1ff) as usize
+}
+
+/// The table at physical address `paddr`.
+///
+/// # Safety
+/// `paddr` has to be one of our tables.
+unsafe fn table(paddr: u64) -> &'static mut [u64; 512] {
+    &mut *paddr_to_kernel_vaddr(paddr.into()).as_mut_ptr::<[u64; 512]>()
+}
+
+/// The bits of a leaf that maps with `rights`.
+fn leaf_bits(rights: MemoryRights) -> Result<u64, KError> {
+    // Writable or executable but not readable needs more than we check for
+    if !rights.contains(MemoryRights::READ) {
+        return Err(KError::InvalidMemoryRights);
+    }
+    let mut bits = READ | WRITE_BACK;
+    if rights.contains(MemoryRights::WRITE) {
+        bits |= WRITE;
+    }
+    if rights.contains(MemoryRights::EXECUTE) {
+        bits |= EXECUTE;
+    }
+    Ok(bits)
+}
+
+pub struct Ept {
+    pml4: Frame,
+    /// The tables below the PML4.
+    tables: Vec<Frame>,
+}
+
+impl Ept {
+    pub fn new() -> Result<Ept, KError> {
+        Ok(Ept {
+            pml4: zeroed_page()?,
+            tables: Vec::new(),
+        })
+    }
+
+    /// The EPT pointer for the VMCS.
+    pub fn pointer(&self) -> u64 {
+        self.pml4.base.as_u64() | POINTER_FLAGS
+    }
+
+    /// Is nothing mapped at `gpa` down to `level`?
+    fn is_free(&self, gpa: u64, level: usize) -> bool {
+        let mut paddr = self.pml4.base.as_u64();
+        for l in (level..=3).rev() {
+            let entry = unsafe { table(paddr) }[index(gpa, l)];
+            if entry & (READ | WRITE | EXECUTE) == 0 {
+                return true;
+            }
+            if l == level || entry & LARGE != 0 {
+                return false;
+            }
+            paddr = entry & ADDRESS_MASK;
+        }
+        false
+    }
+
+    /// The entry for `gpa` in the table at `level`, adds the tables on the
+    /// way that aren't there yet.
+    fn entry(&mut self, gpa: u64, level: usize) -> Result<&'static mut u64, KError> {
+        let mut paddr = self.pml4.base.as_u64();
+        for l in ((level + 1)..=3).rev() {
+            let entry = &mut unsafe { table(paddr) }[index(gpa, l)];
+            if *entry & (READ | WRITE | EXECUTE) == 0 {
+                let frame = zeroed_page()?;
+                if let Err(e) = self.tables.try_push(frame) {
+                    if let Err(e) = crate::memory::release_frame(frame) {
+                        warn!("Leaking {:?}: {}", frame, e);
+                    }
+                    return Err(e.into());
+                }
+                *entry = frame.base.as_u64() | READ | WRITE | EXECUTE;
+            } else if *entry & LARGE != 0 {
+                return Err(KError::GuestAddressMapped);
+            }
+            paddr = *entry & ADDRESS_MASK;
+        }
+        Ok(&mut unsafe { table(paddr) }[index(gpa, level)])
+    }
+
+    /// Maps `frame` at `gpa` with `rights`, with large pages if it can.
+    pub fn map(&mut self, gpa: u64, frame: Frame, rights: MemoryRights) -> Result<(), KError> {
+        let bits = leaf_bits(rights)?;
+        let size = frame.size as u64;
+        let end = gpa.checked_add(size).ok_or(KError::InvalidGuestAddress)?;
+        if size == 0 || gpa % size.min(LARGE_PAGE_SIZE as u64) != 0 || end > MAX_GUEST_ADDRESS {
+            return Err(KError::InvalidGuestAddress);
+        }
+
+        let (page, level, bits) = if size % LARGE_PAGE_SIZE as u64 == 0 {
+            (LARGE_PAGE_SIZE as u64, 1, bits | LARGE)
+        } else {
+            (BASE_PAGE_SIZE as u64, 0, bits)
+        };
+        // We don't want to undo half a mapping
+        if !(0..size)
+            .step_by(page as usize)
+            .all(|o| self.is_free(gpa + o, level))
+        {
+            return Err(KError::GuestAddressMapped);
+        }
+        for offset in (0..size).step_by(page as usize) {
+            *self.entry(gpa + offset, level)? = (frame.base.as_u64() + offset) | bits;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Ept {
+    fn drop(&mut self) {
+        for frame in self.tables.drain(..).chain(core::iter::once(self.pml4)) {
+            if let Err(e) = crate::memory::release_frame(frame) {
+                warn!("Leaking {:?}: {}", frame, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn indices() {
+        let gpa = 0x0000_7f80_4020_1000;
+        assert_eq!(index(gpa, 3), 0xff);
+        assert_eq!(index(gpa, 2), 0x1);
+        assert_eq!(index(gpa, 1), 0x1);
+        assert_eq!(index(gpa, 0), 0x1);
+    }
+
+    #[test]
+    fn rights() {
+        assert_eq!(leaf_bits(MemoryRights::READ), Ok(READ | WRITE_BACK));
+        assert_eq!(
+            leaf_bits(MemoryRights::all()),
+            Ok(READ | WRITE | EXECUTE | WRITE_BACK)
+        );
+        assert_eq!(
+            leaf_bits(MemoryRights::WRITE),
+            Err(KError::InvalidMemoryRights)
+        );
+    }
+}
@@ -0,0 +1,55 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Hypervisors: the one we run on (if any) and the one we are.
+//!
+//! Our guests run in `vm` (on VT-x with EPT). What is below us we find out
+//! here: hypervisors report themselves in the CPUID leaves starting at
+//! 0x4000_0000 (a signature and the highest leaf they implement). Some
+//! offer the interface of another one as well (e.g., KVM with Hyper-V
+//! enlightenments reports Hyper-V at 0x4000_0000 and itself at
+//! 0x4000_0100), so we look at every 0x100 leaves.
+
+use core::arch::x86_64::__cpuid;
+
+mod ept;
+pub mod vm;
+pub mod vmx;
+
+/// "KVMKVMKVM\0\0\0"
+pub const KVM: [u32; 3] = [0x4b4d_564b, 0x564b_4d56, 0x4d];
+
+/// "Microsoft Hv"
+pub const HYPERV: [u32; 3] = [0x7263_694d, 0x666f_736f, 0x7648_2074];
+
+/// "NrkNrkNrkNrk", what our guests see.
+pub const NRK: [u32; 3] = [0x4e6b_724e, 0x724e_6b72, 0x6b72_4e6b];
+
+/// Where the hypervisor leaves start.
+const FIRST_LEAF: u32 = 0x4000_0000;
+
+/// Where we stop looking.
+const LAST_LEAF: u32 = 0x4001_0000;
+
+/// Do we run on a hypervisor?
+pub fn present() -> bool {
+    unsafe { __cpuid(1) }.ecx & (1 << 31) != 0
+}
+
+/// Returns the base leaf of the hypervisor interface with `signature`
+/// (the features are in the leaves after it).
+pub fn find(signature: [u32; 3]) -> Option<u32> {
+    if !present() {
+        return None;
+    }
+    (FIRST_LEAF..LAST_LEAF).step_by(0x100).find(|&base| {
+        let leaf = unsafe { __cpuid(base) };
+        [leaf.ebx, leaf.ecx, leaf.edx] == signature
+    })
+}
+
+/// The highest leaf the hypervisor interface at `base` implements.
+pub fn max_leaf(base: u32) -> u32 {
+    // Old versions of KVM report 0 (they implement one leaf)
+    core::cmp::max(unsafe { __cpuid(base) }.eax, base + 1)
+}
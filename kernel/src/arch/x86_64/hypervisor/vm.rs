@@ -0,0 +1,966 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Virtual machines (see `kpi::vm`): a guest-physical address space (`ept`)
+//! and the vCPUs that run in it.
+//!
+//! A vCPU has a VMCS and is bound to the core that ran it first. `run` loads
+//! the state the VMM passes in, enters the guest and stays in the loop as
+//! long as the exits are ones we handle here, then hands the state back.
+//! The host state goes into the VMCS on every run (the core may run other
+//! processes between runs, with other FS and GS bases for example).
+//!
+//! A VM holds a reference to the frames it maps (`memory::shared`), the
+//! process that gave them keeps its own. `destroy` takes the VM out of
+//! `VMS` (ids aren't used again), the VM and its references go away with
+//! the last `Arc`.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::x86_64::{CpuidResult, __cpuid_count, _rdtsc};
+
+use arrayvec::ArrayVec;
+use fallible_collections::FallibleVec;
+use kpi::vm::{Exit, ExitKind, Segment, VcpuState, INTERRUPT_PENDING, MAX_VCPUS, RCX, RDX, RSP};
+use kpi::MemoryRights;
+use log::warn;
+use spin::Mutex;
+use x86::controlregs::{cr0, cr3, cr4};
+use x86::dtables::{sgdt, sidt, DescriptorTablePointer};
+use x86::msr::{
+    rdmsr, IA32_CSTAR, IA32_EFER, IA32_FMASK, IA32_FS_BASE, IA32_GS_BASE, IA32_KERNEL_GSBASE,
+    IA32_LSTAR, IA32_PAT, IA32_STAR, IA32_SYSENTER_CS, IA32_SYSENTER_EIP, IA32_SYSENTER_ESP,
+    IA32_TIME_STAMP_COUNTER,
+};
+
+use crate::error::KError;
+use crate::memory::{paddr_to_kernel_vaddr, shared, Frame};
+
+use super::super::gdt::GdtTable;
+use super::super::kcb::get_kcb;
+use super::ept::Ept;
+use super::vmx::{self, field::*, Capabilities, PROC_INTERRUPT_WINDOW};
+use super::NRK;
+
+/// How many VMs there can be.
+pub const MAX_VMS: usize = 64;
+
+const EXIT_NMI: u64 = 0;
+const EXIT_EXTERNAL_INTERRUPT: u64 = 1;
+const EXIT_TRIPLE_FAULT: u64 = 2;
+const EXIT_INTERRUPT_WINDOW: u64 = 7;
+const EXIT_CPUID: u64 = 10;
+const EXIT_HLT: u64 = 12;
+const EXIT_CR_ACCESS: u64 = 28;
+const EXIT_IO: u64 = 30;
+const EXIT_RDMSR: u64 = 31;
+const EXIT_WRMSR: u64 = 32;
+const EXIT_EPT_VIOLATION: u64 = 48;
+/// Set in the exit reason if the VM entry failed.
+const EXIT_ENTRY_FAILED: u64 = 1 << 31;
+
+const CR0_PG: u64 = 1 << 31;
+const CR4_VMXE: u64 = 1 << 13;
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+/// SCE, LME, LMA and NXE, the guest can't set anything else.
+const EFER_BITS: u64 = 1 << 0 | EFER_LME | EFER_LMA | 1 << 11;
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// Valid bit of the interruption information.
+const INTERRUPTION_VALID: u64 = 1 << 31;
+const INTERRUPTION_NMI: u64 = 2 << 8;
+const INTERRUPTION_EXCEPTION: u64 = 3 << 8;
+const INTERRUPTION_ERROR_CODE: u64 = 1 << 11;
+const GENERAL_PROTECTION: u64 = 13;
+
+/// The MSRs the CPU swaps on VM entry and exit (the ones of `syscall` and
+/// `swapgs`, the rest are in the VMCS or not for the guest).
+const SWITCHED_MSRS: [u32; 5] = [
+    IA32_STAR,
+    IA32_LSTAR,
+    IA32_CSTAR,
+    IA32_FMASK,
+    IA32_KERNEL_GSBASE,
+];
+/// Offset of the host MSRs in `Vcpu::msrs` (the guest MSRs are at 0).
+const HOST_MSRS: u64 = 2048;
+
+/// The segments of `VcpuState` and their selector, base, limit and access
+/// fields.
+const SEGMENTS: [[u32; 4]; 8] = [
+    [
+        GUEST_CS_SELECTOR,
+        GUEST_CS_BASE,
+        GUEST_CS_LIMIT,
+        GUEST_CS_ACCESS,
+    ],
+    [
+        GUEST_DS_SELECTOR,
+        GUEST_DS_BASE,
+        GUEST_DS_LIMIT,
+        GUEST_DS_ACCESS,
+    ],
+    [
+        GUEST_ES_SELECTOR,
+        GUEST_ES_BASE,
+        GUEST_ES_LIMIT,
+        GUEST_ES_ACCESS,
+    ],
+    [
+        GUEST_FS_SELECTOR,
+        GUEST_FS_BASE,
+        GUEST_FS_LIMIT,
+        GUEST_FS_ACCESS,
+    ],
+    [
+        GUEST_GS_SELECTOR,
+        GUEST_GS_BASE,
+        GUEST_GS_LIMIT,
+        GUEST_GS_ACCESS,
+    ],
+    [
+        GUEST_SS_SELECTOR,
+        GUEST_SS_BASE,
+        GUEST_SS_LIMIT,
+        GUEST_SS_ACCESS,
+    ],
+    [
+        GUEST_TR_SELECTOR,
+        GUEST_TR_BASE,
+        GUEST_TR_LIMIT,
+        GUEST_TR_ACCESS,
+    ],
+    [
+        GUEST_LDTR_SELECTOR,
+        GUEST_LDTR_BASE,
+        GUEST_LDTR_LIMIT,
+        GUEST_LDTR_ACCESS,
+    ],
+];
+
+fn segments(state: &mut VcpuState) -> [&mut Segment; 8] {
+    [
+        &mut state.cs,
+        &mut state.ds,
+        &mut state.es,
+        &mut state.fs,
+        &mut state.gs,
+        &mut state.ss,
+        &mut state.tr,
+        &mut state.ldtr,
+    ]
+}
+
+extern "C" {
+    /// Enters the guest (see `vmx.S`).
+    fn vmx_enter(registers: *mut Registers, resume: u64) -> u64;
+    /// Where the guest exits to (`HOST_RIP`).
+    fn vmx_exit();
+}
+
+/// The guest state that isn't in the VMCS, `vmx.S` swaps it with the one
+/// of the host.
+#[repr(C, align(16))]
+struct Registers {
+    regs: [u64; 16],
+    cr2: u64,
+    _pad: u64,
+    guest_fx: [u8; 512],
+    host_fx: [u8; 512],
+}
+
+static_assertions::const_assert_eq!(memoffset::offset_of!(Registers, cr2), 16 * 8);
+static_assertions::const_assert_eq!(memoffset::offset_of!(Registers, guest_fx), 18 * 8);
+static_assertions::const_assert_eq!(memoffset::offset_of!(Registers, host_fx), 18 * 8 + 512);
+
+impl Registers {
+    fn new() -> Registers {
+        let mut registers = Registers {
+            regs: [0; 16],
+            cr2: 0,
+            _pad: 0,
+            guest_fx: [0; 512],
+            host_fx: [0; 512],
+        };
+        // FCW and MXCSR after reset
+        registers.guest_fx[0..2].copy_from_slice(&0x37fu16.to_le_bytes());
+        registers.guest_fx[24..28].copy_from_slice(&0x1f80u32.to_le_bytes());
+        registers
+    }
+}
+
+/// An entry of the MSR areas.
+#[repr(C)]
+struct MsrEntry {
+    index: u32,
+    reserved: u32,
+    value: u64,
+}
+
+fn release(frame: Frame) {
+    if let Err(e) = crate::memory::release_frame(frame) {
+        warn!("Leaking {:?}: {}", frame, e);
+    }
+}
+
+struct Vcpu {
+    vmcs: Frame,
+    /// The guest MSR area (loaded on entry, stored on exit) and the host MSR
+    /// area (loaded on exit).
+    msrs: Frame,
+    registers: Box<Registers>,
+    /// The core we run on and its epoch when we set up the VMCS.
+    core: Option<(usize, u64)>,
+    /// Do we `vmresume`?
+    launched: bool,
+}
+
+impl Vcpu {
+    fn new(revision: u32) -> Result<Vcpu, KError> {
+        let registers = Box::try_new(Registers::new())?;
+        let vmcs = vmx::zeroed_page()?;
+        let msrs = vmx::zeroed_page().map_err(|e| {
+            release(vmcs);
+            e
+        })?;
+
+        let mut vcpu = Vcpu {
+            vmcs,
+            msrs,
+            registers,
+            core: None,
+            launched: false,
+        };
+        unsafe { *paddr_to_kernel_vaddr(vmcs.base).as_mut_ptr::<u32>() = revision };
+        for host in [false, true].iter().copied() {
+            for (entry, index) in vcpu.msrs(host).iter_mut().zip(SWITCHED_MSRS.iter()) {
+                entry.index = *index;
+            }
+        }
+        Ok(vcpu)
+    }
+
+    fn msrs(&mut self, host: bool) -> &mut [MsrEntry; SWITCHED_MSRS.len()] {
+        let offset = if host { HOST_MSRS } else { 0 };
+        unsafe {
+            &mut *paddr_to_kernel_vaddr(self.msrs.base + offset)
+                .as_mut_ptr::<[MsrEntry; SWITCHED_MSRS.len()]>()
+        }
+    }
+
+    /// The guest value of MSR `index` if the CPU switches it.
+    fn guest_msr(&mut self, index: u32) -> Option<&mut u64> {
+        self.msrs(false)
+            .iter_mut()
+            .find(|e| e.index == index)
+            .map(|e| &mut e.value)
+    }
+
+    /// The part of the VMCS that doesn't change between runs.
+    fn setup(&mut self, caps: &Capabilities, eptp: u64) {
+        vmx::write(PIN_CONTROLS, caps.pin as u64);
+        vmx::write(PROC_CONTROLS, caps.proc as u64);
+        vmx::write(PROC2_CONTROLS, caps.proc2 as u64);
+        vmx::write(EXIT_CONTROLS, caps.exit as u64);
+        vmx::write(EXCEPTION_BITMAP, 0);
+        vmx::write(CR3_TARGET_COUNT, 0);
+        vmx::write(EPT_POINTER, eptp);
+        vmx::write(VMCS_LINK_POINTER, u64::MAX);
+        vmx::write(CR0_GUEST_HOST_MASK, cr0_mask(caps));
+        vmx::write(CR4_GUEST_HOST_MASK, cr4_mask(caps));
+
+        let (guest, host) = (self.msrs.base.as_u64(), self.msrs.base.as_u64() + HOST_MSRS);
+        let count = SWITCHED_MSRS.len() as u64;
+        vmx::write(ENTRY_MSR_LOAD_ADDR, guest);
+        vmx::write(ENTRY_MSR_LOAD_COUNT, count);
+        vmx::write(EXIT_MSR_STORE_ADDR, guest);
+        vmx::write(EXIT_MSR_STORE_COUNT, count);
+        vmx::write(EXIT_MSR_LOAD_ADDR, host);
+        vmx::write(EXIT_MSR_LOAD_COUNT, count);
+
+        // The rest of the guest state after reset
+        vmx::write(GUEST_PAT, 0x0007_0406_0007_0406);
+        vmx::write(GUEST_DR7, 0x400);
+        vmx::write(GUEST_DEBUGCTL, 0);
+        vmx::write(GUEST_PENDING_DEBUG, 0);
+        vmx::write(GUEST_INTERRUPTIBILITY, 0);
+        vmx::write(GUEST_ACTIVITY_STATE, 0);
+        vmx::write(ENTRY_INTERRUPTION_INFO, 0);
+        vmx::write(GUEST_SYSENTER_CS, 0);
+        vmx::write(GUEST_SYSENTER_ESP, 0);
+        vmx::write(GUEST_SYSENTER_EIP, 0);
+        for entry in self.msrs(false).iter_mut() {
+            entry.value = 0;
+        }
+    }
+
+    /// The state of the core we come back to.
+    fn set_host(&mut self) {
+        let kcb = get_kcb();
+        let (mut gdt, mut idt) = (
+            DescriptorTablePointer::<u64>::default(),
+            DescriptorTablePointer::<u64>::default(),
+        );
+        unsafe {
+            sgdt(&mut gdt);
+            sidt(&mut idt);
+
+            vmx::write(HOST_CR0, cr0().bits() as u64);
+            vmx::write(HOST_CR3, cr3());
+            vmx::write(HOST_CR4, cr4().bits() as u64);
+            vmx::write(
+                HOST_CS_SELECTOR,
+                GdtTable::kernel_cs_selector().bits() as u64,
+            );
+            vmx::write(
+                HOST_SS_SELECTOR,
+                GdtTable::kernel_ss_selector().bits() as u64,
+            );
+            for selector in [
+                HOST_DS_SELECTOR,
+                HOST_ES_SELECTOR,
+                HOST_FS_SELECTOR,
+                HOST_GS_SELECTOR,
+            ]
+            .iter()
+            {
+                vmx::write(*selector, 0);
+            }
+            // `tss_selector` without the RPL and TI bits (which are 0)
+            vmx::write(HOST_TR_SELECTOR, (GdtTable::TSS_INDEX << 3) as u64);
+            vmx::write(HOST_TR_BASE, &kcb.arch.tss as *const _ as u64);
+            vmx::write(HOST_GDTR_BASE, gdt.base as u64);
+            vmx::write(HOST_IDTR_BASE, idt.base as u64);
+            vmx::write(HOST_FS_BASE, rdmsr(IA32_FS_BASE));
+            vmx::write(HOST_GS_BASE, rdmsr(IA32_GS_BASE));
+            vmx::write(HOST_SYSENTER_CS, rdmsr(IA32_SYSENTER_CS));
+            vmx::write(HOST_SYSENTER_ESP, rdmsr(IA32_SYSENTER_ESP));
+            vmx::write(HOST_SYSENTER_EIP, rdmsr(IA32_SYSENTER_EIP));
+            vmx::write(HOST_PAT, rdmsr(IA32_PAT));
+            vmx::write(HOST_EFER, rdmsr(IA32_EFER));
+            vmx::write(HOST_RIP, vmx_exit as u64);
+
+            // KERNEL_GSBASE has the GS base of the process right now
+            for entry in self.msrs(true).iter_mut() {
+                entry.value = rdmsr(entry.index);
+            }
+        }
+    }
+
+    /// Puts `state` in the VMCS.
+    fn set_guest(&mut self, caps: &Capabilities, state: &mut VcpuState) {
+        self.registers.regs = state.regs;
+        self.registers.cr2 = state.cr2;
+        vmx::write(GUEST_RSP, state.rsp);
+        vmx::write(GUEST_RIP, state.rip);
+        vmx::write(GUEST_RFLAGS, state.rflags);
+        vmx::write(GUEST_CR3, state.cr3);
+        // The VMM gets a `Failed` exit for a state the CPU doesn't take
+        set_cr0(caps, state.cr0);
+        set_cr4(caps, state.cr4);
+        set_efer(caps, state.efer);
+        for (fields, segment) in SEGMENTS.iter().zip(segments(state).iter_mut()) {
+            vmx::write(fields[0], segment.selector);
+            vmx::write(fields[1], segment.base);
+            vmx::write(fields[2], segment.limit as u64);
+            vmx::write(fields[3], segment.access as u64);
+        }
+        vmx::write(GUEST_GDTR_BASE, state.gdt.base);
+        vmx::write(GUEST_GDTR_LIMIT, state.gdt.limit);
+        vmx::write(GUEST_IDTR_BASE, state.idt.base);
+        vmx::write(GUEST_IDTR_LIMIT, state.idt.limit);
+    }
+
+    /// Takes the state out of the VMCS.
+    fn get_guest(&self, caps: &Capabilities, state: &mut VcpuState) {
+        state.regs = self.registers.regs;
+        state.cr2 = self.registers.cr2;
+        state.rsp = vmx::read(GUEST_RSP);
+        state.rip = vmx::read(GUEST_RIP);
+        state.rflags = vmx::read(GUEST_RFLAGS);
+        state.cr3 = vmx::read(GUEST_CR3);
+        let (cr0_mask, cr4_mask) = (cr0_mask(caps), cr4_mask(caps));
+        state.cr0 = vmx::read(GUEST_CR0) & !cr0_mask | vmx::read(CR0_READ_SHADOW) & cr0_mask;
+        state.cr4 = vmx::read(GUEST_CR4) & !cr4_mask | vmx::read(CR4_READ_SHADOW) & cr4_mask;
+        state.efer = vmx::read(GUEST_EFER);
+        for (fields, segment) in SEGMENTS.iter().zip(segments(state).iter_mut()) {
+            segment.selector = vmx::read(fields[0]);
+            segment.base = vmx::read(fields[1]);
+            segment.limit = vmx::read(fields[2]) as u32;
+            segment.access = vmx::read(fields[3]) as u32;
+        }
+        state.gdt.base = vmx::read(GUEST_GDTR_BASE);
+        state.gdt.limit = vmx::read(GUEST_GDTR_LIMIT);
+        state.idt.base = vmx::read(GUEST_IDTR_BASE);
+        state.idt.limit = vmx::read(GUEST_IDTR_LIMIT);
+    }
+
+    /// Injects the interrupt of `state` if the guest takes interrupts,
+    /// otherwise asks for an exit once it does.
+    fn deliver(&mut self, caps: &Capabilities, state: &mut VcpuState) {
+        let mut controls = caps.proc;
+        if state.interrupt & INTERRUPT_PENDING != 0 {
+            let blocked = vmx::read(GUEST_INTERRUPTIBILITY) & 0b11 != 0
+                || vmx::read(ENTRY_INTERRUPTION_INFO) & INTERRUPTION_VALID != 0;
+            if vmx::read(GUEST_RFLAGS) & RFLAGS_IF != 0 && !blocked {
+                vmx::write(
+                    ENTRY_INTERRUPTION_INFO,
+                    // Type 0 is an external interrupt
+                    INTERRUPTION_VALID | (state.interrupt & 0xff),
+                );
+                state.interrupt = 0;
+            } else {
+                controls |= PROC_INTERRUPT_WINDOW;
+            }
+        }
+        vmx::write(PROC_CONTROLS, controls as u64);
+    }
+
+    /// Moves `rip` past the instruction that exited, which also ends the
+    /// blocking by `sti` or `mov ss`.
+    fn skip(&self) {
+        let rip = vmx::read(GUEST_RIP) + vmx::read(EXIT_INSTRUCTION_LENGTH);
+        vmx::write(GUEST_RIP, rip);
+        vmx::write(
+            GUEST_INTERRUPTIBILITY,
+            vmx::read(GUEST_INTERRUPTIBILITY) & !0b11,
+        );
+    }
+
+    /// Enters the guest until it exits with something for the VMM.
+    fn enter(&mut self, caps: &Capabilities, state: &mut VcpuState) -> Exit {
+        loop {
+            self.deliver(caps, state);
+            let r = unsafe { vmx_enter(&mut *self.registers, self.launched as u64) };
+            if r != 0 {
+                return Exit {
+                    kind: ExitKind::Failed as u64,
+                    reason: if r == 2 { vmx::error() } else { 0 },
+                    ..Default::default()
+                };
+            }
+            self.launched = true;
+
+            let reason = vmx::read(EXIT_REASON);
+            let qualification = vmx::read(EXIT_QUALIFICATION);
+            let exit = |kind: ExitKind| Exit {
+                kind: kind as u64,
+                reason,
+                qualification,
+                ..Default::default()
+            };
+            if reason & EXIT_ENTRY_FAILED != 0 {
+                return exit(ExitKind::Failed);
+            }
+
+            match reason & 0xffff {
+                EXIT_NMI => {
+                    let info = vmx::read(EXIT_INTERRUPTION_INFO);
+                    if info & (7 << 8) != INTERRUPTION_NMI {
+                        return exit(ExitKind::Unhandled);
+                    }
+                    // It was for us
+                    unsafe { llvm_asm!("int $$2" :::: "volatile") };
+                }
+                // The interrupt is still pending, it arrives once we're back
+                // with interrupts on
+                EXIT_EXTERNAL_INTERRUPT => return exit(ExitKind::Interrupted),
+                EXIT_TRIPLE_FAULT => return exit(ExitKind::Shutdown),
+                EXIT_INTERRUPT_WINDOW => {}
+                EXIT_CPUID => {
+                    let regs = &mut self.registers.regs;
+                    let r = cpuid(regs[0] as u32, regs[RCX] as u32);
+                    regs[0] = r.eax as u64;
+                    regs[3] = r.ebx as u64;
+                    regs[RCX] = r.ecx as u64;
+                    regs[RDX] = r.edx as u64;
+                    self.skip();
+                }
+                EXIT_HLT => {
+                    self.skip();
+                    return exit(ExitKind::Hlt);
+                }
+                EXIT_CR_ACCESS => {
+                    let (cr, access, reg) = (
+                        qualification & 0xf,
+                        (qualification >> 4) & 0x3,
+                        (qualification >> 8) & 0xf,
+                    );
+                    // Only moves to CR0 and CR4 exit with our masks
+                    if access != 0 || (cr != 0 && cr != 4) {
+                        return exit(ExitKind::Unhandled);
+                    }
+                    let value = if reg as usize == RSP {
+                        vmx::read(GUEST_RSP)
+                    } else {
+                        self.registers.regs[reg as usize]
+                    };
+                    if cr == 0 && cr0_allowed(caps, value) {
+                        set_cr0(caps, value);
+                        // Long mode starts (or ends) with paging
+                        let efer = vmx::read(GUEST_EFER);
+                        let efer = if value & CR0_PG != 0 && efer & EFER_LME != 0 {
+                            efer | EFER_LMA
+                        } else {
+                            efer & !EFER_LMA
+                        };
+                        set_efer(caps, efer);
+                        self.skip();
+                    } else if cr == 4 && cr4_allowed(caps, value) {
+                        set_cr4(caps, value);
+                        self.skip();
+                    } else {
+                        inject_gp();
+                    }
+                }
+                EXIT_IO => {
+                    let exit = io_exit(qualification, self.registers.regs[0]);
+                    if exit.kind() == ExitKind::Io {
+                        self.skip();
+                    }
+                    return Exit {
+                        reason,
+                        qualification,
+                        ..exit
+                    };
+                }
+                EXIT_RDMSR | EXIT_WRMSR => {
+                    let write = reason & 0xffff == EXIT_WRMSR;
+                    let regs = &mut self.registers.regs;
+                    let msr = regs[RCX] as u32;
+                    let data = (regs[RDX] << 32) | (regs[0] & 0xffff_ffff);
+                    self.skip();
+                    let handled = if write {
+                        self.wrmsr(msr, data)
+                    } else if let Some(value) = self.rdmsr(msr) {
+                        let regs = &mut self.registers.regs;
+                        regs[0] = value & 0xffff_ffff;
+                        regs[RDX] = value >> 32;
+                        true
+                    } else {
+                        false
+                    };
+                    if !handled {
+                        return Exit {
+                            kind: ExitKind::Msr as u64,
+                            address: msr as u64,
+                            write: write as u64,
+                            data: if write { data } else { 0 },
+                            reason,
+                            qualification,
+                            ..Default::default()
+                        };
+                    }
+                }
+                EXIT_EPT_VIOLATION => {
+                    return Exit {
+                        kind: ExitKind::Mmio as u64,
+                        address: vmx::read(GUEST_PHYSICAL_ADDRESS),
+                        write: (qualification >> 1) & 1,
+                        reason,
+                        qualification,
+                        ..Default::default()
+                    };
+                }
+                _ => return exit(ExitKind::Unhandled),
+            }
+        }
+    }
+
+    /// The MSRs of the CPU state, `None` for the ones the VMM handles.
+    fn rdmsr(&mut self, msr: u32) -> Option<u64> {
+        match msr {
+            IA32_EFER => Some(vmx::read(GUEST_EFER)),
+            IA32_PAT => Some(vmx::read(GUEST_PAT)),
+            IA32_FS_BASE => Some(vmx::read(GUEST_FS_BASE)),
+            IA32_GS_BASE => Some(vmx::read(GUEST_GS_BASE)),
+            IA32_SYSENTER_CS => Some(vmx::read(GUEST_SYSENTER_CS)),
+            IA32_SYSENTER_ESP => Some(vmx::read(GUEST_SYSENTER_ESP)),
+            IA32_SYSENTER_EIP => Some(vmx::read(GUEST_SYSENTER_EIP)),
+            IA32_TIME_STAMP_COUNTER => Some(unsafe { _rdtsc() }),
+            _ => self.guest_msr(msr).map(|v| *v),
+        }
+    }
+
+    /// Writes one of the MSRs `rdmsr` knows, `false` for the others.
+    fn wrmsr(&mut self, msr: u32, data: u64) -> bool {
+        let field = match msr {
+            IA32_EFER => {
+                // LMA follows CR0.PG
+                let lma = vmx::read(GUEST_EFER) & EFER_LMA;
+                vmx::write(GUEST_EFER, data & EFER_BITS & !EFER_LMA | lma);
+                return true;
+            }
+            IA32_PAT => GUEST_PAT,
+            IA32_FS_BASE => GUEST_FS_BASE,
+            IA32_GS_BASE => GUEST_GS_BASE,
+            IA32_SYSENTER_CS => GUEST_SYSENTER_CS,
+            IA32_SYSENTER_ESP => GUEST_SYSENTER_ESP,
+            IA32_SYSENTER_EIP => GUEST_SYSENTER_EIP,
+            _ => {
+                return match self.guest_msr(msr) {
+                    Some(value) => {
+                        *value = data;
+                        true
+                    }
+                    None => false,
+                }
+            }
+        };
+        vmx::write(field, data);
+        true
+    }
+}
+
+impl Drop for Vcpu {
+    fn drop(&mut self) {
+        release(self.vmcs);
+        release(self.msrs);
+    }
+}
+
+/// The CR0 bits the guest doesn't own: the ones VMX wants fixed and PG
+/// (long mode starts with it).
+fn cr0_mask(caps: &Capabilities) -> u64 {
+    caps.cr0.0 | !caps.cr0.1 | CR0_PG
+}
+
+/// The CR4 bits the guest doesn't own (VMXE is one of the fixed ones).
+fn cr4_mask(caps: &Capabilities) -> u64 {
+    caps.cr4.0 | !caps.cr4.1
+}
+
+/// Can the guest set CR0 to `value` (the CPU would #GP otherwise)?
+fn cr0_allowed(caps: &Capabilities, value: u64) -> bool {
+    value & !caps.cr0.1 & !CR0_PG == 0 && (value & CR0_PG == 0 || value & 1 != 0)
+}
+
+/// Can the guest set CR4 to `value` (VMXE is ours)?
+fn cr4_allowed(caps: &Capabilities, value: u64) -> bool {
+    value & (!caps.cr4.1 | CR4_VMXE) == 0
+}
+
+/// Sets the CR0 the guest sees to `value`, with the bits VMX wants.
+fn set_cr0(caps: &Capabilities, value: u64) {
+    vmx::write(CR0_READ_SHADOW, value);
+    vmx::write(GUEST_CR0, (value | caps.cr0.0) & caps.cr0.1);
+}
+
+/// Sets the CR4 the guest sees to `value`, with the bits VMX wants.
+fn set_cr4(caps: &Capabilities, value: u64) {
+    vmx::write(CR4_READ_SHADOW, value);
+    vmx::write(GUEST_CR4, (value | caps.cr4.0) & caps.cr4.1);
+}
+
+/// Sets EFER, the guest is in IA-32e mode if LMA is set.
+fn set_efer(caps: &Capabilities, efer: u64) {
+    let mut entry = caps.entry as u64;
+    if efer & EFER_LMA != 0 {
+        entry |= vmx::ENTRY_IA32E_GUEST as u64;
+    }
+    vmx::write(GUEST_EFER, efer & EFER_BITS);
+    vmx::write(ENTRY_CONTROLS, entry);
+}
+
+fn inject_gp() {
+    vmx::write(
+        ENTRY_INTERRUPTION_INFO,
+        INTERRUPTION_VALID | INTERRUPTION_EXCEPTION | INTERRUPTION_ERROR_CODE | GENERAL_PROTECTION,
+    );
+    vmx::write(ENTRY_EXCEPTION_ERROR_CODE, 0);
+}
+
+/// The `cpuid` of the guest: the one of the core without the features we
+/// don't virtualize (VMX, `monitor`, XSAVE and what needs it, CET) and with
+/// us as the hypervisor.
+fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    filter_cpuid(leaf, subleaf, unsafe { __cpuid_count(leaf, subleaf) })
+}
+
+fn filter_cpuid(leaf: u32, subleaf: u32, mut r: CpuidResult) -> CpuidResult {
+    match leaf {
+        0x1 => {
+            // MONITOR, VMX, XSAVE, OSXSAVE, AVX
+            r.ecx &= !(1 << 3 | 1 << 5 | 1 << 26 | 1 << 27 | 1 << 28);
+            r.ecx |= 1 << 31;
+        }
+        0x7 if subleaf == 0 => {
+            // AVX2 and AVX-512
+            r.ebx &= !0xdc23_0020;
+            // CET shadow stacks and IBT
+            r.ecx &= !(1 << 7);
+            r.edx &= !(1 << 20);
+        }
+        0xd => {
+            r = CpuidResult {
+                eax: 0,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            };
+        }
+        0x4000_0000 => {
+            r = CpuidResult {
+                eax: 0x4000_0000,
+                ebx: NRK[0],
+                ecx: NRK[1],
+                edx: NRK[2],
+            };
+        }
+        0x4000_0001..=0x4000_00ff => {
+            r = CpuidResult {
+                eax: 0,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            };
+        }
+        _ => {}
+    }
+    r
+}
+
+/// The exit for an I/O instruction with `qualification`, `rax` has what
+/// `out` writes.
+fn io_exit(qualification: u64, rax: u64) -> Exit {
+    // String instructions would need the segments and the guest page-tables
+    if qualification & (1 << 4) != 0 {
+        return Exit {
+            kind: ExitKind::Unhandled as u64,
+            ..Default::default()
+        };
+    }
+    let size = (qualification & 0x7) + 1;
+    let write = qualification & (1 << 3) == 0;
+    Exit {
+        kind: ExitKind::Io as u64,
+        address: qualification >> 16,
+        size,
+        write: write as u64,
+        data: if write {
+            rax & (u64::MAX >> (64 - 8 * size))
+        } else {
+            0
+        },
+        ..Default::default()
+    }
+}
+
+pub struct Vm {
+    ept: Mutex<Ept>,
+    eptp: u64,
+    vcpus: ArrayVec<Mutex<Vcpu>, MAX_VCPUS>,
+    /// The frames `ept` maps, we hold a reference to each.
+    memory: Mutex<Vec<Frame>>,
+}
+
+impl Drop for Vm {
+    fn drop(&mut self) {
+        for frame in self.memory.get_mut().drain(..) {
+            shared::release(frame);
+        }
+    }
+}
+
+/// All VMs, a VM id is its index (`None` once it's destroyed).
+pub static VMS: Mutex<Vec<Option<Arc<Vm>>>> = Mutex::new(Vec::new());
+
+fn vm(vm: usize) -> Result<Arc<Vm>, KError> {
+    VMS.lock()
+        .get(vm)
+        .cloned()
+        .flatten()
+        .ok_or(KError::VmNotFound)
+}
+
+/// Adds a VM with `vcpus` vCPUs, returns its id.
+pub fn create(vcpus: usize) -> Result<usize, KError> {
+    let caps = vmx::capabilities().ok_or(KError::VmxUnavailable)?;
+    if vcpus == 0 || vcpus > MAX_VCPUS {
+        return Err(KError::InvalidVcpu);
+    }
+    let live = |vms: &Vec<Option<Arc<Vm>>>| vms.iter().flatten().count();
+    if live(&VMS.lock()) >= MAX_VMS {
+        return Err(KError::TooManyVms);
+    }
+
+    let ept = Ept::new()?;
+    let mut vm = Vm {
+        eptp: ept.pointer(),
+        ept: Mutex::new(ept),
+        vcpus: ArrayVec::new(),
+        memory: Mutex::new(Vec::new()),
+    };
+    for _ in 0..vcpus {
+        vm.vcpus.push(Mutex::new(Vcpu::new(caps.revision)?));
+    }
+    let vm = Arc::try_new(vm)?;
+
+    let mut vms = VMS.lock();
+    if live(&vms) >= MAX_VMS {
+        return Err(KError::TooManyVms);
+    }
+    vms.try_push(Some(vm))?;
+    Ok(vms.len() - 1)
+}
+
+/// Maps `frame` at `gpa` of VM `vm`, the VM holds a reference to it until
+/// it's destroyed.
+pub fn map_guest(vm_id: usize, gpa: u64, frame: Frame, rights: MemoryRights) -> Result<(), KError> {
+    let vm = vm(vm_id)?;
+    let mut memory = vm.memory.lock();
+    memory.try_reserve(1)?;
+    shared::share(frame)?;
+    if let Err(e) = vm.ept.lock().map(gpa, frame, rights) {
+        shared::release(frame);
+        return Err(e);
+    }
+    memory.push(frame);
+    Ok(())
+}
+
+/// Removes VM `vm`, it goes away once no core uses it anymore.
+///
+/// The VMCS of a vCPU that ran has to be `vmclear`ed on its core, so this
+/// fails with `VcpuOnOtherCore` unless we're on it (and `VcpuBusy` while a
+/// vCPU runs).
+pub fn destroy(vm_id: usize) -> Result<(), KError> {
+    let vm = vm(vm_id)?;
+    let (core, epoch) = (get_kcb().arch.id(), vmx::epoch());
+    let mut vcpus: ArrayVec<_, MAX_VCPUS> = ArrayVec::new();
+    for vcpu in vm.vcpus.iter() {
+        let vcpu = vcpu.try_lock().ok_or(KError::VcpuBusy)?;
+        if matches!(vcpu.core, Some((c, _)) if c != core) {
+            return Err(KError::VcpuOnOtherCore);
+        }
+        vcpus.push(vcpu);
+    }
+
+    // Nobody finds it anymore, and no vCPU runs
+    match VMS.lock().get_mut(vm_id) {
+        Some(slot @ Some(_)) => *slot = None,
+        _ => return Err(KError::VmNotFound),
+    }
+    for vcpu in vcpus.iter_mut() {
+        // Unless the core left VMX operation (and forgot it) since
+        if let Some((_core, e)) = vcpu.core.take() {
+            if e == epoch {
+                vmx::clear(vcpu.vmcs.base.as_u64())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs vCPU `vcpu` of VM `vm` with `state` on the current core until it
+/// exits to the VMM.
+pub fn run(vm_id: usize, vcpu: usize, state: &mut VcpuState) -> Result<Exit, KError> {
+    let vm = vm(vm_id)?;
+    let mut vcpu = vm
+        .vcpus
+        .get(vcpu)
+        .ok_or(KError::InvalidVcpu)?
+        .try_lock()
+        .ok_or(KError::VcpuBusy)?;
+    vmx::enable()?;
+    let caps = vmx::capabilities().ok_or(KError::VmxUnavailable)?;
+
+    let (core, epoch) = (get_kcb().arch.id(), vmx::epoch());
+    // A VMCS moves between cores only with a `vmclear` on the old one
+    let fresh = match vcpu.core {
+        Some((c, _)) if c != core => return Err(KError::VcpuOnOtherCore),
+        Some((_, e)) => e != epoch,
+        None => true,
+    };
+    vmx::load(vcpu.vmcs.base.as_u64(), fresh)?;
+    if fresh {
+        vcpu.core = Some((core, epoch));
+        vcpu.launched = false;
+        vcpu.setup(caps, vm.eptp);
+    }
+
+    vcpu.set_host();
+    vcpu.set_guest(caps, state);
+    let exit = vcpu.enter(caps, state);
+    vcpu.get_guest(caps, state);
+    Ok(exit)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cpuid_result(value: u32) -> CpuidResult {
+        CpuidResult {
+            eax: value,
+            ebx: value,
+            ecx: value,
+            edx: value,
+        }
+    }
+
+    #[test]
+    fn cpuid_hides_features() {
+        let r = filter_cpuid(1, 0, cpuid_result(u32::MAX));
+        assert_eq!(r.ecx & (1 << 5), 0);
+        assert_eq!(r.ecx & (1 << 26), 0);
+        assert_ne!(r.ecx & (1 << 31), 0);
+        assert_eq!(r.edx, u32::MAX);
+
+        let r = filter_cpuid(7, 0, cpuid_result(u32::MAX));
+        assert_eq!(r.ecx & (1 << 7), 0);
+        assert_eq!(r.edx & (1 << 20), 0);
+        assert_eq!(filter_cpuid(7, 1, cpuid_result(u32::MAX)).ecx, u32::MAX);
+    }
+
+    #[test]
+    fn cpuid_signature() {
+        let r = filter_cpuid(0x4000_0000, 0, cpuid_result(0));
+        let mut signature = [0u8; 12];
+        signature[0..4].copy_from_slice(&r.ebx.to_le_bytes());
+        signature[4..8].copy_from_slice(&r.ecx.to_le_bytes());
+        signature[8..12].copy_from_slice(&r.edx.to_le_bytes());
+        assert_eq!(&signature, b"NrkNrkNrkNrk");
+        assert_eq!(filter_cpuid(0x4000_0001, 0, cpuid_result(7)).eax, 0);
+    }
+
+    #[test]
+    fn control_registers() {
+        let caps = Capabilities {
+            revision: 1,
+            pin: 0,
+            proc: 0,
+            proc2: 0,
+            exit: 0,
+            entry: 0,
+            cr0: (0x20, 0xffff_ffff),
+            cr4: (CR4_VMXE, 0x3f_ffff),
+        };
+        assert!(cr0_allowed(&caps, CR0_PG | 0x11));
+        // Paging without protection
+        assert!(!cr0_allowed(&caps, CR0_PG | 0x10));
+        assert!(!cr0_allowed(&caps, 1 << 32));
+        assert_ne!(cr0_mask(&caps) & CR0_PG, 0);
+        assert!(cr4_allowed(&caps, 1 << 5));
+        assert!(!cr4_allowed(&caps, CR4_VMXE));
+    }
+
+    #[test]
+    fn io() {
+        // out dx, al to 0x3f8
+        let exit = io_exit(0x3f8 << 16, 0x1234_5678);
+        assert_eq!(exit.kind(), ExitKind::Io);
+        assert_eq!((exit.address, exit.size, exit.write), (0x3f8, 1, 1));
+        assert_eq!(exit.data, 0x78);
+
+        // in eax, 0x60
+        let exit = io_exit(0x60 << 16 | 1 << 3 | 3, 0x1234_5678);
+        assert_eq!((exit.address, exit.size, exit.write), (0x60, 4, 0));
+        assert_eq!(exit.data, 0);
+
+        // rep outsb
+        assert_eq!(io_exit(1 << 4 | 1 << 5, 0).kind(), ExitKind::Unhandled);
+    }
+}
@@ -0,0 +1,414 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! VMX operation: VMXON on the cores that run guests and access to the
+//! current VMCS.
+//!
+//! A core enters VMX operation when it runs its first vCPU ([`enable`]) and
+//! has to leave it before it goes down ([`off`]): INIT is blocked in VMX
+//! operation (a parked core wouldn't come back) and neither the firmware nor
+//! a kexec'd kernel expect it. Leaving loses the VMCSs the core had loaded,
+//! the [`epoch`] of the core tells `vm` when that happened.
+//!
+//! We want the "true" control MSRs (to run without CR3 exits), EPT with
+//! 4 levels and write-back tables and unrestricted guests (for guests that
+//! start in real mode), without them VMs are [`supported`] nowhere.
+
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use log::{error, info};
+use spin::Once;
+use x86::bits64::vmx as insn;
+use x86::controlregs::{cr0, cr4, cr4_write, Cr4};
+use x86::msr::{rdmsr, wrmsr};
+
+use crate::error::KError;
+use crate::memory::{paddr_to_kernel_vaddr, Frame, PhysicalPageProvider};
+
+use super::super::kcb::get_kcb;
+use super::super::MAX_CORES;
+
+const IA32_FEATURE_CONTROL: u32 = 0x3a;
+const IA32_VMX_BASIC: u32 = 0x480;
+const IA32_VMX_CR0_FIXED0: u32 = 0x486;
+const IA32_VMX_CR0_FIXED1: u32 = 0x487;
+const IA32_VMX_CR4_FIXED0: u32 = 0x488;
+const IA32_VMX_CR4_FIXED1: u32 = 0x489;
+const IA32_VMX_PROCBASED_CTLS2: u32 = 0x48b;
+const IA32_VMX_EPT_VPID_CAP: u32 = 0x48c;
+const IA32_VMX_TRUE_PINBASED_CTLS: u32 = 0x48d;
+const IA32_VMX_TRUE_PROCBASED_CTLS: u32 = 0x48e;
+const IA32_VMX_TRUE_EXIT_CTLS: u32 = 0x48f;
+const IA32_VMX_TRUE_ENTRY_CTLS: u32 = 0x490;
+
+const FEATURE_CONTROL_LOCKED: u64 = 1 << 0;
+const FEATURE_CONTROL_VMXON: u64 = 1 << 2;
+
+const BASIC_TRUE_CONTROLS: u64 = 1 << 55;
+const EPT_4_LEVELS: u64 = 1 << 6;
+const EPT_WRITE_BACK: u64 = 1 << 14;
+
+/// Pin-based controls.
+pub const PIN_EXTERNAL_INTERRUPT: u32 = 1 << 0;
+pub const PIN_NMI: u32 = 1 << 3;
+/// Primary processor-based controls.
+pub const PROC_INTERRUPT_WINDOW: u32 = 1 << 2;
+pub const PROC_HLT: u32 = 1 << 7;
+pub const PROC_MWAIT: u32 = 1 << 10;
+pub const PROC_UNCONDITIONAL_IO: u32 = 1 << 24;
+pub const PROC_MONITOR: u32 = 1 << 29;
+pub const PROC_SECONDARY: u32 = 1 << 31;
+/// Secondary processor-based controls.
+pub const PROC2_EPT: u32 = 1 << 1;
+pub const PROC2_RDTSCP: u32 = 1 << 3;
+pub const PROC2_UNRESTRICTED: u32 = 1 << 7;
+pub const PROC2_INVPCID: u32 = 1 << 12;
+/// VM-exit controls.
+pub const EXIT_HOST_64: u32 = 1 << 9;
+pub const EXIT_SAVE_PAT: u32 = 1 << 18;
+pub const EXIT_LOAD_PAT: u32 = 1 << 19;
+pub const EXIT_SAVE_EFER: u32 = 1 << 20;
+pub const EXIT_LOAD_EFER: u32 = 1 << 21;
+/// VM-entry controls.
+pub const ENTRY_IA32E_GUEST: u32 = 1 << 9;
+pub const ENTRY_LOAD_PAT: u32 = 1 << 14;
+pub const ENTRY_LOAD_EFER: u32 = 1 << 15;
+
+/// Encodings of the VMCS fields we use.
+#[allow(dead_code)]
+pub mod field {
+    pub const GUEST_ES_SELECTOR: u32 = 0x800;
+    pub const GUEST_CS_SELECTOR: u32 = 0x802;
+    pub const GUEST_SS_SELECTOR: u32 = 0x804;
+    pub const GUEST_DS_SELECTOR: u32 = 0x806;
+    pub const GUEST_FS_SELECTOR: u32 = 0x808;
+    pub const GUEST_GS_SELECTOR: u32 = 0x80a;
+    pub const GUEST_LDTR_SELECTOR: u32 = 0x80c;
+    pub const GUEST_TR_SELECTOR: u32 = 0x80e;
+    pub const HOST_ES_SELECTOR: u32 = 0xc00;
+    pub const HOST_CS_SELECTOR: u32 = 0xc02;
+    pub const HOST_SS_SELECTOR: u32 = 0xc04;
+    pub const HOST_DS_SELECTOR: u32 = 0xc06;
+    pub const HOST_FS_SELECTOR: u32 = 0xc08;
+    pub const HOST_GS_SELECTOR: u32 = 0xc0a;
+    pub const HOST_TR_SELECTOR: u32 = 0xc0c;
+
+    pub const EXIT_MSR_STORE_ADDR: u32 = 0x2006;
+    pub const EXIT_MSR_LOAD_ADDR: u32 = 0x2008;
+    pub const ENTRY_MSR_LOAD_ADDR: u32 = 0x200a;
+    pub const EPT_POINTER: u32 = 0x201a;
+    pub const GUEST_PHYSICAL_ADDRESS: u32 = 0x2400;
+    pub const VMCS_LINK_POINTER: u32 = 0x2800;
+    pub const GUEST_DEBUGCTL: u32 = 0x2802;
+    pub const GUEST_PAT: u32 = 0x2804;
+    pub const GUEST_EFER: u32 = 0x2806;
+    pub const HOST_PAT: u32 = 0x2c00;
+    pub const HOST_EFER: u32 = 0x2c02;
+
+    pub const PIN_CONTROLS: u32 = 0x4000;
+    pub const PROC_CONTROLS: u32 = 0x4002;
+    pub const EXCEPTION_BITMAP: u32 = 0x4004;
+    pub const CR3_TARGET_COUNT: u32 = 0x400a;
+    pub const EXIT_CONTROLS: u32 = 0x400c;
+    pub const EXIT_MSR_STORE_COUNT: u32 = 0x400e;
+    pub const EXIT_MSR_LOAD_COUNT: u32 = 0x4010;
+    pub const ENTRY_CONTROLS: u32 = 0x4012;
+    pub const ENTRY_MSR_LOAD_COUNT: u32 = 0x4014;
+    pub const ENTRY_INTERRUPTION_INFO: u32 = 0x4016;
+    pub const ENTRY_EXCEPTION_ERROR_CODE: u32 = 0x4018;
+    pub const PROC2_CONTROLS: u32 = 0x401e;
+    pub const INSTRUCTION_ERROR: u32 = 0x4400;
+    pub const EXIT_REASON: u32 = 0x4402;
+    pub const EXIT_INTERRUPTION_INFO: u32 = 0x4404;
+    pub const EXIT_INSTRUCTION_LENGTH: u32 = 0x440c;
+
+    pub const GUEST_ES_LIMIT: u32 = 0x4800;
+    pub const GUEST_CS_LIMIT: u32 = 0x4802;
+    pub const GUEST_SS_LIMIT: u32 = 0x4804;
+    pub const GUEST_DS_LIMIT: u32 = 0x4806;
+    pub const GUEST_FS_LIMIT: u32 = 0x4808;
+    pub const GUEST_GS_LIMIT: u32 = 0x480a;
+    pub const GUEST_LDTR_LIMIT: u32 = 0x480c;
+    pub const GUEST_TR_LIMIT: u32 = 0x480e;
+    pub const GUEST_GDTR_LIMIT: u32 = 0x4810;
+    pub const GUEST_IDTR_LIMIT: u32 = 0x4812;
+    pub const GUEST_ES_ACCESS: u32 = 0x4814;
+    pub const GUEST_CS_ACCESS: u32 = 0x4816;
+    pub const GUEST_SS_ACCESS: u32 = 0x4818;
+    pub const GUEST_DS_ACCESS: u32 = 0x481a;
+    pub const GUEST_FS_ACCESS: u32 = 0x481c;
+    pub const GUEST_GS_ACCESS: u32 = 0x481e;
+    pub const GUEST_LDTR_ACCESS: u32 = 0x4820;
+    pub const GUEST_TR_ACCESS: u32 = 0x4822;
+    pub const GUEST_INTERRUPTIBILITY: u32 = 0x4824;
+    pub const GUEST_ACTIVITY_STATE: u32 = 0x4826;
+    pub const GUEST_SYSENTER_CS: u32 = 0x482a;
+    pub const HOST_SYSENTER_CS: u32 = 0x4c00;
+
+    pub const CR0_GUEST_HOST_MASK: u32 = 0x6000;
+    pub const CR4_GUEST_HOST_MASK: u32 = 0x6002;
+    pub const CR0_READ_SHADOW: u32 = 0x6004;
+    pub const CR4_READ_SHADOW: u32 = 0x6006;
+    pub const EXIT_QUALIFICATION: u32 = 0x6400;
+
+    pub const GUEST_CR0: u32 = 0x6800;
+    pub const GUEST_CR3: u32 = 0x6802;
+    pub const GUEST_CR4: u32 = 0x6804;
+    pub const GUEST_ES_BASE: u32 = 0x6806;
+    pub const GUEST_CS_BASE: u32 = 0x6808;
+    pub const GUEST_SS_BASE: u32 = 0x680a;
+    pub const GUEST_DS_BASE: u32 = 0x680c;
+    pub const GUEST_FS_BASE: u32 = 0x680e;
+    pub const GUEST_GS_BASE: u32 = 0x6810;
+    pub const GUEST_LDTR_BASE: u32 = 0x6812;
+    pub const GUEST_TR_BASE: u32 = 0x6814;
+    pub const GUEST_GDTR_BASE: u32 = 0x6816;
+    pub const GUEST_IDTR_BASE: u32 = 0x6818;
+    pub const GUEST_DR7: u32 = 0x681a;
+    pub const GUEST_RSP: u32 = 0x681c;
+    pub const GUEST_RIP: u32 = 0x681e;
+    pub const GUEST_RFLAGS: u32 = 0x6820;
+    pub const GUEST_PENDING_DEBUG: u32 = 0x6822;
+    pub const GUEST_SYSENTER_ESP: u32 = 0x6824;
+    pub const GUEST_SYSENTER_EIP: u32 = 0x6826;
+
+    pub const HOST_CR0: u32 = 0x6c00;
+    pub const HOST_CR3: u32 = 0x6c02;
+    pub const HOST_CR4: u32 = 0x6c04;
+    pub const HOST_FS_BASE: u32 = 0x6c06;
+    pub const HOST_GS_BASE: u32 = 0x6c08;
+    pub const HOST_TR_BASE: u32 = 0x6c0a;
+    pub const HOST_GDTR_BASE: u32 = 0x6c0c;
+    pub const HOST_IDTR_BASE: u32 = 0x6c0e;
+    pub const HOST_SYSENTER_ESP: u32 = 0x6c10;
+    pub const HOST_SYSENTER_EIP: u32 = 0x6c12;
+    pub const HOST_RIP: u32 = 0x6c16;
+}
+
+/// What the cores support (the same on all of them).
+#[derive(Debug, Clone, Copy)]
+pub struct Capabilities {
+    /// The revision of the VMCS format.
+    pub revision: u32,
+    pub pin: u32,
+    pub proc: u32,
+    pub proc2: u32,
+    pub exit: u32,
+    pub entry: u32,
+    /// The bits of CR0 and CR4 that have to be set in a guest (`.0`) and the
+    /// ones that may be set (`.1`).
+    pub cr0: (u64, u64),
+    pub cr4: (u64, u64),
+}
+
+static CAPABILITIES: Once<Option<Capabilities>> = Once::new();
+
+const NOT_ENABLED: AtomicBool = AtomicBool::new(false);
+const NO_REGION: AtomicU64 = AtomicU64::new(0);
+const FIRST_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// Is the core in VMX operation?
+static ENABLED: [AtomicBool; MAX_CORES] = [NOT_ENABLED; MAX_CORES];
+/// The VMXON region of the core (we keep it once we allocated it).
+static REGIONS: [AtomicU64; MAX_CORES] = [NO_REGION; MAX_CORES];
+/// How many times the core left VMX operation.
+static EPOCHS: [AtomicU64; MAX_CORES] = [FIRST_EPOCH; MAX_CORES];
+
+/// The controls with the bits of `required` and the bits of `optional` the
+/// core has (`msr` tells which), `None` if it lacks some of `required`.
+fn adjust(msr: u32, required: u32, optional: u32) -> Option<u32> {
+    let caps = unsafe { rdmsr(msr) };
+    let (must, may) = (caps as u32, (caps >> 32) as u32);
+    if required & !may != 0 {
+        return None;
+    }
+    Some(must | required | (optional & may))
+}
+
+fn probe() -> Option<Capabilities> {
+    if unsafe { __cpuid(1) }.ecx & (1 << 5) == 0 {
+        return None;
+    }
+    let basic = unsafe { rdmsr(IA32_VMX_BASIC) };
+    if basic & BASIC_TRUE_CONTROLS == 0 {
+        return None;
+    }
+    let proc = adjust(
+        IA32_VMX_TRUE_PROCBASED_CTLS,
+        PROC_HLT | PROC_MWAIT | PROC_UNCONDITIONAL_IO | PROC_MONITOR | PROC_SECONDARY,
+        0,
+    )?;
+    let proc2 = adjust(
+        IA32_VMX_PROCBASED_CTLS2,
+        PROC2_EPT | PROC2_UNRESTRICTED,
+        PROC2_RDTSCP | PROC2_INVPCID,
+    )?;
+    let ept = unsafe { rdmsr(IA32_VMX_EPT_VPID_CAP) };
+    if ept & EPT_4_LEVELS == 0 || ept & EPT_WRITE_BACK == 0 {
+        return None;
+    }
+
+    // Protection and paging are up to an unrestricted guest
+    let pe_pg = 1 << 0 | 1 << 31;
+    Some(Capabilities {
+        revision: basic as u32 & 0x7fff_ffff,
+        pin: adjust(
+            IA32_VMX_TRUE_PINBASED_CTLS,
+            PIN_EXTERNAL_INTERRUPT | PIN_NMI,
+            0,
+        )?,
+        proc,
+        proc2,
+        exit: adjust(
+            IA32_VMX_TRUE_EXIT_CTLS,
+            EXIT_HOST_64 | EXIT_SAVE_PAT | EXIT_LOAD_PAT | EXIT_SAVE_EFER | EXIT_LOAD_EFER,
+            0,
+        )?,
+        entry: adjust(
+            IA32_VMX_TRUE_ENTRY_CTLS,
+            ENTRY_LOAD_PAT | ENTRY_LOAD_EFER,
+            0,
+        )?,
+        cr0: unsafe {
+            (
+                rdmsr(IA32_VMX_CR0_FIXED0) & !pe_pg,
+                rdmsr(IA32_VMX_CR0_FIXED1),
+            )
+        },
+        cr4: unsafe { (rdmsr(IA32_VMX_CR4_FIXED0), rdmsr(IA32_VMX_CR4_FIXED1)) },
+    })
+}
+
+/// What the cores support, `None` if we can't run guests on them.
+pub fn capabilities() -> Option<&'static Capabilities> {
+    CAPABILITIES
+        .call_once(|| {
+            let caps = probe();
+            info!("VT-x: {}", if caps.is_some() { "yes" } else { "no" });
+            caps
+        })
+        .as_ref()
+}
+
+/// Can we run guests?
+pub fn supported() -> bool {
+    capabilities().is_some()
+}
+
+/// How many times the current core left VMX operation.
+pub fn epoch() -> u64 {
+    EPOCHS[get_kcb().arch.id()].load(Ordering::Acquire)
+}
+
+/// A zeroed base page from the frame allocator.
+pub(super) fn zeroed_page() -> Result<Frame, KError> {
+    crate::memory::KernelAllocator::try_refill_tcache(1, 0)?;
+    let mut frame = get_kcb().mem_manager().allocate_base_page()?;
+    unsafe { frame.zero() };
+    Ok(frame)
+}
+
+/// Puts the current core in VMX operation (if it isn't yet).
+pub fn enable() -> Result<(), KError> {
+    let kcb = get_kcb();
+    let core = kcb.arch.id();
+    if ENABLED[core].load(Ordering::Acquire) {
+        return Ok(());
+    }
+    let caps = capabilities().ok_or(KError::VmxUnavailable)?;
+    // TODO(correctness): Exits would have to load the CET state
+    if kcb.arch.shadow_stack_token != 0 {
+        return Err(KError::VmxUnavailable);
+    }
+    if unsafe { cr0() }.bits() as u64 & caps.cr0.0 != caps.cr0.0 {
+        return Err(KError::VmxUnavailable);
+    }
+
+    unsafe {
+        let control = rdmsr(IA32_FEATURE_CONTROL);
+        if control & FEATURE_CONTROL_LOCKED == 0 {
+            wrmsr(
+                IA32_FEATURE_CONTROL,
+                control | FEATURE_CONTROL_LOCKED | FEATURE_CONTROL_VMXON,
+            );
+        } else if control & FEATURE_CONTROL_VMXON == 0 {
+            // The firmware turned it off
+            return Err(KError::VmxUnavailable);
+        }
+    }
+
+    let mut region = REGIONS[core].load(Ordering::Acquire);
+    if region == 0 {
+        region = zeroed_page()?.base.as_u64();
+        REGIONS[core].store(region, Ordering::Release);
+    }
+    unsafe {
+        let revision = paddr_to_kernel_vaddr(region.into()).as_mut_ptr::<u32>();
+        *revision = caps.revision;
+
+        cr4_write(cr4() | Cr4::CR4_ENABLE_VMX);
+        if insn::vmxon(region).is_err() {
+            cr4_write(cr4() & !Cr4::CR4_ENABLE_VMX);
+            error!("VMXON failed on core {}", core);
+            return Err(KError::VmxUnavailable);
+        }
+    }
+    ENABLED[core].store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Takes the current core out of VMX operation (if it is in it).
+///
+/// The VMCSs the core had loaded are gone (we don't `vmclear` them, `vm`
+/// sets them up from scratch once the epoch changed).
+pub fn off() {
+    let core = get_kcb().arch.id();
+    if !ENABLED[core].swap(false, Ordering::AcqRel) {
+        return;
+    }
+    EPOCHS[core].fetch_add(1, Ordering::AcqRel);
+    unsafe {
+        let _r = insn::vmxoff();
+        cr4_write(cr4() & !Cr4::CR4_ENABLE_VMX);
+    }
+}
+
+/// Makes the VMCS at `vmcs` the current one, `vmclear`s it first if
+/// `clear`.
+pub fn load(vmcs: u64, clear: bool) -> Result<(), KError> {
+    unsafe {
+        if clear {
+            insn::vmclear(vmcs).map_err(|_e| KError::VmxFailed { error: error() })?;
+        }
+        insn::vmptrld(vmcs).map_err(|_e| KError::VmxFailed { error: error() })
+    }
+}
+
+/// Writes the VMCS at `vmcs` back to memory, the core forgets it (has to
+/// be the core that loaded it).
+pub fn clear(vmcs: u64) -> Result<(), KError> {
+    unsafe { insn::vmclear(vmcs) }.map_err(|_e| KError::VmxFailed { error: error() })
+}
+
+/// Reads `field` of the current VMCS.
+pub fn read(field: u32) -> u64 {
+    unsafe { insn::vmread(field) }.unwrap_or_else(|_e| {
+        error!("Can't read VMCS field {:#x}", field);
+        0
+    })
+}
+
+/// Writes `field` of the current VMCS (the next VM entry fails if that
+/// doesn't work, so it doesn't matter much that we don't return an error).
+pub fn write(field: u32, value: u64) {
+    if unsafe { insn::vmwrite(field, value) }.is_err() {
+        error!("Can't write {:#x} to VMCS field {:#x}", value, field);
+    }
+}
+
+/// The VM-instruction error of the last instruction that failed (0 if there
+/// is no current VMCS).
+pub fn error() -> u64 {
+    unsafe { insn::vmread(field::INSTRUCTION_ERROR) }.unwrap_or(0)
+}
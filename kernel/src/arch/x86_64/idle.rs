@@ -0,0 +1,181 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Idle states of x86 cores (`crate::idle` picks one).
+//!
+//! `Poll` spins. The other states halt the core: with `mwait` and the hint
+//! of the C-state if the CPU has MONITOR/MWAIT (C1E and C6 only if CPUID
+//! leaf 5 lists their sub-state), and with `hlt` for C1 otherwise. Most
+//! hypervisors don't give guests `mwait`, so they end up with poll and C1.
+//!
+//! An interrupt wakes the core up and doesn't return to where it went idle:
+//! the interrupt handler calls [`exit`], which adds the time since [`enter`]
+//! to the residency of the state (in the KCB stats) and tells the governor.
+
+use arrayvec::ArrayVec;
+use kpi::system::IdleState;
+use log::info;
+use spin::{Mutex, Once};
+use x86::time::rdtsc;
+
+use super::kcb::get_kcb;
+use super::{irq, timer, tsc, MAX_CORES};
+use crate::idle::Governor;
+use crate::stats::IDLE_STATES;
+use crate::time::clocksource::ticks_to_duration;
+
+/// `mwait` hints (C-state in bits 7:4, sub-state in bits 3:0).
+const C1_HINT: u32 = 0x00;
+const C1E_HINT: u32 = 0x01;
+const C6_HINT: u32 = 0x20;
+
+/// What the CPU has.
+#[derive(Debug, Copy, Clone)]
+struct Support {
+    mwait: bool,
+    c1e: bool,
+    c6: bool,
+}
+
+impl Support {
+    fn detect() -> Support {
+        let max_leaf = x86::cpuid::cpuid!(0x0).eax;
+        let monitor = x86::cpuid::cpuid!(0x1).ecx & (1 << 3) != 0;
+        if !monitor || max_leaf < 0x5 {
+            return Support {
+                mwait: false,
+                c1e: false,
+                c6: false,
+            };
+        }
+
+        let mwait = x86::cpuid::cpuid!(0x5);
+        // EDX has the number of sub-states of every C-state (4 bits each,
+        // starting with C0) if ECX bit 0 says so
+        let substates = |cstate: u32| match mwait.ecx & 0x1 {
+            0 => 0,
+            _ => (mwait.edx >> (4 * cstate)) & 0xf,
+        };
+        Support {
+            mwait: true,
+            c1e: substates((C1E_HINT >> 4) + 1) > (C1E_HINT & 0xf),
+            c6: substates((C6_HINT >> 4) + 1) > (C6_HINT & 0xf),
+        }
+    }
+
+    fn has(&self, state: IdleState) -> bool {
+        match state {
+            IdleState::Poll | IdleState::C1 => true,
+            IdleState::C1E => self.c1e,
+            IdleState::C6 => self.c6,
+        }
+    }
+}
+
+fn support() -> Support {
+    static SUPPORT: Once<Support> = Once::new();
+    *SUPPORT.call_once(|| {
+        let support = Support::detect();
+        info!(
+            "Idle states: poll, C1 ({}){}{}",
+            if support.mwait { "mwait" } else { "hlt" },
+            if support.c1e { ", C1E" } else { "" },
+            if support.c6 { ", C6" } else { "" },
+        );
+        support
+    })
+}
+
+/// Idle bookkeeping of a core.
+struct Core {
+    governor: Governor,
+    /// The state the core is in and the TSC value when it went there.
+    entered: Option<(IdleState, u64)>,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const AWAKE: Mutex<Core> = Mutex::new(Core {
+    governor: Governor::new(),
+    entered: None,
+});
+static CORES: [Mutex<Core>; MAX_CORES] = [AWAKE; MAX_CORES];
+
+/// Puts the core in the idle state the governor picks until the next
+/// interrupt.
+///
+/// Called with interrupts disabled, set the timer first.
+pub fn enter() -> ! {
+    crate::rcu::quiescent();
+    let kcb = get_kcb();
+    let id = kcb.arch.id();
+    let support = support();
+    let states: ArrayVec<IdleState, IDLE_STATES> = IdleState::ALL
+        .iter()
+        .copied()
+        .filter(|s| *s <= kcb.config.idle && support.has(*s))
+        .collect();
+
+    let now = unsafe { rdtsc() };
+    let until_timer = match timer::next() {
+        0 => u64::MAX,
+        next => ticks_to_duration(next.saturating_sub(now), tsc::frequency()).as_nanos() as u64,
+    };
+    let state = {
+        let mut idle = CORES[id].lock();
+        let state = idle.governor.select(until_timer, &states);
+        idle.entered = Some((state, now));
+        state
+    };
+    kcb.stats.idle_entered(state);
+
+    let hint = match state {
+        IdleState::Poll => {
+            irq::enable();
+            loop {
+                core::hint::spin_loop();
+            }
+        }
+        IdleState::C1 if !support.mwait => {
+            irq::enable();
+            loop {
+                unsafe { x86::halt() };
+            }
+        }
+        IdleState::C1 => C1_HINT,
+        IdleState::C1E => C1E_HINT,
+        IdleState::C6 => C6_HINT,
+    };
+
+    // Nobody writes the line we monitor, `mwait` also returns for other
+    // reasons than interrupts though
+    let line = &CORES[id] as *const Mutex<Core> as u64;
+    loop {
+        unsafe { mwait(line, hint) };
+    }
+}
+
+/// Monitors `line` and waits in the C-state of `hint`, enables interrupts.
+unsafe fn mwait(line: u64, hint: u32) {
+    llvm_asm!("monitor" :: "{rax}" (line), "{ecx}" (0), "{edx}" (0) :: "volatile");
+    // `sti` takes effect after `mwait`, an interrupt can't sneak in between
+    llvm_asm!("
+        sti
+        mwait
+        " ::
+        "{eax}" (hint),
+        "{ecx}" (0)
+        : "memory" : "volatile");
+}
+
+/// Ends the idle period of the core (if it's in one), called by interrupt
+/// handlers.
+pub fn exit() {
+    let kcb = get_kcb();
+    let mut idle = CORES[kcb.arch.id()].lock();
+    if let Some((state, since)) = idle.entered.take() {
+        let elapsed = unsafe { rdtsc() }.saturating_sub(since);
+        let ns = ticks_to_duration(elapsed, tsc::frequency()).as_nanos() as u64;
+        idle.governor.record(ns);
+        kcb.stats.idle_exited(state, ns);
+    }
+}
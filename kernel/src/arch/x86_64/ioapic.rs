@@ -0,0 +1,308 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! IO-APIC driver.
+//!
+//! Every IO-APIC handles a range of global system interrupts (GSIs) and
+//! has a redirection entry per GSI that says which vector to raise on
+//! which core. ISA IRQs are connected to the GSI with the same number,
+//! unless the MADT has an interrupt source override for them (QEMU e.g.
+//! connects the PIT to GSI 2) which may also change their polarity and
+//! trigger mode.
+
+use alloc::vec::Vec;
+use core::ptr;
+
+use fallible_collections::vec::FallibleVec;
+use log::info;
+use spin::Mutex;
+
+use crate::acpi::InterruptOverride;
+use crate::error::KError;
+use crate::memory::vspace::MapAction;
+
+use super::kcb::get_kcb;
+use super::memory::{paddr_to_kernel_vaddr, PAddr, BASE_PAGE_SIZE, KERNEL_BASE};
+
+/// Register select and data window (memory mapped).
+const REG_SELECT: usize = 0x00;
+const REG_WINDOW: usize = 0x10;
+
+/// Version register, bits 16-23 are the index of the last redirection
+/// entry.
+const REG_VERSION: u32 = 0x01;
+/// The 64-bit redirection entries start here (two registers each).
+const REG_REDIRECTION: u32 = 0x10;
+
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_LEVEL: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+const ENTRY_DESTINATION_SHIFT: u64 = 56;
+
+/// MPS INTI flags of an interrupt source override.
+const INTI_POLARITY_MASK: u16 = 0b11;
+const INTI_ACTIVE_LOW: u16 = 0b11;
+const INTI_TRIGGER_MASK: u16 = 0b11 << 2;
+const INTI_LEVEL: u16 = 0b11 << 2;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// How an interrupt line is connected to the IO-APICs.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Route {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+impl Route {
+    /// Where ISA `irq` ends up (ISA interrupts are edge triggered and
+    /// active high unless an override says otherwise).
+    fn isa(irq: u8, overrides: &[InterruptOverride]) -> Route {
+        match overrides.iter().find(|o| o.irq == irq) {
+            Some(o) => Route {
+                gsi: o.gsi,
+                polarity: if o.flags & INTI_POLARITY_MASK == INTI_ACTIVE_LOW {
+                    Polarity::ActiveLow
+                } else {
+                    Polarity::ActiveHigh
+                },
+                trigger: if o.flags & INTI_TRIGGER_MASK == INTI_LEVEL {
+                    TriggerMode::Level
+                } else {
+                    TriggerMode::Edge
+                },
+            },
+            None => Route {
+                gsi: irq as u32,
+                polarity: Polarity::ActiveHigh,
+                trigger: TriggerMode::Edge,
+            },
+        }
+    }
+
+    /// The redirection entry that raises (fixed delivery, physical
+    /// destination) `vector` on the core with `apic_id`.
+    fn entry(&self, vector: u8, apic_id: u32) -> u64 {
+        let mut entry = vector as u64 | (apic_id as u64) << ENTRY_DESTINATION_SHIFT;
+        if self.polarity == Polarity::ActiveLow {
+            entry |= ENTRY_ACTIVE_LOW;
+        }
+        if self.trigger == TriggerMode::Level {
+            entry |= ENTRY_LEVEL;
+        }
+        entry
+    }
+}
+
+struct IoApic {
+    id: u8,
+    base: usize,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + REG_SELECT) as *mut u32, reg);
+            ptr::read_volatile((self.base + REG_WINDOW) as *const u32)
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + REG_SELECT) as *mut u32, reg);
+            ptr::write_volatile((self.base + REG_WINDOW) as *mut u32, value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    fn entry(&self, gsi: u32) -> u64 {
+        let reg = REG_REDIRECTION + 2 * (gsi - self.gsi_base);
+        (self.read(reg + 1) as u64) << 32 | self.read(reg) as u64
+    }
+
+    fn set_entry(&self, gsi: u32, entry: u64) {
+        let reg = REG_REDIRECTION + 2 * (gsi - self.gsi_base);
+        // Mask while the halves disagree, the low half unmasks
+        self.write(reg, ENTRY_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+/// The IO-APICs and their (shared) select/window registers.
+static IOAPICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+
+/// Maps all IO-APICs and masks every entry.
+pub fn init() -> Result<(), KError> {
+    let mut io_apics: Vec<(u8, u32, u32)> = Vec::new();
+    match crate::acpi::platform() {
+        Some(platform) => {
+            for io_apic in platform.madt.io_apics.iter() {
+                io_apics.try_push((io_apic.id, io_apic.address, io_apic.gsi_base))?;
+            }
+        }
+        None => {
+            for io_apic in atopology::MACHINE_TOPOLOGY.io_apics() {
+                io_apics.try_push((io_apic.id, io_apic.address, io_apic.global_irq_base))?;
+            }
+        }
+    }
+
+    let mut ioapics = IOAPICS.lock();
+    for (id, address, gsi_base) in io_apics {
+        let paddr = PAddr::from(address as u64);
+        get_kcb().arch.init_vspace().map_identity_with_offset(
+            PAddr::from(KERNEL_BASE),
+            paddr,
+            BASE_PAGE_SIZE,
+            MapAction::ReadWriteKernel,
+        )?;
+
+        let mut io_apic = IoApic {
+            id,
+            base: paddr_to_kernel_vaddr(paddr).as_usize(),
+            gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = ((io_apic.read(REG_VERSION) >> 16) & 0xff) + 1;
+        for gsi in gsi_base..gsi_base + io_apic.entries {
+            io_apic.set_entry(gsi, ENTRY_MASKED);
+        }
+
+        info!(
+            "IO-APIC {} at {:#x} handles GSI {}..{}",
+            io_apic.id,
+            address,
+            gsi_base,
+            gsi_base + io_apic.entries
+        );
+        ioapics.try_push(io_apic)?;
+    }
+
+    Ok(())
+}
+
+/// Where ISA `irq` is connected.
+pub fn isa_route(irq: u8) -> Route {
+    let overrides = crate::acpi::platform().map_or(&[][..], |p| p.madt.overrides.as_slice());
+    Route::isa(irq, overrides)
+}
+
+/// Raises `vector` on the core with `apic_id` whenever the device behind
+/// `route` interrupts.
+pub fn route(route: Route, vector: u8, apic_id: u32) -> Result<(), KError> {
+    // Physical destination mode only has 8 bits for the APIC ID
+    if apic_id > 0xff {
+        return Err(KError::NotSupported);
+    }
+
+    let ioapics = IOAPICS.lock();
+    let io_apic = ioapics
+        .iter()
+        .find(|io_apic| io_apic.handles(route.gsi))
+        .ok_or(KError::NotSupported)?;
+    io_apic.set_entry(route.gsi, route.entry(vector, apic_id));
+    Ok(())
+}
+
+/// Reads the redirection entries of all IO-APICs (they lose them while
+/// we're suspended).
+pub fn save() -> Result<Vec<u64>, KError> {
+    let ioapics = IOAPICS.lock();
+    let mut entries = Vec::new();
+    for io_apic in ioapics.iter() {
+        for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+            entries.try_push(io_apic.entry(gsi))?;
+        }
+    }
+    Ok(entries)
+}
+
+/// Writes back the entries `save` read.
+pub fn restore(entries: &[u64]) {
+    let ioapics = IOAPICS.lock();
+    let gsis = ioapics.iter().flat_map(|io_apic| {
+        (io_apic.gsi_base..io_apic.gsi_base + io_apic.entries).map(move |gsi| (io_apic, gsi))
+    });
+    for ((io_apic, gsi), entry) in gsis.zip(entries.iter()) {
+        io_apic.set_entry(gsi, *entry);
+    }
+}
+
+/// Masks every entry (before we hand the machine to another kernel).
+pub fn mask_all() {
+    let ioapics = IOAPICS.lock();
+    for io_apic in ioapics.iter() {
+        for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+            io_apic.set_entry(gsi, ENTRY_MASKED);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn isa_overrides() {
+        let overrides = [
+            InterruptOverride {
+                irq: 0,
+                gsi: 2,
+                flags: 0,
+            },
+            InterruptOverride {
+                irq: 9,
+                gsi: 9,
+                flags: INTI_ACTIVE_LOW | INTI_LEVEL,
+            },
+        ];
+
+        assert_eq!(
+            Route::isa(0, &overrides),
+            Route {
+                gsi: 2,
+                polarity: Polarity::ActiveHigh,
+                trigger: TriggerMode::Edge
+            }
+        );
+        assert_eq!(
+            Route::isa(9, &overrides),
+            Route {
+                gsi: 9,
+                polarity: Polarity::ActiveLow,
+                trigger: TriggerMode::Level
+            }
+        );
+        assert_eq!(Route::isa(4, &overrides).gsi, 4);
+    }
+
+    #[test]
+    fn redirection_entry() {
+        let edge = Route::isa(1, &[]);
+        assert_eq!(edge.entry(0x30, 3), 0x0300_0000_0000_0030);
+
+        let level = Route {
+            gsi: 16,
+            polarity: Polarity::ActiveLow,
+            trigger: TriggerMode::Level,
+        };
+        assert_eq!(level.entry(0x31, 0), 0xa031);
+    }
+}
@@ -34,9 +34,11 @@
 #![allow(warnings)]
 
 use core::fmt;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
 
 use alloc::boxed::Box;
 
+use x86::apic::ApicId;
 use x86::bits64::segmentation::Descriptor64;
 use x86::irq::*;
 use x86::segmentation::{
@@ -48,18 +50,19 @@ use apic::ApicDriver;
 use klogger::{sprint, sprintln};
 use log::{info, trace, warn};
 
+use crate::drivers::pci::{self, MsiMessage, PciDevice};
+use crate::error::KError;
 use crate::kcb::ArchSpecificKcb;
-use crate::memory::vspace::MapAction;
-use crate::memory::Frame;
 use crate::panic::{backtrace, backtrace_from};
 use crate::process::{Executor, ResumeHandle};
+use crate::time::clocksource;
 use crate::{cnrfs, nr, nrproc, ExitReason};
 
 use super::gdt::GdtTable;
 use super::kcb::{get_kcb, Arch86Kcb};
-use super::memory::{PAddr, VAddr, BASE_PAGE_SIZE, KERNEL_BASE};
+use super::memory::VAddr;
 use super::process::{Ring3Process, Ring3Resumer};
-use super::{debug, timer};
+use super::{debug, hotplug, ioapic, timer, user_access};
 
 /// A macro to initialize an entry in an IDT table.
 ///
@@ -93,7 +96,8 @@ macro_rules! idt_set {
         //
         // $ist is normally set to 0, which means we use the interrupt_stack from the kcb.
         // $ist is set to 1 for double-faults and other severe exceptions
-        // to use the `unrecoverable_fault_stack` from the kcb
+        // to use the `unrecoverable_fault_stack` from the kcb, and to 2 for
+        // NMIs to use the `nmi_stack`
         $idt_table[$num] = DescriptorBuilder::interrupt_descriptor(seg, $f as u64)
             .dpl(Ring::Ring3)
             .ist($ist)
@@ -106,6 +110,214 @@ macro_rules! idt_set {
 pub const TLB_WORK_PENDING: u8 = 251;
 /// The IDT entry for handling GC in cnr.
 pub const MLNR_GC_INIT: u8 = 250;
+/// Control-protection faults (#CP, not in the `x86` crate yet).
+const CONTROL_PROTECTION_VECTOR: u8 = 21;
+
+/// The first IDT entry we hand out for MSI/MSI-X interrupts (and IO-APIC
+/// routes to kernel handlers).
+pub const MSI_VECTOR_BASE: u8 = 48;
+/// How many IDT entries (starting at `MSI_VECTOR_BASE`) we have for
+/// MSI/MSI-X interrupts (see also `isr.S`).
+pub const MSI_VECTORS: usize = 32;
+
+/// A function that is called (in interrupt context, on the core the vector
+/// is bound to) when a device raises `vector`.
+pub type MsiHandler = fn(vector: u8);
+
+const MSI_VECTOR_FREE: AtomicUsize = AtomicUsize::new(0);
+
+/// The handler for every MSI vector (as `usize` so we can allocate and
+/// dispatch without a lock, 0 means the vector is free).
+static MSI_HANDLERS: [AtomicUsize; MSI_VECTORS] = [MSI_VECTOR_FREE; MSI_VECTORS];
+
+/// An interrupt vector that is bound to a core.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MsiVector {
+    pub vector: u8,
+    /// The core that receives the interrupt.
+    pub core: atopology::GlobalThreadId,
+    apic_id: u32,
+}
+
+impl MsiVector {
+    /// What a device has to send to raise this interrupt.
+    pub fn message(&self) -> MsiMessage {
+        // Fixed delivery, edge triggered, physical destination
+        MsiMessage {
+            address: 0xfee0_0000 | (self.apic_id as u64) << 12,
+            data: self.vector as u32,
+        }
+    }
+}
+
+/// Allocates an MSI vector that is delivered to `core` and calls `handler`.
+pub fn allocate_msi_vector(
+    core: atopology::GlobalThreadId,
+    handler: MsiHandler,
+) -> Result<MsiVector, KError> {
+    let thread = atopology::MACHINE_TOPOLOGY
+        .threads
+        .get(core)
+        .ok_or(KError::InvalidGlobalThreadId)?;
+    let apic_id = match thread.apic_id() {
+        ApicId::XApic(id) => id as u32,
+        ApicId::X2Apic(id) => id,
+    };
+    // Without interrupt remapping the destination in the message is 8 bits
+    if apic_id > 0xff {
+        return Err(KError::NotSupported);
+    }
+
+    for (i, slot) in MSI_HANDLERS.iter().enumerate() {
+        if slot
+            .compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            let vector = MSI_VECTOR_BASE + i as u8;
+            trace!("Allocated MSI vector {} for core {}", vector, core);
+            return Ok(MsiVector {
+                vector,
+                core,
+                apic_id,
+            });
+        }
+    }
+
+    Err(KError::OutOfVectors)
+}
+
+/// Returns `vector` to the allocator (the device should no longer send it).
+pub fn free_msi_vector(vector: MsiVector) {
+    let idx = (vector.vector - MSI_VECTOR_BASE) as usize;
+    MSI_HANDLERS[idx].store(0, Ordering::Release);
+}
+
+/// Allocates a vector for MSI-X table `entry` of `dev` and points the
+/// entry at it.
+///
+/// The BAR with the MSI-X table must be mapped and MSI-X enabled
+/// (`pci::enable_msix`) for interrupts to arrive.
+pub fn route_msix(
+    dev: &PciDevice,
+    entry: u16,
+    core: atopology::GlobalThreadId,
+    handler: MsiHandler,
+) -> Result<MsiVector, KError> {
+    let vector = allocate_msi_vector(core, handler)?;
+    match pci::set_msix_entry(dev, entry, vector.message()) {
+        Ok(()) => {
+            info!(
+                "PCI {} MSI-X entry {} -> vector {} on core {}",
+                dev.address, entry, vector.vector, core
+            );
+            Ok(vector)
+        }
+        Err(e) => {
+            free_msi_vector(vector);
+            Err(e)
+        }
+    }
+}
+
+/// Calls the handler that is registered for the MSI `vector`.
+fn msi_dispatch(vector: u8) {
+    let idx = (vector - MSI_VECTOR_BASE) as usize;
+    match MSI_HANDLERS[idx].load(Ordering::Acquire) {
+        0 => warn!("Spurious MSI on vector {}", vector),
+        handler => {
+            // Safe: We only ever store `MsiHandler`s in `MSI_HANDLERS`
+            let handler = unsafe { core::mem::transmute::<usize, MsiHandler>(handler) };
+            handler(vector);
+        }
+    }
+}
+
+/// The IDT entry of legacy (ISA) IRQ 0 (`ioapic_establish_route` routes
+/// IRQ `n` to `LEGACY_IRQ_BASE + n` for processes).
+pub const LEGACY_IRQ_BASE: u8 = 32;
+/// Number of legacy IRQs.
+pub const LEGACY_IRQS: usize = 16;
+
+/// A function that is called (in interrupt context) when a device raises
+/// legacy `irq`.
+pub type LegacyIrqHandler = fn(irq: u8);
+
+/// Kernel handlers for legacy IRQs (0 means the IRQ can be forwarded to a
+/// process, see `ProcessOperation::AllocateVector`).
+static LEGACY_HANDLERS: [AtomicUsize; LEGACY_IRQS] = [MSI_VECTOR_FREE; LEGACY_IRQS];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_IRQ: AtomicU8 = AtomicU8::new(u8::MAX);
+/// The legacy IRQ behind every MSI vector `route_legacy_irq` allocated.
+static LEGACY_VECTORS: [AtomicU8; MSI_VECTORS] = [NO_IRQ; MSI_VECTORS];
+
+/// Allocates a vector on `core` for the device behind the IO-APIC `route`
+/// and calls `handler` for it.
+pub fn route_gsi(
+    route: ioapic::Route,
+    core: atopology::GlobalThreadId,
+    handler: MsiHandler,
+) -> Result<MsiVector, KError> {
+    let vector = allocate_msi_vector(core, handler)?;
+    match ioapic::route(route, vector.vector, vector.apic_id) {
+        Ok(()) => {
+            info!(
+                "GSI {} -> vector {} on core {}",
+                route.gsi, vector.vector, core
+            );
+            Ok(vector)
+        }
+        Err(e) => {
+            free_msi_vector(vector);
+            Err(e)
+        }
+    }
+}
+
+/// Routes legacy (ISA) `irq` to `core` and calls `handler` for it
+/// (respecting the interrupt source overrides in the MADT).
+///
+/// The device shouldn't raise `irq` before this returns.
+pub fn route_legacy_irq(
+    irq: u8,
+    core: atopology::GlobalThreadId,
+    handler: LegacyIrqHandler,
+) -> Result<MsiVector, KError> {
+    let slot = LEGACY_HANDLERS
+        .get(irq as usize)
+        .ok_or(KError::NotSupported)?;
+    slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+        .map_err(|_| KError::AlreadyPresent)?;
+
+    match route_gsi(ioapic::isa_route(irq), core, legacy_irq_dispatch) {
+        Ok(vector) => {
+            info!("Legacy IRQ {} uses vector {}", irq, vector.vector);
+            LEGACY_VECTORS[(vector.vector - MSI_VECTOR_BASE) as usize]
+                .store(irq, Ordering::Release);
+            Ok(vector)
+        }
+        Err(e) => {
+            slot.store(0, Ordering::Release);
+            Err(e)
+        }
+    }
+}
+
+/// Calls the kernel handler for the legacy IRQ behind MSI `vector`.
+fn legacy_irq_dispatch(vector: u8) {
+    let irq = LEGACY_VECTORS[(vector - MSI_VECTOR_BASE) as usize].load(Ordering::Acquire);
+    match LEGACY_HANDLERS
+        .get(irq as usize)
+        .map(|handler| handler.load(Ordering::Acquire))
+    {
+        None | Some(0) => warn!("Spurious legacy IRQ on vector {}", vector),
+        Some(handler) => {
+            // Safe: We only ever store `LegacyIrqHandler`s in `LEGACY_HANDLERS`
+            let handler = unsafe { core::mem::transmute::<usize, LegacyIrqHandler>(handler) };
+            handler(irq);
+        }
+    }
+}
 
 /// The IDT table can hold a maximum of 256 entries.
 pub const IDT_SIZE: usize = 256;
@@ -129,9 +341,20 @@ impl Default for IdtTable {
         let mut table = IdtTable([Descriptor64::NULL; IDT_SIZE]);
 
         idt_set!(table.0, 0, isr_handler0, 0);
+        #[cfg(not(feature = "gdb"))]
         idt_set!(table.0, 1, isr_handler1, 0);
-        idt_set!(table.0, 2, isr_handler2, 0);
-        idt_set!(table.0, 3, isr_handler3, 0);
+        // The GDB stub needs the registers of the kernel
+        // too (see `gdb`):
+        #[cfg(feature = "gdb")]
+        idt_set!(table.0, 1, isr_handler_frame1, 0);
+        // NMIs can hit anywhere, they get their own stack
+        // (see `nmi`):
+        idt_set!(table.0, 2, isr_handler_frame2, 2);
+        // Breakpoints in the kernel are kprobes (see `kprobes`):
+        #[cfg(not(feature = "gdb"))]
+        idt_set!(table.0, 3, isr_handler_breakpoint, 0);
+        #[cfg(feature = "gdb")]
+        idt_set!(table.0, 3, isr_handler_frame3, 0);
         idt_set!(table.0, 4, isr_handler4, 0);
         idt_set!(table.0, 5, isr_handler5, 0);
         idt_set!(table.0, 6, isr_handler6, 0);
@@ -148,11 +371,12 @@ impl Default for IdtTable {
 
         idt_set!(table.0, 16, isr_handler16, 0);
         idt_set!(table.0, 17, isr_handler17, 0);
-        // For machine-check exceptions, we use the
-        // _early handler to abort in any case:
-        idt_set!(table.0, 18, isr_handler_early18, 1);
+        // Machine-checks can hit anywhere too, they decide
+        // whether to abort (see `mce`):
+        idt_set!(table.0, 18, isr_handler_frame18, 1);
         idt_set!(table.0, 19, isr_handler19, 0);
         idt_set!(table.0, 20, isr_handler20, 0);
+        idt_set!(table.0, 21, isr_handler21, 0);
         idt_set!(table.0, 30, isr_handler30, 0);
 
         // PIC interrupts:
@@ -173,6 +397,40 @@ impl Default for IdtTable {
         idt_set!(table.0, 46, isr_handler46, 0);
         idt_set!(table.0, 47, isr_handler47, 0);
 
+        // MSI/MSI-X interrupts:
+        idt_set!(table.0, 48, isr_handler48, 0);
+        idt_set!(table.0, 49, isr_handler49, 0);
+        idt_set!(table.0, 50, isr_handler50, 0);
+        idt_set!(table.0, 51, isr_handler51, 0);
+        idt_set!(table.0, 52, isr_handler52, 0);
+        idt_set!(table.0, 53, isr_handler53, 0);
+        idt_set!(table.0, 54, isr_handler54, 0);
+        idt_set!(table.0, 55, isr_handler55, 0);
+        idt_set!(table.0, 56, isr_handler56, 0);
+        idt_set!(table.0, 57, isr_handler57, 0);
+        idt_set!(table.0, 58, isr_handler58, 0);
+        idt_set!(table.0, 59, isr_handler59, 0);
+        idt_set!(table.0, 60, isr_handler60, 0);
+        idt_set!(table.0, 61, isr_handler61, 0);
+        idt_set!(table.0, 62, isr_handler62, 0);
+        idt_set!(table.0, 63, isr_handler63, 0);
+        idt_set!(table.0, 64, isr_handler64, 0);
+        idt_set!(table.0, 65, isr_handler65, 0);
+        idt_set!(table.0, 66, isr_handler66, 0);
+        idt_set!(table.0, 67, isr_handler67, 0);
+        idt_set!(table.0, 68, isr_handler68, 0);
+        idt_set!(table.0, 69, isr_handler69, 0);
+        idt_set!(table.0, 70, isr_handler70, 0);
+        idt_set!(table.0, 71, isr_handler71, 0);
+        idt_set!(table.0, 72, isr_handler72, 0);
+        idt_set!(table.0, 73, isr_handler73, 0);
+        idt_set!(table.0, 74, isr_handler74, 0);
+        idt_set!(table.0, 75, isr_handler75, 0);
+        idt_set!(table.0, 76, isr_handler76, 0);
+        idt_set!(table.0, 77, isr_handler77, 0);
+        idt_set!(table.0, 78, isr_handler78, 0);
+        idt_set!(table.0, 79, isr_handler79, 0);
+
         idt_set!(table.0, TLB_WORK_PENDING as usize, isr_handler251, 0);
         idt_set!(table.0, MLNR_GC_INIT as usize, isr_handler250, 0);
         idt_set!(table.0, apic::TSC_TIMER_VECTOR as usize, isr_handler252, 0);
@@ -208,6 +466,7 @@ impl IdtTable {
         idt_set!(table.0, 18, isr_handler_early18, 0);
         idt_set!(table.0, 19, isr_handler_early19, 0);
         idt_set!(table.0, 20, isr_handler_early20, 0);
+        idt_set!(table.0, 21, isr_handler_early21, 0);
         idt_set!(table.0, 30, isr_handler_early30, 0);
 
         idt_set!(table.0, TLB_WORK_PENDING as usize, isr_handler_early251, 0);
@@ -259,6 +518,40 @@ pub struct ExceptionArguments {
     ss: u64,
 }
 
+/// The registers `isr_handler_frameXX` pushes (followed by what the CPU
+/// pushed on entry), see `isr.S`.
+#[repr(C)]
+pub struct InterruptFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl InterruptFrame {
+    pub fn in_user_space(&self) -> bool {
+        self.cs & 0b11 == 3
+    }
+}
+
 impl fmt::Debug for ExceptionArguments {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         unsafe {
@@ -300,6 +593,9 @@ unsafe fn unhandled_irq(a: &ExceptionArguments) {
 
 /// Handler for unexpected page-faults.
 ///
+/// User-space faults in a region the process registered go to the process
+/// (see `userfault`).
+///
 /// TODO: Right now we terminate kernel.
 /// Should abort process and resume.
 unsafe fn pf_handler(a: &ExceptionArguments) {
@@ -318,7 +614,9 @@ unsafe fn pf_handler(a: &ExceptionArguments) {
             .expect("A pid must be set in this if branch (US bit set in page-fault error)");
 
         match nrproc::NrProcess::<Ring3Process>::resolve(pid, faulting_address_va) {
-            Ok((paddr, rights)) => {
+            // A protection fault isn't spurious, the page-table has the
+            // mapping already
+            Ok((paddr, rights)) if !err.contains(PageFaultError::P) => {
                 // TODO(harden): We probably want to warn/abort if we get many
                 // "spurious" pfaults for the same addr in quick succession: one
                 // bug I encountered is when I accidentially made executor
@@ -333,18 +631,28 @@ unsafe fn pf_handler(a: &ExceptionArguments) {
                 let r = kcb_iret_handle(kcb);
                 r.resume()
             }
-            Err(_) => {
-                // unresolved page-fault, proceed with abort below
+            _ => {
+                // unresolved page-fault, proceed with abort below (unless
+                // the process handles it)
             }
         }
+
+        // The page might be evicted (or on its way back)
+        if !err.contains(PageFaultError::P) && super::swap::handle_fault(pid, faulting_address) {
+            let r = kcb_iret_handle(kcb);
+            r.resume()
+        }
+
+        if let Some(r) = super::userfault::upcall(kcb, a.rip, faulting_address, err) {
+            r.resume()
+        }
     }
 
     sprintln!("[IRQ] Page Fault on {}", kcb.arch.id());
     sprintln!("{}", err);
 
-    // Enable user-space access
-    x86::current::rflags::stac();
-
+    // The fault might have hit user-space
+    let _window = user_access::Window::open();
     for i in 0..32 {
         let ptr = (a.rsp as *const u64).offset(i);
         sprintln!("stack[{}] = {:#x}", i, *ptr);
@@ -420,12 +728,28 @@ unsafe fn timer_handler(a: &ExceptionArguments) {
         debug::shutdown(ExitReason::Ok);
     }
 
+    // The rest of our gang waits while we're in here
+    let gang = super::gang::stop();
+
+    // We're not stuck, see if anyone else is
+    super::watchdog::check();
+    // Did GDB send ^C?
+    #[cfg(feature = "gdb")]
+    super::gdb::poll();
+
     // Periodically advance replica state, then resume immediately
     nr::KernelNode::synchronize();
     let kcb = get_kcb();
+    // Check the clocksource didn't drift (once is enough)
+    if kcb.arch.id() == 0 {
+        clocksource::watchdog();
+    }
     for pid in 0..crate::process::MAX_PROCESSES {
         nrproc::NrProcess::<Ring3Process>::synchronize(pid);
     }
+    super::replicas::synchronized();
+    super::tlb::sync_translations();
+    super::gang::resume(gang);
 
     if kcb.arch.has_executor() {
         // TODO(process-mgmt): Ensures that we still periodically
@@ -447,6 +771,11 @@ unsafe fn timer_handler(a: &ExceptionArguments) {
         if is_replica_main_thread {
             timer::set(timer::DEFAULT_TIMER_DEADLINE);
         }
+        // Can make the timer go off sooner
+        super::futex::expire_timers();
+        if let Some(upcall) = timer_upcall(kcb, a) {
+            upcall.resume()
+        }
 
         // Return immediately
         let r = kcb_iret_handle(kcb);
@@ -454,6 +783,7 @@ unsafe fn timer_handler(a: &ExceptionArguments) {
     } else {
         // Go to scheduler instead
         //warn!("got a timer on core {}", kcb.arch.id());
+        super::futex::expire_timers();
         crate::scheduler::schedule()
     }
 }
@@ -467,8 +797,8 @@ unsafe fn gp_handler(a: &ExceptionArguments) {
     sprint!("\n[IRQ] GENERAL PROTECTION FAULT: ");
     sprintln!("From {}", desc.source);
 
-    // Enable user-space access
-    x86::current::rflags::stac();
+    // The fault might have hit user-space
+    let _window = user_access::Window::open();
 
     if a.exception > 0 {
         sprintln!(
@@ -507,6 +837,34 @@ unsafe fn gp_handler(a: &ExceptionArguments) {
     debug::shutdown(ExitReason::GeneralProtectionFault);
 }
 
+/// Handler for a control-protection fault (see `cet`).
+///
+/// TODO: Right now we terminate kernel.
+/// Should abort process and resume.
+unsafe fn cp_handler(a: &ExceptionArguments) {
+    let what = match a.exception & 0x7fff {
+        1 => "ret to another address than the call",
+        2 => "iret to another address than the interrupt",
+        3 => "indirect branch to a missing endbr64",
+        4 => "rstorssp without a restore token",
+        5 => "setssbsy on a busy shadow stack",
+        _ => "unknown",
+    };
+    if a.cs & 0b11 != 3 {
+        panic!(
+            "Control-protection fault in the kernel at {:#x}: {}",
+            a.rip, what
+        );
+    }
+
+    sprintln!(
+        "\n[IRQ] CONTROL PROTECTION FAULT: From user-space at {:#x}: {}",
+        a.rip,
+        what
+    );
+    unhandled_irq(a);
+}
+
 fn kcb_resume_handle(kcb: &crate::kcb::Kcb<Arch86Kcb>) -> Ring3Resumer {
     Ring3Resumer::new_restore(kcb.arch.get_save_area_ptr())
 }
@@ -577,12 +935,52 @@ pub extern "C" fn handle_generic_exception_early(a: ExceptionArguments) -> ! {
 #[no_mangle]
 pub extern "C" fn handle_generic_exception(a: ExceptionArguments) -> ! {
     unsafe {
+        user_access::reset();
         let start = x86::time::rdtsc();
         assert!(a.vector < 256);
         trace!("handle_generic_exception {:?}", a);
         acknowledge();
 
         let kcb = get_kcb();
+        kcb.stats.irq(a.vector);
+        super::idle::exit();
+        // Interrupts (not exceptions) are when cores look at their load
+        if a.vector > 31 {
+            super::cpufreq::sample();
+        }
+
+        // Device interrupts are handled by the kernel, never forwarded
+        let msi_vectors = MSI_VECTOR_BASE as u64..MSI_VECTOR_BASE as u64 + MSI_VECTORS as u64;
+        let vector = a.vector;
+
+        // Any interrupt ends a futex wait, we handle what can't wait and
+        // return from the system call (or forward the interrupt below)
+        let forwarded = |v: u64| v > 30 && v < 250 && !msi_vectors.contains(&v);
+        if vector > 31 && super::futex::unpark(kcb) && !forwarded(vector) {
+            if msi_vectors.contains(&vector) {
+                msi_dispatch(vector as u8);
+            } else if vector == TLB_WORK_PENDING.into() {
+                super::tlb::dequeue(kcb.arch.id());
+            } else if vector == MLNR_GC_INIT.into() {
+                super::tlb::dequeue(kcb.arch.id());
+                // Maybe a nudge for an interval timer of this core
+                super::futex::expire_timers();
+            } else if vector == apic::TSC_TIMER_VECTOR.into() {
+                super::watchdog::check();
+                super::futex::expire_timers();
+            }
+            kcb_resume_handle(kcb).resume()
+        }
+
+        if msi_vectors.contains(&vector) {
+            msi_dispatch(vector as u8);
+
+            if kcb.arch.has_executor() {
+                kcb_iret_handle(kcb).resume()
+            } else {
+                crate::scheduler::schedule()
+            }
+        }
 
         // If we have an active process we should do scheduler activations:
         // TODO(scheduling): do proper masking based on some VCPU mask
@@ -593,11 +991,14 @@ pub extern "C" fn handle_generic_exception(a: ExceptionArguments) -> ! {
             let mut plock = kcb.arch.current_executor();
             let p = plock.as_mut().unwrap();
 
+            // Safe: The kernel alias of the vcpu area is valid while the
+            // executor exists
+            let vcpu = unsafe { &mut *p.vcpu_kernel() };
             let resumer = {
                 let was_disabled = {
-                    trace!("vcpu state is: pc_disabled {:?}", p.vcpu().pc_disabled);
-                    let was_disabled = p.vcpu().upcalls_disabled(VAddr::from(a.rip));
-                    p.vcpu().disable_upcalls();
+                    trace!("vcpu state is: pc_disabled {:?}", vcpu.pc_disabled);
+                    let was_disabled = vcpu.upcalls_disabled(VAddr::from(a.rip));
+                    vcpu.disable_upcalls();
                     was_disabled
                 };
 
@@ -609,7 +1010,7 @@ pub extern "C" fn handle_generic_exception(a: ExceptionArguments) -> ! {
                     // Copy CURRENT_SAVE_AREA to process enabled save area
                     // then resume in the upcall handler
                     kcb.arch.save_area.as_ref().map(|sa| {
-                        p.vcpu().enabled_state = **sa;
+                        vcpu.enabled_state = **sa;
                     });
 
                     p.upcall(a.vector, a.exception)
@@ -627,16 +1028,33 @@ pub extern "C" fn handle_generic_exception(a: ExceptionArguments) -> ! {
             gp_handler(&a);
         } else if a.vector == 0xe {
             pf_handler(&a);
+        } else if a.vector == CONTROL_PROTECTION_VECTOR.into() {
+            cp_handler(&a);
         } else if a.vector == 0x3 {
             dbg_handler(&a);
         } else if a.vector == TLB_WORK_PENDING.into() {
             let kcb = get_kcb();
             trace!("got an interrupt {:?}", kcb.arch.id());
             super::tlb::dequeue(kcb.arch.id());
+            // Catch up with unmaps in the log (`tlb::unmapped`)
+            super::tlb::sync_translations();
+            // Maybe another core of our gang stops it
+            if a.cs & 0x3 == 0x3 {
+                if let Ok(pid) = kcb.current_pid() {
+                    super::gang::hold(pid);
+                }
+            }
+
+            if hotplug::pending() {
+                if !kcb.arch.has_executor() {
+                    hotplug::park()
+                }
+                core_revoked_upcall(kcb, a.rip).resume()
+            }
 
             if kcb.arch.has_executor() {
                 // Return immediately
-                kcb.tlb_time += x86::time::rdtsc() - start;
+                kcb.stats.tlb_shootdown_cycles(x86::time::rdtsc() - start);
                 kcb_iret_handle(kcb).resume()
             } else {
                 // Go to scheduler instead
@@ -648,9 +1066,17 @@ pub extern "C" fn handle_generic_exception(a: ExceptionArguments) -> ! {
 
             let kcb = get_kcb();
             if kcb.arch.has_executor() {
+                // Maybe a nudge for an interval timer of this core
+                super::futex::expire_timers();
+                if let Some(upcall) = timer_upcall(kcb, &a) {
+                    upcall.resume()
+                }
                 kcb_iret_handle(kcb).resume()
             } else {
                 loop {
+                    if hotplug::pending() {
+                        hotplug::park()
+                    }
                     super::tlb::eager_advance_fs_replica();
 
                     // Reset a timer and sleep for some time
@@ -670,6 +1096,55 @@ pub extern "C" fn handle_generic_exception(a: ExceptionArguments) -> ! {
     unreachable!("Should not come here")
 }
 
+/// Asks the process running on the current core to give it back (see
+/// `hotplug`), it just continues if it can't take upcalls right now (we'll
+/// ask again).
+fn core_revoked_upcall(kcb: &crate::kcb::Kcb<Arch86Kcb>, rip: u64) -> Ring3Resumer {
+    let mut plock = kcb.arch.current_executor();
+    let p = plock.as_mut().unwrap();
+
+    // Safe: The kernel alias of the vcpu area is valid while the executor
+    // exists
+    let vcpu = unsafe { &mut *p.vcpu_kernel() };
+    if vcpu.upcalls_disabled(VAddr::from(rip)) {
+        return kcb_resume_handle(kcb);
+    }
+    vcpu.disable_upcalls();
+    kcb.arch.save_area.as_ref().map(|sa| {
+        vcpu.enabled_state = **sa;
+    });
+    p.upcall(kpi::upcall::CORE_REVOKED, kcb.arch.id() as u64)
+}
+
+/// Upcalls the process on the current core for an interval timer of it that
+/// expired (see `crate::itimer`).
+///
+/// `None` if there is none or the process can't take an upcall right now,
+/// the expirations then wait for the next try (`timer::UPCALL_RETRY`).
+fn timer_upcall(kcb: &crate::kcb::Kcb<Arch86Kcb>, a: &ExceptionArguments) -> Option<Ring3Resumer> {
+    // Only when we interrupted the process itself
+    if a.cs & 0x3 != 0x3 {
+        return None;
+    }
+
+    let mut plock = kcb.arch.current_executor();
+    let p = plock.as_mut()?;
+    // Safe: The kernel alias of the vcpu area is valid while the executor
+    // exists
+    let vcpu = unsafe { &mut *p.vcpu_kernel() };
+    if vcpu.upcalls_disabled(VAddr::from(a.rip)) {
+        return None;
+    }
+    let (timer, expired) = crate::itimer::TIMERS.lock().take(p.pid, kcb.arch.id())?;
+
+    vcpu.disable_upcalls();
+    kcb.arch.save_area.as_ref().map(|sa| {
+        vcpu.enabled_state = **sa;
+    });
+    let expired = expired.min(u32::MAX as u64);
+    Some(p.upcall(kpi::upcall::TIMER, (expired << 32) | timer as u64))
+}
+
 /// Registers a handler IRQ handler function.
 pub unsafe fn register_handler(
     vector: usize,
@@ -685,66 +1160,28 @@ pub unsafe fn register_handler(
     //handlers[vector] = handler;
 }
 
-/// Initialize IO APICs by enumerating them
-/// and making sure the device registers are mapped
-/// in the kernel-space.
-pub fn ioapic_initialize() {
-    let ioapic_len = atopology::MACHINE_TOPOLOGY.io_apics().count();
-    /*crate::memory::KernelAllocator::try_refill_tcache(4 * ioapic_len, 0)
-    .expect("Refill didn't work");*/
-
-    let kcb = get_kcb();
-
-    for io_apic in atopology::MACHINE_TOPOLOGY.io_apics() {
-        info!("Initialize IO APIC {:?}", io_apic);
-
-        let paddr = PAddr::from(io_apic.address as u64);
-        let ioapic_frame = Frame::new(paddr, BASE_PAGE_SIZE, 0);
-        let vbase = PAddr::from(KERNEL_BASE);
-        kcb.arch
-            .init_vspace()
-            .map_identity_with_offset(
-                vbase,
-                ioapic_frame.base,
-                ioapic_frame.size(),
-                MapAction::ReadWriteKernel,
-            )
-            .expect("Can't create APIC mapping?");
-    }
-}
-
-/// Establishes a route for a GSI on the IOAPIC.
+/// Routes the legacy IRQs to the BSP (with vector `LEGACY_IRQ_BASE + irq`)
+/// so a process can handle them.
 ///
 /// # TODO
 /// Currently this just enables everything and routes it to
 /// core 0. This is because, we should probably just support MSI(X)
 /// and don't invest a lot in legacy interrupts...
 pub fn ioapic_establish_route(_gsi: u64, _core: u64) {
-    use crate::memory::vspace::MapAction;
-    use crate::memory::{paddr_to_kernel_vaddr, PAddr};
-
-    for io_apic in atopology::MACHINE_TOPOLOGY.io_apics() {
-        let addr = PAddr::from(io_apic.address as u64);
-
-        let mut inst =
-            unsafe { x86::apic::ioapic::IoApic::new(paddr_to_kernel_vaddr(addr).as_usize()) };
-        trace!(
-            "This IOAPIC supports {} Interrupts",
-            inst.supported_interrupts()
-        );
+    let bsp = match atopology::MACHINE_TOPOLOGY.threads[0].apic_id() {
+        ApicId::XApic(id) => id as u32,
+        ApicId::X2Apic(id) => id,
+    };
 
-        for i in 0..inst.supported_interrupts() {
-            let gsi = io_apic.global_irq_base + i as u32;
-            if gsi < 16 {
-                trace!(
-                    "Enable irq {} which maps to GSI#{}",
-                    i,
-                    io_apic.global_irq_base + i as u32
-                );
-                if i != 2 && i != 1 {
-                    inst.enable(i, 0);
-                }
-            }
+    // Skip the PIT, the cascade and IRQs the kernel handles
+    for irq in 3..LEGACY_IRQS as u8 {
+        if LEGACY_HANDLERS[irq as usize].load(Ordering::Acquire) != 0 {
+            continue;
+        }
+        let route = ioapic::isa_route(irq);
+        trace!("Enable irq {} which maps to GSI#{}", irq, route.gsi);
+        if let Err(e) = ioapic::route(route, LEGACY_IRQ_BASE + irq, bsp) {
+            warn!("Can't route legacy IRQ {}: {}", irq, e);
         }
     }
 }
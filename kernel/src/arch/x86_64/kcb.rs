@@ -26,10 +26,12 @@ use crate::kcb::{ArchSpecificKcb, Kcb};
 use crate::nrproc::NrProcess;
 use crate::process::Pid;
 use crate::process::MAX_PROCESSES;
-use crate::stack::{OwnedStack, Stack};
+use crate::stack::{GuardedStack, Stack};
 
+use super::cet::{self, ShadowStacks};
 use super::gdt::GdtTable;
 use super::irq::IdtTable;
+use super::perf::{self, Counters};
 use super::process::{Ring3Executor, Ring3Process};
 use super::vspace::page_table::PageTable;
 use super::KernelArgs;
@@ -104,6 +106,10 @@ pub struct Arch86Kcb {
     /// here).
     pub save_area: Option<Pin<Box<kpi::arch::SaveArea>>>,
 
+    /// Address of the token of the supervisor shadow stack of the core, 0
+    /// if the kernel runs without shadow stacks (see `cet`).
+    pub(crate) shadow_stack_token: u64,
+
     /// A handle to the core-local interrupt driver.
     pub(crate) apic: RefCell<X2APICDriver>,
 
@@ -151,7 +157,7 @@ pub struct Arch86Kcb {
     /// The CPU switches to this stack automatically for normal interrupts
     /// (see `set_interrupt_stacks`).
     /// This member should probably not be touched from normal code.
-    interrupt_stack: Option<OwnedStack>,
+    interrupt_stack: Option<GuardedStack>,
 
     /// A reliable stack that is used for unrecoverable faults
     /// (double-fault, machine-check exception etc.)
@@ -159,19 +165,38 @@ pub struct Arch86Kcb {
     /// The CPU switches to this memory location automatically
     /// (see `set_interrupt_stacks`).
     /// This member should probably not be touched from normal code.
-    unrecoverable_fault_stack: Option<OwnedStack>,
+    unrecoverable_fault_stack: Option<GuardedStack>,
+
+    /// The stack NMIs run on (they can interrupt the kernel anywhere).
+    ///
+    /// The CPU switches to this memory location automatically
+    /// (see `set_interrupt_stacks`).
+    /// This member should probably not be touched from normal code.
+    nmi_stack: Option<GuardedStack>,
 
     /// A handle to the syscall stack memory location.
     ///
     /// We switch rsp/rbp to this stack in `exec.S`.
     /// This member should probably not be touched from normal code.
-    syscall_stack: Option<OwnedStack>,
+    syscall_stack: Option<GuardedStack>,
+
+    /// The shadow stacks of the core (see `cet`).
+    shadow_stacks: Option<ShadowStacks>,
+
+    /// The performance counters loaded in the PMU of the core.
+    perf: Counters,
+
+    /// The process and its unmap generation the TLB of the core is up to
+    /// date with (see `tlb::sync_translations`).
+    pub(crate) tlb_generation: Option<(Pid, u64)>,
 }
 
 // The `syscall_stack_top` entry must be at offset 0 of KCB (referenced early-on in exec.S)
 static_assertions::const_assert_eq!(memoffset::offset_of!(Arch86Kcb, syscall_stack_top), 0);
 // The `save_area` entry must be at offset 8 of KCB (for assembly code)
 static_assertions::const_assert_eq!(memoffset::offset_of!(Arch86Kcb, save_area), 8);
+// The `shadow_stack_token` entry must be at offset 16 of KCB (for assembly code)
+static_assertions::const_assert_eq!(memoffset::offset_of!(Arch86Kcb, shadow_stack_token), 16);
 
 impl Arch86Kcb {
     pub(crate) fn new(
@@ -188,15 +213,20 @@ impl Arch86Kcb {
             idt: Default::default(),
             current_executor: None, // We don't have an executor to schedule initially
             save_area: None,
+            shadow_stack_token: 0,
             init_vspace: RefCell::new(init_vspace),
             interrupt_stack: None,
             syscall_stack: None,
             unrecoverable_fault_stack: None,
+            nmi_stack: None,
+            shadow_stacks: None,
             cnr_replica: None,
             cnrfs: None,
             id: 0,
             node_id: 0,
             max_threads: 0,
+            perf: Default::default(),
+            tlb_generation: None,
         }
     }
 
@@ -217,10 +247,7 @@ impl Arch86Kcb {
         self.id = thread.id as usize;
         self.node_id = thread.node_id.unwrap_or(0);
 
-        self.max_threads = match atopology::MACHINE_TOPOLOGY.nodes().nth(0) {
-            Some(node) => node.threads().count(),
-            None => 1,
-        };
+        self.max_threads = crate::numa::max_cores_per_node();
         self.cnr_replica = Some((replica, idx_token));
     }
 
@@ -234,31 +261,31 @@ impl Arch86Kcb {
         self.id
     }
 
-    pub fn max_threads(&self) -> usize {
-        self.max_threads
-    }
-
-    /// Swaps out current process with a new process. Returns the old process.
-    pub fn swap_current_executor(
-        &mut self,
-        new_executor: Box<Ring3Executor>,
-    ) -> Option<Box<Ring3Executor>> {
-        self.current_executor.replace(new_executor)
+    pub fn take_current_executor(&mut self) -> Option<Box<Ring3Executor>> {
+        let mut old = self.current_executor.take();
+        perf::switch(&mut self.perf, old.as_mut().map(|e| &mut e.perf), None);
+        old
     }
 
     pub fn has_executor(&self) -> bool {
         self.current_executor.is_some()
     }
 
-    pub fn current_executor(&self) -> Result<&Box<Ring3Executor>, KError> {
-        let p = self
-            .current_executor
-            .as_ref()
-            .ok_or(KError::ProcessNotSet)?;
-        Ok(p)
+    /// The counters in the PMU and the (paused) process counters of the
+    /// current executor.
+    pub(crate) fn perf_counters(&mut self) -> (&mut Counters, Option<&mut Counters>) {
+        (
+            &mut self.perf,
+            self.current_executor.as_mut().map(|e| &mut e.perf),
+        )
     }
 
-    pub fn set_interrupt_stacks(&mut self, ex_stack: OwnedStack, fault_stack: OwnedStack) {
+    pub fn set_interrupt_stacks(
+        &mut self,
+        ex_stack: GuardedStack,
+        fault_stack: GuardedStack,
+        nmi_stack: GuardedStack,
+    ) {
         // Add the stack-top to the TSS so the CPU ends up switching
         // to this stack on an interrupt
         debug_assert_eq!(ex_stack.base() as u64 % 16, 0, "Stack not 16-byte aligned");
@@ -270,6 +297,9 @@ impl Arch86Kcb {
             "Stack not 16-byte aligned"
         );
         self.tss.set_ist(0, fault_stack.base() as u64);
+        // ... and ist[1] for the NMI stack
+        debug_assert_eq!(nmi_stack.base() as u64 % 16, 0, "Stack not 16-byte aligned");
+        self.tss.set_ist(1, nmi_stack.base() as u64);
 
         // Link TSS in Gdt
         // It's important to only construct the GdtTable
@@ -279,9 +309,10 @@ impl Arch86Kcb {
 
         self.interrupt_stack = Some(ex_stack);
         self.unrecoverable_fault_stack = Some(fault_stack);
+        self.nmi_stack = Some(nmi_stack);
     }
 
-    pub fn set_syscall_stack(&mut self, stack: OwnedStack) {
+    pub fn set_syscall_stack(&mut self, stack: GuardedStack) {
         self.syscall_stack_top = stack.base();
         trace!("Syscall stack top set to: {:p}", self.syscall_stack_top);
         self.syscall_stack = Some(stack);
@@ -294,6 +325,36 @@ impl Arch86Kcb {
         );
     }
 
+    /// Installs the shadow stacks of the core.
+    pub fn set_shadow_stacks(&mut self, stacks: ShadowStacks) {
+        self.shadow_stack_token = stacks.token();
+        self.shadow_stacks = Some(stacks);
+    }
+
+    /// Panics if the code running on one of the stacks of the core wrote
+    /// past its canaries (call this when returning to user-space, the
+    /// kernel stacks are all but empty there).
+    pub fn check_stack_canaries(&self) {
+        let stacks = [
+            ("syscall", &self.syscall_stack),
+            ("interrupt", &self.interrupt_stack),
+            ("fault", &self.unrecoverable_fault_stack),
+            ("NMI", &self.nmi_stack),
+        ];
+        for (name, stack) in stacks.iter() {
+            if let Some(stack) = stack {
+                if !stack.canaries_intact() {
+                    panic!(
+                        "The {} stack of core {} overflowed (canaries at {:p} overwritten)",
+                        name,
+                        self.id,
+                        stack.limit()
+                    );
+                }
+            }
+        }
+    }
+
     /// Install a CPU register save-area.
     ///
     /// Register are store here in case we get an interrupt/sytem call
@@ -316,6 +377,13 @@ impl Arch86Kcb {
         self.kernel_args
     }
 
+    #[cfg(feature = "test-stack-canary")]
+    pub fn syscall_stack_limit(&self) -> *mut u8 {
+        self.syscall_stack
+            .as_ref()
+            .map_or(ptr::null_mut(), |s| s.limit())
+    }
+
     #[cfg(feature = "test-double-fault")]
     pub fn fault_stack_range(&self) -> (u64, u64) {
         (
@@ -344,6 +412,10 @@ impl crate::kcb::ArchSpecificKcb for Arch86Kcb {
         self.id
     }
 
+    fn max_threads(&self) -> usize {
+        self.max_threads
+    }
+
     fn node(&self) -> usize {
         self.node_id
     }
@@ -352,6 +424,30 @@ impl crate::kcb::ArchSpecificKcb for Arch86Kcb {
         Ok(self.current_executor()?.pid)
     }
 
+    fn current_executor(&self) -> Result<&Ring3Executor, KError> {
+        self.current_executor
+            .as_deref()
+            .ok_or(KError::ProcessNotSet)
+    }
+
+    /// Swaps out current process with a new process. Returns the old process.
+    ///
+    /// The process counters of the old executor leave the PMU with it.
+    fn swap_current_executor(
+        &mut self,
+        mut new_executor: Box<Ring3Executor>,
+    ) -> Option<Box<Ring3Executor>> {
+        let mut old = self.current_executor.take();
+        perf::switch(
+            &mut self.perf,
+            old.as_mut().map(|e| &mut e.perf),
+            Some(&mut new_executor.perf),
+        );
+        cet::switch(old.as_mut().map(|e| &mut e.cet), &new_executor.cet);
+        self.current_executor = Some(new_executor);
+        old
+    }
+
     fn process_table(
         &self,
     ) -> &'static ArrayVec<
@@ -0,0 +1,342 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Starts another kernel on this machine without a firmware reset.
+//!
+//! `crate::kexec` knows how the new kernel has to look in memory, here we
+//! put it there: we assemble the destination in scratch large pages, write
+//! the page-tables, memory map and `KernelArgs` of the new kernel into a
+//! boot region, stop the other cores and devices and call
+//! `kexec_trampoline` (`kexec.S`). It switches to the new page-tables,
+//! copies the scratch pages to the destination and jumps to the new kernel.
+//!
+//! The boot region is one large page:
+//!
+//!  - page 0: a copy of the trampoline,
+//!  - page 1: the copy list (`destination, source, length` triples that end
+//!    with a length of 0),
+//!  - page 2: `KernelArgs`, page 3: the command line,
+//!  - `MM_PAGES` pages for the memory map,
+//!  - the page-tables in the rest.
+//!
+//! The trampoline runs at the physical address of the boot region, so we
+//! rely on the identity mapping our bootloader sets up (and we set up for
+//! the new kernel). Kernels that started some other way can't kexec.
+
+use alloc::vec::Vec;
+use core::cmp;
+use core::mem::size_of;
+use core::slice;
+
+use fallible_collections::FallibleVec;
+use log::{info, warn};
+use uefi::table::boot::{MemoryDescriptor, MemoryType};
+use x86::bits64::paging::{PAddr, VAddr};
+use x86::controlregs;
+
+use crate::drivers::pci;
+use crate::error::KError;
+use crate::kexec::{
+    self, ImageLoader, ImageSize, Layout, PageTables, Staging, KERNEL_ARGS, KERNEL_ELF,
+    KERNEL_OFFSET, KERNEL_PT, KERNEL_STACK, MODULE, STACK_PAGES,
+};
+use crate::memory::dma::DmaBuffer;
+use crate::memory::vspace::MapAction;
+use crate::memory::{BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+
+use super::kcb::get_kcb;
+use super::{hotplug, ioapic, irq, watchdog, BootProtocol, KernelArgs, Module};
+
+/// Pages of the boot region (see the module documentation).
+const COPIES_PAGE: usize = 1;
+const ARGS_PAGE: usize = 2;
+const CMDLINE_PAGE: usize = 3;
+const MM_PAGE: usize = 4;
+const MM_PAGES: usize = 8;
+const TABLES_PAGE: usize = MM_PAGE + MM_PAGES;
+
+/// Entries of the copy list (one is the end marker).
+const MAX_COPIES: usize = BASE_PAGE_SIZE / (3 * size_of::<u64>());
+
+/// `fn(pml4, copies, stack, entry, args) -> !`
+type Trampoline = extern "C" fn(u64, u64, u64, u64, u64) -> !;
+
+/// The code in `kexec.S`.
+fn trampoline() -> &'static [u8] {
+    extern "C" {
+        static kexec_trampoline: u8;
+        static kexec_trampoline_end: u8;
+    }
+
+    // Safe: It's code in the kernel binary, we only read it.
+    unsafe {
+        let start = &kexec_trampoline as *const u8;
+        let end = &kexec_trampoline_end as *const u8;
+        slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+/// Allocates a scratch large page outside of [`start`, `start` + `size`),
+/// the ones we can't use go to `rejected` (so we don't get them again).
+fn allocate_outside(
+    start: u64,
+    size: usize,
+    rejected: &mut Vec<DmaBuffer>,
+) -> Result<DmaBuffer, KError> {
+    loop {
+        let buffer = DmaBuffer::new(LARGE_PAGE_SIZE)?;
+        let base = buffer.paddr().as_u64();
+        if base + LARGE_PAGE_SIZE as u64 <= start || base >= start + size as u64 {
+            return Ok(buffer);
+        }
+        rejected.try_push(buffer)?;
+    }
+}
+
+/// Everything the new kernel needs, ready to jump.
+struct Boot {
+    region: DmaBuffer,
+    /// The scratch pages, they have to stay around until the copy.
+    _chunks: Vec<DmaBuffer>,
+    _rejected: Vec<DmaBuffer>,
+    pml4: u64,
+    stack: u64,
+    entry: u64,
+    args: u64,
+}
+
+impl Boot {
+    /// Stops everything that could get in the way of the copy and starts
+    /// the new kernel, returns if we can't stop the other cores.
+    fn jump(self) -> KError {
+        if let Err(e) = hotplug::offline_others() {
+            return e;
+        }
+        info!("kexec: starting the new kernel at {:#x}", self.entry);
+
+        ioapic::mask_all();
+        if let Err(e) = pci::disable_bus_masters() {
+            warn!("kexec: can't stop DMA of PCI devices: {:?}", e);
+        }
+        irq::disable();
+        watchdog::disarm();
+        super::hypervisor::vmx::off();
+
+        let boot = self.region.paddr().as_u64();
+        let copies = boot + (COPIES_PAGE * BASE_PAGE_SIZE) as u64;
+        let kcb = get_kcb();
+        unsafe {
+            // The process address space doesn't have the identity mapping
+            controlregs::cr3_write(kcb.arch.init_vspace().pml4_address().as_u64());
+            let trampoline: Trampoline = core::mem::transmute(boot);
+            trampoline(self.pml4, copies, self.stack, self.entry, self.args)
+        }
+    }
+}
+
+/// Loads `image` and sets up everything for the jump.
+fn prepare(image: &[u8], cmdline: &str) -> Result<Boot, KError> {
+    let current = get_kcb().arch.kernel_args();
+    if current.protocol != BootProtocol::Uefi && current.protocol != BootProtocol::Kexec {
+        return Err(KError::NotSupported);
+    }
+    if cmdline.len() > BASE_PAGE_SIZE {
+        return Err(KError::NotSupported);
+    }
+
+    let binary = elfloader::ElfBinary::new(image).map_err(|_e| KError::InvalidKernelImage)?;
+    let mut image_size = ImageSize::default();
+    binary
+        .load(&mut image_size)
+        .map_err(|_e| KError::InvalidKernelImage)?;
+    let layout = Layout::new(image_size.size, image.len());
+
+    let mut map: Vec<MemoryDescriptor> = Vec::try_with_capacity(current.mm_iter.len())?;
+    map.try_extend_from_slice(&current.mm_iter)?;
+    let dest = kexec::pick_destination(&map, layout.size).ok_or(KError::KexecNoMemory)?;
+    kexec::reclaim(&mut map);
+
+    let mut rejected = Vec::new();
+    let mut region = allocate_outside(dest, layout.size, &mut rejected)?;
+    let count = (layout.content() + LARGE_PAGE_SIZE - 1) / LARGE_PAGE_SIZE;
+    if count >= MAX_COPIES {
+        return Err(KError::KexecNoMemory);
+    }
+    let mut chunks = Vec::try_with_capacity(count)?;
+    for _i in 0..count {
+        chunks.try_push(allocate_outside(dest, layout.size, &mut rejected)?)?;
+    }
+
+    let offset = KERNEL_OFFSET + dest;
+    let mut slices = Vec::try_with_capacity(count)?;
+    for chunk in chunks.iter_mut() {
+        slices.try_push(chunk.as_mut_slice())?;
+    }
+    let mut staging = Staging::new(slices);
+    binary
+        .load(&mut ImageLoader {
+            staging: &mut staging,
+            offset,
+        })
+        .map_err(|_e| KError::InvalidKernelImage)?;
+    staging.write(layout.file, image)?;
+    drop(staging);
+
+    // The memory map of the new kernel
+    let page = BASE_PAGE_SIZE as u64;
+    let boot = region.paddr().as_u64();
+    kexec::reserve(&mut map, dest, layout.file, KERNEL_ELF)?;
+    kexec::reserve(
+        &mut map,
+        dest + layout.file as u64,
+        layout.stack - layout.file,
+        MODULE,
+    )?;
+    kexec::reserve(
+        &mut map,
+        dest + layout.stack as u64,
+        STACK_PAGES * BASE_PAGE_SIZE,
+        KERNEL_STACK,
+    )?;
+    kexec::reserve(
+        &mut map,
+        boot,
+        ARGS_PAGE * BASE_PAGE_SIZE,
+        MemoryType::LOADER_CODE,
+    )?;
+    kexec::reserve(
+        &mut map,
+        boot + ARGS_PAGE as u64 * page,
+        (TABLES_PAGE - ARGS_PAGE) * BASE_PAGE_SIZE,
+        KERNEL_ARGS,
+    )?;
+    kexec::reserve(
+        &mut map,
+        boot + TABLES_PAGE as u64 * page,
+        LARGE_PAGE_SIZE - TABLES_PAGE * BASE_PAGE_SIZE,
+        KERNEL_PT,
+    )?;
+    if map.len() > MM_PAGES * BASE_PAGE_SIZE / size_of::<MemoryDescriptor>() {
+        return Err(KError::KexecNoMemory);
+    }
+
+    let (pages, tables) = region
+        .as_mut_slice()
+        .split_at_mut(TABLES_PAGE * BASE_PAGE_SIZE);
+
+    // Safe: The tables are page aligned and we don't use them as bytes
+    // anymore.
+    let tables = unsafe {
+        slice::from_raw_parts_mut(
+            tables.as_mut_ptr() as *mut [u64; 512],
+            tables.len() / BASE_PAGE_SIZE,
+        )
+    };
+    let mut page_tables = PageTables::new(tables, boot + TABLES_PAGE as u64 * page)?;
+    page_tables.map_memory(&map)?;
+    for (base, size, rights) in image_size.segments.iter() {
+        page_tables.map(offset + base, dest + base, *size, *rights)?;
+    }
+    // Without the guard page
+    let stack = dest + layout.stack as u64 + page;
+    page_tables.map(
+        KERNEL_OFFSET + stack,
+        stack,
+        (STACK_PAGES - 1) * BASE_PAGE_SIZE,
+        MapAction::ReadWriteKernel,
+    )?;
+    let pml4 = page_tables.pml4();
+
+    let code = trampoline();
+    pages[..code.len()].copy_from_slice(code);
+
+    let copies = &mut pages[COPIES_PAGE * BASE_PAGE_SIZE..ARGS_PAGE * BASE_PAGE_SIZE];
+    for (i, chunk) in chunks.iter().enumerate() {
+        let at = i * LARGE_PAGE_SIZE;
+        let len = cmp::min(LARGE_PAGE_SIZE, layout.content() - at);
+        let entry = [dest + at as u64, chunk.paddr().as_u64(), len as u64];
+        for (j, word) in entry.iter().enumerate() {
+            let idx = (3 * i + j) * size_of::<u64>();
+            copies[idx..idx + size_of::<u64>()].copy_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    let cmdline_at = CMDLINE_PAGE * BASE_PAGE_SIZE;
+    pages[cmdline_at..cmdline_at + cmdline.len()].copy_from_slice(cmdline.as_bytes());
+
+    let mm = pages[MM_PAGE * BASE_PAGE_SIZE..].as_mut_ptr() as *mut MemoryDescriptor;
+    for (i, descriptor) in map.iter().enumerate() {
+        // Safe: We checked that it fits, and the pages are page aligned.
+        unsafe { mm.add(i).write(*descriptor) };
+    }
+
+    let mut args = KernelArgs::new();
+    args.protocol = BootProtocol::Kexec;
+    args.framebuffer = current.framebuffer;
+    args.pml4 = PAddr::from(pml4);
+    args.stack = (
+        PAddr::from(KERNEL_OFFSET + stack),
+        (STACK_PAGES - 1) * BASE_PAGE_SIZE,
+    );
+    args.kernel_elf_offset = VAddr::from(offset);
+    args.acpi1_rsdp = current.acpi1_rsdp;
+    args.acpi2_rsdp = current.acpi2_rsdp;
+    // The firmware uses our virtual addresses for the runtime services now
+    // and can't switch again, so the next kernel does without them
+    args.uefi_system_table = PAddr::from(0u64);
+    args.boot_cpus = current.boot_cpus.clone();
+    // The file we got replaces the old kernel binary, the other modules
+    // stay where they are
+    let file = dest + layout.file as u64;
+    let modules = core::iter::once(Module::new(
+        "kernel",
+        VAddr::from(KERNEL_OFFSET + file),
+        PAddr::from(file),
+        image.len(),
+    ))
+    .chain(current.modules.iter().skip(1).cloned());
+    for module in modules {
+        args.add_module(module)
+            .map_err(|_e| KError::InvalidKernelImage)?;
+    }
+
+    // The new kernel finds these at their physical addresses (like the
+    // bootloader passes them), from here on nothing can fail, we must not
+    // drop `args`.
+    let args_at = boot + ARGS_PAGE as u64 * page;
+    unsafe {
+        args.mm_iter = Vec::from_raw_parts(
+            (boot + MM_PAGE as u64 * page) as *mut MemoryDescriptor,
+            map.len(),
+            MM_PAGES * BASE_PAGE_SIZE / size_of::<MemoryDescriptor>(),
+        );
+        args.command_line = core::str::from_utf8_unchecked(slice::from_raw_parts(
+            (boot + CMDLINE_PAGE as u64 * page) as *const u8,
+            cmdline.len(),
+        ));
+        let dst = pages[ARGS_PAGE * BASE_PAGE_SIZE..].as_mut_ptr() as *mut KernelArgs;
+        dst.write(args);
+    }
+
+    let entry = offset + binary.entry_point();
+    Ok(Boot {
+        region,
+        _chunks: chunks,
+        _rejected: rejected,
+        pml4,
+        stack: KERNEL_OFFSET + dest + layout.stack_top() as u64,
+        entry,
+        args: KERNEL_OFFSET + args_at,
+    })
+}
+
+/// Replaces the running kernel with the one in `image` (an nrk ELF binary)
+/// and starts it with `cmdline`.
+///
+/// Only returns if something went wrong before we stopped the machine.
+pub fn kexec(image: &[u8], cmdline: &str) -> KError {
+    match prepare(image, cmdline) {
+        Ok(boot) => boot.jump(),
+        Err(e) => e,
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! kprobes-lite: count (or log) how often the kernel passes through a place
+//! we marked with `kprobe!`, without rebuilding it.
+//!
+//! `kprobe!("name")` puts a one byte `nop` into the function and records its
+//! address (and the `Probe` it belongs to) in the `kprobes` section. Only the
+//! places marked like this can be probed. Enabling a probe replaces the `nop`
+//! with an `int3`, the breakpoint handler finds the probe by the address,
+//! counts the hit and returns to the instruction after it. Disabling a probe
+//! puts the `nop` back. Since the `int3` only ever replaces a `nop`, we don't
+//! need to execute (or emulate) a displaced instruction.
+//!
+//! Probes are managed with `Debug::kprobe` from user-space.
+
+use core::ptr;
+use core::slice;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use kpi::KprobeMode;
+use log::info;
+use spin::Mutex;
+use x86::controlregs::{cr0, cr0_write, Cr0};
+
+use crate::error::KError;
+
+use super::irq::InterruptFrame;
+use super::kcb::get_kcb;
+
+/// What `kprobe!` places at a probe site.
+const NOP: u8 = 0x90;
+/// What we patch in to enable a probe.
+const INT3: u8 = 0xcc;
+
+/// A named place in the kernel we can instrument (declared by `kprobe!`).
+pub struct Probe {
+    name: &'static str,
+    mode: AtomicU64,
+    hits: AtomicU64,
+}
+
+impl Probe {
+    pub const fn new(name: &'static str) -> Probe {
+        Probe {
+            name,
+            mode: AtomicU64::new(KprobeMode::Off as u64),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    fn hit(&self, frame: &InterruptFrame) {
+        let mode = KprobeMode::from(self.mode.load(Ordering::Relaxed));
+        if mode == KprobeMode::Off {
+            // Raced with `set_mode` disabling us
+            return;
+        }
+
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        if mode == KprobeMode::Trace {
+            // Safe: we keep frame pointers, the probe is inside a function
+            // so rbp points to its frame
+            let caller = unsafe { ptr::read((frame.rbp + 8) as *const u64) };
+            match crate::panic::symbolize(caller) {
+                Some((function, offset)) => info!(
+                    "kprobe {} on core {} called from {}+{:#x}",
+                    self.name,
+                    get_kcb().arch.id(),
+                    function,
+                    offset
+                ),
+                None => info!(
+                    "kprobe {} on core {} called from {:#x}",
+                    self.name,
+                    get_kcb().arch.id(),
+                    caller
+                ),
+            }
+        }
+    }
+}
+
+/// The address of a `nop` placed by `kprobe!` (the layout of the entries
+/// in the `kprobes` section).
+#[repr(C)]
+struct Site {
+    addr: u64,
+    probe: &'static Probe,
+}
+
+extern "C" {
+    static __start_kprobes: Site;
+    static __stop_kprobes: Site;
+}
+
+/// All places marked by `kprobe!`.
+fn sites() -> &'static [Site] {
+    // Safe: the linker puts the entries of the `kprobes` section between
+    // these two symbols
+    unsafe {
+        let start = &__start_kprobes as *const Site;
+        let end = &__stop_kprobes as *const Site;
+        slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Serializes patching the kernel text.
+static PATCH: Mutex<()> = Mutex::new(());
+
+/// Writes `byte` to the kernel text at `addr`.
+///
+/// # Safety
+/// `addr` must be a probe site.
+unsafe fn patch(addr: u64, byte: u8) {
+    let old = cr0();
+    cr0_write(old & !Cr0::CR0_WRITE_PROTECT);
+    ptr::write_volatile(addr as *mut u8, byte);
+    cr0_write(old);
+    // Make sure we don't run stale instructions ourselves (other cores see
+    // either the `nop` or the `int3`, both are fine)
+    x86::cpuid::cpuid!(0);
+}
+
+/// Changes what probe `name` does, returns how often it was hit (while it
+/// was enabled) so far.
+pub fn set_mode(name: &str, mode: KprobeMode) -> Result<u64, KError> {
+    if mode == KprobeMode::Unknown {
+        return Err(KError::InvalidKprobeMode);
+    }
+
+    let _patching = PATCH.lock();
+    let mut found = false;
+    let mut hits = 0;
+    for site in sites().iter().filter(|site| site.probe.name == name) {
+        found = true;
+        hits += site.probe.hits.load(Ordering::Relaxed);
+        site.probe.mode.store(mode as u64, Ordering::Relaxed);
+        let byte = if mode == KprobeMode::Off { NOP } else { INT3 };
+        // Safe: it's a site
+        unsafe { patch(site.addr, byte) };
+    }
+
+    if found {
+        Ok(hits)
+    } else {
+        Err(KError::UnknownKprobe)
+    }
+}
+
+/// Counts the hit if the `int3` came from an enabled probe, returns `false`
+/// if it didn't.
+fn hit(frame: &InterruptFrame) -> bool {
+    if frame.in_user_space() {
+        return false;
+    }
+
+    // The `int3` replaced a `nop`, so `rip` already points to where we
+    // continue
+    let addr = frame.rip.wrapping_sub(1);
+    match sites().iter().find(|site| site.addr == addr) {
+        Some(site) => {
+            site.probe.hit(frame);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Called by `isr_handler_frame3` (see `isr.S`) for breakpoints in the
+/// kernel (and in user-space if we have the GDB stub).
+#[no_mangle]
+pub extern "C" fn handle_breakpoint(frame: &mut InterruptFrame) {
+    if hit(frame) {
+        return;
+    }
+
+    if super::gdb::ENABLED {
+        super::gdb::handle_debug_exception(frame);
+    } else {
+        panic!("Unexpected breakpoint in the kernel at {:#x}", frame.rip);
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! KVM's paravirtual clock (kvm-clock).
+//!
+//! Every vCPU gives KVM a time structure that it keeps up to date with the
+//! TSC value at some point, the time since boot at that point and how to
+//! scale TSC ticks to nanoseconds. KVM corrects it whenever the vCPU moves
+//! to another physical core or the host TSC changes, so unlike a TSC we
+//! calibrated ourselves it stays accurate in nested or overcommitted VMs.
+//! We register it as a clocksource, take the TSC frequency from it and
+//! read the wall-clock time at boot from it (with nanosecond resolution,
+//! unlike the CMOS RTC).
+
+use core::arch::x86_64::__cpuid;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use core::time::Duration;
+
+use log::info;
+use spin::Once;
+use x86::msr::wrmsr;
+
+use super::hypervisor;
+use super::kcb::get_kcb;
+use super::MAX_CORES;
+use crate::error::KError;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::BASE_PAGE_SIZE;
+use crate::time::clocksource::{self, ClockSource};
+
+const MSR_KVM_WALL_CLOCK_NEW: u32 = 0x4b56_4d00;
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+/// Enables updates of the system time structure.
+const SYSTEM_TIME_ENABLE: u64 = 1;
+
+/// The new clock MSRs are available.
+const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+
+/// The time structures of all vCPUs agree with each other.
+const PVCLOCK_TSC_STABLE_BIT: u8 = 1 << 0;
+
+/// `pvclock_wall_clock`: The wall-clock time when system time was 0.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct WallClock {
+    version: u32,
+    sec: u32,
+    nsec: u32,
+}
+
+/// `pvclock_vcpu_time_info`.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct VcpuTimeInfo {
+    version: u32,
+    pad0: u32,
+    tsc_timestamp: u64,
+    system_time: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    flags: u8,
+    pad: [u8; 2],
+}
+
+impl VcpuTimeInfo {
+    /// Nanoseconds since the guest booted at `tsc`.
+    fn system_time(&self, tsc: u64) -> u64 {
+        let mut delta = tsc.wrapping_sub(self.tsc_timestamp);
+        if self.tsc_shift >= 0 {
+            delta <<= self.tsc_shift;
+        } else {
+            delta >>= -self.tsc_shift;
+        }
+        let scaled = (delta as u128 * self.tsc_to_system_mul as u128) >> 32;
+        self.system_time.wrapping_add(scaled as u64)
+    }
+
+    /// The TSC frequency (in Hz) KVM scales with.
+    fn tsc_frequency(&self) -> u64 {
+        let mut frequency = (1_000_000_000u128 << 32) / self.tsc_to_system_mul as u128;
+        if self.tsc_shift >= 0 {
+            frequency >>= self.tsc_shift;
+        } else {
+            frequency <<= -self.tsc_shift;
+        }
+        frequency as u64
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_TIME_INFO: Once<DmaBuffer> = Once::new();
+/// The time structure of every core (in a page from its own node).
+static TIME_INFO: [Once<DmaBuffer>; MAX_CORES] = [NO_TIME_INFO; MAX_CORES];
+
+static KVM_CLOCK: KvmClock = KvmClock;
+
+/// kvm-clock as a clocksource (it counts nanoseconds since boot).
+struct KvmClock;
+
+impl KvmClock {
+    /// Reads the time structure of the current core (or the one of the BSP
+    /// for cores that haven't set up theirs yet).
+    fn time_info(&self) -> Option<VcpuTimeInfo> {
+        let core = get_kcb().arch.id();
+        let buffer = TIME_INFO
+            .get(core)
+            .and_then(Once::get)
+            .or_else(|| TIME_INFO[0].get())?;
+        Some(read_consistent(buffer.as_ptr::<VcpuTimeInfo>(), |i| {
+            i.version
+        }))
+    }
+}
+
+impl ClockSource for KvmClock {
+    fn name(&self) -> &'static str {
+        "kvm-clock"
+    }
+
+    fn read(&self) -> u64 {
+        self.time_info()
+            .map_or(0, |info| info.system_time(unsafe { x86::time::rdtsc() }))
+    }
+
+    fn frequency(&self) -> u64 {
+        1_000_000_000
+    }
+
+    fn rating(&self) -> u32 {
+        400
+    }
+}
+
+/// Are we running on KVM with the (new) paravirtual clock?
+fn available() -> bool {
+    hypervisor::find(hypervisor::KVM).map_or(false, |base| {
+        hypervisor::max_leaf(base) >= base + 1
+            && unsafe { __cpuid(base + 1) }.eax & KVM_FEATURE_CLOCKSOURCE2 != 0
+    })
+}
+
+/// Reads a structure the hypervisor updates (it makes the version odd
+/// while it writes).
+fn read_consistent<T: Copy>(ptr: *const T, version: impl Fn(&T) -> u32) -> T {
+    loop {
+        let value = unsafe { ptr::read_volatile(ptr) };
+        fence(Ordering::Acquire);
+        let again = unsafe { ptr::read_volatile(ptr) };
+        let v = version(&value);
+        if v % 2 == 0 && v == version(&again) {
+            return value;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Gives KVM a time structure for the current core to keep up to date.
+///
+/// Call this on every core once its KCB knows the core id.
+pub fn init_core() -> Result<(), KError> {
+    if !available() {
+        return Err(KError::NotSupported);
+    }
+    let core = get_kcb().arch.id();
+    let slot = TIME_INFO.get(core).ok_or(KError::InvalidGlobalThreadId)?;
+    // A core that comes back online keeps its structure
+    let buffer = match slot.get() {
+        Some(buffer) => buffer,
+        None => {
+            let buffer = DmaBuffer::new(BASE_PAGE_SIZE)?;
+            slot.call_once(|| buffer)
+        }
+    };
+    unsafe {
+        wrmsr(
+            MSR_KVM_SYSTEM_TIME_NEW,
+            buffer.paddr().as_u64() | SYSTEM_TIME_ENABLE,
+        )
+    };
+    Ok(())
+}
+
+/// Sets up kvm-clock on the BSP and registers it as a clocksource.
+pub fn init() -> Result<(), KError> {
+    init_core()?;
+    let info = KVM_CLOCK.time_info().ok_or(KError::NotSupported)?;
+    info!(
+        "kvm-clock: TSC at {} Hz, stable across vCPUs: {}",
+        info.tsc_frequency(),
+        info.flags & PVCLOCK_TSC_STABLE_BIT != 0
+    );
+    clocksource::register(&KVM_CLOCK)
+}
+
+/// The TSC frequency (in Hz) according to KVM (needs `init`).
+pub fn tsc_frequency() -> Option<u64> {
+    KVM_CLOCK
+        .time_info()
+        .filter(|info| info.tsc_to_system_mul != 0)
+        .map(|info| info.tsc_frequency())
+}
+
+/// Returns the current time since the UNIX epoch (needs `init`).
+pub fn wallclock() -> Result<Duration, KError> {
+    if KVM_CLOCK.time_info().is_none() {
+        return Err(KError::NotSupported);
+    }
+
+    let buffer = DmaBuffer::new(BASE_PAGE_SIZE)?;
+    unsafe { wrmsr(MSR_KVM_WALL_CLOCK_NEW, buffer.paddr().as_u64()) };
+    let boot = read_consistent(buffer.as_ptr::<WallClock>(), |w| w.version);
+    let since_boot = KVM_CLOCK.read();
+
+    if boot.version == 0 && boot.sec == 0 {
+        return Err(KError::NotSupported);
+    }
+    Ok(Duration::new(boot.sec as u64, boot.nsec) + Duration::from_nanos(since_boot))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn system_time() {
+        // A 2 GHz TSC: 0.5 ns per tick is mul 2^31 with shift 0
+        let info = VcpuTimeInfo {
+            tsc_timestamp: 1000,
+            system_time: 5_000,
+            tsc_to_system_mul: 1 << 31,
+            tsc_shift: 0,
+            ..Default::default()
+        };
+        assert_eq!(info.system_time(3000), 6_000);
+
+        let shifted = VcpuTimeInfo {
+            tsc_shift: -1,
+            tsc_to_system_mul: u32::MAX,
+            ..info
+        };
+        assert_eq!(shifted.system_time(3000), 5_000 + 999);
+    }
+
+    #[test]
+    fn tsc_frequency() {
+        let info = VcpuTimeInfo {
+            tsc_to_system_mul: 1 << 31,
+            tsc_shift: 0,
+            ..Default::default()
+        };
+        assert_eq!(info.tsc_frequency(), 2_000_000_000);
+
+        // 3 GHz: KVM shifts the delta down by one and uses a bigger factor
+        let info = VcpuTimeInfo {
+            tsc_to_system_mul: 2_863_311_530,
+            tsc_shift: -1,
+            ..Default::default()
+        };
+        assert_eq!(info.tsc_frequency() / 1000, 3_000_000);
+    }
+}
@@ -0,0 +1,344 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Machine-check architecture (MCA) support.
+//!
+//! Every core has a number of error reporting banks (the bank count is in
+//! `IA32_MCG_CAP`) that log hardware errors (memory, caches, interconnect,
+//! TLBs). We enable reporting for all of them and set `CR4.MCE`, so
+//! uncorrected errors raise a machine-check exception (#MC) instead of
+//! shutting the core down (which looks like a triple fault).
+//!
+//! #MC runs on the unrecoverable fault stack: `isr_handler_frame18` saves
+//! the registers there and calls `handle_mce` which logs every valid bank
+//! and decides whether we can return to the interrupted code.
+//!
+//! # See also
+//!  - 15 MACHINE-CHECK ARCHITECTURE in the Intel SDM vol. 3
+
+use core::fmt;
+
+use klogger::sprintln;
+use log::{info, warn};
+use x86::controlregs::{cr4, cr4_write, Cr4};
+use x86::msr::{rdmsr, wrmsr};
+
+use crate::ExitReason;
+
+use super::debug;
+use super::irq::InterruptFrame;
+use super::kcb::get_kcb;
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17a;
+const IA32_MCG_CTL: u32 = 0x17b;
+
+/// Every bank has four MSRs starting at `IA32_MC0_CTL`.
+const IA32_MC0_CTL: u32 = 0x400;
+const IA32_MC0_STATUS: u32 = 0x401;
+const IA32_MC0_ADDR: u32 = 0x402;
+const IA32_MC0_MISC: u32 = 0x403;
+const BANK_MSRS: u32 = 4;
+
+const MCG_CAP_COUNT_MASK: u64 = 0xff;
+const MCG_CAP_CTL_P: u64 = 1 << 8;
+
+/// Restart IP valid: we can return to where the exception hit.
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+/// Error IP valid: the error is related to the interrupted instruction.
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+/// Machine check in progress.
+const MCG_STATUS_MCIP: u64 = 1 << 2;
+
+const STATUS_VAL: u64 = 1 << 63;
+const STATUS_OVER: u64 = 1 << 62;
+const STATUS_UC: u64 = 1 << 61;
+const STATUS_EN: u64 = 1 << 60;
+const STATUS_MISCV: u64 = 1 << 59;
+const STATUS_ADDRV: u64 = 1 << 58;
+const STATUS_PCC: u64 = 1 << 57;
+const STATUS_S: u64 = 1 << 56;
+const STATUS_AR: u64 = 1 << 55;
+
+fn ctl_msr(bank: u32) -> u32 {
+    IA32_MC0_CTL + bank * BANK_MSRS
+}
+
+fn status_msr(bank: u32) -> u32 {
+    IA32_MC0_STATUS + bank * BANK_MSRS
+}
+
+fn addr_msr(bank: u32) -> u32 {
+    IA32_MC0_ADDR + bank * BANK_MSRS
+}
+
+fn misc_msr(bank: u32) -> u32 {
+    IA32_MC0_MISC + bank * BANK_MSRS
+}
+
+/// What an error means for the code that runs on the core.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Severity {
+    /// The hardware fixed it.
+    Corrected,
+    /// Uncorrected, but nothing needs to happen right away (e.g., the
+    /// patrol scrubber found a bad line nobody consumed yet).
+    Deferred,
+    /// We can't continue: the processor context is corrupt or the
+    /// interrupted code consumed bad data (and we can't recover it).
+    Fatal,
+}
+
+/// The content of an `IA32_MCi_STATUS` register.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct BankStatus(pub u64);
+
+impl BankStatus {
+    pub fn is_valid(&self) -> bool {
+        self.0 & STATUS_VAL != 0
+    }
+
+    fn has(&self, bit: u64) -> bool {
+        self.0 & bit != 0
+    }
+
+    /// The architectural (MCA) error code.
+    pub fn error_code(&self) -> u16 {
+        self.0 as u16
+    }
+
+    /// The model specific error code.
+    pub fn model_code(&self) -> u16 {
+        (self.0 >> 16) as u16
+    }
+
+    /// What kind of error the MCA error code describes.
+    pub fn class(&self) -> &'static str {
+        let code = self.error_code();
+        match code {
+            0x0000 => "no error",
+            0x0001 => "unclassified",
+            0x0002 => "microcode ROM parity error",
+            0x0003 => "external error",
+            0x0004 => "FRC error",
+            0x0005 => "internal parity error",
+            0x0006 => "SMM handler code access violation",
+            0x0400 => "internal timer error",
+            0x0e0b => "I/O error",
+            // The compound codes, bit 12 (filtering) is ignored
+            _ if code & 0xe800 == 0x0800 => "bus/interconnect error",
+            _ if code & 0xef00 == 0x0100 => "cache hierarchy error",
+            _ if code & 0xef80 == 0x0080 => "memory controller error",
+            _ if code & 0xeff0 == 0x0010 => "TLB error",
+            _ if code & 0xfc00 == 0x0400 => "internal unclassified error",
+            _ => "unknown error",
+        }
+    }
+
+    /// How bad the error logged in this bank is, `mcg_status` is what
+    /// `IA32_MCG_STATUS` said when the exception hit.
+    pub fn severity(&self, mcg_status: u64) -> Severity {
+        if !self.is_valid() || !self.has(STATUS_UC) {
+            return Severity::Corrected;
+        }
+        if self.has(STATUS_PCC) {
+            return Severity::Fatal;
+        }
+        if self.has(STATUS_S) && self.has(STATUS_AR) {
+            // We'd have to unmap the poisoned page and kill whoever
+            // consumed it, we don't do that (yet)
+            return Severity::Fatal;
+        }
+        if self.has(STATUS_S) && self.has(STATUS_EN) && mcg_status & MCG_STATUS_RIPV == 0 {
+            return Severity::Fatal;
+        }
+        Severity::Deferred
+    }
+}
+
+impl fmt::Debug for BankStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x} {}", self.0, self.class())?;
+        write!(
+            f,
+            " (code {:#06x} model {:#06x})",
+            self.error_code(),
+            self.model_code()
+        )?;
+        for (bit, name) in [
+            (STATUS_OVER, "OVER"),
+            (STATUS_UC, "UC"),
+            (STATUS_EN, "EN"),
+            (STATUS_PCC, "PCC"),
+            (STATUS_S, "S"),
+            (STATUS_AR, "AR"),
+        ]
+        .iter()
+        {
+            if self.has(*bit) {
+                write!(f, " {}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn banks() -> u32 {
+    (unsafe { rdmsr(IA32_MCG_CAP) } & MCG_CAP_COUNT_MASK) as u32
+}
+
+/// Does the CPU do machine-checks (and tell us about them)?
+fn supported() -> bool {
+    let cpuid = x86::cpuid::CpuId::new();
+    cpuid
+        .get_feature_info()
+        .map_or(false, |fi| fi.has_mce() && fi.has_mca())
+}
+
+/// Enables machine-check reporting on the current core.
+///
+/// Logs (and clears) errors the banks still hold from before we booted.
+/// Returns the number of reporting banks.
+pub fn init() -> usize {
+    if !supported() {
+        warn!("No machine-check architecture, hardware errors won't be reported.");
+        return 0;
+    }
+
+    let core = get_kcb().arch.id();
+    let cap = unsafe { rdmsr(IA32_MCG_CAP) };
+    let banks = (cap & MCG_CAP_COUNT_MASK) as u32;
+    unsafe {
+        if cap & MCG_CAP_CTL_P != 0 {
+            wrmsr(IA32_MCG_CTL, u64::max_value());
+        }
+
+        for bank in 0..banks {
+            let status = BankStatus(rdmsr(status_msr(bank)));
+            if status.is_valid() {
+                warn!(
+                    "MCE: core {} bank {} has an old error logged: {:?}",
+                    core, bank, status
+                );
+            }
+            wrmsr(ctl_msr(bank), u64::max_value());
+            wrmsr(status_msr(bank), 0);
+        }
+
+        cr4_write(cr4() | Cr4::CR4_ENABLE_MACHINE_CHECK);
+    }
+
+    if core == 0 {
+        info!("MCE: {} reporting banks", banks);
+    }
+    banks as usize
+}
+
+/// Called by `isr_handler_frame18` (see `isr.S`).
+#[no_mangle]
+pub extern "C" fn handle_mce(frame: &InterruptFrame) {
+    let core = get_kcb().arch.id();
+    let mcg_status = unsafe { rdmsr(IA32_MCG_STATUS) };
+    if mcg_status & MCG_STATUS_MCIP == 0 {
+        // Not raised by the hardware (someone did `int 18`)
+        sprintln!("[MCE] core {}: no machine-check in progress", core);
+        return;
+    }
+
+    sprintln!(
+        "[MCE] Machine-check on core {} at rip = {:#x} in {} (RIPV={} EIPV={})",
+        core,
+        frame.rip,
+        if frame.in_user_space() {
+            "user-space"
+        } else {
+            "the kernel"
+        },
+        mcg_status & MCG_STATUS_RIPV != 0,
+        mcg_status & MCG_STATUS_EIPV != 0
+    );
+
+    let mut worst = Severity::Corrected;
+    for bank in 0..banks() {
+        let status = BankStatus(unsafe { rdmsr(status_msr(bank)) });
+        if !status.is_valid() {
+            continue;
+        }
+
+        let severity = status.severity(mcg_status);
+        sprintln!("[MCE] bank {}: {:?} -> {:?}", bank, status, severity);
+        if status.has(STATUS_ADDRV) {
+            sprintln!("[MCE] bank {}: addr = {:#x}", bank, unsafe {
+                rdmsr(addr_msr(bank))
+            });
+        }
+        if status.has(STATUS_MISCV) {
+            sprintln!("[MCE] bank {}: misc = {:#x}", bank, unsafe {
+                rdmsr(misc_msr(bank))
+            });
+        }
+        worst = worst.max(severity);
+
+        unsafe { wrmsr(status_msr(bank), 0) };
+    }
+
+    if worst == Severity::Fatal || mcg_status & MCG_STATUS_RIPV == 0 {
+        sprintln!("[MCE] Can't recover, shutting down.");
+        debug::shutdown(ExitReason::UnrecoverableError);
+    }
+
+    // Clearing MCIP allows the next machine-check (another one while MCIP
+    // is set shuts the core down)
+    unsafe { wrmsr(IA32_MCG_STATUS, 0) };
+    sprintln!("[MCE] core {} continues", core);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn error_classes() {
+        assert_eq!(BankStatus(STATUS_VAL).class(), "no error");
+        assert_eq!(BankStatus(0x0e0b).class(), "I/O error");
+        // Memory controller, read error on channel 1
+        assert_eq!(BankStatus(0x0091).class(), "memory controller error");
+        // L2 data read
+        assert_eq!(BankStatus(0x0136).class(), "cache hierarchy error");
+        // Same, but filtered
+        assert_eq!(BankStatus(0x1136).class(), "cache hierarchy error");
+        assert_eq!(BankStatus(0x0e0f).class(), "bus/interconnect error");
+        assert_eq!(BankStatus(0x0011).class(), "TLB error");
+        assert_eq!(BankStatus(0x0405).class(), "internal unclassified error");
+        assert_eq!(BankStatus(0x0400).class(), "internal timer error");
+    }
+
+    #[test]
+    fn severities() {
+        let ripv = MCG_STATUS_RIPV | MCG_STATUS_MCIP;
+
+        let corrected = BankStatus(STATUS_VAL | STATUS_EN | 0x0091);
+        assert_eq!(corrected.severity(ripv), Severity::Corrected);
+        assert_eq!(BankStatus(STATUS_UC).severity(ripv), Severity::Corrected);
+
+        // Patrol scrub found something (SRAO)
+        let srao = BankStatus(STATUS_VAL | STATUS_UC | STATUS_EN | STATUS_S | 0x00c0);
+        assert_eq!(srao.severity(ripv), Severity::Deferred);
+        assert_eq!(srao.severity(MCG_STATUS_MCIP), Severity::Fatal);
+
+        // Data load consumed poison (SRAR)
+        let srar = BankStatus(srao.0 | STATUS_AR);
+        assert_eq!(srar.severity(ripv), Severity::Fatal);
+
+        let pcc = BankStatus(STATUS_VAL | STATUS_UC | STATUS_PCC);
+        assert_eq!(pcc.severity(ripv), Severity::Fatal);
+    }
+
+    #[test]
+    fn bank_msrs() {
+        assert_eq!(ctl_msr(0), 0x400);
+        assert_eq!(status_msr(2), 0x409);
+        assert_eq!(addr_msr(3), 0x40e);
+        assert_eq!(misc_msr(1), 0x407);
+    }
+}
@@ -6,6 +6,17 @@
 
 pub use kpi::KERNEL_BASE;
 pub use x86::bits64::paging::{PAddr, VAddr, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+use x86::msr::{wrmsr, IA32_PAT};
+
+/// The page attribute table we program on every core.
+///
+/// It's the reset value except for entry 1 (selected by PWT=1, PCD=0 in a
+/// page-table entry) which is write-combining instead of write-through. We
+/// don't use write-through anywhere, so existing mappings are unaffected and
+/// `MapAction::ReadWriteKernelWriteCombining` just sets PWT.
+///
+/// Entries (in order): WB, WC, UC-, UC, WB, WT, UC-, UC.
+const PAT: u64 = 0x0007_0406_0007_0106;
 
 /// Translate a kernel 'virtual' address to the physical address of the memory.
 pub fn kernel_vaddr_to_paddr(v: VAddr) -> PAddr {
@@ -18,3 +29,8 @@ pub fn paddr_to_kernel_vaddr(p: PAddr) -> VAddr {
     let paddr_val: u64 = p.into();
     VAddr::from((paddr_val + KERNEL_BASE) as usize)
 }
+
+/// Programs the page attribute table (see `PAT`) of the current core.
+pub fn init_pat() {
+    unsafe { wrmsr(IA32_PAT, PAT) };
+}
@@ -26,12 +26,18 @@ use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::mem::transmute;
 use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
 
+use crate::arch_interface::Arch;
+use crate::cmdline::KernelConfig;
 use crate::cnrfs::{MlnrKernelNode, Modify};
-use crate::kcb::{BootloaderArguments, Kcb};
+use crate::drivers::framebuffer::{FramebufferInfo, PixelFormat};
+use crate::error::KError;
+use crate::kcb::Kcb;
+use crate::memory::vspace::MapAction;
 use crate::memory::{mcache, Frame, GlobalMemory, BASE_PAGE_SIZE};
 use crate::nr::{KernelNode, Op};
-use crate::stack::OwnedStack;
+use crate::stack::{GuardedStack, OwnedStack};
 use crate::{xmain, ExitReason};
 
 use apic::x2apic;
@@ -41,7 +47,7 @@ use cnr::{Log as MlnrLog, Replica as MlnrReplica};
 use driverkit::DriverControl;
 use fallible_collections::{FallibleVecGlobal, TryClone};
 use klogger::sprint;
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use node_replication::{Log, Replica};
 use x86::bits64::paging::{PAddr, VAddr, PML4};
 use x86::{controlregs, cpuid};
@@ -54,23 +60,104 @@ use memory::paddr_to_kernel_vaddr;
 use vspace::page_table::PageTable;
 
 pub mod acpi;
+pub mod cet;
+pub mod checkpoint;
 pub mod coreboot;
+pub mod cpufreq;
+pub mod crashdump;
 pub mod debug;
+pub mod efi;
+pub mod futex;
+pub mod gang;
+pub mod gdb;
 pub mod gdt;
+pub mod hotplug;
+pub mod hpet;
+pub mod hyperv;
+pub mod hypervisor;
+pub mod idle;
+pub mod ioapic;
 pub mod irq;
 pub mod kcb;
+pub mod kexec;
+pub mod kprobes;
+pub mod kvmclock;
+pub mod mce;
 pub mod memory;
+pub mod nmi;
+pub mod perf;
+pub mod power;
 pub mod process;
+pub mod replicas;
+pub mod rng;
+pub mod rtc;
+pub mod suspend;
+pub mod swap;
 pub mod syscall;
 pub mod timer;
 pub mod tlb;
+pub mod tsc;
+pub mod user_access;
+pub mod userfault;
+pub mod vdso;
 pub mod vspace;
+pub mod watchdog;
 
 mod isr;
 
 pub const MAX_NUMA_NODES: usize = 12;
 pub const MAX_CORES: usize = 192;
 
+/// The x86-64 backend (see `crate::arch_interface`).
+pub struct X86_64;
+
+/// The backend the kernel runs on.
+pub type Platform = X86_64;
+
+impl Arch for X86_64 {
+    type Kcb = kcb::Arch86Kcb;
+
+    const DEFAULT_TIMER_DEADLINE: Duration = timer::DEFAULT_TIMER_DEADLINE;
+
+    fn online_cores() -> usize {
+        coreboot::online_cores()
+    }
+
+    fn halt() -> ! {
+        halt()
+    }
+
+    fn enable_irqs() {
+        irq::enable()
+    }
+
+    fn disable_irqs() {
+        irq::disable()
+    }
+
+    fn set_timer(deadline: Duration) {
+        timer::set(deadline)
+    }
+
+    fn map_kernel(vbase: VAddr, pregion: (PAddr, usize), action: MapAction) -> Result<(), KError> {
+        kcb::get_kcb()
+            .arch
+            .init_vspace()
+            .map_generic(vbase, pregion, action, true)
+    }
+
+    fn map_kernel_identity(base: PAddr, size: usize, action: MapAction) -> Result<(), KError> {
+        kcb::get_kcb()
+            .arch
+            .init_vspace()
+            .map_identity(base, size, action)
+    }
+
+    fn advance_fs_replica() {
+        advance_fs_replica()
+    }
+}
+
 /// Make sure the machine supports what we require.
 fn assert_required_cpu_features() {
     let cpuid = cpuid::CpuId::new();
@@ -81,6 +168,7 @@ fn assert_required_cpu_features() {
     let has_syscalls = fi.as_ref().map_or(false, |f| f.has_sysenter_sysexit());
     let has_pae = fi.as_ref().map_or(false, |f| f.has_pae());
     let has_msr = fi.as_ref().map_or(false, |f| f.has_msr());
+    let has_pat = fi.as_ref().map_or(false, |f| f.has_pat());
 
     let has_sse = fi.as_ref().map_or(false, |f| f.has_sse());
     let has_sse3 = fi.as_ref().map_or(false, |f| f.has_sse3());
@@ -99,6 +187,29 @@ fn assert_required_cpu_features() {
     assert!(has_syscalls, "No sysenter? Run on a more modern machine!");
     assert!(has_pae, "No PAE? Run on a more modern machine!");
     assert!(has_msr, "No MSR? Run on a more modern machine!");
+    assert!(has_pat, "No PAT? Run on a more modern machine!");
+}
+
+/// Returns the framebuffer the bootloader left us (if it's one we can draw
+/// on).
+fn framebuffer_info(kernel_args: &KernelArgs) -> Option<FramebufferInfo> {
+    let fb = kernel_args.framebuffer.as_ref()?;
+    let format = match fb.format {
+        bootloader_shared::PixelFormat::Rgb => PixelFormat::Rgb,
+        bootloader_shared::PixelFormat::Bgr => PixelFormat::Bgr,
+        bootloader_shared::PixelFormat::Bitmask { red, green, blue } => {
+            PixelFormat::Bitmask { red, green, blue }
+        }
+    };
+
+    Some(FramebufferInfo {
+        paddr: fb.paddr,
+        size: fb.size,
+        width: fb.width,
+        height: fb.height,
+        stride: fb.stride,
+        format,
+    })
 }
 
 /// Enable SSE functionality and disable the old x87 FPU.
@@ -143,16 +254,11 @@ pub fn enable_fsgsbase() {
     };
 }
 
-/// Goes to sleep / halts the core.
+/// Goes to sleep / halts the core (in the idle state `idle::enter` picks).
 ///
 /// Interrupts are enabled before going to sleep.
 pub fn halt() -> ! {
-    unsafe {
-        irq::enable();
-        loop {
-            x86::halt()
-        }
-    }
+    idle::enter()
 }
 
 /// Return a struct to the currently installed page-tables so we
@@ -176,6 +282,11 @@ unsafe fn find_current_ptables() -> PageTable {
     }
 }
 
+/// Allocates one of the per-core kernel stacks (see `Arch86Kcb`).
+fn kernel_stack() -> GuardedStack {
+    GuardedStack::new(128 * BASE_PAGE_SIZE).expect("Can't map a kernel stack")
+}
+
 /// Construct the driver object to manipulate the interrupt controller (XAPIC)
 fn init_apic() -> x2apic::X2APICDriver {
     let mut apic = x2apic::X2APICDriver::default();
@@ -196,7 +307,7 @@ fn init_apic() -> x2apic::X2APICDriver {
 #[cfg(not(feature = "bsp-only"))]
 struct AppCoreArgs {
     _mem_region: Frame,
-    cmdline: BootloaderArguments,
+    config: KernelConfig,
     kernel_binary: &'static [u8],
     kernel_args: &'static KernelArgs,
     global_memory: &'static GlobalMemory,
@@ -216,6 +327,7 @@ fn start_app_core(args: Arc<AppCoreArgs>, initialized: &AtomicBool) {
     enable_sse();
     enable_fsgsbase();
     assert_required_cpu_features();
+    memory::init_pat();
     syscall::enable_fast_syscalls();
     irq::disable();
 
@@ -225,28 +337,32 @@ fn start_app_core(args: Arc<AppCoreArgs>, initialized: &AtomicBool) {
     };
     let start = rawtime::Instant::now();
 
-    let emanager = mcache::TCacheSp::new(args.node);
+    // Nodes without memory allocate from another one
+    let memory_node = crate::numa::memory_node(args.node);
+    let emanager = mcache::TCacheSp::new(memory_node);
     let init_ptable = unsafe { find_current_ptables() }; // Safe, done once during init
 
     let arch = kcb::Arch86Kcb::new(args.kernel_args, init_apic(), init_ptable);
-    let mut kcb =
-        Kcb::<kcb::Arch86Kcb>::new(args.kernel_binary, args.cmdline, emanager, arch, args.node);
+    let mut kcb = Kcb::<kcb::Arch86Kcb>::new(
+        args.kernel_binary,
+        args.config.clone(),
+        emanager,
+        arch,
+        args.node,
+    );
 
     kcb.set_global_memory(args.global_memory);
-    kcb.set_physical_memory_manager(mcache::TCache::new(args.node));
+    kcb.set_physical_memory_manager(mcache::TCache::new(memory_node));
 
     let static_kcb = unsafe {
         core::mem::transmute::<&mut Kcb<kcb::Arch86Kcb>, &'static mut Kcb<kcb::Arch86Kcb>>(&mut kcb)
     };
     kcb::init_kcb(static_kcb);
 
-    static_kcb.arch.set_interrupt_stacks(
-        OwnedStack::new(128 * BASE_PAGE_SIZE),
-        OwnedStack::new(128 * BASE_PAGE_SIZE),
-    );
     static_kcb
         .arch
-        .set_syscall_stack(OwnedStack::new(128 * BASE_PAGE_SIZE));
+        .set_interrupt_stacks(kernel_stack(), kernel_stack(), kernel_stack());
+    static_kcb.arch.set_syscall_stack(kernel_stack());
     static_kcb
         .arch
         .set_save_area(Box::pin(kpi::x86_64::SaveArea::empty()));
@@ -255,15 +371,21 @@ fn start_app_core(args: Arc<AppCoreArgs>, initialized: &AtomicBool) {
     );
     static_kcb.install();
     core::mem::forget(kcb);
+    mce::init();
+    perf::init();
+    user_access::init();
+    cet::init();
+    cpufreq::init();
 
     {
         let kcb = kcb::get_kcb();
-        let local_ridx = args.replica.register().unwrap();
-        kcb.setup_node_replication(args.replica.clone(), local_ridx);
+        let local_ridx = replicas::register_core(kcb, &args.replica, &args.fs_replica)
+            .expect("Can't register with the replicas");
 
-        let fs_replica = args.fs_replica.register().unwrap();
-        kcb.arch.setup_cnr(args.fs_replica.clone(), fs_replica);
-        kcb.register_with_process_replicas();
+        // Needs the core id (from `setup_cnr`)
+        if let Err(e) = kvmclock::init_core() {
+            debug!("Unable to use kvm-clock on this core: {}", e);
+        }
 
         // Don't modify this line without adjusting `coreboot` integration test:
         info!(
@@ -275,8 +397,12 @@ fn start_app_core(args: Arc<AppCoreArgs>, initialized: &AtomicBool) {
     }
 
     // Signals to BSP core that we're done initializing.
+    coreboot::mark_online(args.thread);
     initialized.store(true, Ordering::SeqCst);
 
+    // Don't schedule anything before everyone is up
+    coreboot::rendezvous();
+    unsafe { cet::enable() };
     crate::scheduler::schedule()
 }
 
@@ -296,7 +422,7 @@ fn start_app_core(args: Arc<AppCoreArgs>, initialized: &AtomicBool) {
 ///  - Local APIC driver
 #[cfg(not(feature = "bsp-only"))]
 fn boot_app_cores(
-    cmdline: BootloaderArguments,
+    config: KernelConfig,
     kernel_binary: &'static [u8],
     kernel_args: &'static KernelArgs,
     log: Arc<Log<'static, Op>>,
@@ -306,7 +432,6 @@ fn boot_app_cores(
 ) {
     use crate::memory::PhysicalPageProvider;
 
-    let bsp_thread = atopology::MACHINE_TOPOLOGY.current_thread();
     let kcb = kcb::get_kcb();
     debug_assert_eq!(kcb.node, 0, "The BSP core is not on node 0?");
 
@@ -326,6 +451,12 @@ fn boot_app_cores(
     fs_replicas.push(fs_replica);
 
     for node in 1..numa_nodes {
+        if !crate::numa::has_cores(node) {
+            // Filled in below, once the nodes it can use exist
+            replicas.push(replicas[0].clone());
+            fs_replicas.push(fs_replicas[0].clone());
+            continue;
+        }
         kcb.set_allocation_affinity(node as atopology::NodeId)
             .expect("Can't set affinity");
 
@@ -342,18 +473,23 @@ fn boot_app_cores(
         kcb.set_allocation_affinity(0).expect("Can't set affinity");
     }
 
+    // Nodes without cores share the replicas of the nearest node with cores
+    for node in 1..numa_nodes {
+        let other = crate::numa::replica_node(node);
+        if other != node {
+            replicas[node] = replicas[other].clone();
+            fs_replicas[node] = fs_replicas[other].clone();
+        }
+    }
+
     let global_memory = kcb
         .physical_memory
         .gmanager
         .expect("boot_app_cores requires kcb.gmanager");
 
-    // For now just boot everything, except ourselves
-    // Create a single log and one replica...
-    let threads_to_boot = atopology::MACHINE_TOPOLOGY
-        .threads()
-        .filter(|t| t != &bsp_thread);
-
-    for thread in threads_to_boot {
+    let mut cores = 1;
+    for thread in coreboot::application_cores() {
+        cores += 1;
         let node = thread.node_id.unwrap_or(0);
         trace!("Booting {:?} on node {}", thread, node);
         kcb.set_allocation_affinity(node)
@@ -361,7 +497,7 @@ fn boot_app_cores(
 
         // A simple stack for the app core (non bootstrap core)
         let coreboot_stack: OwnedStack = OwnedStack::new(BASE_PAGE_SIZE * 512);
-        let mem_region = global_memory.node_caches[node as usize]
+        let mem_region = global_memory.node_caches[crate::numa::memory_node(node)]
             .lock()
             .allocate_large_page()
             .expect("Can't allocate large page");
@@ -369,7 +505,7 @@ fn boot_app_cores(
         let initialized: AtomicBool = AtomicBool::new(false);
         let arg: Arc<AppCoreArgs> = Arc::try_new(AppCoreArgs {
             _mem_region: mem_region,
-            cmdline,
+            config: config.clone(),
             kernel_binary,
             kernel_args,
             node,
@@ -417,6 +553,12 @@ fn boot_app_cores(
         kcb.set_allocation_affinity(0).expect("Can't set affinity");
     }
 
+    coreboot::release();
+    info!(
+        "SMP: {} of {} cores online",
+        coreboot::online_cores(),
+        cores
+    );
     core::mem::forget(replicas);
 }
 
@@ -431,6 +573,8 @@ fn boot_app_cores(
 /// only run this once and don't expect thousands of NUMA nodes or
 /// memory regions anyways.
 ///
+/// The affinities come from the SRAT memory ranges `crate::acpi` parsed.
+///
 /// # Notes
 /// There are some implicit assumptions here that a memory region always has
 /// just one affinity -- which is also what `topology` assumes.
@@ -438,23 +582,25 @@ fn identify_numa_affinity(
     memory_regions: &ArrayVec<Frame, MAX_PHYSICAL_REGIONS>,
     annotated_regions: &mut ArrayVec<Frame, MAX_PHYSICAL_REGIONS>,
 ) {
-    if atopology::MACHINE_TOPOLOGY.num_nodes() > 0 {
+    // The allocators are indexed by the node ids of `atopology`
+    let numa_memory = crate::acpi::platform()
+        .filter(|platform| platform.nodes.len() == atopology::MACHINE_TOPOLOGY.num_nodes())
+        .map(|platform| platform.memory.as_slice())
+        .filter(|memory| !memory.is_empty());
+
+    if let Some(numa_memory) = numa_memory {
         for orig_frame in memory_regions.iter() {
-            for node in atopology::MACHINE_TOPOLOGY.nodes() {
-                // trying to find a NUMA memory affinity that contains the given `orig_frame`
-                for affinity_region in node.memory() {
-                    match affinity_region.contains(orig_frame.base.into(), orig_frame.end().into())
-                    {
-                        (_, mid, _) => {
-                            if mid.0 > 0 {
-                                let mid_paddr = (PAddr::from(mid.0), PAddr::from(mid.1));
-                                let annotated_frame = Frame::from_range(mid_paddr, node.id);
-                                trace!("Identified NUMA region for {:?}", annotated_frame);
-                                assert!(!annotated_regions.is_full());
-                                annotated_regions.push(annotated_frame);
-                            }
-                        }
-                    }
+            // trying to find the NUMA memory affinities that overlap with the given `orig_frame`
+            for range in numa_memory.iter() {
+                let start = core::cmp::max(orig_frame.base.as_u64(), range.base);
+                let end = core::cmp::min(orig_frame.end().as_u64(), range.base + range.length);
+                if start < end {
+                    let mid_paddr = (PAddr::from(start), PAddr::from(end));
+                    let annotated_frame =
+                        Frame::from_range(mid_paddr, range.node as atopology::NodeId);
+                    trace!("Identified NUMA region for {:?}", annotated_frame);
+                    assert!(!annotated_regions.is_full());
+                    annotated_regions.push(annotated_frame);
                 }
             }
         }
@@ -501,14 +647,16 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     // they are lazy_static we may not end up using them until way later).
     lazy_static::initialize(&rawtime::WALL_TIME_ANCHOR);
     lazy_static::initialize(&rawtime::BOOT_TIME_ANCHOR);
+    tsc::record_boot();
 
     // We construct a &'static mut for KernelArgs (mut is just because of `mm_iter`)
     let kernel_args: &'static mut KernelArgs =
         unsafe { transmute::<u64, &'static mut KernelArgs>(argc as u64) };
 
     // Parse the command line arguments
-    let cmdline = BootloaderArguments::from_str(kernel_args.command_line);
-    klogger::init(cmdline.log_filter).expect("Can't set-up logging");
+    let config = KernelConfig::from_bootloader(kernel_args.command_line);
+    crate::console::init(config.log_filter).expect("Can't set-up logging");
+    config.warn_ignored();
 
     info!(
         "Started at {} with {:?} since CPU startup",
@@ -525,12 +673,15 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     // Figure out what this machine supports,
     // fail if it doesn't have what we need.
     assert_required_cpu_features();
+    memory::init_pat();
     syscall::enable_fast_syscalls();
 
     // Initializes the serial console.
     // (this is already done in a very basic form by klogger/init_logging())
     debug::init();
 
+    info!("Booted with {:?}", kernel_args.protocol);
+
     // Get the kernel binary (to later store it in the KCB)
     // The binary is useful for symbol name lookups when printing stacktraces
     // in case things go wrong (see panic.rs).
@@ -552,17 +703,26 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     // regions of memory.
     let mut emanager: Option<mcache::TCacheSp> = None;
     let mut memory_regions: ArrayVec<Frame, MAX_PHYSICAL_REGIONS> = ArrayVec::new();
+    let mut memory_used: usize = 0;
     for region in &mut kernel_args.mm_iter {
         if region.ty == MemoryType::CONVENTIONAL {
             debug!("Found physical memory region {:?}", region);
 
             let base: PAddr = PAddr::from(region.phys_start);
             let size: usize = region.page_count as usize * BASE_PAGE_SIZE;
-            let f = Frame::new(base, size, 0);
 
             const ONE_MIB: usize = 1 * 1024 * 1024;
             const EARLY_MEMORY_CAPACITY: usize = 32 * 1024 * 1024;
             if base.as_usize() >= ONE_MIB {
+                // Respect `mem=` (if given) by cutting off what's above the limit
+                let size = config.usable_memory(memory_used, size);
+                if size == 0 {
+                    debug!("Skipping {:?} (mem={:?})", region, config.memory_limit);
+                    continue;
+                }
+                memory_used += size;
+                let f = Frame::new(base, size, 0);
+
                 if size > EARLY_MEMORY_CAPACITY && emanager.is_none() {
                     // This seems like a good frame for the early allocator on the BSP core.
                     // We don't have NUMA information yet so we'd hope that on
@@ -598,7 +758,7 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     let arch = kcb::Arch86Kcb::new(kernel_args, init_apic(), init_ptable);
 
     // Construct the Kcb so we can access these things later on in the code
-    let mut kcb = Kcb::new(kernel_binary, cmdline, emanager, arch, 0);
+    let mut kcb = Kcb::new(kernel_binary, config.clone(), emanager, arch, 0);
     kcb::init_kcb(&mut kcb);
     debug!("Memory allocation should work at this point...");
     let static_kcb = unsafe {
@@ -606,13 +766,10 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     };
 
     // Let's finish KCB initialization (easier as we have alloc now):
-    static_kcb.arch.set_interrupt_stacks(
-        OwnedStack::new(128 * BASE_PAGE_SIZE),
-        OwnedStack::new(128 * BASE_PAGE_SIZE),
-    );
     static_kcb
         .arch
-        .set_syscall_stack(OwnedStack::new(128 * BASE_PAGE_SIZE));
+        .set_interrupt_stacks(kernel_stack(), kernel_stack(), kernel_stack());
+    static_kcb.arch.set_syscall_stack(kernel_stack());
     static_kcb
         .arch
         .set_save_area(Box::pin(kpi::x86_64::SaveArea::empty()));
@@ -620,6 +777,15 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
         String::try_with_capacity(128).expect("Not enough memory to initialize system"),
     );
     static_kcb.install();
+    mce::init();
+    perf::init();
+    user_access::init();
+    cet::init();
+    cpufreq::init();
+
+    // Stop as early as we can handle breakpoints
+    #[cfg(feature = "gdb")]
+    gdb::wait_for_debugger();
 
     // Make sure we don't drop the KCB and anything in it,
     // the kcb is on the init stack and remains allocated on it,
@@ -630,12 +796,24 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     #[cfg(feature = "test-double-fault")]
     debug::cause_double_fault();
 
+    // Also log to the screen (needs alloc)
+    if let Some(info) = framebuffer_info(static_kcb.arch.kernel_args()) {
+        if let Err(e) = crate::drivers::framebuffer::init(info) {
+            error!("Can't use the framebuffer for logging: {}", e);
+        }
+    }
+
     // Initialize the ACPI sub-system (needs alloc)
     {
         let r = acpi::init();
         assert!(r.is_ok());
     }
 
+    // Parse the MADT, SRAT and SLIT ourselves (needs alloc)
+    if let Err(e) = crate::acpi::init(&acpi::KernelMemory, &acpi::rsdp_hints()) {
+        warn!("Can't parse the ACPI tables: {}", e);
+    }
+
     // Initialize the machine topology (needs ACPI and alloc):
     {
         lazy_static::initialize(&atopology::MACHINE_TOPOLOGY);
@@ -689,48 +867,160 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
         kcb.set_physical_memory_manager(tcache);
     }
 
+    // UEFI runtime services (needs global memory and the identity mapping
+    // of the bootloader)
+    if let Err(e) = efi::init(static_kcb.arch.kernel_args()) {
+        debug!("Unable to use the UEFI runtime services: {}", e);
+    }
+
     // Set-up interrupt routing drivers (I/O APIC controllers)
-    irq::ioapic_initialize();
+    ioapic::init().expect("Can't initialize the IO-APICs");
+
+    // Paravirtual clocks (they need global memory), kvm-clock also gives
+    // us the wall-clock time
+    if let Err(e) = kvmclock::init() {
+        debug!("Unable to use kvm-clock: {}", e);
+    }
+    if let Err(e) = hyperv::init() {
+        debug!("Unable to use the Hyper-V reference TSC: {}", e);
+    }
+
+    // Establish the wall-clock time
+    match kvmclock::wallclock() {
+        Ok(now) => crate::time::init(now, "kvm-clock"),
+        Err(_) => match efi::time() {
+            Ok(now) => crate::time::init(now, "UEFI"),
+            Err(_) => {
+                let now = core::time::Duration::from_secs(rtc::now().unix_time());
+                crate::time::init(now, "RTC")
+            }
+        },
+    }
+
+    // Register clocksources (the TSC might need the HPET for calibration)
+    // and set up the timer (needs a clocksource)
+    {
+        use crate::time::clocksource;
+
+        if let Err(e) = hpet::init() {
+            debug!("Unable to use HPET: {}", e);
+        }
+        if let Err(e) = tsc::init() {
+            error!("Unable to register TSC clocksource: {}", e);
+        }
+        if let Some(name) = config.clocksource {
+            if let Err(e) = clocksource::select(name) {
+                error!("Clocksource {} unavailable: {}", name, e);
+            }
+        }
+        timer::init();
+    }
+
+    // Processes read the clock from the time page (needs the TSC frequency
+    // and the wall-clock time)
+    if let Err(e) = vdso::init() {
+        error!("Unable to set up the time page: {}", e);
+    }
+
+    // Seed the entropy pool (devices like virtio-rng add more once they
+    // are attached)
+    if let Err(e) = rng::init() {
+        debug!("Unable to use RDRAND: {}", e);
+    }
+
+    // Find devices on the PCI bus and attach drivers (needs ACPI, the kernel
+    // vspace and global memory)
+    {
+        use crate::drivers::{self, pci};
+
+        if let Err(e) = drivers::init() {
+            error!("Unable to register drivers: {}", e);
+        }
+
+        let r = match acpi::mcfg() {
+            Some((base, start_bus, end_bus)) => {
+                let size = ((end_bus - start_bus) as usize + 1) << 20;
+                kcb::get_kcb()
+                    .arch
+                    .init_vspace()
+                    .map_identity(base, size, MapAction::ReadWriteKernel)
+                    .and_then(|_| {
+                        pci::init(Box::new(pci::Ecam {
+                            base: base.as_u64(),
+                            start_bus,
+                            end_bus,
+                        }))
+                    })
+            }
+            None => pci::init(Box::new(pci::LegacyConfigSpace)),
+        };
+        if let Err(e) = r {
+            error!("PCI enumeration failed: {}", e);
+        }
+    }
+
+    // Find where crash dumps go (needs the block devices from PCI)
+    if let Some(target) = config.crashdump {
+        if let Err(e) = crashdump::init(target) {
+            error!("Can't write crash dumps to {}: {}", target, e);
+        }
+    }
+
+    // Evicted pages may go to a block device as well
+    if config.zswap.is_some() || config.swap.is_some() {
+        if let Err(e) = swap::init(config.zswap, config.swap) {
+            error!("Can't evict pages: {}", e);
+        }
+    }
+
+    if config.syscall_latency {
+        if let Err(e) = crate::latency::init() {
+            error!("Can't record system call latencies: {}", e);
+        }
+    }
+
+    // A trace to replay is just another module from the bootloader
+    let replay = static_kcb
+        .arch
+        .kernel_args()
+        .modules
+        .iter()
+        .find(|module| module.name() == crate::trace::REPLAY_MODULE)
+        .map(|module| unsafe { module.as_slice() });
+    if let Err(e) = crate::trace::init(replay) {
+        error!("Can't trace system calls: {}", e);
+    }
+
+    #[cfg(feature = "heap-tracking")]
+    if let Err(e) = crate::memory::track::init() {
+        error!("Can't keep track of heap allocations: {}", e);
+    }
+
+    if let Some(seed) = config.fault_seed {
+        if let Err(e) = crate::fault::init(seed) {
+            error!("Can't inject faults: {}", e);
+        } else {
+            warn!("Injecting faults (seed {})", seed);
+        }
+    }
 
     // Create the global operation log and first replica
     // and store it in the BSP kcb
     let log: Arc<Log<Op>> = Arc::try_new(Log::<Op>::new(LARGE_PAGE_SIZE))
         .expect("Not enough memory to initialize system");
     let bsp_replica = Replica::<KernelNode>::new(&log);
-    let local_ridx = bsp_replica.register().unwrap();
-    {
-        let kcb = kcb::get_kcb();
-        kcb.setup_node_replication(bsp_replica.clone(), local_ridx);
-    }
-
-    let num_nodes = atopology::MACHINE_TOPOLOGY.num_nodes();
-    let func = move |rid: &[AtomicBool; cnr::MAX_REPLICAS_PER_LOG], idx: usize| {
-        assert_eq!(rid.len(), cnr::MAX_REPLICAS_PER_LOG);
-        for replica in 0..num_nodes {
-            if rid[replica].load(Ordering::Relaxed) == true {
-                let mut cores = atopology::MACHINE_TOPOLOGY
-                    .nodes()
-                    .nth(replica)
-                    .unwrap()
-                    .threads();
-                let core_id = cores.nth(idx - 1).unwrap().id;
-                trace!(
-                    "Replica {} needs to make progress on Log {}; use core_id {:?}",
-                    replica + 1,
-                    idx,
-                    core_id
-                );
-                crate::arch::tlb::advance_replica(core_id, idx);
-                rid[replica].store(false, Ordering::Relaxed);
-            }
-        }
-    };
+    if let Err(e) = crate::procfs::register("/proc/processes", crate::nr::proc_processes) {
+        debug!("Unable to register /proc/processes: {}", e);
+    }
+    if let Err(e) = crate::procfs::register("/proc/replicas", replicas::proc_replicas) {
+        debug!("Unable to register /proc/replicas: {}", e);
+    }
+    if let Err(e) = crate::procfs::register("/proc/gangs", gang::proc_gangs) {
+        debug!("Unable to register /proc/gangs: {}", e);
+    }
 
-    let cores_per_node = atopology::MACHINE_TOPOLOGY
-        .nodes()
-        .nth(0)
-        .map(|node| node.threads().count())
-        .unwrap_or(1);
+    // Nodes may have different numbers of cores, go with the biggest one
+    let cores_per_node = crate::numa::max_cores_per_node();
 
     let mut fs_logs: Vec<Arc<MlnrLog<Modify>>> =
         Vec::try_with_capacity(cores_per_node).expect("Not enough memory to initialize system");
@@ -739,8 +1029,8 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
         let mut log = Arc::try_new(MlnrLog::<Modify>::new(LARGE_PAGE_SIZE, i + 1))
             .expect("Not enough memory to initialize system");
 
-        // TODO(api): `func` should be passed as part of constructor:
-        unsafe { Arc::get_mut_unchecked(&mut log).update_closure(func) };
+        // TODO(api): `log_full` should be passed as part of constructor:
+        unsafe { Arc::get_mut_unchecked(&mut log).update_closure(replicas::log_full) };
 
         debug_assert!(fs_logs.capacity() > i, "No re-allocation for fs_logs.");
         fs_logs.push(log);
@@ -752,23 +1042,49 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
             .try_clone()
             .expect("Not enough memory to initialize system"),
     );
-    let local_ridx = fs_replica.register().unwrap();
     {
+        lazy_static::initialize(&process::PROCESS_TABLE);
         let kcb = kcb::get_kcb();
-        kcb.arch.setup_cnr(fs_replica.clone(), local_ridx);
+        replicas::register_core(kcb, &bsp_replica, &fs_replica)
+            .expect("Can't register with the replicas");
         kcb.arch.init_cnrfs();
     }
+    if let Err(e) = KernelNode::set_core_policy(config.core_policy) {
+        error!("Can't set the core policy: {}", e);
+    }
 
-    {
-        lazy_static::initialize(&process::PROCESS_TABLE);
-        let kcb = kcb::get_kcb();
-        kcb.register_with_process_replicas();
+    // Bring back the files and directories of the last boot (the initrd
+    // replaces the ones it has)
+    if let Some(target) = config.fsjournal {
+        if let Err(e) = crate::fs::journal::init(target) {
+            error!("Can't mount the file-system journal on {}: {}", target, e);
+        }
+    }
+
+    // Unpack the initrd before any process looks for its files
+    if let Err(e) = crate::initrd::init(&kernel_args.modules, config.initrd) {
+        error!("Can't unpack the initrd: {}", e);
+    }
+
+    // Bring up the kernel network stack if we were asked to (needs alloc, vspace)
+    #[cfg(feature = "smoltcp")]
+    if let Some(net) = config.net {
+        match crate::net::init(net) {
+            Ok(()) => {
+                info!("Network stack initialized ({:?})", net);
+                if let Err(e) = crate::rpc::init() {
+                    error!("Unable to initialize RPC transport: {}", e);
+                }
+            }
+            Err(e) => error!("Unable to initialize network stack: {}", e),
+        }
     }
 
     // Bring up the rest of the system (needs topology, APIC, and global memory)
+    coreboot::mark_online(atopology::MACHINE_TOPOLOGY.current_thread().id);
     #[cfg(not(feature = "bsp-only"))]
     boot_app_cores(
-        cmdline,
+        config,
         kernel_binary,
         kernel_args,
         log.clone(),
@@ -779,6 +1095,7 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
 
     // Done with initialization, now we go in
     // the arch-independent part:
+    unsafe { cet::enable() };
     let _r = xmain();
 
     error!("Returned from main, shutting down...");
@@ -0,0 +1,178 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Non-maskable interrupt handling.
+//!
+//! An NMI can arrive anywhere (also with interrupts disabled, in the middle
+//! of another interrupt handler, or while the core holds a lock) so it runs
+//! on its own IST stack and leaves `kcb.save_area` alone: `isr_handler_frame2`
+//! saves the registers on that stack and calls `handle_nmi`.
+//!
+//! Every NMI dumps where it hit the core. NMIs we asked for with
+//! `nmi_all` return to the interrupted code afterwards, anything else
+//! (the watchdog found the core stuck, the hardware watchdog expired or
+//! someone injected one) terminates, or stops in the debugger if we have
+//! the GDB stub. The stub also uses NMIs to stop cores, and so does the
+//! crash dump to collect their registers, those don't dump.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use klogger::{sprint, sprintln};
+use rustc_demangle::demangle;
+
+use crate::panic::{backtrace_from, symbolize};
+use crate::ExitReason;
+
+use super::irq::InterruptFrame;
+use super::kcb::get_kcb;
+use super::{coreboot, crashdump, debug, gdb, watchdog, MAX_CORES};
+
+/// How long `nmi_all` waits for the other cores to finish their dumps.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where we stop walking the stack.
+const MAX_FRAMES: usize = 32;
+/// How far above the interrupted `rsp` we still trust a frame pointer
+/// (all kernel stacks are smaller).
+const MAX_STACK_SIZE: u64 = 1 << 20;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Cores that should expect an NMI from `nmi_all`.
+static REQUESTED: [AtomicBool; MAX_CORES] = [NOT_REQUESTED; MAX_CORES];
+
+/// Several cores dump at the same time, don't interleave them.
+static DUMP_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+/// Prints a return address (and the function it's in).
+fn print_frame(depth: usize, addr: u64, elf_offset: u64) {
+    sprint!(
+        "  #{} {:#x} ({:#x})",
+        depth,
+        addr,
+        addr.wrapping_sub(elf_offset)
+    );
+    match symbolize(addr) {
+        Some((name, offset)) => sprintln!(" {:#}+{:#x}", demangle(name), offset),
+        None => sprintln!(""),
+    }
+}
+
+/// Prints the return addresses of a frame pointer chain.
+///
+/// Doesn't allocate or take locks (unlike `panic::backtrace_from`) since
+/// we might have interrupted the allocator. The addresses in parenthesis
+/// are what `addr2line` wants for the kernel ELF.
+fn walk_frames(frame: &InterruptFrame) {
+    let elf_offset = get_kcb().arch.kernel_args().kernel_elf_offset.as_u64();
+    let stack = frame.rsp..frame.rsp.saturating_add(MAX_STACK_SIZE);
+
+    print_frame(0, frame.rip, elf_offset);
+    let mut rbp = frame.rbp;
+    for depth in 1..MAX_FRAMES {
+        if !stack.contains(&rbp) || rbp % 8 != 0 {
+            break;
+        }
+        // Safe: `rbp` points into the interrupted stack
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if ret == 0 {
+            break;
+        }
+        print_frame(depth, ret, elf_offset);
+        // Frames only go up the stack
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+/// Called by `isr_handler_frame2` (see `isr.S`).
+#[no_mangle]
+pub extern "C" fn handle_nmi(frame: &mut InterruptFrame) {
+    if gdb::handle_nmi(frame) || crashdump::handle_nmi(frame) {
+        return;
+    }
+    let guard = DUMP_LOCK.lock();
+
+    let kcb = get_kcb();
+    let core = kcb.arch.id();
+    let requested = REQUESTED[core].load(Ordering::Acquire);
+    let stuck = watchdog::is_stuck();
+
+    if stuck {
+        sprintln!("[IRQ] NMI on core {} (stuck, sent by watchdog)", core);
+    } else if requested {
+        sprintln!("[IRQ] NMI on core {} (requested)", core);
+    } else {
+        sprintln!("[IRQ] NMI on core {}", core);
+    }
+    sprintln!(
+        "rip = {:#x} rsp = {:#x} rbp = {:#x} rflags = {:#x} in {}",
+        frame.rip,
+        frame.rsp,
+        frame.rbp,
+        frame.rflags,
+        if frame.in_user_space() {
+            "user-space"
+        } else {
+            "the kernel"
+        }
+    );
+
+    if requested && !stuck {
+        if !frame.in_user_space() {
+            walk_frames(frame);
+        }
+        REQUESTED[core].store(false, Ordering::Release);
+        return;
+    }
+
+    if !kcb.in_panic_mode && !frame.in_user_space() {
+        backtrace_from(frame.rbp, frame.rsp, frame.rip);
+    }
+    if gdb::ENABLED {
+        drop(guard);
+        gdb::stuck(frame);
+        return;
+    }
+    debug::shutdown(ExitReason::Watchdog);
+}
+
+/// Sends an NMI to all other online cores and waits until they dumped
+/// their state.
+///
+/// Returns how many cores answered (the others are probably stuck in
+/// an NMI handler already).
+pub fn nmi_all() -> usize {
+    let me = get_kcb().arch.id();
+
+    let mut asked = 0;
+    for thread in atopology::MACHINE_TOPOLOGY.threads() {
+        if thread.id != me && coreboot::is_online(thread.id) {
+            REQUESTED[thread.id].store(true, Ordering::Release);
+            watchdog::send_nmi(thread.id);
+            asked += 1;
+        }
+    }
+
+    let start = rawtime::Instant::now();
+    let pending = || {
+        REQUESTED
+            .iter()
+            .filter(|r| r.load(Ordering::Acquire))
+            .count()
+    };
+    while pending() > 0 && start.elapsed() < TIMEOUT {
+        core::hint::spin_loop();
+    }
+
+    let _guard = DUMP_LOCK.lock();
+    for (core, requested) in REQUESTED.iter().enumerate() {
+        if requested.load(Ordering::Acquire) {
+            sprintln!("[IRQ] core {} didn't answer its NMI", core);
+        }
+    }
+    asked.saturating_sub(pending())
+}
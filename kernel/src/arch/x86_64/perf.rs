@@ -0,0 +1,220 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Hardware performance counters.
+//!
+//! We use the general purpose counters of the architectural performance
+//! monitoring (CPUID leaf 0xA): `IA32_PERFEVTSELx` selects what `IA32_PMCx`
+//! counts. Offcore response events also need a request/response mask in
+//! one of the two `MSR_OFFCORE_RSP_x`, a counter gets whichever is free.
+//!
+//! A counter either counts everything on the core (`PerfScope::Core`) or
+//! only the user-space of the process that started it
+//! (`PerfScope::Process`). Process counters belong to the executor: they
+//! leave the PMU with it and come back when it runs on the core again
+//! (see `swap_current_executor` in the `ArchSpecificKcb` impl of `Arch86Kcb`).
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use kpi::perf::{PerfEvent, PerfScope};
+use log::info;
+use x86::msr::{rdmsr, wrmsr};
+
+use crate::error::KError;
+
+use super::kcb::get_kcb;
+
+const IA32_PMC0: u32 = 0xc1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+
+/// `MSR_OFFCORE_RSP_0` and `MSR_OFFCORE_RSP_1`.
+const MSR_OFFCORE_RSP: [u32; 2] = [0x1a6, 0x1a7];
+/// The event that takes its mask from the `MSR_OFFCORE_RSP` with the same
+/// index.
+const OFFCORE_EVENTS: [u8; 2] = [0xb7, 0xbb];
+
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_EN: u64 = 1 << 22;
+
+/// How many counters we hand out (at most, the PMU may have fewer).
+pub const MAX_COUNTERS: usize = 4;
+
+/// How many counters the PMU of the cores has (set by `init`).
+static AVAILABLE: AtomicUsize = AtomicUsize::new(0);
+
+/// A started counter.
+#[derive(Debug, Copy, Clone)]
+pub struct Counter {
+    event: PerfEvent,
+    scope: PerfScope,
+    /// What it counted while it was loaded before (the hardware counter
+    /// starts from zero every time we load it).
+    value: u64,
+    /// The `MSR_OFFCORE_RSP` it uses while it's loaded.
+    offcore_msr: Option<usize>,
+}
+
+impl Counter {
+    fn new(event: PerfEvent, scope: PerfScope) -> Counter {
+        Counter {
+            event,
+            scope,
+            value: 0,
+            offcore_msr: None,
+        }
+    }
+}
+
+/// The counters loaded in the PMU of a core, or the process counters of
+/// an executor that doesn't run.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Counters([Option<Counter>; MAX_COUNTERS]);
+
+impl Counters {
+    /// Which `MSR_OFFCORE_RSP` an offcore event with `mask` can use.
+    fn offcore_msr(&self, mask: u64) -> Option<usize> {
+        (0..MSR_OFFCORE_RSP.len()).find(|msr| {
+            self.0
+                .iter()
+                .flatten()
+                .all(|c| c.offcore_msr != Some(*msr) || c.event.offcore == mask)
+        })
+    }
+
+    /// Programs hardware counter `idx` with `counter`.
+    fn load(&mut self, idx: usize, mut counter: Counter) -> Result<(), KError> {
+        debug_assert!(self.0[idx].is_none());
+        let mut event = counter.event;
+        if event.is_offcore() {
+            let msr = self.offcore_msr(event.offcore).ok_or(KError::CounterBusy)?;
+            event.event = OFFCORE_EVENTS[msr];
+            counter.offcore_msr = Some(msr);
+            unsafe { wrmsr(MSR_OFFCORE_RSP[msr], event.offcore) };
+        }
+
+        let mut evtsel = event.config() | EVTSEL_USR | EVTSEL_EN;
+        if counter.scope == PerfScope::Core {
+            evtsel |= EVTSEL_OS;
+        }
+        unsafe {
+            wrmsr(IA32_PERFEVTSEL0 + idx as u32, 0);
+            wrmsr(IA32_PMC0 + idx as u32, 0);
+            wrmsr(IA32_PERFEVTSEL0 + idx as u32, evtsel);
+        }
+        self.0[idx] = Some(counter);
+        Ok(())
+    }
+
+    /// Stops hardware counter `idx`, returns what it was programmed with
+    /// (and everything it counted so far).
+    fn unload(&mut self, idx: usize) -> Option<Counter> {
+        let mut counter = self.0[idx].take()?;
+        unsafe {
+            wrmsr(IA32_PERFEVTSEL0 + idx as u32, 0);
+            counter.value += rdmsr(IA32_PMC0 + idx as u32);
+        }
+        counter.offcore_msr = None;
+        Some(counter)
+    }
+
+    /// The current value of hardware counter `idx`.
+    fn value(&self, idx: usize) -> Option<u64> {
+        self.0[idx].map(|c| c.value + unsafe { rdmsr(IA32_PMC0 + idx as u32) })
+    }
+}
+
+/// Finds out how many counters we have and stops all of them.
+pub fn init() {
+    let cpuid = x86::cpuid::CpuId::new();
+    let (version, counters) = cpuid
+        .get_performance_monitoring_info()
+        .map_or((0, 0), |pm| {
+            (pm.version_id(), pm.number_of_counters() as usize)
+        });
+    if version == 0 || counters == 0 {
+        return;
+    }
+
+    let counters = core::cmp::min(counters, MAX_COUNTERS);
+    unsafe {
+        for idx in 0..counters {
+            wrmsr(IA32_PERFEVTSEL0 + idx as u32, 0);
+        }
+        if version >= 2 {
+            wrmsr(IA32_PERF_GLOBAL_CTRL, (1 << counters) - 1);
+        }
+    }
+
+    AVAILABLE.store(counters, Ordering::Relaxed);
+    if get_kcb().arch.id() == 0 {
+        info!("PMU: version {}, using {} counters", version, counters);
+    }
+}
+
+fn check(idx: usize) -> Result<(), KError> {
+    match AVAILABLE.load(Ordering::Relaxed) {
+        0 => Err(KError::PmuUnavailable),
+        available if idx >= available => Err(KError::InvalidCounter),
+        _ => Ok(()),
+    }
+}
+
+/// Moves the process counters of the executor that leaves the core
+/// (`from`) out of the PMU and loads the ones of the executor that runs
+/// next (`to`).
+pub fn switch(core: &mut Counters, from: Option<&mut Counters>, to: Option<&mut Counters>) {
+    if let Some(from) = from {
+        for idx in 0..MAX_COUNTERS {
+            if core.0[idx].map_or(false, |c| c.scope == PerfScope::Process) {
+                from.0[idx] = core.unload(idx);
+            }
+        }
+    }
+
+    if let Some(to) = to {
+        for idx in 0..MAX_COUNTERS {
+            if let Some(counter) = to.0[idx] {
+                // If a core counter took the slot in the meantime the
+                // process counter stays with the executor (and pauses)
+                if core.0[idx].is_none() && core.load(idx, counter).is_ok() {
+                    to.0[idx] = None;
+                }
+            }
+        }
+    }
+}
+
+/// Starts counter `idx` of the current core.
+pub fn start(idx: usize, event: PerfEvent, scope: PerfScope) -> Result<(), KError> {
+    check(idx)?;
+    if scope == PerfScope::Unknown {
+        return Err(KError::InvalidCounter);
+    }
+
+    let (core, executor) = get_kcb().arch.perf_counters();
+    if core.0[idx].is_some() || executor.map_or(false, |e| e.0[idx].is_some()) {
+        return Err(KError::CounterBusy);
+    }
+    core.load(idx, Counter::new(event, scope))
+}
+
+/// Stops counter `idx` of the current core, returns what it counted.
+pub fn stop(idx: usize) -> Result<u64, KError> {
+    check(idx)?;
+    let (core, executor) = get_kcb().arch.perf_counters();
+    core.unload(idx)
+        .or_else(|| executor.and_then(|e| e.0[idx].take()))
+        .map(|c| c.value)
+        .ok_or(KError::CounterNotStarted)
+}
+
+/// Reads counter `idx` of the current core.
+pub fn read(idx: usize) -> Result<u64, KError> {
+    check(idx)?;
+    let (core, executor) = get_kcb().arch.perf_counters();
+    core.value(idx)
+        .or_else(|| executor.and_then(|e| e.0[idx].map(|c| c.value)))
+        .ok_or(KError::CounterNotStarted)
+}
@@ -0,0 +1,65 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Turning the machine off and resetting it.
+//!
+//! Under QEMU the debug exit device ends the run with the exit code we
+//! give it, that's how integration tests report whether they passed.
+//! Everywhere else we power off through ACPI (S5) or the UEFI runtime
+//! services. To reset we try the ACPI reset register, UEFI, the keyboard
+//! controller and a triple fault, in that order.
+
+use core::ptr;
+
+use log::{error, info, warn};
+use x86::dtables::{self, DescriptorTablePointer};
+use x86::io;
+
+use super::efi::{self, ResetType};
+use super::{acpi, irq};
+
+/// Turns the machine off, QEMU exits with `code`.
+pub fn power_off(code: u8) -> ! {
+    info!("Powering off (exit code {})", code);
+    super::debug::request_exit(code);
+
+    irq::disable();
+    let status = acpi::power_off();
+    warn!("ACPI power off didn't work ({:?}), trying UEFI", status);
+    let e = efi::reset(ResetType::Shutdown);
+    error!("Couldn't power off: {}", e);
+
+    loop {
+        unsafe { x86::halt() };
+    }
+}
+
+/// Resets the machine.
+pub fn reboot() -> ! {
+    info!("Rebooting");
+    irq::disable();
+
+    let status = acpi::reset();
+    warn!("ACPI reset didn't work ({:?}), trying UEFI", status);
+    let e = efi::reset(ResetType::Cold);
+    warn!("UEFI reset didn't work ({}), trying the 8042", e);
+    unsafe {
+        // Pulses the reset line
+        io::outb(0x64, 0xfe);
+    }
+
+    // Nothing handles an exception without an IDT
+    warn!("8042 reset didn't work, triple faulting");
+    unsafe {
+        let idt: DescriptorTablePointer<u64> = DescriptorTablePointer {
+            limit: 0,
+            base: ptr::null(),
+        };
+        dtables::lidt(&idt);
+        llvm_asm!("int3" :::: "volatile");
+    }
+
+    loop {
+        unsafe { x86::halt() };
+    }
+}
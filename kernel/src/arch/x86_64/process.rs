@@ -6,13 +6,12 @@ use alloc::collections::TryReserveError;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::cmp::PartialEq;
-use core::ops::{Deref, DerefMut};
 use core::{fmt, ptr};
 
 use arrayvec::ArrayVec;
 use fallible_collections::try_vec;
 use fallible_collections::FallibleVec;
-use kpi::process::{FrameId, ELF_OFFSET, EXECUTOR_OFFSET};
+use kpi::process::{AddressLayout, Credentials, FrameId, ELF_OFFSET, EXECUTOR_OFFSET};
 use lazy_static::lazy_static;
 use log::{debug, info, trace, warn};
 use node_replication::{Dispatch, Log, Replica};
@@ -26,7 +25,7 @@ use crate::kcb::ArchSpecificKcb;
 use crate::kcb::{self, Kcb};
 use crate::memory::detmem::DA;
 use crate::memory::vspace::{AddressSpace, MapAction};
-use crate::memory::{paddr_to_kernel_vaddr, Frame, KernelAllocator, PAddr, VAddr};
+use crate::memory::{paddr_to_kernel_vaddr, Frame, KernelAllocator, PAddr, VAddr, KERNEL_BASE};
 use crate::nrproc::NrProcess;
 use crate::process::{
     Eid, Executor, Pid, Process, ResumeHandle, MAX_FRAMES_PER_PROCESS, MAX_PROCESSES,
@@ -34,12 +33,16 @@ use crate::process::{
 };
 use crate::round_up;
 
+use super::cet;
 use super::kcb::Arch86Kcb;
+use super::perf::Counters;
 use super::vspace::*;
 use super::Module;
 use super::MAX_NUMA_NODES;
 
-const INVALID_EXECUTOR_START: VAddr = VAddr(0xdeadffff);
+pub use super::user_access::UserSlice;
+
+pub const INVALID_EXECUTOR_START: VAddr = VAddr(0xdeadffff);
 
 lazy_static! {
     pub static ref PROCESS_TABLE: ArrayVec<ArrayVec<Arc<Replica<'static, NrProcess<Ring3Process>>>, MAX_PROCESSES>, MAX_NUMA_NODES> = {
@@ -60,141 +63,36 @@ lazy_static! {
 
             let da = DA::new().expect("Can't initialize process deterministic memory allocator");
             for node in 0..numa_nodes {
+                if !crate::numa::has_cores(node) {
+                    continue;
+                }
                 let kcb = kcb::get_kcb();
                 kcb.set_allocation_affinity(node as atopology::NodeId).expect("Can't change affinity");
                 debug_assert!(!numa_cache[node].is_full());
 
                 let p = Box::try_new(Ring3Process::new(pid, da.clone()).expect("Can't create process during init")).expect("Not enough memory to initialize processes");
-                let nrp = NrProcess::new(p, da.clone());
+                let nrp = NrProcess::new(pid, p, da.clone());
 
                 numa_cache[node].push(Replica::<NrProcess<Ring3Process>>::with_data(&log, nrp));
 
                 debug_assert_eq!(kcb.arch.node(), 0, "Expect initialization to happen on node 0.");
                 kcb.set_allocation_affinity(0 as atopology::NodeId).expect("Can't change affinity");
             }
+
+            // Nodes without cores use the replica of the nearest node with cores
+            for node in 0..numa_nodes {
+                let other = crate::numa::replica_node(node);
+                if other != node {
+                    let replica = numa_cache[other][pid].clone();
+                    numa_cache[node].push(replica);
+                }
+            }
         }
 
         numa_cache
     };
 }
 
-pub struct UserPtr<T> {
-    value: *mut T,
-}
-
-impl<T> UserPtr<T> {
-    pub fn new(pointer: *mut T) -> UserPtr<T> {
-        UserPtr { value: pointer }
-    }
-
-    pub fn vaddr(&self) -> VAddr {
-        VAddr::from(self.value as u64)
-    }
-}
-
-impl<T> Deref for UserPtr<T> {
-    type Target = T;
-    fn deref(&self) -> &Self::Target {
-        unsafe {
-            rflags::stac();
-            &*self.value
-        }
-    }
-}
-
-impl<T> DerefMut for UserPtr<T> {
-    fn deref_mut(&mut self) -> &mut T {
-        unsafe {
-            rflags::stac();
-            &mut *self.value
-        }
-    }
-}
-
-impl<T> Drop for UserPtr<T> {
-    fn drop(&mut self) {
-        unsafe { rflags::clac() };
-    }
-}
-
-pub struct UserValue<T> {
-    value: T,
-}
-
-impl<T> UserValue<T> {
-    pub fn new(pointer: T) -> UserValue<T> {
-        UserValue { value: pointer }
-    }
-
-    pub fn as_mut_ptr(&mut self) -> *mut T {
-        unsafe { core::mem::transmute(&self.value) }
-    }
-}
-
-impl<T> Deref for UserValue<T> {
-    type Target = T;
-    fn deref(&self) -> &T {
-        unsafe {
-            rflags::stac();
-            &self.value
-        }
-    }
-}
-
-impl<T> DerefMut for UserValue<T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe {
-            rflags::stac();
-            &mut self.value
-        }
-    }
-}
-
-impl<T> Drop for UserValue<T> {
-    fn drop(&mut self) {
-        unsafe { rflags::clac() };
-    }
-}
-
-pub struct UserSlice<'a> {
-    pub buffer: &'a mut [u8],
-}
-
-impl<'a> UserSlice<'a> {
-    pub fn new(base: u64, len: usize) -> UserSlice<'a> {
-        let mut user_ptr = VAddr::from(base);
-        let slice_ptr = UserPtr::new(&mut user_ptr);
-        let user_slice: &mut [u8] =
-            unsafe { core::slice::from_raw_parts_mut(slice_ptr.as_mut_ptr(), len) };
-        UserSlice { buffer: user_slice }
-    }
-}
-
-impl<'a> Deref for UserSlice<'a> {
-    type Target = [u8];
-    fn deref(&self) -> &Self::Target {
-        unsafe {
-            rflags::stac();
-            &*self.buffer
-        }
-    }
-}
-
-impl<'a> DerefMut for UserSlice<'a> {
-    fn deref_mut(&mut self) -> &mut [u8] {
-        unsafe {
-            rflags::stac();
-            self.buffer
-        }
-    }
-}
-
-impl<'a> Drop for UserSlice<'a> {
-    fn drop(&mut self) {
-        unsafe { rflags::clac() };
-    }
-}
-
 /// A Ring3Resumer that can either be an upcall or a context restore.
 ///
 /// # TODO
@@ -216,6 +114,7 @@ pub struct Ring3Resumer {
 
 impl ResumeHandle for Ring3Resumer {
     unsafe fn resume(self) -> ! {
+        kcb::get_kcb().arch.check_stack_canaries();
         match self.typ {
             ResumeStrategy::Start => self.start(),
             ResumeStrategy::Upcall => self.upcall(),
@@ -292,6 +191,7 @@ impl Ring3Resumer {
         //info!("resuming User-space with ctxt: {:?}", (*(self.save_area)),);
 
         // Resumes a process using iretq
+        cet::leave_kernel_shadow_stack(false);
         llvm_asm!("
                 // Restore fs and gs registers
                 swapgs
@@ -350,6 +250,7 @@ impl Ring3Resumer {
         // This routine assumes the following set-up
         // %rdi points to SaveArea
         // r11 has rflags
+        cet::leave_kernel_shadow_stack(true);
         llvm_asm!("
                 // Restore CPU registers
                 movq  0*8(%rdi), %rax
@@ -410,6 +311,7 @@ impl Ring3Resumer {
         // %rcx Program entry point in Ring 3
         // %r11 RFlags
         trace!("Jumping to {:#x}", self.entry_point);
+        cet::leave_kernel_shadow_stack(true);
         llvm_asm!("
                 // rax: contains stack pointer
                 movq       $$0, %rbx
@@ -465,6 +367,7 @@ impl Ring3Resumer {
         // %rcx Program entry point in Ring 3
         // %r11 RFlags
         trace!("Jumping to {:#x}", self.entry_point);
+        cet::leave_kernel_shadow_stack(true);
         llvm_asm!("
                 // rax: contains stack pointer
                 movq       $$0, %rbx
@@ -539,6 +442,17 @@ pub struct Ring3Executor {
     /// Alias to `vcpu_ctl` but accessible in kernel space.
     pub vcpu_ctl_kernel: VAddr,
 
+    /// Shadow stack (base address), only mapped as such with user CET (see
+    /// `cet`).
+    pub shadow_stack_base: VAddr,
+
+    /// Alias to `shadow_stack_base` but accessible (and writable) in kernel
+    /// space.
+    pub shadow_stack_kernel: VAddr,
+
+    /// The shadow-stack state of the executor while it doesn't run.
+    pub cet: cet::UserState,
+
     /// Entry point where the executor should start executing from
     ///
     /// Usually an ELF start point (for the first dispatcher) then somthing set
@@ -549,6 +463,10 @@ pub struct Ring3Executor {
 
     /// A handle to the vspace PML4 entry point.
     pub pml4: PAddr,
+
+    /// Performance counters of the process that are paused while the
+    /// executor doesn't run.
+    pub perf: Counters,
 }
 
 // CPU context save area (must be first, see exec.S)
@@ -565,10 +483,14 @@ impl Ring3Executor {
     const INIT_STACK_SIZE: usize = 24 * BASE_PAGE_SIZE;
     /// Size of the upcall signal stack for the dispatcher.
     const UPCALL_STACK_SIZE: usize = 24 * BASE_PAGE_SIZE;
+    /// Size of the shadow stack (both stacks use it, see `cet`).
+    const SHADOW_STACK_SIZE: usize = 8 * BASE_PAGE_SIZE;
     /// Total memory consumption (in a process' vspace) that the executor uses.
-    /// (2 stacks plus the VirtualCpu struct.)
-    const EXECUTOR_SPACE_REQUIREMENT: usize =
-        Ring3Executor::INIT_STACK_SIZE + Ring3Executor::UPCALL_STACK_SIZE + BASE_PAGE_SIZE;
+    /// (2 stacks, the VirtualCpu struct and the shadow stack.)
+    const EXECUTOR_SPACE_REQUIREMENT: usize = Ring3Executor::INIT_STACK_SIZE
+        + Ring3Executor::UPCALL_STACK_SIZE
+        + BASE_PAGE_SIZE
+        + Ring3Executor::SHADOW_STACK_SIZE;
 
     fn new(
         process: &Ring3Process,
@@ -580,10 +502,7 @@ impl Ring3Executor {
         let (from, to) = region;
         assert!(to > from, "Malformed region");
         assert!(
-            (to - from).as_usize()
-                >= Ring3Executor::INIT_STACK_SIZE
-                    + Ring3Executor::UPCALL_STACK_SIZE
-                    + core::mem::size_of::<kpi::arch::VirtualCpu>(),
+            (to - from).as_usize() >= Ring3Executor::EXECUTOR_SPACE_REQUIREMENT,
             "Virtual region not big enough"
         );
 
@@ -592,6 +511,10 @@ impl Ring3Executor {
 
         let vcpu_vaddr: VAddr =
             from + Ring3Executor::INIT_STACK_SIZE + Ring3Executor::UPCALL_STACK_SIZE;
+        // The shadow stack comes right after the vcpu page (in the same
+        // memory)
+        let shadow_stack_base = vcpu_vaddr + BASE_PAGE_SIZE;
+        let shadow_stack_kernel = vcpu_ctl_kernel + BASE_PAGE_SIZE;
 
         Ring3Executor {
             stack_base,
@@ -601,6 +524,9 @@ impl Ring3Executor {
             affinity,
             vcpu_ctl_kernel,
             vcpu_ctl: vcpu_vaddr,
+            shadow_stack_base,
+            shadow_stack_kernel,
+            cet: Default::default(),
             save_area: Default::default(),
             entry_point: process.offset + process.entry_point,
             // Note: The PML4 is a bit awkward here, we must ensure to use the
@@ -609,13 +535,10 @@ impl Ring3Executor {
             // executor on a different replica (which means the advance log on
             // pfault would not really advance the right set of page-tables)
             pml4: process.vspace.pml4_address(),
+            perf: Default::default(),
         }
     }
 
-    pub fn vcpu(&self) -> UserPtr<kpi::arch::VirtualCpu> {
-        UserPtr::new(self.vcpu_ctl.as_mut_ptr())
-    }
-
     pub fn vcpu_addr(&self) -> VAddr {
         self.vcpu_ctl
     }
@@ -629,6 +552,24 @@ impl Ring3Executor {
         // -8 due to x86 stack alignemnt requirements
         self.upcall_stack_base + Ring3Executor::UPCALL_STACK_SIZE - 8usize
     }
+
+    fn shadow_stack_top(&self) -> VAddr {
+        self.shadow_stack_base + Ring3Executor::SHADOW_STACK_SIZE
+    }
+
+    /// The upcall entry point the process set in its vcpu area.
+    ///
+    /// `sysretq` to an address that isn't canonical faults in the kernel
+    /// (on the user-space stack, see CVE-2012-0217), we go to 0 instead of
+    /// anything outside of user-space so the process faults there.
+    fn upcall_entry_point(&self) -> VAddr {
+        let entry_point = unsafe { (*self.vcpu_kernel()).resume_with_upcall };
+        if entry_point.as_u64() < KERNEL_BASE {
+            entry_point
+        } else {
+            VAddr::zero()
+        }
+    }
 }
 
 impl fmt::Display for Ring3Executor {
@@ -658,7 +599,18 @@ impl Executor for Ring3Executor {
         assert_eq!(kcb.arch.node(), self.affinity, "Run on remote replica?");
 
         self.maybe_switch_vspace();
+        // We may still have translations the process unmapped while the core
+        // didn't run it
+        super::tlb::sync_translations();
+        // Don't start while the rest of the gang is stopped
+        super::gang::join(self.pid);
         let entry_point = unsafe { (*self.vcpu_kernel()).resume_with_upcall };
+        cet::start_user(self.shadow_stack_top());
+
+        // A restored process continues where its checkpoint was taken
+        if let Some(state) = super::checkpoint::take_resume(self.pid) {
+            return Ring3Resumer::new_restore(super::checkpoint::load_resume(&state));
+        }
 
         if entry_point == INVALID_EXECUTOR_START {
             Ring3Resumer::new_start(self.entry_point, self.stack_top())
@@ -667,9 +619,11 @@ impl Executor for Ring3Executor {
             // handler, but on the regular stack (for that dispatcher) and not
             // the upcall stack. It's used to add a new core to a process.
 
-            let entry_point = unsafe { (*self.vcpu_kernel()).resume_with_upcall };
+            let entry_point = self.upcall_entry_point();
             trace!("Added core entry point is at {:#x}", entry_point);
-            let cpu_ctl = self.vcpu().vaddr().as_u64();
+            let cpu_ctl = self.vcpu_addr().as_u64();
+            // Nothing to go back to
+            unsafe { (*self.vcpu_kernel()).upcall_ssp = 0 };
 
             Ring3Resumer::new_upcall(
                 entry_point,
@@ -692,8 +646,18 @@ impl Executor for Ring3Executor {
         assert_eq!(kcb::get_kcb().node, self.affinity, "Run on remote replica?");
 
         self.maybe_switch_vspace();
-        let entry_point = self.vcpu().resume_with_upcall;
-        let cpu_ctl = self.vcpu().vaddr().as_u64();
+        let entry_point = self.upcall_entry_point();
+        let cpu_ctl = self.vcpu_addr().as_u64();
+
+        // The upcall can go back to `enabled_state` (see `cet`)
+        unsafe {
+            let vcpu = &mut *self.vcpu_kernel();
+            vcpu.upcall_ssp = cet::push_user_frame(
+                vcpu.enabled_state.rip,
+                (self.shadow_stack_base, self.shadow_stack_kernel),
+                Ring3Executor::SHADOW_STACK_SIZE,
+            );
+        }
 
         Ring3Resumer::new_upcall(
             entry_point,
@@ -733,6 +697,8 @@ pub struct Ring3Process {
     pub executor_cache: ArrayVec<Option<Vec<Box<Ring3Executor>>>, MAX_NUMA_NODES>,
     /// Offset where executor memory is located in user-space.
     pub executor_offset: VAddr,
+    /// The frames `allocate_executors` got (the executors live in them).
+    pub executor_memory: Vec<Frame>,
     /// File descriptors for the opened file.
     pub fds: ArrayVec<Option<Fd>, MAX_FILES_PER_PROCESS>,
     /// Physical frame objects registered to the process.
@@ -767,6 +733,7 @@ impl Ring3Process {
             entry_point: VAddr::from(0usize),
             executor_cache,
             executor_offset: VAddr::from(EXECUTOR_OFFSET),
+            executor_memory: Vec::new(),
             fds,
             pinfo: Default::default(),
             frames,
@@ -1036,10 +1003,15 @@ impl Process for Ring3Process {
     fn load(
         &mut self,
         pid: Pid,
+        creds: Credentials,
+        layout: AddressLayout,
         module: &Module,
         writeable_sections: Vec<Frame>,
     ) -> Result<(), KError> {
         self.pid = pid;
+        self.pinfo.creds = creds;
+        self.pinfo.layout = layout;
+        self.executor_offset = VAddr::from(layout.stack_start);
         // TODO(error-handling): properly unwind on error
         self.writeable_sections.clear();
         for sec in writeable_sections {
@@ -1059,6 +1031,9 @@ impl Process for Ring3Process {
             e.load(self)?;
         }
 
+        // The clock (see `kpi::time`)
+        super::vdso::map(&mut self.vspace)?;
+
         // Install the kernel mappings
         // TODO(efficiency): These should probably be global mappings
         // TODO(broken): Big (>= 2 MiB) allocations should be inserted here too
@@ -1105,24 +1080,67 @@ impl Process for Ring3Process {
         }
     }
 
+    fn prefer_executor(&mut self, eid: Eid) -> Result<atopology::NodeId, KError> {
+        for (node, executors) in self.executor_cache.iter_mut().enumerate() {
+            if let Some(executors) = executors {
+                if let Some(idx) = executors.iter().position(|e| e.eid == eid) {
+                    // We pop from the end
+                    let executor = executors.remove(idx);
+                    executors.push(executor);
+                    return Ok(node as atopology::NodeId);
+                }
+            }
+        }
+        Err(KError::NoExecutorAllocated)
+    }
+
     /// Create a series of dispatcher objects for the process
     fn allocate_executors(&mut self, memory: Frame) -> Result<usize, KError> {
         let executor_space_requirement = Ring3Executor::EXECUTOR_SPACE_REQUIREMENT;
         let executors_to_create = memory.size() / executor_space_requirement;
+        self.executor_memory.try_reserve(1)?;
 
         KernelAllocator::try_refill_tcache(20, 0).expect("Refill didn't work");
-        {
+        if cet::user_shadow_stacks() {
+            // The shadow stacks (at the end of every executor) can't be
+            // writable
+            let mut vaddr = self.executor_offset;
+            let mut rest = memory;
+            for _cnt in 0..executors_to_create {
+                let (writable, tail) =
+                    rest.split_at(executor_space_requirement - Ring3Executor::SHADOW_STACK_SIZE);
+                let (shadow_stack, tail) = tail.split_at(Ring3Executor::SHADOW_STACK_SIZE);
+                self.vspace
+                    .map_frame(vaddr, writable, MapAction::ReadWriteUser)
+                    .expect("Can't map user-space executor memory.");
+                self.vspace
+                    .map_frame(
+                        vaddr + writable.size(),
+                        shadow_stack,
+                        MapAction::ShadowStackUser,
+                    )
+                    .expect("Can't map user-space executor memory.");
+                vaddr = vaddr + executor_space_requirement;
+                rest = tail;
+            }
+            if rest.size() > 0 {
+                self.vspace
+                    .map_frame(vaddr, rest, MapAction::ReadWriteUser)
+                    .expect("Can't map user-space executor memory.");
+            }
+        } else {
             self.vspace
                 .map_frame(self.executor_offset, memory, MapAction::ReadWriteUser)
                 .expect("Can't map user-space executor memory.");
-
-            info!(
-                "executor space base expanded {:#x} size: {} end {:#x}",
-                self.executor_offset,
-                memory.size(),
-                self.executor_offset + memory.size()
-            );
         }
+        self.executor_memory.push(memory);
+
+        info!(
+            "executor space base expanded {:#x} size: {} end {:#x}",
+            self.executor_offset,
+            memory.size(),
+            self.executor_offset + memory.size()
+        );
 
         let cur_paddr_offset = memory.base;
         let mut cur_offset = self.executor_offset;
@@ -1171,6 +1189,20 @@ impl Process for Ring3Process {
         Ok(executors_to_create)
     }
 
+    fn destroy(&mut self) -> Result<Vec<Frame>, KError> {
+        let da = self.vspace.page_table.da.clone().map_or_else(DA::new, Ok)?;
+        let mut fresh = Ring3Process::new(self.pid, da)?;
+        let mut owned =
+            Vec::try_with_capacity(self.writeable_sections.len() + self.executor_memory.len())?;
+        owned.extend(self.writeable_sections.drain(..));
+        owned.append(&mut self.executor_memory);
+
+        // Dropping the old address space frees its page-tables (not the
+        // frames it maps)
+        core::mem::swap(self, &mut fresh);
+        Ok(owned)
+    }
+
     fn allocate_fd(&mut self) -> Option<(u64, &mut Fd)> {
         if let Some(fid) = self.fds.iter().position(|fd| fd.is_none()) {
             self.fds[fid] = Some(Default::default());
@@ -1207,7 +1239,7 @@ impl Process for Ring3Process {
         }
     }
 
-    fn get_frame(&mut self, frame_id: FrameId) -> Result<Frame, KError> {
+    fn get_frame(&self, frame_id: FrameId) -> Result<Frame, KError> {
         self.frames
             .get(frame_id)
             .cloned()
@@ -1236,12 +1268,14 @@ impl Process for Ring3Process {
 /// - Then we allocate a bunch of memory on all NUMA nodes to create enough dispatchers
 ///   so we can run on all cores
 /// - Finally we allocate a dispatcher to the current core (0) and start running the process
+///
+/// The process runs as `creds` for its whole life.
 #[cfg(target_os = "none")]
-pub fn spawn(binary: &'static str) -> Result<Pid, KError> {
+pub fn spawn(binary: &'static str, creds: Credentials) -> Result<Pid, KError> {
     use crate::nr;
     use crate::process::{allocate_dispatchers, make_process};
 
-    let pid = make_process::<Ring3Process>(binary)?;
+    let pid = make_process::<Ring3Process>(binary, creds, None)?;
     allocate_dispatchers::<Ring3Process>(pid)?;
 
     // Set current thread to run executor from our process (on the current core)
@@ -0,0 +1,186 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Registers cores with the replicas of their NUMA node and keeps the
+//! replicas from falling behind their logs.
+//!
+//! Every core registers with the replicas of its node once it boots: the
+//! kernel state (`nr::KernelNode`), the file-system (`cnrfs`) and all
+//! processes (`process::PROCESS_TABLE`). A core that `hotplug` parks keeps
+//! its registrations (they live in its KCB) and uses them again once it's
+//! back, so the number of registrations never exceeds the number of cores.
+//! We only count the cores that are online though ([`core_offline`],
+//! [`core_online`]).
+//!
+//! A log can only reuse entries all replicas have applied. If a replica
+//! falls behind (e.g., because nothing runs on its node) and a CNR log fills
+//! up, the log calls [`log_full`] and we ask an online core of that node to
+//! advance the replica (`tlb::advance_replica`). The NR logs don't have this
+//! callback, the first core of every node (which never goes offline)
+//! advances them from the timer interrupt and whenever it's idle.
+//!
+//! For every replica we count how often it synchronized, when it last did
+//! and how often a log had to ask for it, and how many entries of the NR
+//! logs it has yet to apply (`nr::LogProgress`, `/proc/replicas`).
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use cnr::{Replica as MlnrReplica, MAX_REPLICAS_PER_LOG};
+use crossbeam_utils::CachePadded;
+use log::trace;
+use node_replication::{Replica, ReplicaToken};
+
+use crate::cnrfs::MlnrKernelNode;
+use crate::error::KError;
+use crate::kcb::Kcb;
+use crate::nr::{KernelNode, KERNEL_LOG};
+use crate::nrproc::PROCESS_LOGS;
+
+use super::kcb::{get_kcb, Arch86Kcb};
+use super::{coreboot, tlb, MAX_NUMA_NODES};
+
+/// What we know about the replicas of a NUMA node.
+struct ReplicaStats {
+    /// Online cores registered with the replicas.
+    cores: AtomicUsize,
+    /// How often the replicas synchronized with their logs.
+    syncs: AtomicU64,
+    /// When they last did (ns since boot).
+    last_sync: AtomicU64,
+    /// How often a full log asked the node to advance its replica.
+    gc_requests: AtomicU64,
+}
+
+impl ReplicaStats {
+    const fn new() -> ReplicaStats {
+        ReplicaStats {
+            cores: AtomicUsize::new(0),
+            syncs: AtomicU64::new(0),
+            last_sync: AtomicU64::new(0),
+            gc_requests: AtomicU64::new(0),
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_STATS: CachePadded<ReplicaStats> = CachePadded::new(ReplicaStats::new());
+static STATS: [CachePadded<ReplicaStats>; MAX_NUMA_NODES] = [NO_STATS; MAX_NUMA_NODES];
+
+fn since_boot() -> u64 {
+    rawtime::BOOT_TIME_ANCHOR.elapsed().as_nanos() as u64
+}
+
+/// Registers the current core with the replicas of its node.
+///
+/// Returns the token for the kernel state replica.
+pub fn register_core(
+    kcb: &mut Kcb<Arch86Kcb>,
+    replica: &Arc<Replica<'static, KernelNode>>,
+    fs_replica: &Arc<MlnrReplica<'static, MlnrKernelNode>>,
+) -> Result<ReplicaToken, KError> {
+    let token = replica.register().ok_or(KError::ReplicaFull)?;
+    kcb.setup_node_replication(replica.clone(), token);
+
+    let fs_token = fs_replica.register().ok_or(KError::ReplicaFull)?;
+    kcb.arch.setup_cnr(fs_replica.clone(), fs_token);
+    // Needs the node (from `setup_cnr`)
+    kcb.register_with_process_replicas();
+
+    STATS[kcb.arch.node()].cores.fetch_add(1, Ordering::Relaxed);
+    Ok(token)
+}
+
+/// Records that the current core goes offline (its registrations stay).
+pub fn core_offline() {
+    STATS[get_kcb().arch.node()]
+        .cores
+        .fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Records that the current core is back online.
+pub fn core_online() {
+    STATS[get_kcb().arch.node()]
+        .cores
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// How many entries of the NR logs (kernel state and processes) the
+/// replicas of `node` have yet to apply.
+fn lag(node: atopology::NodeId) -> u64 {
+    if !crate::numa::has_cores(node) {
+        // No replicas
+        return 0;
+    }
+    KERNEL_LOG.lag(node) + PROCESS_LOGS.iter().map(|log| log.lag(node)).sum::<u64>()
+}
+
+/// Records that the current core synchronized the replicas of its node.
+pub fn synchronized() {
+    let stats = &STATS[get_kcb().arch.node()];
+    stats.syncs.fetch_add(1, Ordering::Relaxed);
+    stats.last_sync.store(since_boot(), Ordering::Relaxed);
+}
+
+/// Called by a CNR log (number `idx`) that is full because the replicas
+/// marked in `rid` didn't apply its entries yet.
+pub fn log_full(rid: &[AtomicBool; MAX_REPLICAS_PER_LOG], idx: usize) {
+    let num_nodes = atopology::MACHINE_TOPOLOGY.num_nodes();
+    for replica in 0..num_nodes {
+        if rid[replica].load(Ordering::Relaxed) {
+            // Only nodes with cores have replicas (see `crate::numa`)
+            let node = atopology::MACHINE_TOPOLOGY
+                .nodes()
+                .filter(|node| node.threads().next().is_some())
+                .nth(replica)
+                .unwrap();
+            let core_id = node
+                .threads()
+                .nth(idx - 1)
+                .filter(|core| coreboot::is_online(core.id))
+                // The first core of a node never goes offline
+                .or_else(|| node.threads().next())
+                .unwrap()
+                .id;
+            trace!(
+                "Replica {} needs to make progress on Log {}; use core_id {:?}",
+                replica + 1,
+                idx,
+                core_id
+            );
+            STATS[node.id].gc_requests.fetch_add(1, Ordering::Relaxed);
+            tlb::advance_replica(core_id, idx);
+            rid[replica].store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Generates `/proc/replicas`.
+pub fn proc_replicas(out: &mut String) -> fmt::Result {
+    writeln!(
+        out,
+        "{:>4} {:>5} {:>12} {:>14} {:>11} {:>8}",
+        "node", "cores", "syncs", "us_since_sync", "gc_requests", "lag"
+    )?;
+
+    let now = since_boot();
+    let nodes = core::cmp::max(1, atopology::MACHINE_TOPOLOGY.num_nodes());
+    for (node, stats) in STATS.iter().enumerate().take(nodes) {
+        let last_sync = stats.last_sync.load(Ordering::Relaxed);
+        let ago = now.saturating_sub(last_sync) / 1000;
+        writeln!(
+            out,
+            "{:>4} {:>5} {:>12} {:>14} {:>11} {:>8}",
+            node,
+            stats.cores.load(Ordering::Relaxed),
+            stats.syncs.load(Ordering::Relaxed),
+            ago,
+            stats.gc_requests.load(Ordering::Relaxed),
+            lag(node)
+        )?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,76 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Random numbers from the processor (RDSEED and RDRAND).
+
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdrand64_step, _rdseed64_step};
+
+use crate::entropy;
+use crate::error::KError;
+
+/// The instructions can fail if the hardware generator is exhausted, the
+/// SDM recommends retrying a few times.
+const RETRIES: usize = 10;
+
+fn has_rdrand() -> bool {
+    unsafe { __cpuid(1) }.ecx & (1 << 30) != 0
+}
+
+fn has_rdseed() -> bool {
+    unsafe { __cpuid(0) }.eax >= 7 && unsafe { __cpuid_count(7, 0) }.ebx & (1 << 18) != 0
+}
+
+#[target_feature(enable = "rdseed")]
+unsafe fn rdseed() -> Option<u64> {
+    let mut value = 0;
+    for _ in 0..RETRIES {
+        if _rdseed64_step(&mut value) == 1 {
+            return Some(value);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand() -> Option<u64> {
+    let mut value = 0;
+    for _ in 0..RETRIES {
+        if _rdrand64_step(&mut value) == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Fills `buf` with RDSEED output (RDRAND if RDSEED isn't available or
+/// runs dry).
+fn fill(buf: &mut [u8]) -> usize {
+    let (seed, rand) = (has_rdseed(), has_rdrand());
+    let mut filled = 0;
+    for chunk in buf.chunks_mut(8) {
+        let value = if seed { unsafe { rdseed() } } else { None }.or_else(|| {
+            if rand {
+                unsafe { rdrand() }
+            } else {
+                None
+            }
+        });
+        match value {
+            Some(value) => {
+                chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+                filled += chunk.len();
+            }
+            None => break,
+        }
+    }
+    filled
+}
+
+/// Registers the processor as an entropy source (if it has RDRAND).
+pub fn init() -> Result<(), KError> {
+    if !has_rdrand() {
+        return Err(KError::NotSupported);
+    }
+    entropy::register_source("rdrand", fill)
+}
@@ -0,0 +1,199 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Reads the date and time from the CMOS real-time clock and sets its
+//! alarm (which wakes us up from suspend).
+
+use x86::io;
+
+use crate::time::DateTime;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// Keeps NMIs disabled while we access the CMOS.
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_SECONDS_ALARM: u8 = 0x01;
+const REG_MINUTES: u8 = 0x02;
+const REG_MINUTES_ALARM: u8 = 0x03;
+const REG_HOURS: u8 = 0x04;
+const REG_HOURS_ALARM: u8 = 0x05;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+/// Interrupt flags, reading acknowledges them.
+const REG_STATUS_C: u8 = 0x0c;
+
+/// An update is in progress (the registers are about to change).
+const STATUS_A_UPDATE: u8 = 1 << 7;
+const STATUS_B_24H: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_ALARM_INTERRUPT: u8 = 1 << 5;
+/// Set in the hours register for PM in 12 hour mode.
+const HOURS_PM: u8 = 1 << 7;
+
+fn read(reg: u8) -> u8 {
+    unsafe {
+        io::outb(CMOS_ADDRESS, NMI_DISABLE | reg);
+        io::inb(CMOS_DATA)
+    }
+}
+
+fn write(reg: u8, value: u8) {
+    unsafe {
+        io::outb(CMOS_ADDRESS, NMI_DISABLE | reg);
+        io::outb(CMOS_DATA, value);
+    }
+}
+
+/// The raw date and time registers.
+fn registers() -> [u8; 6] {
+    while read(REG_STATUS_A) & STATUS_A_UPDATE != 0 {
+        core::hint::spin_loop();
+    }
+    [
+        read(REG_SECONDS),
+        read(REG_MINUTES),
+        read(REG_HOURS),
+        read(REG_DAY),
+        read(REG_MONTH),
+        read(REG_YEAR),
+    ]
+}
+
+/// Converts the RTC registers to a date, `status_b` tells us how they are
+/// encoded.
+fn decode(regs: [u8; 6], status_b: u8) -> DateTime {
+    let value = |v: u8| {
+        if status_b & STATUS_B_BINARY != 0 {
+            v as u32
+        } else {
+            (v >> 4) as u32 * 10 + (v & 0xf) as u32
+        }
+    };
+
+    let [seconds, minutes, hours, day, month, year] = regs;
+    let mut hour = value(hours & !HOURS_PM);
+    if status_b & STATUS_B_24H == 0 {
+        // 12 AM is 0 and 12 PM is 12
+        hour %= 12;
+        if hours & HOURS_PM != 0 {
+            hour += 12;
+        }
+    }
+
+    DateTime {
+        // TODO(correctness): Use the century register from the FADT.
+        year: 2000 + value(year),
+        month: value(month),
+        day: value(day),
+        hour,
+        minute: value(minutes),
+        second: value(seconds),
+    }
+}
+
+/// The alarm registers (seconds, minutes, hours) for the time of day of
+/// `at`, encoded the way `status_b` says.
+fn encode_alarm(at: &DateTime, status_b: u8) -> [u8; 3] {
+    let value = |v: u32| {
+        if status_b & STATUS_B_BINARY != 0 {
+            v as u8
+        } else {
+            ((v / 10) << 4 | v % 10) as u8
+        }
+    };
+
+    let hours = if status_b & STATUS_B_24H != 0 {
+        value(at.hour)
+    } else {
+        let pm = if at.hour >= 12 { HOURS_PM } else { 0 };
+        // 0 is 12 AM and 12 is 12 PM
+        let hour = if at.hour % 12 == 0 { 12 } else { at.hour % 12 };
+        value(hour) | pm
+    };
+
+    [value(at.second), value(at.minute), hours]
+}
+
+/// Raises the alarm interrupt (and wakes us up if we're suspended) at
+/// `at`, which has to be less than a day from now (the alarm only
+/// compares the time of day).
+pub fn set_alarm(at: &DateTime) {
+    let status_b = read(REG_STATUS_B);
+    let [seconds, minutes, hours] = encode_alarm(at, status_b);
+    write(REG_STATUS_B, status_b & !STATUS_B_ALARM_INTERRUPT);
+    write(REG_SECONDS_ALARM, seconds);
+    write(REG_MINUTES_ALARM, minutes);
+    write(REG_HOURS_ALARM, hours);
+    // Drop an alarm that's still pending
+    read(REG_STATUS_C);
+    write(REG_STATUS_B, status_b | STATUS_B_ALARM_INTERRUPT);
+}
+
+/// Turns the alarm off again.
+pub fn clear_alarm() {
+    let status_b = read(REG_STATUS_B);
+    write(REG_STATUS_B, status_b & !STATUS_B_ALARM_INTERRUPT);
+    read(REG_STATUS_C);
+}
+
+/// Reads the current time (the RTC keeps UTC).
+pub fn now() -> DateTime {
+    // Read until we get the same value twice (in case an update happened
+    // right after we checked)
+    let mut regs = registers();
+    loop {
+        let again = registers();
+        if again == regs {
+            break;
+        }
+        regs = again;
+    }
+    decode(regs, read(REG_STATUS_B))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bcd_12h() {
+        // 2021-07-04 11:05:09 PM
+        let regs = [0x09, 0x05, HOURS_PM | 0x11, 0x04, 0x07, 0x21];
+        let date = decode(regs, 0);
+        assert_eq!(date.to_string(), "2021-07-04 23:05:09 UTC");
+
+        // 12 AM
+        let date = decode([0, 0, 0x12, 1, 1, 0x22], 0);
+        assert_eq!(date.hour, 0);
+    }
+
+    #[test]
+    fn binary_24h() {
+        let regs = [59, 30, 12, 31, 12, 99];
+        let date = decode(regs, STATUS_B_BINARY | STATUS_B_24H);
+        assert_eq!(date.to_string(), "2099-12-31 12:30:59 UTC");
+    }
+
+    #[test]
+    fn alarm() {
+        let at = DateTime::from_unix_time(1625439909);
+        assert_eq!(at.to_string(), "2021-07-04 23:05:09 UTC");
+        assert_eq!(encode_alarm(&at, 0), [0x09, 0x05, HOURS_PM | 0x11]);
+        assert_eq!(
+            encode_alarm(&at, STATUS_B_BINARY | STATUS_B_24H),
+            [9, 5, 23]
+        );
+
+        // Round trips through `decode`
+        let midnight = DateTime::from_unix_time(0);
+        let [second, minute, hour] = encode_alarm(&midnight, 0);
+        assert_eq!(encode_alarm(&midnight, STATUS_B_24H)[2], 0);
+        let date = decode([second, minute, hour, 1, 1, 0x70], 0);
+        assert_eq!(date.hour, 0);
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Suspend-to-RAM (ACPI S3).
+//!
+//! Only the BSP goes to sleep: the other cores park first (like for
+//! hotplug) and come back once we resumed. The firmware wakes the BSP up
+//! in real mode at the waking vector, which is the bootstrap code the other
+//! cores start in (`start_ap.S`), so it ends up in `resume` on a stack of
+//! its own.
+//!
+//! Memory keeps its content, the cores and devices don't. We save and
+//! restore the I/O APIC routes and the PCI configuration space and set the
+//! BSP up like a core that comes back from hotplug. Devices that keep more
+//! state than that lose it, their drivers don't know about suspend (yet).
+//!
+//! The RTC alarm wakes us up again.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use log::{error, info, warn};
+use x86::controlregs;
+
+use crate::drivers::pci::{self, SavedConfig};
+use crate::error::KError;
+use crate::kcb::Kcb;
+use crate::mutex::Mutex;
+use crate::stack::{OwnedStack, Stack};
+use crate::time::{self, DateTime};
+
+use super::kcb::{get_kcb, Arch86Kcb};
+use super::memory::BASE_PAGE_SIZE;
+use super::{acpi, cet, coreboot, hotplug, ioapic, irq, kvmclock, rtc, tsc, vdso};
+
+/// We sleep at least this long, the RTC alarm has a resolution of a second.
+const MIN_SLEEP: Duration = Duration::from_secs(1);
+/// The alarm only compares the time of day.
+const MAX_SLEEP: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What `resume` needs to get going again.
+struct Saved {
+    /// Cores we parked.
+    cores: Vec<atopology::GlobalThreadId>,
+    ioapic: Vec<u64>,
+    pci: Vec<SavedConfig>,
+    /// Address space of the process that suspended us.
+    cr3: u64,
+    tsc: u64,
+    /// RTC time (in seconds since the epoch) when we went to sleep.
+    rtc: u64,
+}
+
+static SAVED: Mutex<Option<Saved>> = Mutex::new("suspend_saved", None);
+
+/// The stack `resume` runs on.
+static STACK: Mutex<Option<OwnedStack>> = Mutex::new("suspend_stack", None);
+
+/// Set once we resumed (the bootstrap code wants a flag).
+static RESUMED: AtomicBool = AtomicBool::new(false);
+
+/// Suspends the machine to RAM and wakes it up after `sleep`.
+///
+/// Has to run on the BSP from a system call. We return to the process
+/// from `resume`, so this only returns if we couldn't suspend.
+pub fn suspend(sleep: Duration) -> KError {
+    let kcb = get_kcb();
+    if kcb.arch.id() != 0 {
+        return KError::NotOnBootCore;
+    }
+    if sleep < MIN_SLEEP || sleep >= MAX_SLEEP {
+        return KError::InvalidWakeupTime;
+    }
+    if !acpi::can_suspend() || kcb.arch.shadow_stack_token != 0 || cet::user_shadow_stacks() {
+        // TODO(correctness): The shadow stacks would have to be set up again
+        return KError::SuspendUnsupported;
+    }
+
+    let cores = match hotplug::offline_others() {
+        Ok(cores) => cores,
+        Err(e) => return e,
+    };
+    *SAVED.lock() = Some(Saved {
+        cores,
+        ioapic: Vec::new(),
+        pci: Vec::new(),
+        cr3: unsafe { controlregs::cr3() },
+        tsc: 0,
+        rtc: 0,
+    });
+
+    if let Err(e) = prepare(sleep) {
+        return abort(e);
+    }
+
+    info!("Suspending for {:?}", sleep);
+    irq::disable();
+    let tsc = unsafe { x86::time::rdtsc() };
+    if let Some(saved) = SAVED.lock().as_mut() {
+        saved.tsc = tsc;
+    }
+    // Dirty cache lines don't survive S3
+    unsafe { llvm_asm!("wbinvd" :::: "volatile") };
+    super::hypervisor::vmx::off();
+
+    let status = acpi::enter_suspend();
+    error!("Couldn't suspend: {:?}", status);
+    acpi::leave_suspend();
+    abort(KError::SuspendFailed)
+}
+
+/// Saves the machine state, sets the waking vector and arms the alarm.
+fn prepare(sleep: Duration) -> Result<(), KError> {
+    let ioapic = ioapic::save()?;
+    let pci = match pci::save_config() {
+        Err(KError::PciUnavailable) => Vec::new(),
+        config => config?,
+    };
+
+    let vector = {
+        let mut stack = STACK.lock();
+        let stack: &dyn Stack = stack.get_or_insert_with(|| OwnedStack::new(BASE_PAGE_SIZE * 512));
+        let kcb = get_kcb() as *mut Kcb<Arch86Kcb> as usize;
+        RESUMED.store(false, Ordering::SeqCst);
+        unsafe { coreboot::prepare(resume, Arc::try_new(kcb)?, &RESUMED, stack) }
+    };
+
+    let now = rtc::now().unix_time();
+    if let Some(saved) = SAVED.lock().as_mut() {
+        saved.ioapic = ioapic;
+        saved.pci = pci;
+        saved.rtc = now;
+    }
+    rtc::set_alarm(&DateTime::from_unix_time(now + sleep.as_secs()));
+    acpi::prepare_suspend(vector).map_err(|status| {
+        error!("Can't prepare the suspend: {:?}", status);
+        KError::SuspendFailed
+    })
+}
+
+/// We didn't suspend: brings the other cores back and returns `error`.
+fn abort(error: KError) -> KError {
+    rtc::clear_alarm();
+    let saved = SAVED.lock().take();
+    if let Some(saved) = saved {
+        hotplug::online_all(&saved.cores);
+    }
+    error
+}
+
+/// Where the BSP continues (from `start_ap.S`) after the firmware woke it
+/// up.
+fn resume(kcb: Arc<usize>, initialized: &AtomicBool) {
+    super::debug::init();
+    hotplug::reset_core(*kcb);
+
+    let saved = SAVED.lock().take();
+    let saved = saved.expect("Resumed without a suspend?");
+    tsc::resume(saved.tsc);
+    // The kvmclock page isn't registered anymore
+    let _ = kvmclock::init_core();
+
+    acpi::leave_suspend();
+    rtc::clear_alarm();
+    ioapic::restore(&saved.ioapic);
+    if let Err(e) = pci::restore_config(&saved.pci) {
+        warn!("Can't restore the PCI configuration: {:?}", e);
+    }
+
+    let slept = rtc::now().unix_time().saturating_sub(saved.rtc);
+    time::add_suspended(Duration::from_secs(slept));
+    vdso::update();
+    initialized.store(true, Ordering::SeqCst);
+    info!("Resumed after {} s", slept);
+
+    hotplug::online_all(&saved.cores);
+
+    let kcb = get_kcb();
+    unsafe { controlregs::cr3_write(saved.cr3) };
+    kcb.arch.save_area.as_mut().map(|sa| {
+        sa.set_syscall_ret1(0);
+        sa.set_syscall_ret2(0);
+        sa.set_syscall_error_code(kpi::SystemCallError::Ok);
+    });
+    let r = super::process::Ring3Resumer::new_restore(kcb.arch.get_save_area_ptr());
+    unsafe { r.resume() }
+}
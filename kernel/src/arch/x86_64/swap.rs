@@ -0,0 +1,471 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Evicting anonymous pages of processes and bringing them back.
+//!
+//! Only memory from `VSpace::map` in base pages is evicted, large pages and
+//! frame or device mappings stay where they are. Evicting a page is an unmap
+//! (with the pins of the process excluded, so the kernel doesn't work on
+//! it) followed by a synchronous TLB shootdown, after that nobody writes to
+//! the frame and we copy it: compressed into the pool if it gets small
+//! enough, otherwise to the swap device (see `memory::swap`).
+//!
+//! A fault on an evicted page in user-space (`irq::pf_handler`) or in the
+//! kernel (`user_access::validate`) maps it again in a new frame. Pages are
+//! evicted when a process asks for it (`VSpace::page_out`) or when we run
+//! out of frames, then a clock passes over the anonymous pages and evicts
+//! the ones that weren't accessed since it passed last. It looks at the
+//! accessed bits of the page-table of the local replica only.
+//!
+//! A process that learned the physical address of a page (with
+//! `VSpace::identify`) may hand it to a device, we don't evict those.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::hint::spin_loop;
+use core::ops::Range;
+
+use fallible_collections::vec::FallibleVec;
+use log::{error, info, warn};
+use spin::{Mutex, MutexGuard, Once};
+
+use crate::drivers::block;
+use crate::error::KError;
+use crate::memory::swap::{self, Location, Lookup, SwapDevice, Swapped};
+use crate::memory::vspace::MapAction;
+use crate::memory::{Frame, KernelAllocator, VAddr, BASE_PAGE_SIZE};
+use crate::nrproc::NrProcess;
+use crate::process::Pid;
+
+use super::kcb::get_kcb;
+use super::process::Ring3Process;
+use super::{tlb, user_access};
+
+/// How many pages we evict when we're out of frames.
+pub const RECLAIM_BATCH: usize = 32;
+
+/// Most pages the clock passes in one `reclaim`.
+const MAX_SCAN: usize = 4096;
+
+/// The evicted and anonymous pages (`None` if we have nowhere to put them).
+static SWAPPED: Mutex<Option<Swapped>> = Mutex::new(None);
+
+/// Where pages go that don't fit in the pool.
+static DEVICE: Once<SwapDevice> = Once::new();
+
+/// Held during an eviction, two of them would wait for each other's TLB
+/// shootdown.
+static EVICTING: Mutex<()> = Mutex::new(());
+
+/// Parses `<dev>[:<lba>]`.
+fn parse_target(target: &str) -> Result<(&str, u64), KError> {
+    let (name, lba) = match target.split_once(':') {
+        Some((name, lba)) => (
+            name,
+            lba.parse::<u64>().map_err(|_| KError::InvalidSwapTarget)?,
+        ),
+        None => (target, 0),
+    };
+    if name.is_empty() {
+        return Err(KError::InvalidSwapTarget);
+    }
+    Ok((name, lba))
+}
+
+/// Starts evicting pages to a pool of `pool` bytes and the swap device
+/// `target` (either can be missing).
+pub fn init(pool: Option<usize>, target: Option<&str>) -> Result<(), KError> {
+    if let Some(target) = target {
+        let (name, lba) = parse_target(target)?;
+        let dev = block::get(name).ok_or(KError::SwapTargetNotFound)?;
+        let device = SwapDevice::new(dev, lba)?;
+        DEVICE.call_once(|| device);
+    }
+
+    let pool = pool.unwrap_or(0);
+    *SWAPPED.lock() = Some(Swapped::new(pool));
+    crate::procfs::register("/proc/swap", proc_swap)?;
+
+    info!(
+        "Evicting pages to a {} KiB pool and {} device slots",
+        pool / 1024,
+        DEVICE.get().map_or(0, |d| d.usage().1)
+    );
+    Ok(())
+}
+
+/// Whether we have somewhere to put evicted pages.
+pub fn available() -> bool {
+    SWAPPED.lock().is_some()
+}
+
+fn with_swapped<R>(f: impl FnOnce(&mut Swapped) -> R) -> Result<R, KError> {
+    SWAPPED
+        .lock()
+        .as_mut()
+        .map(f)
+        .ok_or(KError::SwapUnavailable)
+}
+
+/// Pages in `range` of `pid` came from `VSpace::map`.
+pub fn anonymous(pid: Pid, range: Range<u64>) -> Result<(), KError> {
+    match with_swapped(|s| s.add_anonymous(pid, range)) {
+        Err(KError::SwapUnavailable) => Ok(()),
+        r => r?,
+    }
+}
+
+/// Pages in `range` of `pid` were unmapped.
+///
+/// Has to be called before the pins are no longer excluded, so an eviction
+/// doesn't find a new mapping there.
+pub fn unmapped(pid: Pid, range: Range<u64>) -> Result<(), KError> {
+    match with_swapped(|s| s.remove_anonymous(pid, range)) {
+        Err(KError::SwapUnavailable) => Ok(()),
+        r => r?,
+    }
+}
+
+/// Fails if an evicted page of `pid` is in `range` (it's still mapped as far
+/// as the process is concerned).
+pub fn check_evicted(pid: Pid, range: Range<u64>) -> Result<(), KError> {
+    match SWAPPED
+        .lock()
+        .as_ref()
+        .and_then(|s| s.evicted_in(pid, range))
+    {
+        Some(addr) => Err(KError::AlreadyMapped {
+            base: VAddr::from(addr),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Brings the pages in `[base, base + len)` of `pid` back and keeps them
+/// from being evicted again (their physical address has to stay the same).
+pub fn pin(pid: Pid, base: u64, len: u64) -> Result<(), KError> {
+    let start = base & !(BASE_PAGE_SIZE as u64 - 1);
+    let end = base.checked_add(len).ok_or(KError::BadAddress)?;
+    unmapped(pid, start..end)?;
+    for page in (start..end).step_by(BASE_PAGE_SIZE) {
+        // An eviction that started before we removed it finishes first
+        fault_in(pid, page)?;
+    }
+    Ok(())
+}
+
+/// Brings the evicted page at `addr` of `pid` back, returns false if it
+/// isn't evicted.
+pub fn fault_in(pid: Pid, addr: u64) -> Result<bool, KError> {
+    load(pid, addr & !(BASE_PAGE_SIZE as u64 - 1), true)
+}
+
+/// Brings all evicted pages of `pid` back, they stay until the guard is
+/// dropped (nobody evicts anything in the meantime).
+pub fn resident(pid: Pid) -> Result<MutexGuard<'static, ()>, KError> {
+    let evicted = || {
+        SWAPPED
+            .lock()
+            .as_ref()
+            .and_then(|s| s.evicted_in(pid, 0..kpi::KERNEL_BASE))
+    };
+    loop {
+        while let Some(addr) = evicted() {
+            fault_in(pid, addr)?;
+        }
+        let evicting = loop {
+            match EVICTING.try_lock() {
+                Some(guard) => break guard,
+                None => wait_for_others(),
+            }
+        };
+        // Unless somebody evicted one again before we got the lock
+        if evicted().is_none() {
+            return Ok(evicting);
+        }
+    }
+}
+
+/// Whether the page at `addr` of `pid` came from `VSpace::map` (and may be
+/// evicted).
+pub fn is_anonymous(pid: Pid, addr: u64) -> bool {
+    SWAPPED
+        .lock()
+        .as_ref()
+        .map_or(false, |s| s.is_anonymous(pid, addr))
+}
+
+/// Handles a user-space fault at `addr` of `pid`, returns whether the
+/// process can continue (and fault again if the page is still in transit).
+pub fn handle_fault(pid: Pid, addr: u64) -> bool {
+    match load(pid, addr & !(BASE_PAGE_SIZE as u64 - 1), false) {
+        Ok(found) => found,
+        Err(e) => {
+            error!("Can't bring back page {:#x} of {}: {}", addr, pid, e);
+            false
+        }
+    }
+}
+
+/// Drops the evicted page at `addr` of `pid` (the process unmaps it),
+/// returns false if it isn't evicted.
+pub fn forget(pid: Pid, addr: u64) -> Result<bool, KError> {
+    let addr = addr & !(BASE_PAGE_SIZE as u64 - 1);
+    loop {
+        let lookup = match SWAPPED.lock().as_mut() {
+            Some(swapped) => swapped.forget(pid, addr),
+            None => return Ok(false),
+        };
+        match lookup {
+            Lookup::Resident => return Ok(false),
+            Lookup::Load(location, _rights) => {
+                release(location);
+                return Ok(true);
+            }
+            Lookup::Busy => wait_for_others(),
+        }
+    }
+}
+
+/// Evicts the anonymous pages of `pid` in `range`, returns how many.
+pub fn page_out(pid: Pid, range: Range<u64>) -> Result<u64, KError> {
+    if !available() {
+        return Err(KError::SwapUnavailable);
+    }
+
+    let mut evicted = 0;
+    let start = range.start & !(BASE_PAGE_SIZE as u64 - 1);
+    for addr in (start..range.end).step_by(BASE_PAGE_SIZE) {
+        match evict(pid, addr) {
+            Ok(true) => evicted += 1,
+            // Not anonymous, in use by the kernel or evicted already
+            Ok(false) => {}
+            Err(KError::SwapFull) if evicted > 0 => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(evicted)
+}
+
+/// Evicts up to `pages` pages that weren't accessed lately, returns how
+/// many.
+pub fn reclaim(pages: usize) -> usize {
+    let scan = match SWAPPED.lock().as_ref() {
+        // Every page gets a second chance
+        Some(swapped) => core::cmp::min(2 * swapped.anonymous_pages(), MAX_SCAN),
+        None => return 0,
+    };
+
+    let mut evicted = 0;
+    for _ in 0..scan {
+        if evicted == pages {
+            break;
+        }
+        let (pid, addr) = match SWAPPED.lock().as_mut().and_then(|s| s.next_page()) {
+            Some(page) => page,
+            None => break,
+        };
+        // Accessed since we passed last, or evicted already
+        if NrProcess::<Ring3Process>::accessed(pid, VAddr::from(addr)) != Ok(false) {
+            continue;
+        }
+        match evict(pid, addr) {
+            Ok(true) => evicted += 1,
+            Ok(false) => {}
+            Err(KError::SwapFull) => break,
+            Err(e) => warn!("Can't evict page {:#x} of {}: {}", addr, pid, e),
+        }
+    }
+    evicted
+}
+
+/// Spins while another core evicts or loads a page, it may wait for us to
+/// flush our TLB.
+fn wait_for_others() {
+    tlb::dequeue(get_kcb().arch.id());
+    spin_loop();
+}
+
+/// Evicts the page at `addr` of `pid`, returns false if we can't evict it.
+fn evict(pid: Pid, addr: u64) -> Result<bool, KError> {
+    let va = VAddr::from(addr);
+    let _evicting = loop {
+        match EVICTING.try_lock() {
+            Some(guard) => break guard,
+            None => wait_for_others(),
+        }
+    };
+    // So we can put the frame back without allocating
+    let mut frames: Vec<Frame> = Vec::try_with_capacity(1)?;
+
+    let (handle, rights) = {
+        // Unmap and Protect of the page wait until it's unmapped
+        let _pins = match user_access::try_exclude_pins(pid, addr) {
+            Some(pins) => pins,
+            None => return Ok(false),
+        };
+        let rights = match NrProcess::<Ring3Process>::mapping(pid, va) {
+            Ok((_paddr, rights)) => rights,
+            Err(_e) => return Ok(false),
+        };
+        let marked =
+            with_swapped(|s| s.is_anonymous(pid, addr) && s.evicting(pid, addr, rights).is_ok())?;
+        if !marked {
+            return Ok(false);
+        }
+        match NrProcess::<Ring3Process>::unmap(pid, va) {
+            Ok((handle, _generation)) => (handle, rights),
+            Err(e) => {
+                with_swapped(|s| s.abort(pid, addr))?;
+                return Err(e);
+            }
+        }
+    };
+    debug_assert_eq!(handle.frame.size, BASE_PAGE_SIZE);
+    let frame = handle.frame;
+    // Nobody writes to the frame after this
+    tlb::shootdown(handle);
+
+    let page =
+        unsafe { core::slice::from_raw_parts(frame.kernel_vaddr().as_ptr::<u8>(), BASE_PAGE_SIZE) };
+    match store(page) {
+        Ok(location) => {
+            with_swapped(|s| s.stored(pid, addr, location))?;
+            if let Err(e) = crate::memory::release_frame(frame) {
+                warn!("Leaking {:?}: {}", frame, e);
+            }
+            Ok(true)
+        }
+        Err(e) => {
+            // Map it again the way it was, faults wait until we're done
+            frames.push(frame);
+            if let Err(e) = NrProcess::<Ring3Process>::map_frames(pid, va, frames, rights) {
+                error!("Lost page {:#x} of {}: {}", addr, pid, e);
+            }
+            with_swapped(|s| s.abort(pid, addr))?;
+            Err(e)
+        }
+    }
+}
+
+/// Puts the contents of an evicted page where they fit.
+fn store(page: &[u8]) -> Result<Location, KError> {
+    let mut compressed = Vec::new();
+    if swap::compress(page, &mut compressed)?
+        && with_swapped(|s| s.pool_has_room(compressed.len()))?
+    {
+        // Only keep what we need
+        let mut data = Vec::try_with_capacity(compressed.len())?;
+        data.extend_from_slice(&compressed);
+        return Ok(Location::Pool(data));
+    }
+
+    let device = DEVICE.get().ok_or(KError::SwapFull)?;
+    Ok(Location::Device(device.write(page)?))
+}
+
+/// Frees what holds an evicted page.
+fn release(location: Location) {
+    if let (Location::Device(slot), Some(device)) = (&location, DEVICE.get()) {
+        device.free(*slot);
+    }
+}
+
+/// Maps the evicted page at `addr` of `pid` again (`wait`s if somebody
+/// else evicts or loads it), returns false if it isn't evicted.
+fn load(pid: Pid, addr: u64, wait: bool) -> Result<bool, KError> {
+    let (location, rights) = loop {
+        let lookup = match SWAPPED.lock().as_mut() {
+            Some(swapped) => swapped.load(pid, addr),
+            None => return Ok(false),
+        };
+        match lookup {
+            Lookup::Resident => return Ok(false),
+            Lookup::Load(location, rights) => break (location, rights),
+            Lookup::Busy if wait => wait_for_others(),
+            Lookup::Busy => return Ok(true),
+        }
+    };
+
+    match restore(pid, addr, &location, rights) {
+        Ok(()) => {
+            with_swapped(|s| s.loaded(pid, addr))?;
+            release(location);
+            Ok(true)
+        }
+        Err(e) => {
+            with_swapped(|s| s.stored(pid, addr, location))?;
+            Err(e)
+        }
+    }
+}
+
+/// Copies an evicted page into a new frame and maps it at `addr`.
+fn restore(pid: Pid, addr: u64, location: &Location, rights: MapAction) -> Result<(), KError> {
+    let frame = allocate_frame()?;
+    let mut frames = Vec::new();
+    let page = unsafe {
+        core::slice::from_raw_parts_mut(frame.kernel_vaddr().as_mut_ptr::<u8>(), BASE_PAGE_SIZE)
+    };
+    let filled = frames
+        .try_push(frame)
+        .map_err(KError::from)
+        .and_then(|_| match location {
+            Location::Pool(data) => swap::decompress(data, page),
+            Location::Device(slot) => DEVICE
+                .get()
+                .ok_or(KError::SwapUnavailable)
+                .and_then(|device| device.read(*slot, page)),
+        });
+    if let Err(e) = filled {
+        if let Err(e) = crate::memory::release_frame(frame) {
+            warn!("Leaking {:?}: {}", frame, e);
+        }
+        return Err(e);
+    }
+
+    NrProcess::<Ring3Process>::map_frames(pid, VAddr::from(addr), frames, rights)?;
+    // It wasn't written through the mapping, but a checkpoint has to save it
+    NrProcess::<Ring3Process>::set_dirty(pid, VAddr::from(addr))?;
+    Ok(())
+}
+
+/// A frame for a page we bring back, we evict others if there is none.
+fn allocate_frame() -> Result<Frame, KError> {
+    let allocate = || {
+        KernelAllocator::try_refill_tcache(1, 0)?;
+        get_kcb().mem_manager().allocate_base_page()
+    };
+    allocate().or_else(|e| {
+        if reclaim(RECLAIM_BATCH) > 0 {
+            allocate()
+        } else {
+            Err(e)
+        }
+    })
+}
+
+fn proc_swap(out: &mut String) -> fmt::Result {
+    let swapped = SWAPPED.lock();
+    let swapped = match swapped.as_ref() {
+        Some(swapped) => swapped,
+        None => return Ok(()),
+    };
+    let (pool_bytes, pool_pages, device_pages) = swapped.usage();
+    let (_used, slots) = DEVICE.get().map_or((0, 0), |d| d.usage());
+
+    writeln!(out, "{:<16} {}", "pool_limit", swapped.pool_limit())?;
+    writeln!(out, "{:<16} {}", "pool_bytes", pool_bytes)?;
+    writeln!(out, "{:<16} {}", "pool_pages", pool_pages)?;
+    writeln!(out, "{:<16} {}", "device_slots", slots)?;
+    writeln!(out, "{:<16} {}", "device_pages", device_pages)?;
+    writeln!(
+        out,
+        "{:<16} {}",
+        "anonymous_pages",
+        swapped.anonymous_pages()
+    )?;
+    writeln!(out, "{:<16} {}", "evictions", swapped.evictions)?;
+    writeln!(out, "{:<16} {}", "loads", swapped.loads)
+}
@@ -7,7 +7,9 @@ use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::convert::TryInto;
+use core::iter;
 
+use arrayvec::ArrayVec;
 use fallible_collections::{FallibleVec, FallibleVecGlobal};
 use klogger::{sprint, sprintln};
 use log::{debug, error, info, trace, warn};
@@ -15,28 +17,51 @@ use x86::bits64::paging::{PAddr, VAddr, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
 use x86::bits64::rflags;
 use x86::msr::{rdmsr, wrmsr, IA32_EFER, IA32_FMASK, IA32_LSTAR, IA32_STAR};
 
-use kpi::process::FrameId;
+use kpi::abi::{AbiFeatures, AbiVersion, ABI_VERSION};
+use kpi::cap::CapRights;
+use kpi::event::EventFlags;
+use kpi::filter::SyscallFilter;
+use kpi::io::FileFlags;
+use kpi::ipc::{Message, MAX_DOOR_NAME, MAX_PAYLOAD};
+use kpi::net::{PollEvents, PollFd, MAX_POLL_FDS, POLL_EVENT_HANDLE, VSOCK_FD};
+use kpi::perf::{PerfEvent, PerfScope};
+use kpi::process::{AddressLayout, FrameId, ANY_CORE};
+use kpi::system::{CorePolicy, KeyEvent};
+use kpi::time::{TimerFlags, MIN_TIMER_INTERVAL_NS};
+use kpi::vm::{Exit, VcpuState};
 use kpi::{
-    FileOperation, ProcessOperation, SystemCall, SystemCallError, SystemOperation, VSpaceOperation,
+    CapOperation, DebugOperation, FileOperation, IpcOperation, KprobeMode, MemoryRights,
+    NetworkOperation, PerfOperation, ProcessOperation, SystemCall, SystemCallError,
+    SystemOperation, TimeOperation, VSpaceOperation, VmOperation,
 };
 
+use crate::cap::{Capability, Object};
 use crate::error::KError;
 use crate::fs::FileSystem;
 use crate::kcb::ArchSpecificKcb;
-use crate::memory::vspace::MapAction;
-use crate::memory::{Frame, PhysicalPageProvider, KERNEL_BASE};
-use crate::process::{Pid, ResumeHandle};
-use crate::{cnrfs, nr, nrproc};
+use crate::memory::vspace::{MapAction, UserAccess};
+use crate::memory::{Frame, PhysicalPageProvider};
+use crate::process::{userptr_to_str, Executor, Pid, ResumeHandle, UserPtr};
+use crate::{cnrfs, event, ipc, itimer, nr, nrproc, procfs, syscall_filter};
 
 use super::gdt::GdtTable;
-use super::process::{Ring3Process, UserValue};
+use super::hypervisor;
+use super::process::{Ring3Process, Ring3Resumer};
+use super::user_access;
 
 extern "C" {
     #[no_mangle]
     fn syscall_enter();
 }
 
-fn handle_system(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError> {
+/// Most bytes we hand out with one `SystemOperation::GetRandom` call.
+const MAX_GETRANDOM: usize = 64 * 1024;
+
+/// How often a core that waits in `Poll` for sockets checks the NIC itself
+/// (the devices are polled, nobody else might do it).
+const NET_POLL_INTERVAL: core::time::Duration = core::time::Duration::from_micros(100);
+
+fn handle_system(arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> Result<(u64, u64), KError> {
     let op = SystemOperation::from(arg1);
 
     match op {
@@ -44,7 +69,10 @@ fn handle_system(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
             let vaddr_buf = arg2; // buf.as_mut_ptr() as u64
             let vaddr_buf_len = arg3; // buf.len() as u64
 
-            let hwthreads = atopology::MACHINE_TOPOLOGY.threads();
+            // Only the cores we booted
+            let hwthreads = atopology::MACHINE_TOPOLOGY
+                .threads()
+                .filter(|t| super::coreboot::is_online(t.id));
             let num_threads = atopology::MACHINE_TOPOLOGY.num_threads();
 
             let mut return_threads = Vec::try_with_capacity(num_threads)?;
@@ -61,29 +89,332 @@ fn handle_system(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
             // TODO(dependency): Get rid of serde/serde_cbor, use something sane instead
             let serialized = serde_cbor::to_vec(&return_threads).unwrap();
             if serialized.len() <= vaddr_buf_len as usize {
-                let mut user_slice = super::process::UserSlice::new(vaddr_buf, serialized.len());
-                user_slice.copy_from_slice(serialized.as_slice());
+                user_access::copy_out(vaddr_buf, serialized.as_slice())?;
             }
 
             Ok((serialized.len() as u64, 0))
         }
         SystemOperation::Stats => {
-            let kcb = super::kcb::get_kcb();
-            info!("IRQ handler time: {} cycles", kcb.tlb_time);
-            Ok((0, 0))
+            let vaddr_buf = arg2; // buf.as_mut_ptr() as u64
+            let vaddr_buf_len = arg3; // buf.len() as u64
+
+            let stats = crate::stats::collect()?;
+            let serialized = serde_cbor::to_vec(&stats).unwrap();
+            if serialized.len() <= vaddr_buf_len as usize {
+                user_access::copy_out(vaddr_buf, serialized.as_slice())?;
+            }
+
+            Ok((serialized.len() as u64, 0))
         }
         SystemOperation::GetCoreID => {
             let kcb = super::kcb::get_kcb();
             Ok((kcb.arch.id() as u64, 0))
         }
+        SystemOperation::ReadKeyEvents => {
+            let vaddr_buf = arg2; // events.as_mut_ptr() as u64
+            let max_events = arg3 as usize; // events.len() as u64
+
+            let mut events = [KeyEvent::default(); 32];
+            let read = crate::drivers::input::pop(&mut events[..max_events.min(32)]);
+            if read > 0 {
+                let len = read * core::mem::size_of::<KeyEvent>();
+                let bytes =
+                    unsafe { core::slice::from_raw_parts(events.as_ptr() as *const u8, len) };
+                user_access::copy_out(vaddr_buf, bytes)?;
+            }
+
+            Ok((read as u64, 0))
+        }
+        SystemOperation::GetRandom => {
+            let vaddr_buf = arg2; // buf.as_mut_ptr() as u64
+            let len = (arg3 as usize).min(MAX_GETRANDOM); // buf.len() as u64
+
+            let mut chunk = [0u8; 256];
+            for offset in (0..len).step_by(chunk.len()) {
+                let n = (len - offset).min(chunk.len());
+                crate::entropy::fill(&mut chunk[..n]);
+                user_access::copy_out(vaddr_buf + offset as u64, &chunk[..n])?;
+            }
+
+            Ok((len as u64, 0))
+        }
+        SystemOperation::GetTopology => {
+            let vaddr_buf = arg2; // buf.as_mut_ptr() as u64
+            let vaddr_buf_len = arg3; // buf.len() as u64
+
+            let mut nodes = Vec::new();
+            match crate::acpi::platform().filter(|platform| !platform.nodes.is_empty()) {
+                Some(platform) => {
+                    for node in platform.nodes.iter() {
+                        let mut distances = Vec::try_with_capacity(platform.nodes.len())?;
+                        for to in 0..platform.nodes.len() {
+                            distances.try_push(platform.distance(node.id, to))?;
+                        }
+                        nodes.try_push(kpi::system::NumaNode {
+                            id: node.id,
+                            memory: node.memory as usize,
+                            distances,
+                        })?;
+                    }
+                }
+                None => {
+                    // No SRAT, everything is on one node
+                    let mut distances = Vec::new();
+                    distances.try_push(10)?;
+                    nodes.try_push(kpi::system::NumaNode {
+                        id: 0,
+                        memory: 0,
+                        distances,
+                    })?;
+                }
+            }
+
+            let serialized = serde_cbor::to_vec(&nodes).unwrap();
+            if serialized.len() <= vaddr_buf_len as usize {
+                user_access::copy_out(vaddr_buf, serialized.as_slice())?;
+            }
+
+            Ok((serialized.len() as u64, 0))
+        }
+        SystemOperation::OfflineCore => {
+            require_privileged()?;
+            let gtid = arg2 as usize;
+            super::hotplug::offline(gtid)?;
+            nr::KernelNode::set_core_online(gtid, false)?;
+            Ok((0, 0))
+        }
+        SystemOperation::OnlineCore => {
+            require_privileged()?;
+            let gtid = arg2 as usize;
+            super::hotplug::online(gtid)?;
+            nr::KernelNode::set_core_online(gtid, true)?;
+            Ok((0, 0))
+        }
+        SystemOperation::GetCorePolicy => Ok((nr::KernelNode::core_policy()? as u64, 0)),
+        SystemOperation::SetCorePolicy => {
+            require_privileged()?;
+            let policy = CorePolicy::from_u64(arg2).ok_or(KError::InvalidCorePolicy)?;
+            let previous = nr::KernelNode::set_core_policy(policy)?;
+            info!("Core policy is {} (was {})", policy.name(), previous.name());
+            Ok((previous as u64, 0))
+        }
+        SystemOperation::SetGang => {
+            require_privileged()?;
+            let pid = match arg2 {
+                u64::MAX => super::kcb::get_kcb().current_pid()?,
+                pid => pid.try_into().map_err(|_e| KError::NoProcessFoundForPid)?,
+            };
+            super::gang::set(pid, arg3 != 0)?;
+            Ok((0, 0))
+        }
+        SystemOperation::GetFrequency => {
+            let gtid = arg2 as usize;
+            let vaddr_buf = arg3;
+            let vaddr_buf_len = arg4;
+
+            let frequency = super::cpufreq::get(gtid)?;
+            let serialized = serde_cbor::to_vec(&frequency).unwrap();
+            if serialized.len() <= vaddr_buf_len as usize {
+                user_access::copy_out(vaddr_buf, serialized.as_slice())?;
+            }
+
+            Ok((serialized.len() as u64, 0))
+        }
+        SystemOperation::SetFrequency => {
+            require_privileged()?;
+            let gtid = arg2 as usize;
+            let mhz = match arg3 {
+                0 => None,
+                mhz => Some(mhz.try_into().map_err(|_e| KError::InvalidFrequency)?),
+            };
+            super::cpufreq::set(gtid, mhz)?;
+            Ok((0, 0))
+        }
+        SystemOperation::Suspend => {
+            require_privileged()?;
+            // We come back through `suspend::resume` if this works
+            Err(super::suspend::suspend(core::time::Duration::from_nanos(
+                arg2,
+            )))
+        }
+        SystemOperation::Kexec => {
+            require_privileged()?;
+            let cmdline = user_access::copy_in_str(arg4)?;
+            // A copy, so we don't parse it with user memory accessible
+            let mut image: Vec<u8> = Vec::new();
+            image.try_resize(arg3 as usize, 0)?;
+            user_access::copy_in(&mut image, arg2)?;
+            Err(super::kexec::kexec(&image, &cmdline))
+        }
+        SystemOperation::Shutdown => {
+            require_privileged()?;
+            // QEMU exits with `(code << 1) | 1`, the exit status has 8 bits
+            if arg2 >= 128 {
+                return Err(KError::InvalidExitCode);
+            }
+            super::power::power_off(arg2 as u8)
+        }
+        SystemOperation::Reboot => {
+            require_privileged()?;
+            super::power::reboot()
+        }
+        SystemOperation::Dmesg => {
+            let vaddr_buf = arg2; // buf.as_mut_ptr() as u64
+            let len = (arg3 as usize).min(crate::dmesg::SIZE); // buf.len() as u64
+            let offset = arg4;
+
+            let mut kbuf: Vec<u8> = Vec::try_with_capacity(len)?;
+            kbuf.resize(len, 0);
+            let (read, next) = crate::dmesg::read(offset, &mut kbuf);
+            user_access::copy_out(vaddr_buf, &kbuf[..read])?;
+
+            Ok((read as u64, next))
+        }
+        SystemOperation::AbiVersion => {
+            let theirs = AbiVersion::from(arg2);
+            if !theirs.is_compatible(ABI_VERSION) {
+                // They'll find out, but this helps to see why things break
+                warn!(
+                    "Process built for system call interface {}, we have {}",
+                    theirs, ABI_VERSION
+                );
+            }
+
+            let mut features = AbiFeatures::empty();
+            if cfg!(feature = "smoltcp") {
+                features |= AbiFeatures::NETWORK;
+            }
+            if cfg!(feature = "heap-tracking") {
+                features |= AbiFeatures::HEAP_TRACKING;
+            }
+            if hypervisor::vmx::supported() {
+                features |= AbiFeatures::VIRTUALIZATION;
+            }
+            if crate::net::vsock::available() {
+                features |= AbiFeatures::VSOCK;
+            }
+            if super::swap::available() {
+                features |= AbiFeatures::SWAP;
+            }
+            Ok((u64::from(ABI_VERSION), features.bits()))
+        }
         SystemOperation::Unknown => Err(KError::InvalidSystemOperation { a: arg1 }),
     }
 }
 
+/// System call handler for clocks
+fn handle_time(arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> Result<(u64, u64), KError> {
+    match TimeOperation::from(arg1) {
+        TimeOperation::Wallclock => {
+            let now = super::vdso::realtime().ok_or(KError::ClockUnavailable)?;
+            Ok((now.as_secs(), now.subsec_nanos() as u64))
+        }
+        TimeOperation::Monotonic => Ok((super::vdso::monotonic().as_nanos() as u64, 0)),
+        TimeOperation::Resolution => Ok((super::vdso::clock().resolution_ns(), 0)),
+        TimeOperation::TimePage => {
+            let page = super::vdso::address().ok_or(KError::NotSupported)?;
+            Ok((page, 0))
+        }
+        TimeOperation::TimerCreate => {
+            let interval = core::time::Duration::from_nanos(arg2.max(MIN_TIMER_INTERVAL_NS));
+            let flags = TimerFlags::from_bits_truncate(arg3);
+            let core = arg4 as usize;
+            if core >= atopology::MACHINE_TOPOLOGY.num_threads() {
+                return Err(KError::InvalidGlobalThreadId);
+            }
+            let kcb = super::kcb::get_kcb();
+            let pid = kcb.current_pid()?;
+
+            let ticks =
+                crate::time::clocksource::duration_to_ticks(interval, super::tsc::frequency());
+            let deadline = x86::time::rdtsc().saturating_add(ticks);
+            let period = if flags.contains(TimerFlags::PERIODIC) {
+                Some(ticks)
+            } else {
+                None
+            };
+            let timer = itimer::TIMERS.lock().create(pid, core, deadline, period)?;
+
+            // The core that delivers it has to set its timer
+            if core == kcb.arch.id() {
+                super::futex::expire_timers();
+            } else {
+                super::futex::kick(core);
+            }
+            Ok((timer as u64, 0))
+        }
+        TimeOperation::TimerCancel => {
+            let pid = super::kcb::get_kcb().current_pid()?;
+            itimer::TIMERS.lock().cancel(pid, arg2 as usize)?;
+            Ok((0, 0))
+        }
+        TimeOperation::Unknown => Err(KError::InvalidTimeOperation { a: arg1 }),
+    }
+}
+
+/// System call handler for debugging the kernel
+fn handle_debug(arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> Result<(u64, u64), KError> {
+    match DebugOperation::from(arg1) {
+        DebugOperation::NmiAll => Ok((super::nmi::nmi_all() as u64, 0)),
+        DebugOperation::SetLogFilter => {
+            let len = arg3 as usize;
+            if len > user_access::MAX_STR_LEN {
+                return Err(KError::InvalidLogFilter);
+            }
+            let mut kbuf: Vec<u8> = Vec::try_with_capacity(len)?;
+            kbuf.resize(len, 0);
+            user_access::copy_in(&mut kbuf, arg2)?;
+            let filter = core::str::from_utf8(&kbuf).map_err(|_| KError::InvalidLogFilter)?;
+
+            crate::console::set_filter(filter)?;
+            info!("Log filter is now '{}'", filter);
+            Ok((0, 0))
+        }
+        DebugOperation::Kprobe => {
+            let len = arg3 as usize;
+            if len > user_access::MAX_STR_LEN {
+                return Err(KError::UnknownKprobe);
+            }
+            let mut kbuf: Vec<u8> = Vec::try_with_capacity(len)?;
+            kbuf.resize(len, 0);
+            user_access::copy_in(&mut kbuf, arg2)?;
+            let name = core::str::from_utf8(&kbuf).map_err(|_| KError::UnknownKprobe)?;
+
+            let hits = super::kprobes::set_mode(name, KprobeMode::from(arg4))?;
+            Ok((hits, 0))
+        }
+        DebugOperation::DumpSchedGraph => {
+            crate::scheduler::debug::dump::<Ring3Process>()?;
+            Ok((0, 0))
+        }
+        DebugOperation::TraceSyscalls => {
+            let pid = super::kcb::get_kcb().current_pid()?;
+            crate::trace::set(pid, arg2 != 0)?;
+            Ok((0, 0))
+        }
+        DebugOperation::Unknown => Err(KError::InvalidDebugOperation { a: arg1 }),
+    }
+}
+
+/// System call handler for the performance counters
+fn handle_perf(arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> Result<(u64, u64), KError> {
+    let counter = arg2 as usize;
+    match PerfOperation::from(arg1) {
+        PerfOperation::Start => {
+            let event = PerfEvent::from_config(arg3, arg4);
+            let scope = PerfScope::from(arg3 >> 32);
+            super::perf::start(counter, event, scope)?;
+            Ok((0, 0))
+        }
+        PerfOperation::Stop => Ok((super::perf::stop(counter)?, 0)),
+        PerfOperation::Read => Ok((super::perf::read(counter)?, 0)),
+        PerfOperation::Unknown => Err(KError::InvalidPerfOperation { a: arg1 }),
+    }
+}
+
 /// System call handler for printing
-fn process_print(buf: UserValue<&str>) -> Result<(u64, u64), KError> {
+fn process_print(buffer: &str) -> Result<(u64, u64), KError> {
     let mut kcb = super::kcb::get_kcb();
-    let buffer: &str = *buf;
 
     // A poor mans line buffer scheme:
     match &mut kcb.print_buffer {
@@ -95,6 +426,7 @@ fn process_print(buf: UserValue<&str>) -> Result<(u64, u64), KError> {
                     let r = klogger::SERIAL_LINE_MUTEX.lock();
                     sprint!("{}", kbuf);
                 }
+                crate::console::write_str(kbuf);
                 kbuf.clear();
                 kbuf.push_str(high);
             }
@@ -106,13 +438,17 @@ fn process_print(buf: UserValue<&str>) -> Result<(u64, u64), KError> {
                         let r = klogger::SERIAL_LINE_MUTEX.lock();
                         sprint!("{}", kbuf);
                     }
+                    crate::console::write_str(kbuf);
                     kbuf.clear();
                 }
             }
         },
         None => {
-            let r = klogger::SERIAL_LINE_MUTEX.lock();
-            sprint!("{}", buffer);
+            {
+                let r = klogger::SERIAL_LINE_MUTEX.lock();
+                sprint!("{}", buffer);
+            }
+            crate::console::write_str(buffer);
         }
     }
 
@@ -122,6 +458,11 @@ fn process_print(buf: UserValue<&str>) -> Result<(u64, u64), KError> {
 /// System call handler for process exit
 fn process_exit(code: u64) -> Result<(u64, u64), KError> {
     debug!("Process got exit, we are done for now...");
+    #[cfg(feature = "heap-tracking")]
+    crate::memory::track::report();
+    if let Ok(pid) = super::kcb::get_kcb().current_pid() {
+        crate::trace::dump(pid);
+    }
     // TODO: For now just a dummy version that exits Qemu
     if code != 0 {
         // When testing we want to indicate to our integration
@@ -132,20 +473,301 @@ fn process_exit(code: u64) -> Result<(u64, u64), KError> {
     }
 }
 
-fn handle_process(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError> {
+/// Gives process `to` the capability `handle` of process `pid` (with the
+/// rights of `handle` that are in `rights`), returns the handle in `to`.
+fn transfer_capability(pid: Pid, handle: u64, to: Pid, rights: CapRights) -> Result<u64, KError> {
+    let capability = nrproc::NrProcess::<Ring3Process>::capability(pid, handle)?;
+    capability.check(CapRights::GRANT)?;
+    nr::KernelNode::process(to)?;
+
+    let object = match capability.object {
+        Object::File(fd) => Object::File(cnrfs::MlnrKernelNode::dup_fd(pid, fd, to)?),
+        Object::Frame(fid) => {
+            let frame = nrproc::NrProcess::<Ring3Process>::frame(pid, fid)?;
+            let fid = nrproc::NrProcess::<Ring3Process>::allocate_frame_to_process(to, frame)?;
+            Object::Frame(fid)
+        }
+        // A core runs the process that requested it
+        Object::Core(_gtid) => return Err(KError::NotSupported),
+        Object::Door(door) => Object::Door(door),
+        Object::Event(event) => Object::Event(event),
+        Object::Vm(vm) => Object::Vm(vm),
+    };
+
+    let transferred = Capability::new(object, capability.rights & rights);
+    nrproc::NrProcess::<Ring3Process>::insert_capability(to, transferred).map_err(|e| {
+        if let Object::File(fd) = object {
+            let _r = cnrfs::MlnrKernelNode::unmap_fd(to, fd);
+        }
+        e
+    })
+}
+
+/// System call handler for capability operations
+fn handle_capability(arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> Result<(u64, u64), KError> {
+    let kcb = super::kcb::get_kcb();
+    let pid = kcb.arch.current_pid()?;
+    let handle = arg2;
+
+    match CapOperation::from(arg1) {
+        CapOperation::Restrict => {
+            let rights = CapRights::from_bits_truncate(arg3);
+            nrproc::NrProcess::<Ring3Process>::restrict_capability(pid, handle, rights)?;
+            Ok((0, 0))
+        }
+        CapOperation::Transfer => {
+            let to = arg3 as Pid;
+            let rights = CapRights::from_bits_truncate(arg4);
+            Ok((transfer_capability(pid, handle, to, rights)?, 0))
+        }
+        CapOperation::Identify => {
+            let capability = nrproc::NrProcess::<Ring3Process>::capability(pid, handle)?;
+            Ok((capability.object.kind() as u64, capability.rights.bits()))
+        }
+        CapOperation::Unknown => Err(KError::InvalidCapOperation { a: arg1 }),
+    }
+}
+
+/// Reads the message at `vaddr`, with its capability (if any) transferred
+/// from `pid` to `to`.
+fn message_in(vaddr: u64, pid: Pid, to: Pid) -> Result<Message, KError> {
+    let mut msg = UserPtr::<Message>::new(vaddr)?.read()?;
+    msg.len = msg.len.min(MAX_PAYLOAD as u64);
+    if msg.cap != 0 {
+        msg.cap = transfer_capability(pid, msg.cap, to, CapRights::all())?;
+    }
+    Ok(msg)
+}
+
+/// Copies in the door name of `len` bytes at `vaddr`.
+fn door_name(vaddr: u64, len: u64) -> Result<ArrayVec<u8, MAX_DOOR_NAME>, KError> {
+    if len == 0 || len > MAX_DOOR_NAME as u64 {
+        return Err(KError::InvalidDoorName);
+    }
+    let mut name = ArrayVec::new();
+    name.extend(core::iter::repeat(0).take(len as usize));
+    user_access::copy_in(&mut name, vaddr)?;
+    Ok(name)
+}
+
+/// System call handler for doors and events
+fn handle_ipc(arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64) -> Result<(u64, u64), KError> {
+    let kcb = super::kcb::get_kcb();
+    let pid = kcb.current_pid()?;
+    let core = kcb.arch.id();
+
+    match IpcOperation::from(arg1) {
+        IpcOperation::Create => {
+            let name = door_name(arg2, arg3)?;
+            let name = core::str::from_utf8(&name).map_err(|_e| KError::InvalidDoorName)?;
+            let door = ipc::DOORS.lock().create(name, pid)?;
+            let rights = CapRights::SERVE | CapRights::CALL | CapRights::GRANT;
+            let handle = nrproc::NrProcess::<Ring3Process>::insert_capability(
+                pid,
+                Capability::new(Object::Door(door), rights),
+            )?;
+            Ok((handle, 0))
+        }
+        IpcOperation::Connect => {
+            let name = door_name(arg2, arg3)?;
+            let name = core::str::from_utf8(&name).map_err(|_e| KError::InvalidDoorName)?;
+            let door = ipc::DOORS.lock().lookup(name)?;
+            let rights = CapRights::CALL | CapRights::GRANT;
+            let handle = nrproc::NrProcess::<Ring3Process>::insert_capability(
+                pid,
+                Capability::new(Object::Door(door), rights),
+            )?;
+            Ok((handle, 0))
+        }
+        IpcOperation::Call => {
+            let door =
+                nrproc::NrProcess::<Ring3Process>::capability(pid, arg2)?.door(CapRights::CALL)?;
+            let owner = ipc::DOORS.lock().owner(door)?;
+            let request = message_in(arg3, pid, owner)?;
+
+            let (id, waiting) = ipc::DOORS.lock().call(door, pid, request)?;
+            if let Some(server) = waiting {
+                super::futex::kick(server);
+            }
+            let notify = ipc::DOORS.lock().notify(door)?;
+            if let Some(event) = notify {
+                // The call is queued, the server will see it
+                let _r = super::futex::signal_event(event, 1);
+            }
+            Ok((id, 0))
+        }
+        IpcOperation::Await => {
+            let door =
+                nrproc::NrProcess::<Ring3Process>::capability(pid, arg2)?.door(CapRights::CALL)?;
+            let (id, reply) = (arg3, UserPtr::<Message>::new(arg4)?);
+
+            let ready = ipc::DOORS.lock().await_reply(door, pid, id, core)?;
+            match ready {
+                Some(msg) => {
+                    reply.write(msg)?;
+                    Ok((0, 0))
+                }
+                // The server kicks us once it replied
+                None => super::futex::park(Some(KError::WouldBlock), None),
+            }
+        }
+        IpcOperation::Reply => {
+            let door =
+                nrproc::NrProcess::<Ring3Process>::capability(pid, arg2)?.door(CapRights::SERVE)?;
+            let (id, next) = (arg3, arg5);
+
+            if id != 0 {
+                let client = ipc::DOORS.lock().client(door, pid, id)?;
+                let reply = message_in(arg4, pid, client)?;
+                let waiting = ipc::DOORS.lock().reply(door, pid, id, reply)?;
+                if let Some(client_core) = waiting {
+                    super::futex::kick(client_core);
+                }
+            }
+            if next == 0 {
+                return Ok((0, 0));
+            }
+
+            let request = UserPtr::<Message>::new(next)?;
+            let received = ipc::DOORS.lock().receive(door, pid, core)?;
+            match received {
+                Some((id, msg)) => {
+                    request.write(msg)?;
+                    Ok((id, 0))
+                }
+                // A client kicks us once it called
+                None => super::futex::park(Some(KError::WouldBlock), None),
+            }
+        }
+        IpcOperation::EventCreate => {
+            let (initial, flags) = (arg2, EventFlags::from_bits_truncate(arg3));
+            let event = event::EVENTS
+                .lock()
+                .create(initial, flags.contains(EventFlags::SEMAPHORE))?;
+            let rights = CapRights::READ | CapRights::WRITE | CapRights::GRANT;
+            let handle = nrproc::NrProcess::<Ring3Process>::insert_capability(
+                pid,
+                Capability::new(Object::Event(event), rights),
+            )?;
+            Ok((handle, 0))
+        }
+        IpcOperation::EventSignal => {
+            let event = nrproc::NrProcess::<Ring3Process>::capability(pid, arg2)?
+                .event(CapRights::WRITE)?;
+            super::futex::signal_event(event, arg3)?;
+            Ok((0, 0))
+        }
+        IpcOperation::EventWait => {
+            let event =
+                nrproc::NrProcess::<Ring3Process>::capability(pid, arg2)?.event(CapRights::READ)?;
+            let block = arg3 != 0;
+
+            let taken = event::EVENTS
+                .lock()
+                .take(event, if block { Some(core) } else { None })?;
+            match taken {
+                Some(value) => Ok((value, 0)),
+                None if !block => Err(KError::WouldBlock),
+                // `signal_event` kicks us
+                None => super::futex::park(Some(KError::WouldBlock), None),
+            }
+        }
+        IpcOperation::EventTimer => {
+            let event = nrproc::NrProcess::<Ring3Process>::capability(pid, arg2)?
+                .event(CapRights::WRITE)?;
+            // u64::MAX cancels the timer
+            let deadline = if arg3 == u64::MAX {
+                None
+            } else {
+                let after = core::time::Duration::from_nanos(arg3);
+                let ticks =
+                    crate::time::clocksource::duration_to_ticks(after, super::tsc::frequency());
+                Some(x86::time::rdtsc().saturating_add(ticks))
+            };
+            event::EVENTS.lock().set_timer(event, deadline)?;
+            super::futex::expire_timers();
+            Ok((0, 0))
+        }
+        IpcOperation::Notify => {
+            let door =
+                nrproc::NrProcess::<Ring3Process>::capability(pid, arg2)?.door(CapRights::SERVE)?;
+            let event = nrproc::NrProcess::<Ring3Process>::capability(pid, arg3)?
+                .event(CapRights::WRITE)?;
+            ipc::DOORS.lock().set_notify(door, pid, Some(event))?;
+            Ok((0, 0))
+        }
+        IpcOperation::Unknown => Err(KError::InvalidIpcOperation { a: arg1 }),
+    }
+}
+
+/// System call handler for virtual machines
+fn handle_vm(arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64) -> Result<(u64, u64), KError> {
+    let pid = super::kcb::get_kcb().current_pid()?;
+
+    match VmOperation::from(arg1) {
+        VmOperation::Create => {
+            let vm = hypervisor::vm::create(arg2 as usize)?;
+            let rights = CapRights::MAP | CapRights::WRITE | CapRights::GRANT;
+            let handle = nrproc::NrProcess::<Ring3Process>::insert_capability(
+                pid,
+                Capability::new(Object::Vm(vm), rights),
+            )?;
+            Ok((handle, 0))
+        }
+        VmOperation::MapGuest => {
+            let vm =
+                nrproc::NrProcess::<Ring3Process>::capability(pid, arg2)?.vm(CapRights::MAP)?;
+            let rights = MemoryRights::from_bits(arg5).ok_or(KError::InvalidMemoryRights)?;
+            // The guest shouldn't write to a frame the process can't
+            let needed = if rights.contains(MemoryRights::WRITE) {
+                CapRights::MAP | CapRights::WRITE
+            } else {
+                CapRights::MAP
+            };
+            let fid = nrproc::NrProcess::<Ring3Process>::capability(pid, arg3)?.frame(needed)?;
+            let frame = nrproc::NrProcess::<Ring3Process>::frame(pid, fid)?;
+            // The VM holds its own reference until it's destroyed
+            hypervisor::vm::map_guest(vm, arg4, frame, rights)?;
+            Ok((0, 0))
+        }
+        VmOperation::Run => {
+            let vm =
+                nrproc::NrProcess::<Ring3Process>::capability(pid, arg2)?.vm(CapRights::WRITE)?;
+            let (state, exit) = (
+                UserPtr::<VcpuState>::new(arg4)?,
+                UserPtr::<Exit>::new(arg5)?,
+            );
+            let mut vcpu = state.read()?;
+            let why = hypervisor::vm::run(vm, arg3 as usize, &mut vcpu)?;
+            state.write(vcpu)?;
+            exit.write(why)?;
+            Ok((0, 0))
+        }
+        VmOperation::Destroy => {
+            let vm =
+                nrproc::NrProcess::<Ring3Process>::capability(pid, arg2)?.vm(CapRights::WRITE)?;
+            hypervisor::vm::destroy(vm)?;
+            nrproc::NrProcess::<Ring3Process>::remove_capability(pid, arg2)?;
+            Ok((0, 0))
+        }
+        VmOperation::Unknown => Err(KError::InvalidVmOperation { a: arg1 }),
+    }
+}
+
+fn handle_process(arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> Result<(u64, u64), KError> {
     let op = ProcessOperation::from(arg1);
 
     match op {
         ProcessOperation::Log => {
-            let buffer: *const u8 = arg2 as *const u8;
+            let buffer = arg2;
             let len: usize = arg3 as usize;
 
-            let user_str = unsafe {
-                let slice = core::slice::from_raw_parts(buffer, len);
-                core::str::from_utf8_unchecked(slice)
-            };
+            let mut kbuf: Vec<u8> = Vec::try_with_capacity(len)?;
+            kbuf.resize(len, 0);
+            user_access::copy_in(&mut kbuf, buffer)?;
+            let user_str = unsafe { core::str::from_utf8_unchecked(&kbuf) };
 
-            process_print(UserValue::new(user_str))
+            process_print(user_str)
         }
         ProcessOperation::GetVCpuArea => unsafe {
             let kcb = super::kcb::get_kcb();
@@ -172,13 +794,13 @@ fn handle_process(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
 
             let pid = kcb.current_pid()?;
             let mut pinfo = nrproc::NrProcess::<Ring3Process>::pinfo(pid)?;
-            pinfo.cmdline = kcb.cmdline.init_args;
-            pinfo.app_cmdline = kcb.cmdline.app_args;
+            pinfo.cmdline = kcb.config.init_args;
+            pinfo.tests = kcb.config.init_tests;
+            pinfo.app_cmdline = kcb.config.app_args;
 
             let serialized = serde_cbor::to_vec(&pinfo).unwrap();
             if serialized.len() <= vaddr_buf_len as usize {
-                let mut user_slice = super::process::UserSlice::new(vaddr_buf, serialized.len());
-                user_slice.copy_from_slice(serialized.as_slice());
+                user_access::copy_out(vaddr_buf, serialized.as_slice())?;
             }
 
             Ok((serialized.len() as u64, 0))
@@ -187,24 +809,61 @@ fn handle_process(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
             let gtid: usize = arg2.try_into().unwrap();
             let entry_point = arg3;
             let kcb = super::kcb::get_kcb();
+            require_privileged()?;
+            let pid = kcb.current_pid()?;
 
-            let mut affinity = None;
-            for thread in atopology::MACHINE_TOPOLOGY.threads() {
-                if thread.id == gtid {
-                    affinity = Some(thread.node_id.unwrap_or(0));
+            let gtid = if gtid == ANY_CORE {
+                // The core policy picks one
+                let gtid = nr::KernelNode::allocate_core_to_process(
+                    pid,
+                    VAddr::from(entry_point),
+                    None,
+                    None,
+                )?;
+                // Cores that never booted are online as far as the replicas
+                // know
+                if !super::coreboot::is_online(gtid) {
+                    nr::KernelNode::release_core_from_process(pid, gtid)?;
+                    return Err(KError::CoreOffline);
+                }
+                gtid
+            } else {
+                let affinity = nr::KernelNode::core_node(gtid)?;
+                // A parked core wouldn't run the process
+                if !super::coreboot::is_online(gtid) {
+                    return Err(KError::CoreOffline);
                 }
-            }
-            let affinity = affinity.ok_or(KError::InvalidGlobalThreadId)?;
-            let pid = kcb.current_pid()?;
 
-            let gtid = nr::KernelNode::allocate_core_to_process(
+                nr::KernelNode::allocate_core_to_process(
+                    pid,
+                    VAddr::from(entry_point),
+                    Some(affinity),
+                    Some(gtid),
+                )?
+            };
+            // Only names the core for now, it can't be handed on
+            let handle = nrproc::NrProcess::<Ring3Process>::insert_capability(
                 pid,
-                VAddr::from(entry_point),
-                Some(affinity),
-                Some(gtid),
+                Capability::new(Object::Core(gtid), CapRights::empty()),
             )?;
 
-            Ok((arg2, 0))
+            Ok((gtid as u64, handle))
+        }
+        ProcessOperation::GetLayout => {
+            let kcb = super::kcb::get_kcb();
+            let pid = kcb.current_pid()?;
+
+            let layout = nrproc::NrProcess::<Ring3Process>::pinfo(pid)?.layout;
+            UserPtr::<AddressLayout>::new(arg2)?.write(layout)?;
+            Ok((0, 0))
+        }
+        ProcessOperation::RestrictSyscalls => {
+            let kcb = super::kcb::get_kcb();
+            let pid = kcb.current_pid()?;
+
+            let filter = UserPtr::<SyscallFilter>::new(arg2)?.read()?;
+            syscall_filter::restrict(pid, &filter);
+            Ok((0, 0))
         }
         ProcessOperation::AllocatePhysical => {
             let page_size: usize = arg2.try_into().unwrap_or(0);
@@ -224,6 +883,7 @@ fn handle_process(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
                 (0, 1)
             };
             crate::memory::KernelAllocator::try_refill_tcache(bp, lp)?;
+            crate::fault::allocation()?;
 
             // Allocate the page (need to make sure we drop pamanager again
             // before we go to NR):
@@ -238,17 +898,90 @@ fn handle_process(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
 
             // Associate memory with the process
             let pid = kcb.current_pid()?;
-            let fid = nrproc::NrProcess::<Ring3Process>::allocate_frame_to_process(pid, frame)?;
+            let fid = nrproc::NrProcess::<Ring3Process>::allocate_frame_to_process(pid, frame)
+                .map_err(|e| {
+                    if let Err(e) = crate::memory::release_frame(frame) {
+                        warn!("Leaking {:?}: {}", frame, e);
+                    }
+                    e
+                })?;
+            let handle = nrproc::NrProcess::<Ring3Process>::insert_capability(
+                pid,
+                Capability::new(Object::Frame(fid), CapRights::all()),
+            )?;
+
+            Ok((handle, frame.base.as_u64()))
+        }
+        ProcessOperation::ReleaseCore => {
+            let kcb = super::kcb::get_kcb();
+            let pid = kcb.current_pid()?;
+            let gtid = kcb.arch.id();
+
+            nr::KernelNode::release_core_from_process(pid, gtid)?;
+            nrproc::NrProcess::<Ring3Process>::release_executor(pid, gtid)?;
+            nrproc::NrProcess::<Ring3Process>::remove_capabilities(pid, Object::Core(gtid))?;
+            // TODO(hotplug): The executor should go back to the process
+            let _executor = kcb.arch.take_current_executor();
+
+            if super::hotplug::pending() {
+                super::hotplug::park()
+            } else {
+                crate::scheduler::schedule()
+            }
+        }
+        ProcessOperation::FutexWait => {
+            let vaddr = arg2;
+            let expected = arg3 as u32;
+            // u64::MAX waits forever
+            let timeout = if arg4 == u64::MAX {
+                None
+            } else {
+                Some(core::time::Duration::from_nanos(arg4))
+            };
+
+            super::futex::wait(vaddr, expected, timeout)
+        }
+        ProcessOperation::FutexWake => {
+            let vaddr = arg2;
+            let count = arg3;
+
+            super::futex::wake(vaddr, count)
+        }
+        ProcessOperation::Checkpoint => {
+            let kcb = super::kcb::get_kcb();
+            let pid = kcb.current_pid()?;
+
+            // The restored process returns 1 (see `checkpoint::registers`)
+            super::checkpoint::take(pid, userptr_to_str(arg2)?)?;
+            Ok((0, 0))
+        }
+        ProcessOperation::Restore => {
+            let image = arg2;
+            let len = arg3 as usize;
 
-            Ok((fid as u64, frame.base.as_u64()))
+            let pid = super::checkpoint::restore(image, len)?;
+            Ok((pid as u64, 0))
         }
         ProcessOperation::SubscribeEvent => Err(KError::InvalidProcessOperation { a: arg1 }),
         ProcessOperation::Unknown => Err(KError::InvalidProcessOperation { a: arg1 }),
     }
 }
 
+/// The `MapAction` for user memory with `rights` (see `MemoryRights`).
+fn user_map_action(rights: u64) -> Result<MapAction, KError> {
+    let rights = MemoryRights::from_bits(rights).ok_or(KError::InvalidMemoryRights)?;
+    let writeable = rights.contains(MemoryRights::WRITE);
+    let executable = rights.contains(MemoryRights::EXECUTE);
+    Ok(match (writeable, executable) {
+        (false, false) => MapAction::ReadUser,
+        (true, false) => MapAction::ReadWriteUser,
+        (false, true) => MapAction::ReadExecuteUser,
+        (true, true) => MapAction::ReadWriteExecuteUser,
+    })
+}
+
 /// System call handler for vspace operations
-fn handle_vspace(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError> {
+fn handle_vspace(arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> Result<(u64, u64), KError> {
     let op = VSpaceOperation::from(arg1);
     let base = VAddr::from(arg2);
     let region_size = arg3;
@@ -258,10 +991,25 @@ fn handle_vspace(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
     let mut p = kcb.arch.current_executor()?;
 
     match op {
-        VSpaceOperation::Map => unsafe {
+        VSpaceOperation::Map | VSpaceOperation::MapAnywhere => unsafe {
+            // We pick the address for `MapAnywhere` (in the mapping region)
+            let base = if op == VSpaceOperation::MapAnywhere {
+                nrproc::NrProcess::<Ring3Process>::reserve(p.pid, region_size as usize)?
+            } else {
+                base
+            };
+
+            // Evicted pages are still there as far as the process knows
+            let end = base.as_u64().saturating_add(region_size);
+            super::swap::check_evicted(p.pid, base.as_u64()..end)?;
+
             let (bp, lp) = crate::memory::size_to_pages(region_size as usize);
             let mut frames = Vec::try_with_capacity(bp + lp)?;
-            crate::memory::KernelAllocator::try_refill_tcache(20 + bp, lp)?;
+            if crate::memory::KernelAllocator::try_refill_tcache(20 + bp, lp).is_err() {
+                // Make room by evicting pages nobody used lately
+                super::swap::reclaim(core::cmp::max(bp, super::swap::RECLAIM_BATCH));
+                crate::memory::KernelAllocator::try_refill_tcache(20 + bp, lp)?;
+            }
 
             // TODO(apihell): This `paddr` is bogus, it will return the PAddr of the
             // first frame mapped but if you map multiple Frames, no chance getting that
@@ -273,23 +1021,30 @@ fn handle_vspace(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
             {
                 let mut pmanager = kcb.mem_manager();
 
-                for _i in 0..lp {
-                    let mut frame = pmanager
-                        .allocate_large_page()
-                        .expect("We refilled so allocation should work.");
-                    total_len += frame.size;
-                    unsafe { frame.zero() };
-                    frames
-                        .try_push(frame)
-                        .expect("Can't fail see `try_with_capacity`");
-                    if paddr.is_none() {
-                        paddr = Some(frame.base);
-                    }
-                }
-                for _i in 0..bp {
-                    let mut frame = pmanager
-                        .allocate_base_page()
-                        .expect("We refilled so allocation should work.");
+                let sizes = iter::repeat(LARGE_PAGE_SIZE)
+                    .take(lp)
+                    .chain(iter::repeat(BASE_PAGE_SIZE).take(bp));
+                for size in sizes {
+                    // We refilled, but we might still not get it (or not be
+                    // supposed to get it, see `fault`)
+                    let frame = crate::fault::allocation().and_then(|_| {
+                        if size == LARGE_PAGE_SIZE {
+                            pmanager.allocate_large_page()
+                        } else {
+                            pmanager.allocate_base_page()
+                        }
+                    });
+                    let mut frame = match frame {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            for frame in frames {
+                                if let Err(e) = crate::memory::release_frame(frame) {
+                                    warn!("Leaking {:?}: {}", frame, e);
+                                }
+                            }
+                            return Err(e);
+                        }
+                    };
                     total_len += frame.size;
                     unsafe { frame.zero() };
                     frames
@@ -306,10 +1061,17 @@ fn handle_vspace(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
                 base,
                 frames,
                 MapAction::ReadWriteUser,
-            )
-            .expect("Can't map memory");
+            )?;
+            // Only the base pages (after the large ones) can be evicted
+            let small = base.as_u64() + (lp * LARGE_PAGE_SIZE) as u64;
+            super::swap::anonymous(p.pid, small..small + (bp * BASE_PAGE_SIZE) as u64)?;
 
-            Ok((paddr.unwrap().as_u64(), total_len as u64))
+            let paddr = paddr.unwrap().as_u64();
+            if op == VSpaceOperation::MapAnywhere {
+                Ok((base.as_u64(), paddr))
+            } else {
+                Ok((paddr, total_len as u64))
+            }
         },
         VSpaceOperation::MapDevice => unsafe {
             let paddr = PAddr::from(base.as_u64());
@@ -325,28 +1087,98 @@ fn handle_vspace(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
         },
         VSpaceOperation::MapFrame => unsafe {
             let base = VAddr::from(arg2);
-            let frame_id: FrameId = arg3.try_into().map_err(|_e| KError::InvalidFrameId)?;
+            let capability = nrproc::NrProcess::<Ring3Process>::capability(p.pid, arg3)?;
+            let frame_id: FrameId = capability.frame(CapRights::MAP)?;
+            let action = if capability.rights.contains(CapRights::WRITE) {
+                MapAction::ReadWriteUser
+            } else {
+                MapAction::ReadUser
+            };
 
-            let (paddr, size) = nrproc::NrProcess::<Ring3Process>::map_frame_id(
-                p.pid,
-                frame_id,
-                base,
-                MapAction::ReadWriteUser,
-            )?;
+            let (paddr, size) =
+                nrproc::NrProcess::<Ring3Process>::map_frame_id(p.pid, frame_id, base, action)?;
+            // Whoever else has the frame writes to it behind our back
+            for offset in (0..size as usize).step_by(BASE_PAGE_SIZE) {
+                nrproc::NrProcess::<Ring3Process>::set_dirty(p.pid, base + offset)?;
+            }
             Ok((paddr.as_u64(), size as u64))
         },
         VSpaceOperation::Unmap => {
-            let handle = nrproc::NrProcess::<Ring3Process>::unmap(p.pid, base)?;
+            let (handle, generation) = {
+                // Nobody can pin the mapping while we unmap it
+                let _pins = user_access::exclude_pins(p.pid, base.as_u64());
+                // Nor evict it (evictable mappings are base pages)
+                let page = base.as_u64() & !(BASE_PAGE_SIZE as u64 - 1);
+                super::swap::unmapped(p.pid, page..page + BASE_PAGE_SIZE as u64)?;
+                match nrproc::NrProcess::<Ring3Process>::unmap(p.pid, base) {
+                    // An evicted page has no translations to flush
+                    Err(KError::NotMapped) if super::swap::forget(p.pid, page)? => {
+                        return Ok((page, BASE_PAGE_SIZE as u64));
+                    }
+                    // Unless it came back while we looked
+                    Err(KError::NotMapped) => {
+                        nrproc::NrProcess::<Ring3Process>::unmap(p.pid, base)?
+                    }
+                    unmapped => unmapped?,
+                }
+            };
             let va: u64 = handle.vaddr.as_u64();
             let sz: u64 = handle.frame.size as u64;
-            super::tlb::shootdown(handle);
+            super::tlb::unmapped(p.pid, handle, generation);
 
             Ok((va, sz))
         }
+        VSpaceOperation::Protect => {
+            let action = user_map_action(arg4)?;
+            let end = base + region_size as usize;
+            if end.as_u64() > kpi::KERNEL_BASE {
+                return Err(KError::BadAddress);
+            }
+
+            // One mapping at a time, each one needs its own shootdown
+            let mut vaddr = base;
+            while vaddr < end {
+                let adjusted = {
+                    let _pins = user_access::exclude_pins(p.pid, vaddr.as_u64());
+                    nrproc::NrProcess::<Ring3Process>::adjust(p.pid, vaddr, action)
+                };
+                let handle = match adjusted {
+                    // Evicted pages get the new rights once they're back
+                    Err(KError::NotMapped) if super::swap::fault_in(p.pid, vaddr.as_u64())? => {
+                        continue
+                    }
+                    adjusted => adjusted?,
+                };
+                vaddr = handle.vaddr + handle.frame.size;
+                super::tlb::shootdown(handle);
+            }
+
+            Ok((base.as_u64(), region_size))
+        }
         VSpaceOperation::Identify => unsafe {
             trace!("Identify base {:#x}.", base);
-            nrproc::NrProcess::<Ring3Process>::resolve(p.pid, base)
+            // The process may hand the address to a device, so it has to stay
+            super::swap::pin(p.pid, base.as_u64(), 1)?;
+            let resolved = nrproc::NrProcess::<Ring3Process>::resolve(p.pid, base)?;
+            // A device writes to it without the page-table knowing
+            nrproc::NrProcess::<Ring3Process>::set_dirty(p.pid, base)?;
+            Ok(resolved)
         },
+        VSpaceOperation::RegisterFaultRegion => {
+            super::userfault::register(p.pid, base.as_u64(), region_size)
+        }
+        VSpaceOperation::UnregisterFaultRegion => {
+            super::userfault::unregister(p.pid, base.as_u64())
+        }
+        VSpaceOperation::PageOut => {
+            let end = base
+                .as_u64()
+                .checked_add(region_size)
+                .filter(|end| *end <= kpi::KERNEL_BASE)
+                .ok_or(KError::BadAddress)?;
+            let evicted = super::swap::page_out(p.pid, base.as_u64()..end)?;
+            Ok((evicted, region_size))
+        }
         VSpaceOperation::Unknown => {
             error!("Got an invalid VSpaceOperation code.");
             Err(KError::InvalidVSpaceOperation { a: arg1 })
@@ -354,6 +1186,17 @@ fn handle_vspace(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
     }
 }
 
+/// The file descriptor of the file capability `handle`, if it has the
+/// rights for `op`.
+fn file_capability(pid: Pid, handle: u64, op: FileOperation) -> Result<u64, KError> {
+    let rights = match op {
+        FileOperation::Read | FileOperation::ReadAt => CapRights::READ,
+        FileOperation::Write | FileOperation::WriteAt => CapRights::WRITE,
+        _ => CapRights::empty(),
+    };
+    nrproc::NrProcess::<Ring3Process>::capability(pid, handle)?.file(rights)
+}
+
 /// System call handler for file operations
 fn handle_fileio(
     arg1: u64,
@@ -375,41 +1218,49 @@ fn handle_fileio(
             let pathname = arg2;
             let flags = arg3;
             let modes = arg4;
-            let _r = user_virt_addr_valid(pid, pathname, 0)?;
-            cnrfs::MlnrKernelNode::map_fd(pid, pathname, flags, modes)
+            procfs::refresh(&userptr_to_str(pathname)?)?;
+            let (fd, _) = cnrfs::MlnrKernelNode::map_fd(pid, pathname, flags, modes)?;
+
+            let flags = FileFlags::from(flags);
+            let mut rights = CapRights::GRANT;
+            rights.set(CapRights::READ, flags.is_read());
+            rights.set(CapRights::WRITE, flags.is_write());
+            let capability = Capability::new(Object::File(fd), rights);
+            match nrproc::NrProcess::<Ring3Process>::insert_capability(pid, capability) {
+                Ok(handle) => Ok((handle, 0)),
+                Err(e) => {
+                    let _r = cnrfs::MlnrKernelNode::unmap_fd(pid, fd);
+                    Err(e)
+                }
+            }
         }
         FileOperation::Read | FileOperation::Write => {
-            let fd = arg2;
+            let fd = file_capability(pid, arg2, op)?;
             let buffer = arg3;
             let len = arg4;
-
-            let _r = user_virt_addr_valid(pid, buffer, len)?;
             cnrfs::MlnrKernelNode::file_io(op, pid, fd, buffer, len, -1)
         }
         FileOperation::ReadAt | FileOperation::WriteAt => {
-            let fd = arg2;
+            let fd = file_capability(pid, arg2, op)?;
             let buffer = arg3;
             let len = arg4;
             let offset = arg5 as i64;
-
-            let _r = user_virt_addr_valid(pid, buffer, len)?;
             cnrfs::MlnrKernelNode::file_io(op, pid, fd, buffer, len, offset)
         }
         FileOperation::Close => {
-            let fd = arg2;
-            cnrfs::MlnrKernelNode::unmap_fd(pid, fd)
+            let handle = arg2;
+            let _fd = file_capability(pid, handle, op)?;
+            let capability = nrproc::NrProcess::<Ring3Process>::remove_capability(pid, handle)?;
+            cnrfs::MlnrKernelNode::unmap_fd(pid, capability.file(CapRights::empty())?)
         }
         FileOperation::GetInfo => {
             let name = arg2;
             let info_ptr = arg3;
-
-            let _r = user_virt_addr_valid(pid, name, 0)?;
+            procfs::refresh(&userptr_to_str(name)?)?;
             cnrfs::MlnrKernelNode::file_info(pid, name, info_ptr)
         }
         FileOperation::Delete => {
             let name = arg2;
-
-            let _r = user_virt_addr_valid(pid, name, 0)?;
             cnrfs::MlnrKernelNode::file_delete(pid, name)
         }
         FileOperation::WriteDirect => {
@@ -419,7 +1270,7 @@ fn handle_fileio(
                 offset = 0;
             }
 
-            let mut kernslice = crate::process::KernSlice::new(arg2, len as usize);
+            let mut kernslice = crate::process::KernSlice::new(arg2, len as usize)?;
             let mut buffer = unsafe { Arc::get_mut_unchecked(&mut kernslice.buffer) };
             let cnrfs = super::kcb::get_kcb().arch.cnrfs.as_ref().unwrap();
 
@@ -431,18 +1282,18 @@ fn handle_fileio(
             let oldname = arg2;
             let newname = arg3;
 
-            let _r = user_virt_addr_valid(pid, oldname, 0)?;
-            let _r = user_virt_addr_valid(pid, newname, 0)?;
-
             cnrfs::MlnrKernelNode::file_rename(pid, oldname, newname)
         }
         FileOperation::MkDir => {
             let pathname = arg2;
             let modes = arg3;
-            let _r = user_virt_addr_valid(pid, pathname, 0)?;
 
             cnrfs::MlnrKernelNode::mkdir(pid, pathname, modes)
         }
+        FileOperation::FSync => {
+            let fd = file_capability(pid, arg2, op)?;
+            cnrfs::MlnrKernelNode::file_sync(pid, fd)
+        }
         FileOperation::Unknown => {
             unreachable!("FileOperation not allowed");
             Err(KError::NotSupported)
@@ -450,30 +1301,317 @@ fn handle_fileio(
     }
 }
 
-/// TODO: This method makes file-operations slow, improve it to use large page
-/// sizes. Or maintain a list of (low, high) memory limits per process and check
-/// if (base, size) are within the process memory limits.
-fn user_virt_addr_valid(pid: Pid, base: u64, size: u64) -> Result<(u64, u64), KError> {
-    let mut base = base;
-    let upper_addr = base + size;
-
-    if upper_addr < KERNEL_BASE {
-        while base <= upper_addr {
-            // Validate addresses for the buffer end.
-            if upper_addr - base <= BASE_PAGE_SIZE as u64 {
-                let _r = nrproc::NrProcess::<Ring3Process>::resolve(pid, VAddr::from(base))?;
-                return nrproc::NrProcess::<Ring3Process>::resolve(
-                    pid,
-                    VAddr::from(upper_addr - 1),
-                );
+/// System call handler for network operations
+#[cfg(feature = "smoltcp")]
+fn handle_network(
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+) -> Result<(u64, u64), KError> {
+    use core::convert::TryFrom;
+    use core::time::Duration;
+
+    use kpi::net::{SocketAddr, SocketType};
+
+    use super::process::UserSlice;
+    use crate::net::socket;
+
+    let op = NetworkOperation::from(arg1);
+
+    let kcb = super::kcb::get_kcb();
+    let pid = kcb.arch.current_pid()?;
+
+    match op {
+        NetworkOperation::Socket => {
+            let ty = SocketType::try_from(arg2)
+                .map_err(|_e| KError::InvalidSyscallArgument1 { a: arg2 })?;
+            let fd = socket::socket(pid, ty)?;
+            Ok((fd, 0))
+        }
+        NetworkOperation::Bind => {
+            let port: u16 = arg3
+                .try_into()
+                .map_err(|_e| KError::InvalidSyscallArgument1 { a: arg3 })?;
+            socket::bind(pid, arg2, port)?;
+            Ok((0, 0))
+        }
+        NetworkOperation::Connect => {
+            socket::connect(pid, arg2, SocketAddr::from_u64(arg3))?;
+            Ok((0, 0))
+        }
+        NetworkOperation::Send => {
+            let fd = arg2;
+            let buffer = arg3;
+            let len = arg4;
+            let user_slice = UserSlice::new(buffer, len as usize, UserAccess::Read)?;
+            let sent = socket::send(pid, fd, &user_slice, SocketAddr::from_u64(arg5))?;
+            Ok((sent as u64, 0))
+        }
+        NetworkOperation::Recv => {
+            let fd = arg2;
+            let buffer = arg3;
+            let len = arg4;
+            let (received, from) = {
+                let mut user_slice = UserSlice::new(buffer, len as usize, UserAccess::Write)?;
+                socket::recv(pid, fd, &mut user_slice)?
+            };
+            if arg5 != 0 {
+                UserPtr::<u64>::new(arg5)?.write(from.as_u64())?;
             }
+            Ok((received as u64, 0))
+        }
+        NetworkOperation::Close => {
+            socket::close(pid, arg2)?;
+            Ok((0, 0))
+        }
+        NetworkOperation::Poll => poll(pid, arg2, arg3 as usize, arg4),
+        NetworkOperation::XdpAttach => {
+            let rings = arg2;
+            let umem = arg3;
+            let umem_len = arg4;
+            let port: u16 = arg5
+                .try_into()
+                .map_err(|_e| KError::InvalidSyscallArgument1 { a: arg5 })?;
+
+            let rings_len = core::mem::size_of::<kpi::net::XdpRings>() as u64;
+            let rings_paddr = user_contiguous_paddr(pid, rings, rings_len)?;
+            let umem_paddr = user_contiguous_paddr(pid, umem, umem_len)?;
+
+            // Safe: We checked that both regions are mapped and physically contiguous
+            let fd = unsafe {
+                crate::net::xdp::attach(pid, rings_paddr, umem_paddr, umem_len as usize, port)?
+            };
+            Ok((fd, 0))
+        }
+        NetworkOperation::XdpKick => {
+            crate::net::xdp::kick(pid, arg2)?;
+            Ok((0, 0))
+        }
+        NetworkOperation::Ping => {
+            let addr = smoltcp::wire::Ipv4Address((arg2 as u32).to_be_bytes());
+            let rtt = crate::net::icmp::ping(addr, Duration::from_micros(arg3))?;
+            Ok((rtt.as_micros() as u64, 0))
+        }
+        NetworkOperation::VsockConnect
+        | NetworkOperation::VsockListen
+        | NetworkOperation::VsockAccept
+        | NetworkOperation::VsockSend
+        | NetworkOperation::VsockRecv
+        | NetworkOperation::VsockClose
+        | NetworkOperation::VsockLocalCid => handle_vsock(pid, op, arg2, arg3, arg4),
+        NetworkOperation::Unknown => Err(KError::InvalidNetworkOperation { a: arg1 }),
+    }
+}
+
+/// System call handler for network operations
+#[cfg(not(feature = "smoltcp"))]
+fn handle_network(
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    _arg5: u64,
+) -> Result<(u64, u64), KError> {
+    let op = NetworkOperation::from(arg1);
+    let pid = super::kcb::get_kcb().current_pid()?;
+    match op {
+        // Events and vsock work without sockets
+        NetworkOperation::Poll => poll(pid, arg2, arg3 as usize, arg4),
+        NetworkOperation::VsockConnect
+        | NetworkOperation::VsockListen
+        | NetworkOperation::VsockAccept
+        | NetworkOperation::VsockSend
+        | NetworkOperation::VsockRecv
+        | NetworkOperation::VsockClose
+        | NetworkOperation::VsockLocalCid => handle_vsock(pid, op, arg2, arg3, arg4),
+        NetworkOperation::Unknown => Err(KError::InvalidNetworkOperation { a: arg1 }),
+        _ => Err(KError::NetStackUnavailable),
+    }
+}
 
-            let _r = nrproc::NrProcess::<Ring3Process>::resolve(pid, VAddr::from(base))?;
-            base += BASE_PAGE_SIZE as u64;
+/// Handles the vsock network operations (`net::vsock`).
+fn handle_vsock(
+    pid: Pid,
+    op: NetworkOperation,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+) -> Result<(u64, u64), KError> {
+    use kpi::net::VsockAddr;
+
+    use super::process::UserSlice;
+    use crate::net::vsock;
+
+    match op {
+        NetworkOperation::VsockConnect => {
+            let fd = vsock::connect(pid, VsockAddr::from_u64(arg2))?;
+            Ok((fd, 0))
+        }
+        NetworkOperation::VsockListen => {
+            let port: u32 = arg2
+                .try_into()
+                .map_err(|_e| KError::InvalidSyscallArgument1 { a: arg2 })?;
+            let fd = vsock::listen(pid, port)?;
+            Ok((fd, 0))
+        }
+        NetworkOperation::VsockAccept => {
+            let (fd, peer) = vsock::accept(pid, arg2)?;
+            if arg3 != 0 {
+                UserPtr::<u64>::new(arg3)?.write(peer.as_u64())?;
+            }
+            Ok((fd, 0))
+        }
+        NetworkOperation::VsockSend => {
+            let user_slice = UserSlice::new(arg3, arg4 as usize, UserAccess::Read)?;
+            let sent = vsock::send(pid, arg2, &user_slice)?;
+            Ok((sent as u64, 0))
         }
-        return Ok((base, size));
+        NetworkOperation::VsockRecv => {
+            let mut user_slice = UserSlice::new(arg3, arg4 as usize, UserAccess::Write)?;
+            let received = vsock::recv(pid, arg2, &mut user_slice)?;
+            Ok((received as u64, 0))
+        }
+        NetworkOperation::VsockClose => {
+            vsock::close(pid, arg2)?;
+            Ok((0, 0))
+        }
+        NetworkOperation::VsockLocalCid => Ok((vsock::local_cid()?, 0)),
+        _ => unreachable!("Not a vsock operation"),
     }
-    Err(KError::BadAddress)
+}
+
+/// Fills in `revents` of the `nfds` `PollFd`s at `fds`, returns how many
+/// are ready.
+///
+/// If none are, the core waits until `wake_pollers` kicks it or `timeout`
+/// (in ns, 0 doesn't wait, `u64::MAX` forever) passed and returns 0. The
+/// caller has to poll again to find out what is ready.
+fn poll(pid: Pid, fds: u64, nfds: usize, timeout: u64) -> Result<(u64, u64), KError> {
+    if nfds > MAX_POLL_FDS {
+        return Err(KError::InvalidLength);
+    }
+    let size = core::mem::size_of::<PollFd>();
+    let len = nfds.checked_mul(size).ok_or(KError::InvalidLength)?;
+    fds.checked_add(len as u64).ok_or(KError::BadAddress)?;
+
+    // Fails for a misaligned array
+    let entry = |i: usize| UserPtr::<PollFd>::new(fds + (i * size) as u64);
+
+    // Work on a copy, user-space can change the array under our feet
+    let mut poll_fds: Vec<PollFd> = Vec::try_with_capacity(nfds)?;
+    for i in 0..nfds {
+        poll_fds.push(entry(i)?.read()?);
+    }
+
+    // Before we check, so a socket that becomes ready right after still
+    // wakes us up
+    let core = super::kcb::get_kcb().arch.id();
+    if timeout != 0 {
+        super::futex::add_poller(core);
+    }
+    let ready = poll_fds_ready(pid, &mut poll_fds).and_then(|ready| {
+        for (i, pfd) in poll_fds.iter().enumerate() {
+            entry(i)?.write(*pfd)?;
+        }
+        Ok(ready)
+    });
+    if timeout == 0 || !matches!(ready, Ok(0)) {
+        super::futex::remove_poller(core);
+    }
+    let ready = ready?;
+    if ready > 0 || timeout == 0 {
+        return Ok((ready as u64, 0));
+    }
+
+    // u64::MAX waits forever
+    let timeout = if timeout == u64::MAX {
+        None
+    } else {
+        Some(core::time::Duration::from_nanos(timeout))
+    };
+    // Events get kicked, sockets only if someone else polls the devices
+    let timeout = if poll_fds.iter().all(|pfd| pfd.fd & POLL_EVENT_HANDLE != 0) {
+        timeout
+    } else {
+        Some(timeout.map_or(NET_POLL_INTERVAL, |t| t.min(NET_POLL_INTERVAL)))
+    };
+    super::futex::park(None, timeout)
+}
+
+/// Fills in `revents` of `poll_fds`, returns how many are ready.
+fn poll_fds_ready(pid: Pid, poll_fds: &mut [PollFd]) -> Result<usize, KError> {
+    let mut ready = poll_events(pid, poll_fds);
+    if poll_fds
+        .iter()
+        .any(|pfd| crate::net::vsock::is_vsock(pfd.fd))
+    {
+        ready += crate::net::vsock::poll(pid, poll_fds);
+    }
+    if poll_fds
+        .iter()
+        .any(|pfd| pfd.fd & (POLL_EVENT_HANDLE | VSOCK_FD) == 0)
+    {
+        #[cfg(feature = "smoltcp")]
+        {
+            ready += crate::net::socket::poll(pid, poll_fds)?;
+        }
+        #[cfg(not(feature = "smoltcp"))]
+        return Err(KError::NetStackUnavailable);
+    }
+    Ok(ready)
+}
+
+/// Fills in `revents` for the event handles in `fds` (see `PollFd::event`),
+/// returns how many are ready.
+fn poll_events(pid: Pid, fds: &mut [PollFd]) -> usize {
+    let mut ready = 0;
+    for pfd in fds.iter_mut().filter(|pfd| pfd.fd & POLL_EVENT_HANDLE != 0) {
+        let requested = PollEvents::from_bits_truncate(pfd.events);
+        let mut events = PollEvents::empty();
+
+        let handle = pfd.fd & !POLL_EVENT_HANDLE;
+        let readiness = nrproc::NrProcess::<Ring3Process>::capability(pid, handle)
+            .and_then(|c| c.event(CapRights::READ))
+            .and_then(|event| event::EVENTS.lock().readiness(event));
+        match readiness {
+            Ok((readable, writable)) => {
+                events.set(PollEvents::POLLIN, readable);
+                events.set(PollEvents::POLLOUT, writable);
+            }
+            Err(_e) => events.insert(PollEvents::POLLERR),
+        }
+
+        let revents = events & (requested | PollEvents::POLLERR);
+        pfd.revents = revents.bits();
+        if !revents.is_empty() {
+            ready += 1;
+        }
+    }
+    ready
+}
+
+/// Returns the physical address of the user buffer at `base` with length `size`
+/// if the buffer is mapped and physically contiguous.
+fn user_contiguous_paddr(pid: Pid, base: u64, size: u64) -> Result<PAddr, KError> {
+    if size == 0 || base % BASE_PAGE_SIZE as u64 != 0 {
+        return Err(KError::InvalidBase);
+    }
+    // The device uses the physical addresses, the pages have to stay
+    super::swap::pin(pid, base, size)?;
+    user_access::validate(pid, base, size, UserAccess::Write)?;
+
+    let (start, _) = nrproc::NrProcess::<Ring3Process>::resolve(pid, VAddr::from(base))?;
+    for offset in (0..size).step_by(BASE_PAGE_SIZE) {
+        let (paddr, _) =
+            nrproc::NrProcess::<Ring3Process>::resolve(pid, VAddr::from(base + offset))?;
+        if paddr != start + offset {
+            return Err(KError::InvalidFrame);
+        }
+    }
+
+    Ok(PAddr::from(start))
 }
 
 #[allow(unused)]
@@ -521,6 +1659,48 @@ fn debug_print_syscall(function: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64
                 arg5
             );
         }
+        SystemCall::Network => {
+            sprintln!(
+                " {:?} {} {} {} {}",
+                NetworkOperation::from(arg1),
+                arg2,
+                arg3,
+                arg4,
+                arg5
+            );
+        }
+        SystemCall::Time => {
+            sprintln!(" {:?}", TimeOperation::from(arg1));
+        }
+        SystemCall::Debug => {
+            sprintln!(" {:?}", DebugOperation::from(arg1));
+        }
+        SystemCall::Perf => {
+            sprintln!(" {:?} counter={}", PerfOperation::from(arg1), arg2);
+        }
+        SystemCall::Capability => {
+            sprintln!(" {:?} {} {} {}", CapOperation::from(arg1), arg2, arg3, arg4);
+        }
+        SystemCall::Ipc => {
+            sprintln!(
+                " {:?} {} {} {} {}",
+                IpcOperation::from(arg1),
+                arg2,
+                arg3,
+                arg4,
+                arg5
+            );
+        }
+        SystemCall::Vm => {
+            sprintln!(
+                " {:?} {} {} {:#x} {:#x}",
+                VmOperation::from(arg1),
+                arg2,
+                arg3,
+                arg4,
+                arg5
+            );
+        }
         SystemCall::Unknown => unreachable!(),
     }
 }
@@ -535,16 +1715,42 @@ pub extern "C" fn syscall_handle(
     arg4: u64,
     arg5: u64,
 ) -> ! {
+    super::kcb::get_kcb().stats.syscall(function);
+    let start = x86::time::rdtsc();
+    if let Ok(pid) = super::kcb::get_kcb().current_pid() {
+        if !syscall_filter::allows(pid, function, arg1, arg3) {
+            syscall_denied(pid, function, arg1);
+        }
+    }
+
+    #[cfg(feature = "heap-tracking")]
+    crate::memory::track::set_tag(function);
+
     let status: Result<(u64, u64), KError> = match SystemCall::new(function) {
-        SystemCall::System => handle_system(arg1, arg2, arg3),
-        SystemCall::Process => handle_process(arg1, arg2, arg3),
-        SystemCall::VSpace => handle_vspace(arg1, arg2, arg3),
+        SystemCall::System => handle_system(arg1, arg2, arg3, arg4),
+        SystemCall::Process => handle_process(arg1, arg2, arg3, arg4),
+        SystemCall::VSpace => handle_vspace(arg1, arg2, arg3, arg4),
         SystemCall::FileIO => handle_fileio(arg1, arg2, arg3, arg4, arg5),
+        SystemCall::Network => handle_network(arg1, arg2, arg3, arg4, arg5),
+        SystemCall::Time => handle_time(arg1, arg2, arg3, arg4),
+        SystemCall::Debug => handle_debug(arg1, arg2, arg3, arg4),
+        SystemCall::Perf => handle_perf(arg1, arg2, arg3, arg4),
+        SystemCall::Capability => handle_capability(arg1, arg2, arg3, arg4),
+        SystemCall::Ipc => handle_ipc(arg1, arg2, arg3, arg4, arg5),
+        SystemCall::Vm => handle_vm(arg1, arg2, arg3, arg4, arg5),
         _ => Err(KError::InvalidSyscallArgument1 { a: function }),
     };
+    #[cfg(feature = "heap-tracking")]
+    crate::memory::track::set_tag(0);
 
     let r = {
         let kcb = super::kcb::get_kcb();
+        let end = x86::time::rdtsc();
+        crate::latency::record(kcb.arch.id(), function, arg1, end - start);
+        if crate::trace::enabled() && kcb.current_pid().map_or(false, crate::trace::traced) {
+            let args = [arg1, arg2, arg3, arg4, arg5];
+            crate::trace::record(function, args, &status, start, end);
+        }
 
         let _retcode = match status {
             Ok((a1, a2)) => {
@@ -555,19 +1761,80 @@ pub extern "C" fn syscall_handle(
                 });
             }
             Err(status) => {
-                error!("System call returned with error: {:?}", status);
+                // Non-blocking sockets hit this a lot, not worth an error:
+                if status != KError::WouldBlock {
+                    error!("System call returned with error: {:?}", status);
+                }
                 kcb.arch.save_area.as_mut().map(|sa| {
                     sa.set_syscall_error_code(status.into());
                 });
             }
         };
 
+        if crate::fault::inject(crate::fault::Fault::Interrupt) {
+            // Arrives once we're back in user-space
+            super::tlb::send_spurious_ipi();
+        }
+
+        // Nothing the call read from RCU cells survives it
+        crate::rcu::quiescent();
+
         super::process::Ring3Resumer::new_restore(kcb.arch.get_save_area_ptr())
     };
 
     unsafe { r.resume() }
 }
 
+/// Fails with `NotPrivileged` unless the current process runs as root
+/// (grabbing and parking cores affects everyone on the machine).
+fn require_privileged() -> Result<(), KError> {
+    let pid = super::kcb::get_kcb().current_pid()?;
+    if nrproc::NrProcess::<Ring3Process>::credentials(pid)?.is_privileged() {
+        Ok(())
+    } else {
+        Err(KError::NotPrivileged)
+    }
+}
+
+/// Fails a system call the filter of `pid` doesn't allow (see
+/// `syscall_filter`) and tells the process with a
+/// `kpi::upcall::SYSCALL_DENIED` upcall, unless it can't take upcalls right
+/// now (e.g., the upcall handler made the call).
+fn syscall_denied(pid: Pid, function: u64, arg1: u64) -> ! {
+    let kcb = super::kcb::get_kcb();
+    warn!(
+        "Process {} isn't allowed to make system call {:?} ({})",
+        pid,
+        SystemCall::new(function),
+        arg1
+    );
+
+    let mut rip = 0;
+    kcb.arch.save_area.as_mut().map(|sa| {
+        sa.set_syscall_error_code(SystemCallError::PermissionError);
+        rip = sa.rip;
+    });
+
+    let upcall = kcb.arch.current_executor().ok().and_then(|p| {
+        // Safe: The kernel alias of the vcpu area is valid while the executor
+        // exists
+        let vcpu = unsafe { &mut *p.vcpu_kernel() };
+        if vcpu.upcalls_disabled(VAddr::from(rip)) {
+            return None;
+        }
+
+        vcpu.disable_upcalls();
+        kcb.arch.save_area.as_ref().map(|sa| {
+            vcpu.enabled_state = **sa;
+        });
+        let call = function << 32 | (arg1 & 0xffff_ffff);
+        Some(p.upcall(kpi::upcall::SYSCALL_DENIED, call))
+    });
+
+    let r = upcall.unwrap_or_else(|| Ring3Resumer::new_restore(kcb.arch.get_save_area_ptr()));
+    unsafe { r.resume() }
+}
+
 /// Enables syscall/sysret functionality.
 pub fn enable_fast_syscalls() {
     let cs_selector = GdtTable::kernel_cs_selector();
@@ -584,6 +1851,7 @@ pub fn enable_fast_syscalls() {
         wrmsr(IA32_LSTAR, rip);
         debug!("Set up fast syscalls. `sysenter` will jump to {:#x}.", rip);
 
+        // Clears everything else on entry, including AC (see `user_access`)
         wrmsr(
             IA32_FMASK,
             !(rflags::RFlags::FLAGS_IOPL3 | rflags::RFlags::FLAGS_A1).bits(),
@@ -2,21 +2,108 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 //! Timer API
+//!
+//! Uses the TSC-deadline mode of the local APIC timer if the processor
+//! supports it, otherwise the one-shot mode (with a frequency we measure
+//! against the current clocksource during boot).
+
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use log::info;
+use spin::Once;
 
 use super::kcb::get_kcb;
+use super::{tsc, watchdog};
+use crate::kcb::CoreLocalKey;
+use crate::time::clocksource::{self, duration_to_ticks};
 use apic::ApicDriver;
 
-/// Default when to raise the next timer irq (in rdtsc ticks)
-pub const DEFAULT_TIMER_DEADLINE: u64 = 2_000_000_000;
+/// Default when to raise the next timer irq
+pub const DEFAULT_TIMER_DEADLINE: Duration = Duration::from_secs(1);
 
-/// Register a periodic timer to advance replica
+/// How soon we try again to deliver an interval timer (`crate::itimer`) to
+/// a process that had upcalls disabled.
+pub const UPCALL_RETRY: Duration = Duration::from_micros(100);
+
+/// How long we measure the APIC timer against the clocksource.
+const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+
+/// Frequency of the APIC timer in Hz (if we don't use TSC-deadline mode).
+static APIC_TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// TSC value at which the timer of a core goes off next (see `next`),
+/// registered in `init` before the application cores start.
+static NEXT: Once<CoreLocalKey<AtomicU64>> = Once::new();
+
+/// The deadline of the current core in `NEXT` (`None` before `init`).
+fn next_deadline() -> Option<&'static AtomicU64> {
+    NEXT.get().and_then(CoreLocalKey::try_get)
+}
+
+/// Does the local APIC support TSC-deadline mode?
+fn has_tsc_deadline() -> bool {
+    unsafe { __cpuid(1) }.ecx & (1 << 24) != 0
+}
+
+/// Measures the APIC timer frequency unless we can use TSC-deadline mode.
 ///
-/// TODO(api): Ideally this should come from Instant::now() +
-/// Duration::from_millis(10) and for that we need a way to reliably
-/// convert between TSC and Instant
-pub fn set(deadline: u64) {
+/// Needs a clocksource, only call this on the BSP before the application
+/// cores start.
+pub fn init() {
+    NEXT.call_once(crate::kcb::register);
+
+    if has_tsc_deadline() {
+        info!("Using TSC-deadline timer");
+        return;
+    }
+
+    let source = match clocksource::current() {
+        Some(source) => source,
+        None => return,
+    };
+    let kcb = get_kcb();
+    let mut apic = kcb.arch.apic();
+
+    let ticks = duration_to_ticks(CALIBRATION_TIME, source.frequency());
+    let start = source.read();
+    apic.timer_oneshot(u32::MAX);
+    while source.read().wrapping_sub(start) & source.mask() < ticks {
+        core::hint::spin_loop();
+    }
+    let elapsed = u32::MAX - apic.timer_count();
+    apic.timer_oneshot(0);
+
+    let frequency = (elapsed as u128 * 1_000_000_000 / CALIBRATION_TIME.as_nanos()) as u64;
+    APIC_TIMER_FREQUENCY.store(frequency, Ordering::Relaxed);
+    info!("Using APIC timer at {} Hz", frequency);
+}
+
+/// Register a periodic timer to advance replica
+pub fn set(deadline: Duration) {
+    watchdog::arm(deadline);
+
     let kcb = get_kcb();
     let mut apic = kcb.arch.apic();
-    apic.tsc_enable();
-    unsafe { apic.tsc_set(x86::time::rdtsc() + deadline) };
+    let next =
+        unsafe { x86::time::rdtsc() }.saturating_add(duration_to_ticks(deadline, tsc::frequency()));
+    if let Some(next_deadline) = next_deadline() {
+        next_deadline.store(next, Ordering::Relaxed);
+    }
+
+    let apic_frequency = APIC_TIMER_FREQUENCY.load(Ordering::Relaxed);
+    if apic_frequency == 0 {
+        apic.tsc_enable();
+        unsafe { apic.tsc_set(next) };
+    } else {
+        let ticks = duration_to_ticks(deadline, apic_frequency);
+        apic.timer_oneshot(ticks.clamp(1, u32::MAX as u64) as u32);
+    }
+}
+
+/// TSC value at which the timer of the current core goes off next (0 if it
+/// was never set).
+pub fn next() -> u64 {
+    next_deadline().map_or(0, |next| next.load(Ordering::Relaxed))
 }
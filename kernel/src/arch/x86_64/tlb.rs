@@ -3,24 +3,26 @@
 
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::ops::Range;
-use core::sync::atomic::{AtomicBool, Ordering};
 
 use apic::ApicDriver;
 use bit_field::BitField;
 use crossbeam_queue::ArrayQueue;
 use fallible_collections::FallibleVecGlobal;
 use lazy_static::lazy_static;
-use log::trace;
+use log::{trace, warn};
 use x86::apic::{
     ApicId, DeliveryMode, DeliveryStatus, DestinationMode, DestinationShorthand, Icr, Level,
     TriggerMode,
 };
 
 use super::memory::BASE_PAGE_SIZE;
-use crate::kcb;
+use super::process::Ring3Process;
+use crate::kcb::{self, ArchSpecificKcb};
+use crate::memory::shootdown;
 use crate::memory::vspace::TlbFlushHandle;
-use crate::{cnrfs, is_page_aligned, nr};
+use crate::nrproc::NrProcess;
+use crate::process::Pid;
+use crate::{cnrfs, nr};
 
 // In the xAPIC mode, the Destination Format Register (DFR) through the MMIO
 // interface determines the choice of a flat logical mode or a clustered logical
@@ -54,62 +56,42 @@ lazy_static! {
     };
 }
 
+pub use crate::memory::shootdown::Shootdown;
+
 #[derive(Debug)]
 pub enum WorkItem {
     Shootdown(Arc<Shootdown>),
     AdvanceReplica(usize),
 }
 
-#[derive(Debug)]
-pub struct Shootdown {
-    vregion: Range<u64>,
-    ack: AtomicBool,
-}
-
-impl Shootdown {
-    /// Create a new shootdown request.
-    pub fn new(vregion: Range<u64>) -> Self {
-        debug_assert!(is_page_aligned!(vregion.start));
-        debug_assert!(is_page_aligned!(vregion.end));
-        Shootdown {
-            vregion,
-            ack: AtomicBool::new(false),
-        }
-    }
-
-    /// Acknowledge shootdown to sender/requestor core.
-    fn acknowledge(&self) {
-        self.ack.store(true, Ordering::Relaxed);
-    }
-
-    /// Check if receiver has acknowledged the shootdown.
-    pub fn is_acknowledged(&self) -> bool {
-        self.ack.load(Ordering::Relaxed)
-    }
-
-    /// Flush the TLB entries.
-    fn process(&self) {
-        // Safe to acknowledge first as we won't return/interrupt
-        // before this function completes:
-        self.acknowledge();
-
-        let it = self.vregion.clone().step_by(BASE_PAGE_SIZE);
-        if it.count() > 20 {
-            trace!("flush the entire TLB");
-            unsafe { x86::tlb::flush_all() };
-        } else {
-            let it = self.vregion.clone().step_by(BASE_PAGE_SIZE);
-            for va in it {
-                trace!("flushing TLB page {:#x}", va);
-                unsafe { x86::tlb::flush(va as usize) };
-            }
+/// Flushes the TLB entries of `s` (and acknowledges it).
+fn process(s: &Shootdown) {
+    // Safe to acknowledge first as we won't return/interrupt
+    // before this function completes:
+    s.acknowledge();
+
+    let it = s.vregion().step_by(BASE_PAGE_SIZE);
+    if it.count() > 20 {
+        trace!("flush the entire TLB");
+        unsafe { x86::tlb::flush_all() };
+    } else {
+        let it = s.vregion().step_by(BASE_PAGE_SIZE);
+        for va in it {
+            trace!("flushing TLB page {:#x}", va);
+            unsafe { x86::tlb::flush(va as usize) };
         }
     }
 }
 
 pub fn enqueue(gtid: atopology::GlobalThreadId, s: WorkItem) {
     trace!("TLB enqueue shootdown msg {:?}", s);
-    let _ignore = IPI_WORKQUEUE[gtid as usize].push(s);
+    // A full queue has IPIs on the way that empty it, dropping `s` would
+    // leave its sender waiting forever
+    let mut item = s;
+    while let Err(rejected) = IPI_WORKQUEUE[gtid as usize].push(item) {
+        item = rejected;
+        core::hint::spin_loop();
+    }
 }
 
 pub fn dequeue(gtid: atopology::GlobalThreadId) {
@@ -117,7 +99,8 @@ pub fn dequeue(gtid: atopology::GlobalThreadId) {
         Some(msg) => match msg {
             WorkItem::Shootdown(s) => {
                 trace!("TLB channel got msg {:?}", s);
-                s.process();
+                process(&s);
+                super::kcb::get_kcb().stats.tlb_shootdown_received();
             }
             WorkItem::AdvanceReplica(log_id) => advance_log(log_id),
         },
@@ -138,6 +121,7 @@ fn advance_log(log_id: usize) {
         Ok(_) => { /* Simply return */ }
         Err(e) => unreachable!("Error {:?} while advancing the log {}", e, log_id),
     }
+    super::replicas::synchronized();
 }
 
 pub fn eager_advance_fs_replica() {
@@ -188,6 +172,27 @@ pub fn send_ipi_to_apic(apic_id: ApicId) {
     unsafe { apic.send_ipi(icr) }
 }
 
+/// Sends a `TLB_WORK_PENDING` IPI to ourselves without queueing any work (to
+/// inject a spurious interrupt, see `fault`).
+pub fn send_spurious_ipi() {
+    let kcb = super::kcb::get_kcb();
+    let apic_id = atopology::MACHINE_TOPOLOGY.threads[kcb.arch.id()].apic_id();
+    let mut apic = kcb.arch.apic();
+
+    let icr = Icr::for_x2apic(
+        super::irq::TLB_WORK_PENDING,
+        apic_id,
+        DestinationShorthand::NoShorthand,
+        DeliveryMode::Fixed,
+        DestinationMode::Physical,
+        DeliveryStatus::Idle,
+        Level::Assert,
+        TriggerMode::Edge,
+    );
+
+    unsafe { apic.send_ipi(icr) }
+}
+
 fn send_ipi_multicast(ldr: u32) {
     let kcb = super::kcb::get_kcb();
     let mut apic = kcb.arch.apic();
@@ -207,12 +212,10 @@ fn send_ipi_multicast(ldr: u32) {
     unsafe { apic.send_ipi(icr) }
 }
 
-/// Runs the TLB shootdown protocol.
+/// Sends a `TLB_WORK_PENDING` IPI to all cores in `handle` (except us).
 ///
-/// Takes the `TlbFlushHandle` and figures out what cores it needs to send an IPI to.
 /// It divides IPIs into clusters to avoid overhead of sending IPIs individually.
-/// Finally, waits until all cores have acknowledged the IPI before it returns.
-pub fn shootdown(handle: TlbFlushHandle) {
+fn send_ipis(handle: &TlbFlushHandle) {
     let my_gtid = super::kcb::get_kcb().arch.id();
 
     // We support up to 16 IPI clusters, this will address `16*16 = 256` cores
@@ -238,11 +241,6 @@ pub fn shootdown(handle: TlbFlushHandle) {
         15 << 16,
     ];
 
-    let num_cores = atopology::MACHINE_TOPOLOGY.num_threads();
-    let mut shootdowns: Vec<Arc<Shootdown>> = Vec::try_with_capacity(num_cores)
-        .expect("TODO(error-handling): ideally: no possible failure during shootdown");
-    let range = handle.vaddr.as_u64()..(handle.vaddr + handle.frame.size).as_u64();
-
     for gtid in handle.cores() {
         if gtid != my_gtid {
             let apic_id = atopology::MACHINE_TOPOLOGY.threads[gtid].apic_id();
@@ -250,13 +248,39 @@ pub fn shootdown(handle: TlbFlushHandle) {
             let cluster = apic_id.x2apic_logical_cluster_id();
 
             trace!(
-                "Send shootdown to gtid:{} in cluster:{} cluster_addr:{}",
+                "Send IPI to gtid:{} in cluster:{} cluster_addr:{}",
                 gtid,
                 cluster,
                 cluster_addr
             );
             cluster_destination[cluster as usize].set_bit(cluster_addr as usize, true);
+        }
+    }
 
+    for cluster_ldr in cluster_destination {
+        // Do we need to send to anyone inside this cluster?
+        if cluster_ldr.get_bits(0..=3) != 0 {
+            trace!("send ipi multicast to {}", cluster_ldr);
+            crate::fault::delay_ipi();
+            send_ipi_multicast(cluster_ldr);
+        }
+    }
+}
+
+/// Runs the TLB shootdown protocol.
+///
+/// Takes the `TlbFlushHandle` and figures out what cores it needs to send an IPI to.
+/// Finally, waits until all cores have acknowledged the IPI before it returns.
+pub fn shootdown(handle: TlbFlushHandle) {
+    let my_gtid = super::kcb::get_kcb().arch.id();
+
+    let num_cores = atopology::MACHINE_TOPOLOGY.num_threads();
+    let mut shootdowns: Vec<Arc<Shootdown>> = Vec::try_with_capacity(num_cores)
+        .expect("TODO(error-handling): ideally: no possible failure during shootdown");
+    let range = handle.vaddr.as_u64()..(handle.vaddr + handle.frame.size).as_u64();
+
+    for gtid in handle.cores() {
+        if gtid != my_gtid {
             let shootdown = Arc::try_new(Shootdown::new(range.clone()))
                 .expect("TODO(error-handling): ideally: no possible failure during shootdown");
             enqueue(gtid, WorkItem::Shootdown(shootdown.clone()));
@@ -266,28 +290,81 @@ pub fn shootdown(handle: TlbFlushHandle) {
         }
     }
 
+    super::kcb::get_kcb()
+        .stats
+        .tlb_shootdowns_sent(shootdowns.len() as u64);
+
     // Notify the cores in all clusters of new work in the queue
-    for cluster_ldr in cluster_destination {
-        // Do we need to send to anyone inside this cluster?
-        if cluster_ldr.get_bits(0..=3) != 0 {
-            trace!("send ipi multicast to {}", cluster_ldr);
-            send_ipi_multicast(cluster_ldr);
-        }
-    }
+    send_ipis(&handle);
 
     // Finally, we also need to shootdown our own TLB
-    let shootdown = Shootdown::new(range);
-    shootdown.process();
+    process(&Shootdown::new(range));
 
     // Wait synchronously on cores to complete
-    while !shootdowns.is_empty() {
-        shootdowns.drain_filter(|s| s.is_acknowledged());
-        core::hint::spin_loop();
-    }
+    shootdown::wait(&mut shootdowns);
 
     trace!("done with all shootdowns");
 }
 
+/// Finishes an unmap of process `pid` (which brought it to unmap
+/// `generation`).
+///
+/// We wait until the cores that run the process flushed the old mapping
+/// (like `shootdown`): once `Unmap` returns the process may map another frame
+/// at the address, and a core that still has the old translation would
+/// write to the old frame instead. Cores that pick up the process later find
+/// the unmap in its log and flush then (`sync_translations`).
+pub fn unmapped(pid: Pid, handle: TlbFlushHandle, generation: u64) {
+    shootdown(handle);
+
+    // If we didn't miss an earlier unmap, our TLB is up to date now
+    let kcb = super::kcb::get_kcb();
+    if kcb.arch.tlb_generation == Some((pid, generation - 1)) {
+        kcb.arch.tlb_generation = Some((pid, generation));
+    }
+}
+
+/// Flushes the TLB of the current core if the process that runs on it
+/// unmapped memory since the last flush.
+///
+/// Asking the replica for the unmap generation applies the outstanding
+/// operations of the log first, so we see every unmap that returned before we
+/// got here. The kernel calls this before it uses a translation for user
+/// memory (`user_access::validate`) and before it starts a process; the
+/// timer interrupt and the shootdown IPIs call it too.
+///
+/// If we can't tell (the process goes away), we flush the whole TLB.
+pub fn sync_translations() {
+    let kcb = super::kcb::get_kcb();
+    let pid = match kcb.arch.current_pid() {
+        Ok(pid) => pid,
+        // No user-space translations in use
+        Err(_e) => return,
+    };
+
+    match NrProcess::<Ring3Process>::unmap_generation(pid) {
+        Ok(generation) => {
+            if kcb.arch.tlb_generation != Some((pid, generation)) {
+                trace!(
+                    "flush the TLB for unmap generation {} of {}",
+                    generation,
+                    pid
+                );
+                unsafe { x86::tlb::flush_all() };
+                kcb.arch.tlb_generation = Some((pid, generation));
+            }
+        }
+        Err(e) => {
+            warn!(
+                "Can't read the unmap generation of {}, flushing the TLB: {}",
+                pid, e
+            );
+            unsafe { x86::tlb::flush_all() };
+            kcb.arch.tlb_generation = None;
+        }
+    }
+}
+
 pub fn advance_replica(gtid: atopology::GlobalThreadId, log_id: usize) {
     trace!("Send AdvanceReplica IPI for {} to {}", log_id, gtid);
     let apic_id = atopology::MACHINE_TOPOLOGY.threads[gtid as usize].apic_id();
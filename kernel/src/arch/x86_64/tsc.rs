@@ -0,0 +1,202 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The time-stamp counter as a clocksource.
+//!
+//! We take the TSC frequency from the hypervisor's paravirtual clock if we
+//! run on KVM or Hyper-V (it knows the host TSC better than we can measure
+//! it in an overcommitted VM), then from CPUID if the processor (or
+//! hypervisor) reports it, otherwise we measure it against the HPET.
+
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use log::{info, warn};
+use spin::Once;
+use x86::msr::{rdmsr, wrmsr};
+use x86::time::rdtsc;
+
+use super::{hyperv, hypervisor, kvmclock};
+use crate::error::KError;
+use crate::time::clocksource::{self, duration_to_ticks, ClockSource};
+
+/// What we assume if we can't find out the frequency.
+const FALLBACK_FREQUENCY: u64 = 2_000_000_000;
+
+/// How long we measure the TSC against the HPET.
+const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+
+static TSC: Once<Tsc> = Once::new();
+
+/// The TSC when we booted (see `record_boot`).
+static BOOT: AtomicU64 = AtomicU64::new(0);
+
+const IA32_TSC: u32 = 0x10;
+/// Offset added to the TSC (per core) (0 until we resume from suspend).
+const IA32_TSC_ADJUST: u32 = 0x3b;
+
+/// What we wrote to `IA32_TSC_ADJUST` when we resumed (see `resume`).
+static ADJUST: AtomicU64 = AtomicU64::new(0);
+
+pub struct Tsc {
+    frequency: u64,
+    invariant: bool,
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn read(&self) -> u64 {
+        unsafe { rdtsc() }
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn rating(&self) -> u32 {
+        if self.invariant {
+            300
+        } else {
+            100
+        }
+    }
+}
+
+/// Does the TSC run at a constant rate in all power states?
+fn invariant() -> bool {
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+/// Can we move the TSC with `IA32_TSC_ADJUST`?
+fn has_adjust() -> bool {
+    unsafe { __cpuid(0) }.eax >= 7 && unsafe { __cpuid_count(7, 0) }.ebx & (1 << 1) != 0
+}
+
+/// The TSC frequency (in Hz) as reported by CPUID.
+fn cpuid_frequency() -> Option<u64> {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+
+    // Ratio of TSC to core crystal clock (and the crystal frequency)
+    if max_leaf >= 0x15 {
+        let leaf = unsafe { __cpuid(0x15) };
+        if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+            return Some(leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64);
+        }
+    }
+
+    // Hypervisor timing leaf (TSC frequency in kHz)
+    if hypervisor::present() && unsafe { __cpuid(0x4000_0000) }.eax >= 0x4000_0010 {
+        let khz = unsafe { __cpuid(0x4000_0010) }.eax;
+        if khz != 0 {
+            return Some(khz as u64 * 1000);
+        }
+    }
+
+    // Processor base frequency (in MHz)
+    if max_leaf >= 0x16 {
+        let mhz = unsafe { __cpuid(0x16) }.eax & 0xffff;
+        if mhz != 0 {
+            return Some(mhz as u64 * 1_000_000);
+        }
+    }
+
+    None
+}
+
+/// Measures the TSC frequency against `reference`.
+fn calibrate(reference: &dyn ClockSource) -> u64 {
+    let ticks = duration_to_ticks(CALIBRATION_TIME, reference.frequency());
+    let (reference_start, start) = (reference.read(), unsafe { rdtsc() });
+    while reference.read().wrapping_sub(reference_start) & reference.mask() < ticks {
+        core::hint::spin_loop();
+    }
+    let (reference_end, end) = (reference.read(), unsafe { rdtsc() });
+
+    let elapsed = reference_end.wrapping_sub(reference_start) & reference.mask();
+    ((end - start) as u128 * reference.frequency() as u128 / elapsed as u128) as u64
+}
+
+/// Finds out the TSC frequency and registers the TSC as a clocksource.
+///
+/// Call this after the paravirtual clocks and the HPET are registered (we
+/// might need to calibrate against the HPET).
+pub fn init() -> Result<(), KError> {
+    let (frequency, source) = kvmclock::tsc_frequency()
+        .map(|frequency| (frequency, "kvm-clock"))
+        .or_else(|| hyperv::tsc_frequency().map(|frequency| (frequency, "hyperv")))
+        .or_else(|| cpuid_frequency().map(|frequency| (frequency, "cpuid")))
+        .or_else(|| {
+            clocksource::get("hpet").map(|reference| (calibrate(reference), reference.name()))
+        })
+        .unwrap_or_else(|| {
+            warn!(
+                "Can't determine TSC frequency, assuming {} Hz",
+                FALLBACK_FREQUENCY
+            );
+            (FALLBACK_FREQUENCY, "guess")
+        });
+
+    let tsc = TSC.call_once(|| Tsc {
+        frequency,
+        invariant: invariant(),
+    });
+    info!(
+        "TSC runs at {} Hz (from {}), invariant: {}",
+        tsc.frequency, source, tsc.invariant
+    );
+    clocksource::register(tsc)
+}
+
+/// The TSC frequency in Hz.
+pub fn frequency() -> u64 {
+    TSC.get()
+        .map(|tsc| tsc.frequency)
+        .unwrap_or(FALLBACK_FREQUENCY)
+}
+
+/// Remembers the current TSC as the time we booted (monotonic time 0, see
+/// `kpi::time`). Call this once, early on the BSP.
+pub fn record_boot() {
+    BOOT.store(unsafe { rdtsc() }, Ordering::Relaxed);
+}
+
+/// The TSC when we booted.
+pub fn boot() -> u64 {
+    BOOT.load(Ordering::Relaxed)
+}
+
+/// Puts the TSC back to `before` (what it read when we suspended) after
+/// the firmware reset it, monotonic time continues where it stopped.
+///
+/// Call this on the BSP right after we resumed, the other cores pick up
+/// the same offset in `sync_core` when they come back.
+pub fn resume(before: u64) {
+    let now = unsafe { rdtsc() };
+    if now >= before {
+        return;
+    }
+
+    if has_adjust() {
+        let adjust = unsafe { rdmsr(IA32_TSC_ADJUST) }.wrapping_add(before - now);
+        unsafe { wrmsr(IA32_TSC_ADJUST, adjust) };
+        ADJUST.store(adjust, Ordering::Relaxed);
+    } else {
+        // TODO(correctness): The other cores can't follow without
+        // `IA32_TSC_ADJUST` (their TSC restarts at 0)
+        unsafe { wrmsr(IA32_TSC, before) };
+    }
+}
+
+/// Applies the offset from `resume` to the current core (it's a no-op
+/// unless we resumed from suspend).
+pub fn sync_core() {
+    let adjust = ADJUST.load(Ordering::Relaxed);
+    if adjust != 0 && has_adjust() {
+        unsafe { wrmsr(IA32_TSC_ADJUST, adjust) };
+    }
+}
@@ -0,0 +1,404 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Kernel accesses to user-space memory.
+//!
+//! We enable SMEP and SMAP on every core: the kernel faults if it executes
+//! or touches a user-accessible page, unless `RFLAGS.AC` is set. All
+//! accesses to user memory go through this module so there is exactly one
+//! place that opens such a window (STAC) and closes it again (CLAC):
+//!
+//!  - [`UserSlice`] is a user buffer of the current process the kernel works
+//!    on in place (file reads, sockets). Creating one checks that the buffer
+//!    is mapped with the rights the access needs and pins it, the window is
+//!    only open while `read_into` and `write_from` copy.
+//!  - `copy_in`, `copy_out` and `copy_in_str` copy from/to a kernel buffer
+//!    through a `UserSlice` (and `process::UserPtr` for single values).
+//!
+//! Pinned buffers can't be unmapped or protected: `Unmap` and `Protect` wait
+//! until the kernel is done with them ([`exclude_pins`]), so the kernel never
+//! faults on a buffer it checked.
+//!
+//! `syscall` entry clears AC (through `IA32_FMASK`) and `reset` clears it
+//! on interrupt entry, so user-space can't hand us an open window.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use fallible_collections::FallibleVec;
+use log::info;
+use x86::bits64::paging::{VAddr, BASE_PAGE_SIZE};
+use x86::bits64::rflags::{self, RFlags};
+use x86::controlregs::{cr4, cr4_write, Cr4};
+
+use crate::error::KError;
+use crate::kcb::ArchSpecificKcb;
+use crate::memory::vspace::UserAccess;
+use crate::memory::KERNEL_BASE;
+use crate::mutex::{Mutex, MutexGuard};
+use crate::nrproc::NrProcess;
+use crate::process::{Pid, MAX_PROCESSES};
+
+use super::kcb::get_kcb;
+use super::process::Ring3Process;
+
+/// Longest string `copy_in_str` accepts (including the NUL byte).
+pub const MAX_STR_LEN: usize = 4096;
+
+/// Whether the cores run with SMAP (STAC and CLAC fault without it).
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+/// Enables SMEP and SMAP (if the core has them) on the current core.
+pub fn init() {
+    let cpuid = x86::cpuid::CpuId::new();
+    let (smep, smap) = cpuid
+        .get_extended_feature_info()
+        .map_or((false, false), |f| (f.has_smep(), f.has_smap()));
+
+    unsafe {
+        let mut flags = cr4();
+        if smep {
+            flags |= Cr4::CR4_ENABLE_SMEP;
+        }
+        if smap {
+            flags |= Cr4::CR4_ENABLE_SMAP;
+            rflags::clac();
+        }
+        cr4_write(flags);
+    }
+
+    SMAP.store(smap, Ordering::Relaxed);
+    if get_kcb().arch.id() == 0 {
+        info!("User access protection: SMEP {}, SMAP {}", smep, smap);
+    }
+}
+
+/// Closes a window the interrupted code left open (user-space can set AC
+/// too), called on interrupt entry.
+pub fn reset() {
+    if SMAP.load(Ordering::Relaxed) {
+        unsafe { rflags::clac() };
+    }
+}
+
+/// The kernel can access user memory while a `Window` lives.
+///
+/// Windows nest: only the outermost one closes on drop.
+pub struct Window {
+    opened: bool,
+}
+
+impl Window {
+    pub fn open() -> Window {
+        let opened = SMAP.load(Ordering::Relaxed) && !rflags::read().contains(RFlags::FLAGS_AC);
+        if opened {
+            unsafe { rflags::stac() };
+        }
+        Window { opened }
+    }
+}
+
+impl Drop for Window {
+    fn drop(&mut self) {
+        if self.opened {
+            unsafe { rflags::clac() };
+        }
+    }
+}
+
+/// Checks that `[base, base + len)` is in the user half of the address-space
+/// and mapped in process `pid` with the rights `access` needs (for `len == 0`
+/// only `base` has to be mapped).
+///
+/// It also makes sure the TLB of the core has no translations the process
+/// unmapped already (see `tlb::unmapped`), so the access that follows uses
+/// the mappings we checked.
+///
+/// TODO: This resolves every page of the buffer which makes file-operations
+/// slow, improve it to use large page sizes. Or maintain a list of (low,
+/// high) memory limits per process and check if (base, len) are within the
+/// process memory limits.
+pub fn validate(pid: Pid, base: u64, len: u64, access: UserAccess) -> Result<(), KError> {
+    let end = user_range(base, len)?.end;
+
+    let mut page = base & !(BASE_PAGE_SIZE as u64 - 1);
+    loop {
+        let (_paddr, rights) = match NrProcess::<Ring3Process>::mapping(pid, VAddr::from(page)) {
+            // Bring it back if it's evicted
+            Err(KError::NotMapped) if super::swap::fault_in(pid, page)? => continue,
+            mapping => mapping?,
+        };
+        if !rights.allows(access) {
+            return Err(KError::BadAddress);
+        }
+        page += BASE_PAGE_SIZE as u64;
+        if page >= end {
+            super::tlb::sync_translations();
+            return Ok(());
+        }
+    }
+}
+
+/// `[base, base + len)` if it's in the user half of the address-space.
+fn user_range(base: u64, len: u64) -> Result<Range<u64>, KError> {
+    match base.checked_add(len) {
+        Some(end) if end <= KERNEL_BASE => Ok(base..end),
+        _ => Err(KError::BadAddress),
+    }
+}
+
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_PINS: Mutex<Vec<Range<u64>>> = Mutex::new("user_access::PINS", Vec::new());
+
+/// The user buffers the kernel works on, for every process.
+static PINS: [Mutex<Vec<Range<u64>>>; MAX_PROCESSES] = [NO_PINS; MAX_PROCESSES];
+
+/// Keeps a user buffer from being unmapped while it lives.
+struct Pin {
+    pid: Pid,
+    range: Range<u64>,
+}
+
+impl Pin {
+    fn new(pid: Pid, range: Range<u64>) -> Result<Pin, KError> {
+        PINS[pid].lock().try_push(range.clone())?;
+        Ok(Pin { pid, range })
+    }
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        let mut pins = PINS[self.pid].lock();
+        if let Some(idx) = pins.iter().position(|r| *r == self.range) {
+            pins.swap_remove(idx);
+        }
+    }
+}
+
+/// Waits until the kernel is done with the buffers of process `pid` in the
+/// mapping at `base`, then keeps anyone from pinning new buffers until the
+/// returned guard is dropped (i.e., the mapping changed).
+///
+/// Buffers are only pinned during a system call.
+pub fn exclude_pins(pid: Pid, base: u64) -> MutexGuard<'static, Vec<Range<u64>>> {
+    let page = base & !(BASE_PAGE_SIZE as u64 - 1);
+    loop {
+        let pins = PINS[pid].lock();
+        // Look again every time, it may change while we wait
+        let mapping = match NrProcess::<Ring3Process>::extent(pid, VAddr::from(page)) {
+            Ok((start, size)) => start.as_u64()..start.as_u64() + size as u64,
+            // Evicted (evictable mappings are base pages) or not mapped
+            Err(_e) => page..page + BASE_PAGE_SIZE as u64,
+        };
+        if !pins.iter().any(|r| overlaps(r, &mapping)) {
+            return pins;
+        }
+        drop(pins);
+        core::hint::spin_loop();
+    }
+}
+
+/// Like `exclude_pins` for the base page at `base`, but returns `None`
+/// right away if the kernel works on a buffer in it.
+pub fn try_exclude_pins(pid: Pid, base: u64) -> Option<MutexGuard<'static, Vec<Range<u64>>>> {
+    let page = base..base + BASE_PAGE_SIZE as u64;
+    let pins = PINS[pid].lock();
+    if pins.iter().any(|r| overlaps(r, &page)) {
+        None
+    } else {
+        Some(pins)
+    }
+}
+
+/// A user buffer of the current process the kernel works on in place (file
+/// reads write into it, sockets send from it).
+///
+/// The buffer is pinned while it lives. Anything that can copy all at once
+/// should use `copy_in` and `copy_out` instead.
+pub struct UserSlice<'a> {
+    base: u64,
+    len: usize,
+    _pin: Pin,
+    _buffer: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> UserSlice<'a> {
+    /// Checks that `[base, base + len)` is mapped in the current process with
+    /// the rights `access` needs and pins it.
+    pub fn new(base: u64, len: usize, access: UserAccess) -> Result<UserSlice<'a>, KError> {
+        let pid = get_kcb().arch.current_pid()?;
+        let range = user_range(base, len as u64)?;
+        // Pin first, nobody can unmap it once we checked it's mapped
+        let pin = Pin::new(pid, range)?;
+        validate(pid, base, len as u64, access)?;
+
+        Ok(UserSlice {
+            base,
+            len,
+            _pin: pin,
+            _buffer: PhantomData,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Copies the `dst.len()` bytes at `offset` in the buffer into `dst`.
+    pub fn read_into(&self, offset: usize, dst: &mut [u8]) {
+        assert!(offset <= self.len && dst.len() <= self.len - offset);
+        let _window = Window::open();
+        // Safe: We checked that this is mapped user memory, and it stays
+        // mapped while we have the pin
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                (self.base + offset as u64) as *const u8,
+                dst.as_mut_ptr(),
+                dst.len(),
+            )
+        };
+    }
+
+    /// Copies `src` to `offset` in the buffer.
+    pub fn write_from(&mut self, offset: usize, src: &[u8]) {
+        assert!(offset <= self.len && src.len() <= self.len - offset);
+        let _window = Window::open();
+        // Safe: See `read_into`
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                src.as_ptr(),
+                (self.base + offset as u64) as *mut u8,
+                src.len(),
+            )
+        };
+    }
+}
+
+/// Copies `dst.len()` bytes from user address `src` of the current
+/// process into `dst`.
+pub fn copy_in(dst: &mut [u8], src: u64) -> Result<(), KError> {
+    if dst.is_empty() {
+        return Ok(());
+    }
+    UserSlice::new(src, dst.len(), UserAccess::Read)?.read_into(0, dst);
+    Ok(())
+}
+
+/// Copies `src` to user address `dst` of the current process.
+pub fn copy_out(dst: u64, src: &[u8]) -> Result<(), KError> {
+    if src.is_empty() {
+        return Ok(());
+    }
+    UserSlice::new(dst, src.len(), UserAccess::Write)?.write_from(0, src);
+    Ok(())
+}
+
+/// How much to read next of a string at `addr` when we have `copied` bytes
+/// of it: the rest of the page (the string may end right before an unmapped
+/// one), but no more than `MAX_STR_LEN` in total.
+fn str_chunk(addr: u64, copied: usize) -> usize {
+    let in_page = BASE_PAGE_SIZE - (addr as usize % BASE_PAGE_SIZE);
+    core::cmp::min(in_page, MAX_STR_LEN - copied)
+}
+
+/// Copies the NUL-terminated string at user address `src` of the current
+/// process (without the NUL byte).
+///
+/// Fails with `NotSupported` if it isn't valid UTF-8 or longer than
+/// `MAX_STR_LEN`.
+pub fn copy_in_str(src: u64) -> Result<String, KError> {
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut addr = src;
+
+    while bytes.len() < MAX_STR_LEN {
+        let chunk = str_chunk(addr, bytes.len());
+        let user = UserSlice::new(addr, chunk, UserAccess::Read)?;
+
+        let start = bytes.len();
+        bytes.try_resize(start + chunk, 0)?;
+        user.read_into(0, &mut bytes[start..]);
+        if let Some(nul) = bytes[start..].iter().position(|b| *b == 0) {
+            bytes.truncate(start + nul);
+            return String::from_utf8(bytes).map_err(|_e| KError::NotSupported);
+        }
+        addr += chunk as u64;
+    }
+
+    Err(KError::NotSupported)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn user_range_rejects_kernel_and_overflow() {
+        assert_eq!(user_range(0x1000, 0x10), Ok(0x1000..0x1010));
+        assert_eq!(user_range(0x1000, 0), Ok(0x1000..0x1000));
+        assert_eq!(
+            user_range(KERNEL_BASE - 0x10, 0x10),
+            Ok(KERNEL_BASE - 0x10..KERNEL_BASE)
+        );
+        assert_eq!(
+            user_range(KERNEL_BASE - 0x10, 0x11),
+            Err(KError::BadAddress)
+        );
+        assert_eq!(user_range(KERNEL_BASE, 0), Ok(KERNEL_BASE..KERNEL_BASE));
+        assert_eq!(user_range(KERNEL_BASE, 1), Err(KError::BadAddress));
+        assert_eq!(user_range(u64::MAX - 1, 4), Err(KError::BadAddress));
+    }
+
+    #[test]
+    fn pins_overlap() {
+        let pin = 0x1000..0x3000;
+        assert!(overlaps(&pin, &(0x2000..0x4000)));
+        assert!(overlaps(&pin, &(0x0..0x1001)));
+        assert!(overlaps(&pin, &(0x1800..0x1900)));
+        assert!(overlaps(&pin, &(0x0..0x20_0000)));
+        // Adjacent ranges don't
+        assert!(!overlaps(&pin, &(0x0..0x1000)));
+        assert!(!overlaps(&pin, &(0x3000..0x4000)));
+        assert!(!overlaps(&(0x3000..0x4000), &pin));
+    }
+
+    #[test]
+    fn str_chunks_stop_at_pages() {
+        assert_eq!(str_chunk(0x1000, 0), BASE_PAGE_SIZE);
+        assert_eq!(str_chunk(0x1ffc, 0), 4);
+        assert_eq!(str_chunk(0x1800, 0), 0x800);
+        // The last chunk stops at `MAX_STR_LEN`
+        assert_eq!(str_chunk(0x2000, 4), MAX_STR_LEN - 4);
+        assert_eq!(str_chunk(0x2000, MAX_STR_LEN - 1), 1);
+    }
+
+    #[test]
+    fn str_chunks_add_up() {
+        for src in [0x1000, 0x1001, 0x1ffc, 0x1fff, 0x1800] {
+            let mut addr = src;
+            let mut copied = 0;
+            while copied < MAX_STR_LEN {
+                let chunk = str_chunk(addr, copied);
+                assert!(chunk > 0);
+                // Never crosses a page
+                assert_eq!(
+                    addr / BASE_PAGE_SIZE as u64,
+                    (addr + chunk as u64 - 1) / BASE_PAGE_SIZE as u64
+                );
+                addr += chunk as u64;
+                copied += chunk;
+            }
+            assert_eq!(copied, MAX_STR_LEN);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Page faults a process handles itself.
+//!
+//! A process registers regions of its address space
+//! (`VSpaceOperation::RegisterFaultRegion`). A user-space fault in one of
+//! them that the kernel can't resolve (nothing is mapped there or the
+//! access isn't allowed) becomes a `kpi::upcall::PAGE_FAULT` upcall instead
+//! of a fatal error: the faulting context goes to
+//! `VirtualCpu::enabled_state` and the address and kind of access to
+//! `VirtualCpu::fault`. The process maps something (or changes its mind)
+//! and resumes the context, which retries the access.
+
+use arrayvec::ArrayVec;
+use log::{trace, warn};
+use spin::Mutex;
+use x86::irq::PageFaultError;
+
+use crate::error::KError;
+use crate::kcb::ArchSpecificKcb;
+use crate::process::{Executor, Pid};
+
+use super::kcb::Arch86Kcb;
+use super::memory::{VAddr, KERNEL_BASE};
+use super::process::Ring3Resumer;
+
+/// How many regions all processes can register together.
+const MAX_REGIONS: usize = 64;
+
+/// `[start, end)` of the address space of `pid` it handles faults in.
+struct Region {
+    pid: Pid,
+    start: u64,
+    end: u64,
+}
+
+static REGIONS: Mutex<ArrayVec<Region, MAX_REGIONS>> = Mutex::new(ArrayVec::new_const());
+
+/// Forwards faults in `[base, base + size)` of `pid` to the process.
+pub fn register(pid: Pid, base: u64, size: u64) -> Result<(u64, u64), KError> {
+    let end = base.checked_add(size).ok_or(KError::InvalidFaultRegion)?;
+    if size == 0 || end > KERNEL_BASE {
+        return Err(KError::InvalidFaultRegion);
+    }
+
+    let mut regions = REGIONS.lock();
+    if regions
+        .iter()
+        .any(|r| r.pid == pid && r.start < end && base < r.end)
+    {
+        return Err(KError::FaultRegionOverlaps);
+    }
+    regions
+        .try_push(Region {
+            pid,
+            start: base,
+            end,
+        })
+        .map_err(|_| KError::TooManyFaultRegions)?;
+
+    Ok((base, size))
+}
+
+/// Removes the region of `pid` that starts at `base`.
+pub fn unregister(pid: Pid, base: u64) -> Result<(u64, u64), KError> {
+    let mut regions = REGIONS.lock();
+    let idx = regions
+        .iter()
+        .position(|r| r.pid == pid && r.start == base)
+        .ok_or(KError::FaultRegionNotFound)?;
+    let region = regions.swap_remove(idx);
+
+    Ok((region.start, region.end - region.start))
+}
+
+fn is_registered(pid: Pid, address: u64) -> bool {
+    REGIONS
+        .lock()
+        .iter()
+        .any(|r| r.pid == pid && r.start <= address && address < r.end)
+}
+
+/// Hands a user-space page fault at `address` to the running process if it
+/// registered a region for it and can take upcalls right now (a fault in
+/// its upcall handler is fatal, like before).
+pub(super) fn upcall(
+    kcb: &crate::kcb::Kcb<Arch86Kcb>,
+    rip: u64,
+    address: u64,
+    err: PageFaultError,
+) -> Option<Ring3Resumer> {
+    let pid = kcb.current_pid().ok()?;
+    if !is_registered(pid, address) {
+        return None;
+    }
+
+    let mut plock = kcb.arch.current_executor();
+    let p = plock.as_mut().ok()?;
+
+    // Safe: The kernel alias of the vcpu area is valid while the executor
+    // exists
+    let vcpu = unsafe { &mut *p.vcpu_kernel() };
+    if vcpu.upcalls_disabled(VAddr::from(rip)) {
+        warn!("Page fault at {:#x} while upcalls are disabled", address);
+        return None;
+    }
+
+    vcpu.disable_upcalls();
+    kcb.arch.save_area.as_ref().map(|sa| {
+        vcpu.enabled_state = **sa;
+    });
+    vcpu.fault = kpi::arch::PageFault {
+        address,
+        access: err.bits() as u64,
+    };
+    trace!(
+        "Forwarding page fault at {:#x} ({}) to {}",
+        address,
+        err,
+        pid
+    );
+
+    Some(p.upcall(kpi::upcall::PAGE_FAULT, address))
+}
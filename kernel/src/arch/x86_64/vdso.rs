@@ -0,0 +1,104 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The time page every process maps read-only (see `kpi::time`), our
+//! stand-in for a vDSO.
+//!
+//! There is one page for the whole system. We fill it in once the TSC
+//! frequency and the wall-clock time are known and map it into processes
+//! when they are loaded, the time system calls read the same clock.
+
+use core::time::Duration;
+
+use kpi::time::{Clock, TimePage};
+use log::info;
+use spin::Once;
+use x86::bits64::paging::VAddr;
+use x86::time::rdtsc;
+
+use super::tsc;
+use super::vspace::VSpace;
+use crate::error::KError;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::vspace::{AddressSpace, MapAction};
+use crate::memory::BASE_PAGE_SIZE;
+
+/// Where processes find the time page (just below the ELF binary).
+pub const TIME_PAGE: u64 = kpi::process::ELF_OFFSET as u64 - BASE_PAGE_SIZE as u64;
+
+static PAGE: Once<DmaBuffer> = Once::new();
+
+fn page() -> Option<&'static TimePage> {
+    PAGE.get()
+        .map(|buffer| unsafe { &*buffer.as_ptr::<TimePage>() })
+}
+
+/// The clock from what we know right now.
+fn current() -> Clock {
+    let mut clock = Clock {
+        tsc_frequency: tsc::frequency(),
+        tsc_base: tsc::boot(),
+        realtime_base: 0,
+    };
+    if let Some(now) = crate::time::wallclock() {
+        let since_boot = clock.monotonic_ns(unsafe { rdtsc() });
+        clock.realtime_base = (now.as_nanos() as u64).saturating_sub(since_boot);
+    }
+    clock
+}
+
+/// Allocates and fills in the time page.
+///
+/// Call this after the TSC is registered and the wall-clock time is set.
+pub fn init() -> Result<(), KError> {
+    let buffer = DmaBuffer::new(BASE_PAGE_SIZE)?;
+    let page = PAGE.call_once(|| buffer);
+    let clock = current();
+    unsafe { &*page.as_ptr::<TimePage>() }.write(&clock);
+    info!(
+        "Time page at {:#x}: TSC {} Hz, resolution {} ns",
+        TIME_PAGE,
+        clock.tsc_frequency,
+        clock.resolution_ns()
+    );
+    Ok(())
+}
+
+/// Rewrites the time page after the wall-clock moved (we resumed from
+/// suspend).
+pub fn update() {
+    if let Some(page) = page() {
+        page.write(&current());
+    }
+}
+
+/// The clock processes see.
+pub fn clock() -> Clock {
+    page().and_then(TimePage::read).unwrap_or_else(current)
+}
+
+/// Time since boot.
+pub fn monotonic() -> Duration {
+    Duration::from_nanos(clock().monotonic_ns(unsafe { rdtsc() }))
+}
+
+/// Time since the UNIX epoch (`None` if we don't know the time).
+pub fn realtime() -> Option<Duration> {
+    clock()
+        .realtime_ns(unsafe { rdtsc() })
+        .map(Duration::from_nanos)
+}
+
+/// Where processes find the time page (`None` before `init`).
+pub fn address() -> Option<u64> {
+    PAGE.get().map(|_buffer| TIME_PAGE)
+}
+
+/// Maps the time page into `vspace` at `TIME_PAGE` (read-only, if there is
+/// one yet).
+pub fn map(vspace: &mut VSpace) -> Result<(), KError> {
+    if let Some(buffer) = PAGE.get() {
+        vspace.map_frame(VAddr::from(TIME_PAGE), buffer.frame(), MapAction::ReadUser)?;
+    }
+    Ok(())
+}
@@ -1,18 +1,22 @@
 // Copyright © 2021 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
+use alloc::vec::Vec;
 use core::ops::Bound::*;
 
 use fallible_collections::btree::BTreeMap;
+use fallible_collections::{FallibleVec, FallibleVecGlobal};
 
 mod debug;
 pub mod page_table; /* TODO(encapsulation): This should be a private module but we break encapsulation in a few places */
+#[cfg(kani)]
+mod proofs;
 #[cfg(test)]
 mod test;
 
 use crate::error::KError;
 use crate::memory::{detmem::DA, vspace::*};
-use crate::memory::{Frame, PAddr, VAddr};
+use crate::memory::{Frame, PAddr, VAddr, BASE_PAGE_SIZE};
 
 use page_table::PageTable;
 
@@ -78,6 +82,43 @@ impl AddressSpace for VSpace {
         self.page_table.resolve(addr)
     }
 
+    fn extent(&self, addr: VAddr) -> Result<(VAddr, usize), KError> {
+        self.page_table.extent(addr)
+    }
+
+    fn harvest_accessed(&self, addr: VAddr) -> Result<bool, KError> {
+        self.page_table.harvest_accessed(addr)
+    }
+
+    fn dirty(&self, addr: VAddr) -> Result<bool, KError> {
+        self.page_table.dirty(addr)
+    }
+
+    fn set_dirty(&self, addr: VAddr) -> Result<(), KError> {
+        self.page_table.set_dirty(addr)
+    }
+
+    fn regions(&self) -> Result<Vec<Region>, KError> {
+        let mut regions = Vec::try_with_capacity(self.mappings.len())?;
+        for (&base, mapping) in self.mappings.iter() {
+            // A mapping can have more than one leaf in the page-table
+            let mut dirty = false;
+            for offset in (0..mapping.frame.size()).step_by(BASE_PAGE_SIZE) {
+                if self.page_table.dirty(base + offset)? {
+                    dirty = true;
+                    break;
+                }
+            }
+            regions.try_push(Region {
+                base,
+                frame: mapping.frame,
+                rights: mapping.rights,
+                dirty,
+            })?;
+        }
+        Ok(regions)
+    }
+
     fn unmap(&mut self, base: VAddr) -> Result<TlbFlushHandle, KError> {
         for (&existing_base, existing_mapping) in
             self.mappings.range((Unbounded, Included(base))).rev()
@@ -90,9 +131,13 @@ impl AddressSpace for VSpace {
             }
         }
 
-        let r = self.page_table.unmap(base)?;
+        let mut r = self.page_table.unmap(base)?;
         let rbt = self.mappings.remove(&r.vaddr);
         debug_assert!(rbt.is_some());
+        if let Some(mapping) = rbt {
+            // The page-table doesn't know where the frame came from
+            r.frame.affinity = mapping.frame.affinity;
+        }
         Ok(r)
     }
 
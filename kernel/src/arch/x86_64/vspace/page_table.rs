@@ -6,6 +6,7 @@ use core::alloc::Layout;
 use core::mem::transmute;
 use core::pin::Pin;
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use kpi::KERNEL_BASE;
 use log::{debug, trace};
@@ -16,6 +17,11 @@ use crate::memory::detmem::DA;
 use crate::memory::vspace::*;
 use crate::memory::{kernel_vaddr_to_paddr, paddr_to_kernel_vaddr, Frame, PAddr, VAddr};
 
+/// Set by the MMU in the entries of all levels when it uses them.
+const ACCESSED: u64 = 1 << 5;
+/// Set by the MMU in the entry that maps a page when it's written.
+const DIRTY: u64 = 1 << 6;
+
 /// Describes a potential modification operation on existing page tables.
 const PT_LAYOUT: Layout =
     unsafe { Layout::from_size_align_unchecked(BASE_PAGE_SIZE, BASE_PAGE_SIZE) };
@@ -159,6 +165,48 @@ impl AddressSpace for PageTable {
         Err(KError::NotMapped)
     }
 
+    fn extent(&self, addr: VAddr) -> Result<(VAddr, usize), KError> {
+        let pml4_entry = self.pml4[pml4_index(addr)];
+        if !pml4_entry.is_present() {
+            return Err(KError::NotMapped);
+        }
+        let pdpt_entry = self.get_pdpt(pml4_entry)[pdpt_index(addr)];
+        if !pdpt_entry.is_present() {
+            return Err(KError::NotMapped);
+        }
+        if pdpt_entry.is_page() {
+            return Ok((addr.align_down_to_huge_page(), HUGE_PAGE_SIZE));
+        }
+        let pd_entry = self.get_pd(pdpt_entry)[pd_index(addr)];
+        if !pd_entry.is_present() {
+            return Err(KError::NotMapped);
+        }
+        if pd_entry.is_page() {
+            return Ok((addr.align_down_to_large_page(), LARGE_PAGE_SIZE));
+        }
+        if !self.get_pt(pd_entry)[pt_index(addr)].is_present() {
+            return Err(KError::NotMapped);
+        }
+        Ok((addr.align_down_to_base_page(), BASE_PAGE_SIZE))
+    }
+
+    fn harvest_accessed(&self, addr: VAddr) -> Result<bool, KError> {
+        // The MMU sets the bit while we clear it, so it has to be atomic.
+        // We don't flush the TLB: a core that still has the translation
+        // doesn't set the bit again, at worst a page looks colder than it is.
+        let entry = self.leaf_entry(addr)?;
+        Ok(entry.fetch_and(!ACCESSED, Ordering::Relaxed) & ACCESSED != 0)
+    }
+
+    fn dirty(&self, addr: VAddr) -> Result<bool, KError> {
+        Ok(self.leaf_entry(addr)?.load(Ordering::Relaxed) & DIRTY != 0)
+    }
+
+    fn set_dirty(&self, addr: VAddr) -> Result<(), KError> {
+        self.leaf_entry(addr)?.fetch_or(DIRTY, Ordering::Relaxed);
+        Ok(())
+    }
+
     fn unmap(&mut self, base: VAddr) -> Result<TlbFlushHandle, KError> {
         if !base.is_base_page_aligned() {
             return Err(KError::InvalidBase);
@@ -272,6 +320,10 @@ impl PageTable {
 
         let no_underlying_2mib_mappings = if !pdpt_entry.is_present() {
             true
+        } else if pdpt_entry.is_page() {
+            // Already a 1 GiB mapping (not a PD), `insert_huge_mappings` checks if
+            // it's the one we want
+            true
         } else {
             // We go and check if the underlying page-table is emtpy
             // (previous mappings could've left a PD here which since has been emptied)
@@ -328,6 +380,10 @@ impl PageTable {
 
         let no_underlying_4kib_mappings = if !pd_entry.is_present() {
             true
+        } else if pd_entry.is_page() {
+            // Already a 2 MiB mapping (not a PT), `insert_large_mappings` checks if
+            // it's the one we want
+            true
         } else {
             // We go and check if the underlying page-table is emtpy
             // (previous mappings could've left a PT here which since has been emptied)
@@ -372,7 +428,12 @@ impl PageTable {
                 if pdpt[pdpt_idx].is_present() {
                     let address = pdpt[pdpt_idx].address();
                     let cur_rights: MapAction = pdpt[pdpt_idx].flags().into();
-                    if address != pbase + mapped || cur_rights != rights {
+                    // A present entry that isn't a page points to the next
+                    // level of tables (which may hold mappings)
+                    if !pdpt[pdpt_idx].is_page()
+                        || address != pbase + mapped
+                        || cur_rights != rights
+                    {
                         // Return an error if a frame is present,
                         // and it's not exactly the frame+rights combo we're
                         // trying to map
@@ -385,7 +446,10 @@ impl PageTable {
                 if pdpt[pdpt_idx].is_present() {
                     let address = pdpt[pdpt_idx].address();
                     let cur_rights: MapAction = pdpt[pdpt_idx].flags().into();
-                    if address != pbase + mapped || cur_rights != rights {
+                    if !pdpt[pdpt_idx].is_page()
+                        || address != pbase + mapped
+                        || cur_rights != rights
+                    {
                         panic!("Trying to map 1 GiB page but it conflicts with existing mapping");
                     }
                 }
@@ -457,7 +521,7 @@ impl PageTable {
                 if pd[pd_idx].is_present() {
                     let address = pd[pd_idx].address();
                     let cur_rights: MapAction = pd[pd_idx].flags().into();
-                    if address != pbase + mapped || cur_rights != rights {
+                    if !pd[pd_idx].is_page() || address != pbase + mapped || cur_rights != rights {
                         // Return an error if a frame is present,
                         // and it's not exactly the frame+rights combo we're
                         // trying to map anyways
@@ -470,7 +534,7 @@ impl PageTable {
                 if pd[pd_idx].is_present() {
                     let address = pd[pd_idx].address();
                     let cur_rights: MapAction = pd[pd_idx].flags().into();
-                    if address != pbase + mapped || cur_rights != rights {
+                    if !pd[pd_idx].is_page() || address != pbase + mapped || cur_rights != rights {
                         panic!("Trying to map 2 MiB page but it conflicts with existing mapping");
                     }
                 }
@@ -809,6 +873,7 @@ impl PageTable {
 
     fn alloc_frame(&self) -> Frame {
         use core::alloc::Allocator;
+        kprobe!("pt_alloc");
         let frame_ptr = self.da.as_ref().map_or_else(
             || unsafe {
                 let ptr = alloc::alloc::alloc(PT_LAYOUT);
@@ -842,6 +907,38 @@ impl PageTable {
         return PML4Entry::new(frame.base, PML4Flags::P | PML4Flags::RW | PML4Flags::US);
     }
 
+    /// The entry that maps `addr` (of a page-table, page directory or PDPT
+    /// for 4 KiB, 2 MiB and 1 GiB pages), as an atomic because the MMU
+    /// updates its accessed and dirty bits behind our back.
+    fn leaf_entry(&self, addr: VAddr) -> Result<&AtomicU64, KError> {
+        let pml4_entry = self.pml4[pml4_index(addr)];
+        if !pml4_entry.is_present() {
+            return Err(KError::NotMapped);
+        }
+        let pdpt_entry = &self.get_pdpt(pml4_entry)[pdpt_index(addr)];
+        if !pdpt_entry.is_present() {
+            return Err(KError::NotMapped);
+        }
+        let entry = if pdpt_entry.is_page() {
+            pdpt_entry as *const PDPTEntry as *const u64
+        } else {
+            let pd_entry = &self.get_pd(*pdpt_entry)[pd_index(addr)];
+            if !pd_entry.is_present() {
+                return Err(KError::NotMapped);
+            }
+            if pd_entry.is_page() {
+                pd_entry as *const PDEntry as *const u64
+            } else {
+                let pt_entry = &self.get_pt(*pd_entry)[pt_index(addr)];
+                if !pt_entry.is_present() {
+                    return Err(KError::NotMapped);
+                }
+                pt_entry as *const PTEntry as *const u64
+            }
+        };
+        Ok(unsafe { &*(entry as *const AtomicU64) })
+    }
+
     /// Resolve a PDEntry to a page table.
     fn get_pt(&self, entry: PDEntry) -> &PT {
         assert_ne!(entry.address(), PAddr::zero());
@@ -0,0 +1,161 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Proofs (for [Kani](https://github.com/model-checking/kani)) that the
+//! page-table puts mappings where we asked for them.
+//!
+//! Unlike the proptests in `test` these check every address, frame and set of
+//! rights (but only for one or two mappings of a single page each, a bigger
+//! page-table is too much for the solver). Run them with `cargo kani --tests`
+//! in `kernel` (see the Testing chapter of the documentation).
+
+use alloc::boxed::Box;
+use core::mem;
+
+use super::page_table::PageTable;
+use crate::error::KError;
+use crate::memory::vspace::MapAction;
+use crate::memory::{PAddr, VAddr, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+
+use x86::bits64::paging::{PML4Entry, PML4Flags, HUGE_PAGE_SIZE, PAGE_SIZE_ENTRIES};
+
+/// Size of the lower half of the canonical address space.
+const VADDR_LIMIT: u64 = 1 << 47;
+
+/// Physical addresses a page-table entry can hold (MAXPHYADDR is 52).
+const PADDR_LIMIT: u64 = 1 << 52;
+
+/// Every `MapAction` we can map something with.
+const RIGHTS: [MapAction; 12] = [
+    MapAction::ReadUser,
+    MapAction::ReadKernel,
+    MapAction::ReadWriteUser,
+    MapAction::ReadWriteUserNoCache,
+    MapAction::ReadWriteKernel,
+    MapAction::ReadWriteKernelWriteCombining,
+    MapAction::ReadExecuteUser,
+    MapAction::ReadExecuteKernel,
+    MapAction::ReadWriteExecuteUser,
+    MapAction::ReadWriteExecuteKernel,
+    MapAction::ShadowStackUser,
+    MapAction::ShadowStackKernel,
+];
+
+/// An empty page-table that allocates its tables from the heap.
+fn page_table() -> PageTable {
+    let pml4 =
+        Box::new([PML4Entry::new(PAddr::from(0x0u64), PML4Flags::empty()); PAGE_SIZE_ENTRIES]);
+
+    PageTable {
+        pml4: Box::into_pin(pml4),
+        da: None,
+    }
+}
+
+/// Any of the page sizes we have page-table entries for.
+fn any_page_size() -> usize {
+    match kani::any::<u8>() {
+        0 => BASE_PAGE_SIZE,
+        1 => LARGE_PAGE_SIZE,
+        _ => HUGE_PAGE_SIZE,
+    }
+}
+
+/// Any user-space address aligned to `size` with room for `size` bytes.
+fn any_vaddr(size: usize) -> VAddr {
+    let vaddr: u64 = kani::any();
+    kani::assume(vaddr % size as u64 == 0);
+    kani::assume(vaddr < VADDR_LIMIT - size as u64);
+    VAddr::from(vaddr)
+}
+
+/// Any physical address aligned to `size` with room for `size` bytes.
+fn any_paddr(size: usize) -> PAddr {
+    let paddr: u64 = kani::any();
+    kani::assume(paddr % size as u64 == 0);
+    kani::assume(paddr < PADDR_LIMIT - size as u64);
+    PAddr::from(paddr)
+}
+
+fn any_rights() -> MapAction {
+    let idx: usize = kani::any();
+    kani::assume(idx < RIGHTS.len());
+    RIGHTS[idx]
+}
+
+/// Any offset into a page of `size` bytes.
+fn any_offset(size: usize) -> usize {
+    let offset: usize = kani::any();
+    kani::assume(offset < size);
+    offset
+}
+
+/// A page (of any size) maps exactly its range, to the frame and with the
+/// rights we gave it.
+#[kani::proof]
+#[kani::unwind(513)]
+fn map_resolves_to_frame() {
+    let mut pt = page_table();
+    let size = any_page_size();
+    let (vbase, pbase, rights) = (any_vaddr(size), any_paddr(size), any_rights());
+
+    assert_eq!(pt.map_generic(vbase, (pbase, size), rights, true), Ok(()));
+
+    let offset = any_offset(size);
+    assert_eq!(pt.resolve(vbase + offset), Ok((pbase + offset, rights)));
+
+    let outside: u64 = kani::any();
+    kani::assume(outside < VADDR_LIMIT);
+    kani::assume(outside < vbase.as_u64() || outside >= (vbase + size).as_u64());
+    assert_eq!(pt.resolve(VAddr::from(outside)), Err(KError::NotMapped));
+
+    // `Drop` walks all the tables below `KERNEL_BASE`, that's too much for the
+    // solver: leak them instead.
+    mem::forget(pt);
+}
+
+/// Mapping a page again the same way changes nothing.
+#[kani::proof]
+#[kani::unwind(513)]
+fn map_same_frame_again() {
+    let mut pt = page_table();
+    let size = any_page_size();
+    let (vbase, pbase, rights) = (any_vaddr(size), any_paddr(size), any_rights());
+
+    assert_eq!(pt.map_generic(vbase, (pbase, size), rights, true), Ok(()));
+    assert_eq!(pt.map_generic(vbase, (pbase, size), rights, false), Ok(()));
+    assert_eq!(pt.map_generic(vbase, (pbase, size), rights, true), Ok(()));
+
+    let offset = any_offset(size);
+    assert_eq!(pt.resolve(vbase + offset), Ok((pbase + offset, rights)));
+
+    mem::forget(pt);
+}
+
+/// Checking whether a page overlapping an existing one (with other rights)
+/// can be mapped fails and leaves the existing page as it is.
+///
+/// (`map_generic` panics instead if we ask it to insert the mapping.)
+#[kani::proof]
+#[kani::unwind(513)]
+fn map_does_not_overwrite() {
+    let mut pt = page_table();
+    let size = any_page_size();
+    let (vbase, pbase, rights) = (any_vaddr(size), any_paddr(size), any_rights());
+    assert_eq!(pt.map_generic(vbase, (pbase, size), rights, true), Ok(()));
+
+    let other_size = any_page_size();
+    let (other_vbase, other_pbase, other_rights) =
+        (any_vaddr(other_size), any_paddr(other_size), any_rights());
+    kani::assume(other_vbase.as_u64() < (vbase + size).as_u64());
+    kani::assume(vbase.as_u64() < (other_vbase + other_size).as_u64());
+    kani::assume(other_rights != rights);
+
+    let r = pt.map_generic(other_vbase, (other_pbase, other_size), other_rights, false);
+    assert!(matches!(r, Err(KError::AlreadyMapped { .. })));
+
+    let offset = any_offset(size);
+    assert_eq!(pt.resolve(vbase + offset), Ok((pbase + offset, rights)));
+
+    mem::forget(pt);
+}
@@ -20,6 +20,7 @@ enum TestAction {
     Map(VAddr, Frame, MapAction),
     Adjust(VAddr, MapAction),
     Resolve(VAddr),
+    Extent(VAddr),
     Unmap(VAddr),
 }
 
@@ -39,6 +40,7 @@ fn action() -> impl Strategy<Value = TestAction> {
         (vaddrs(0x60_0000), map_rights()).prop_map(|(a, b)| TestAction::Adjust(a, b)),
         vaddrs(0x60_0000).prop_map(TestAction::Unmap),
         vaddrs(0x60_0000).prop_map(TestAction::Resolve),
+        vaddrs(0x60_0000).prop_map(TestAction::Extent),
     ]
 }
 
@@ -51,11 +53,15 @@ fn map_rights() -> impl Strategy<Value = MapAction> {
         Just(MapAction::ReadUser),
         Just(MapAction::ReadKernel),
         Just(MapAction::ReadWriteUser),
+        Just(MapAction::ReadWriteUserNoCache),
         Just(MapAction::ReadWriteKernel),
+        Just(MapAction::ReadWriteKernelWriteCombining),
         Just(MapAction::ReadExecuteUser),
         Just(MapAction::ReadExecuteKernel),
         Just(MapAction::ReadWriteExecuteUser),
         Just(MapAction::ReadWriteExecuteKernel),
+        Just(MapAction::ShadowStackUser),
+        Just(MapAction::ShadowStackKernel),
     ]
 }
 
@@ -120,6 +126,11 @@ proptest! {
                     let rtotest = totest.resolve(vaddr);
                     assert_eq!(rmodel, rtotest);
                 }
+                Extent(vaddr) => {
+                    let rmodel = model.extent(vaddr);
+                    let rtotest = totest.extent(vaddr);
+                    assert_eq!(rmodel, rtotest);
+                }
                 Unmap(vaddr) => {
                     let rmodel = model.unmap(vaddr);
                     let rtotest = totest.unmap(vaddr);
@@ -0,0 +1,124 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Detects cores that stopped taking interrupts.
+//!
+//! A core arms its watchdog whenever it programs the timer and the timer
+//! interrupt disarms it again. If the interrupt is overdue by more than
+//! `GRACE` the core is spinning with interrupts disabled: the next core
+//! that takes a timer interrupt notices and sends it an NMI, which makes
+//! the stuck core dump its registers and a backtrace on the serial
+//! console (see `nmi::handle_nmi`).
+//!
+//! If no core takes interrupts anymore, a hardware watchdog (which core 0
+//! pets) fires instead.
+//!
+//! The timer interrupt also checks the stack canaries of the core, for
+//! cores that stay in the kernel and never return to user-space.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use apic::ApicDriver;
+use klogger::sprintln;
+use x86::apic::{
+    DeliveryMode, DeliveryStatus, DestinationMode, DestinationShorthand, Icr, Level, TriggerMode,
+};
+use x86::time::rdtsc;
+
+use crate::time::clocksource::{duration_to_ticks, ticks_to_duration};
+
+use super::kcb::get_kcb;
+use super::{tsc, MAX_CORES};
+
+/// How late a timer interrupt can be before we consider a core stuck.
+const GRACE: Duration = Duration::from_secs(10);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const DISARMED: AtomicU64 = AtomicU64::new(0);
+/// TSC value at which every core is stuck if it didn't take a timer
+/// interrupt yet (0 if the core doesn't expect one).
+static DEADLINES: [AtomicU64; MAX_CORES] = [DISARMED; MAX_CORES];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_STUCK: AtomicBool = AtomicBool::new(false);
+/// Cores we sent an NMI because they were stuck.
+static STUCK: [AtomicBool; MAX_CORES] = [NOT_STUCK; MAX_CORES];
+
+/// Expects a timer interrupt on the current core within `deadline`.
+pub fn arm(deadline: Duration) {
+    let ticks = duration_to_ticks(deadline + GRACE, tsc::frequency());
+    DEADLINES[get_kcb().arch.id()].store(unsafe { rdtsc() } + ticks, Ordering::Relaxed);
+}
+
+/// Stops expecting timer interrupts on the current core.
+pub fn disarm() {
+    DEADLINES[get_kcb().arch.id()].store(0, Ordering::Relaxed);
+}
+
+/// Forgets all deadlines and stuck cores (the GDB stub stopped every core
+/// for a while).
+pub fn reset() {
+    for (deadline, stuck) in DEADLINES.iter().zip(STUCK.iter()) {
+        deadline.store(0, Ordering::Relaxed);
+        stuck.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Did we send an NMI to the current core because it was stuck?
+pub fn is_stuck() -> bool {
+    STUCK[get_kcb().arch.id()].load(Ordering::Relaxed)
+}
+
+/// Sends an NMI to `core`.
+pub fn send_nmi(core: usize) {
+    let apic_id = atopology::MACHINE_TOPOLOGY.threads[core].apic_id();
+    let icr = Icr::for_x2apic(
+        0,
+        apic_id,
+        DestinationShorthand::NoShorthand,
+        DeliveryMode::NMI,
+        DestinationMode::Physical,
+        DeliveryStatus::Idle,
+        Level::Assert,
+        TriggerMode::Edge,
+    );
+    unsafe { get_kcb().arch.apic().send_ipi(icr) };
+}
+
+/// Disarms the current core, checks its stack canaries and looks for
+/// stuck cores (call this from the timer interrupt).
+pub fn check() {
+    let core = get_kcb().arch.id();
+    DEADLINES[core].store(0, Ordering::Relaxed);
+    get_kcb().arch.check_stack_canaries();
+
+    let now = unsafe { rdtsc() };
+    for (other, deadline) in DEADLINES.iter().enumerate() {
+        let stuck_at = deadline.load(Ordering::Relaxed);
+        if stuck_at == 0 || now < stuck_at {
+            continue;
+        }
+        // Only one core reports it
+        if deadline
+            .compare_exchange(stuck_at, 0, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            let late = ticks_to_duration(now - stuck_at, tsc::frequency()) + GRACE;
+            sprintln!(
+                "[watchdog] core {} missed its timer interrupt by {:?}, sending NMI",
+                other,
+                late
+            );
+            STUCK[other].store(true, Ordering::Relaxed);
+            #[cfg(feature = "lock-debug")]
+            crate::mutex::report();
+            send_nmi(other);
+        }
+    }
+
+    #[cfg(target_os = "none")]
+    if core == 0 {
+        crate::drivers::i6300esb::pet();
+    }
+}
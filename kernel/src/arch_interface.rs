@@ -0,0 +1,71 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The interface between the architecture independent parts of the kernel
+//! and an `arch` backend.
+//!
+//! A backend (`arch/x86_64`, or `arch/unix` which runs the kernel as a
+//! regular process for tests) implements [`Arch`] for a unit type that it
+//! exports as `crate::arch::Platform`, and `ArchSpecificKcb` for its part of
+//! the KCB (per-core state, e.g., the executor that currently runs on the
+//! core). The memory manager, the scheduler and the process code reach the
+//! hardware only through these two traits and the `AddressSpace`, `Process`
+//! and `Executor` traits.
+//!
+//! Besides that, every backend exports the same few constants, types and
+//! helpers that are plain data: `MAX_CORES`, `MAX_NUMA_NODES`,
+//! `KernelArgs`, `Module`, `VirtualCpu`, `memory::paddr_to_kernel_vaddr`,
+//! `process::{spawn, UserSlice, PROCESS_TABLE}` and `user_access`.
+//! Everything else in a backend (the APIC driver in the x86 KCB, IDT, GDT,
+//! ACPI, ...) is private to it: drivers and tests that need those are arch
+//! specific themselves.
+//!
+//! Cores are brought up by the backend before it calls `xmain` on each of
+//! them.
+
+use core::time::Duration;
+
+use crate::error::KError;
+use crate::kcb::ArchSpecificKcb;
+use crate::memory::vspace::MapAction;
+use crate::memory::{PAddr, VAddr};
+
+pub trait Arch {
+    /// The part of the KCB that belongs to the backend.
+    type Kcb: ArchSpecificKcb;
+
+    /// When the timer interrupts a core that runs a process by default (to
+    /// advance the replicas).
+    const DEFAULT_TIMER_DEADLINE: Duration;
+
+    /// How many cores have been brought up (and run the kernel).
+    fn online_cores() -> usize;
+
+    /// Puts the current core to sleep with interrupts enabled, never
+    /// returns.
+    fn halt() -> !;
+
+    /// Enables interrupts on the current core.
+    fn enable_irqs();
+
+    /// Disables interrupts on the current core.
+    fn disable_irqs();
+
+    /// Interrupts the current core after `deadline`.
+    fn set_timer(deadline: Duration);
+
+    /// Maps `pregion` at `vbase` in the kernel address space.
+    fn map_kernel(vbase: VAddr, pregion: (PAddr, usize), action: MapAction) -> Result<(), KError>;
+
+    /// Maps the physical range [`base`, `base` + `size`] at the same
+    /// virtual address in the kernel address space (e.g., device
+    /// registers).
+    fn map_kernel_identity(base: PAddr, size: usize, action: MapAction) -> Result<(), KError> {
+        Self::map_kernel(VAddr::from(base.as_u64()), (base, size), action)
+    }
+
+    /// Applies outstanding updates of the file-system replica of this core
+    /// (for cores that have nothing to run and poll instead of waiting for
+    /// an IPI).
+    fn advance_fs_replica();
+}
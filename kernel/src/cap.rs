@@ -0,0 +1,330 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Capability tables: what the handles of a process refer to.
+//!
+//! Every process has a [`CapTable`] (in its `NrProcess`, so it's replicated
+//! like the rest of the process). System calls that take a handle look it up
+//! with [`NrProcess::capability`](crate::nrproc::NrProcess::capability) and
+//! check the kind and rights before they touch the object, the objects
+//! themselves stay where they were (the file descriptors in `cnrfs`, the
+//! frames in the process, doors and events in `ipc` and `event`, VMs in
+//! `arch::hypervisor`).
+//!
+//! A handle is the slot in the table and the generation of the slot, which
+//! changes whenever the slot is reused: a stale handle doesn't suddenly
+//! refer to another object. Handles fit in 31 bits for programs that store
+//! them in a C `int`.
+
+use alloc::vec::Vec;
+
+use fallible_collections::FallibleVec;
+use kpi::cap::{CapKind, CapRights};
+use kpi::process::FrameId;
+
+use crate::error::KError;
+use crate::event::EventId;
+use crate::fs::FD;
+use crate::ipc::DoorId;
+
+/// How many capabilities a process can have.
+pub const MAX_CAPABILITIES: usize = 4096;
+
+/// Bits of a handle that select the slot.
+const SLOT_BITS: u64 = 16;
+/// Generations wrap around before the handle needs more than 31 bits.
+const MAX_GENERATION: u16 = 0x7fff;
+
+/// A kernel object a process can have a capability for.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Object {
+    /// A file descriptor of the process in `cnrfs`.
+    File(FD),
+    /// A frame registered with the process.
+    Frame(FrameId),
+    /// A core allocated to the process.
+    Core(atopology::GlobalThreadId),
+    /// A door in `ipc::DOORS`.
+    Door(DoorId),
+    /// An event in `event::EVENTS`.
+    Event(EventId),
+    /// A VM (on x86-64 in `arch::hypervisor::vm::VMS`).
+    Vm(usize),
+}
+
+impl Object {
+    pub fn kind(&self) -> CapKind {
+        match self {
+            Object::File(_) => CapKind::File,
+            Object::Frame(_) => CapKind::Frame,
+            Object::Core(_) => CapKind::Core,
+            Object::Door(_) => CapKind::Door,
+            Object::Event(_) => CapKind::Event,
+            Object::Vm(_) => CapKind::Vm,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Capability {
+    pub object: Object,
+    pub rights: CapRights,
+}
+
+impl Capability {
+    pub fn new(object: Object, rights: CapRights) -> Capability {
+        Capability { object, rights }
+    }
+
+    /// Fails with `InsufficientRights` unless the capability has all of
+    /// `rights`.
+    pub fn check(&self, rights: CapRights) -> Result<(), KError> {
+        if self.rights.contains(rights) {
+            Ok(())
+        } else {
+            Err(KError::InsufficientRights)
+        }
+    }
+
+    /// The file descriptor, if this is a file capability with `rights`.
+    pub fn file(&self, rights: CapRights) -> Result<FD, KError> {
+        match self.object {
+            Object::File(fd) => self.check(rights).map(|_| fd),
+            _ => Err(KError::InvalidCapability),
+        }
+    }
+
+    /// The frame id, if this is a frame capability with `rights`.
+    pub fn frame(&self, rights: CapRights) -> Result<FrameId, KError> {
+        match self.object {
+            Object::Frame(fid) => self.check(rights).map(|_| fid),
+            _ => Err(KError::InvalidCapability),
+        }
+    }
+
+    /// The door id, if this is a door capability with `rights`.
+    pub fn door(&self, rights: CapRights) -> Result<DoorId, KError> {
+        match self.object {
+            Object::Door(door) => self.check(rights).map(|_| door),
+            _ => Err(KError::InvalidCapability),
+        }
+    }
+
+    /// The event id, if this is an event capability with `rights`.
+    pub fn event(&self, rights: CapRights) -> Result<EventId, KError> {
+        match self.object {
+            Object::Event(event) => self.check(rights).map(|_| event),
+            _ => Err(KError::InvalidCapability),
+        }
+    }
+
+    /// The VM id, if this is a VM capability with `rights`.
+    pub fn vm(&self, rights: CapRights) -> Result<usize, KError> {
+        match self.object {
+            Object::Vm(vm) => self.check(rights).map(|_| vm),
+            _ => Err(KError::InvalidCapability),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Slot {
+    generation: u16,
+    capability: Option<Capability>,
+}
+
+impl Slot {
+    /// Empties the slot, the handles for it become stale.
+    fn clear(&mut self) -> Option<Capability> {
+        self.generation = if self.generation == MAX_GENERATION {
+            1
+        } else {
+            self.generation + 1
+        };
+        self.capability.take()
+    }
+}
+
+/// The capabilities of a process.
+#[derive(Debug, Default)]
+pub struct CapTable {
+    slots: Vec<Slot>,
+}
+
+impl CapTable {
+    fn handle(slot: usize, generation: u16) -> u64 {
+        (generation as u64) << SLOT_BITS | slot as u64
+    }
+
+    fn slot(&self, handle: u64) -> Result<usize, KError> {
+        let slot = (handle & ((1 << SLOT_BITS) - 1)) as usize;
+        let generation = handle >> SLOT_BITS;
+        match self.slots.get(slot) {
+            Some(s) if s.capability.is_some() && s.generation as u64 == generation => Ok(slot),
+            _ => Err(KError::InvalidCapability),
+        }
+    }
+
+    /// Adds `capability` to the table, returns its handle.
+    pub fn insert(&mut self, capability: Capability) -> Result<u64, KError> {
+        if let Some(slot) = self.slots.iter().position(|s| s.capability.is_none()) {
+            let s = &mut self.slots[slot];
+            s.capability = Some(capability);
+            return Ok(CapTable::handle(slot, s.generation));
+        }
+
+        if self.slots.len() >= MAX_CAPABILITIES {
+            return Err(KError::TooManyCapabilities);
+        }
+        self.slots.try_push(Slot {
+            generation: 1,
+            capability: Some(capability),
+        })?;
+        Ok(CapTable::handle(self.slots.len() - 1, 1))
+    }
+
+    /// Adds `capability` as `handle` (for a restored process that expects
+    /// its old handles), fails if the slot is taken.
+    pub fn insert_at(&mut self, handle: u64, capability: Capability) -> Result<(), KError> {
+        let slot = (handle & ((1 << SLOT_BITS) - 1)) as usize;
+        let generation = handle >> SLOT_BITS;
+        if slot >= MAX_CAPABILITIES || generation == 0 || generation > MAX_GENERATION as u64 {
+            return Err(KError::InvalidCapability);
+        }
+        while self.slots.len() <= slot {
+            self.slots.try_push(Slot {
+                generation: 1,
+                capability: None,
+            })?;
+        }
+
+        let s = &mut self.slots[slot];
+        if s.capability.is_some() {
+            return Err(KError::InvalidCapability);
+        }
+        s.generation = generation as u16;
+        s.capability = Some(capability);
+        Ok(())
+    }
+
+    /// All capabilities in the table with their handles.
+    pub fn capabilities(&self) -> impl Iterator<Item = (u64, Capability)> + '_ {
+        self.slots.iter().enumerate().filter_map(|(slot, s)| {
+            s.capability
+                .map(|capability| (CapTable::handle(slot, s.generation), capability))
+        })
+    }
+
+    pub fn get(&self, handle: u64) -> Result<Capability, KError> {
+        let slot = self.slot(handle)?;
+        Ok(self.slots[slot].capability.unwrap())
+    }
+
+    /// Drops all rights of `handle` that aren't in `rights`.
+    pub fn restrict(&mut self, handle: u64, rights: CapRights) -> Result<(), KError> {
+        let slot = self.slot(handle)?;
+        if let Some(capability) = self.slots[slot].capability.as_mut() {
+            capability.rights &= rights;
+        }
+        Ok(())
+    }
+
+    /// Removes `handle` from the table, the handle is never valid again
+    /// (until the generation wraps around).
+    pub fn remove(&mut self, handle: u64) -> Result<Capability, KError> {
+        let slot = self.slot(handle)?;
+        Ok(self.slots[slot].clear().unwrap())
+    }
+
+    /// Removes all capabilities for `object`.
+    pub fn remove_object(&mut self, object: Object) {
+        for s in self.slots.iter_mut() {
+            if s.capability.map(|c| c.object) == Some(object) {
+                s.clear();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn file(fd: FD) -> Capability {
+        Capability::new(Object::File(fd), CapRights::READ | CapRights::GRANT)
+    }
+
+    #[test]
+    fn handles() {
+        let mut caps = CapTable::default();
+        let a = caps.insert(file(3)).unwrap();
+        let b = caps.insert(file(4)).unwrap();
+        assert_ne!(a, 0);
+        assert_ne!(a, b);
+        assert_eq!(caps.get(b).unwrap().file(CapRights::READ), Ok(4));
+        assert_eq!(caps.get(a + 7), Err(KError::InvalidCapability));
+    }
+
+    #[test]
+    fn stale_handles() {
+        let mut caps = CapTable::default();
+        let a = caps.insert(file(3)).unwrap();
+        assert_eq!(caps.remove(a).unwrap().object, Object::File(3));
+        assert_eq!(caps.remove(a), Err(KError::InvalidCapability));
+
+        // Same slot, but the old handle doesn't refer to it
+        let b = caps.insert(file(5)).unwrap();
+        assert_ne!(a, b);
+        assert_eq!(caps.get(a), Err(KError::InvalidCapability));
+        assert!(b < i32::MAX as u64);
+    }
+
+    #[test]
+    fn rights() {
+        let mut caps = CapTable::default();
+        let a = caps.insert(file(3)).unwrap();
+        assert_eq!(
+            caps.get(a).unwrap().file(CapRights::WRITE),
+            Err(KError::InsufficientRights)
+        );
+        assert_eq!(
+            caps.get(a).unwrap().frame(CapRights::READ),
+            Err(KError::InvalidCapability)
+        );
+
+        // Can't add rights back
+        caps.restrict(a, CapRights::GRANT).unwrap();
+        caps.restrict(a, CapRights::all()).unwrap();
+        assert_eq!(caps.get(a).unwrap().rights, CapRights::GRANT);
+    }
+
+    #[test]
+    fn insert_at() {
+        let mut old = CapTable::default();
+        let a = old.insert(file(3)).unwrap();
+        old.remove(a).unwrap();
+        let b = old.insert(file(4)).unwrap();
+        let c = old.insert(file(5)).unwrap();
+
+        let mut caps = CapTable::default();
+        for (handle, capability) in old.capabilities() {
+            caps.insert_at(handle, capability).unwrap();
+        }
+        assert_eq!(caps.get(b).unwrap().object, Object::File(4));
+        assert_eq!(caps.get(c).unwrap().object, Object::File(5));
+        assert_eq!(caps.get(a), Err(KError::InvalidCapability));
+        assert_eq!(caps.insert_at(c, file(6)), Err(KError::InvalidCapability));
+        assert_eq!(caps.capabilities().count(), 2);
+    }
+
+    #[test]
+    fn remove_object() {
+        let mut caps = CapTable::default();
+        let core = Capability::new(Object::Core(1), CapRights::empty());
+        let a = caps.insert(core).unwrap();
+        let b = caps.insert(file(3)).unwrap();
+        caps.remove_object(Object::Core(1));
+        assert_eq!(caps.get(a), Err(KError::InvalidCapability));
+        assert!(caps.get(b).is_ok());
+    }
+}
@@ -0,0 +1,518 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Checkpoints of processes (`Process::checkpoint`) and restoring them.
+//!
+//! A checkpoint is a file with what it takes to start the process again
+//! where it made the system call: the binary (by name, we load it again
+//! from the modules or the initrd), credentials, address layout and
+//! registers, the mappings with their contents and the open files (path,
+//! flags and offset). Restoring one creates a new process from the binary
+//! with the old layout, puts the contents back and starts it with the old
+//! registers, the system call returns again, this time in the new process.
+//!
+//! We don't save what the restored process gets anyway: a page that is clean
+//! in the page-table (see `AddressSpace::dirty`) has what the kernel put
+//! there when it was mapped, zeroes or the binary or executor state. The
+//! kernel marks pages dirty if it fills them some other way (swap, restore).
+//!
+//! This file has the image format, `arch::checkpoint` takes and restores
+//! them.
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::{TryFrom, TryInto};
+
+use fallible_collections::{FallibleVec, FallibleVecGlobal};
+use kpi::cap::CapRights;
+use kpi::filter::MAX_SYSCALLS;
+use kpi::process::{AddressLayout, Credentials};
+
+use crate::error::KError;
+use crate::fallible_string::TryString;
+use crate::memory::vspace::MapAction;
+use crate::memory::BASE_PAGE_SIZE;
+use crate::process::Eid;
+
+/// First bytes of a checkpoint.
+const MAGIC: [u8; 8] = *b"NRKCKPT\0";
+
+/// Changes whenever the format does, we don't restore older checkpoints.
+const VERSION: u32 = 1;
+
+/// How many bytes we hand to the sink at once.
+pub const CHUNK: usize = 64 * 1024;
+
+/// Set for a mapping that came from `VSpace::map` (it can be evicted).
+const ANONYMOUS: u8 = 1 << 0;
+/// Set for a mapping we saved the contents of.
+const HAS_DATA: u8 = 1 << 1;
+
+/// The process as a whole.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    /// Name of the module or initrd program the process runs.
+    pub binary: String,
+    /// `hash` of the binary, we only restore it with the same one.
+    pub binary_hash: u64,
+    pub creds: Credentials,
+    pub layout: AddressLayout,
+    /// The executor of the core that made the checkpoint (user-space knows
+    /// where its vCPU area is).
+    pub eid: Eid,
+    /// Where the next `VSpace::map_anywhere` goes.
+    pub mmap_next: u64,
+    /// The system call filter, if the process restricted itself.
+    pub filter: Option<[u64; MAX_SYSCALLS]>,
+    /// The registers (the save area of the architecture).
+    pub registers: Vec<u8>,
+}
+
+/// A mapping of the process.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mapping<'a> {
+    pub base: u64,
+    pub size: u64,
+    pub rights: MapAction,
+    pub anonymous: bool,
+    /// The contents (`size` bytes), `None` if the restored process gets
+    /// them anyway.
+    pub data: Option<&'a [u8]>,
+}
+
+/// An open file of the process.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenFile {
+    /// The handle of its capability.
+    pub handle: u64,
+    pub rights: CapRights,
+    pub path: String,
+    pub flags: u64,
+    pub offset: usize,
+}
+
+/// A checkpoint we read.
+#[derive(Debug)]
+pub struct Image<'a> {
+    pub header: Header,
+    pub files: Vec<OpenFile>,
+    pub mappings: Vec<Mapping<'a>>,
+}
+
+/// FNV-1a of `bytes`, to recognize the binary again.
+pub fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+fn rights_to_u8(rights: MapAction) -> Result<u8, KError> {
+    match rights {
+        MapAction::ReadUser => Ok(1),
+        MapAction::ReadWriteUser => Ok(2),
+        MapAction::ReadExecuteUser => Ok(3),
+        MapAction::ReadWriteExecuteUser => Ok(4),
+        _ => Err(KError::NotSupported),
+    }
+}
+
+fn rights_from_u8(rights: u8) -> Result<MapAction, KError> {
+    match rights {
+        1 => Ok(MapAction::ReadUser),
+        2 => Ok(MapAction::ReadWriteUser),
+        3 => Ok(MapAction::ReadExecuteUser),
+        4 => Ok(MapAction::ReadWriteExecuteUser),
+        _ => Err(KError::InvalidCheckpoint),
+    }
+}
+
+/// Collects the encoded image and hands it to a sink in `CHUNK`s.
+struct Writer<F: FnMut(&[u8]) -> Result<(), KError>> {
+    buf: Vec<u8>,
+    sink: F,
+    written: usize,
+}
+
+impl<F: FnMut(&[u8]) -> Result<(), KError>> Writer<F> {
+    fn bytes(&mut self, mut bytes: &[u8]) -> Result<(), KError> {
+        while !bytes.is_empty() {
+            let n = core::cmp::min(CHUNK - self.buf.len(), bytes.len());
+            self.buf.try_extend_from_slice(&bytes[..n])?;
+            bytes = &bytes[n..];
+            if self.buf.len() == CHUNK {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn u8(&mut self, v: u8) -> Result<(), KError> {
+        self.bytes(&[v])
+    }
+
+    fn u32(&mut self, v: u32) -> Result<(), KError> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn u64(&mut self, v: u64) -> Result<(), KError> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn str(&mut self, s: &str) -> Result<(), KError> {
+        self.u32(s.len() as u32)?;
+        self.bytes(s.as_bytes())
+    }
+
+    fn flush(&mut self) -> Result<(), KError> {
+        if !self.buf.is_empty() {
+            (self.sink)(&self.buf)?;
+            self.written += self.buf.len();
+            self.buf.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Encodes a checkpoint, hands it to `sink` piece by piece (so we don't
+/// have to keep a copy of the whole process around), returns its size.
+pub fn encode<F: FnMut(&[u8]) -> Result<(), KError>>(
+    header: &Header,
+    files: &[OpenFile],
+    mappings: &[Mapping],
+    sink: F,
+) -> Result<usize, KError> {
+    let mut w = Writer {
+        buf: Vec::try_with_capacity(CHUNK)?,
+        sink,
+        written: 0,
+    };
+
+    w.bytes(&MAGIC)?;
+    w.u32(VERSION)?;
+    w.str(&header.binary)?;
+    w.u64(header.binary_hash)?;
+    w.u32(header.creds.uid)?;
+    w.u32(header.creds.gid)?;
+    w.u64(header.layout.stack_start)?;
+    w.u64(header.layout.heap_start)?;
+    w.u64(header.layout.mmap_start)?;
+    w.u64(header.eid as u64)?;
+    w.u64(header.mmap_next)?;
+    match &header.filter {
+        Some(filter) => {
+            w.u8(1)?;
+            for ops in filter.iter() {
+                w.u64(*ops)?;
+            }
+        }
+        None => w.u8(0)?,
+    }
+    w.u32(header.registers.len() as u32)?;
+    w.bytes(&header.registers)?;
+
+    w.u32(files.len() as u32)?;
+    for file in files {
+        w.u64(file.handle)?;
+        w.u64(file.rights.bits())?;
+        w.u64(file.flags)?;
+        w.u64(file.offset as u64)?;
+        w.str(&file.path)?;
+    }
+
+    w.u32(mappings.len() as u32)?;
+    for mapping in mappings {
+        let mut flags = 0;
+        if mapping.anonymous {
+            flags |= ANONYMOUS;
+        }
+        if let Some(data) = mapping.data {
+            debug_assert_eq!(data.len() as u64, mapping.size);
+            flags |= HAS_DATA;
+        }
+        w.u64(mapping.base)?;
+        w.u64(mapping.size)?;
+        w.u8(rights_to_u8(mapping.rights)?)?;
+        w.u8(flags)?;
+        if let Some(data) = mapping.data {
+            w.bytes(data)?;
+        }
+    }
+
+    w.flush()?;
+    Ok(w.written)
+}
+
+/// Reads an image, all errors are `InvalidCheckpoint`.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], KError> {
+        if self.data.len() < n {
+            return Err(KError::InvalidCheckpoint);
+        }
+        let (bytes, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, KError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, KError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, KError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<String, KError> {
+        let len = self.u32()? as usize;
+        let bytes = self.bytes(len)?;
+        let s = core::str::from_utf8(bytes).map_err(|_e| KError::InvalidCheckpoint)?;
+        Ok(TryString::try_from(s)?.into())
+    }
+}
+
+/// Reads a checkpoint, the contents of the mappings stay in `data`.
+pub fn decode(data: &[u8]) -> Result<Image, KError> {
+    let mut r = Reader { data };
+    if r.bytes(MAGIC.len())? != MAGIC || r.u32()? != VERSION {
+        return Err(KError::InvalidCheckpoint);
+    }
+
+    let binary = r.str()?;
+    let binary_hash = r.u64()?;
+    let creds = Credentials {
+        uid: r.u32()?,
+        gid: r.u32()?,
+    };
+    let layout = AddressLayout {
+        stack_start: r.u64()?,
+        heap_start: r.u64()?,
+        mmap_start: r.u64()?,
+    };
+    let eid = r.u64()? as Eid;
+    let mmap_next = r.u64()?;
+    let filter = match r.u8()? {
+        0 => None,
+        1 => {
+            let mut filter = [0; MAX_SYSCALLS];
+            for ops in filter.iter_mut() {
+                *ops = r.u64()?;
+            }
+            Some(filter)
+        }
+        _ => return Err(KError::InvalidCheckpoint),
+    };
+    let len = r.u32()? as usize;
+    let mut registers = Vec::try_with_capacity(len)?;
+    registers.try_extend_from_slice(r.bytes(len)?)?;
+
+    let header = Header {
+        binary,
+        binary_hash,
+        creds,
+        layout,
+        eid,
+        mmap_next,
+        filter,
+        registers,
+    };
+
+    let count = r.u32()? as usize;
+    let mut files = Vec::new();
+    for _ in 0..count {
+        let handle = r.u64()?;
+        let rights = CapRights::from_bits(r.u64()?).ok_or(KError::InvalidCheckpoint)?;
+        let flags = r.u64()?;
+        let offset = r.u64()? as usize;
+        let path = r.str()?;
+        files.try_push(OpenFile {
+            handle,
+            rights,
+            path,
+            flags,
+            offset,
+        })?;
+    }
+
+    let count = r.u32()? as usize;
+    let mut mappings = Vec::new();
+    for _ in 0..count {
+        let base = r.u64()?;
+        let size = r.u64()?;
+        let rights = rights_from_u8(r.u8()?)?;
+        let flags = r.u8()?;
+        let end = base.checked_add(size).ok_or(KError::InvalidCheckpoint)?;
+        let page = BASE_PAGE_SIZE as u64;
+        if size == 0 || base % page != 0 || size % page != 0 || end > kpi::KERNEL_BASE {
+            return Err(KError::InvalidCheckpoint);
+        }
+        let data = if flags & HAS_DATA != 0 {
+            Some(r.bytes(size as usize)?)
+        } else {
+            None
+        };
+        mappings.try_push(Mapping {
+            base,
+            size,
+            rights,
+            anonymous: flags & ANONYMOUS != 0,
+            data,
+        })?;
+    }
+
+    if !r.data.is_empty() {
+        return Err(KError::InvalidCheckpoint);
+    }
+    Ok(Image {
+        header,
+        files,
+        mappings,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header() -> Header {
+        Header {
+            binary: String::from("init"),
+            binary_hash: hash(b"\x7fELF"),
+            creds: Credentials {
+                uid: 1000,
+                gid: 100,
+            },
+            layout: AddressLayout::FIXED,
+            eid: 3,
+            mmap_next: AddressLayout::FIXED.mmap_start + 0x3000,
+            filter: None,
+            registers: alloc::vec![7u8; 704],
+        }
+    }
+
+    fn encode_all(header: &Header, files: &[OpenFile], mappings: &[Mapping]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut chunks = 0;
+        let len = encode(header, files, mappings, |chunk| {
+            assert!(chunk.len() <= CHUNK);
+            chunks += 1;
+            out.extend_from_slice(chunk);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(len, out.len());
+        assert_eq!(chunks, (len + CHUNK - 1) / CHUNK);
+        out
+    }
+
+    #[test]
+    fn roundtrip() {
+        let page = alloc::vec![0xabu8; 4096];
+        let big = alloc::vec![0x5au8; 2 * 1024 * 1024];
+        let mut header = header();
+        header.filter = Some([0x1f; MAX_SYSCALLS]);
+        let files = [OpenFile {
+            handle: 0x2_0001,
+            rights: CapRights::READ | CapRights::WRITE,
+            path: String::from("/data/log"),
+            flags: 0x3,
+            offset: 1234,
+        }];
+        let mappings = [
+            Mapping {
+                base: 0x10_0000,
+                size: 4096,
+                rights: MapAction::ReadWriteUser,
+                anonymous: true,
+                data: Some(&page),
+            },
+            Mapping {
+                base: 0x20_0000,
+                size: 4096,
+                rights: MapAction::ReadExecuteUser,
+                anonymous: false,
+                data: None,
+            },
+            Mapping {
+                base: 0x40_0000,
+                size: big.len() as u64,
+                rights: MapAction::ReadWriteUser,
+                anonymous: false,
+                data: Some(&big),
+            },
+        ];
+
+        let image = encode_all(&header, &files, &mappings);
+        let decoded = decode(&image).unwrap();
+        assert_eq!(decoded.header, header);
+        assert_eq!(&decoded.files[..], &files[..]);
+        assert_eq!(&decoded.mappings[..], &mappings[..]);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let image = encode_all(&header(), &[], &[]);
+        assert!(decode(&image).is_ok());
+
+        // Truncated
+        assert_eq!(
+            decode(&image[..image.len() - 1]).unwrap_err(),
+            KError::InvalidCheckpoint
+        );
+        // Trailing bytes
+        let mut longer = image.clone();
+        longer.push(0);
+        assert_eq!(decode(&longer).unwrap_err(), KError::InvalidCheckpoint);
+        // Another version
+        let mut other = image.clone();
+        other[MAGIC.len()] += 1;
+        assert_eq!(decode(&other).unwrap_err(), KError::InvalidCheckpoint);
+    }
+
+    #[test]
+    fn kernel_mappings_are_invalid() {
+        let mappings = [Mapping {
+            base: kpi::KERNEL_BASE - 4096,
+            size: 8192,
+            rights: MapAction::ReadUser,
+            anonymous: false,
+            data: None,
+        }];
+        let image = encode_all(&header(), &[], &mappings);
+        assert_eq!(decode(&image).unwrap_err(), KError::InvalidCheckpoint);
+
+        // Not page-aligned
+        let mappings = [Mapping {
+            base: 0x1800,
+            size: 4096,
+            rights: MapAction::ReadUser,
+            anonymous: false,
+            data: None,
+        }];
+        let image = encode_all(&header(), &[], &mappings);
+        assert_eq!(decode(&image).unwrap_err(), KError::InvalidCheckpoint);
+    }
+
+    #[test]
+    fn only_user_rights() {
+        let mappings = [Mapping {
+            base: 0x1000,
+            size: 4096,
+            rights: MapAction::ShadowStackUser,
+            anonymous: false,
+            data: None,
+        }];
+        assert_eq!(
+            encode(&header(), &[], &mappings, |_chunk| Ok(())),
+            Err(KError::NotSupported)
+        );
+    }
+}
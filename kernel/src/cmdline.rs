@@ -0,0 +1,771 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The kernel command-line.
+//!
+//! The bootloader hands us a line of options separated by spaces: flags
+//! (`syscall_latency`) and `key=value` pairs, where values with spaces are
+//! quoted (`appcmd='--threads=1 --num=50000'`). An optional first word
+//! starting with `./` is the kernel binary and ignored. We parse everything
+//! once at boot into a [`KernelConfig`] (kept in the KCB), so the rest of the
+//! kernel deals with typed values and doesn't parse strings.
+//!
+//! Options we don't know or can't parse are remembered and logged with
+//! [`KernelConfig::warn_ignored`] once logging works (the log filter is an
+//! option itself).
+//!
+//! | Option            | Value                                      |
+//! |-------------------|--------------------------------------------|
+//! | `log`             | Log filter (e.g., `'warn,nrk::memory=trace'`) |
+//! | `init`            | Binary of the first process                |
+//! | `initargs`        | Arguments for init (e.g., the test or benchmark to run) |
+//! | `tests`           | Tests init runs (e.g., `map,fs,scheduler`)  |
+//! | `appcmd`          | Arguments for the (rump) application       |
+//! | `net`             | `dhcp` or `static:<ip>/<prefix>[,<gateway>]` |
+//! | `mem`             | Use at most this much memory (e.g., `512M`) |
+//! | `sched`           | What idle cores do: `poll` or `halt`       |
+//! | `corepolicy`      | Which free core a process gets: `packed`, `spread` or `smt` |
+//! | `clocksource`     | Clocksource to use (e.g., `hpet` or `tsc`) |
+//! | `crashdump`       | Where crash dumps go (`pmem`, `<dev>` or `'<dev>:<lba>'`) |
+//! | `initrd`          | Module to unpack into the file-system      |
+//! | `inituser`        | Run init as `<uid>[:<gid>]` (default `0:0`) |
+//! | `syscall_latency` | Record system call latencies (flag)        |
+//! | `noaslr`          | Same address-space layout for every process (flag) |
+//! | `cet`             | Control-flow enforcement: `off`, `kernel` or `user` |
+//! | `idle`            | Deepest idle state: `poll`, `c1`, `c1e` or `c6` |
+//! | `cpufreq`         | Frequency governor: `ondemand`, `performance` or `off` |
+//! | `faults`          | Seed for fault injection (needs `fault-injection`) |
+//! | `zswap`           | Size of the compressed RAM pool for evicted pages (e.g., `64M`) |
+//! | `swap`            | Where evicted pages go if the pool is full (`<dev>` or `'<dev>:<lba>'`) |
+//! | `fsjournal`       | Journal of the file-system metadata (`<dev>` or `'<dev>:<lba>'`) |
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use core::slice::from_raw_parts;
+
+use arrayvec::ArrayVec;
+use kpi::process::Credentials;
+use kpi::system::{CorePolicy, IdleState};
+use log::warn;
+
+use crate::arch::memory::paddr_to_kernel_vaddr;
+use crate::memory::{PAddr, BASE_PAGE_SIZE};
+use crate::net::IpConfig;
+
+/// How many ignored options we remember (to warn about them later).
+const MAX_IGNORED: usize = 8;
+
+/// What a core does when there is nothing to run.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SchedulerPolicy {
+    /// The first core of a replica keeps advancing the replicas (and polls
+    /// the network), the others halt until the next timer interrupt.
+    Poll,
+    /// All idle cores halt until the next timer interrupt.
+    Halt,
+}
+
+impl SchedulerPolicy {
+    fn parse(policy: &str) -> Result<SchedulerPolicy, &'static str> {
+        match policy {
+            "poll" => Ok(SchedulerPolicy::Poll),
+            "halt" => Ok(SchedulerPolicy::Halt),
+            _ => Err("should be poll or halt"),
+        }
+    }
+}
+
+/// What we use control-flow enforcement (shadow stacks and indirect-branch
+/// tracking) for, on CPUs that have it (see `arch::cet`).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CetPolicy {
+    /// Not at all.
+    Off,
+    /// The kernel only.
+    Kernel,
+    /// The kernel and all processes (their binaries and runtime have to be
+    /// built for it).
+    User,
+}
+
+impl CetPolicy {
+    fn parse(policy: &str) -> Result<CetPolicy, &'static str> {
+        match policy {
+            "off" => Ok(CetPolicy::Off),
+            "kernel" => Ok(CetPolicy::Kernel),
+            "user" => Ok(CetPolicy::User),
+            _ => Err("should be off, kernel or user"),
+        }
+    }
+}
+
+/// How the kernel sets the frequency of cores (see `crate::cpufreq`), unless
+/// a process pins it.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CpufreqPolicy {
+    /// Follow the load.
+    Ondemand,
+    /// Always the highest frequency.
+    Performance,
+    /// Leave it to the firmware (processes can't pin it either).
+    Off,
+}
+
+impl CpufreqPolicy {
+    fn parse(policy: &str) -> Result<CpufreqPolicy, &'static str> {
+        match policy {
+            "ondemand" => Ok(CpufreqPolicy::Ondemand),
+            "performance" => Ok(CpufreqPolicy::Performance),
+            "off" => Ok(CpufreqPolicy::Off),
+            _ => Err("should be ondemand, performance or off"),
+        }
+    }
+}
+
+/// The configuration from the kernel command-line.
+#[derive(Clone, Debug)]
+pub struct KernelConfig {
+    /// Log filter (see `console::set_filter`).
+    pub log_filter: &'static str,
+    pub init_binary: &'static str,
+    pub init_args: &'static str,
+    /// Tests init runs (names separated by commas, init checks them).
+    pub init_tests: &'static str,
+    pub app_args: &'static str,
+    /// Configuration of the kernel network stack (we don't start it if
+    /// unset).
+    pub net: Option<IpConfig>,
+    /// Physical memory we use at most (in bytes).
+    pub memory_limit: Option<usize>,
+    pub scheduler: SchedulerPolicy,
+    /// How we pick a core for processes that take any (see
+    /// `scheduler::policy`).
+    pub core_policy: CorePolicy,
+    /// Clocksource to use (we pick the best one if unset).
+    pub clocksource: Option<&'static str>,
+    /// Where crash dumps go (we don't write them if unset).
+    pub crashdump: Option<&'static str>,
+    /// Name of the module we unpack into the file-system at boot.
+    pub initrd: &'static str,
+    /// Who the first process runs as.
+    pub init_creds: Credentials,
+    /// Record system call latencies (in `/proc/syscall_latency`).
+    pub syscall_latency: bool,
+    /// Randomize where the stacks, heap and mappings of processes are
+    /// (`kpi::process::AddressLayout`).
+    pub aslr: bool,
+    pub cet: CetPolicy,
+    /// The deepest idle state cores go to (if the CPU has it).
+    pub idle: IdleState,
+    pub cpufreq: CpufreqPolicy,
+    /// Inject faults, where depends on the seed (see `fault`).
+    pub fault_seed: Option<u64>,
+    /// Bytes of compressed pages we keep in memory (see `memory::swap`).
+    pub zswap: Option<usize>,
+    /// Block device for evicted pages (we only use the pool if unset).
+    pub swap: Option<&'static str>,
+    /// Block device for the journal of the file-system (nothing survives a
+    /// reboot if unset, see `fs::journal`).
+    pub fsjournal: Option<&'static str>,
+    /// Options we didn't use and why.
+    ignored: ArrayVec<(&'static str, &'static str), MAX_IGNORED>,
+}
+
+impl Default for KernelConfig {
+    fn default() -> KernelConfig {
+        KernelConfig::new()
+    }
+}
+
+impl KernelConfig {
+    /// The configuration for an empty command-line.
+    pub const fn new() -> KernelConfig {
+        KernelConfig {
+            log_filter: "info",
+            init_binary: "init",
+            init_args: "",
+            init_tests: "",
+            app_args: "",
+            net: None,
+            memory_limit: None,
+            scheduler: SchedulerPolicy::Poll,
+            core_policy: CorePolicy::Packed,
+            clocksource: None,
+            crashdump: None,
+            initrd: crate::initrd::INITRD_MODULE,
+            init_creds: Credentials::ROOT,
+            syscall_latency: false,
+            aslr: true,
+            cet: CetPolicy::Kernel,
+            idle: IdleState::C6,
+            cpufreq: CpufreqPolicy::Ondemand,
+            fault_seed: None,
+            zswap: None,
+            swap: None,
+            fsjournal: None,
+            ignored: ArrayVec::new_const(),
+        }
+    }
+
+    /// Parses the command-line the bootloader left for us at (physical
+    /// address) `args`.
+    pub fn from_bootloader(args: &'static str) -> KernelConfig {
+        let args_kaddr = paddr_to_kernel_vaddr(PAddr::from(args.as_ptr() as u64));
+        // Safe: Depends on bootloader setting up identity mapping abobe `KERNEL_BASE`.
+        let args_kslice = unsafe { from_raw_parts(args_kaddr.as_ptr(), args.len()) };
+        match core::str::from_utf8(args_kslice) {
+            Ok(args) => KernelConfig::parse(args),
+            Err(_e) => {
+                let mut config = KernelConfig::new();
+                config.ignore("<command-line>", "not UTF-8");
+                config
+            }
+        }
+    }
+
+    /// Parses the command-line `args`.
+    pub fn parse(args: &'static str) -> KernelConfig {
+        let mut config = KernelConfig::new();
+
+        let mut options = Options { rest: args }.peekable();
+        if let Some((binary, None)) = options.peek() {
+            if binary.starts_with("./") {
+                options.next();
+            }
+        }
+
+        for (key, value) in options {
+            if let Err(reason) = config.set(key, value) {
+                config.ignore(key, reason);
+            }
+        }
+
+        config
+    }
+
+    fn set(&mut self, key: &'static str, value: Option<&'static str>) -> Result<(), &'static str> {
+        match (key, value) {
+            ("log", Some(filter)) => self.log_filter = filter,
+            ("init", Some(binary)) => self.init_binary = binary,
+            ("initargs", Some(args)) => self.init_args = args,
+            ("tests", Some(tests)) => self.init_tests = tests,
+            ("appcmd", Some(args)) => self.app_args = args,
+            ("net", Some(net)) => {
+                let net = IpConfig::parse(net)
+                    .map_err(|_e| "should be dhcp or static:<ip>/<prefix>[,<gateway>]")?;
+                self.net = Some(net);
+            }
+            ("mem", Some(size)) => self.memory_limit = Some(parse_size(size)?),
+            ("sched", Some(policy)) => self.scheduler = SchedulerPolicy::parse(policy)?,
+            ("corepolicy", Some(policy)) => {
+                self.core_policy =
+                    CorePolicy::parse(policy).ok_or("should be packed, spread or smt")?
+            }
+            ("clocksource", Some(name)) => self.clocksource = Some(name),
+            ("crashdump", Some(target)) => self.crashdump = Some(target),
+            ("initrd", Some(module)) => self.initrd = module,
+            ("inituser", Some(user)) => self.init_creds = parse_credentials(user)?,
+            ("syscall_latency", None) => self.syscall_latency = true,
+            ("syscall_latency", Some(_)) => return Err("doesn't take a value"),
+            ("noaslr", None) => self.aslr = false,
+            ("noaslr", Some(_)) => return Err("doesn't take a value"),
+            ("cet", Some(policy)) => self.cet = CetPolicy::parse(policy)?,
+            ("idle", Some(state)) => self.idle = parse_idle_state(state)?,
+            ("cpufreq", Some(policy)) => self.cpufreq = CpufreqPolicy::parse(policy)?,
+            ("faults", Some(seed)) => {
+                self.fault_seed = Some(seed.parse().map_err(|_e| "should be a number")?)
+            }
+            ("zswap", Some(size)) => self.zswap = Some(parse_size(size)?),
+            ("swap", Some(target)) => self.swap = Some(target),
+            ("fsjournal", Some(target)) => self.fsjournal = Some(target),
+            ("log", None)
+            | ("init", None)
+            | ("initargs", None)
+            | ("tests", None)
+            | ("appcmd", None)
+            | ("net", None)
+            | ("mem", None)
+            | ("sched", None)
+            | ("corepolicy", None)
+            | ("clocksource", None)
+            | ("crashdump", None)
+            | ("initrd", None)
+            | ("inituser", None)
+            | ("cet", None)
+            | ("idle", None)
+            | ("cpufreq", None)
+            | ("faults", None)
+            | ("zswap", None)
+            | ("swap", None)
+            | ("fsjournal", None) => return Err("needs a value"),
+            _ => return Err("unknown option"),
+        }
+        Ok(())
+    }
+
+    fn ignore(&mut self, option: &'static str, reason: &'static str) {
+        // We only remember the first few, the rest is probably garbage
+        let _ = self.ignored.try_push((option, reason));
+    }
+
+    /// Logs the options we didn't use.
+    pub fn warn_ignored(&self) {
+        for (option, reason) in self.ignored.iter() {
+            warn!("Ignored '{}' on the command-line: {}", option, reason);
+        }
+    }
+
+    /// How much of a `size` bytes memory region we can use if we use `used`
+    /// bytes already (whole pages, within `mem=`).
+    pub fn usable_memory(&self, used: usize, size: usize) -> usize {
+        match self.memory_limit {
+            Some(limit) => {
+                let left = limit.saturating_sub(used) & !(BASE_PAGE_SIZE - 1);
+                core::cmp::min(size, left)
+            }
+            None => size,
+        }
+    }
+}
+
+/// Splits a command-line into options (`key` or `key=value`).
+struct Options {
+    rest: &'static str,
+}
+
+impl Iterator for Options {
+    type Item = (&'static str, Option<&'static str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = self.rest.trim_start_matches(' ');
+        if line.is_empty() {
+            return None;
+        }
+
+        let key_len = line.find(|c| c == '=' || c == ' ').unwrap_or(line.len());
+        let key = &line[..key_len];
+        let value = match line[key_len..].strip_prefix('=') {
+            Some(value) => value,
+            None => {
+                self.rest = &line[key_len..];
+                return Some((key, None));
+            }
+        };
+
+        if let Some(quoted) = value.strip_prefix('\'') {
+            // A quoted value ends at the next quote (or the end of the line)
+            let len = quoted.find('\'').unwrap_or(quoted.len());
+            self.rest = quoted.get(len + 1..).unwrap_or("");
+            Some((key, Some(&quoted[..len])))
+        } else {
+            let len = value.find(' ').unwrap_or(value.len());
+            self.rest = &value[len..];
+            Some((key, Some(&value[..len])))
+        }
+    }
+}
+
+/// Parses a size in bytes with an optional `K`, `M` or `G` suffix.
+fn parse_size(size: &str) -> Result<usize, &'static str> {
+    const INVALID: &str = "should be a size like 512M or 4G";
+    let (digits, shift) = match size.as_bytes().last() {
+        Some(b'K') | Some(b'k') => (&size[..size.len() - 1], 10),
+        Some(b'M') | Some(b'm') => (&size[..size.len() - 1], 20),
+        Some(b'G') | Some(b'g') => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+    let size = digits.parse::<usize>().map_err(|_e| INVALID)?;
+    size.checked_mul(1 << shift).ok_or(INVALID)
+}
+
+/// Parses `<uid>[:<gid>]` (the group is the uid if there is none).
+fn parse_credentials(user: &str) -> Result<Credentials, &'static str> {
+    const INVALID: &str = "should be <uid>[:<gid>]";
+    let (uid, gid) = match user.split_once(':') {
+        Some((uid, gid)) => (uid, gid),
+        None => (user, user),
+    };
+    Ok(Credentials {
+        uid: uid.parse().map_err(|_e| INVALID)?,
+        gid: gid.parse().map_err(|_e| INVALID)?,
+    })
+}
+
+/// Parses the name of an idle state (`poll`, `c1`, `c1e` or `c6`).
+fn parse_idle_state(state: &str) -> Result<IdleState, &'static str> {
+    IdleState::ALL
+        .iter()
+        .copied()
+        .find(|s| s.name().eq_ignore_ascii_case(state))
+        .ok_or("should be poll, c1, c1e or c6")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_args_empty() {
+        let ba = KernelConfig::parse("");
+        assert_eq!(ba.log_filter, "info");
+        assert_eq!(ba.init_binary, "init");
+        assert_eq!(ba.init_args, "");
+    }
+
+    #[test]
+    fn parse_args_nrk() {
+        let ba = KernelConfig::parse("./nrk");
+        assert_eq!(ba.log_filter, "info");
+        assert_eq!(ba.init_binary, "init");
+        assert_eq!(ba.init_args, "");
+    }
+
+    #[test]
+    fn parse_args_basic() {
+        let ba = KernelConfig::parse("./kernel");
+        assert_eq!(ba.log_filter, "info");
+        assert_eq!(ba.init_binary, "init");
+        assert_eq!(ba.init_args, "");
+    }
+
+    #[test]
+    fn parse_args_log() {
+        let ba = KernelConfig::parse("./kernel log=error");
+        assert_eq!(ba.log_filter, "error");
+        assert_eq!(ba.init_binary, "init");
+        assert_eq!(ba.init_args, "");
+    }
+
+    #[test]
+    fn parse_args_init() {
+        let ba = KernelConfig::parse("./kernel init=file log=trace");
+        assert_eq!(ba.log_filter, "trace");
+        assert_eq!(ba.init_binary, "file");
+        assert_eq!(ba.init_args, "");
+    }
+
+    #[test]
+    fn parse_args_initargs() {
+        let ba = KernelConfig::parse("./kernel initargs=0");
+        assert_eq!(ba.log_filter, "info");
+        assert_eq!(ba.init_binary, "init");
+        assert_eq!(ba.init_args, "0");
+    }
+
+    #[test]
+    fn parse_args_tests() {
+        let ba = KernelConfig::parse("./kernel tests=map,fs,scheduler initargs=2");
+        assert_eq!(ba.init_tests, "map,fs,scheduler");
+        assert_eq!(ba.init_args, "2");
+
+        let ba = KernelConfig::parse("./kernel tests");
+        assert_eq!(ba.init_tests, "");
+        assert_eq!(ba.ignored[0], ("tests", "needs a value"));
+    }
+
+    #[test]
+    fn parse_args_leveldb() {
+        let args = "./kernel log=warn init=dbbench.bin initargs=3 appcmd='--threads=1 --benchmarks=fillseq,readrandom --reads=100000 --num=50000 --value_size=65535'";
+
+        let ba = KernelConfig::parse(args);
+        assert_eq!(ba.log_filter, "warn");
+        assert_eq!(ba.init_binary, "dbbench.bin");
+        assert_eq!(ba.init_args, "3");
+        assert_eq!(ba.app_args, "--threads=1 --benchmarks=fillseq,readrandom --reads=100000 --num=50000 --value_size=65535");
+    }
+
+    #[test]
+    fn parse_args_fxmark() {
+        let args = "log=debug initargs=1X1XmixX0";
+        let ba = KernelConfig::parse(args);
+        assert_eq!(ba.log_filter, "debug");
+        assert_eq!(ba.init_binary, "init");
+        assert_eq!(ba.init_args, "1X1XmixX0");
+    }
+
+    #[test]
+    fn parse_args_empty_literal_quotes() {
+        let args = "./kernel initargs='\"\"' log=debug";
+        let ba = KernelConfig::parse(args);
+        assert_eq!(ba.log_filter, "debug");
+        assert_eq!(ba.init_args, "\"\"");
+    }
+
+    #[test]
+    fn parse_args_empty_literal() {
+        let args = "./kernel initargs='' log=debug";
+        let ba = KernelConfig::parse(args);
+        assert_eq!(ba.log_filter, "debug");
+        assert_eq!(ba.init_args, "");
+    }
+
+    #[test]
+    fn parse_args_net_dhcp() {
+        let args = "./kernel log=debug net=dhcp";
+        let ba = KernelConfig::parse(args);
+        assert_eq!(ba.log_filter, "debug");
+        assert_eq!(ba.net, Some(IpConfig::Dhcp));
+    }
+
+    #[test]
+    fn parse_args_net_static() {
+        let args = "./kernel net=static:172.31.0.10/24,172.31.0.20 init=file";
+        let ba = KernelConfig::parse(args);
+        assert_eq!(ba.init_binary, "file");
+        assert_eq!(
+            ba.net,
+            Some(IpConfig::Static {
+                address: [172, 31, 0, 10],
+                prefix_len: 24,
+                gateway: Some([172, 31, 0, 20]),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_args_net_invalid() {
+        let ba = KernelConfig::parse("./kernel net=static:172.31.0.10 log=debug");
+        assert_eq!(ba.net, None);
+        assert_eq!(ba.log_filter, "debug");
+        assert_eq!(ba.ignored.len(), 1);
+        assert_eq!(ba.ignored[0].0, "net");
+    }
+
+    #[test]
+    fn parse_args_log_modules() {
+        let ba = KernelConfig::parse("./kernel log='warn,nrk::memory=trace' init=file");
+        assert_eq!(ba.log_filter, "warn,nrk::memory=trace");
+        assert_eq!(ba.init_binary, "file");
+    }
+
+    #[test]
+    fn parse_args_crashdump() {
+        let ba = KernelConfig::parse("./kernel crashdump=nvme0 log=debug");
+        assert_eq!(ba.log_filter, "debug");
+        assert_eq!(ba.crashdump, Some("nvme0"));
+
+        let ba = KernelConfig::parse("./kernel crashdump='sata0:2048'");
+        assert_eq!(ba.crashdump, Some("sata0:2048"));
+    }
+
+    #[test]
+    fn parse_args_swap() {
+        let ba = KernelConfig::parse("./kernel log=debug");
+        assert_eq!(ba.zswap, None);
+        assert_eq!(ba.swap, None);
+
+        let ba = KernelConfig::parse("./kernel zswap=64M swap='nvme0:4096'");
+        assert_eq!(ba.zswap, Some(64 * 1024 * 1024));
+        assert_eq!(ba.swap, Some("nvme0:4096"));
+
+        let ba = KernelConfig::parse("zswap=some");
+        assert_eq!(ba.zswap, None);
+        assert_eq!(ba.ignored[0], ("zswap", "should be a size like 512M or 4G"));
+    }
+
+    #[test]
+    fn parse_args_fsjournal() {
+        let ba = KernelConfig::parse("./kernel log=debug");
+        assert_eq!(ba.fsjournal, None);
+
+        let ba = KernelConfig::parse("./kernel fsjournal='sata0:8192' log=debug");
+        assert_eq!(ba.fsjournal, Some("sata0:8192"));
+
+        let ba = KernelConfig::parse("./kernel fsjournal");
+        assert_eq!(ba.fsjournal, None);
+        assert_eq!(ba.ignored[0], ("fsjournal", "needs a value"));
+    }
+
+    #[test]
+    fn parse_args_initrd() {
+        let ba = KernelConfig::parse("./kernel log=debug");
+        assert_eq!(ba.initrd, "initrd");
+
+        let ba = KernelConfig::parse("./kernel initrd=rootfs.cpio init=file");
+        assert_eq!(ba.initrd, "rootfs.cpio");
+        assert_eq!(ba.init_binary, "file");
+    }
+
+    #[test]
+    fn parse_args_inituser() {
+        assert_eq!(KernelConfig::parse("").init_creds, Credentials::ROOT);
+
+        let ba = KernelConfig::parse("./kernel inituser=1000:100 init=file");
+        assert_eq!(
+            ba.init_creds,
+            Credentials {
+                uid: 1000,
+                gid: 100
+            }
+        );
+        assert_eq!(ba.init_binary, "file");
+
+        let ba = KernelConfig::parse("inituser=7");
+        assert_eq!(ba.init_creds, Credentials { uid: 7, gid: 7 });
+
+        let ba = KernelConfig::parse("inituser=nobody");
+        assert_eq!(ba.init_creds, Credentials::ROOT);
+    }
+
+    #[test]
+    fn parse_args_syscall_latency() {
+        let ba = KernelConfig::parse("./kernel log=debug");
+        assert!(!ba.syscall_latency);
+
+        let ba = KernelConfig::parse("./kernel syscall_latency log=debug");
+        assert!(ba.syscall_latency);
+        assert_eq!(ba.log_filter, "debug");
+    }
+
+    #[test]
+    fn parse_args_noaslr() {
+        assert!(KernelConfig::parse("./kernel log=debug").aslr);
+
+        let ba = KernelConfig::parse("./kernel noaslr initargs=1");
+        assert!(!ba.aslr);
+        assert_eq!(ba.init_args, "1");
+        assert!(KernelConfig::parse("noaslr=1").aslr);
+    }
+
+    #[test]
+    fn parse_args_cet() {
+        assert_eq!(KernelConfig::parse("").cet, CetPolicy::Kernel);
+        assert_eq!(KernelConfig::parse("cet=off").cet, CetPolicy::Off);
+
+        let ba = KernelConfig::parse("./kernel cet=user init=file");
+        assert_eq!(ba.cet, CetPolicy::User);
+        assert_eq!(ba.init_binary, "file");
+
+        let ba = KernelConfig::parse("cet=on");
+        assert_eq!(ba.cet, CetPolicy::Kernel);
+        assert_eq!(ba.ignored[0], ("cet", "should be off, kernel or user"));
+    }
+
+    #[test]
+    fn parse_args_core_policy() {
+        assert_eq!(KernelConfig::parse("").core_policy, CorePolicy::Packed);
+        assert_eq!(
+            KernelConfig::parse("corepolicy=spread").core_policy,
+            CorePolicy::Spread
+        );
+
+        let ba = KernelConfig::parse("corepolicy=fair");
+        assert_eq!(ba.core_policy, CorePolicy::Packed);
+        assert_eq!(
+            ba.ignored[0],
+            ("corepolicy", "should be packed, spread or smt")
+        );
+    }
+
+    #[test]
+    fn parse_args_idle() {
+        assert_eq!(KernelConfig::parse("").idle, IdleState::C6);
+        assert_eq!(KernelConfig::parse("idle=poll").idle, IdleState::Poll);
+        assert_eq!(KernelConfig::parse("idle=C1E").idle, IdleState::C1E);
+
+        let ba = KernelConfig::parse("idle=c3");
+        assert_eq!(ba.idle, IdleState::C6);
+        assert_eq!(ba.ignored[0], ("idle", "should be poll, c1, c1e or c6"));
+    }
+
+    #[test]
+    fn parse_args_cpufreq() {
+        assert_eq!(KernelConfig::parse("").cpufreq, CpufreqPolicy::Ondemand);
+        assert_eq!(
+            KernelConfig::parse("cpufreq=performance").cpufreq,
+            CpufreqPolicy::Performance
+        );
+
+        let ba = KernelConfig::parse("cpufreq=powersave idle=c1");
+        assert_eq!(ba.cpufreq, CpufreqPolicy::Ondemand);
+        assert_eq!(ba.idle, IdleState::C1);
+        assert_eq!(
+            ba.ignored[0],
+            ("cpufreq", "should be ondemand, performance or off")
+        );
+    }
+
+    #[test]
+    fn parse_args_faults() {
+        assert_eq!(KernelConfig::parse("").fault_seed, None);
+        assert_eq!(KernelConfig::parse("faults=42").fault_seed, Some(42));
+
+        let ba = KernelConfig::parse("faults=often log=warn");
+        assert_eq!(ba.fault_seed, None);
+        assert_eq!(ba.log_filter, "warn");
+        assert_eq!(ba.ignored[0], ("faults", "should be a number"));
+    }
+
+    #[test]
+    fn parse_args_mem() {
+        let ba = KernelConfig::parse("./kernel mem=512M");
+        assert_eq!(ba.memory_limit, Some(512 * 1024 * 1024));
+        assert_eq!(ba.usable_memory(0, 1024 * 1024 * 1024), 512 * 1024 * 1024);
+        assert_eq!(
+            ba.usable_memory(500 * 1024 * 1024, 64 * 1024 * 1024),
+            12 * 1024 * 1024
+        );
+        assert_eq!(ba.usable_memory(512 * 1024 * 1024, 4096), 0);
+
+        assert_eq!(KernelConfig::parse("mem=4g").memory_limit, Some(4 << 30));
+        assert_eq!(KernelConfig::parse("mem=4096").memory_limit, Some(4096));
+        assert_eq!(KernelConfig::parse("mem=lots").memory_limit, None);
+        assert_eq!(
+            KernelConfig::parse("").usable_memory(usize::MAX, 4096),
+            4096
+        );
+    }
+
+    #[test]
+    fn parse_args_sched() {
+        assert_eq!(KernelConfig::parse("").scheduler, SchedulerPolicy::Poll);
+        assert_eq!(
+            KernelConfig::parse("sched=halt").scheduler,
+            SchedulerPolicy::Halt
+        );
+        let ba = KernelConfig::parse("sched=fifo");
+        assert_eq!(ba.scheduler, SchedulerPolicy::Poll);
+        assert_eq!(ba.ignored[0], ("sched", "should be poll or halt"));
+    }
+
+    #[test]
+    fn parse_args_invalid() {
+        let args = "./kernel initg='asdf' log=debug";
+        let ba = KernelConfig::parse(args);
+        assert_eq!(ba.log_filter, "debug");
+        assert_eq!(ba.init_args, "");
+        assert_eq!(ba.ignored[0], ("initg", "unknown option"));
+    }
+
+    #[test]
+    fn parse_args_invalid2() {
+        let args = "./sadf init='asdf' log=debug";
+        let ba = KernelConfig::parse(args);
+        assert_eq!(ba.log_filter, "debug");
+        assert_eq!(ba.init_args, "");
+    }
+
+    #[test]
+    fn parse_args_invalid3() {
+        let args = "./kernel init=---  as-s- log=debug";
+        let ba = KernelConfig::parse(args);
+        assert_eq!(ba.log_filter, "debug");
+        assert_eq!(ba.init_args, "");
+        assert_eq!(ba.ignored[0], ("as-s-", "unknown option"));
+    }
+
+    #[test]
+    fn parse_args_missing_value() {
+        let ba = KernelConfig::parse("./kernel log init=file syscall_latency=1");
+        assert_eq!(ba.log_filter, "info");
+        assert_eq!(ba.init_binary, "file");
+        assert!(!ba.syscall_latency);
+        assert_eq!(
+            ba.ignored.as_slice(),
+            &[
+                ("log", "needs a value"),
+                ("syscall_latency", "doesn't take a value")
+            ]
+        );
+    }
+}
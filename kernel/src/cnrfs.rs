@@ -1,21 +1,24 @@
 // Copyright © 2021 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-use crate::arch::process::{UserPtr, UserSlice};
+use crate::arch::process::UserSlice;
 use crate::error::KError;
 use crate::fs::fd::FileDesc;
+use crate::fs::journal::{self, Record, Ticket};
 use crate::fs::{
     Buffer, FileDescriptor, FileSystem, Filename, Flags, Len, MlnrFS, Mnode, Modes, NrLock, Offset,
     FD, MNODE_OFFSET,
 };
-use crate::memory::VAddr;
+use crate::memory::vspace::UserAccess;
 use crate::prelude::*;
-use crate::process::{userptr_to_str, KernSlice, Pid};
+use crate::process::{userptr_to_str, KernSlice, Pid, UserPtr};
 
 use alloc::sync::Arc;
 use cnr::{Dispatch, LogMapper};
+use core::sync::atomic::{AtomicU64, Ordering};
 use hashbrown::HashMap;
 use kpi::io::*;
+use kpi::process::Credentials;
 use kpi::FileOperation;
 
 pub struct MlnrKernelNode {
@@ -25,6 +28,9 @@ pub struct MlnrKernelNode {
     process_map: NrLock<HashMap<Pid, FileDesc>>,
     /// MLNR kernel node primarily replicates the in-memory filesystem.
     fs: MlnrFS,
+    /// Ticket of the next metadata update (see `fs::journal`), the same on
+    /// every replica since those updates are scan operations.
+    journal_ticket: AtomicU64,
 }
 
 impl Default for MlnrKernelNode {
@@ -32,20 +38,38 @@ impl Default for MlnrKernelNode {
         MlnrKernelNode {
             process_map: NrLock::<HashMap<Pid, FileDesc>>::default(),
             fs: MlnrFS::default(),
+            journal_ticket: AtomicU64::new(0),
         }
     }
 }
 
 #[derive(Hash, Clone, Debug, PartialEq)]
 pub enum Modify {
-    ProcessAdd(Pid),
+    /// Add a process that runs as `Credentials`.
+    ProcessAdd(Pid, Credentials),
     ProcessRemove(Pid),
     FileOpen(Pid, String, Flags, Modes),
     FileWrite(Pid, FD, Mnode, Arc<[u8]>, Len, Offset),
     FileClose(Pid, FD),
+    /// Open the file of a descriptor in another process (with the same
+    /// flags).
+    FileDup(Pid, FD, Pid),
     FileDelete(Pid, String),
     FileRename(Pid, String, String),
     MkDir(Pid, String, Modes),
+    /// Replace the contents of a procfs file (create it if necessary).
+    ProcfsUpdate(String, Arc<[u8]>),
+    /// Create a file with the given contents (or replace the contents) on
+    /// behalf of the kernel, e.g., when we unpack the initrd.
+    KernelFileCreate(String, Modes, Arc<[u8]>),
+    /// Create a directory on behalf of the kernel.
+    KernelMkDir(String, Modes),
+    /// Open a file again for a restored process (with the flags and at the
+    /// offset it had).
+    FileRestore(Pid, String, Flags, usize),
+    /// Apply an update from the journal when we mount the file-system
+    /// (without permission checks).
+    JournalReplay(Record),
 }
 
 // TODO: Stateless op to log mapping. Maintain some state for correct redirection.
@@ -54,16 +78,22 @@ impl LogMapper for Modify {
         debug_assert!(logs.capacity() >= nlogs, "Push can't fail.");
         logs.clear();
         match self {
-            Modify::ProcessAdd(_pid) => push_to_all(nlogs, logs),
+            Modify::ProcessAdd(_pid, _creds) => push_to_all(nlogs, logs),
             Modify::ProcessRemove(_pid) => push_to_all(nlogs, logs),
             Modify::FileOpen(_pid, _filename, _flags, _modes) => push_to_all(nlogs, logs),
             Modify::FileWrite(_pid, _fd, mnode, _kernslice, _len, _offset) => {
                 logs.push((*mnode as usize - MNODE_OFFSET) % nlogs)
             }
             Modify::FileClose(_pid, _fd) => push_to_all(nlogs, logs),
+            Modify::FileDup(_pid, _fd, _to) => push_to_all(nlogs, logs),
             Modify::FileDelete(_pid, _filename) => push_to_all(nlogs, logs),
             Modify::FileRename(_pid, _oldname, _newname) => push_to_all(nlogs, logs),
             Modify::MkDir(_pid, _name, _modes) => push_to_all(nlogs, logs),
+            Modify::ProcfsUpdate(_name, _contents) => push_to_all(nlogs, logs),
+            Modify::KernelFileCreate(_name, _modes, _contents) => push_to_all(nlogs, logs),
+            Modify::KernelMkDir(_name, _modes) => push_to_all(nlogs, logs),
+            Modify::FileRestore(_pid, _name, _flags, _offset) => push_to_all(nlogs, logs),
+            Modify::JournalReplay(_record) => push_to_all(nlogs, logs),
         }
 
         fn push_to_all(nlogs: usize, logs: &mut Vec<usize>) {
@@ -79,7 +109,11 @@ pub enum Access {
     FileRead(Pid, FD, Mnode, Buffer, Len, Offset),
     FileInfo(Pid, Filename, Mnode, u64),
     FdToMnode(Pid, FD),
+    /// The path, flags and offset of a descriptor.
+    FdInfo(Pid, FD),
     FileNameToMnode(Pid, Filename),
+    /// Who process `pid` runs as.
+    Credentials(Pid),
     Synchronize(usize),
 }
 
@@ -97,7 +131,9 @@ impl LogMapper for Access {
             }
             // TODO: Assume that all metadata modifying operations go through log 0.
             Access::FdToMnode(_pid, _fd) => logs.push(0),
+            Access::FdInfo(_pid, _fd) => logs.push(0),
             Access::FileNameToMnode(_pid, _filename) => logs.push(0),
+            Access::Credentials(_pid) => logs.push(0),
             // Log number start with 1 in CNR, however, replica uses mod
             // operation which starts with 0; hence `log_id - 1`.
             Access::Synchronize(log_id) => logs.push((*log_id - 1) % nlogs),
@@ -110,26 +146,31 @@ pub enum MlnrNodeResult {
     ProcessAdded(Pid),
     ProcessRemoved(Pid),
     FileOpened(FD),
+    /// `FileOpen` had to create the file.
+    FileCreated(FD, Ticket),
     FileAccessed(Len),
     FileClosed(u64),
-    FileDeleted,
+    FileDeleted(Ticket),
     FileInfo(FileInfo),
-    FileRenamed,
-    DirCreated,
+    FileRenamed(Ticket),
+    DirCreated(Ticket),
     MappedFileToMnode(u64),
+    FdInfo(String, Flags, usize),
+    Credentials(Credentials),
+    Replayed,
     Synchronized,
 }
 
 /// TODO: Most of the functions looks same as in nr.rs. Merge the
 /// two and maybe move all the functions to a separate file?
 impl MlnrKernelNode {
-    pub fn add_process(pid: usize) -> Result<(u64, u64), KError> {
+    pub fn add_process(pid: usize, creds: Credentials) -> Result<(u64, u64), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.arch
             .cnr_replica
             .as_ref()
             .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
-                let response = replica.execute_mut_scan(Modify::ProcessAdd(pid), *token);
+                let response = replica.execute_mut_scan(Modify::ProcessAdd(pid, creds), *token);
                 match response {
                     Ok(MlnrNodeResult::ProcessAdded(pid)) => Ok((pid as u64, 0)),
                     Err(e) => Err(e),
@@ -138,6 +179,22 @@ impl MlnrKernelNode {
             })
     }
 
+    /// Forgets process `pid` (and closes its files).
+    pub fn remove_process(pid: Pid) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut_scan(Modify::ProcessRemove(pid), *token);
+                match response {
+                    Ok(MlnrNodeResult::ProcessRemoved(_pid)) => Ok(()),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
     pub fn map_fd(pid: Pid, pathname: u64, flags: u64, modes: u64) -> Result<(FD, u64), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.arch
@@ -145,11 +202,16 @@ impl MlnrKernelNode {
             .as_ref()
             .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
                 let filename = userptr_to_str(pathname)?;
+                let record = MlnrKernelNode::create_record(pid, &filename, flags, modes)?;
                 let response =
                     replica.execute_mut_scan(Modify::FileOpen(pid, filename, flags, modes), *token);
 
                 match response {
                     Ok(MlnrNodeResult::FileOpened(fd)) => Ok((fd, 0)),
+                    Ok(MlnrNodeResult::FileCreated(fd, ticket)) => {
+                        MlnrKernelNode::commit_open(pid, fd, ticket, record)?;
+                        Ok((fd, 0))
+                    }
                     Err(e) => Err(e),
                     Ok(_) => unreachable!("Got unexpected response"),
                 }
@@ -173,7 +235,7 @@ impl MlnrKernelNode {
             Err(KError::ReplicaNotSet),
             |(replica, token)| match op {
                 FileOperation::Write | FileOperation::WriteAt => {
-                    let kernslice = KernSlice::new(buffer, len as usize);
+                    let kernslice = KernSlice::new(buffer, len as usize)?;
 
                     let response = replica.execute_mut(
                         Modify::FileWrite(pid, fd, mnode, kernslice.buffer, len, offset),
@@ -220,6 +282,24 @@ impl MlnrKernelNode {
             })
     }
 
+    /// Opens the file behind `fd` of process `pid` in process `to`, returns
+    /// the descriptor in `to`.
+    pub fn dup_fd(pid: Pid, fd: FD, to: Pid) -> Result<FD, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut_scan(Modify::FileDup(pid, fd, to), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::FileOpened(fd)) => Ok(fd),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
     pub fn file_delete(pid: Pid, name: u64) -> Result<(u64, u64), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.arch
@@ -227,10 +307,14 @@ impl MlnrKernelNode {
             .as_ref()
             .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
                 let filename = userptr_to_str(name)?;
+                let record = journal::prepare(|| Record::delete(&filename))?;
                 let response = replica.execute_mut_scan(Modify::FileDelete(pid, filename), *token);
 
                 match response {
-                    Ok(MlnrNodeResult::FileDeleted) => Ok((0, 0)),
+                    Ok(MlnrNodeResult::FileDeleted(ticket)) => {
+                        journal::commit(ticket, record)?;
+                        Ok((0, 0))
+                    }
                     Err(e) => Err(e),
                     Ok(_) => unreachable!("Got unexpected response"),
                 }
@@ -250,11 +334,7 @@ impl MlnrKernelNode {
 
                 match response {
                     Ok(MlnrNodeResult::FileInfo(f_info)) => {
-                        let user_ptr = UserPtr::new(&mut VAddr::from(info_ptr));
-                        unsafe {
-                            (*user_ptr.as_mut_ptr::<FileInfo>()).ftype = f_info.ftype;
-                            (*user_ptr.as_mut_ptr::<FileInfo>()).fsize = f_info.fsize;
-                        }
+                        UserPtr::<FileInfo>::new(info_ptr)?.write(f_info)?;
                         Ok((0, 0))
                     }
                     Err(e) => Err(e),
@@ -272,10 +352,14 @@ impl MlnrKernelNode {
                 let oldfilename = userptr_to_str(oldname)?;
                 let newfilename = userptr_to_str(newname)?;
 
+                let record = journal::prepare(|| Record::rename(&oldfilename, &newfilename))?;
                 let response = replica
                     .execute_mut_scan(Modify::FileRename(pid, oldfilename, newfilename), *token);
                 match response {
-                    Ok(MlnrNodeResult::FileRenamed) => Ok((0, 0)),
+                    Ok(MlnrNodeResult::FileRenamed(ticket)) => {
+                        journal::commit(ticket, record)?;
+                        Ok((0, 0))
+                    }
                     Err(e) => Err(e),
                     Ok(_) => unreachable!("Got unexpected response"),
                 }
@@ -289,11 +373,17 @@ impl MlnrKernelNode {
             .as_ref()
             .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
                 let filename = userptr_to_str(pathname)?;
+                let record = journal::prepare(|| {
+                    Record::mkdir(&filename, modes, MlnrKernelNode::credentials(pid)?)
+                })?;
                 let response =
                     replica.execute_mut_scan(Modify::MkDir(pid, filename, modes), *token);
 
                 match response {
-                    Ok(MlnrNodeResult::DirCreated) => Ok((0, 0)),
+                    Ok(MlnrNodeResult::DirCreated(ticket)) => {
+                        journal::commit(ticket, record)?;
+                        Ok((0, 0))
+                    }
                     Err(e) => Err(e),
                     Ok(_) => unreachable!("Got unexpected response"),
                 }
@@ -317,6 +407,94 @@ impl MlnrKernelNode {
             })
     }
 
+    /// The path, flags and offset of `fd` of process `pid`, fails with
+    /// `InvalidFile` if the file was deleted.
+    pub fn fd_info(pid: Pid, fd: FD) -> Result<(String, Flags, usize), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(Access::FdInfo(pid, fd), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::FdInfo(path, flags, offset)) => Ok((path, flags, offset)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Opens `filename` for process `pid` with `flags` and the descriptor at
+    /// `offset`, returns the descriptor.
+    pub fn restore_fd(
+        pid: Pid,
+        filename: String,
+        flags: Flags,
+        offset: usize,
+    ) -> Result<FD, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica
+                    .execute_mut_scan(Modify::FileRestore(pid, filename, flags, offset), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::FileOpened(fd)) => Ok(fd),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Opens `filename` for process `pid` (like `map_fd`, for a name the
+    /// kernel has already).
+    pub fn open(pid: Pid, filename: String, flags: Flags, modes: Modes) -> Result<FD, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let record = MlnrKernelNode::create_record(pid, &filename, flags, modes)?;
+                let response =
+                    replica.execute_mut_scan(Modify::FileOpen(pid, filename, flags, modes), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::FileOpened(fd)) => Ok(fd),
+                    Ok(MlnrNodeResult::FileCreated(fd, ticket)) => {
+                        MlnrKernelNode::commit_open(pid, fd, ticket, record)?;
+                        Ok(fd)
+                    }
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Appends `contents` to `fd` of process `pid` (at the offset of the
+    /// descriptor).
+    pub fn write(pid: Pid, fd: FD, contents: Arc<[u8]>) -> Result<Len, KError> {
+        let (mnode, _) =
+            MlnrKernelNode::fd_to_mnode(pid, fd).map_err(|_e| KError::InvalidFileDescriptor)?;
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let len = contents.len() as Len;
+                let response = replica
+                    .execute_mut(Modify::FileWrite(pid, fd, mnode, contents, len, -1), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::FileAccessed(len)) => Ok(len),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
     #[inline(always)]
     pub fn filename_to_mnode(pid: Pid, filename: Filename) -> Result<(u64, u64), KError> {
         let kcb = super::kcb::get_kcb();
@@ -334,6 +512,132 @@ impl MlnrKernelNode {
             })
     }
 
+    pub fn procfs_update(filename: String, contents: Arc<[u8]>) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response =
+                    replica.execute_mut_scan(Modify::ProcfsUpdate(filename, contents), *token);
+                match response {
+                    Ok(MlnrNodeResult::FileAccessed(len)) => Ok((len, 0)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Creates `filename` with `contents` (replaces the contents if the file
+    /// exists already).
+    pub fn kernel_file_create(
+        filename: String,
+        modes: Modes,
+        contents: Arc<[u8]>,
+    ) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica
+                    .execute_mut_scan(Modify::KernelFileCreate(filename, modes, contents), *token);
+                match response {
+                    Ok(MlnrNodeResult::FileAccessed(len)) => Ok((len, 0)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Who process `pid` runs as.
+    pub fn credentials(pid: Pid) -> Result<Credentials, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(Access::Credentials(pid), *token);
+                match response {
+                    Ok(MlnrNodeResult::Credentials(creds)) => Ok(creds),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// The journal record of a `FileOpen` that may create `filename`.
+    fn create_record(
+        pid: Pid,
+        filename: &str,
+        flags: Flags,
+        modes: Modes,
+    ) -> Result<Option<Record>, KError> {
+        if !FileFlags::from(flags).is_create() {
+            return Ok(None);
+        }
+        journal::prepare(|| Record::create(filename, modes, MlnrKernelNode::credentials(pid)?))
+    }
+
+    /// Journals the file a `FileOpen` created, closes `fd` again if that
+    /// fails.
+    fn commit_open(pid: Pid, fd: FD, ticket: Ticket, record: Option<Record>) -> Result<(), KError> {
+        journal::commit(ticket, record).map_err(|e| {
+            let _r = MlnrKernelNode::unmap_fd(pid, fd);
+            e
+        })
+    }
+
+    /// Applies `record` from the journal (see `fs::journal::init`).
+    pub fn journal_replay(record: Record) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut_scan(Modify::JournalReplay(record), *token);
+                match response {
+                    Ok(MlnrNodeResult::Replayed) => Ok(()),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    pub fn kernel_mkdir(pathname: String, modes: Modes) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response =
+                    replica.execute_mut_scan(Modify::KernelMkDir(pathname, modes), *token);
+                match response {
+                    Ok(MlnrNodeResult::DirCreated(ticket)) => {
+                        // We don't journal what the kernel creates
+                        journal::skip(ticket);
+                        Ok((0, 0))
+                    }
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Waits until the replica of this core applied all writes to the file
+    /// behind `fd`.
+    ///
+    /// The file system is in memory, so there is nothing to write back: a
+    /// write is done once it's in the log of the file, but a replica might
+    /// not have applied it yet.
+    pub fn file_sync(pid: Pid, fd: FD) -> Result<(u64, u64), KError> {
+        let (mnode, _) =
+            MlnrKernelNode::fd_to_mnode(pid, fd).map_err(|_e| KError::InvalidFileDescriptor)?;
+        // Writes to `mnode` go to log `(mnode - MNODE_OFFSET) % nlogs`, log
+        // ids start at 1
+        MlnrKernelNode::synchronize_log(mnode as usize - MNODE_OFFSET + 1)
+    }
+
     pub fn synchronize_log(log_id: usize) -> Result<(u64, u64), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.arch
@@ -350,6 +654,29 @@ impl MlnrKernelNode {
     }
 }
 
+impl MlnrKernelNode {
+    /// Hands out the ticket of a metadata update.
+    fn next_ticket(&self) -> Ticket {
+        self.journal_ticket.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Sets the contents of `filename` (creates it with `modes` if it doesn't
+    /// exist).
+    fn replace_file(
+        &self,
+        filename: &str,
+        modes: Modes,
+        contents: &[u8],
+    ) -> Result<MlnrNodeResult, KError> {
+        let mnode_num = match self.fs.lookup(filename) {
+            Some(mnode) => *mnode,
+            None => self.fs.create(filename, modes)?,
+        };
+        let len = self.fs.set_contents(mnode_num, contents)?;
+        Ok(MlnrNodeResult::FileAccessed(len as u64))
+    }
+}
+
 impl Dispatch for MlnrKernelNode {
     type ReadOperation = Access;
     type WriteOperation = Modify;
@@ -358,7 +685,7 @@ impl Dispatch for MlnrKernelNode {
     fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
         match op {
             Access::FileRead(pid, fd, _mnode, buffer, len, offset) => {
-                let mut userslice = UserSlice::new(buffer, len as usize);
+                let mut userslice = UserSlice::new(buffer, len as usize, UserAccess::Write)?;
                 let process_lookup = self.process_map.read();
                 let p = process_lookup
                     .get(&pid)
@@ -419,6 +746,21 @@ impl Dispatch for MlnrKernelNode {
                 Ok(MlnrNodeResult::MappedFileToMnode(mnode_num))
             }
 
+            Access::FdInfo(pid, fd) => {
+                let process_map_locked = self.process_map.read();
+                let p = process_map_locked
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+
+                let fd = p.get_fd(fd as usize).ok_or(KError::InvalidFileDescriptor)?;
+                let path = self.fs.path(fd.get_mnode()).ok_or(KError::InvalidFile)?;
+                Ok(MlnrNodeResult::FdInfo(
+                    path,
+                    fd.get_flags().into(),
+                    fd.get_offset(),
+                ))
+            }
+
             Access::FileNameToMnode(pid, name) => {
                 let _p = self
                     .process_map
@@ -435,6 +777,14 @@ impl Dispatch for MlnrKernelNode {
                 }
             }
 
+            Access::Credentials(pid) => {
+                let process_map_locked = self.process_map.read();
+                let p = process_map_locked
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                Ok(MlnrNodeResult::Credentials(p.credentials()))
+            }
+
             Access::Synchronize(_log_id) => {
                 // A NOP that just makes sure we've advanced the replica
                 Ok(MlnrNodeResult::Synchronized)
@@ -444,10 +794,10 @@ impl Dispatch for MlnrKernelNode {
 
     fn dispatch_mut(&self, op: Self::WriteOperation) -> Self::Response {
         match op {
-            Modify::ProcessAdd(pid) => {
+            Modify::ProcessAdd(pid, creds) => {
                 let mut pmap = self.process_map.write();
                 pmap.try_reserve(1)?;
-                pmap.try_insert(pid, FileDesc::default())
+                pmap.try_insert(pid, FileDesc::new(creds))
                     .map_err(|_e| KError::FileDescForPidAlreadyAdded)?;
                 Ok(MlnrNodeResult::ProcessAdded(pid))
             }
@@ -469,9 +819,17 @@ impl Dispatch for MlnrKernelNode {
                 let p = pmap
                     .get_mut(&pid)
                     .expect("TODO: FileOpen process lookup failed");
+                let creds = p.credentials();
+                if let Some(mnode) = mnode.as_ref() {
+                    let mut access = FileModes::empty();
+                    access.set(FileModes::S_IRUSR, flags.is_read());
+                    access.set(FileModes::S_IWUSR, flags.is_write() || flags.is_truncate());
+                    self.fs.check_access(**mnode, creds, access)?;
+                }
                 let (fid, fd) = p.allocate_fd().ok_or(KError::NotSupported)?;
 
                 let mnode_num;
+                let created = mnode.is_none();
                 if let Some(mnode) = mnode {
                     // File exists and FileOpen is called with O_TRUNC flag.
                     if flags.is_truncate() {
@@ -479,7 +837,7 @@ impl Dispatch for MlnrKernelNode {
                     }
                     mnode_num = *mnode;
                 } else {
-                    match self.fs.create(&filename, modes) {
+                    match self.fs.create_as(&filename, modes, creds) {
                         Ok(m_num) => mnode_num = m_num,
                         Err(e) => {
                             let fdesc = fid as usize;
@@ -490,6 +848,27 @@ impl Dispatch for MlnrKernelNode {
                 }
 
                 fd.update_fd(mnode_num, flags);
+                if created {
+                    Ok(MlnrNodeResult::FileCreated(fid, self.next_ticket()))
+                } else {
+                    Ok(MlnrNodeResult::FileOpened(fid))
+                }
+            }
+
+            Modify::FileRestore(pid, filename, flags, offset) => {
+                let flags = FileFlags::from(flags);
+                let mnode_num = *self.fs.lookup(&filename).ok_or(KError::InvalidFile)?;
+
+                let mut pmap = self.process_map.write();
+                let p = pmap.get_mut(&pid).ok_or(KError::NoProcessFoundForPid)?;
+                let mut access = FileModes::empty();
+                access.set(FileModes::S_IRUSR, flags.is_read());
+                access.set(FileModes::S_IWUSR, flags.is_write());
+                self.fs.check_access(mnode_num, p.credentials(), access)?;
+
+                let (fid, fd) = p.allocate_fd().ok_or(KError::OpenFileLimit)?;
+                fd.update_fd(mnode_num, flags);
+                fd.update_offset(offset);
                 Ok(MlnrNodeResult::FileOpened(fid))
             }
 
@@ -541,34 +920,85 @@ impl Dispatch for MlnrKernelNode {
                 Ok(MlnrNodeResult::FileClosed(fd))
             }
 
+            Modify::FileDup(pid, fd, to) => {
+                let mut pmap = self.process_map.write();
+                let (mnode_num, flags) = {
+                    let p = pmap.get(&pid).ok_or(KError::NoProcessFoundForPid)?;
+                    let fd = p.get_fd(fd as usize).ok_or(KError::InvalidFileDescriptor)?;
+                    (fd.get_mnode(), fd.get_flags())
+                };
+
+                let p = pmap.get_mut(&to).ok_or(KError::NoProcessFoundForPid)?;
+                let (fid, fd) = p.allocate_fd().ok_or(KError::OpenFileLimit)?;
+                fd.update_fd(mnode_num, flags);
+                Ok(MlnrNodeResult::FileOpened(fid))
+            }
+
             Modify::FileDelete(pid, filename) => {
-                let _p = self
+                let creds = self
                     .process_map
                     .read()
                     .get(&pid)
-                    .ok_or(KError::NoProcessFoundForPid)?;
+                    .ok_or(KError::NoProcessFoundForPid)?
+                    .credentials();
+                self.fs.check_owner(&filename, creds)?;
                 let _is_deleted = self.fs.delete(&filename)?;
-                Ok(MlnrNodeResult::FileDeleted)
+                Ok(MlnrNodeResult::FileDeleted(self.next_ticket()))
             }
 
             Modify::FileRename(pid, oldname, newname) => {
-                let _p = self
+                let creds = self
                     .process_map
                     .read()
                     .get(&pid)
-                    .ok_or(KError::NoProcessFoundForPid)?;
+                    .ok_or(KError::NoProcessFoundForPid)?
+                    .credentials();
+                self.fs.check_owner(&oldname, creds)?;
+                // Renaming over a file replaces it
+                if self.fs.lookup(&newname).is_some() {
+                    self.fs.check_owner(&newname, creds)?;
+                }
                 let _is_renamed = self.fs.rename(&oldname, &newname)?;
-                Ok(MlnrNodeResult::FileRenamed)
+                Ok(MlnrNodeResult::FileRenamed(self.next_ticket()))
             }
 
             Modify::MkDir(pid, filename, modes) => {
-                let _p = self
+                let creds = self
                     .process_map
                     .read()
                     .get(&pid)
-                    .ok_or(KError::NoProcessFoundForPid)?;
-                let _is_created = self.fs.mkdir(&filename, modes)?;
-                Ok(MlnrNodeResult::DirCreated)
+                    .ok_or(KError::NoProcessFoundForPid)?
+                    .credentials();
+                let _is_created = self.fs.mkdir_as(&filename, modes, creds)?;
+                Ok(MlnrNodeResult::DirCreated(self.next_ticket()))
+            }
+
+            Modify::ProcfsUpdate(filename, contents) => {
+                let modes = FileModes::S_IRUSR | FileModes::S_IRGRP | FileModes::S_IROTH;
+                self.replace_file(&filename, modes.into(), &contents)
+            }
+
+            Modify::KernelFileCreate(filename, modes, contents) => {
+                self.replace_file(&filename, modes, &contents)
+            }
+
+            Modify::KernelMkDir(pathname, modes) => {
+                self.fs.mkdir(&pathname, modes)?;
+                Ok(MlnrNodeResult::DirCreated(self.next_ticket()))
+            }
+
+            Modify::JournalReplay(record) => {
+                match record {
+                    Record::Create { path, modes, owner } => {
+                        self.fs.create_as(&path, modes, owner)?;
+                    }
+                    Record::MkDir { path, modes, owner } => {
+                        self.fs.mkdir_as(&path, modes, owner)?
+                    }
+                    Record::Delete { path } => self.fs.delete(&path)?,
+                    Record::Rename { old, new } => self.fs.rename(&old, &new)?,
+                }
+                Ok(MlnrNodeResult::Replayed)
             }
         }
     }
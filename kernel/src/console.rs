@@ -0,0 +1,268 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Kernel log output.
+//!
+//! Log records always go to the serial line (with klogger's `sprint`) and
+//! the log ring (`dmesg`), and are also written to every console
+//! registered with [`register`] (e.g., the framebuffer on machines without
+//! a serial port).
+//!
+//! Which records get logged is decided at runtime by a filter: a default
+//! level and levels for module paths, e.g. `warn,nrk::memory=trace` logs
+//! warnings everywhere and everything in `nrk::memory` and its submodules.
+//! It comes from `log=` on the command line and can be replaced later with
+//! [`set_filter`] (the `Debug::set_log_filter` system call).
+
+use core::fmt::{self, Write};
+use core::str::FromStr;
+
+use arrayvec::{ArrayString, ArrayVec};
+use klogger::sprintln;
+use log::{error, LevelFilter, Metadata, Record, SetLoggerError};
+use spin::RwLock;
+
+use crate::dmesg;
+use crate::error::KError;
+
+/// Maximum number of consoles (besides serial).
+const MAX_CONSOLES: usize = 4;
+
+/// Maximum number of module paths in a filter.
+const MAX_DIRECTIVES: usize = 16;
+/// Longest module path in a filter.
+const MAX_PATH_LEN: usize = 64;
+
+/// An output device for kernel log messages.
+pub trait Console: Sync {
+    /// Name of the console (for diagnostics).
+    #[cfg_attr(not(target_os = "none"), allow(dead_code))]
+    fn name(&self) -> &'static str;
+
+    /// Writes `s` to the console.
+    ///
+    /// This is called with the console list locked, so implementations
+    /// must not log.
+    fn write_str(&self, s: &str);
+}
+
+static CONSOLES: RwLock<ArrayVec<&'static dyn Console, MAX_CONSOLES>> =
+    RwLock::new(ArrayVec::new_const());
+
+/// Forwards formatted output to a console.
+struct ConsoleWriter<'a>(&'a dyn Console);
+
+impl Write for ConsoleWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s);
+        Ok(())
+    }
+}
+
+/// Decides which log records we print.
+#[derive(Debug, PartialEq)]
+pub struct Filter {
+    /// Level for modules without a directive.
+    default: LevelFilter,
+    /// Module path prefixes and their levels.
+    directives: ArrayVec<(ArrayString<MAX_PATH_LEN>, LevelFilter), MAX_DIRECTIVES>,
+}
+
+impl Filter {
+    const fn new(default: LevelFilter) -> Filter {
+        Filter {
+            default,
+            directives: ArrayVec::new_const(),
+        }
+    }
+
+    /// Parses a comma separated list of `level`, `path=level` or `path`
+    /// (which logs everything in `path`).
+    pub fn parse(spec: &str) -> Result<Filter, KError> {
+        let mut filter = Filter::new(LevelFilter::Info);
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let mut parts = directive.splitn(2, '=');
+            let (path, level) = match (parts.next(), parts.next()) {
+                (Some(level), None) => match LevelFilter::from_str(level) {
+                    Ok(level) => {
+                        filter.default = level;
+                        continue;
+                    }
+                    Err(_) => (level, LevelFilter::Trace),
+                },
+                (Some(path), Some(level)) => (
+                    path,
+                    LevelFilter::from_str(level).map_err(|_| KError::InvalidLogFilter)?,
+                ),
+                _ => return Err(KError::InvalidLogFilter),
+            };
+            if path.is_empty() {
+                return Err(KError::InvalidLogFilter);
+            }
+
+            let path = ArrayString::from(path).map_err(|_| KError::InvalidLogFilter)?;
+            filter
+                .directives
+                .try_push((path, level))
+                .map_err(|_| KError::CapacityOverflow)?;
+        }
+        Ok(filter)
+    }
+
+    /// The level for records from `target` (the longest matching path
+    /// wins).
+    fn level(&self, target: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .filter(|(path, _)| {
+                target.starts_with(path.as_str())
+                    && (target.len() == path.len() || target[path.len()..].starts_with("::"))
+            })
+            .max_by_key(|(path, _)| path.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// The highest level anything gets logged at.
+    fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, LevelFilter::max)
+    }
+}
+
+static FILTER: RwLock<Filter> = RwLock::new(Filter::new(LevelFilter::Info));
+
+struct Logger;
+
+static LOGGER: Logger = Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= FILTER.read().level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        {
+            let _r = klogger::SERIAL_LINE_MUTEX.lock();
+            sprintln!(
+                "[{:>5}] - {}: {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+        let _r = writeln!(
+            dmesg::writer(),
+            "[{:>5}] - {}: {}",
+            record.level(),
+            record.target(),
+            record.args()
+        );
+
+        // Formatting doesn't allocate, so this is fine to call from the
+        // memory allocator
+        for console in CONSOLES.read().iter() {
+            let _r = writeln!(
+                ConsoleWriter(*console),
+                "[{:>5}] {}",
+                record.level(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the kernel logger, `filter` decides what gets logged (e.g.,
+/// "info" or "info,nrk::memory=trace", see [`Filter::parse`]).
+pub fn init(filter: &str) -> Result<(), SetLoggerError> {
+    log::set_logger(&LOGGER)?;
+    log::set_max_level(LevelFilter::Info);
+    if let Err(e) = set_filter(filter) {
+        error!("Invalid log filter '{}' (using info): {}", filter, e);
+    }
+    Ok(())
+}
+
+/// Replaces the log filter with `spec` (see [`Filter::parse`]).
+pub fn set_filter(spec: &str) -> Result<(), KError> {
+    let filter = Filter::parse(spec)?;
+    // The `log` macros don't call us for anything above this
+    let max_level = filter.max_level();
+    *FILTER.write() = filter;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// Adds `console` to the outputs for log messages.
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn register(console: &'static dyn Console) -> Result<(), KError> {
+    CONSOLES
+        .write()
+        .try_push(console)
+        .map_err(|_| KError::CapacityOverflow)?;
+    log::info!("Registered console {}", console.name());
+    Ok(())
+}
+
+/// Writes `s` to all registered consoles and the log ring (but not to
+/// serial).
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn write_str(s: &str) {
+    dmesg::write_str(s);
+    for console in CONSOLES.read().iter() {
+        console.write_str(s);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn filter_level() {
+        let filter = Filter::parse("info").unwrap();
+        assert_eq!(filter.level("nrk::memory"), LevelFilter::Info);
+        assert_eq!(filter.max_level(), LevelFilter::Info);
+
+        let filter = Filter::parse("warn,nrk::memory=trace, nrk::memory::vspace=off").unwrap();
+        assert_eq!(filter.level("nrk"), LevelFilter::Warn);
+        assert_eq!(filter.level("nrk::memory"), LevelFilter::Trace);
+        assert_eq!(filter.level("nrk::memory::mcache"), LevelFilter::Trace);
+        assert_eq!(filter.level("nrk::memory::vspace"), LevelFilter::Off);
+        assert_eq!(filter.level("nrk::memoryx"), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+
+        let filter = Filter::parse("nrk::net").unwrap();
+        assert_eq!(filter.level("nrk::net::socket"), LevelFilter::Trace);
+        assert_eq!(filter.level("nrk::nr"), LevelFilter::Info);
+
+        assert_eq!(Filter::parse(""), Ok(Filter::new(LevelFilter::Info)));
+    }
+
+    #[test]
+    fn filter_invalid() {
+        assert_eq!(
+            Filter::parse("nrk::net=loud"),
+            Err(KError::InvalidLogFilter)
+        );
+        assert_eq!(Filter::parse("=info"), Err(KError::InvalidLogFilter));
+        let long = [b'a'; MAX_PATH_LEN + 1];
+        assert_eq!(
+            Filter::parse(core::str::from_utf8(&long).unwrap()),
+            Err(KError::InvalidLogFilter)
+        );
+
+        let mut many = alloc::string::String::new();
+        for _ in 0..=MAX_DIRECTIVES {
+            many.push_str("nrk=info,");
+        }
+        assert_eq!(Filter::parse(&many), Err(KError::CapacityOverflow));
+    }
+}
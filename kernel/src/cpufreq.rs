@@ -0,0 +1,99 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Frequency scaling of cores, the ondemand governor.
+//!
+//! A core samples how busy it was since its last sample (the time it didn't
+//! spend idle, see `crate::idle`), at most every `SAMPLE_INTERVAL`. Above
+//! `UP_THRESHOLD` percent it goes to its highest frequency, below that to
+//! one in proportion to the load. Root can pin the frequency of a core
+//! instead (`System::set_frequency`), e.g., for reproducible benchmarks, or
+//! to run a governor of its own in user-space on top of the idle residency
+//! in `System::stats`. Reading and programming frequencies is up to the
+//! architecture (see `arch::cpufreq`).
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use core::time::Duration;
+
+/// How often a core looks at its load (at most).
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Load (in percent) above which a core runs at its highest frequency.
+pub const UP_THRESHOLD: u64 = 80;
+
+/// The frequency for a core that was busy `load` percent of the time,
+/// between `min` and `max`.
+pub fn ondemand(load: u64, min: u32, max: u32) -> u32 {
+    if load >= UP_THRESHOLD || max <= min {
+        max
+    } else {
+        min + ((max - min) as u64 * load / 100) as u32
+    }
+}
+
+/// Measures the load of a core from one sample to the next.
+#[derive(Debug, Default)]
+pub struct Sampler {
+    /// When we took the last sample and how long the core had been idle
+    /// in total by then (ns).
+    last: Option<(u64, u64)>,
+}
+
+impl Sampler {
+    pub const fn new() -> Sampler {
+        Sampler { last: None }
+    }
+
+    /// Takes a sample at `now` (ns) with the core idle for `idle` ns in
+    /// total, returns the load (in percent) since the last one.
+    ///
+    /// There is no load for the first sample and until `SAMPLE_INTERVAL`
+    /// passed (that doesn't count as a sample).
+    pub fn sample(&mut self, now: u64, idle: u64) -> Option<u64> {
+        let load = match self.last {
+            Some((then, _)) if now.saturating_sub(then) < SAMPLE_INTERVAL.as_nanos() as u64 => {
+                return None
+            }
+            Some((then, was_idle)) => {
+                let elapsed = now - then;
+                let idle = idle.saturating_sub(was_idle).min(elapsed);
+                Some(100 - idle * 100 / elapsed)
+            }
+            None => None,
+        };
+        self.last = Some((now, idle));
+        load
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MS: u64 = 1_000_000;
+
+    #[test]
+    fn governor() {
+        assert_eq!(ondemand(0, 800, 3000), 800);
+        assert_eq!(ondemand(50, 800, 3000), 1900);
+        assert_eq!(ondemand(UP_THRESHOLD, 800, 3000), 3000);
+        assert_eq!(ondemand(100, 800, 3000), 3000);
+        assert_eq!(ondemand(10, 2000, 2000), 2000);
+    }
+
+    #[test]
+    fn sampling() {
+        let mut sampler = Sampler::new();
+        assert_eq!(sampler.sample(100 * MS, 50 * MS), None);
+        // Too soon
+        assert_eq!(sampler.sample(105 * MS, 50 * MS), None);
+        // Idle for a quarter of it
+        assert_eq!(sampler.sample(120 * MS, 55 * MS), Some(75));
+        // Busy all the time
+        assert_eq!(sampler.sample(140 * MS, 55 * MS), Some(100));
+        // Idle all the time (and a bit more, idle periods are accounted
+        // when they end)
+        assert_eq!(sampler.sample(150 * MS, 70 * MS), Some(0));
+    }
+}
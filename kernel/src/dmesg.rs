@@ -0,0 +1,151 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The kernel log ring: the most recent `SIZE` bytes of kernel output
+//! (log records and what processes print).
+//!
+//! Positions in the ring are byte offsets since boot, readers keep the
+//! offset they want to continue at, and notice a gap if the ring wrapped
+//! past it in the mean time.
+
+use core::fmt;
+
+use spin::{Mutex, MutexGuard};
+
+/// How much output we keep.
+pub const SIZE: usize = 64 * 1024;
+
+pub struct Ring {
+    data: [u8; SIZE],
+    /// Bytes written since boot.
+    written: u64,
+}
+
+impl Ring {
+    const fn new() -> Ring {
+        Ring {
+            data: [0; SIZE],
+            written: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.data[(self.written % SIZE as u64) as usize] = *b;
+            self.written += 1;
+        }
+    }
+
+    /// Offset of the oldest byte we still have.
+    fn start(&self) -> u64 {
+        self.written.saturating_sub(SIZE as u64)
+    }
+
+    /// The contents (oldest first) as two slices.
+    fn as_slices(&self) -> (&[u8], &[u8]) {
+        if self.written <= SIZE as u64 {
+            (&self.data[..self.written as usize], &[])
+        } else {
+            let split = (self.written % SIZE as u64) as usize;
+            (&self.data[split..], &self.data[..split])
+        }
+    }
+
+    /// Copies the output at `offset` into `buf`, returns how many bytes we
+    /// copied and the offset to continue at.
+    ///
+    /// Reads from the oldest byte if `offset` got overwritten already.
+    fn read(&self, offset: u64, buf: &mut [u8]) -> (usize, u64) {
+        let offset = offset.max(self.start()).min(self.written);
+        let len = ((self.written - offset) as usize).min(buf.len());
+
+        let (old, new) = self.as_slices();
+        let skip = (offset - self.start()) as usize;
+        for (dst, src) in buf[..len]
+            .iter_mut()
+            .zip(old.iter().chain(new.iter()).skip(skip))
+        {
+            *dst = *src;
+        }
+        (len, offset + len as u64)
+    }
+}
+
+/// Appends formatted output to the ring.
+pub struct RingWriter<'a>(MutexGuard<'a, Ring>);
+
+impl fmt::Write for RingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring::new());
+
+/// Locks the ring for writing (don't log while holding it).
+pub fn writer() -> RingWriter<'static> {
+    RingWriter(RING.lock())
+}
+
+/// Appends `s` to the ring.
+pub fn write_str(s: &str) {
+    RING.lock().push(s.as_bytes());
+}
+
+/// Copies the output at `offset` into `buf`, returns how many bytes we
+/// copied and the offset to continue at.
+pub fn read(offset: u64, buf: &mut [u8]) -> (usize, u64) {
+    RING.lock().read(offset, buf)
+}
+
+/// Runs `f` with the contents of the ring, unless it is locked (e.g., we
+/// panicked while writing to it).
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn try_with_contents<R>(f: impl FnOnce(&[u8], &[u8]) -> R) -> Option<R> {
+    RING.try_lock().map(|ring| {
+        let (old, new) = ring.as_slices();
+        f(old, new)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::boxed::Box;
+
+    #[test]
+    fn wrap_around() {
+        let mut ring = Box::new(Ring::new());
+        ring.push(b"abc");
+        assert_eq!(ring.as_slices(), (&b"abc"[..], &b""[..]));
+
+        ring.push(&[b'x'; SIZE - 2]);
+        let (old, new) = ring.as_slices();
+        assert_eq!(old.len() + new.len(), SIZE);
+        assert_eq!(&old[..2], b"bc");
+        assert_eq!(new, b"x");
+    }
+
+    #[test]
+    fn read_offsets() {
+        let mut ring = Box::new(Ring::new());
+        ring.push(b"hello world");
+
+        let mut buf = [0u8; 5];
+        assert_eq!(ring.read(0, &mut buf), (5, 5));
+        assert_eq!(&buf, b"hello");
+        assert_eq!(ring.read(5, &mut buf), (5, 10));
+        assert_eq!(&buf, b" worl");
+        assert_eq!(ring.read(10, &mut buf), (1, 11));
+        assert_eq!(ring.read(11, &mut buf), (0, 11));
+        assert_eq!(ring.read(100, &mut buf), (0, 11));
+
+        // Offset 0 got overwritten, we continue at the oldest byte
+        ring.push(&[b'x'; SIZE]);
+        assert_eq!(ring.read(0, &mut buf), (5, 16));
+        assert_eq!(&buf, b"xxxxx");
+        assert_eq!(ring.read(SIZE as u64 + 6, &mut buf), (5, SIZE as u64 + 11));
+        assert_eq!(&buf, b"xxxxx");
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Driver for AHCI host bus adapters (SATA disks).
+//!
+//! Every port with a SATA disk becomes a block device. If the HBA and the
+//! disk support native command queuing, reads and writes from different
+//! cores are queued on the disk concurrently, otherwise the HBA issues them
+//! one after another. We poll for completions.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{debug, info, warn};
+
+use crate::arch::Platform;
+use crate::arch_interface::Arch;
+use crate::drivers::block::{self, BlockDevice};
+use crate::drivers::pci::{self, Bar, PciDevice, PciDriver, PciMatch};
+use crate::error::KError;
+use crate::memory::vspace::MapAction;
+use crate::memory::PAddr;
+
+mod port;
+mod spec;
+
+use port::{Port, SLOT_BUFFER_SIZE};
+use spec::*;
+
+/// The PCI driver (matches all SATA controllers in AHCI mode).
+pub static DRIVER: PciDriver = PciDriver {
+    name: "ahci",
+    ids: &[PciMatch::Class {
+        class: 0x01,
+        subclass: 0x06,
+    }],
+    attach,
+};
+
+/// Bytes of the IDENTIFY DEVICE data.
+const IDENTIFY_SIZE: usize = 512;
+
+/// Used to name the disks (sata0, sata1, ...).
+static DISKS: AtomicUsize = AtomicUsize::new(0);
+
+/// A SATA disk.
+pub struct Disk {
+    port: Port,
+    block_size: usize,
+    num_blocks: u64,
+}
+
+impl Disk {
+    /// Reads the IDENTIFY DEVICE data of the disk on `port`, `hba_ncq` is
+    /// whether the HBA supports NCQ.
+    fn identify(mut port: Port, hba_ncq: bool) -> Result<Disk, KError> {
+        let mut words = [0u16; IDENTIFY_SIZE / 2];
+        port.with_slot(false, |slot, buffer| {
+            let fis = RegisterFis::new(ATA_IDENTIFY_DEVICE, 0, 0, 0);
+            port.execute(slot, fis, false, IDENTIFY_SIZE, false)?;
+            for (i, word) in words.iter_mut().enumerate() {
+                *word = u16::from_le_bytes([buffer[2 * i], buffer[2 * i + 1]]);
+            }
+            Ok(())
+        })?;
+
+        // ATA strings have the two characters of a word swapped
+        let model: String = words[27..47]
+            .iter()
+            .flat_map(|w| w.to_be_bytes())
+            .map(|b| b as char)
+            .collect();
+
+        if words[83] & (1 << 10) == 0 {
+            // TODO(correctness): Use READ/WRITE DMA (28-bit LBAs) for disks
+            // without the 48-bit address feature set.
+            return Err(KError::NotSupported);
+        }
+        let num_blocks = words[100..104]
+            .iter()
+            .rev()
+            .fold(0u64, |acc, w| acc << 16 | *w as u64);
+
+        // Word 106 is valid if bit 14 is set and bit 15 clear, bit 12 means
+        // the logical sector is larger than 256 words
+        let block_size = if words[106] & 0xd000 == 0x5000 {
+            (words[117] as usize | (words[118] as usize) << 16) * 2
+        } else {
+            512
+        };
+        if block_size > SLOT_BUFFER_SIZE {
+            return Err(KError::NotSupported);
+        }
+
+        let disk_ncq = words[76] & (1 << 8) != 0;
+        if hba_ncq && disk_ncq {
+            port.enable_ncq((words[75] & 0x1f) as usize + 1);
+        }
+
+        info!(
+            "SATA disk {} on port {}: {} blocks of {} bytes, NCQ {}",
+            model.trim(),
+            port.num(),
+            num_blocks,
+            block_size,
+            if port.ncq() { "on" } else { "off" }
+        );
+        Ok(Disk {
+            port,
+            block_size,
+            num_blocks,
+        })
+    }
+
+    /// Reads or writes `len` bytes at `lba`, `copy` moves the data of a
+    /// chunk between the slot buffer and the caller's buffer.
+    fn transfer(
+        &self,
+        write: bool,
+        lba: u64,
+        len: usize,
+        mut copy: impl FnMut(usize, &mut [u8]),
+    ) -> Result<(), KError> {
+        block::check_range(self, lba, len)?;
+        let chunk = SLOT_BUFFER_SIZE / self.block_size * self.block_size;
+        let queued = self.port.ncq();
+
+        let mut offset = 0;
+        while offset < len {
+            let bytes = chunk.min(len - offset);
+            let blocks = (bytes / self.block_size) as u16;
+            let lba = lba + (offset / self.block_size) as u64;
+
+            self.port.with_slot(queued, |slot, buffer| {
+                let fis = match (queued, write) {
+                    // NCQ commands have the count in the features and the
+                    // tag in the count register
+                    (true, false) => {
+                        RegisterFis::new(ATA_READ_FPDMA_QUEUED, lba, blocks, (slot as u16) << 3)
+                    }
+                    (true, true) => {
+                        RegisterFis::new(ATA_WRITE_FPDMA_QUEUED, lba, blocks, (slot as u16) << 3)
+                    }
+                    (false, false) => RegisterFis::new(ATA_READ_DMA_EXT, lba, 0, blocks),
+                    (false, true) => RegisterFis::new(ATA_WRITE_DMA_EXT, lba, 0, blocks),
+                };
+
+                if write {
+                    copy(offset, &mut buffer[..bytes]);
+                }
+                self.port.execute(slot, fis, write, bytes, queued)?;
+                if !write {
+                    copy(offset, &mut buffer[..bytes]);
+                }
+                Ok(())
+            })?;
+            offset += bytes;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for Disk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), KError> {
+        let len = buf.len();
+        self.transfer(false, lba, len, |offset, chunk| {
+            buf[offset..offset + chunk.len()].copy_from_slice(chunk)
+        })
+    }
+
+    fn write(&self, lba: u64, buf: &[u8]) -> Result<(), KError> {
+        self.transfer(true, lba, buf.len(), |offset, chunk| {
+            chunk.copy_from_slice(&buf[offset..offset + chunk.len()])
+        })
+    }
+
+    fn flush(&self) -> Result<(), KError> {
+        self.port.with_slot(false, |slot, _buffer| {
+            let fis = RegisterFis::new(ATA_FLUSH_CACHE_EXT, 0, 0, 0);
+            self.port.execute(slot, fis, false, 0, false)
+        })
+    }
+}
+
+/// Enables AHCI mode on the HBA `dev` and registers a block device for
+/// every disk attached to it.
+fn attach(dev: &PciDevice) -> Result<(), KError> {
+    let (base, size) = match dev.bars[5] {
+        Some(Bar::Memory { base, size, .. }) => (base, size),
+        _ => return Err(KError::NotSupported),
+    };
+    Platform::map_kernel_identity(PAddr::from(base), size as usize, MapAction::ReadWriteKernel)?;
+    pci::enable_bus_master(dev)?;
+
+    let read = |reg: u64| unsafe { ptr::read_volatile((base + reg) as *const u32) };
+    let write =
+        |reg: u64, value: u32| unsafe { ptr::write_volatile((base + reg) as *mut u32, value) };
+
+    write(HBA_GHC, read(HBA_GHC) | GHC_AHCI_ENABLE);
+    let cap = read(HBA_CAP);
+    let implemented = read(HBA_PI);
+    let version = read(HBA_VS);
+    let slots = ((cap >> 8) & 0x1f) as usize + 1;
+    debug!(
+        "AHCI version {}.{} cap {:#x} ports {:#x}",
+        version >> 16,
+        (version >> 8) & 0xff,
+        cap,
+        implemented
+    );
+
+    for num in (0..32).filter(|n| implemented & (1 << n) != 0) {
+        let port = match Port::new(base, num, slots, cap & CAP_S64A != 0) {
+            Ok(Some(port)) => port,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("Unable to use AHCI port {}: {}", num, e);
+                continue;
+            }
+        };
+
+        let registered = port
+            .start()
+            .and_then(|_| Disk::identify(port, cap & CAP_SNCQ != 0))
+            .and_then(|disk| {
+                let name = format!("sata{}", DISKS.fetch_add(1, Ordering::Relaxed));
+                block::register(name, Arc::try_new(disk)?)
+            });
+        if let Err(e) = registered {
+            warn!("Unable to use SATA disk on port {}: {}", num, e);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,307 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A port of the HBA with its command list and command slots.
+
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use log::trace;
+
+use crate::error::KError;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::LARGE_PAGE_SIZE;
+
+use super::spec::*;
+
+/// How long we wait for the device to complete a command.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long we wait for the command list/FIS receive engines to stop.
+const ENGINE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Layout of the DMA memory of a port: the command list, the received FIS
+/// area, one command table per slot and one data buffer per slot.
+const COMMAND_LIST_OFFSET: usize = 0x0;
+const RECEIVED_FIS_OFFSET: usize = 0x400;
+const COMMAND_TABLE_OFFSET: usize = 0x1000;
+const COMMAND_TABLE_SIZE: usize = 0x100;
+const SLOT_BUFFER_OFFSET: usize = 0x10000;
+
+/// Bytes a single command can transfer.
+pub const SLOT_BUFFER_SIZE: usize = 0x8000;
+
+/// Number of command slots the AHCI specification allows.
+pub const MAX_SLOTS: usize = 32;
+
+static_assertions::const_assert!(
+    COMMAND_TABLE_OFFSET + MAX_SLOTS * COMMAND_TABLE_SIZE <= SLOT_BUFFER_OFFSET
+);
+static_assertions::const_assert!(
+    SLOT_BUFFER_OFFSET + MAX_SLOTS * SLOT_BUFFER_SIZE <= LARGE_PAGE_SIZE
+);
+
+/// A port with a SATA disk attached.
+///
+/// Every command slot has its own command table and data buffer, so cores
+/// can have commands outstanding on the same port concurrently (the disk
+/// reorders them if it supports NCQ).
+pub struct Port {
+    num: u32,
+    /// Address of the port registers.
+    regs: u64,
+    mem: DmaBuffer,
+    /// Bitmask of the slots we may use.
+    slots: u32,
+    /// Bitmask of the slots that have a command in flight.
+    busy: AtomicU32,
+    /// Whether commands are queued (NCQ).
+    ncq: bool,
+}
+
+impl Port {
+    /// Returns port `num` of the HBA at `abar` if a SATA disk is attached
+    /// to it.
+    ///
+    /// `slots` is the number of command slots the HBA supports, `s64a`
+    /// whether it can address memory above 4 GiB.
+    pub fn new(abar: u64, num: u32, slots: usize, s64a: bool) -> Result<Option<Port>, KError> {
+        let regs = abar + PORT_BASE + num as u64 * PORT_SIZE;
+        let read = |reg: u64| unsafe { ptr::read_volatile((regs + reg) as *const u32) };
+        if read(PX_SSTS) & 0xf != SSTS_DET_PRESENT || read(PX_SIG) != SIG_ATA {
+            return Ok(None);
+        }
+
+        let mem = DmaBuffer::new(LARGE_PAGE_SIZE)?;
+        if !s64a && mem.paddr().as_u64() + mem.len() as u64 > u32::MAX as u64 {
+            // TODO(correctness): Allocate from memory below 4 GiB instead.
+            return Err(KError::NotSupported);
+        }
+
+        let slots = slots.min(MAX_SLOTS);
+        Ok(Some(Port {
+            num,
+            regs,
+            mem,
+            slots: (((1u64 << slots) - 1) as u32),
+            busy: AtomicU32::new(0),
+            ncq: false,
+        }))
+    }
+
+    pub fn num(&self) -> u32 {
+        self.num
+    }
+
+    pub fn ncq(&self) -> bool {
+        self.ncq
+    }
+
+    /// Queue commands (with at most `depth` outstanding ones).
+    pub fn enable_ncq(&mut self, depth: usize) {
+        let depth = depth.min(self.slots.count_ones() as usize);
+        self.slots = ((1u64 << depth) - 1) as u32;
+        self.ncq = true;
+    }
+
+    fn read(&self, reg: u64) -> u32 {
+        unsafe { ptr::read_volatile((self.regs + reg) as *const u32) }
+    }
+
+    fn write(&self, reg: u64, value: u32) {
+        unsafe { ptr::write_volatile((self.regs + reg) as *mut u32, value) }
+    }
+
+    /// Waits until the bits `mask` of register `reg` are clear.
+    fn wait_clear(&self, reg: u64, mask: u32, timeout: Duration) -> Result<(), KError> {
+        let start = rawtime::Instant::now();
+        while self.read(reg) & mask != 0 {
+            if start.elapsed() > timeout {
+                return Err(KError::DeviceTimeout);
+            }
+            spin_loop();
+        }
+        Ok(())
+    }
+
+    fn paddr(&self, offset: usize) -> u64 {
+        self.mem.paddr().as_u64() + offset as u64
+    }
+
+    /// Stops the port, points it to our command list and received FIS area
+    /// and starts it again.
+    pub fn start(&self) -> Result<(), KError> {
+        let cmd = self.read(PX_CMD);
+        self.write(PX_CMD, cmd & !CMD_START);
+        self.wait_clear(PX_CMD, CMD_LIST_RUNNING, ENGINE_TIMEOUT)?;
+        let cmd = self.read(PX_CMD);
+        self.write(PX_CMD, cmd & !CMD_FIS_RX_ENABLE);
+        self.wait_clear(PX_CMD, CMD_FIS_RX_RUNNING, ENGINE_TIMEOUT)?;
+
+        let clb = self.paddr(COMMAND_LIST_OFFSET);
+        let fb = self.paddr(RECEIVED_FIS_OFFSET);
+        self.write(PX_CLB, clb as u32);
+        self.write(PX_CLBU, (clb >> 32) as u32);
+        self.write(PX_FB, fb as u32);
+        self.write(PX_FBU, (fb >> 32) as u32);
+
+        // We poll, clear errors and interrupts left from the firmware
+        self.write(PX_IE, 0);
+        self.write(PX_SERR, u32::MAX);
+        self.write(PX_IS, u32::MAX);
+
+        let cmd = self.read(PX_CMD);
+        self.write(PX_CMD, cmd | CMD_FIS_RX_ENABLE);
+        self.wait_clear(PX_TFD, TFD_BSY | TFD_DRQ, COMMAND_TIMEOUT)?;
+        self.write(PX_CMD, cmd | CMD_FIS_RX_ENABLE | CMD_START);
+        Ok(())
+    }
+
+    /// Runs `f` with a free command slot and its data buffer.
+    ///
+    /// With NCQ, a non-`queued` command must not be in flight together with
+    /// any other command, so it waits until it has all the slots.
+    pub fn with_slot<R>(
+        &self,
+        queued: bool,
+        f: impl FnOnce(usize, &mut [u8]) -> Result<R, KError>,
+    ) -> Result<R, KError> {
+        let exclusive = self.ncq && !queued;
+        let slot = loop {
+            let busy = self.busy.load(Ordering::Relaxed);
+            let claim = if exclusive {
+                if busy == 0 {
+                    Some((0, self.slots))
+                } else {
+                    None
+                }
+            } else {
+                let free = !busy & self.slots;
+                if free != 0 {
+                    let slot = free.trailing_zeros();
+                    Some((slot, busy | 1 << slot))
+                } else {
+                    None
+                }
+            };
+
+            if let Some((slot, new)) = claim {
+                if self
+                    .busy
+                    .compare_exchange_weak(busy, new, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    break slot as usize;
+                }
+            }
+            spin_loop();
+        };
+
+        let buffer = unsafe {
+            core::slice::from_raw_parts_mut(
+                self.mem
+                    .as_ptr::<u8>()
+                    .add(SLOT_BUFFER_OFFSET + slot * SLOT_BUFFER_SIZE),
+                SLOT_BUFFER_SIZE,
+            )
+        };
+        let r = f(slot, buffer);
+
+        let release = if exclusive { self.slots } else { 1 << slot };
+        self.busy.fetch_and(!release, Ordering::Release);
+        r
+    }
+
+    /// Issues `fis` in `slot` and waits for it to complete.
+    ///
+    /// The command transfers the first `len` bytes of the slot's buffer,
+    /// `queued` means `fis` is an NCQ command with `slot` as tag.
+    ///
+    /// We don't recover from errors: A failed command stops the port and
+    /// all further commands on it fail too.
+    pub fn execute(
+        &self,
+        slot: usize,
+        fis: RegisterFis,
+        write: bool,
+        len: usize,
+        queued: bool,
+    ) -> Result<(), KError> {
+        debug_assert!(self.busy.load(Ordering::Relaxed) & 1 << slot != 0);
+        debug_assert!(len <= SLOT_BUFFER_SIZE && len % 2 == 0);
+        if self.read(PX_IS) & IS_TFES != 0 {
+            return Err(KError::AhciCommandFailed {
+                status: self.read(PX_TFD),
+            });
+        }
+
+        let table_offset = COMMAND_TABLE_OFFSET + slot * COMMAND_TABLE_SIZE;
+        let mut table = CommandTable {
+            cfis: [0; 64],
+            acmd: [0; 16],
+            reserved: [0; 48],
+            prd: Prd {
+                dba: self.paddr(SLOT_BUFFER_OFFSET + slot * SLOT_BUFFER_SIZE),
+                reserved: 0,
+                dbc: len.saturating_sub(1) as u32,
+            },
+        };
+        let fis_bytes = unsafe {
+            core::slice::from_raw_parts(
+                &fis as *const RegisterFis as *const u8,
+                core::mem::size_of::<RegisterFis>(),
+            )
+        };
+        table.cfis[..fis_bytes.len()].copy_from_slice(fis_bytes);
+        let header =
+            CommandHeader::new(self.paddr(table_offset), write, if len > 0 { 1 } else { 0 });
+
+        unsafe {
+            ptr::write_volatile(
+                self.mem.as_ptr::<u8>().add(table_offset) as *mut CommandTable,
+                table,
+            );
+            let list = self.mem.as_ptr::<u8>().add(COMMAND_LIST_OFFSET) as *mut CommandHeader;
+            ptr::write_volatile(list.add(slot), header);
+        }
+
+        let bit = 1 << slot;
+        if queued {
+            self.write(PX_SACT, bit);
+        }
+        self.write(PX_CI, bit);
+
+        let start = rawtime::Instant::now();
+        loop {
+            let mut pending = self.read(PX_CI);
+            if queued {
+                pending |= self.read(PX_SACT);
+            }
+            if pending & bit == 0 {
+                break;
+            }
+            if self.read(PX_IS) & IS_TFES != 0 {
+                break;
+            }
+            if start.elapsed() > COMMAND_TIMEOUT {
+                return Err(KError::DeviceTimeout);
+            }
+            spin_loop();
+        }
+
+        let tfd = self.read(PX_TFD);
+        trace!(
+            "ahci p{} slot {} command {:#x} tfd {:#x}",
+            self.num,
+            slot,
+            fis.command,
+            tfd
+        );
+        if self.read(PX_IS) & IS_TFES != 0 || tfd & TFD_ERR != 0 {
+            return Err(KError::AhciCommandFailed { status: tfd });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Registers and data structures from the AHCI (rev. 1.3.1) and ATA/ATAPI
+//! command set specifications.
+
+/// HBA capabilities.
+pub const HBA_CAP: u64 = 0x00;
+/// Global HBA control.
+pub const HBA_GHC: u64 = 0x04;
+/// Ports implemented.
+pub const HBA_PI: u64 = 0x0c;
+/// Version.
+pub const HBA_VS: u64 = 0x10;
+
+pub const CAP_SNCQ: u32 = 1 << 30;
+pub const CAP_S64A: u32 = 1 << 31;
+pub const GHC_AHCI_ENABLE: u32 = 1 << 31;
+
+/// Port registers are at `PORT_BASE + port * PORT_SIZE`.
+pub const PORT_BASE: u64 = 0x100;
+pub const PORT_SIZE: u64 = 0x80;
+
+/// Command list base address (lower and upper 32 bits).
+pub const PX_CLB: u64 = 0x00;
+pub const PX_CLBU: u64 = 0x04;
+/// FIS base address (lower and upper 32 bits).
+pub const PX_FB: u64 = 0x08;
+pub const PX_FBU: u64 = 0x0c;
+/// Interrupt status.
+pub const PX_IS: u64 = 0x10;
+/// Interrupt enable.
+pub const PX_IE: u64 = 0x14;
+/// Command and status.
+pub const PX_CMD: u64 = 0x18;
+/// Task file data.
+pub const PX_TFD: u64 = 0x20;
+/// Signature.
+pub const PX_SIG: u64 = 0x24;
+/// SATA status.
+pub const PX_SSTS: u64 = 0x28;
+/// SATA error.
+pub const PX_SERR: u64 = 0x30;
+/// SATA active (NCQ tags that are outstanding).
+pub const PX_SACT: u64 = 0x34;
+/// Command issue.
+pub const PX_CI: u64 = 0x38;
+
+pub const CMD_START: u32 = 1 << 0;
+pub const CMD_FIS_RX_ENABLE: u32 = 1 << 4;
+pub const CMD_FIS_RX_RUNNING: u32 = 1 << 14;
+pub const CMD_LIST_RUNNING: u32 = 1 << 15;
+
+/// Task file error.
+pub const IS_TFES: u32 = 1 << 30;
+
+pub const TFD_ERR: u32 = 1 << 0;
+pub const TFD_DRQ: u32 = 1 << 3;
+pub const TFD_BSY: u32 = 1 << 7;
+
+/// Device detected and PHY communication established.
+pub const SSTS_DET_PRESENT: u32 = 0x3;
+/// Signature of a SATA disk (as opposed to ATAPI, port multipliers, ...).
+pub const SIG_ATA: u32 = 0x0000_0101;
+
+pub const ATA_READ_DMA_EXT: u8 = 0x25;
+pub const ATA_WRITE_DMA_EXT: u8 = 0x35;
+pub const ATA_READ_FPDMA_QUEUED: u8 = 0x60;
+pub const ATA_WRITE_FPDMA_QUEUED: u8 = 0x61;
+pub const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+pub const ATA_IDENTIFY_DEVICE: u8 = 0xec;
+
+/// Device register: LBA addressing.
+pub const DEVICE_LBA: u8 = 1 << 6;
+
+/// An entry of the command list.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CommandHeader {
+    /// FIS length (in dwords), write bit, and PRD table length.
+    pub flags: u32,
+    /// Bytes transferred (updated by the HBA).
+    pub prdbc: u32,
+    /// Command table base address (128-byte aligned).
+    pub ctba: u64,
+    pub reserved: [u32; 4],
+}
+
+static_assertions::const_assert_eq!(core::mem::size_of::<CommandHeader>(), 32);
+
+impl CommandHeader {
+    pub fn new(ctba: u64, write: bool, prdtl: u16) -> CommandHeader {
+        let cfl = (core::mem::size_of::<RegisterFis>() / 4) as u32;
+        let write = if write { 1 << 6 } else { 0 };
+        CommandHeader {
+            flags: (prdtl as u32) << 16 | write | cfl,
+            prdbc: 0,
+            ctba,
+            reserved: [0; 4],
+        }
+    }
+}
+
+/// A physical region descriptor.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Prd {
+    pub dba: u64,
+    pub reserved: u32,
+    /// Byte count - 1 (bit 0 must be set, i.e., even byte counts).
+    pub dbc: u32,
+}
+
+static_assertions::const_assert_eq!(core::mem::size_of::<Prd>(), 16);
+
+/// A command table with a single PRD.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct CommandTable {
+    pub cfis: [u8; 64],
+    pub acmd: [u8; 16],
+    pub reserved: [u8; 48],
+    pub prd: Prd,
+}
+
+static_assertions::const_assert_eq!(core::mem::size_of::<CommandTable>(), 0x90);
+
+/// Register host to device FIS.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct RegisterFis {
+    pub fis_type: u8,
+    /// Bit 7 means the FIS carries a command.
+    pub pm_c: u8,
+    pub command: u8,
+    pub feature_low: u8,
+    pub lba0: u8,
+    pub lba1: u8,
+    pub lba2: u8,
+    pub device: u8,
+    pub lba3: u8,
+    pub lba4: u8,
+    pub lba5: u8,
+    pub feature_high: u8,
+    pub count_low: u8,
+    pub count_high: u8,
+    pub icc: u8,
+    pub control: u8,
+    pub reserved: [u8; 4],
+}
+
+static_assertions::const_assert_eq!(core::mem::size_of::<RegisterFis>(), 20);
+
+impl RegisterFis {
+    const TYPE_H2D: u8 = 0x27;
+
+    /// An ATA `command` for `lba` (`count` goes to features for NCQ
+    /// commands and to the count field otherwise).
+    pub fn new(command: u8, lba: u64, features: u16, count: u16) -> RegisterFis {
+        RegisterFis {
+            fis_type: RegisterFis::TYPE_H2D,
+            pm_c: 1 << 7,
+            command,
+            feature_low: features as u8,
+            lba0: lba as u8,
+            lba1: (lba >> 8) as u8,
+            lba2: (lba >> 16) as u8,
+            device: DEVICE_LBA,
+            lba3: (lba >> 24) as u8,
+            lba4: (lba >> 32) as u8,
+            lba5: (lba >> 40) as u8,
+            feature_high: (features >> 8) as u8,
+            count_low: count as u8,
+            count_high: (count >> 8) as u8,
+            ..Default::default()
+        }
+    }
+}
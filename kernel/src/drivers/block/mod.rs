@@ -0,0 +1,152 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Block devices (disks) and a registry to find them by name.
+//!
+//! Drivers of devices with multiple hardware queues go through the
+//! multi-queue layer in `mq`.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use fallible_collections::vec::FallibleVec;
+use log::info;
+use spin::Mutex;
+
+use crate::error::KError;
+
+pub mod mq;
+
+/// A device that reads and writes fixed-size blocks.
+///
+/// Implementations are shared between cores, drivers that have multiple
+/// hardware queues implement `mq::MqDriver` instead and register a
+/// `mq::BlkMq`.
+pub trait BlockDevice: Send + Sync {
+    /// Size of a block (in bytes).
+    fn block_size(&self) -> usize;
+
+    /// Capacity of the device (in blocks).
+    fn num_blocks(&self) -> u64;
+
+    /// Reads `buf.len() / block_size()` blocks starting at block `lba`.
+    fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), KError>;
+
+    /// Writes `buf.len() / block_size()` blocks starting at block `lba`.
+    fn write(&self, lba: u64, buf: &[u8]) -> Result<(), KError>;
+
+    /// Makes sure completed writes are on stable storage.
+    fn flush(&self) -> Result<(), KError>;
+}
+
+/// Checks that a transfer of `len` bytes at `lba` fits on `dev` and is a
+/// multiple of the block size, returns the number of blocks.
+pub fn check_range(dev: &dyn BlockDevice, lba: u64, len: usize) -> Result<u64, KError> {
+    if len == 0 || len % dev.block_size() != 0 {
+        return Err(KError::InvalidBlockRange);
+    }
+    let blocks = (len / dev.block_size()) as u64;
+    match lba.checked_add(blocks) {
+        Some(end) if end <= dev.num_blocks() => Ok(blocks),
+        _ => Err(KError::InvalidBlockRange),
+    }
+}
+
+/// All block devices the drivers found.
+static DEVICES: Mutex<Vec<(String, Arc<dyn BlockDevice>)>> = Mutex::new(Vec::new());
+
+/// Makes `dev` available as `name` (e.g., nvme0).
+pub fn register(name: String, dev: Arc<dyn BlockDevice>) -> Result<(), KError> {
+    let mut devices = DEVICES.lock();
+    if devices.iter().any(|(n, _)| *n == name) {
+        return Err(KError::AlreadyPresent);
+    }
+
+    info!(
+        "Block device {}: {} blocks of {} bytes",
+        name,
+        dev.num_blocks(),
+        dev.block_size()
+    );
+    devices.try_push((name, dev))?;
+    Ok(())
+}
+
+/// Looks up the block device called `name`.
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, dev)| dev.clone())
+}
+
+/// Generates `/proc/block/devices`.
+pub(crate) fn proc_block_devices(out: &mut String) -> fmt::Result {
+    for (name, dev) in DEVICES.lock().iter() {
+        writeln!(out, "{} {} {}", name, dev.num_blocks(), dev.block_size())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A device without storage, only its geometry matters.
+    struct Geometry;
+
+    impl BlockDevice for Geometry {
+        fn block_size(&self) -> usize {
+            512
+        }
+
+        fn num_blocks(&self) -> u64 {
+            8
+        }
+
+        fn read(&self, _lba: u64, _buf: &mut [u8]) -> Result<(), KError> {
+            Ok(())
+        }
+
+        fn write(&self, _lba: u64, _buf: &[u8]) -> Result<(), KError> {
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), KError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn range() {
+        assert_eq!(check_range(&Geometry, 0, 512), Ok(1));
+        assert_eq!(check_range(&Geometry, 6, 1024), Ok(2));
+        assert_eq!(
+            check_range(&Geometry, 7, 1024),
+            Err(KError::InvalidBlockRange)
+        );
+        assert_eq!(
+            check_range(&Geometry, 0, 100),
+            Err(KError::InvalidBlockRange)
+        );
+        assert_eq!(check_range(&Geometry, 0, 0), Err(KError::InvalidBlockRange));
+        assert_eq!(
+            check_range(&Geometry, u64::MAX, 512),
+            Err(KError::InvalidBlockRange)
+        );
+    }
+
+    #[test]
+    fn registry() {
+        register(String::from("test0"), Arc::new(Geometry)).expect("Can't register");
+        assert_eq!(
+            register(String::from("test0"), Arc::new(Geometry)),
+            Err(KError::AlreadyPresent)
+        );
+        assert_eq!(get("test0").map(|d| d.num_blocks()), Some(8));
+        assert!(get("test1").is_none());
+    }
+}
@@ -0,0 +1,374 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A multi-queue block layer (like blk-mq in Linux).
+//!
+//! Drivers of devices with several hardware queues implement `MqDriver` and
+//! get wrapped in a `BlkMq`, which is the `BlockDevice` everybody else uses.
+//! Every core is mapped to a hardware queue and stages its requests in a
+//! software queue of its own, they go to the driver in one batch. A request
+//! is identified by a tag of its hardware queue, the tag also picks the
+//! bounce buffer its data goes through. Whichever core polls a hardware
+//! queue, completions are steered to the software queue of the core that
+//! submitted the request.
+//!
+//! Transfers are split into requests of at most a page which are in flight
+//! at the same time (as many as we get tags for), and cores on different
+//! hardware queues don't share any locks.
+
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use arrayvec::ArrayVec;
+use fallible_collections::vec::FallibleVec;
+use log::warn;
+use spin::Mutex;
+
+use super::{check_range, BlockDevice};
+use crate::error::KError;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::{PAddr, BASE_PAGE_SIZE};
+
+/// Most requests a hardware queue has in flight (tags are bits of a u64).
+pub const MAX_QUEUE_DEPTH: usize = 64;
+
+/// Size of the buffer of a request.
+pub const MAX_REQUEST_SIZE: usize = BASE_PAGE_SIZE;
+
+/// How long we wait for the device to complete any of our requests.
+const COMPLETION_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Op {
+    Read,
+    Write,
+    Flush,
+}
+
+/// What a driver has to do for a tag.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Request {
+    pub op: Op,
+    pub lba: u64,
+    /// Number of blocks (0 for flushes).
+    pub blocks: u32,
+    /// The (physically contiguous) data of the request.
+    pub paddr: PAddr,
+}
+
+/// A device with hardware queues that can have several requests in flight.
+pub trait MqDriver: Send + Sync {
+    /// Size of a block (in bytes, at most `MAX_REQUEST_SIZE`).
+    fn block_size(&self) -> usize;
+
+    /// Capacity of the device (in blocks).
+    fn num_blocks(&self) -> u64;
+
+    /// Number of hardware queues.
+    fn hw_queues(&self) -> usize;
+
+    /// How many requests a hardware queue can have in flight.
+    fn queue_depth(&self) -> usize;
+
+    /// Starts the (tag, request) pairs in `requests` on hardware queue `hwq`.
+    ///
+    /// The requests are what one core staged, the device should learn about
+    /// all of them at once. If this fails none of them was started.
+    fn queue_rqs(&self, hwq: usize, requests: &[(u16, Request)]) -> Result<(), KError>;
+
+    /// Calls `complete` with the tag and the result of every request on
+    /// `hwq` that completed since the last call.
+    fn poll(&self, hwq: usize, complete: &mut dyn FnMut(u16, Result<(), KError>));
+}
+
+/// The tags of a hardware queue (a set bit is a free tag).
+struct Tags {
+    free: AtomicU64,
+}
+
+impl Tags {
+    fn new(depth: usize) -> Tags {
+        let free = if depth >= MAX_QUEUE_DEPTH {
+            u64::MAX
+        } else {
+            (1 << depth) - 1
+        };
+        Tags {
+            free: AtomicU64::new(free),
+        }
+    }
+
+    fn alloc(&self) -> Option<u16> {
+        let mut free = self.free.load(Ordering::Relaxed);
+        while free != 0 {
+            let tag = free.trailing_zeros();
+            match self.free.compare_exchange_weak(
+                free,
+                free & !(1 << tag),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(tag as u16),
+                Err(current) => free = current,
+            }
+        }
+        None
+    }
+
+    fn free(&self, tag: u16) {
+        let old = self.free.fetch_or(1 << tag, Ordering::Release);
+        debug_assert_eq!(old & (1 << tag), 0, "Tag {} was free", tag);
+    }
+}
+
+/// What belongs to a tag.
+struct Slot {
+    /// The core that submitted the request (and gets the completion).
+    owner: AtomicUsize,
+    /// Allocated on first use (by a core of the queue, so it's close).
+    buffer: Mutex<Option<DmaBuffer>>,
+}
+
+struct HwQueue {
+    tags: Tags,
+    slots: Vec<Slot>,
+}
+
+/// The requests a core staged and the completions steered to it.
+#[derive(Default)]
+struct SwQueue {
+    staged: ArrayVec<(u16, Request), MAX_QUEUE_DEPTH>,
+    completed: ArrayVec<(u16, Result<(), KError>), MAX_QUEUE_DEPTH>,
+}
+
+/// The block device of an `MqDriver`.
+pub struct BlkMq<D: MqDriver> {
+    driver: D,
+    hw: Vec<HwQueue>,
+    /// One per core.
+    sw: Vec<Mutex<SwQueue>>,
+}
+
+impl<D: MqDriver> BlkMq<D> {
+    pub fn new(driver: D) -> Result<BlkMq<D>, KError> {
+        if driver.block_size() == 0 || driver.block_size() > MAX_REQUEST_SIZE {
+            return Err(KError::NotSupported);
+        }
+        let depth = driver.queue_depth().clamp(1, MAX_QUEUE_DEPTH);
+
+        let mut hw = Vec::new();
+        for _ in 0..driver.hw_queues().max(1) {
+            let mut slots = Vec::new();
+            for _ in 0..depth {
+                slots.try_push(Slot {
+                    owner: AtomicUsize::new(0),
+                    buffer: Mutex::new(None),
+                })?;
+            }
+            hw.try_push(HwQueue {
+                tags: Tags::new(depth),
+                slots,
+            })?;
+        }
+
+        let mut sw = Vec::new();
+        for _ in 0..atopology::MACHINE_TOPOLOGY.num_threads().max(1) {
+            sw.try_push(Mutex::new(SwQueue::default()))?;
+        }
+
+        Ok(BlkMq { driver, hw, sw })
+    }
+
+    /// Hands the requests `core` staged to hardware queue `hwq`.
+    fn run_queue(&self, core: usize, hwq: usize) {
+        let staged = core::mem::take(&mut self.sw[core].lock().staged);
+        if staged.is_empty() {
+            return;
+        }
+        if let Err(e) = self.driver.queue_rqs(hwq, &staged) {
+            let mut sw = self.sw[core].lock();
+            for (tag, _request) in staged {
+                sw.completed.push((tag, Err(e.clone())));
+            }
+        }
+    }
+
+    /// Steers the completions of `hwq` to the cores that submitted the
+    /// requests.
+    fn poll(&self, hwq: usize) {
+        let slots = &self.hw[hwq].slots;
+        let sw = &self.sw;
+        self.driver
+            .poll(hwq, &mut |tag, result| match slots.get(tag as usize) {
+                Some(slot) => {
+                    let owner = slot.owner.load(Ordering::Relaxed);
+                    sw[owner].lock().completed.push((tag, result));
+                }
+                None => warn!("Completion for unknown tag {} on queue {}", tag, hwq),
+            });
+    }
+
+    /// Splits `len` bytes at `lba` into requests and waits until they
+    /// completed, `copy` moves the data of a request between its buffer and
+    /// the caller's buffer.
+    fn transfer(
+        &self,
+        op: Op,
+        lba: u64,
+        len: usize,
+        mut copy: impl FnMut(usize, &mut [u8]),
+    ) -> Result<(), KError> {
+        let block_size = self.driver.block_size();
+        let chunk = MAX_REQUEST_SIZE / block_size * block_size;
+        let requests = match op {
+            Op::Flush => 1,
+            _ => (len + chunk - 1) / chunk,
+        };
+
+        let core = atopology::MACHINE_TOPOLOGY.current_thread().id;
+        let hwq = core % self.hw.len();
+        let queue = &self.hw[hwq];
+        // Offset and length of the data of our requests (by tag)
+        let mut ours: [Option<(usize, usize)>; MAX_QUEUE_DEPTH] = [None; MAX_QUEUE_DEPTH];
+        let (mut next, mut inflight) = (0, 0);
+        let mut error = None;
+        let mut progress = rawtime::Instant::now();
+
+        while next < requests || inflight > 0 {
+            // Stage as many requests as we get tags for
+            while next < requests && error.is_none() {
+                let tag = match queue.tags.alloc() {
+                    Some(tag) => tag,
+                    None => break,
+                };
+                let offset = next * chunk;
+                let bytes = match op {
+                    Op::Flush => 0,
+                    _ => chunk.min(len - offset),
+                };
+
+                let slot = &queue.slots[tag as usize];
+                let mut buffer = slot.buffer.lock();
+                if buffer.is_none() {
+                    match DmaBuffer::new(MAX_REQUEST_SIZE) {
+                        Ok(b) => *buffer = Some(b),
+                        Err(e) => {
+                            queue.tags.free(tag);
+                            error = Some(e);
+                            break;
+                        }
+                    }
+                }
+                let buffer = buffer.as_mut().unwrap();
+                if op == Op::Write {
+                    copy(offset, &mut buffer.as_mut_slice()[..bytes]);
+                }
+                slot.owner.store(core, Ordering::Relaxed);
+
+                let request = Request {
+                    op,
+                    lba: lba + (offset / block_size) as u64,
+                    blocks: (bytes / block_size) as u32,
+                    paddr: buffer.paddr(),
+                };
+                self.sw[core].lock().staged.push((tag, request));
+                ours[tag as usize] = Some((offset, bytes));
+                next += 1;
+                inflight += 1;
+            }
+            if error.is_some() && inflight == 0 {
+                break;
+            }
+
+            self.run_queue(core, hwq);
+            self.poll(hwq);
+            let completed = core::mem::take(&mut self.sw[core].lock().completed);
+            for (tag, result) in completed {
+                // Not ours if a transfer that timed out left it behind
+                if let Some((offset, bytes)) = ours[tag as usize].take() {
+                    match result {
+                        Ok(()) if op == Op::Read => {
+                            let mut buffer = queue.slots[tag as usize].buffer.lock();
+                            if let Some(buffer) = buffer.as_mut() {
+                                copy(offset, &mut buffer.as_mut_slice()[..bytes]);
+                            }
+                        }
+                        Ok(()) => {}
+                        Err(e) => error = error.or(Some(e)),
+                    }
+                    inflight -= 1;
+                    progress = rawtime::Instant::now();
+                }
+                queue.tags.free(tag);
+            }
+
+            if inflight > 0 && progress.elapsed() > COMPLETION_TIMEOUT {
+                // The tags stay allocated, the device might still write to
+                // their buffers
+                return Err(KError::DeviceTimeout);
+            }
+            spin_loop();
+        }
+
+        error.map_or(Ok(()), Err)
+    }
+}
+
+impl<D: MqDriver> BlockDevice for BlkMq<D> {
+    fn block_size(&self) -> usize {
+        self.driver.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.driver.num_blocks()
+    }
+
+    fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), KError> {
+        check_range(self, lba, buf.len())?;
+        self.transfer(Op::Read, lba, buf.len(), |offset, chunk| {
+            buf[offset..offset + chunk.len()].copy_from_slice(chunk)
+        })
+    }
+
+    fn write(&self, lba: u64, buf: &[u8]) -> Result<(), KError> {
+        check_range(self, lba, buf.len())?;
+        self.transfer(Op::Write, lba, buf.len(), |offset, chunk| {
+            chunk.copy_from_slice(&buf[offset..offset + chunk.len()])
+        })
+    }
+
+    fn flush(&self) -> Result<(), KError> {
+        self.transfer(Op::Flush, 0, 0, |_offset, _chunk| {})
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tags() {
+        let tags = Tags::new(3);
+        assert_eq!(tags.alloc(), Some(0));
+        assert_eq!(tags.alloc(), Some(1));
+        assert_eq!(tags.alloc(), Some(2));
+        assert_eq!(tags.alloc(), None);
+
+        tags.free(1);
+        assert_eq!(tags.alloc(), Some(1));
+        assert_eq!(tags.alloc(), None);
+    }
+
+    #[test]
+    fn full_depth() {
+        let tags = Tags::new(MAX_QUEUE_DEPTH);
+        for tag in 0..MAX_QUEUE_DEPTH {
+            assert_eq!(tags.alloc(), Some(tag as u16));
+        }
+        assert_eq!(tags.alloc(), None);
+        tags.free(63);
+        assert_eq!(tags.alloc(), Some(63));
+    }
+}
@@ -0,0 +1,339 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A text console on the linear framebuffer the bootloader set up.
+//!
+//! Characters are drawn with a fixed 8x8 font (scaled to 8x16 cells). We
+//! keep a copy of the text on the screen so scrolling only has to redraw
+//! the cells that change and never reads from the framebuffer (which is
+//! mapped write-combining and slow to read).
+
+use alloc::vec::Vec;
+use core::ptr;
+
+use font8x8::UnicodeFonts;
+use spin::Mutex;
+
+use crate::console::Console;
+use crate::error::KError;
+use crate::memory::PAddr;
+
+const CELL_WIDTH: usize = 8;
+const CELL_HEIGHT: usize = 16;
+
+/// Light gray.
+const FOREGROUND: u32 = 0xaaaaaa;
+const BACKGROUND: u32 = 0x000000;
+
+/// How pixels are laid out in the framebuffer (all formats use 32 bits per
+/// pixel).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PixelFormat {
+    /// Red in the lowest byte.
+    Rgb,
+    /// Blue in the lowest byte.
+    Bgr,
+    /// Channels are at the given bits.
+    Bitmask { red: u32, green: u32, blue: u32 },
+}
+
+impl PixelFormat {
+    /// Converts a 0xRRGGBB color to a pixel value.
+    fn encode(&self, rgb: u32) -> u32 {
+        let (r, g, b) = ((rgb >> 16) & 0xff, (rgb >> 8) & 0xff, rgb & 0xff);
+        let channel = |value: u32, mask: u32| {
+            if mask == 0 {
+                return 0;
+            }
+            let bits = mask.count_ones().min(8);
+            ((value >> (8 - bits)) << mask.trailing_zeros()) & mask
+        };
+
+        match *self {
+            PixelFormat::Rgb => b << 16 | g << 8 | r,
+            PixelFormat::Bgr => r << 16 | g << 8 | b,
+            PixelFormat::Bitmask { red, green, blue } => {
+                channel(r, red) | channel(g, green) | channel(b, blue)
+            }
+        }
+    }
+}
+
+/// A linear framebuffer as reported by the bootloader.
+#[derive(Debug, Copy, Clone)]
+pub struct FramebufferInfo {
+    pub paddr: PAddr,
+    /// Size in bytes.
+    pub size: usize,
+    pub width: usize,
+    pub height: usize,
+    /// Pixels per scan line.
+    pub stride: usize,
+    pub format: PixelFormat,
+}
+
+/// Draws text on a framebuffer.
+struct TextConsole {
+    /// Pixel (0, 0) of the framebuffer.
+    pixels: *mut u32,
+    stride: usize,
+    cols: usize,
+    rows: usize,
+    col: usize,
+    row: usize,
+    foreground: u32,
+    background: u32,
+    /// The character in every cell (row-major).
+    text: Vec<u8>,
+}
+
+// Safety: The framebuffer is only accessed through a `Mutex<TextConsole>`.
+unsafe impl Send for TextConsole {}
+
+impl TextConsole {
+    /// Creates a console on the framebuffer `info` that starts at `pixels`
+    /// and clears the screen.
+    fn new(info: &FramebufferInfo, pixels: *mut u32) -> Result<TextConsole, KError> {
+        let cols = info.width / CELL_WIDTH;
+        let rows = info.height / CELL_HEIGHT;
+        if cols == 0 || rows == 0 || info.stride < info.width {
+            return Err(KError::NotSupported);
+        }
+
+        let mut text = Vec::try_with_capacity(cols * rows)?;
+        text.resize(cols * rows, b' ');
+        let console = TextConsole {
+            pixels,
+            stride: info.stride,
+            cols,
+            rows,
+            col: 0,
+            row: 0,
+            foreground: info.format.encode(FOREGROUND),
+            background: info.format.encode(BACKGROUND),
+            text,
+        };
+
+        for y in 0..info.height {
+            for x in 0..info.width {
+                console.set_pixel(x, y, console.background);
+            }
+        }
+        Ok(console)
+    }
+
+    fn set_pixel(&self, x: usize, y: usize, value: u32) {
+        unsafe { ptr::write_volatile(self.pixels.add(y * self.stride + x), value) };
+    }
+
+    /// Draws `c` in cell (`col`, `row`).
+    fn draw(&self, col: usize, row: usize, c: u8) {
+        let glyph = font8x8::BASIC_FONTS
+            .get(c as char)
+            .unwrap_or([0; CELL_WIDTH]);
+
+        for y in 0..CELL_HEIGHT {
+            // Every line of the font is drawn twice
+            let line = glyph[y / 2];
+            for x in 0..CELL_WIDTH {
+                let value = if line & (1 << x) != 0 {
+                    self.foreground
+                } else {
+                    self.background
+                };
+                self.set_pixel(col * CELL_WIDTH + x, row * CELL_HEIGHT + y, value);
+            }
+        }
+    }
+
+    /// Puts `c` in cell (`col`, `row`), redraws the cell if it changed.
+    fn set(&mut self, col: usize, row: usize, c: u8) {
+        let cell = &mut self.text[row * self.cols + col];
+        if *cell != c {
+            *cell = c;
+            self.draw(col, row, c);
+        }
+    }
+
+    /// Moves all lines up by one and clears the last line.
+    fn scroll(&mut self) {
+        for row in 0..self.rows {
+            for col in 0..self.cols {
+                let c = if row + 1 < self.rows {
+                    self.text[(row + 1) * self.cols + col]
+                } else {
+                    b' '
+                };
+                self.set(col, row, c);
+            }
+        }
+    }
+
+    fn newline(&mut self) {
+        self.col = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.scroll();
+        }
+    }
+
+    fn putc(&mut self, c: char) {
+        match c {
+            '\n' => self.newline(),
+            '\r' => self.col = 0,
+            '\t' => {
+                let stop = ((self.col / 8 + 1) * 8).min(self.cols);
+                while self.col < stop {
+                    self.putc(' ');
+                }
+            }
+            c => {
+                if self.col == self.cols {
+                    self.newline();
+                }
+                let c = if c.is_ascii() && !c.is_ascii_control() {
+                    c as u8
+                } else {
+                    b'?'
+                };
+                self.set(self.col, self.row, c);
+                self.col += 1;
+            }
+        }
+    }
+
+    fn write_str(&mut self, s: &str) {
+        for c in s.chars() {
+            self.putc(c);
+        }
+    }
+}
+
+/// The console on the boot framebuffer (if there is one).
+pub struct FramebufferConsole {
+    inner: Mutex<Option<TextConsole>>,
+}
+
+pub static CONSOLE: FramebufferConsole = FramebufferConsole {
+    inner: Mutex::new(None),
+};
+
+impl Console for FramebufferConsole {
+    fn name(&self) -> &'static str {
+        "framebuffer"
+    }
+
+    fn write_str(&self, s: &str) {
+        if let Some(console) = self.inner.lock().as_mut() {
+            console.write_str(s);
+        }
+    }
+}
+
+/// Maps the framebuffer `info` (write-combining), clears it and starts
+/// logging to it.
+#[cfg(target_os = "none")]
+pub fn init(info: FramebufferInfo) -> Result<(), KError> {
+    use crate::arch::Platform;
+    use crate::arch_interface::Arch;
+    use crate::memory::vspace::MapAction;
+
+    let base = info.paddr.align_down_to_base_page();
+    let end = (info.paddr + info.size).align_up_to_base_page();
+    Platform::map_kernel_identity(
+        base,
+        (end - base).as_usize(),
+        MapAction::ReadWriteKernelWriteCombining,
+    )?;
+
+    let console = TextConsole::new(&info, info.paddr.as_u64() as *mut u32)?;
+    *CONSOLE.inner.lock() = Some(console);
+    crate::console::register(&CONSOLE)?;
+
+    log::info!(
+        "Framebuffer console {}x{} ({:?}) at {:#x}",
+        info.width,
+        info.height,
+        info.format,
+        info.paddr
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn framebuffer(width: usize, height: usize) -> (FramebufferInfo, Vec<u32>) {
+        let info = FramebufferInfo {
+            paddr: PAddr::from(0x8000_0000u64),
+            size: width * height * 4,
+            width,
+            height,
+            stride: width,
+            format: PixelFormat::Bgr,
+        };
+        (info, alloc::vec![0xdead_beef; width * height])
+    }
+
+    /// Returns the text in `row`.
+    fn line(console: &TextConsole, row: usize) -> &str {
+        let text = &console.text[row * console.cols..(row + 1) * console.cols];
+        core::str::from_utf8(text).unwrap().trim_end()
+    }
+
+    #[test]
+    fn pixel_formats() {
+        assert_eq!(PixelFormat::Bgr.encode(0x123456), 0x123456);
+        assert_eq!(PixelFormat::Rgb.encode(0x123456), 0x563412);
+        let rgb565 = PixelFormat::Bitmask {
+            red: 0xf800,
+            green: 0x07e0,
+            blue: 0x001f,
+        };
+        assert_eq!(rgb565.encode(0xffffff), 0xffff);
+        assert_eq!(rgb565.encode(0xff0000), 0xf800);
+    }
+
+    #[test]
+    fn draws_text() {
+        let (info, mut pixels) = framebuffer(32, 32);
+        let mut console = TextConsole::new(&info, pixels.as_mut_ptr()).unwrap();
+        assert!(pixels.iter().all(|p| *p == BACKGROUND));
+
+        console.write_str("A");
+        assert_eq!(line(&console, 0), "A");
+
+        let glyph = font8x8::BASIC_FONTS.get('A').unwrap();
+        for y in 0..CELL_HEIGHT {
+            for x in 0..32 {
+                let lit = x < CELL_WIDTH && glyph[y / 2] & (1 << x) != 0;
+                let expected = if lit { FOREGROUND } else { BACKGROUND };
+                assert_eq!(pixels[y * 32 + x], expected, "pixel ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn wraps_and_scrolls() {
+        // 4 columns, 2 rows
+        let (info, mut pixels) = framebuffer(32, 32);
+        let mut console = TextConsole::new(&info, pixels.as_mut_ptr()).unwrap();
+
+        console.write_str("abcdef");
+        assert_eq!(line(&console, 0), "abcd");
+        assert_eq!(line(&console, 1), "ef");
+
+        // The tab fills the rest of the line
+        console.write_str("\nxy\tz");
+        assert_eq!(line(&console, 0), "xy");
+        assert_eq!(line(&console, 1), "z");
+        assert_eq!((console.col, console.row), (1, 1));
+
+        // The bottom line was redrawn from scratch
+        console.write_str("\n");
+        assert_eq!(line(&console, 1), "");
+        assert!(pixels[CELL_HEIGHT * 32..].iter().all(|p| *p == BACKGROUND));
+    }
+}
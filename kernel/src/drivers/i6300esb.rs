@@ -0,0 +1,152 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Driver for the watchdog timer of the Intel 6300ESB I/O controller (the
+//! one QEMU emulates).
+//!
+//! The watchdog counts down in two stages, if nobody pets it before the
+//! second stage runs out it triggers the reset line (QEMU does whatever
+//! `-watchdog-action` says, `inject-nmi` makes every core dump its state).
+//! The core watchdog (`arch::watchdog`) pets it from the timer interrupt.
+
+use core::ptr;
+use core::time::Duration;
+
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::arch::Platform;
+use crate::arch_interface::Arch;
+use crate::drivers::pci::{self, Bar, PciDevice, PciDriver, PciMatch};
+use crate::error::KError;
+use crate::memory::vspace::MapAction;
+use crate::memory::PAddr;
+
+/// The PCI driver.
+pub static DRIVER: PciDriver = PciDriver {
+    name: "i6300esb",
+    ids: &[PciMatch::Device {
+        vendor: 0x8086,
+        device: 0x25ab,
+    }],
+    attach,
+};
+
+/// Configuration register (PCI config space, the device ignores anything
+/// but 16-bit writes).
+const REG_CONFIG: u16 = 0x60;
+/// No interrupt at the end of the first stage, 1 kHz clock, reset line on.
+const CONFIG_NO_INTERRUPT: u16 = 0x3;
+/// Lock register (PCI config space, 8-bit writes only).
+const REG_LOCK: u16 = 0x68;
+const LOCK_ENABLE: u8 = 1 << 1;
+
+/// Timeouts of the two stages (memory mapped).
+const TIMER1: u64 = 0x00;
+const TIMER2: u64 = 0x04;
+/// Reload register (memory mapped).
+const RELOAD: u64 = 0x0c;
+const RELOAD_TIMEOUT: u16 = 1 << 9;
+const RELOAD_PREVENT: u16 = 1 << 8;
+/// The timer and reload registers need this sequence before every write.
+const UNLOCK: [u16; 2] = [0x80, 0x86];
+
+/// Time until the watchdog fires if nobody pets it.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// The stage timers are 20 bits wide.
+const MAX_TIMEOUT: Duration = Duration::from_secs(2046);
+
+struct Esb {
+    base: u64,
+}
+
+impl Esb {
+    fn unlock(&self) {
+        for value in UNLOCK.iter() {
+            unsafe { ptr::write_volatile((self.base + RELOAD) as *mut u16, *value) };
+        }
+    }
+
+    fn write(&self, reg: u64, value: u32) {
+        self.unlock();
+        unsafe { ptr::write_volatile((self.base + reg) as *mut u32, value) };
+    }
+
+    fn reload(&self, value: u16) {
+        self.unlock();
+        unsafe { ptr::write_volatile((self.base + RELOAD) as *mut u16, value) };
+    }
+
+    fn timed_out(&self) -> bool {
+        unsafe { ptr::read_volatile((self.base + RELOAD) as *const u16) & RELOAD_TIMEOUT != 0 }
+    }
+
+    fn set_timeout(&self, timeout: Duration) {
+        // The timers tick at about 1 kHz, this makes each stage take
+        // about half of `timeout`
+        let value = (timeout.as_secs() as u32) << 9;
+        self.write(TIMER1, value);
+        self.write(TIMER2, value);
+        self.reload(RELOAD_PREVENT);
+    }
+}
+
+/// We only support one device.
+static ESB: Once<Mutex<Esb>> = Once::new();
+
+/// Restarts the countdown (does nothing if there is no watchdog).
+pub fn pet() {
+    // We're called from interrupt context, if someone else is using the
+    // device they can pet it
+    if let Some(esb) = ESB.get().and_then(|esb| esb.try_lock()) {
+        esb.reload(RELOAD_PREVENT);
+    }
+}
+
+/// Changes how long the watchdog waits before firing.
+pub fn set_timeout(timeout: Duration) -> Result<(), KError> {
+    if timeout < Duration::from_secs(1) || timeout > MAX_TIMEOUT {
+        return Err(KError::NotSupported);
+    }
+    ESB.get()
+        .ok_or(KError::NotSupported)?
+        .lock()
+        .set_timeout(timeout);
+    Ok(())
+}
+
+fn attach(dev: &PciDevice) -> Result<(), KError> {
+    if ESB.is_completed() {
+        return Err(KError::AlreadyPresent);
+    }
+
+    let base = match dev.bars[0] {
+        Some(Bar::Memory { base, size, .. }) => {
+            Platform::map_kernel_identity(
+                PAddr::from(base),
+                size as usize,
+                MapAction::ReadWriteKernel,
+            )?;
+            base
+        }
+        _ => return Err(KError::NotSupported),
+    };
+    pci::enable_bus_master(dev)?;
+
+    let esb = Esb { base };
+    if esb.timed_out() {
+        warn!("i6300esb: the watchdog reset the machine");
+    }
+
+    pci::write_config16(dev, REG_CONFIG, CONFIG_NO_INTERRUPT)?;
+    // Watchdog mode, disabled while we set it up
+    pci::write_config8(dev, REG_LOCK, 0)?;
+
+    esb.reload(RELOAD_TIMEOUT | RELOAD_PREVENT);
+    esb.set_timeout(DEFAULT_TIMEOUT);
+    pci::write_config8(dev, REG_LOCK, LOCK_ENABLE)?;
+
+    ESB.call_once(|| Mutex::new(esb));
+    info!("i6300esb watchdog armed ({:?})", DEFAULT_TIMEOUT);
+    Ok(())
+}
@@ -0,0 +1,68 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Keyboard events for user-space.
+//!
+//! Keyboard and console drivers push events (from interrupt context) and
+//! user-space reads them with `SystemOperation::ReadKeyEvents`.
+
+use crossbeam_queue::ArrayQueue;
+use kpi::system::KeyEvent;
+use lazy_static::lazy_static;
+use log::trace;
+
+/// Events we buffer until user-space reads them.
+const QUEUE_CAPACITY: usize = 256;
+
+lazy_static! {
+    static ref EVENTS: ArrayQueue<KeyEvent> = ArrayQueue::new(QUEUE_CAPACITY);
+}
+
+/// Allocates the queue (so we never allocate in interrupt context).
+pub fn init() {
+    lazy_static::initialize(&EVENTS);
+}
+
+/// Queues `event`, it is dropped if the queue is full.
+pub fn push(event: KeyEvent) {
+    if EVENTS.push(event).is_err() {
+        trace!("Input queue full, dropped {:?}", event);
+    }
+}
+
+/// Moves queued events into `events`, returns how many.
+pub fn pop(events: &mut [KeyEvent]) -> usize {
+    let mut read = 0;
+    for slot in events.iter_mut() {
+        match EVENTS.pop() {
+            Some(event) => *slot = event,
+            None => break,
+        }
+        read += 1;
+    }
+    read
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn push_pop() {
+        let event = |code| KeyEvent {
+            code,
+            pressed: true,
+            ascii: 0,
+        };
+        for code in 0..(QUEUE_CAPACITY + 1) as u16 {
+            push(event(code));
+        }
+
+        let mut events = [KeyEvent::default(); QUEUE_CAPACITY + 1];
+        assert_eq!(pop(&mut events[..2]), 2);
+        assert_eq!(events[1], event(1));
+        assert_eq!(pop(&mut events), QUEUE_CAPACITY - 2);
+        assert_eq!(events[0], event(2));
+        assert_eq!(pop(&mut events), 0);
+    }
+}
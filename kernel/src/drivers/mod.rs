@@ -0,0 +1,49 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Device drivers, device discovery and the interfaces drivers export
+//! (e.g., block devices).
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use log::debug;
+
+use crate::error::KError;
+
+#[cfg(target_os = "none")]
+pub mod ahci;
+pub mod block;
+pub mod framebuffer;
+#[cfg(target_os = "none")]
+pub mod i6300esb;
+pub mod input;
+#[cfg(target_os = "none")]
+pub mod nvme;
+pub mod pci;
+pub mod ps2;
+pub mod virtio;
+
+/// Registers our drivers with the bus they are on (needs to happen before
+/// the bus is enumerated so drivers attach right away).
+pub fn init() -> Result<(), KError> {
+    #[cfg(target_os = "none")]
+    {
+        pci::register_driver(&nvme::DRIVER)?;
+        pci::register_driver(&ahci::DRIVER)?;
+        pci::register_driver(&virtio::console::DRIVER)?;
+        pci::register_driver(&virtio::rng::DRIVER)?;
+        pci::register_driver(&virtio::vsock::DRIVER)?;
+        pci::register_driver(&i6300esb::DRIVER)?;
+    }
+
+    input::init();
+    // Most newer machines don't have (or emulate) a PS/2 controller
+    #[cfg(target_os = "none")]
+    if let Err(e) = ps2::init() {
+        debug!("No PS/2 keyboard: {}", e);
+    }
+
+    if let Err(e) = crate::procfs::register("/proc/block/devices", block::proc_block_devices) {
+        debug!("Unable to register /proc/block/devices: {}", e);
+    }
+    Ok(())
+}
@@ -0,0 +1,337 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Driver for NVMe SSDs.
+//!
+//! The controller gets an admin queue when it is attached. Its I/O queue
+//! pairs are the hardware queues of the multi-queue block layer
+//! (`block::mq`), which also allocates the command ids (they are the tags of
+//! the requests). A queue pair is created (with memory from the NUMA node of
+//! the core that uses it) the first time requests get queued on it. If the
+//! controller has fewer queues than we have cores, cores share queues.
+//!
+//! We only use the first namespace and poll for completions.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+use fallible_collections::vec::FallibleVec;
+use log::{debug, info};
+use spin::Mutex;
+
+use crate::arch::Platform;
+use crate::arch_interface::Arch;
+use crate::drivers::block;
+use crate::drivers::block::mq::{BlkMq, MqDriver, Op, Request};
+use crate::drivers::pci::{self, Bar, PciDevice, PciDriver, PciMatch};
+use crate::error::KError;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::vspace::MapAction;
+use crate::memory::{PAddr, BASE_PAGE_SIZE};
+
+mod queue;
+mod spec;
+
+use queue::QueuePair;
+use spec::*;
+
+/// The PCI driver (matches all NVM Express controllers).
+pub static DRIVER: PciDriver = PciDriver {
+    name: "nvme",
+    ids: &[PciMatch::Class {
+        class: 0x01,
+        subclass: 0x08,
+    }],
+    attach,
+};
+
+/// Entries of the admin queues.
+const ADMIN_QUEUE_ENTRIES: u16 = 32;
+
+/// Entries of the I/O queues (if the controller supports that many).
+const IO_QUEUE_ENTRIES: u16 = 64;
+
+/// The namespace we use.
+const NSID: u32 = 1;
+
+/// Used to name the controllers (nvme0, nvme1, ...).
+static CONTROLLERS: AtomicUsize = AtomicUsize::new(0);
+
+/// The controller registers (BAR0).
+struct Registers {
+    /// Virtual (identity mapped) address of BAR0.
+    bar: u64,
+}
+
+impl Registers {
+    fn read32(&self, reg: u64) -> u32 {
+        unsafe { ptr::read_volatile((self.bar + reg) as *const u32) }
+    }
+
+    fn write32(&self, reg: u64, value: u32) {
+        unsafe { ptr::write_volatile((self.bar + reg) as *mut u32, value) }
+    }
+
+    fn read64(&self, reg: u64) -> u64 {
+        unsafe { ptr::read_volatile((self.bar + reg) as *const u64) }
+    }
+
+    fn write64(&self, reg: u64, value: u64) {
+        unsafe { ptr::write_volatile((self.bar + reg) as *mut u64, value) }
+    }
+
+    /// Waits until CSTS.RDY is `ready`.
+    fn wait_ready(&self, ready: bool, timeout: Duration) -> Result<(), KError> {
+        let start = rawtime::Instant::now();
+        loop {
+            let csts = self.read32(REG_CSTS);
+            if csts & CSTS_FATAL != 0 {
+                return Err(KError::NvmeControllerFailed);
+            }
+            if (csts & CSTS_READY != 0) == ready {
+                return Ok(());
+            }
+            if start.elapsed() > timeout {
+                return Err(KError::DeviceTimeout);
+            }
+            spin_loop();
+        }
+    }
+}
+
+pub struct Controller {
+    regs: Registers,
+    doorbell_stride: u64,
+    /// Maximum entries of a queue the controller supports.
+    max_entries: u16,
+    admin: Mutex<QueuePair>,
+    /// I/O queues with ids 1..=io.len(), created on first use.
+    io: Vec<Mutex<Option<QueuePair>>>,
+    block_size: usize,
+    num_blocks: u64,
+}
+
+impl Controller {
+    /// Resets the controller at `bar` and sets up the admin queues.
+    fn new(bar: u64) -> Result<Controller, KError> {
+        let regs = Registers { bar };
+
+        let cap = regs.read64(REG_CAP);
+        let max_entries = ((cap & 0xffff) + 1).min(u16::MAX as u64) as u16;
+        let doorbell_stride = 4 << ((cap >> 32) & 0xf);
+        // CAP.TO is in 500 ms units
+        let timeout = Duration::from_millis(((cap >> 24) & 0xff).max(1) * 500);
+        let version = regs.read32(REG_VS);
+        debug!(
+            "NVMe version {}.{} cap {:#x}",
+            version >> 16,
+            (version >> 8) & 0xff,
+            cap
+        );
+
+        // Disable the controller before we change the admin queues
+        let cc = regs.read32(REG_CC);
+        if cc & CC_ENABLE != 0 {
+            regs.write32(REG_CC, cc & !CC_ENABLE);
+        }
+        regs.wait_ready(false, timeout)?;
+
+        let admin = QueuePair::new(
+            0,
+            ADMIN_QUEUE_ENTRIES.min(max_entries),
+            bar,
+            doorbell_stride,
+        )?;
+        let size = (admin.entries() - 1) as u32;
+        regs.write32(REG_AQA, size << 16 | size);
+        regs.write64(REG_ASQ, admin.sq_paddr().as_u64());
+        regs.write64(REG_ACQ, admin.cq_paddr().as_u64());
+
+        regs.write32(REG_CC, CC_ENABLE | CC_IOSQES | CC_IOCQES);
+        regs.wait_ready(true, timeout)?;
+
+        Ok(Controller {
+            regs,
+            doorbell_stride,
+            max_entries,
+            admin: Mutex::new(admin),
+            io: Vec::new(),
+            block_size: 0,
+            num_blocks: 0,
+        })
+    }
+
+    /// Reads the controller and namespace information and decides how many
+    /// I/O queues we use.
+    fn identify(&mut self) -> Result<(), KError> {
+        let buffer = DmaBuffer::new(BASE_PAGE_SIZE)?;
+        let identify = |cns: u32, nsid: u32| Command {
+            opcode: ADMIN_IDENTIFY,
+            nsid,
+            prp1: buffer.paddr().as_u64(),
+            cdw10: cns,
+            ..Default::default()
+        };
+        let field = |range: core::ops::Range<usize>| -> String {
+            String::from_utf8_lossy(&buffer.as_slice()[range])
+                .trim()
+                .into()
+        };
+        let read_u32 = |offset: usize| {
+            let b = &buffer.as_slice()[offset..offset + 4];
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+        };
+
+        self.admin
+            .lock()
+            .execute(identify(IDENTIFY_CONTROLLER, 0))?;
+        let serial: String = field(4..24);
+        let model: String = field(24..64);
+        let namespaces = read_u32(516);
+        if namespaces < NSID {
+            return Err(KError::NotSupported);
+        }
+
+        self.admin
+            .lock()
+            .execute(identify(IDENTIFY_NAMESPACE, NSID))?;
+        self.num_blocks = read_u32(0) as u64 | (read_u32(4) as u64) << 32;
+        let format = (buffer.as_slice()[26] & 0xf) as usize;
+        let lba_format = read_u32(128 + 4 * format);
+        self.block_size = 1 << ((lba_format >> 16) & 0xff);
+
+        // Ask for one queue pair per core, the controller tells us how many
+        // we actually get (both counts are 0's based)
+        let wanted = atopology::MACHINE_TOPOLOGY.num_threads().clamp(1, 0xffff) as u32 - 1;
+        let granted = self.admin.lock().execute(Command {
+            opcode: ADMIN_SET_FEATURES,
+            cdw10: FEATURE_NUMBER_OF_QUEUES,
+            cdw11: wanted << 16 | wanted,
+            ..Default::default()
+        })?;
+        let queues = (granted & 0xffff).min(granted >> 16).min(wanted) as usize + 1;
+        for _ in 0..queues {
+            self.io.try_push(Mutex::new(None))?;
+        }
+
+        info!(
+            "NVMe {} ({}): {} blocks of {} bytes, {} I/O queues",
+            model, serial, self.num_blocks, self.block_size, queues
+        );
+        Ok(())
+    }
+
+    /// Creates I/O queue pair `qid` (on the current core).
+    fn create_io_queue(&self, qid: u16) -> Result<QueuePair, KError> {
+        let qp = QueuePair::new(
+            qid,
+            self.io_queue_entries(),
+            self.regs.bar,
+            self.doorbell_stride,
+        )?;
+        let size = (qp.entries() - 1) as u32;
+
+        let mut admin = self.admin.lock();
+        admin.execute(Command {
+            opcode: ADMIN_CREATE_IO_CQ,
+            prp1: qp.cq_paddr().as_u64(),
+            cdw10: size << 16 | qid as u32,
+            cdw11: QUEUE_PHYS_CONTIGUOUS,
+            ..Default::default()
+        })?;
+        admin.execute(Command {
+            opcode: ADMIN_CREATE_IO_SQ,
+            prp1: qp.sq_paddr().as_u64(),
+            cdw10: size << 16 | qid as u32,
+            cdw11: (qid as u32) << 16 | QUEUE_PHYS_CONTIGUOUS,
+            ..Default::default()
+        })?;
+        debug!("Created NVMe I/O queue {}", qid);
+
+        Ok(qp)
+    }
+
+    /// Entries of an I/O queue pair.
+    fn io_queue_entries(&self) -> u16 {
+        let entries = IO_QUEUE_ENTRIES.min(self.max_entries) as usize;
+        entries.min(BASE_PAGE_SIZE / core::mem::size_of::<Command>()) as u16
+    }
+}
+
+impl MqDriver for Controller {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn hw_queues(&self) -> usize {
+        self.io.len()
+    }
+
+    fn queue_depth(&self) -> usize {
+        // A full submission queue would look empty
+        self.io_queue_entries() as usize - 1
+    }
+
+    fn queue_rqs(&self, hwq: usize, requests: &[(u16, Request)]) -> Result<(), KError> {
+        let mut queue = self.io[hwq].lock();
+        if queue.is_none() {
+            *queue = Some(self.create_io_queue(hwq as u16 + 1)?);
+        }
+        let qp = queue.as_mut().unwrap();
+
+        for &(tag, request) in requests {
+            let opcode = match request.op {
+                Op::Read => IO_READ,
+                Op::Write => IO_WRITE,
+                Op::Flush => IO_FLUSH,
+            };
+            let mut cmd = Command {
+                opcode,
+                cid: tag,
+                nsid: NSID,
+                ..Default::default()
+            };
+            if request.op != Op::Flush {
+                cmd.prp1 = request.paddr.as_u64();
+                cmd.cdw10 = request.lba as u32;
+                cmd.cdw11 = (request.lba >> 32) as u32;
+                cmd.cdw12 = request.blocks - 1;
+            }
+            qp.push(cmd);
+        }
+        qp.ring();
+        Ok(())
+    }
+
+    fn poll(&self, hwq: usize, complete: &mut dyn FnMut(u16, Result<(), KError>)) {
+        if let Some(qp) = self.io[hwq].lock().as_mut() {
+            qp.reap(|cid, result| complete(cid, result.map(|_| ())));
+        }
+    }
+}
+
+/// Brings up the controller `dev` and registers it as a block device.
+fn attach(dev: &PciDevice) -> Result<(), KError> {
+    let (base, size) = match dev.bars[0] {
+        Some(Bar::Memory { base, size, .. }) => (base, size),
+        _ => return Err(KError::NotSupported),
+    };
+    Platform::map_kernel_identity(PAddr::from(base), size as usize, MapAction::ReadWriteKernel)?;
+    pci::enable_bus_master(dev)?;
+
+    let mut ctrl = Controller::new(base)?;
+    ctrl.identify()?;
+
+    let name = format!("nvme{}", CONTROLLERS.fetch_add(1, Ordering::Relaxed));
+    block::register(name, Arc::try_new(BlkMq::new(ctrl)?)?)
+}
@@ -0,0 +1,162 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A submission/completion queue pair.
+
+use core::hint::spin_loop;
+use core::ptr;
+use core::time::Duration;
+
+use log::trace;
+
+use crate::error::KError;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::{PAddr, BASE_PAGE_SIZE};
+
+use super::spec::{Command, Completion, REG_DOORBELL_BASE};
+
+/// How long we wait for the controller to complete a command.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The submission and completion queue with the same queue id.
+///
+/// Commands are identified by their cid, whoever pushes a command picks it
+/// and makes sure there are never more than `entries - 1` outstanding (a
+/// full submission queue would look empty).
+pub struct QueuePair {
+    id: u16,
+    entries: u16,
+    sq: DmaBuffer,
+    cq: DmaBuffer,
+    sq_tail: u16,
+    cq_head: u16,
+    /// Phase tag we expect for new completions (flips every wrap-around).
+    phase: bool,
+    next_cid: u16,
+    /// Address of the submission queue tail doorbell.
+    sq_doorbell: u64,
+    /// Address of the completion queue head doorbell.
+    cq_doorbell: u64,
+}
+
+impl QueuePair {
+    /// Allocates queue pair `id` (with memory from the current NUMA node).
+    ///
+    /// The queues have at most `entries` entries (limited to what fits in a
+    /// base page).
+    pub fn new(id: u16, entries: u16, bar: u64, doorbell_stride: u64) -> Result<QueuePair, KError> {
+        let entries = core::cmp::min(
+            entries as usize,
+            BASE_PAGE_SIZE / core::mem::size_of::<Command>(),
+        ) as u16;
+        let doorbell = |n: u64| bar + REG_DOORBELL_BASE + n * doorbell_stride;
+
+        Ok(QueuePair {
+            id,
+            entries,
+            sq: DmaBuffer::new(entries as usize * core::mem::size_of::<Command>())?,
+            cq: DmaBuffer::new(entries as usize * core::mem::size_of::<Completion>())?,
+            sq_tail: 0,
+            cq_head: 0,
+            phase: true,
+            next_cid: 0,
+            sq_doorbell: doorbell(2 * id as u64),
+            cq_doorbell: doorbell(2 * id as u64 + 1),
+        })
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn entries(&self) -> u16 {
+        self.entries
+    }
+
+    pub fn sq_paddr(&self) -> PAddr {
+        self.sq.paddr()
+    }
+
+    pub fn cq_paddr(&self) -> PAddr {
+        self.cq.paddr()
+    }
+
+    /// Puts `cmd` in the submission queue, the controller learns about it
+    /// with the next `ring`.
+    pub fn push(&mut self, cmd: Command) {
+        trace!("nvme q{} opcode {:#x} cid {}", self.id, cmd.opcode, cmd.cid);
+        unsafe {
+            ptr::write_volatile(self.sq.as_ptr::<Command>().add(self.sq_tail as usize), cmd);
+        }
+        self.sq_tail = (self.sq_tail + 1) % self.entries;
+    }
+
+    /// Tells the controller about the commands we pushed.
+    pub fn ring(&self) {
+        unsafe { ptr::write_volatile(self.sq_doorbell as *mut u32, self.sq_tail as u32) };
+    }
+
+    /// Calls `complete` with the cid and the command specific result of
+    /// every new completion.
+    pub fn reap(&mut self, mut complete: impl FnMut(u16, Result<u32, KError>)) {
+        let mut reaped = false;
+        loop {
+            let entry = unsafe {
+                ptr::read_volatile(self.cq.as_ptr::<Completion>().add(self.cq_head as usize))
+            };
+            if entry.phase() != self.phase {
+                break;
+            }
+
+            self.cq_head += 1;
+            if self.cq_head == self.entries {
+                self.cq_head = 0;
+                self.phase = !self.phase;
+            }
+            reaped = true;
+
+            trace!(
+                "nvme q{} cid {} status {:#x}",
+                self.id,
+                entry.cid,
+                entry.status_code()
+            );
+            complete(
+                entry.cid,
+                match entry.status_code() {
+                    0 => Ok(entry.result),
+                    status => Err(KError::NvmeCommandFailed { status }),
+                },
+            );
+        }
+
+        if reaped {
+            unsafe { ptr::write_volatile(self.cq_doorbell as *mut u32, self.cq_head as u32) };
+        }
+    }
+
+    /// Submits `cmd` and waits for it to complete (for queues where nobody
+    /// else has commands outstanding, like the admin queue).
+    ///
+    /// Returns the command specific result of the completion.
+    pub fn execute(&mut self, mut cmd: Command) -> Result<u32, KError> {
+        cmd.cid = self.next_cid;
+        self.next_cid = self.next_cid.wrapping_add(1);
+        self.push(cmd);
+        self.ring();
+
+        let start = rawtime::Instant::now();
+        let mut result = None;
+        while result.is_none() {
+            self.reap(|cid, r| {
+                debug_assert_eq!(cid, cmd.cid, "Only one command is outstanding");
+                result = Some(r);
+            });
+            if result.is_none() && start.elapsed() > COMMAND_TIMEOUT {
+                return Err(KError::DeviceTimeout);
+            }
+            spin_loop();
+        }
+        result.unwrap()
+    }
+}
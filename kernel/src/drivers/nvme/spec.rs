@@ -0,0 +1,97 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Registers and data structures from the NVMe specification (rev. 1.4).
+
+/// Controller capabilities.
+pub const REG_CAP: u64 = 0x00;
+/// Version.
+pub const REG_VS: u64 = 0x08;
+/// Controller configuration.
+pub const REG_CC: u64 = 0x14;
+/// Controller status.
+pub const REG_CSTS: u64 = 0x1c;
+/// Admin queue attributes.
+pub const REG_AQA: u64 = 0x24;
+/// Admin submission queue base address.
+pub const REG_ASQ: u64 = 0x28;
+/// Admin completion queue base address.
+pub const REG_ACQ: u64 = 0x30;
+/// First doorbell register (the stride between them is in CAP).
+pub const REG_DOORBELL_BASE: u64 = 0x1000;
+
+pub const CC_ENABLE: u32 = 1 << 0;
+/// I/O submission queue entry size (2^6 = 64 bytes).
+pub const CC_IOSQES: u32 = 6 << 16;
+/// I/O completion queue entry size (2^4 = 16 bytes).
+pub const CC_IOCQES: u32 = 4 << 20;
+
+pub const CSTS_READY: u32 = 1 << 0;
+pub const CSTS_FATAL: u32 = 1 << 1;
+
+pub const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+pub const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+pub const ADMIN_IDENTIFY: u8 = 0x06;
+pub const ADMIN_SET_FEATURES: u8 = 0x09;
+
+pub const IO_FLUSH: u8 = 0x00;
+pub const IO_WRITE: u8 = 0x01;
+pub const IO_READ: u8 = 0x02;
+
+pub const IDENTIFY_NAMESPACE: u32 = 0x00;
+pub const IDENTIFY_CONTROLLER: u32 = 0x01;
+
+pub const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+/// Queue is physically contiguous (for create I/O queue commands).
+pub const QUEUE_PHYS_CONTIGUOUS: u32 = 1 << 0;
+
+/// A submission queue entry.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Command {
+    pub opcode: u8,
+    pub flags: u8,
+    /// Command identifier (set when the command is submitted).
+    pub cid: u16,
+    pub nsid: u32,
+    pub reserved: u64,
+    pub mptr: u64,
+    pub prp1: u64,
+    pub prp2: u64,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+}
+
+static_assertions::const_assert_eq!(core::mem::size_of::<Command>(), 64);
+
+/// A completion queue entry.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Completion {
+    /// Command specific result.
+    pub result: u32,
+    pub reserved: u32,
+    pub sq_head: u16,
+    pub sq_id: u16,
+    pub cid: u16,
+    /// Phase tag (bit 0) and status field.
+    pub status: u16,
+}
+
+static_assertions::const_assert_eq!(core::mem::size_of::<Completion>(), 16);
+
+impl Completion {
+    pub fn phase(&self) -> bool {
+        self.status & 0x1 == 0x1
+    }
+
+    /// Status code type and status code (0 on success).
+    pub fn status_code(&self) -> u16 {
+        (self.status >> 1) & 0x7ff
+    }
+}
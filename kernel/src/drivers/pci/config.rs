@@ -0,0 +1,148 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Access to the PCI configuration space.
+
+use core::ptr;
+
+use x86::io;
+
+use super::PciAddress;
+
+/// A way to read and write the configuration space of PCI functions.
+///
+/// Offsets are in bytes and must be 4-byte aligned (2-byte aligned for
+/// 16-bit accesses).
+pub trait ConfigSpace {
+    fn read(&self, addr: PciAddress, offset: u16) -> u32;
+    fn write(&self, addr: PciAddress, offset: u16, value: u32);
+
+    fn read16(&self, addr: PciAddress, offset: u16) -> u16 {
+        (self.read(addr, offset & !0x3) >> ((offset & 0x2) * 8)) as u16
+    }
+
+    fn read8(&self, addr: PciAddress, offset: u16) -> u8 {
+        (self.read(addr, offset & !0x3) >> ((offset & 0x3) * 8)) as u8
+    }
+
+    /// Writes 16 bits (some devices only decode a register if it is
+    /// accessed with its own width, so implementations should override
+    /// this read-modify-write).
+    fn write16(&self, addr: PciAddress, offset: u16, value: u16) {
+        let shift = (offset & 0x2) * 8;
+        let old = self.read(addr, offset & !0x3) & !(0xffff << shift);
+        self.write(addr, offset & !0x3, old | (value as u32) << shift);
+    }
+
+    /// Writes 8 bits (see `write16`).
+    fn write8(&self, addr: PciAddress, offset: u16, value: u8) {
+        let shift = (offset & 0x3) * 8;
+        let old = self.read(addr, offset & !0x3) & !(0xff << shift);
+        self.write(addr, offset & !0x3, old | (value as u32) << shift);
+    }
+}
+
+/// Configuration mechanism #1 (I/O ports 0xcf8/0xcfc).
+///
+/// Only reaches the first 256 bytes of a function's configuration space.
+pub struct LegacyConfigSpace;
+
+impl LegacyConfigSpace {
+    const CONF_ADDR: u16 = 0xcf8;
+    const CONF_DATA: u16 = 0xcfc;
+
+    fn address(addr: PciAddress, offset: u16) -> u32 {
+        assert!(
+            offset <= 0xfc,
+            "Legacy config access is limited to 256 bytes"
+        );
+        (1 << 31)
+            | (addr.bus as u32) << 16
+            | (addr.device as u32) << 11
+            | (addr.function as u32) << 8
+            | (offset as u32 & 0xfc)
+    }
+}
+
+impl ConfigSpace for LegacyConfigSpace {
+    fn read(&self, addr: PciAddress, offset: u16) -> u32 {
+        unsafe {
+            io::outl(Self::CONF_ADDR, Self::address(addr, offset));
+            io::inl(Self::CONF_DATA)
+        }
+    }
+
+    fn write(&self, addr: PciAddress, offset: u16, value: u32) {
+        unsafe {
+            io::outl(Self::CONF_ADDR, Self::address(addr, offset));
+            io::outl(Self::CONF_DATA, value);
+        }
+    }
+
+    fn write16(&self, addr: PciAddress, offset: u16, value: u16) {
+        unsafe {
+            io::outl(Self::CONF_ADDR, Self::address(addr, offset));
+            io::outw(Self::CONF_DATA + (offset & 0x2), value);
+        }
+    }
+
+    fn write8(&self, addr: PciAddress, offset: u16, value: u8) {
+        unsafe {
+            io::outl(Self::CONF_ADDR, Self::address(addr, offset));
+            io::outb(Self::CONF_DATA + (offset & 0x3), value);
+        }
+    }
+}
+
+/// Memory-mapped configuration space (PCIe ECAM).
+///
+/// Every function has a 4 KiB window at `base + (bus << 20 | device << 15 |
+/// function << 12)`, `base` must be mapped for buses `start_bus` to
+/// `end_bus`.
+pub struct Ecam {
+    pub base: u64,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl Ecam {
+    fn address(&self, addr: PciAddress, offset: u16) -> Option<*mut u32> {
+        if addr.bus < self.start_bus || addr.bus > self.end_bus || offset >= 0x1000 {
+            return None;
+        }
+        let bus = (addr.bus - self.start_bus) as u64;
+        Some(
+            (self.base
+                + (bus << 20 | (addr.device as u64) << 15 | (addr.function as u64) << 12)
+                + (offset & !0x3) as u64) as *mut u32,
+        )
+    }
+}
+
+impl ConfigSpace for Ecam {
+    fn read(&self, addr: PciAddress, offset: u16) -> u32 {
+        // A non-existing function reads as all ones
+        self.address(addr, offset)
+            .map_or(u32::MAX, |reg| unsafe { ptr::read_volatile(reg) })
+    }
+
+    fn write(&self, addr: PciAddress, offset: u16, value: u32) {
+        if let Some(reg) = self.address(addr, offset) {
+            unsafe { ptr::write_volatile(reg, value) }
+        }
+    }
+
+    fn write16(&self, addr: PciAddress, offset: u16, value: u16) {
+        if let Some(reg) = self.address(addr, offset) {
+            let reg = (reg as u64 + (offset & 0x2) as u64) as *mut u16;
+            unsafe { ptr::write_volatile(reg, value) }
+        }
+    }
+
+    fn write8(&self, addr: PciAddress, offset: u16, value: u8) {
+        if let Some(reg) = self.address(addr, offset) {
+            let reg = (reg as u64 + (offset & 0x3) as u64) as *mut u8;
+            unsafe { ptr::write_volatile(reg, value) }
+        }
+    }
+}
@@ -0,0 +1,587 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! PCI/PCIe device enumeration and driver binding.
+//!
+//! `init` walks the configuration space (ECAM if ACPI has an MCFG table,
+//! otherwise the legacy I/O ports), starting at bus 0 and following
+//! PCI-to-PCI bridges. For every function we record the BARs and the
+//! capability list (MSI, MSI-X, ...).
+//!
+//! Drivers either look up their device with `find` or register a
+//! `PciDriver`, which gets attached to every (present and future) device
+//! that matches one of its `PciMatch` entries.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use fallible_collections::vec::FallibleVec;
+use log::{debug, info, warn};
+use spin::Mutex;
+
+use crate::error::KError;
+
+mod config;
+mod msi;
+#[cfg(test)]
+mod test;
+
+pub use config::{ConfigSpace, Ecam, LegacyConfigSpace};
+pub use msi::{disable_msix, enable_msi, enable_msix, mask_msix_entry, set_msix_entry, MsiMessage};
+
+const REG_VENDOR_ID: u16 = 0x00;
+const REG_DEVICE_ID: u16 = 0x02;
+const REG_COMMAND: u16 = 0x04;
+const REG_STATUS: u16 = 0x06;
+const REG_CLASS: u16 = 0x08;
+const REG_HEADER_TYPE: u16 = 0x0e;
+const REG_BAR0: u16 = 0x10;
+const REG_SECONDARY_BUS: u16 = 0x19;
+const REG_CAPABILITIES: u16 = 0x34;
+const REG_INTERRUPT_LINE: u16 = 0x3c;
+const REG_INTERRUPT_PIN: u16 = 0x3d;
+
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const HEADER_TYPE_MULTIFUNCTION: u8 = 0x80;
+const HEADER_TYPE_DEVICE: u8 = 0x00;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+
+const CAP_ID_MSI: u8 = 0x05;
+const CAP_ID_MSIX: u8 = 0x11;
+
+/// Location of a function on the PCI bus.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(bus: u8, device: u8, function: u8) -> PciAddress {
+        PciAddress {
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A base address register.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Bar {
+    Memory {
+        base: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+/// An entry of a function's capability list.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Capability {
+    Msi {
+        /// Offset of the capability in configuration space.
+        offset: u8,
+        /// Number of vectors the function supports (1..=32).
+        vectors: u8,
+        is_64bit: bool,
+    },
+    MsiX {
+        offset: u8,
+        /// Number of table entries.
+        table_size: u16,
+        /// BAR index and offset of the vector table.
+        table: (u8, u32),
+        /// BAR index and offset of the pending bit array.
+        pba: (u8, u32),
+    },
+    Other {
+        id: u8,
+        offset: u8,
+    },
+}
+
+/// A PCI function we found during enumeration.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    /// The BARs (the upper half of a 64-bit BAR is `None`).
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Vec<Capability>,
+    /// Name of the driver that is attached to the device.
+    pub driver: Option<&'static str>,
+}
+
+impl PciDevice {
+    pub fn msi(&self) -> Option<Capability> {
+        self.capabilities
+            .iter()
+            .find(|c| matches!(c, Capability::Msi { .. }))
+            .copied()
+    }
+
+    pub fn msix(&self) -> Option<Capability> {
+        self.capabilities
+            .iter()
+            .find(|c| matches!(c, Capability::MsiX { .. }))
+            .copied()
+    }
+}
+
+/// Describes which devices a driver supports.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PciMatch {
+    Device { vendor: u16, device: u16 },
+    Class { class: u8, subclass: u8 },
+}
+
+impl PciMatch {
+    pub fn matches(&self, dev: &PciDevice) -> bool {
+        match *self {
+            PciMatch::Device { vendor, device } => {
+                dev.vendor_id == vendor && dev.device_id == device
+            }
+            PciMatch::Class { class, subclass } => dev.class == class && dev.subclass == subclass,
+        }
+    }
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub ids: &'static [PciMatch],
+    /// Brings up the device (the device stays unbound if this fails).
+    pub attach: fn(&PciDevice) -> Result<(), KError>,
+}
+
+/// All functions we found (in bus order).
+static DEVICES: Mutex<Vec<PciDevice>> = Mutex::new(Vec::new());
+
+static DRIVERS: Mutex<Vec<&'static PciDriver>> = Mutex::new(Vec::new());
+
+/// How we reach the configuration space (set by `init`, drivers need it
+/// later to configure interrupts).
+static CONFIG_SPACE: Mutex<Option<Box<dyn ConfigSpace + Send>>> = Mutex::new(None);
+
+/// Runs `f` with the configuration space `init` used.
+fn with_config_space<R>(f: impl FnOnce(&dyn ConfigSpace) -> R) -> Result<R, KError> {
+    CONFIG_SPACE
+        .lock()
+        .as_deref()
+        .map(|cs| f(cs))
+        .ok_or(KError::PciUnavailable)
+}
+
+/// Determines the type and size of the BAR at `index`.
+///
+/// Returns the BAR (if implemented) and how many BAR slots it occupies.
+fn probe_bar(cs: &dyn ConfigSpace, addr: PciAddress, index: usize) -> (Option<Bar>, usize) {
+    let reg = REG_BAR0 + 4 * index as u16;
+
+    // Size a BAR by writing all ones and looking at which bits stick
+    let size_mask = |reg: u16| {
+        let orig = cs.read(addr, reg);
+        cs.write(addr, reg, u32::MAX);
+        let mask = cs.read(addr, reg);
+        cs.write(addr, reg, orig);
+        (orig, mask)
+    };
+
+    let (orig, mask) = size_mask(reg);
+    if orig & 0x1 == 0x1 {
+        let mask = mask & !0x3;
+        let bar = if mask == 0 {
+            None
+        } else {
+            Some(Bar::Io {
+                port: (orig & !0x3) as u16,
+                size: (!mask as u16).wrapping_add(1),
+            })
+        };
+        return (bar, 1);
+    }
+
+    let prefetchable = orig & 0x8 == 0x8;
+    let (base, mask, slots) = if (orig >> 1) & 0x3 == 0x2 && index < 5 {
+        let (orig_high, mask_high) = size_mask(reg + 4);
+        (
+            (orig_high as u64) << 32 | (orig & !0xf) as u64,
+            (mask_high as u64) << 32 | (mask & !0xf) as u64,
+            2,
+        )
+    } else {
+        (
+            (orig & !0xf) as u64,
+            0xffff_ffff_0000_0000 | (mask & !0xf) as u64,
+            1,
+        )
+    };
+
+    let bar = if mask & 0xffff_ffff == 0 && slots == 1 || mask == 0 {
+        None
+    } else {
+        Some(Bar::Memory {
+            base,
+            size: (!mask).wrapping_add(1),
+            prefetchable,
+        })
+    };
+    (bar, slots)
+}
+
+fn probe_bars(cs: &dyn ConfigSpace, addr: PciAddress, count: usize) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+
+    // Don't let the device decode addresses while we size the BARs (we
+    // only write the command half so we don't clear status bits)
+    let command = cs.read(addr, REG_COMMAND) & 0xffff;
+    let decode = (COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE) as u32;
+    cs.write(addr, REG_COMMAND, command & !decode);
+
+    let mut index = 0;
+    while index < count {
+        let (bar, slots) = probe_bar(cs, addr, index);
+        bars[index] = bar;
+        index += slots;
+    }
+
+    cs.write(addr, REG_COMMAND, command);
+    bars
+}
+
+fn probe_capabilities(cs: &dyn ConfigSpace, addr: PciAddress) -> Result<Vec<Capability>, KError> {
+    let mut capabilities = Vec::new();
+    if cs.read16(addr, REG_STATUS) & STATUS_CAPABILITIES == 0 {
+        return Ok(capabilities);
+    }
+
+    let mut offset = cs.read8(addr, REG_CAPABILITIES) & !0x3;
+    // Bounded in case of a broken (cyclic) list
+    for _ in 0..48 {
+        if offset == 0 {
+            break;
+        }
+
+        let header = cs.read(addr, offset as u16);
+        let id = header as u8;
+        let control = (header >> 16) as u16;
+        let capability = match id {
+            CAP_ID_MSI => Capability::Msi {
+                offset,
+                vectors: 1 << ((control >> 1) & 0x7).min(5),
+                is_64bit: control & (1 << 7) != 0,
+            },
+            CAP_ID_MSIX => {
+                let table = cs.read(addr, offset as u16 + 4);
+                let pba = cs.read(addr, offset as u16 + 8);
+                Capability::MsiX {
+                    offset,
+                    table_size: (control & 0x7ff) + 1,
+                    table: ((table & 0x7) as u8, table & !0x7),
+                    pba: ((pba & 0x7) as u8, pba & !0x7),
+                }
+            }
+            _ => Capability::Other { id, offset },
+        };
+        capabilities.try_push(capability)?;
+
+        offset = (header >> 8) as u8 & !0x3;
+    }
+
+    Ok(capabilities)
+}
+
+/// Reads the information about the function at `addr` (if it exists).
+fn probe(cs: &dyn ConfigSpace, addr: PciAddress) -> Result<Option<PciDevice>, KError> {
+    let vendor_id = cs.read16(addr, REG_VENDOR_ID);
+    if vendor_id == 0xffff {
+        return Ok(None);
+    }
+
+    let class = cs.read(addr, REG_CLASS);
+    let header_type = cs.read8(addr, REG_HEADER_TYPE) & HEADER_TYPE_MASK;
+    let bar_count = match header_type {
+        HEADER_TYPE_DEVICE => 6,
+        HEADER_TYPE_BRIDGE => 2,
+        _ => 0,
+    };
+
+    Ok(Some(PciDevice {
+        address: addr,
+        vendor_id,
+        device_id: cs.read16(addr, REG_DEVICE_ID),
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+        revision: class as u8,
+        interrupt_line: cs.read8(addr, REG_INTERRUPT_LINE),
+        interrupt_pin: cs.read8(addr, REG_INTERRUPT_PIN),
+        bars: probe_bars(cs, addr, bar_count),
+        capabilities: probe_capabilities(cs, addr)?,
+        driver: None,
+    }))
+}
+
+fn scan_bus(cs: &dyn ConfigSpace, bus: u8, devices: &mut Vec<PciDevice>) -> Result<(), KError> {
+    for device in 0..32 {
+        let addr = PciAddress::new(bus, device, 0);
+        if cs.read16(addr, REG_VENDOR_ID) == 0xffff {
+            continue;
+        }
+        let functions = if cs.read8(addr, REG_HEADER_TYPE) & HEADER_TYPE_MULTIFUNCTION != 0 {
+            8
+        } else {
+            1
+        };
+
+        for function in 0..functions {
+            let addr = PciAddress::new(bus, device, function);
+            if let Some(dev) = probe(cs, addr)? {
+                let is_bridge =
+                    cs.read8(addr, REG_HEADER_TYPE) & HEADER_TYPE_MASK == HEADER_TYPE_BRIDGE;
+                devices.try_push(dev)?;
+
+                if is_bridge {
+                    let secondary = cs.read8(addr, REG_SECONDARY_BUS);
+                    // A bus number that doesn't increase means the bridge
+                    // isn't configured (and we might loop forever)
+                    if secondary > bus {
+                        scan_bus(cs, secondary, devices)?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds all functions reachable from bus 0.
+pub fn enumerate(cs: &dyn ConfigSpace) -> Result<Vec<PciDevice>, KError> {
+    let mut devices = Vec::new();
+    scan_bus(cs, 0, &mut devices)?;
+    Ok(devices)
+}
+
+/// Attaches `driver` to all matching devices that don't have a driver yet.
+fn bind(driver: &'static PciDriver) {
+    let candidates: Vec<PciDevice> = DEVICES
+        .lock()
+        .iter()
+        .filter(|dev| dev.driver.is_none() && driver.ids.iter().any(|id| id.matches(dev)))
+        .cloned()
+        .collect();
+
+    // Attach without holding the lock so drivers can call `find`
+    for dev in candidates {
+        match (driver.attach)(&dev) {
+            Ok(()) => {
+                info!("PCI: {} attached to {}", driver.name, dev.address);
+                if let Some(d) = DEVICES.lock().iter_mut().find(|d| d.address == dev.address) {
+                    d.driver = Some(driver.name);
+                }
+            }
+            Err(e) => warn!(
+                "PCI: {} failed to attach to {}: {}",
+                driver.name, dev.address, e
+            ),
+        }
+    }
+}
+
+/// Makes `driver` available, it gets attached to matching devices right
+/// away (if `init` ran already) or once the bus is enumerated.
+pub fn register_driver(driver: &'static PciDriver) -> Result<(), KError> {
+    DRIVERS.lock().try_push(driver)?;
+    bind(driver);
+    Ok(())
+}
+
+/// Returns the first function that matches `id`.
+pub fn find(id: PciMatch) -> Option<PciDevice> {
+    DEVICES.lock().iter().find(|dev| id.matches(dev)).cloned()
+}
+
+/// Lets `dev` decode its memory BARs and access memory (DMA).
+pub fn enable_bus_master(dev: &PciDevice) -> Result<(), KError> {
+    with_config_space(|cs| {
+        // Only write the command half, the status bits are write-1-to-clear
+        let command = cs.read(dev.address, REG_COMMAND) & 0xffff;
+        let enable = (COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER) as u32;
+        cs.write(dev.address, REG_COMMAND, command | enable);
+    })
+}
+
+/// Lets `dev` decode its I/O BARs.
+pub fn enable_io_space(dev: &PciDevice) -> Result<(), KError> {
+    with_config_space(|cs| {
+        let command = cs.read(dev.address, REG_COMMAND) & 0xffff;
+        cs.write(dev.address, REG_COMMAND, command | COMMAND_IO_SPACE as u32);
+    })
+}
+
+/// Reads the 32-bit (device specific) register at `offset` in the
+/// configuration space of `dev`.
+pub fn read_config32(dev: &PciDevice, offset: u16) -> Result<u32, KError> {
+    with_config_space(|cs| cs.read(dev.address, offset))
+}
+
+/// Writes the 16-bit (device specific) register at `offset` in the
+/// configuration space of `dev`.
+pub fn write_config16(dev: &PciDevice, offset: u16, value: u16) -> Result<(), KError> {
+    with_config_space(|cs| cs.write16(dev.address, offset, value))
+}
+
+/// Writes the 8-bit (device specific) register at `offset` in the
+/// configuration space of `dev`.
+pub fn write_config8(dev: &PciDevice, offset: u16, value: u8) -> Result<(), KError> {
+    with_config_space(|cs| cs.write8(dev.address, offset, value))
+}
+
+/// Number of dwords in the (legacy) configuration space of a function.
+const CONFIG_DWORDS: usize = 64;
+
+/// The configuration space of a function as `save_config` read it.
+pub type SavedConfig = (PciAddress, [u32; CONFIG_DWORDS]);
+
+/// Reads the configuration space (header and the capabilities in the
+/// first 256 bytes) of every function, a suspend clears it.
+pub fn save_config() -> Result<Vec<SavedConfig>, KError> {
+    let devices = DEVICES.lock();
+    let mut saved = Vec::try_with_capacity(devices.len())?;
+    with_config_space(|cs| {
+        for dev in devices.iter() {
+            let mut config = [0u32; CONFIG_DWORDS];
+            for (i, dword) in config.iter_mut().enumerate() {
+                *dword = cs.read(dev.address, (i * 4) as u16);
+            }
+            saved.push((dev.address, config));
+        }
+    })?;
+    Ok(saved)
+}
+
+/// Writes back what `save_config` read.
+///
+/// We go from the top down so the command register (which turns the BARs
+/// and DMA back on) comes last, writing the read-only registers has no
+/// effect.
+pub fn restore_config(saved: &[SavedConfig]) -> Result<(), KError> {
+    with_config_space(|cs| {
+        for (address, config) in saved.iter() {
+            for i in (1..CONFIG_DWORDS).rev() {
+                let offset = (i * 4) as u16;
+                if cs.read(*address, offset) != config[i] {
+                    cs.write(*address, offset, config[i]);
+                }
+            }
+        }
+    })
+}
+
+/// Stops every function from doing DMA (before we hand the machine to
+/// another kernel, it would write into memory that isn't ours anymore).
+pub fn disable_bus_masters() -> Result<(), KError> {
+    let devices = DEVICES.lock();
+    with_config_space(|cs| {
+        for dev in devices.iter() {
+            let command = cs.read(dev.address, REG_COMMAND) & 0xffff;
+            let disable = !(COMMAND_BUS_MASTER as u32);
+            cs.write(dev.address, REG_COMMAND, command & disable);
+        }
+    })
+}
+
+/// Returns all functions we found.
+pub fn devices() -> Vec<PciDevice> {
+    DEVICES.lock().clone()
+}
+
+/// Enumerates the bus (using `cs`) and binds registered drivers.
+pub fn init(cs: Box<dyn ConfigSpace + Send>) -> Result<(), KError> {
+    let devices = enumerate(&*cs)?;
+    *CONFIG_SPACE.lock() = Some(cs);
+    for dev in devices.iter() {
+        debug!(
+            "PCI: {} [{:04x}:{:04x}] class {:02x}.{:02x}",
+            dev.address, dev.vendor_id, dev.device_id, dev.class, dev.subclass
+        );
+    }
+    info!("PCI: found {} functions", devices.len());
+    *DEVICES.lock() = devices;
+
+    let drivers: Vec<&'static PciDriver> = DRIVERS.lock().clone();
+    for driver in drivers {
+        bind(driver);
+    }
+
+    if let Err(e) = crate::procfs::register("/proc/bus/pci/devices", proc_pci_devices) {
+        debug!("Unable to register /proc/bus/pci/devices: {}", e);
+    }
+    Ok(())
+}
+
+/// Generates `/proc/bus/pci/devices`.
+fn proc_pci_devices(out: &mut String) -> fmt::Result {
+    for dev in DEVICES.lock().iter() {
+        writeln!(
+            out,
+            "{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x} irq {} driver {}",
+            dev.address,
+            dev.vendor_id,
+            dev.device_id,
+            dev.class,
+            dev.subclass,
+            dev.prog_if,
+            dev.interrupt_line,
+            dev.driver.unwrap_or("-")
+        )?;
+        for (i, bar) in dev.bars.iter().enumerate() {
+            match bar {
+                Some(Bar::Memory {
+                    base,
+                    size,
+                    prefetchable,
+                }) => writeln!(
+                    out,
+                    "  bar{} mem {:#x} size {:#x}{}",
+                    i,
+                    base,
+                    size,
+                    if *prefetchable { " prefetchable" } else { "" }
+                )?,
+                Some(Bar::Io { port, size }) => {
+                    writeln!(out, "  bar{} io {:#x} size {:#x}", i, port, size)?
+                }
+                None => {}
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,174 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Configuration of message signaled interrupts (MSI and MSI-X).
+//!
+//! This only programs the device, which vector (and core) a message ends
+//! up at is decided by the interrupt code of the architecture.
+
+use core::ptr;
+
+use crate::error::KError;
+
+use super::{
+    with_config_space, Bar, Capability, ConfigSpace, PciDevice, COMMAND_INTX_DISABLE, REG_COMMAND,
+};
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+/// Multiple message enable (we always use a single vector).
+const MSI_CONTROL_MME: u16 = 0x7 << 4;
+
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_ENTRY_ADDR_LOW: u64 = 0x0;
+const MSIX_ENTRY_ADDR_HIGH: u64 = 0x4;
+const MSIX_ENTRY_DATA: u64 = 0x8;
+const MSIX_ENTRY_CONTROL: u64 = 0xc;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+/// What a device writes (and where) to raise an interrupt.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+/// Updates the message control word of the capability at `offset`.
+fn update_control(cs: &dyn ConfigSpace, dev: &PciDevice, offset: u8, f: impl Fn(u16) -> u16) {
+    let header = cs.read(dev.address, offset as u16);
+    let control = f((header >> 16) as u16);
+    cs.write(
+        dev.address,
+        offset as u16,
+        (header & 0xffff) | (control as u32) << 16,
+    );
+}
+
+/// Stops the function from asserting its legacy interrupt pin.
+fn disable_intx(cs: &dyn ConfigSpace, dev: &PciDevice) {
+    // Only write the command half, the status bits are write-1-to-clear
+    let command = cs.read(dev.address, REG_COMMAND) & 0xffff;
+    cs.write(
+        dev.address,
+        REG_COMMAND,
+        command | COMMAND_INTX_DISABLE as u32,
+    );
+}
+
+pub(super) fn write_msi(
+    cs: &dyn ConfigSpace,
+    dev: &PciDevice,
+    msg: MsiMessage,
+) -> Result<(), KError> {
+    let (offset, is_64bit) = match dev.msi() {
+        Some(Capability::Msi {
+            offset, is_64bit, ..
+        }) => (offset as u16, is_64bit),
+        _ => return Err(KError::MsiUnsupported),
+    };
+
+    cs.write(dev.address, offset + 4, msg.address as u32);
+    if is_64bit {
+        cs.write(dev.address, offset + 8, (msg.address >> 32) as u32);
+        cs.write(dev.address, offset + 12, msg.data);
+    } else if msg.address >> 32 == 0 {
+        cs.write(dev.address, offset + 8, msg.data);
+    } else {
+        return Err(KError::MsiUnsupported);
+    }
+
+    update_control(cs, dev, offset as u8, |control| {
+        (control & !MSI_CONTROL_MME) | MSI_CONTROL_ENABLE
+    });
+    disable_intx(cs, dev);
+    Ok(())
+}
+
+pub(super) fn write_msix_enable(
+    cs: &dyn ConfigSpace,
+    dev: &PciDevice,
+    enable: bool,
+) -> Result<(), KError> {
+    let offset = match dev.msix() {
+        Some(Capability::MsiX { offset, .. }) => offset,
+        _ => return Err(KError::MsiUnsupported),
+    };
+
+    update_control(cs, dev, offset, |control| {
+        if enable {
+            (control | MSIX_CONTROL_ENABLE) & !MSIX_CONTROL_FUNCTION_MASK
+        } else {
+            control & !MSIX_CONTROL_ENABLE
+        }
+    });
+    if enable {
+        disable_intx(cs, dev);
+    }
+    Ok(())
+}
+
+/// Returns the address of `entry` in the MSI-X table of `dev`.
+///
+/// The BAR that holds the table has to be mapped (identity) already.
+fn msix_entry(dev: &PciDevice, entry: u16) -> Result<u64, KError> {
+    let (table_size, (bar, offset)) = match dev.msix() {
+        Some(Capability::MsiX {
+            table_size, table, ..
+        }) => (table_size, table),
+        _ => return Err(KError::MsiUnsupported),
+    };
+    if entry >= table_size {
+        return Err(KError::InvalidMsiEntry);
+    }
+
+    match dev.bars.get(bar as usize) {
+        Some(Some(Bar::Memory { base, size, .. }))
+            if offset as u64 + table_size as u64 * MSIX_ENTRY_SIZE <= *size =>
+        {
+            Ok(base + offset as u64 + entry as u64 * MSIX_ENTRY_SIZE)
+        }
+        _ => Err(KError::InvalidMsiEntry),
+    }
+}
+
+/// Configures the MSI capability of `dev` to send `msg` and enables it
+/// (with a single vector).
+pub fn enable_msi(dev: &PciDevice, msg: MsiMessage) -> Result<(), KError> {
+    with_config_space(|cs| write_msi(cs, dev, msg))?
+}
+
+/// Turns on MSI-X for `dev` (entries are still masked until they're set
+/// with `set_msix_entry`).
+pub fn enable_msix(dev: &PciDevice) -> Result<(), KError> {
+    with_config_space(|cs| write_msix_enable(cs, dev, true))?
+}
+
+pub fn disable_msix(dev: &PciDevice) -> Result<(), KError> {
+    with_config_space(|cs| write_msix_enable(cs, dev, false))?
+}
+
+/// Makes MSI-X table `entry` of `dev` send `msg` and unmasks it.
+pub fn set_msix_entry(dev: &PciDevice, entry: u16, msg: MsiMessage) -> Result<(), KError> {
+    let base = msix_entry(dev, entry)?;
+    unsafe {
+        ptr::write_volatile((base + MSIX_ENTRY_CONTROL) as *mut u32, MSIX_ENTRY_MASKED);
+        ptr::write_volatile((base + MSIX_ENTRY_ADDR_LOW) as *mut u32, msg.address as u32);
+        ptr::write_volatile(
+            (base + MSIX_ENTRY_ADDR_HIGH) as *mut u32,
+            (msg.address >> 32) as u32,
+        );
+        ptr::write_volatile((base + MSIX_ENTRY_DATA) as *mut u32, msg.data);
+        ptr::write_volatile((base + MSIX_ENTRY_CONTROL) as *mut u32, 0);
+    }
+    Ok(())
+}
+
+/// Masks (or unmasks) MSI-X table `entry` of `dev`.
+pub fn mask_msix_entry(dev: &PciDevice, entry: u16, masked: bool) -> Result<(), KError> {
+    let base = msix_entry(dev, entry)?;
+    let control = if masked { MSIX_ENTRY_MASKED } else { 0 };
+    unsafe { ptr::write_volatile((base + MSIX_ENTRY_CONTROL) as *mut u32, control) };
+    Ok(())
+}
@@ -0,0 +1,287 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Tests PCI enumeration against a made-up configuration space.
+
+use alloc::collections::BTreeMap;
+use core::cell::RefCell;
+
+use super::*;
+
+/// Configuration space of one function.
+#[derive(Clone)]
+struct Function {
+    regs: [u32; 64],
+    /// Which bits of a BAR are writable (0 for unimplemented BARs).
+    bar_masks: [u32; 6],
+}
+
+impl Function {
+    fn new(vendor: u16, device: u16, class: u32, header_type: u8) -> Function {
+        let mut f = Function {
+            regs: [0; 64],
+            bar_masks: [0; 6],
+        };
+        f.regs[0] = (device as u32) << 16 | vendor as u32;
+        f.regs[2] = class << 8 | 0x01;
+        f.regs[3] = (header_type as u32) << 16;
+        f
+    }
+
+    fn bar(mut self, index: usize, value: u32, mask: u32) -> Function {
+        self.regs[4 + index] = value;
+        self.bar_masks[index] = mask;
+        self
+    }
+
+    /// Adds a capability at `offset` and links it into the list.
+    fn capability(mut self, offset: u8, header: u32, data: &[u32]) -> Function {
+        // Status: capability list present
+        self.regs[1] |= (STATUS_CAPABILITIES as u32) << 16;
+        let head = REG_CAPABILITIES as usize / 4;
+        if self.regs[head] as u8 == 0 {
+            self.regs[head] = offset as u32;
+        } else {
+            let mut at = self.regs[head] as u8 as usize;
+            loop {
+                let next = (self.regs[at / 4] >> 8) as u8 as usize;
+                if next == 0 {
+                    self.regs[at / 4] |= (offset as u32) << 8;
+                    break;
+                }
+                at = next;
+            }
+        }
+        self.regs[offset as usize / 4] = header;
+        for (i, d) in data.iter().enumerate() {
+            self.regs[offset as usize / 4 + 1 + i] = *d;
+        }
+        self
+    }
+}
+
+#[derive(Default)]
+struct FakeConfigSpace {
+    functions: RefCell<BTreeMap<PciAddress, Function>>,
+}
+
+impl FakeConfigSpace {
+    fn add(&self, addr: PciAddress, f: Function) {
+        self.functions.borrow_mut().insert(addr, f);
+    }
+}
+
+impl ConfigSpace for FakeConfigSpace {
+    fn read(&self, addr: PciAddress, offset: u16) -> u32 {
+        self.functions
+            .borrow()
+            .get(&addr)
+            .map_or(u32::MAX, |f| f.regs[offset as usize / 4])
+    }
+
+    fn write(&self, addr: PciAddress, offset: u16, value: u32) {
+        if let Some(f) = self.functions.borrow_mut().get_mut(&addr) {
+            let reg = offset as usize / 4;
+            if reg == REG_COMMAND as usize / 4 {
+                // The status half is write-1-to-clear, leave it alone
+                f.regs[reg] = (f.regs[reg] & 0xffff_0000) | (value & 0xffff);
+            } else if (4..10).contains(&reg) {
+                let mask = f.bar_masks[reg - 4];
+                f.regs[reg] = (f.regs[reg] & !mask) | (value & mask);
+            } else {
+                f.regs[reg] = value;
+            }
+        }
+    }
+}
+
+/// A host bridge, a multi-function device, and a device behind a
+/// PCI-to-PCI bridge.
+fn machine() -> FakeConfigSpace {
+    let cs = FakeConfigSpace::default();
+    cs.add(
+        PciAddress::new(0, 0, 0),
+        Function::new(0x8086, 0x1237, 0x060000, HEADER_TYPE_DEVICE),
+    );
+    cs.add(
+        PciAddress::new(0, 1, 0),
+        Function::new(0x8086, 0x7000, 0x060100, HEADER_TYPE_MULTIFUNCTION),
+    );
+    cs.add(
+        PciAddress::new(0, 1, 1),
+        Function::new(0x8086, 0x7010, 0x010180, HEADER_TYPE_DEVICE).bar(4, 0xc001, 0xfff0),
+    );
+
+    let mut bridge = Function::new(0x1b36, 0x0001, 0x060400, HEADER_TYPE_BRIDGE);
+    bridge.regs[REG_SECONDARY_BUS as usize / 4] = 1 << 8;
+    cs.add(PciAddress::new(0, 2, 0), bridge);
+
+    cs.add(
+        PciAddress::new(1, 0, 0),
+        Function::new(0x15ad, 0x07b0, 0x020000, HEADER_TYPE_DEVICE)
+            .bar(0, 0xfebf_0000, 0xffff_f000)
+            .bar(2, 0xc000_000c, 0xffff_c000)
+            .bar(3, 0x0000_0001, 0xffff_ffff)
+            .capability(0x40, 0x0003_0000 | CAP_ID_MSIX as u32, &[0x2000, 0x3000])
+            .capability(0x50, 0x0080_0000 | CAP_ID_MSI as u32, &[]),
+    );
+    cs
+}
+
+#[test]
+fn enumerate_finds_all_functions() {
+    let devices = enumerate(&machine()).expect("enumerate failed");
+    let addresses: Vec<PciAddress> = devices.iter().map(|d| d.address).collect();
+    assert_eq!(
+        addresses,
+        vec![
+            PciAddress::new(0, 0, 0),
+            PciAddress::new(0, 1, 0),
+            PciAddress::new(0, 1, 1),
+            PciAddress::new(0, 2, 0),
+            PciAddress::new(1, 0, 0),
+        ]
+    );
+
+    let ide = &devices[2];
+    assert_eq!((ide.class, ide.subclass, ide.prog_if), (0x01, 0x01, 0x80));
+    assert_eq!(ide.revision, 0x01);
+}
+
+#[test]
+fn bars_are_sized() {
+    let cs = machine();
+    let devices = enumerate(&cs).expect("enumerate failed");
+
+    assert_eq!(
+        devices[2].bars[4],
+        Some(Bar::Io {
+            port: 0xc000,
+            size: 16
+        })
+    );
+
+    let nic = &devices[4];
+    assert_eq!(
+        nic.bars[0],
+        Some(Bar::Memory {
+            base: 0xfebf_0000,
+            size: 0x1000,
+            prefetchable: false
+        })
+    );
+    assert_eq!(nic.bars[1], None);
+    assert_eq!(
+        nic.bars[2],
+        Some(Bar::Memory {
+            base: 0x1_c000_0000,
+            size: 0x4000,
+            prefetchable: true
+        })
+    );
+    // Upper half of the 64-bit BAR
+    assert_eq!(nic.bars[3], None);
+
+    // Sizing restores the original values
+    let addr = PciAddress::new(1, 0, 0);
+    assert_eq!(cs.read(addr, REG_BAR0), 0xfebf_0000);
+    assert_eq!(cs.read(addr, REG_BAR0 + 12), 0x1);
+}
+
+#[test]
+fn capabilities_are_parsed() {
+    let devices = enumerate(&machine()).expect("enumerate failed");
+    let nic = &devices[4];
+
+    assert_eq!(
+        nic.msix(),
+        Some(Capability::MsiX {
+            offset: 0x40,
+            table_size: 4,
+            table: (0, 0x2000),
+            pba: (0, 0x3000),
+        })
+    );
+    assert_eq!(
+        nic.msi(),
+        Some(Capability::Msi {
+            offset: 0x50,
+            vectors: 1,
+            is_64bit: true,
+        })
+    );
+    assert!(devices[0].capabilities.is_empty());
+}
+
+#[test]
+fn match_devices() {
+    let devices = enumerate(&machine()).expect("enumerate failed");
+    let vmxnet3 = PciMatch::Device {
+        vendor: 0x15ad,
+        device: 0x07b0,
+    };
+    let ethernet = PciMatch::Class {
+        class: 0x02,
+        subclass: 0x00,
+    };
+
+    assert!(vmxnet3.matches(&devices[4]));
+    assert!(ethernet.matches(&devices[4]));
+    assert!(!vmxnet3.matches(&devices[0]));
+    assert!(!ethernet.matches(&devices[3]));
+}
+
+#[test]
+fn msi_is_programmed() {
+    let cs = machine();
+    let devices = enumerate(&cs).expect("enumerate failed");
+    let nic = &devices[4];
+    let msg = MsiMessage {
+        address: 0xfee0_1000,
+        data: 0x30,
+    };
+
+    msi::write_msi(&cs, nic, msg).expect("NIC has MSI");
+    assert_eq!(cs.read(nic.address, 0x54), 0xfee0_1000);
+    assert_eq!(cs.read(nic.address, 0x58), 0x0);
+    assert_eq!(cs.read(nic.address, 0x5c), 0x30);
+    // Enabled with a single message
+    assert_eq!(cs.read16(nic.address, 0x52) & 0x71, 0x1);
+    assert_ne!(
+        cs.read16(nic.address, REG_COMMAND) & COMMAND_INTX_DISABLE,
+        0
+    );
+
+    assert_eq!(
+        msi::write_msi(&cs, &devices[0], msg),
+        Err(KError::MsiUnsupported)
+    );
+}
+
+#[test]
+fn msix_enable() {
+    let cs = machine();
+    let devices = enumerate(&cs).expect("enumerate failed");
+    let nic = &devices[4];
+
+    msi::write_msix_enable(&cs, nic, true).expect("NIC has MSI-X");
+    assert_eq!(cs.read16(nic.address, 0x42) >> 14, 0b10);
+    // Table size is read-only
+    assert_eq!(cs.read16(nic.address, 0x42) & 0x7ff, 3);
+
+    msi::write_msix_enable(&cs, nic, false).expect("NIC has MSI-X");
+    assert_eq!(cs.read16(nic.address, 0x42) >> 14, 0b00);
+}
+
+#[test]
+fn narrow_writes() {
+    let cs = machine();
+    let host = PciAddress::new(0, 0, 0);
+
+    cs.write(host, 0x60, 0x1122_3344);
+    cs.write16(host, 0x62, 0xaabb);
+    assert_eq!(cs.read(host, 0x60), 0xaabb_3344);
+    cs.write8(host, 0x61, 0xcc);
+    assert_eq!(cs.read(host, 0x60), 0xaabb_cc44);
+    assert_eq!(cs.read8(host, 0x61), 0xcc);
+}
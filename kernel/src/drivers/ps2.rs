@@ -0,0 +1,397 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Driver for a keyboard on the first port of the PS/2 (8042) controller.
+//!
+//! We turn off the controller's translation to scancode set 1, decode set 2
+//! ourselves and queue the resulting events in `drivers::input`.
+
+#[cfg(target_os = "none")]
+use core::time::Duration;
+
+use kpi::system::KeyEvent;
+#[cfg(target_os = "none")]
+use log::info;
+#[cfg(target_os = "none")]
+use spin::Mutex;
+#[cfg(target_os = "none")]
+use x86::io;
+
+#[cfg(target_os = "none")]
+use crate::error::KError;
+
+/// Data port.
+const DATA: u16 = 0x60;
+/// Status (read) and command (write) port.
+const STATUS: u16 = 0x64;
+const COMMAND: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT2: u8 = 0xa7;
+const CMD_DISABLE_PORT1: u8 = 0xad;
+const CMD_ENABLE_PORT1: u8 = 0xae;
+/// Puts the next byte written to `DATA` in the output buffer as if it came
+/// from the keyboard.
+pub const CMD_WRITE_PORT1_OUTPUT: u8 = 0xd2;
+
+const CONFIG_PORT1_IRQ: u8 = 1 << 0;
+const CONFIG_PORT2_IRQ: u8 = 1 << 1;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const KBD_SET_SCANCODE_SET: u8 = 0xf0;
+const KBD_ENABLE_SCANNING: u8 = 0xf4;
+const KBD_RESET: u8 = 0xff;
+const KBD_ACK: u8 = 0xfa;
+const KBD_RESEND: u8 = 0xfe;
+const KBD_SELF_TEST_PASSED: u8 = 0xaa;
+
+/// The legacy IRQ of the first PS/2 port.
+pub const KEYBOARD_IRQ: u8 = 1;
+
+// Codes of the keys that change what other keys produce.
+const LEFT_SHIFT: u16 = 0x12;
+const RIGHT_SHIFT: u16 = 0x59;
+const LEFT_CTRL: u16 = 0x14;
+const RIGHT_CTRL: u16 = 0xe014;
+const CAPS_LOCK: u16 = 0x58;
+
+/// Characters (unshifted, shifted) of the keys on a US keyboard.
+const US_KEYMAP: &[(u8, u8, u8)] = &[
+    (0x0e, b'`', b'~'),
+    (0x16, b'1', b'!'),
+    (0x1e, b'2', b'@'),
+    (0x26, b'3', b'#'),
+    (0x25, b'4', b'$'),
+    (0x2e, b'5', b'%'),
+    (0x36, b'6', b'^'),
+    (0x3d, b'7', b'&'),
+    (0x3e, b'8', b'*'),
+    (0x46, b'9', b'('),
+    (0x45, b'0', b')'),
+    (0x4e, b'-', b'_'),
+    (0x55, b'=', b'+'),
+    (0x66, 0x08, 0x08),
+    (0x0d, b'\t', b'\t'),
+    (0x15, b'q', b'Q'),
+    (0x1d, b'w', b'W'),
+    (0x24, b'e', b'E'),
+    (0x2d, b'r', b'R'),
+    (0x2c, b't', b'T'),
+    (0x35, b'y', b'Y'),
+    (0x3c, b'u', b'U'),
+    (0x43, b'i', b'I'),
+    (0x44, b'o', b'O'),
+    (0x4d, b'p', b'P'),
+    (0x54, b'[', b'{'),
+    (0x5b, b']', b'}'),
+    (0x5d, b'\\', b'|'),
+    (0x1c, b'a', b'A'),
+    (0x1b, b's', b'S'),
+    (0x23, b'd', b'D'),
+    (0x2b, b'f', b'F'),
+    (0x34, b'g', b'G'),
+    (0x33, b'h', b'H'),
+    (0x3b, b'j', b'J'),
+    (0x42, b'k', b'K'),
+    (0x4b, b'l', b'L'),
+    (0x4c, b';', b':'),
+    (0x52, b'\'', b'"'),
+    (0x5a, b'\n', b'\n'),
+    (0x1a, b'z', b'Z'),
+    (0x22, b'x', b'X'),
+    (0x21, b'c', b'C'),
+    (0x2a, b'v', b'V'),
+    (0x32, b'b', b'B'),
+    (0x31, b'n', b'N'),
+    (0x3a, b'm', b'M'),
+    (0x41, b',', b'<'),
+    (0x49, b'.', b'>'),
+    (0x4a, b'/', b'?'),
+    (0x29, b' ', b' '),
+    (0x76, 0x1b, 0x1b),
+];
+
+/// Turns scancode set 2 bytes into key events.
+struct Decoder {
+    /// Got the 0xe0 prefix.
+    extended: bool,
+    /// Got the 0xf0 prefix.
+    release: bool,
+    /// Bytes of the pause key sequence we still have to skip.
+    skip: u8,
+    left_shift: bool,
+    right_shift: bool,
+    ctrl: bool,
+    caps_lock: bool,
+}
+
+impl Decoder {
+    const fn new() -> Decoder {
+        Decoder {
+            extended: false,
+            release: false,
+            skip: 0,
+            left_shift: false,
+            right_shift: false,
+            ctrl: false,
+            caps_lock: false,
+        }
+    }
+
+    /// Decodes the next byte from the keyboard, returns an event once a key
+    /// sequence is complete.
+    fn feed(&mut self, byte: u8) -> Option<KeyEvent> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return None;
+        }
+        match byte {
+            0xe0 => {
+                self.extended = true;
+                return None;
+            }
+            0xf0 => {
+                self.release = true;
+                return None;
+            }
+            0xe1 => {
+                // Pause is e1 14 77 e1 f0 14 f0 77 (and has no release)
+                self.skip = 7;
+                return None;
+            }
+            _ => {}
+        }
+
+        let code = if self.extended {
+            0xe000 | byte as u16
+        } else {
+            byte as u16
+        };
+        let pressed = !self.release;
+        self.extended = false;
+        self.release = false;
+
+        match code {
+            LEFT_SHIFT => self.left_shift = pressed,
+            RIGHT_SHIFT => self.right_shift = pressed,
+            LEFT_CTRL | RIGHT_CTRL => self.ctrl = pressed,
+            CAPS_LOCK if pressed => self.caps_lock = !self.caps_lock,
+            _ => {}
+        }
+
+        Some(KeyEvent {
+            code,
+            pressed,
+            ascii: if pressed { self.ascii(code) } else { 0 },
+        })
+    }
+
+    /// The character key `code` produces with the current modifiers.
+    fn ascii(&self, code: u16) -> u8 {
+        let (normal, shifted) = match code {
+            // Keypad enter and slash
+            0xe05a => return b'\n',
+            0xe04a => return b'/',
+            _ => match US_KEYMAP.iter().find(|(c, _, _)| *c as u16 == code) {
+                Some((_, normal, shifted)) => (*normal, *shifted),
+                None => return 0,
+            },
+        };
+
+        let mut shift = self.left_shift || self.right_shift;
+        if self.caps_lock && normal.is_ascii_alphabetic() {
+            shift = !shift;
+        }
+        if self.ctrl && normal.is_ascii_alphabetic() {
+            return normal & 0x1f;
+        }
+        if shift {
+            shifted
+        } else {
+            normal
+        }
+    }
+}
+
+#[cfg(target_os = "none")]
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder::new());
+
+/// How long we wait for the controller (and the keyboard) to respond.
+#[cfg(target_os = "none")]
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Waits until the controller's status has `mask` set (or cleared).
+#[cfg(target_os = "none")]
+fn wait(mask: u8, set: bool) -> Result<(), KError> {
+    let start = rawtime::Instant::now();
+    while (unsafe { io::inb(STATUS) } & mask != 0) != set {
+        if start.elapsed() > TIMEOUT {
+            return Err(KError::DeviceTimeout);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// Sends a command to the controller.
+#[cfg(target_os = "none")]
+pub fn command(cmd: u8) -> Result<(), KError> {
+    wait(STATUS_INPUT_FULL, false)?;
+    unsafe { io::outb(COMMAND, cmd) };
+    Ok(())
+}
+
+/// Writes to the data port (the keyboard, or a command's argument).
+#[cfg(target_os = "none")]
+pub fn write(data: u8) -> Result<(), KError> {
+    wait(STATUS_INPUT_FULL, false)?;
+    unsafe { io::outb(DATA, data) };
+    Ok(())
+}
+
+#[cfg(target_os = "none")]
+fn read() -> Result<u8, KError> {
+    wait(STATUS_OUTPUT_FULL, true)?;
+    Ok(unsafe { io::inb(DATA) })
+}
+
+/// Sends `cmd` to the keyboard and waits for the acknowledgement.
+#[cfg(target_os = "none")]
+fn keyboard_command(cmd: u8) -> Result<(), KError> {
+    for _retry in 0..3 {
+        write(cmd)?;
+        match read()? {
+            KBD_ACK => return Ok(()),
+            KBD_RESEND => continue,
+            _ => return Err(KError::NotSupported),
+        }
+    }
+    Err(KError::DeviceTimeout)
+}
+
+/// Resets the keyboard, switches it to scancode set 2 and routes its
+/// interrupt to `poll`.
+#[cfg(target_os = "none")]
+pub fn init() -> Result<(), KError> {
+    // Without a controller the status port reads as all ones
+    if unsafe { io::inb(STATUS) } == 0xff {
+        return Err(KError::NotSupported);
+    }
+
+    command(CMD_DISABLE_PORT1)?;
+    command(CMD_DISABLE_PORT2)?;
+    while unsafe { io::inb(STATUS) } & STATUS_OUTPUT_FULL != 0 {
+        unsafe { io::inb(DATA) };
+    }
+
+    command(CMD_READ_CONFIG)?;
+    let config = read()? & !(CONFIG_PORT1_IRQ | CONFIG_PORT2_IRQ | CONFIG_TRANSLATION);
+    command(CMD_WRITE_CONFIG)?;
+    write(config)?;
+
+    command(CMD_ENABLE_PORT1)?;
+    keyboard_command(KBD_RESET)?;
+    if read()? != KBD_SELF_TEST_PASSED {
+        return Err(KError::NotSupported);
+    }
+    keyboard_command(KBD_SET_SCANCODE_SET)?;
+    keyboard_command(2)?;
+    keyboard_command(KBD_ENABLE_SCANNING)?;
+
+    // The BSP handles keyboard interrupts
+    crate::arch::irq::route_legacy_irq(KEYBOARD_IRQ, 0, |_irq| poll())?;
+    command(CMD_WRITE_CONFIG)?;
+    write(config | CONFIG_PORT1_IRQ)?;
+
+    info!("PS/2 keyboard initialized");
+    Ok(())
+}
+
+/// Decodes everything the keyboard sent (called from the interrupt
+/// handler).
+#[cfg(target_os = "none")]
+pub fn poll() {
+    let mut decoder = DECODER.lock();
+    while unsafe { io::inb(STATUS) } & STATUS_OUTPUT_FULL != 0 {
+        let byte = unsafe { io::inb(DATA) };
+        if let Some(event) = decoder.feed(byte) {
+            super::input::push(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode(decoder: &mut Decoder, bytes: &[u8]) -> Vec<KeyEvent> {
+        bytes.iter().filter_map(|b| decoder.feed(*b)).collect()
+    }
+
+    fn typed(bytes: &[u8]) -> String {
+        let mut decoder = Decoder::new();
+        decode(&mut decoder, bytes)
+            .iter()
+            .filter(|e| e.ascii != 0)
+            .map(|e| e.ascii as char)
+            .collect()
+    }
+
+    #[test]
+    fn press_and_release() {
+        let mut decoder = Decoder::new();
+        let events = decode(&mut decoder, &[0x1c, 0xf0, 0x1c]);
+        assert_eq!(
+            events,
+            [
+                KeyEvent {
+                    code: 0x1c,
+                    pressed: true,
+                    ascii: b'a'
+                },
+                KeyEvent {
+                    code: 0x1c,
+                    pressed: false,
+                    ascii: 0
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn extended_keys() {
+        let mut decoder = Decoder::new();
+        // Right ctrl, then cursor up
+        let events = decode(&mut decoder, &[0xe0, 0x14, 0xe0, 0xf0, 0x14, 0xe0, 0x75]);
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].code, RIGHT_CTRL);
+        assert!(!events[1].pressed);
+        assert_eq!(events[2].code, 0xe075);
+        assert_eq!(events[2].ascii, 0);
+    }
+
+    #[test]
+    fn modifiers() {
+        // h, shift+i, shift release, 1, shift+1
+        assert_eq!(
+            typed(&[0x33, 0x12, 0x43, 0xf0, 0x12, 0x16, 0x59, 0x16]),
+            "hI1!"
+        );
+        // Caps lock only affects letters, shift inverts it
+        assert_eq!(typed(&[0x58, 0xf0, 0x58, 0x1c, 0x16, 0x12, 0x1c]), "A1a");
+        // Ctrl+c
+        assert_eq!(typed(&[0x14, 0x21]), "\x03");
+    }
+
+    #[test]
+    fn pause_is_ignored() {
+        assert_eq!(
+            typed(&[0xe1, 0x14, 0x77, 0xe1, 0xf0, 0x14, 0xf0, 0x77, 0x32]),
+            "b"
+        );
+    }
+}
@@ -0,0 +1,340 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Driver for virtio consoles (virtio-serial).
+//!
+//! With the multiport feature the device has a pair of control queues and
+//! announces its ports with control messages. We set up queues for the
+//! first `MAX_PORTS` ports and use the port the device marks as console
+//! (port 0 without multiport) for log output and as keyboard input.
+//!
+//! Output is synchronous: we wait for the device to consume every buffer,
+//! so unlike the emulated UART nothing gets dropped when we log a lot.
+//! Input arrives on an MSI-X interrupt.
+
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::time::Duration;
+
+use arrayvec::ArrayVec;
+use kpi::system::KeyEvent;
+use log::{debug, info, trace, warn};
+use spin::{Mutex, Once};
+
+use super::{LegacyTransport, Virtqueue, NO_VECTOR, VENDOR_ID};
+use crate::arch::Platform;
+use crate::arch_interface::Arch;
+use crate::console::Console;
+use crate::drivers::input;
+use crate::drivers::pci::{self, Bar, PciDevice, PciDriver, PciMatch};
+use crate::error::KError;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::vspace::MapAction;
+use crate::memory::{PAddr, LARGE_PAGE_SIZE};
+
+/// The PCI driver (matches transitional virtio console devices).
+pub static DRIVER: PciDriver = PciDriver {
+    name: "virtio-console",
+    ids: &[PciMatch::Device {
+        vendor: VENDOR_ID,
+        device: 0x1003,
+    }],
+    attach,
+};
+
+const F_MULTIPORT: u32 = 1 << 1;
+
+/// Offset of `max_nr_ports` in the device configuration.
+const CONFIG_MAX_NR_PORTS: u16 = 4;
+
+const CONTROL_DEVICE_READY: u16 = 0;
+const CONTROL_DEVICE_ADD: u16 = 1;
+const CONTROL_PORT_READY: u16 = 3;
+const CONTROL_CONSOLE_PORT: u16 = 4;
+const CONTROL_PORT_OPEN: u16 = 6;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct ControlMessage {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+/// Ports we set up queues for.
+const MAX_PORTS: u32 = 4;
+const NO_PORT: u32 = u32::MAX;
+
+/// Number of the control receive queue (transmit is the next one).
+const CONTROL_RX: u16 = 2;
+/// Control queues and a pair for every port.
+const MAX_QUEUES: usize = 2 + 2 * MAX_PORTS as usize;
+
+// Everything lives in one DMA buffer: the rings of queue `n` at
+// `n * QUEUE_MEMORY`, then the receive buffers, then one transmit buffer
+// per queue.
+const QUEUE_MEMORY: usize = 0x4000;
+const RX_BUFFERS: u16 = 16;
+const RX_BUFFER_SIZE: usize = 256;
+const RX_BASE: usize = MAX_QUEUES * QUEUE_MEMORY;
+const TX_BUFFER_SIZE: usize = 2048;
+const TX_BASE: usize = RX_BASE + MAX_QUEUES * RX_BUFFERS as usize * RX_BUFFER_SIZE;
+static_assertions::const_assert!(TX_BASE + MAX_QUEUES * TX_BUFFER_SIZE <= LARGE_PAGE_SIZE);
+
+/// The receive (or transmit) queues of a device.
+type Queues = ArrayVec<Virtqueue, { MAX_QUEUES / 2 }>;
+
+/// How long we wait for the device to consume output.
+const TX_TIMEOUT: Duration = Duration::from_millis(100);
+/// How long we wait for the device to tell us which port is the console.
+const SETUP_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The receive queue of `port` (transmit is the next one).
+fn rx_queue(port: u32) -> u16 {
+    if port == 0 {
+        0
+    } else {
+        CONTROL_RX + 2 * port as u16
+    }
+}
+
+fn rx_buffer(queue: u16, id: u16) -> usize {
+    RX_BASE + (queue as usize * RX_BUFFERS as usize + id as usize) * RX_BUFFER_SIZE
+}
+
+fn tx_buffer(queue: u16) -> usize {
+    TX_BASE + queue as usize * TX_BUFFER_SIZE
+}
+
+pub struct VirtioConsole {
+    transport: LegacyTransport,
+    memory: DmaBuffer,
+    ports: u32,
+    /// The port we write to (`NO_PORT` until the device tells us).
+    console_port: AtomicU32,
+    /// The device didn't consume a buffer in time, we stop writing to it.
+    stalled: AtomicBool,
+    /// Lock order: `rx` before `tx`.
+    rx: Mutex<Queues>,
+    tx: Mutex<Queues>,
+}
+
+/// We only support one device.
+static CONSOLE: Once<VirtioConsole> = Once::new();
+
+impl VirtioConsole {
+    /// Sends `data` on the transmit queue `index` and waits until the device
+    /// consumed it.
+    fn transmit(&self, tx: &mut Queues, index: u16, data: &[u8]) {
+        let queue = match tx.iter_mut().find(|q| q.index() == index) {
+            Some(queue) => queue,
+            None => return,
+        };
+
+        for chunk in data.chunks(TX_BUFFER_SIZE) {
+            if self.stalled.load(Ordering::Relaxed) {
+                return;
+            }
+            let offset = tx_buffer(index);
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    chunk.as_ptr(),
+                    self.memory.as_ptr::<u8>().add(offset),
+                    chunk.len(),
+                )
+            };
+            queue.set(0, self.memory.paddr() + offset, chunk.len() as u32, false);
+            queue.submit(0);
+            self.transport.notify(queue);
+
+            let start = rawtime::Instant::now();
+            while queue.pop_used().is_none() {
+                if start.elapsed() > TX_TIMEOUT {
+                    // The buffer still belongs to the device (we can't log
+                    // here, we might be called by the logger)
+                    self.stalled.store(true, Ordering::Relaxed);
+                    return;
+                }
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    fn send_control(&self, id: u32, event: u16, value: u16) {
+        let msg = ControlMessage { id, event, value };
+        let bytes = unsafe {
+            core::slice::from_raw_parts(&msg as *const _ as *const u8, size_of::<ControlMessage>())
+        };
+        self.transmit(&mut self.tx.lock(), CONTROL_RX + 1, bytes);
+    }
+
+    fn control(&self, msg: ControlMessage) {
+        trace!("virtio-console control {:?}", msg);
+        match msg.event {
+            CONTROL_DEVICE_ADD => {
+                let ready = msg.id < self.ports;
+                self.send_control(msg.id, CONTROL_PORT_READY, ready as u16);
+            }
+            CONTROL_CONSOLE_PORT if msg.id < self.ports => {
+                self.console_port.store(msg.id, Ordering::Release);
+                self.send_control(msg.id, CONTROL_PORT_OPEN, 1);
+                debug!("virtio-console: port {} is the console", msg.id);
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles everything the device sent (control messages and input).
+    fn poll(&self) {
+        let mut rx = self.rx.lock();
+        for queue in rx.iter_mut() {
+            let mut returned = false;
+            while let Some((id, len)) = queue.pop_used() {
+                let offset = rx_buffer(queue.index(), id);
+                let len = (len as usize).min(RX_BUFFER_SIZE);
+                let data = &self.memory.as_slice()[offset..offset + len];
+
+                if queue.index() == CONTROL_RX {
+                    if len >= size_of::<ControlMessage>() {
+                        let msg = unsafe { ptr::read_unaligned(data.as_ptr() as *const _) };
+                        self.control(msg);
+                    }
+                } else if queue.index() == rx_queue(self.console_port.load(Ordering::Acquire)) {
+                    for c in data {
+                        input::push(KeyEvent {
+                            code: 0,
+                            pressed: true,
+                            ascii: *c,
+                        });
+                    }
+                }
+
+                // Give the buffer back
+                queue.submit(id);
+                returned = true;
+            }
+            if returned {
+                self.transport.notify(queue);
+            }
+        }
+    }
+}
+
+impl Console for VirtioConsole {
+    fn name(&self) -> &'static str {
+        "virtio-console"
+    }
+
+    fn write_str(&self, s: &str) {
+        let port = self.console_port.load(Ordering::Acquire);
+        if port != NO_PORT {
+            self.transmit(&mut self.tx.lock(), rx_queue(port) + 1, s.as_bytes());
+        }
+    }
+}
+
+fn interrupt(_vector: u8) {
+    if let Some(console) = CONSOLE.get() {
+        console.poll();
+    }
+}
+
+/// Routes MSI-X table entry 0 (which we use for all queues) to the BSP.
+fn setup_msix(dev: &PciDevice, transport: &mut LegacyTransport) -> Result<(), KError> {
+    let table_bar = match dev.msix() {
+        Some(pci::Capability::MsiX { table, .. }) => table.0 as usize,
+        _ => return Err(KError::MsiUnsupported),
+    };
+    if let Some(Some(Bar::Memory { base, size, .. })) = dev.bars.get(table_bar) {
+        Platform::map_kernel_identity(
+            PAddr::from(*base),
+            *size as usize,
+            MapAction::ReadWriteKernel,
+        )?;
+    }
+
+    crate::arch::irq::route_msix(dev, 0, 0, interrupt)?;
+    pci::enable_msix(dev)?;
+    transport.enable_msix(NO_VECTOR)
+}
+
+fn attach(dev: &PciDevice) -> Result<(), KError> {
+    if CONSOLE.is_completed() {
+        return Err(KError::AlreadyPresent);
+    }
+
+    let mut transport = LegacyTransport::new(dev)?;
+    let features = transport.negotiate(F_MULTIPORT);
+    let vector = match setup_msix(dev, &mut transport) {
+        Ok(()) => 0,
+        Err(e) => {
+            warn!("virtio-console: no input without MSI-X ({})", e);
+            NO_VECTOR
+        }
+    };
+
+    let multiport = features & F_MULTIPORT != 0;
+    let ports = if multiport {
+        transport.config32(CONFIG_MAX_NR_PORTS).min(MAX_PORTS)
+    } else {
+        1
+    };
+
+    let memory = DmaBuffer::new(LARGE_PAGE_SIZE)?;
+    let mut rx = Queues::new();
+    let mut tx = Queues::new();
+    let mut queues = ArrayVec::<u16, { MAX_QUEUES / 2 }>::new();
+    queues.extend((0..ports).map(rx_queue));
+    if multiport {
+        queues.push(CONTROL_RX);
+    }
+    for index in queues {
+        let setup = |index: u16| {
+            let offset = index as usize * QUEUE_MEMORY;
+            transport.setup_queue(index, &memory, offset, QUEUE_MEMORY, vector)
+        };
+
+        let mut queue = setup(index)?;
+        for id in 0..RX_BUFFERS.min(queue.size()) {
+            let paddr = memory.paddr() + rx_buffer(index, id);
+            queue.set(id, paddr, RX_BUFFER_SIZE as u32, true);
+            queue.submit(id);
+        }
+        rx.push(queue);
+        tx.push(setup(index + 1)?);
+    }
+
+    transport.driver_ok()?;
+    for queue in rx.iter() {
+        transport.notify(queue);
+    }
+
+    let console = CONSOLE.call_once(|| VirtioConsole {
+        transport,
+        memory,
+        ports,
+        console_port: AtomicU32::new(if multiport { NO_PORT } else { 0 }),
+        stalled: AtomicBool::new(false),
+        rx: Mutex::new(rx),
+        tx: Mutex::new(tx),
+    });
+
+    if multiport {
+        console.send_control(0, CONTROL_DEVICE_READY, 1);
+        let start = rawtime::Instant::now();
+        while console.console_port.load(Ordering::Acquire) == NO_PORT
+            && start.elapsed() < SETUP_TIMEOUT
+        {
+            console.poll();
+            core::hint::spin_loop();
+        }
+    }
+
+    crate::console::register(console)?;
+    match console.console_port.load(Ordering::Acquire) {
+        NO_PORT => info!("virtio-console: {} ports, none is a console", ports),
+        port => info!("virtio-console: {} ports, console on port {}", ports, port),
+    }
+    Ok(())
+}
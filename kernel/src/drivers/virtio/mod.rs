@@ -0,0 +1,183 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Virtio devices on PCI.
+//!
+//! We use the legacy (virtio 0.9.5) interface of transitional devices: it
+//! is a handful of registers in an I/O BAR, works the same on QEMU and
+//! most other hypervisors and doesn't need vendor capability parsing.
+//! Devices that are modern-only use `ModernTransport`.
+
+use x86::io;
+
+use crate::drivers::pci::{self, Bar, PciDevice};
+use crate::error::KError;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::BASE_PAGE_SIZE;
+
+#[cfg(target_os = "none")]
+pub mod console;
+mod modern;
+mod queue;
+#[cfg(target_os = "none")]
+pub mod rng;
+#[cfg(target_os = "none")]
+pub mod vsock;
+
+pub use modern::{ModernTransport, F_VERSION_1};
+pub use queue::Virtqueue;
+
+/// PCI vendor of all virtio devices.
+pub const VENDOR_ID: u16 = 0x1af4;
+
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_PFN: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0c;
+const REG_QUEUE_SELECT: u16 = 0x0e;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_STATUS: u16 = 0x12;
+const REG_ISR: u16 = 0x13;
+const REG_CONFIG_VECTOR: u16 = 0x14;
+const REG_QUEUE_VECTOR: u16 = 0x16;
+/// Start of the device configuration (moves to `REG_CONFIG_MSIX` once
+/// MSI-X is enabled).
+const REG_CONFIG: u16 = 0x14;
+const REG_CONFIG_MSIX: u16 = 0x18;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FAILED: u8 = 0x80;
+
+/// Tells the device not to raise an interrupt for a queue.
+pub const NO_VECTOR: u16 = 0xffff;
+
+/// The registers of a legacy virtio device.
+pub struct LegacyTransport {
+    port: u16,
+    /// MSI-X is enabled (and the device configuration moved).
+    msix: bool,
+}
+
+impl LegacyTransport {
+    /// Resets `dev` and tells it we have a driver for it.
+    pub fn new(dev: &PciDevice) -> Result<LegacyTransport, KError> {
+        let port = match dev.bars[0] {
+            Some(Bar::Io { port, .. }) => port,
+            _ => return Err(KError::NotSupported),
+        };
+        pci::enable_bus_master(dev)?;
+        pci::enable_io_space(dev)?;
+
+        let transport = LegacyTransport { port, msix: false };
+        transport.set_status(0);
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        Ok(transport)
+    }
+
+    fn set_status(&self, status: u8) {
+        unsafe { io::outb(self.port + REG_STATUS, status) };
+    }
+
+    fn status(&self) -> u8 {
+        unsafe { io::inb(self.port + REG_STATUS) }
+    }
+
+    /// Accepts the features in `supported` the device offers, returns the
+    /// features we ended up with.
+    pub fn negotiate(&self, supported: u32) -> u32 {
+        let features = unsafe { io::inl(self.port + REG_DEVICE_FEATURES) } & supported;
+        unsafe { io::outl(self.port + REG_GUEST_FEATURES, features) };
+        features
+    }
+
+    /// Makes the device configuration (and queue vector registers) use the
+    /// MSI-X layout, call after `pci::enable_msix`.
+    pub fn enable_msix(&mut self, config_vector: u16) -> Result<(), KError> {
+        self.msix = true;
+        unsafe { io::outw(self.port + REG_CONFIG_VECTOR, config_vector) };
+        if unsafe { io::inw(self.port + REG_CONFIG_VECTOR) } != config_vector {
+            return Err(KError::OutOfVectors);
+        }
+        Ok(())
+    }
+
+    /// Sets up queue `index` in `memory` at `offset` (which has to be page
+    /// aligned), `vector` is the MSI-X table entry for its interrupts.
+    pub fn setup_queue(
+        &self,
+        index: u16,
+        memory: &DmaBuffer,
+        offset: usize,
+        max_size: usize,
+        vector: u16,
+    ) -> Result<Virtqueue, KError> {
+        unsafe { io::outw(self.port + REG_QUEUE_SELECT, index) };
+        let size = unsafe { io::inw(self.port + REG_QUEUE_SIZE) };
+        let pfn = unsafe { io::inl(self.port + REG_QUEUE_PFN) };
+        // Legacy devices dictate the queue size
+        if size == 0 || pfn != 0 {
+            return Err(KError::NotSupported);
+        }
+        if Virtqueue::memory_size(size) > max_size
+            || offset % BASE_PAGE_SIZE != 0
+            || offset + max_size > memory.len()
+        {
+            return Err(KError::InvalidLayout);
+        }
+
+        if self.msix {
+            unsafe { io::outw(self.port + REG_QUEUE_VECTOR, vector) };
+            if unsafe { io::inw(self.port + REG_QUEUE_VECTOR) } != vector {
+                return Err(KError::OutOfVectors);
+            }
+        }
+
+        let paddr = memory.paddr() + offset;
+        let queue = unsafe { Virtqueue::new(index, size, memory.as_ptr::<u8>().add(offset)) };
+        unsafe {
+            io::outl(
+                self.port + REG_QUEUE_PFN,
+                (paddr.as_u64() / BASE_PAGE_SIZE as u64) as u32,
+            )
+        };
+        Ok(queue)
+    }
+
+    /// Tells the device there are new buffers in `queue`.
+    pub fn notify(&self, queue: &Virtqueue) {
+        unsafe { io::outw(self.port + REG_QUEUE_NOTIFY, queue.index()) };
+    }
+
+    /// Lets the device start processing the queues.
+    pub fn driver_ok(&self) -> Result<(), KError> {
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK);
+        if self.status() & STATUS_FAILED != 0 {
+            return Err(KError::NotSupported);
+        }
+        Ok(())
+    }
+
+    /// Reads (and clears) the interrupt status.
+    pub fn isr(&self) -> u8 {
+        unsafe { io::inb(self.port + REG_ISR) }
+    }
+
+    fn config_port(&self, offset: u16) -> u16 {
+        let base = if self.msix {
+            REG_CONFIG_MSIX
+        } else {
+            REG_CONFIG
+        };
+        self.port + base + offset
+    }
+
+    pub fn config16(&self, offset: u16) -> u16 {
+        unsafe { io::inw(self.config_port(offset)) }
+    }
+
+    pub fn config32(&self, offset: u16) -> u32 {
+        unsafe { io::inl(self.config_port(offset)) }
+    }
+}
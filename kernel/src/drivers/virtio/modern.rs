@@ -0,0 +1,270 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The modern (virtio 1.0) PCI interface.
+//!
+//! Some devices (e.g., QEMU's vhost-vsock-pci) don't have a legacy
+//! interface. Modern devices describe where their register blocks are with
+//! vendor-specific capabilities, the blocks live in memory BARs.
+
+use core::ptr;
+
+use super::{Virtqueue, STATUS_ACKNOWLEDGE, STATUS_DRIVER, STATUS_DRIVER_OK, STATUS_FAILED};
+use crate::arch::Platform;
+use crate::arch_interface::Arch;
+use crate::drivers::pci::{self, Bar, Capability, PciDevice};
+use crate::error::KError;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::vspace::MapAction;
+use crate::memory::{PAddr, BASE_PAGE_SIZE};
+
+/// Capability id of vendor-specific capabilities.
+const CAP_VENDOR: u8 = 0x09;
+
+/// `cfg_type` of the register blocks (in the vendor capabilities).
+const CAP_COMMON: u8 = 1;
+const CAP_NOTIFY: u8 = 2;
+const CAP_ISR: u8 = 3;
+const CAP_DEVICE: u8 = 4;
+
+// Registers of the common configuration
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0c;
+const COMMON_MSIX_CONFIG: usize = 0x10;
+const COMMON_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: usize = 0x1a;
+const COMMON_QUEUE_ENABLE: usize = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1e;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+const STATUS_FEATURES_OK: u8 = 8;
+
+/// Queues we support per device.
+const MAX_QUEUES: usize = 8;
+
+/// The device follows the virtio 1.0 specification (modern devices refuse
+/// to work without it).
+pub const F_VERSION_1: u64 = 1 << 32;
+
+/// The registers of a modern virtio device.
+pub struct ModernTransport {
+    /// Virtual (identity mapped) addresses of the register blocks.
+    common: u64,
+    notify: u64,
+    isr: u64,
+    device: u64,
+    notify_off_multiplier: u32,
+    /// Notification offset of every queue (reading it needs a queue
+    /// select, which would race with other users of the transport).
+    notify_offsets: [u16; MAX_QUEUES],
+}
+
+/// Returns the address of the block a vendor capability at `offset`
+/// describes and maps the BAR it is in.
+fn map_block(dev: &PciDevice, offset: u16) -> Result<u64, KError> {
+    let bar = pci::read_config32(dev, offset + 4)? as u8 as usize;
+    let block = pci::read_config32(dev, offset + 8)?;
+    match dev.bars.get(bar) {
+        Some(Some(Bar::Memory { base, size, .. })) => {
+            Platform::map_kernel_identity(
+                PAddr::from(*base),
+                *size as usize,
+                MapAction::ReadWriteKernel,
+            )?;
+            Ok(*base + block as u64)
+        }
+        _ => Err(KError::NotSupported),
+    }
+}
+
+impl ModernTransport {
+    /// Finds the register blocks of `dev`, resets it and tells it we have a
+    /// driver for it.
+    pub fn new(dev: &PciDevice) -> Result<ModernTransport, KError> {
+        let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+        let mut notify_off_multiplier = 0;
+
+        for cap in dev.capabilities.iter() {
+            let offset = match *cap {
+                Capability::Other { id, offset } if id == CAP_VENDOR => offset as u16,
+                _ => continue,
+            };
+            // The first capability of a type is the preferred one
+            let cfg_type = (pci::read_config32(dev, offset)? >> 24) as u8;
+            match cfg_type {
+                CAP_COMMON if common.is_none() => common = Some(map_block(dev, offset)?),
+                CAP_NOTIFY if notify.is_none() => {
+                    notify = Some(map_block(dev, offset)?);
+                    notify_off_multiplier = pci::read_config32(dev, offset + 16)?;
+                }
+                CAP_ISR if isr.is_none() => isr = Some(map_block(dev, offset)?),
+                CAP_DEVICE if device.is_none() => device = Some(map_block(dev, offset)?),
+                _ => {}
+            }
+        }
+
+        let transport = match (common, notify, isr) {
+            (Some(common), Some(notify), Some(isr)) => ModernTransport {
+                common,
+                notify,
+                isr,
+                device: device.unwrap_or(0),
+                notify_off_multiplier,
+                notify_offsets: [0; MAX_QUEUES],
+            },
+            _ => return Err(KError::NotSupported),
+        };
+        pci::enable_bus_master(dev)?;
+
+        transport.set_status(0);
+        // The reset is done once we read back 0
+        while transport.status() != 0 {
+            core::hint::spin_loop();
+        }
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        Ok(transport)
+    }
+
+    fn read<T: Copy>(&self, reg: usize) -> T {
+        unsafe { ptr::read_volatile((self.common as usize + reg) as *const T) }
+    }
+
+    fn write<T: Copy>(&self, reg: usize, value: T) {
+        unsafe { ptr::write_volatile((self.common as usize + reg) as *mut T, value) }
+    }
+
+    /// Writes a 64-bit register as two halves (devices don't have to
+    /// support wider accesses).
+    fn write64(&self, reg: usize, value: u64) {
+        self.write(reg, value as u32);
+        self.write(reg + 4, (value >> 32) as u32);
+    }
+
+    fn set_status(&self, status: u8) {
+        self.write(COMMON_STATUS, status);
+    }
+
+    fn status(&self) -> u8 {
+        self.read(COMMON_STATUS)
+    }
+
+    /// Accepts the features in `supported` the device offers (`F_VERSION_1`
+    /// is always part of it), returns the features we ended up with.
+    pub fn negotiate(&self, supported: u64) -> Result<u64, KError> {
+        let mut features = 0;
+        for half in 0..2u32 {
+            self.write(COMMON_DEVICE_FEATURE_SELECT, half);
+            features |= (self.read::<u32>(COMMON_DEVICE_FEATURE) as u64) << (32 * half);
+        }
+        features &= supported | F_VERSION_1;
+        if features & F_VERSION_1 == 0 {
+            return Err(KError::NotSupported);
+        }
+
+        for half in 0..2u32 {
+            self.write(COMMON_DRIVER_FEATURE_SELECT, half);
+            self.write(COMMON_DRIVER_FEATURE, (features >> (32 * half)) as u32);
+        }
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            return Err(KError::NotSupported);
+        }
+        Ok(features)
+    }
+
+    /// Sets the MSI-X table entry for configuration changes, call after
+    /// `pci::enable_msix`.
+    pub fn enable_msix(&mut self, config_vector: u16) -> Result<(), KError> {
+        self.write(COMMON_MSIX_CONFIG, config_vector);
+        if self.read::<u16>(COMMON_MSIX_CONFIG) != config_vector {
+            return Err(KError::OutOfVectors);
+        }
+        Ok(())
+    }
+
+    /// Sets up queue `index` in `memory` at `offset` (which has to be page
+    /// aligned), `vector` is the MSI-X table entry for its interrupts.
+    ///
+    /// Unlike with legacy devices we can make the queue smaller than what
+    /// the device offers, so it fits in `max_size`.
+    pub fn setup_queue(
+        &mut self,
+        index: u16,
+        memory: &DmaBuffer,
+        offset: usize,
+        max_size: usize,
+        vector: u16,
+    ) -> Result<Virtqueue, KError> {
+        if index as usize >= MAX_QUEUES {
+            return Err(KError::NotSupported);
+        }
+        self.write(COMMON_QUEUE_SELECT, index);
+        let mut size: u16 = self.read(COMMON_QUEUE_SIZE);
+        if size == 0 || self.read::<u16>(COMMON_QUEUE_ENABLE) != 0 {
+            return Err(KError::NotSupported);
+        }
+        while size > 1 && Virtqueue::memory_size(size) > max_size {
+            size /= 2;
+        }
+        if Virtqueue::memory_size(size) > max_size
+            || offset % BASE_PAGE_SIZE != 0
+            || offset + max_size > memory.len()
+        {
+            return Err(KError::InvalidLayout);
+        }
+
+        self.write(COMMON_QUEUE_SIZE, size);
+        self.write(COMMON_QUEUE_MSIX_VECTOR, vector);
+        if self.read::<u16>(COMMON_QUEUE_MSIX_VECTOR) != vector {
+            return Err(KError::OutOfVectors);
+        }
+
+        let paddr = (memory.paddr() + offset).as_u64();
+        self.write64(COMMON_QUEUE_DESC, paddr);
+        self.write64(
+            COMMON_QUEUE_DRIVER,
+            paddr + Virtqueue::avail_offset(size) as u64,
+        );
+        self.write64(
+            COMMON_QUEUE_DEVICE,
+            paddr + Virtqueue::used_offset(size) as u64,
+        );
+        self.notify_offsets[index as usize] = self.read(COMMON_QUEUE_NOTIFY_OFF);
+        let queue = unsafe { Virtqueue::new(index, size, memory.as_ptr::<u8>().add(offset)) };
+        self.write(COMMON_QUEUE_ENABLE, 1u16);
+        Ok(queue)
+    }
+
+    /// Tells the device there are new buffers in `queue`.
+    pub fn notify(&self, queue: &Virtqueue) {
+        let off = self.notify_offsets[queue.index() as usize] as u64;
+        let addr = self.notify + off * self.notify_off_multiplier as u64;
+        unsafe { ptr::write_volatile(addr as *mut u16, queue.index()) };
+    }
+
+    /// Lets the device start processing the queues.
+    pub fn driver_ok(&self) -> Result<(), KError> {
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK);
+        if self.status() & STATUS_FAILED != 0 {
+            return Err(KError::NotSupported);
+        }
+        Ok(())
+    }
+
+    /// Reads (and clears) the interrupt status.
+    pub fn isr(&self) -> u8 {
+        unsafe { ptr::read_volatile(self.isr as *const u8) }
+    }
+
+    /// Reads the device configuration at `offset` (little-endian).
+    pub fn config32(&self, offset: usize) -> u32 {
+        assert!(self.device != 0, "Device has no configuration");
+        unsafe { ptr::read_volatile((self.device as usize + offset) as *const u32) }
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Split virtqueues (in the legacy layout).
+//!
+//! Modern devices take the addresses of the three parts separately, they
+//! work with the same layout (see `ModernTransport::setup_queue`).
+//!
+//! Drivers use one descriptor per buffer (no chaining), so a descriptor's
+//! index also identifies the buffer when the device returns it.
+
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::memory::PAddr;
+
+/// The device writes to the buffer.
+const DESC_F_WRITE: u16 = 1 << 1;
+
+/// The used ring starts at this alignment in the legacy layout.
+const USED_ALIGN: usize = 4096;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+/// A virtqueue with `size` entries.
+pub struct Virtqueue {
+    /// Index of the queue on the device.
+    index: u16,
+    size: u16,
+    desc: *mut Descriptor,
+    /// Flags, index and ring of the available ring.
+    avail: *mut u16,
+    /// Flags and index of the used ring (followed by the ring).
+    used: *mut u16,
+    /// Next index we put in the available ring.
+    avail_idx: u16,
+    /// Index of the next used element we haven't looked at.
+    last_used: u16,
+}
+
+// Safety: The rings are only accessed through `&mut Virtqueue`.
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    /// How many bytes a queue with `size` entries needs (the memory has to
+    /// be 4 KiB aligned).
+    pub const fn memory_size(size: u16) -> usize {
+        let size = size as usize;
+        let avail = core::mem::size_of::<Descriptor>() * size + 2 * (3 + size);
+        let used = 2 * 3 + core::mem::size_of::<UsedElement>() * size;
+        (avail + USED_ALIGN - 1) / USED_ALIGN * USED_ALIGN + used
+    }
+
+    /// Offset of the available ring from the start of the queue memory.
+    pub const fn avail_offset(size: u16) -> usize {
+        core::mem::size_of::<Descriptor>() * size as usize
+    }
+
+    /// Offset of the used ring from the start of the queue memory.
+    pub const fn used_offset(size: u16) -> usize {
+        Self::memory_size(size) - (2 * 3 + core::mem::size_of::<UsedElement>() * size as usize)
+    }
+
+    /// Creates queue `index` in (zeroed) memory at `base`.
+    ///
+    /// # Safety
+    /// `base` must point to `memory_size(size)` bytes that are only used by
+    /// this queue.
+    pub unsafe fn new(index: u16, size: u16, base: *mut u8) -> Virtqueue {
+        Virtqueue {
+            index,
+            size,
+            desc: base as *mut Descriptor,
+            avail: base.add(Self::avail_offset(size)) as *mut u16,
+            used: base.add(Self::used_offset(size)) as *mut u16,
+            avail_idx: 0,
+            last_used: 0,
+        }
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Points descriptor `id` at the buffer (`paddr`, `len`).
+    pub fn set(&mut self, id: u16, paddr: PAddr, len: u32, device_writes: bool) {
+        assert!(id < self.size);
+        let desc = Descriptor {
+            addr: paddr.as_u64(),
+            len,
+            flags: if device_writes { DESC_F_WRITE } else { 0 },
+            next: 0,
+        };
+        unsafe { ptr::write_volatile(self.desc.add(id as usize), desc) };
+    }
+
+    /// Hands descriptor `id` to the device (it still needs a notification).
+    pub fn submit(&mut self, id: u16) {
+        let slot = (self.avail_idx % self.size) as usize;
+        unsafe { ptr::write_volatile(self.avail.add(2 + slot), id) };
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+
+        // The device must see the ring entry before the new index
+        fence(Ordering::SeqCst);
+        unsafe { ptr::write_volatile(self.avail.add(1), self.avail_idx) };
+    }
+
+    /// Returns the next buffer the device is done with (descriptor id and
+    /// how many bytes the device wrote).
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_idx = unsafe { ptr::read_volatile(self.used.add(1)) };
+        if used_idx == self.last_used {
+            return None;
+        }
+        // Don't read the element before the index
+        fence(Ordering::SeqCst);
+
+        let slot = (self.last_used % self.size) as usize;
+        let element =
+            unsafe { ptr::read_volatile((self.used.add(2) as *const UsedElement).add(slot)) };
+        self.last_used = self.last_used.wrapping_add(1);
+        Some((element.id as u16, element.len))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        // Sizes from the legacy virtio specification
+        assert_eq!(Virtqueue::memory_size(128), 4096 + 6 + 8 * 128);
+        assert_eq!(Virtqueue::memory_size(256), 8192 + 6 + 8 * 256);
+        assert_eq!(Virtqueue::avail_offset(128), 16 * 128);
+        assert_eq!(Virtqueue::used_offset(128), 4096);
+    }
+
+    #[test]
+    fn submit_and_complete() {
+        let mut memory = alloc::vec![0u8; Virtqueue::memory_size(4) + USED_ALIGN];
+        let base = memory.as_mut_ptr();
+        let base = unsafe { base.add(base.align_offset(USED_ALIGN)) };
+        let mut queue = unsafe { Virtqueue::new(1, 4, base) };
+
+        queue.set(2, PAddr::from(0x1000u64), 64, true);
+        queue.submit(2);
+        assert_eq!(unsafe { *queue.avail.add(1) }, 1);
+        assert_eq!(unsafe { *queue.avail.add(2) }, 2);
+        let desc = unsafe { *queue.desc.add(2) };
+        assert_eq!(
+            (desc.addr, desc.len, desc.flags),
+            (0x1000, 64, DESC_F_WRITE)
+        );
+        assert_eq!(queue.pop_used(), None);
+
+        // What the device does once it filled the buffer
+        unsafe {
+            *(queue.used.add(2) as *mut UsedElement) = UsedElement { id: 2, len: 10 };
+            *queue.used.add(1) = 1;
+        }
+        assert_eq!(queue.pop_used(), Some((2, 10)));
+        assert_eq!(queue.pop_used(), None);
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Driver for virtio entropy devices (virtio-rng).
+//!
+//! The device has a single queue: we hand it a buffer and it fills it with
+//! random bytes. We only read when the entropy pool reseeds, so requests
+//! are synchronous and don't need an interrupt.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use log::info;
+use spin::{Mutex, Once};
+
+use super::{LegacyTransport, Virtqueue, NO_VECTOR, VENDOR_ID};
+use crate::drivers::pci::{PciDevice, PciDriver, PciMatch};
+use crate::entropy;
+use crate::error::KError;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::LARGE_PAGE_SIZE;
+
+/// The PCI driver (matches transitional virtio entropy devices).
+pub static DRIVER: PciDriver = PciDriver {
+    name: "virtio-rng",
+    ids: &[PciMatch::Device {
+        vendor: VENDOR_ID,
+        device: 0x1005,
+    }],
+    attach,
+};
+
+// The rings at the start of the DMA buffer, followed by the buffer the
+// device fills.
+const QUEUE_MEMORY: usize = 0x4000;
+const BUFFER: usize = QUEUE_MEMORY;
+const BUFFER_SIZE: usize = 256;
+
+/// How long we wait for the device to fill the buffer.
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub struct VirtioRng {
+    transport: LegacyTransport,
+    memory: DmaBuffer,
+    queue: Mutex<Virtqueue>,
+    /// The device didn't return the buffer in time, we stop using it.
+    stalled: AtomicBool,
+}
+
+/// We only support one device.
+static RNG: Once<VirtioRng> = Once::new();
+
+/// Reads random bytes from the device into `buf`, returns how many we got.
+fn read(buf: &mut [u8]) -> usize {
+    let rng = match RNG.get() {
+        Some(rng) if !rng.stalled.load(Ordering::Relaxed) => rng,
+        _ => return 0,
+    };
+    let mut queue = rng.queue.lock();
+    let mut filled = 0;
+
+    while filled < buf.len() {
+        let len = (buf.len() - filled).min(BUFFER_SIZE);
+        queue.set(0, rng.memory.paddr() + BUFFER, len as u32, true);
+        queue.submit(0);
+        rng.transport.notify(&queue);
+
+        let start = rawtime::Instant::now();
+        let written = loop {
+            if let Some((_id, written)) = queue.pop_used() {
+                break (written as usize).min(len);
+            }
+            if start.elapsed() > READ_TIMEOUT {
+                // The buffer still belongs to the device
+                rng.stalled.store(true, Ordering::Relaxed);
+                return filled;
+            }
+            core::hint::spin_loop();
+        };
+        if written == 0 {
+            break;
+        }
+
+        unsafe {
+            ptr::copy_nonoverlapping(
+                rng.memory.as_ptr::<u8>().add(BUFFER),
+                buf[filled..].as_mut_ptr(),
+                written,
+            )
+        };
+        filled += written;
+    }
+
+    filled
+}
+
+fn attach(dev: &PciDevice) -> Result<(), KError> {
+    if RNG.is_completed() {
+        return Err(KError::AlreadyPresent);
+    }
+
+    let transport = LegacyTransport::new(dev)?;
+    transport.negotiate(0);
+    let memory = DmaBuffer::new(LARGE_PAGE_SIZE)?;
+    let queue = transport.setup_queue(0, &memory, 0, QUEUE_MEMORY, NO_VECTOR)?;
+    transport.driver_ok()?;
+
+    RNG.call_once(|| VirtioRng {
+        transport,
+        memory,
+        queue: Mutex::new(queue),
+        stalled: AtomicBool::new(false),
+    });
+    info!("virtio-rng attached");
+    entropy::register_source("virtio-rng", read)
+}
@@ -0,0 +1,312 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Driver for virtio socket devices (vsock).
+//!
+//! The device moves packets between the guest and the host (CID 2), the
+//! connections themselves are handled by `net::vsock`. We hand it every
+//! packet we receive and send what it gives us.
+//!
+//! Like the console, sending is synchronous: we wait until the device
+//! consumed the buffer. Received packets arrive on an MSI-X interrupt (or
+//! when `poll` is called, which `net::vsock` does before every operation).
+
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use super::{ModernTransport, Virtqueue, NO_VECTOR, VENDOR_ID};
+use crate::arch::Platform;
+use crate::arch_interface::Arch;
+use crate::drivers::pci::{self, Bar, PciDevice, PciDriver, PciMatch};
+use crate::error::KError;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::vspace::MapAction;
+use crate::memory::{PAddr, LARGE_PAGE_SIZE};
+
+/// The PCI driver (vsock devices are modern-only).
+pub static DRIVER: PciDriver = PciDriver {
+    name: "virtio-vsock",
+    ids: &[PciMatch::Device {
+        vendor: VENDOR_ID,
+        device: 0x1053,
+    }],
+    attach,
+};
+
+/// The CID of the host.
+pub const HOST_CID: u64 = 2;
+
+/// Offset of `guest_cid` in the device configuration.
+const CONFIG_GUEST_CID: usize = 0;
+
+/// The only socket type (`Header::ty`) devices have to support.
+pub const TYPE_STREAM: u16 = 1;
+
+// Operations (`Header::op`)
+pub const OP_REQUEST: u16 = 1;
+pub const OP_RESPONSE: u16 = 2;
+pub const OP_RST: u16 = 3;
+pub const OP_SHUTDOWN: u16 = 4;
+pub const OP_RW: u16 = 5;
+pub const OP_CREDIT_UPDATE: u16 = 6;
+pub const OP_CREDIT_REQUEST: u16 = 7;
+
+/// `Header::flags` of a shutdown: the sender won't receive anymore.
+pub const SHUTDOWN_RCV: u32 = 1;
+/// `Header::flags` of a shutdown: the sender won't send anymore.
+pub const SHUTDOWN_SEND: u32 = 2;
+
+/// The device lost all connections (e.g., after a migration).
+const EVENT_TRANSPORT_RESET: u32 = 0;
+
+/// The header of every packet (little-endian, like the rest of the device
+/// interface).
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Header {
+    pub src_cid: u64,
+    pub dst_cid: u64,
+    pub src_port: u32,
+    pub dst_port: u32,
+    /// Length of the payload.
+    pub len: u32,
+    pub ty: u16,
+    pub op: u16,
+    pub flags: u32,
+    /// Size of the receive buffer of the sender.
+    pub buf_alloc: u32,
+    /// How many bytes the sender consumed from its receive buffer.
+    pub fwd_cnt: u32,
+}
+static_assertions::const_assert_eq!(size_of::<Header>(), 44);
+
+const RX: u16 = 0;
+const TX: u16 = 1;
+const EVENT: u16 = 2;
+
+// Everything lives in one DMA buffer: the rings of queue `n` at
+// `n * QUEUE_MEMORY`, then the receive buffers, the transmit buffer and
+// the event buffers.
+const QUEUE_MEMORY: usize = 0x4000;
+const RX_BUFFERS: u16 = 64;
+const BUFFER_SIZE: usize = 4096;
+const RX_BASE: usize = 3 * QUEUE_MEMORY;
+const TX_BASE: usize = RX_BASE + RX_BUFFERS as usize * BUFFER_SIZE;
+const EVENT_BUFFERS: u16 = 4;
+const EVENT_BUFFER_SIZE: usize = 64;
+const EVENT_BASE: usize = TX_BASE + BUFFER_SIZE;
+static_assertions::const_assert!(
+    EVENT_BASE + EVENT_BUFFERS as usize * EVENT_BUFFER_SIZE <= LARGE_PAGE_SIZE
+);
+
+/// Largest payload of a packet (in either direction).
+pub const MAX_PAYLOAD: usize = BUFFER_SIZE - size_of::<Header>();
+
+/// How long we wait for the device to consume a packet.
+const TX_TIMEOUT: Duration = Duration::from_millis(100);
+
+fn rx_buffer(id: u16) -> usize {
+    RX_BASE + id as usize * BUFFER_SIZE
+}
+
+fn event_buffer(id: u16) -> usize {
+    EVENT_BASE + id as usize * EVENT_BUFFER_SIZE
+}
+
+struct VirtioVsock {
+    transport: ModernTransport,
+    memory: DmaBuffer,
+    /// Our CID (can change after a transport reset).
+    cid: AtomicU64,
+    /// The device didn't consume a packet in time, we stop sending.
+    stalled: AtomicBool,
+    /// The receive and the event queue.
+    ///
+    /// Lock order: `rx` before the connections of `net::vsock` before `tx`.
+    rx: Mutex<(Virtqueue, Virtqueue)>,
+    tx: Mutex<Virtqueue>,
+}
+
+/// We only support one device.
+static VSOCK: Once<VirtioVsock> = Once::new();
+
+/// Our CID, `None` if there is no device.
+pub fn guest_cid() -> Option<u64> {
+    VSOCK.get().map(|vsock| vsock.cid.load(Ordering::Relaxed))
+}
+
+/// Sends a packet (fills in `src_cid` and `len`).
+pub fn send(header: &Header, payload: &[u8]) -> Result<(), KError> {
+    let vsock = VSOCK.get().ok_or(KError::VsockUnavailable)?;
+    if payload.len() > MAX_PAYLOAD {
+        return Err(KError::InvalidLayout);
+    }
+    if vsock.stalled.load(Ordering::Relaxed) {
+        return Err(KError::VsockTimeout);
+    }
+
+    let header = Header {
+        src_cid: vsock.cid.load(Ordering::Relaxed),
+        len: payload.len() as u32,
+        ..*header
+    };
+    let mut tx = vsock.tx.lock();
+    unsafe {
+        let buffer = vsock.memory.as_ptr::<u8>().add(TX_BASE);
+        ptr::write_unaligned(buffer as *mut Header, header);
+        ptr::copy_nonoverlapping(
+            payload.as_ptr(),
+            buffer.add(size_of::<Header>()),
+            payload.len(),
+        );
+    }
+    let len = size_of::<Header>() + payload.len();
+    tx.set(0, vsock.memory.paddr() + TX_BASE, len as u32, false);
+    tx.submit(0);
+    vsock.transport.notify(&tx);
+
+    let start = rawtime::Instant::now();
+    while tx.pop_used().is_none() {
+        if start.elapsed() > TX_TIMEOUT {
+            // The buffer still belongs to the device
+            vsock.stalled.store(true, Ordering::Relaxed);
+            warn!("virtio-vsock: device doesn't consume packets");
+            return Err(KError::VsockTimeout);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// Passes every packet the device sent to `net::vsock`.
+pub fn poll() {
+    let vsock = match VSOCK.get() {
+        Some(vsock) => vsock,
+        None => return,
+    };
+    let mut rx = vsock.rx.lock();
+    let (rx, events) = &mut *rx;
+
+    let mut returned = false;
+    while let Some((id, len)) = rx.pop_used() {
+        let data = &vsock.memory.as_slice()[rx_buffer(id)..rx_buffer(id) + BUFFER_SIZE];
+        let len = (len as usize).min(BUFFER_SIZE);
+        if len >= size_of::<Header>() {
+            let header: Header = unsafe { ptr::read_unaligned(data.as_ptr() as *const _) };
+            let payload_len = (header.len as usize).min(len - size_of::<Header>());
+            let payload = &data[size_of::<Header>()..size_of::<Header>() + payload_len];
+            crate::net::vsock::receive(&header, payload);
+        }
+
+        // Give the buffer back
+        rx.submit(id);
+        returned = true;
+    }
+    if returned {
+        vsock.transport.notify(rx);
+        // Connections might be ready now
+        crate::arch::futex::wake_pollers();
+    }
+
+    returned = false;
+    while let Some((id, _len)) = events.pop_used() {
+        let event = unsafe {
+            ptr::read_volatile(vsock.memory.as_ptr::<u8>().add(event_buffer(id)) as *const u32)
+        };
+        if event == EVENT_TRANSPORT_RESET {
+            let cid = read_cid(&vsock.transport);
+            vsock.cid.store(cid, Ordering::Relaxed);
+            info!("virtio-vsock: transport reset, CID is {}", cid);
+            crate::net::vsock::reset();
+        }
+        events.submit(id);
+        returned = true;
+    }
+    if returned {
+        vsock.transport.notify(events);
+    }
+}
+
+fn read_cid(transport: &ModernTransport) -> u64 {
+    // Only the lower 32 bits are in use (the upper half is reserved)
+    transport.config32(CONFIG_GUEST_CID) as u64
+}
+
+fn interrupt(_vector: u8) {
+    poll();
+}
+
+/// Routes MSI-X table entry 0 (which we use for all queues) to the BSP.
+fn setup_msix(dev: &PciDevice, transport: &mut ModernTransport) -> Result<(), KError> {
+    let table_bar = match dev.msix() {
+        Some(pci::Capability::MsiX { table, .. }) => table.0 as usize,
+        _ => return Err(KError::MsiUnsupported),
+    };
+    if let Some(Some(Bar::Memory { base, size, .. })) = dev.bars.get(table_bar) {
+        Platform::map_kernel_identity(
+            PAddr::from(*base),
+            *size as usize,
+            MapAction::ReadWriteKernel,
+        )?;
+    }
+
+    crate::arch::irq::route_msix(dev, 0, 0, interrupt)?;
+    pci::enable_msix(dev)?;
+    transport.enable_msix(NO_VECTOR)
+}
+
+fn attach(dev: &PciDevice) -> Result<(), KError> {
+    if VSOCK.is_completed() {
+        return Err(KError::AlreadyPresent);
+    }
+
+    let mut transport = ModernTransport::new(dev)?;
+    transport.negotiate(0)?;
+    let vector = match setup_msix(dev, &mut transport) {
+        Ok(()) => 0,
+        Err(e) => {
+            warn!("virtio-vsock: polling for packets without MSI-X ({})", e);
+            NO_VECTOR
+        }
+    };
+
+    let memory = DmaBuffer::new(LARGE_PAGE_SIZE)?;
+    let mut setup = |index: u16| {
+        let offset = index as usize * QUEUE_MEMORY;
+        transport.setup_queue(index, &memory, offset, QUEUE_MEMORY, vector)
+    };
+    let mut rx = setup(RX)?;
+    let tx = setup(TX)?;
+    let mut events = setup(EVENT)?;
+
+    for id in 0..RX_BUFFERS.min(rx.size()) {
+        rx.set(id, memory.paddr() + rx_buffer(id), BUFFER_SIZE as u32, true);
+        rx.submit(id);
+    }
+    for id in 0..EVENT_BUFFERS.min(events.size()) {
+        let paddr = memory.paddr() + event_buffer(id);
+        events.set(id, paddr, EVENT_BUFFER_SIZE as u32, true);
+        events.submit(id);
+    }
+
+    transport.driver_ok()?;
+    transport.notify(&rx);
+    transport.notify(&events);
+
+    let cid = read_cid(&transport);
+    VSOCK.call_once(|| VirtioVsock {
+        transport,
+        memory,
+        cid: AtomicU64::new(cid),
+        stalled: AtomicBool::new(false),
+        rx: Mutex::new((rx, events)),
+        tx: Mutex::new(tx),
+    });
+    info!("virtio-vsock: CID {}", cid);
+    Ok(())
+}
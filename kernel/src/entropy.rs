@@ -0,0 +1,267 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The kernel entropy pool.
+//!
+//! Random numbers come from a ChaCha20 based generator that replaces its
+//! key with fresh output at the end of every request ("fast key erasure"),
+//! so earlier output can't be recovered from the state. Entropy sources
+//! (RDSEED/RDRAND, virtio-rng) register with `register_source`, we mix
+//! their output into the key right away and again after we handed out
+//! `RESEED_INTERVAL` bytes.
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use arrayvec::ArrayVec;
+use log::{info, warn};
+use spin::{Mutex, RwLock};
+
+use crate::error::KError;
+
+/// Fills the buffer with random bytes, returns how many it wrote.
+pub type Source = fn(&mut [u8]) -> usize;
+
+/// Maximum number of registered entropy sources.
+const MAX_SOURCES: usize = 4;
+
+/// How much we read from every source when we reseed.
+const SEED_SIZE: usize = 32;
+
+/// Bytes of output after which we reseed.
+const RESEED_INTERVAL: usize = 1 << 20;
+
+/// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block function (RFC 7539).
+fn chacha20_block(key: &[u32; 8], counter: u32, nonce: [u32; 3]) -> [u32; 16] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter;
+    input[13..].copy_from_slice(&nonce);
+
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, input) in state.iter_mut().zip(input.iter()) {
+        *word = word.wrapping_add(*input);
+    }
+    state
+}
+
+struct Pool {
+    key: [u32; 8],
+    /// Blocks generated with the current key.
+    counter: u64,
+    /// We mixed in something from an entropy source.
+    seeded: bool,
+    /// Bytes handed out since the last reseed.
+    output: usize,
+}
+
+impl Pool {
+    const fn new() -> Pool {
+        Pool {
+            key: [0; 8],
+            counter: 0,
+            seeded: false,
+            output: 0,
+        }
+    }
+
+    fn block(&mut self) -> [u32; 16] {
+        let nonce = [(self.counter >> 32) as u32, 0, 0];
+        let block = chacha20_block(&self.key, self.counter as u32, nonce);
+        self.counter += 1;
+        block
+    }
+
+    /// Replaces the key with generator output.
+    fn rekey(&mut self) {
+        let block = self.block();
+        self.key.copy_from_slice(&block[..8]);
+        self.counter = 0;
+    }
+
+    /// Mixes `data` into the key.
+    fn mix(&mut self, data: &[u8]) {
+        for chunk in data.chunks(32) {
+            for (i, byte) in chunk.iter().enumerate() {
+                self.key[i / 4] ^= (*byte as u32) << (8 * (i % 4));
+            }
+            self.rekey();
+        }
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(64) {
+            let block = self.block();
+            for (i, byte) in chunk.iter_mut().enumerate() {
+                *byte = (block[i / 4] >> (8 * (i % 4))) as u8;
+            }
+        }
+        self.rekey();
+        self.output += buf.len();
+    }
+}
+
+static POOL: Mutex<Pool> = Mutex::new(Pool::new());
+
+static SOURCES: RwLock<ArrayVec<(&'static str, Source), MAX_SOURCES>> =
+    RwLock::new(ArrayVec::new_const());
+
+/// Mixes `data` into the pool (it doesn't have to be random, it just
+/// can't make things worse).
+pub fn add_entropy(data: &[u8]) {
+    POOL.lock().mix(data);
+}
+
+/// Reads from `source` and mixes it into the pool, returns whether the
+/// source produced anything.
+fn seed_from(source: Source) -> bool {
+    let mut seed = [0u8; SEED_SIZE];
+    let read = source(&mut seed).min(SEED_SIZE);
+    add_entropy(&seed[..read]);
+    read > 0
+}
+
+/// Reads from all sources (and the TSC as a weak last resort).
+fn reseed() {
+    let mut seeded = false;
+    for (_name, source) in SOURCES.read().iter() {
+        seeded |= seed_from(*source);
+    }
+    add_entropy(&unsafe { x86::time::rdtsc() }.to_le_bytes());
+
+    let mut pool = POOL.lock();
+    if !seeded && !pool.seeded {
+        warn!("No entropy source available, random numbers are predictable");
+    }
+    pool.seeded = true;
+    pool.output = 0;
+}
+
+/// Adds `source` to the sources we reseed from (and seeds from it now).
+pub fn register_source(name: &'static str, source: Source) -> Result<(), KError> {
+    SOURCES
+        .write()
+        .try_push((name, source))
+        .map_err(|_| KError::CapacityOverflow)?;
+    if seed_from(source) {
+        POOL.lock().seeded = true;
+    }
+    info!("Registered entropy source {}", name);
+    Ok(())
+}
+
+/// Fills `buf` with random bytes.
+pub fn fill(buf: &mut [u8]) {
+    let needs_reseed = {
+        let pool = POOL.lock();
+        !pool.seeded || pool.output >= RESEED_INTERVAL
+    };
+    if needs_reseed {
+        reseed();
+    }
+    POOL.lock().fill(buf);
+}
+
+/// A random number (e.g., for address space layouts or sequence numbers).
+#[cfg_attr(not(feature = "smoltcp"), allow(dead_code))]
+pub fn rand() -> u64 {
+    let mut bytes = [0u8; 8];
+    fill(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Test vector from RFC 7539, section 2.3.2.
+    #[test]
+    fn chacha20() {
+        let key = [
+            0x0302_0100,
+            0x0706_0504,
+            0x0b0a_0908,
+            0x0f0e_0d0c,
+            0x1312_1110,
+            0x1716_1514,
+            0x1b1a_1918,
+            0x1f1e_1d1c,
+        ];
+        let block = chacha20_block(&key, 1, [0x0900_0000, 0x4a00_0000, 0]);
+        assert_eq!(
+            block,
+            [
+                0xe4e7_f110,
+                0x1559_3bd1,
+                0x1fdd_0f50,
+                0xc471_20a3,
+                0xc7f4_d1c7,
+                0x0368_c033,
+                0x9aaa_2204,
+                0x4e6c_d4c3,
+                0x4664_82d2,
+                0x09aa_9f07,
+                0x05d7_c214,
+                0xa202_8bd9,
+                0xd19c_12b5,
+                0xb94e_16de,
+                0xe883_d0cb,
+                0x4e3c_50a2,
+            ]
+        );
+    }
+
+    #[test]
+    fn pool() {
+        let (mut a, mut b) = (Pool::new(), Pool::new());
+        let (mut out_a, mut out_b) = ([0u8; 100], [0u8; 100]);
+
+        // Same seed, same output
+        a.mix(b"seed");
+        b.mix(b"seed");
+        a.fill(&mut out_a);
+        b.fill(&mut out_b);
+        assert_eq!(out_a[..], out_b[..]);
+        assert_eq!(a.output, 100);
+
+        // Output doesn't repeat
+        let previous = out_a;
+        a.fill(&mut out_a);
+        assert_ne!(out_a[..], previous[..]);
+
+        // Mixing changes the output
+        a.mix(b"more");
+        b.mix(b"less");
+        a.fill(&mut out_a);
+        b.fill(&mut out_b);
+        assert_ne!(out_a[..], out_b[..]);
+    }
+
+    #[test]
+    fn rand_differs() {
+        assert_ne!(rand(), rand());
+    }
+}
@@ -27,6 +27,13 @@ pub enum KError {
     InvalidVSpaceOperation { a: u64 },
     InvalidProcessOperation { a: u64 },
     InvalidSystemOperation { a: u64 },
+    InvalidNetworkOperation { a: u64 },
+    InvalidTimeOperation { a: u64 },
+    InvalidDebugOperation { a: u64 },
+    InvalidPerfOperation { a: u64 },
+    InvalidCapOperation { a: u64 },
+    InvalidIpcOperation { a: u64 },
+    InvalidVmOperation { a: u64 },
 
     // Physical memory errors
     InvalidLayout,
@@ -42,6 +49,7 @@ pub enum KError {
     ProcessLoadingFailed,
     ProcessCreate,
     NoProcessFoundForPid,
+    ProcessRunning,
     UnableToLoad,
     UnableToParseElf,
     NoExecutorAllocated,
@@ -63,6 +71,7 @@ pub enum KError {
     NotMapped,
     InvalidLength,
     InvalidBase,
+    InvalidMemoryRights,
 
     // File IO
     InvalidFile,
@@ -74,6 +83,168 @@ pub enum KError {
     OpenFileLimit,
     FileDescForPidAlreadyAdded,
     NoFileDescForPid,
+
+    // Network errors
+    InvalidNetConfig,
+    NetDeviceUnavailable,
+    DhcpTimeout,
+    NetStackUnavailable,
+    InvalidSocket,
+    SocketError,
+    WouldBlock,
+    PingTimeout,
+
+    // RPC errors
+    InvalidRpcMessage,
+    RpcServiceAlreadyRegistered,
+    RpcServiceNotFound,
+    RpcFailed,
+    RpcTimeout,
+
+    // Interrupt/device errors
+    OutOfVectors,
+    PciUnavailable,
+    MsiUnsupported,
+    InvalidMsiEntry,
+
+    // Block device errors
+    InvalidBlockRange,
+    DeviceTimeout,
+    NvmeControllerFailed,
+    NvmeCommandFailed { status: u16 },
+    AhciCommandFailed { status: u32 },
+
+    // Time errors
+    ClockUnavailable,
+
+    // ACPI errors
+    AcpiUnavailable,
+    AcpiTableNotFound,
+    InvalidAcpiTable,
+
+    // Hotplug errors
+    CoreOffline,
+    CoreOnline,
+    CoreNotParkable,
+    CoreBusy,
+
+    // Scheduling errors
+    InvalidCorePolicy,
+
+    // Replication errors
+    ReplicaFull,
+
+    // Capability errors
+    InvalidCapability,
+    InsufficientRights,
+    TooManyCapabilities,
+
+    // Credential errors
+    NotPrivileged,
+
+    // Performance counter errors
+    PmuUnavailable,
+    InvalidCounter,
+    CounterBusy,
+    CounterNotStarted,
+
+    // Crash dump errors
+    InvalidCrashDumpTarget,
+    CrashDumpTargetNotFound,
+
+    // Log errors
+    InvalidLogFilter,
+
+    // kprobe errors
+    UnknownKprobe,
+    InvalidKprobeMode,
+
+    // Syscall trace errors
+    TraceBusy,
+
+    // Futex errors
+    FutexUnaligned,
+    FutexTimeout,
+    TooManyFutexWaiters,
+
+    // Door errors
+    InvalidDoorName,
+    DoorExists,
+    DoorNotFound,
+    TooManyDoors,
+    TooManyCalls,
+    InvalidCall,
+
+    // Event errors
+    EventNotFound,
+    EventOverflow,
+    TooManyEvents,
+
+    // Interval timer errors
+    TimerNotFound,
+    TooManyTimers,
+
+    // Frequency scaling errors
+    CpufreqUnavailable,
+    InvalidFrequency,
+
+    // User-space page fault errors
+    InvalidFaultRegion,
+    FaultRegionOverlaps,
+    FaultRegionNotFound,
+    TooManyFaultRegions,
+
+    // Initrd errors
+    UnknownInitrdFormat,
+    MalformedInitrd,
+
+    // Suspend and kexec errors
+    SuspendUnsupported,
+    NotOnBootCore,
+    InvalidWakeupTime,
+    SuspendFailed,
+    InvalidKernelImage,
+    KexecNoMemory,
+
+    // Power errors
+    InvalidExitCode,
+
+    // UEFI runtime service errors
+    EfiUnavailable,
+    EfiVariableNotFound,
+    EfiBufferTooSmall { needed: usize },
+    InvalidEfiVariableName,
+    EfiError { status: usize },
+
+    // Hypervisor errors
+    VmxUnavailable,
+    VmNotFound,
+    TooManyVms,
+    InvalidVcpu,
+    VcpuBusy,
+    VcpuOnOtherCore,
+    InvalidGuestAddress,
+    GuestAddressMapped,
+    VmxFailed { error: u64 },
+    VsockUnavailable,
+    VsockTimeout,
+    VsockPortInUse,
+
+    // Swap errors
+    SwapUnavailable,
+    SwapFull,
+    InvalidSwapTarget,
+    SwapTargetNotFound,
+    CorruptSwapData,
+
+    // File-system journal errors
+    InvalidJournalTarget,
+    JournalTargetNotFound,
+    JournalFull,
+
+    // Checkpoint errors
+    InvalidCheckpoint,
+    CheckpointNeedsOneCore,
 }
 
 impl From<CapacityError<crate::memory::Frame>> for KError {
@@ -133,7 +304,84 @@ impl From<KError> for SystemCallError {
             KError::InvalidSyscallArgument1 { .. } => SystemCallError::NotSupported,
             KError::InvalidVSpaceOperation { .. } => SystemCallError::NotSupported,
             KError::InvalidProcessOperation { .. } => SystemCallError::NotSupported,
+            KError::InvalidNetworkOperation { .. } => SystemCallError::NotSupported,
+            KError::InvalidTimeOperation { .. } => SystemCallError::NotSupported,
+            KError::InvalidDebugOperation { .. } => SystemCallError::NotSupported,
+            KError::ClockUnavailable => SystemCallError::NotSupported,
+            KError::AcpiUnavailable => SystemCallError::NotSupported,
             KError::BadAddress { .. } => SystemCallError::BadAddress,
+            KError::OutOfMemory => SystemCallError::OutOfMemory,
+            KError::CacheExhausted => SystemCallError::OutOfMemory,
+            KError::AlreadyMapped { .. } => SystemCallError::VSpaceAlreadyMapped,
+            KError::NetStackUnavailable => SystemCallError::NotSupported,
+            KError::InvalidSocket => SystemCallError::BadFileDescriptor,
+            KError::WouldBlock => SystemCallError::WouldBlock,
+            KError::PingTimeout => SystemCallError::TimedOut,
+            KError::RpcTimeout => SystemCallError::TimedOut,
+            KError::InvalidGlobalThreadId => SystemCallError::NotSupported,
+            KError::CoreOffline => SystemCallError::NotSupported,
+            KError::CoreOnline => SystemCallError::NotSupported,
+            KError::CoreNotParkable => SystemCallError::PermissionError,
+            KError::CoreBusy => SystemCallError::TimedOut,
+            KError::InvalidCorePolicy => SystemCallError::BadFlags,
+            KError::InvalidPerfOperation { .. } => SystemCallError::NotSupported,
+            KError::InvalidCapOperation { .. } => SystemCallError::NotSupported,
+            KError::InvalidCapability => SystemCallError::BadFileDescriptor,
+            KError::InsufficientRights => SystemCallError::PermissionError,
+            KError::TooManyCapabilities => SystemCallError::OutOfMemory,
+            KError::NotPrivileged => SystemCallError::PermissionError,
+            KError::PmuUnavailable => SystemCallError::NotSupported,
+            KError::InvalidCounter => SystemCallError::NotSupported,
+            KError::CounterBusy => SystemCallError::PermissionError,
+            KError::CounterNotStarted => SystemCallError::BadFlags,
+            KError::InvalidLogFilter => SystemCallError::BadFlags,
+            KError::UnknownKprobe => SystemCallError::NotSupported,
+            KError::InvalidKprobeMode => SystemCallError::BadFlags,
+            KError::TraceBusy => SystemCallError::PermissionError,
+            KError::FutexUnaligned => SystemCallError::BadAddress,
+            KError::FutexTimeout => SystemCallError::TimedOut,
+            KError::TooManyFutexWaiters => SystemCallError::OutOfMemory,
+            KError::InvalidIpcOperation { .. } => SystemCallError::NotSupported,
+            KError::InvalidDoorName => SystemCallError::BadFlags,
+            KError::DoorExists => SystemCallError::PermissionError,
+            KError::DoorNotFound => SystemCallError::BadFileDescriptor,
+            KError::TooManyDoors => SystemCallError::OutOfMemory,
+            KError::TooManyCalls => SystemCallError::WouldBlock,
+            KError::InvalidCall => SystemCallError::BadFlags,
+            KError::EventNotFound => SystemCallError::BadFileDescriptor,
+            KError::EventOverflow => SystemCallError::WouldBlock,
+            KError::TooManyEvents => SystemCallError::OutOfMemory,
+            KError::TimerNotFound => SystemCallError::BadFileDescriptor,
+            KError::TooManyTimers => SystemCallError::OutOfMemory,
+            KError::CpufreqUnavailable => SystemCallError::NotSupported,
+            KError::InvalidFrequency => SystemCallError::BadFlags,
+            KError::InvalidFaultRegion => SystemCallError::BadAddress,
+            KError::FaultRegionOverlaps => SystemCallError::VSpaceAlreadyMapped,
+            KError::FaultRegionNotFound => SystemCallError::BadAddress,
+            KError::TooManyFaultRegions => SystemCallError::OutOfMemory,
+            KError::InvalidMemoryRights => SystemCallError::BadFlags,
+            KError::SuspendUnsupported => SystemCallError::NotSupported,
+            KError::NotOnBootCore => SystemCallError::NotSupported,
+            KError::InvalidWakeupTime => SystemCallError::BadFlags,
+            KError::InvalidKernelImage => SystemCallError::BadFlags,
+            KError::KexecNoMemory => SystemCallError::OutOfMemory,
+            KError::InvalidExitCode => SystemCallError::BadFlags,
+            KError::InvalidVmOperation { .. } => SystemCallError::NotSupported,
+            KError::VmxUnavailable => SystemCallError::NotSupported,
+            KError::VmNotFound => SystemCallError::BadFileDescriptor,
+            KError::TooManyVms => SystemCallError::OutOfMemory,
+            KError::InvalidVcpu => SystemCallError::BadFlags,
+            KError::VcpuBusy => SystemCallError::WouldBlock,
+            KError::VcpuOnOtherCore => SystemCallError::PermissionError,
+            KError::InvalidGuestAddress => SystemCallError::BadAddress,
+            KError::GuestAddressMapped => SystemCallError::VSpaceAlreadyMapped,
+            KError::VsockUnavailable => SystemCallError::NotSupported,
+            KError::VsockTimeout => SystemCallError::TimedOut,
+            KError::VsockPortInUse => SystemCallError::PermissionError,
+            KError::SwapUnavailable => SystemCallError::NotSupported,
+            KError::SwapFull => SystemCallError::OutOfMemory,
+            KError::InvalidCheckpoint => SystemCallError::BadFlags,
+            KError::CheckpointNeedsOneCore => SystemCallError::WouldBlock,
             _ => SystemCallError::InternalError,
         }
     }
@@ -192,6 +440,55 @@ impl fmt::Display for KError {
                     a
                 )
             }
+            KError::InvalidNetworkOperation { a } => {
+                write!(
+                    f,
+                    "Invalid Network Operation (2nd syscall argument) supplied: {}",
+                    a
+                )
+            }
+            KError::InvalidTimeOperation { a } => {
+                write!(
+                    f,
+                    "Invalid Time Operation (2nd syscall argument) supplied: {}",
+                    a
+                )
+            }
+            KError::InvalidDebugOperation { a } => {
+                write!(
+                    f,
+                    "Invalid Debug Operation (2nd syscall argument) supplied: {}",
+                    a
+                )
+            }
+            KError::InvalidPerfOperation { a } => {
+                write!(
+                    f,
+                    "Invalid Perf Operation (2nd syscall argument) supplied: {}",
+                    a
+                )
+            }
+            KError::InvalidCapOperation { a } => {
+                write!(
+                    f,
+                    "Invalid Capability Operation (2nd syscall argument) supplied: {}",
+                    a
+                )
+            }
+            KError::InvalidIpcOperation { a } => {
+                write!(
+                    f,
+                    "Invalid Ipc Operation (2nd syscall argument) supplied: {}",
+                    a
+                )
+            }
+            KError::InvalidVmOperation { a } => {
+                write!(
+                    f,
+                    "Invalid Vm Operation (2nd syscall argument) supplied: {}",
+                    a
+                )
+            }
             KError::InvalidAffinityId => {
                 write!(f, "Specified an invalid NUMA node ID for affinity.")
             }
@@ -207,6 +504,7 @@ impl fmt::Display for KError {
 
             KError::ProcessCreate  => write!(f, "Unable to create process"),
             KError::NoProcessFoundForPid => write!(f, "No process was associated with the given Pid."),
+            KError::ProcessRunning => write!(f, "The process still runs on a core"),
             KError::UnableToLoad => write!(f, "Couldn't load process, invalid ELF file?"),
             KError::UnableToParseElf => write!(f, "Couldn't parse ELF file, invalid?"),
             KError::NoExecutorAllocated => write!(f, "We never allocated executors for this affinity region and process (need to fill cache)."),
@@ -226,6 +524,7 @@ impl fmt::Display for KError {
             KError::NotMapped => write!(f, "The requested mapping was not found"),
             KError::InvalidLength => write!(f, "The supplied length was invalid"),
             KError::InvalidBase => write!(f, "The supplied base was invalid (alignment?)"),
+            KError::InvalidMemoryRights => write!(f, "Unknown bits in the memory access rights"),
 
             KError::InvalidLayout => write!(f, "Invalid layout for allocator provided."),
             KError::CacheExhausted => write!(f, "Couldn't allocate bytes on this cache, need to re-grow first."),
@@ -242,6 +541,119 @@ impl fmt::Display for KError {
             KError::AlreadyPresent => write!(f, "Fd/File already exists"),
             KError::DirectoryError => write!(f, "Can't read or write to a directory"),
             KError::OpenFileLimit => write!(f, "Maximum files are opened for a process"),
+
+            KError::InvalidNetConfig => write!(f, "Invalid network configuration (expected net=dhcp or net=static:ip/prefix[,gw])"),
+            KError::NetDeviceUnavailable => write!(f, "Unable to initialize the network device"),
+            KError::DhcpTimeout => write!(f, "Didn't receive a DHCP lease in time"),
+            KError::NetStackUnavailable => write!(f, "The kernel network stack is not initialized"),
+            KError::InvalidSocket => write!(f, "Supplied socket descriptor was invalid"),
+            KError::SocketError => write!(f, "Socket operation failed (not bound/connected?)"),
+            KError::WouldBlock => write!(f, "Operation would block"),
+            KError::PingTimeout => write!(f, "Didn't receive an ICMP echo reply in time"),
+
+            KError::InvalidRpcMessage => write!(f, "Received a malformed RPC message"),
+            KError::RpcServiceAlreadyRegistered => write!(f, "An RPC service with this id is already registered"),
+            KError::RpcServiceNotFound => write!(f, "The remote kernel doesn't provide the requested RPC service"),
+            KError::RpcFailed => write!(f, "The remote RPC handler returned an error"),
+            KError::RpcTimeout => write!(f, "Didn't receive an RPC response in time"),
+
+            KError::OutOfVectors => write!(f, "All interrupt vectors for MSI/MSI-X are in use"),
+            KError::PciUnavailable => write!(f, "The PCI bus has not been enumerated"),
+            KError::MsiUnsupported => write!(f, "The device doesn't support MSI/MSI-X"),
+            KError::InvalidMsiEntry => write!(f, "Invalid MSI-X table entry (or table not in a memory BAR)"),
+
+            KError::InvalidBlockRange => write!(f, "Transfer is not a multiple of the block size or exceeds the device"),
+            KError::DeviceTimeout => write!(f, "The device didn't respond in time"),
+            KError::NvmeControllerFailed => write!(f, "The NVMe controller reported a fatal error"),
+            KError::NvmeCommandFailed { status } => write!(f, "NVMe command failed with status {:#x}", status),
+            KError::AhciCommandFailed { status } => write!(f, "ATA command failed with task file {:#x}", status),
+
+            KError::ClockUnavailable => write!(f, "The wall-clock time is not known"),
+
+            KError::AcpiUnavailable => write!(f, "Couldn't find (or parse) the ACPI tables"),
+            KError::AcpiTableNotFound => write!(f, "The firmware doesn't provide the ACPI table"),
+            KError::InvalidAcpiTable => write!(f, "ACPI table has a bad signature, length or checksum"),
+
+            KError::CoreOffline => write!(f, "The core is offline"),
+            KError::CoreOnline => write!(f, "The core is online"),
+            KError::CoreNotParkable => write!(f, "The core is needed by its replica and can't go offline"),
+            KError::CoreBusy => write!(f, "The core didn't give up its work in time"),
+
+            KError::InvalidCorePolicy => write!(f, "There is no such core policy"),
+
+            KError::ReplicaFull => write!(f, "The replica can't register more cores"),
+
+            KError::InvalidCapability => write!(f, "The handle doesn't refer to a capability of this kind"),
+            KError::InsufficientRights => write!(f, "The capability doesn't have the rights for this"),
+            KError::TooManyCapabilities => write!(f, "The capability table of the process is full"),
+            KError::NotPrivileged => write!(f, "Only privileged processes may do this"),
+
+            KError::PmuUnavailable => write!(f, "The core doesn't have performance counters"),
+            KError::InvalidCounter => write!(f, "The core doesn't have this performance counter"),
+            KError::CounterBusy => write!(f, "The performance counter is in use"),
+            KError::CounterNotStarted => write!(f, "The performance counter isn't counting"),
+            KError::InvalidCrashDumpTarget => write!(f, "Crash dump target should be `pmem`, `<device>` or `<device>:<lba>`"),
+            KError::CrashDumpTargetNotFound => write!(f, "Can't find the device or memory region for crash dumps"),
+            KError::InvalidLogFilter => write!(f, "Log filter should be a list of `level`, `path=level` or `path`"),
+            KError::UnknownKprobe => write!(f, "There is no kprobe with this name"),
+            KError::InvalidKprobeMode => write!(f, "kprobe mode should be off, count or trace"),
+            KError::TraceBusy => write!(f, "We already record the system calls of another process"),
+            KError::FutexUnaligned => write!(f, "A futex has to be 4 byte aligned"),
+            KError::FutexTimeout => write!(f, "Nobody woke up the futex in time"),
+            KError::TooManyFutexWaiters => write!(f, "Too many cores wait on futexes"),
+            KError::InvalidDoorName => write!(f, "A door name has to be 1 to 64 bytes of UTF-8"),
+            KError::DoorExists => write!(f, "There is a door with this name already"),
+            KError::DoorNotFound => write!(f, "There is no door with this name"),
+            KError::TooManyDoors => write!(f, "Can't create more doors"),
+            KError::TooManyCalls => write!(f, "Too many calls wait for the server of the door"),
+            KError::InvalidCall => write!(f, "The door has no call with this id for us"),
+            KError::EventNotFound => write!(f, "There is no event with this id"),
+            KError::EventOverflow => write!(f, "The counter of the event would overflow"),
+            KError::TooManyEvents => write!(f, "Can't create more events"),
+            KError::TimerNotFound => write!(f, "The process has no timer with this id"),
+            KError::TooManyTimers => write!(f, "Can't create more interval timers"),
+            KError::CpufreqUnavailable => write!(f, "We can't change the frequency of the cores"),
+            KError::InvalidFrequency => write!(f, "The core can't run at this frequency"),
+            KError::InvalidFaultRegion => write!(f, "A fault region has to be a non-empty part of user-space"),
+            KError::FaultRegionOverlaps => write!(f, "The fault region overlaps with one that is registered already"),
+            KError::FaultRegionNotFound => write!(f, "No fault region starts at this address"),
+            KError::TooManyFaultRegions => write!(f, "Too many fault regions are registered"),
+            KError::UnknownInitrdFormat => write!(f, "The initrd is neither a cpio (newc) nor a tar (ustar) archive"),
+            KError::MalformedInitrd => write!(f, "The initrd archive is truncated or has an invalid header"),
+            KError::SuspendUnsupported => write!(f, "The firmware doesn't support suspend-to-RAM"),
+            KError::NotOnBootCore => write!(f, "Only the boot core can do this"),
+            KError::InvalidWakeupTime => write!(f, "We can wake up between a second and a day from now"),
+            KError::SuspendFailed => write!(f, "The firmware didn't suspend the machine"),
+            KError::InvalidKernelImage => write!(f, "The kernel image isn't a relocatable x86-64 ELF we can boot"),
+            KError::KexecNoMemory => write!(f, "Not enough free memory to stage the new kernel"),
+            KError::InvalidExitCode => write!(f, "The exit code has to be below 128"),
+            KError::EfiUnavailable => write!(f, "The UEFI runtime services aren't available"),
+            KError::EfiVariableNotFound => write!(f, "There is no UEFI variable with this name"),
+            KError::EfiBufferTooSmall { needed } => write!(f, "The UEFI variable needs a buffer of {} bytes", needed),
+            KError::InvalidEfiVariableName => write!(f, "UEFI variable names are at most 127 UCS-2 characters"),
+            KError::EfiError { status } => write!(f, "The UEFI firmware returned status {:#x}", status),
+            KError::VmxUnavailable => write!(f, "The core can't run guests (no VT-x with EPT and unrestricted guests)"),
+            KError::VmNotFound => write!(f, "There is no VM with this id"),
+            KError::TooManyVms => write!(f, "Can't create more VMs"),
+            KError::InvalidVcpu => write!(f, "The VM has no vCPU with this index"),
+            KError::VcpuBusy => write!(f, "Another core runs the vCPU right now"),
+            KError::VcpuOnOtherCore => write!(f, "The vCPU runs on another core"),
+            KError::InvalidGuestAddress => write!(f, "The guest-physical address isn't aligned to the frame or out of range"),
+            KError::GuestAddressMapped => write!(f, "Something is mapped at this guest-physical address already"),
+            KError::VmxFailed { error } => write!(f, "A VMX instruction failed with error {}", error),
+            KError::VsockUnavailable => write!(f, "There is no virtio-vsock device"),
+            KError::VsockTimeout => write!(f, "The virtio-vsock device doesn't consume packets"),
+            KError::VsockPortInUse => write!(f, "Somebody listens on this vsock port already"),
+            KError::SwapUnavailable => write!(f, "Neither a zswap pool nor a swap device is configured"),
+            KError::SwapFull => write!(f, "The zswap pool and the swap device are full"),
+            KError::InvalidSwapTarget => write!(f, "Swap target should be `<device>` or `<device>:<lba>`"),
+            KError::SwapTargetNotFound => write!(f, "There is no block device with this name"),
+            KError::CorruptSwapData => write!(f, "An evicted page doesn't decompress to a page"),
+            KError::InvalidJournalTarget => write!(f, "Journal target should be `<device>` or `<device>:<lba>`"),
+            KError::JournalTargetNotFound => write!(f, "There is no block device with this name"),
+            KError::JournalFull => write!(f, "The file-system metadata doesn't fit in the journal"),
+            KError::InvalidCheckpoint => write!(f, "Not a checkpoint (or not one of this binary)"),
+            KError::CheckpointNeedsOneCore => write!(f, "The process has to give up all other cores before a checkpoint"),
         }
     }
 }
@@ -0,0 +1,265 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Event counters (see `kpi::event`).
+//!
+//! The table keeps the counters, the cores that wait for them and their
+//! timers. Halting and waking up cores is up to the architecture (see
+//! `arch::futex`): `take` remembers the waiting core, `signal` and `expire`
+//! return the cores to wake up. Timer deadlines are in whatever unit the
+//! caller uses for "now" (TSC ticks on x86).
+//!
+//! Like doors, events stay until the kernel shuts down.
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::vec::Vec;
+
+use fallible_collections::FallibleVec;
+use kpi::event::MAX_EVENT_VALUE;
+use spin::Mutex;
+
+use crate::error::KError;
+
+/// How many events there can be.
+pub const MAX_EVENTS: usize = 1024;
+
+pub type EventId = usize;
+
+#[derive(Debug)]
+struct Event {
+    value: u64,
+    /// Waiting takes 1 instead of everything.
+    semaphore: bool,
+    /// Cores that wait for the counter to go up.
+    waiters: Vec<usize>,
+    /// When the timer signals the event.
+    deadline: Option<u64>,
+}
+
+impl Event {
+    /// Adds `n`, returns the cores to wake up.
+    fn signal(&mut self, n: u64) -> Result<Vec<usize>, KError> {
+        match self.value.checked_add(n) {
+            Some(value) if value <= MAX_EVENT_VALUE => {
+                self.value = value;
+                Ok(if value > 0 {
+                    core::mem::take(&mut self.waiters)
+                } else {
+                    Vec::new()
+                })
+            }
+            _ => Err(KError::EventOverflow),
+        }
+    }
+}
+
+/// All events, an event id is its index.
+#[derive(Debug, Default)]
+pub struct Events {
+    events: Vec<Event>,
+}
+
+impl Events {
+    pub const fn new() -> Events {
+        Events { events: Vec::new() }
+    }
+
+    fn event(&mut self, event: EventId) -> Result<&mut Event, KError> {
+        self.events.get_mut(event).ok_or(KError::EventNotFound)
+    }
+
+    pub fn create(&mut self, initial: u64, semaphore: bool) -> Result<EventId, KError> {
+        if initial > MAX_EVENT_VALUE {
+            return Err(KError::EventOverflow);
+        }
+        if self.events.len() >= MAX_EVENTS {
+            return Err(KError::TooManyEvents);
+        }
+
+        self.events.try_push(Event {
+            value: initial,
+            semaphore,
+            waiters: Vec::new(),
+            deadline: None,
+        })?;
+        Ok(self.events.len() - 1)
+    }
+
+    /// Adds `n` to the counter of `event`, returns the cores that waited for
+    /// it.
+    pub fn signal(&mut self, event: EventId, n: u64) -> Result<Vec<usize>, KError> {
+        self.event(event)?.signal(n)
+    }
+
+    /// Takes the counter of `event` (or 1 of it) unless it's 0. If it is,
+    /// `core` (if any) waits for it.
+    pub fn take(&mut self, event: EventId, core: Option<usize>) -> Result<Option<u64>, KError> {
+        let event = self.event(event)?;
+        if event.value > 0 {
+            let taken = if event.semaphore { 1 } else { event.value };
+            event.value -= taken;
+            return Ok(Some(taken));
+        }
+
+        if let Some(core) = core {
+            if !event.waiters.contains(&core) {
+                event.waiters.try_push(core)?;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Can `event` be taken and can it be signaled (by 1)?
+    pub fn readiness(&mut self, event: EventId) -> Result<(bool, bool), KError> {
+        let event = self.event(event)?;
+        Ok((event.value > 0, event.value < MAX_EVENT_VALUE))
+    }
+
+    /// Signals `event` (by 1) at `deadline` (never for `None`).
+    pub fn set_timer(&mut self, event: EventId, deadline: Option<u64>) -> Result<(), KError> {
+        self.event(event)?.deadline = deadline;
+        Ok(())
+    }
+
+    /// The earliest timer.
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.events.iter().filter_map(|e| e.deadline).min()
+    }
+
+    /// Signals the events with timers that expired at `now`, returns the
+    /// cores to wake up.
+    pub fn expire(&mut self, now: u64) -> Vec<usize> {
+        let mut cores = Vec::new();
+        for event in self.events.iter_mut() {
+            if event.deadline.map_or(false, |d| d <= now) {
+                event.deadline = None;
+                // A full counter misses the tick
+                if let Ok(waiters) = event.signal(1) {
+                    cores.extend(waiters);
+                }
+            }
+        }
+        cores
+    }
+}
+
+/// The events of all processes.
+pub static EVENTS: Mutex<Events> = Mutex::new(Events::new());
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn counter() {
+        let mut events = Events::default();
+        let e = events.create(0, false).unwrap();
+        assert_eq!(events.take(e, None), Ok(None));
+        assert_eq!(events.signal(e, 2), Ok(Vec::new()));
+        assert_eq!(events.signal(e, 3), Ok(Vec::new()));
+        assert_eq!(events.take(e, None), Ok(Some(5)));
+        assert_eq!(events.take(e, None), Ok(None));
+        assert_eq!(events.take(e + 1, None), Err(KError::EventNotFound));
+    }
+
+    #[test]
+    fn semaphore() {
+        let mut events = Events::default();
+        let e = events.create(2, true).unwrap();
+        assert_eq!(events.take(e, None), Ok(Some(1)));
+        assert_eq!(events.take(e, None), Ok(Some(1)));
+        assert_eq!(events.take(e, None), Ok(None));
+    }
+
+    #[test]
+    fn waiters() {
+        let mut events = Events::default();
+        let e = events.create(0, false).unwrap();
+        assert_eq!(events.take(e, Some(1)), Ok(None));
+        assert_eq!(events.take(e, Some(3)), Ok(None));
+        assert_eq!(events.take(e, Some(1)), Ok(None));
+        // Signaling 0 doesn't wake anyone up
+        assert_eq!(events.signal(e, 0), Ok(Vec::new()));
+        assert_eq!(events.signal(e, 1), Ok(vec![1, 3]));
+        assert_eq!(events.signal(e, 1), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn overflow() {
+        let mut events = Events::default();
+        let e = events.create(MAX_EVENT_VALUE - 1, false).unwrap();
+        assert_eq!(events.readiness(e), Ok((true, true)));
+        assert_eq!(events.signal(e, 1), Ok(Vec::new()));
+        assert_eq!(events.readiness(e), Ok((true, false)));
+        assert_eq!(events.signal(e, 1), Err(KError::EventOverflow));
+        assert_eq!(events.take(e, None), Ok(Some(MAX_EVENT_VALUE)));
+        assert_eq!(events.readiness(e), Ok((false, true)));
+    }
+
+    #[test]
+    fn timers() {
+        let mut events = Events::default();
+        let a = events.create(0, false).unwrap();
+        let b = events.create(0, false).unwrap();
+        assert_eq!(events.next_deadline(), None);
+        events.set_timer(a, Some(200)).unwrap();
+        events.set_timer(b, Some(100)).unwrap();
+        assert_eq!(events.take(a, Some(4)), Ok(None));
+        assert_eq!(events.next_deadline(), Some(100));
+
+        assert_eq!(events.expire(150), Vec::<usize>::new());
+        assert_eq!(events.take(b, None), Ok(Some(1)));
+        assert_eq!(events.expire(300), vec![4]);
+        assert_eq!(events.take(a, None), Ok(Some(1)));
+        assert_eq!(events.next_deadline(), None);
+    }
+}
+
+#[cfg(all(test, feature = "loom"))]
+mod model {
+    use alloc::sync::Arc;
+
+    use loom::thread;
+
+    use super::*;
+    use crate::sync::atomic::{AtomicBool, Ordering};
+    use crate::sync::spin_loop;
+
+    /// A core that waits for an event wakes up if another core signals it
+    /// at the same time.
+    ///
+    /// Like `arch::futex` does it: the waiter takes the event or registers
+    /// (both under the lock of the table) before it parks until an IPI
+    /// arrives. An IPI that arrives before it parked stays pending.
+    #[test]
+    fn loom_event_wakeup() {
+        crate::sync::model(|| {
+            let events = Arc::new(crate::sync::Mutex::new("events", Events::default()));
+            let event = events.lock().create(0, false).unwrap();
+            // IPI for core 1
+            let pending = Arc::new(AtomicBool::new(false));
+
+            let waiter = {
+                let (events, pending) = (events.clone(), pending.clone());
+                thread::spawn(move || loop {
+                    let taken = events.lock().take(event, Some(1)).unwrap();
+                    if let Some(taken) = taken {
+                        return taken;
+                    }
+                    while !pending.swap(false, Ordering::Acquire) {
+                        spin_loop();
+                    }
+                })
+            };
+
+            let cores = events.lock().signal(event, 1).unwrap();
+            for core in cores {
+                assert_eq!(core, 1);
+                pending.store(true, Ordering::Release);
+            }
+            assert_eq!(waiter.join().unwrap(), 1);
+        });
+    }
+}
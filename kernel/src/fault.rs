@@ -0,0 +1,219 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Fault injection (feature `fault-injection`, enabled with `faults=<seed>`
+//! on the command-line).
+//!
+//! Things that rarely go wrong on a test machine go wrong a lot, at points
+//! picked at random:
+//!
+//! - System calls don't get the frames they ask for
+//!   ([`Fault::Allocation`]), like when memory runs out.
+//! - Cores get an interrupt nobody sent when they return from a system call
+//!   ([`Fault::Interrupt`]): a TLB work-queue IPI with nothing in the queue.
+//! - IPIs for TLB shootdowns arrive late ([`Fault::IpiDelay`]): we spin for
+//!   a while before we send them.
+//!
+//! Every core draws from its own generator, seeded with the seed and its id,
+//! so running the same thing with the same seed injects the same faults (as
+//! long as the cores do things in the same order). `/proc/faults` says how
+//! many we injected.
+//!
+//! Faults are only injected where the kernel can handle them without
+//! changing the outcome of an operation on the replicas (those have to come
+//! out the same on every replica).
+
+#![cfg_attr(
+    any(not(target_os = "none"), not(feature = "fault-injection")),
+    allow(dead_code)
+)]
+
+#[cfg(feature = "fault-injection")]
+use alloc::string::String;
+#[cfg(feature = "fault-injection")]
+use core::fmt::{self, Write};
+#[cfg(feature = "fault-injection")]
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+#[cfg(feature = "fault-injection")]
+use crate::arch::MAX_CORES;
+use crate::error::KError;
+#[cfg(feature = "fault-injection")]
+use crate::kcb::ArchSpecificKcb;
+
+/// Something that can go wrong.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Fault {
+    /// A system call doesn't get memory.
+    Allocation,
+    /// A spurious interrupt.
+    Interrupt,
+    /// A late IPI.
+    IpiDelay,
+}
+
+impl Fault {
+    const ALL: [Fault; 3] = [Fault::Allocation, Fault::Interrupt, Fault::IpiDelay];
+
+    /// One in how many chances to inject it we take.
+    fn odds(self) -> u64 {
+        match self {
+            // Often enough to hit every allocation of a process, rarely
+            // enough for it to still make progress
+            Fault::Allocation => 16,
+            Fault::Interrupt => 8,
+            Fault::IpiDelay => 4,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Fault::Allocation => "allocation",
+            Fault::Interrupt => "interrupt",
+            Fault::IpiDelay => "ipi-delay",
+        }
+    }
+}
+
+/// Longest we delay an IPI (in spins).
+#[cfg(feature = "fault-injection")]
+const MAX_IPI_DELAY: u64 = 1 << 16;
+
+#[cfg(feature = "fault-injection")]
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[allow(clippy::declare_interior_mutable_const)]
+#[cfg(feature = "fault-injection")]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+/// The generator of every core (only the core itself uses it).
+#[cfg(feature = "fault-injection")]
+static STATES: [AtomicU64; MAX_CORES] = [ZERO; MAX_CORES];
+/// How many faults of every kind we injected.
+#[cfg(feature = "fault-injection")]
+static INJECTED: [AtomicU64; Fault::ALL.len()] = [ZERO; Fault::ALL.len()];
+
+/// SplitMix64, for the seeds of the cores.
+#[cfg(feature = "fault-injection")]
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// xorshift64*, the generator of a core.
+#[cfg(feature = "fault-injection")]
+fn next(state: &AtomicU64) -> u64 {
+    let mut x = state.load(Ordering::Relaxed);
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    state.store(x, Ordering::Relaxed);
+    x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+}
+
+/// Starts injecting faults, `seed` picks where.
+#[cfg(feature = "fault-injection")]
+pub fn init(seed: u64) -> Result<(), KError> {
+    crate::procfs::register("/proc/faults", proc_faults)?;
+    for (core, state) in STATES.iter().enumerate() {
+        // xorshift gets stuck at 0
+        let s = splitmix64(seed ^ splitmix64(core as u64));
+        state.store(if s == 0 { 1 } else { s }, Ordering::Relaxed);
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(not(feature = "fault-injection"))]
+pub fn init(_seed: u64) -> Result<(), KError> {
+    Err(KError::NotSupported)
+}
+
+/// Should we inject `fault` now?
+#[cfg(feature = "fault-injection")]
+pub fn inject(fault: Fault) -> bool {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return false;
+    }
+    let core = match crate::kcb::try_get_kcb() {
+        Some(kcb) => kcb.arch.hwthread_id(),
+        None => return false,
+    };
+
+    let hit = STATES
+        .get(core)
+        .map_or(false, |state| next(state) % fault.odds() == 0);
+    if hit {
+        INJECTED[fault as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    hit
+}
+
+#[cfg(not(feature = "fault-injection"))]
+#[inline(always)]
+pub fn inject(_fault: Fault) -> bool {
+    false
+}
+
+/// `Err(OutOfMemory)` if a system call shouldn't get memory now.
+#[inline(always)]
+pub fn allocation() -> Result<(), KError> {
+    if inject(Fault::Allocation) {
+        Err(KError::OutOfMemory)
+    } else {
+        Ok(())
+    }
+}
+
+/// Spins for a while if an IPI should be late.
+#[inline(always)]
+pub fn delay_ipi() {
+    #[cfg(feature = "fault-injection")]
+    if inject(Fault::IpiDelay) {
+        let core = crate::kcb::try_get_kcb().map_or(0, |kcb| kcb.arch.hwthread_id());
+        let spins = STATES
+            .get(core)
+            .map_or(0, |state| next(state) % MAX_IPI_DELAY);
+        for _i in 0..spins {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Contents of `/proc/faults`.
+#[cfg(feature = "fault-injection")]
+fn proc_faults(out: &mut String) -> fmt::Result {
+    for fault in Fault::ALL.iter() {
+        writeln!(
+            out,
+            "{:<12} {}",
+            fault.name(),
+            INJECTED[*fault as usize].load(Ordering::Relaxed)
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "fault-injection"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seeds_differ_per_core() {
+        let a = splitmix64(42 ^ splitmix64(0));
+        let b = splitmix64(42 ^ splitmix64(1));
+        assert_ne!(a, b);
+        assert_eq!(a, splitmix64(42 ^ splitmix64(0)));
+    }
+
+    #[test]
+    fn generator_repeats() {
+        let (x, y) = (AtomicU64::new(7), AtomicU64::new(7));
+        for _i in 0..100 {
+            let v = next(&x);
+            assert_eq!(v, next(&y));
+        }
+        assert_ne!(x.load(Ordering::Relaxed), 0);
+    }
+}
@@ -1,23 +1,36 @@
 // Copyright © 2021 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
+use kpi::process::Credentials;
+
 use super::{Fd, MAX_FILES_PER_PROCESS};
 use crate::error::KError;
 
 pub struct FileDesc {
     fds: arrayvec::ArrayVec<Option<Fd>, MAX_FILES_PER_PROCESS>,
+    /// Who the process runs as (for the permission checks).
+    creds: Credentials,
 }
 
 impl Default for FileDesc {
     fn default() -> Self {
+        FileDesc::new(Credentials::ROOT)
+    }
+}
+
+impl FileDesc {
+    pub fn new(creds: Credentials) -> Self {
         const NONE_FD: Option<Fd> = None;
         FileDesc {
             fds: arrayvec::ArrayVec::from([NONE_FD; MAX_FILES_PER_PROCESS]),
+            creds,
         }
     }
-}
 
-impl FileDesc {
+    pub fn credentials(&self) -> Credentials {
+        self.creds
+    }
+
     pub fn allocate_fd(&mut self) -> Option<(u64, &mut Fd)> {
         if let Some(fid) = self.fds.iter().position(|fd| fd.is_none()) {
             self.fds[fid] = Some(Default::default());
@@ -8,6 +8,7 @@ use core::mem::size_of;
 use fallible_collections::{FallibleVec, FallibleVecGlobal};
 use kpi::io::*;
 
+use crate::arch::process::UserSlice;
 use crate::error::KError;
 use crate::memory::BASE_PAGE_SIZE;
 
@@ -149,7 +150,7 @@ impl File {
     /// end_offset(not inclusive).
     pub fn read_file(
         &self,
-        user_slice: &mut [u8],
+        user_slice: &mut UserSlice,
         start_offset: usize,
         end_offset: usize,
     ) -> Result<usize, KError> {
@@ -175,8 +176,7 @@ impl File {
                 src_end = src_start + remaining;
                 copied += remaining;
             }
-            user_slice[dst_start..dst_end]
-                .copy_from_slice(&self.mcache[buffer_num].data[src_start..src_end]);
+            user_slice.write_from(dst_start, &self.mcache[buffer_num].data[src_start..src_end]);
             buffer_num += 1;
             dst_start = dst_end;
             offset_in_buffer = 0;
@@ -361,7 +361,8 @@ pub mod test {
         assert_eq!(file.get_size(), 10000);
 
         for i in 0..10000 {
-            file.read_file(&mut rbuffer[i..i + 1], i, i + 1).unwrap();
+            file.read_file(&mut UserSlice::from_slice(&mut rbuffer[i..i + 1]), i, i + 1)
+                .unwrap();
             assert_eq!(rbuffer[i], 0xb);
         }
     }
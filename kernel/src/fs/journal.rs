@@ -0,0 +1,916 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A journal of the file-system metadata on a block device (`fsjournal=`).
+//!
+//! NrFS lives in memory. With a journal the files and directories processes
+//! create, delete and rename are still there after a reboot (empty, the
+//! contents of files aren't journaled): every such update is a transaction
+//! that is written and flushed before the system call returns, and `init`
+//! replays the journal when we mount the file-system at boot.
+//!
+//! The replicas hand out a ticket for every update they apply (the same one
+//! on every replica, the updates are scan operations). An update goes to the
+//! journal once the ones with lower tickets are there, so the journal has
+//! them in the order the file-system applied them without holding a lock
+//! across the replica operation. If the journal can't take an update the
+//! system call fails, the update stays in memory until the next reboot.
+//!
+//! A transaction is a header (magic, checksum, generation, sequence number
+//! and length of the records) followed by its records, padded to whole
+//! blocks. The checksum covers everything after it, so a transaction we
+//! were still writing when the system went down doesn't count and the
+//! replay stops there.
+//!
+//! The region of the journal has two halves. We append transactions to one
+//! of them until it is full, then the metadata as it is now goes to the
+//! start of the other half as a single transaction (a snapshot) of the next
+//! generation. At mount we take the half that starts with a valid snapshot
+//! of the higher generation and replay the transactions of that generation
+//! with consecutive sequence numbers after it.
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp;
+use core::convert::{TryFrom, TryInto};
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use fallible_collections::btree::BTreeMap;
+use fallible_collections::vec::FallibleVec;
+use kpi::process::Credentials;
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::cnrfs::MlnrKernelNode;
+use crate::drivers::block::{self, BlockDevice};
+use crate::error::KError;
+use crate::fallible_string::TryString;
+
+use super::Modes;
+
+/// Most blocks we use on the device (both halves).
+pub const MAX_BLOCKS: u64 = 8192;
+
+/// First word of every transaction.
+const MAGIC: u64 = u64::from_le_bytes(*b"NRFSJRNL");
+
+/// Bytes of the transaction header.
+const HEADER_LEN: usize = 40;
+
+/// Longest path in a record.
+const MAX_PATH: usize = 4096;
+
+/// The position of a metadata update in the order the file-system applied
+/// them.
+pub type Ticket = u64;
+
+/// An update of the file-system metadata.
+#[derive(Hash, Clone, Debug, PartialEq)]
+pub enum Record {
+    /// `owner` created a file.
+    Create {
+        path: String,
+        modes: Modes,
+        owner: Credentials,
+    },
+    /// `owner` created a directory.
+    MkDir {
+        path: String,
+        modes: Modes,
+        owner: Credentials,
+    },
+    Delete {
+        path: String,
+    },
+    /// Rename `old` to `new` (replaces `new` if it exists).
+    Rename {
+        old: String,
+        new: String,
+    },
+}
+
+impl Record {
+    pub fn create(path: &str, modes: Modes, owner: Credentials) -> Result<Record, KError> {
+        Ok(Record::Create {
+            path: TryString::try_from(path)?.into(),
+            modes,
+            owner,
+        })
+    }
+
+    pub fn mkdir(path: &str, modes: Modes, owner: Credentials) -> Result<Record, KError> {
+        Ok(Record::MkDir {
+            path: TryString::try_from(path)?.into(),
+            modes,
+            owner,
+        })
+    }
+
+    pub fn delete(path: &str) -> Result<Record, KError> {
+        Ok(Record::Delete {
+            path: TryString::try_from(path)?.into(),
+        })
+    }
+
+    pub fn rename(old: &str, new: &str) -> Result<Record, KError> {
+        Ok(Record::Rename {
+            old: TryString::try_from(old)?.into(),
+            new: TryString::try_from(new)?.into(),
+        })
+    }
+
+    /// Bytes of the encoded record.
+    fn encoded_len(&self) -> usize {
+        match self {
+            Record::Create { path, .. } | Record::MkDir { path, .. } => 1 + 4 + path.len() + 16,
+            Record::Delete { path } => 1 + 4 + path.len(),
+            Record::Rename { old, new } => 1 + 4 + old.len() + 4 + new.len(),
+        }
+    }
+
+    /// Appends the record to `out` (which has room for it).
+    fn encode(&self, out: &mut Vec<u8>) {
+        fn put_str(out: &mut Vec<u8>, s: &str) {
+            out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            out.extend_from_slice(s.as_bytes());
+        }
+
+        match self {
+            Record::Create { path, modes, owner } | Record::MkDir { path, modes, owner } => {
+                out.push(if matches!(self, Record::Create { .. }) {
+                    1
+                } else {
+                    2
+                });
+                put_str(out, path);
+                out.extend_from_slice(&modes.to_le_bytes());
+                out.extend_from_slice(&owner.uid.to_le_bytes());
+                out.extend_from_slice(&owner.gid.to_le_bytes());
+            }
+            Record::Delete { path } => {
+                out.push(3);
+                put_str(out, path);
+            }
+            Record::Rename { old, new } => {
+                out.push(4);
+                put_str(out, old);
+                put_str(out, new);
+            }
+        }
+    }
+
+    /// Reads the next record, `None` if it isn't one.
+    fn decode(reader: &mut Reader) -> Result<Option<Record>, KError> {
+        let tag = match reader.bytes(1) {
+            Some(tag) => tag[0],
+            None => return Ok(None),
+        };
+        let path = match reader.string()? {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        match tag {
+            1 | 2 => {
+                let (modes, uid, gid) = match (reader.u64(), reader.u32(), reader.u32()) {
+                    (Some(modes), Some(uid), Some(gid)) => (modes, uid, gid),
+                    _ => return Ok(None),
+                };
+                let owner = Credentials { uid, gid };
+                Ok(Some(if tag == 1 {
+                    Record::Create { path, modes, owner }
+                } else {
+                    Record::MkDir { path, modes, owner }
+                }))
+            }
+            3 => Ok(Some(Record::Delete { path })),
+            4 => Ok(reader
+                .string()?
+                .map(|new| Record::Rename { old: path, new })),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Reads the records of a transaction.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<Option<String>, KError> {
+        let len = match self.u32() {
+            Some(len) if (len as usize) <= MAX_PATH => len as usize,
+            _ => return Ok(None),
+        };
+        match self.bytes(len).map(core::str::from_utf8) {
+            Some(Ok(s)) => Ok(Some(TryString::try_from(s)?.into())),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// The start of a transaction.
+#[derive(Debug, PartialEq)]
+struct Header {
+    checksum: u64,
+    generation: u64,
+    seq: u64,
+    /// Bytes of the records.
+    len: usize,
+}
+
+impl Header {
+    /// Reads the header at the start of `buf`, `None` if there is none.
+    fn parse(buf: &[u8]) -> Option<Header> {
+        let word = |i: usize| u64::from_le_bytes(buf[i * 8..i * 8 + 8].try_into().unwrap());
+        if buf.len() < HEADER_LEN || word(0) != MAGIC {
+            return None;
+        }
+        Some(Header {
+            checksum: word(1),
+            generation: word(2),
+            seq: word(3),
+            len: usize::try_from(word(4)).ok()?,
+        })
+    }
+
+    /// Blocks of the transaction on a device with blocks of `block_size`.
+    fn blocks(&self, block_size: usize) -> Option<u64> {
+        let bytes = self.len.checked_add(HEADER_LEN + block_size - 1)?;
+        Some((bytes / block_size) as u64)
+    }
+}
+
+/// Blocks of a transaction with `len` bytes of records.
+fn blocks(len: usize, block_size: usize) -> u64 {
+    ((HEADER_LEN + len + block_size - 1) / block_size) as u64
+}
+
+/// FNV-1a of `bytes`, the checksum of a transaction.
+fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+/// Encodes `records` as transaction `seq` of `generation`, padded to whole
+/// blocks of `block_size`.
+fn encode(
+    generation: u64,
+    seq: u64,
+    records: &[Record],
+    block_size: usize,
+) -> Result<Vec<u8>, KError> {
+    let len: usize = records.iter().map(Record::encoded_len).sum();
+    let padded = (HEADER_LEN + len + block_size - 1) / block_size * block_size;
+
+    let mut buf = Vec::try_with_capacity(padded)?;
+    buf.extend_from_slice(&MAGIC.to_le_bytes());
+    buf.extend_from_slice(&0u64.to_le_bytes());
+    buf.extend_from_slice(&generation.to_le_bytes());
+    buf.extend_from_slice(&seq.to_le_bytes());
+    buf.extend_from_slice(&(len as u64).to_le_bytes());
+    for record in records {
+        record.encode(&mut buf);
+    }
+    let checksum = hash(&buf[16..]);
+    buf[8..16].copy_from_slice(&checksum.to_le_bytes());
+    buf.resize(padded, 0);
+    Ok(buf)
+}
+
+/// The records of the transaction in `buf`, `None` if it is torn or not a
+/// transaction of `header`.
+fn decode(buf: &[u8], header: &Header) -> Result<Option<Vec<Record>>, KError> {
+    let end = match HEADER_LEN.checked_add(header.len) {
+        Some(end) if end <= buf.len() => end,
+        _ => return Ok(None),
+    };
+    if Header::parse(buf).as_ref() != Some(header) || hash(&buf[16..end]) != header.checksum {
+        return Ok(None);
+    }
+
+    let mut records = Vec::new();
+    let mut reader = Reader {
+        buf: &buf[..end],
+        pos: HEADER_LEN,
+    };
+    while reader.pos < end {
+        match Record::decode(&mut reader)? {
+            Some(record) => records.try_push(record)?,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(records))
+}
+
+/// A file or directory that is in the journal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Entry {
+    dir: bool,
+    modes: Modes,
+    owner: Credentials,
+}
+
+/// Updates `entries` with `record`.
+fn apply(entries: &mut BTreeMap<String, Entry>, record: &Record) -> Result<(), KError> {
+    match record {
+        Record::Create { path, modes, owner } | Record::MkDir { path, modes, owner } => {
+            let entry = Entry {
+                dir: matches!(record, Record::MkDir { .. }),
+                modes: *modes,
+                owner: *owner,
+            };
+            entries.try_insert(TryString::try_from(path.as_str())?.into(), entry)?;
+        }
+        Record::Delete { path } => {
+            entries.remove(path);
+        }
+        Record::Rename { old, new } => {
+            // We don't know files the kernel created, they stay gone
+            entries.remove(new);
+            if let Some(entry) = entries.remove(old) {
+                entries.try_insert(TryString::try_from(new.as_str())?.into(), entry)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The journal on a block device.
+pub struct Journal {
+    dev: Arc<dyn BlockDevice>,
+    /// First block of the journal.
+    lba: u64,
+    /// Blocks of each half.
+    half: u64,
+    /// The half we append to.
+    active: u64,
+    generation: u64,
+    /// Sequence number of the next transaction.
+    seq: u64,
+    /// Where the next transaction goes (relative to the active half).
+    tail: u64,
+    /// The files and directories as of the last transaction.
+    entries: BTreeMap<String, Entry>,
+}
+
+impl Journal {
+    /// Uses `dev` from block `lba` on (at most `MAX_BLOCKS`), replays the
+    /// journal that is there or starts a new one.
+    pub fn mount(dev: Arc<dyn BlockDevice>, lba: u64) -> Result<Journal, KError> {
+        let bs = dev.block_size();
+        if bs < HEADER_LEN {
+            return Err(KError::NotSupported);
+        }
+        let blocks = dev
+            .num_blocks()
+            .checked_sub(lba)
+            .ok_or(KError::InvalidBlockRange)?;
+        let half = cmp::min(blocks, MAX_BLOCKS) / 2;
+        if half == 0 {
+            return Err(KError::InvalidBlockRange);
+        }
+        block::check_range(&*dev, lba, 2 * half as usize * bs)?;
+
+        let mut journal = Journal {
+            dev,
+            lba,
+            half,
+            active: 0,
+            generation: 0,
+            seq: 0,
+            tail: 0,
+            entries: BTreeMap::new(),
+        };
+
+        let mut newest = None;
+        for half in 0..2 {
+            if let Some((header, _records)) = journal.read(half, 0)? {
+                if header.seq == 0 && newest.map_or(true, |(_, g)| header.generation > g) {
+                    newest = Some((half, header.generation));
+                }
+            }
+        }
+
+        match newest {
+            Some((half, generation)) => {
+                journal.active = half;
+                journal.generation = generation;
+                journal.replay()?;
+            }
+            None => {
+                // Nothing there (or nothing we can use), the first snapshot
+                // goes to half 0
+                journal.active = 1;
+                journal.compact(None)?;
+            }
+        }
+        Ok(journal)
+    }
+
+    /// First block of `half`.
+    fn start(&self, half: u64) -> u64 {
+        self.lba + half * self.half
+    }
+
+    /// Reads the transaction at `block` of `half`, `None` if there is none.
+    fn read(&self, half: u64, block: u64) -> Result<Option<(Header, Vec<Record>)>, KError> {
+        let bs = self.dev.block_size();
+        let mut buf = Vec::try_with_capacity(bs)?;
+        buf.resize(bs, 0);
+        self.dev.read(self.start(half) + block, &mut buf)?;
+
+        let header = match Header::parse(&buf) {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let blocks = match header.blocks(bs) {
+            Some(blocks) if block + blocks <= self.half => blocks,
+            _ => return Ok(None),
+        };
+        if blocks > 1 {
+            buf = Vec::try_with_capacity(blocks as usize * bs)?;
+            buf.resize(blocks as usize * bs, 0);
+            self.dev.read(self.start(half) + block, &mut buf)?;
+        }
+
+        Ok(decode(&buf, &header)?.map(|records| (header, records)))
+    }
+
+    /// Applies the transactions of the active half.
+    fn replay(&mut self) -> Result<(), KError> {
+        let bs = self.dev.block_size();
+        let (mut block, mut seq) = (0, 0);
+        while block < self.half {
+            match self.read(self.active, block)? {
+                Some((header, records))
+                    if header.generation == self.generation && header.seq == seq =>
+                {
+                    for record in records.iter() {
+                        apply(&mut self.entries, record)?;
+                    }
+                    // `read` checked that it fits
+                    block += header.blocks(bs).unwrap();
+                    seq += 1;
+                }
+                _ => break,
+            }
+        }
+
+        self.tail = block;
+        self.seq = seq;
+        Ok(())
+    }
+
+    /// The records that create the files and directories in the journal
+    /// (parents before their children).
+    pub fn snapshot(&self) -> Result<Vec<Record>, KError> {
+        self.snapshot_after(None)
+    }
+
+    /// The records of a snapshot that has `record` applied (without
+    /// applying it to `entries`).
+    fn snapshot_after(&self, record: Option<&Record>) -> Result<Vec<Record>, KError> {
+        fn to_record(path: &str, entry: &Entry) -> Result<Record, KError> {
+            if entry.dir {
+                Record::mkdir(path, entry.modes, entry.owner)
+            } else {
+                Record::create(path, entry.modes, entry.owner)
+            }
+        }
+
+        let mut records = Vec::try_with_capacity(self.entries.len() + 1)?;
+        let mut renamed = None;
+        for (path, entry) in self.entries.iter() {
+            match record {
+                Some(Record::Create { path: p, .. })
+                | Some(Record::MkDir { path: p, .. })
+                | Some(Record::Delete { path: p })
+                    if p == path =>
+                {
+                    continue
+                }
+                Some(Record::Rename { old, new }) if old == path => {
+                    renamed = Some((new, *entry));
+                    continue;
+                }
+                Some(Record::Rename { new, .. }) if new == path => continue,
+                _ => records.push(to_record(path, entry)?),
+            }
+        }
+
+        // After the others, its parent is there already
+        if let Some((path, entry)) = renamed {
+            records.push(to_record(path, &entry)?);
+        }
+        if let Some(record) = record {
+            if matches!(record, Record::Create { .. } | Record::MkDir { .. }) {
+                records.push(record.clone());
+            }
+        }
+        Ok(records)
+    }
+
+    /// Writes a snapshot that has `record` applied to the other half and
+    /// continues there.
+    fn compact(&mut self, record: Option<&Record>) -> Result<(), KError> {
+        let bs = self.dev.block_size();
+        let next = 1 - self.active;
+        let records = self.snapshot_after(record)?;
+        let buf = encode(self.generation + 1, 0, &records, bs)?;
+        let blocks = (buf.len() / bs) as u64;
+        if blocks > self.half {
+            return Err(KError::JournalFull);
+        }
+
+        self.dev.write(self.start(next), &buf)?;
+        self.dev.flush()?;
+        if let Some(record) = record {
+            apply(&mut self.entries, record)?;
+        }
+        self.active = next;
+        self.generation += 1;
+        self.seq = 1;
+        self.tail = blocks;
+        Ok(())
+    }
+
+    /// Whether there is room for `record` (after a compaction if need be).
+    fn fits(&self, record: &Record) -> Result<bool, KError> {
+        let bs = self.dev.block_size();
+        if self.tail + blocks(record.encoded_len(), bs) <= self.half {
+            return Ok(true);
+        }
+        let snapshot = self.snapshot_after(Some(record))?;
+        let len = snapshot.iter().map(Record::encoded_len).sum();
+        Ok(blocks(len, bs) <= self.half)
+    }
+
+    /// Writes `record` as a transaction, it's on stable storage once this
+    /// returns `Ok`.
+    pub fn append(&mut self, record: &Record) -> Result<(), KError> {
+        let bs = self.dev.block_size();
+        let buf = encode(self.generation, self.seq, core::slice::from_ref(record), bs)?;
+        let blocks = (buf.len() / bs) as u64;
+        if self.tail + blocks > self.half {
+            return self.compact(Some(record));
+        }
+
+        self.dev.write(self.start(self.active) + self.tail, &buf)?;
+        self.dev.flush()?;
+        apply(&mut self.entries, record)?;
+        self.tail += blocks;
+        self.seq += 1;
+        Ok(())
+    }
+}
+
+/// The journal (if we have one).
+static JOURNAL: Once<Mutex<Journal>> = Once::new();
+
+/// Ticket of the update that goes to the journal next.
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+/// The record of an update (`None` if there is no journal), fails if the
+/// journal can't take it.
+///
+/// Call this before the replica applies the update.
+pub fn prepare<F>(record: F) -> Result<Option<Record>, KError>
+where
+    F: FnOnce() -> Result<Record, KError>,
+{
+    match JOURNAL.get() {
+        Some(journal) => {
+            let record = record()?;
+            if !journal.lock().fits(&record)? {
+                return Err(KError::JournalFull);
+            }
+            Ok(Some(record))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Writes `record` of update `ticket` to the journal, returns once it's on
+/// stable storage.
+///
+/// Every ticket the replicas hand out has to end up here (or in `skip`),
+/// the updates after it wait for it.
+pub fn commit(ticket: Ticket, record: Option<Record>) -> Result<(), KError> {
+    let journal = match JOURNAL.get() {
+        Some(journal) => journal,
+        None => return Ok(()),
+    };
+
+    // The updates before this one are done with their replica operation
+    // already, they only have to get to the journal
+    while NEXT.load(Ordering::Acquire) != ticket {
+        spin_loop();
+    }
+    let r = match record {
+        Some(record) => journal.lock().append(&record),
+        None => Ok(()),
+    };
+    NEXT.store(ticket + 1, Ordering::Release);
+    r
+}
+
+/// Update `ticket` doesn't go to the journal.
+pub fn skip(ticket: Ticket) {
+    let _r = commit(ticket, None);
+}
+
+/// Parses `<dev>` or `<dev>:<lba>`.
+fn parse_target(target: &str) -> Result<(&str, u64), KError> {
+    let (name, lba) = match target.split_once(':') {
+        Some((name, lba)) => (
+            name,
+            lba.parse::<u64>()
+                .map_err(|_| KError::InvalidJournalTarget)?,
+        ),
+        None => (target, 0),
+    };
+    if name.is_empty() {
+        return Err(KError::InvalidJournalTarget);
+    }
+    Ok((name, lba))
+}
+
+/// Mounts the journal on `target` and brings back the files and
+/// directories in it (call this once the file-system replicas are there and
+/// before anything else updates the file-system).
+pub fn init(target: &str) -> Result<(), KError> {
+    let (name, lba) = parse_target(target)?;
+    let dev = block::get(name).ok_or(KError::JournalTargetNotFound)?;
+    let journal = Journal::mount(dev, lba)?;
+
+    let records = journal.snapshot()?;
+    for record in records.iter() {
+        if let Err(e) = MlnrKernelNode::journal_replay(record.clone()) {
+            warn!("Can't replay {:?}: {}", record, e);
+        }
+    }
+    info!(
+        "File-system journal on {} (generation {}): {} files and directories",
+        target,
+        journal.generation,
+        records.len()
+    );
+
+    JOURNAL.call_once(|| Mutex::new(journal));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A device in memory that can stop writing half-way.
+    struct Ram {
+        blocks: Mutex<Vec<u8>>,
+        /// Blocks of the next write that make it to the device.
+        torn: Mutex<Option<usize>>,
+    }
+
+    impl Ram {
+        fn new(blocks: usize) -> Arc<Ram> {
+            Arc::new(Ram {
+                blocks: Mutex::new(alloc::vec![0; blocks * 512]),
+                torn: Mutex::new(None),
+            })
+        }
+    }
+
+    impl BlockDevice for Ram {
+        fn block_size(&self) -> usize {
+            512
+        }
+
+        fn num_blocks(&self) -> u64 {
+            (self.blocks.lock().len() / 512) as u64
+        }
+
+        fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), KError> {
+            block::check_range(self, lba, buf.len())?;
+            let start = lba as usize * 512;
+            buf.copy_from_slice(&self.blocks.lock()[start..start + buf.len()]);
+            Ok(())
+        }
+
+        fn write(&self, lba: u64, buf: &[u8]) -> Result<(), KError> {
+            block::check_range(self, lba, buf.len())?;
+            let len = match self.torn.lock().take() {
+                Some(blocks) => cmp::min(blocks * 512, buf.len()),
+                None => buf.len(),
+            };
+            let start = lba as usize * 512;
+            self.blocks.lock()[start..start + len].copy_from_slice(&buf[..len]);
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), KError> {
+            Ok(())
+        }
+    }
+
+    const USER: Credentials = Credentials { uid: 7, gid: 8 };
+
+    fn paths(journal: &Journal) -> Vec<String> {
+        journal.entries.iter().map(|(p, _e)| p.clone()).collect()
+    }
+
+    #[test]
+    fn records_roundtrip() {
+        let records = alloc::vec![
+            Record::create("/a", 0o644, USER).unwrap(),
+            Record::mkdir("/d", 0o755, Credentials::ROOT).unwrap(),
+            Record::rename("/a", "/d/b").unwrap(),
+            Record::delete("/d/b").unwrap(),
+        ];
+        let buf = encode(3, 9, &records, 512).unwrap();
+        assert_eq!(buf.len(), 512);
+
+        let header = Header::parse(&buf).unwrap();
+        assert_eq!((header.generation, header.seq), (3, 9));
+        assert_eq!(decode(&buf, &header).unwrap(), Some(records));
+    }
+
+    #[test]
+    fn corrupt_transaction() {
+        let records = alloc::vec![Record::create("/a", 0o644, USER).unwrap()];
+        let mut buf = encode(1, 0, &records, 512).unwrap();
+        let header = Header::parse(&buf).unwrap();
+        buf[HEADER_LEN + 2] ^= 1;
+        assert_eq!(decode(&buf, &header).unwrap(), None);
+        assert_eq!(Header::parse(&[0; 512]), None);
+    }
+
+    #[test]
+    fn apply_records() {
+        let mut entries = BTreeMap::new();
+        for record in [
+            Record::create("/a", 0o644, USER).unwrap(),
+            Record::create("/b", 0o600, USER).unwrap(),
+            Record::rename("/a", "/b").unwrap(),
+            Record::rename("/kernel", "/c").unwrap(),
+            Record::mkdir("/d", 0o755, USER).unwrap(),
+            Record::delete("/d").unwrap(),
+        ]
+        .iter()
+        {
+            apply(&mut entries, record).unwrap();
+        }
+
+        let entries: Vec<_> = entries.iter().map(|(p, e)| (p.clone(), *e)).collect();
+        let file = Entry {
+            dir: false,
+            modes: 0o644,
+            owner: USER,
+        };
+        assert_eq!(entries, alloc::vec![(String::from("/b"), file)]);
+    }
+
+    #[test]
+    fn replay_after_mount() {
+        let dev = Ram::new(64);
+        let mut journal = Journal::mount(dev.clone(), 16).unwrap();
+        assert_eq!((journal.generation, journal.seq), (1, 1));
+        journal
+            .append(&Record::mkdir("/d", 0o755, USER).unwrap())
+            .unwrap();
+        journal
+            .append(&Record::create("/d/f", 0o644, USER).unwrap())
+            .unwrap();
+        journal
+            .append(&Record::create("/g", 0o644, USER).unwrap())
+            .unwrap();
+        journal.append(&Record::delete("/g").unwrap()).unwrap();
+
+        let journal = Journal::mount(dev, 16).unwrap();
+        assert_eq!(paths(&journal), ["/d", "/d/f"]);
+        assert_eq!(
+            journal.snapshot().unwrap(),
+            [
+                Record::mkdir("/d", 0o755, USER).unwrap(),
+                Record::create("/d/f", 0o644, USER).unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn torn_transaction() {
+        let dev = Ram::new(32);
+        let mut journal = Journal::mount(dev.clone(), 0).unwrap();
+        journal
+            .append(&Record::create("/a", 0o644, USER).unwrap())
+            .unwrap();
+
+        // Only the first block of a two block transaction makes it
+        let long = "/x".repeat(400);
+        *dev.torn.lock() = Some(1);
+        journal
+            .append(&Record::create(&long, 0o644, USER).unwrap())
+            .unwrap();
+
+        let mut journal = Journal::mount(dev.clone(), 0).unwrap();
+        assert_eq!(paths(&journal), ["/a"]);
+
+        // Appending after it overwrites the torn one
+        journal
+            .append(&Record::create("/b", 0o644, USER).unwrap())
+            .unwrap();
+        let journal = Journal::mount(dev, 0).unwrap();
+        assert_eq!(paths(&journal), ["/a", "/b"]);
+    }
+
+    #[test]
+    fn compaction() {
+        // Halves of four blocks, a snapshot and three transactions
+        let dev = Ram::new(8);
+        let mut journal = Journal::mount(dev.clone(), 0).unwrap();
+        for i in 0..10 {
+            let path = alloc::format!("/f{}", i);
+            journal
+                .append(&Record::create(&path, 0o644, USER).unwrap())
+                .unwrap();
+            journal.append(&Record::delete(&path).unwrap()).unwrap();
+        }
+        journal
+            .append(&Record::create("/last", 0o644, USER).unwrap())
+            .unwrap();
+        assert!(journal.generation > 1);
+
+        let journal = Journal::mount(dev.clone(), 0).unwrap();
+        assert_eq!(paths(&journal), ["/last"]);
+
+        // A torn snapshot leaves the old half in charge
+        let mut journal = journal;
+        let generation = journal.generation;
+        while journal.tail + 1 <= journal.half {
+            journal
+                .append(&Record::create("/last", 0o644, USER).unwrap())
+                .unwrap();
+        }
+        *dev.torn.lock() = Some(0);
+        journal
+            .append(&Record::create("/lost", 0o644, USER).unwrap())
+            .unwrap();
+        let journal = Journal::mount(dev, 0).unwrap();
+        assert_eq!(journal.generation, generation);
+        assert_eq!(paths(&journal), ["/last"]);
+    }
+
+    #[test]
+    fn journal_full() {
+        let dev = Ram::new(4);
+        let mut journal = Journal::mount(dev, 0).unwrap();
+        let long = "/x".repeat(200);
+        for i in 0..4 {
+            let path = alloc::format!("{}{}", long, i);
+            let _r = journal.append(&Record::create(&path, 0o644, USER).unwrap());
+        }
+        let path = alloc::format!("{}{}", long, 5);
+        let record = Record::create(&path, 0o644, USER).unwrap();
+        assert_eq!(journal.fits(&record), Ok(false));
+        assert_eq!(journal.append(&record), Err(KError::JournalFull));
+
+        // The records that didn't make it aren't in the next snapshot
+        assert_eq!(journal.entries.len(), 2);
+        let first = alloc::format!("{}{}", long, 0);
+        let delete = Record::delete(&first).unwrap();
+        assert_eq!(journal.fits(&delete), Ok(true));
+        journal.append(&delete).unwrap();
+        assert_eq!(journal.entries.len(), 1);
+    }
+
+    #[test]
+    fn targets() {
+        assert_eq!(parse_target("sata0"), Ok(("sata0", 0)));
+        assert_eq!(parse_target("nvme0:8192"), Ok(("nvme0", 8192)));
+        assert_eq!(parse_target(":1"), Err(KError::InvalidJournalTarget));
+        assert_eq!(parse_target("nvme0:x"), Err(KError::InvalidJournalTarget));
+    }
+}
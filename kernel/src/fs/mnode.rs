@@ -4,7 +4,8 @@
 use alloc::string::String;
 use core::convert::TryFrom;
 
-use kpi::io::FileType;
+use kpi::io::{FileModes, FileType};
+use kpi::process::Credentials;
 
 use crate::arch::process::UserSlice;
 use crate::error::KError;
@@ -20,6 +21,8 @@ pub struct MemNode {
     name: String,
     node_type: FileType,
     file: Option<File>,
+    /// Who created it (the kernel creates files as root).
+    owner: Credentials,
 }
 
 /// Required for the testing
@@ -40,6 +43,7 @@ impl Default for MemNode {
             name: String::new(),
             node_type: FileType::File,
             file: None,
+            owner: Credentials::ROOT,
         }
     }
 }
@@ -65,13 +69,40 @@ impl MemNode {
             name: TryString::try_from(pathname)?.into(),
             node_type,
             file,
+            owner: Credentials::ROOT,
         })
     }
 
+    pub fn owner(&self) -> Credentials {
+        self.owner
+    }
+
+    pub fn set_owner(&mut self, owner: Credentials) {
+        self.owner = owner;
+    }
+
+    /// May a process with `creds` access the node for `access` (user bits
+    /// of `FileModes`)? Privileged processes may do anything, others need
+    /// the bits of the user, group or other class they are in.
+    pub fn may_access(&self, creds: Credentials, access: FileModes) -> bool {
+        if creds.is_privileged() {
+            return true;
+        }
+        match self.file.as_ref() {
+            Some(file) => file
+                .get_mode()
+                .for_credentials(self.owner, creds)
+                .contains(access),
+            // Directories don't have modes (yet), they belong to their owner
+            None => creds.uid == self.owner.uid,
+        }
+    }
+
     /// Write to an in-memory file.
     pub fn write(&mut self, buffer: &[u8], offset: usize) -> Result<usize, KError> {
         // Return if the user doesn't have write permissions for the file.
-        if self.node_type != FileType::File || !self.file.as_ref().unwrap().get_mode().is_writable()
+        if self.node_type != FileType::File
+            || !self.file.as_ref().unwrap().get_mode().is_writable_by_any()
         {
             return Err(KError::PermissionError);
         }
@@ -83,7 +114,8 @@ impl MemNode {
     /// Read from an in-memory file.
     pub fn read(&self, buffer: &mut UserSlice, offset: usize) -> Result<usize, KError> {
         // Return if the user doesn't have read permissions for the file.
-        if self.node_type != FileType::File || !self.file.as_ref().unwrap().get_mode().is_readable()
+        if self.node_type != FileType::File
+            || !self.file.as_ref().unwrap().get_mode().is_readable_by_any()
         {
             return Err(KError::PermissionError);
         }
@@ -110,7 +142,7 @@ impl MemNode {
         self.file
             .as_ref()
             .unwrap()
-            .read_file(&mut *buffer, offset, new_offset)
+            .read_file(buffer, offset, new_offset)
     }
 
     /// Get the file size
@@ -125,7 +157,8 @@ impl MemNode {
 
     /// Truncate the file in reasponse of O_TRUNC flag.
     pub fn file_truncate(&mut self) -> Result<(), KError> {
-        if self.node_type != FileType::File || !self.file.as_ref().unwrap().get_mode().is_writable()
+        if self.node_type != FileType::File
+            || !self.file.as_ref().unwrap().get_mode().is_writable_by_any()
         {
             return Err(KError::PermissionError);
         }
@@ -134,11 +167,24 @@ impl MemNode {
         self.file.as_mut().unwrap().file_truncate();
         Ok(())
     }
+
+    /// Replaces the contents of a file regardless of its modes (for files
+    /// the kernel provides).
+    pub fn set_contents(&mut self, buffer: &[u8]) -> Result<usize, KError> {
+        if self.node_type != FileType::File {
+            return Err(KError::PermissionError);
+        }
+
+        let file = self.file.as_mut().unwrap();
+        file.file_truncate();
+        file.write_file(buffer, buffer.len(), 0)
+    }
 }
 
 #[cfg(test)]
 pub mod test {
     use super::*;
+    use crate::memory::vspace::UserAccess;
     use alloc::string::ToString;
     use kpi::io::*;
 
@@ -215,6 +261,22 @@ pub mod test {
         assert_eq!(memnode.write(buffer, 0), Err(KError::PermissionError));
     }
 
+    #[test]
+    /// The kernel can set the contents of files processes can only read.
+    fn test_mnode_file_set_contents() {
+        let filename = "file.txt";
+        let mut memnode =
+            MemNode::new(1, filename, FileModes::S_IRUSR.into(), FileType::File).unwrap();
+        let buffer: &mut [u8; 10] = &mut [0xb; 10];
+        assert_eq!(memnode.set_contents(buffer).unwrap(), 10);
+        assert_eq!(memnode.set_contents(&buffer[..4]).unwrap(), 4);
+        assert_eq!(memnode.get_file_size(), 4);
+
+        let mut dir =
+            MemNode::new(2, "dir", FileModes::S_IRWXU.into(), FileType::Directory).unwrap();
+        assert_eq!(dir.set_contents(buffer), Err(KError::PermissionError));
+    }
+
     #[test]
     /// Read from mnode file.
     fn test_mnode_file_read() {
@@ -233,7 +295,10 @@ pub mod test {
         let buffer: &mut [u8; 10] = &mut [0; 10];
         assert_eq!(
             memnode
-                .read(&mut UserSlice::new(buffer.as_ptr() as u64, 10), 0)
+                .read(
+                    &mut UserSlice::new(buffer.as_ptr() as u64, 10, UserAccess::Write).unwrap(),
+                    0
+                )
                 .unwrap(),
             10
         );
@@ -255,11 +320,49 @@ pub mod test {
         assert_eq!(memnode.node_type, FileType::File);
         let buffer: &[u8; 10] = &[0xb; 10];
         assert_eq!(
-            memnode.read(&mut UserSlice::new(buffer.as_ptr() as u64, 10), 0),
+            memnode.read(
+                &mut UserSlice::new(buffer.as_ptr() as u64, 10, UserAccess::Write).unwrap(),
+                0
+            ),
             Err(KError::PermissionError)
         );
     }
 
+    #[test]
+    /// Only the class (user, group, other) of the process counts.
+    fn test_mnode_may_access() {
+        let owner = Credentials {
+            uid: 1000,
+            gid: 100,
+        };
+        let modes = FileModes::S_IRUSR | FileModes::S_IWUSR | FileModes::S_IRGRP;
+        let mut memnode = MemNode::new(1, "file.txt", modes.into(), FileType::File).unwrap();
+        memnode.set_owner(owner);
+
+        let rw = FileModes::S_IRUSR | FileModes::S_IWUSR;
+        let member = Credentials {
+            uid: 1001,
+            gid: 100,
+        };
+        let other = Credentials {
+            uid: 1002,
+            gid: 1002,
+        };
+        assert!(memnode.may_access(owner, rw));
+        assert!(memnode.may_access(member, FileModes::S_IRUSR));
+        assert!(!memnode.may_access(member, rw));
+        assert!(!memnode.may_access(other, FileModes::S_IRUSR));
+        assert!(memnode.may_access(Credentials::ROOT, rw));
+
+        // Group members can write if the group can (even if the owner can't)
+        let modes = FileModes::S_IRUSR | FileModes::S_IRGRP | FileModes::S_IWGRP;
+        let mut memnode = MemNode::new(2, "shared.txt", modes.into(), FileType::File).unwrap();
+        memnode.set_owner(owner);
+        assert!(memnode.may_access(member, rw));
+        let buffer: &mut [u8; 10] = &mut [0xb; 10];
+        assert_eq!(memnode.write(buffer, 0).unwrap(), 10);
+    }
+
     #[test]
     /// Test if the offset is updated properly.
     fn test_offset_tracking() {
@@ -274,7 +377,10 @@ pub mod test {
             let buffer: &mut [u8; 1] = &mut [0; 1];
             assert_eq!(
                 memnode
-                    .read(&mut UserSlice::new(buffer.as_ptr() as u64, 1), 0)
+                    .read(
+                        &mut UserSlice::new(buffer.as_ptr() as u64, 1, UserAccess::Write).unwrap(),
+                        0
+                    )
                     .unwrap(),
                 1
             );
@@ -284,7 +390,10 @@ pub mod test {
         let buffer: &mut [u8; 1] = &mut [0; 1];
         assert_eq!(
             memnode
-                .read(&mut UserSlice::new(buffer.as_ptr() as u64, 1), 10)
+                .read(
+                    &mut UserSlice::new(buffer.as_ptr() as u64, 1, UserAccess::Write).unwrap(),
+                    10
+                )
                 .unwrap(),
             0
         );
@@ -303,7 +412,10 @@ pub mod test {
         let buffer: &mut [u8; 1] = &mut [0; 1];
         assert_eq!(
             memnode
-                .read(&mut UserSlice::new(buffer.as_ptr() as u64, 1), 9)
+                .read(
+                    &mut UserSlice::new(buffer.as_ptr() as u64, 1, UserAccess::Write).unwrap(),
+                    9
+                )
                 .unwrap(),
             1
         );
@@ -312,7 +424,10 @@ pub mod test {
         let buffer: &mut [u8; 1] = &mut [0; 1];
         assert_eq!(
             memnode
-                .read(&mut UserSlice::new(buffer.as_ptr() as u64, 1), 10)
+                .read(
+                    &mut UserSlice::new(buffer.as_ptr() as u64, 1, UserAccess::Write).unwrap(),
+                    10
+                )
                 .unwrap(),
             0
         );
@@ -331,7 +446,10 @@ pub mod test {
         let buffer: &mut [u8; 1] = &mut [0; 1];
         assert_eq!(
             memnode
-                .read(&mut UserSlice::new(buffer.as_ptr() as u64, 1), 10)
+                .read(
+                    &mut UserSlice::new(buffer.as_ptr() as u64, 1, UserAccess::Write).unwrap(),
+                    10
+                )
                 .unwrap(),
             0
         );
@@ -352,7 +470,10 @@ pub mod test {
         let rbuffer: &mut [u8; 10] = &mut [0; 10];
         assert_eq!(
             memnode
-                .read(&mut UserSlice::new(rbuffer.as_ptr() as u64, 10), 0)
+                .read(
+                    &mut UserSlice::new(rbuffer.as_ptr() as u64, 10, UserAccess::Write).unwrap(),
+                    0
+                )
                 .unwrap(),
             10
         );
@@ -373,7 +494,10 @@ pub mod test {
         assert_eq!(memnode.write(buffer, 0).unwrap(), 10);
         assert_eq!(
             memnode
-                .read(&mut UserSlice::new(rbuffer.as_ptr() as u64, 10), 0)
+                .read(
+                    &mut UserSlice::new(rbuffer.as_ptr() as u64, 10, UserAccess::Write).unwrap(),
+                    0
+                )
                 .unwrap(),
             10
         );
@@ -384,7 +508,10 @@ pub mod test {
         assert_eq!(memnode.write(buffer, 20).unwrap(), 10);
         assert_eq!(
             memnode
-                .read(&mut UserSlice::new(rbuffer.as_ptr() as u64, 20), 10)
+                .read(
+                    &mut UserSlice::new(rbuffer.as_ptr() as u64, 20, UserAccess::Write).unwrap(),
+                    10
+                )
                 .unwrap(),
             20
         );
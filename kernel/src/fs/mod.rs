@@ -8,6 +8,7 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 
 use hashbrown::HashMap;
 use kpi::io::*;
+use kpi::process::Credentials;
 use spin::RwLock;
 
 use crate::arch::process::UserSlice;
@@ -17,6 +18,7 @@ use crate::fallible_string::TryString;
 pub use rwlock::RwLock as NrLock;
 
 pub mod fd;
+pub mod journal;
 
 mod file;
 mod mnode;
@@ -177,6 +179,73 @@ impl MlnrFS {
     fn get_next_mno(&self) -> usize {
         self.nextmemnode.fetch_add(1, Ordering::Relaxed)
     }
+
+    /// Creates a file that belongs to `owner`.
+    pub fn create_as(
+        &self,
+        pathname: &str,
+        modes: Modes,
+        owner: Credentials,
+    ) -> Result<u64, KError> {
+        let mnode_num = self.create(pathname, modes)?;
+        if let Some(mnode) = self.mnodes.read().get(&mnode_num) {
+            mnode.write().set_owner(owner);
+        }
+        Ok(mnode_num)
+    }
+
+    /// Creates a directory that belongs to `owner`.
+    pub fn mkdir_as(&self, pathname: &str, modes: Modes, owner: Credentials) -> Result<(), KError> {
+        self.mkdir(pathname, modes)?;
+        let mnode_num = *self.lookup(pathname).ok_or(KError::InvalidFile)?;
+        if let Some(mnode) = self.mnodes.read().get(&mnode_num) {
+            mnode.write().set_owner(owner);
+        }
+        Ok(())
+    }
+
+    /// Fails with `PermissionError` unless a process with `creds` may
+    /// access `mnode_num` for `access` (user bits of `FileModes`).
+    pub fn check_access(
+        &self,
+        mnode_num: Mnode,
+        creds: Credentials,
+        access: FileModes,
+    ) -> Result<(), KError> {
+        match self.mnodes.read().get(&mnode_num) {
+            Some(mnode) if mnode.read().may_access(creds, access) => Ok(()),
+            Some(_) => Err(KError::PermissionError),
+            None => Err(KError::InvalidFile),
+        }
+    }
+
+    /// Fails with `PermissionError` unless a process with `creds` may
+    /// delete or rename `pathname` (it has to own it or be privileged).
+    pub fn check_owner(&self, pathname: &str, creds: Credentials) -> Result<(), KError> {
+        let mnode_num = *self.lookup(pathname).ok_or(KError::InvalidFile)?;
+        match self.mnodes.read().get(&mnode_num) {
+            Some(mnode) if creds.is_privileged() || mnode.read().owner().uid == creds.uid => Ok(()),
+            Some(_) => Err(KError::PermissionError),
+            None => Err(KError::InvalidFile),
+        }
+    }
+
+    /// The path of `mnode_num`, if it still has one.
+    pub fn path(&self, mnode_num: Mnode) -> Option<String> {
+        self.files
+            .read()
+            .iter()
+            .find(|(_name, mnode)| ***mnode == mnode_num)
+            .map(|(name, _mnode)| name.clone())
+    }
+
+    /// Replaces the contents of a file, even if it is read-only.
+    pub fn set_contents(&self, mnode_num: Mnode, buffer: &[u8]) -> Result<usize, KError> {
+        match self.mnodes.read().get(&mnode_num) {
+            Some(mnode) => mnode.write().set_contents(buffer),
+            None => Err(KError::InvalidFile),
+        }
+    }
 }
 
 impl FileSystem for MlnrFS {
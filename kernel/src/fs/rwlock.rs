@@ -15,6 +15,7 @@ use core::ops::{Deref, DerefMut};
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use crate::arch::MAX_CORES;
+use crate::kcb::ArchSpecificKcb;
 
 use crossbeam_utils::CachePadded;
 
@@ -141,7 +142,7 @@ where
     /// Locks the underlying data-structure for reads. Allows multiple readers to acquire the lock.
     /// Blocks until there aren't any active writers.
     pub fn read(&self) -> ReadGuard<T> {
-        let tid: usize = crate::kcb::get_kcb().arch.id();
+        let tid: usize = crate::kcb::get_kcb().arch.hwthread_id();
         // We perform a small optimization. Before attempting to acquire a read lock, we issue
         // naked reads to the write lock and wait until it is free. For that, we retrieve a
         // raw pointer to the write lock over here.
@@ -15,6 +15,7 @@ use log::trace;
 use proptest::prelude::*;
 
 use super::*;
+use crate::memory::vspace::UserAccess;
 use crate::*;
 
 /// What operations that the model needs to keep track of.
@@ -157,7 +158,7 @@ impl FileSystem for ModelFS {
                 match x {
                     // Check if the file is writable or not
                     ModelOperation::Created(_path, mode, mnode) => {
-                        if mnode_num == *mnode && !FileModes::from(*mode).is_writable() {
+                        if mnode_num == *mnode && !FileModes::from(*mode).is_writable_by_any() {
                             return Err(KError::PermissionError);
                         }
                     }
@@ -237,7 +238,7 @@ impl FileSystem for ModelFS {
                     }
 
                     ModelOperation::Created(_path, mode, mnode) => {
-                        if mnode_num == *mnode && !FileModes::from(*mode).is_readable() {
+                        if mnode_num == *mnode && !FileModes::from(*mode).is_readable_by_any() {
                             return Err(KError::PermissionError);
                         }
                     }
@@ -568,7 +569,11 @@ fn test_file_read_permission_error() {
     // On error read returns 0.
     assert_eq!(
         memfs
-            .read(2, &mut UserSlice::new(buffer.as_ptr() as u64, 10), 0)
+            .read(
+                2,
+                &mut UserSlice::new(buffer.as_ptr() as u64, 10, UserAccess::Write).unwrap(),
+                0
+            )
             .is_err(),
         true
     );
@@ -589,7 +594,11 @@ fn test_file_write_permission_error() {
     );
     // On error read returns 0.
     assert_eq!(
-        memfs.write(2, &mut UserSlice::new(buffer.as_ptr() as u64, 10), 0),
+        memfs.write(
+            2,
+            &mut UserSlice::new(buffer.as_ptr() as u64, 10, UserAccess::Read).unwrap(),
+            0
+        ),
         Err(KError::PermissionError)
     );
 }
@@ -609,7 +618,11 @@ fn test_file_write() {
     );
     assert_eq!(
         memfs
-            .write(2, &mut UserSlice::new(buffer.as_ptr() as u64, 10), 0)
+            .write(
+                2,
+                &mut UserSlice::new(buffer.as_ptr() as u64, 10, UserAccess::Read).unwrap(),
+                0
+            )
             .unwrap(),
         10
     );
@@ -633,13 +646,21 @@ fn test_file_read() {
     );
     assert_eq!(
         memfs
-            .write(2, &mut UserSlice::new(wbuffer.as_ptr() as u64, len), 0)
+            .write(
+                2,
+                &mut UserSlice::new(wbuffer.as_ptr() as u64, len, UserAccess::Read).unwrap(),
+                0
+            )
             .unwrap(),
         len
     );
     assert_eq!(
         memfs
-            .read(2, &mut UserSlice::new(rbuffer.as_ptr() as u64, len), 0)
+            .read(
+                2,
+                &mut UserSlice::new(rbuffer.as_ptr() as u64, len, UserAccess::Write).unwrap(),
+                0
+            )
             .unwrap(),
         len
     );
@@ -725,11 +746,19 @@ fn test_file_delete() {
     assert_eq!(memfs.delete(filename).is_err(), true);
     assert_eq!(memfs.lookup(filename), None);
     assert_eq!(
-        memfs.write(2, &mut UserSlice::new(buffer.as_ptr() as u64, 10), 0),
+        memfs.write(
+            2,
+            &mut UserSlice::new(buffer.as_ptr() as u64, 10, UserAccess::Read).unwrap(),
+            0
+        ),
         Err(KError::InvalidFile)
     );
     assert_eq!(
-        memfs.read(2, &mut UserSlice::new(buffer.as_ptr() as u64, 10), 0),
+        memfs.read(
+            2,
+            &mut UserSlice::new(buffer.as_ptr() as u64, 10, UserAccess::Write).unwrap(),
+            0
+        ),
         Err(KError::InvalidFile)
     );
 }
@@ -754,7 +783,11 @@ fn test_file_rename_and_read() {
 
     let buffer: &mut [u8; 10] = &mut [0xb; 10];
     assert_eq!(
-        memfs.write(mnode, &mut UserSlice::new(buffer.as_ptr() as u64, 10), 0),
+        memfs.write(
+            mnode,
+            &mut UserSlice::new(buffer.as_ptr() as u64, 10, UserAccess::Read).unwrap(),
+            0
+        ),
         Ok(10)
     );
 
@@ -762,7 +795,11 @@ fn test_file_rename_and_read() {
     assert!(memfs.rename(filename, newname).is_ok());
     let mnode = memfs.lookup(newname).unwrap();
     assert_eq!(
-        memfs.read(*mnode, &mut UserSlice::new(rbuffer.as_ptr() as u64, 10), 0),
+        memfs.read(
+            *mnode,
+            &mut UserSlice::new(rbuffer.as_ptr() as u64, 10, UserAccess::Write).unwrap(),
+            0
+        ),
         Ok(10)
     );
     assert_eq!(rbuffer[0], 0xb);
@@ -783,7 +820,11 @@ fn test_file_rename_and_write() {
     assert_eq!(finfo.fsize, 0);
     let buffer: &mut [u8; 10] = &mut [0xb; 10];
     assert_eq!(
-        memfs.write(*mnode, &mut UserSlice::new(buffer.as_ptr() as u64, 10), 0),
+        memfs.write(
+            *mnode,
+            &mut UserSlice::new(buffer.as_ptr() as u64, 10, UserAccess::Read).unwrap(),
+            0
+        ),
         Ok(10)
     );
     let finfo = memfs.file_info(*mnode);
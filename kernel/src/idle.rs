@@ -0,0 +1,137 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Picks the idle state for a core that has nothing to do.
+//!
+//! Deeper states save more energy but take longer to wake up from, and they
+//! only pay off if the core stays in them for a while (the target
+//! residency). The [`Governor`] of a core predicts how long it will be idle:
+//! until its next timer at most, less if something else (an IPI, a device)
+//! woke it up earlier in the last few idle periods. It picks the deepest
+//! state that the prediction covers, out of the ones the CPU has and the
+//! command-line allows (`idle=`). Going idle and waking up is up to the
+//! architecture (see `arch::idle`).
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use kpi::system::IdleState;
+
+/// How many idle periods of a core we remember.
+const HISTORY: usize = 8;
+
+/// How long (ns) it takes to wake up from `state`.
+///
+/// The numbers Linux uses for Skylake servers, the real ones depend on the
+/// CPU (and the hypervisor).
+pub fn exit_latency_ns(state: IdleState) -> u64 {
+    match state {
+        IdleState::Poll => 0,
+        IdleState::C1 => 2_000,
+        IdleState::C1E => 10_000,
+        IdleState::C6 => 133_000,
+    }
+}
+
+/// How long (ns) a core has to stay in `state` for it to save energy
+/// compared to the state before.
+pub fn target_residency_ns(state: IdleState) -> u64 {
+    match state {
+        IdleState::Poll => 0,
+        IdleState::C1 => 2_000,
+        IdleState::C1E => 20_000,
+        IdleState::C6 => 600_000,
+    }
+}
+
+/// Picks idle states for a core from how long it was idle recently.
+#[derive(Debug, Default)]
+pub struct Governor {
+    /// The last idle periods (in ns), a ring.
+    history: [u64; HISTORY],
+    /// How many idle periods we recorded.
+    recorded: usize,
+}
+
+impl Governor {
+    pub const fn new() -> Governor {
+        Governor {
+            history: [0; HISTORY],
+            recorded: 0,
+        }
+    }
+
+    /// How long (ns) we expect to be idle if the timer goes off in
+    /// `until_timer` ns: the average of the recent idle periods, but not
+    /// longer than that.
+    pub fn predict(&self, until_timer: u64) -> u64 {
+        let periods = &self.history[..self.recorded.min(HISTORY)];
+        if periods.is_empty() {
+            return until_timer;
+        }
+        let average = periods.iter().fold(0u64, |a, p| a.saturating_add(*p)) / periods.len() as u64;
+        average.min(until_timer)
+    }
+
+    /// The deepest of `states` (sorted from the shallowest) that pays off
+    /// for the idle period we predict, `Poll` if none does.
+    pub fn select(&self, until_timer: u64, states: &[IdleState]) -> IdleState {
+        let predicted = self.predict(until_timer);
+        states
+            .iter()
+            .copied()
+            .filter(|s| target_residency_ns(*s) <= predicted)
+            .last()
+            .unwrap_or(IdleState::Poll)
+    }
+
+    /// The core was idle for `ns`.
+    pub fn record(&mut self, ns: u64) {
+        self.history[self.recorded % HISTORY] = ns;
+        self.recorded = self.recorded.wrapping_add(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ALL: &[IdleState] = &IdleState::ALL;
+
+    #[test]
+    fn select_by_timer() {
+        let governor = Governor::new();
+        assert_eq!(governor.select(1_000, ALL), IdleState::Poll);
+        assert_eq!(governor.select(5_000, ALL), IdleState::C1);
+        assert_eq!(governor.select(100_000, ALL), IdleState::C1E);
+        assert_eq!(governor.select(1_000_000_000, ALL), IdleState::C6);
+    }
+
+    #[test]
+    fn select_by_history() {
+        let mut governor = Governor::new();
+        for _i in 0..HISTORY {
+            governor.record(30_000);
+        }
+        // Something wakes us up long before the timer
+        assert_eq!(governor.predict(1_000_000_000), 30_000);
+        assert_eq!(governor.select(1_000_000_000, ALL), IdleState::C1E);
+        // The timer comes first
+        assert_eq!(governor.select(3_000, ALL), IdleState::C1);
+
+        // Long idle periods push out the short ones
+        for _i in 0..HISTORY {
+            governor.record(1_000_000);
+        }
+        assert_eq!(governor.select(1_000_000_000, ALL), IdleState::C6);
+    }
+
+    #[test]
+    fn select_from_available() {
+        let governor = Governor::new();
+        let halt_only = &[IdleState::Poll, IdleState::C1];
+        assert_eq!(governor.select(1_000_000_000, halt_only), IdleState::C1);
+        let no_c1e = &[IdleState::Poll, IdleState::C1, IdleState::C6];
+        assert_eq!(governor.select(100_000, no_c1e), IdleState::C1);
+        assert_eq!(governor.select(1_000_000_000, &[]), IdleState::Poll);
+    }
+}
@@ -0,0 +1,463 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The initial ramdisk: an archive the bootloader loads like a module.
+//!
+//! We unpack it into the file-system at boot, so configuration and test data
+//! are there before the first process starts. Programs in the archive can be
+//! started like modules: `make_process` looks them up with [`binary`] if no
+//! module has the name.
+//!
+//! We read uncompressed cpio archives in the "new ASCII" format (`cpio -o -H
+//! newc`) and POSIX tar archives (`tar --format=ustar`).
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::str;
+
+use fallible_collections::vec::FallibleVec;
+use kpi::io::FileModes;
+use log::{debug, info, warn};
+use spin::Once;
+
+use crate::arch::memory::kernel_vaddr_to_paddr;
+use crate::arch::Module;
+use crate::cnrfs::MlnrKernelNode;
+use crate::error::KError;
+use crate::fallible_string::{FallibleString, TryString};
+use crate::fs::Modes;
+use crate::memory::VAddr;
+
+/// Name of the module with the initrd (unless `initrd=` says otherwise).
+pub const INITRD_MODULE: &str = "initrd";
+
+/// Where we look for programs that are started by name only.
+const BIN_DIR: &str = "/bin/";
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+const CPIO_MAGIC: &[u8] = b"070701";
+/// Same as `CPIO_MAGIC` but with checksums (which we ignore).
+const CPIO_CRC_MAGIC: &[u8] = b"070702";
+const CPIO_HEADER_LEN: usize = 110;
+/// Name of the last entry in a cpio archive.
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const TAR_BLOCK_SIZE: usize = 512;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// The programs in the initrd, by path.
+static BINARIES: Once<Vec<(String, Module)>> = Once::new();
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// Links, devices etc. (we skip those).
+    Other,
+}
+
+/// A file or directory in the archive.
+#[derive(Debug, Copy, Clone)]
+pub struct Entry<'a> {
+    /// Directory part of the path (only tar splits long paths).
+    prefix: &'a str,
+    name: &'a str,
+    pub kind: EntryKind,
+    /// Permission bits (e.g., 0o755).
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+impl<'a> Entry<'a> {
+    /// The absolute path of the entry in our file-system (empty for the root
+    /// directory).
+    pub fn path(&self) -> Result<String, KError> {
+        let mut path = String::try_with_capacity(self.prefix.len() + self.name.len() + 2)?;
+        for part in self.prefix.split('/').chain(self.name.split('/')) {
+            if part.is_empty() || part == "." {
+                continue;
+            }
+            path.push('/');
+            path.push_str(part);
+        }
+        Ok(path)
+    }
+
+    /// The file-system modes for the permission bits of the entry.
+    fn modes(&self) -> Modes {
+        FileModes::from_posix(self.mode as u64).into()
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Format {
+    Cpio,
+    Tar,
+}
+
+/// Iterates over the entries of a cpio or tar archive.
+pub struct Archive<'a> {
+    data: &'a [u8],
+    format: Format,
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Archive<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Archive<'a>, KError> {
+        let format = if data.starts_with(CPIO_MAGIC) || data.starts_with(CPIO_CRC_MAGIC) {
+            Format::Cpio
+        } else if data.get(257..262) == Some(TAR_MAGIC) {
+            Format::Tar
+        } else {
+            return Err(KError::UnknownInitrdFormat);
+        };
+
+        Ok(Archive {
+            data,
+            format,
+            offset: 0,
+            done: false,
+        })
+    }
+
+    fn next_cpio(&mut self) -> Result<Option<Entry<'a>>, KError> {
+        let header = self
+            .data
+            .get(self.offset..self.offset + CPIO_HEADER_LEN)
+            .ok_or(KError::MalformedInitrd)?;
+        if !header.starts_with(CPIO_MAGIC) && !header.starts_with(CPIO_CRC_MAGIC) {
+            return Err(KError::MalformedInitrd);
+        }
+        // After the magic, every field is 8 hex digits
+        let field = |n: usize| parse_number(&header[6 + 8 * n..14 + 8 * n], 16);
+        let mode = field(1)? as u32;
+        let size = field(6)?;
+        let name_len = field(11)?;
+
+        // The name ends with a NUL byte, header + name and the data are
+        // padded to 4 bytes
+        let name_start = self.offset + CPIO_HEADER_LEN;
+        let name = self
+            .data
+            .get(name_start..name_start + name_len)
+            .ok_or(KError::MalformedInitrd)?;
+        let name = c_str(name)?;
+        let data_start = round_up!(name_start + name_len, 4);
+        let data = self
+            .data
+            .get(data_start..data_start + size)
+            .ok_or(KError::MalformedInitrd)?;
+        self.offset = round_up!(data_start + size, 4);
+
+        if name == CPIO_TRAILER {
+            return Ok(None);
+        }
+        let kind = match mode & 0o170000 {
+            0o100000 => EntryKind::File,
+            0o040000 => EntryKind::Directory,
+            _ => EntryKind::Other,
+        };
+        Ok(Some(Entry {
+            prefix: "",
+            name,
+            kind,
+            mode: mode & 0o7777,
+            data,
+        }))
+    }
+
+    fn next_tar(&mut self) -> Result<Option<Entry<'a>>, KError> {
+        if self.offset == self.data.len() {
+            // Some tools leave out the zero blocks at the end
+            return Ok(None);
+        }
+        let header = self
+            .data
+            .get(self.offset..self.offset + TAR_BLOCK_SIZE)
+            .ok_or(KError::MalformedInitrd)?;
+        if header.iter().all(|b| *b == 0) {
+            return Ok(None);
+        }
+        if &header[257..262] != TAR_MAGIC {
+            return Err(KError::MalformedInitrd);
+        }
+
+        let name = c_str(&header[0..100])?;
+        let mode = parse_number(&header[100..108], 8)? as u32;
+        let size = parse_number(&header[124..136], 8)?;
+        let prefix = c_str(&header[345..500])?;
+        let kind = match header[156] {
+            b'0' | b'\0' => EntryKind::File,
+            b'5' => EntryKind::Directory,
+            _ => EntryKind::Other,
+        };
+
+        let data_start = self.offset + TAR_BLOCK_SIZE;
+        let data = self
+            .data
+            .get(data_start..data_start + size)
+            .ok_or(KError::MalformedInitrd)?;
+        self.offset = round_up!(data_start + size, TAR_BLOCK_SIZE);
+
+        Ok(Some(Entry {
+            prefix,
+            name,
+            kind,
+            mode: mode & 0o7777,
+            data,
+        }))
+    }
+}
+
+impl<'a> Iterator for Archive<'a> {
+    type Item = Result<Entry<'a>, KError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let entry = match self.format {
+            Format::Cpio => self.next_cpio(),
+            Format::Tar => self.next_tar(),
+        };
+        match entry {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Parses a number field (padded with NUL bytes or spaces).
+fn parse_number(field: &[u8], radix: u32) -> Result<usize, KError> {
+    let digits = str::from_utf8(field).map_err(|_e| KError::MalformedInitrd)?;
+    let digits = digits.trim_matches(|c| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    usize::from_str_radix(digits, radix).map_err(|_e| KError::MalformedInitrd)
+}
+
+/// The string in `field` up to the first NUL byte.
+fn c_str(field: &[u8]) -> Result<&str, KError> {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..len]).map_err(|_e| KError::MalformedInitrd)
+}
+
+/// Unpacks the initrd (the module called `name`) into the file-system.
+///
+/// Does nothing if there is no such module.
+pub fn init(modules: &[Module], name: &str) -> Result<(), KError> {
+    let module = match modules.iter().find(|module| module.name() == name) {
+        Some(module) => module,
+        None => {
+            debug!("No initrd (module {} not found)", name);
+            return Ok(());
+        }
+    };
+    // Safe: The bootloader modules stay mapped in kernel space
+    let archive = unsafe { module.as_slice() };
+
+    let mut binaries = Vec::new();
+    let (mut files, mut directories) = (0, 0);
+    for entry in Archive::new(archive)? {
+        let entry = entry?;
+        let path = entry.path()?;
+        if path.is_empty() {
+            continue;
+        }
+
+        match entry.kind {
+            EntryKind::Directory => {
+                match MlnrKernelNode::kernel_mkdir(path, entry.modes()) {
+                    // Directories can be in the archive more than once
+                    Ok(_) | Err(KError::AlreadyPresent) => directories += 1,
+                    Err(e) => return Err(e),
+                }
+            }
+            EntryKind::File => {
+                if entry.data.starts_with(ELF_MAGIC) {
+                    let vaddr = VAddr::from(entry.data.as_ptr() as u64);
+                    let file_name = path.rsplit('/').next().unwrap_or("");
+                    let binary = Module::new(
+                        file_name,
+                        vaddr,
+                        kernel_vaddr_to_paddr(vaddr),
+                        entry.data.len(),
+                    );
+                    binaries.try_push((TryString::try_from(path.as_str())?.into(), binary))?;
+                }
+                let contents: Arc<[u8]> = Arc::from(entry.data);
+                MlnrKernelNode::kernel_file_create(path, entry.modes(), contents)?;
+                files += 1;
+            }
+            EntryKind::Other => warn!("Skipped {} in initrd (not a file or directory)", path),
+        }
+    }
+
+    info!(
+        "Unpacked initrd: {} files ({} programs), {} directories",
+        files,
+        binaries.len(),
+        directories
+    );
+    BINARIES.call_once(|| binaries);
+    Ok(())
+}
+
+/// Finds a program in the initrd.
+///
+/// `name` is either an absolute path or the name of a file in `/bin`.
+pub fn binary(name: &str) -> Option<&'static Module> {
+    program(name).map(|(_path, binary)| binary)
+}
+
+/// Like [`binary`], but also returns the absolute path of the program.
+pub fn program(name: &str) -> Option<(&'static str, &'static Module)> {
+    let path_matches = |path: &str| {
+        if name.starts_with('/') {
+            path == name
+        } else {
+            path.strip_prefix(BIN_DIR) == Some(name)
+        }
+    };
+
+    BINARIES
+        .get()?
+        .iter()
+        .find(|(path, _)| path_matches(path))
+        .map(|(path, binary)| (path.as_str(), binary))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+
+    /// Appends a cpio (newc) entry to `archive`.
+    fn cpio_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let fields = [
+            1,
+            mode,
+            0,
+            0,
+            1,
+            0,
+            data.len() as u32,
+            0,
+            0,
+            0,
+            0,
+            name.len() as u32 + 1,
+            0,
+        ];
+        archive.extend_from_slice(CPIO_MAGIC);
+        for field in fields.iter() {
+            archive.extend_from_slice(format!("{:08X}", field).as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(round_up!(archive.len(), 4), 0);
+        archive.extend_from_slice(data);
+        archive.resize(round_up!(archive.len(), 4), 0);
+    }
+
+    /// Appends a tar (ustar) entry to `archive`.
+    fn tar_entry(archive: &mut Vec<u8>, prefix: &str, name: &str, kind: u8, data: &[u8]) {
+        let mut header = [0u8; TAR_BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize(round_up!(archive.len(), TAR_BLOCK_SIZE), 0);
+    }
+
+    #[test]
+    fn cpio() {
+        let mut archive = Vec::new();
+        cpio_entry(&mut archive, ".", 0o040755, &[]);
+        cpio_entry(&mut archive, "bin", 0o040755, &[]);
+        cpio_entry(&mut archive, "bin/init", 0o100755, b"\x7fELF...");
+        cpio_entry(&mut archive, "./etc/motd", 0o100444, b"hello");
+        cpio_entry(&mut archive, "bin/sh", 0o120777, b"init");
+        cpio_entry(&mut archive, CPIO_TRAILER, 0, &[]);
+        // Archives are padded to a block size
+        archive.resize(archive.len() + 512, 0);
+
+        let entries: Vec<Entry> = Archive::new(&archive)
+            .expect("Not a cpio archive")
+            .collect::<Result<_, _>>()
+            .expect("Can't parse archive");
+        let paths: Vec<String> = entries.iter().map(|e| e.path().unwrap()).collect();
+        assert_eq!(paths, ["", "/bin", "/bin/init", "/etc/motd", "/bin/sh"]);
+        assert_eq!(entries[1].kind, EntryKind::Directory);
+        assert_eq!(entries[2].kind, EntryKind::File);
+        assert_eq!(entries[2].mode, 0o755);
+        assert_eq!(entries[2].data, b"\x7fELF...");
+        assert_eq!(entries[3].data, b"hello");
+        assert_eq!(
+            entries[3].modes(),
+            u64::from(FileModes::S_IRUSR | FileModes::S_IRGRP | FileModes::S_IROTH)
+        );
+        assert_eq!(entries[4].kind, EntryKind::Other);
+    }
+
+    #[test]
+    fn tar() {
+        let mut archive = Vec::new();
+        tar_entry(&mut archive, "", "data/", b'5', &[]);
+        tar_entry(&mut archive, "", "data/file.txt", b'0', &[1u8; 600]);
+        tar_entry(&mut archive, "a/long", "path", b'0', b"x");
+        archive.resize(archive.len() + 2 * TAR_BLOCK_SIZE, 0);
+
+        let entries: Vec<Entry> = Archive::new(&archive)
+            .expect("Not a tar archive")
+            .collect::<Result<_, _>>()
+            .expect("Can't parse archive");
+        let paths: Vec<String> = entries.iter().map(|e| e.path().unwrap()).collect();
+        assert_eq!(paths, ["/data", "/data/file.txt", "/a/long/path"]);
+        assert_eq!(entries[0].kind, EntryKind::Directory);
+        assert_eq!(entries[1].data.len(), 600);
+        assert_eq!(entries[1].mode, 0o644);
+        assert_eq!(
+            entries[1].modes(),
+            u64::from(
+                FileModes::S_IRUSR | FileModes::S_IWUSR | FileModes::S_IRGRP | FileModes::S_IROTH
+            )
+        );
+        assert_eq!(entries[2].data, b"x");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            Archive::new(b"not an archive").err(),
+            Some(KError::UnknownInitrdFormat)
+        );
+
+        // A file that's cut off
+        let mut archive = Vec::new();
+        cpio_entry(&mut archive, "file", 0o100644, &[0u8; 64]);
+        archive.truncate(archive.len() - 32);
+        let mut entries = Archive::new(&archive).expect("Not a cpio archive");
+        assert_eq!(entries.next().unwrap().err(), Some(KError::MalformedInitrd));
+        assert!(entries.next().is_none());
+    }
+}
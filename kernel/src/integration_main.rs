@@ -175,6 +175,447 @@ pub fn xmain() {
     arch::debug::shutdown(ExitReason::Ok);
 }
 
+/// Checks that we enumerate the PCI bus and can look up devices.
+#[cfg(all(feature = "integration-test", feature = "test-pci"))]
+pub fn xmain() {
+    use log::info;
+
+    use crate::drivers::pci::{self, PciMatch};
+
+    let devices = pci::devices();
+    assert!(!devices.is_empty(), "Found no PCI devices");
+    for dev in devices.iter() {
+        info!(
+            "PCI device {} {:04x}:{:04x}",
+            dev.address, dev.vendor_id, dev.device_id
+        );
+    }
+
+    // There is always a host bridge at 00:00.0
+    let host_bridge = pci::find(PciMatch::Class {
+        class: 0x06,
+        subclass: 0x00,
+    })
+    .expect("No host bridge found");
+    assert_eq!(host_bridge.address, pci::PciAddress::new(0, 0, 0));
+
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
+/// Checks that MSI vectors get allocated, routed and dispatched to their
+/// handler.
+#[cfg(all(feature = "integration-test", feature = "test-msi"))]
+pub fn xmain() {
+    use apic::ApicDriver;
+    use core::time::Duration;
+    use log::info;
+    use x86::apic::{
+        DeliveryMode, DeliveryStatus, DestinationMode, DestinationShorthand, Icr, Level,
+        TriggerMode,
+    };
+
+    use crate::drivers::pci::{self, Bar, PciMatch};
+    use crate::memory::vspace::MapAction;
+    use crate::memory::PAddr;
+
+    fn handler(vector: u8) {
+        // Don't change this line without changing
+        // `s02_msi` in integration-test.rs:
+        info!("Got MSI on vector {}", vector);
+        arch::debug::shutdown(ExitReason::Ok);
+    }
+
+    let kcb = crate::kcb::get_kcb();
+    let core = kcb.arch.id();
+    let vector = arch::irq::allocate_msi_vector(core, handler).expect("Can't allocate vector");
+    info!("Allocated {:?} ({:?})", vector, vector.message());
+
+    // Program (but keep masked) the first MSI-X entry of the NIC
+    if let Some(dev) = pci::find(PciMatch::Device {
+        vendor: 0x15ad,
+        device: 0x07b0,
+    }) {
+        for bar in dev.bars.iter() {
+            if let Some(Bar::Memory { base, size, .. }) = *bar {
+                kcb.arch
+                    .init_vspace()
+                    .map_identity(PAddr::from(base), size as usize, MapAction::ReadWriteKernel)
+                    .expect("Can't map BAR");
+            }
+        }
+        let nic_vector =
+            arch::irq::route_msix(&dev, 0, core, handler).expect("Can't route MSI-X entry");
+        pci::mask_msix_entry(&dev, 0, true).expect("Can't mask MSI-X entry");
+        pci::enable_msix(&dev).expect("Can't enable MSI-X");
+        info!("vmxnet3 MSI-X entry 0 uses vector {}", nic_vector.vector);
+    }
+
+    // Raise the interrupt with an IPI to ourselves
+    let icr = Icr::for_x2apic(
+        vector.vector,
+        atopology::MACHINE_TOPOLOGY.threads[core].apic_id(),
+        DestinationShorthand::NoShorthand,
+        DeliveryMode::Fixed,
+        DestinationMode::Physical,
+        DeliveryStatus::Idle,
+        Level::Assert,
+        TriggerMode::Edge,
+    );
+    unsafe { kcb.arch.apic().send_ipi(icr) };
+
+    let start = rawtime::Instant::now();
+    arch::irq::enable();
+    while start.elapsed() < Duration::from_secs(1) {
+        core::hint::spin_loop();
+    }
+    arch::irq::disable();
+
+    panic!("Didn't receive the MSI");
+}
+
+/// Reads and writes blocks on the NVMe drive (see `s02_nvme` in
+/// integration-test.rs for the contents of the disk image).
+#[cfg(all(feature = "integration-test", feature = "test-nvme"))]
+pub fn xmain() {
+    use alloc::vec;
+    use log::info;
+
+    use crate::drivers::block;
+
+    const PATTERN: &[u8] = b"nrk nvme test pattern";
+
+    let disk = block::get("nvme0").expect("No NVMe drive found");
+    let bs = disk.block_size();
+
+    let mut buf = vec![0u8; 2 * bs];
+    disk.read(0, &mut buf).expect("Read failed");
+    assert_eq!(&buf[..PATTERN.len()], PATTERN, "Unexpected contents");
+    info!("nvme read ok");
+
+    // Write block 2 (and read it back)
+    for (i, b) in buf.iter_mut().take(bs).enumerate() {
+        *b = PATTERN[i % PATTERN.len()];
+    }
+    disk.write(2, &buf[..bs]).expect("Write failed");
+    disk.flush().expect("Flush failed");
+    let mut check = vec![0u8; bs];
+    disk.read(2, &mut check).expect("Read failed");
+    assert_eq!(&check[..], &buf[..bs]);
+    info!("nvme write ok");
+
+    // More pages than a hardware queue has tags, so requests have to wait
+    // for others to complete
+    let len = 96 * 4096;
+    let data: alloc::vec::Vec<u8> = (0..len).map(|i| (i / bs ^ i) as u8).collect();
+    disk.write(64, &data).expect("Multi-page write failed");
+    let mut check = vec![0u8; len];
+    disk.read(64, &mut check).expect("Multi-page read failed");
+    assert!(check == data, "Multi-page read returned different data");
+    info!("nvme multi-page ok");
+
+    assert!(disk.read(disk.num_blocks(), &mut check[..bs]).is_err());
+
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
+/// Reads and writes blocks on a SATA disk (see `s02_ahci` in
+/// integration-test.rs for the contents of the disk image).
+#[cfg(all(feature = "integration-test", feature = "test-ahci"))]
+pub fn xmain() {
+    use alloc::vec;
+    use log::info;
+
+    use crate::drivers::block;
+
+    const PATTERN: &[u8] = b"nrk ahci test pattern";
+
+    let disk = block::get("sata0").expect("No SATA disk found");
+    let bs = disk.block_size();
+
+    let mut buf = vec![0u8; 2 * bs];
+    disk.read(0, &mut buf).expect("Read failed");
+    assert_eq!(&buf[..PATTERN.len()], PATTERN, "Unexpected contents");
+    info!("ahci read ok");
+
+    // Write block 2 (and read it back)
+    for (i, b) in buf.iter_mut().take(bs).enumerate() {
+        *b = PATTERN[i % PATTERN.len()];
+    }
+    disk.write(2, &buf[..bs]).expect("Write failed");
+    disk.flush().expect("Flush failed");
+    let mut check = vec![0u8; bs];
+    disk.read(2, &mut check).expect("Read failed");
+    assert_eq!(&check[..], &buf[..bs]);
+
+    // A transfer that needs more than one command
+    let mut large = vec![0u8; 256 * 1024];
+    disk.read(0, &mut large).expect("Large read failed");
+    assert_eq!(&large[..PATTERN.len()], PATTERN);
+    assert_eq!(&large[2 * bs..3 * bs], &buf[..bs]);
+    info!("ahci write ok");
+
+    assert!(disk.read(disk.num_blocks(), &mut check).is_err());
+
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
+/// Logs enough lines to scroll the framebuffer console (set up during boot
+/// if the bootloader gave us a framebuffer).
+#[cfg(all(feature = "integration-test", feature = "test-framebuffer"))]
+pub fn xmain() {
+    use log::info;
+
+    for i in 0..200 {
+        info!("framebuffer line {}", i);
+    }
+    info!("framebuffer ok");
+
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
+/// Makes the PS/2 controller report key presses (as if they came from the
+/// keyboard) and checks that they end up in the input queue.
+#[cfg(all(feature = "integration-test", feature = "test-ps2"))]
+pub fn xmain() {
+    use kpi::system::KeyEvent;
+    use log::info;
+
+    use crate::drivers::{input, ps2};
+
+    // Shift+n, k
+    for byte in &[0x12, 0x31, 0xf0, 0x31, 0xf0, 0x12, 0x42, 0xf0, 0x42] {
+        ps2::command(ps2::CMD_WRITE_PORT1_OUTPUT).expect("Can't write command");
+        ps2::write(*byte).expect("Can't write scancode");
+        // Interrupts are disabled here so we decode ourselves
+        ps2::poll();
+    }
+
+    let mut events = [KeyEvent::default(); 16];
+    let read = input::pop(&mut events);
+    assert_eq!(read, 6);
+    let typed: alloc::string::String = events[..read]
+        .iter()
+        .filter(|e| e.ascii != 0)
+        .map(|e| e.ascii as char)
+        .collect();
+    info!("ps2 typed {}", typed);
+    assert_eq!(typed, "Nk");
+    info!("ps2 ok");
+
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
+/// Logs a lot of lines quickly (`s02_virtio_console` checks that all of
+/// them made it to the virtio console).
+#[cfg(all(feature = "integration-test", feature = "test-virtio-console"))]
+pub fn xmain() {
+    use log::info;
+
+    for i in 0..1000 {
+        info!("virtio console line {}", i);
+    }
+    info!("virtio console ok");
+
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
+/// Checks that the HPET and TSC clocksources agree and that we can switch
+/// between them.
+#[cfg(all(
+    feature = "integration-test",
+    feature = "test-clocksource",
+    target_arch = "x86_64"
+))]
+pub fn xmain() {
+    use core::time::Duration;
+    use log::info;
+
+    use crate::time::clocksource::{self, duration_to_ticks, ticks_to_duration};
+
+    let hpet = clocksource::get("hpet").expect("HPET not registered");
+    let tsc = clocksource::get("tsc").expect("TSC not registered");
+
+    // Measure 100 ms of HPET time with another source
+    let measure = |source: &dyn clocksource::ClockSource| {
+        let ticks = duration_to_ticks(Duration::from_millis(100), hpet.frequency());
+        let (hpet_start, start) = (hpet.read(), source.read());
+        while hpet.read().wrapping_sub(hpet_start) & hpet.mask() < ticks {
+            core::hint::spin_loop();
+        }
+        let measured = ticks_to_duration(source.read() - start, source.frequency());
+        info!("100 ms of hpet are {:?} of {}", measured, source.name());
+        assert!(measured > Duration::from_millis(95) && measured < Duration::from_millis(105));
+    };
+    measure(tsc);
+
+    // The paravirtual clocks are preferred if the hypervisor has them
+    for name in &["kvm-clock", "hyperv-tsc"] {
+        if let Some(source) = clocksource::get(name) {
+            measure(source);
+            assert_eq!(clocksource::current().unwrap().rating(), 400);
+        }
+    }
+
+    clocksource::select("hpet").expect("Can't select HPET");
+    assert_eq!(clocksource::current().unwrap().name(), "hpet");
+    clocksource::select("tsc").expect("Can't select TSC");
+    assert_eq!(clocksource::current().unwrap().name(), "tsc");
+    assert!(clocksource::select("pit").is_err());
+
+    info!("clocksource ok");
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
+/// Spins with interrupts disabled until the (i6300esb) watchdog fires.
+#[cfg(all(
+    feature = "integration-test",
+    feature = "test-watchdog",
+    target_arch = "x86_64"
+))]
+pub fn xmain() {
+    use core::time::Duration;
+    use log::info;
+
+    crate::drivers::i6300esb::set_timeout(Duration::from_secs(2)).expect("No i6300esb watchdog");
+    info!("Spinning with interrupts off");
+    arch::irq::disable();
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Checks that the PIT override from the MADT is respected and that a key
+/// press on the PS/2 port arrives through the IO-APIC.
+#[cfg(all(feature = "integration-test", feature = "test-ioapic"))]
+pub fn xmain() {
+    use core::time::Duration;
+
+    use kpi::system::KeyEvent;
+    use log::info;
+
+    use crate::drivers::{input, ps2};
+
+    // QEMU connects the PIT to GSI 2
+    assert_eq!(arch::ioapic::isa_route(0).gsi, 2);
+
+    arch::irq::enable();
+    ps2::command(ps2::CMD_WRITE_PORT1_OUTPUT).expect("Can't write command");
+    ps2::write(0x31).expect("Can't write scancode");
+
+    let mut events = [KeyEvent::default(); 4];
+    let start = rawtime::Instant::now();
+    let mut read = 0;
+    while read == 0 && start.elapsed() < Duration::from_secs(1) {
+        read = input::pop(&mut events);
+    }
+    arch::irq::disable();
+
+    assert_eq!(read, 1, "Key press didn't raise an interrupt");
+    assert_eq!(events[0].ascii, b'n');
+    info!("ioapic ok");
+
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
+/// Takes a core offline and brings it back (twice).
+#[cfg(all(feature = "integration-test", feature = "test-hotplug"))]
+pub fn xmain() {
+    use log::info;
+
+    use crate::error::KError;
+    use arch::{coreboot, hotplug};
+
+    assert_eq!(hotplug::offline(0), Err(KError::CoreNotParkable));
+    assert_eq!(hotplug::online(2), Err(KError::CoreOnline));
+
+    for _round in 0..2 {
+        hotplug::offline(2).expect("Can't take core offline");
+        assert!(!coreboot::is_online(2));
+        assert_eq!(hotplug::offline(2), Err(KError::CoreOffline));
+
+        hotplug::online(2).expect("Can't bring core back");
+        assert!(coreboot::is_online(2));
+    }
+    info!("hotplug ok");
+
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
+/// Makes the other cores dump their state with an NMI (they keep running
+/// afterwards).
+#[cfg(all(feature = "integration-test", feature = "test-nmi"))]
+pub fn xmain() {
+    use log::info;
+
+    let others = atopology::MACHINE_TOPOLOGY.num_threads() - 1;
+    assert_eq!(arch::nmi::nmi_all(), others);
+    // They went back to what they did and answer again
+    assert_eq!(arch::nmi::nmi_all(), others);
+    info!("nmi ok");
+
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
+/// Test that machine-check reporting is enabled and that the #MC handler
+/// returns to the kernel if there is nothing fatal logged.
+#[cfg(all(feature = "integration-test", feature = "test-mce"))]
+pub fn xmain() {
+    use log::info;
+
+    // Doing it again doesn't hurt
+    assert!(arch::mce::init() > 0);
+    arch::debug::cause_machine_check();
+    arch::debug::cause_machine_check();
+    info!("mce ok");
+
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
+/// Test that a panic writes a crash dump (to the disk given with
+/// `crashdump=nvme0`).
+#[cfg(all(feature = "integration-test", feature = "test-crashdump"))]
+pub fn xmain() {
+    panic!("crashdump test");
+}
+
+/// Reads the clock and the variables through the UEFI runtime services.
+#[cfg(all(feature = "integration-test", feature = "test-efi"))]
+pub fn xmain() {
+    use alloc::vec;
+    use log::info;
+
+    use crate::error::KError;
+    use arch::{efi, rtc};
+
+    // The firmware reads the same RTC we do
+    let now = efi::time().expect("Can't read the UEFI clock").as_secs();
+    let rtc = rtc::now().unix_time();
+    assert!(
+        now.max(rtc) - now.min(rtc) <= 2,
+        "UEFI time {} isn't RTC time {}",
+        now,
+        rtc
+    );
+
+    let variables = efi::variables().expect("Can't list the UEFI variables");
+    info!("{} UEFI variables", variables.len());
+    let (guid, name) = variables.first().expect("Have no UEFI variables");
+    let needed = match efi::variable(guid, name, &mut []) {
+        Err(KError::EfiBufferTooSmall { needed }) => needed,
+        r => panic!("Read {}-{} into an empty buffer: {:?}", name, guid, r),
+    };
+    let mut data = vec![0; needed];
+    let (size, _attributes) = efi::variable(guid, name, &mut data).expect("Can't read variable");
+    assert_eq!(size, needed);
+    assert_eq!(
+        efi::variable(&efi::GLOBAL_VARIABLE, "NoSuchVariable", &mut data),
+        Err(KError::EfiVariableNotFound)
+    );
+    info!("efi ok");
+
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
 /// Checks that we can initialize ACPI, query the ACPI tables
 /// and correctly parse a large NUMA topology (8 sockets, 80 cores).
 #[cfg(all(feature = "integration-test", feature = "test-acpi-topology"))]
@@ -406,6 +847,45 @@ pub fn xmain() {
     arch::debug::shutdown(ExitReason::Ok);
 }
 
+/// Checks the NUMA view for the layout `s03_numa` gives QEMU (all cores
+/// have booted at this point).
+#[cfg(all(feature = "integration-test", feature = "test-numa"))]
+pub fn xmain() {
+    use log::info;
+
+    use crate::memory::PhysicalPageProvider;
+    use crate::numa;
+
+    // 0: cores and memory, 1: only cores, 2: only memory, 3: both
+    assert_eq!(numa::nodes(), 4);
+    for (node, (cores, memory)) in [(true, true), (true, false), (false, true), (true, true)]
+        .iter()
+        .enumerate()
+    {
+        assert_eq!(numa::has_cores(node), *cores, "Cores of node {}", node);
+        assert_eq!(numa::has_memory(node), *memory, "Memory of node {}", node);
+    }
+    assert_eq!(numa::distance(0, 2), 30);
+    assert_eq!(numa::distance(2, 0), 40);
+    assert_eq!(numa::memory_node(1), 2);
+    assert_eq!(numa::replica_node(2), 1);
+    assert_eq!(numa::max_cores_per_node(), 2);
+
+    // Every node gets memory (from where it should)
+    let gmanager = crate::kcb::get_kcb().physical_memory.gmanager.unwrap();
+    for node in 0..numa::nodes() {
+        let frame = gmanager.node_caches[numa::memory_node(node)]
+            .lock()
+            .allocate_large_page()
+            .expect("Can't allocate on node");
+        assert_eq!(frame.affinity, numa::memory_node(node));
+        crate::memory::release_frame(frame).expect("Can't release frame");
+    }
+    info!("numa ok");
+
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
 /// Test process loading / user-space.
 #[cfg(all(
     feature = "integration-test",
@@ -413,7 +893,7 @@ pub fn xmain() {
 ))]
 pub fn xmain() {
     let kcb = kcb::get_kcb();
-    assert!(crate::arch::process::spawn(kcb.cmdline.init_binary).is_ok());
+    assert!(crate::arch::process::spawn(kcb.config.init_binary, kcb.config.init_creds).is_ok());
     crate::scheduler::schedule()
 }
 
@@ -449,6 +929,19 @@ pub fn xmain() {
     arch::debug::shutdown(ExitReason::ReturnFromMain);
 }
 
+/// Test that we notice when something wrote past the end of a kernel stack.
+#[cfg(all(feature = "integration-test", feature = "test-stack-canary"))]
+pub fn xmain() {
+    let kcb = kcb::get_kcb();
+    kcb.arch.check_stack_canaries();
+
+    // What a syscall handler that ran out of stack would do
+    unsafe { (kcb.arch.syscall_stack_limit() as *mut u64).write_volatile(0) };
+    kcb.arch.check_stack_canaries();
+
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
 /// Test shootdown facilities in the kernel.
 #[cfg(all(
     feature = "integration-test",
@@ -745,3 +1238,69 @@ pub fn xmain() -> Result<(), crate::error::KError> {
     }
     arch::debug::shutdown(ExitReason::Ok);
 }
+
+/// Test that the kernel network stack gets configured from the command-line
+/// (`net=dhcp` or `net=static:...`).
+#[cfg(all(
+    feature = "integration-test",
+    feature = "test-net-config",
+    target_arch = "x86_64"
+))]
+pub fn xmain() {
+    {
+        let stack = crate::net::NET_STACK.lock();
+        let stack = stack.as_ref().expect("Network stack not initialized?");
+        let addr = stack
+            .iface
+            .ipv4_address()
+            .expect("Interface has no IPv4 address?");
+        assert!(!addr.is_unspecified());
+        // Don't change the next line without changing `integration-test.rs`
+        info!("net_config: interface has address {}", addr);
+    }
+
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
+/// Test the RPC transport: Serves an echo service for requests sent by the
+/// host (see `s03_rpc_echo` in `integration-test.rs`).
+#[cfg(all(
+    feature = "integration-test",
+    feature = "test-rpc",
+    target_arch = "x86_64"
+))]
+pub fn xmain() {
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::time::Duration;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn echo(request: &[u8], response: &mut Vec<u8>) -> Result<(), crate::error::KError> {
+        let n = CALLS.fetch_add(1, Ordering::SeqCst) + 1;
+        info!("rpc_echo: request #{} ({} bytes)", n, request.len());
+        response.extend_from_slice(request);
+        Ok(())
+    }
+
+    crate::rpc::register(1, echo).expect("Can't register echo service");
+    info!("rpc_test: serving on port {}", crate::rpc::RPC_PORT);
+
+    // The host sends two requests (and retransmits the first one which must
+    // not execute again)
+    let start = rawtime::Instant::now();
+    while CALLS.load(Ordering::SeqCst) < 2 {
+        assert!(start.elapsed() < Duration::from_secs(20), "Timed out");
+        crate::rpc::poll();
+    }
+
+    // Keep serving for a bit longer to catch duplicate executions
+    let start = rawtime::Instant::now();
+    while start.elapsed() < Duration::from_millis(500) {
+        crate::rpc::poll();
+    }
+    assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+
+    info!("rpc_test OK");
+    arch::debug::shutdown(ExitReason::Ok);
+}
@@ -0,0 +1,357 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Doors: synchronous calls between processes (see `kpi::ipc`).
+//!
+//! The table only keeps the calls, the syscall handler does the rest: it
+//! transfers the capabilities in the messages, halts cores that wait for a
+//! call or a reply and wakes them up with an IPI. A call goes through three
+//! states: the client queues it, the server receives it and replies, the
+//! client picks up the reply (which removes the call).
+//!
+//! Doors stay until the kernel shuts down, processes don't go away before.
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use fallible_collections::FallibleVec;
+use kpi::ipc::{Message, MAX_DOOR_NAME};
+use spin::Mutex;
+
+use crate::error::KError;
+use crate::event::EventId;
+use crate::fallible_string::TryString;
+use crate::process::Pid;
+
+/// How many doors there can be.
+pub const MAX_DOORS: usize = 64;
+
+/// How many calls a door can have at the same time.
+pub const MAX_CALLS: usize = 64;
+
+pub type DoorId = usize;
+
+#[derive(Debug)]
+enum CallState {
+    /// Waits for the server, with the request.
+    Queued(Message),
+    /// The server has the request.
+    Received,
+    /// Waits for the client, with the reply.
+    Replied(Message),
+}
+
+#[derive(Debug)]
+struct Call {
+    id: u64,
+    client: Pid,
+    /// The core of the client that waits for the reply.
+    core: Option<usize>,
+    state: CallState,
+}
+
+#[derive(Debug)]
+struct Door {
+    name: String,
+    owner: Pid,
+    next_call: u64,
+    calls: Vec<Call>,
+    /// The core of the server that waits for a call.
+    server: Option<usize>,
+    /// The event to signal when a call arrives.
+    notify: Option<EventId>,
+}
+
+impl Door {
+    fn call(&mut self, id: u64, client: Pid) -> Result<&mut Call, KError> {
+        self.calls
+            .iter_mut()
+            .find(|c| c.id == id && c.client == client)
+            .ok_or(KError::InvalidCall)
+    }
+}
+
+/// All doors, a door id is its index.
+#[derive(Debug, Default)]
+pub struct Doors {
+    doors: Vec<Door>,
+}
+
+impl Doors {
+    pub const fn new() -> Doors {
+        Doors { doors: Vec::new() }
+    }
+
+    fn door(&mut self, door: DoorId) -> Result<&mut Door, KError> {
+        self.doors.get_mut(door).ok_or(KError::DoorNotFound)
+    }
+
+    /// The door of `owner` that `owner` serves.
+    fn served(&mut self, door: DoorId, owner: Pid) -> Result<&mut Door, KError> {
+        let door = self.door(door)?;
+        if door.owner != owner {
+            return Err(KError::InsufficientRights);
+        }
+        Ok(door)
+    }
+
+    /// Adds a door called `name` that `owner` serves.
+    pub fn create(&mut self, name: &str, owner: Pid) -> Result<DoorId, KError> {
+        if name.is_empty() || name.len() > MAX_DOOR_NAME {
+            return Err(KError::InvalidDoorName);
+        }
+        if self.lookup(name).is_ok() {
+            return Err(KError::DoorExists);
+        }
+        if self.doors.len() >= MAX_DOORS {
+            return Err(KError::TooManyDoors);
+        }
+
+        self.doors.try_push(Door {
+            name: TryString::try_from(name)?.into(),
+            owner,
+            next_call: 1,
+            calls: Vec::new(),
+            server: None,
+            notify: None,
+        })?;
+        Ok(self.doors.len() - 1)
+    }
+
+    pub fn lookup(&self, name: &str) -> Result<DoorId, KError> {
+        self.doors
+            .iter()
+            .position(|d| d.name == name)
+            .ok_or(KError::DoorNotFound)
+    }
+
+    /// The process that serves `door`.
+    pub fn owner(&mut self, door: DoorId) -> Result<Pid, KError> {
+        Ok(self.door(door)?.owner)
+    }
+
+    /// Signals `event` whenever a call arrives at `door` (never for
+    /// `None`).
+    pub fn set_notify(
+        &mut self,
+        door: DoorId,
+        owner: Pid,
+        event: Option<EventId>,
+    ) -> Result<(), KError> {
+        self.served(door, owner)?.notify = event;
+        Ok(())
+    }
+
+    /// The event to signal for a call to `door`.
+    pub fn notify(&mut self, door: DoorId) -> Result<Option<EventId>, KError> {
+        Ok(self.door(door)?.notify)
+    }
+
+    /// The process that made call `id` (as long as the server didn't reply).
+    pub fn client(&mut self, door: DoorId, owner: Pid, id: u64) -> Result<Pid, KError> {
+        self.served(door, owner)?
+            .calls
+            .iter()
+            .find(|c| c.id == id && matches!(c.state, CallState::Received))
+            .map(|c| c.client)
+            .ok_or(KError::InvalidCall)
+    }
+
+    /// Queues a call with `request` from `client`, returns the call id and
+    /// the core of the server to wake up (if it waits).
+    pub fn call(
+        &mut self,
+        door: DoorId,
+        client: Pid,
+        request: Message,
+    ) -> Result<(u64, Option<usize>), KError> {
+        let door = self.door(door)?;
+        if door.calls.len() >= MAX_CALLS {
+            return Err(KError::TooManyCalls);
+        }
+
+        let id = door.next_call;
+        door.calls.try_push(Call {
+            id,
+            client,
+            core: None,
+            state: CallState::Queued(request),
+        })?;
+        door.next_call += 1;
+        Ok((id, door.server.take()))
+    }
+
+    /// Takes the reply to call `id` of `client` or, if there is none yet,
+    /// remembers that `core` waits for it.
+    pub fn await_reply(
+        &mut self,
+        door: DoorId,
+        client: Pid,
+        id: u64,
+        core: usize,
+    ) -> Result<Option<Message>, KError> {
+        let door = self.door(door)?;
+        let call = door.call(id, client)?;
+        if let CallState::Replied(reply) = call.state {
+            door.calls.retain(|c| c.id != id);
+            Ok(Some(reply))
+        } else {
+            call.core = Some(core);
+            Ok(None)
+        }
+    }
+
+    /// Posts `reply` for the received call `id`, returns the core of the
+    /// client to wake up (if it waits).
+    pub fn reply(
+        &mut self,
+        door: DoorId,
+        owner: Pid,
+        id: u64,
+        reply: Message,
+    ) -> Result<Option<usize>, KError> {
+        let door = self.served(door, owner)?;
+        let call = door
+            .calls
+            .iter_mut()
+            .find(|c| c.id == id && matches!(c.state, CallState::Received))
+            .ok_or(KError::InvalidCall)?;
+        call.state = CallState::Replied(reply);
+        Ok(call.core.take())
+    }
+
+    /// Hands the oldest queued call to the server or, if there is none,
+    /// remembers that `core` waits for one.
+    pub fn receive(
+        &mut self,
+        door: DoorId,
+        owner: Pid,
+        core: usize,
+    ) -> Result<Option<(u64, Message)>, KError> {
+        let door = self.served(door, owner)?;
+        for call in door.calls.iter_mut() {
+            if let CallState::Queued(request) = call.state {
+                call.state = CallState::Received;
+                return Ok(Some((call.id, request)));
+            }
+        }
+        door.server = Some(core);
+        Ok(None)
+    }
+}
+
+/// The doors of all processes.
+pub static DOORS: Mutex<Doors> = Mutex::new(Doors::new());
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn msg(data: &[u8]) -> Message {
+        Message::new(data).unwrap()
+    }
+
+    #[test]
+    fn names() {
+        let mut doors = Doors::default();
+        let a = doors.create("fs", 1).unwrap();
+        let b = doors.create("net", 2).unwrap();
+        assert_ne!(a, b);
+        assert_eq!(doors.lookup("net"), Ok(b));
+        assert_eq!(doors.lookup("nfs"), Err(KError::DoorNotFound));
+        assert_eq!(doors.create("fs", 2), Err(KError::DoorExists));
+        assert_eq!(doors.create("", 2), Err(KError::InvalidDoorName));
+        assert_eq!(doors.owner(b), Ok(2));
+    }
+
+    #[test]
+    fn call_and_reply() {
+        let mut doors = Doors::default();
+        let door = doors.create("echo", 1).unwrap();
+
+        // The server waits first
+        assert_eq!(doors.receive(door, 1, 3).unwrap().map(|r| r.0), None);
+        let (id, server) = doors.call(door, 2, msg(b"ping")).unwrap();
+        assert_eq!(server, Some(3));
+        assert!(doors.await_reply(door, 2, id, 0).unwrap().is_none());
+
+        let (received, request) = doors.receive(door, 1, 3).unwrap().unwrap();
+        assert_eq!(received, id);
+        assert_eq!(request.data(), b"ping");
+        assert_eq!(doors.client(door, 1, id), Ok(2));
+        assert_eq!(doors.reply(door, 1, id, msg(b"pong")), Ok(Some(0)));
+
+        let reply = doors.await_reply(door, 2, id, 0).unwrap().unwrap();
+        assert_eq!(reply.data(), b"pong");
+        // Gone once the client has the reply
+        assert_eq!(
+            doors.await_reply(door, 2, id, 0).map(|_r| ()),
+            Err(KError::InvalidCall)
+        );
+    }
+
+    #[test]
+    fn calls_in_order() {
+        let mut doors = Doors::default();
+        let door = doors.create("echo", 1).unwrap();
+        let (a, server) = doors.call(door, 2, msg(b"a")).unwrap();
+        let (b, _server) = doors.call(door, 3, msg(b"b")).unwrap();
+        assert_eq!(server, None);
+        assert_ne!(a, b);
+
+        assert_eq!(doors.receive(door, 1, 0).unwrap().map(|r| r.0), Some(a));
+        assert_eq!(doors.receive(door, 1, 0).unwrap().map(|r| r.0), Some(b));
+        assert_eq!(doors.receive(door, 1, 0).unwrap().map(|r| r.0), None);
+    }
+
+    #[test]
+    fn notify() {
+        let mut doors = Doors::default();
+        let door = doors.create("echo", 1).unwrap();
+        assert_eq!(doors.notify(door), Ok(None));
+        assert_eq!(
+            doors.set_notify(door, 2, Some(7)),
+            Err(KError::InsufficientRights)
+        );
+        doors.set_notify(door, 1, Some(7)).unwrap();
+        assert_eq!(doors.notify(door), Ok(Some(7)));
+    }
+
+    #[test]
+    fn wrong_process() {
+        let mut doors = Doors::default();
+        let door = doors.create("echo", 1).unwrap();
+        let (id, _server) = doors.call(door, 2, msg(b"a")).unwrap();
+
+        // Only the owner serves and only the client gets the reply
+        assert_eq!(
+            doors.receive(door, 2, 0).map(|_r| ()),
+            Err(KError::InsufficientRights)
+        );
+        assert_eq!(
+            doors.await_reply(door, 3, id, 0).map(|_r| ()),
+            Err(KError::InvalidCall)
+        );
+        // Can't reply before receiving
+        assert_eq!(
+            doors.reply(door, 1, id, Message::empty()),
+            Err(KError::InvalidCall)
+        );
+    }
+
+    #[test]
+    fn too_many_calls() {
+        let mut doors = Doors::default();
+        let door = doors.create("echo", 1).unwrap();
+        for _i in 0..MAX_CALLS {
+            doors.call(door, 2, Message::empty()).unwrap();
+        }
+        assert_eq!(
+            doors.call(door, 2, Message::empty()).map(|_r| ()),
+            Err(KError::TooManyCalls)
+        );
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Interval timers of processes (see `kpi::syscalls::Time::timer_create`).
+//!
+//! A timer belongs to a process and goes off on one core. `expire` counts
+//! the expirations and tells which cores have some to deliver, the core
+//! `take`s them once it can upcall the process (see `arch::irq`). Deadlines
+//! and intervals are in whatever unit the caller uses for "now" (TSC ticks
+//! on x86).
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::vec::Vec;
+
+use fallible_collections::FallibleVec;
+use kpi::time::MAX_TIMERS;
+use spin::Mutex;
+
+use crate::error::KError;
+use crate::process::Pid;
+
+pub type TimerId = usize;
+
+#[derive(Debug, Eq, PartialEq)]
+struct Timer {
+    pid: Pid,
+    /// The core that gets the upcalls.
+    core: usize,
+    /// When it goes off next (`None` once a one-shot timer went off).
+    deadline: Option<u64>,
+    /// Set for periodic timers.
+    interval: Option<u64>,
+    /// Expirations we didn't deliver yet.
+    expired: u64,
+}
+
+/// All interval timers, a timer id is its index.
+#[derive(Debug, Default)]
+pub struct Timers {
+    timers: Vec<Option<Timer>>,
+}
+
+impl Timers {
+    pub const fn new() -> Timers {
+        Timers { timers: Vec::new() }
+    }
+
+    /// Adds a timer of `pid` that goes off on `core` at `deadline` (and
+    /// every `interval` after that if there is one).
+    pub fn create(
+        &mut self,
+        pid: Pid,
+        core: usize,
+        deadline: u64,
+        interval: Option<u64>,
+    ) -> Result<TimerId, KError> {
+        let timer = Timer {
+            pid,
+            core,
+            deadline: Some(deadline),
+            interval: interval.map(|i| i.max(1)),
+            expired: 0,
+        };
+
+        if let Some(free) = self.timers.iter().position(Option::is_none) {
+            self.timers[free] = Some(timer);
+            return Ok(free);
+        }
+        if self.timers.len() >= MAX_TIMERS {
+            return Err(KError::TooManyTimers);
+        }
+        self.timers.try_push(Some(timer))?;
+        Ok(self.timers.len() - 1)
+    }
+
+    /// Removes `timer` (if it belongs to `pid`).
+    pub fn cancel(&mut self, pid: Pid, timer: TimerId) -> Result<(), KError> {
+        match self.timers.get_mut(timer) {
+            Some(slot) if slot.as_ref().map_or(false, |t| t.pid == pid) => {
+                *slot = None;
+                Ok(())
+            }
+            _ => Err(KError::TimerNotFound),
+        }
+    }
+
+    /// The earliest deadline of a timer on `core`.
+    pub fn next_deadline(&self, core: usize) -> Option<u64> {
+        self.timers
+            .iter()
+            .flatten()
+            .filter(|t| t.core == core)
+            .filter_map(|t| t.deadline)
+            .min()
+    }
+
+    /// Does `core` have expirations to deliver?
+    pub fn has_expired(&self, core: usize) -> bool {
+        self.timers
+            .iter()
+            .flatten()
+            .any(|t| t.core == core && t.expired > 0)
+    }
+
+    /// Counts the expirations up to `now`, returns the cores that have new
+    /// ones to deliver.
+    ///
+    /// A periodic timer that missed a few intervals counts all of them and
+    /// goes off next on its schedule (not `interval` after `now`).
+    pub fn expire(&mut self, now: u64) -> Vec<usize> {
+        let mut cores = Vec::new();
+        for timer in self.timers.iter_mut().flatten() {
+            let deadline = match timer.deadline {
+                Some(deadline) if deadline <= now => deadline,
+                _ => continue,
+            };
+
+            match timer.interval {
+                Some(interval) => {
+                    let missed = (now - deadline) / interval + 1;
+                    timer.expired += missed;
+                    timer.deadline = Some(deadline + missed * interval);
+                }
+                None => {
+                    timer.expired += 1;
+                    timer.deadline = None;
+                }
+            }
+            if !cores.contains(&timer.core) {
+                // Only fails without memory, the core finds out on its
+                // next timer interrupt
+                let _r = cores.try_push(timer.core);
+            }
+        }
+        cores
+    }
+
+    /// Takes the expirations of a timer of `pid` on `core`, returns the
+    /// timer and how often it went off.
+    ///
+    /// A one-shot timer is gone once its expiration is taken.
+    pub fn take(&mut self, pid: Pid, core: usize) -> Option<(TimerId, u64)> {
+        let (id, slot) = self.timers.iter_mut().enumerate().find(|(_id, slot)| {
+            slot.as_ref()
+                .map_or(false, |t| t.pid == pid && t.core == core && t.expired > 0)
+        })?;
+
+        let timer = slot.as_mut()?;
+        let expired = core::mem::take(&mut timer.expired);
+        if timer.deadline.is_none() {
+            *slot = None;
+        }
+        Some((id, expired))
+    }
+}
+
+/// The interval timers of all processes.
+pub static TIMERS: Mutex<Timers> = Mutex::new(Timers::new());
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn oneshot() {
+        let mut timers = Timers::default();
+        let t = timers.create(1, 2, 100, None).unwrap();
+        assert_eq!(timers.next_deadline(2), Some(100));
+        assert_eq!(timers.next_deadline(1), None);
+
+        assert_eq!(timers.expire(50), Vec::<usize>::new());
+        assert_eq!(timers.take(1, 2), None);
+        assert_eq!(timers.expire(150), vec![2]);
+        assert!(timers.has_expired(2));
+        assert_eq!(timers.next_deadline(2), None);
+        // Only the process that owns it on its core gets it
+        assert_eq!(timers.take(2, 2), None);
+        assert_eq!(timers.take(1, 3), None);
+        assert_eq!(timers.take(1, 2), Some((t, 1)));
+        assert_eq!(timers.take(1, 2), None);
+        assert_eq!(timers.cancel(1, t), Err(KError::TimerNotFound));
+    }
+
+    #[test]
+    fn periodic() {
+        let mut timers = Timers::default();
+        let t = timers.create(1, 0, 100, Some(100)).unwrap();
+        assert_eq!(timers.expire(100), vec![0]);
+        assert_eq!(timers.next_deadline(0), Some(200));
+        // Missed two more, next one stays on schedule
+        assert_eq!(timers.expire(450), vec![0]);
+        assert_eq!(timers.next_deadline(0), Some(500));
+        assert_eq!(timers.take(1, 0), Some((t, 4)));
+        assert!(!timers.has_expired(0));
+
+        assert_eq!(timers.cancel(2, t), Err(KError::TimerNotFound));
+        assert_eq!(timers.cancel(1, t), Ok(()));
+        assert_eq!(timers.next_deadline(0), None);
+        assert_eq!(timers.expire(1000), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn reuse() {
+        let mut timers = Timers::default();
+        let a = timers.create(1, 0, 100, None).unwrap();
+        let b = timers.create(1, 1, 50, None).unwrap();
+        assert_ne!(a, b);
+        timers.cancel(1, a).unwrap();
+        assert_eq!(timers.create(2, 0, 10, None), Ok(a));
+        assert_eq!(timers.expire(200), vec![0, 1]);
+        assert_eq!(timers.take(1, 1), Some((b, 1)));
+        assert_eq!(timers.take(2, 0), Some((a, 1)));
+    }
+
+    #[test]
+    fn limit() {
+        let mut timers = Timers::default();
+        for _i in 0..MAX_TIMERS {
+            timers.create(1, 0, 100, None).unwrap();
+        }
+        assert_eq!(timers.create(1, 0, 100, None), Err(KError::TooManyTimers));
+        timers.cancel(1, 7).unwrap();
+        assert_eq!(timers.create(1, 0, 100, None), Ok(7));
+    }
+}
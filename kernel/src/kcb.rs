@@ -2,203 +2,153 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 //! KCB is the local kernel control that stores all core local state.
+//!
+//! Subsystems that need per-core state of their own don't have to add a
+//! field here: [`register`] gives them a [`CoreLocalKey`] for a slot in
+//! the KCB of every core, which they reach through the KCB like the fields.
 
+use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::sync::Arc;
 use core::cell::{RefCell, RefMut};
 use core::fmt::Debug;
-use core::slice::from_raw_parts;
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
 
 use arrayvec::ArrayVec;
-use log::error;
-use logos::Logos;
 use node_replication::{Replica, ReplicaToken};
 use slabmalloc::ZoneAllocator;
 
 use crate::arch::kcb::init_kcb;
-use crate::arch::memory::paddr_to_kernel_vaddr;
 use crate::arch::MAX_NUMA_NODES;
+use crate::cmdline::KernelConfig;
 use crate::error::KError;
 
 use crate::arch::process::PROCESS_TABLE;
 use crate::memory::emem::EmergencyAllocator;
 use crate::memory::mcache::TCache;
 use crate::memory::mcache::TCacheSp;
-use crate::memory::{AllocatorStatistics, GlobalMemory, GrowBackend, PAddr, PhysicalPageProvider};
+use crate::memory::{AllocatorStatistics, GlobalMemory, GrowBackend, PhysicalPageProvider};
+use crate::mutex::{Mutex, QueueNodes};
 use crate::nr::KernelNode;
 use crate::nrproc::NrProcess;
 use crate::process::{Pid, Process, MAX_PROCESSES};
+use crate::stats::Stats;
 
 pub use crate::arch::kcb::{get_kcb, try_get_kcb};
 
 pub trait MemManager: PhysicalPageProvider + AllocatorStatistics + GrowBackend {}
 
-/// Definition to parse the kernel command-line arguments.
-#[derive(Logos, Debug, PartialEq, Clone, Copy)]
-enum CmdToken {
-    /// Kernel binary name
-    #[regex("./[a-zA-Z]+")]
-    KernelBinary,
+/// How many subsystems can add per-core state with `register`.
+const MAX_EXTENSIONS: usize = 32;
 
-    /// Kernel log level directive
-    #[token("log")]
-    Log,
+/// Allocates the state of a registered subsystem for a core.
+type NewExtension = fn() -> *mut u8;
 
-    /// Init binary (which is loaded by default)
-    #[token("init")]
-    InitBinary,
+/// Everything `register` handed out a key for (by slot).
+static REGISTERED: Mutex<ArrayVec<NewExtension, MAX_EXTENSIONS>> =
+    Mutex::new("kcb_extensions", ArrayVec::new_const());
 
-    /// Command line arguments to passed to init.
-    #[token("initargs")]
-    InitArgs,
+fn new_extension<T: Default>() -> *mut u8 {
+    Box::into_raw(Box::new(T::default())) as *mut u8
+}
 
-    /// Command line arguments to passed to a (rump) application.
-    #[token("appcmd")]
-    AppArgs,
+/// Adds per-core state of type `T` to the KCB, returns the key to find
+/// it on the current core.
+///
+/// Allocates the state for the current core right away, cores allocate it
+/// when they install their KCB, so register at boot before the application
+/// cores start (keys of cores that were up already find nothing). Finding
+/// the state never allocates or locks, interrupt handlers can use it.
+///
+/// # Panics
+/// If more than `MAX_EXTENSIONS` subsystems register.
+pub fn register<T: Default + 'static>() -> CoreLocalKey<T> {
+    let slot = {
+        let mut registered = REGISTERED.lock();
+        let slot = registered.len();
+        registered
+            .try_push(new_extension::<T>)
+            .expect("Too many KCB extensions, increase MAX_EXTENSIONS");
+        slot
+    };
+    if let Some(kcb) = try_get_kcb() {
+        kcb.allocate_extensions();
+    }
+
+    CoreLocalKey {
+        slot,
+        _type: PhantomData,
+    }
+}
 
-    #[regex("[a-zA-Z0-9\\._-]*")]
-    Ident,
+/// Finds the state a subsystem added to the KCB (see `register`).
+///
+/// Every core has its own `T`, it never leaves the core (unless `T` is
+/// `Sync`). Interrupt handlers on the core see the same `T`, so it
+/// shouldn't hand out `&mut` without checking (`RefCell::try_borrow_mut`)
+/// if they use it too.
+pub struct CoreLocalKey<T> {
+    slot: usize,
+    _type: PhantomData<fn() -> T>,
+}
 
-    /// Kernel log level
-    #[token("=", priority = 22)]
-    KVSeparator,
+impl<T> Clone for CoreLocalKey<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
 
-    #[regex(r#"'([^'\\]|\\t|\\u|\\n|[0-9a-zA-Z:.,_=]*|\\')*'"#)]
-    LiteralString,
+impl<T> Copy for CoreLocalKey<T> {}
 
-    /// Anything not properly encoded
-    #[error]
-    #[regex(r"[ ]+", logos::skip)]
-    Error,
-}
+impl<T: Default + 'static> CoreLocalKey<T> {
+    /// The state of the current core.
+    ///
+    /// # Panics
+    /// Before the core has a KCB or if the core was up before `T` was
+    /// registered.
+    pub fn get(&self) -> &T {
+        self.slot_in(&get_kcb().extensions)
+            .expect("KCB extension registered after the core started")
+    }
 
-/// Arguments parsed from command line string passed
-/// from the bootloader to the kernel.
-#[derive(Copy, Clone, Debug)]
-pub struct BootloaderArguments {
-    pub log_filter: &'static str,
-    pub init_binary: &'static str,
-    pub init_args: &'static str,
-    pub app_args: &'static str,
-}
+    /// The state of the current core, `None` before it has a KCB or if the
+    /// core was up before `T` was registered.
+    pub fn try_get(&self) -> Option<&T> {
+        try_get_kcb().and_then(|kcb| self.slot_in(&kcb.extensions))
+    }
 
-impl Default for BootloaderArguments {
-    fn default() -> BootloaderArguments {
-        BootloaderArguments {
-            log_filter: "info",
-            init_binary: "init",
-            init_args: "",
-            app_args: "",
-        }
+    fn slot_in(&self, extensions: &Extensions) -> Option<&T> {
+        let state = extensions.slots[self.slot].load(Ordering::Acquire);
+        // Safe: Slot `self.slot` only ever holds a `T` (from
+        // `new_extension::<T>`), KCBs are never freed
+        unsafe { (state as *const T).as_ref() }
     }
 }
 
-impl BootloaderArguments {
-    pub const fn new(
-        log_filter: &'static str,
-        init_binary: &'static str,
-        init_args: &'static str,
-        app_args: &'static str,
-    ) -> Self {
-        BootloaderArguments {
-            log_filter,
-            init_binary,
-            init_args,
-            app_args,
+/// The slots of a KCB for `register`ed state (null until allocated).
+pub struct Extensions {
+    slots: [AtomicPtr<u8>; MAX_EXTENSIONS],
+}
+
+impl Extensions {
+    const fn new() -> Extensions {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+        Extensions {
+            slots: [EMPTY; MAX_EXTENSIONS],
         }
     }
 
-    /// Parse command line argument and initialize the logging infrastructure.
-    ///
-    /// Example: If args is './kernel log=trace' -> sets level to Level::Trace
-    pub fn from_str(args: &'static str) -> BootloaderArguments {
-        // The args argument will be a physical address slice that
-        // goes away once we switch to a process address space
-        // make sure we translate it into a kernel virtual address:
-        let args_paddr = args.as_ptr();
-        let args_kaddr = paddr_to_kernel_vaddr(PAddr::from(args_paddr as u64));
-        // Safe: Depends on bootloader setting up identity mapping abobe `KERNEL_BASE`.
-        let args_kslice = unsafe { from_raw_parts(args_kaddr.as_ptr(), args.len()) };
-        let args = core::str::from_utf8(args_kslice).expect("Can't read args in kernel space?");
-
-        let mut parsed_args: BootloaderArguments = Default::default();
-        let mut lexer = CmdToken::lexer(args);
-        let mut prev = CmdToken::Error;
-        while let Some(token) = lexer.next() {
-            let slice = lexer.slice();
-
-            match token {
-                CmdToken::KernelBinary => {
-                    //assert_eq!(slice, "./kernel");
-                }
-                CmdToken::Log | CmdToken::InitBinary | CmdToken::InitArgs | CmdToken::AppArgs => {
-                    prev = token;
-                }
-                CmdToken::Ident => match prev {
-                    CmdToken::Log => {
-                        parsed_args.log_filter = slice;
-                        prev = CmdToken::Error;
-                    }
-                    CmdToken::InitBinary => {
-                        parsed_args.init_binary = slice;
-                        prev = CmdToken::Error;
-                    }
-                    CmdToken::InitArgs => {
-                        parsed_args.init_args = slice;
-                        prev = CmdToken::Error;
-                    }
-                    CmdToken::AppArgs => {
-                        parsed_args.app_args = slice;
-                        prev = CmdToken::Error;
-                    }
-                    _ => {
-                        error!("Invalid cmd arguments: {} (skipped {})", args, slice);
-                        continue;
-                    }
-                },
-                CmdToken::KVSeparator => {
-                    if prev != CmdToken::Log
-                        && prev != CmdToken::InitBinary
-                        && prev != CmdToken::InitArgs
-                        && prev != CmdToken::AppArgs
-                    {
-                        error!("Malformed args (unexpected equal sign) in {}", args);
-                        continue;
-                    }
-                }
-                CmdToken::LiteralString => {
-                    // We strip the quotes with 1..slice.len()-1
-                    match prev {
-                        CmdToken::Log => {
-                            parsed_args.log_filter = &slice[1..slice.len() - 1];
-                            prev = CmdToken::Error;
-                        }
-                        CmdToken::InitBinary => {
-                            parsed_args.init_binary = &slice[1..slice.len() - 1];
-                            prev = CmdToken::Error;
-                        }
-                        CmdToken::InitArgs => {
-                            parsed_args.init_args = &slice[1..slice.len() - 1];
-                            prev = CmdToken::Error;
-                        }
-                        CmdToken::AppArgs => {
-                            parsed_args.app_args = &slice[1..slice.len() - 1];
-                            prev = CmdToken::Error;
-                        }
-                        _ => {
-                            error!("Invalid cmd arguments: {} (skipped {})", args, slice);
-                            continue;
-                        }
-                    }
-                }
-                CmdToken::Error => {
-                    error!("Ignored '{}' while parsing cmd args: {}", slice, args);
-                    continue;
-                }
+    /// Allocates the state of everything registered so far.
+    fn allocate(&self) {
+        let registered = REGISTERED.lock();
+        for (slot, new) in self.slots.iter().zip(registered.iter()) {
+            if slot.load(Ordering::Acquire).is_null() {
+                slot.store(new(), Ordering::Release);
             }
         }
-
-        parsed_args
     }
 }
 
@@ -253,7 +203,8 @@ where
     /// - `panic.rs`
     pub in_panic_mode: bool,
 
-    pub cmdline: BootloaderArguments,
+    /// The configuration from the kernel command-line.
+    pub config: KernelConfig,
 
     /// A pointer to the memory location of the kernel (ELF binary).
     kernel_binary: &'static [u8],
@@ -281,8 +232,15 @@ where
     /// A handle to the node-local kernel replica.
     pub replica: Option<(Arc<Replica<'static, KernelNode>>, ReplicaToken)>,
 
-    /// Measures cycles spent in TLB shootdown handler for responder.
-    pub tlb_time: u64,
+    /// Event counters of the core.
+    pub stats: Stats,
+
+    /// What the core queues with when it waits for a lock (see
+    /// `crate::mutex`).
+    pub lock_nodes: QueueNodes,
+
+    /// State subsystems added with `register`.
+    extensions: Extensions,
 
     /// Tokens to access process replicas
     pub process_token: ArrayVec<ReplicaToken, { MAX_PROCESSES }>,
@@ -291,7 +249,7 @@ where
 impl<A: ArchSpecificKcb> Kcb<A> {
     pub const fn new(
         kernel_binary: &'static [u8],
-        cmdline: BootloaderArguments,
+        config: KernelConfig,
         emanager: TCacheSp,
         arch: A,
         node: atopology::NodeId,
@@ -300,7 +258,7 @@ impl<A: ArchSpecificKcb> Kcb<A> {
 
         Kcb {
             arch,
-            cmdline,
+            config,
             in_panic_mode: false,
             kernel_binary,
             emanager: RefCell::new(emanager),
@@ -312,7 +270,9 @@ impl<A: ArchSpecificKcb> Kcb<A> {
             physical_memory: PhysicalMemoryArena::uninit_with_node(node),
             print_buffer: None,
             replica: None,
-            tlb_time: 0,
+            stats: Stats::new(),
+            lock_nodes: QueueNodes::new(),
+            extensions: Extensions::new(),
             process_token: ArrayVec::new_const(),
         }
     }
@@ -345,10 +305,22 @@ impl<A: ArchSpecificKcb> Kcb<A> {
     /// Ties this KCB to the local CPU by setting the KCB's GDT and IDT.
     pub fn install(&'static mut self) {
         self.arch.install();
+        let gtid = self.arch.hwthread_id();
+        let stats: *const Stats = &self.stats;
 
         // Reloading gdt means we lost the content in `gs` so we
         // also set the kcb again using `wrgsbase`:
         init_kcb(self);
+
+        // Safe: we're a 'static KCB
+        crate::stats::register(gtid, unsafe { &*stats });
+        get_kcb().allocate_extensions();
+    }
+
+    /// Allocates the state of the subsystems that `register`ed so far (the
+    /// ones the KCB doesn't have yet).
+    pub(crate) fn allocate_extensions(&self) {
+        self.extensions.allocate();
     }
 
     pub fn set_global_memory(&mut self, gm: &'static GlobalMemory) {
@@ -356,6 +328,8 @@ impl<A: ArchSpecificKcb> Kcb<A> {
     }
 
     pub fn set_allocation_affinity(&mut self, node: atopology::NodeId) -> Result<(), KError> {
+        // Nodes without memory allocate from another one
+        let node = crate::numa::memory_node(node);
         if node == self.physical_memory.affinity {
             // Allocation affinity is already set to correct NUMA node
             return Ok(());
@@ -451,14 +425,30 @@ impl<A: ArchSpecificKcb> Kcb<A> {
     }
 }
 
+/// The per-core state of an arch backend (see `crate::arch_interface`).
 pub trait ArchSpecificKcb {
     type Process: Process + Sync;
 
     fn node(&self) -> usize;
     fn hwthread_id(&self) -> usize;
+    /// Number of hardware threads on the first NUMA node.
+    fn max_threads(&self) -> usize;
     fn install(&mut self);
     fn current_pid(&self) -> Result<Pid, KError>;
 
+    /// The executor that runs on this core (`KError::ProcessNotSet` if
+    /// there is none).
+    fn current_executor(&self) -> Result<&<Self::Process as Process>::E, KError>;
+
+    /// Makes `executor` the one that runs on this core, returns the
+    /// previous one.
+    ///
+    /// Switching to it happens when the scheduler resumes it.
+    fn swap_current_executor(
+        &mut self,
+        executor: Box<<Self::Process as Process>::E>,
+    ) -> Option<Box<<Self::Process as Process>::E>>;
+
     #[allow(clippy::type_complexity)] // fix this once `associated_type_defaults` works
     fn process_table(
         &self,
@@ -470,113 +460,41 @@ pub trait ArchSpecificKcb {
 
 #[cfg(test)]
 mod test {
-    use super::BootloaderArguments;
-
-    #[test]
-    fn parse_args_empty() {
-        let ba = BootloaderArguments::from_str("");
-        assert_eq!(ba.log_filter, "info");
-        assert_eq!(ba.init_binary, "init");
-        assert_eq!(ba.init_args, "");
-    }
-
-    #[test]
-    fn parse_args_nrk() {
-        let ba = BootloaderArguments::from_str("./nrk");
-        assert_eq!(ba.log_filter, "info");
-        assert_eq!(ba.init_binary, "init");
-        assert_eq!(ba.init_args, "");
-    }
-
-    #[test]
-    fn parse_args_basic() {
-        let ba = BootloaderArguments::from_str("./kernel");
-        assert_eq!(ba.log_filter, "info");
-        assert_eq!(ba.init_binary, "init");
-        assert_eq!(ba.init_args, "");
-    }
-
-    #[test]
-    fn parse_args_log() {
-        let ba = BootloaderArguments::from_str("./kernel log=error");
-        assert_eq!(ba.log_filter, "error");
-        assert_eq!(ba.init_binary, "init");
-        assert_eq!(ba.init_args, "");
-    }
-
-    #[test]
-    fn parse_args_init() {
-        let ba = BootloaderArguments::from_str("./kernel init=file log=trace");
-        assert_eq!(ba.log_filter, "trace");
-        assert_eq!(ba.init_binary, "file");
-        assert_eq!(ba.init_args, "");
-    }
-
-    #[test]
-    fn parse_args_initargs() {
-        let ba = BootloaderArguments::from_str("./kernel initargs=0");
-        assert_eq!(ba.log_filter, "info");
-        assert_eq!(ba.init_binary, "init");
-        assert_eq!(ba.init_args, "0");
-    }
-
-    #[test]
-    fn parse_args_leveldb() {
-        let args = "./kernel log=warn init=dbbench.bin initargs=3 appcmd='--threads=1 --benchmarks=fillseq,readrandom --reads=100000 --num=50000 --value_size=65535'";
-
-        let ba = BootloaderArguments::from_str(args);
-        assert_eq!(ba.log_filter, "warn");
-        assert_eq!(ba.init_binary, "dbbench.bin");
-        assert_eq!(ba.init_args, "3");
-        assert_eq!(ba.app_args, "--threads=1 --benchmarks=fillseq,readrandom --reads=100000 --num=50000 --value_size=65535");
-    }
-
-    #[test]
-    fn parse_args_fxmark() {
-        let args = "log=debug initargs=1X1XmixX0";
-        let ba = BootloaderArguments::from_str(args);
-        assert_eq!(ba.log_filter, "debug");
-        assert_eq!(ba.init_binary, "init");
-        assert_eq!(ba.init_args, "1X1XmixX0");
-    }
-
-    #[test]
-    fn parse_args_empty_literal_quotes() {
-        let args = "./kernel initargs='\"\"' log=debug";
-        let ba = BootloaderArguments::from_str(args);
-        assert_eq!(ba.log_filter, "debug");
-        assert_eq!(ba.init_args, "\"\"");
-    }
-
-    #[test]
-    fn parse_args_empty_literal() {
-        let args = "./kernel initargs='' log=debug";
-        let ba = BootloaderArguments::from_str(args);
-        assert_eq!(ba.log_filter, "debug");
-        assert_eq!(ba.init_args, "");
-    }
-
-    #[test]
-    fn parse_args_invalid() {
-        let args = "./kernel initg='asdf' log=debug";
-        let ba = BootloaderArguments::from_str(args);
-        assert_eq!(ba.log_filter, "debug");
-        assert_eq!(ba.init_args, "");
-    }
-
-    #[test]
-    fn parse_args_invalid2() {
-        let args = "./sadf init='asdf' log=debug";
-        let ba = BootloaderArguments::from_str(args);
-        assert_eq!(ba.log_filter, "debug");
-        assert_eq!(ba.init_args, "");
-    }
+    use super::*;
+    use core::cell::Cell;
 
     #[test]
-    fn parse_args_invalid3() {
-        let args = "./kernel init=---  as-s- log=debug";
-        let ba = BootloaderArguments::from_str(args);
-        assert_eq!(ba.log_filter, "debug");
-        assert_eq!(ba.init_args, "");
+    fn core_local_keys() {
+        let counter = register::<Cell<usize>>();
+        let name = register::<RefCell<String>>();
+
+        counter.get().set(counter.get().get() + 1);
+        assert_eq!(counter.get().get(), 1);
+        name.get().borrow_mut().push_str("core");
+        assert_eq!(counter.try_get().map(Cell::get), Some(1));
+        assert_eq!(name.get().borrow().as_str(), "core");
+
+        // Every core (thread) has its own
+        std::thread::spawn(move || assert_eq!(counter.get().get(), 0))
+            .join()
+            .expect("Other core failed");
+        assert_eq!(counter.get().get(), 1);
+
+        // Cores that were up before don't get one
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (go, wait) = std::sync::mpsc::channel();
+        let earlier = std::thread::spawn(move || {
+            get_kcb();
+            tx.send(()).expect("Test thread gone");
+            let late: CoreLocalKey<Cell<u64>> = wait.recv().expect("Test thread gone");
+            assert!(late.try_get().is_none());
+            get_kcb().allocate_extensions();
+            assert_eq!(late.try_get().map(Cell::get), Some(0));
+        });
+        rx.recv().expect("Other core failed");
+        let late = register::<Cell<u64>>();
+        assert_eq!(late.get().get(), 0);
+        go.send(late).expect("Other core failed");
+        earlier.join().expect("Other core failed");
     }
 }
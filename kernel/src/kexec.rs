@@ -0,0 +1,698 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Starts another kernel without going through the firmware (kexec).
+//!
+//! We do what our UEFI bootloader does (see `bootloader/src/main.rs`): load
+//! the ELF into physically contiguous memory, relocate it to run at
+//! `KERNEL_OFFSET` plus its physical address, give it a stack, page-tables
+//! that map memory the way the bootloader maps it and `KernelArgs`.
+//!
+//! The memory the new kernel goes to (the destination) is in use until we
+//! jump, so we assemble its content in scratch large pages first. The
+//! destination looks like what the bootloader sets up: the loaded ELF, the
+//! ELF file (the `kernel` module the new kernel reads its symbols from) and
+//! the init stack. Page-tables, `KernelArgs` and the code that copies the
+//! scratch pages into place live in a separate boot region.
+//!
+//! This file has the parts that don't touch the hardware, `arch::kexec`
+//! puts them together.
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::vec::Vec;
+use core::cmp;
+
+use fallible_collections::FallibleVec;
+use uefi::table::boot::{MemoryDescriptor, MemoryType};
+use x86::bits64::paging::{PDFlags, PDPTFlags, PML4Flags, PTFlags, HUGE_PAGE_SIZE};
+
+use crate::error::KError;
+use crate::memory::vspace::MapAction;
+use crate::memory::{VAddr, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+use crate::round_up;
+
+/// Memory types of our UEFI bootloader (see `bootloader/src/kernel.rs`).
+pub const KERNEL_ELF: MemoryType = MemoryType(0x8000_0001);
+pub const KERNEL_PT: MemoryType = MemoryType(0x8000_0002);
+pub const KERNEL_STACK: MemoryType = MemoryType(0x8000_0003);
+pub const UEFI_MEMORY_MAP: MemoryType = MemoryType(0x8000_0004);
+pub const KERNEL_ARGS: MemoryType = MemoryType(0x8000_0005);
+pub const MODULE: MemoryType = MemoryType(0x8000_0006);
+
+/// Where the bootloader maps memory for the kernel (`KERNEL_BASE` on
+/// x86-64).
+pub const KERNEL_OFFSET: u64 = 1 << 46;
+
+/// Pages of the init stack (the first one is a guard page), as many as
+/// the bootloader gives us.
+pub const STACK_PAGES: usize = 768;
+
+/// The bootloader leaves the region that covers the local APIC alone.
+const APIC_BASE: u64 = 0xfee0_0000;
+const ONE_MIB: u64 = 1024 * 1024;
+
+/// Where the parts of the new kernel go (as offsets from the start of the
+/// destination).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Layout {
+    /// Size of the loaded ELF (it starts at offset 0).
+    pub image: usize,
+    /// Offset of the ELF file.
+    pub file: usize,
+    pub file_size: usize,
+    /// Offset of the stack (including the guard page).
+    pub stack: usize,
+    /// Size of everything (a multiple of `LARGE_PAGE_SIZE`).
+    pub size: usize,
+}
+
+impl Layout {
+    pub fn new(image: usize, file_size: usize) -> Layout {
+        let file = round_up!(image, BASE_PAGE_SIZE);
+        let stack = round_up!(file + file_size, BASE_PAGE_SIZE);
+        let size = round_up!(stack + STACK_PAGES * BASE_PAGE_SIZE, LARGE_PAGE_SIZE);
+        Layout {
+            image,
+            file,
+            file_size,
+            stack,
+            size,
+        }
+    }
+
+    /// How much of the destination has content we need to copy (the stack
+    /// doesn't).
+    pub fn content(&self) -> usize {
+        self.stack
+    }
+
+    /// Where the new kernel starts with its stack pointer (one page below
+    /// the top, like the bootloader).
+    pub fn stack_top(&self) -> usize {
+        self.stack + (STACK_PAGES - 1) * BASE_PAGE_SIZE
+    }
+}
+
+fn region_end(region: &MemoryDescriptor) -> u64 {
+    region.phys_start + region.page_count * BASE_PAGE_SIZE as u64
+}
+
+/// Picks the physical address of the destination: the (large page
+/// aligned) end of the biggest conventional region above 1 MiB, away
+/// from where the early allocators of both kernels take memory from.
+pub fn pick_destination(map: &[MemoryDescriptor], size: usize) -> Option<u64> {
+    let large_page = LARGE_PAGE_SIZE as u64;
+    map.iter()
+        .filter(|region| region.ty == MemoryType::CONVENTIONAL)
+        .filter_map(|region| {
+            let start = round_up!(cmp::max(region.phys_start, ONE_MIB), large_page);
+            let end = region_end(region) & !(large_page - 1);
+            let destination = end.checked_sub(size as u64)?;
+            if destination >= start {
+                Some((region.page_count, destination))
+            } else {
+                None
+            }
+        })
+        .max_by_key(|(pages, _destination)| *pages)
+        .map(|(_pages, destination)| destination)
+}
+
+/// Marks [`start`, `start` + `size`) as `ty` in `map`, the range has to be
+/// within a conventional region (which we split).
+pub fn reserve(
+    map: &mut Vec<MemoryDescriptor>,
+    start: u64,
+    size: usize,
+    ty: MemoryType,
+) -> Result<(), KError> {
+    let end = start + size as u64;
+    let idx = map
+        .iter()
+        .position(|region| {
+            region.ty == MemoryType::CONVENTIONAL
+                && region.phys_start <= start
+                && end <= region_end(region)
+        })
+        .ok_or(KError::KexecNoMemory)?;
+
+    let region = map[idx];
+    let parts = [
+        (region.phys_start, start, MemoryType::CONVENTIONAL),
+        (start, end, ty),
+        (end, region_end(&region), MemoryType::CONVENTIONAL),
+    ];
+    let mut split = Vec::try_with_capacity(map.len() + 2)?;
+    for (i, other) in map.iter().enumerate() {
+        if i != idx {
+            split.try_push(*other)?;
+            continue;
+        }
+        for (start, end, ty) in parts.iter().filter(|(start, end, _ty)| start < end) {
+            let mut part = region;
+            part.ty = *ty;
+            part.phys_start = *start;
+            part.page_count = (end - start) / BASE_PAGE_SIZE as u64;
+            split.try_push(part)?;
+        }
+    }
+    *map = split;
+    Ok(())
+}
+
+/// Gives the memory only the running kernel needs (its ELF, stack,
+/// page-tables and boot information) to the new kernel.
+pub fn reclaim(map: &mut [MemoryDescriptor]) {
+    let private = [
+        KERNEL_ELF,
+        KERNEL_PT,
+        KERNEL_STACK,
+        KERNEL_ARGS,
+        UEFI_MEMORY_MAP,
+    ];
+    for region in map.iter_mut() {
+        if private.contains(&region.ty) {
+            region.ty = MemoryType::CONVENTIONAL;
+        }
+    }
+}
+
+/// How the bootloader maps memory of type `ty` (see `map_physical_memory`
+/// in `bootloader/src/main.rs`): the rights of the identity mapping and
+/// whether it maps it at `KERNEL_OFFSET` too.
+pub fn memory_rights(ty: MemoryType) -> (MapAction, bool) {
+    let rights = match ty {
+        MemoryType::LOADER_CODE
+        | MemoryType::BOOT_SERVICES_CODE
+        | MemoryType::RUNTIME_SERVICES_CODE
+        | MemoryType::PAL_CODE => MapAction::ReadExecuteKernel,
+        MemoryType::LOADER_DATA
+        | MemoryType::BOOT_SERVICES_DATA
+        | MemoryType::RUNTIME_SERVICES_DATA
+        | MemoryType::ACPI_RECLAIM
+        | MemoryType::ACPI_NON_VOLATILE
+        | MemoryType::MMIO
+        | MemoryType::MMIO_PORT_SPACE
+        | MemoryType::PERSISTENT_MEMORY
+        | KERNEL_PT
+        | KERNEL_STACK
+        | UEFI_MEMORY_MAP => MapAction::ReadWriteKernel,
+        MemoryType::CONVENTIONAL => MapAction::ReadWriteExecuteKernel,
+        KERNEL_ELF | KERNEL_ARGS | MODULE => MapAction::ReadKernel,
+        _ => MapAction::None,
+    };
+    let offset = [
+        MemoryType::CONVENTIONAL,
+        MemoryType::BOOT_SERVICES_DATA,
+        MemoryType::LOADER_DATA,
+        KERNEL_PT,
+        MODULE,
+        KERNEL_ARGS,
+    ]
+    .contains(&ty);
+    (rights, offset)
+}
+
+/// The rights for an ELF segment with `flags` (the bootloader skips the
+/// ones we can't express).
+fn segment_rights(flags: elfloader::Flags) -> MapAction {
+    match (flags.is_execute(), flags.is_write(), flags.is_read()) {
+        (false, false, true) => MapAction::ReadKernel,
+        (true, false, true) => MapAction::ReadExecuteKernel,
+        (false, true, true) => MapAction::ReadWriteKernel,
+        (true, true, true) => MapAction::ReadWriteExecuteKernel,
+        _ => MapAction::None,
+    }
+}
+
+/// The content of the destination while we assemble it, one buffer per
+/// large page.
+pub struct Staging<'a> {
+    chunks: Vec<&'a mut [u8]>,
+}
+
+impl<'a> Staging<'a> {
+    /// `chunks` have to be `LARGE_PAGE_SIZE` bytes each.
+    pub fn new(chunks: Vec<&'a mut [u8]>) -> Staging<'a> {
+        debug_assert!(chunks.iter().all(|c| c.len() == LARGE_PAGE_SIZE));
+        Staging { chunks }
+    }
+
+    /// Writes `bytes` at `offset` (from the start of the destination).
+    pub fn write(&mut self, mut offset: usize, mut bytes: &[u8]) -> Result<(), KError> {
+        while !bytes.is_empty() {
+            let chunk = self
+                .chunks
+                .get_mut(offset / LARGE_PAGE_SIZE)
+                .ok_or(KError::InvalidKernelImage)?;
+            let at = offset % LARGE_PAGE_SIZE;
+            let len = cmp::min(bytes.len(), LARGE_PAGE_SIZE - at);
+            chunk[at..at + len].copy_from_slice(&bytes[..len]);
+            offset += len;
+            bytes = &bytes[len..];
+        }
+        Ok(())
+    }
+}
+
+/// First pass over the ELF: finds out how big the image is and where the
+/// segments go.
+#[derive(Default)]
+pub struct ImageSize {
+    /// Page aligned base, size and rights of every segment.
+    pub segments: Vec<(u64, usize, MapAction)>,
+    /// Size of the image (it starts at 0, like the bootloader assumes).
+    pub size: usize,
+}
+
+impl elfloader::ElfLoader for ImageSize {
+    fn allocate(
+        &mut self,
+        load_headers: elfloader::LoadableHeaders,
+    ) -> Result<(), elfloader::ElfLoaderErr> {
+        for header in load_headers.into_iter() {
+            let base = header.virtual_addr();
+            let page_base = base & !(BASE_PAGE_SIZE as u64 - 1);
+            let size_page = round_up!(
+                header.mem_size() as usize + (base - page_base) as usize,
+                BASE_PAGE_SIZE
+            );
+
+            let rights = segment_rights(header.flags());
+            if rights != MapAction::None {
+                self.segments
+                    .try_push((page_base, size_page, rights))
+                    .map_err(|_e| elfloader::ElfLoaderErr::OutOfMemory)?;
+            }
+            self.size = cmp::max(self.size, page_base as usize + size_page);
+        }
+        Ok(())
+    }
+
+    fn load(
+        &mut self,
+        _flags: elfloader::Flags,
+        _destination: u64,
+        _region: &[u8],
+    ) -> Result<(), elfloader::ElfLoaderErr> {
+        Ok(())
+    }
+
+    fn relocate(
+        &mut self,
+        _entry: &elfloader::Rela<elfloader::P64>,
+    ) -> Result<(), elfloader::ElfLoaderErr> {
+        Ok(())
+    }
+}
+
+/// Second pass: writes the segments into the staging buffers and relocates
+/// them for the new kernel to run at `offset` (`KERNEL_OFFSET` plus the
+/// destination).
+pub struct ImageLoader<'a, 'b> {
+    pub staging: &'a mut Staging<'b>,
+    pub offset: u64,
+}
+
+impl<'a, 'b> elfloader::ElfLoader for ImageLoader<'a, 'b> {
+    fn allocate(
+        &mut self,
+        _load_headers: elfloader::LoadableHeaders,
+    ) -> Result<(), elfloader::ElfLoaderErr> {
+        Ok(())
+    }
+
+    fn load(
+        &mut self,
+        _flags: elfloader::Flags,
+        destination: u64,
+        region: &[u8],
+    ) -> Result<(), elfloader::ElfLoaderErr> {
+        self.staging
+            .write(destination as usize, region)
+            .map_err(|_e| elfloader::ElfLoaderErr::OutOfMemory)
+    }
+
+    fn relocate(
+        &mut self,
+        entry: &elfloader::Rela<elfloader::P64>,
+    ) -> Result<(), elfloader::ElfLoaderErr> {
+        use elfloader::TypeRela64;
+        if let TypeRela64::R_RELATIVE = TypeRela64::from(entry.get_type()) {
+            let value = self.offset + entry.get_addend();
+            self.staging
+                .write(entry.get_offset() as usize, &value.to_le_bytes())
+                .map_err(|_e| elfloader::ElfLoaderErr::OutOfMemory)
+        } else {
+            Err(elfloader::ElfLoaderErr::UnsupportedRelocationEntry)
+        }
+    }
+}
+
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+fn pml4_index(vaddr: u64) -> usize {
+    ((vaddr >> 39) & 0x1ff) as usize
+}
+
+fn pdpt_index(vaddr: u64) -> usize {
+    ((vaddr >> 30) & 0x1ff) as usize
+}
+
+fn pd_index(vaddr: u64) -> usize {
+    ((vaddr >> 21) & 0x1ff) as usize
+}
+
+fn pt_index(vaddr: u64) -> usize {
+    ((vaddr >> 12) & 0x1ff) as usize
+}
+
+/// Page-tables for the new kernel, built in `tables` which are at
+/// physical address `base` (the first one is the PML4).
+pub struct PageTables<'a> {
+    tables: &'a mut [[u64; 512]],
+    base: u64,
+    used: usize,
+}
+
+impl<'a> PageTables<'a> {
+    pub fn new(tables: &'a mut [[u64; 512]], base: u64) -> Result<PageTables<'a>, KError> {
+        let mut page_tables = PageTables {
+            tables,
+            base,
+            used: 0,
+        };
+        page_tables.allocate()?;
+        Ok(page_tables)
+    }
+
+    /// Physical address of the PML4.
+    pub fn pml4(&self) -> u64 {
+        self.base
+    }
+
+    /// How many tables we used.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    fn allocate(&mut self) -> Result<u64, KError> {
+        let table = self
+            .tables
+            .get_mut(self.used)
+            .ok_or(KError::KexecNoMemory)?;
+        *table = [0; 512];
+        self.used += 1;
+        Ok(self.base + ((self.used - 1) * BASE_PAGE_SIZE) as u64)
+    }
+
+    fn table(&mut self, paddr: u64) -> &mut [u64; 512] {
+        &mut self.tables[(paddr - self.base) as usize / BASE_PAGE_SIZE]
+    }
+
+    /// The table entry `idx` of `table` points to (we add one if there is
+    /// none), fails if a page already covers `vaddr`.
+    fn next(&mut self, table: u64, idx: usize, vaddr: u64) -> Result<u64, KError> {
+        let entry = self.table(table)[idx];
+        if entry & PML4Flags::P.bits() == 0 {
+            let next = self.allocate()?;
+            // Like the bootloader: the leaves decide about the rights
+            self.table(table)[idx] = next | (PML4Flags::P | PML4Flags::RW).bits();
+            Ok(next)
+        } else if entry & PDFlags::PS.bits() != 0 {
+            Err(KError::AlreadyMapped {
+                base: VAddr::from(vaddr),
+            })
+        } else {
+            Ok(entry & ADDRESS_MASK)
+        }
+    }
+
+    /// Maps [`pbase`, `pbase` + `size`) at `vbase` using the biggest pages
+    /// that fit (like the bootloader).
+    pub fn map(
+        &mut self,
+        vbase: u64,
+        pbase: u64,
+        size: usize,
+        rights: MapAction,
+    ) -> Result<(), KError> {
+        let aligned = |vaddr: u64, paddr: u64, to: u64| vaddr % to == 0 && paddr % to == 0;
+        let (huge, large, base) = (
+            HUGE_PAGE_SIZE as u64,
+            LARGE_PAGE_SIZE as u64,
+            BASE_PAGE_SIZE as u64,
+        );
+
+        let mut mapped = 0;
+        while mapped < size as u64 {
+            let (vaddr, paddr) = (vbase + mapped, pbase + mapped);
+            let left = size as u64 - mapped;
+
+            let pdpt = self.next(self.pml4(), pml4_index(vaddr), vaddr)?;
+            let idx = pdpt_index(vaddr);
+            if self.table(pdpt)[idx] == 0 && aligned(vaddr, paddr, huge) && left >= huge {
+                let flags = PDPTFlags::P | PDPTFlags::PS | rights.to_pdpt_rights();
+                self.table(pdpt)[idx] = paddr | flags.bits();
+                mapped += huge;
+                continue;
+            }
+
+            let pd = self.next(pdpt, idx, vaddr)?;
+            let idx = pd_index(vaddr);
+            if self.table(pd)[idx] == 0 && aligned(vaddr, paddr, large) && left >= large {
+                let flags = PDFlags::P | PDFlags::PS | rights.to_pd_rights();
+                self.table(pd)[idx] = paddr | flags.bits();
+                mapped += large;
+                continue;
+            }
+
+            let pt = self.next(pd, idx, vaddr)?;
+            let idx = pt_index(vaddr);
+            if self.table(pt)[idx] != 0 {
+                return Err(KError::AlreadyMapped {
+                    base: VAddr::from(vaddr),
+                });
+            }
+            self.table(pt)[idx] = paddr | (PTFlags::P | rights.to_pt_rights()).bits();
+            mapped += base;
+        }
+
+        Ok(())
+    }
+
+    /// Maps the memory in `map` like the bootloader does.
+    pub fn map_memory(&mut self, map: &[MemoryDescriptor]) -> Result<(), KError> {
+        for region in map.iter() {
+            let (start, end) = (region.phys_start, region_end(region));
+            if start == 0 || (start <= APIC_BASE && end >= APIC_BASE) {
+                continue;
+            }
+
+            let (rights, offset) = memory_rights(region.ty);
+            if rights == MapAction::None {
+                continue;
+            }
+            let size = (end - start) as usize;
+            self.map(start, start, size, rights)?;
+            if offset {
+                self.map(KERNEL_OFFSET + start, start, size, rights)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn region(ty: MemoryType, start: u64, size: u64) -> MemoryDescriptor {
+        let mut region = MemoryDescriptor::default();
+        region.ty = ty;
+        region.phys_start = start;
+        region.page_count = size / BASE_PAGE_SIZE as u64;
+        region
+    }
+
+    const MIB: u64 = 1024 * 1024;
+    const GIB: u64 = 1024 * MIB;
+
+    #[test]
+    fn layout() {
+        let layout = Layout::new(0x20_1000, 0x1234);
+        assert_eq!(layout.file, 0x20_1000);
+        assert_eq!(layout.stack, 0x20_3000);
+        assert_eq!(layout.size % LARGE_PAGE_SIZE, 0);
+        assert!(layout.size >= layout.stack + STACK_PAGES * BASE_PAGE_SIZE);
+        assert_eq!(
+            layout.stack_top(),
+            layout.stack + STACK_PAGES * BASE_PAGE_SIZE - BASE_PAGE_SIZE
+        );
+    }
+
+    #[test]
+    fn destination() {
+        let map = [
+            region(MemoryType::CONVENTIONAL, 0x1000, 0x9f000),
+            region(MemoryType::CONVENTIONAL, MIB, 7 * MIB),
+            region(MemoryType::ACPI_RECLAIM, 8 * MIB, MIB),
+            region(MemoryType::CONVENTIONAL, 9 * MIB + 0x1000, 100 * MIB),
+        ];
+        // The end of the biggest region, rounded down to 2 MiB
+        assert_eq!(pick_destination(&map, 4 * MIB as usize), Some(104 * MIB));
+        assert_eq!(pick_destination(&map, 96 * MIB as usize), Some(12 * MIB));
+        assert_eq!(pick_destination(&map, 98 * MIB as usize), Some(10 * MIB));
+        assert_eq!(pick_destination(&map, 100 * MIB as usize), None);
+        // Nothing below 1 MiB
+        assert_eq!(pick_destination(&map[..1], 2 * MIB as usize), None);
+    }
+
+    #[test]
+    fn reserve_splits() {
+        let mut map = vec![
+            region(MemoryType::RESERVED, 0, MIB),
+            region(MemoryType::CONVENTIONAL, MIB, 15 * MIB),
+        ];
+        reserve(&mut map, 4 * MIB, 2 * MIB as usize, KERNEL_ELF).unwrap();
+        assert_eq!(map.len(), 4);
+        assert_eq!(map[1].ty, MemoryType::CONVENTIONAL);
+        assert_eq!(map[1].page_count, 3 * MIB / BASE_PAGE_SIZE as u64);
+        assert_eq!(map[2].ty, KERNEL_ELF);
+        assert_eq!(map[2].phys_start, 4 * MIB);
+        assert_eq!(map[3].phys_start, 6 * MIB);
+        assert_eq!(region_end(&map[3]), 16 * MIB);
+
+        // At the end of a region (no empty part)
+        reserve(&mut map, 14 * MIB, 2 * MIB as usize, MODULE).unwrap();
+        assert_eq!(map.len(), 5);
+        assert_eq!(map[4].ty, MODULE);
+
+        // Not conventional (anymore)
+        assert!(reserve(&mut map, 4 * MIB, BASE_PAGE_SIZE, MODULE).is_err());
+        assert!(reserve(&mut map, 0, BASE_PAGE_SIZE, MODULE).is_err());
+    }
+
+    #[test]
+    fn reclaim_private() {
+        let mut map = [
+            region(KERNEL_ELF, MIB, MIB),
+            region(MODULE, 2 * MIB, MIB),
+            region(KERNEL_STACK, 3 * MIB, MIB),
+        ];
+        reclaim(&mut map);
+        assert_eq!(map[0].ty, MemoryType::CONVENTIONAL);
+        assert_eq!(map[1].ty, MODULE);
+        assert_eq!(map[2].ty, MemoryType::CONVENTIONAL);
+    }
+
+    #[test]
+    fn staging() {
+        let mut chunks = vec![vec![0u8; LARGE_PAGE_SIZE], vec![0u8; LARGE_PAGE_SIZE]];
+        let slices = chunks.iter_mut().map(|c| c.as_mut_slice()).collect();
+        let mut staging = Staging::new(slices);
+        staging.write(LARGE_PAGE_SIZE - 2, &[1, 2, 3, 4]).unwrap();
+        assert!(staging.write(2 * LARGE_PAGE_SIZE - 1, &[5, 6]).is_err());
+        drop(staging);
+
+        assert_eq!(&chunks[0][LARGE_PAGE_SIZE - 2..], &[1, 2]);
+        assert_eq!(&chunks[1][..2], &[3, 4]);
+    }
+
+    #[test]
+    fn page_sizes() {
+        let mut tables = vec![[0u64; 512]; 8];
+        let base = 0x10_0000;
+        let mut pt = PageTables::new(&mut tables, base).unwrap();
+
+        // 1 GiB, then 2 MiB, then 4 KiB pages
+        pt.map(
+            GIB,
+            GIB,
+            (GIB + 2 * MIB) as usize + BASE_PAGE_SIZE,
+            MapAction::ReadWriteKernel,
+        )
+        .unwrap();
+        // PML4, PDPT, PD, PT
+        assert_eq!(pt.used(), 4);
+        drop(pt);
+
+        let pdpt = tables[0][0] & ADDRESS_MASK;
+        assert_eq!(pdpt, base + BASE_PAGE_SIZE as u64);
+        let huge = tables[1][1];
+        assert_eq!(huge & ADDRESS_MASK, GIB);
+        assert_ne!(huge & PDPTFlags::PS.bits(), 0);
+        assert_ne!(huge & PDPTFlags::XD.bits(), 0);
+        let large = tables[2][0];
+        assert_eq!(large & ADDRESS_MASK, 2 * GIB);
+        assert_ne!(large & PDFlags::PS.bits(), 0);
+        let small = tables[3][0];
+        assert_eq!(small & ADDRESS_MASK, 2 * GIB + 2 * MIB);
+        assert_eq!(small & PTFlags::XD.bits(), PTFlags::XD.bits());
+    }
+
+    #[test]
+    fn map_memory_like_the_bootloader() {
+        let mut tables = vec![[0u64; 512]; 16];
+        let mut pt = PageTables::new(&mut tables, 0x10_0000).unwrap();
+        let map = [
+            region(MemoryType::CONVENTIONAL, 0, 0xa0000),
+            region(MemoryType::CONVENTIONAL, MIB, 7 * MIB),
+            region(KERNEL_ELF, 8 * MIB, 2 * MIB),
+            region(MemoryType::RESERVED, 10 * MIB, MIB),
+        ];
+        pt.map_memory(&map).unwrap();
+        // Identity and at `KERNEL_OFFSET` (its own PML4 slot)
+        assert!(pt
+            .map(MIB, MIB, BASE_PAGE_SIZE, MapAction::ReadKernel)
+            .is_err());
+        assert!(pt
+            .map(
+                KERNEL_OFFSET + 2 * MIB,
+                2 * MIB,
+                BASE_PAGE_SIZE,
+                MapAction::ReadKernel
+            )
+            .is_err());
+        // The ELF only has an identity mapping
+        pt.map(
+            KERNEL_OFFSET + 8 * MIB,
+            8 * MIB,
+            LARGE_PAGE_SIZE,
+            MapAction::ReadKernel,
+        )
+        .unwrap();
+        // Region at 0 and reserved memory aren't mapped
+        pt.map(0, 0, BASE_PAGE_SIZE, MapAction::ReadKernel).unwrap();
+        pt.map(10 * MIB, 10 * MIB, BASE_PAGE_SIZE, MapAction::ReadKernel)
+            .unwrap();
+        drop(pt);
+        assert_ne!(tables[0][pml4_index(KERNEL_OFFSET)], 0);
+    }
+
+    #[test]
+    fn out_of_tables() {
+        let mut tables = vec![[0u64; 512]; 2];
+        let mut pt = PageTables::new(&mut tables, 0x10_0000).unwrap();
+        assert_eq!(
+            pt.map(MIB, MIB, BASE_PAGE_SIZE, MapAction::ReadKernel),
+            Err(KError::KexecNoMemory)
+        );
+    }
+
+    #[test]
+    fn rights() {
+        assert_eq!(
+            memory_rights(MemoryType::CONVENTIONAL),
+            (MapAction::ReadWriteExecuteKernel, true)
+        );
+        assert_eq!(memory_rights(KERNEL_ELF), (MapAction::ReadKernel, false));
+        assert_eq!(memory_rights(KERNEL_ARGS), (MapAction::ReadKernel, true));
+        assert_eq!(
+            memory_rights(MemoryType::LOADER_CODE),
+            (MapAction::ReadExecuteKernel, false)
+        );
+        assert_eq!(memory_rights(MemoryType::RESERVED).0, MapAction::None);
+    }
+}
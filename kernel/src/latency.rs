@@ -0,0 +1,165 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Latency histograms for system calls (enabled with `syscall_latency` on the
+//! command line).
+//!
+//! Every core records the cycles it spends in a system call handler into its
+//! own histograms (one per call and operation, e.g., `VSpace::Map`), reading
+//! `/proc/syscall_latency` merges them.
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use fallible_collections::vec::FallibleVec;
+use histogram::Histogram;
+use kpi::{
+    CapOperation, DebugOperation, FileOperation, IpcOperation, NetworkOperation, PerfOperation,
+    ProcessOperation, SystemCall, SystemOperation, TimeOperation, VSpaceOperation, VmOperation,
+};
+use log::error;
+use spin::Mutex;
+
+use crate::arch::MAX_CORES;
+use crate::error::KError;
+
+/// Latencies above this (in cycles) end up in the last bucket.
+const MAX_LATENCY: u64 = 1 << 32;
+
+/// Percentiles we report.
+const PERCENTILES: [f64; 4] = [50.0, 90.0, 99.0, 99.9];
+
+/// The latencies of one operation.
+struct OpHistogram {
+    function: u64,
+    op: u64,
+    histogram: Histogram,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HISTOGRAMS: Mutex<Vec<OpHistogram>> = Mutex::new(Vec::new());
+/// The histograms of every core (only the core itself records into them,
+/// readers lock them briefly to merge).
+static CORES: [Mutex<Vec<OpHistogram>>; MAX_CORES] = [NO_HISTOGRAMS; MAX_CORES];
+
+/// Starts recording system call latencies.
+pub fn init() -> Result<(), KError> {
+    crate::procfs::register("/proc/syscall_latency", proc_syscall_latency)?;
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Are we recording latencies?
+#[inline]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn new_histogram() -> Histogram {
+    Histogram::configure()
+        .precision(2)
+        .max_value(MAX_LATENCY)
+        .build()
+        .expect("Histogram config is valid")
+}
+
+/// Records that operation `op` of system call `function` took `cycles` on
+/// core `gtid`.
+pub fn record(gtid: atopology::GlobalThreadId, function: u64, op: u64, cycles: u64) {
+    if !enabled() {
+        return;
+    }
+
+    let mut histograms = match CORES.get(gtid) {
+        Some(histograms) => histograms.lock(),
+        None => return,
+    };
+    let idx = match histograms
+        .iter()
+        .position(|h| h.function == function && h.op == op)
+    {
+        Some(idx) => idx,
+        None => {
+            let h = OpHistogram {
+                function,
+                op,
+                histogram: new_histogram(),
+            };
+            if let Err(e) = histograms.try_push(h) {
+                error!("Can't record syscall latency: {:?}", e);
+                return;
+            }
+            histograms.len() - 1
+        }
+    };
+
+    let _r = histograms[idx].histogram.increment(cycles.min(MAX_LATENCY));
+}
+
+/// Writes e.g., `VSpace::Map` for `function` and `op`.
+fn write_name(out: &mut String, function: u64, op: u64) -> fmt::Result {
+    match SystemCall::new(function) {
+        SystemCall::System => write!(out, "System::{:?}", SystemOperation::from(op)),
+        SystemCall::Process => write!(out, "Process::{:?}", ProcessOperation::from(op)),
+        SystemCall::VSpace => write!(out, "VSpace::{:?}", VSpaceOperation::from(op)),
+        SystemCall::FileIO => write!(out, "FileIO::{:?}", FileOperation::from(op)),
+        SystemCall::Network => write!(out, "Network::{:?}", NetworkOperation::from(op)),
+        SystemCall::Time => write!(out, "Time::{:?}", TimeOperation::from(op)),
+        SystemCall::Debug => write!(out, "Debug::{:?}", DebugOperation::from(op)),
+        SystemCall::Perf => write!(out, "Perf::{:?}", PerfOperation::from(op)),
+        SystemCall::Capability => write!(out, "Capability::{:?}", CapOperation::from(op)),
+        SystemCall::Ipc => write!(out, "Ipc::{:?}", IpcOperation::from(op)),
+        SystemCall::Vm => write!(out, "Vm::{:?}", VmOperation::from(op)),
+        SystemCall::Unknown => write!(out, "{}::{}", function, op),
+    }
+}
+
+/// The histograms of all cores, merged per operation (sorted).
+fn merged() -> Vec<OpHistogram> {
+    let mut merged: Vec<OpHistogram> = Vec::new();
+    for core in CORES.iter() {
+        for h in core.lock().iter() {
+            match merged
+                .iter_mut()
+                .find(|m| m.function == h.function && m.op == h.op)
+            {
+                Some(m) => m.histogram.merge(&h.histogram),
+                None => merged.push(OpHistogram {
+                    function: h.function,
+                    op: h.op,
+                    histogram: h.histogram.clone(),
+                }),
+            }
+        }
+    }
+
+    merged.sort_by_key(|h| (h.function, h.op));
+    merged
+}
+
+/// Contents of `/proc/syscall_latency` (in cycles).
+fn proc_syscall_latency(out: &mut String) -> fmt::Result {
+    writeln!(
+        out,
+        "{:<24} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "syscall", "count", "p50", "p90", "p99", "p99.9", "max"
+    )?;
+
+    for h in merged() {
+        let mut name = String::new();
+        write_name(&mut name, h.function, h.op)?;
+        write!(out, "{:<24} {:>10}", name, h.histogram.entries())?;
+        for p in PERCENTILES.iter() {
+            write!(out, " {:>10}", h.histogram.percentile(*p).unwrap_or(0))?;
+        }
+        writeln!(out, " {:>10}", h.histogram.maximum().unwrap_or(0))?;
+    }
+
+    Ok(())
+}
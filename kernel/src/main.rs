@@ -12,6 +12,7 @@
     intrinsics,
     core_intrinsics,
     llvm_asm,
+    asm,
     lang_items,
     start,
     box_syntax,
@@ -49,26 +50,61 @@ pub mod arch;
 pub mod arch;
 
 /// To write unit-tests for our bare-metal code, we include the x86_64
-/// arch-specific code on the `unix` platform.
-#[cfg(all(test, target_arch = "x86_64", target_family = "unix"))]
+/// arch-specific code on the `unix` platform (but not for model-checking,
+/// see `sync`).
+#[cfg(all(
+    test,
+    target_arch = "x86_64",
+    target_family = "unix",
+    not(feature = "loom")
+))]
 #[path = "arch/x86_64/mod.rs"]
 pub mod x86_64_arch;
 
+mod acpi;
+mod arch_interface;
+mod cap;
+mod checkpoint;
+mod cmdline;
 mod cnrfs;
+mod console;
+mod cpufreq;
+mod dmesg;
+mod drivers;
+mod entropy;
 mod error;
+mod event;
+mod fault;
 mod fs;
 mod graphviz;
+mod idle;
+mod initrd;
+mod ipc;
+mod itimer;
 mod kcb;
+mod kexec;
+mod latency;
 mod memory;
+mod net;
 mod nr;
 mod nrproc;
+mod numa;
 #[macro_use]
 mod prelude;
 mod fallible_string;
 mod mpmc;
+mod mutex;
 mod process;
+mod procfs;
+mod rcu;
+mod rpc;
 mod scheduler;
 mod stack;
+mod stats;
+mod sync;
+mod syscall_filter;
+mod time;
+mod trace;
 
 pub mod panic;
 
@@ -95,6 +131,7 @@ pub enum ExitReason {
     UserSpaceError = 7,
     ExceptionDuringInitialization = 8,
     UnrecoverableError = 9,
+    Watchdog = 10,
 }
 
 /// Kernel entry-point (after initialization has completed).
@@ -105,7 +142,8 @@ pub enum ExitReason {
 #[no_mangle]
 #[cfg(not(feature = "integration-test"))]
 pub fn xmain() {
-    let ret = arch::process::spawn("init");
+    let config = &kcb::get_kcb().config;
+    let ret = arch::process::spawn(config.init_binary, config.init_creds);
     if let Err(e) = ret {
         log::warn!("{}", e);
     }
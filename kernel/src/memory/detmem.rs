@@ -19,12 +19,12 @@ use arrayvec::ArrayVec;
 use atopology::MACHINE_TOPOLOGY;
 use crossbeam_utils::CachePadded;
 use log::info;
-use spin::Mutex;
 
 use crate::arch::MAX_NUMA_NODES;
 use crate::error::KError;
 use crate::kcb;
 use crate::mpmc::Queue;
+use crate::sync::Mutex;
 
 /// Makes allocation failures are deterministic (across all replicas) when used
 /// within a replica.
@@ -37,6 +37,9 @@ pub struct DeterministicAlloc {
     /// every allocation. Layout is technically not necessary (but used to
     /// sanity check the code).
     qs: ArrayVec<CachePadded<Queue<(Layout, u64)>>, MAX_NUMA_NODES>,
+    /// The nodes that have a replica (and take allocations out of their
+    /// queue); we don't allocate for the others.
+    replicated: ArrayVec<usize, MAX_NUMA_NODES>,
     /// Mutex that needs to be acquired when a leading replica needs to allocate
     /// for all replicas.
     fill: CachePadded<Mutex<()>>,
@@ -44,10 +47,24 @@ pub struct DeterministicAlloc {
 
 impl DeterministicAlloc {
     pub fn new() -> Result<Self, KError> {
-        DeterministicAlloc::new_with_nodes(atopology::MACHINE_TOPOLOGY.num_nodes())
+        let mut da = DeterministicAlloc::new_with_nodes(MACHINE_TOPOLOGY.num_nodes())?;
+        // Nodes without cores don't get replicas (see `crate::numa`)
+        da.replicated.retain(|node| crate::numa::has_cores(*node));
+        Ok(da)
     }
 
     pub fn new_with_nodes(nodes: usize) -> Result<Self, KError> {
+        // Need to figure out this capacity; it is hard to determine,
+        // something like: (#allocations of write op in NR with most
+        // allocations)*(max log entries till GC)
+        const ALLOC_CAP: usize = 32_000;
+
+        DeterministicAlloc::with_capacity(nodes, ALLOC_CAP)
+    }
+
+    /// An allocator for `nodes` replicas that are at most `capacity`
+    /// allocations apart.
+    fn with_capacity(nodes: usize, capacity: usize) -> Result<Self, KError> {
         assert!(
             nodes < MAX_NUMA_NODES,
             "Can't have more nodes than MAX_NUMA_NODES"
@@ -56,27 +73,51 @@ impl DeterministicAlloc {
         // Make sure we have at least 1 node
         let nodes = core::cmp::max(1, nodes);
 
-        // Need to figure out this capacity; it is hard to determine,
-        // something like: (#allocations of write op in NR with most
-        // allocations)*(max log entries till GC)
-        const ALLOC_CAP: usize = 32_000;
-
         let mut qs = ArrayVec::new();
-        for _i in 0..nodes {
-            qs.push(CachePadded::new(Queue::with_capacity(ALLOC_CAP)?));
+        let mut replicated = ArrayVec::new();
+        for i in 0..nodes {
+            qs.push(CachePadded::new(Queue::with_capacity(capacity)?));
+            replicated.push(i);
         }
 
         Ok(Self {
-            fill: CachePadded::new(Mutex::new(())),
+            fill: CachePadded::new(Mutex::new("detmem_fill", ())),
             qs,
+            replicated,
         })
     }
 
     pub fn alloc(&self, l: Layout) -> *mut u8 {
         // Current NUMA node id (hack approximate with the thread id):
-        let kcb = kcb::get_kcb();
-        let nid = kcb.node;
+        let nid = kcb::get_kcb().node;
+
+        let ptr = self.alloc_for(
+            nid,
+            l,
+            |node| {
+                kcb::get_kcb().set_allocation_affinity(node);
+                unsafe { alloc(l) }
+            },
+            |node, ptr| {
+                kcb::get_kcb().set_allocation_affinity(node);
+                unsafe { dealloc(ptr, l) };
+            },
+        );
+        kcb::get_kcb().set_allocation_affinity(nid);
+        ptr
+    }
 
+    /// Allocates `l` for the replica on node `nid`.
+    ///
+    /// `alloc_on` allocates on a node and `dealloc_on` frees what it
+    /// allocated (if it failed on another node).
+    fn alloc_for(
+        &self,
+        nid: usize,
+        l: Layout,
+        mut alloc_on: impl FnMut(usize) -> *mut u8,
+        mut dealloc_on: impl FnMut(usize, *mut u8),
+    ) -> *mut u8 {
         if let Some((rl, ptr)) = self.qs[nid].pop() {
             // Queue wasn't empty; the leading replica already allocated on our
             // behalf
@@ -99,18 +140,16 @@ impl DeterministicAlloc {
                 // Now that we locked `fill`, perform allocation for all
                 // replicas
 
-                let mut allocs = ArrayVec::<*mut u8, MAX_NUMA_NODES>::new();
-                for i in 0..self.qs.len() {
-                    kcb.set_allocation_affinity(i);
-                    allocs.push(unsafe { alloc(l) });
+                let mut allocs = [ptr::null_mut(); MAX_NUMA_NODES];
+                for &i in self.replicated.iter() {
+                    allocs[i] = alloc_on(i);
                 }
-                kcb.set_allocation_affinity(nid);
                 // Check if any of the allocation failed:
-                let succeeded = allocs.iter().filter(|e| e.is_null()).count() == 0;
+                let succeeded = self.replicated.iter().all(|&i| !allocs[i].is_null());
                 if succeeded {
                     // If we could allocate on every node, push all results to
                     // the queues
-                    for i in 0..self.qs.len() {
+                    for &i in self.replicated.iter() {
                         if i != nid {
                             self.qs[i]
                                 .push((l, allocs[i] as u64))
@@ -119,16 +158,17 @@ impl DeterministicAlloc {
                     }
                 } else {
                     // If we didn't succeed to allocate on all nodes
-                    for i in 0..self.qs.len() {
-                        kcb.set_allocation_affinity(i);
+                    for &i in self.replicated.iter() {
                         // Free any allocations that may have succeeded
                         if !allocs[i].is_null() {
-                            unsafe { dealloc(allocs[i], l) };
+                            dealloc_on(i, allocs[i]);
                         }
                         // Set all allocation results to NULL
-                        self.qs[i].push((l, 0x0)).expect("Can't push (2)");
+                        if i != nid {
+                            self.qs[i].push((l, 0x0)).expect("Can't push (2)");
+                        }
                     }
-                    kcb.set_allocation_affinity(nid);
+                    allocs[nid] = ptr::null_mut();
                 }
 
                 // Return allocation for current queue
@@ -275,4 +315,79 @@ mod test {
 
         Ok(())
     }
+
+    /// Nodes without a replica don't get allocations (nobody would take them
+    /// out of the queue).
+    #[test]
+    fn det_mem_skips_nodes_without_replica() -> Result<(), KError> {
+        let mut da = DeterministicAlloc::with_capacity(3, 4)?;
+        da.replicated.retain(|node| *node != 1);
+
+        let l = Layout::from_size_align(8, 8).unwrap();
+        let mut nodes = Vec::new();
+        let ptr = da.alloc_for(
+            0,
+            l,
+            |node| {
+                nodes.push(node);
+                0x1000 as *mut u8
+            },
+            |_node, _ptr| {},
+        );
+
+        assert_eq!(ptr as u64, 0x1000);
+        assert_eq!(nodes, [0, 2]);
+        assert!(da.qs[1].pop().is_none());
+        assert_eq!(da.qs[2].pop(), Some((l, 0x1000)));
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "loom"))]
+mod model {
+    use alloc::vec::Vec;
+
+    use loom::thread;
+
+    use super::*;
+    use crate::sync::atomic::{AtomicU64, Ordering};
+
+    /// Replicas that allocate the same things get memory of their own for
+    /// each (or all fail), no matter which one leads.
+    #[test]
+    fn loom_detmem_replicas() {
+        fn replica(da: Arc<DeterministicAlloc>, next: Arc<AtomicU64>, nid: usize) -> Vec<u64> {
+            let l = Layout::from_size_align(8, 8).unwrap();
+            (0..2)
+                .map(|i| {
+                    let alloc_on = |node: usize| {
+                        // The second allocation fails on node 1
+                        if i == 1 && node == 1 {
+                            ptr::null_mut()
+                        } else {
+                            next.fetch_add(0x10, Ordering::Relaxed) as *mut u8
+                        }
+                    };
+                    da.alloc_for(nid, l, alloc_on, |_node, _ptr| {}) as u64
+                })
+                .collect()
+        }
+
+        crate::sync::model(|| {
+            let da = Arc::new(DeterministicAlloc::with_capacity(2, 4).unwrap());
+            let next = Arc::new(AtomicU64::new(0x1000));
+
+            let other = {
+                let (da, next) = (da.clone(), next.clone());
+                thread::spawn(move || replica(da, next, 1))
+            };
+            let mine = replica(da, next, 0);
+            let theirs = other.join().unwrap();
+
+            assert_ne!(mine[0], 0);
+            assert_ne!(theirs[0], 0);
+            assert_ne!(mine[0], theirs[0]);
+            assert_eq!((mine[1], theirs[1]), (0, 0));
+        });
+    }
 }
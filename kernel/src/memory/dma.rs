@@ -0,0 +1,92 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Physically contiguous memory for devices to read from and write to.
+//!
+//! Buffers come from the memory of the NUMA node the allocating core is on,
+//! so a driver that sets up its queues on the core that uses them gets
+//! node-local DMA memory.
+
+use core::{fmt, slice};
+
+use log::warn;
+
+use crate::error::KError;
+use crate::kcb;
+
+use super::{Frame, KernelAllocator, PAddr, PhysicalPageProvider, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+
+/// A zeroed, physically contiguous buffer (a base or a large page).
+pub struct DmaBuffer {
+    frame: Frame,
+}
+
+impl DmaBuffer {
+    /// Allocates a buffer that holds at least `size` bytes.
+    pub fn new(size: usize) -> Result<DmaBuffer, KError> {
+        if size > LARGE_PAGE_SIZE {
+            return Err(KError::InvalidLayout);
+        }
+        let large = size > BASE_PAGE_SIZE;
+
+        let (bp, lp) = if large { (0, 1) } else { (1, 0) };
+        KernelAllocator::try_refill_tcache(bp, lp)?;
+
+        let mut frame = {
+            let kcb = kcb::get_kcb();
+            let mut pmanager = kcb.try_mem_manager()?;
+            if large {
+                pmanager.allocate_large_page()?
+            } else {
+                pmanager.allocate_base_page()?
+            }
+        };
+        unsafe { frame.zero() };
+
+        Ok(DmaBuffer { frame })
+    }
+
+    /// The address a device uses to access the buffer.
+    pub fn paddr(&self) -> PAddr {
+        self.frame.base
+    }
+
+    pub fn len(&self) -> usize {
+        self.frame.size()
+    }
+
+    /// The frame behind the buffer (e.g., to map it somewhere else too).
+    pub fn frame(&self) -> Frame {
+        self.frame
+    }
+
+    /// Pointer to the start of the buffer (in the kernel address space).
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.frame.kernel_vaddr().as_mut_ptr()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_ptr(), self.len()) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        if let Err(e) = super::release_frame(self.frame) {
+            warn!("Leaking DMA buffer {:?}: {}", self.frame, e);
+        }
+    }
+}
+
+impl fmt::Debug for DmaBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DmaBuffer")
+            .field("paddr", &self.paddr())
+            .field("len", &self.len())
+            .finish()
+    }
+}
@@ -22,10 +22,11 @@ use core::{fmt, ptr};
 use arrayvec::ArrayVec;
 use log::{debug, error, trace, warn};
 use slabmalloc::{Allocator, ZoneAllocator};
-use spin::Mutex;
 use x86::bits64::paging;
 
-use crate::arch::MAX_NUMA_NODES;
+use crate::arch::{Platform, MAX_NUMA_NODES};
+use crate::arch_interface::Arch;
+use crate::mutex::Mutex;
 use crate::prelude::*;
 use crate::{kcb, round_up};
 
@@ -38,10 +39,16 @@ pub use crate::arch::memory::{
 use vspace::MapAction;
 
 pub mod detmem;
+pub mod dma;
 pub mod emem;
 pub mod mcache;
+pub mod shared;
+pub mod shootdown;
+pub mod swap;
+#[cfg(feature = "heap-tracking")]
+pub mod track;
 pub mod vspace;
-#[cfg(test)]
+#[cfg(not(target_os = "none"))]
 pub mod vspace_model;
 
 /// How many initial physical memory regions we support.
@@ -93,6 +100,20 @@ pub fn size_to_pages(size: usize) -> (usize, usize) {
     (base_pages, large_pages)
 }
 
+/// Gives a (base or large page) frame back to the node it came from (we might
+/// be on a different one now).
+pub fn release_frame(frame: Frame) -> Result<(), KError> {
+    let gmanager = kcb::try_get_kcb()
+        .and_then(|kcb| kcb.physical_memory.gmanager)
+        .ok_or(KError::GlobalMemoryNotSet)?;
+    let mut ncache = gmanager.node_caches[frame.affinity as usize].lock();
+    if frame.size() == LARGE_PAGE_SIZE {
+        ncache.release_large_page(frame)
+    } else {
+        ncache.release_base_page(frame)
+    }
+}
+
 impl KernelAllocator {
     /// Try to allocate a piece of memory.
     fn try_alloc(&self, layout: Layout) -> Result<ptr::NonNull<u8>, KError> {
@@ -162,22 +183,19 @@ impl KernelAllocator {
 
                 let base_ptr = unsafe { ptr::NonNull::new_unchecked(start_at as *mut u8) };
 
-                let mut kvspace = kcb.arch.init_vspace();
                 for _ in 0..large {
                     let mut pmanager = kcb.try_mem_manager()?;
                     let f = pmanager
                         .allocate_large_page()
                         .expect("Can't run out of memory");
-                    drop(pmanager); // `map_generic` might try to re-acquire mem_manager
+                    drop(pmanager); // `map_kernel` might try to re-acquire mem_manager
 
-                    kvspace
-                        .map_generic(
-                            VAddr::from(start_at),
-                            (f.base, f.size()),
-                            MapAction::ReadWriteKernel,
-                            true,
-                        )
-                        .expect("Can't create the mapping");
+                    Platform::map_kernel(
+                        VAddr::from(start_at),
+                        (f.base, f.size()),
+                        MapAction::ReadWriteKernel,
+                    )
+                    .expect("Can't create the mapping");
 
                     start_at += LARGE_PAGE_SIZE as u64;
                 }
@@ -187,16 +205,14 @@ impl KernelAllocator {
                     let f = pmanager
                         .allocate_base_page()
                         .expect("Can't run out of memory");
-                    drop(pmanager); // `map_generic` might try to re-acquire mem_manager
-
-                    kvspace
-                        .map_generic(
-                            VAddr::from(start_at),
-                            (f.base, f.size()),
-                            MapAction::ReadWriteKernel,
-                            true,
-                        )
-                        .expect("Can't create the mapping");
+                    drop(pmanager); // `map_kernel` might try to re-acquire mem_manager
+
+                    Platform::map_kernel(
+                        VAddr::from(start_at),
+                        (f.base, f.size()),
+                        MapAction::ReadWriteKernel,
+                    )
+                    .expect("Can't create the mapping");
                     start_at += BASE_PAGE_SIZE as u64;
                 }
 
@@ -273,6 +289,7 @@ impl KernelAllocator {
         needed_base_pages: usize,
         needed_large_pages: usize,
     ) -> Result<(), KError> {
+        kprobe!("tcache_refill");
         let kcb = kcb::try_get_kcb().ok_or(KError::KcbUnavailable)?;
         if kcb.physical_memory.gmanager.is_none() {
             // No gmanager, can't refill then, let's hope it works anyways...
@@ -405,6 +422,8 @@ unsafe impl GlobalAlloc for KernelAllocator {
             match res {
                 // Allocation worked
                 Ok(nptr) => {
+                    #[cfg(feature = "heap-tracking")]
+                    track::allocated(nptr.as_ptr(), layout.size());
                     return nptr.as_ptr();
                 }
                 Err(KError::KcbUnavailable) => {
@@ -446,6 +465,8 @@ unsafe impl GlobalAlloc for KernelAllocator {
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "heap-tracking")]
+        track::freed(ptr);
         crate::kcb::try_get_kcb().map_or_else(
             || {
                 unreachable!("Trying to deallocate {:p} {:?} without a KCB.", ptr, layout);
@@ -541,6 +562,8 @@ unsafe impl GlobalAlloc for KernelAllocator {
                 {
                     // Don't do a re-allocation if we're in a big enough size-class
                     // in the ZoneAllocator
+                    #[cfg(feature = "heap-tracking")]
+                    track::allocated(ptr, new_size);
                     ptr
                 } else {
                     // Slow path, allocate a bigger region and de-allocate the old one
@@ -652,51 +675,65 @@ impl GlobalMemory {
         debug_assert!(!memory.is_empty());
         let mut gm = GlobalMemory::default();
 
-        // How many NUMA nodes are there in the system
+        // How many NUMA nodes are there in the system (nodes without memory,
+        // see `numa`, get empty caches)
         let max_affinity: usize = memory
             .iter()
             .map(|f| f.affinity as usize)
             .max()
             .expect("Need at least some frames")
             + 1;
+        let nodes = core::cmp::max(max_affinity, crate::numa::nodes());
 
         // Construct the `emem`'s for all NUMA nodes:
-        let mut cur_affinity = 0;
         // Top of the frames that we didn't end up using for the `emem` construction
         let mut leftovers: ArrayVec<Frame, MAX_PHYSICAL_REGIONS> = ArrayVec::new();
-        for frame in memory.iter_mut() {
+        for affinity in 0..nodes {
             const EMEM_SIZE: usize = 2 * LARGE_PAGE_SIZE + 64 * BASE_PAGE_SIZE;
-            if frame.affinity == cur_affinity && frame.size() > EMEM_SIZE {
-                // Let's make sure we have a frame that starts at a 2 MiB boundary which makes it easier
-                // to populate the TCache
-                let (low, large_page_aligned_frame) = frame.split_at_nearest_large_page_boundary();
-                *frame = low;
-
-                // Cut-away the top memory if the frame we got is too big
-                let (emem, leftover_mem) = large_page_aligned_frame.split_at(EMEM_SIZE);
-                if leftover_mem != Frame::empty() {
-                    // And safe it for later processing
-                    leftovers.push(leftover_mem);
+            let frame = memory
+                .iter_mut()
+                .find(|frame| frame.affinity == affinity && frame.size() > EMEM_SIZE);
+
+            let emem = match frame {
+                Some(frame) => {
+                    // Let's make sure we have a frame that starts at a 2 MiB boundary which makes it easier
+                    // to populate the TCache
+                    let (low, large_page_aligned_frame) =
+                        frame.split_at_nearest_large_page_boundary();
+                    *frame = low;
+
+                    // Cut-away the top memory if the frame we got is too big
+                    let (emem, leftover_mem) = large_page_aligned_frame.split_at(EMEM_SIZE);
+                    if leftover_mem != Frame::empty() {
+                        // And safe it for later processing
+                        leftovers.push(leftover_mem);
+                    }
+                    mcache::TCache::new_with_frame(affinity, emem)
                 }
-
-                gm.emem.push(Mutex::new(mcache::TCache::new_with_frame(
-                    cur_affinity,
-                    emem,
-                )));
-
-                cur_affinity += 1;
-            }
+                None => {
+                    // If this fails, memory is really fragmented or the node has very little
+                    assert!(
+                        !memory.iter().any(|frame| frame.affinity == affinity),
+                        "Can't add an early manager for NUMA node {}",
+                        affinity
+                    );
+                    mcache::TCache::new(affinity)
+                }
+            };
+            gm.emem.push(Mutex::new("emem", emem));
         }
-        // If this fails, memory is really fragmented or some nodes have no/little memory
-        assert_eq!(
-            gm.emem.len(),
-            max_affinity,
-            "Added early managers for all NUMA nodes"
-        );
 
         // Construct an NCache for all nodes
-        for affinity in 0..max_affinity {
-            let mut ncache_memory = gm.emem[affinity].lock().allocate_large_page()?;
+        for affinity in 0..nodes {
+            // A node without memory keeps its (empty) NCache on the node it
+            // allocates from
+            let local = gm.emem[affinity].lock().allocate_large_page();
+            let mut ncache_memory = match local {
+                Ok(frame) => frame,
+                Err(_e) => gm.emem[crate::numa::memory_node(affinity)]
+                    .lock()
+                    .allocate_large_page()?,
+            };
             let ncache_memory_addr: PAddr = ncache_memory.base;
             assert!(ncache_memory_addr != PAddr::zero());
             ncache_memory.zero(); // TODO(perf) this happens twice atm?
@@ -709,7 +746,8 @@ impl GlobalMemory {
                 paddr_to_kernel_vaddr(ncache_memory_addr).as_u64()
             );
 
-            gm.node_caches.push(CachePadded::new(Mutex::new(ncache)));
+            gm.node_caches
+                .push(CachePadded::new(Mutex::new("node_cache", ncache)));
         }
 
         // Populate the NCaches with all remaining memory
@@ -0,0 +1,84 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Frames that have more than one owner, e.g., a frame of a process that a
+//! VM maps as guest memory.
+//!
+//! A frame starts with a single owner and isn't in the table. [`share`] adds
+//! an owner, [`release`] drops one and gives the frame back to the memory
+//! manager once the last one is gone. Owners only ever drop their own
+//! reference, the others can keep using the frame.
+
+use alloc::vec::Vec;
+
+use fallible_collections::FallibleVec;
+use log::warn;
+
+use crate::error::KError;
+use crate::mutex::Mutex;
+
+use super::{Frame, PAddr};
+
+/// How many owners the shared frames have besides the first one (by base).
+static SHARED: Mutex<Vec<(PAddr, usize)>> = Mutex::new("shared-frames", Vec::new());
+
+/// Adds an owner of the frame at `base`.
+fn add_owner(shared: &mut Vec<(PAddr, usize)>, base: PAddr) -> Result<(), KError> {
+    match shared.iter_mut().find(|(other, _owners)| *other == base) {
+        Some((_base, owners)) => *owners += 1,
+        None => shared.try_push((base, 1))?,
+    }
+    Ok(())
+}
+
+/// Drops an owner of the frame at `base`, returns whether it was the last.
+fn drop_owner(shared: &mut Vec<(PAddr, usize)>, base: PAddr) -> bool {
+    match shared.iter().position(|(other, _owners)| *other == base) {
+        Some(idx) => {
+            shared[idx].1 -= 1;
+            if shared[idx].1 == 0 {
+                shared.swap_remove(idx);
+            }
+            false
+        }
+        None => true,
+    }
+}
+
+/// Somebody else holds on to `frame` as well, until it calls `release`.
+pub fn share(frame: Frame) -> Result<(), KError> {
+    add_owner(&mut SHARED.lock(), frame.base)
+}
+
+/// An owner of `frame` is done with it, the last one gives it back to the
+/// memory manager.
+pub fn release(frame: Frame) {
+    let last = drop_owner(&mut SHARED.lock(), frame.base);
+    if last {
+        if let Err(e) = super::release_frame(frame) {
+            warn!("Leaking {:?}: {}", frame, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn owners() {
+        let mut shared = Vec::new();
+        let (a, b) = (PAddr::from(0x1000), PAddr::from(0x20_0000));
+        add_owner(&mut shared, a).unwrap();
+        add_owner(&mut shared, a).unwrap();
+        add_owner(&mut shared, b).unwrap();
+        assert_eq!(shared, vec![(a, 2), (b, 1)]);
+
+        assert!(!drop_owner(&mut shared, a));
+        assert!(!drop_owner(&mut shared, b));
+        assert!(!drop_owner(&mut shared, a));
+        assert!(shared.is_empty());
+        // The one that allocated it
+        assert!(drop_owner(&mut shared, a));
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The hand-shake of a TLB shootdown.
+//!
+//! The core that changed a mapping hands a [`Shootdown`] to every other core
+//! that may still have it in its TLB and spins in [`wait`] until all of them
+//! acknowledged it. Getting the request to the other cores (work-queues and
+//! IPIs) and flushing the TLB is up to the architecture (`arch::tlb`).
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use super::BASE_PAGE_SIZE;
+use crate::is_page_aligned;
+use crate::sync::atomic::{AtomicBool, Ordering};
+use crate::sync::spin_loop;
+
+/// A request to flush the translations of `vregion`.
+#[derive(Debug)]
+pub struct Shootdown {
+    vregion: Range<u64>,
+    ack: AtomicBool,
+}
+
+impl Shootdown {
+    /// Create a new shootdown request.
+    pub fn new(vregion: Range<u64>) -> Self {
+        debug_assert!(is_page_aligned!(vregion.start));
+        debug_assert!(is_page_aligned!(vregion.end));
+        Shootdown {
+            vregion,
+            ack: AtomicBool::new(false),
+        }
+    }
+
+    /// The region to flush.
+    pub fn vregion(&self) -> Range<u64> {
+        self.vregion.clone()
+    }
+
+    /// Acknowledge shootdown to sender/requestor core.
+    ///
+    /// Everything we did before is visible to the requestor once it saw the
+    /// acknowledgement.
+    pub fn acknowledge(&self) {
+        self.ack.store(true, Ordering::Release);
+    }
+
+    /// Check if receiver has acknowledged the shootdown.
+    pub fn is_acknowledged(&self) -> bool {
+        self.ack.load(Ordering::Acquire)
+    }
+}
+
+/// Spins until all `shootdowns` are acknowledged (and removes them).
+pub fn wait(shootdowns: &mut Vec<Arc<Shootdown>>) {
+    while !shootdowns.is_empty() {
+        shootdowns.drain_filter(|s| s.is_acknowledged());
+        spin_loop();
+    }
+}
+
+#[cfg(all(test, feature = "loom"))]
+mod test {
+    use super::*;
+    use crate::sync::atomic::AtomicUsize;
+    use loom::thread;
+
+    /// Once `wait` returns, the requestor sees what the other cores did
+    /// before they acknowledged (i.e., flushed their TLB).
+    #[test]
+    fn loom_shootdown_handshake() {
+        crate::sync::model(|| {
+            let flushed = Arc::new(AtomicUsize::new(0));
+            let mut shootdowns = Vec::new();
+            let mut cores = Vec::new();
+            for _core in 0..2 {
+                let shootdown = Arc::new(Shootdown::new(0x1000..0x2000));
+                shootdowns.push(shootdown.clone());
+
+                let flushed = flushed.clone();
+                cores.push(thread::spawn(move || {
+                    assert_eq!(shootdown.vregion(), 0x1000..0x2000);
+                    flushed.fetch_add(1, Ordering::Relaxed);
+                    shootdown.acknowledge();
+                }));
+            }
+
+            wait(&mut shootdowns);
+            assert!(shootdowns.is_empty());
+            assert_eq!(flushed.load(Ordering::Relaxed), 2);
+
+            for core in cores {
+                core.join().unwrap();
+            }
+        });
+    }
+}
@@ -0,0 +1,676 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Bookkeeping for evicted pages of processes.
+//!
+//! An evicted page is either compressed and kept in memory (a pool like
+//! zswap, its size is `zswap=` on the command-line) or written to a slot of
+//! a block device (`swap=`). Which pages can be evicted, the table of evicted
+//! pages and the compression live here, evicting a page and bringing it back
+//! is up to the architecture (`arch::swap`).
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::ops::Range;
+
+use fallible_collections::btree::BTreeMap;
+use fallible_collections::vec::FallibleVec;
+use spin::Mutex;
+
+use super::vspace::MapAction;
+use super::BASE_PAGE_SIZE;
+use crate::drivers::block::{self, BlockDevice};
+use crate::error::KError;
+use crate::process::Pid;
+
+/// We keep a page in the pool if it compresses to at most this many bytes.
+pub const MAX_COMPRESSED: usize = BASE_PAGE_SIZE / 4 * 3;
+
+/// Most slots we use on a swap device (64 GiB with 4 KiB pages).
+pub const MAX_SLOTS: usize = 16 * 1024 * 1024;
+
+/// Words per page.
+const WORDS: usize = BASE_PAGE_SIZE / 8;
+
+/// Most words a tag of the encoding covers.
+const MAX_TOKEN_WORDS: usize = 128;
+
+fn word(page: &[u8], i: usize) -> u64 {
+    u64::from_le_bytes(page[i * 8..i * 8 + 8].try_into().unwrap())
+}
+
+/// Compresses `page` into `out`, returns false if it doesn't get below
+/// `MAX_COMPRESSED` bytes.
+///
+/// Anonymous memory is mostly zeroes and repeated words, so this is a
+/// run-length encoding of the 64-bit words: a tag `n < 0x80` is followed
+/// by `n + 1` words as they are, a tag `0x80 | n` by one word that repeats
+/// `n + 1` times.
+pub fn compress(page: &[u8], out: &mut Vec<u8>) -> Result<bool, KError> {
+    debug_assert_eq!(page.len(), BASE_PAGE_SIZE);
+    out.clear();
+    // We stop once we're over the limit, a tag adds at most this much
+    out.try_reserve(MAX_COMPRESSED + 1 + MAX_TOKEN_WORDS * 8)?;
+
+    let mut i = 0;
+    while i < WORDS {
+        let mut run = 1;
+        while i + run < WORDS && run < MAX_TOKEN_WORDS && word(page, i + run) == word(page, i) {
+            run += 1;
+        }
+
+        if run > 1 {
+            out.push(0x80 | (run - 1) as u8);
+            out.extend_from_slice(&page[i * 8..i * 8 + 8]);
+            i += run;
+        } else {
+            // Words as they are until the next run starts
+            let start = i;
+            i += 1;
+            while i < WORDS
+                && i - start < MAX_TOKEN_WORDS
+                && !(i + 1 < WORDS && word(page, i) == word(page, i + 1))
+            {
+                i += 1;
+            }
+            out.push((i - start - 1) as u8);
+            out.extend_from_slice(&page[start * 8..i * 8]);
+        }
+
+        if out.len() > MAX_COMPRESSED {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Restores a page `compress` produced `data` from.
+pub fn decompress(data: &[u8], page: &mut [u8]) -> Result<(), KError> {
+    let (mut pos, mut filled) = (0, 0);
+    while pos < data.len() {
+        let tag = data[pos];
+        let words = (tag & 0x7f) as usize + 1;
+        pos += 1;
+
+        let end = filled + words * 8;
+        if end > page.len() {
+            return Err(KError::CorruptSwapData);
+        }
+        if tag & 0x80 != 0 {
+            let value = data.get(pos..pos + 8).ok_or(KError::CorruptSwapData)?;
+            for chunk in page[filled..end].chunks_exact_mut(8) {
+                chunk.copy_from_slice(value);
+            }
+            pos += 8;
+        } else {
+            let literal = data
+                .get(pos..pos + words * 8)
+                .ok_or(KError::CorruptSwapData)?;
+            page[filled..end].copy_from_slice(literal);
+            pos += literal.len();
+        }
+        filled = end;
+    }
+
+    if filled != page.len() {
+        return Err(KError::CorruptSwapData);
+    }
+    Ok(())
+}
+
+/// The slots of a swap device (a set bit is a used slot).
+struct Slots {
+    used: Vec<u64>,
+    count: usize,
+    free: usize,
+    /// Where we start looking for a free slot.
+    next: usize,
+}
+
+impl Slots {
+    fn new(count: usize) -> Result<Slots, KError> {
+        let mut used = Vec::try_with_capacity((count + 63) / 64)?;
+        used.resize((count + 63) / 64, 0);
+        // The bits past the end are never free
+        if count % 64 != 0 {
+            *used.last_mut().unwrap() = !((1 << (count % 64)) - 1);
+        }
+        Ok(Slots {
+            used,
+            count,
+            free: count,
+            next: 0,
+        })
+    }
+
+    fn alloc(&mut self) -> Option<u32> {
+        if self.free == 0 {
+            return None;
+        }
+        let words = self.used.len();
+        for i in 0..words {
+            let idx = (self.next + i) % words;
+            let bits = self.used[idx];
+            if bits != u64::MAX {
+                let bit = (!bits).trailing_zeros() as usize;
+                self.used[idx] |= 1 << bit;
+                self.free -= 1;
+                self.next = idx;
+                return Some((idx * 64 + bit) as u32);
+            }
+        }
+        None
+    }
+
+    fn free(&mut self, slot: u32) {
+        let (idx, bit) = (slot as usize / 64, slot as usize % 64);
+        debug_assert!(self.used[idx] & (1 << bit) != 0, "Slot {} was free", slot);
+        self.used[idx] &= !(1 << bit);
+        self.free += 1;
+    }
+}
+
+/// A block device (or a region of it) that holds evicted pages.
+pub struct SwapDevice {
+    dev: Arc<dyn BlockDevice>,
+    /// Block of the first slot.
+    lba: u64,
+    slots: Mutex<Slots>,
+}
+
+impl SwapDevice {
+    /// Uses `dev` from block `lba` to the end (or `MAX_SLOTS` pages).
+    pub fn new(dev: Arc<dyn BlockDevice>, lba: u64) -> Result<SwapDevice, KError> {
+        let bs = dev.block_size();
+        if BASE_PAGE_SIZE % bs != 0 {
+            return Err(KError::NotSupported);
+        }
+        let blocks = dev
+            .num_blocks()
+            .checked_sub(lba)
+            .ok_or(KError::InvalidBlockRange)?;
+        let count = core::cmp::min(blocks as usize / (BASE_PAGE_SIZE / bs), MAX_SLOTS);
+        if count == 0 {
+            return Err(KError::InvalidBlockRange);
+        }
+        block::check_range(&*dev, lba, count * BASE_PAGE_SIZE)?;
+
+        Ok(SwapDevice {
+            dev,
+            lba,
+            slots: Mutex::new(Slots::new(count)?),
+        })
+    }
+
+    fn lba(&self, slot: u32) -> u64 {
+        self.lba + slot as u64 * (BASE_PAGE_SIZE / self.dev.block_size()) as u64
+    }
+
+    /// Writes `page` to a free slot and returns it.
+    pub fn write(&self, page: &[u8]) -> Result<u32, KError> {
+        let slot = self.slots.lock().alloc().ok_or(KError::SwapFull)?;
+        match self.dev.write(self.lba(slot), page) {
+            Ok(()) => Ok(slot),
+            Err(e) => {
+                self.slots.lock().free(slot);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn read(&self, slot: u32, page: &mut [u8]) -> Result<(), KError> {
+        self.dev.read(self.lba(slot), page)
+    }
+
+    /// The page in `slot` is no longer needed.
+    pub fn free(&self, slot: u32) {
+        self.slots.lock().free(slot)
+    }
+
+    /// Used and total slots.
+    pub fn usage(&self) -> (usize, usize) {
+        let slots = self.slots.lock();
+        (slots.count - slots.free, slots.count)
+    }
+}
+
+/// Where an evicted page is.
+#[derive(Debug, PartialEq)]
+pub enum Location {
+    /// Compressed, in the pool.
+    Pool(Vec<u8>),
+    /// In a slot of the swap device.
+    Device(u32),
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    /// Unmapped, but not stored yet.
+    Evicting,
+    Stored(Location),
+    /// Somebody maps it again.
+    Loading,
+}
+
+#[derive(Debug)]
+struct Entry {
+    state: State,
+    rights: MapAction,
+}
+
+/// What `Swapped::load` found.
+#[derive(Debug, PartialEq)]
+pub enum Lookup {
+    /// The page isn't evicted.
+    Resident,
+    /// Somebody evicts or loads it right now, try again later.
+    Busy,
+    /// The caller brings it back with these rights (and tells us with
+    /// `loaded` or gives it back with `stored`).
+    Load(Location, MapAction),
+}
+
+/// The evicted pages and the pages that can be evicted.
+pub struct Swapped {
+    entries: BTreeMap<(Pid, u64), Entry>,
+    /// Memory processes got with `VSpace::map` (sorted).
+    anonymous: Vec<(Pid, Range<u64>)>,
+    /// The next page the clock looks at.
+    hand: (Pid, u64),
+    pool_limit: usize,
+    pool_used: usize,
+    pub evictions: u64,
+    pub loads: u64,
+}
+
+impl Swapped {
+    pub fn new(pool_limit: usize) -> Swapped {
+        Swapped {
+            entries: BTreeMap::new(),
+            anonymous: Vec::new(),
+            hand: (0, 0),
+            pool_limit,
+            pool_used: 0,
+            evictions: 0,
+            loads: 0,
+        }
+    }
+
+    /// Pages in `range` of `pid` can be evicted.
+    pub fn add_anonymous(&mut self, pid: Pid, range: Range<u64>) -> Result<(), KError> {
+        if range.is_empty() {
+            return Ok(());
+        }
+        let idx = self
+            .anonymous
+            .partition_point(|(p, r)| (*p, r.start) < (pid, range.start));
+        self.anonymous.try_reserve(1)?;
+        self.anonymous.insert(idx, (pid, range));
+        Ok(())
+    }
+
+    /// Pages in `range` of `pid` can no longer be evicted.
+    pub fn remove_anonymous(&mut self, pid: Pid, range: Range<u64>) -> Result<(), KError> {
+        // Cutting a hole in a range needs one more
+        self.anonymous.try_reserve(1)?;
+        let mut i = 0;
+        while i < self.anonymous.len() {
+            let (p, r) = self.anonymous[i].clone();
+            if p != pid || r.end <= range.start || r.start >= range.end {
+                i += 1;
+                continue;
+            }
+            match (r.start < range.start, r.end > range.end) {
+                (true, true) => {
+                    self.anonymous[i].1 = r.start..range.start;
+                    self.anonymous.insert(i + 1, (pid, range.end..r.end));
+                    i += 2;
+                }
+                (true, false) => {
+                    self.anonymous[i].1 = r.start..range.start;
+                    i += 1;
+                }
+                (false, true) => {
+                    self.anonymous[i].1 = range.end..r.end;
+                    i += 1;
+                }
+                (false, false) => {
+                    self.anonymous.remove(i);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_anonymous(&self, pid: Pid, addr: u64) -> bool {
+        self.anonymous
+            .iter()
+            .any(|(p, r)| *p == pid && r.contains(&addr))
+    }
+
+    /// Number of pages that can be evicted (or are).
+    pub fn anonymous_pages(&self) -> usize {
+        self.anonymous
+            .iter()
+            .map(|(_p, r)| ((r.end - r.start) / BASE_PAGE_SIZE as u64) as usize)
+            .sum()
+    }
+
+    /// Advances the clock, returns the anonymous page it passed.
+    pub fn next_page(&mut self) -> Option<(Pid, u64)> {
+        let (pid, addr) = self.hand;
+        let (p, page) = match self
+            .anonymous
+            .iter()
+            .find(|(p, r)| (*p, r.end) > (pid, addr))
+        {
+            Some((p, r)) if *p == pid && r.start < addr => (*p, addr),
+            Some((p, r)) => (*p, r.start),
+            // Start over
+            None => self.anonymous.first().map(|(p, r)| (*p, r.start))?,
+        };
+        self.hand = (p, page + BASE_PAGE_SIZE as u64);
+        Some((p, page))
+    }
+
+    /// Whether the page at `addr` of `pid` is evicted (or on its way).
+    pub fn is_evicted(&self, pid: Pid, addr: u64) -> bool {
+        self.entries.get(&(pid, addr)).is_some()
+    }
+
+    /// The page at `addr` of `pid` (mapped with `rights`) is about to be
+    /// unmapped, others have to wait until it's stored.
+    pub fn evicting(&mut self, pid: Pid, addr: u64, rights: MapAction) -> Result<(), KError> {
+        if self.is_evicted(pid, addr) {
+            return Err(KError::AlreadyPresent);
+        }
+        self.entries.try_insert(
+            (pid, addr),
+            Entry {
+                state: State::Evicting,
+                rights,
+            },
+        )?;
+        Ok(())
+    }
+
+    /// The page is at `location` now.
+    pub fn stored(&mut self, pid: Pid, addr: u64, location: Location) {
+        match self.entries.get_mut(&(pid, addr)) {
+            Some(entry) => {
+                if let Location::Pool(data) = &location {
+                    self.pool_used += data.len();
+                }
+                if entry.state == State::Evicting {
+                    self.evictions += 1;
+                }
+                entry.state = State::Stored(location);
+            }
+            None => debug_assert!(false, "Stored {:#x} of {} we don't know", addr, pid),
+        }
+    }
+
+    /// The eviction of the page didn't work out (it's mapped again).
+    pub fn abort(&mut self, pid: Pid, addr: u64) {
+        let entry = self.entries.remove(&(pid, addr));
+        debug_assert!(matches!(
+            entry,
+            Some(Entry {
+                state: State::Evicting,
+                ..
+            })
+        ));
+    }
+
+    /// Hands the page to the caller to bring it back.
+    pub fn load(&mut self, pid: Pid, addr: u64) -> Lookup {
+        let entry = match self.entries.get_mut(&(pid, addr)) {
+            Some(entry) => entry,
+            None => return Lookup::Resident,
+        };
+        match core::mem::replace(&mut entry.state, State::Loading) {
+            State::Stored(location) => {
+                if let Location::Pool(data) = &location {
+                    self.pool_used -= data.len();
+                }
+                Lookup::Load(location, entry.rights)
+            }
+            state => {
+                entry.state = state;
+                Lookup::Busy
+            }
+        }
+    }
+
+    /// The page is mapped again.
+    pub fn loaded(&mut self, pid: Pid, addr: u64) {
+        let entry = self.entries.remove(&(pid, addr));
+        debug_assert!(matches!(
+            entry,
+            Some(Entry {
+                state: State::Loading,
+                ..
+            })
+        ));
+        self.loads += 1;
+    }
+
+    /// Drops the page (its process unmapped it), the caller frees what the
+    /// returned location holds.
+    pub fn forget(&mut self, pid: Pid, addr: u64) -> Lookup {
+        let lookup = self.load(pid, addr);
+        if let Lookup::Load(..) = lookup {
+            self.entries.remove(&(pid, addr));
+        }
+        lookup
+    }
+
+    /// The first evicted page of `pid` in `range`.
+    pub fn evicted_in(&self, pid: Pid, range: Range<u64>) -> Option<u64> {
+        self.entries
+            .range((pid, range.start)..(pid, range.end))
+            .next()
+            .map(|((_pid, addr), _entry)| *addr)
+    }
+
+    /// Whether `len` more bytes fit in the pool.
+    pub fn pool_has_room(&self, len: usize) -> bool {
+        self.pool_used + len <= self.pool_limit
+    }
+
+    /// Bytes in the pool, pages in the pool and pages on the device.
+    pub fn usage(&self) -> (usize, usize, usize) {
+        let pooled = self
+            .entries
+            .values()
+            .filter(|e| matches!(e.state, State::Stored(Location::Pool(_))))
+            .count();
+        let on_device = self
+            .entries
+            .values()
+            .filter(|e| matches!(e.state, State::Stored(Location::Device(_))))
+            .count();
+        (self.pool_used, pooled, on_device)
+    }
+
+    pub fn pool_limit(&self) -> usize {
+        self.pool_limit
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn roundtrip(page: &[u8]) -> Option<usize> {
+        let mut data = Vec::new();
+        if !compress(page, &mut data).unwrap() {
+            return None;
+        }
+        let mut restored = alloc::vec![0xffu8; BASE_PAGE_SIZE];
+        decompress(&data, &mut restored).unwrap();
+        assert_eq!(page, &restored[..]);
+        Some(data.len())
+    }
+
+    #[test]
+    fn compress_zero_page() {
+        let page = alloc::vec![0u8; BASE_PAGE_SIZE];
+        // 512 words are four runs of 128
+        assert_eq!(roundtrip(&page), Some(4 * 9));
+    }
+
+    #[test]
+    fn compress_patterns() {
+        let mut page = alloc::vec![0u8; BASE_PAGE_SIZE];
+        for (i, chunk) in page.chunks_exact_mut(8).enumerate() {
+            // Runs of four words and a few counters in between
+            let value = if i % 64 < 8 { i as u64 } else { (i / 4) as u64 };
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        assert!(roundtrip(&page).unwrap() < MAX_COMPRESSED);
+
+        // A single word different from its neighbours at the end
+        let mut page = alloc::vec![0u8; BASE_PAGE_SIZE];
+        page[BASE_PAGE_SIZE - 1] = 1;
+        assert_eq!(roundtrip(&page), Some(4 * 9 + 9));
+    }
+
+    #[test]
+    fn compress_random() {
+        let mut page = alloc::vec![0u8; BASE_PAGE_SIZE];
+        let mut x = 0x2545_f491_4f6c_dd1du64;
+        for chunk in page.chunks_exact_mut(8) {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            chunk.copy_from_slice(&x.to_le_bytes());
+        }
+        assert_eq!(roundtrip(&page), None);
+    }
+
+    #[test]
+    fn decompress_corrupt() {
+        let mut page = alloc::vec![0u8; BASE_PAGE_SIZE];
+        // Too short
+        assert!(decompress(&[0x80 | 3, 0, 0, 0, 0, 0, 0, 0, 0], &mut page).is_err());
+        // Truncated word
+        assert!(decompress(&[0xff, 0, 0], &mut page).is_err());
+        // Too long
+        let mut data = Vec::new();
+        for _ in 0..5 {
+            data.extend_from_slice(&[0xff, 0, 0, 0, 0, 0, 0, 0, 0]);
+        }
+        assert!(decompress(&data, &mut page).is_err());
+    }
+
+    #[test]
+    fn slots() {
+        let mut slots = Slots::new(66).unwrap();
+        for i in 0..66 {
+            assert_eq!(slots.alloc(), Some(i));
+        }
+        assert_eq!(slots.alloc(), None);
+        slots.free(65);
+        slots.free(3);
+        assert_eq!(slots.alloc(), Some(65));
+        assert_eq!(slots.alloc(), Some(3));
+        assert_eq!(slots.alloc(), None);
+    }
+
+    #[test]
+    fn anonymous_ranges() {
+        let page = BASE_PAGE_SIZE as u64;
+        let mut swapped = Swapped::new(0);
+        swapped
+            .add_anonymous(1, 0x10000..0x10000 + 8 * page)
+            .unwrap();
+        swapped.add_anonymous(0, 0x20000..0x20000 + page).unwrap();
+        assert_eq!(swapped.anonymous_pages(), 9);
+
+        swapped
+            .remove_anonymous(1, 0x10000 + 2 * page..0x10000 + 3 * page)
+            .unwrap();
+        assert_eq!(swapped.anonymous_pages(), 8);
+        assert!(swapped.is_anonymous(1, 0x10000 + page));
+        assert!(!swapped.is_anonymous(1, 0x10000 + 2 * page));
+        assert!(swapped.is_anonymous(1, 0x10000 + 3 * page));
+        assert!(!swapped.is_anonymous(1, 0x20000));
+
+        swapped.remove_anonymous(1, 0..u64::MAX).unwrap();
+        assert_eq!(swapped.anonymous_pages(), 1);
+    }
+
+    #[test]
+    fn clock() {
+        let page = BASE_PAGE_SIZE as u64;
+        let mut swapped = Swapped::new(0);
+        assert_eq!(swapped.next_page(), None);
+
+        swapped.add_anonymous(2, 0x1000..0x1000 + 2 * page).unwrap();
+        swapped.add_anonymous(1, 0x8000..0x8000 + page).unwrap();
+        assert_eq!(swapped.next_page(), Some((1, 0x8000)));
+        assert_eq!(swapped.next_page(), Some((2, 0x1000)));
+        assert_eq!(swapped.next_page(), Some((2, 0x1000 + page)));
+        // Wraps around
+        assert_eq!(swapped.next_page(), Some((1, 0x8000)));
+
+        // Keeps its place if the range it's in shrinks
+        swapped.remove_anonymous(2, 0x1000..0x1000 + page).unwrap();
+        assert_eq!(swapped.next_page(), Some((2, 0x1000 + page)));
+    }
+
+    #[test]
+    fn evict_and_load() {
+        let mut swapped = Swapped::new(100);
+        assert_eq!(swapped.load(0, 0x1000), Lookup::Resident);
+
+        swapped
+            .evicting(0, 0x1000, MapAction::ReadWriteUser)
+            .unwrap();
+        assert!(swapped.evicting(0, 0x1000, MapAction::ReadUser).is_err());
+        assert_eq!(swapped.load(0, 0x1000), Lookup::Busy);
+
+        assert!(swapped.pool_has_room(100));
+        swapped.stored(0, 0x1000, Location::Pool(alloc::vec![0; 60]));
+        assert!(!swapped.pool_has_room(41));
+        assert_eq!(swapped.usage(), (60, 1, 0));
+
+        let lookup = swapped.load(0, 0x1000);
+        assert_eq!(
+            lookup,
+            Lookup::Load(Location::Pool(alloc::vec![0; 60]), MapAction::ReadWriteUser)
+        );
+        assert_eq!(swapped.load(0, 0x1000), Lookup::Busy);
+        assert_eq!(swapped.usage(), (0, 0, 0));
+
+        // Loading failed, it goes back
+        if let Lookup::Load(location, _rights) = lookup {
+            swapped.stored(0, 0x1000, location);
+        }
+        assert_eq!(swapped.usage(), (60, 1, 0));
+        assert!(matches!(swapped.load(0, 0x1000), Lookup::Load(..)));
+        swapped.loaded(0, 0x1000);
+        assert_eq!(swapped.load(0, 0x1000), Lookup::Resident);
+        assert_eq!((swapped.evictions, swapped.loads), (1, 1));
+
+        swapped.evicting(1, 0x2000, MapAction::ReadUser).unwrap();
+        swapped.abort(1, 0x2000);
+        assert_eq!(swapped.load(1, 0x2000), Lookup::Resident);
+
+        swapped.evicting(1, 0x3000, MapAction::ReadUser).unwrap();
+        swapped.stored(1, 0x3000, Location::Device(7));
+        assert_eq!(swapped.evicted_in(1, 0x1000..0x3000), None);
+        assert_eq!(swapped.evicted_in(1, 0x1000..0x4000), Some(0x3000));
+        assert_eq!(
+            swapped.forget(1, 0x3000),
+            Lookup::Load(Location::Device(7), MapAction::ReadUser)
+        );
+        assert_eq!(swapped.forget(1, 0x3000), Lookup::Resident);
+        assert_eq!(swapped.loads, 1);
+    }
+}
@@ -0,0 +1,306 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Keeps track of every live kernel heap allocation (feature
+//! `heap-tracking`) to find out which subsystem leaks memory.
+//!
+//! We remember the size, the subsystem that allocated it (the system call
+//! the core was handling, or the kernel itself) and when. A report of what
+//! is still allocated (per subsystem and the oldest allocations) gets
+//! printed when a process exits and can be read from `/proc/heap`.
+//!
+//! The records live in a fixed-size hash table (we can't allocate while we
+//! allocate). If it is full, or an interrupt allocates while its core
+//! updates the table, we don't keep track of the allocation (the report
+//! says how many we missed).
+
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use kpi::SystemCall;
+use log::{error, info};
+use spin::Mutex;
+use x86::time::rdtsc;
+
+use crate::arch::MAX_CORES;
+use crate::error::KError;
+use crate::kcb::ArchSpecificKcb;
+use crate::stats::SYSCALLS;
+
+/// How many allocations we keep track of (at most 3/4 of it are used).
+const CAPACITY: usize = 1 << 15;
+
+/// How many of the oldest allocations we list in a report.
+const OLDEST: usize = 8;
+
+/// Subsystem of allocations a core makes outside of a system call.
+const KERNEL: u8 = 0;
+
+#[derive(Copy, Clone)]
+struct Record {
+    /// 0 if the slot is empty.
+    addr: usize,
+    size: usize,
+    /// The system call (or `KERNEL`).
+    tag: u8,
+    /// TSC when it was allocated.
+    since: u64,
+}
+
+impl Record {
+    const EMPTY: Record = Record {
+        addr: 0,
+        size: 0,
+        tag: KERNEL,
+        since: 0,
+    };
+}
+
+/// Open addressing with linear probing (and backward shift on removal).
+struct Table {
+    records: [Record; CAPACITY],
+    len: usize,
+}
+
+impl Table {
+    const fn new() -> Table {
+        Table {
+            records: [Record::EMPTY; CAPACITY],
+            len: 0,
+        }
+    }
+
+    fn home(addr: usize) -> usize {
+        // Fibonacci hashing, allocations are at least 8 byte aligned
+        ((addr >> 3).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - CAPACITY.trailing_zeros()))
+            & (CAPACITY - 1)
+    }
+
+    fn insert(&mut self, record: Record) -> bool {
+        if self.len >= CAPACITY / 4 * 3 {
+            return false;
+        }
+
+        let mut idx = Table::home(record.addr);
+        while self.records[idx].addr != 0 && self.records[idx].addr != record.addr {
+            idx = (idx + 1) & (CAPACITY - 1);
+        }
+        if self.records[idx].addr == 0 {
+            self.len += 1;
+        }
+        self.records[idx] = record;
+        true
+    }
+
+    fn remove(&mut self, addr: usize) -> bool {
+        let mut hole = Table::home(addr);
+        loop {
+            match self.records[hole].addr {
+                0 => return false,
+                a if a == addr => break,
+                _ => hole = (hole + 1) & (CAPACITY - 1),
+            }
+        }
+
+        // Move entries that can't be found anymore (their probe sequence
+        // passes through the hole) into the hole
+        let mut next = (hole + 1) & (CAPACITY - 1);
+        while self.records[next].addr != 0 {
+            let home = Table::home(self.records[next].addr);
+            let reachable = if hole <= next {
+                hole < home && home <= next
+            } else {
+                hole < home || home <= next
+            };
+            if !reachable {
+                self.records[hole] = self.records[next];
+                hole = next;
+            }
+            next = (next + 1) & (CAPACITY - 1);
+        }
+
+        self.records[hole] = Record::EMPTY;
+        self.len -= 1;
+        true
+    }
+}
+
+static TABLE: Mutex<Table> = Mutex::new(Table::new());
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Allocations we didn't keep track of.
+static MISSED: AtomicU64 = AtomicU64::new(0);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const IN_KERNEL: AtomicU8 = AtomicU8::new(KERNEL);
+/// The subsystem every core currently allocates for.
+static TAGS: [AtomicU8; MAX_CORES] = [IN_KERNEL; MAX_CORES];
+
+fn current_core() -> Option<usize> {
+    crate::kcb::try_get_kcb().map(|kcb| kcb.arch.hwthread_id())
+}
+
+/// Starts keeping track of allocations.
+pub fn init() -> Result<(), KError> {
+    crate::procfs::register("/proc/heap", proc_heap)?;
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Attributes the allocations of the current core to system call
+/// `function` (0 for the kernel itself).
+pub fn set_tag(function: u64) {
+    if let Some(core) = current_core() {
+        let tag = if function < SYSCALLS as u64 {
+            function as u8
+        } else {
+            KERNEL
+        };
+        TAGS[core].store(tag, Ordering::Relaxed);
+    }
+}
+
+/// Remembers the allocation at `ptr`.
+pub fn allocated(ptr: *mut u8, size: usize) {
+    if !ENABLED.load(Ordering::Relaxed) || ptr.is_null() {
+        return;
+    }
+
+    let tag = current_core().map_or(KERNEL, |core| TAGS[core].load(Ordering::Relaxed));
+    let record = Record {
+        addr: ptr as usize,
+        size,
+        tag,
+        since: unsafe { rdtsc() },
+    };
+    let tracked = TABLE
+        .try_lock()
+        .map_or(false, |mut table| table.insert(record));
+    if !tracked {
+        MISSED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Forgets the allocation at `ptr`.
+pub fn freed(ptr: *mut u8) {
+    if !ENABLED.load(Ordering::Relaxed) || ptr.is_null() {
+        return;
+    }
+
+    // Allocations we missed or made before `init` aren't in the table
+    if let Some(mut table) = TABLE.try_lock() {
+        table.remove(ptr as usize);
+    }
+}
+
+fn write_tag(out: &mut String, tag: u8) -> fmt::Result {
+    if tag == KERNEL {
+        write!(out, "Kernel")
+    } else {
+        write!(out, "{:?}", SystemCall::new(tag as u64))
+    }
+}
+
+/// Writes what is still allocated (per subsystem and the oldest
+/// allocations).
+pub fn write_report(out: &mut String) -> fmt::Result {
+    let mut counts = [0usize; SYSCALLS];
+    let mut bytes = [0usize; SYSCALLS];
+    let mut oldest = [Record::EMPTY; OLDEST];
+    let now = unsafe { rdtsc() };
+
+    {
+        let table = TABLE.lock();
+        for record in table.records.iter().filter(|r| r.addr != 0) {
+            counts[record.tag as usize] += 1;
+            bytes[record.tag as usize] += record.size;
+
+            // `oldest` is sorted (oldest first, empty slots last)
+            if let Some(pos) = oldest
+                .iter()
+                .position(|o| o.addr == 0 || record.since < o.since)
+            {
+                oldest[pos..].rotate_right(1);
+                oldest[pos] = *record;
+            }
+        }
+    }
+
+    writeln!(out, "{:<12} {:>10} {:>12}", "subsystem", "allocs", "bytes")?;
+    for (tag, (count, bytes)) in counts.iter().zip(bytes.iter()).enumerate() {
+        if *count > 0 {
+            let mut name = String::new();
+            write_tag(&mut name, tag as u8)?;
+            writeln!(out, "{:<12} {:>10} {:>12}", name, count, bytes)?;
+        }
+    }
+    writeln!(out, "missed {}", MISSED.load(Ordering::Relaxed))?;
+
+    writeln!(out, "oldest:")?;
+    for record in oldest.iter().filter(|r| r.addr != 0) {
+        let mut name = String::new();
+        write_tag(&mut name, record.tag)?;
+        writeln!(
+            out,
+            "{:#x} {} bytes by {} {} cycles ago",
+            record.addr,
+            record.size,
+            name,
+            now.saturating_sub(record.since)
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Prints what is still allocated.
+pub fn report() {
+    let mut out = String::new();
+    match write_report(&mut out) {
+        Ok(()) => {
+            for line in out.lines() {
+                info!("[heap] {}", line);
+            }
+        }
+        Err(e) => error!("Can't write heap report: {:?}", e),
+    }
+}
+
+/// Contents of `/proc/heap`.
+fn proc_heap(out: &mut String) -> fmt::Result {
+    write_report(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn insert_remove() {
+        // Too big for the stack
+        static TEST_TABLE: Mutex<Table> = Mutex::new(Table::new());
+        let mut table = TEST_TABLE.lock();
+        let record = |addr| Record {
+            addr,
+            size: 8,
+            tag: KERNEL,
+            since: 0,
+        };
+
+        for addr in (1..1000).map(|i| i * 16) {
+            assert!(table.insert(record(addr)));
+        }
+        assert_eq!(table.len, 999);
+        for addr in (1..1000).filter(|i| i % 3 == 0).map(|i| i * 16) {
+            assert!(table.remove(addr));
+        }
+        assert!(!table.remove(3 * 16));
+        for addr in (1..1000).filter(|i| i % 3 != 0).map(|i| i * 16) {
+            assert!(table.remove(addr), "Can still find {:#x}", addr);
+        }
+        assert_eq!(table.len, 0);
+        assert!(table.records.iter().all(|r| r.addr == 0));
+    }
+}
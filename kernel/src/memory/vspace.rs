@@ -3,6 +3,7 @@
 
 //! A trait defining architecture independent address spaces.
 
+use alloc::vec::Vec;
 use core::cmp::PartialEq;
 use core::fmt;
 
@@ -167,6 +168,30 @@ pub trait AddressSpace {
     /// and access rights or an error in case no mapping is found.
     fn resolve(&self, vaddr: VAddr) -> Result<(PAddr, MapAction), KError>;
 
+    /// Where the mapping that contains `vaddr` starts and how large it is
+    /// (the region `unmap` would remove).
+    fn extent(&self, vaddr: VAddr) -> Result<(VAddr, usize), KError>;
+
+    /// Clears the accessed bit of the mapping that contains `vaddr`.
+    ///
+    /// # Returns
+    /// Whether the mapping was accessed since the bit was cleared last.
+    fn harvest_accessed(&self, _vaddr: VAddr) -> Result<bool, KError> {
+        Err(KError::NotSupported)
+    }
+
+    /// Whether the mapping that contains `vaddr` was written since it was
+    /// mapped (or since `set_dirty`, the bit is never cleared).
+    fn dirty(&self, _vaddr: VAddr) -> Result<bool, KError> {
+        Err(KError::NotSupported)
+    }
+
+    /// Marks the mapping that contains `vaddr` as written, for contents the
+    /// kernel put there through another mapping of the frame.
+    fn set_dirty(&self, _vaddr: VAddr) -> Result<(), KError> {
+        Err(KError::NotSupported)
+    }
+
     /// Removes the frame from the address space that contains `vaddr`.
     ///
     /// # Returns
@@ -174,8 +199,20 @@ pub trait AddressSpace {
     /// invoked to flush the TLB.
     fn unmap(&mut self, vaddr: VAddr) -> Result<TlbFlushHandle, KError>;
 
-    // Returns an iterator of all currently mapped memory regions.
-    //fn mappings()
+    /// All mappings of the address space, ordered by address.
+    fn regions(&self) -> Result<Vec<Region>, KError> {
+        Err(KError::NotSupported)
+    }
+}
+
+/// A mapping of an address space (see `AddressSpace::regions`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub base: VAddr,
+    pub frame: Frame,
+    pub rights: MapAction,
+    /// See `AddressSpace::dirty`.
+    pub dirty: bool,
 }
 
 /// Mapping rights to give to address translation.
@@ -194,6 +231,9 @@ pub enum MapAction {
     ReadWriteUserNoCache,
     /// Map region read-write for kernel.
     ReadWriteKernel,
+    /// Map region read-write for kernel with write-combining (e.g., for a
+    /// framebuffer).
+    ReadWriteKernelWriteCombining,
     /// Map region read-executable.
     ReadExecuteUser,
     /// Map region read-executable for kernel.
@@ -202,9 +242,41 @@ pub enum MapAction {
     ReadWriteExecuteUser,
     /// Map region read-write-executable for kernel.
     ReadWriteExecuteKernel,
+    /// Map region as a shadow stack (CET) for user-space.
+    ShadowStackUser,
+    /// Map region as a shadow stack (CET) for the kernel.
+    ShadowStackKernel,
+}
+
+/// What the kernel does with user memory (e.g., the buffer of a system call).
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum UserAccess {
+    /// The kernel reads it (e.g., the data for a file write).
+    Read,
+    /// The kernel writes to it (e.g., the data of a file read).
+    Write,
 }
 
 impl MapAction {
+    /// Whether user-space memory mapped like this can be used for `access`.
+    pub fn allows(self, access: UserAccess) -> bool {
+        use MapAction::*;
+        match access {
+            UserAccess::Read => matches!(
+                self,
+                ReadUser
+                    | ReadWriteUser
+                    | ReadWriteUserNoCache
+                    | ReadExecuteUser
+                    | ReadWriteExecuteUser
+            ),
+            UserAccess::Write => matches!(
+                self,
+                ReadWriteUser | ReadWriteUserNoCache | ReadWriteExecuteUser
+            ),
+        }
+    }
+
     /// Transform MapAction into rights for 1 GiB page.
     pub fn to_pdpt_rights(self) -> PDPTFlags {
         use MapAction::*;
@@ -213,12 +285,16 @@ impl MapAction {
             ReadUser => PDPTFlags::XD | PDPTFlags::US,
             ReadKernel => PDPTFlags::XD,
             ReadWriteUser => PDPTFlags::RW | PDPTFlags::XD | PDPTFlags::US,
-            ReadWriteUserNoCache => PDPTFlags::RW | PDPTFlags::XD | PDPTFlags::US,
+            ReadWriteUserNoCache => PDPTFlags::RW | PDPTFlags::XD | PDPTFlags::US | PDPTFlags::PCD,
             ReadWriteKernel => PDPTFlags::RW | PDPTFlags::XD,
+            ReadWriteKernelWriteCombining => PDPTFlags::RW | PDPTFlags::XD | PDPTFlags::PWT,
             ReadExecuteUser => PDPTFlags::US,
             ReadExecuteKernel => PDPTFlags::empty(),
             ReadWriteExecuteUser => PDPTFlags::RW | PDPTFlags::US,
             ReadWriteExecuteKernel => PDPTFlags::RW,
+            // Shadow stacks are read-only and dirty
+            ShadowStackUser => PDPTFlags::XD | PDPTFlags::US | PDPTFlags::D,
+            ShadowStackKernel => PDPTFlags::XD | PDPTFlags::D,
         }
     }
 
@@ -230,12 +306,16 @@ impl MapAction {
             ReadUser => PDFlags::XD | PDFlags::US,
             ReadKernel => PDFlags::XD,
             ReadWriteUser => PDFlags::RW | PDFlags::XD | PDFlags::US,
-            ReadWriteUserNoCache => PDFlags::RW | PDFlags::XD | PDFlags::US,
+            ReadWriteUserNoCache => PDFlags::RW | PDFlags::XD | PDFlags::US | PDFlags::PCD,
             ReadWriteKernel => PDFlags::RW | PDFlags::XD,
+            ReadWriteKernelWriteCombining => PDFlags::RW | PDFlags::XD | PDFlags::PWT,
             ReadExecuteUser => PDFlags::US,
             ReadExecuteKernel => PDFlags::empty(),
             ReadWriteExecuteUser => PDFlags::RW | PDFlags::US,
             ReadWriteExecuteKernel => PDFlags::RW,
+            // Shadow stacks are read-only and dirty
+            ShadowStackUser => PDFlags::XD | PDFlags::US | PDFlags::D,
+            ShadowStackKernel => PDFlags::XD | PDFlags::D,
         }
     }
 
@@ -247,12 +327,16 @@ impl MapAction {
             ReadUser => PTFlags::XD | PTFlags::US,
             ReadKernel => PTFlags::XD,
             ReadWriteUser => PTFlags::RW | PTFlags::XD | PTFlags::US,
-            ReadWriteUserNoCache => PTFlags::RW | PTFlags::XD | PTFlags::US,
+            ReadWriteUserNoCache => PTFlags::RW | PTFlags::XD | PTFlags::US | PTFlags::PCD,
             ReadWriteKernel => PTFlags::RW | PTFlags::XD,
+            ReadWriteKernelWriteCombining => PTFlags::RW | PTFlags::XD | PTFlags::PWT,
             ReadExecuteUser => PTFlags::US,
             ReadExecuteKernel => PTFlags::empty(),
             ReadWriteExecuteUser => PTFlags::RW | PTFlags::US,
             ReadWriteExecuteKernel => PTFlags::RW,
+            // Shadow stacks are read-only and dirty
+            ShadowStackUser => PTFlags::XD | PTFlags::US | PTFlags::D,
+            ShadowStackKernel => PTFlags::XD | PTFlags::D,
         }
     }
 }
@@ -260,14 +344,19 @@ impl MapAction {
 impl From<PTFlags> for MapAction {
     fn from(f: PTFlags) -> MapAction {
         use MapAction::*;
-        let irrelevant_bits: PTFlags =
-            PTFlags::PWT | PTFlags::A | PTFlags::D | PTFlags::G | PTFlags::PWT;
+        let irrelevant_bits: PTFlags = PTFlags::A | PTFlags::D | PTFlags::G;
 
+        // Nothing but a shadow stack is read-only and dirty
+        let shadow_stack = f.contains(PTFlags::D) && !f.contains(PTFlags::RW);
         let mut cleaned = f;
         cleaned.remove(irrelevant_bits);
 
         // Ugly if else (due to https://github.com/bitflags/bitflags/issues/201)
-        if cleaned == PTFlags::P | PTFlags::US | PTFlags::XD {
+        if shadow_stack && cleaned == PTFlags::P | PTFlags::US | PTFlags::XD {
+            ShadowStackUser
+        } else if shadow_stack && cleaned == PTFlags::P | PTFlags::XD {
+            ShadowStackKernel
+        } else if cleaned == PTFlags::P | PTFlags::US | PTFlags::XD {
             MapAction::ReadUser
         } else if cleaned == PTFlags::XD | PTFlags::P {
             MapAction::ReadKernel
@@ -277,6 +366,8 @@ impl From<PTFlags> for MapAction {
             ReadWriteUser
         } else if cleaned == PTFlags::RW | PTFlags::XD | PTFlags::P {
             ReadWriteKernel
+        } else if cleaned == PTFlags::RW | PTFlags::XD | PTFlags::P | PTFlags::PWT {
+            ReadWriteKernelWriteCombining
         } else if cleaned == PTFlags::US | PTFlags::P {
             ReadExecuteUser
         } else if cleaned == PTFlags::RW | PTFlags::US | PTFlags::P {
@@ -295,14 +386,19 @@ impl From<PDFlags> for MapAction {
     fn from(f: PDFlags) -> MapAction {
         use MapAction::*;
 
-        let irrelevant_bits =
-            PDFlags::PWT | PDFlags::A | PDFlags::D | PDFlags::PS | PDFlags::G | PDFlags::PAT;
+        let irrelevant_bits = PDFlags::A | PDFlags::D | PDFlags::PS | PDFlags::G | PDFlags::PAT;
 
+        // Nothing but a shadow stack is read-only and dirty
+        let shadow_stack = f.contains(PDFlags::D) && !f.contains(PDFlags::RW);
         let mut cleaned = f;
         cleaned.remove(irrelevant_bits);
 
         // Ugly if else (due to https://github.com/bitflags/bitflags/issues/201)
-        if cleaned == PDFlags::P | PDFlags::US | PDFlags::XD {
+        if shadow_stack && cleaned == PDFlags::P | PDFlags::US | PDFlags::XD {
+            ShadowStackUser
+        } else if shadow_stack && cleaned == PDFlags::P | PDFlags::XD {
+            ShadowStackKernel
+        } else if cleaned == PDFlags::P | PDFlags::US | PDFlags::XD {
             MapAction::ReadUser
         } else if cleaned == PDFlags::XD | PDFlags::P {
             MapAction::ReadKernel
@@ -312,6 +408,8 @@ impl From<PDFlags> for MapAction {
             ReadWriteUser
         } else if cleaned == PDFlags::RW | PDFlags::XD | PDFlags::P {
             ReadWriteKernel
+        } else if cleaned == PDFlags::RW | PDFlags::XD | PDFlags::P | PDFlags::PWT {
+            ReadWriteKernelWriteCombining
         } else if cleaned == PDFlags::US | PDFlags::P {
             ReadExecuteUser
         } else if cleaned == PDFlags::RW | PDFlags::US | PDFlags::P {
@@ -330,18 +428,20 @@ impl From<PDPTFlags> for MapAction {
     fn from(f: PDPTFlags) -> MapAction {
         use MapAction::*;
 
-        let irrelevant_bits: PDPTFlags = PDPTFlags::PWT
-            | PDPTFlags::A
-            | PDPTFlags::D
-            | PDPTFlags::PS
-            | PDPTFlags::G
-            | PDPTFlags::PAT;
+        let irrelevant_bits: PDPTFlags =
+            PDPTFlags::A | PDPTFlags::D | PDPTFlags::PS | PDPTFlags::G | PDPTFlags::PAT;
 
+        // Nothing but a shadow stack is read-only and dirty
+        let shadow_stack = f.contains(PDPTFlags::D) && !f.contains(PDPTFlags::RW);
         let mut cleaned = f;
         cleaned.remove(irrelevant_bits);
 
         // Ugly if else (due to https://github.com/bitflags/bitflags/issues/201)
-        if cleaned == PDPTFlags::P | PDPTFlags::US | PDPTFlags::XD {
+        if shadow_stack && cleaned == PDPTFlags::P | PDPTFlags::US | PDPTFlags::XD {
+            ShadowStackUser
+        } else if shadow_stack && cleaned == PDPTFlags::P | PDPTFlags::XD {
+            ShadowStackKernel
+        } else if cleaned == PDPTFlags::P | PDPTFlags::US | PDPTFlags::XD {
             MapAction::ReadUser
         } else if cleaned == PDPTFlags::XD | PDPTFlags::P {
             MapAction::ReadKernel
@@ -353,6 +453,8 @@ impl From<PDPTFlags> for MapAction {
             ReadWriteUser
         } else if cleaned == PDPTFlags::RW | PDPTFlags::XD | PDPTFlags::P {
             ReadWriteKernel
+        } else if cleaned == PDPTFlags::RW | PDPTFlags::XD | PDPTFlags::P | PDPTFlags::PWT {
+            ReadWriteKernelWriteCombining
         } else if cleaned == PDPTFlags::US | PDPTFlags::P {
             ReadExecuteUser
         } else if cleaned == PDPTFlags::RW | PDPTFlags::US | PDPTFlags::P {
@@ -377,10 +479,13 @@ impl fmt::Display for MapAction {
             ReadWriteUser => write!(f, "uRW-"),
             ReadWriteUserNoCache => write!(f, "uRW-IO"),
             ReadWriteKernel => write!(f, "kRW-"),
+            ReadWriteKernelWriteCombining => write!(f, "kRW-WC"),
             ReadExecuteUser => write!(f, "uR-X"),
             ReadExecuteKernel => write!(f, "kR-X"),
             ReadWriteExecuteUser => write!(f, "uRWX"),
             ReadWriteExecuteKernel => write!(f, "kRWX"),
+            ShadowStackUser => write!(f, "uSS-"),
+            ShadowStackKernel => write!(f, "kSS-"),
         }
     }
 }
@@ -1,11 +1,11 @@
 //! Implementation of a model vspace (for testing/model checking)
 use core::iter::Iterator;
 
-use x86::current::paging::PTFlags;
-
 use super::vspace::*;
 use crate::error::KError;
-use crate::memory::{Frame, PAddr, VAddr, BASE_PAGE_SIZE};
+#[cfg(test)]
+use crate::memory::BASE_PAGE_SIZE;
+use crate::memory::{Frame, PAddr, VAddr};
 
 /// A simple model address space
 ///
@@ -162,6 +162,17 @@ impl AddressSpace for ModelAddressSpace {
         Err(KError::NotMapped)
     }
 
+    fn extent(&self, vaddr: VAddr) -> Result<(VAddr, usize), KError> {
+        for (cur_vaddr, _cur_paddr, length, _rights) in self.oplog.iter().rev() {
+            let cur_range = cur_vaddr.as_usize()..cur_vaddr.as_usize() + *length;
+            if cur_range.contains(&vaddr.as_usize()) {
+                return Ok((*cur_vaddr, *length));
+            }
+        }
+
+        Err(KError::NotMapped)
+    }
+
     fn unmap(&mut self, base: VAddr) -> Result<TlbFlushHandle, KError> {
         if !base.is_base_page_aligned() {
             return Err(KError::InvalidBase);
@@ -301,6 +312,8 @@ fn model_bug_already_mapped3() {
 /// map_frame should allow increase of mapping
 #[test]
 fn from_ptflags() {
+    use x86::current::paging::PTFlags;
+
     let ru = PTFlags::P | PTFlags::US | PTFlags::XD;
     let ma: MapAction = ru.into();
     assert_eq!(ma, MapAction::ReadUser);
@@ -10,14 +10,14 @@
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::cell::UnsafeCell;
-use core::sync::atomic::AtomicUsize;
-use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 
 use fallible_collections::vec::FallibleVec;
 use fallible_collections::vec::TryCollect;
 
 use crate::error::KError;
 use crate::prelude::*;
+use crate::sync::atomic::AtomicUsize;
+use crate::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 
 struct Node<T> {
     sequence: AtomicUsize,
@@ -157,3 +157,64 @@ impl<T: Send> Clone for Queue<T> {
         }
     }
 }
+
+#[cfg(all(test, feature = "loom"))]
+mod test {
+    use super::*;
+    use crate::sync::spin_loop;
+    use loom::thread;
+
+    /// What one thread pushes comes out once and in order, also if the
+    /// queue is full in between.
+    #[test]
+    fn loom_mpmc_push_pop() {
+        crate::sync::model(|| {
+            let q = Queue::with_capacity(2).unwrap();
+            let producer = {
+                let q = q.clone();
+                thread::spawn(move || {
+                    for i in 0..3 {
+                        let mut value = i;
+                        while let Err(v) = q.push(value) {
+                            value = v;
+                            spin_loop();
+                        }
+                    }
+                })
+            };
+
+            let mut popped = Vec::new();
+            while popped.len() < 3 {
+                match q.pop() {
+                    Some(v) => popped.push(v),
+                    None => spin_loop(),
+                }
+            }
+            producer.join().unwrap();
+            assert_eq!(popped, vec![0, 1, 2]);
+            assert_eq!(q.pop(), None);
+        });
+    }
+
+    /// Two threads popping at the same time never get the same value.
+    #[test]
+    fn loom_mpmc_concurrent_pop() {
+        crate::sync::model(|| {
+            let q = Queue::with_capacity(2).unwrap();
+            q.push(1).unwrap();
+            q.push(2).unwrap();
+
+            let other = {
+                let q = q.clone();
+                thread::spawn(move || q.pop())
+            };
+            let mine = q.pop();
+            let theirs = other.join().unwrap();
+
+            let mut values = vec![mine.unwrap(), theirs.unwrap()];
+            values.sort_unstable();
+            assert_eq!(values, vec![1, 2]);
+            assert_eq!(q.pop(), None);
+        });
+    }
+}
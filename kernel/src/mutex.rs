@@ -0,0 +1,676 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The spin-lock we use for the kernel's shared locks (memory, hotplug).
+//!
+//! It is a queued (MCS) lock: a core that has to wait appends a queue node
+//! to the lock and spins on its own node until the core before it is done,
+//! so the waiters don't all pull the cache line of the lock back and forth.
+//! Only the first core in the queue spins on the lock itself. Queue nodes
+//! are only needed while waiting, every core has a few in its KCB
+//! ([`QueueNodes`], interrupts can take locks while the core waits for
+//! another one). Without a KCB (early boot) or with all of its nodes in
+//! use, a core lines up with a ticket instead.
+//!
+//! With the `lock-debug` feature every core also keeps track of the locks
+//! it holds (where it took them and when) and the lock it is spinning on,
+//! which lets us:
+//!
+//! - panic when a core takes a lock it already holds (instead of spinning
+//!   forever),
+//! - find cores that wait for each other in a cycle while we spin,
+//! - print who holds and who waits for which lock when the watchdog found a
+//!   stuck core (`report`).
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::hint::spin_loop;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+#[cfg(feature = "lock-debug")]
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+/// How many queue nodes a core has (it can wait for this many locks at
+/// the same time, e.g., in a system call, an interrupt and an NMI).
+const QUEUE_NODES: usize = 4;
+
+/// A named spin-lock.
+pub struct Mutex<T> {
+    name: &'static str,
+    raw: RawLock,
+    data: UnsafeCell<T>,
+}
+
+// Safe: The lock hands out access to `data` to one core at a time
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+/// Releases the lock when dropped.
+pub struct MutexGuard<'a, T> {
+    lock: &'a Mutex<T>,
+    /// The lock if we keep track of it for this core (`lock-debug`).
+    #[cfg(feature = "lock-debug")]
+    tracked: Option<usize>,
+    /// Send and Sync like `&mut T`.
+    _data: PhantomData<&'a mut T>,
+}
+
+impl<T> Mutex<T> {
+    /// Creates a new lock, `name` is what we call it in reports.
+    pub const fn new(name: &'static str, value: T) -> Mutex<T> {
+        Mutex {
+            name,
+            raw: RawLock::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Spins until we have the lock.
+    #[cfg(not(feature = "lock-debug"))]
+    pub fn lock(&self) -> MutexGuard<T> {
+        self.raw.lock(&mut || {});
+        MutexGuard {
+            lock: self,
+            _data: PhantomData,
+        }
+    }
+
+    /// Spins until we have the lock.
+    #[cfg(feature = "lock-debug")]
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<T> {
+        let tracked = debug::lock(&self.raw, self.name, Location::caller());
+        MutexGuard {
+            lock: self,
+            tracked,
+            _data: PhantomData,
+        }
+    }
+
+    /// Takes the lock if nobody holds it (or waits for it).
+    #[cfg_attr(feature = "lock-debug", track_caller)]
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        if !self.raw.try_lock() {
+            return None;
+        }
+        Some(MutexGuard {
+            lock: self,
+            #[cfg(feature = "lock-debug")]
+            tracked: debug::acquired(&self.raw, self.name, Location::caller(), 0),
+            _data: PhantomData,
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Mutex<T> {
+        Mutex::new("unnamed", T::default())
+    }
+}
+
+impl<T> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mutex")
+            .field("name", &self.name)
+            .field("locked", &self.is_locked())
+            .finish()
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safe: We hold the lock
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safe: We hold the lock
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // We forget about it before we unlock
+        #[cfg(feature = "lock-debug")]
+        if let Some(addr) = self.tracked {
+            debug::released(addr);
+        }
+        self.lock.raw.unlock();
+    }
+}
+
+/// A waiter in the queue of a lock.
+#[repr(align(64))]
+struct QueueNode {
+    /// The waiter behind us (null until it linked itself).
+    next: AtomicPtr<QueueNode>,
+    /// We're first in the queue (set by the waiter before us).
+    head: AtomicBool,
+}
+
+impl QueueNode {
+    const fn new() -> QueueNode {
+        QueueNode {
+            next: AtomicPtr::new(ptr::null_mut()),
+            head: AtomicBool::new(false),
+        }
+    }
+}
+
+/// The queue nodes of a core (it has them in its KCB).
+pub struct QueueNodes {
+    nodes: [QueueNode; QUEUE_NODES],
+    /// Bitmap of the nodes the core waits with.
+    used: AtomicUsize,
+}
+
+impl QueueNodes {
+    pub const fn new() -> QueueNodes {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const UNUSED: QueueNode = QueueNode::new();
+        QueueNodes {
+            nodes: [UNUSED; QUEUE_NODES],
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Takes a free node (an interrupt may take one in between, so we
+    /// don't just count).
+    fn take(&self) -> Option<usize> {
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            let free = (!used).trailing_zeros() as usize;
+            if free >= QUEUE_NODES {
+                return None;
+            }
+            match self.used.compare_exchange_weak(
+                used,
+                used | (1 << free),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(free),
+                Err(now) => used = now,
+            }
+        }
+    }
+
+    fn give_back(&self, idx: usize) {
+        self.used.fetch_and(!(1 << idx), Ordering::Release);
+    }
+}
+
+impl Default for QueueNodes {
+    fn default() -> QueueNodes {
+        QueueNodes::new()
+    }
+}
+
+/// Calls `f` with the queue nodes of the current core (`None` if it has no
+/// KCB yet).
+#[cfg(target_os = "none")]
+fn with_queue_nodes<R>(f: impl FnOnce(Option<&QueueNodes>) -> R) -> R {
+    f(crate::kcb::try_get_kcb().map(|kcb| &kcb.lock_nodes))
+}
+
+/// Calls `f` with the queue nodes of the current thread (every thread is a
+/// core of its own when we're hosted).
+#[cfg(not(target_os = "none"))]
+fn with_queue_nodes<R>(f: impl FnOnce(Option<&QueueNodes>) -> R) -> R {
+    #[thread_local]
+    static NODES: QueueNodes = QueueNodes::new();
+    f(Some(&NODES))
+}
+
+/// The queued lock without the data.
+struct RawLock {
+    /// Somebody holds the lock.
+    locked: AtomicBool,
+    /// The last node in the queue (null if nobody queues).
+    tail: AtomicPtr<QueueNode>,
+    /// The next ticket for waiters without a queue node.
+    next_ticket: AtomicUsize,
+    /// The ticket that may take the lock next.
+    serving: AtomicUsize,
+}
+
+impl RawLock {
+    const fn new() -> RawLock {
+        RawLock {
+            locked: AtomicBool::new(false),
+            tail: AtomicPtr::new(ptr::null_mut()),
+            next_ticket: AtomicUsize::new(0),
+            serving: AtomicUsize::new(0),
+        }
+    }
+
+    fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Takes the lock if nobody holds it and nobody queues for it.
+    fn try_lock(&self) -> bool {
+        self.tail.load(Ordering::Relaxed).is_null()
+            && self
+                .locked
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    /// Spins until we have the lock, calls `spinning` every time we look
+    /// again.
+    #[inline]
+    fn lock(&self, spinning: &mut dyn FnMut()) {
+        if self.try_lock() {
+            return;
+        }
+
+        with_queue_nodes(|nodes| match nodes.and_then(|n| Some((n, n.take()?))) {
+            Some((nodes, idx)) => {
+                self.lock_queued(&nodes.nodes[idx], spinning);
+                nodes.give_back(idx);
+            }
+            None => self.lock_ticket(spinning),
+        })
+    }
+
+    fn lock_queued(&self, node: &QueueNode, spinning: &mut dyn FnMut()) {
+        node.next.store(ptr::null_mut(), Ordering::Relaxed);
+        node.head.store(false, Ordering::Relaxed);
+        let me = node as *const QueueNode as *mut QueueNode;
+
+        let prev = self.tail.swap(me, Ordering::AcqRel);
+        if !prev.is_null() {
+            // Safe: A node stays in the queue until its waiter handed the
+            // head on to us
+            unsafe { (*prev).next.store(me, Ordering::Release) };
+            while !node.head.load(Ordering::Acquire) {
+                spinning();
+                spin_loop();
+            }
+        }
+
+        self.acquire(spinning);
+
+        // Leave the queue, the one behind us is first now
+        if self
+            .tail
+            .compare_exchange(me, ptr::null_mut(), Ordering::AcqRel, Ordering::Relaxed)
+            .is_err()
+        {
+            let next = loop {
+                // It swapped the tail but didn't link itself yet
+                let next = node.next.load(Ordering::Acquire);
+                if !next.is_null() {
+                    break next;
+                }
+                spin_loop();
+            };
+            // Safe: `next` waits for this
+            unsafe { (*next).head.store(true, Ordering::Release) };
+        }
+    }
+
+    fn lock_ticket(&self, spinning: &mut dyn FnMut()) {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.serving.load(Ordering::Acquire) != ticket {
+            spinning();
+            spin_loop();
+        }
+        self.acquire(spinning);
+        self.serving.fetch_add(1, Ordering::Release);
+    }
+
+    /// Takes the lock once the holder lets go (we're first in our queue).
+    fn acquire(&self, spinning: &mut dyn FnMut()) {
+        loop {
+            if !self.locked.load(Ordering::Relaxed)
+                && self
+                    .locked
+                    .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return;
+            }
+            spinning();
+            spin_loop();
+        }
+    }
+
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+/// Prints the locks every core holds and waits for.
+#[cfg(feature = "lock-debug")]
+pub fn report() {
+    debug::report()
+}
+
+/// The name of the lock `core` spins on (if it does).
+#[cfg(feature = "lock-debug")]
+pub fn waits_for(core: usize) -> Option<&'static str> {
+    debug::waits_for(core)
+}
+
+#[cfg(feature = "lock-debug")]
+mod debug {
+    use core::panic::Location;
+
+    use klogger::sprintln;
+    use x86::time::rdtsc;
+
+    use crate::arch::MAX_CORES;
+    use crate::kcb::ArchSpecificKcb;
+
+    use super::RawLock;
+
+    /// How many locks a core can hold at the same time (we stop keeping
+    /// track of the ones above).
+    const MAX_HELD: usize = 8;
+
+    /// How many times we spin on a lock before we look for a cycle.
+    const CHECK_INTERVAL: u64 = 1 << 24;
+
+    /// A lock a core holds or waits for.
+    #[derive(Clone, Copy)]
+    struct Acquisition {
+        addr: usize,
+        name: &'static str,
+        location: &'static Location<'static>,
+        /// TSC when we took it (or started waiting).
+        since: u64,
+        /// How many cycles we spun before we got it.
+        waited: u64,
+    }
+
+    /// What a core holds and waits for.
+    struct CoreLocks {
+        held: [Option<Acquisition>; MAX_HELD],
+        waiting: Option<Acquisition>,
+    }
+
+    impl CoreLocks {
+        const fn new() -> CoreLocks {
+            CoreLocks {
+                held: [None; MAX_HELD],
+                waiting: None,
+            }
+        }
+
+        fn holds(&self, addr: usize) -> Option<Acquisition> {
+            self.held.iter().flatten().find(|a| a.addr == addr).copied()
+        }
+    }
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NO_LOCKS: spin::Mutex<CoreLocks> = spin::Mutex::new(CoreLocks::new());
+    /// Every core only changes its own entry, others read it for reports and
+    /// cycles. Everyone only ever tries to lock an entry: if an interrupt
+    /// takes a lock while we update our own entry we don't keep track of it.
+    static CORES: [spin::Mutex<CoreLocks>; MAX_CORES] = [NO_LOCKS; MAX_CORES];
+
+    fn current_core() -> Option<usize> {
+        crate::kcb::try_get_kcb().map(|kcb| kcb.arch.hwthread_id())
+    }
+
+    fn with_core<R>(core: usize, f: impl FnOnce(&mut CoreLocks) -> R) -> Option<R> {
+        CORES.get(core)?.try_lock().map(|mut locks| f(&mut locks))
+    }
+
+    fn addr(lock: &RawLock) -> usize {
+        lock as *const RawLock as usize
+    }
+
+    pub(super) fn lock(
+        lock: &RawLock,
+        name: &'static str,
+        location: &'static Location<'static>,
+    ) -> Option<usize> {
+        let core = match current_core() {
+            Some(core) => core,
+            // Too early to keep track
+            None => {
+                lock.lock(&mut || {});
+                return None;
+            }
+        };
+        let addr = addr(lock);
+
+        if let Some(Some(first)) = with_core(core, |locks| locks.holds(addr)) {
+            panic!(
+                "Core {} takes lock {} at {} but already holds it (took it at {})",
+                core, name, location, first.location
+            );
+        }
+
+        if lock.try_lock() {
+            return acquired(lock, name, location, 0);
+        }
+
+        let start = unsafe { rdtsc() };
+        with_core(core, |locks| {
+            locks.waiting = Some(Acquisition {
+                addr,
+                name,
+                location,
+                since: start,
+                waited: 0,
+            })
+        });
+        let mut spins = 0u64;
+        let mut suspect = false;
+        lock.lock(&mut || {
+            spins += 1;
+            if spins % CHECK_INTERVAL == 0 {
+                // We read the other cores one after another, only trust a
+                // cycle we see twice
+                let cycle = in_cycle(core, addr);
+                if cycle && suspect {
+                    sprintln!("[lock-debug] deadlock on core {}:", core);
+                    report();
+                    panic!("Core {} deadlocked on lock {} at {}", core, name, location);
+                }
+                suspect = cycle;
+            }
+        });
+        with_core(core, |locks| locks.waiting = None);
+
+        let waited = unsafe { rdtsc() } - start;
+        acquired(lock, name, location, waited)
+    }
+
+    /// Remembers that the current core holds `lock` (returns its address if
+    /// we do).
+    pub(super) fn acquired(
+        lock: &RawLock,
+        name: &'static str,
+        location: &'static Location<'static>,
+        waited: u64,
+    ) -> Option<usize> {
+        let core = current_core()?;
+        let acquisition = Acquisition {
+            addr: addr(lock),
+            name,
+            location,
+            since: unsafe { rdtsc() },
+            waited,
+        };
+        with_core(core, |locks| {
+            let slot = locks.held.iter_mut().find(|slot| slot.is_none())?;
+            *slot = Some(acquisition);
+            Some(acquisition.addr)
+        })
+        .flatten()
+    }
+
+    /// Forgets that the current core holds the lock at `addr`.
+    pub(super) fn released(addr: usize) {
+        if let Some(core) = current_core() {
+            with_core(core, |locks| {
+                for slot in locks.held.iter_mut() {
+                    if slot.map_or(false, |a| a.addr == addr) {
+                        *slot = None;
+                    }
+                }
+            });
+        }
+    }
+
+    /// Which core holds the lock at `addr`?
+    fn owner(addr: usize) -> Option<usize> {
+        (0..MAX_CORES)
+            .find(|core| with_core(*core, |locks| locks.holds(addr).is_some()) == Some(true))
+    }
+
+    /// Does following the owner of the lock at `addr` (and the lock that
+    /// core waits for and so on) lead back to `core`?
+    fn in_cycle(core: usize, mut addr: usize) -> bool {
+        for _i in 0..MAX_CORES {
+            let holder = match owner(addr) {
+                Some(holder) => holder,
+                None => return false,
+            };
+            if holder == core {
+                return true;
+            }
+            addr = match with_core(holder, |locks| locks.waiting.map(|a| a.addr)).flatten() {
+                Some(addr) => addr,
+                None => return false,
+            };
+        }
+        false
+    }
+
+    pub(super) fn waits_for(core: usize) -> Option<&'static str> {
+        with_core(core, |locks| locks.waiting.map(|a| a.name)).flatten()
+    }
+
+    pub(super) fn report() {
+        let now = unsafe { rdtsc() };
+        for (core, locks) in CORES.iter().enumerate() {
+            let locks = match locks.try_lock() {
+                Some(locks) => locks,
+                None => {
+                    sprintln!("[lock-debug] core {}: busy", core);
+                    continue;
+                }
+            };
+            for a in locks.held.iter().flatten() {
+                sprintln!(
+                    "[lock-debug] core {} holds {} (taken at {}, held for {} cycles, waited {} cycles)",
+                    core,
+                    a.name,
+                    a.location,
+                    now.saturating_sub(a.since),
+                    a.waited
+                );
+            }
+            if let Some(a) = locks.waiting {
+                sprintln!(
+                    "[lock-debug] core {} waits for {} at {} for {} cycles",
+                    core,
+                    a.name,
+                    a.location,
+                    now.saturating_sub(a.since)
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lock_unlock() {
+        let lock = Mutex::new("test", 1);
+        {
+            let mut guard = lock.lock();
+            *guard += 1;
+            assert!(lock.is_locked());
+            assert!(lock.try_lock().is_none());
+        }
+        assert!(!lock.is_locked());
+        assert_eq!(*lock.try_lock().unwrap(), 2);
+        assert_eq!(lock.into_inner(), 2);
+    }
+
+    /// Threads that fight for the lock (queued, and with tickets as if
+    /// they had no queue nodes) never hold it at the same time.
+    #[test]
+    fn contended() {
+        use std::sync::Arc;
+        use std::thread;
+
+        const ROUNDS: usize = 10_000;
+        let lock = Arc::new(Mutex::new("test", (0usize, false)));
+        let threads: Vec<_> = (0..6)
+            .map(|i| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _round in 0..ROUNDS {
+                        if i % 2 == 0 {
+                            lock.raw.lock_ticket(&mut || {});
+                        } else {
+                            lock.raw.lock(&mut || {});
+                        }
+                        // Safe: We hold the lock
+                        let (count, inside) = unsafe { &mut *lock.data.get() };
+                        assert!(!*inside, "Two threads hold the lock");
+                        *inside = true;
+                        *count += 1;
+                        *inside = false;
+                        lock.raw.unlock();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(lock.lock().0, 6 * ROUNDS);
+        assert!(lock.raw.tail.load(Ordering::Relaxed).is_null());
+    }
+
+    #[test]
+    fn queue_nodes() {
+        let nodes = QueueNodes::new();
+        let taken: Vec<_> = (0..QUEUE_NODES).map(|_| nodes.take()).collect();
+        assert_eq!(taken, (0..QUEUE_NODES).map(Some).collect::<Vec<_>>());
+        assert_eq!(nodes.take(), None);
+        nodes.give_back(1);
+        assert_eq!(nodes.take(), Some(1));
+    }
+
+    #[cfg(feature = "lock-debug")]
+    #[test]
+    #[should_panic(expected = "already holds it")]
+    fn double_acquire() {
+        let lock = Mutex::new("test", ());
+        let _first = lock.lock();
+        let _second = lock.lock();
+    }
+}
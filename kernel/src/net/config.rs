@@ -0,0 +1,139 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Parsing of the `net=` kernel command-line argument.
+//!
+//! Supported formats are:
+//! - `net=dhcp`: Acquire an address from a DHCP server.
+//! - `net=static:172.31.0.10/24`: Use a fixed address and prefix length.
+//! - `net=static:172.31.0.10/24,172.31.0.20`: Same as above but also install
+//!   a default route through the given gateway.
+
+use crate::error::KError;
+
+/// How the network interface should obtain its IPv4 address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpConfig {
+    /// Run a DHCP client until we get a lease.
+    Dhcp,
+    /// Use a statically assigned address.
+    Static {
+        address: [u8; 4],
+        prefix_len: u8,
+        gateway: Option<[u8; 4]>,
+    },
+}
+
+impl IpConfig {
+    /// Parses the value of the `net=` command-line argument.
+    pub fn parse(config: &str) -> Result<IpConfig, KError> {
+        if config == "dhcp" {
+            return Ok(IpConfig::Dhcp);
+        }
+
+        let spec = config
+            .strip_prefix("static:")
+            .ok_or(KError::InvalidNetConfig)?;
+        let (cidr, gateway) = match spec.split_once(',') {
+            Some((cidr, gw)) => (cidr, Some(parse_ipv4(gw)?)),
+            None => (spec, None),
+        };
+        let (address, prefix_len) = cidr.split_once('/').ok_or(KError::InvalidNetConfig)?;
+        let prefix_len = prefix_len
+            .parse::<u8>()
+            .map_err(|_e| KError::InvalidNetConfig)?;
+        if prefix_len > 32 {
+            return Err(KError::InvalidNetConfig);
+        }
+
+        Ok(IpConfig::Static {
+            address: parse_ipv4(address)?,
+            prefix_len,
+            gateway,
+        })
+    }
+}
+
+/// Parses a dotted-quad IPv4 address (e.g., `172.31.0.10`).
+fn parse_ipv4(addr: &str) -> Result<[u8; 4], KError> {
+    let mut octets = [0u8; 4];
+    let mut parts = addr.split('.');
+    for octet in octets.iter_mut() {
+        *octet = parts
+            .next()
+            .ok_or(KError::InvalidNetConfig)?
+            .parse::<u8>()
+            .map_err(|_e| KError::InvalidNetConfig)?;
+    }
+
+    if parts.next().is_some() {
+        Err(KError::InvalidNetConfig)
+    } else {
+        Ok(octets)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::IpConfig;
+    use crate::error::KError;
+
+    #[test]
+    fn parse_dhcp() {
+        assert_eq!(IpConfig::parse("dhcp"), Ok(IpConfig::Dhcp));
+    }
+
+    #[test]
+    fn parse_static() {
+        assert_eq!(
+            IpConfig::parse("static:172.31.0.10/24"),
+            Ok(IpConfig::Static {
+                address: [172, 31, 0, 10],
+                prefix_len: 24,
+                gateway: None
+            })
+        );
+    }
+
+    #[test]
+    fn parse_static_gateway() {
+        assert_eq!(
+            IpConfig::parse("static:172.31.0.10/24,172.31.0.20"),
+            Ok(IpConfig::Static {
+                address: [172, 31, 0, 10],
+                prefix_len: 24,
+                gateway: Some([172, 31, 0, 20])
+            })
+        );
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(IpConfig::parse(""), Err(KError::InvalidNetConfig));
+        assert_eq!(IpConfig::parse("static"), Err(KError::InvalidNetConfig));
+        assert_eq!(
+            IpConfig::parse("static:172.31.0.10"),
+            Err(KError::InvalidNetConfig)
+        );
+        assert_eq!(
+            IpConfig::parse("static:172.31.0.10/33"),
+            Err(KError::InvalidNetConfig)
+        );
+        assert_eq!(
+            IpConfig::parse("static:172.31.0/24"),
+            Err(KError::InvalidNetConfig)
+        );
+        assert_eq!(
+            IpConfig::parse("static:172.31.0.10.1/24"),
+            Err(KError::InvalidNetConfig)
+        );
+        assert_eq!(
+            IpConfig::parse("static:172.31.0.256/24"),
+            Err(KError::InvalidNetConfig)
+        );
+        assert_eq!(
+            IpConfig::parse("static:172.31.0.10/24,"),
+            Err(KError::InvalidNetConfig)
+        );
+    }
+}
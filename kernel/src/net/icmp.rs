@@ -0,0 +1,89 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! ICMP echo (ping).
+//!
+//! smoltcp already answers echo requests for our address whenever the
+//! stack gets polled, this adds the client side to check connectivity
+//! without a user-space network stack.
+
+use alloc::vec;
+use core::time::Duration;
+
+use smoltcp::phy::{ChecksumCapabilities, Device};
+use smoltcp::socket::{IcmpEndpoint, IcmpPacketMetadata, IcmpSocket, IcmpSocketBuffer};
+use smoltcp::wire::{Icmpv4Packet, Icmpv4Repr, IpAddress, Ipv4Address};
+
+use crate::error::KError;
+
+use super::stack::NET_STACK;
+
+/// Payload we send with an echo request.
+const PING_DATA: &[u8] = b"nrk-ping";
+
+/// Sends an ICMP echo request to `addr` and waits (at most `timeout`) for
+/// the reply.
+///
+/// Returns the round-trip time. This spins in the kernel so it's only meant
+/// for diagnostics.
+pub fn ping(addr: Ipv4Address, timeout: Duration) -> Result<Duration, KError> {
+    // Random so concurrent pings don't see each others replies
+    let ident = crate::entropy::rand() as u16;
+    let buffer = || IcmpSocketBuffer::new(vec![IcmpPacketMetadata::EMPTY; 1], vec![0; 256]);
+
+    let mut stack = NET_STACK.lock();
+    let stack = stack.as_mut().ok_or(KError::NetStackUnavailable)?;
+    let checksum = stack.iface.device().capabilities().checksum;
+
+    let mut socket = IcmpSocket::new(buffer(), buffer());
+    socket
+        .bind(IcmpEndpoint::Ident(ident))
+        .map_err(|_e| KError::SocketError)?;
+    let handle = stack.sockets.add(socket);
+
+    let request = Icmpv4Repr::EchoRequest {
+        ident,
+        seq_no: 0,
+        data: PING_DATA,
+    };
+    let start = rawtime::Instant::now();
+    let sent = match stack
+        .sockets
+        .get::<IcmpSocket>(handle)
+        .send(request.buffer_len(), IpAddress::Ipv4(addr))
+    {
+        Ok(buf) => {
+            request.emit(&mut Icmpv4Packet::new_unchecked(buf), &checksum);
+            Ok(())
+        }
+        Err(_e) => Err(KError::SocketError),
+    };
+
+    let result = sent.and_then(|()| loop {
+        // Resolves the neighbor (if necessary) and sends the request
+        stack.poll();
+
+        if let Ok((payload, from)) = stack.sockets.get::<IcmpSocket>(handle).recv() {
+            if from == IpAddress::Ipv4(addr) && is_reply(payload, ident, &checksum) {
+                break Ok(start.elapsed());
+            }
+        }
+        if start.elapsed() > timeout {
+            break Err(KError::PingTimeout);
+        }
+        core::hint::spin_loop();
+    });
+
+    stack.sockets.remove(handle);
+    result
+}
+
+/// Is `payload` the reply to our echo request `ident`?
+fn is_reply(payload: &[u8], ident: u16, checksum: &ChecksumCapabilities) -> bool {
+    Icmpv4Packet::new_checked(payload)
+        .and_then(|packet| Icmpv4Repr::parse(&packet, checksum))
+        .map_or(false, |repr| match repr {
+            Icmpv4Repr::EchoReply { ident: i, data, .. } => i == ident && data == PING_DATA,
+            _ => false,
+        })
+}
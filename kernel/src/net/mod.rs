@@ -0,0 +1,31 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The in-kernel network stack.
+//!
+//! Runs smoltcp on top of the vmxnet3 driver. How the interface gets its
+//! IPv4 address is determined by the `net=` command-line argument (see
+//! [`IpConfig`]).
+//!
+//! `vsock` connections to the host don't need any of that, they go through
+//! a virtio-vsock device.
+
+mod config;
+
+#[cfg(all(feature = "smoltcp", target_os = "none"))]
+pub mod icmp;
+#[cfg(all(feature = "smoltcp", target_os = "none"))]
+mod neighbor;
+#[cfg(all(feature = "smoltcp", target_os = "none"))]
+pub mod socket;
+#[cfg(all(feature = "smoltcp", target_os = "none"))]
+mod stack;
+#[cfg(target_os = "none")]
+pub mod vsock;
+#[cfg(all(feature = "smoltcp", target_os = "none"))]
+pub mod xdp;
+
+pub use config::IpConfig;
+
+#[cfg(all(feature = "smoltcp", target_os = "none"))]
+pub use stack::{init, now, poll, NetStack, NET_STACK};
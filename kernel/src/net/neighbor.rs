@@ -0,0 +1,235 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! ARP neighbor cache.
+//!
+//! smoltcp resolves addresses on its own but only sends one ARP request per
+//! second and forgets entries after a minute, so a lost request or reply
+//! stalls traffic for a while. This cache sits between smoltcp and the NIC:
+//!
+//! - It learns addresses from every ARP packet we receive.
+//! - It watches the requests smoltcp sends: If we know the answer already,
+//!   a reply is handed to smoltcp right away. Otherwise the request is
+//!   retransmitted every `REQUEST_INTERVAL` until there is a reply (or we
+//!   tried `MAX_REQUESTS` times).
+//! - Entries expire after `ENTRY_LIFETIME`.
+//!
+//! Packets waiting for an address stay queued in the buffer of their socket,
+//! smoltcp sends them once it learned the address (from the reply).
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::time::Duration;
+
+use log::debug;
+use smoltcp::time::{self, Instant};
+use smoltcp::wire::{
+    ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol,
+    EthernetRepr, Ipv4Address,
+};
+
+/// How long we keep an entry after we last heard from a neighbor.
+const ENTRY_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Time between two requests for the same address.
+const REQUEST_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How many requests we send before we give up on an address.
+const MAX_REQUESTS: usize = 8;
+
+/// Upper bound of replies we keep around for smoltcp.
+const MAX_INJECTED: usize = 16;
+
+#[derive(Debug)]
+enum Neighbor {
+    /// We're waiting for a reply to `request` (an ARP request frame).
+    Incomplete {
+        request: Vec<u8>,
+        requests: usize,
+        last_request: Instant,
+    },
+    Reachable {
+        hardware_addr: EthernetAddress,
+        updated: Instant,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct NeighborCache {
+    entries: BTreeMap<Ipv4Address, Neighbor>,
+    /// ARP replies (that we answered from the cache) for smoltcp.
+    injected: VecDeque<Vec<u8>>,
+}
+
+/// Parses an ARP packet (if `frame` contains one).
+fn parse_arp(frame: &[u8]) -> Option<ArpRepr> {
+    let eth = EthernetFrame::new_checked(frame).ok()?;
+    if eth.ethertype() != EthernetProtocol::Arp {
+        return None;
+    }
+    let packet = ArpPacket::new_checked(eth.payload()).ok()?;
+    ArpRepr::parse(&packet).ok()
+}
+
+/// Builds an Ethernet frame containing `arp`.
+fn emit_arp(arp: &ArpRepr, dst_addr: EthernetAddress) -> Vec<u8> {
+    let src_addr = match arp {
+        ArpRepr::EthernetIpv4 {
+            source_hardware_addr,
+            ..
+        } => *source_hardware_addr,
+        _ => unreachable!("We only ever build Ethernet/IPv4 ARP packets"),
+    };
+
+    let mut frame = vec![0u8; EthernetFrame::<&[u8]>::buffer_len(arp.buffer_len())];
+    let mut eth = EthernetFrame::new_unchecked(&mut frame[..]);
+    EthernetRepr {
+        src_addr,
+        dst_addr,
+        ethertype: EthernetProtocol::Arp,
+    }
+    .emit(&mut eth);
+    arp.emit(&mut ArpPacket::new_unchecked(eth.payload_mut()));
+    frame
+}
+
+impl NeighborCache {
+    /// Looks at a frame we received (before smoltcp sees it).
+    pub fn process_rx(&mut self, frame: &[u8], timestamp: Instant) {
+        if let Some(ArpRepr::EthernetIpv4 {
+            source_hardware_addr,
+            source_protocol_addr,
+            ..
+        }) = parse_arp(frame)
+        {
+            if source_hardware_addr.is_unicast() && source_protocol_addr.is_unicast() {
+                self.entries.insert(
+                    source_protocol_addr,
+                    Neighbor::Reachable {
+                        hardware_addr: source_hardware_addr,
+                        updated: timestamp,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Looks at a frame smoltcp sent.
+    pub fn process_tx(&mut self, frame: &[u8], timestamp: Instant) {
+        let (source_hardware_addr, source_protocol_addr, target_protocol_addr) =
+            match parse_arp(frame) {
+                Some(ArpRepr::EthernetIpv4 {
+                    operation: ArpOperation::Request,
+                    source_hardware_addr,
+                    source_protocol_addr,
+                    target_protocol_addr,
+                    ..
+                }) => (
+                    source_hardware_addr,
+                    source_protocol_addr,
+                    target_protocol_addr,
+                ),
+                _ => return,
+            };
+
+        match self.entries.get(&target_protocol_addr) {
+            Some(Neighbor::Reachable { hardware_addr, .. }) => {
+                // smoltcp forgot about the neighbor but we still know it
+                if self.injected.len() < MAX_INJECTED {
+                    let reply = ArpRepr::EthernetIpv4 {
+                        operation: ArpOperation::Reply,
+                        source_hardware_addr: *hardware_addr,
+                        source_protocol_addr: target_protocol_addr,
+                        target_hardware_addr: source_hardware_addr,
+                        target_protocol_addr: source_protocol_addr,
+                    };
+                    self.injected
+                        .push_back(emit_arp(&reply, source_hardware_addr));
+                }
+            }
+            // We're already retransmitting the request
+            Some(Neighbor::Incomplete { .. }) => {}
+            None => {
+                self.entries.insert(
+                    target_protocol_addr,
+                    Neighbor::Incomplete {
+                        request: frame.to_vec(),
+                        requests: 1,
+                        last_request: timestamp,
+                    },
+                );
+            }
+        }
+    }
+
+    pub fn has_injected(&self) -> bool {
+        !self.injected.is_empty()
+    }
+
+    /// Returns the next ARP reply we answered from the cache.
+    pub fn next_injected(&mut self) -> Option<Vec<u8>> {
+        self.injected.pop_front()
+    }
+
+    /// Expires old entries and calls `send` for every request that needs to
+    /// be retransmitted.
+    pub fn poll(&mut self, timestamp: Instant, mut send: impl FnMut(&[u8])) {
+        self.entries.retain(|addr, neighbor| match neighbor {
+            Neighbor::Reachable { updated, .. } => {
+                timestamp < *updated + time::Duration::from(ENTRY_LIFETIME)
+            }
+            Neighbor::Incomplete {
+                request,
+                requests,
+                last_request,
+            } => {
+                if timestamp < *last_request + time::Duration::from(REQUEST_INTERVAL) {
+                    true
+                } else if *requests < MAX_REQUESTS {
+                    send(request);
+                    *requests += 1;
+                    *last_request = timestamp;
+                    true
+                } else {
+                    debug!("arp: no reply from {}, giving up", addr);
+                    false
+                }
+            }
+        });
+    }
+
+    /// Writes the cache contents in the format of `/proc/net/arp`.
+    pub fn render(&self, out: &mut String, timestamp: Instant) -> fmt::Result {
+        writeln!(
+            out,
+            "{:<16} {:<18} {:<11} Age",
+            "IP address", "HW address", "State"
+        )?;
+        for (addr, neighbor) in self.entries.iter() {
+            let addr = format!("{}", addr);
+            match neighbor {
+                Neighbor::Reachable {
+                    hardware_addr,
+                    updated,
+                } => writeln!(
+                    out,
+                    "{:<16} {:<18} {:<11} {}ms",
+                    addr,
+                    format!("{}", hardware_addr),
+                    "reachable",
+                    (timestamp - *updated).total_millis()
+                )?,
+                Neighbor::Incomplete { requests, .. } => writeln!(
+                    out,
+                    "{:<16} {:<18} {:<11} ({} requests)",
+                    addr, "-", "incomplete", requests
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
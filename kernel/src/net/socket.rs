@@ -0,0 +1,361 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Non-blocking UDP/TCP sockets for user-space processes.
+//!
+//! Every process has its own socket descriptor namespace, descriptors map to
+//! smoltcp sockets in the `SocketSet` of the kernel network stack.
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use kpi::net::{PollEvents, PollFd, SocketAddr, SocketType, POLL_EVENT_HANDLE, VSOCK_FD};
+use smoltcp::socket::{
+    SocketHandle, SocketSet, TcpSocket, TcpSocketBuffer, TcpState, UdpPacketMetadata, UdpSocket,
+    UdpSocketBuffer,
+};
+use smoltcp::wire::{IpAddress, IpEndpoint};
+
+use crate::arch::process::UserSlice;
+use crate::error::KError;
+use crate::process::Pid;
+
+use super::stack::NET_STACK;
+
+/// Size of the receive and send buffer of a socket (in bytes).
+const SOCKET_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How many datagrams we buffer (per direction) for a UDP socket.
+const UDP_PACKET_SLOTS: usize = 64;
+
+/// First port we hand out for outgoing TCP connections.
+const EPHEMERAL_PORT_START: u16 = 49152;
+/// Number of ports we hand out for outgoing TCP connections.
+const EPHEMERAL_PORTS: u64 = (u16::MAX - EPHEMERAL_PORT_START) as u64 + 1;
+
+/// A socket owned by a process.
+struct Socket {
+    ty: SocketType,
+    handle: SocketHandle,
+    /// Default peer for UDP sockets (set by `connect`).
+    peer: Option<IpEndpoint>,
+}
+
+/// Maps (pid, fd) pairs to smoltcp sockets.
+pub struct SocketTable {
+    sockets: BTreeMap<(Pid, u64), Socket>,
+    /// TCP sockets that were closed by the process but still need to
+    /// finish the connection tear-down.
+    closing: Vec<SocketHandle>,
+    next_fd: u64,
+    next_port: u16,
+}
+
+impl SocketTable {
+    pub fn new() -> SocketTable {
+        SocketTable {
+            sockets: BTreeMap::new(),
+            closing: Vec::new(),
+            next_fd: 1,
+            // Start at a random port so connections are harder to guess
+            next_port: EPHEMERAL_PORT_START + (crate::entropy::rand() % EPHEMERAL_PORTS) as u16,
+        }
+    }
+
+    /// Frees closed TCP sockets once they finished the tear-down.
+    pub(super) fn reap(&mut self, set: &mut SocketSet<'static>) {
+        self.closing.retain(|handle| {
+            if set.get::<TcpSocket>(*handle).is_open() {
+                true
+            } else {
+                set.remove(*handle);
+                false
+            }
+        });
+    }
+
+    fn get(&self, pid: Pid, fd: u64) -> Result<&Socket, KError> {
+        self.sockets.get(&(pid, fd)).ok_or(KError::InvalidSocket)
+    }
+
+    /// Hands out a new descriptor (shared with zero-copy queues).
+    pub(super) fn allocate_fd(&mut self) -> u64 {
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        fd
+    }
+
+    fn ephemeral_port(&mut self) -> u16 {
+        let port = self.next_port;
+        self.next_port = self
+            .next_port
+            .checked_add(1)
+            .unwrap_or(EPHEMERAL_PORT_START);
+        port
+    }
+}
+
+fn to_endpoint(addr: SocketAddr) -> IpEndpoint {
+    let [a, b, c, d] = addr.ip;
+    IpEndpoint::new(IpAddress::v4(a, b, c, d), addr.port)
+}
+
+fn from_endpoint(endpoint: IpEndpoint) -> SocketAddr {
+    match endpoint.addr {
+        IpAddress::Ipv4(ip) => SocketAddr::new(ip.0, endpoint.port),
+        _ => SocketAddr::default(),
+    }
+}
+
+/// Translates smoltcp errors for socket operations.
+fn to_kerror(e: smoltcp::Error) -> KError {
+    match e {
+        // Buffers are full/empty or the TCP connection isn't established yet:
+        smoltcp::Error::Exhausted | smoltcp::Error::Illegal => KError::WouldBlock,
+        _ => KError::SocketError,
+    }
+}
+
+/// Runs `f` with the (polled) network stack.
+fn with_stack<R>(
+    f: impl FnOnce(&mut SocketSet<'static>, &mut SocketTable) -> Result<R, KError>,
+) -> Result<R, KError> {
+    let mut stack = NET_STACK.lock();
+    let stack = stack.as_mut().ok_or(KError::NetStackUnavailable)?;
+    stack.poll();
+    let r = f(&mut stack.sockets, &mut stack.table);
+    // Make sure what we queued goes out right away:
+    stack.poll();
+    r
+}
+
+/// Creates a new socket of type `ty` for process `pid`.
+pub fn socket(pid: Pid, ty: SocketType) -> Result<u64, KError> {
+    with_stack(|set, table| {
+        let handle = match ty {
+            SocketType::Udp => set.add(UdpSocket::new(
+                UdpSocketBuffer::new(
+                    vec![UdpPacketMetadata::EMPTY; UDP_PACKET_SLOTS],
+                    vec![0; SOCKET_BUFFER_SIZE],
+                ),
+                UdpSocketBuffer::new(
+                    vec![UdpPacketMetadata::EMPTY; UDP_PACKET_SLOTS],
+                    vec![0; SOCKET_BUFFER_SIZE],
+                ),
+            )),
+            SocketType::Tcp => set.add(TcpSocket::new(
+                TcpSocketBuffer::new(vec![0; SOCKET_BUFFER_SIZE]),
+                TcpSocketBuffer::new(vec![0; SOCKET_BUFFER_SIZE]),
+            )),
+        };
+
+        let fd = table.allocate_fd();
+        table.sockets.insert(
+            (pid, fd),
+            Socket {
+                ty,
+                handle,
+                peer: None,
+            },
+        );
+        Ok(fd)
+    })
+}
+
+/// Binds a UDP socket to `port` or makes a TCP socket listen on `port`.
+pub fn bind(pid: Pid, fd: u64, port: u16) -> Result<(), KError> {
+    with_stack(|set, table| {
+        let socket = table.get(pid, fd)?;
+        match socket.ty {
+            SocketType::Udp => set.get::<UdpSocket>(socket.handle).bind(port),
+            SocketType::Tcp => set.get::<TcpSocket>(socket.handle).listen(port),
+        }
+        .map_err(|_e| KError::SocketError)
+    })
+}
+
+/// Connects a TCP socket to `addr` or sets the default peer of a UDP socket.
+pub fn connect(pid: Pid, fd: u64, addr: SocketAddr) -> Result<(), KError> {
+    with_stack(|set, table| {
+        let (ty, handle) = {
+            let socket = table.get(pid, fd)?;
+            (socket.ty, socket.handle)
+        };
+
+        match ty {
+            SocketType::Udp => {
+                let mut udp = set.get::<UdpSocket>(handle);
+                if !udp.is_open() {
+                    let port = table.ephemeral_port();
+                    udp.bind(port).map_err(|_e| KError::SocketError)?;
+                }
+                table
+                    .sockets
+                    .get_mut(&(pid, fd))
+                    .ok_or(KError::InvalidSocket)?
+                    .peer = Some(to_endpoint(addr));
+                Ok(())
+            }
+            SocketType::Tcp => {
+                let port = table.ephemeral_port();
+                set.get::<TcpSocket>(handle)
+                    .connect(to_endpoint(addr), port)
+                    .map_err(|_e| KError::SocketError)
+            }
+        }
+    })
+}
+
+/// Queues `buf` for sending, returns how many bytes were queued.
+pub fn send(pid: Pid, fd: u64, buf: &UserSlice, addr: SocketAddr) -> Result<usize, KError> {
+    with_stack(|set, table| {
+        let socket = table.get(pid, fd)?;
+        match socket.ty {
+            SocketType::Udp => {
+                let endpoint = if addr.is_unspecified() {
+                    socket.peer.ok_or(KError::SocketError)?
+                } else {
+                    to_endpoint(addr)
+                };
+                let mut udp = set.get::<UdpSocket>(socket.handle);
+                let payload = udp.send(buf.len(), endpoint).map_err(to_kerror)?;
+                buf.read_into(0, payload);
+                Ok(buf.len())
+            }
+            SocketType::Tcp => {
+                let mut tcp = set.get::<TcpSocket>(socket.handle);
+                // The free space may wrap around in the buffer
+                let mut sent = 0;
+                while sent < buf.len() {
+                    let queued = tcp
+                        .send(|room| {
+                            let len = room.len().min(buf.len() - sent);
+                            buf.read_into(sent, &mut room[..len]);
+                            (len, len)
+                        })
+                        .map_err(to_kerror)?;
+                    if queued == 0 {
+                        break;
+                    }
+                    sent += queued;
+                }
+                Ok(sent)
+            }
+        }
+    })
+}
+
+/// Receives data into `buf`, returns the length and the sender.
+///
+/// A length of 0 on a TCP socket means the peer closed the connection.
+pub fn recv(pid: Pid, fd: u64, buf: &mut UserSlice) -> Result<(usize, SocketAddr), KError> {
+    with_stack(|set, table| {
+        let socket = table.get(pid, fd)?;
+        match socket.ty {
+            SocketType::Udp => {
+                let mut udp = set.get::<UdpSocket>(socket.handle);
+                let (payload, endpoint) = udp.recv().map_err(to_kerror)?;
+                // The rest of a datagram that doesn't fit is lost
+                let len = payload.len().min(buf.len());
+                buf.write_from(0, &payload[..len]);
+                Ok((len, from_endpoint(endpoint)))
+            }
+            SocketType::Tcp => {
+                let mut tcp = set.get::<TcpSocket>(socket.handle);
+                let peer = from_endpoint(tcp.remote_endpoint());
+                // The data may wrap around in the buffer
+                let mut received = 0;
+                while received < buf.len() {
+                    let read = tcp.recv(|data| {
+                        let len = data.len().min(buf.len() - received);
+                        buf.write_from(received, &data[..len]);
+                        (len, len)
+                    });
+                    match read {
+                        Ok(0) | Err(smoltcp::Error::Finished) => break,
+                        Ok(len) => received += len,
+                        Err(e) => return Err(to_kerror(e)),
+                    }
+                }
+                Ok((received, peer))
+            }
+        }
+    })
+}
+
+/// Closes a socket (TCP connections are shut down gracefully).
+///
+/// Also detaches zero-copy queues (see `xdp`).
+pub fn close(pid: Pid, fd: u64) -> Result<(), KError> {
+    let r = with_stack(|set, table| {
+        let socket = table
+            .sockets
+            .remove(&(pid, fd))
+            .ok_or(KError::InvalidSocket)?;
+        match socket.ty {
+            SocketType::Udp => {
+                set.remove(socket.handle);
+            }
+            SocketType::Tcp => {
+                set.get::<TcpSocket>(socket.handle).close();
+                table.closing.push(socket.handle);
+            }
+        }
+        Ok(())
+    });
+
+    match r {
+        Err(KError::InvalidSocket) => super::xdp::detach(pid, fd),
+        r => r,
+    }
+}
+
+/// Fills in `revents` for every socket in `fds`, returns the number of
+/// sockets with events (event handles and vsock descriptors are left
+/// alone).
+pub fn poll(pid: Pid, fds: &mut [PollFd]) -> Result<usize, KError> {
+    with_stack(|set, table| {
+        let mut ready = 0;
+        for pfd in fds
+            .iter_mut()
+            .filter(|pfd| pfd.fd & (POLL_EVENT_HANDLE | VSOCK_FD) == 0)
+        {
+            let requested = PollEvents::from_bits_truncate(pfd.events);
+            let mut events = PollEvents::empty();
+
+            match table.get(pid, pfd.fd) {
+                Ok(socket) => match socket.ty {
+                    SocketType::Udp => {
+                        let udp = set.get::<UdpSocket>(socket.handle);
+                        events.set(PollEvents::POLLIN, udp.can_recv());
+                        events.set(PollEvents::POLLOUT, udp.can_send());
+                    }
+                    SocketType::Tcp => {
+                        let tcp = set.get::<TcpSocket>(socket.handle);
+                        let hup = matches!(
+                            tcp.state(),
+                            TcpState::Closed
+                                | TcpState::CloseWait
+                                | TcpState::LastAck
+                                | TcpState::TimeWait
+                        );
+                        // A hang-up is readable (recv returns 0):
+                        events.set(PollEvents::POLLIN, tcp.can_recv() || hup);
+                        events.set(PollEvents::POLLOUT, tcp.can_send());
+                        events.set(PollEvents::POLLHUP, hup);
+                    }
+                },
+                Err(_e) => events.insert(PollEvents::POLLERR),
+            }
+
+            // Errors and hang-ups are always reported:
+            let revents = events & (requested | PollEvents::POLLERR | PollEvents::POLLHUP);
+            pfd.revents = revents.bits();
+            if !revents.is_empty() {
+                ready += 1;
+            }
+        }
+        Ok(ready)
+    })
+}
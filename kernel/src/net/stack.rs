@@ -0,0 +1,227 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Interface set-up and polling for the kernel network stack.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use core::fmt;
+use core::time::Duration;
+
+use log::{debug, info};
+use smoltcp::dhcp::Dhcpv4Client;
+use smoltcp::iface::{EthernetInterface, EthernetInterfaceBuilder, NeighborCache, Routes};
+use smoltcp::socket::{RawPacketMetadata, RawSocketBuffer, SocketSet};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, IpCidr, Ipv4Address, Ipv4Cidr};
+use spin::Mutex;
+use vmxnet3::smoltcp::DevQueuePhy;
+use vmxnet3::vmx::VMXNet3;
+
+use crate::arch::Platform;
+use crate::arch_interface::Arch;
+use crate::drivers::pci::{self, Bar, PciMatch};
+use crate::error::KError;
+use crate::memory::vspace::MapAction;
+use crate::memory::PAddr;
+
+use super::socket::SocketTable;
+use super::xdp::XdpPhy;
+use super::IpConfig;
+
+/// MAC address of the vmxnet3 NIC (matches what `run.py` passes to QEMU).
+const ETHERNET_ADDR: EthernetAddress = EthernetAddress([0x56, 0xb4, 0x44, 0xe9, 0x62, 0xdc]);
+
+/// PCI vendor and device ID of the VMware vmxnet3 NIC.
+const VMXNET3_ID: PciMatch = PciMatch::Device {
+    vendor: 0x15ad,
+    device: 0x07b0,
+};
+
+/// How long we wait for a DHCP lease during `init` before giving up.
+const DHCP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The network interface and all sockets that are bound to it.
+pub struct NetStack {
+    pub iface: EthernetInterface<'static, XdpPhy>,
+    pub sockets: SocketSet<'static>,
+    /// Sockets that are in use by user-space processes.
+    pub table: SocketTable,
+    /// Keeps renewing our lease (only present if configured with DHCP).
+    dhcp: Option<Dhcpv4Client>,
+}
+
+// Safe: The stack is only ever accessed through `NET_STACK` (with the lock held).
+unsafe impl Send for NetStack {}
+
+/// The kernel network stack (initialized by `init`).
+pub static NET_STACK: Mutex<Option<NetStack>> = Mutex::new(None);
+
+/// Current time in a format smoltcp understands.
+pub fn now() -> Instant {
+    Instant::from_millis(rawtime::BOOT_TIME_ANCHOR.elapsed().as_millis() as i64)
+}
+
+/// Finds the vmxnet3 NIC on the PCI bus, maps its BARs and brings up the
+/// device.
+fn attach_vmxnet3() -> Result<XdpPhy, KError> {
+    let dev = pci::find(VMXNET3_ID).ok_or(KError::NetDeviceUnavailable)?;
+
+    for bar in dev.bars.iter() {
+        if let Some(Bar::Memory { base, size, .. }) = *bar {
+            Platform::map_kernel_identity(
+                PAddr::from(base),
+                size as usize,
+                MapAction::ReadWriteKernel,
+            )?;
+        }
+    }
+
+    let mut vmx = VMXNet3::new_at(
+        dev.address.bus as u32,
+        dev.address.device as u32,
+        dev.address.function as u32,
+        2,
+        2,
+    )
+    .map_err(|_e| KError::NetDeviceUnavailable)?;
+    vmx.attach_pre()
+        .map_err(|_e| KError::NetDeviceUnavailable)?;
+    vmx.init();
+
+    let phy = DevQueuePhy::new(vmx).map_err(|_e| KError::NetDeviceUnavailable)?;
+    Ok(XdpPhy::new(phy))
+}
+
+/// Replaces the (single) IPv4 address of the interface with `cidr`.
+fn set_ipv4_addr(iface: &mut EthernetInterface<'static, XdpPhy>, cidr: Ipv4Cidr) {
+    iface.update_ip_addrs(|addrs| {
+        if let Some(addr) = addrs.iter_mut().next() {
+            *addr = IpCidr::Ipv4(cidr);
+        }
+    });
+}
+
+impl NetStack {
+    /// Does the interface have a (non-zero) IPv4 address?
+    fn has_address(&self) -> bool {
+        self.iface
+            .ipv4_address()
+            .map_or(false, |a| !a.is_unspecified())
+    }
+
+    /// Sends/receives pending packets (waking up cores in `Poll` if there
+    /// were any) and advances the DHCP client.
+    pub(crate) fn poll(&mut self) {
+        let timestamp = now();
+        self.iface.device_mut().poll_neighbors(timestamp);
+        match self.iface.poll(&mut self.sockets, timestamp) {
+            // Sockets might be ready now
+            Ok(true) => crate::arch::futex::wake_pollers(),
+            Ok(false) => {}
+            Err(e) => debug!("poll error: {}", e),
+        }
+        self.table.reap(&mut self.sockets);
+
+        if let Some(client) = self.dhcp.as_mut() {
+            let config = client
+                .poll(&mut self.iface, &mut self.sockets, timestamp)
+                .unwrap_or_else(|e| {
+                    debug!("DHCP error: {}", e);
+                    None
+                });
+
+            if let Some(config) = config {
+                if let Some(cidr) = config.address {
+                    if self.iface.ipv4_address() != Some(cidr.address()) {
+                        set_ipv4_addr(&mut self.iface, cidr);
+                        info!("DHCP: assigned IPv4 address {}", cidr);
+                    }
+                }
+                if let Some(router) = config.router {
+                    let _r = self.iface.routes_mut().add_default_ipv4_route(router);
+                }
+            }
+        }
+    }
+}
+
+/// Brings up the NIC and configures the interface according to `config`.
+///
+/// With `IpConfig::Dhcp` this polls the device until we got a lease
+/// (or `DHCP_TIMEOUT` expired).
+pub fn init(config: IpConfig) -> Result<(), KError> {
+    let device = attach_vmxnet3()?;
+    let mut iface = EthernetInterfaceBuilder::new(device)
+        .ethernet_addr(ETHERNET_ADDR)
+        .neighbor_cache(NeighborCache::new(BTreeMap::new()))
+        .ip_addrs(vec![IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0)])
+        .routes(Routes::new(BTreeMap::new()))
+        .finalize();
+    let mut sockets = SocketSet::new(vec![]);
+
+    let dhcp = match config {
+        IpConfig::Static {
+            address,
+            prefix_len,
+            gateway,
+        } => {
+            let cidr = Ipv4Cidr::new(Ipv4Address(address), prefix_len);
+            set_ipv4_addr(&mut iface, cidr);
+            if let Some(gw) = gateway {
+                iface
+                    .routes_mut()
+                    .add_default_ipv4_route(Ipv4Address(gw))
+                    .map_err(|_e| KError::InvalidNetConfig)?;
+            }
+            info!("Static IPv4 address {}", cidr);
+            None
+        }
+        IpConfig::Dhcp => {
+            let rx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; 1], vec![0; 900]);
+            let tx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; 1], vec![0; 600]);
+            Some(Dhcpv4Client::new(&mut sockets, rx_buffer, tx_buffer, now()))
+        }
+    };
+
+    let mut stack = NetStack {
+        iface,
+        sockets,
+        table: SocketTable::new(),
+        dhcp,
+    };
+
+    if stack.dhcp.is_some() {
+        let start = rawtime::Instant::now();
+        while !stack.has_address() {
+            if start.elapsed() > DHCP_TIMEOUT {
+                return Err(KError::DhcpTimeout);
+            }
+            stack.poll();
+            core::hint::spin_loop();
+        }
+    }
+
+    *NET_STACK.lock() = Some(stack);
+
+    if let Err(e) = crate::procfs::register("/proc/net/arp", proc_net_arp) {
+        debug!("Unable to register /proc/net/arp: {}", e);
+    }
+    Ok(())
+}
+
+/// Generates `/proc/net/arp`.
+fn proc_net_arp(out: &mut String) -> fmt::Result {
+    match NET_STACK.lock().as_ref() {
+        Some(stack) => stack.iface.device().neighbors.borrow().render(out, now()),
+        None => Ok(()),
+    }
+}
+
+/// Polls the kernel network stack (if it is initialized).
+pub fn poll() {
+    if let Some(stack) = NET_STACK.lock().as_mut() {
+        stack.poll();
+    }
+}
@@ -0,0 +1,512 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! vsock stream connections between processes and the host (or other VMs).
+//!
+//! Doesn't need the IP stack: packets go through the virtio-vsock device
+//! (see `drivers::virtio::vsock`). Like sockets, descriptors are per process
+//! and non-blocking, they have `VSOCK_FD` set so `Poll` can tell them apart
+//! from sockets.
+//!
+//! Flow control is credit based: every packet tells the peer how big our
+//! receive buffer is and how much of it the process consumed, we never send
+//! more than the peer has room for.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use fallible_collections::FallibleVec;
+use kpi::net::{PollEvents, PollFd, VsockAddr, VSOCK_FD};
+use spin::Mutex;
+
+use crate::arch::process::UserSlice;
+use crate::drivers::virtio::vsock::{self as device, Header};
+use crate::drivers::virtio::vsock::{
+    OP_CREDIT_REQUEST, OP_CREDIT_UPDATE, OP_REQUEST, OP_RESPONSE, OP_RST, OP_RW, OP_SHUTDOWN,
+    SHUTDOWN_RCV, SHUTDOWN_SEND, TYPE_STREAM,
+};
+use crate::error::KError;
+use crate::process::Pid;
+
+/// Size of the receive buffer of a connection (in bytes).
+const BUFFER_SIZE: u32 = 64 * 1024;
+
+/// How many connections wait for `accept` at most (per listener).
+const BACKLOG: usize = 16;
+
+/// First port we hand out for outgoing connections.
+const EPHEMERAL_PORT_START: u32 = 49152;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum State {
+    /// We sent a request and wait for the response.
+    Connecting,
+    Connected,
+    /// The peer reset the connection (or refused it).
+    Closed,
+}
+
+struct Connection {
+    local_port: u32,
+    peer: VsockAddr,
+    state: State,
+    /// The peer won't send anymore.
+    peer_send_shutdown: bool,
+    /// The peer won't receive anymore.
+    peer_rcv_shutdown: bool,
+    rx: VecDeque<u8>,
+    /// Bytes the process consumed from `rx`.
+    fwd_cnt: u32,
+    /// The `fwd_cnt` the peer knows about.
+    reported_fwd_cnt: u32,
+    /// Bytes we sent.
+    tx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+}
+
+impl Connection {
+    fn new(local_port: u32, peer: VsockAddr, state: State) -> Connection {
+        Connection {
+            local_port,
+            peer,
+            state,
+            peer_send_shutdown: false,
+            peer_rcv_shutdown: false,
+            rx: VecDeque::new(),
+            fwd_cnt: 0,
+            reported_fwd_cnt: 0,
+            tx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+        }
+    }
+
+    /// How many bytes we can send before the peer's buffer is full.
+    fn credit(&self) -> u32 {
+        self.peer_buf_alloc
+            .saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+
+    /// A packet from us to the peer (with our credit information).
+    fn header(&mut self, op: u16, flags: u32) -> Header {
+        self.reported_fwd_cnt = self.fwd_cnt;
+        Header {
+            dst_cid: self.peer.cid as u64,
+            src_port: self.local_port,
+            dst_port: self.peer.port,
+            ty: TYPE_STREAM,
+            op,
+            flags,
+            buf_alloc: BUFFER_SIZE,
+            fwd_cnt: self.fwd_cnt,
+            ..Default::default()
+        }
+    }
+
+    /// Has the connection been torn down (by the peer)?
+    fn hung_up(&self) -> bool {
+        self.state == State::Closed || self.peer_send_shutdown
+    }
+}
+
+enum Kind {
+    Stream(Connection),
+    /// Listens on a port, holds the descriptors of connections nobody
+    /// accepted yet.
+    Listener {
+        port: u32,
+        pending: VecDeque<u64>,
+    },
+}
+
+struct Vsock {
+    pid: Pid,
+    fd: u64,
+    kind: Kind,
+}
+
+/// The vsock descriptors of all processes.
+struct Vsocks {
+    vsocks: Vec<Vsock>,
+    next_fd: u64,
+    next_port: u32,
+}
+
+impl Vsocks {
+    const fn new() -> Vsocks {
+        Vsocks {
+            vsocks: Vec::new(),
+            next_fd: 1,
+            next_port: EPHEMERAL_PORT_START,
+        }
+    }
+
+    fn insert(&mut self, pid: Pid, kind: Kind) -> Result<u64, KError> {
+        let fd = self.next_fd | VSOCK_FD;
+        self.vsocks.try_push(Vsock { pid, fd, kind })?;
+        self.next_fd += 1;
+        Ok(fd)
+    }
+
+    fn get(&mut self, pid: Pid, fd: u64) -> Result<&mut Kind, KError> {
+        self.vsocks
+            .iter_mut()
+            .find(|v| v.pid == pid && v.fd == fd)
+            .map(|v| &mut v.kind)
+            .ok_or(KError::InvalidSocket)
+    }
+
+    fn stream(&mut self, pid: Pid, fd: u64) -> Result<&mut Connection, KError> {
+        match self.get(pid, fd)? {
+            Kind::Stream(conn) => Ok(conn),
+            Kind::Listener { .. } => Err(KError::SocketError),
+        }
+    }
+
+    fn remove(&mut self, pid: Pid, fd: u64) -> Result<Kind, KError> {
+        let index = self
+            .vsocks
+            .iter()
+            .position(|v| v.pid == pid && v.fd == fd)
+            .ok_or(KError::InvalidSocket)?;
+        Ok(self.vsocks.swap_remove(index).kind)
+    }
+
+    fn port_in_use(&self, port: u32) -> bool {
+        self.vsocks.iter().any(|v| match &v.kind {
+            Kind::Stream(conn) => conn.local_port == port,
+            Kind::Listener { port: p, .. } => *p == port,
+        })
+    }
+
+    fn ephemeral_port(&mut self) -> u32 {
+        loop {
+            let port = self.next_port;
+            self.next_port = self
+                .next_port
+                .checked_add(1)
+                .unwrap_or(EPHEMERAL_PORT_START);
+            if !self.port_in_use(port) {
+                return port;
+            }
+        }
+    }
+
+    /// The connection a packet from `peer` to `port` belongs to.
+    fn connection(&mut self, port: u32, peer: VsockAddr) -> Option<&mut Connection> {
+        self.vsocks.iter_mut().find_map(|v| match &mut v.kind {
+            Kind::Stream(conn) if conn.local_port == port && conn.peer == peer => Some(conn),
+            _ => None,
+        })
+    }
+
+    /// Handles a packet for a connection we don't know (yet), returns the
+    /// reply.
+    fn request(&mut self, header: &Header, peer: VsockAddr) -> Option<Header> {
+        let listener = self.vsocks.iter().position(|v| match &v.kind {
+            Kind::Listener { port, pending } => *port == header.dst_port && pending.len() < BACKLOG,
+            _ => false,
+        });
+
+        let mut conn = Connection::new(header.dst_port, peer, State::Connected);
+        conn.peer_buf_alloc = header.buf_alloc;
+        conn.peer_fwd_cnt = header.fwd_cnt;
+        match (header.op, listener) {
+            (OP_REQUEST, Some(index)) => {
+                let response = conn.header(OP_RESPONSE, 0);
+                let reset = conn.header(OP_RST, 0);
+                let pid = self.vsocks[index].pid;
+                match self.insert(pid, Kind::Stream(conn)) {
+                    Ok(fd) => {
+                        match &mut self.vsocks[index].kind {
+                            Kind::Listener { pending, .. } => pending.push_back(fd),
+                            Kind::Stream(_) => unreachable!("Found a listener"),
+                        }
+                        Some(response)
+                    }
+                    Err(_e) => Some(reset),
+                }
+            }
+            // Never answer a reset with a reset
+            (OP_RST, _) => None,
+            _ => Some(conn.header(OP_RST, 0)),
+        }
+    }
+}
+
+static VSOCKS: Mutex<Vsocks> = Mutex::new(Vsocks::new());
+
+/// Sends a packet without payload.
+fn send_control(header: Header) -> Result<(), KError> {
+    device::send(&header, &[])
+}
+
+/// Handles a packet the device received (called by the driver).
+pub(crate) fn receive(header: &Header, payload: &[u8]) {
+    let peer = VsockAddr::new(header.src_cid as u32, header.src_port);
+    let mut vsocks = VSOCKS.lock();
+    if header.ty != TYPE_STREAM {
+        if header.op != OP_RST {
+            let mut conn = Connection::new(header.dst_port, peer, State::Closed);
+            let _ = send_control(conn.header(OP_RST, 0));
+        }
+        return;
+    }
+
+    let conn = match vsocks.connection(header.dst_port, peer) {
+        Some(conn) => conn,
+        None => {
+            if let Some(reply) = vsocks.request(header, peer) {
+                let _ = send_control(reply);
+            }
+            return;
+        }
+    };
+
+    conn.peer_buf_alloc = header.buf_alloc;
+    conn.peer_fwd_cnt = header.fwd_cnt;
+    let reply = match header.op {
+        OP_RESPONSE if conn.state == State::Connecting => {
+            conn.state = State::Connected;
+            None
+        }
+        OP_RW if conn.state == State::Connected => {
+            if conn.rx.len() + payload.len() > BUFFER_SIZE as usize {
+                // The peer ignored our credit
+                conn.state = State::Closed;
+                Some(conn.header(OP_RST, 0))
+            } else if conn.rx.try_reserve(payload.len()).is_ok() {
+                conn.rx.extend(payload);
+                None
+            } else {
+                // TODO(error-handling): Losing data on a stream is as bad as
+                // resetting it
+                conn.state = State::Closed;
+                Some(conn.header(OP_RST, 0))
+            }
+        }
+        OP_CREDIT_UPDATE => None,
+        OP_CREDIT_REQUEST => Some(conn.header(OP_CREDIT_UPDATE, 0)),
+        OP_SHUTDOWN => {
+            conn.peer_send_shutdown |= header.flags & SHUTDOWN_SEND != 0;
+            conn.peer_rcv_shutdown |= header.flags & SHUTDOWN_RCV != 0;
+            None
+        }
+        OP_RST => {
+            conn.state = State::Closed;
+            None
+        }
+        _ => {
+            conn.state = State::Closed;
+            Some(conn.header(OP_RST, 0))
+        }
+    };
+    if let Some(reply) = reply {
+        let _ = send_control(reply);
+    }
+}
+
+/// The device lost all connections.
+pub(crate) fn reset() {
+    for v in VSOCKS.lock().vsocks.iter_mut() {
+        if let Kind::Stream(conn) = &mut v.kind {
+            conn.state = State::Closed;
+        }
+    }
+}
+
+/// Is there a device we can make connections with?
+pub fn available() -> bool {
+    device::guest_cid().is_some()
+}
+
+/// Returns the CID of this VM.
+pub fn local_cid() -> Result<u64, KError> {
+    device::guest_cid().ok_or(KError::VsockUnavailable)
+}
+
+/// Connects to `addr`, returns the descriptor of the connection.
+pub fn connect(pid: Pid, addr: VsockAddr) -> Result<u64, KError> {
+    local_cid()?;
+    device::poll();
+
+    let mut vsocks = VSOCKS.lock();
+    let port = vsocks.ephemeral_port();
+    let mut conn = Connection::new(port, addr, State::Connecting);
+    let request = conn.header(OP_REQUEST, 0);
+    let fd = vsocks.insert(pid, Kind::Stream(conn))?;
+    if let Err(e) = send_control(request) {
+        let _ = vsocks.remove(pid, fd);
+        return Err(e);
+    }
+    Ok(fd)
+}
+
+/// Listens on `port`, returns the descriptor to accept connections with.
+pub fn listen(pid: Pid, port: u32) -> Result<u64, KError> {
+    local_cid()?;
+    let mut vsocks = VSOCKS.lock();
+    if vsocks.port_in_use(port) {
+        return Err(KError::VsockPortInUse);
+    }
+    vsocks.insert(
+        pid,
+        Kind::Listener {
+            port,
+            pending: VecDeque::new(),
+        },
+    )
+}
+
+/// Takes the next connection of the listener `fd`, returns its descriptor
+/// and the peer.
+pub fn accept(pid: Pid, fd: u64) -> Result<(u64, VsockAddr), KError> {
+    device::poll();
+
+    let mut vsocks = VSOCKS.lock();
+    let conn = match vsocks.get(pid, fd)? {
+        Kind::Listener { pending, .. } => pending.pop_front().ok_or(KError::WouldBlock)?,
+        Kind::Stream(_) => return Err(KError::SocketError),
+    };
+    let peer = vsocks.stream(pid, conn)?.peer;
+    Ok((conn, peer))
+}
+
+/// Sends (as much as the peer has room for of) `buf`, returns how many
+/// bytes were sent.
+pub fn send(pid: Pid, fd: u64, buf: &UserSlice) -> Result<usize, KError> {
+    device::poll();
+
+    let mut vsocks = VSOCKS.lock();
+    let conn = vsocks.stream(pid, fd)?;
+    match conn.state {
+        State::Connecting => return Err(KError::WouldBlock),
+        State::Closed => return Err(KError::SocketError),
+        State::Connected if conn.peer_rcv_shutdown => return Err(KError::SocketError),
+        State::Connected => {}
+    }
+
+    let mut sent = 0;
+    let mut chunk: Vec<u8> = Vec::new();
+    while sent < buf.len() {
+        let len = (buf.len() - sent)
+            .min(device::MAX_PAYLOAD)
+            .min(conn.credit() as usize);
+        if len == 0 {
+            break;
+        }
+        chunk.try_resize(len, 0)?;
+        buf.read_into(sent, &mut chunk);
+        let header = conn.header(OP_RW, 0);
+        device::send(&header, &chunk)?;
+        conn.tx_cnt = conn.tx_cnt.wrapping_add(len as u32);
+        sent += len;
+    }
+
+    if sent == 0 && !buf.is_empty() {
+        // Make sure the peer tells us once it has room again
+        let request = conn.header(OP_CREDIT_REQUEST, 0);
+        send_control(request)?;
+        return Err(KError::WouldBlock);
+    }
+    Ok(sent)
+}
+
+/// Receives data into `buf`, returns its length.
+///
+/// A length of 0 means the peer closed the connection.
+pub fn recv(pid: Pid, fd: u64, buf: &mut UserSlice) -> Result<usize, KError> {
+    device::poll();
+
+    let mut vsocks = VSOCKS.lock();
+    let conn = vsocks.stream(pid, fd)?;
+    if conn.rx.is_empty() {
+        return if conn.hung_up() {
+            Ok(0)
+        } else {
+            Err(KError::WouldBlock)
+        };
+    }
+
+    let len = buf.len().min(conn.rx.len());
+    let (front, back) = conn.rx.as_slices();
+    let in_front = len.min(front.len());
+    buf.write_from(0, &front[..in_front]);
+    buf.write_from(in_front, &back[..len - in_front]);
+    conn.rx.drain(..len);
+    conn.fwd_cnt = conn.fwd_cnt.wrapping_add(len as u32);
+
+    // Tell the peer about the room we made once it's worth a packet
+    if conn.state == State::Connected
+        && conn.fwd_cnt.wrapping_sub(conn.reported_fwd_cnt) >= BUFFER_SIZE / 2
+    {
+        let update = conn.header(OP_CREDIT_UPDATE, 0);
+        send_control(update)?;
+    }
+    Ok(len)
+}
+
+/// Closes a connection (or a listener and the connections nobody accepted).
+pub fn close(pid: Pid, fd: u64) -> Result<(), KError> {
+    let mut vsocks = VSOCKS.lock();
+    match vsocks.remove(pid, fd)? {
+        Kind::Stream(mut conn) => {
+            if conn.state != State::Closed {
+                let shutdown = conn.header(OP_SHUTDOWN, SHUTDOWN_RCV | SHUTDOWN_SEND);
+                send_control(shutdown)?;
+            }
+        }
+        Kind::Listener { pending, .. } => {
+            for fd in pending {
+                if let Ok(Kind::Stream(mut conn)) = vsocks.remove(pid, fd) {
+                    let _ = send_control(conn.header(OP_RST, 0));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fills in `revents` for every vsock descriptor in `fds`, returns the
+/// number of descriptors with events.
+pub fn poll(pid: Pid, fds: &mut [PollFd]) -> usize {
+    device::poll();
+
+    let mut vsocks = VSOCKS.lock();
+    let mut ready = 0;
+    for pfd in fds.iter_mut().filter(|pfd| is_vsock(pfd.fd)) {
+        let requested = PollEvents::from_bits_truncate(pfd.events);
+        let mut events = PollEvents::empty();
+
+        match vsocks.get(pid, pfd.fd) {
+            Ok(Kind::Stream(conn)) => {
+                let hup = conn.hung_up();
+                // A hang-up is readable (recv returns 0):
+                events.set(PollEvents::POLLIN, !conn.rx.is_empty() || hup);
+                events.set(
+                    PollEvents::POLLOUT,
+                    conn.state == State::Connected && !conn.peer_rcv_shutdown && conn.credit() > 0,
+                );
+                events.set(PollEvents::POLLHUP, hup);
+            }
+            Ok(Kind::Listener { pending, .. }) => {
+                events.set(PollEvents::POLLIN, !pending.is_empty());
+            }
+            Err(_e) => events.insert(PollEvents::POLLERR),
+        }
+
+        // Errors and hang-ups are always reported:
+        let revents = events & (requested | PollEvents::POLLERR | PollEvents::POLLHUP);
+        pfd.revents = revents.bits();
+        if !revents.is_empty() {
+            ready += 1;
+        }
+    }
+    ready
+}
+
+/// Is `fd` a vsock descriptor (and not a socket or an event handle)?
+pub fn is_vsock(fd: u64) -> bool {
+    fd & (VSOCK_FD | kpi::net::POLL_EVENT_HANDLE) == VSOCK_FD
+}
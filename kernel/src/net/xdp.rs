@@ -0,0 +1,367 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Zero-copy packet queues shared with user-space (similar to AF_XDP).
+//!
+//! A process registers a UMEM region and a set of rings (`kpi::net::XdpRings`).
+//! UDP packets for the registered port bypass smoltcp and get placed in UMEM
+//! buffers the process handed us through the fill ring. The process sends
+//! packets by putting UMEM buffers in the TX ring.
+//!
+//! The kernel keeps ownership of the NIC, all other traffic still goes
+//! through smoltcp. ARP traffic also passes through the `NeighborCache`
+//! on the way.
+//!
+//! # TODO
+//! The driver can't DMA into UMEM buffers yet, so there is still one copy
+//! between the vmxnet3 I/O buffers and the UMEM region.
+
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+use kpi::net::{XdpDesc, XdpRings, XDP_FRAME_SIZE};
+use log::{debug, warn};
+use smoltcp::phy::{Device, DeviceCapabilities, RxToken, TxToken};
+use smoltcp::time::Instant;
+use vmxnet3::smoltcp::{DevQueuePhy, RxPacket, TxPacket};
+
+use crate::arch::memory::paddr_to_kernel_vaddr;
+use crate::error::KError;
+use crate::memory::PAddr;
+use crate::process::Pid;
+
+use super::neighbor::NeighborCache;
+use super::stack::{now, NET_STACK};
+
+/// Size of an Ethernet header.
+const ETHERNET_HEADER_LEN: usize = 14;
+
+/// Returns the UDP destination port if `frame` is an IPv4/UDP packet.
+fn udp_dst_port(frame: &[u8]) -> Option<u16> {
+    const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+    const IP_PROTOCOL_UDP: u8 = 17;
+
+    let ip = frame.get(ETHERNET_HEADER_LEN..)?;
+    if frame.get(12..14)? != ETHERTYPE_IPV4 || *ip.get(9)? != IP_PROTOCOL_UDP {
+        return None;
+    }
+
+    let ihl = (*ip.get(0)? & 0xf) as usize * 4;
+    let udp = ip.get(ihl..ihl + 4)?;
+    Some(u16::from_be_bytes([udp[2], udp[3]]))
+}
+
+/// A zero-copy queue registered by a process.
+pub struct XdpQueue {
+    pub pid: Pid,
+    pub fd: u64,
+    port: u16,
+    rings: &'static XdpRings,
+    umem: &'static mut [u8],
+}
+
+impl XdpQueue {
+    /// Creates a queue from the physical addresses of the rings and the UMEM region.
+    ///
+    /// # Safety
+    /// The caller needs to make sure the memory is mapped in the process
+    /// and physically contiguous.
+    pub unsafe fn new(
+        pid: Pid,
+        fd: u64,
+        port: u16,
+        rings: PAddr,
+        umem: PAddr,
+        umem_len: usize,
+    ) -> XdpQueue {
+        let rings = &*paddr_to_kernel_vaddr(rings).as_ptr::<XdpRings>();
+        let umem =
+            core::slice::from_raw_parts_mut(paddr_to_kernel_vaddr(umem).as_mut_ptr(), umem_len);
+        XdpQueue {
+            pid,
+            fd,
+            port,
+            rings,
+            umem,
+        }
+    }
+
+    /// Is this a packet for the queue?
+    fn matches(&self, frame: &[u8]) -> bool {
+        udp_dst_port(frame) == Some(self.port)
+    }
+
+    /// Returns the UMEM buffer described by `desc` (if it is valid).
+    fn buffer(&mut self, desc: XdpDesc, len: usize) -> Option<&mut [u8]> {
+        let start = desc.addr as usize;
+        if len > XDP_FRAME_SIZE {
+            return None;
+        }
+        self.umem.get_mut(start..start.checked_add(len)?)
+    }
+
+    /// Places `frame` in the next free UMEM buffer and publishes it on the RX ring.
+    ///
+    /// Drops the packet if the process didn't give us enough buffers.
+    fn deliver(&mut self, frame: &[u8]) {
+        if self.rings.rx.is_full() {
+            debug!("xdp: RX ring full, dropping packet");
+            return;
+        }
+        let desc = match self.rings.fill.pop() {
+            Some(desc) => desc,
+            None => {
+                debug!("xdp: fill ring empty, dropping packet");
+                return;
+            }
+        };
+
+        match self.buffer(desc, frame.len()) {
+            Some(buf) => {
+                buf.copy_from_slice(frame);
+                let pushed = self
+                    .rings
+                    .rx
+                    .push(XdpDesc::new(desc.addr, frame.len() as u32));
+                debug_assert!(pushed, "Checked for space above");
+            }
+            None => warn!("xdp: invalid buffer {:?} in fill ring", desc),
+        }
+    }
+}
+
+/// Wraps the vmxnet3 device and diverts packets for a zero-copy queue
+/// before they reach smoltcp.
+pub struct XdpPhy {
+    inner: DevQueuePhy,
+    pub queue: Option<XdpQueue>,
+    /// Shared by the RX and TX token of `receive`.
+    pub neighbors: RefCell<NeighborCache>,
+}
+
+impl XdpPhy {
+    pub fn new(inner: DevQueuePhy) -> XdpPhy {
+        XdpPhy {
+            inner,
+            queue: None,
+            neighbors: RefCell::new(NeighborCache::default()),
+        }
+    }
+
+    /// Retransmits pending ARP requests and expires old neighbors.
+    pub fn poll_neighbors(&mut self, timestamp: Instant) {
+        let inner = &mut self.inner;
+        self.neighbors
+            .get_mut()
+            .poll(timestamp, |frame| match inner.transmit() {
+                Some(token) => {
+                    let r = token.consume(timestamp, frame.len(), |buf| {
+                        buf.copy_from_slice(frame);
+                        Ok(())
+                    });
+                    if let Err(e) = r {
+                        debug!("arp: transmit failed {}", e);
+                    }
+                }
+                None => debug!("arp: no TX descriptor available, dropping request"),
+            });
+    }
+
+    /// Is `fd` of process `pid` the attached queue?
+    fn owns(&self, pid: Pid, fd: u64) -> bool {
+        self.queue
+            .as_ref()
+            .map_or(false, |queue| queue.pid == pid && queue.fd == fd)
+    }
+
+    /// Sends the packets of the TX ring and returns the buffers on the completion ring.
+    pub fn transmit_queue(&mut self, timestamp: Instant) {
+        let inner = &mut self.inner;
+        let queue = match self.queue.as_mut() {
+            Some(queue) => queue,
+            None => return,
+        };
+
+        while !queue.rings.completion.is_full() {
+            let desc = match queue.rings.tx.pop() {
+                Some(desc) => desc,
+                None => break,
+            };
+
+            match (queue.buffer(desc, desc.len as usize), inner.transmit()) {
+                (Some(frame), Some(token)) => {
+                    let r = token.consume(timestamp, frame.len(), |buf| {
+                        buf.copy_from_slice(frame);
+                        Ok(())
+                    });
+                    if let Err(e) = r {
+                        debug!("xdp: transmit failed {}", e);
+                    }
+                }
+                (None, _) => warn!("xdp: invalid buffer {:?} in TX ring", desc),
+                (_, None) => debug!("xdp: no TX descriptor available, dropping packet"),
+            }
+
+            let pushed = queue.rings.completion.push(XdpDesc::new(desc.addr, 0));
+            debug_assert!(pushed, "Checked for space above");
+        }
+    }
+}
+
+impl<'a> Device<'a> for XdpPhy {
+    type RxToken = XdpRxToken<'a>;
+    type TxToken = XdpTxToken<'a>;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let neighbors = &self.neighbors;
+
+        // ARP replies we answered from the neighbor cache come first
+        if neighbors.borrow().has_injected() {
+            let inner = self.inner.transmit()?;
+            let frame = neighbors
+                .borrow_mut()
+                .next_injected()
+                .expect("Checked for a frame above");
+            let rx = XdpRxToken {
+                frame: RxFrame::Injected(frame),
+                queue: None,
+                neighbors,
+            };
+            return Some((rx, XdpTxToken { inner, neighbors }));
+        }
+
+        let queue = self.queue.as_mut();
+        let (inner, tx) = self.inner.receive()?;
+        let rx = XdpRxToken {
+            frame: RxFrame::Device(inner),
+            queue,
+            neighbors,
+        };
+        Some((
+            rx,
+            XdpTxToken {
+                inner: tx,
+                neighbors,
+            },
+        ))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        let inner = self.inner.transmit()?;
+        Some(XdpTxToken {
+            inner,
+            neighbors: &self.neighbors,
+        })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+}
+
+enum RxFrame<'a> {
+    Device(RxPacket<'a>),
+    /// A frame made up by the neighbor cache.
+    Injected(Vec<u8>),
+}
+
+/// Receive token that hands packets to the zero-copy queue if they match.
+pub struct XdpRxToken<'a> {
+    frame: RxFrame<'a>,
+    queue: Option<&'a mut XdpQueue>,
+    neighbors: &'a RefCell<NeighborCache>,
+}
+
+impl<'a> RxToken for XdpRxToken<'a> {
+    fn consume<R, F>(self, timestamp: Instant, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        let queue = self.queue;
+        let neighbors = self.neighbors;
+        match self.frame {
+            RxFrame::Device(inner) => inner.consume(timestamp, |frame| match queue {
+                Some(queue) if queue.matches(frame) => {
+                    queue.deliver(frame);
+                    // Tell smoltcp to ignore it:
+                    Err(smoltcp::Error::Dropped)
+                }
+                _ => {
+                    neighbors.borrow_mut().process_rx(frame, timestamp);
+                    f(frame)
+                }
+            }),
+            RxFrame::Injected(mut frame) => f(&mut frame),
+        }
+    }
+}
+
+/// Transmit token that shows outgoing ARP requests to the neighbor cache.
+pub struct XdpTxToken<'a> {
+    inner: TxPacket<'a>,
+    neighbors: &'a RefCell<NeighborCache>,
+}
+
+impl<'a> TxToken for XdpTxToken<'a> {
+    fn consume<R, F>(self, timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        let neighbors = self.neighbors;
+        self.inner.consume(timestamp, len, |buf| {
+            let r = f(buf)?;
+            neighbors.borrow_mut().process_tx(buf, timestamp);
+            Ok(r)
+        })
+    }
+}
+
+/// Registers a zero-copy queue for `pid` (there can only be one per NIC).
+///
+/// # Safety
+/// `rings` and `umem` must point to physically contiguous memory that is
+/// mapped in the process.
+pub unsafe fn attach(
+    pid: Pid,
+    rings: PAddr,
+    umem: PAddr,
+    umem_len: usize,
+    port: u16,
+) -> Result<u64, KError> {
+    let mut stack = NET_STACK.lock();
+    let stack = stack.as_mut().ok_or(KError::NetStackUnavailable)?;
+    if stack.iface.device_mut().queue.is_some() {
+        return Err(KError::AlreadyPresent);
+    }
+
+    let fd = stack.table.allocate_fd();
+    stack.iface.device_mut().queue = Some(XdpQueue::new(pid, fd, port, rings, umem, umem_len));
+    Ok(fd)
+}
+
+/// Sends everything in the TX ring of queue `fd` and polls the NIC.
+pub fn kick(pid: Pid, fd: u64) -> Result<(), KError> {
+    let mut stack = NET_STACK.lock();
+    let stack = stack.as_mut().ok_or(KError::NetStackUnavailable)?;
+    let device = stack.iface.device_mut();
+    if !device.owns(pid, fd) {
+        return Err(KError::InvalidSocket);
+    }
+
+    device.transmit_queue(now());
+    stack.poll();
+    Ok(())
+}
+
+/// Removes queue `fd`, packets for the port go to smoltcp again.
+pub fn detach(pid: Pid, fd: u64) -> Result<(), KError> {
+    let mut stack = NET_STACK.lock();
+    let stack = stack.as_mut().ok_or(KError::NetStackUnavailable)?;
+    let device = stack.iface.device_mut();
+    if !device.owns(pid, fd) {
+        return Err(KError::InvalidSocket);
+    }
+
+    device.queue = None;
+    Ok(())
+}
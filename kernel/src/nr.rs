@@ -1,36 +1,133 @@
 // Copyright © 2021 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
+//! The replicated global kernel state: the process table and which core
+//! runs which process.
+//!
+//! Every NUMA node has a [`KernelNode`] replica (in node-local memory) and
+//! all of them share one log, so updates (spawning a process, assigning a
+//! core) are ordered through the log while queries (what runs on this core,
+//! which node a core is on, which processes exist) are answered by the local
+//! replica. Every replica has its own copy of the thread topology for that
+//! reason, and picks cores for processes that take any with the same
+//! [`crate::scheduler::policy`].
+//!
+//! The state of a process itself (address space, executors) is replicated
+//! separately, see [`crate::nrproc`].
+//!
+//! Looking up a process doesn't need a replica: updates that change the
+//! process table publish a copy of it with [`crate::rcu`], so system calls
+//! and interrupts that just want to know about a process don't take the
+//! replica's lock (or apply the log) for it.
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
 use crate::prelude::*;
-use core::fmt::Debug;
+use core::fmt::{self, Debug, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
 
+use fallible_collections::FallibleVecGlobal;
 use hashbrown::HashMap;
+use kpi::system::CorePolicy;
 use log::{error, trace};
 use node_replication::Dispatch;
 
-use crate::arch::MAX_CORES;
+use crate::arch::{MAX_CORES, MAX_NUMA_NODES};
 use crate::error::KError;
+use crate::kcb::ArchSpecificKcb;
 use crate::memory::VAddr;
+use crate::mutex::Mutex;
 use crate::process::{Pid, MAX_PROCESSES};
+use crate::rcu::{self, Rcu};
+use crate::scheduler::policy::{self, Core};
+
+/// The process table as of the last update that changed it (sorted by pid).
+static PROCESSES: Rcu<Vec<(Pid, ProcessEntry)>> = Rcu::empty();
+
+/// Serializes publishing `PROCESSES` (so an older copy can't replace a
+/// newer one).
+static PUBLISHING: Mutex<()> = Mutex::new("nr_processes", ());
+
+/// How far the replicas got on the log of the kernel state.
+pub static KERNEL_LOG: LogProgress = LogProgress::new();
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOTHING_APPLIED: AtomicU64 = AtomicU64::new(0);
+
+/// How many entries of an NR log the replica of every node applied.
+///
+/// The core that appends entries applies them to its replica right away, so
+/// the most any replica applied is where the log ends (`node_replication`
+/// doesn't tell us its tail).
+pub struct LogProgress {
+    applied: [AtomicU64; MAX_NUMA_NODES],
+}
+
+impl LogProgress {
+    pub const fn new() -> LogProgress {
+        LogProgress {
+            applied: [NOTHING_APPLIED; MAX_NUMA_NODES],
+        }
+    }
+
+    /// Records that the replica of the current core applied an entry (from
+    /// `dispatch_mut`).
+    pub fn applied(&self) {
+        let node = super::kcb::get_kcb().arch.node();
+        self.applied[node].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// How many entries the replica of `node` has yet to apply.
+    pub fn lag(&self, node: atopology::NodeId) -> u64 {
+        let tail = self
+            .applied
+            .iter()
+            .map(|applied| applied.load(Ordering::Relaxed))
+            .max()
+            .unwrap_or(0);
+        // It may apply more while we look
+        tail.saturating_sub(self.applied[node].load(Ordering::Relaxed))
+    }
+}
 
 #[derive(PartialEq, Clone, Copy, Debug)]
 pub enum ReadOps {
     CurrentProcess(atopology::GlobalThreadId),
+    /// All processes and the cores allocated to them.
+    Scheduling,
+    /// The processes and how many cores they have.
+    Processes,
+    /// The NUMA node of a core.
+    CoreNode(atopology::GlobalThreadId),
+    /// How cores are picked for processes that take any.
+    CorePolicy,
+    /// The cores of a process in gang mode.
+    Gang(Pid),
 }
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum Op {
-    /// Allocate a new process (Pid)
-    AllocatePid,
-    /// Destroy a process
+    /// Allocate a new process (Pid) for a binary
+    AllocatePid(&'static str),
+    /// Destroy a process (and take its cores away)
     FreePid(Pid),
-    /// Assign a core to a process
+    /// Assign a core to a process (any free one on the node if no core is
+    /// given)
     SchedAllocateCore(
         Pid,
         Option<atopology::NodeId>,
         Option<atopology::GlobalThreadId>,
         VAddr,
     ),
+    /// Take a core away from a process
+    SchedReleaseCore(Pid, atopology::GlobalThreadId),
+    /// A core was parked (`false`) or came back (`true`), policies don't
+    /// pick parked cores
+    SchedSetOnline(atopology::GlobalThreadId, bool),
+    /// Switch how cores are picked (returns the old policy)
+    SetCorePolicy(CorePolicy),
+    /// Put a process in gang mode or take it out again
+    SetGang(Pid, bool),
 }
 
 #[derive(Debug, Clone)]
@@ -39,6 +136,14 @@ pub enum NodeResult {
     PidReturned,
     CoreInfo(CoreInfo),
     CoreAllocated(atopology::GlobalThreadId),
+    CoreReleased,
+    Scheduling(Vec<Pid>, Vec<(atopology::GlobalThreadId, Pid)>),
+    Processes(Vec<(Pid, ProcessEntry)>),
+    CoreNode(atopology::NodeId),
+    CorePolicy(CorePolicy),
+    CoreOnline,
+    Gang(Vec<atopology::GlobalThreadId>),
+    GangSet,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -47,21 +152,69 @@ pub struct CoreInfo {
     pub entry_point: VAddr,
 }
 
+/// What we know about a process in the process table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessEntry {
+    /// The binary the process was spawned from.
+    pub binary: &'static str,
+    /// How many cores are allocated to the process.
+    pub cores: usize,
+    /// Its cores are preempted and resumed together (see
+    /// `arch::x86_64::gang`).
+    pub gang: bool,
+}
+
 pub struct KernelNode {
-    process_map: HashMap<Pid, ()>,
+    process_map: HashMap<Pid, ProcessEntry>,
     scheduler_map: HashMap<atopology::GlobalThreadId, CoreInfo>,
+    /// Every core, where it is and if it's taken (sorted by core).
+    topology: Vec<Core>,
+    /// How we pick a core if a process takes any.
+    policy: CorePolicy,
 }
 
 impl Default for KernelNode {
     fn default() -> KernelNode {
+        // Replicas are created on their node, so this copy is node-local
+        let topology = atopology::MACHINE_TOPOLOGY
+            .threads()
+            .map(|t| Core {
+                gtid: t.id,
+                node: t.node_id.unwrap_or(0),
+                package: t.package_id as usize,
+                core: t.core_id as usize,
+                allocated: false,
+                online: true,
+            })
+            .collect();
+        KernelNode::new(topology)
+    }
+}
+
+impl KernelNode {
+    fn new(mut topology: Vec<Core>) -> KernelNode {
+        topology.sort_unstable_by_key(|core| core.gtid);
         KernelNode {
             process_map: HashMap::new(),   // with_capacity(MAX_PROCESSES),
             scheduler_map: HashMap::new(), // with_capacity(MAX_CORES),
+            topology,
+            policy: CorePolicy::Packed,
         }
     }
-}
 
-impl KernelNode {
+    /// Finds a core on `affinity` (any node if `None`) that doesn't run a
+    /// process, the policy decides which one.
+    fn free_core(&self, affinity: Option<atopology::NodeId>) -> Option<atopology::GlobalThreadId> {
+        policy::get(self.policy).pick(&self.topology, affinity)
+    }
+
+    fn topology_mut(&mut self, gtid: atopology::GlobalThreadId) -> Option<&mut Core> {
+        self.topology
+            .binary_search_by_key(&gtid, |core| core.gtid)
+            .ok()
+            .map(move |idx| &mut self.topology[idx])
+    }
+
     pub fn synchronize() -> Result<(), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.replica
@@ -72,6 +225,119 @@ impl KernelNode {
             })
     }
 
+    /// Adds a process for `binary` to the process table.
+    pub fn allocate_pid(binary: &'static str) -> Result<Pid, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::AllocatePid(binary), *token);
+
+                match response {
+                    Ok(NodeResult::PidAllocated(pid)) => {
+                        KernelNode::publish_processes();
+                        Ok(pid)
+                    }
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Removes a process from the process table (and releases its cores).
+    pub fn free_pid(pid: Pid) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::FreePid(pid), *token);
+
+                match response {
+                    Ok(NodeResult::PidReturned) => {
+                        KernelNode::publish_processes();
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Returns what should run on core `gtid`.
+    pub fn current_process(gtid: atopology::GlobalThreadId) -> Result<CoreInfo, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(ReadOps::CurrentProcess(gtid), *token);
+
+                match response {
+                    Ok(NodeResult::CoreInfo(ci)) => Ok(ci),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Returns the NUMA node of core `gtid`.
+    pub fn core_node(gtid: atopology::GlobalThreadId) -> Result<atopology::NodeId, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(ReadOps::CoreNode(gtid), *token);
+
+                match response {
+                    Ok(NodeResult::CoreNode(node)) => Ok(node),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Returns all processes (sorted by Pid).
+    pub fn processes() -> Result<Vec<(Pid, ProcessEntry)>, KError> {
+        let guard = rcu::read_lock();
+        let published = PROCESSES.get(&guard).map_or(&[][..], |p| &p[..]);
+        let mut processes = Vec::try_with_capacity(published.len())?;
+        processes.extend_from_slice(published);
+        Ok(processes)
+    }
+
+    /// Returns what the process table knows about process `pid`.
+    pub fn process(pid: Pid) -> Result<ProcessEntry, KError> {
+        let guard = rcu::read_lock();
+        let processes = PROCESSES.get(&guard).ok_or(KError::NoProcessFoundForPid)?;
+        processes
+            .binary_search_by_key(&pid, |(other, _entry)| *other)
+            .map(|idx| processes[idx].1)
+            .map_err(|_idx| KError::NoProcessFoundForPid)
+    }
+
+    /// Publishes the process table of our replica for `processes` and
+    /// `process`, called after updates that change it.
+    ///
+    /// The update already happened, so this doesn't fail: if we can't copy
+    /// the table the old copy stays until the next update publishes it.
+    fn publish_processes() {
+        let _publishing = PUBLISHING.lock();
+        let kcb = super::kcb::get_kcb();
+        let response = kcb
+            .replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                replica.execute(ReadOps::Processes, *token)
+            });
+        match response {
+            Ok(NodeResult::Processes(processes)) => PROCESSES.publish(Box::new(processes)),
+            Err(e) => error!(
+                "Can't publish the process table, keeping the old one: {}",
+                e
+            ),
+            Ok(_) => unreachable!("Got unexpected response"),
+        }
+    }
+
     pub fn allocate_core_to_process(
         pid: Pid,
         entry_point: VAddr,
@@ -86,12 +352,147 @@ impl KernelNode {
                 let response = replica.execute_mut(op, *token);
 
                 match response {
-                    Ok(NodeResult::CoreAllocated(rgtid)) => Ok(rgtid),
+                    Ok(NodeResult::CoreAllocated(rgtid)) => {
+                        KernelNode::publish_processes();
+                        Ok(rgtid)
+                    }
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    pub fn release_core_from_process(
+        pid: Pid,
+        gtid: atopology::GlobalThreadId,
+    ) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let op = Op::SchedReleaseCore(pid, gtid);
+                let response = replica.execute_mut(op, *token);
+
+                match response {
+                    Ok(NodeResult::CoreReleased) => {
+                        KernelNode::publish_processes();
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Returns how cores are picked for processes that take any.
+    pub fn core_policy() -> Result<CorePolicy, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(ReadOps::CorePolicy, *token);
+
+                match response {
+                    Ok(NodeResult::CorePolicy(policy)) => Ok(policy),
                     Err(e) => Err(e),
                     Ok(_) => unreachable!("Got unexpected response"),
                 }
             })
     }
+
+    /// Switches how cores are picked, returns the previous policy.
+    pub fn set_core_policy(policy: CorePolicy) -> Result<CorePolicy, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::SetCorePolicy(policy), *token);
+
+                match response {
+                    Ok(NodeResult::CorePolicy(previous)) => Ok(previous),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Tells the replicas core `gtid` was parked or came back.
+    pub fn set_core_online(gtid: atopology::GlobalThreadId, online: bool) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::SchedSetOnline(gtid, online), *token);
+
+                match response {
+                    Ok(NodeResult::CoreOnline) => Ok(()),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Puts process `pid` in gang mode (or takes it out).
+    pub fn set_gang(pid: Pid, gang: bool) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::SetGang(pid, gang), *token);
+
+                match response {
+                    Ok(NodeResult::GangSet) => {
+                        KernelNode::publish_processes();
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Returns the cores of process `pid` (sorted) if it's in gang mode,
+    /// nothing otherwise.
+    pub fn gang(pid: Pid) -> Result<Vec<atopology::GlobalThreadId>, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(ReadOps::Gang(pid), *token);
+
+                match response {
+                    Ok(NodeResult::Gang(cores)) => Ok(cores),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Returns all processes and which process every allocated core belongs
+    /// to.
+    pub fn scheduling() -> Result<(Vec<Pid>, Vec<(atopology::GlobalThreadId, Pid)>), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(ReadOps::Scheduling, *token);
+
+                match response {
+                    Ok(NodeResult::Scheduling(pids, cores)) => Ok((pids, cores)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+}
+
+/// Generates `/proc/processes` (every process, its binary and cores).
+pub fn proc_processes(out: &mut String) -> fmt::Result {
+    writeln!(out, "{:>5} {:>5} binary", "pid", "cores")?;
+    for (pid, process) in KernelNode::processes().map_err(|_e| fmt::Error)? {
+        writeln!(out, "{:>5} {:>5} {}", pid, process.cores, process.binary)?;
+    }
+    Ok(())
 }
 
 impl Dispatch for KernelNode {
@@ -108,51 +509,338 @@ impl Dispatch for KernelNode {
                     .ok_or(KError::NoExecutorForCore)?;
                 Ok(NodeResult::CoreInfo(*core_info))
             }
+            ReadOps::Scheduling => {
+                let mut pids = Vec::try_with_capacity(self.process_map.len())?;
+                pids.extend(self.process_map.keys().copied());
+                pids.sort_unstable();
+
+                let mut cores = Vec::try_with_capacity(self.scheduler_map.len())?;
+                cores.extend(self.scheduler_map.iter().map(|(gtid, ci)| (*gtid, ci.pid)));
+                cores.sort_unstable();
+
+                Ok(NodeResult::Scheduling(pids, cores))
+            }
+            ReadOps::Processes => {
+                let mut processes = Vec::try_with_capacity(self.process_map.len())?;
+                processes.extend(self.process_map.iter().map(|(pid, entry)| (*pid, *entry)));
+                processes.sort_unstable_by_key(|(pid, _entry)| *pid);
+                Ok(NodeResult::Processes(processes))
+            }
+            ReadOps::CoreNode(gtid) => self
+                .topology
+                .binary_search_by_key(&gtid, |core| core.gtid)
+                .map(|idx| NodeResult::CoreNode(self.topology[idx].node))
+                .map_err(|_idx| KError::InvalidGlobalThreadId),
+            ReadOps::CorePolicy => Ok(NodeResult::CorePolicy(self.policy)),
+            ReadOps::Gang(pid) => {
+                let process = self
+                    .process_map
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                if !process.gang {
+                    return Ok(NodeResult::Gang(Vec::new()));
+                }
+
+                let mut cores = Vec::try_with_capacity(process.cores)?;
+                cores.extend(
+                    self.scheduler_map
+                        .iter()
+                        .filter(|(_gtid, ci)| ci.pid == pid)
+                        .map(|(gtid, _ci)| *gtid),
+                );
+                cores.sort_unstable();
+                Ok(NodeResult::Gang(cores))
+            }
         }
     }
 
     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+        KERNEL_LOG.applied();
         match op {
-            Op::AllocatePid => {
+            Op::AllocatePid(binary) => {
                 // TODO(performance): O(n) scan probably not what we really
                 // want, fine for now, MAX_PROCESSES is tiny
                 for i in 0..MAX_PROCESSES {
                     if !self.process_map.contains_key(&i) {
                         self.process_map.try_reserve(1)?;
-                        let r = self.process_map.insert(i, ());
+                        let r = self.process_map.insert(
+                            i,
+                            ProcessEntry {
+                                binary,
+                                cores: 0,
+                                gang: false,
+                            },
+                        );
                         assert!(r.is_none(), "!contains_key");
                         return Ok(NodeResult::PidAllocated(i));
                     }
                 }
                 Err(KError::OutOfPids)
             }
-            // TODO: better impl, what about scheduler_map?
             Op::FreePid(pid) => match self.process_map.remove(&pid) {
-                Some(_) => Ok(NodeResult::PidReturned),
+                Some(_) => {
+                    self.scheduler_map.retain(|_gtid, cinfo| cinfo.pid != pid);
+                    let scheduler_map = &self.scheduler_map;
+                    for core in self.topology.iter_mut() {
+                        core.allocated = scheduler_map.contains_key(&core.gtid);
+                    }
+                    Ok(NodeResult::PidReturned)
+                }
                 None => {
                     error!("Process not found");
                     Err(KError::NoProcessFoundForPid)
                 }
             },
-            Op::SchedAllocateCore(pid, _affinity, Some(gtid), entry_point) => {
+            Op::SchedAllocateCore(pid, affinity, gtid, entry_point) => {
+                let gtid = match gtid {
+                    Some(gtid) => gtid,
+                    None => self
+                        .free_core(affinity)
+                        .ok_or(KError::CoreAlreadyAllocated)?,
+                };
                 assert!((gtid as usize) < MAX_CORES, "Invalid gtid");
 
                 match self.scheduler_map.get(&gtid) {
                     Some(_cinfo) => Err(KError::CoreAlreadyAllocated),
                     None => {
                         trace!("Op::SchedAllocateCore pid={}, gtid={}", pid, gtid);
-
                         self.scheduler_map.try_reserve(1)?;
+                        let process = self
+                            .process_map
+                            .get_mut(&pid)
+                            .ok_or(KError::NoProcessFoundForPid)?;
+                        process.cores += 1;
+
                         let r = self
                             .scheduler_map
                             .insert(gtid, CoreInfo { pid, entry_point });
                         assert!(r.is_none(), "get() -> None");
+                        if let Some(core) = self.topology_mut(gtid) {
+                            core.allocated = true;
+                        }
 
                         Ok(NodeResult::CoreAllocated(gtid))
                     }
                 }
             }
-            Op::SchedAllocateCore(_pid, _affinity, _gtid, _entry_point) => unimplemented!(),
+            Op::SchedReleaseCore(pid, gtid) => match self.scheduler_map.get(&gtid) {
+                Some(cinfo) if cinfo.pid == pid => {
+                    trace!("Op::SchedReleaseCore pid={}, gtid={}", pid, gtid);
+                    self.scheduler_map.remove(&gtid);
+                    if let Some(process) = self.process_map.get_mut(&pid) {
+                        process.cores -= 1;
+                    }
+                    if let Some(core) = self.topology_mut(gtid) {
+                        core.allocated = false;
+                    }
+                    Ok(NodeResult::CoreReleased)
+                }
+                _ => Err(KError::NoExecutorForCore),
+            },
+            Op::SchedSetOnline(gtid, online) => {
+                let core = self
+                    .topology_mut(gtid)
+                    .ok_or(KError::InvalidGlobalThreadId)?;
+                core.online = online;
+                Ok(NodeResult::CoreOnline)
+            }
+            Op::SetCorePolicy(policy) => {
+                let previous = core::mem::replace(&mut self.policy, policy);
+                Ok(NodeResult::CorePolicy(previous))
+            }
+            Op::SetGang(pid, gang) => {
+                let process = self
+                    .process_map
+                    .get_mut(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                process.gang = gang;
+                Ok(NodeResult::GangSet)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn node() -> KernelNode {
+        let topology = vec![(2, 1), (0, 0), (3, 1), (1, 0)];
+        KernelNode::new(
+            topology
+                .into_iter()
+                .map(|(gtid, node)| Core {
+                    gtid,
+                    node,
+                    package: node,
+                    core: gtid,
+                    allocated: false,
+                    online: true,
+                })
+                .collect(),
+        )
+    }
+
+    fn spawn(kn: &mut KernelNode, binary: &'static str) -> Pid {
+        match kn.dispatch_mut(Op::AllocatePid(binary)) {
+            Ok(NodeResult::PidAllocated(pid)) => pid,
+            r => panic!("Unexpected {:?}", r),
         }
     }
+
+    fn allocate(
+        kn: &mut KernelNode,
+        pid: Pid,
+        affinity: Option<atopology::NodeId>,
+        gtid: Option<atopology::GlobalThreadId>,
+    ) -> Result<atopology::GlobalThreadId, KError> {
+        match kn.dispatch_mut(Op::SchedAllocateCore(pid, affinity, gtid, VAddr::zero())) {
+            Ok(NodeResult::CoreAllocated(gtid)) => Ok(gtid),
+            Ok(r) => panic!("Unexpected {:?}", r),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn processes(kn: &KernelNode) -> Vec<(Pid, ProcessEntry)> {
+        match kn.dispatch(ReadOps::Processes) {
+            Ok(NodeResult::Processes(processes)) => processes,
+            r => panic!("Unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn allocate_cores() {
+        let mut kn = node();
+        let pid = spawn(&mut kn, "init");
+
+        assert_eq!(allocate(&mut kn, pid, None, Some(0)), Ok(0));
+        assert_eq!(
+            allocate(&mut kn, pid, None, Some(0)),
+            Err(KError::CoreAlreadyAllocated)
+        );
+        // Any free core on the node
+        assert_eq!(allocate(&mut kn, pid, Some(1), None), Ok(2));
+        assert_eq!(allocate(&mut kn, pid, Some(1), None), Ok(3));
+        assert_eq!(
+            allocate(&mut kn, pid, Some(1), None),
+            Err(KError::CoreAlreadyAllocated)
+        );
+        assert_eq!(allocate(&mut kn, pid, None, None), Ok(1));
+        assert_eq!(
+            allocate(&mut kn, 7, None, None),
+            Err(KError::CoreAlreadyAllocated)
+        );
+
+        assert_eq!(
+            processes(&kn),
+            vec![(
+                pid,
+                ProcessEntry {
+                    binary: "init",
+                    cores: 4,
+                    gang: false,
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn free_pid() {
+        let mut kn = node();
+        let init = spawn(&mut kn, "init");
+        let other = spawn(&mut kn, "other");
+        assert_ne!(init, other);
+        assert_eq!(allocate(&mut kn, init, None, Some(0)), Ok(0));
+        assert_eq!(allocate(&mut kn, other, None, Some(1)), Ok(1));
+
+        assert!(kn.dispatch_mut(Op::FreePid(other)).is_ok());
+        assert_eq!(
+            kn.dispatch_mut(Op::FreePid(other)).err(),
+            Some(KError::NoProcessFoundForPid)
+        );
+        // The cores of `other` are free again
+        assert!(matches!(
+            kn.dispatch(ReadOps::CurrentProcess(1)),
+            Err(KError::NoExecutorForCore)
+        ));
+        assert!(matches!(
+            kn.dispatch(ReadOps::CurrentProcess(0)),
+            Ok(NodeResult::CoreInfo(CoreInfo { pid, .. })) if pid == init
+        ));
+        assert_eq!(processes(&kn).len(), 1);
+    }
+
+    #[test]
+    fn core_policy() {
+        let mut kn = node();
+        let pid = spawn(&mut kn, "init");
+        assert!(matches!(
+            kn.dispatch_mut(Op::SetCorePolicy(CorePolicy::Spread)),
+            Ok(NodeResult::CorePolicy(CorePolicy::Packed))
+        ));
+        assert!(matches!(
+            kn.dispatch(ReadOps::CorePolicy),
+            Ok(NodeResult::CorePolicy(CorePolicy::Spread))
+        ));
+
+        // Parked cores aren't handed out
+        assert!(kn.dispatch_mut(Op::SchedSetOnline(2, false)).is_ok());
+        assert_eq!(allocate(&mut kn, pid, None, Some(0)), Ok(0));
+        assert_eq!(allocate(&mut kn, pid, None, None), Ok(3));
+        assert_eq!(allocate(&mut kn, pid, None, None), Ok(1));
+        assert_eq!(
+            allocate(&mut kn, pid, None, None),
+            Err(KError::CoreAlreadyAllocated)
+        );
+
+        // Released cores can be picked again
+        assert!(kn.dispatch_mut(Op::SchedReleaseCore(pid, 3)).is_ok());
+        assert!(kn.dispatch_mut(Op::SchedSetOnline(2, true)).is_ok());
+        assert_eq!(allocate(&mut kn, pid, None, None), Ok(2));
+        assert!(matches!(
+            kn.dispatch_mut(Op::SchedSetOnline(4, true)),
+            Err(KError::InvalidGlobalThreadId)
+        ));
+    }
+
+    #[test]
+    fn gang() {
+        let mut kn = node();
+        let pid = spawn(&mut kn, "init");
+        let other = spawn(&mut kn, "other");
+        assert_eq!(allocate(&mut kn, pid, None, Some(3)), Ok(3));
+        assert_eq!(allocate(&mut kn, other, None, Some(2)), Ok(2));
+        assert_eq!(allocate(&mut kn, pid, None, Some(0)), Ok(0));
+        assert!(matches!(
+            kn.dispatch(ReadOps::Gang(pid)),
+            Ok(NodeResult::Gang(cores)) if cores.is_empty()
+        ));
+
+        assert!(kn.dispatch_mut(Op::SetGang(pid, true)).is_ok());
+        assert!(matches!(
+            kn.dispatch(ReadOps::Gang(pid)),
+            Ok(NodeResult::Gang(cores)) if cores == vec![0, 3]
+        ));
+        assert!(matches!(
+            kn.dispatch(ReadOps::Gang(other)),
+            Ok(NodeResult::Gang(cores)) if cores.is_empty()
+        ));
+        assert!(matches!(
+            kn.dispatch_mut(Op::SetGang(7, true)),
+            Err(KError::NoProcessFoundForPid)
+        ));
+    }
+
+    #[test]
+    fn core_node() {
+        let kn = node();
+        assert!(matches!(
+            kn.dispatch(ReadOps::CoreNode(3)),
+            Ok(NodeResult::CoreNode(1))
+        ));
+        assert!(matches!(
+            kn.dispatch(ReadOps::CoreNode(4)),
+            Err(KError::InvalidGlobalThreadId)
+        ));
+    }
 }
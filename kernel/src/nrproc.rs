@@ -7,16 +7,22 @@ use alloc::vec::Vec;
 use core::alloc::Allocator;
 
 use fallible_collections::vec::FallibleVec;
-use kpi::process::{FrameId, ProcessInfo};
+use fallible_collections::FallibleVecGlobal;
+use kpi::cap::CapRights;
+use kpi::process::{AddressLayout, Credentials, FrameId, ProcessInfo, MMAP_SIZE};
+use log::warn;
 use node_replication::Dispatch;
 
 use crate::arch::process::PROCESS_TABLE;
 use crate::arch::Module;
+use crate::cap::{CapTable, Capability, Object};
 use crate::error::KError;
 use crate::memory::detmem::DA;
-use crate::memory::vspace::{AddressSpace, MapAction, TlbFlushHandle};
-use crate::memory::{Frame, PAddr, VAddr};
+use crate::memory::vspace::{AddressSpace, MapAction, Region, TlbFlushHandle};
+use crate::memory::{Frame, PAddr, VAddr, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+use crate::nr::LogProgress;
 use crate::process::{Eid, Executor, Pid, Process, MAX_PROCESSES};
+use crate::round_up;
 
 use crate::kcb::{ArchSpecificKcb, Kcb};
 
@@ -25,17 +31,43 @@ use crate::kcb::{ArchSpecificKcb, Kcb};
 pub enum ReadOps {
     ProcessInfo,
     MemResolve(VAddr),
+    /// Where the mapping at the address starts and how large it is.
+    MemExtent(VAddr),
+    /// Whether the mapping at the address was accessed (clears the bit).
+    MemAccessed(VAddr),
+    /// Marks the mapping at the address as written.
+    MemSetDirty(VAddr),
+    /// All mappings of the process.
+    Regions,
+    /// Where the next reservation in the mapping region goes.
+    MmapNext,
+    /// The cores the process runs on (and the executor on each).
+    ActiveCores,
+    /// How many unmaps the process did so far.
+    UnmapGeneration,
+    /// The frame registered with a `FrameId`.
+    Frame(FrameId),
+    /// What a handle refers to.
+    Capability(u64),
+    /// All capabilities of the process with their handles.
+    Capabilities,
 }
 
 /// Mutable operations on the NrProcess.
 #[derive(PartialEq, Clone, Debug)]
 pub enum Op {
     ProcRaiseIrq,
-    Load(Pid, &'static Module, Vec<Frame>),
+    Load(Pid, Credentials, AddressLayout, &'static Module, Vec<Frame>),
 
     /// Assign a core to a process.
     AssignExecutor(atopology::NodeId, atopology::GlobalThreadId),
+    /// The process no longer runs on a core.
+    ReleaseExecutor(atopology::GlobalThreadId),
+    /// Hand out this executor next (returns its node).
+    PreferExecutor(Eid),
 
+    /// Forget the process (it can't run anywhere), returns the frames it
+    /// owned (see `Process::destroy`).
     Destroy,
 
     /// Assign a physical frame to a process (returns a FrameId).
@@ -46,27 +78,63 @@ pub enum Op {
     MemMapFrame(VAddr, Frame, MapAction),
     MemMapDevice(Frame, MapAction),
     MemMapFrameId(VAddr, FrameId, MapAction),
-    MemAdjust,
+    MemAdjust(VAddr, MapAction),
     MemUnmap(VAddr),
+    /// Find room for a mapping of this size in the mapping region.
+    MemReserve(usize),
+
+    /// Add a capability (returns its handle).
+    CapInsert(Capability),
+    /// Add a capability with a given handle.
+    CapInsertAt(u64, Capability),
+    /// Drop rights of a capability.
+    CapRestrict(u64, CapRights),
+    /// Remove a capability.
+    CapRemove(u64),
+    /// Remove all capabilities for an object.
+    CapRemoveObject(Object),
 }
 
 /// Possible return values from the NrProcess.
 #[derive(Debug, Clone)]
 pub enum NodeResult<E: Executor> {
     Loaded,
-    Destroyed,
+    Destroyed(Vec<Frame>),
     ProcessInfo(ProcessInfo),
     Executor(Box<E>),
+    ExecutorReleased,
+    ExecutorPreferred(atopology::NodeId),
     VectorAllocated(u64),
     ExecutorsCreated(usize),
     Mapped,
+    Reserved(VAddr),
     MappedFrameId(PAddr, usize),
-    Adjusted,
-    Unmapped(TlbFlushHandle),
+    Adjusted(TlbFlushHandle),
+    /// The unmapped region and the unmap generation that unmapped it.
+    Unmapped(TlbFlushHandle, u64),
     Resolved(PAddr, MapAction),
+    Extent(VAddr, usize),
+    Accessed(bool),
+    MarkedDirty,
+    Regions(Vec<Region>),
     FrameId(usize),
+    ActiveCores(Vec<(atopology::GlobalThreadId, Eid)>),
+    UnmapGeneration(u64),
+    Frame(Frame),
+    Capability(Capability),
+    Capabilities(Vec<(u64, Capability)>),
+    CapInserted(u64),
+    CapRestricted,
+    CapRemoved(Capability),
+    CapsRemoved,
 }
 
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_PROGRESS: LogProgress = LogProgress::new();
+
+/// How far the replicas got on the log of every process.
+pub static PROCESS_LOGS: [LogProgress; MAX_PROCESSES] = [NO_PROGRESS; MAX_PROCESSES];
+
 /// Advances the replica of all the processes on the current NUMA node.
 pub fn advance_all() {
     let kcb = super::kcb::get_kcb();
@@ -79,16 +147,30 @@ pub fn advance_all() {
 
 /// A node-replicated process.
 pub struct NrProcess<P: Process, M: Allocator + Clone = alloc::alloc::Global> {
+    /// Which process it is (for `PROCESS_LOGS`).
+    pid: Pid,
     /// A list of all cores where the current process is running.
     active_cores: Vec<(atopology::GlobalThreadId, Eid), M>,
+    /// How many unmaps were applied to the address space, cores compare it
+    /// with what their TLB saw (see `arch::tlb::sync_translations`).
+    unmaps: u64,
+    /// What the handles of the process refer to.
+    caps: CapTable,
+    /// Where the next mapping in the mapping region goes and where the
+    /// region ends (see `AddressLayout`).
+    mmap: (VAddr, VAddr),
     /// The process struct itself.
     process: Box<P>,
 }
 
 impl<P: Process> NrProcess<P> {
-    pub fn new(process: Box<P>, _da: DA) -> NrProcess<P> {
+    pub fn new(pid: Pid, process: Box<P>, _da: DA) -> NrProcess<P> {
         NrProcess {
+            pid,
             active_cores: Vec::new(),
+            unmaps: 0,
+            caps: CapTable::default(),
+            mmap: (VAddr::zero(), VAddr::zero()),
             process,
         }
     }
@@ -97,6 +179,8 @@ impl<P: Process> NrProcess<P> {
 impl<P: Process> NrProcess<P> {
     pub fn load(
         pid: Pid,
+        creds: Credentials,
+        layout: AddressLayout,
         module: &'static Module,
         writeable_sections: Vec<Frame>,
     ) -> Result<(), KError> {
@@ -106,7 +190,7 @@ impl<P: Process> NrProcess<P> {
         let node = kcb.arch.node();
 
         let response = PROCESS_TABLE[node][pid].execute_mut(
-            Op::Load(pid, module, writeable_sections),
+            Op::Load(pid, creds, layout, module, writeable_sections),
             kcb.process_token[pid],
         );
         match response {
@@ -132,6 +216,109 @@ impl<P: Process> NrProcess<P> {
         }
     }
 
+    /// The physical address `base` maps to and the rights of its mapping.
+    pub fn mapping(pid: Pid, base: VAddr) -> Result<(PAddr, MapAction), KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+        debug_assert!(base.as_u64() < kpi::KERNEL_BASE, "Invalid base");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute(ReadOps::MemResolve(base), kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::Resolved(paddr, rights)) => Ok((paddr, rights)),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Where the mapping that contains `base` starts and how large it is.
+    pub fn extent(pid: Pid, base: VAddr) -> Result<(VAddr, usize), KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+        debug_assert!(base.as_u64() < kpi::KERNEL_BASE, "Invalid base");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute(ReadOps::MemExtent(base), kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::Extent(start, size)) => Ok((start, size)),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Whether the mapping at `base` was accessed since the last call.
+    ///
+    /// This only looks at the page-table of the local replica, so it doesn't
+    /// see accesses from cores on other NUMA nodes.
+    pub fn accessed(pid: Pid, base: VAddr) -> Result<bool, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+        debug_assert!(base.as_u64() < kpi::KERNEL_BASE, "Invalid base");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute(ReadOps::MemAccessed(base), kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::Accessed(accessed)) => Ok(accessed),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Marks the mapping at `base` as written (in the page-table of the
+    /// local replica, like `accessed`).
+    pub fn set_dirty(pid: Pid, base: VAddr) -> Result<(), KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+        debug_assert!(base.as_u64() < kpi::KERNEL_BASE, "Invalid base");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute(ReadOps::MemSetDirty(base), kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::MarkedDirty) => Ok(()),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// All mappings of process `pid`, whether they are dirty is what the
+    /// page-table of the local replica says.
+    pub fn regions(pid: Pid) -> Result<Vec<Region>, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response = PROCESS_TABLE[node][pid].execute(ReadOps::Regions, kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::Regions(regions)) => Ok(regions),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Where the next reservation in the mapping region of `pid` goes.
+    pub fn mmap_next(pid: Pid) -> Result<VAddr, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response = PROCESS_TABLE[node][pid].execute(ReadOps::MmapNext, kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::Reserved(next)) => Ok(next),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
     pub fn synchronize(pid: Pid) {
         debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
         let kcb = super::kcb::get_kcb();
@@ -159,7 +346,9 @@ impl<P: Process> NrProcess<P> {
         }
     }
 
-    pub fn unmap(pid: Pid, base: VAddr) -> Result<TlbFlushHandle, KError> {
+    /// Unmaps the mapping at `base`, returns it with the unmap generation of
+    /// the process after the unmap.
+    pub fn unmap(pid: Pid, base: VAddr) -> Result<(TlbFlushHandle, u64), KError> {
         debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
 
         let kcb = super::kcb::get_kcb();
@@ -168,7 +357,59 @@ impl<P: Process> NrProcess<P> {
         let response =
             PROCESS_TABLE[node][pid].execute_mut(Op::MemUnmap(base), kcb.process_token[pid]);
         match response {
-            Ok(NodeResult::Unmapped(handle)) => Ok(handle),
+            Ok(NodeResult::Unmapped(handle, generation)) => Ok((handle, generation)),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Finds room for a mapping of `size` bytes in the mapping region of
+    /// the process (addresses are never handed out twice).
+    pub fn reserve(pid: Pid, size: usize) -> Result<VAddr, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute_mut(Op::MemReserve(size), kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::Reserved(base)) => Ok(base),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// How many unmaps the process did so far.
+    ///
+    /// Like every read this applies the outstanding operations of the log to
+    /// the local replica first: it includes all unmaps that returned (on any
+    /// core) before we asked.
+    pub fn unmap_generation(pid: Pid) -> Result<u64, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute(ReadOps::UnmapGeneration, kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::UnmapGeneration(generation)) => Ok(generation),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    pub fn adjust(pid: Pid, base: VAddr, action: MapAction) -> Result<TlbFlushHandle, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response = PROCESS_TABLE[node][pid]
+            .execute_mut(Op::MemAdjust(base, action), kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::Adjusted(handle)) => Ok(handle),
             Err(e) => Err(e),
             _ => unreachable!("Got unexpected response"),
         }
@@ -208,13 +449,24 @@ impl<P: Process> NrProcess<P> {
         let node = kcb.arch.node();
 
         let mut virtual_offset = 0;
-        for frame in frames {
+        let mut frames = frames.into_iter();
+        while let Some(frame) = frames.next() {
             let response = PROCESS_TABLE[node][pid].execute_mut(
                 Op::MemMapFrame(base + virtual_offset, frame, action),
                 kcb.process_token[pid],
             );
             match response {
                 Ok(NodeResult::Mapped) => {}
+                Err(e) => {
+                    // What we mapped stays mapped (and the process can unmap
+                    // it), the rest nobody uses
+                    for frame in core::iter::once(frame).chain(frames) {
+                        if let Err(e) = crate::memory::release_frame(frame) {
+                            warn!("Leaking {:?}: {}", frame, e);
+                        }
+                    }
+                    return Err(e);
+                }
                 e => unreachable!(
                     "Got unexpected response MemMapFrame {:?} {:?} {:?} {:?}",
                     e,
@@ -245,6 +497,26 @@ impl<P: Process> NrProcess<P> {
         }
     }
 
+    /// Who process `pid` runs as.
+    pub fn credentials(pid: Pid) -> Result<Credentials, KError> {
+        NrProcess::<P>::pinfo(pid).map(|pinfo| pinfo.creds)
+    }
+
+    pub fn active_cores(pid: Pid) -> Result<Vec<(atopology::GlobalThreadId, Eid)>, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute(ReadOps::ActiveCores, kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::ActiveCores(cores)) => Ok(cores),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
     pub fn allocate_executor<A>(kcb: &Kcb<A>, pid: Pid) -> Result<Box<P::E>, KError>
     where
         A: ArchSpecificKcb<Process = P>,
@@ -264,6 +536,59 @@ impl<P: Process> NrProcess<P> {
         }
     }
 
+    /// Forgets that the process runs on `gtid` (so it no longer gets TLB
+    /// shootdowns there).
+    pub fn release_executor(pid: Pid, gtid: atopology::GlobalThreadId) -> Result<(), KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute_mut(Op::ReleaseExecutor(gtid), kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::ExecutorReleased) => Ok(()),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Makes executor `eid` the one the next core of `pid` gets on its
+    /// node, returns the node.
+    pub fn prefer_executor(pid: Pid, eid: Eid) -> Result<atopology::NodeId, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute_mut(Op::PreferExecutor(eid), kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::ExecutorPreferred(node)) => Ok(node),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Resets process `pid` so the pid can be used again, returns the frames
+    /// of its binary and executors for the caller to release.
+    ///
+    /// Other frames it maps stay with whoever gave them to the process, the
+    /// process must not run anywhere.
+    pub fn destroy(pid: Pid) -> Result<Vec<Frame>, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response = PROCESS_TABLE[node][pid].execute_mut(Op::Destroy, kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::Destroyed(frames)) => Ok(frames),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
     pub fn allocate_frame_to_process(pid: Pid, frame: Frame) -> Result<FrameId, KError> {
         debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
 
@@ -279,6 +604,138 @@ impl<P: Process> NrProcess<P> {
         }
     }
 
+    /// The frame process `pid` registered as `fid`.
+    pub fn frame(pid: Pid, fid: FrameId) -> Result<Frame, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute(ReadOps::Frame(fid), kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::Frame(frame)) => Ok(frame),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// The capability `handle` of process `pid`.
+    pub fn capability(pid: Pid, handle: u64) -> Result<Capability, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute(ReadOps::Capability(handle), kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::Capability(capability)) => Ok(capability),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Gives process `pid` a capability, returns its handle.
+    pub fn insert_capability(pid: Pid, capability: Capability) -> Result<u64, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute_mut(Op::CapInsert(capability), kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::CapInserted(handle)) => Ok(handle),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Gives process `pid` a capability as `handle`.
+    pub fn insert_capability_at(
+        pid: Pid,
+        handle: u64,
+        capability: Capability,
+    ) -> Result<(), KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response = PROCESS_TABLE[node][pid]
+            .execute_mut(Op::CapInsertAt(handle, capability), kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::CapInserted(_handle)) => Ok(()),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// All capabilities of process `pid` with their handles.
+    pub fn capabilities(pid: Pid) -> Result<Vec<(u64, Capability)>, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute(ReadOps::Capabilities, kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::Capabilities(capabilities)) => Ok(capabilities),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Drops all rights of `handle` that aren't in `rights`.
+    pub fn restrict_capability(pid: Pid, handle: u64, rights: CapRights) -> Result<(), KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response = PROCESS_TABLE[node][pid]
+            .execute_mut(Op::CapRestrict(handle, rights), kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::CapRestricted) => Ok(()),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Takes the capability `handle` away from process `pid`.
+    pub fn remove_capability(pid: Pid, handle: u64) -> Result<Capability, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute_mut(Op::CapRemove(handle), kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::CapRemoved(capability)) => Ok(capability),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Takes all capabilities for `object` away from process `pid`.
+    pub fn remove_capabilities(pid: Pid, object: Object) -> Result<(), KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response = PROCESS_TABLE[node][pid]
+            .execute_mut(Op::CapRemoveObject(object), kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::CapsRemoved) => Ok(()),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
     pub fn allocate_dispatchers(pid: Pid, frame: Frame) -> Result<usize, KError> {
         debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
 
@@ -313,17 +770,57 @@ where
                 let (paddr, rights) = self.process.vspace().resolve(base)?;
                 Ok(NodeResult::Resolved(paddr, rights))
             }
+            ReadOps::MemExtent(base) => {
+                let (start, size) = self.process.vspace().extent(base)?;
+                Ok(NodeResult::Extent(start, size))
+            }
+            ReadOps::MemAccessed(base) => Ok(NodeResult::Accessed(
+                self.process.vspace().harvest_accessed(base)?,
+            )),
+            ReadOps::MemSetDirty(base) => {
+                self.process.vspace().set_dirty(base)?;
+                Ok(NodeResult::MarkedDirty)
+            }
+            ReadOps::Regions => Ok(NodeResult::Regions(self.process.vspace().regions()?)),
+            ReadOps::MmapNext => Ok(NodeResult::Reserved(self.mmap.0)),
+            ReadOps::ActiveCores => {
+                let mut cores = Vec::try_with_capacity(self.active_cores.len())?;
+                cores.extend(self.active_cores.iter().copied());
+                Ok(NodeResult::ActiveCores(cores))
+            }
+            ReadOps::UnmapGeneration => Ok(NodeResult::UnmapGeneration(self.unmaps)),
+            ReadOps::Frame(fid) => Ok(NodeResult::Frame(self.process.get_frame(fid)?)),
+            ReadOps::Capability(handle) => Ok(NodeResult::Capability(self.caps.get(handle)?)),
+            ReadOps::Capabilities => {
+                let mut capabilities = Vec::new();
+                for capability in self.caps.capabilities() {
+                    capabilities.try_push(capability)?;
+                }
+                Ok(NodeResult::Capabilities(capabilities))
+            }
         }
     }
 
     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+        PROCESS_LOGS[self.pid].applied();
         match op {
-            Op::Destroy => unimplemented!("Destrroy"),
+            Op::Destroy => {
+                if !self.active_cores.is_empty() {
+                    return Err(KError::ProcessRunning);
+                }
+                let frames = self.process.destroy()?;
+                self.caps = CapTable::default();
+                self.mmap = (VAddr::zero(), VAddr::zero());
+                // `unmaps` keeps counting, cores remember generations by pid
+                Ok(NodeResult::Destroyed(frames))
+            }
             Op::ProcRaiseIrq => unimplemented!("ProcRaiseIrq"),
-            Op::MemAdjust => unimplemented!("MemAdjust"),
 
-            Op::Load(pid, module, writeable_sections) => {
-                self.process.load(pid, module, writeable_sections)?;
+            Op::Load(pid, creds, layout, module, writeable_sections) => {
+                self.process
+                    .load(pid, creds, layout, module, writeable_sections)?;
+                let mmap_start = VAddr::from(layout.mmap_start);
+                self.mmap = (mmap_start, mmap_start + MMAP_SIZE);
                 Ok(NodeResult::Loaded)
             }
 
@@ -355,13 +852,47 @@ where
 
             Op::MemUnmap(vaddr) => {
                 let mut shootdown_handle = self.process.vspace_mut().unmap(vaddr)?;
+                self.unmaps += 1;
                 // Figure out which cores are running our current process
-                // (this is where we send IPIs later)
+                // (they may still have the mapping in their TLB)
                 for (gtid, _eid) in self.active_cores.iter() {
                     shootdown_handle.add_core(*gtid);
                 }
 
-                Ok(NodeResult::Unmapped(shootdown_handle))
+                Ok(NodeResult::Unmapped(shootdown_handle, self.unmaps))
+            }
+
+            Op::MemReserve(size) => {
+                // Mappings start with their large pages
+                let align = if size >= LARGE_PAGE_SIZE {
+                    LARGE_PAGE_SIZE
+                } else {
+                    BASE_PAGE_SIZE
+                };
+                let (next, end) = self.mmap;
+                let base = VAddr::from(round_up!(next.as_usize(), align));
+                let next = base + round_up!(size, BASE_PAGE_SIZE);
+                if size == 0 || next > end {
+                    return Err(KError::NotEnoughMemory);
+                }
+                self.mmap.0 = next;
+                Ok(NodeResult::Reserved(base))
+            }
+
+            Op::MemAdjust(vaddr, action) => {
+                let (paddr, old_action) = self.process.vspace().resolve(vaddr)?;
+                // A writable shadow stack is no shadow stack
+                if old_action == MapAction::ShadowStackUser {
+                    return Err(KError::PermissionError);
+                }
+                let (vaddr, size) = self.process.vspace_mut().adjust(vaddr, action)?;
+                // Cores running the process may have the old rights cached
+                let mut shootdown_handle = TlbFlushHandle::new(vaddr, Frame::new(paddr, size, 0));
+                for (gtid, _eid) in self.active_cores.iter() {
+                    shootdown_handle.add_core(*gtid);
+                }
+
+                Ok(NodeResult::Adjusted(shootdown_handle))
             }
 
             Op::AssignExecutor(gtid, region) => {
@@ -371,10 +902,34 @@ where
                 Ok(NodeResult::Executor(executor))
             }
 
+            Op::ReleaseExecutor(gtid) => {
+                self.active_cores.retain(|(core, _eid)| *core != gtid);
+                Ok(NodeResult::ExecutorReleased)
+            }
+
+            Op::PreferExecutor(eid) => Ok(NodeResult::ExecutorPreferred(
+                self.process.prefer_executor(eid)?,
+            )),
+
             Op::AllocateFrameToProcess(frame) => {
                 let fid = self.process.add_frame(frame)?;
                 Ok(NodeResult::FrameId(fid))
             }
+
+            Op::CapInsert(capability) => Ok(NodeResult::CapInserted(self.caps.insert(capability)?)),
+            Op::CapInsertAt(handle, capability) => {
+                self.caps.insert_at(handle, capability)?;
+                Ok(NodeResult::CapInserted(handle))
+            }
+            Op::CapRestrict(handle, rights) => {
+                self.caps.restrict(handle, rights)?;
+                Ok(NodeResult::CapRestricted)
+            }
+            Op::CapRemove(handle) => Ok(NodeResult::CapRemoved(self.caps.remove(handle)?)),
+            Op::CapRemoveObject(object) => {
+                self.caps.remove_object(object);
+                Ok(NodeResult::CapsRemoved)
+            }
         }
     }
 }
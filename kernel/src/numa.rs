@@ -0,0 +1,144 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The NUMA nodes as the kernel uses them.
+//!
+//! `atopology` tells us which cores are on which node, the ACPI tables
+//! (`crate::acpi`) how much memory every node has and how far apart they
+//! are. On real machines every node has both, but QEMU (`-numa`) can also
+//! make nodes that only have cores (memory-less) or only memory (CPU-less),
+//! give them different numbers of cores and put them at any distance:
+//!
+//! - A node without memory allocates from the nearest node that has some
+//!   ([`memory_node`]).
+//! - A node without cores doesn't get replicas: nothing would apply the log
+//!   on them, so the logs would fill up. Where we keep replicas by node it
+//!   uses the ones of the nearest node with cores ([`replica_node`]).
+//!
+//! Nearest is by distance (from the SLIT, see `acpi::Platform::distance`),
+//! ties go to the node with the lower id.
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use atopology::{NodeId, MACHINE_TOPOLOGY};
+
+use crate::acpi::Platform;
+
+/// The ACPI view of the nodes, if it numbers them like `atopology` does.
+fn platform() -> Option<&'static Platform> {
+    crate::acpi::platform().filter(|platform| platform.nodes.len() == MACHINE_TOPOLOGY.num_nodes())
+}
+
+/// How many nodes we have (at least one).
+pub fn nodes() -> usize {
+    core::cmp::max(1, MACHINE_TOPOLOGY.num_nodes())
+}
+
+/// Does `node` have cores?
+pub fn has_cores(node: NodeId) -> bool {
+    if MACHINE_TOPOLOGY.num_nodes() == 0 {
+        return node == 0;
+    }
+    MACHINE_TOPOLOGY
+        .nodes()
+        .nth(node)
+        .map_or(false, |n| n.threads().next().is_some())
+}
+
+/// Does `node` have memory (that isn't hot-pluggable)?
+///
+/// Without NUMA information from ACPI all memory is on node 0 (see
+/// `identify_numa_affinity`).
+pub fn has_memory(node: NodeId) -> bool {
+    match platform() {
+        Some(platform) => platform.nodes.get(node).map_or(false, |n| n.memory > 0),
+        None => node == 0,
+    }
+}
+
+/// Relative distance between two nodes (10 for the same node).
+pub fn distance(from: NodeId, to: NodeId) -> u8 {
+    match platform() {
+        Some(platform) => platform.distance(from, to),
+        None if from == to => 10,
+        None => 20,
+    }
+}
+
+/// The nearest of the `n` nodes (`node` itself if it can) that `can`.
+fn nearest(
+    node: NodeId,
+    n: usize,
+    distance: impl Fn(NodeId, NodeId) -> u8,
+    can: impl Fn(NodeId) -> bool,
+) -> Option<NodeId> {
+    if can(node) {
+        return Some(node);
+    }
+    (0..n)
+        .filter(|other| can(*other))
+        .min_by_key(|other| (distance(node, *other), *other))
+}
+
+/// The node `node` allocates memory from.
+pub fn memory_node(node: NodeId) -> NodeId {
+    nearest(node, nodes(), distance, has_memory).unwrap_or(0)
+}
+
+/// The node whose replicas `node` uses.
+pub fn replica_node(node: NodeId) -> NodeId {
+    nearest(node, nodes(), distance, has_cores).unwrap_or(0)
+}
+
+/// The most cores a node has.
+pub fn max_cores_per_node() -> usize {
+    MACHINE_TOPOLOGY
+        .nodes()
+        .map(|node| node.threads().count())
+        .max()
+        .unwrap_or_else(|| MACHINE_TOPOLOGY.num_threads())
+        .max(1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Distances like `-numa dist` gives them to QEMU for four nodes on a
+    /// line (0 - 1 - 2 - 3), except that 3 is closer to 0 than to 2.
+    fn line(from: NodeId, to: NodeId) -> u8 {
+        const D: [[u8; 4]; 4] = [
+            [10, 20, 30, 25],
+            [20, 10, 20, 30],
+            [30, 20, 10, 40],
+            [25, 30, 40, 10],
+        ];
+        D[from][to]
+    }
+
+    #[test]
+    fn nearest_is_itself() {
+        for node in 0..4 {
+            assert_eq!(nearest(node, 4, line, |_n| true), Some(node));
+        }
+    }
+
+    #[test]
+    fn nearest_by_distance() {
+        // Node 2 has no memory: 1 is nearer than 0 or 3
+        let memory = |n: NodeId| n != 2;
+        assert_eq!(nearest(2, 4, line, memory), Some(1));
+        // Only 0 and 2 have cores: 3 is nearer to 0 (asymmetric layout)
+        let cores = |n: NodeId| n == 0 || n == 2;
+        assert_eq!(nearest(3, 4, line, cores), Some(0));
+        assert_eq!(nearest(1, 4, line, cores), Some(0));
+    }
+
+    #[test]
+    fn nearest_ties_go_to_lower_id() {
+        let flat = |from: NodeId, to: NodeId| if from == to { 10 } else { 20 };
+        assert_eq!(nearest(3, 4, flat, |n| n != 3), Some(0));
+        assert_eq!(nearest(1, 4, flat, |n| n >= 2), Some(2));
+        assert_eq!(nearest(1, 4, flat, |_n| false), None);
+    }
+}
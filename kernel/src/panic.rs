@@ -4,6 +4,8 @@
 #[cfg(target_os = "none")]
 use core::alloc::Layout;
 #[cfg(target_os = "none")]
+use core::fmt::Write;
+#[cfg(target_os = "none")]
 use core::panic::PanicInfo;
 
 #[cfg(target_os = "none")]
@@ -13,7 +15,10 @@ use crate::kcb;
 use crate::ExitReason;
 use addr2line::{gimli, Context};
 use alloc::rc::Rc;
+#[cfg(target_os = "none")]
+use arrayvec::ArrayString;
 use klogger::{sprint, sprintln};
+use rustc_demangle::demangle;
 
 //pub type EndianRcSlice<gimli::Endian> = gimli::EndianReader<gimli::Endian, Rc<[u8]>>;
 
@@ -63,27 +68,101 @@ fn new_ctxt(
     .ok()
 }
 
+/// Size of an `Elf64_Sym` entry.
+const ELF64_SYM_SIZE: usize = 24;
+/// `STT_FUNC` (the low nibble of `st_info`).
+const STT_FUNC: u8 = 2;
+
+/// The function symbols of the kernel ELF (`.symtab` and `.strtab`).
+///
+/// Unlike the DWARF information (see `new_ctxt`) this doesn't allocate, so
+/// it works in release builds without debug info, for OOM and from NMIs.
+pub struct SymbolTable<'a> {
+    symtab: &'a [u8],
+    strtab: &'a [u8],
+}
+
+impl<'a> SymbolTable<'a> {
+    pub fn new(elf: &elfloader::ElfBinary<'a>) -> Option<SymbolTable<'a>> {
+        let section = |name| {
+            elf.file
+                .find_section_by_name(name)
+                .map(|s| s.raw_data(&elf.file))
+        };
+        Some(SymbolTable {
+            symtab: section(".symtab")?,
+            strtab: section(".strtab")?,
+        })
+    }
+
+    fn name(&self, offset: usize) -> Option<&'a str> {
+        let bytes = self.strtab.get(offset..)?;
+        let len = bytes.iter().position(|b| *b == 0)?;
+        core::str::from_utf8(&bytes[..len]).ok()
+    }
+
+    /// The (mangled) name of the function that contains the link-time
+    /// address `addr`, and how far into the function `addr` is.
+    pub fn lookup(&self, addr: u64) -> Option<(&'a str, u64)> {
+        let u64_at = |sym: &[u8], at: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&sym[at..at + 8]);
+            u64::from_le_bytes(bytes)
+        };
+
+        self.symtab
+            .chunks_exact(ELF64_SYM_SIZE)
+            .filter(|sym| sym[4] & 0xf == STT_FUNC)
+            .find_map(|sym| {
+                let (value, size) = (u64_at(sym, 8), u64_at(sym, 16));
+                let offset = addr.checked_sub(value)?;
+                if offset < size || offset == 0 {
+                    let name = u32::from_le_bytes([sym[0], sym[1], sym[2], sym[3]]);
+                    Some((self.name(name as usize)?, offset))
+                } else {
+                    None
+                }
+            })
+    }
+}
+
+/// The kernel ELF and the offset it was relocated by (if we have a KCB).
+fn kernel_info() -> Option<(&'static [u8], u64)> {
+    kcb::try_get_kcb().map(|k| {
+        (
+            k.kernel_binary(),
+            k.arch.kernel_args().kernel_elf_offset.as_u64(),
+        )
+    })
+}
+
+/// Resolves the (run-time) address `ip` to a function and the offset into
+/// it with the kernel's symbol table.
+pub fn symbolize(ip: u64) -> Option<(&'static str, u64)> {
+    let (elf_data, relocated_offset) = kernel_info()?;
+    let elf_binary = elfloader::ElfBinary::new(elf_data).ok()?;
+    SymbolTable::new(&elf_binary)?.lookup(ip.wrapping_sub(relocated_offset))
+}
+
 fn backtrace_format(
     context: Option<&Context<gimli::EndianRcSlice<gimli::RunTimeEndian>>>,
+    symbols: Option<&SymbolTable>,
     relocated_offset: u64,
     count: usize,
     frame: &backtracer_core::Frame,
 ) -> bool {
     let ip = frame.ip();
     sprint!("frame #{:<2} - {:#02$x}", count, ip as usize, 20);
-    let mut resolved = false;
+    match symbols.and_then(|s| s.lookup((ip as u64).wrapping_sub(relocated_offset))) {
+        Some((name, offset)) => sprintln!(" - {:#}+{:#x}", demangle(name), offset),
+        None => sprintln!(" - <unknown>"),
+    }
 
+    // Source locations (and inlined functions) if we have debug info
     let _r = backtracer_core::resolve(context, relocated_offset, ip, |symbol| {
-        if !resolved {
-            resolved = true;
-        } else {
-            sprint!("                                ");
-        }
         if let Some(name) = symbol.name() {
-            if name.as_bytes().is_empty() {
-                sprint!(" - <empty>");
-            } else {
-                sprint!(" - {}", name);
+            if !name.as_bytes().is_empty() {
+                sprint!("                                at {}", name);
                 if let Some(file) = symbol.filename() {
                     sprint!(" ({}", file);
                     if let Some(line) = symbol.lineno() {
@@ -92,41 +171,34 @@ fn backtrace_format(
                         sprint!(")");
                     }
                 }
+                sprintln!("");
             }
-        } else {
-            sprint!(" - <unknown>");
         }
-        sprintln!("");
     });
-
-    if !resolved {
-        sprintln!(" - <no info>");
-    }
     true
 }
 
 #[inline(always)]
 pub fn backtrace_from(rbp: u64, rsp: u64, rip: u64) {
-    let kernel_info = kcb::try_get_kcb().map(|k| {
-        (
-            k.kernel_binary(),
-            k.arch.kernel_args().kernel_elf_offset.as_u64(),
-        )
-    });
-
-    if kernel_info.is_some() {
+    if let Some((elf_data, relocated_offset)) = kernel_info() {
         sprintln!("Backtrace:");
-        let (elf_data, relocated_offset) = kernel_info.expect("Don't have kernel info");
         match elfloader::ElfBinary::new(elf_data) {
             Ok(elf_binary) => {
                 let context = new_ctxt(&elf_binary);
+                let symbols = SymbolTable::new(&elf_binary);
 
                 let mut count = 0;
                 backtracer_core::trace_from(
                     backtracer_core::EntryPoint::new(rbp, rsp, rip),
                     |frame| {
                         count += 1;
-                        backtrace_format(context.as_ref(), relocated_offset, count, frame)
+                        backtrace_format(
+                            context.as_ref(),
+                            symbols.as_ref(),
+                            relocated_offset,
+                            count,
+                            frame,
+                        )
                     },
                 );
                 // TODO(bug): Investigate why freeing context tries to dealloc an invalid
@@ -144,24 +216,23 @@ pub fn backtrace_from(rbp: u64, rsp: u64, rip: u64) {
 
 #[inline(always)]
 pub fn backtrace() {
-    let kernel_info = kcb::try_get_kcb().map(|k| {
-        (
-            k.kernel_binary(),
-            k.arch.kernel_args().kernel_elf_offset.as_u64(),
-        )
-    });
-
-    if kernel_info.is_some() {
+    if let Some((elf_data, relocated_offset)) = kernel_info() {
         sprintln!("Backtrace:");
-
-        let (elf_data, relocated_offset) = kernel_info.expect("Don't have kernel info.");
         match elfloader::ElfBinary::new(elf_data) {
             Ok(elf_binary) => {
                 let context = new_ctxt(&elf_binary);
+                let symbols = SymbolTable::new(&elf_binary);
+
                 let mut count = 0;
                 backtracer_core::trace(|frame| {
                     count += 1;
-                    backtrace_format(context.as_ref(), relocated_offset, count, frame)
+                    backtrace_format(
+                        context.as_ref(),
+                        symbols.as_ref(),
+                        relocated_offset,
+                        count,
+                        frame,
+                    )
                 });
             }
             Err(e) => {
@@ -173,17 +244,20 @@ pub fn backtrace() {
     }
 }
 
+/// A backtrace without DWARF information (it needs to allocate), we still
+/// have function names from the symbol table.
 #[allow(unused)]
 #[inline(always)]
 pub fn backtrace_no_context() {
     sprintln!("Backtrace:");
-    let relocation_offset =
-        kcb::try_get_kcb().map_or(0x0, |k| k.arch.kernel_args().kernel_elf_offset.as_u64());
+    let (elf_data, relocated_offset) = kernel_info().unwrap_or((&[], 0x0));
+    let elf_binary = elfloader::ElfBinary::new(elf_data).ok();
+    let symbols = elf_binary.as_ref().and_then(SymbolTable::new);
 
     let mut count = 0;
     backtracer_core::trace(|frame| {
         count += 1;
-        backtrace_format(None, relocation_offset, count, frame)
+        backtrace_format(None, symbols.as_ref(), relocated_offset, count, frame)
     });
 }
 
@@ -214,6 +288,15 @@ pub fn panic_impl(info: &PanicInfo) -> ! {
             // we can't use it because it will just trigger another panic)
             k.set_panic_mode();
             backtrace();
+
+            let mut message = ArrayString::<256>::new();
+            if let Some(m) = info.message() {
+                let _ = write!(message, "{}", m);
+            }
+            if let Some(location) = info.location() {
+                let _ = write!(message, " in {}:{}", location.file(), location.line());
+            }
+            arch::crashdump::write(&message);
         } else {
             sprintln!("Encountered a recursive panic, exit immediately!")
         }
@@ -292,3 +375,40 @@ pub fn oom(layout: Layout) -> ! {
 pub fn _Unwind_Resume() {
     loop {}
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn symbol(name: u32, info: u8, value: u64, size: u64) -> Vec<u8> {
+        let mut sym = Vec::new();
+        sym.extend_from_slice(&name.to_le_bytes());
+        sym.extend_from_slice(&[info, 0, 1, 0]);
+        sym.extend_from_slice(&value.to_le_bytes());
+        sym.extend_from_slice(&size.to_le_bytes());
+        sym
+    }
+
+    #[test]
+    fn symbol_lookup() {
+        let strtab = b"\0_ZN3nrk5xmain17h0123456789abcdefE\0data\0_start\0";
+        let mut symtab = symbol(0, 0, 0, 0);
+        symtab.extend(symbol(1, 0x12, 0x1000, 0x40));
+        // An object, not a function
+        symtab.extend(symbol(35, 0x11, 0x2000, 0x100));
+        symtab.extend(symbol(40, 0x12, 0x3000, 0));
+
+        let symbols = SymbolTable {
+            symtab: &symtab,
+            strtab,
+        };
+        let (name, offset) = symbols.lookup(0x1010).expect("in xmain");
+        assert_eq!(offset, 0x10);
+        assert_eq!(alloc::format!("{:#}", demangle(name)), "nrk::xmain");
+        assert_eq!(symbols.lookup(0x1040), None);
+        assert_eq!(symbols.lookup(0x2010), None);
+        assert_eq!(symbols.lookup(0x3000), Some(("_start", 0)));
+        assert_eq!(symbols.lookup(0x3001), None);
+    }
+}
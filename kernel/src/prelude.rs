@@ -30,6 +30,33 @@ macro_rules! is_large_page_aligned {
     };
 }
 
+/// Marks a place where we can count how often the kernel passes through at
+/// runtime (see `arch::kprobes`), `name` is what we enable it with.
+///
+/// This puts a single `nop` here (nothing on `unix`).
+#[macro_export]
+macro_rules! kprobe {
+    ($name:literal) => {{
+        #[cfg(target_os = "none")]
+        {
+            use $crate::arch::kprobes::Probe;
+            static PROBE: Probe = Probe::new($name);
+            // Safe: a `nop`, and the address of it in the `kprobes` section
+            unsafe {
+                asm!(
+                    "2: nop",
+                    ".pushsection kprobes, \"aw\"",
+                    ".balign 8",
+                    ".quad 2b, {probe}",
+                    ".popsection",
+                    probe = sym PROBE,
+                    options(nomem, nostack, preserves_flags)
+                );
+            }
+        }
+    }};
+}
+
 pub trait PowersOf2 {
     fn log2(self) -> u8;
 }
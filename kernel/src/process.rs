@@ -8,20 +8,20 @@ use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::convert::{TryFrom, TryInto};
 use core::fmt::Debug;
+use core::marker::PhantomData;
+use core::mem::{self, MaybeUninit};
 
 use arrayvec::ArrayVec;
-use cstr_core::CStr;
 use fallible_collections::vec::FallibleVecGlobal;
 use fallible_collections::vec::TryCollect;
 use fallible_collections::TryReserveError;
-use kpi::process::{FrameId, ELF_OFFSET};
+use kpi::process::{AddressLayout, Credentials, FrameId, ELF_OFFSET};
 use log::{debug, info, trace};
 
 use crate::arch::memory::{paddr_to_kernel_vaddr, LARGE_PAGE_SIZE};
-use crate::arch::process::UserPtr;
+use crate::arch::user_access;
 use crate::arch::{Module, MAX_CORES, MAX_NUMA_NODES};
 use crate::error::KError;
-use crate::fallible_string::TryString;
 use crate::fs::Fd;
 use crate::memory::vspace::AddressSpace;
 use crate::memory::{Frame, KernelAllocator, PhysicalPageProvider, VAddr};
@@ -45,33 +45,62 @@ pub struct KernSlice {
 }
 
 impl KernSlice {
-    pub fn new(base: u64, len: usize) -> KernSlice {
+    pub fn new(base: u64, len: usize) -> Result<KernSlice, KError> {
         let buffer = Arc::<[u8]>::new_uninit_slice(len);
         let mut buffer = unsafe { buffer.assume_init() };
+        user_access::copy_in(unsafe { Arc::get_mut_unchecked(&mut buffer) }, base)?;
+        Ok(KernSlice { buffer })
+    }
+}
 
-        let mut user_ptr = VAddr::from(base);
-        let slice_ptr = UserPtr::new(&mut user_ptr);
-        let user_slice: &mut [u8] =
-            unsafe { core::slice::from_raw_parts_mut(slice_ptr.as_mut_ptr(), len) };
-        unsafe { Arc::get_mut_unchecked(&mut buffer).copy_from_slice(&user_slice[0..len]) };
-        KernSlice { buffer }
+/// A `T` in the memory of the current process (e.g., a system call argument
+/// that points to a struct the kernel fills in).
+///
+/// `T` has to be plain data that is valid for any bit pattern (integers and
+/// `repr(C)` structs of them), user-space can put anything there.
+pub struct UserPtr<T> {
+    addr: u64,
+    _value: PhantomData<T>,
+}
+
+impl<T: Copy> UserPtr<T> {
+    /// Fails with `BadAddress` if `addr` isn't aligned for `T`.
+    pub fn new(addr: u64) -> Result<UserPtr<T>, KError> {
+        if addr % mem::align_of::<T>() as u64 != 0 {
+            return Err(KError::BadAddress);
+        }
+        Ok(UserPtr {
+            addr,
+            _value: PhantomData,
+        })
+    }
+
+    pub fn read(&self) -> Result<T, KError> {
+        let mut value = MaybeUninit::<T>::zeroed();
+        // Safe: The slice covers exactly `value`
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, mem::size_of::<T>())
+        };
+        user_access::copy_in(bytes, self.addr)?;
+        // Safe: `T` is plain data (see above)
+        Ok(unsafe { value.assume_init() })
+    }
+
+    pub fn write(&self, value: T) -> Result<(), KError> {
+        // Safe: The slice covers exactly `value`
+        let bytes = unsafe {
+            core::slice::from_raw_parts(&value as *const T as *const u8, mem::size_of::<T>())
+        };
+        user_access::copy_out(self.addr, bytes)
     }
 }
 
 pub fn userptr_to_str(useraddr: u64) -> Result<String, KError> {
-    let mut user_ptr = VAddr::from(useraddr);
-    let str_ptr = UserPtr::new(&mut user_ptr);
-    unsafe {
-        match CStr::from_ptr(str_ptr.as_ptr()).to_str() {
-            Ok(path) => {
-                if !path.is_ascii() || path.is_empty() {
-                    return Err(KError::NotSupported);
-                }
-                Ok(TryString::try_from(path)?.into())
-            }
-            Err(_) => Err(KError::NotSupported),
-        }
+    let path = user_access::copy_in_str(useraddr)?;
+    if !path.is_ascii() || path.is_empty() {
+        return Err(KError::NotSupported);
     }
+    Ok(path)
 }
 
 /// Process ID.
@@ -88,6 +117,8 @@ pub trait Process {
     fn load(
         &mut self,
         pid: Pid,
+        creds: Credentials,
+        layout: AddressLayout,
         module: &Module,
         writable_sections: Vec<Frame>,
     ) -> Result<(), KError>
@@ -107,6 +138,19 @@ pub trait Process {
 
     fn get_executor(&mut self, for_region: atopology::NodeId) -> Result<Box<Self::E>, KError>;
 
+    /// Makes sure executor `eid` is the next one `get_executor` hands out
+    /// for its node, returns the node.
+    fn prefer_executor(&mut self, _eid: Eid) -> Result<atopology::NodeId, KError> {
+        Err(KError::NotSupported)
+    }
+
+    /// Forgets everything since `load` so the pid can be loaded again,
+    /// returns the frames the process got for its binary and its executors
+    /// (every replica returns the same ones, the caller releases them once).
+    fn destroy(&mut self) -> Result<Vec<Frame>, KError> {
+        Err(KError::NotSupported)
+    }
+
     fn allocate_fd(&mut self) -> Option<(u64, &mut Fd)>;
 
     fn deallocate_fd(&mut self, fd: usize) -> Result<usize, KError>;
@@ -115,8 +159,13 @@ pub trait Process {
 
     fn pinfo(&self) -> &kpi::process::ProcessInfo;
 
+    /// Who the process runs as (set by `load`).
+    fn credentials(&self) -> Credentials {
+        self.pinfo().creds
+    }
+
     fn add_frame(&mut self, frame: Frame) -> Result<FrameId, KError>;
-    fn get_frame(&mut self, frame_id: FrameId) -> Result<Frame, KError>;
+    fn get_frame(&self, frame_id: FrameId) -> Result<Frame, KError>;
     fn deallocate_frame(&mut self, fid: FrameId) -> Result<Frame, KError>;
 }
 
@@ -335,11 +384,18 @@ impl elfloader::ElfLoader for DataSecAllocator {
 ///
 /// Parse & relocate ELF
 /// Create an initial VSpace
-pub fn make_process<P: Process>(binary: &'static str) -> Result<Pid, KError> {
+///
+/// `layout` is the address layout of the process, `None` picks one (used by
+/// restore to get the layout of the checkpoint).
+pub fn make_process<P: Process>(
+    binary: &'static str,
+    creds: Credentials,
+    layout: Option<AddressLayout>,
+) -> Result<Pid, KError> {
     KernelAllocator::try_refill_tcache(7, 1)?;
     let kcb = kcb::get_kcb();
 
-    // Lookup binary of the process
+    // Lookup binary of the process (a module or a program in the initrd)
     let mut mod_file = None;
     for module in &kcb.arch.kernel_args().modules {
         if module.name() == binary {
@@ -347,10 +403,12 @@ pub fn make_process<P: Process>(binary: &'static str) -> Result<Pid, KError> {
         }
     }
 
-    let mod_file = mod_file.ok_or(KError::BinaryNotFound { binary })?;
+    let mod_file = mod_file
+        .or_else(|| crate::initrd::binary(binary))
+        .ok_or(KError::BinaryNotFound { binary })?;
     info!(
         "binary={} cmdline={} module={:?}",
-        binary, kcb.cmdline.init_args, mod_file
+        binary, kcb.config.init_args, mod_file
     );
 
     let elf_module = unsafe {
@@ -378,20 +436,24 @@ pub fn make_process<P: Process>(binary: &'static str) -> Result<Pid, KError> {
     );
 
     // Allocate a new process
-    kcb.replica
-        .as_ref()
-        .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
-            let response = replica.execute_mut(nr::Op::AllocatePid, *token)?;
-            if let nr::NodeResult::PidAllocated(pid) = response {
-                cnrfs::MlnrKernelNode::add_process(pid)
-                    .expect("TODO(error-handling): revert state");
-                crate::nrproc::NrProcess::<P>::load(pid, mod_file, data_frames)
-                    .expect("TODO(error-handling): revert state properly");
-                Ok(pid)
-            } else {
-                Err(KError::ProcessLoadingFailed)
-            }
-        })
+    let pid = nr::KernelNode::allocate_pid(binary)?;
+    if let Err(e) = cnrfs::MlnrKernelNode::add_process(pid, creds) {
+        nr::KernelNode::free_pid(pid)?;
+        return Err(e);
+    }
+    crate::syscall_filter::reset(pid);
+    // Every process gets its own layout (unless we want reproducible runs)
+    let layout = layout.unwrap_or_else(|| {
+        if kcb.config.aslr {
+            AddressLayout::randomized(crate::entropy::rand)
+        } else {
+            AddressLayout::FIXED
+        }
+    });
+    debug!("pid={} layout={:x?}", pid, layout);
+    crate::nrproc::NrProcess::<P>::load(pid, creds, layout, mod_file, data_frames)
+        .expect("TODO(error-handling): revert state properly");
+    Ok(pid)
 }
 
 /// Create dispatchers for a given Pid to run on all cores.
@@ -420,7 +482,8 @@ pub fn allocate_dispatchers<P: Process>(pid: Pid) -> Result<(), KError> {
             KernelAllocator::try_refill_tcache(20, 1)?;
             let mut frame = {
                 let kcb = crate::kcb::get_kcb();
-                kcb.physical_memory.gmanager.unwrap().node_caches[affinity as usize]
+                let node = crate::numa::memory_node(affinity);
+                kcb.physical_memory.gmanager.unwrap().node_caches[node]
                     .lock()
                     .allocate_large_page()?
             };
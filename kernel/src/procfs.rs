@@ -0,0 +1,112 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Files in `/proc` that expose kernel state for debugging.
+//!
+//! A subsystem registers a generator for a path. Whenever a process opens
+//! (or stats) the file, we run the generator and store the output as the
+//! file contents in the (replicated) file-system, so reads go through the
+//! regular file-system paths and see a snapshot taken at open time.
+//!
+//! Every `open` and `stat` looks up the path here, so the list of files is
+//! an [`Rcu`] cell: lookups don't lock and registering a file publishes a
+//! new list.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
+
+use fallible_collections::vec::FallibleVec;
+use spin::Mutex;
+
+use crate::cnrfs::MlnrKernelNode;
+use crate::error::KError;
+use crate::fallible_string::TryString;
+use crate::rcu::{self, Rcu};
+
+/// All files in procfs live below this directory.
+pub const PROC_ROOT: &str = "/proc/";
+
+/// Writes the current contents of a procfs file.
+pub type ProcGenerator = fn(out: &mut String) -> fmt::Result;
+
+type Files = Vec<(&'static str, ProcGenerator)>;
+
+static FILES: Rcu<Files> = Rcu::empty();
+
+/// Serializes `register` calls (they copy and publish `FILES`).
+static REGISTERING: Mutex<()> = Mutex::new(());
+
+/// Adds the file `path` (must start with `PROC_ROOT`).
+pub fn register(path: &'static str, generator: ProcGenerator) -> Result<(), KError> {
+    if !path.starts_with(PROC_ROOT) {
+        return Err(KError::InvalidFile);
+    }
+
+    let _registering = REGISTERING.lock();
+    let mut files = Files::new();
+    {
+        let guard = rcu::read_lock();
+        let current = FILES.get(&guard).map_or(&[][..], |files| &files[..]);
+        if current.iter().any(|(p, _)| *p == path) {
+            return Err(KError::AlreadyPresent);
+        }
+        files.try_reserve(current.len() + 1)?;
+        files.extend_from_slice(current);
+    }
+    files.try_push((path, generator))?;
+    FILES.publish(Box::new(files));
+    Ok(())
+}
+
+fn lookup(path: &str) -> Option<ProcGenerator> {
+    let guard = rcu::read_lock();
+    FILES
+        .get(&guard)?
+        .iter()
+        .find(|(p, _)| *p == path)
+        .map(|(_, generator)| *generator)
+}
+
+/// Regenerates the contents of `path` if it is a procfs file (does nothing
+/// otherwise).
+pub fn refresh(path: &str) -> Result<(), KError> {
+    if !path.starts_with(PROC_ROOT) {
+        return Ok(());
+    }
+
+    if let Some(generator) = lookup(path) {
+        let mut contents = String::new();
+        generator(&mut contents).map_err(|_e| KError::OutOfMemory)?;
+        let contents: Arc<[u8]> = Arc::from(contents.as_bytes());
+        let path = TryString::try_from(path)?.into();
+        MlnrKernelNode::procfs_update(path, contents)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hello(out: &mut String) -> fmt::Result {
+        out.push_str("hello");
+        Ok(())
+    }
+
+    #[test]
+    fn register_file() {
+        register("/proc/test/hello", hello).expect("Can't register file");
+        assert_eq!(
+            register("/proc/test/hello", hello),
+            Err(KError::AlreadyPresent)
+        );
+        assert_eq!(register("/tmp/hello", hello), Err(KError::InvalidFile));
+        assert!(lookup("/proc/test/hello").is_some());
+        assert!(lookup("/proc/test/missing").is_none());
+    }
+}
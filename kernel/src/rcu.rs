@@ -0,0 +1,382 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Read-copy-update for kernel data that is read a lot and rarely changes.
+//!
+//! Readers don't take a lock: they enter a read-side section with
+//! [`read_lock`] and follow the pointer of an [`Rcu`] cell. A writer copies
+//! the data, changes the copy and publishes it, the old version goes to
+//! [`defer_free`] and is dropped once no core can still be reading it.
+//!
+//! We track readers with epochs: a core that enters a read-side section
+//! records the global epoch, publishing something advances it. Garbage
+//! from epoch `e` can go once every core is either outside of a read-side
+//! section or entered it after `e`. Cores report quiescent states when they
+//! return from a system call and when they go idle ([`quiescent`]), that's
+//! when we free what became safe to free. [`synchronize`] waits for the
+//! readers instead (for writers that have to know nobody sees the old
+//! data anymore).
+//!
+//! Read-side sections nest (interrupts may read too), but they must not
+//! block and guards have to be dropped in the reverse order they were
+//! taken in. Publishing takes a lock, so interrupt handlers only read.
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use log::error;
+
+use crate::arch::MAX_CORES;
+use crate::mutex::Mutex;
+
+/// The epoch of a core outside of a read-side section.
+const QUIESCENT: u64 = 0;
+
+/// Advances whenever something is published (starts after `QUIESCENT`).
+static EPOCH: AtomicU64 = AtomicU64::new(QUIESCENT + 1);
+
+/// The read-side state of a core.
+struct Reader {
+    /// The epoch the core entered its outermost read-side section in
+    /// (`QUIESCENT` outside of one).
+    epoch: AtomicU64,
+}
+
+impl Reader {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const IDLE: Reader = Reader {
+        epoch: AtomicU64::new(QUIESCENT),
+    };
+}
+
+static READERS: [Reader; MAX_CORES] = [Reader::IDLE; MAX_CORES];
+
+/// Readers we can't tell apart (no KCB yet), we wait for all of them.
+static UNTRACKED: AtomicUsize = AtomicUsize::new(0);
+
+/// Something that can be dropped once the readers of its epoch are gone.
+type Garbage = (u64, Box<dyn Send>);
+
+static GARBAGE: Mutex<Vec<Garbage>> = Mutex::new("rcu_garbage", Vec::new());
+
+/// How much garbage waits in `GARBAGE` (so quiescent states don't have to
+/// take the lock for nothing).
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// The reader state of the current core (`None` without a KCB).
+#[cfg(target_os = "none")]
+fn current() -> Option<&'static Reader> {
+    crate::kcb::try_get_kcb().map(|kcb| &READERS[kcb.arch.id()])
+}
+
+/// The reader state of the current thread (every thread is a core of its
+/// own when we're hosted, `None` once we ran out of them).
+#[cfg(not(target_os = "none"))]
+fn current() -> Option<&'static Reader> {
+    const UNASSIGNED: usize = usize::MAX;
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    #[thread_local]
+    static SLOT: AtomicUsize = AtomicUsize::new(UNASSIGNED);
+
+    if SLOT.load(Ordering::Relaxed) == UNASSIGNED {
+        SLOT.store(NEXT.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+    }
+    READERS.get(SLOT.load(Ordering::Relaxed))
+}
+
+/// Ends a read-side section when dropped.
+pub struct ReadGuard {
+    reader: Option<&'static Reader>,
+    /// The epoch of the core before we entered (to restore it).
+    outer: u64,
+    /// Stays on the core that took it.
+    _core: PhantomData<*const ()>,
+}
+
+impl Drop for ReadGuard {
+    fn drop(&mut self) {
+        match self.reader {
+            Some(reader) => reader.epoch.store(self.outer, Ordering::Release),
+            None => {
+                UNTRACKED.fetch_sub(1, Ordering::Release);
+            }
+        }
+    }
+}
+
+/// Enters a read-side section, what it reads from [`Rcu`] cells stays
+/// valid until the guard is dropped.
+pub fn read_lock() -> ReadGuard {
+    let reader = current();
+    let outer = match reader {
+        Some(reader) => {
+            let outer = reader.epoch.load(Ordering::Relaxed);
+            if outer == QUIESCENT {
+                reader
+                    .epoch
+                    .store(EPOCH.load(Ordering::SeqCst), Ordering::Relaxed);
+            }
+            outer
+        }
+        None => {
+            UNTRACKED.fetch_add(1, Ordering::Relaxed);
+            QUIESCENT
+        }
+    };
+    // Writers have to see us before we read what they might free
+    fence(Ordering::SeqCst);
+
+    ReadGuard {
+        reader,
+        outer,
+        _core: PhantomData,
+    }
+}
+
+/// Is the current core in a read-side section?
+fn in_read_section() -> bool {
+    current().map_or(false, |r| r.epoch.load(Ordering::Relaxed) != QUIESCENT)
+}
+
+/// The oldest epoch a reader might be in (garbage from before it can go).
+fn oldest_reader() -> u64 {
+    fence(Ordering::SeqCst);
+    if UNTRACKED.load(Ordering::Acquire) > 0 {
+        return QUIESCENT;
+    }
+    READERS
+        .iter()
+        .map(|r| r.epoch.load(Ordering::Acquire))
+        .filter(|epoch| *epoch != QUIESCENT)
+        .min()
+        .unwrap_or_else(|| EPOCH.load(Ordering::SeqCst))
+}
+
+/// Waits until every core left the read-side sections it was in when we
+/// were called, afterwards nobody can see what was unpublished before.
+///
+/// Must not be called in a read-side section (it would wait for itself).
+pub fn synchronize() {
+    debug_assert!(!in_read_section(), "synchronize in a read-side section");
+    let epoch = EPOCH.fetch_add(1, Ordering::SeqCst);
+    while oldest_reader() <= epoch {
+        spin_loop();
+    }
+    collect();
+}
+
+/// Drops `garbage` once no core can read it anymore (unpublish it first).
+pub fn defer_free<T: Send + 'static>(garbage: Box<T>) {
+    // Readers that entered up to now might still see it
+    let epoch = EPOCH.fetch_add(1, Ordering::SeqCst);
+
+    let mut pending = GARBAGE.lock();
+    if pending.try_reserve(1).is_err() {
+        drop(pending);
+        // Nowhere to put it, so wait for the readers instead
+        if !in_read_section() {
+            synchronize();
+            drop(garbage);
+            return;
+        }
+        // Better than freeing something that might be in use
+        error!("Leaking RCU garbage, out of memory in a read-side section");
+        core::mem::forget(garbage);
+        return;
+    }
+    pending.push((epoch, garbage));
+    PENDING.fetch_add(1, Ordering::Release);
+}
+
+/// Reports a quiescent state of the current core (it doesn't hold on to
+/// anything it read) and frees the garbage no reader can see anymore.
+pub fn quiescent() {
+    debug_assert!(!in_read_section(), "quiescent in a read-side section");
+    if PENDING.load(Ordering::Acquire) > 0 {
+        collect();
+    }
+}
+
+/// Frees the garbage that is safe to free.
+fn collect() {
+    let oldest = oldest_reader();
+    let mut safe = Vec::new();
+    {
+        let mut pending = GARBAGE.lock();
+        let mut i = 0;
+        while i < pending.len() {
+            if pending[i].0 < oldest && safe.try_reserve(1).is_ok() {
+                safe.push(pending.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        PENDING.store(pending.len(), Ordering::Release);
+    }
+    // Outside of the lock, dropping might take it again
+    drop(safe);
+}
+
+/// A pointer to a `T` that readers follow without locking.
+///
+/// Writers have to serialize among themselves.
+pub struct Rcu<T> {
+    ptr: AtomicPtr<T>,
+}
+
+impl<T> Rcu<T> {
+    /// A cell that doesn't point to anything yet.
+    pub const fn empty() -> Rcu<T> {
+        Rcu {
+            ptr: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+}
+
+impl<T: Send + Sync + 'static> Rcu<T> {
+    /// The current version (`None` if nothing was published yet).
+    pub fn get<'a>(&'a self, _guard: &'a ReadGuard) -> Option<&'a T> {
+        // Safe: Published versions are only freed once all readers that
+        // could see them (like the owner of `_guard`) are gone
+        unsafe { self.ptr.load(Ordering::Acquire).as_ref() }
+    }
+
+    /// Makes `value` the current version, frees the previous one once its
+    /// readers are gone.
+    pub fn publish(&self, value: Box<T>) {
+        let previous = self.ptr.swap(Box::into_raw(value), Ordering::AcqRel);
+        if !previous.is_null() {
+            // Safe: Came from `Box::into_raw` and isn't reachable anymore
+            defer_free(unsafe { Box::from_raw(previous) });
+        }
+    }
+}
+
+impl<T> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        let current = *self.ptr.get_mut();
+        if !current.is_null() {
+            // Safe: Came from `Box::into_raw`, readers borrow the cell
+            drop(unsafe { Box::from_raw(current) });
+        }
+    }
+}
+
+// Safe: Readers on other cores get a `&T`, writers hand over a `T`
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Barrier;
+
+    /// Counts how often it was dropped.
+    struct Tracked(Arc<AtomicUsize>);
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn nested_read_lock() {
+        assert!(!in_read_section());
+        {
+            let _outer = read_lock();
+            let epoch = current().unwrap().epoch.load(Ordering::Relaxed);
+            {
+                let _inner = read_lock();
+                assert_eq!(current().unwrap().epoch.load(Ordering::Relaxed), epoch);
+            }
+            assert!(in_read_section());
+        }
+        assert!(!in_read_section());
+    }
+
+    #[test]
+    fn publish_and_free() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let cell: Rcu<Tracked> = Rcu::empty();
+        assert!(cell.get(&read_lock()).is_none());
+
+        cell.publish(Box::new(Tracked(drops.clone())));
+        {
+            let guard = read_lock();
+            let first = cell.get(&guard).unwrap() as *const Tracked;
+            cell.publish(Box::new(Tracked(drops.clone())));
+            // Another core collecting garbage leaves the first one alone,
+            // we might still look at it
+            quiescent_elsewhere();
+            assert!(cell.get(&guard).unwrap() as *const Tracked != first);
+            assert_eq!(drops.load(Ordering::SeqCst), 0);
+        }
+
+        synchronize();
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+
+    /// Reports a quiescent state from another thread.
+    fn quiescent_elsewhere() {
+        std::thread::spawn(quiescent).join().unwrap();
+    }
+
+    #[test]
+    fn synchronize_waits_for_readers() {
+        let entered = Arc::new(Barrier::new(2));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let reader = {
+            let (entered, done) = (entered.clone(), done.clone());
+            std::thread::spawn(move || {
+                let _guard = read_lock();
+                entered.wait();
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                done.store(true, Ordering::SeqCst);
+            })
+        };
+
+        entered.wait();
+        synchronize();
+        assert!(done.load(Ordering::SeqCst));
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn readers_and_writer() {
+        let cell: Arc<Rcu<Vec<usize>>> = Arc::new(Rcu::empty());
+        cell.publish(Box::new(vec![0; 16]));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let cell = cell.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let guard = read_lock();
+                        let v = cell.get(&guard).unwrap();
+                        // Every version has the same value everywhere
+                        assert!(v.iter().all(|x| *x == v[0]));
+                        drop(guard);
+                        quiescent();
+                    }
+                })
+            })
+            .collect();
+
+        for i in 1..200 {
+            cell.publish(Box::new(vec![i; 16]));
+        }
+        for r in readers {
+            r.join().unwrap();
+        }
+        synchronize();
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Kernel-to-kernel RPC.
+//!
+//! Lets a kernel invoke services of a kernel on another machine over UDP
+//! (port [`RPC_PORT`]). This is the groundwork for replicating kernel state
+//! across machines: A replica on a remote machine registers a service that
+//! receives the (encoded) node-replication log operations as the request
+//! payload and replies with the result.
+//!
+//! A request gets retransmitted until the response arrives. The server
+//! remembers the last response it sent to a peer, a retransmitted request is
+//! answered from there so every request executes at most once.
+
+use alloc::vec::Vec;
+
+use fallible_collections::vec::FallibleVec;
+use spin::Mutex;
+
+use crate::error::KError;
+
+mod proto;
+#[cfg(all(feature = "smoltcp", target_os = "none"))]
+mod transport;
+
+pub use proto::{ServiceId, MAX_PAYLOAD};
+#[cfg(all(feature = "smoltcp", target_os = "none"))]
+pub use transport::{call, init, poll, RPC_PORT};
+
+/// Handles a request for a service, writes the reply into `response`.
+///
+/// Handlers run with the network stack locked and therefore can't send
+/// RPCs (or use sockets) themselves.
+pub type RpcHandler = fn(request: &[u8], response: &mut Vec<u8>) -> Result<(), KError>;
+
+/// All services this kernel provides.
+static SERVICES: Mutex<Vec<(ServiceId, RpcHandler)>> = Mutex::new(Vec::new());
+
+/// Makes `handler` available to other kernels as service `id`.
+pub fn register(id: ServiceId, handler: RpcHandler) -> Result<(), KError> {
+    let mut services = SERVICES.lock();
+    if services.iter().any(|(sid, _)| *sid == id) {
+        return Err(KError::RpcServiceAlreadyRegistered);
+    }
+    services.try_push((id, handler))?;
+    Ok(())
+}
+
+/// Removes service `id` (if it was registered).
+#[allow(unused)]
+pub fn unregister(id: ServiceId) {
+    SERVICES.lock().retain(|(sid, _)| *sid != id);
+}
+
+/// Finds the handler for service `id`.
+#[allow(unused)]
+fn lookup(id: ServiceId) -> Option<RpcHandler> {
+    SERVICES
+        .lock()
+        .iter()
+        .find(|(sid, _)| *sid == id)
+        .map(|(_, handler)| *handler)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn echo(request: &[u8], response: &mut Vec<u8>) -> Result<(), KError> {
+        response.extend_from_slice(request);
+        Ok(())
+    }
+
+    #[test]
+    fn register_service() {
+        register(0xfff0, echo).expect("Can't register service");
+        assert_eq!(
+            register(0xfff0, echo),
+            Err(KError::RpcServiceAlreadyRegistered)
+        );
+        assert!(lookup(0xfff0).is_some());
+        assert!(lookup(0xfff1).is_none());
+
+        unregister(0xfff0);
+        assert!(lookup(0xfff0).is_none());
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Wire format of RPC messages.
+//!
+//! Every UDP datagram holds one message: A 20 byte header (all fields
+//! little-endian) followed by the payload.
+//!
+//! | offset | size | field                           |
+//! |--------|------|---------------------------------|
+//! | 0      | 2    | magic (`RPC_MAGIC`)             |
+//! | 2      | 1    | kind (`MsgKind`)                |
+//! | 3      | 1    | status (`RpcStatus`, responses) |
+//! | 4      | 2    | service id                      |
+//! | 6      | 2    | reserved (0)                    |
+//! | 8      | 8    | sequence number                 |
+//! | 16     | 4    | payload length                  |
+
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use crate::error::KError;
+
+/// Identifies a service registered with `rpc::register`.
+pub type ServiceId = u16;
+
+/// First two bytes of every message ("NR").
+pub const RPC_MAGIC: u16 = 0x524e;
+
+/// Size of the message header (in bytes).
+pub const HEADER_SIZE: usize = 20;
+
+/// Largest payload of a request or response (so a message fits in a single
+/// Ethernet frame).
+pub const MAX_PAYLOAD: usize = 1400;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum MsgKind {
+    Request = 1,
+    Response = 2,
+}
+
+/// Outcome of a request (as reported by the server).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum RpcStatus {
+    Ok = 0,
+    /// No handler is registered for the service.
+    NoService = 1,
+    /// The handler returned an error.
+    Failed = 2,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RpcHeader {
+    pub kind: MsgKind,
+    pub status: RpcStatus,
+    pub service: ServiceId,
+    pub seq: u64,
+    pub len: u32,
+}
+
+impl RpcHeader {
+    pub fn request(service: ServiceId, seq: u64, len: usize) -> RpcHeader {
+        RpcHeader {
+            kind: MsgKind::Request,
+            status: RpcStatus::Ok,
+            service,
+            seq,
+            len: len as u32,
+        }
+    }
+
+    /// The header of the response to `self`.
+    pub fn response(&self, status: RpcStatus, len: usize) -> RpcHeader {
+        RpcHeader {
+            kind: MsgKind::Response,
+            status,
+            service: self.service,
+            seq: self.seq,
+            len: len as u32,
+        }
+    }
+}
+
+/// Serializes a message.
+pub fn encode(hdr: &RpcHeader, payload: &[u8]) -> Vec<u8> {
+    debug_assert_eq!(hdr.len as usize, payload.len());
+    let mut msg = Vec::with_capacity(HEADER_SIZE + payload.len());
+    msg.extend_from_slice(&RPC_MAGIC.to_le_bytes());
+    msg.push(hdr.kind as u8);
+    msg.push(hdr.status as u8);
+    msg.extend_from_slice(&hdr.service.to_le_bytes());
+    msg.extend_from_slice(&0u16.to_le_bytes());
+    msg.extend_from_slice(&hdr.seq.to_le_bytes());
+    msg.extend_from_slice(&hdr.len.to_le_bytes());
+    msg.extend_from_slice(payload);
+    msg
+}
+
+/// Parses a message, returns the header and the payload.
+pub fn decode(msg: &[u8]) -> Result<(RpcHeader, &[u8]), KError> {
+    if msg.len() < HEADER_SIZE || msg[0..2] != RPC_MAGIC.to_le_bytes() {
+        return Err(KError::InvalidRpcMessage);
+    }
+
+    let kind = match msg[2] {
+        1 => MsgKind::Request,
+        2 => MsgKind::Response,
+        _ => return Err(KError::InvalidRpcMessage),
+    };
+    let status = match msg[3] {
+        0 => RpcStatus::Ok,
+        1 => RpcStatus::NoService,
+        2 => RpcStatus::Failed,
+        _ => return Err(KError::InvalidRpcMessage),
+    };
+    let service = u16::from_le_bytes(msg[4..6].try_into().unwrap());
+    let seq = u64::from_le_bytes(msg[8..16].try_into().unwrap());
+    let len = u32::from_le_bytes(msg[16..20].try_into().unwrap());
+
+    let payload = &msg[HEADER_SIZE..];
+    if payload.len() != len as usize || payload.len() > MAX_PAYLOAD {
+        return Err(KError::InvalidRpcMessage);
+    }
+
+    Ok((
+        RpcHeader {
+            kind,
+            status,
+            service,
+            seq,
+            len,
+        },
+        payload,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_decode() {
+        let req = RpcHeader::request(7, 0xdead_beef, 5);
+        let msg = encode(&req, b"hello");
+        assert_eq!(msg.len(), HEADER_SIZE + 5);
+        assert_eq!(decode(&msg), Ok((req, &b"hello"[..])));
+
+        let resp = req.response(RpcStatus::NoService, 0);
+        let msg = encode(&resp, &[]);
+        let (hdr, payload) = decode(&msg).unwrap();
+        assert_eq!(hdr.kind, MsgKind::Response);
+        assert_eq!(hdr.status, RpcStatus::NoService);
+        assert_eq!(hdr.seq, 0xdead_beef);
+        assert_eq!(hdr.service, 7);
+        assert!(payload.is_empty());
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let msg = encode(&RpcHeader::request(1, 1, 3), b"abc");
+        assert!(decode(&msg[..HEADER_SIZE - 1]).is_err());
+        // Payload length doesn't match:
+        assert!(decode(&msg[..msg.len() - 1]).is_err());
+
+        let mut bad_magic = msg.clone();
+        bad_magic[0] = 0;
+        assert!(decode(&bad_magic).is_err());
+
+        let mut bad_kind = msg;
+        bad_kind[2] = 3;
+        assert!(decode(&bad_kind).is_err());
+    }
+}
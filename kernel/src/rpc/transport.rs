@@ -0,0 +1,227 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Sends and receives RPC messages with a UDP socket of the kernel network
+//! stack.
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use log::debug;
+use smoltcp::socket::{SocketHandle, UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
+use smoltcp::wire::IpEndpoint;
+use spin::Mutex;
+
+use crate::error::KError;
+use crate::net::{NetStack, NET_STACK};
+
+use super::proto::{self, MsgKind, RpcHeader, RpcStatus, ServiceId, HEADER_SIZE, MAX_PAYLOAD};
+
+/// UDP port the RPC server listens on.
+pub const RPC_PORT: u16 = 6972;
+
+/// How long we wait for a response before sending the request again.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How many times we send a request before giving up.
+const MAX_ATTEMPTS: usize = 20;
+
+/// How many messages we buffer (per direction).
+const PACKET_SLOTS: usize = 32;
+
+struct Transport {
+    handle: SocketHandle,
+    next_seq: u64,
+    /// The last request we executed for a peer and the response we sent.
+    ///
+    /// TODO(correctness): A peer that reboots starts again with sequence
+    /// number 1 and gets ignored until its numbers catch up.
+    served: BTreeMap<IpEndpoint, (u64, Vec<u8>)>,
+}
+
+/// The RPC socket (initialized by `init`).
+///
+/// Lock order: `TRANSPORT` before `NET_STACK`.
+static TRANSPORT: Mutex<Option<Transport>> = Mutex::new(None);
+
+fn send(stack: &mut NetStack, handle: SocketHandle, to: IpEndpoint, msg: &[u8]) {
+    // If the send buffer is full we drop the message, the client retransmits
+    if let Err(e) = stack.sockets.get::<UdpSocket>(handle).send_slice(msg, to) {
+        debug!("rpc: unable to send message to {}: {}", to, e);
+    }
+}
+
+impl Transport {
+    /// Handles all messages that arrived.
+    ///
+    /// Requests get served, a response matching `waiting` is copied into
+    /// `response` and its status returned.
+    fn process(
+        &mut self,
+        stack: &mut NetStack,
+        waiting: Option<(IpEndpoint, u64)>,
+        response: &mut Vec<u8>,
+    ) -> Option<RpcStatus> {
+        let mut buf = [0u8; HEADER_SIZE + MAX_PAYLOAD];
+        let mut status = None;
+
+        loop {
+            let (len, from) = match stack
+                .sockets
+                .get::<UdpSocket>(self.handle)
+                .recv_slice(&mut buf)
+            {
+                Ok(r) => r,
+                Err(_) => break,
+            };
+
+            let (hdr, payload) = match proto::decode(&buf[..len]) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    debug!("rpc: dropping message from {}: {}", from, e);
+                    continue;
+                }
+            };
+
+            match hdr.kind {
+                MsgKind::Request => self.serve(stack, from, &hdr, payload),
+                MsgKind::Response if waiting == Some((from, hdr.seq)) => {
+                    response.clear();
+                    response.extend_from_slice(payload);
+                    status = Some(hdr.status);
+                }
+                // Duplicate response to a request we retransmitted
+                MsgKind::Response => {}
+            }
+        }
+
+        status
+    }
+
+    /// Executes a request and sends back the response.
+    fn serve(&mut self, stack: &mut NetStack, from: IpEndpoint, hdr: &RpcHeader, payload: &[u8]) {
+        if let Some((seq, msg)) = self.served.get(&from) {
+            if hdr.seq == *seq {
+                // Our response got lost, send it again
+                send(stack, self.handle, from, msg);
+                return;
+            } else if hdr.seq < *seq {
+                // Old retransmission that arrived late
+                return;
+            }
+        }
+
+        let mut response = Vec::new();
+        let status = match super::lookup(hdr.service) {
+            Some(handler) => match handler(payload, &mut response) {
+                Ok(()) if response.len() <= MAX_PAYLOAD => RpcStatus::Ok,
+                Ok(()) => {
+                    debug!("rpc: response of service {} too big", hdr.service);
+                    RpcStatus::Failed
+                }
+                Err(e) => {
+                    debug!("rpc: service {} failed: {}", hdr.service, e);
+                    RpcStatus::Failed
+                }
+            },
+            None => RpcStatus::NoService,
+        };
+        if status != RpcStatus::Ok {
+            response.clear();
+        }
+
+        let msg = proto::encode(&hdr.response(status, response.len()), &response);
+        send(stack, self.handle, from, &msg);
+        self.served.insert(from, (hdr.seq, msg));
+    }
+}
+
+/// Opens the RPC socket (needs an initialized network stack).
+pub fn init() -> Result<(), KError> {
+    let mut transport = TRANSPORT.lock();
+    let mut stack = NET_STACK.lock();
+    let stack = stack.as_mut().ok_or(KError::NetStackUnavailable)?;
+
+    let buffer = || {
+        UdpSocketBuffer::new(
+            vec![UdpPacketMetadata::EMPTY; PACKET_SLOTS],
+            vec![0; PACKET_SLOTS * (HEADER_SIZE + MAX_PAYLOAD)],
+        )
+    };
+    let mut socket = UdpSocket::new(buffer(), buffer());
+    socket.bind(RPC_PORT).map_err(|_e| KError::SocketError)?;
+
+    *transport = Some(Transport {
+        handle: stack.sockets.add(socket),
+        next_seq: 1,
+        served: BTreeMap::new(),
+    });
+    Ok(())
+}
+
+/// Calls `service` on the kernel at `peer` and waits for the response.
+///
+/// Requests we receive in the meantime are served as well (so two kernels
+/// calling each other don't deadlock).
+pub fn call(
+    peer: IpEndpoint,
+    service: ServiceId,
+    request: &[u8],
+    response: &mut Vec<u8>,
+) -> Result<(), KError> {
+    if request.len() > MAX_PAYLOAD {
+        return Err(KError::InvalidLength);
+    }
+
+    let mut transport = TRANSPORT.lock();
+    let transport = transport.as_mut().ok_or(KError::NetStackUnavailable)?;
+    let seq = transport.next_seq;
+    transport.next_seq += 1;
+    let msg = proto::encode(&RpcHeader::request(service, seq, request.len()), request);
+
+    for attempt in 0..MAX_ATTEMPTS {
+        if attempt > 0 {
+            debug!("rpc: retransmitting request {} to {}", seq, peer);
+        }
+
+        let sent = rawtime::Instant::now();
+        {
+            let mut stack = NET_STACK.lock();
+            let stack = stack.as_mut().ok_or(KError::NetStackUnavailable)?;
+            send(stack, transport.handle, peer, &msg);
+        }
+
+        while sent.elapsed() < RETRANSMIT_TIMEOUT {
+            let mut stack = NET_STACK.lock();
+            let stack = stack.as_mut().ok_or(KError::NetStackUnavailable)?;
+            stack.poll();
+
+            match transport.process(stack, Some((peer, seq)), response) {
+                Some(RpcStatus::Ok) => return Ok(()),
+                Some(RpcStatus::NoService) => return Err(KError::RpcServiceNotFound),
+                Some(RpcStatus::Failed) => return Err(KError::RpcFailed),
+                None => {}
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    Err(KError::RpcTimeout)
+}
+
+/// Serves requests that arrived (does nothing if RPC isn't initialized or
+/// another core is busy with it).
+pub fn poll() {
+    if let Some(mut transport) = TRANSPORT.try_lock() {
+        if let Some(transport) = transport.as_mut() {
+            if let Some(stack) = NET_STACK.lock().as_mut() {
+                stack.poll();
+                transport.process(stack, None, &mut Vec::new());
+                // Sends the responses
+                stack.poll();
+            }
+        }
+    }
+}
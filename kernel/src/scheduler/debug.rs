@@ -0,0 +1,173 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Prints the scheduler state with graphviz (`Debug::dump_sched_graph`):
+//! every core points to the process it is allocated to, every process to
+//! its executors (vCPUs) and the core they run on. With `lock-debug`, cores
+//! that spin on a lock also point to the lock.
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::borrow::Cow;
+use alloc::format;
+use alloc::vec::Vec;
+
+use fallible_collections::vec::FallibleVec;
+
+use crate::error::KError;
+use crate::graphviz as dot;
+use crate::nr::KernelNode;
+use crate::nrproc::NrProcess;
+use crate::process::{Eid, Pid, Process};
+
+#[derive(Copy, Clone)]
+pub enum Nd {
+    Core(atopology::GlobalThreadId),
+    Process(Pid),
+    Executor(Pid, Eid, atopology::GlobalThreadId),
+    Lock(&'static str),
+}
+
+/// An edge and its label.
+type Ed = (Nd, Nd, &'static str);
+
+/// A snapshot of who runs where.
+pub struct SchedGraph {
+    nodes: Vec<Nd>,
+    edges: Vec<Ed>,
+    /// Cores allocated to a process.
+    busy: Vec<atopology::GlobalThreadId>,
+}
+
+impl SchedGraph {
+    pub fn snapshot<P: Process>() -> Result<SchedGraph, KError> {
+        let (pids, allocated) = KernelNode::scheduling()?;
+        let mut graph = SchedGraph {
+            nodes: Vec::new(),
+            edges: Vec::new(),
+            busy: Vec::new(),
+        };
+
+        for thread in atopology::MACHINE_TOPOLOGY.threads() {
+            graph.nodes.try_push(Nd::Core(thread.id))?;
+        }
+        for (gtid, pid) in allocated {
+            graph.busy.try_push(gtid)?;
+            graph
+                .edges
+                .try_push((Nd::Core(gtid), Nd::Process(pid), "allocated to"))?;
+        }
+
+        for pid in pids {
+            graph.nodes.try_push(Nd::Process(pid))?;
+            for (gtid, eid) in NrProcess::<P>::active_cores(pid)? {
+                let executor = Nd::Executor(pid, eid, gtid);
+                graph.nodes.try_push(executor)?;
+                graph
+                    .edges
+                    .try_push((Nd::Process(pid), executor, "executor"))?;
+                graph
+                    .edges
+                    .try_push((executor, Nd::Core(gtid), "runs on"))?;
+            }
+        }
+
+        #[cfg(feature = "lock-debug")]
+        for thread in atopology::MACHINE_TOPOLOGY.threads() {
+            if let Some(lock) = crate::mutex::waits_for(thread.id) {
+                let node = Nd::Lock(lock);
+                if !graph
+                    .nodes
+                    .iter()
+                    .any(|n| matches!(n, Nd::Lock(l) if *l == lock))
+                {
+                    graph.nodes.try_push(node)?;
+                }
+                graph
+                    .edges
+                    .try_push((Nd::Core(thread.id), node, "waits for"))?;
+            }
+        }
+
+        Ok(graph)
+    }
+}
+
+impl<'a> dot::Labeller<'a> for SchedGraph {
+    type Node = Nd;
+    type Edge = Ed;
+
+    fn graph_id(&'a self) -> dot::Id<'a> {
+        dot::Id::new("scheduler").unwrap()
+    }
+
+    fn node_id(&'a self, n: &Nd) -> dot::Id<'a> {
+        let id = match n {
+            Nd::Core(gtid) => format!("core_{}", gtid),
+            Nd::Process(pid) => format!("process_{}", pid),
+            Nd::Executor(pid, eid, _gtid) => format!("executor_{}_{}", pid, eid),
+            Nd::Lock(name) => format!("lock_{}", name),
+        };
+        dot::Id::new(id).expect("Can't make id")
+    }
+
+    fn node_shape(&'a self, n: &Nd) -> Option<dot::LabelText<'a>> {
+        let shape = match n {
+            Nd::Core(_) => "box",
+            Nd::Process(_) => "ellipse",
+            Nd::Executor(_, _, _) => "ellipse",
+            Nd::Lock(_) => "diamond",
+        };
+        Some(dot::LabelText::label(shape))
+    }
+
+    fn node_label(&'a self, n: &Nd) -> dot::LabelText<'a> {
+        let label = match n {
+            Nd::Core(gtid) if self.busy.contains(gtid) => format!("core {}", gtid),
+            Nd::Core(gtid) => format!("core {} (idle)", gtid),
+            Nd::Process(pid) => format!("process {}", pid),
+            Nd::Executor(_pid, eid, gtid) => format!("executor {} on core {}", eid, gtid),
+            Nd::Lock(name) => format!("lock {}", name),
+        };
+        dot::LabelText::label(label)
+    }
+
+    fn node_style(&'a self, n: &Nd) -> dot::Style {
+        match n {
+            Nd::Lock(_) => dot::Style::Filled,
+            _ => dot::Style::None,
+        }
+    }
+
+    fn edge_label(&'a self, e: &Ed) -> dot::LabelText<'a> {
+        dot::LabelText::label(e.2)
+    }
+}
+
+impl<'a> dot::GraphWalk<'a> for SchedGraph {
+    type Node = Nd;
+    type Edge = Ed;
+
+    fn nodes(&'a self) -> dot::Nodes<'a, Nd> {
+        Cow::Borrowed(&self.nodes)
+    }
+
+    fn edges(&'a self) -> dot::Edges<'a, Ed> {
+        Cow::Borrowed(&self.edges)
+    }
+
+    fn source(&'a self, e: &Ed) -> Nd {
+        e.0
+    }
+
+    fn target(&'a self, e: &Ed) -> Nd {
+        e.1
+    }
+}
+
+/// Prints the scheduler state on the serial console.
+pub fn dump<P: Process>() -> Result<(), KError> {
+    let graph = SchedGraph::snapshot::<P>()?;
+    dot::render_opts(&graph, &[dot::RenderOption::RankDirectionLR]);
+    Ok(())
+}
@@ -3,16 +3,20 @@
 
 //! Scheduling logic
 
+pub mod debug;
+pub mod policy;
+
 use core::intrinsics::unlikely;
 
+use crate::arch::Platform;
+use crate::arch_interface::Arch;
+use crate::cmdline::SchedulerPolicy;
 use crate::error::KError;
 use crate::kcb::{self, ArchSpecificKcb};
 use crate::nr;
 use crate::nrproc::NrProcess;
 use crate::process::{Executor, ResumeHandle};
 
-use crate::arch::timer;
-
 /// Runs the process allocated to the given core.
 pub fn schedule() -> ! {
     let kcb = kcb::get_kcb();
@@ -36,38 +40,43 @@ pub fn schedule() -> ! {
 
     // No process assigned to core? Figure out if there is one now:
     if unlikely(kcb.arch.current_executor().is_err()) {
-        if let Some((replica, token)) = kcb.replica.as_ref() {
-            loop {
-                let response =
-                    replica.execute(nr::ReadOps::CurrentProcess(kcb.arch.hwthread_id()), *token);
-
-                match response {
-                    Ok(nr::NodeResult::CoreInfo(ci)) => {
-                        let executor =
-                            NrProcess::allocate_executor(kcb, ci.pid).expect("This should work");
-                        unsafe {
-                            (*executor.vcpu_kernel()).resume_with_upcall = ci.entry_point;
-                        }
+        loop {
+            match nr::KernelNode::current_process(kcb.arch.hwthread_id()) {
+                Ok(ci) => {
+                    let executor =
+                        NrProcess::allocate_executor(kcb, ci.pid).expect("This should work");
+                    unsafe {
+                        (*executor.vcpu_kernel()).resume_with_upcall = ci.entry_point;
+                    }
 
-                        // info!("Start execution of {} on gtid {}", executor.eid, gtid);
-                        let no = kcb::get_kcb().arch.swap_current_executor(executor);
-                        assert!(no.is_none(), "Handle the case where we replace a process.");
-                        if is_replica_main_thread {
-                            // Make sure we periodically try and advance the replica on main-thread
-                            // even if we're running something (e.g., if everything polls in
-                            // user-space we can livelock)
-                            timer::set(timer::DEFAULT_TIMER_DEADLINE);
-                        }
-                        break;
+                    // info!("Start execution of {} on gtid {}", executor.eid, gtid);
+                    let no = kcb::get_kcb().arch.swap_current_executor(executor);
+                    assert!(no.is_none(), "Handle the case where we replace a process.");
+                    kcb.stats.context_switch();
+                    if is_replica_main_thread {
+                        // Make sure we periodically try and advance the replica on main-thread
+                        // even if we're running something (e.g., if everything polls in
+                        // user-space we can livelock)
+                        Platform::set_timer(Platform::DEFAULT_TIMER_DEADLINE);
                     }
-                    Err(KError::NoExecutorForCore) => {
-                        if is_replica_main_thread {
-                            // There is no process but we're the "main" thread,
-                            // aggressively try and advance the replica
-                            let start = rawtime::Instant::now();
-                            crate::nrproc::advance_all();
-                            crate::arch::advance_fs_replica();
+                    break;
+                }
+                Err(KError::NoExecutorForCore) => {
+                    if is_replica_main_thread {
+                        // There is no process but we're the "main" thread,
+                        // try and advance the replica
+                        let start = rawtime::Instant::now();
+                        crate::nrproc::advance_all();
+                        Platform::advance_fs_replica();
+                        // Answer pings and serve requests of other kernels
+                        #[cfg(all(feature = "smoltcp", target_os = "none"))]
+                        {
+                            crate::net::poll();
+                            crate::rpc::poll();
+                        }
 
+                        // With `sched=poll` we keep doing this aggressively
+                        if kcb.config.scheduler == SchedulerPolicy::Poll {
                             if start.elapsed().as_millis() < 1 {
                                 // Wait for a bit in case we don't end up doing
                                 // any work, otherwise this causes too much
@@ -77,20 +86,20 @@ pub fn schedule() -> ! {
                                 }
                             }
                             continue;
-                        } else {
-                            // There is no process, set a timer and go to sleep
-                            timer::set(timer::DEFAULT_TIMER_DEADLINE);
                         }
-                        crate::arch::halt();
                     }
-                    other => {
-                        unreachable!(
-                            "Unexpected return from ReadOps::CurrentExecutor {:?}.",
-                            other
-                        );
-                    }
-                };
-            }
+
+                    // There is no process, set a timer and go to sleep
+                    Platform::set_timer(Platform::DEFAULT_TIMER_DEADLINE);
+                    Platform::halt();
+                }
+                other => {
+                    unreachable!(
+                        "Unexpected return from ReadOps::CurrentProcess {:?}.",
+                        other
+                    );
+                }
+            };
         }
     }
     debug_assert!(
@@ -0,0 +1,223 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Which core a process gets if it doesn't ask for a particular one.
+//!
+//! A [`Policy`] picks a core from the replicated view of the machine in
+//! [`crate::nr::KernelNode`]. It runs while an operation is applied to a
+//! replica, so it has to be deterministic: the same cores in, the same core
+//! out, or the replicas disagree on who runs where. Policies only look at
+//! what they're given and keep no state of their own.
+//!
+//! New policies go into [`get`] (and `kpi::system::CorePolicy`), processes
+//! switch between them with `System::set_core_policy` and the kernel starts
+//! with the one from `corepolicy=` on the command-line.
+
+use core::cmp::Reverse;
+
+use kpi::system::CorePolicy;
+
+/// A hardware thread as a policy sees it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Core {
+    pub gtid: atopology::GlobalThreadId,
+    pub node: atopology::NodeId,
+    pub package: usize,
+    /// The physical core (within the package) the thread belongs to, SMT
+    /// siblings have the same one.
+    pub core: usize,
+    /// Runs a process.
+    pub allocated: bool,
+    /// Can run a process (isn't parked).
+    pub online: bool,
+}
+
+impl Core {
+    fn is_free(&self) -> bool {
+        self.online && !self.allocated
+    }
+
+    fn is_sibling(&self, other: &Core) -> bool {
+        self.package == other.package && self.core == other.core && self.gtid != other.gtid
+    }
+}
+
+/// Decides which core to hand out.
+pub trait Policy: Sync {
+    /// Picks a free core from `cores` (on node `affinity` if given) or
+    /// `None` if there is none.
+    fn pick(
+        &self,
+        cores: &[Core],
+        affinity: Option<atopology::NodeId>,
+    ) -> Option<atopology::GlobalThreadId>;
+}
+
+/// Returns the implementation of `policy`.
+pub fn get(policy: CorePolicy) -> &'static dyn Policy {
+    match policy {
+        CorePolicy::Packed => &Packed,
+        CorePolicy::Spread => &Spread,
+        CorePolicy::Smt => &SmtAware,
+    }
+}
+
+/// The free cores on `affinity` (all nodes if `None`).
+fn candidates<'a>(
+    cores: &'a [Core],
+    affinity: Option<atopology::NodeId>,
+) -> impl Iterator<Item = &'a Core> {
+    cores
+        .iter()
+        .filter(move |c| c.is_free() && affinity.map_or(true, |node| node == c.node))
+}
+
+/// How many cores on `node` are allocated.
+fn allocated_on_node(cores: &[Core], node: atopology::NodeId) -> usize {
+    cores
+        .iter()
+        .filter(|c| c.node == node && c.allocated)
+        .count()
+}
+
+/// How many SMT siblings of `core` are allocated.
+fn allocated_siblings(cores: &[Core], core: &Core) -> usize {
+    cores
+        .iter()
+        .filter(|c| c.is_sibling(core) && c.allocated)
+        .count()
+}
+
+/// Fills one node after the other and the threads of a core before the
+/// next core, keeps the rest of the machine idle (or for other processes).
+pub struct Packed;
+
+impl Policy for Packed {
+    fn pick(
+        &self,
+        cores: &[Core],
+        affinity: Option<atopology::NodeId>,
+    ) -> Option<atopology::GlobalThreadId> {
+        candidates(cores, affinity)
+            .min_by_key(|c| {
+                (
+                    Reverse(allocated_on_node(cores, c.node)),
+                    Reverse(allocated_siblings(cores, c)),
+                    c.gtid,
+                )
+            })
+            .map(|c| c.gtid)
+    }
+}
+
+/// Balances the allocated cores across nodes (for memory bandwidth) and
+/// within a node across physical cores.
+pub struct Spread;
+
+impl Policy for Spread {
+    fn pick(
+        &self,
+        cores: &[Core],
+        affinity: Option<atopology::NodeId>,
+    ) -> Option<atopology::GlobalThreadId> {
+        candidates(cores, affinity)
+            .min_by_key(|c| {
+                (
+                    allocated_on_node(cores, c.node),
+                    allocated_siblings(cores, c),
+                    c.gtid,
+                )
+            })
+            .map(|c| c.gtid)
+    }
+}
+
+/// Hands out a thread whose siblings are idle while there are such
+/// threads, so processes don't share a physical core unless they have to.
+pub struct SmtAware;
+
+impl Policy for SmtAware {
+    fn pick(
+        &self,
+        cores: &[Core],
+        affinity: Option<atopology::NodeId>,
+    ) -> Option<atopology::GlobalThreadId> {
+        candidates(cores, affinity)
+            .min_by_key(|c| (allocated_siblings(cores, c), c.gtid))
+            .map(|c| c.gtid)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Two nodes with two cores with two threads each (gtid = node * 4 +
+    /// core * 2 + thread).
+    fn machine() -> Vec<Core> {
+        (0..8)
+            .map(|gtid| Core {
+                gtid,
+                node: gtid / 4,
+                package: gtid / 4,
+                core: (gtid / 2) % 2,
+                allocated: false,
+                online: true,
+            })
+            .collect()
+    }
+
+    /// Allocates `n` cores with `policy`, returns them in order.
+    fn allocate(policy: CorePolicy, cores: &mut [Core], n: usize) -> Vec<usize> {
+        (0..n)
+            .map(|_| {
+                let gtid = get(policy).pick(cores, None).expect("free core");
+                cores[gtid].allocated = true;
+                gtid
+            })
+            .collect()
+    }
+
+    #[test]
+    fn packed() {
+        let mut cores = machine();
+        assert_eq!(
+            allocate(CorePolicy::Packed, &mut cores, 8),
+            vec![0, 1, 2, 3, 4, 5, 6, 7]
+        );
+        assert_eq!(get(CorePolicy::Packed).pick(&cores, None), None);
+
+        // Continues where something is allocated already
+        let mut cores = machine();
+        cores[6].allocated = true;
+        assert_eq!(allocate(CorePolicy::Packed, &mut cores, 2), vec![7, 4]);
+    }
+
+    #[test]
+    fn spread() {
+        let mut cores = machine();
+        assert_eq!(
+            allocate(CorePolicy::Spread, &mut cores, 8),
+            vec![0, 4, 2, 6, 1, 5, 3, 7]
+        );
+    }
+
+    #[test]
+    fn smt_aware() {
+        let mut cores = machine();
+        assert_eq!(
+            allocate(CorePolicy::Smt, &mut cores, 8),
+            vec![0, 2, 4, 6, 1, 3, 5, 7]
+        );
+    }
+
+    #[test]
+    fn affinity_and_offline() {
+        let mut cores = machine();
+        cores[4].online = false;
+        for policy in CorePolicy::ALL.iter().copied() {
+            assert_eq!(get(policy).pick(&cores, Some(1)), Some(5));
+        }
+        assert_eq!(get(CorePolicy::Spread).pick(&cores, Some(2)), None);
+    }
+}
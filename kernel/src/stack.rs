@@ -11,9 +11,18 @@ use alloc::boxed::Box;
 
 use core::alloc::Layout;
 use core::slice;
+#[cfg(target_os = "none")]
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use x86::bits64::paging::BASE_PAGE_SIZE;
 
+#[cfg(target_os = "none")]
+use crate::error::KError;
+#[cfg(target_os = "none")]
+use crate::memory::vspace::MapAction;
+#[cfg(target_os = "none")]
+use crate::memory::{Frame, VAddr, KERNEL_BASE, LARGE_PAGE_SIZE};
+
 pub const STACK_ALIGNMENT: usize = 16;
 
 #[derive(Debug, Clone, Copy)]
@@ -139,3 +148,135 @@ unsafe impl Stack for OwnedStack {
         self.0.as_ptr() as *mut u8
     }
 }
+
+/// Where the kernel maps `GuardedStack`s (PML4 slot 133, after the big
+/// objects of the `KernelAllocator`).
+#[cfg(target_os = "none")]
+const GUARDED_STACKS_START: u64 = KERNEL_BASE + (2560 * x86::bits64::paging::HUGE_PAGE_SIZE) as u64;
+
+/// The next free window for a `GuardedStack`.
+#[cfg(target_os = "none")]
+static GUARDED_STACKS_SBRK: AtomicU64 = AtomicU64::new(GUARDED_STACKS_START);
+
+/// The value of the canary words at the low end of a `GuardedStack`.
+#[cfg(any(test, target_os = "none"))]
+const CANARY: u64 = 0x5eed_c0de_57ac_fa11;
+
+/// How many canary words a `GuardedStack` has.
+#[cfg(any(test, target_os = "none"))]
+const CANARY_WORDS: usize = 8;
+
+/// GuardedStack holds a stack that sits at the top of its own 2 MiB window
+/// of the kernel address space.
+///
+/// Everything in the window below the stack is unmapped, so running off the
+/// end faults instead of silently corrupting whatever was allocated next to
+/// it. The lowest `CANARY_WORDS` words of the stack hold canaries, which catch
+/// overflows that stop short of the guard (see `canaries_intact`).
+///
+/// The stack is never unmapped or freed (we only use them for the per-core
+/// stacks in the KCB).
+#[cfg(target_os = "none")]
+#[derive(Debug)]
+pub struct GuardedStack {
+    limit: *mut u8,
+    size: usize,
+}
+
+#[cfg(target_os = "none")]
+impl GuardedStack {
+    /// Maps a new stack with `size` accessible bytes (at most 2 MiB minus the
+    /// guard page) and places the canaries.
+    pub fn new(size: usize) -> Result<GuardedStack, KError> {
+        let (limit, _frame) = map_guarded(size, MapAction::ReadWriteKernel)?;
+        let stack = GuardedStack {
+            limit: limit.as_mut_ptr(),
+            size: crate::round_up!(size, BASE_PAGE_SIZE),
+        };
+        unsafe { place_canaries(stack.limit()) };
+        Ok(stack)
+    }
+
+    /// Are the canaries at the low end of the stack still there?
+    pub fn canaries_intact(&self) -> bool {
+        unsafe { canaries_intact(self.limit()) }
+    }
+}
+
+/// Maps `size` bytes of memory with `action` at the top of the next free
+/// window for guarded stacks, returns where the mapping starts and the
+/// memory behind it.
+#[cfg(target_os = "none")]
+pub fn map_guarded(size: usize, action: MapAction) -> Result<(VAddr, Frame), KError> {
+    use crate::arch::Platform;
+    use crate::arch_interface::Arch;
+
+    let size = crate::round_up!(size, BASE_PAGE_SIZE);
+    assert!(size > 0 && size <= LARGE_PAGE_SIZE - BASE_PAGE_SIZE);
+
+    let frame = {
+        let kcb = crate::kcb::get_kcb();
+        let mut pmanager = kcb.try_mem_manager()?;
+        pmanager.allocate_large_page()?
+    }; // `map_kernel` might try to re-acquire mem_manager
+
+    let window = GUARDED_STACKS_SBRK.fetch_add(LARGE_PAGE_SIZE as u64, Ordering::Relaxed);
+    let limit = VAddr::from(window + (LARGE_PAGE_SIZE - size) as u64);
+    Platform::map_kernel(limit, (frame.base, size), action)?;
+    Ok((limit, frame))
+}
+
+#[cfg(target_os = "none")]
+unsafe impl Stack for GuardedStack {
+    #[inline(always)]
+    fn base(&self) -> *mut u8 {
+        unsafe { self.limit.add(self.size) }
+    }
+
+    #[inline(always)]
+    fn limit(&self) -> *mut u8 {
+        self.limit
+    }
+}
+
+/// Writes the canary words at `limit` (the low end of a stack).
+///
+/// # Safety
+/// `limit` must point to at least `CANARY_WORDS` writable, aligned words.
+#[cfg(any(test, target_os = "none"))]
+unsafe fn place_canaries(limit: *mut u8) {
+    let words = limit as *mut u64;
+    for i in 0..CANARY_WORDS {
+        words.add(i).write_volatile(CANARY);
+    }
+}
+
+/// Are the canary words at `limit` unchanged?
+///
+/// # Safety
+/// `limit` must point to at least `CANARY_WORDS` readable, aligned words.
+#[cfg(any(test, target_os = "none"))]
+unsafe fn canaries_intact(limit: *const u8) -> bool {
+    let words = limit as *const u64;
+    (0..CANARY_WORDS).all(|i| words.add(i).read_volatile() == CANARY)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn canaries() {
+        let stack = OwnedStack::new(4 * BASE_PAGE_SIZE);
+        unsafe {
+            place_canaries(stack.limit());
+            assert!(canaries_intact(stack.limit()));
+
+            // Anything the stack pushes down there is caught
+            (stack.limit() as *mut u64)
+                .add(CANARY_WORDS - 1)
+                .write_volatile(0x1);
+            assert!(!canaries_intact(stack.limit()));
+        }
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Per-core event counters (system calls, interrupts, context switches, TLB
+//! shootdowns and idle residency).
+//!
+//! Every core only bumps the counters in its own KCB, we go through all of
+//! them when someone asks (`System::stats`).
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+
+use kpi::system::{CoreStats, IdleState};
+
+use crate::arch::MAX_CORES;
+use crate::error::KError;
+
+/// How many system call numbers we count (`kpi::SystemCall`).
+pub const SYSCALLS: usize = 16;
+
+/// How many interrupt vectors we count.
+pub const IRQS: usize = 256;
+
+/// How many idle states we count (`kpi::system::IdleState`).
+pub const IDLE_STATES: usize = IdleState::ALL.len();
+
+/// The counters of a core (lives in the KCB).
+pub struct Stats {
+    syscalls: [AtomicU64; SYSCALLS],
+    irqs: [AtomicU64; IRQS],
+    context_switches: AtomicU64,
+    tlb_shootdowns_sent: AtomicU64,
+    tlb_shootdowns_received: AtomicU64,
+    tlb_shootdown_cycles: AtomicU64,
+    idle_entries: [AtomicU64; IDLE_STATES],
+    idle_ns: [AtomicU64; IDLE_STATES],
+}
+
+impl Stats {
+    pub const fn new() -> Stats {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Stats {
+            syscalls: [ZERO; SYSCALLS],
+            irqs: [ZERO; IRQS],
+            context_switches: ZERO,
+            tlb_shootdowns_sent: ZERO,
+            tlb_shootdowns_received: ZERO,
+            tlb_shootdown_cycles: ZERO,
+            idle_entries: [ZERO; IDLE_STATES],
+            idle_ns: [ZERO; IDLE_STATES],
+        }
+    }
+
+    pub fn syscall(&self, function: u64) {
+        if let Some(counter) = self.syscalls.get(function as usize) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn irq(&self, vector: u64) {
+        if let Some(counter) = self.irqs.get(vector as usize) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn context_switch(&self) {
+        self.context_switches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tlb_shootdowns_sent(&self, cores: u64) {
+        self.tlb_shootdowns_sent.fetch_add(cores, Ordering::Relaxed);
+    }
+
+    pub fn tlb_shootdown_received(&self) {
+        self.tlb_shootdowns_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The TLB shootdown IPI handler took `cycles`.
+    pub fn tlb_shootdown_cycles(&self, cycles: u64) {
+        self.tlb_shootdown_cycles
+            .fetch_add(cycles, Ordering::Relaxed);
+    }
+
+    /// The core went idle in `state`.
+    pub fn idle_entered(&self, state: IdleState) {
+        self.idle_entries[state as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The core woke up after `ns` in `state`.
+    pub fn idle_exited(&self, state: IdleState, ns: u64) {
+        self.idle_ns[state as usize].fetch_add(ns, Ordering::Relaxed);
+    }
+
+    /// How long the core was idle in total (ns).
+    pub fn idle_ns(&self) -> u64 {
+        self.idle_ns
+            .iter()
+            .map(|ns| ns.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Copies the current values.
+    pub fn snapshot(&self, id: atopology::GlobalThreadId) -> Result<CoreStats, KError> {
+        fn load_all(counters: &[AtomicU64]) -> Result<Vec<u64>, KError> {
+            let mut values = Vec::try_with_capacity(counters.len())?;
+            for counter in counters {
+                values.try_push(counter.load(Ordering::Relaxed))?;
+            }
+            Ok(values)
+        }
+
+        Ok(CoreStats {
+            id,
+            syscalls: load_all(&self.syscalls)?,
+            irqs: load_all(&self.irqs)?,
+            context_switches: self.context_switches.load(Ordering::Relaxed),
+            tlb_shootdowns_sent: self.tlb_shootdowns_sent.load(Ordering::Relaxed),
+            tlb_shootdowns_received: self.tlb_shootdowns_received.load(Ordering::Relaxed),
+            tlb_shootdown_cycles: self.tlb_shootdown_cycles.load(Ordering::Relaxed),
+            idle_entries: load_all(&self.idle_entries)?,
+            idle_ns: load_all(&self.idle_ns)?,
+        })
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_REGISTERED: AtomicPtr<Stats> = AtomicPtr::new(ptr::null_mut());
+
+/// The counters of every core that installed its KCB.
+static CORES: [AtomicPtr<Stats>; MAX_CORES] = [NOT_REGISTERED; MAX_CORES];
+
+/// Makes the counters of core `gtid` visible to `collect`.
+pub fn register(gtid: atopology::GlobalThreadId, stats: &'static Stats) {
+    if let Some(slot) = CORES.get(gtid) {
+        slot.store(stats as *const Stats as *mut Stats, Ordering::Release);
+    }
+}
+
+/// Current counters of all cores.
+pub fn collect() -> Result<Vec<CoreStats>, KError> {
+    let mut all = Vec::new();
+    for (gtid, slot) in CORES.iter().enumerate() {
+        let stats = slot.load(Ordering::Acquire);
+        if !stats.is_null() {
+            // Safe: registered KCBs live forever
+            let stats = unsafe { &*stats };
+            all.try_push(stats.snapshot(gtid)?)?;
+        }
+    }
+    Ok(all)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counters() {
+        let stats = Stats::new();
+        stats.syscall(1);
+        stats.syscall(1);
+        stats.syscall(1000);
+        stats.irq(0x20);
+        stats.tlb_shootdowns_sent(3);
+        stats.tlb_shootdown_received();
+        stats.tlb_shootdown_cycles(50);
+        stats.idle_entered(IdleState::C1E);
+        stats.idle_exited(IdleState::C1E, 1200);
+
+        let snapshot = stats.snapshot(2).unwrap();
+        assert_eq!(snapshot.id, 2);
+        assert_eq!(snapshot.syscalls.len(), SYSCALLS);
+        assert_eq!(snapshot.syscalls[1], 2);
+        assert_eq!(snapshot.irqs[0x20], 1);
+        assert_eq!(snapshot.irqs.iter().sum::<u64>(), 1);
+        assert_eq!(snapshot.context_switches, 0);
+        assert_eq!(snapshot.tlb_shootdowns_sent, 3);
+        assert_eq!(snapshot.tlb_shootdowns_received, 1);
+        assert_eq!(snapshot.tlb_shootdown_cycles, 50);
+        assert_eq!(snapshot.idle_entries, alloc::vec![0, 0, 1, 0]);
+        assert_eq!(snapshot.idle_ns[IdleState::C1E as usize], 1200);
+        assert_eq!(stats.idle_ns(), 1200);
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Synchronization primitives for the structures we model-check.
+//!
+//! With the `loom` feature (hosted builds only) these are the ones of
+//! [loom](https://github.com/tokio-rs/loom), which runs a test under every
+//! interleaving of its threads (and the reorderings the memory model allows)
+//! and fails if one of them breaks an assertion or never finishes. Without
+//! it they are the ones we use anyways.
+//!
+//! Only structures we create at run-time can use them: the loom types don't
+//! have `const` constructors and only work inside `loom::model`. So the
+//! `loom` feature is for the model-checking tests only (`cargo test
+//! --features loom -- loom_`), the kernel and its other tests don't run with
+//! it.
+//!
+//! What we check this way:
+//!
+//! - the queues the deterministic allocator of the replicas hands out
+//!   allocations with (`mpmc`, `memory::detmem`),
+//! - the hand-shake of TLB shootdowns (`memory::shootdown`),
+//! - waiting for and signaling events (`event`).
+//!
+//! Node-replication itself and `crate::mutex::Mutex` (a queued spin-lock) use
+//! `core` atomics loom doesn't see, the structures above take their lock
+//! from here instead.
+
+#[cfg(not(feature = "loom"))]
+pub use crate::mutex::Mutex;
+#[cfg(not(feature = "loom"))]
+pub use core::hint::spin_loop;
+#[cfg(not(feature = "loom"))]
+pub use core::sync::atomic;
+
+#[cfg(feature = "loom")]
+pub use self::model::Mutex;
+#[cfg(feature = "loom")]
+pub use loom::hint::spin_loop;
+#[cfg(feature = "loom")]
+pub use loom::sync::atomic;
+
+/// A loom mutex with the interface of `crate::mutex::Mutex`.
+#[cfg(feature = "loom")]
+mod model {
+    pub struct Mutex<T> {
+        inner: loom::sync::Mutex<T>,
+    }
+
+    pub type MutexGuard<'a, T> = loom::sync::MutexGuard<'a, T>;
+
+    impl<T> Mutex<T> {
+        pub fn new(_name: &'static str, value: T) -> Mutex<T> {
+            Mutex {
+                inner: loom::sync::Mutex::new(value),
+            }
+        }
+
+        pub fn lock(&self) -> MutexGuard<T> {
+            self.inner.lock().expect("Poisoned lock")
+        }
+    }
+}
+
+/// Runs `f` under every schedule loom comes up with.
+#[cfg(all(test, feature = "loom"))]
+pub fn model<F: Fn() + Sync + Send + 'static>(f: F) {
+    loom::model(f)
+}
@@ -0,0 +1,92 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The system calls every process may still make (see `kpi::filter`).
+//!
+//! The syscall handler checks every call against the filter of the process
+//! before it dispatches it, so the filters are plain atomics here (a bit for
+//! every operation) instead of state in the replicated `NrProcess`. Filters
+//! only ever lose bits: restricting is a `fetch_and`, two cores of a process
+//! that restrict it at the same time end up with what both allowed.
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use kpi::filter::{SyscallFilter, MAX_SYSCALLS};
+
+use crate::process::{Pid, MAX_PROCESSES};
+
+struct Filter {
+    /// Set once the process restricted itself (saves looking at `allowed`
+    /// for everyone else).
+    restricted: AtomicBool,
+    allowed: [AtomicU64; MAX_SYSCALLS],
+}
+
+impl Filter {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ALL: AtomicU64 = AtomicU64::new(u64::MAX);
+
+    const fn new() -> Filter {
+        Filter {
+            restricted: AtomicBool::new(false),
+            allowed: [Filter::ALL; MAX_SYSCALLS],
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNRESTRICTED: Filter = Filter::new();
+
+static FILTERS: [Filter; MAX_PROCESSES] = [UNRESTRICTED; MAX_PROCESSES];
+
+/// Allows everything for a new process `pid`.
+pub fn reset(pid: Pid) {
+    let filter = &FILTERS[pid];
+    for ops in filter.allowed.iter() {
+        ops.store(u64::MAX, Ordering::Relaxed);
+    }
+    filter.restricted.store(false, Ordering::Release);
+}
+
+/// From now on, `pid` may only make the system calls its filter and
+/// `filter` allow.
+pub fn restrict(pid: Pid, filter: &SyscallFilter) {
+    let current = &FILTERS[pid];
+    for (call, ops) in current.allowed.iter().enumerate() {
+        ops.fetch_and(filter.operations(call), Ordering::AcqRel);
+    }
+    current.restricted.store(true, Ordering::Release);
+}
+
+/// The operations `pid` may still make (see `SyscallFilter::operations`),
+/// `None` if it never restricted itself.
+pub fn current(pid: Pid) -> Option<[u64; MAX_SYSCALLS]> {
+    let filter = &FILTERS[pid];
+    if !filter.restricted.load(Ordering::Acquire) {
+        return None;
+    }
+    let mut allowed = [0; MAX_SYSCALLS];
+    for (ops, current) in allowed.iter_mut().zip(filter.allowed.iter()) {
+        *ops = current.load(Ordering::Relaxed);
+    }
+    Some(allowed)
+}
+
+/// May `pid` make system call `call` with arguments `arg1` and `arg3`?
+pub fn allows(pid: Pid, call: u64, arg1: u64, arg3: u64) -> bool {
+    let filter = &FILTERS[pid];
+    !filter.restricted.load(Ordering::Acquire)
+        || SyscallFilter::allows_in(
+            |call| {
+                filter
+                    .allowed
+                    .get(call)
+                    .map_or(0, |ops| ops.load(Ordering::Relaxed))
+            },
+            call,
+            arg1,
+            arg3,
+        )
+}
@@ -0,0 +1,349 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Clock sources: free-running counters we can read time from.
+//!
+//! The architecture registers every counter it finds (e.g., the TSC and
+//! the HPET) and we use the one with the best rating, unless the command
+//! line asks for a specific one (`clocksource=hpet`). `watchdog` compares
+//! the current source against the next best one and switches away from it
+//! if they drift apart (e.g., a TSC that isn't as invariant as it claims).
+
+use core::time::Duration;
+
+use arrayvec::ArrayVec;
+use log::{info, warn};
+use spin::{Mutex, RwLock};
+
+use crate::error::KError;
+
+/// Maximum number of registered clock sources.
+const MAX_SOURCES: usize = 4;
+
+/// How far (in parts per million) two sources can drift apart before we
+/// stop trusting the current one.
+pub const MAX_DRIFT_PPM: u64 = 500;
+
+/// The watchdog only compares sources over intervals at least this long
+/// (shorter intervals are too noisy).
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A counter that increments at a fixed frequency.
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Reads the counter.
+    fn read(&self) -> u64;
+
+    /// Frequency of the counter in Hz.
+    fn frequency(&self) -> u64;
+
+    /// Bits of the counter that are implemented (it wraps around after
+    /// `mask`).
+    fn mask(&self) -> u64 {
+        u64::MAX
+    }
+
+    /// How good the source is (higher is better).
+    fn rating(&self) -> u32;
+}
+
+/// Converts `ticks` of a counter with `frequency` to a duration.
+pub fn ticks_to_duration(ticks: u64, frequency: u64) -> Duration {
+    let nanos = ticks as u128 * 1_000_000_000 / frequency as u128;
+    Duration::new(
+        (nanos / 1_000_000_000) as u64,
+        (nanos % 1_000_000_000) as u32,
+    )
+}
+
+/// Converts `duration` to ticks of a counter with `frequency`.
+pub fn duration_to_ticks(duration: Duration, frequency: u64) -> u64 {
+    (duration.as_nanos() * frequency as u128 / 1_000_000_000) as u64
+}
+
+struct Registration {
+    source: &'static dyn ClockSource,
+    /// The watchdog caught it drifting.
+    unstable: bool,
+}
+
+struct Registry {
+    sources: ArrayVec<Registration, MAX_SOURCES>,
+    /// Index of the source we use.
+    current: Option<usize>,
+    /// Name of the source the user asked for.
+    preferred: Option<&'static str>,
+}
+
+impl Registry {
+    /// Picks the preferred source (if it is registered and stable),
+    /// otherwise the stable source with the best rating.
+    fn best(&self) -> Option<usize> {
+        let stable = || self.sources.iter().enumerate().filter(|(_, r)| !r.unstable);
+        stable()
+            .find(|(_, r)| Some(r.source.name()) == self.preferred)
+            .or_else(|| stable().max_by_key(|(_, r)| r.source.rating()))
+            .map(|(idx, _)| idx)
+    }
+
+    /// Switches to the best source.
+    fn reselect(&mut self) {
+        let best = self.best();
+        if best != self.current {
+            self.current = best;
+            if let Some(idx) = best {
+                info!("Using clocksource {}", self.sources[idx].source.name());
+            }
+        }
+    }
+}
+
+static REGISTRY: RwLock<Registry> = RwLock::new(Registry {
+    sources: ArrayVec::new_const(),
+    current: None,
+    preferred: None,
+});
+
+/// Counter values of the current source and the reference source the last
+/// time the watchdog ran.
+struct WatchdogState {
+    current: usize,
+    reference: usize,
+    current_start: u64,
+    reference_start: u64,
+}
+
+static WATCHDOG: Mutex<Option<WatchdogState>> = Mutex::new(None);
+
+/// Makes `source` available (we switch to it if it's the best one).
+pub fn register(source: &'static dyn ClockSource) -> Result<(), KError> {
+    let mut registry = REGISTRY.write();
+    registry
+        .sources
+        .try_push(Registration {
+            source,
+            unstable: false,
+        })
+        .map_err(|_| KError::CapacityOverflow)?;
+    info!(
+        "Registered clocksource {} ({} Hz)",
+        source.name(),
+        source.frequency()
+    );
+    registry.reselect();
+    Ok(())
+}
+
+/// Prefers the source called `name` (now and when it gets registered
+/// later).
+pub fn select(name: &'static str) -> Result<(), KError> {
+    let mut registry = REGISTRY.write();
+    registry.preferred = Some(name);
+    registry.reselect();
+    match registry.current {
+        Some(idx) if registry.sources[idx].source.name() == name => Ok(()),
+        _ => Err(KError::NotSupported),
+    }
+}
+
+/// The source we currently use.
+pub fn current() -> Option<&'static dyn ClockSource> {
+    let registry = REGISTRY.read();
+    registry.current.map(|idx| registry.sources[idx].source)
+}
+
+/// Finds a registered source by name.
+pub fn get(name: &str) -> Option<&'static dyn ClockSource> {
+    REGISTRY
+        .read()
+        .sources
+        .iter()
+        .find(|r| r.source.name() == name)
+        .map(|r| r.source)
+}
+
+/// Drift (in ppm) between `elapsed` ticks of `source` and `reference`.
+fn drift_ppm(
+    source: &dyn ClockSource,
+    elapsed: u64,
+    reference: &dyn ClockSource,
+    reference_elapsed: u64,
+) -> u64 {
+    let a = ticks_to_duration(elapsed, source.frequency()).as_nanos() as i128;
+    let b = ticks_to_duration(reference_elapsed, reference.frequency()).as_nanos() as i128;
+    if b == 0 {
+        return 0;
+    }
+    ((a - b).abs() * 1_000_000 / b) as u64
+}
+
+/// Checks that the current source agrees with the next best one, switches
+/// sources if it drifted (call this periodically, e.g., from the timer
+/// interrupt).
+pub fn watchdog() {
+    let (mut state, mut registry) = match (WATCHDOG.try_lock(), REGISTRY.try_write()) {
+        (Some(state), Some(registry)) => (state, registry),
+        // We interrupted someone holding the locks, try again next time
+        _ => return,
+    };
+
+    let current = match registry.current {
+        Some(current) => current,
+        None => return,
+    };
+    let reference = registry
+        .sources
+        .iter()
+        .enumerate()
+        .filter(|(idx, r)| *idx != current && !r.unstable)
+        .max_by_key(|(_, r)| r.source.rating())
+        .map(|(idx, _)| idx);
+    let reference = match reference {
+        Some(reference) => reference,
+        None => return,
+    };
+
+    let (source, reference_source) = (
+        registry.sources[current].source,
+        registry.sources[reference].source,
+    );
+    let (now, reference_now) = (source.read(), reference_source.read());
+    let restart = |state: &mut Option<WatchdogState>| {
+        *state = Some(WatchdogState {
+            current,
+            reference,
+            current_start: now,
+            reference_start: reference_now,
+        });
+    };
+
+    match state.as_ref() {
+        Some(s) if s.current == current && s.reference == reference => {
+            let elapsed = now.wrapping_sub(s.current_start) & source.mask();
+            let reference_elapsed =
+                reference_now.wrapping_sub(s.reference_start) & reference_source.mask();
+            if ticks_to_duration(reference_elapsed, reference_source.frequency())
+                < WATCHDOG_INTERVAL
+            {
+                return;
+            }
+
+            let drift = drift_ppm(source, elapsed, reference_source, reference_elapsed);
+            if drift > MAX_DRIFT_PPM {
+                warn!(
+                    "Clocksource {} drifted {} ppm against {}, marking it unstable",
+                    source.name(),
+                    drift,
+                    reference_source.name()
+                );
+                registry.sources[current].unstable = true;
+                registry.reselect();
+                *state = None;
+            } else {
+                restart(&mut state);
+            }
+        }
+        _ => restart(&mut state),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    struct FakeSource {
+        name: &'static str,
+        counter: AtomicU64,
+        frequency: u64,
+        rating: u32,
+    }
+
+    impl FakeSource {
+        const fn new(name: &'static str, frequency: u64, rating: u32) -> FakeSource {
+            FakeSource {
+                name,
+                counter: AtomicU64::new(0),
+                frequency,
+                rating,
+            }
+        }
+
+        fn advance(&self, duration: Duration) {
+            let ticks = duration_to_ticks(duration, self.frequency);
+            self.counter.fetch_add(ticks, Ordering::Relaxed);
+        }
+    }
+
+    impl ClockSource for FakeSource {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn read(&self) -> u64 {
+            self.counter.load(Ordering::Relaxed)
+        }
+
+        fn frequency(&self) -> u64 {
+            self.frequency
+        }
+
+        fn rating(&self) -> u32 {
+            self.rating
+        }
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(
+            ticks_to_duration(3_000_000_000, 2_000_000_000),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(duration_to_ticks(Duration::from_micros(1), 14_318_180), 14);
+        assert_eq!(
+            duration_to_ticks(Duration::from_secs(3600), 4_000_000_000),
+            14_400_000_000_000
+        );
+    }
+
+    #[test]
+    fn drift() {
+        let a = FakeSource::new("a", 1_000_000, 1);
+        let b = FakeSource::new("b", 2_000_000, 1);
+        assert_eq!(drift_ppm(&a, 1_000_000, &b, 2_000_000), 0);
+        assert_eq!(drift_ppm(&a, 1_001_000, &b, 2_000_000), 1000);
+    }
+
+    /// Registration, selection and the watchdog share the global registry
+    /// so they are tested together.
+    #[test]
+    fn select_and_watchdog() {
+        static SLOW: FakeSource = FakeSource::new("test-slow", 10_000_000, 100);
+        static FAST: FakeSource = FakeSource::new("test-fast", 3_000_000_000, 300);
+
+        register(&SLOW).unwrap();
+        assert_eq!(current().unwrap().name(), "test-slow");
+        register(&FAST).unwrap();
+        assert_eq!(current().unwrap().name(), "test-fast");
+        assert_eq!(get("test-slow").unwrap().frequency(), 10_000_000);
+
+        assert!(select("test-slow").is_ok());
+        assert_eq!(current().unwrap().name(), "test-slow");
+        assert!(select("test-missing").is_err());
+        assert_eq!(current().unwrap().name(), "test-fast");
+
+        // Both agree
+        watchdog();
+        SLOW.advance(Duration::from_secs(1));
+        FAST.advance(Duration::from_secs(1));
+        watchdog();
+        assert_eq!(current().unwrap().name(), "test-fast");
+
+        // The fast source runs 1% too fast
+        SLOW.advance(Duration::from_secs(1));
+        FAST.advance(Duration::from_millis(1010));
+        watchdog();
+        assert_eq!(current().unwrap().name(), "test-slow");
+    }
+}
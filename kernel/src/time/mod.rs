@@ -0,0 +1,170 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Wall-clock time.
+//!
+//! The architecture reads the current time once during boot (from the RTC
+//! or the hypervisor) and we remember which `rawtime::Instant` it belongs
+//! to. Wall-clock time is then that epoch plus the (TSC based) time that
+//! elapsed since, so reading it is cheap and never goes backwards.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use log::info;
+use spin::Once;
+
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub mod clocksource;
+
+/// Wall-clock time at boot (since the UNIX epoch) and when we read it.
+static BOOT_WALLCLOCK: Once<(Duration, rawtime::Instant)> = Once::new();
+
+/// How long we were suspended in total (in ns, the TSC doesn't count that
+/// time but the wall-clock has to).
+static SUSPENDED: AtomicU64 = AtomicU64::new(0);
+
+/// A point in time in UTC (split into calendar fields).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DateTime {
+    pub year: u32,
+    /// 1..=12
+    pub month: u32,
+    /// 1..=31
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    /// Seconds since the UNIX epoch.
+    #[cfg_attr(not(target_os = "none"), allow(dead_code))]
+    pub fn unix_time(&self) -> u64 {
+        // Days from civil (http://howardhinnant.github.io/date_algorithms.html)
+        let (year, month) = if self.month <= 2 {
+            (self.year as i64 - 1, self.month as i64 + 9)
+        } else {
+            (self.year as i64, self.month as i64 - 3)
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+
+        (days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64)
+            as u64
+    }
+
+    /// The date and time `unix_time` seconds after the UNIX epoch.
+    pub fn from_unix_time(unix_time: u64) -> DateTime {
+        let days = (unix_time / 86400) as i64 + 719468;
+        let seconds = unix_time % 86400;
+
+        let era = days.div_euclid(146097);
+        let day_of_era = days - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+        DateTime {
+            year: year as u32,
+            month: month as u32,
+            day: day as u32,
+            hour: (seconds / 3600) as u32,
+            minute: (seconds / 60 % 60) as u32,
+            second: (seconds % 60) as u32,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Sets the wall-clock time (`now` is the time since the UNIX epoch,
+/// `source` where it came from), only the first call has an effect.
+pub fn init(now: Duration, source: &str) {
+    let mut first = false;
+    BOOT_WALLCLOCK.call_once(|| {
+        first = true;
+        (now, rawtime::Instant::now())
+    });
+    if first {
+        info!(
+            "Wall-clock time is {} (from {})",
+            DateTime::from_unix_time(now.as_secs()),
+            source
+        );
+    }
+}
+
+/// The current time since the UNIX epoch (`None` if we don't know the
+/// time).
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn wallclock() -> Option<Duration> {
+    BOOT_WALLCLOCK
+        .get()
+        .map(|(epoch, at)| *epoch + at.elapsed() + suspended())
+}
+
+/// Total time we spent suspended.
+fn suspended() -> Duration {
+    Duration::from_nanos(SUSPENDED.load(Ordering::Relaxed))
+}
+
+/// Moves the wall-clock forward by `duration` (the time we were just
+/// suspended for).
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn add_suspended(duration: Duration) {
+    SUSPENDED.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unix_time() {
+        let epoch = DateTime {
+            year: 1970,
+            month: 1,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        };
+        assert_eq!(epoch.unix_time(), 0);
+
+        let leap_day = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 13,
+            minute: 37,
+            second: 42,
+        };
+        assert_eq!(leap_day.unix_time(), 1709213862);
+        assert_eq!(DateTime::from_unix_time(1709213862), leap_day);
+        assert_eq!(leap_day.to_string(), "2024-02-29 13:37:42 UTC");
+    }
+
+    #[test]
+    fn round_trip() {
+        for t in (0..5_000_000_000u64).step_by(86_399 * 37) {
+            assert_eq!(DateTime::from_unix_time(t).unix_time(), t);
+        }
+    }
+}
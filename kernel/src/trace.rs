@@ -0,0 +1,158 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Records the system calls of a process to replay them later.
+//!
+//! A process starts (and stops) recording its own system calls with
+//! `Debug::trace_syscalls`. We keep the number, arguments, results and time
+//! stamps of every call (`kpi::trace::TraceEntry`) and print the trace when
+//! the process exits. It is also in `/proc/syscall_trace`.
+//!
+//! To replay a trace, boot a fresh kernel with the trace as the
+//! `syscall.trace` module: its contents show up in `/proc/syscall_replay`
+//! and the same binary can re-issue the calls (`Debug::reissue`) and compare
+//! the results.
+
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use fallible_collections::vec::FallibleVec;
+use klogger::sprintln;
+use kpi::trace::TraceEntry;
+use kpi::SystemCallError;
+use spin::{Mutex, Once};
+
+use crate::error::KError;
+use crate::process::Pid;
+
+/// The name of the module that holds a trace to replay.
+pub const REPLAY_MODULE: &str = "syscall.trace";
+
+/// How many system calls we record at most.
+const MAX_ENTRIES: usize = 1 << 16;
+
+/// `TRACED` if we don't record anything.
+const NOBODY: u64 = u64::MAX;
+
+/// The process we record.
+static TRACED: AtomicU64 = AtomicU64::new(NOBODY);
+
+/// The process we recorded `TRACE` for (`TRACED` or the last one).
+static OWNER: AtomicU64 = AtomicU64::new(NOBODY);
+
+/// Calls we didn't record (out of memory or `MAX_ENTRIES`).
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+static TRACE: Mutex<Vec<TraceEntry>> = Mutex::new(Vec::new());
+
+/// The trace we boot with (if any).
+static REPLAY: Once<&'static str> = Once::new();
+
+/// Adds `/proc/syscall_trace` (and `/proc/syscall_replay` with `replay`,
+/// the contents of the `REPLAY_MODULE`).
+pub fn init(replay: Option<&'static [u8]>) -> Result<(), KError> {
+    crate::procfs::register("/proc/syscall_trace", proc_syscall_trace)?;
+
+    if let Some(replay) = replay {
+        let replay = core::str::from_utf8(replay).map_err(|_| KError::InvalidFile)?;
+        REPLAY.call_once(|| replay);
+        crate::procfs::register("/proc/syscall_replay", proc_syscall_replay)?;
+    }
+
+    Ok(())
+}
+
+/// Starts (discarding the previous trace) or stops recording the system
+/// calls of `pid`.
+pub fn set(pid: Pid, enable: bool) -> Result<(), KError> {
+    let pid = pid as u64;
+    if enable {
+        TRACED
+            .compare_exchange(NOBODY, pid, Ordering::AcqRel, Ordering::Relaxed)
+            .or_else(|traced| if traced == pid { Ok(pid) } else { Err(()) })
+            .map_err(|_| KError::TraceBusy)?;
+        TRACE.lock().clear();
+        DROPPED.store(0, Ordering::Relaxed);
+        OWNER.store(pid, Ordering::Relaxed);
+    } else {
+        TRACED
+            .compare_exchange(pid, NOBODY, Ordering::AcqRel, Ordering::Relaxed)
+            .map_err(|_| KError::TraceBusy)?;
+    }
+
+    Ok(())
+}
+
+/// Do we record the system calls of `pid`?
+#[inline]
+pub fn traced(pid: Pid) -> bool {
+    TRACED.load(Ordering::Relaxed) == pid as u64
+}
+
+/// Is anybody recorded?
+#[inline]
+pub fn enabled() -> bool {
+    TRACED.load(Ordering::Relaxed) != NOBODY
+}
+
+/// Records that system call `function` with `args` returned `status`.
+pub fn record(
+    function: u64,
+    args: [u64; 5],
+    status: &Result<(u64, u64), KError>,
+    start: u64,
+    end: u64,
+) {
+    let (error, ret) = match status {
+        Ok((r1, r2)) => (SystemCallError::Ok, [*r1, *r2]),
+        Err(e) => (SystemCallError::from(e.clone()), [0, 0]),
+    };
+    let entry = TraceEntry {
+        function,
+        args,
+        error: error as u64,
+        ret,
+        start,
+        end,
+    };
+
+    let mut trace = TRACE.lock();
+    if trace.len() >= MAX_ENTRIES || trace.try_push(entry).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Prints the trace of `pid` on the console (if we recorded one).
+pub fn dump(pid: Pid) {
+    if OWNER.load(Ordering::Relaxed) != pid as u64 {
+        return;
+    }
+
+    sprintln!("===== syscall trace =====");
+    for entry in TRACE.lock().iter() {
+        sprintln!("{}", entry);
+    }
+    sprintln!("===== end syscall trace =====");
+
+    let dropped = DROPPED.load(Ordering::Relaxed);
+    if dropped > 0 {
+        sprintln!("Dropped {} system calls from the trace", dropped);
+    }
+}
+
+/// Contents of `/proc/syscall_trace`.
+fn proc_syscall_trace(out: &mut String) -> fmt::Result {
+    for entry in TRACE.lock().iter() {
+        writeln!(out, "{}", entry)?;
+    }
+    Ok(())
+}
+
+/// Contents of `/proc/syscall_replay`.
+fn proc_syscall_replay(out: &mut String) -> fmt::Result {
+    out.write_str(REPLAY.get().copied().unwrap_or(""))
+}
@@ -6,6 +6,10 @@ use log::trace;
 use driverkit::{DriverControl, DriverState};
 use x86::apic::x2apic::X2APIC;
 use x86::apic::{ApicControl, ApicId, Icr};
+use x86::msr::{
+    rdmsr, wrmsr, IA32_X2APIC_CUR_COUNT, IA32_X2APIC_DIV_CONF, IA32_X2APIC_INIT_COUNT,
+    IA32_X2APIC_LVT_TIMER,
+};
 
 /// An x2APIC driver
 #[derive(Debug)]
@@ -26,6 +30,27 @@ impl Default for X2APICDriver {
     }
 }
 
+impl X2APICDriver {
+    /// Arm the APIC timer to fire once after `count` ticks of the bus
+    /// clock (undivided).
+    ///
+    /// A `count` of 0 stops the timer.
+    pub fn timer_oneshot(&mut self, count: u32) {
+        unsafe {
+            // Divide by 1
+            wrmsr(IA32_X2APIC_DIV_CONF, 0b1011);
+            // One-shot mode (bits 17-18 are zero), unmasked
+            wrmsr(IA32_X2APIC_LVT_TIMER, self.timer_vector as u64);
+            wrmsr(IA32_X2APIC_INIT_COUNT, count as u64);
+        }
+    }
+
+    /// Ticks left until the APIC timer fires.
+    pub fn timer_count(&self) -> u32 {
+        unsafe { rdmsr(IA32_X2APIC_CUR_COUNT) as u32 }
+    }
+}
+
 impl crate::ApicDriver for X2APICDriver {
     /// Is a bootstrap processor?
     fn bsp(&self) -> bool {
@@ -11,12 +11,19 @@
 //! architectural targets). In a best-case scenario this
 //! just works, but it's best if these structs stay plain-old-data
 //! without any implementations etc.
-#![no_std]
+//!
+//! The kernel can also be started by bootloaders that speak a standard
+//! protocol (multiboot2, e.g., GRUB2, and Limine). `multiboot2` and
+//! `limine` turn what those pass into the same `KernelArgs`.
+#![cfg_attr(not(test), no_std)]
 #![feature(const_mut_refs)]
 extern crate alloc;
 
 use alloc::vec::Vec;
 
+pub mod limine;
+pub mod multiboot2;
+
 /// Describes an ELF binary we loaded from the UEFI image into memory.
 #[derive(Eq, PartialEq, Clone)]
 pub struct Module {
@@ -107,6 +114,59 @@ impl core::fmt::Debug for Module {
     }
 }
 
+/// How the pixels of a framebuffer are laid out.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PixelFormat {
+    /// 32-bit pixels, red in the lowest byte.
+    Rgb,
+    /// 32-bit pixels, blue in the lowest byte.
+    Bgr,
+    /// 32-bit pixels with the given masks for the colors.
+    Bitmask { red: u32, green: u32, blue: u32 },
+}
+
+/// A linear framebuffer the bootloader set up.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Framebuffer {
+    /// Physical address of the first pixel.
+    pub paddr: x86::bits64::paging::PAddr,
+    /// Size in bytes.
+    pub size: usize,
+    /// Visible pixels per line.
+    pub width: usize,
+    /// Number of lines.
+    pub height: usize,
+    /// Pixels per line (including the invisible ones).
+    pub stride: usize,
+    pub format: PixelFormat,
+}
+
+/// Which boot protocol started the kernel.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BootProtocol {
+    /// Our own UEFI bootloader.
+    Uefi,
+    Multiboot2,
+    Limine,
+    /// The kernel that ran before us (it loaded us the way our bootloader
+    /// does).
+    Kexec,
+}
+
+/// Why we couldn't make sense of the boot information.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BootInfoError {
+    /// The structure doesn't look like what the protocol describes.
+    Malformed,
+    /// `mm_iter` has no space for more memory regions.
+    TooManyRegions,
+    /// More modules than `KernelArgs::MAX_MODULES`.
+    TooManyModules,
+    /// The bootloader didn't tell us something we need (e.g., the memory
+    /// map).
+    Missing(&'static str),
+}
+
 /// Arguments that are passed on to the kernel by the bootloader.
 #[repr(C)]
 #[derive(Debug)]
@@ -120,11 +180,12 @@ pub struct KernelArgs {
     /// String of the command line
     pub command_line: &'static str,
 
-    /// A slice into the GPU frame-buffer
-    pub frame_buffer: Option<&'static mut [u8]>,
+    /// How the kernel was started.
+    pub protocol: BootProtocol,
 
-    /// Current video mode that was set by the boot-loader
-    pub mode_info: Option<uefi::proto::console::gop::ModeInfo>,
+    /// The framebuffer (if the bootloader set up a graphics mode we can draw
+    /// on).
+    pub framebuffer: Option<Framebuffer>,
 
     /// The physical base address of root PML4 (page) for the kernel
     /// address space that gets loaded in cr3.
@@ -143,9 +204,17 @@ pub struct KernelArgs {
     /// The physical address of the ACPIv2 RSDP (Root System Description Pointer)
     pub acpi2_rsdp: x86::bits64::paging::PAddr,
 
+    /// The physical address of the UEFI system table (0 if the kernel can't
+    /// use the runtime services, only our UEFI bootloader passes it).
+    pub uefi_system_table: x86::bits64::paging::PAddr,
+
     /// Modules (ELF binaries found in the UEFI partition) passed to the kernel
     /// modules[0] is the kernel binary
     pub modules: arrayvec::ArrayVec<Module, { KernelArgs::MAX_MODULES }>,
+
+    /// Local APIC ids of the cores the bootloader found (empty if it
+    /// doesn't tell us, the kernel uses ACPI for this anyways).
+    pub boot_cpus: arrayvec::ArrayVec<u32, { KernelArgs::MAX_BOOT_CPUS }>,
 }
 
 impl KernelArgs {
@@ -154,14 +223,16 @@ impl KernelArgs {
             mm: (x86::bits64::paging::PAddr(0), 0),
             mm_iter: Vec::new(),
             command_line: "<< unset >>",
-            frame_buffer: None,
-            mode_info: None,
+            protocol: BootProtocol::Uefi,
+            framebuffer: None,
             pml4: x86::bits64::paging::PAddr(0),
             stack: (x86::bits64::paging::PAddr(0), 0),
             kernel_elf_offset: x86::bits64::paging::VAddr(0),
             acpi1_rsdp: x86::bits64::paging::PAddr(0),
             acpi2_rsdp: x86::bits64::paging::PAddr(0),
+            uefi_system_table: x86::bits64::paging::PAddr(0),
             modules: arrayvec::ArrayVec::new_const(),
+            boot_cpus: arrayvec::ArrayVec::new_const(),
         }
     }
 }
@@ -174,4 +245,47 @@ impl Default for KernelArgs {
 
 impl KernelArgs {
     pub const MAX_MODULES: usize = 32;
+    pub const MAX_BOOT_CPUS: usize = 256;
+
+    /// Adds a module, the one called `kernel` goes first.
+    pub fn add_module(&mut self, module: Module) -> Result<(), BootInfoError> {
+        if self.modules.is_full() {
+            return Err(BootInfoError::TooManyModules);
+        }
+        if module.name() == "kernel" {
+            self.modules.insert(0, module);
+        } else {
+            self.modules.push(module);
+        }
+        Ok(())
+    }
+
+    /// Adds the (whole) pages in [`base`, `base` + `size`] to the memory
+    /// map.
+    ///
+    /// `mm_iter` has to have the capacity for it already, the kernel has no
+    /// allocator yet when it parses the boot information.
+    pub fn add_memory_region(
+        &mut self,
+        ty: uefi::table::boot::MemoryType,
+        base: u64,
+        size: u64,
+    ) -> Result<(), BootInfoError> {
+        const PAGE_SIZE: u64 = 4096;
+        let start = (base + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let end = base.saturating_add(size) & !(PAGE_SIZE - 1);
+        if end <= start {
+            return Ok(());
+        }
+        if self.mm_iter.len() == self.mm_iter.capacity() {
+            return Err(BootInfoError::TooManyRegions);
+        }
+
+        let mut descriptor = uefi::table::boot::MemoryDescriptor::default();
+        descriptor.ty = ty;
+        descriptor.phys_start = start;
+        descriptor.page_count = (end - start) / PAGE_SIZE;
+        self.mm_iter.push(descriptor);
+        Ok(())
+    }
 }
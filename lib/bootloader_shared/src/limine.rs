@@ -0,0 +1,527 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Reads what the Limine bootloader tells us.
+//!
+//! The kernel puts `Requests` in its binary, Limine finds the requests by
+//! their ids and fills in a pointer to its response before it jumps to
+//! the kernel. Pointers in the responses are virtual addresses in Limine's
+//! higher-half direct map (HHDM) of physical memory, we translate them back
+//! to physical addresses with the offset from the HHDM response.
+
+// The layouts come from the protocol, we don't read every field.
+#![allow(dead_code)]
+
+use core::cell::UnsafeCell;
+use core::ptr;
+
+use uefi::table::boot::MemoryType;
+use x86::bits64::paging::{PAddr, VAddr};
+
+use crate::{BootInfoError, BootProtocol, Framebuffer, KernelArgs, Module, PixelFormat};
+
+/// The first half of every request id.
+pub const COMMON_MAGIC: [u64; 2] = [0xc7b1_dd30_df4c_8b88, 0x0a82_e883_a194_f07b];
+
+const HHDM_ID: [u64; 2] = [0x48dc_f1cb_8ad2_b852, 0x6398_4e95_9a98_244b];
+const MEMMAP_ID: [u64; 2] = [0x67cf_3d9d_378a_806f, 0xe304_acdf_c50c_3c62];
+const KERNEL_FILE_ID: [u64; 2] = [0xad97_e90e_83f1_ed67, 0x31eb_5d1c_5ff2_3b69];
+const MODULE_ID: [u64; 2] = [0x3e7e_2797_02be_32af, 0xca1c_4f3b_d128_0cee];
+const FRAMEBUFFER_ID: [u64; 2] = [0x9d58_27dc_d881_dd75, 0xa314_8604_f6fa_b11b];
+const RSDP_ID: [u64; 2] = [0xc5e7_7b6b_397e_7b43, 0x2763_7845_accd_cf3c];
+const SMP_ID: [u64; 2] = [0x95a6_7b81_9a1b_857e, 0xa0b6_1b72_3b6a_73e0];
+
+/// Memory map entry types.
+const MEMMAP_USABLE: u64 = 0;
+const MEMMAP_ACPI_RECLAIMABLE: u64 = 2;
+const MEMMAP_ACPI_NVS: u64 = 3;
+const MEMMAP_BAD_MEMORY: u64 = 4;
+const MEMMAP_BOOTLOADER_RECLAIMABLE: u64 = 5;
+const MEMMAP_KERNEL_AND_MODULES: u64 = 6;
+
+/// Framebuffer with direct RGB color.
+const FRAMEBUFFER_RGB: u8 = 1;
+
+/// Offset of the revision in the RSDP (2 and up have the XSDT).
+const RSDP_REVISION: usize = 15;
+
+/// Longest path or command line we read.
+const MAX_STRING: usize = 4096;
+
+/// A request for a response of type `R`.
+#[repr(C)]
+pub struct Request<R> {
+    id: [u64; 4],
+    revision: u64,
+    /// Filled in by Limine.
+    response: UnsafeCell<*const R>,
+}
+
+// Safe: Limine writes `response` before the kernel runs, we only read it.
+unsafe impl<R> Sync for Request<R> {}
+
+impl<R> Request<R> {
+    const fn new(id: [u64; 2]) -> Request<R> {
+        Request {
+            id: [COMMON_MAGIC[0], COMMON_MAGIC[1], id[0], id[1]],
+            revision: 0,
+            response: UnsafeCell::new(ptr::null()),
+        }
+    }
+
+    /// The response (if Limine answered).
+    fn response(&self) -> Option<&'static R> {
+        // Safe: Limine points us to a response that stays around
+        unsafe { ptr::read_volatile(self.response.get()).as_ref() }
+    }
+
+    #[cfg(test)]
+    fn respond(&self, response: &'static R) {
+        unsafe { *self.response.get() = response };
+    }
+}
+
+#[repr(C)]
+pub struct SmpRequest {
+    request: Request<SmpResponse>,
+    flags: u64,
+}
+
+/// Everything we ask Limine for.
+#[repr(C)]
+pub struct Requests {
+    hhdm: Request<HhdmResponse>,
+    memmap: Request<MemmapResponse>,
+    kernel_file: Request<KernelFileResponse>,
+    modules: Request<ModuleResponse>,
+    framebuffer: Request<FramebufferResponse>,
+    rsdp: Request<RsdpResponse>,
+    smp: SmpRequest,
+}
+
+impl Requests {
+    pub const fn new() -> Requests {
+        Requests {
+            hhdm: Request::new(HHDM_ID),
+            memmap: Request::new(MEMMAP_ID),
+            kernel_file: Request::new(KERNEL_FILE_ID),
+            modules: Request::new(MODULE_ID),
+            framebuffer: Request::new(FRAMEBUFFER_ID),
+            rsdp: Request::new(RSDP_ID),
+            smp: SmpRequest {
+                request: Request::new(SMP_ID),
+                flags: 0,
+            },
+        }
+    }
+}
+
+impl Default for Requests {
+    fn default() -> Self {
+        Requests::new()
+    }
+}
+
+#[repr(C)]
+pub struct HhdmResponse {
+    revision: u64,
+    offset: u64,
+}
+
+#[repr(C)]
+pub struct MemmapEntry {
+    base: u64,
+    length: u64,
+    ty: u64,
+}
+
+#[repr(C)]
+pub struct MemmapResponse {
+    revision: u64,
+    entry_count: u64,
+    entries: *const *const MemmapEntry,
+}
+
+/// A file Limine loaded (the kernel or a module).
+#[repr(C)]
+pub struct File {
+    revision: u64,
+    address: *const u8,
+    size: u64,
+    path: *const u8,
+    cmdline: *const u8,
+    media_type: u32,
+    unused: u32,
+    tftp_ip: u32,
+    tftp_port: u32,
+    partition_index: u32,
+    mbr_disk_id: u32,
+    gpt_disk_uuid: [u8; 16],
+    gpt_part_uuid: [u8; 16],
+    part_uuid: [u8; 16],
+}
+
+#[repr(C)]
+pub struct KernelFileResponse {
+    revision: u64,
+    kernel_file: *const File,
+}
+
+#[repr(C)]
+pub struct ModuleResponse {
+    revision: u64,
+    module_count: u64,
+    modules: *const *const File,
+}
+
+#[repr(C)]
+pub struct LimineFramebuffer {
+    address: *const u8,
+    width: u64,
+    height: u64,
+    pitch: u64,
+    bpp: u16,
+    memory_model: u8,
+    red_mask_size: u8,
+    red_mask_shift: u8,
+    green_mask_size: u8,
+    green_mask_shift: u8,
+    blue_mask_size: u8,
+    blue_mask_shift: u8,
+    unused: [u8; 7],
+    edid_size: u64,
+    edid: *const u8,
+}
+
+#[repr(C)]
+pub struct FramebufferResponse {
+    revision: u64,
+    framebuffer_count: u64,
+    framebuffers: *const *const LimineFramebuffer,
+}
+
+#[repr(C)]
+pub struct RsdpResponse {
+    revision: u64,
+    address: *const u8,
+}
+
+#[repr(C)]
+pub struct SmpInfo {
+    processor_id: u32,
+    lapic_id: u32,
+    reserved: u64,
+    goto_address: u64,
+    extra_argument: u64,
+}
+
+#[repr(C)]
+pub struct SmpResponse {
+    revision: u64,
+    flags: u32,
+    bsp_lapic_id: u32,
+    cpu_count: u64,
+    cpus: *const *const SmpInfo,
+}
+
+/// The `count` entries of the array of pointers at `array`.
+///
+/// # Safety
+/// `array` has to point to `count` valid pointers.
+unsafe fn entries<T>(array: *const *const T, count: u64) -> impl Iterator<Item = &'static T> {
+    (0..count as usize).filter_map(move |i| (*array.add(i)).as_ref())
+}
+
+/// The NUL-terminated string at `ptr`.
+///
+/// # Safety
+/// `ptr` has to point to a string that stays around.
+unsafe fn c_str(ptr: *const u8) -> Result<&'static str, BootInfoError> {
+    if ptr.is_null() {
+        return Ok("");
+    }
+    let len = (0..MAX_STRING)
+        .find(|&i| *ptr.add(i) == 0)
+        .ok_or(BootInfoError::Malformed)?;
+    core::str::from_utf8(core::slice::from_raw_parts(ptr, len))
+        .map_err(|_| BootInfoError::Malformed)
+}
+
+fn memory_type(ty: u64) -> MemoryType {
+    match ty {
+        MEMMAP_USABLE => MemoryType::CONVENTIONAL,
+        MEMMAP_ACPI_RECLAIMABLE => MemoryType::ACPI_RECLAIM,
+        MEMMAP_ACPI_NVS => MemoryType::ACPI_NON_VOLATILE,
+        MEMMAP_BAD_MEMORY => MemoryType::UNUSABLE,
+        // Holds the responses we read, we can't use it right away
+        MEMMAP_BOOTLOADER_RECLAIMABLE => MemoryType::LOADER_DATA,
+        MEMMAP_KERNEL_AND_MODULES => MemoryType::LOADER_CODE,
+        _ => MemoryType::RESERVED,
+    }
+}
+
+fn mask(size: u8, shift: u8) -> Result<u32, BootInfoError> {
+    if size as u32 + shift as u32 > 32 {
+        return Err(BootInfoError::Malformed);
+    }
+    Ok((((1u64 << size) - 1) as u32) << shift)
+}
+
+/// Converts what Limine tells us about a file to a module.
+fn module(file: &File, name: &str, hhdm: u64, phys_offset: u64) -> Result<Module, BootInfoError> {
+    let paddr = (file.address as u64)
+        .checked_sub(hhdm)
+        .ok_or(BootInfoError::Malformed)?;
+    Ok(Module::new(
+        name,
+        VAddr::from(phys_offset + paddr),
+        PAddr::from(paddr),
+        file.size as usize,
+    ))
+}
+
+/// Fills `args` from Limine's responses to `requests`, physical memory is
+/// mapped at `phys_offset` in the kernel address space.
+///
+/// # Safety
+/// Limine has to have answered `requests` (and we have to still run with
+/// its HHDM so we can follow the pointers in the responses).
+pub unsafe fn parse(
+    requests: &Requests,
+    phys_offset: u64,
+    args: &mut KernelArgs,
+) -> Result<(), BootInfoError> {
+    args.protocol = BootProtocol::Limine;
+    let hhdm = requests
+        .hhdm
+        .response()
+        .ok_or(BootInfoError::Missing("HHDM"))?
+        .offset;
+    let to_paddr = |vaddr: u64| PAddr::from(vaddr.checked_sub(hhdm).unwrap_or(vaddr));
+
+    let memmap = requests
+        .memmap
+        .response()
+        .ok_or(BootInfoError::Missing("memory map"))?;
+    for entry in entries(memmap.entries, memmap.entry_count) {
+        args.add_memory_region(memory_type(entry.ty), entry.base, entry.length)?;
+    }
+
+    let kernel = requests
+        .kernel_file
+        .response()
+        .and_then(|r| r.kernel_file.as_ref())
+        .ok_or(BootInfoError::Missing("kernel file"))?;
+    args.command_line = c_str(kernel.cmdline)?;
+    args.add_module(module(kernel, "kernel", hhdm, phys_offset)?)?;
+
+    if let Some(modules) = requests.modules.response() {
+        for file in entries(modules.modules, modules.module_count) {
+            let path = c_str(file.path)?;
+            let name = path.rsplit('/').next().unwrap_or(path);
+            args.add_module(module(file, name, hhdm, phys_offset)?)?;
+        }
+    }
+
+    if let Some(framebuffers) = requests.framebuffer.response() {
+        // We only draw 32-bit direct color pixels
+        let fb = entries(framebuffers.framebuffers, framebuffers.framebuffer_count)
+            .find(|fb| fb.bpp == 32 && fb.memory_model == FRAMEBUFFER_RGB);
+        if let Some(fb) = fb {
+            let red = mask(fb.red_mask_size, fb.red_mask_shift)?;
+            let green = mask(fb.green_mask_size, fb.green_mask_shift)?;
+            let blue = mask(fb.blue_mask_size, fb.blue_mask_shift)?;
+            args.framebuffer = Some(Framebuffer {
+                paddr: to_paddr(fb.address as u64),
+                size: (fb.pitch * fb.height) as usize,
+                width: fb.width as usize,
+                height: fb.height as usize,
+                stride: fb.pitch as usize / 4,
+                format: match (red, green, blue) {
+                    (0xff, 0xff00, 0xff_0000) => PixelFormat::Rgb,
+                    (0xff_0000, 0xff00, 0xff) => PixelFormat::Bgr,
+                    _ => PixelFormat::Bitmask { red, green, blue },
+                },
+            });
+        }
+    }
+
+    if let Some(rsdp) = requests.rsdp.response().filter(|r| !r.address.is_null()) {
+        let paddr = to_paddr(rsdp.address as u64);
+        if *rsdp.address.add(RSDP_REVISION) >= 2 {
+            args.acpi2_rsdp = paddr;
+        } else {
+            args.acpi1_rsdp = paddr;
+        }
+    }
+
+    if let Some(smp) = requests.smp.request.response() {
+        for cpu in entries(smp.cpus, smp.cpu_count) {
+            if args.boot_cpus.try_push(cpu.lapic_id).is_err() {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    const HHDM: u64 = 0xffff_8000_0000_0000;
+    const PHYS_OFFSET: u64 = 0x4000_0000_0000;
+
+    fn leak<T>(value: T) -> &'static T {
+        Box::leak(Box::new(value))
+    }
+
+    fn array<T>(values: Vec<&'static T>) -> *const *const T {
+        let pointers: Vec<*const T> = values.into_iter().map(|v| v as *const T).collect();
+        Box::leak(pointers.into_boxed_slice()).as_ptr()
+    }
+
+    fn file(paddr: u64, size: u64, path: &'static [u8], cmdline: &'static [u8]) -> &'static File {
+        leak(File {
+            revision: 0,
+            address: (HHDM + paddr) as *const u8,
+            size,
+            path: path.as_ptr(),
+            cmdline: cmdline.as_ptr(),
+            media_type: 0,
+            unused: 0,
+            tftp_ip: 0,
+            tftp_port: 0,
+            partition_index: 0,
+            mbr_disk_id: 0,
+            gpt_disk_uuid: [0; 16],
+            gpt_part_uuid: [0; 16],
+            part_uuid: [0; 16],
+        })
+    }
+
+    #[test]
+    fn parse_responses() {
+        let requests: &'static Requests = leak(Requests::new());
+        let mut args = KernelArgs::new();
+        args.mm_iter = Vec::with_capacity(4);
+
+        assert_eq!(
+            unsafe { parse(requests, PHYS_OFFSET, &mut args) },
+            Err(BootInfoError::Missing("HHDM"))
+        );
+
+        requests.hhdm.respond(leak(HhdmResponse {
+            revision: 0,
+            offset: HHDM,
+        }));
+        let regions = vec![
+            leak(MemmapEntry {
+                base: 0x1000,
+                length: 0x9e000,
+                ty: MEMMAP_USABLE,
+            }),
+            leak(MemmapEntry {
+                base: 0x10_0000,
+                length: 0x20_0000,
+                ty: MEMMAP_KERNEL_AND_MODULES,
+            }),
+            leak(MemmapEntry {
+                base: 0x30_0000,
+                length: 0x7fd0_0000,
+                ty: MEMMAP_USABLE,
+            }),
+        ];
+        requests.memmap.respond(leak(MemmapResponse {
+            revision: 0,
+            entry_count: 3,
+            entries: array(regions),
+        }));
+        requests.kernel_file.respond(leak(KernelFileResponse {
+            revision: 0,
+            kernel_file: file(0x10_0000, 0x8_0000, b"/boot/nrk\0", b"log=info init=init\0"),
+        }));
+        requests.modules.respond(leak(ModuleResponse {
+            revision: 0,
+            module_count: 1,
+            modules: array(vec![file(0x18_0000, 0x1000, b"/boot/init\0", b"\0")]),
+        }));
+        requests.framebuffer.respond(leak(FramebufferResponse {
+            revision: 0,
+            framebuffer_count: 1,
+            framebuffers: array(vec![leak(LimineFramebuffer {
+                address: (HHDM + 0xfd00_0000) as *const u8,
+                width: 800,
+                height: 600,
+                pitch: 3200,
+                bpp: 32,
+                memory_model: FRAMEBUFFER_RGB,
+                red_mask_size: 8,
+                red_mask_shift: 16,
+                green_mask_size: 8,
+                green_mask_shift: 8,
+                blue_mask_size: 8,
+                blue_mask_shift: 0,
+                unused: [0; 7],
+                edid_size: 0,
+                edid: ptr::null(),
+            })]),
+        }));
+        let mut rsdp = [0u8; 36];
+        rsdp[RSDP_REVISION] = 2;
+        let rsdp: &'static [u8; 36] = leak(rsdp);
+        requests.rsdp.respond(leak(RsdpResponse {
+            revision: 0,
+            address: rsdp.as_ptr(),
+        }));
+        let cpus = (0..4)
+            .map(|i| {
+                leak(SmpInfo {
+                    processor_id: i,
+                    lapic_id: i * 2,
+                    reserved: 0,
+                    goto_address: 0,
+                    extra_argument: 0,
+                })
+            })
+            .collect();
+        requests.smp.request.respond(leak(SmpResponse {
+            revision: 0,
+            flags: 0,
+            bsp_lapic_id: 0,
+            cpu_count: 4,
+            cpus: array(cpus),
+        }));
+
+        unsafe { parse(requests, PHYS_OFFSET, &mut args) }.unwrap();
+
+        assert_eq!(args.protocol, BootProtocol::Limine);
+        assert_eq!(args.command_line, "log=info init=init");
+
+        assert_eq!(args.mm_iter.len(), 3);
+        assert_eq!(args.mm_iter[0].ty, MemoryType::CONVENTIONAL);
+        assert_eq!(args.mm_iter[0].page_count, 0x9e);
+        assert_eq!(args.mm_iter[1].ty, MemoryType::LOADER_CODE);
+
+        assert_eq!(args.modules.len(), 2);
+        assert_eq!(args.modules[0].name(), "kernel");
+        assert_eq!(args.modules[0].binary_paddr, PAddr::from(0x10_0000u64));
+        assert_eq!(args.modules[0].base(), VAddr::from(PHYS_OFFSET + 0x10_0000));
+        assert_eq!(args.modules[1].name(), "init");
+        assert_eq!(args.modules[1].size(), 0x1000);
+
+        let fb = args.framebuffer.unwrap();
+        assert_eq!(fb.paddr, PAddr::from(0xfd00_0000u64));
+        assert_eq!((fb.width, fb.height, fb.stride), (800, 600, 800));
+        assert_eq!(fb.format, PixelFormat::Bgr);
+
+        // Our test RSDP is not in the HHDM, we leave such addresses alone
+        assert_eq!(args.acpi2_rsdp, PAddr::from(rsdp.as_ptr() as u64));
+        assert_eq!(args.acpi1_rsdp, PAddr::from(0u64));
+
+        assert_eq!(args.boot_cpus.as_slice(), &[0, 2, 4, 6]);
+    }
+}
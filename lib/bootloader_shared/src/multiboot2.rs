@@ -0,0 +1,340 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Reads the boot information of a multiboot2 bootloader (e.g., GRUB2).
+//!
+//! The bootloader passes a list of tags (8-byte aligned, each starting with
+//! its type and size) that ends with a tag of type 0. We use the command
+//! line, the modules, the memory map, the framebuffer and the ACPI RSDP
+//! (which the bootloader copies into its tag).
+//!
+//! The kernel ELF itself is not a tag: load it as a module called `kernel`
+//! as well (`module2 /nrk kernel`) so the kernel finds its symbols.
+
+use uefi::table::boot::MemoryType;
+use x86::bits64::paging::{PAddr, VAddr};
+
+use crate::{BootInfoError, BootProtocol, Framebuffer, KernelArgs, Module, PixelFormat};
+
+/// What the bootloader puts in `eax` before it jumps to the kernel.
+pub const BOOTLOADER_MAGIC: u32 = 0x36d7_6289;
+
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_MODULE: u32 = 3;
+const TAG_MMAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+
+/// Memory map entry types.
+const MEMORY_AVAILABLE: u32 = 1;
+const MEMORY_ACPI_RECLAIMABLE: u32 = 3;
+const MEMORY_NVS: u32 = 4;
+const MEMORY_BADRAM: u32 = 5;
+
+/// Framebuffer with direct RGB color.
+const FRAMEBUFFER_TYPE_RGB: u8 = 1;
+
+fn u8_at(bytes: &[u8], offset: usize) -> Result<u8, BootInfoError> {
+    bytes.get(offset).copied().ok_or(BootInfoError::Malformed)
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, BootInfoError> {
+    let raw = bytes
+        .get(offset..offset + 4)
+        .ok_or(BootInfoError::Malformed)?;
+    Ok(u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> Result<u64, BootInfoError> {
+    Ok(u32_at(bytes, offset)? as u64 | (u32_at(bytes, offset + 4)? as u64) << 32)
+}
+
+/// The bytes of `tag` from `offset` on.
+fn tail(tag: &'static [u8], offset: usize) -> Result<&'static [u8], BootInfoError> {
+    tag.get(offset..).ok_or(BootInfoError::Malformed)
+}
+
+/// The NUL-terminated string at the start of `bytes`.
+fn str_at(bytes: &'static [u8]) -> Result<&'static str, BootInfoError> {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..len]).map_err(|_| BootInfoError::Malformed)
+}
+
+/// The module name is the file name of the first word of its command
+/// line.
+fn module_name(cmdline: &str) -> &str {
+    let path = cmdline.split_whitespace().next().unwrap_or("");
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+fn memory_type(ty: u32) -> MemoryType {
+    match ty {
+        MEMORY_AVAILABLE => MemoryType::CONVENTIONAL,
+        MEMORY_ACPI_RECLAIMABLE => MemoryType::ACPI_RECLAIM,
+        MEMORY_NVS => MemoryType::ACPI_NON_VOLATILE,
+        MEMORY_BADRAM => MemoryType::UNUSABLE,
+        _ => MemoryType::RESERVED,
+    }
+}
+
+/// Turns the color fields of a framebuffer tag into a pixel format.
+fn pixel_format(color: &[u8]) -> Result<PixelFormat, BootInfoError> {
+    let mask = |position: usize| -> Result<u32, BootInfoError> {
+        let shift = u8_at(color, position)? as u32;
+        let size = u8_at(color, position + 1)? as u32;
+        if shift + size > 32 {
+            return Err(BootInfoError::Malformed);
+        }
+        Ok((((1u64 << size) - 1) as u32) << shift)
+    };
+    let (red, green, blue) = (mask(0)?, mask(2)?, mask(4)?);
+    Ok(match (red, green, blue) {
+        (0xff, 0xff00, 0xff_0000) => PixelFormat::Rgb,
+        (0xff_0000, 0xff00, 0xff) => PixelFormat::Bgr,
+        _ => PixelFormat::Bitmask { red, green, blue },
+    })
+}
+
+/// Fills `args` from the multiboot2 boot information `info` (which starts
+/// at `info_paddr`), physical memory is mapped at `phys_offset` in the
+/// kernel address space.
+pub fn parse(
+    info: &'static [u8],
+    info_paddr: PAddr,
+    phys_offset: u64,
+    args: &mut KernelArgs,
+) -> Result<(), BootInfoError> {
+    let total_size = u32_at(info, 0)? as usize;
+    let info = info.get(..total_size).ok_or(BootInfoError::Malformed)?;
+    args.protocol = BootProtocol::Multiboot2;
+
+    let mut has_memory_map = false;
+    let mut offset = 8;
+    loop {
+        let ty = u32_at(info, offset)?;
+        let size = u32_at(info, offset + 4)? as usize;
+        if size < 8 {
+            return Err(BootInfoError::Malformed);
+        }
+        let tag = info
+            .get(offset..offset + size)
+            .ok_or(BootInfoError::Malformed)?;
+
+        match ty {
+            TAG_END => break,
+            TAG_CMDLINE => args.command_line = str_at(tail(tag, 8)?)?,
+            TAG_MODULE => {
+                let start = u32_at(tag, 8)? as u64;
+                let end = u32_at(tag, 12)? as u64;
+                if end < start {
+                    return Err(BootInfoError::Malformed);
+                }
+                let name = module_name(str_at(tail(tag, 16)?)?);
+                args.add_module(Module::new(
+                    name,
+                    VAddr::from(phys_offset + start),
+                    PAddr::from(start),
+                    (end - start) as usize,
+                ))?;
+            }
+            TAG_MMAP => {
+                let entry_size = u32_at(tag, 8)? as usize;
+                if entry_size < 24 {
+                    return Err(BootInfoError::Malformed);
+                }
+                for entry in tail(tag, 16)?.chunks_exact(entry_size) {
+                    let base = u64_at(entry, 0)?;
+                    let length = u64_at(entry, 8)?;
+                    let ty = u32_at(entry, 16)?;
+                    args.add_memory_region(memory_type(ty), base, length)?;
+                }
+                has_memory_map = true;
+            }
+            TAG_FRAMEBUFFER => {
+                let paddr = u64_at(tag, 8)?;
+                let pitch = u32_at(tag, 16)? as usize;
+                let width = u32_at(tag, 20)? as usize;
+                let height = u32_at(tag, 24)? as usize;
+                let bpp = u8_at(tag, 28)?;
+                let fb_type = u8_at(tag, 29)?;
+                // We only draw 32-bit direct color pixels
+                if bpp == 32 && fb_type == FRAMEBUFFER_TYPE_RGB {
+                    args.framebuffer = Some(Framebuffer {
+                        paddr: PAddr::from(paddr),
+                        size: pitch * height,
+                        width,
+                        height,
+                        stride: pitch / 4,
+                        format: pixel_format(tail(tag, 32)?)?,
+                    });
+                }
+            }
+            TAG_ACPI_OLD => args.acpi1_rsdp = info_paddr + (offset + 8) as u64,
+            TAG_ACPI_NEW => args.acpi2_rsdp = info_paddr + (offset + 8) as u64,
+            _ => {}
+        }
+
+        offset += (size + 7) & !7;
+    }
+
+    if !has_memory_map {
+        return Err(BootInfoError::Missing("memory map"));
+    }
+    Ok(())
+}
+
+/// Fills `args` from the multiboot2 boot information we can read at `info`
+/// (its physical address is `info_paddr`).
+///
+/// # Safety
+/// `info` has to point to the boot information the bootloader passed and
+/// it must stay around (we point into it, e.g., for the command line).
+pub unsafe fn parse_raw(
+    info: *const u8,
+    info_paddr: PAddr,
+    phys_offset: u64,
+    args: &mut KernelArgs,
+) -> Result<(), BootInfoError> {
+    let total_size = (info as *const u32).read_unaligned() as usize;
+    let info = core::slice::from_raw_parts(info, total_size);
+    parse(info, info_paddr, phys_offset, args)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Builds boot information from (type, payload) tags.
+    fn build(tags: &[(u32, Vec<u8>)]) -> &'static [u8] {
+        let mut info = vec![0u8; 8];
+        for (ty, payload) in tags.iter().chain(core::iter::once(&(TAG_END, Vec::new()))) {
+            info.extend_from_slice(&ty.to_le_bytes());
+            info.extend_from_slice(&(8 + payload.len() as u32).to_le_bytes());
+            info.extend_from_slice(payload);
+            while info.len() % 8 != 0 {
+                info.push(0);
+            }
+        }
+        let len = info.len() as u32;
+        info[0..4].copy_from_slice(&len.to_le_bytes());
+        Box::leak(info.into_boxed_slice())
+    }
+
+    fn mmap_entry(base: u64, length: u64, ty: u32) -> Vec<u8> {
+        let mut entry = Vec::new();
+        entry.extend_from_slice(&base.to_le_bytes());
+        entry.extend_from_slice(&length.to_le_bytes());
+        entry.extend_from_slice(&ty.to_le_bytes());
+        entry.extend_from_slice(&0u32.to_le_bytes());
+        entry
+    }
+
+    fn module(start: u32, end: u32, cmdline: &str) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&start.to_le_bytes());
+        payload.extend_from_slice(&end.to_le_bytes());
+        payload.extend_from_slice(cmdline.as_bytes());
+        payload.push(0);
+        payload
+    }
+
+    fn args() -> KernelArgs {
+        let mut args = KernelArgs::new();
+        args.mm_iter = Vec::with_capacity(4);
+        args
+    }
+
+    #[test]
+    fn parse_tags() {
+        let mut mmap = Vec::new();
+        mmap.extend_from_slice(&24u32.to_le_bytes());
+        mmap.extend_from_slice(&0u32.to_le_bytes());
+        mmap.extend(mmap_entry(0, 0x9fc00, MEMORY_AVAILABLE));
+        mmap.extend(mmap_entry(0x10_0000, 0x7ff0_0000, MEMORY_AVAILABLE));
+        mmap.extend(mmap_entry(0x7ff0_0000, 0x1_0000, MEMORY_ACPI_RECLAIMABLE));
+
+        let mut framebuffer = Vec::new();
+        framebuffer.extend_from_slice(&0xfd00_0000u64.to_le_bytes());
+        framebuffer.extend_from_slice(&(1024u32 * 4).to_le_bytes());
+        framebuffer.extend_from_slice(&1024u32.to_le_bytes());
+        framebuffer.extend_from_slice(&768u32.to_le_bytes());
+        framebuffer.extend_from_slice(&[32, FRAMEBUFFER_TYPE_RGB, 0, 0]);
+        framebuffer.extend_from_slice(&[16, 8, 8, 8, 0, 8]);
+
+        let info = build(&[
+            (TAG_CMDLINE, b"./kernel log=info init=init\0".to_vec()),
+            (TAG_MODULE, module(0x20_0000, 0x30_0000, "/boot/init")),
+            (TAG_MODULE, module(0x40_0000, 0x48_0000, "/boot/nrk kernel")),
+            (TAG_MMAP, mmap),
+            (TAG_FRAMEBUFFER, framebuffer),
+            (TAG_ACPI_NEW, vec![0xaa; 36]),
+        ]);
+
+        let mut args = args();
+        parse(info, PAddr::from(0x1_0000u64), 0x4000_0000_0000, &mut args).unwrap();
+
+        assert_eq!(args.protocol, BootProtocol::Multiboot2);
+        assert_eq!(args.command_line, "./kernel log=info init=init");
+
+        assert_eq!(args.modules.len(), 2);
+        assert_eq!(args.modules[0].name(), "kernel");
+        assert_eq!(args.modules[1].name(), "init");
+        assert_eq!(args.modules[1].size(), 0x10_0000);
+        assert_eq!(args.modules[1].binary_paddr, PAddr::from(0x20_0000u64));
+        assert_eq!(
+            args.modules[1].base(),
+            VAddr::from(0x4000_0000_0000u64 + 0x20_0000)
+        );
+
+        assert_eq!(args.mm_iter.len(), 3);
+        assert_eq!(args.mm_iter[0].ty, MemoryType::CONVENTIONAL);
+        assert_eq!(args.mm_iter[0].page_count, 0x9f);
+        assert_eq!(args.mm_iter[2].ty, MemoryType::ACPI_RECLAIM);
+
+        let fb = args.framebuffer.unwrap();
+        assert_eq!((fb.width, fb.height, fb.stride), (1024, 768, 1024));
+        assert_eq!(fb.format, PixelFormat::Bgr);
+        assert_eq!(fb.paddr, PAddr::from(0xfd00_0000u64));
+
+        // The RSDP is in the tag (cmdline tag is 40 bytes, the modules 32
+        // and 40, the memory map 88 and the framebuffer 40)
+        assert_eq!(args.acpi2_rsdp, PAddr::from(0x1_0000u64 + 8 + 240 + 8));
+        assert_eq!(args.acpi1_rsdp, PAddr::from(0u64));
+    }
+
+    #[test]
+    fn errors() {
+        let info = build(&[(TAG_CMDLINE, b"nrk\0".to_vec())]);
+        assert_eq!(
+            parse(info, PAddr::from(0u64), 0, &mut args()),
+            Err(BootInfoError::Missing("memory map"))
+        );
+
+        let mut mmap = Vec::new();
+        mmap.extend_from_slice(&24u32.to_le_bytes());
+        mmap.extend_from_slice(&0u32.to_le_bytes());
+        for i in 0..5 {
+            mmap.extend(mmap_entry(i * 0x10_0000, 0x10_0000, MEMORY_AVAILABLE));
+        }
+        let info = build(&[(TAG_MMAP, mmap)]);
+        assert_eq!(
+            parse(info, PAddr::from(0u64), 0, &mut args()),
+            Err(BootInfoError::TooManyRegions)
+        );
+
+        // A tag that claims to be longer than the information
+        let info = Box::leak(
+            build(&[(TAG_CMDLINE, b"nrk\0".to_vec())])
+                .to_vec()
+                .into_boxed_slice(),
+        );
+        info[12] = 0xff;
+        assert_eq!(
+            parse(info, PAddr::from(0u64), 0, &mut args()),
+            Err(BootInfoError::Malformed)
+        );
+    }
+}
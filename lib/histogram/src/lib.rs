@@ -4,17 +4,26 @@
 
 //! A histogram implementation
 //!
-//! Original sources from https://github.com/brayniac/histogram -- modified to work with no_std.
+//! Original sources from https://github.com/brayniac/histogram -- modified to work with no_std
+//! (shared by the kernel and user-space programs).
 //! TODO: Remove this and include histogram = "*" as dependency once there is a std.
 
+#![no_std]
 #![cfg_attr(feature = "cargo-clippy", deny(missing_docs))]
 #![cfg_attr(feature = "cargo-clippy", deny(warnings))]
 
+extern crate alloc;
+
 use alloc::vec;
 use alloc::vec::Vec;
 use core::{f64, fmt, mem};
 
-use crate::f64 as std_f64;
+use libm::{ceil, floor, pow, sqrt};
+
+/// `2^exp` (`f64::powi` is not in core).
+fn pow2(exp: i32) -> f64 {
+    pow(2.0_f64, f64::from(exp))
+}
 
 /// A configuration struct for building custom `Histogram`s.
 #[derive(Clone, Copy)]
@@ -302,7 +311,7 @@ impl Histogram {
     fn configured(config: Config) -> Option<Histogram> {
         let buckets_inner: u32 = config.radix.pow(config.precision);
         let linear_power: u32 = 32 - buckets_inner.leading_zeros();
-        let linear_max: u64 = 2.0_f64.powi(linear_power as i32) as u64;
+        let linear_max: u64 = pow2(linear_power as i32) as u64;
         let max_value_power: u32 = 64 - config.max_value.leading_zeros();
 
         let buckets_outer = if max_value_power > linear_power {
@@ -495,11 +504,11 @@ impl Histogram {
 
         let l_power = 63 - self.properties.linear_max.leading_zeros();
 
-        let remain = value as f64 - 2.0_f64.powi(outer as i32);
+        let remain = value as f64 - pow2(outer as i32);
 
-        let inner = (f64::from(self.properties.buckets_inner) * remain as f64
-            / 2.0_f64.powi((outer) as i32))
-        .floor() as u32;
+        let inner =
+            floor(f64::from(self.properties.buckets_inner) * remain as f64 / pow2(outer as i32))
+                as u32;
 
         // this gives the shifted outer index
         let outer = outer as u32 - l_power;
@@ -522,18 +531,17 @@ impl Histogram {
 
         let log_index = index - linear_max;
 
-        let outer =
-            (f64::from(log_index) / f64::from(self.properties.buckets_inner)).floor() as u32;
+        let outer = floor(f64::from(log_index) / f64::from(self.properties.buckets_inner)) as u32;
 
         let inner = log_index - outer * self.properties.buckets_inner as u32;
 
-        let mut value = 2.0_f64.powi((outer as u32 + self.properties.linear_power) as i32);
+        let mut value = pow2((outer as u32 + self.properties.linear_power) as i32);
         value += f64::from(inner) * (value as f64 / f64::from(self.properties.buckets_inner));
 
         if value > self.config.max_value as f64 {
             return self.config.max_value as u64;
         }
-        value.ceil() as u64
+        ceil(value) as u64
     }
 
     /// return the value for the given percentile
@@ -559,7 +567,7 @@ impl Histogram {
         if percentile <= 100.0 && percentile >= 0.0 {
             let total = self.entries();
 
-            let mut need = (total as f64 * (percentile / 100.0_f64)).ceil() as u64;
+            let mut need = ceil(total as f64 * (percentile / 100.0_f64)) as u64;
 
             if need > total {
                 need = total;
@@ -663,7 +671,7 @@ impl Histogram {
             mean += (self.index_value(index) as f64 * self.data.data[index] as f64) as f64
                 / total as f64;
         }
-        Ok(mean.ceil() as u64)
+        Ok(ceil(mean) as u64)
     }
 
     /// standard variance approximation across the histogram
@@ -696,7 +704,7 @@ impl Histogram {
 
         stdvar /= total;
 
-        Ok(stdvar.ceil() as u64)
+        Ok(ceil(stdvar) as u64)
     }
 
     /// standard deviation approximation across the histogram
@@ -730,9 +738,9 @@ impl Histogram {
 
         let stdvar = self.stdvar().unwrap() as f64;
 
-        let stddev = stdvar.sqrt();
+        let stddev = sqrt(stdvar);
 
-        Some(stddev.ceil() as u64)
+        Some(ceil(stddev) as u64)
     }
 
     /// merge one Histogram into another Histogram
@@ -0,0 +1,83 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Versioning of the system call interface (see `syscalls::System::abi_version`).
+//!
+//! The major version changes whenever an existing system call changes in an
+//! incompatible way (its arguments, return values or the layout of a
+//! struct it passes, like `process::ProcessInfo`). The minor version
+//! changes when system calls are added. A binary works with a kernel that
+//! has the same major version and at least its minor version.
+//!
+//! Parts of the interface the kernel only has in some builds are announced
+//! in `AbiFeatures`.
+
+use bitflags::*;
+
+/// Version of the interface this crate implements.
+pub const ABI_VERSION: AbiVersion = AbiVersion { major: 3, minor: 0 };
+
+/// A version of the system call interface.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct AbiVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl AbiVersion {
+    /// Can a binary built against `self` use a kernel that has `kernel`?
+    pub fn is_compatible(&self, kernel: AbiVersion) -> bool {
+        self.major == kernel.major && self.minor <= kernel.minor
+    }
+}
+
+impl From<u64> for AbiVersion {
+    fn from(v: u64) -> AbiVersion {
+        AbiVersion {
+            major: (v >> 32) as u32,
+            minor: v as u32,
+        }
+    }
+}
+
+impl From<AbiVersion> for u64 {
+    fn from(v: AbiVersion) -> u64 {
+        (v.major as u64) << 32 | v.minor as u64
+    }
+}
+
+impl core::fmt::Display for AbiVersion {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+bitflags! {
+    /// Optional parts of the system call interface.
+    pub struct AbiFeatures: u64 {
+        /// Sockets (`NetworkOperation`).
+        const NETWORK = 1 << 0;
+        /// Per-subsystem heap statistics (`/proc/heap`).
+        const HEAP_TRACKING = 1 << 1;
+        /// Virtual machines (`VmOperation`), the cores have VT-x with EPT.
+        const VIRTUALIZATION = 1 << 2;
+        /// vsock connections (`NetworkOperation::VsockConnect` etc.), there is
+        /// a virtio-vsock device.
+        const VSOCK = 1 << 3;
+        /// Evicting memory (`VSpaceOperation::PageOut`), there is a zswap pool
+        /// or a swap device.
+        const SWAP = 1 << 4;
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn abi_version() {
+    let v = AbiVersion { major: 3, minor: 7 };
+    assert_eq!(AbiVersion::from(u64::from(v)), v);
+
+    assert!(v.is_compatible(v));
+    assert!(v.is_compatible(AbiVersion { major: 3, minor: 8 }));
+    assert!(!v.is_compatible(AbiVersion { major: 3, minor: 6 }));
+    assert!(!v.is_compatible(AbiVersion { major: 4, minor: 7 }));
+}
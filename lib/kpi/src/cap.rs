@@ -0,0 +1,75 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Capabilities: how processes refer to kernel objects (see
+//! `syscalls::Capability`).
+//!
+//! Files, physical memory, cores, doors, events and VMs are named by
+//! handles into the capability table the kernel keeps for every process. A
+//! handle only means something in the process it was given to and a process
+//! can't make one up: it has the handles the kernel returned (`Fs::open`,
+//! `PhysicalMemory::allocate_base_page`, `Process::request_core`,
+//! `Ipc::create`, `Event::create`, `Vm::create`) and the ones other
+//! processes transferred to it. A handle is never 0 and fits in a (positive) C `int`.
+//!
+//! Every capability has a kind and rights, the kernel checks both whenever
+//! a handle is used. A process can drop rights (`Capability::restrict`) and,
+//! if it has `GRANT`, pass the capability on with the same or fewer rights
+//! (`Capability::transfer`).
+
+use bitflags::*;
+
+bitflags! {
+    /// What a process may do with the object of a capability.
+    pub struct CapRights: u64 {
+        /// Read the file or memory.
+        const READ = 1 << 0;
+        /// Write the file or memory.
+        const WRITE = 1 << 1;
+        /// Map the memory in the address-space.
+        const MAP = 1 << 2;
+        /// Transfer the capability to another process.
+        const GRANT = 1 << 3;
+        /// Call the door.
+        const CALL = 1 << 4;
+        /// Receive and reply to the calls of the door.
+        const SERVE = 1 << 5;
+    }
+}
+
+/// The kind of object a capability refers to.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u64)]
+pub enum CapKind {
+    /// An open file.
+    File = 1,
+    /// A physical frame (from `PhysicalMemory`).
+    Frame = 2,
+    /// A core the process runs on.
+    Core = 3,
+    /// Reserved, sockets still have descriptors of their own (`Net`).
+    Socket = 4,
+    /// A door (see `ipc`).
+    Door = 5,
+    /// An event counter (see `event`).
+    Event = 6,
+    /// A virtual machine (see `vm`).
+    Vm = 7,
+    Unknown,
+}
+
+impl From<u64> for CapKind {
+    /// Construct a CapKind enum based on a 64-bit value.
+    fn from(kind: u64) -> CapKind {
+        match kind {
+            1 => CapKind::File,
+            2 => CapKind::Frame,
+            3 => CapKind::Core,
+            4 => CapKind::Socket,
+            5 => CapKind::Door,
+            6 => CapKind::Event,
+            7 => CapKind::Vm,
+            _ => CapKind::Unknown,
+        }
+    }
+}
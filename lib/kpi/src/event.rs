@@ -0,0 +1,29 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Event counters: notifications between processes and from the kernel
+//! (see `syscalls::Event`), like an `eventfd` on Linux.
+//!
+//! An event is a 64-bit counter behind a capability. Signaling it (with
+//! `CapRights::WRITE`) adds to the counter, waiting (with `CapRights::READ`)
+//! blocks until the counter isn't 0 and then takes the whole counter, or
+//! just 1 for a `EventFlags::SEMAPHORE` event. The kernel signals events for
+//! a timer (`Event::set_timer`) and for calls arriving at a door
+//! (`Ipc::notify`).
+//!
+//! `Net::poll` reports an event as readable (`POLLIN`) while its counter
+//! isn't 0, so a process can wait for events and sockets together (see
+//! `net::PollFd::event`).
+
+use bitflags::*;
+
+/// The largest value of the counter, a signal that would go beyond fails.
+pub const MAX_EVENT_VALUE: u64 = u64::MAX - 1;
+
+bitflags! {
+    /// How an event behaves.
+    pub struct EventFlags: u64 {
+        /// Waiting takes 1 from the counter, not all of it.
+        const SEMAPHORE = 1 << 0;
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Which system calls a process may make (see
+//! `syscalls::Process::restrict_syscalls`).
+//!
+//! A filter has a bit for every operation of every `SystemCall`. A process
+//! starts out with all of them set and can only ever clear bits, there is no
+//! way back. A call the filter doesn't allow fails with
+//! `SystemCallError::PermissionError` and the process gets a
+//! `upcall::SYSCALL_DENIED` upcall. `Process::exit` is always allowed.
+//!
+//! Opening a file to write, create or truncate it counts as a
+//! `FileOperation::Write`.
+
+use crate::io::FileFlags;
+use crate::{FileOperation, ProcessOperation, SystemCall, SystemOperation, VSpaceOperation};
+
+/// How many `SystemCall`s a filter has room for.
+pub const MAX_SYSCALLS: usize = 16;
+
+/// The system calls a process may make, operation `op` of `SystemCall` `s`
+/// is bit `op` of `allowed[s]`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(C)]
+pub struct SyscallFilter {
+    allowed: [u64; MAX_SYSCALLS],
+}
+
+impl SyscallFilter {
+    /// Allows everything (what a process starts with).
+    pub const fn all() -> SyscallFilter {
+        SyscallFilter {
+            allowed: [u64::MAX; MAX_SYSCALLS],
+        }
+    }
+
+    /// Allows nothing but `Process::exit`.
+    pub const fn none() -> SyscallFilter {
+        SyscallFilter {
+            allowed: [0; MAX_SYSCALLS],
+        }
+    }
+
+    /// Reading files and printing, and what the runtime (vibrio and lineup)
+    /// needs to keep going: growing the heap, futexes and giving cores back.
+    pub fn read_and_print() -> SyscallFilter {
+        SyscallFilter::none()
+            .allow(SystemCall::Process, ProcessOperation::Log as u64)
+            .allow(SystemCall::Process, ProcessOperation::FutexWait as u64)
+            .allow(SystemCall::Process, ProcessOperation::FutexWake as u64)
+            .allow(SystemCall::Process, ProcessOperation::ReleaseCore as u64)
+            .allow(SystemCall::System, SystemOperation::GetCoreID as u64)
+            .allow(SystemCall::VSpace, VSpaceOperation::Map as u64)
+            .allow(SystemCall::FileIO, FileOperation::Open as u64)
+            .allow(SystemCall::FileIO, FileOperation::Read as u64)
+            .allow(SystemCall::FileIO, FileOperation::ReadAt as u64)
+            .allow(SystemCall::FileIO, FileOperation::GetInfo as u64)
+            .allow(SystemCall::FileIO, FileOperation::Close as u64)
+    }
+
+    /// The filter that allows `allowed[s]` of every `SystemCall` `s` (what
+    /// `operations` returns for all of them).
+    pub const fn from_operations(allowed: [u64; MAX_SYSCALLS]) -> SyscallFilter {
+        SyscallFilter { allowed }
+    }
+
+    /// Also allows operation `op` of `call`.
+    pub fn allow(mut self, call: SystemCall, op: u64) -> SyscallFilter {
+        if let Some(ops) = self.allowed.get_mut(call as usize) {
+            if op < 64 {
+                *ops |= 1 << op;
+            }
+        }
+        self
+    }
+
+    /// Also allows all operations of `call`.
+    pub fn allow_all(mut self, call: SystemCall) -> SyscallFilter {
+        if let Some(ops) = self.allowed.get_mut(call as usize) {
+            *ops = u64::MAX;
+        }
+        self
+    }
+
+    /// What both `self` and `other` allow.
+    pub fn intersect(&self, other: &SyscallFilter) -> SyscallFilter {
+        let mut filter = *self;
+        for (ops, other) in filter.allowed.iter_mut().zip(other.allowed.iter()) {
+            *ops &= other;
+        }
+        filter
+    }
+
+    /// The operations of the `SystemCall` `call` it allows (a bit for
+    /// every operation).
+    pub fn operations(&self, call: usize) -> u64 {
+        self.allowed.get(call).copied().unwrap_or(0)
+    }
+
+    /// Does it allow system call `call` with arguments `arg1` (the
+    /// operation) and `arg3`?
+    pub fn allows(&self, call: u64, arg1: u64, arg3: u64) -> bool {
+        SyscallFilter::allows_in(|c| self.operations(c), call, arg1, arg3)
+    }
+
+    /// `allows` for a filter given by its `operations`.
+    pub fn allows_in<F: Fn(usize) -> u64>(operations: F, call: u64, arg1: u64, arg3: u64) -> bool {
+        let allowed =
+            |call: SystemCall, op: u64| op < 64 && operations(call as usize) & (1 << op) != 0;
+
+        match (SystemCall::new(call), arg1) {
+            (SystemCall::Unknown, _) => false,
+            (SystemCall::Process, op) if op == ProcessOperation::Exit as u64 => true,
+            (SystemCall::FileIO, op) if op == FileOperation::Open as u64 => {
+                let flags = FileFlags::from(arg3);
+                let writes =
+                    flags.is_write() || flags.intersects(FileFlags::O_CREAT | FileFlags::O_TRUNC);
+                allowed(SystemCall::FileIO, op)
+                    && (!writes || allowed(SystemCall::FileIO, FileOperation::Write as u64))
+            }
+            (call, op) => allowed(call, op),
+        }
+    }
+}
+
+impl Default for SyscallFilter {
+    fn default() -> SyscallFilter {
+        SyscallFilter::all()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FILE_IO: u64 = SystemCall::FileIO as u64;
+
+    #[test]
+    fn read_and_print() {
+        let filter = SyscallFilter::read_and_print();
+        let open = FileOperation::Open as u64;
+        let rdonly = FileFlags::O_RDONLY.bits();
+
+        assert!(filter.allows(FILE_IO, FileOperation::Read as u64, 0));
+        assert!(filter.allows(FILE_IO, open, rdonly));
+        assert!(!filter.allows(FILE_IO, FileOperation::Write as u64, 0));
+        assert!(!filter.allows(FILE_IO, open, FileFlags::O_RDWR.bits()));
+        assert!(!filter.allows(
+            FILE_IO,
+            open,
+            (FileFlags::O_RDONLY | FileFlags::O_CREAT).bits()
+        ));
+        assert!(!filter.allows(SystemCall::Network as u64, 1, 0));
+        assert!(!filter.allows(0xff, 1, 0));
+    }
+
+    #[test]
+    fn exit() {
+        let exit = ProcessOperation::Exit as u64;
+        assert!(SyscallFilter::none().allows(SystemCall::Process as u64, exit, 0));
+        assert!(!SyscallFilter::none().allows(SystemCall::Process as u64, 0xff, 0));
+    }
+
+    #[test]
+    fn intersect() {
+        let read = FileOperation::Read as u64;
+        let filter = SyscallFilter::none()
+            .allow_all(SystemCall::FileIO)
+            .intersect(&SyscallFilter::none().allow(SystemCall::FileIO, read));
+        assert!(filter.allows(FILE_IO, read, 0));
+        assert!(!filter.allows(FILE_IO, FileOperation::ReadAt as u64, 0));
+        assert_eq!(filter.intersect(&SyscallFilter::all()), filter);
+    }
+}
@@ -3,8 +3,11 @@
 
 use bitflags::*;
 
+use crate::process::Credentials;
+
 /// Struct used in `file_getinfo` systemcall.
 #[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[repr(C)]
 pub struct FileInfo {
     pub ftype: u64,
     pub fsize: u64,
@@ -89,12 +92,24 @@ impl FileFlags {
 
 bitflags! {
     /// FileModes to store the file in the memory. A file can be stored in
-    /// readable, writable or executable mode.
+    /// readable, writable or executable mode for its owner (user), processes
+    /// in the group of the owner and everyone else.
+    ///
+    /// The user bits are the lowest, the reverse of the octal digits of a
+    /// POSIX mode (see `from_posix`).
     pub struct FileModes: u64 {
         const S_IRWXU = 0x007; /* RWX mask for user */
         const S_IRUSR = 0x004; /* R for user */
         const S_IWUSR = 0x002; /* W for user */
         const S_IXUSR = 0x001; /* X for user */
+        const S_IRWXG = 0x038; /* RWX mask for group */
+        const S_IRGRP = 0x020; /* R for group */
+        const S_IWGRP = 0x010; /* W for group */
+        const S_IXGRP = 0x008; /* X for group */
+        const S_IRWXO = 0x1c0; /* RWX mask for other */
+        const S_IROTH = 0x100; /* R for other */
+        const S_IWOTH = 0x080; /* W for other */
+        const S_IXOTH = 0x040; /* X for other */
     }
 }
 
@@ -125,4 +140,35 @@ impl FileModes {
     pub fn is_executable(&self) -> bool {
         (*self & FileModes::S_IXUSR) == FileModes::S_IXUSR
     }
+
+    /// Can anyone (user, group or other) read the file?
+    pub fn is_readable_by_any(&self) -> bool {
+        self.intersects(FileModes::S_IRUSR | FileModes::S_IRGRP | FileModes::S_IROTH)
+    }
+
+    /// Can anyone (user, group or other) write the file?
+    pub fn is_writable_by_any(&self) -> bool {
+        self.intersects(FileModes::S_IWUSR | FileModes::S_IWGRP | FileModes::S_IWOTH)
+    }
+
+    /// The modes for a POSIX mode (e.g., `0o644`).
+    pub fn from_posix(mode: u64) -> FileModes {
+        let user = (mode >> 6) & 0o7;
+        let group = (mode >> 3) & 0o7;
+        let other = mode & 0o7;
+        FileModes::from_bits_truncate(user | group << 3 | other << 6)
+    }
+
+    /// The modes that apply to `creds` for a file that belongs to `owner`,
+    /// moved to the user bits.
+    pub fn for_credentials(&self, owner: Credentials, creds: Credentials) -> FileModes {
+        let shift = if creds.uid == owner.uid {
+            0
+        } else if creds.gid == owner.gid {
+            3
+        } else {
+            6
+        };
+        FileModes::from_bits_truncate(self.bits() >> shift) & FileModes::S_IRWXU
+    }
 }
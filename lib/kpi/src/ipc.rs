@@ -0,0 +1,79 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Doors: synchronous calls from one process into another (see
+//! `syscalls::Ipc`).
+//!
+//! A server creates a door under a name and gets a capability with
+//! `CapRights::SERVE` for it. Clients connect to the name (or get the door
+//! capability transferred) and call the door with a small `Message`, the
+//! call blocks until the server replies with another `Message`.
+//!
+//! A message can carry one capability of the sender (`Message::cap`), the
+//! receiver gets its own handle for the object in the message it receives.
+//! The sender needs `CapRights::GRANT` for it and keeps its handle.
+
+/// How many bytes a message carries inline.
+pub const MAX_PAYLOAD: usize = 112;
+
+/// Longest name of a door.
+pub const MAX_DOOR_NAME: usize = 64;
+
+/// A request or reply.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct Message {
+    /// How many bytes of `payload` are used.
+    pub len: u64,
+    /// A capability handle that goes with the message (0 for none).
+    pub cap: u64,
+    pub payload: [u8; MAX_PAYLOAD],
+}
+
+impl Message {
+    /// A message with no data and no capability.
+    pub const fn empty() -> Message {
+        Message {
+            len: 0,
+            cap: 0,
+            payload: [0; MAX_PAYLOAD],
+        }
+    }
+
+    /// A message with `data` (`None` if it's longer than `MAX_PAYLOAD`).
+    pub fn new(data: &[u8]) -> Option<Message> {
+        if data.len() > MAX_PAYLOAD {
+            return None;
+        }
+        let mut msg = Message::empty();
+        msg.payload[..data.len()].copy_from_slice(data);
+        msg.len = data.len() as u64;
+        Some(msg)
+    }
+
+    /// Sends capability `handle` along.
+    pub fn with_cap(mut self, handle: u64) -> Message {
+        self.cap = handle;
+        self
+    }
+
+    /// The bytes in the message.
+    pub fn data(&self) -> &[u8] {
+        &self.payload[..(self.len as usize).min(MAX_PAYLOAD)]
+    }
+}
+
+impl Default for Message {
+    fn default() -> Message {
+        Message::empty()
+    }
+}
+
+impl core::fmt::Debug for Message {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Message")
+            .field("data", &self.data())
+            .field("cap", &self.cap)
+            .finish()
+    }
+}
@@ -9,10 +9,22 @@
 #[cfg(not(target_os = "none"))]
 extern crate alloc;
 
+use bitflags::*;
+
+pub mod abi;
+pub mod cap;
+pub mod event;
+pub mod filter;
 pub mod io;
+pub mod ipc;
+pub mod net;
+pub mod perf;
 pub mod process;
 pub mod system;
+pub mod time;
+pub mod trace;
 pub mod upcall;
+pub mod vm;
 pub mod x86_64;
 
 /// The syscall layer (only relevant for Ring3 code -> target_os = nrk)
@@ -54,6 +66,13 @@ pub enum SystemCallError {
     PermissionError = 9,
     /// Bad offset
     OffsetError = 10,
+    /// Operation can't complete right now (try again later).
+    WouldBlock = 11,
+    /// Operation didn't complete within the given time.
+    TimedOut = 12,
+    /// The kernel doesn't implement the system call interface we were
+    /// built for (see `abi`).
+    IncompatibleAbi = 13,
     /// Placeholder for an invalid, unknown error code.
     Unknown,
 }
@@ -72,6 +91,9 @@ impl From<u64> for SystemCallError {
             8 => SystemCallError::BadFlags,
             9 => SystemCallError::PermissionError,
             10 => SystemCallError::OffsetError,
+            11 => SystemCallError::WouldBlock,
+            12 => SystemCallError::TimedOut,
+            13 => SystemCallError::IncompatibleAbi,
             _ => SystemCallError::Unknown,
         }
     }
@@ -98,6 +120,20 @@ pub enum ProcessOperation {
     RequestCore = 7,
     /// Allocate a physical memory page as a mem object to the process.
     AllocatePhysical = 8,
+    /// Give the current core back to the kernel.
+    ReleaseCore = 9,
+    /// Sleep until a futex is woken up.
+    FutexWait = 10,
+    /// Wake up cores sleeping on a futex.
+    FutexWake = 11,
+    /// Narrow down the system calls the process may make.
+    RestrictSyscalls = 12,
+    /// Where the stacks, the heap and the mapping region of the process are.
+    GetLayout = 13,
+    /// Write a checkpoint of the process to a file.
+    Checkpoint = 14,
+    /// Start a process from a checkpoint.
+    Restore = 15,
     Unknown,
 }
 
@@ -113,6 +149,13 @@ impl From<u64> for ProcessOperation {
             6 => ProcessOperation::GetProcessInfo,
             7 => ProcessOperation::RequestCore,
             8 => ProcessOperation::AllocatePhysical,
+            9 => ProcessOperation::ReleaseCore,
+            10 => ProcessOperation::FutexWait,
+            11 => ProcessOperation::FutexWake,
+            12 => ProcessOperation::RestrictSyscalls,
+            13 => ProcessOperation::GetLayout,
+            14 => ProcessOperation::Checkpoint,
+            15 => ProcessOperation::Restore,
             _ => ProcessOperation::Unknown,
         }
     }
@@ -130,6 +173,13 @@ impl From<&str> for ProcessOperation {
             "GetProcessInfo" => ProcessOperation::GetProcessInfo,
             "RequestCore" => ProcessOperation::RequestCore,
             "AllocatePhysical" => ProcessOperation::AllocatePhysical,
+            "ReleaseCore" => ProcessOperation::ReleaseCore,
+            "FutexWait" => ProcessOperation::FutexWait,
+            "FutexWake" => ProcessOperation::FutexWake,
+            "RestrictSyscalls" => ProcessOperation::RestrictSyscalls,
+            "GetLayout" => ProcessOperation::GetLayout,
+            "Checkpoint" => ProcessOperation::Checkpoint,
+            "Restore" => ProcessOperation::Restore,
             _ => ProcessOperation::Unknown,
         }
     }
@@ -149,6 +199,16 @@ pub enum VSpaceOperation {
     MapFrame = 4,
     /// Resolve a virtual to a physical address
     Identify = 5,
+    /// Handle page faults in a region in user-space (see `upcall::PAGE_FAULT`)
+    RegisterFaultRegion = 6,
+    /// Let the kernel handle page faults in a region again
+    UnregisterFaultRegion = 7,
+    /// Change the access rights of a mapped region
+    Protect = 8,
+    /// Map some anonymous memory where the kernel sees fit
+    MapAnywhere = 9,
+    /// Evict anonymous memory (it comes back on the next access)
+    PageOut = 10,
     Unknown,
 }
 
@@ -161,6 +221,11 @@ impl From<u64> for VSpaceOperation {
             3 => VSpaceOperation::MapDevice,
             4 => VSpaceOperation::MapFrame,
             5 => VSpaceOperation::Identify,
+            6 => VSpaceOperation::RegisterFaultRegion,
+            7 => VSpaceOperation::UnregisterFaultRegion,
+            8 => VSpaceOperation::Protect,
+            9 => VSpaceOperation::MapAnywhere,
+            10 => VSpaceOperation::PageOut,
             _ => VSpaceOperation::Unknown,
         }
     }
@@ -175,11 +240,28 @@ impl From<&str> for VSpaceOperation {
             "MapDevice" => VSpaceOperation::MapDevice,
             "MapFrame" => VSpaceOperation::MapFrame,
             "Identify" => VSpaceOperation::Identify,
+            "RegisterFaultRegion" => VSpaceOperation::RegisterFaultRegion,
+            "UnregisterFaultRegion" => VSpaceOperation::UnregisterFaultRegion,
+            "Protect" => VSpaceOperation::Protect,
+            "MapAnywhere" => VSpaceOperation::MapAnywhere,
+            "PageOut" => VSpaceOperation::PageOut,
             _ => VSpaceOperation::Unknown,
         }
     }
 }
 
+bitflags! {
+    /// Access rights of a region for `VSpaceOperation::Protect`.
+    ///
+    /// Mapped memory is always readable, `WRITE` without `READ` is the
+    /// same as `READ | WRITE`.
+    pub struct MemoryRights: u64 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXECUTE = 1 << 2;
+    }
+}
+
 /// Flags for the fs related system call
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 #[repr(u64)]
@@ -208,6 +290,8 @@ pub enum FileOperation {
     FileRename = 11,
     /// Create a directory.
     MkDir = 12,
+    /// Wait until all writes to a file are applied.
+    FSync = 13,
     Unknown,
 }
 
@@ -227,6 +311,7 @@ impl From<u64> for FileOperation {
             10 => FileOperation::WriteDirect,
             11 => FileOperation::FileRename,
             12 => FileOperation::MkDir,
+            13 => FileOperation::FSync,
             _ => FileOperation::Unknown,
         }
     }
@@ -248,6 +333,7 @@ impl From<&str> for FileOperation {
             "WriteDirect" => FileOperation::WriteDirect,
             "Rename" => FileOperation::FileRename,
             "MkDir" => FileOperation::MkDir,
+            "FSync" => FileOperation::FSync,
             _ => FileOperation::Unknown,
         }
     }
@@ -259,10 +345,42 @@ impl From<&str> for FileOperation {
 pub enum SystemOperation {
     /// Query information about available hardware threads in the system
     GetHardwareThreads = 1,
-    /// Print system/per-core info.
+    /// Query the event counters of all cores.
     Stats = 2,
     /// Get the core id for the current thread.
     GetCoreID = 3,
+    /// Read queued keyboard events.
+    ReadKeyEvents = 4,
+    /// Fill a buffer with random bytes.
+    GetRandom = 5,
+    /// Query the NUMA nodes and their distances.
+    GetTopology = 6,
+    /// Take a core offline.
+    OfflineCore = 7,
+    /// Bring an offline core back.
+    OnlineCore = 8,
+    /// Read from the kernel log ring.
+    Dmesg = 9,
+    /// Query the system call interface version and features.
+    AbiVersion = 10,
+    /// Query the frequency of a core.
+    GetFrequency = 11,
+    /// Pin the frequency of a core (or hand it back to the kernel).
+    SetFrequency = 12,
+    /// Suspend the machine to RAM for a while.
+    Suspend = 13,
+    /// Replace the kernel with another one (without a reboot).
+    Kexec = 14,
+    /// Turn the machine off (QEMU exits with the given code).
+    Shutdown = 15,
+    /// Reset the machine.
+    Reboot = 16,
+    /// Query how the kernel picks cores for processes.
+    GetCorePolicy = 17,
+    /// Change how the kernel picks cores for processes.
+    SetCorePolicy = 18,
+    /// Put a process in gang mode (or take it out).
+    SetGang = 19,
     Unknown,
 }
 
@@ -273,6 +391,22 @@ impl From<u64> for SystemOperation {
             1 => SystemOperation::GetHardwareThreads,
             2 => SystemOperation::Stats,
             3 => SystemOperation::GetCoreID,
+            4 => SystemOperation::ReadKeyEvents,
+            5 => SystemOperation::GetRandom,
+            6 => SystemOperation::GetTopology,
+            7 => SystemOperation::OfflineCore,
+            8 => SystemOperation::OnlineCore,
+            9 => SystemOperation::Dmesg,
+            10 => SystemOperation::AbiVersion,
+            11 => SystemOperation::GetFrequency,
+            12 => SystemOperation::SetFrequency,
+            13 => SystemOperation::Suspend,
+            14 => SystemOperation::Kexec,
+            15 => SystemOperation::Shutdown,
+            16 => SystemOperation::Reboot,
+            17 => SystemOperation::GetCorePolicy,
+            18 => SystemOperation::SetCorePolicy,
+            19 => SystemOperation::SetGang,
             _ => SystemOperation::Unknown,
         }
     }
@@ -285,11 +419,419 @@ impl From<&str> for SystemOperation {
             "GetHardwareThreads" => SystemOperation::GetHardwareThreads,
             "Stats" => SystemOperation::Stats,
             "GetCoreID" => SystemOperation::GetCoreID,
+            "ReadKeyEvents" => SystemOperation::ReadKeyEvents,
+            "GetRandom" => SystemOperation::GetRandom,
+            "GetTopology" => SystemOperation::GetTopology,
+            "OfflineCore" => SystemOperation::OfflineCore,
+            "OnlineCore" => SystemOperation::OnlineCore,
+            "Dmesg" => SystemOperation::Dmesg,
+            "AbiVersion" => SystemOperation::AbiVersion,
+            "GetFrequency" => SystemOperation::GetFrequency,
+            "SetFrequency" => SystemOperation::SetFrequency,
+            "Suspend" => SystemOperation::Suspend,
+            "Kexec" => SystemOperation::Kexec,
+            "Shutdown" => SystemOperation::Shutdown,
+            "Reboot" => SystemOperation::Reboot,
+            "GetCorePolicy" => SystemOperation::GetCorePolicy,
+            "SetCorePolicy" => SystemOperation::SetCorePolicy,
+            "SetGang" => SystemOperation::SetGang,
             _ => SystemOperation::Unknown,
         }
     }
 }
 
+/// Operations on (non-blocking) network sockets.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u64)]
+pub enum NetworkOperation {
+    /// Create a new socket.
+    Socket = 1,
+    /// Bind a UDP socket to a port (or listen on a port with a TCP socket).
+    Bind = 2,
+    /// Connect a TCP socket (or set the default peer for a UDP socket).
+    Connect = 3,
+    /// Send data on a socket.
+    Send = 4,
+    /// Receive data from a socket.
+    Recv = 5,
+    /// Close a socket.
+    Close = 6,
+    /// Query readiness of a set of sockets.
+    Poll = 7,
+    /// Attach zero-copy packet rings to the NIC.
+    XdpAttach = 8,
+    /// Tell the kernel to process the TX ring of a zero-copy queue.
+    XdpKick = 9,
+    /// Send an ICMP echo request and wait for the reply.
+    Ping = 10,
+    /// Connect a vsock stream to a port on another VM (or the host).
+    VsockConnect = 11,
+    /// Listen for vsock connections on a port.
+    VsockListen = 12,
+    /// Take a connection from a listening vsock descriptor.
+    VsockAccept = 13,
+    /// Send data on a vsock connection.
+    VsockSend = 14,
+    /// Receive data from a vsock connection.
+    VsockRecv = 15,
+    /// Close a vsock descriptor.
+    VsockClose = 16,
+    /// Get the CID of this VM.
+    VsockLocalCid = 17,
+    Unknown,
+}
+
+impl From<u64> for NetworkOperation {
+    /// Construct a NetworkOperation enum based on a 64-bit value.
+    fn from(op: u64) -> NetworkOperation {
+        match op {
+            1 => NetworkOperation::Socket,
+            2 => NetworkOperation::Bind,
+            3 => NetworkOperation::Connect,
+            4 => NetworkOperation::Send,
+            5 => NetworkOperation::Recv,
+            6 => NetworkOperation::Close,
+            7 => NetworkOperation::Poll,
+            8 => NetworkOperation::XdpAttach,
+            9 => NetworkOperation::XdpKick,
+            10 => NetworkOperation::Ping,
+            11 => NetworkOperation::VsockConnect,
+            12 => NetworkOperation::VsockListen,
+            13 => NetworkOperation::VsockAccept,
+            14 => NetworkOperation::VsockSend,
+            15 => NetworkOperation::VsockRecv,
+            16 => NetworkOperation::VsockClose,
+            17 => NetworkOperation::VsockLocalCid,
+            _ => NetworkOperation::Unknown,
+        }
+    }
+}
+
+impl From<&str> for NetworkOperation {
+    /// Construct a NetworkOperation enum based on a str.
+    fn from(op: &str) -> NetworkOperation {
+        match op {
+            "Socket" => NetworkOperation::Socket,
+            "Bind" => NetworkOperation::Bind,
+            "Connect" => NetworkOperation::Connect,
+            "Send" => NetworkOperation::Send,
+            "Recv" => NetworkOperation::Recv,
+            "Close" => NetworkOperation::Close,
+            "Poll" => NetworkOperation::Poll,
+            "XdpAttach" => NetworkOperation::XdpAttach,
+            "XdpKick" => NetworkOperation::XdpKick,
+            "Ping" => NetworkOperation::Ping,
+            "VsockConnect" => NetworkOperation::VsockConnect,
+            "VsockListen" => NetworkOperation::VsockListen,
+            "VsockAccept" => NetworkOperation::VsockAccept,
+            "VsockSend" => NetworkOperation::VsockSend,
+            "VsockRecv" => NetworkOperation::VsockRecv,
+            "VsockClose" => NetworkOperation::VsockClose,
+            "VsockLocalCid" => NetworkOperation::VsockLocalCid,
+            _ => NetworkOperation::Unknown,
+        }
+    }
+}
+
+/// Operations to query clocks.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u64)]
+pub enum TimeOperation {
+    /// Get the wall-clock time.
+    Wallclock = 1,
+    /// Get the time since boot.
+    Monotonic = 2,
+    /// Get the resolution of the clocks.
+    Resolution = 3,
+    /// Get the address of the time page (see `time`).
+    TimePage = 4,
+    /// Create an interval timer (see `upcall::TIMER`).
+    TimerCreate = 5,
+    /// Cancel an interval timer.
+    TimerCancel = 6,
+    Unknown,
+}
+
+impl From<u64> for TimeOperation {
+    /// Construct a TimeOperation enum based on a 64-bit value.
+    fn from(op: u64) -> TimeOperation {
+        match op {
+            1 => TimeOperation::Wallclock,
+            2 => TimeOperation::Monotonic,
+            3 => TimeOperation::Resolution,
+            4 => TimeOperation::TimePage,
+            5 => TimeOperation::TimerCreate,
+            6 => TimeOperation::TimerCancel,
+            _ => TimeOperation::Unknown,
+        }
+    }
+}
+
+impl From<&str> for TimeOperation {
+    /// Construct a TimeOperation enum based on a str.
+    fn from(op: &str) -> TimeOperation {
+        match op {
+            "Wallclock" => TimeOperation::Wallclock,
+            "Monotonic" => TimeOperation::Monotonic,
+            "Resolution" => TimeOperation::Resolution,
+            "TimePage" => TimeOperation::TimePage,
+            "TimerCreate" => TimeOperation::TimerCreate,
+            "TimerCancel" => TimeOperation::TimerCancel,
+            _ => TimeOperation::Unknown,
+        }
+    }
+}
+
+/// Operations to debug the kernel.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u64)]
+pub enum DebugOperation {
+    /// Make every core dump where it is (and its stack).
+    NmiAll = 1,
+    /// Replace the kernel log filter (e.g., `info,nrk::memory=trace`).
+    SetLogFilter = 2,
+    /// Enable, disable or read a kprobe (see `KprobeMode`).
+    Kprobe = 3,
+    /// Print the scheduler state (cores, processes, executors) as a graph.
+    DumpSchedGraph = 4,
+    /// Start or stop recording the system calls of the calling process.
+    TraceSyscalls = 5,
+    Unknown,
+}
+
+impl From<u64> for DebugOperation {
+    /// Construct a DebugOperation enum based on a 64-bit value.
+    fn from(op: u64) -> DebugOperation {
+        match op {
+            1 => DebugOperation::NmiAll,
+            2 => DebugOperation::SetLogFilter,
+            3 => DebugOperation::Kprobe,
+            4 => DebugOperation::DumpSchedGraph,
+            5 => DebugOperation::TraceSyscalls,
+            _ => DebugOperation::Unknown,
+        }
+    }
+}
+
+impl From<&str> for DebugOperation {
+    /// Construct a DebugOperation enum based on a str.
+    fn from(op: &str) -> DebugOperation {
+        match op {
+            "NmiAll" => DebugOperation::NmiAll,
+            "SetLogFilter" => DebugOperation::SetLogFilter,
+            "Kprobe" => DebugOperation::Kprobe,
+            "DumpSchedGraph" => DebugOperation::DumpSchedGraph,
+            "TraceSyscalls" => DebugOperation::TraceSyscalls,
+            _ => DebugOperation::Unknown,
+        }
+    }
+}
+
+/// What a kprobe does when the kernel passes through it.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u64)]
+pub enum KprobeMode {
+    /// The probe is disabled.
+    Off = 0,
+    /// Count how often we pass through.
+    Count = 1,
+    /// Count and log every hit (with the caller).
+    Trace = 2,
+    Unknown,
+}
+
+impl From<u64> for KprobeMode {
+    /// Construct a KprobeMode enum based on a 64-bit value.
+    fn from(mode: u64) -> KprobeMode {
+        match mode {
+            0 => KprobeMode::Off,
+            1 => KprobeMode::Count,
+            2 => KprobeMode::Trace,
+            _ => KprobeMode::Unknown,
+        }
+    }
+}
+
+/// Operations on the hardware performance counters.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u64)]
+pub enum PerfOperation {
+    /// Program a counter and start counting.
+    Start = 1,
+    /// Stop a counter (and free it).
+    Stop = 2,
+    /// Read the current value of a counter.
+    Read = 3,
+    Unknown,
+}
+
+impl From<u64> for PerfOperation {
+    /// Construct a PerfOperation enum based on a 64-bit value.
+    fn from(op: u64) -> PerfOperation {
+        match op {
+            1 => PerfOperation::Start,
+            2 => PerfOperation::Stop,
+            3 => PerfOperation::Read,
+            _ => PerfOperation::Unknown,
+        }
+    }
+}
+
+impl From<&str> for PerfOperation {
+    /// Construct a PerfOperation enum based on a str.
+    fn from(op: &str) -> PerfOperation {
+        match op {
+            "Start" => PerfOperation::Start,
+            "Stop" => PerfOperation::Stop,
+            "Read" => PerfOperation::Read,
+            _ => PerfOperation::Unknown,
+        }
+    }
+}
+
+/// Operations on capabilities (see `cap`).
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u64)]
+pub enum CapOperation {
+    /// Drop rights of a capability.
+    Restrict = 1,
+    /// Give a capability to another process.
+    Transfer = 2,
+    /// Kind and rights of a capability.
+    Identify = 3,
+    Unknown,
+}
+
+impl From<u64> for CapOperation {
+    /// Construct a CapOperation enum based on a 64-bit value.
+    fn from(op: u64) -> CapOperation {
+        match op {
+            1 => CapOperation::Restrict,
+            2 => CapOperation::Transfer,
+            3 => CapOperation::Identify,
+            _ => CapOperation::Unknown,
+        }
+    }
+}
+
+impl From<&str> for CapOperation {
+    /// Construct a CapOperation enum based on a str.
+    fn from(op: &str) -> CapOperation {
+        match op {
+            "Restrict" => CapOperation::Restrict,
+            "Transfer" => CapOperation::Transfer,
+            "Identify" => CapOperation::Identify,
+            _ => CapOperation::Unknown,
+        }
+    }
+}
+
+/// Operations on doors and events (see `ipc` and `event`).
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u64)]
+pub enum IpcOperation {
+    /// Create a door under a name.
+    Create = 1,
+    /// Get a capability for the door with a name.
+    Connect = 2,
+    /// Queue a call on a door.
+    Call = 3,
+    /// Wait for the reply to a call.
+    Await = 4,
+    /// Reply to a call and wait for the next one.
+    Reply = 5,
+    /// Create an event counter (see `event`).
+    EventCreate = 6,
+    /// Add to the counter of an event.
+    EventSignal = 7,
+    /// Wait for an event and take its counter.
+    EventWait = 8,
+    /// Set or cancel the timer of an event.
+    EventTimer = 9,
+    /// Signal an event whenever a call arrives at a door.
+    Notify = 10,
+    Unknown,
+}
+
+impl From<u64> for IpcOperation {
+    /// Construct a IpcOperation enum based on a 64-bit value.
+    fn from(op: u64) -> IpcOperation {
+        match op {
+            1 => IpcOperation::Create,
+            2 => IpcOperation::Connect,
+            3 => IpcOperation::Call,
+            4 => IpcOperation::Await,
+            5 => IpcOperation::Reply,
+            6 => IpcOperation::EventCreate,
+            7 => IpcOperation::EventSignal,
+            8 => IpcOperation::EventWait,
+            9 => IpcOperation::EventTimer,
+            10 => IpcOperation::Notify,
+            _ => IpcOperation::Unknown,
+        }
+    }
+}
+
+impl From<&str> for IpcOperation {
+    /// Construct a IpcOperation enum based on a str.
+    fn from(op: &str) -> IpcOperation {
+        match op {
+            "Create" => IpcOperation::Create,
+            "Connect" => IpcOperation::Connect,
+            "Call" => IpcOperation::Call,
+            "Await" => IpcOperation::Await,
+            "Reply" => IpcOperation::Reply,
+            "EventCreate" => IpcOperation::EventCreate,
+            "EventSignal" => IpcOperation::EventSignal,
+            "EventWait" => IpcOperation::EventWait,
+            "EventTimer" => IpcOperation::EventTimer,
+            "Notify" => IpcOperation::Notify,
+            _ => IpcOperation::Unknown,
+        }
+    }
+}
+
+/// Operations on virtual machines (see `vm`).
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u64)]
+pub enum VmOperation {
+    /// Create a VM with some vCPUs.
+    Create = 1,
+    /// Back guest-physical memory with a frame.
+    MapGuest = 2,
+    /// Run a vCPU until it exits to the VMM.
+    Run = 3,
+    /// Remove a VM (and its guest memory).
+    Destroy = 4,
+    Unknown,
+}
+
+impl From<u64> for VmOperation {
+    /// Construct a VmOperation enum based on a 64-bit value.
+    fn from(op: u64) -> VmOperation {
+        match op {
+            1 => VmOperation::Create,
+            2 => VmOperation::MapGuest,
+            3 => VmOperation::Run,
+            4 => VmOperation::Destroy,
+            _ => VmOperation::Unknown,
+        }
+    }
+}
+
+impl From<&str> for VmOperation {
+    /// Construct a VmOperation enum based on a str.
+    fn from(op: &str) -> VmOperation {
+        match op {
+            "Create" => VmOperation::Create,
+            "MapGuest" => VmOperation::MapGuest,
+            "Run" => VmOperation::Run,
+            "Destroy" => VmOperation::Destroy,
+            _ => VmOperation::Unknown,
+        }
+    }
+}
+
 /// SystemCall is the type of call we are invoking.
 ///
 /// It is passed to the kernel in the %rdi register.
@@ -300,6 +842,13 @@ pub enum SystemCall {
     Process = 2,
     VSpace = 3,
     FileIO = 4,
+    Network = 5,
+    Time = 6,
+    Debug = 7,
+    Perf = 8,
+    Capability = 9,
+    Ipc = 10,
+    Vm = 11,
     Unknown,
 }
 
@@ -311,6 +860,13 @@ impl SystemCall {
             2 => SystemCall::Process,
             3 => SystemCall::VSpace,
             4 => SystemCall::FileIO,
+            5 => SystemCall::Network,
+            6 => SystemCall::Time,
+            7 => SystemCall::Debug,
+            8 => SystemCall::Perf,
+            9 => SystemCall::Capability,
+            10 => SystemCall::Ipc,
+            11 => SystemCall::Vm,
             _ => SystemCall::Unknown,
         }
     }
@@ -324,6 +880,13 @@ impl From<&str> for SystemCall {
             "Process" => SystemCall::Process,
             "VSpace" => SystemCall::VSpace,
             "FileIO" => SystemCall::FileIO,
+            "Network" => SystemCall::Network,
+            "Time" => SystemCall::Time,
+            "Debug" => SystemCall::Debug,
+            "Perf" => SystemCall::Perf,
+            "Capability" => SystemCall::Capability,
+            "Ipc" => SystemCall::Ipc,
+            "Vm" => SystemCall::Vm,
             _ => SystemCall::Unknown,
         }
     }
@@ -0,0 +1,305 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Data structures to exchange network related information between kernel and user-space.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use bitflags::*;
+
+/// Socket types that can be created with the `Socket` network operation.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[repr(u64)]
+pub enum SocketType {
+    /// A datagram (UDP) socket.
+    Udp = 1,
+    /// A stream (TCP) socket.
+    Tcp = 2,
+}
+
+impl core::convert::TryFrom<u64> for SocketType {
+    type Error = ();
+
+    fn try_from(ty: u64) -> Result<SocketType, ()> {
+        match ty {
+            1 => Ok(SocketType::Udp),
+            2 => Ok(SocketType::Tcp),
+            _ => Err(()),
+        }
+    }
+}
+
+/// An IPv4 endpoint.
+///
+/// Endpoints are passed in registers, see `SocketAddr::as_u64`.
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct SocketAddr {
+    pub ip: [u8; 4],
+    pub port: u16,
+}
+
+impl SocketAddr {
+    pub const fn new(ip: [u8; 4], port: u16) -> SocketAddr {
+        SocketAddr { ip, port }
+    }
+
+    /// Packs the endpoint into a u64 (IP in bits 16..48, port in bits 0..16).
+    pub fn as_u64(&self) -> u64 {
+        (u32::from_be_bytes(self.ip) as u64) << 16 | self.port as u64
+    }
+
+    /// Unpacks an endpoint from a u64 (see `as_u64`).
+    pub fn from_u64(packed: u64) -> SocketAddr {
+        SocketAddr {
+            ip: ((packed >> 16) as u32).to_be_bytes(),
+            port: packed as u16,
+        }
+    }
+
+    /// Unspecified endpoints are encoded as 0.
+    pub fn is_unspecified(&self) -> bool {
+        self.as_u64() == 0
+    }
+}
+
+bitflags! {
+    /// Readiness events reported by the `Poll` network operation.
+    pub struct PollEvents: u16 {
+        /// Data is available to read (or a connection got closed).
+        const POLLIN = 0x0001;
+        /// There is space in the send buffer.
+        const POLLOUT = 0x0004;
+        /// The socket is in an error state.
+        const POLLERR = 0x0008;
+        /// The peer closed the connection.
+        const POLLHUP = 0x0010;
+    }
+}
+
+/// Marks the `fd` of a `PollFd` as an event handle (see `event`).
+pub const POLL_EVENT_HANDLE: u64 = 1 << 63;
+
+/// How many `PollFd`s a single `Poll` takes at most.
+pub const MAX_POLL_FDS: usize = 1024;
+
+/// A socket and the events we want to wait for, as passed to `Poll`.
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+#[repr(C)]
+pub struct PollFd {
+    /// The socket descriptor (or an event handle, see `PollFd::event`).
+    pub fd: u64,
+    /// Requested events (`PollEvents` bits).
+    pub events: u16,
+    /// Events that occured (`PollEvents` bits), filled in by the kernel.
+    pub revents: u16,
+}
+
+impl PollFd {
+    pub fn new(fd: u64, events: PollEvents) -> PollFd {
+        PollFd {
+            fd,
+            events: events.bits(),
+            revents: 0,
+        }
+    }
+
+    /// Asks about event `handle` instead of a socket: it is readable while
+    /// its counter isn't 0 and writable while a signal of 1 would succeed.
+    pub fn event(handle: u64, events: PollEvents) -> PollFd {
+        PollFd::new(handle | POLL_EVENT_HANDLE, events)
+    }
+
+    /// Events the kernel reported for this socket.
+    pub fn revents(&self) -> PollEvents {
+        PollEvents::from_bits_truncate(self.revents)
+    }
+}
+
+/// Marks vsock descriptors (see `VsockAddr`), so they can be passed to
+/// `Poll` together with sockets.
+pub const VSOCK_FD: u64 = 1 << 62;
+
+/// The CID of the host.
+pub const VSOCK_CID_HOST: u32 = 2;
+
+/// A vsock endpoint: a context id (CID) that names the VM (or the host)
+/// and a port.
+///
+/// Endpoints are passed in registers, see `VsockAddr::as_u64`.
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct VsockAddr {
+    pub cid: u32,
+    pub port: u32,
+}
+
+impl VsockAddr {
+    pub const fn new(cid: u32, port: u32) -> VsockAddr {
+        VsockAddr { cid, port }
+    }
+
+    /// Packs the endpoint into a u64 (CID in the upper half).
+    pub fn as_u64(&self) -> u64 {
+        (self.cid as u64) << 32 | self.port as u64
+    }
+
+    /// Unpacks an endpoint from a u64 (see `as_u64`).
+    pub fn from_u64(packed: u64) -> VsockAddr {
+        VsockAddr {
+            cid: (packed >> 32) as u32,
+            port: packed as u32,
+        }
+    }
+}
+
+/// Number of entries in a zero-copy ring (see `XdpRing`).
+pub const XDP_RING_SIZE: usize = 256;
+
+/// Maximum size of a packet in the UMEM region.
+pub const XDP_FRAME_SIZE: usize = 2048;
+
+/// A packet buffer in the UMEM region.
+///
+/// `addr` is the offset of the packet from the start of the UMEM region.
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+#[repr(C)]
+pub struct XdpDesc {
+    pub addr: u64,
+    pub len: u32,
+    pub options: u32,
+}
+
+impl XdpDesc {
+    pub const fn new(addr: u64, len: u32) -> XdpDesc {
+        XdpDesc {
+            addr,
+            len,
+            options: 0,
+        }
+    }
+}
+
+/// A single-producer, single-consumer ring of `XdpDesc` that lives in memory
+/// shared between a process and the kernel.
+///
+/// A zeroed ring is a valid, empty ring.
+#[repr(C)]
+pub struct XdpRing {
+    producer: AtomicU32,
+    consumer: AtomicU32,
+    entries: UnsafeCell<[XdpDesc; XDP_RING_SIZE]>,
+}
+
+// Safe: Entries are only written by the producer before publishing them
+// and only read by the consumer after they have been published.
+unsafe impl Sync for XdpRing {}
+
+impl XdpRing {
+    pub const fn new() -> XdpRing {
+        XdpRing {
+            producer: AtomicU32::new(0),
+            consumer: AtomicU32::new(0),
+            entries: UnsafeCell::new([XdpDesc::new(0, 0); XDP_RING_SIZE]),
+        }
+    }
+
+    /// Number of descriptors in the ring.
+    pub fn len(&self) -> usize {
+        let prod = self.producer.load(Ordering::Acquire);
+        let cons = self.consumer.load(Ordering::Acquire);
+        prod.wrapping_sub(cons) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= XDP_RING_SIZE
+    }
+
+    /// Enqueues `desc`, returns false if the ring is full.
+    ///
+    /// Must only be called by the producer of the ring.
+    pub fn push(&self, desc: XdpDesc) -> bool {
+        let prod = self.producer.load(Ordering::Relaxed);
+        let cons = self.consumer.load(Ordering::Acquire);
+        if prod.wrapping_sub(cons) as usize >= XDP_RING_SIZE {
+            return false;
+        }
+
+        unsafe { (*self.entries.get())[prod as usize % XDP_RING_SIZE] = desc };
+        self.producer.store(prod.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Dequeues the oldest descriptor.
+    ///
+    /// Must only be called by the consumer of the ring.
+    pub fn pop(&self) -> Option<XdpDesc> {
+        let cons = self.consumer.load(Ordering::Relaxed);
+        let prod = self.producer.load(Ordering::Acquire);
+        if cons == prod {
+            return None;
+        }
+
+        let desc = unsafe { (*self.entries.get())[cons as usize % XDP_RING_SIZE] };
+        self.consumer.store(cons.wrapping_add(1), Ordering::Release);
+        Some(desc)
+    }
+}
+
+/// The rings of a zero-copy packet queue.
+///
+/// - `fill`: Process gives free UMEM buffers to the kernel.
+/// - `rx`: Kernel hands received packets to the process.
+/// - `tx`: Process hands packets to send to the kernel.
+/// - `completion`: Kernel returns UMEM buffers of sent packets.
+#[repr(C)]
+pub struct XdpRings {
+    pub fill: XdpRing,
+    pub rx: XdpRing,
+    pub tx: XdpRing,
+    pub completion: XdpRing,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn socket_addr_packing() {
+        let addr = SocketAddr::new([172, 31, 0, 10], 6970);
+        assert_eq!(SocketAddr::from_u64(addr.as_u64()), addr);
+        assert!(SocketAddr::default().is_unspecified());
+        assert!(!addr.is_unspecified());
+    }
+
+    #[test]
+    fn xdp_ring_push_pop() {
+        let ring = XdpRing::new();
+        assert!(ring.is_empty());
+        assert_eq!(ring.pop(), None);
+
+        for i in 0..XDP_RING_SIZE {
+            assert!(ring.push(XdpDesc::new(i as u64, 64)));
+        }
+        assert!(ring.is_full());
+        assert!(!ring.push(XdpDesc::new(0, 0)));
+
+        for i in 0..XDP_RING_SIZE {
+            assert_eq!(ring.pop(), Some(XdpDesc::new(i as u64, 64)));
+        }
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn xdp_ring_wraps() {
+        let ring = XdpRing::new();
+        for i in 0..(3 * XDP_RING_SIZE + 7) {
+            assert!(ring.push(XdpDesc::new(i as u64, i as u32)));
+            assert_eq!(ring.len(), 1);
+            assert_eq!(ring.pop(), Some(XdpDesc::new(i as u64, i as u32)));
+        }
+    }
+}
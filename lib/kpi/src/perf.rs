@@ -0,0 +1,127 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Data structures to program the hardware performance counters (see
+//! `syscalls::Perf`).
+
+/// A hardware event a performance counter can count.
+///
+/// Events are given as (Intel) event select and unit mask, the constants
+/// are the architectural events plus a few model specific ones that are
+/// the same on Haswell and later.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub struct PerfEvent {
+    /// Event select (bits 0-7 of `IA32_PERFEVTSELx`).
+    pub event: u8,
+    /// Unit mask (bits 8-15 of `IA32_PERFEVTSELx`).
+    pub umask: u8,
+    /// Request and response type mask of an offcore response event (0
+    /// for all other events).
+    pub offcore: u64,
+}
+
+impl PerfEvent {
+    /// Unhalted core cycles.
+    pub const CYCLES: PerfEvent = PerfEvent::new(0x3c, 0x00);
+    /// Retired instructions.
+    pub const INSTRUCTIONS: PerfEvent = PerfEvent::new(0xc0, 0x00);
+    /// Requests that went to the last-level cache.
+    pub const LLC_REFERENCES: PerfEvent = PerfEvent::new(0x2e, 0x4f);
+    /// Requests that missed in the last-level cache.
+    pub const LLC_MISSES: PerfEvent = PerfEvent::new(0x2e, 0x41);
+    /// Retired branch instructions.
+    pub const BRANCHES: PerfEvent = PerfEvent::new(0xc4, 0x00);
+    /// Mispredicted retired branch instructions.
+    pub const BRANCH_MISSES: PerfEvent = PerfEvent::new(0xc5, 0x00);
+    /// Data loads that missed the TLB and caused a page walk.
+    pub const DTLB_LOAD_MISSES: PerfEvent = PerfEvent::new(0x08, 0x01);
+    /// Data stores that missed the TLB and caused a page walk.
+    pub const DTLB_STORE_MISSES: PerfEvent = PerfEvent::new(0x49, 0x01);
+    /// Instruction fetches that missed the TLB and caused a page walk.
+    pub const ITLB_MISSES: PerfEvent = PerfEvent::new(0x85, 0x01);
+
+    /// Event select of `OFFCORE_RESPONSE_0` (the kernel may use
+    /// `OFFCORE_RESPONSE_1` instead).
+    const OFFCORE_RESPONSE: u8 = 0xb7;
+
+    pub const fn new(event: u8, umask: u8) -> PerfEvent {
+        PerfEvent {
+            event,
+            umask,
+            offcore: 0,
+        }
+    }
+
+    /// An offcore response event, `mask` selects the requests and
+    /// responses to count (it's model specific, see `MSR_OFFCORE_RSP_x`
+    /// in the Intel SDM).
+    pub const fn offcore(mask: u64) -> PerfEvent {
+        PerfEvent {
+            event: PerfEvent::OFFCORE_RESPONSE,
+            umask: 0x01,
+            offcore: mask,
+        }
+    }
+
+    pub fn is_offcore(&self) -> bool {
+        self.event == PerfEvent::OFFCORE_RESPONSE && self.umask == 0x01
+    }
+
+    /// Event select and unit mask the way `IA32_PERFEVTSELx` wants them.
+    pub fn config(&self) -> u64 {
+        self.event as u64 | (self.umask as u64) << 8
+    }
+
+    /// Inverse of `config` (ignores the other bits).
+    pub fn from_config(config: u64, offcore: u64) -> PerfEvent {
+        PerfEvent {
+            event: config as u8,
+            umask: (config >> 8) as u8,
+            offcore,
+        }
+    }
+}
+
+/// What a counter counts.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u64)]
+pub enum PerfScope {
+    /// Everything that runs on the core (kernel and all processes).
+    Core = 1,
+    /// Only the process that started the counter (in user-space).
+    Process = 2,
+    Unknown,
+}
+
+impl From<u64> for PerfScope {
+    /// Construct a PerfScope enum based on a 64-bit value.
+    fn from(scope: u64) -> PerfScope {
+        match scope {
+            1 => PerfScope::Core,
+            2 => PerfScope::Process,
+            _ => PerfScope::Unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn event_config() {
+        assert_eq!(PerfEvent::LLC_MISSES.config(), 0x412e);
+        assert_eq!(
+            PerfEvent::from_config(PerfEvent::LLC_MISSES.config() | 2 << 32, 0),
+            PerfEvent::LLC_MISSES
+        );
+
+        let offcore = PerfEvent::offcore(0x3f_8000_0001);
+        assert!(offcore.is_offcore());
+        assert!(!PerfEvent::CYCLES.is_offcore());
+        assert_eq!(
+            PerfEvent::from_config(offcore.config(), offcore.offcore),
+            offcore
+        );
+    }
+}
@@ -24,23 +24,129 @@ pub const HEAP_PER_CORE_REGION: usize = 0x2_0000_0000;
 /// End of Heap memory.
 pub const HEAP_END: usize = HEAP_START + ((MAX_CORES + 1) * HEAP_PER_CORE_REGION);
 
+/// How far (at most) ASLR moves the executor stacks, the heap and the mapping
+/// region up (see `AddressLayout`).
+pub const ASLR_RANGE: usize = 0x4_0000_0000;
+
+/// Granularity of the ASLR offsets (large pages, the executor memory is
+/// mapped with them).
+pub const ASLR_ALIGN: usize = 0x20_0000;
+
+/// Start of the region for anonymous mappings the kernel places
+/// (`VSpace::map_anywhere`).
+pub const MMAP_START: usize = HEAP_END + ASLR_RANGE;
+
+/// Size of the region for anonymous mappings.
+pub const MMAP_SIZE: usize = 0x4_0000_0000;
+
 // Make sure that all our process regions are in the first PML4 slot. This isn't
 // really necessary for anything except benchmarking: it helps for scalability
 // benchmarks if we know that all other slots are "empty" and we don't
 // accidentially try to map somewhere where there are already mappings...
 static_assertions::const_assert!(HEAP_END <= 2 * PML4_SLOT_SIZE);
+static_assertions::const_assert!(MMAP_START + MMAP_SIZE + ASLR_RANGE <= 2 * PML4_SLOT_SIZE);
+static_assertions::const_assert!(EXECUTOR_OFFSET + ASLR_RANGE <= HEAP_START);
 static_assertions::const_assert!(EXECUTOR_OFFSET <= PML4_SLOT_SIZE);
 static_assertions::const_assert!(ELF_OFFSET <= PML4_SLOT_SIZE);
 
+/// Handle of a frame capability (see `cap`).
 pub type FrameId = usize;
 
+/// Passed to `Process::request_core` instead of a core: any free core
+/// will do, the kernel picks one (see `system::CorePolicy`).
+pub const ANY_CORE: usize = usize::MAX;
+
+/// A core the process got with `Process::request_core`.
 #[derive(Debug)]
-pub struct CoreToken(usize);
+pub struct CoreToken {
+    gtid: usize,
+    handle: u64,
+}
 
 impl CoreToken {
     #[allow(unused)]
-    pub(crate) fn from(ret: u64) -> Self {
-        CoreToken(ret.try_into().unwrap())
+    pub(crate) fn from(gtid: u64, handle: u64) -> Self {
+        CoreToken {
+            gtid: gtid.try_into().unwrap(),
+            handle,
+        }
+    }
+
+    /// The id of the core.
+    pub fn gtid(&self) -> usize {
+        self.gtid
+    }
+
+    /// Handle of the core capability (see `cap`).
+    pub fn handle(&self) -> u64 {
+        self.handle
+    }
+}
+
+/// Who a process runs as, set when it is spawned.
+///
+/// Files belong to the credentials of the process that created them (see
+/// `io::FileModes`). Processes with uid 0 are privileged: they may access all
+/// files and request cores.
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Credentials {
+    pub const ROOT: Credentials = Credentials { uid: 0, gid: 0 };
+
+    pub fn is_privileged(&self) -> bool {
+        self.uid == 0
+    }
+}
+
+/// Where the regions of a process start.
+///
+/// Unless the kernel runs with `noaslr`, every process gets its own random
+/// (large page aligned) offsets below `ASLR_RANGE` for the executor stacks
+/// (`EXECUTOR_OFFSET`), the heap (`HEAP_START`) and the mapping region
+/// (`MMAP_START`). Processes read it with `Process::layout`.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[repr(C)]
+pub struct AddressLayout {
+    /// Where the stacks of the executors start.
+    pub stack_start: u64,
+    /// Where the heap starts (the per-core heap regions follow it).
+    pub heap_start: u64,
+    /// Where the anonymous mappings of `VSpace::map_anywhere` start.
+    pub mmap_start: u64,
+}
+
+impl AddressLayout {
+    /// The layout without ASLR.
+    pub const FIXED: AddressLayout = AddressLayout {
+        stack_start: EXECUTOR_OFFSET as u64,
+        heap_start: HEAP_START as u64,
+        mmap_start: MMAP_START as u64,
+    };
+
+    /// A layout with the regions moved up by offsets from `rand`.
+    pub fn randomized<F: FnMut() -> u64>(mut rand: F) -> AddressLayout {
+        let mut offset = || (rand() % (ASLR_RANGE / ASLR_ALIGN) as u64) * ASLR_ALIGN as u64;
+        AddressLayout {
+            stack_start: AddressLayout::FIXED.stack_start + offset(),
+            heap_start: AddressLayout::FIXED.heap_start + offset(),
+            mmap_start: AddressLayout::FIXED.mmap_start + offset(),
+        }
+    }
+
+    /// The heap region of core `core` (`HEAP_PER_CORE_REGION` bytes).
+    pub fn heap_region(&self, core: usize) -> (u64, u64) {
+        let start = self.heap_start + (core * HEAP_PER_CORE_REGION) as u64;
+        (start, start + HEAP_PER_CORE_REGION as u64)
+    }
+}
+
+impl Default for AddressLayout {
+    fn default() -> AddressLayout {
+        AddressLayout::FIXED
     }
 }
 
@@ -60,6 +166,15 @@ pub struct ProcessInfo {
     /// App specific command line argument, for example: benchmarks, reads,
     /// value_size for leveldb (passed to the rump init function).
     pub app_cmdline: &'static str,
+    /// Tests init runs (`tests=` on the kernel command-line).
+    #[serde(default)]
+    pub tests: &'static str,
+    /// Who the process runs as.
+    #[serde(default)]
+    pub creds: Credentials,
+    /// Where the regions of the process are.
+    #[serde(default)]
+    pub layout: AddressLayout,
 }
 
 #[cfg(test)]
@@ -76,10 +191,44 @@ fn serialize() {
         alignment: 3,
         cmdline: "test",
         app_cmdline: "app_cmdline",
+        tests: "map,fs",
+        creds: Credentials {
+            uid: 1000,
+            gid: 100,
+        },
+        layout: AddressLayout::randomized(|| 0x1234_5678),
     };
 
     let serialized: &'static [u8] = Vec::leak(serde_cbor::to_vec(&point).unwrap());
     let deserialized: ProcessInfo = serde_cbor::from_slice(&serialized).unwrap();
     log::info!("serialized.len = {}", serialized.len());
     log::info!("deserialized = {:?}", deserialized);
+    assert_eq!(deserialized, point);
+}
+
+#[cfg(test)]
+#[test]
+fn randomized_layout() {
+    let mut next = 0u64;
+    let layout = AddressLayout::randomized(|| {
+        next = next.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        next
+    });
+    assert_ne!(layout, AddressLayout::FIXED);
+
+    for (start, fixed) in [
+        (layout.stack_start, EXECUTOR_OFFSET),
+        (layout.heap_start, HEAP_START),
+        (layout.mmap_start, MMAP_START),
+    ]
+    .iter()
+    {
+        assert_eq!(start % ASLR_ALIGN as u64, 0);
+        assert!(*start >= *fixed as u64 && *start < (*fixed + ASLR_RANGE) as u64);
+    }
+
+    // The last heap region doesn't run into the mapping region
+    let (_start, end) = layout.heap_region(MAX_CORES);
+    assert!(end <= AddressLayout::FIXED.mmap_start);
+    assert_eq!(AddressLayout::randomized(|| 0), AddressLayout::FIXED);
 }
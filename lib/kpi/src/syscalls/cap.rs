@@ -0,0 +1,75 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! System calls to restrict and delegate capabilities (see `cap`).
+
+use crate::cap::{CapKind, CapRights};
+use crate::{syscall, *};
+
+pub struct Capability;
+
+impl Capability {
+    /// Drops all rights of `handle` that aren't in `rights`.
+    ///
+    /// Rights can't be added again, everything the process transferred the
+    /// capability to keeps its rights.
+    pub fn restrict(handle: u64, rights: CapRights) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::Capability as u64,
+                CapOperation::Restrict as u64,
+                handle,
+                rights.bits(),
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Gives process `pid` the object of `handle` with the rights of `handle`
+    /// that are in `rights`, returns the handle in process `pid`.
+    ///
+    /// Fails with `PermissionError` if `handle` doesn't have `GRANT` and
+    /// with `NotSupported` for cores (they can't change their process).
+    pub fn transfer(handle: u64, pid: usize, rights: CapRights) -> Result<u64, SystemCallError> {
+        let (r, transferred) = unsafe {
+            syscall!(
+                SystemCall::Capability as u64,
+                CapOperation::Transfer as u64,
+                handle,
+                pid as u64,
+                rights.bits(),
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(transferred)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Kind and rights of `handle`.
+    pub fn identify(handle: u64) -> Result<(CapKind, CapRights), SystemCallError> {
+        let (r, kind, rights) = unsafe {
+            syscall!(
+                SystemCall::Capability as u64,
+                CapOperation::Identify as u64,
+                handle,
+                3
+            )
+        };
+
+        if r == 0 {
+            Ok((CapKind::from(kind), CapRights::from_bits_truncate(rights)))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! System calls to debug the kernel.
+
+use crate::trace::TraceEntry;
+use crate::{syscall, *};
+
+pub struct Debug;
+
+impl Debug {
+    /// Sends an NMI to every online core, each of them prints where it was
+    /// interrupted and a stack trace on the serial console.
+    ///
+    /// Returns how many cores answered.
+    pub fn nmi_all() -> Result<usize, SystemCallError> {
+        let (r, answered) =
+            unsafe { syscall!(SystemCall::Debug as u64, DebugOperation::NmiAll as u64, 2) };
+
+        if r == 0 {
+            Ok(answered as usize)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Changes which kernel log messages get printed, `filter` is a comma
+    /// separated list of `level`, `path=level` or `path` (everything in
+    /// `path`), e.g. `warn,nrk::memory=trace`.
+    pub fn set_log_filter(filter: &str) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::Debug as u64,
+                DebugOperation::SetLogFilter as u64,
+                filter.as_ptr() as u64,
+                filter.len() as u64,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Sets what the kprobe `name` does (e.g., `pt_alloc`, see `kprobe!` in
+    /// the kernel for the ones we have).
+    ///
+    /// Returns how often the probe was hit (while enabled) so far.
+    pub fn kprobe(name: &str, mode: KprobeMode) -> Result<u64, SystemCallError> {
+        let (r, hits) = unsafe {
+            syscall!(
+                SystemCall::Debug as u64,
+                DebugOperation::Kprobe as u64,
+                name.as_ptr() as u64,
+                name.len() as u64,
+                mode as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(hits)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Prints which process every core is allocated to and where its
+    /// executors run (in graphviz format) on the kernel's serial console.
+    pub fn dump_sched_graph() -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::Debug as u64,
+                DebugOperation::DumpSchedGraph as u64,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Starts (or stops) recording every system call this process makes,
+    /// the kernel prints the trace when the process exits (see
+    /// `trace::TraceEntry` for the format).
+    pub fn trace_syscalls(enable: bool) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::Debug as u64,
+                DebugOperation::TraceSyscalls as u64,
+                enable as u64,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Makes the system call recorded in `entry` again, returns what it
+    /// returned this time (as a `TraceEntry` without time stamps).
+    ///
+    /// # Safety
+    /// Does whatever the recorded call did (e.g., unmap memory), pointer
+    /// arguments must still point to the same data.
+    pub unsafe fn reissue(entry: &TraceEntry) -> TraceEntry {
+        let (error, ret1, ret2) = syscall!(
+            entry.function,
+            entry.args[0],
+            entry.args[1],
+            entry.args[2],
+            entry.args[3],
+            entry.args[4],
+            3
+        );
+
+        TraceEntry {
+            error,
+            ret: [ret1, ret2],
+            start: 0,
+            end: 0,
+            ..*entry
+        }
+    }
+}
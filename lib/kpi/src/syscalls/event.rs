@@ -0,0 +1,119 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! System calls to signal and wait for event counters (see `event`).
+
+use core::convert::TryInto;
+use core::time::Duration;
+
+use crate::event::EventFlags;
+use crate::{syscall, *};
+
+pub struct Event;
+
+impl Event {
+    /// Creates an event with counter `initial`, returns a capability to
+    /// signal and wait for it.
+    pub fn create(initial: u64, flags: EventFlags) -> Result<u64, SystemCallError> {
+        let (r, handle) = unsafe {
+            syscall!(
+                SystemCall::Ipc as u64,
+                IpcOperation::EventCreate as u64,
+                initial,
+                flags.bits(),
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(handle)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Adds `n` to the counter of event `handle` and wakes up the cores
+    /// that wait for it.
+    ///
+    /// Fails with `WouldBlock` if the counter would go beyond
+    /// `MAX_EVENT_VALUE`.
+    pub fn signal(handle: u64, n: u64) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::Ipc as u64,
+                IpcOperation::EventSignal as u64,
+                handle,
+                n,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Waits until the counter of event `handle` isn't 0, returns what we
+    /// took from it.
+    ///
+    /// This halts the core, not just the calling thread.
+    pub fn wait(handle: u64) -> Result<u64, SystemCallError> {
+        loop {
+            match Event::wait_op(handle, true) {
+                Err(SystemCallError::WouldBlock) => continue,
+                r => return r,
+            }
+        }
+    }
+
+    /// Like `wait`, but fails with `WouldBlock` instead of waiting.
+    pub fn try_wait(handle: u64) -> Result<u64, SystemCallError> {
+        Event::wait_op(handle, false)
+    }
+
+    fn wait_op(handle: u64, block: bool) -> Result<u64, SystemCallError> {
+        let (r, value) = unsafe {
+            syscall!(
+                SystemCall::Ipc as u64,
+                IpcOperation::EventWait as u64,
+                handle,
+                block as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(value)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Has the kernel signal event `handle` (by 1) once `after` passed,
+    /// `None` cancels the timer.
+    ///
+    /// An event has one timer, setting it again replaces the old one.
+    pub fn set_timer(handle: u64, after: Option<Duration>) -> Result<(), SystemCallError> {
+        // u64::MAX cancels
+        let after = after.map_or(u64::MAX, |t| {
+            t.as_nanos().try_into().unwrap_or(u64::MAX - 1)
+        });
+        let r = unsafe {
+            syscall!(
+                SystemCall::Ipc as u64,
+                IpcOperation::EventTimer as u64,
+                handle,
+                after,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+}
@@ -252,4 +252,16 @@ impl Fs {
             Err(SystemCallError::from(r))
         }
     }
+
+    /// Returns once all writes to the file `fd` that completed before the
+    /// call are applied on the replica of this core.
+    pub fn fsync(fd: u64) -> Result<(), SystemCallError> {
+        let r = unsafe { syscall!(SystemCall::FileIO as u64, FileOperation::FSync, fd, 1) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
 }
@@ -0,0 +1,155 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! System calls to serve and call doors (see `ipc`).
+
+use crate::ipc::Message;
+use crate::{syscall, *};
+
+pub struct Ipc;
+
+impl Ipc {
+    /// Creates a door called `name`, returns a capability to serve (and
+    /// call) it.
+    ///
+    /// Fails with `PermissionError` if a door with that name exists.
+    pub fn create(name: &str) -> Result<u64, SystemCallError> {
+        let (r, handle) = unsafe {
+            syscall!(
+                SystemCall::Ipc as u64,
+                IpcOperation::Create as u64,
+                name.as_ptr() as u64,
+                name.len() as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(handle)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Returns a capability to call the door called `name`.
+    pub fn connect(name: &str) -> Result<u64, SystemCallError> {
+        let (r, handle) = unsafe {
+            syscall!(
+                SystemCall::Ipc as u64,
+                IpcOperation::Connect as u64,
+                name.as_ptr() as u64,
+                name.len() as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(handle)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Sends `request` to the server of door `handle` and waits for its
+    /// reply.
+    pub fn call(handle: u64, request: &Message) -> Result<Message, SystemCallError> {
+        let (r, id) = unsafe {
+            syscall!(
+                SystemCall::Ipc as u64,
+                IpcOperation::Call as u64,
+                handle,
+                request as *const Message as u64,
+                2
+            )
+        };
+        if r != 0 {
+            return Err(SystemCallError::from(r));
+        }
+
+        // The core can wake up before the reply is there
+        let mut reply = Message::empty();
+        loop {
+            let r = unsafe {
+                syscall!(
+                    SystemCall::Ipc as u64,
+                    IpcOperation::Await as u64,
+                    handle,
+                    id,
+                    &mut reply as *mut Message as u64,
+                    1
+                )
+            };
+
+            if r == 0 {
+                return Ok(reply);
+            }
+            match SystemCallError::from(r) {
+                SystemCallError::WouldBlock => continue,
+                e => return Err(e),
+            }
+        }
+    }
+
+    /// Replies to call `call` (if any) of door `handle`, then waits for the
+    /// next call and stores its request in `next` (if any).
+    ///
+    /// Returns the id of the next call to pass with its reply to `reply`
+    /// (0 without `next`). Only the process that created the door can serve
+    /// it.
+    pub fn reply(
+        handle: u64,
+        call: Option<(u64, &Message)>,
+        next: Option<&mut Message>,
+    ) -> Result<u64, SystemCallError> {
+        let (mut id, mut reply) =
+            call.map_or((0, 0), |(id, reply)| (id, reply as *const Message as u64));
+        let next = next.map_or(0, |request| request as *mut Message as u64);
+
+        loop {
+            let (r, received) = unsafe {
+                syscall!(
+                    SystemCall::Ipc as u64,
+                    IpcOperation::Reply as u64,
+                    handle,
+                    id,
+                    reply,
+                    next,
+                    2
+                )
+            };
+
+            if r == 0 {
+                return Ok(received);
+            }
+            match SystemCallError::from(r) {
+                // The reply went out, now we're just waiting
+                SystemCallError::WouldBlock => {
+                    id = 0;
+                    reply = 0;
+                }
+                e => return Err(e),
+            }
+        }
+    }
+
+    /// Has the kernel signal event `event` (see `Event`) whenever a call
+    /// arrives at door `handle`, so the server can wait for calls and other
+    /// things at the same time.
+    pub fn notify(handle: u64, event: u64) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::Ipc as u64,
+                IpcOperation::Notify as u64,
+                handle,
+                event,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+}
@@ -24,6 +24,30 @@ impl VSpace {
         VSpace::vspace(VSpaceOperation::Map, base, bound)
     }
 
+    /// Back `bound` bytes of memory with DRAM somewhere in the mapping region
+    /// of the process (see `process::AddressLayout`), returns where.
+    ///
+    /// The kernel never hands out the same addresses twice, `unmap` gives the
+    /// memory back but not the addresses.
+    ///
+    /// # Safety
+    /// Manipulates address space of process.
+    pub unsafe fn map_anywhere(bound: u64) -> Result<(VAddr, PAddr), SystemCallError> {
+        let (err, vaddr, paddr) = syscall!(
+            SystemCall::VSpace as u64,
+            VSpaceOperation::MapAnywhere as u64,
+            0,
+            bound,
+            3
+        );
+
+        if err == 0 {
+            Ok((VAddr::from(vaddr), PAddr::from(paddr)))
+        } else {
+            Err(SystemCallError::from(err))
+        }
+    }
+
     /// Unmap region of virtual memory.
     ///
     /// # Safety
@@ -40,7 +64,8 @@ impl VSpace {
         VSpace::vspace(VSpaceOperation::MapDevice, base, bound)
     }
 
-    /// Maps a registered frame.
+    /// Maps the frame `frame_id` (a capability with `MAP`), it's writeable
+    /// if the capability has `WRITE`.
     ///
     /// # Safety
     /// Manipulates address space of process.
@@ -64,10 +89,81 @@ impl VSpace {
         }
     }
 
+    /// Changes the access rights of the mapped region `[base, base + bound)`.
+    ///
+    /// # Safety
+    /// Manipulates address space of process.
+    pub unsafe fn protect(
+        base: u64,
+        bound: u64,
+        rights: MemoryRights,
+    ) -> Result<(), SystemCallError> {
+        let (err, _base, _size) = syscall!(
+            SystemCall::VSpace as u64,
+            VSpaceOperation::Protect as u64,
+            base,
+            bound,
+            rights.bits(),
+            3
+        );
+
+        if err == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(err))
+        }
+    }
+
+    /// Evicts the anonymous memory in `[base, base + size)` to the zswap
+    /// pool or the swap device, returns how many pages were evicted.
+    ///
+    /// The contents stay the same, an access brings a page back.
+    pub fn page_out(base: u64, size: u64) -> Result<u64, SystemCallError> {
+        let (err, evicted, _size) = unsafe {
+            syscall!(
+                SystemCall::VSpace as u64,
+                VSpaceOperation::PageOut as u64,
+                base,
+                size,
+                3
+            )
+        };
+
+        if err == 0 {
+            Ok(evicted)
+        } else {
+            Err(SystemCallError::from(err))
+        }
+    }
+
     pub fn identify(base: u64) -> Result<(VAddr, PAddr), SystemCallError> {
         unsafe { VSpace::vspace(VSpaceOperation::Identify, base, 0) }
     }
 
+    /// Page faults in `[base, base + size)` the kernel can't resolve become
+    /// `upcall::PAGE_FAULT` upcalls (instead of ending the process).
+    pub fn register_fault_region(base: u64, size: u64) -> Result<(), SystemCallError> {
+        unsafe { VSpace::vspace(VSpaceOperation::RegisterFaultRegion, base, size).map(|_r| ()) }
+    }
+
+    /// Removes the region `register_fault_region` added at `base`.
+    pub fn unregister_fault_region(base: u64) -> Result<(), SystemCallError> {
+        let (err, _base, _size) = unsafe {
+            syscall!(
+                SystemCall::VSpace as u64,
+                VSpaceOperation::UnregisterFaultRegion as u64,
+                base,
+                3
+            )
+        };
+
+        if err == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(err))
+        }
+    }
+
     /// Manipulate the virtual address space.
     unsafe fn vspace(
         op: VSpaceOperation,
@@ -5,13 +5,29 @@
 //!
 //! Code in this module is not linked into the kernel.
 
+mod cap;
+mod debug;
+mod event;
 mod io;
+mod ipc;
 mod macros;
 mod memory;
+mod net;
+mod perf;
 mod process;
 mod system;
+mod time;
+mod vm;
 
+pub use cap::Capability;
+pub use debug::Debug;
+pub use event::Event;
 pub use io::{Fs, Irq};
+pub use ipc::Ipc;
 pub use memory::{PhysicalMemory, VSpace};
+pub use net::Net;
+pub use perf::Perf;
 pub use process::Process;
 pub use system::System;
+pub use time::Time;
+pub use vm::Vm;
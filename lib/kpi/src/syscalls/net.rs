@@ -0,0 +1,386 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! System calls to use the kernel network stack.
+//!
+//! All sockets are non-blocking: operations that can't make progress return
+//! `SystemCallError::WouldBlock` and `Net::poll` is used to figure out which
+//! sockets are ready.
+
+use core::convert::TryInto;
+use core::time::Duration;
+
+use crate::net::{PollFd, SocketAddr, SocketType, VsockAddr};
+use crate::{syscall, *};
+
+pub struct Net;
+
+impl Net {
+    /// Create a new socket of type `ty`.
+    pub fn socket(ty: SocketType) -> Result<u64, SystemCallError> {
+        let (r, fd) = unsafe {
+            syscall!(
+                SystemCall::Network as u64,
+                NetworkOperation::Socket as u64,
+                ty as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(fd)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Bind a UDP socket to `port`, or start listening on `port` with a TCP socket.
+    ///
+    /// A listening TCP socket becomes the connection once a peer connects.
+    pub fn bind(fd: u64, port: u16) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::Network as u64,
+                NetworkOperation::Bind as u64,
+                fd,
+                port as u64,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Connect a TCP socket to `addr` (for UDP, this sets the default peer).
+    pub fn connect(fd: u64, addr: SocketAddr) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::Network as u64,
+                NetworkOperation::Connect as u64,
+                fd,
+                addr.as_u64(),
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Send `buf` on a connected socket. Returns how many bytes were queued.
+    pub fn send(fd: u64, buf: &[u8]) -> Result<usize, SystemCallError> {
+        Net::send_to(fd, buf, SocketAddr::default())
+    }
+
+    /// Send `buf` to `addr` (UDP only, TCP sockets ignore `addr`).
+    pub fn send_to(fd: u64, buf: &[u8], addr: SocketAddr) -> Result<usize, SystemCallError> {
+        let (r, len) = unsafe {
+            syscall!(
+                SystemCall::Network as u64,
+                NetworkOperation::Send as u64,
+                fd,
+                buf.as_ptr() as u64,
+                buf.len() as u64,
+                addr.as_u64(),
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(len as usize)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Receive data from a socket into `buf`.
+    pub fn recv(fd: u64, buf: &mut [u8]) -> Result<usize, SystemCallError> {
+        Net::recv_from(fd, buf).map(|(len, _addr)| len)
+    }
+
+    /// Receive data into `buf`, also returns the endpoint of the sender.
+    pub fn recv_from(fd: u64, buf: &mut [u8]) -> Result<(usize, SocketAddr), SystemCallError> {
+        let mut addr: u64 = 0;
+        let (r, len) = unsafe {
+            syscall!(
+                SystemCall::Network as u64,
+                NetworkOperation::Recv as u64,
+                fd,
+                buf.as_mut_ptr() as u64,
+                buf.len() as u64,
+                &mut addr as *mut u64 as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok((len as usize, SocketAddr::from_u64(addr)))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Close a socket.
+    pub fn close(fd: u64) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::Network as u64,
+                NetworkOperation::Close as u64,
+                fd,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Updates `revents` of every entry in `fds` and returns the number of
+    /// sockets that are ready.
+    ///
+    /// If none are, the core sleeps until one might be or `timeout` passed
+    /// (`None` waits forever, zero doesn't wait) and returns 0: it doesn't
+    /// tell what woke it up, so poll again (see `vibrio::net::poll`). `fds`
+    /// can have at most `MAX_POLL_FDS` entries.
+    pub fn poll(fds: &mut [PollFd], timeout: Option<Duration>) -> Result<usize, SystemCallError> {
+        let timeout = timeout.map_or(u64::MAX, |t| {
+            t.as_nanos().try_into().unwrap_or(u64::MAX - 1)
+        });
+        let (r, ready) = unsafe {
+            syscall!(
+                SystemCall::Network as u64,
+                NetworkOperation::Poll as u64,
+                fds.as_mut_ptr() as u64,
+                fds.len() as u64,
+                timeout,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(ready as usize)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Attaches a zero-copy packet queue to the NIC.
+    ///
+    /// UDP packets for `port` bypass the socket layer and are delivered
+    /// into the UMEM region through the rings at `rings` (see `XdpRings`).
+    /// Both `rings` and `umem` must be backed by physically contiguous memory
+    /// (e.g., a large page).
+    pub fn xdp_attach(
+        rings: u64,
+        umem: u64,
+        umem_len: u64,
+        port: u16,
+    ) -> Result<u64, SystemCallError> {
+        let (r, fd) = unsafe {
+            syscall!(
+                SystemCall::Network as u64,
+                NetworkOperation::XdpAttach as u64,
+                rings,
+                umem,
+                umem_len,
+                port as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(fd)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Sends all packets in the TX ring of the zero-copy queue `fd`
+    /// (and checks for newly received packets).
+    pub fn xdp_kick(fd: u64) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::Network as u64,
+                NetworkOperation::XdpKick as u64,
+                fd,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Pings `addr` (ICMP echo) and returns the round-trip time.
+    ///
+    /// Fails with `SystemCallError::TimedOut` if there was no reply within
+    /// `timeout`. Note that the kernel spins while waiting.
+    pub fn ping(addr: [u8; 4], timeout: Duration) -> Result<Duration, SystemCallError> {
+        let (r, rtt) = unsafe {
+            syscall!(
+                SystemCall::Network as u64,
+                NetworkOperation::Ping as u64,
+                u32::from_be_bytes(addr) as u64,
+                timeout.as_micros() as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(Duration::from_micros(rtt))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Connects a vsock stream to `addr`, returns its descriptor.
+    ///
+    /// Like a TCP connect this doesn't wait: the descriptor becomes writable
+    /// once the peer accepted the connection (or hangs up if it refused).
+    pub fn vsock_connect(addr: VsockAddr) -> Result<u64, SystemCallError> {
+        let (r, fd) = unsafe {
+            syscall!(
+                SystemCall::Network as u64,
+                NetworkOperation::VsockConnect as u64,
+                addr.as_u64(),
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(fd)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Listens for vsock connections on `port`, returns the descriptor to
+    /// pass to `Net::vsock_accept`.
+    pub fn vsock_listen(port: u32) -> Result<u64, SystemCallError> {
+        let (r, fd) = unsafe {
+            syscall!(
+                SystemCall::Network as u64,
+                NetworkOperation::VsockListen as u64,
+                port as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(fd)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Takes the next connection of the listening descriptor `fd`, returns
+    /// its descriptor and the endpoint of the peer.
+    pub fn vsock_accept(fd: u64) -> Result<(u64, VsockAddr), SystemCallError> {
+        let mut addr: u64 = 0;
+        let (r, conn) = unsafe {
+            syscall!(
+                SystemCall::Network as u64,
+                NetworkOperation::VsockAccept as u64,
+                fd,
+                &mut addr as *mut u64 as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok((conn, VsockAddr::from_u64(addr)))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Sends `buf` on a vsock connection. Returns how many bytes were sent
+    /// (the peer's receive buffer limits it).
+    pub fn vsock_send(fd: u64, buf: &[u8]) -> Result<usize, SystemCallError> {
+        let (r, len) = unsafe {
+            syscall!(
+                SystemCall::Network as u64,
+                NetworkOperation::VsockSend as u64,
+                fd,
+                buf.as_ptr() as u64,
+                buf.len() as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(len as usize)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Receives data from a vsock connection into `buf`, 0 means the peer
+    /// closed the connection.
+    pub fn vsock_recv(fd: u64, buf: &mut [u8]) -> Result<usize, SystemCallError> {
+        let (r, len) = unsafe {
+            syscall!(
+                SystemCall::Network as u64,
+                NetworkOperation::VsockRecv as u64,
+                fd,
+                buf.as_mut_ptr() as u64,
+                buf.len() as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(len as usize)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Closes a vsock connection (or stops listening).
+    pub fn vsock_close(fd: u64) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::Network as u64,
+                NetworkOperation::VsockClose as u64,
+                fd,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Returns the CID of this VM.
+    pub fn vsock_local_cid() -> Result<u32, SystemCallError> {
+        let (r, cid) = unsafe {
+            syscall!(
+                SystemCall::Network as u64,
+                NetworkOperation::VsockLocalCid as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(cid as u32)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+}
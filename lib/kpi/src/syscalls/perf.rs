@@ -0,0 +1,81 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! System calls to use the hardware performance counters.
+//!
+//! Counters belong to the core the calls are made on: every core has a
+//! few of them (numbered from 0) and a thread that wants to count on
+//! several cores starts a counter on each of them.
+
+use crate::perf::{PerfEvent, PerfScope};
+use crate::{syscall, *};
+
+pub struct Perf;
+
+impl Perf {
+    /// Starts counting `event` with `counter` of the current core.
+    ///
+    /// Fails with `NotSupported` if the core doesn't have `counter` (or
+    /// no performance counters at all) and with `PermissionError` if it
+    /// is in use already.
+    pub fn start(
+        counter: usize,
+        event: PerfEvent,
+        scope: PerfScope,
+    ) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::Perf as u64,
+                PerfOperation::Start as u64,
+                counter as u64,
+                event.config() | (scope as u64) << 32,
+                event.offcore,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Stops `counter` of the current core, returns its final value.
+    ///
+    /// Fails with `BadFlags` if the counter isn't started.
+    pub fn stop(counter: usize) -> Result<u64, SystemCallError> {
+        let (r, value) = unsafe {
+            syscall!(
+                SystemCall::Perf as u64,
+                PerfOperation::Stop as u64,
+                counter as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(value)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Reads `counter` of the current core (it keeps counting).
+    pub fn read(counter: usize) -> Result<u64, SystemCallError> {
+        let (r, value) = unsafe {
+            syscall!(
+                SystemCall::Perf as u64,
+                PerfOperation::Read as u64,
+                counter as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(value)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+}
@@ -3,9 +3,14 @@
 
 //! Abstraction for system calls to do control the current process.
 
+use core::convert::TryInto;
+use core::sync::atomic::AtomicU32;
+use core::time::Duration;
+
 use crate::*;
 
-use crate::process::{CoreToken, ProcessInfo};
+use crate::filter::SyscallFilter;
+use crate::process::{AddressLayout, CoreToken, ProcessInfo, ANY_CORE};
 use crate::syscall;
 use crate::x86_64::VirtualCpu;
 
@@ -16,7 +21,7 @@ pub struct Process;
 impl Process {
     /// Request to run on `core_id` starting at `entry_point`.
     pub fn request_core(core_id: usize, entry_point: VAddr) -> Result<CoreToken, SystemCallError> {
-        let (r, gtid, _eid) = unsafe {
+        let (r, gtid, handle) = unsafe {
             syscall!(
                 SystemCall::Process as u64,
                 ProcessOperation::RequestCore as u64,
@@ -27,13 +32,21 @@ impl Process {
         };
 
         if r == 0 {
-            debug_assert_eq!(gtid as usize, core_id, "Should this hold?");
-            Ok(CoreToken::from(gtid))
+            debug_assert!(core_id == ANY_CORE || gtid as usize == core_id);
+            Ok(CoreToken::from(gtid, handle))
         } else {
             Err(SystemCallError::from(r))
         }
     }
 
+    /// Request to run on whatever free core the kernel's core policy picks
+    /// (see `System::set_core_policy`), starting at `entry_point`.
+    ///
+    /// `CoreToken::gtid` tells which core it was.
+    pub fn request_any_core(entry_point: VAddr) -> Result<CoreToken, SystemCallError> {
+        Process::request_core(ANY_CORE, entry_point)
+    }
+
     /// Print `buffer` on the console.
     pub fn print(buffer: &str) -> Result<(), SystemCallError> {
         let r = unsafe {
@@ -79,28 +92,186 @@ impl Process {
     /// Query process specific information.
     pub fn process_info() -> Result<ProcessInfo, SystemCallError> {
         let mut buf = alloc::vec![0; 256];
-        let (r, len) = unsafe {
+        loop {
+            let (r, len) = unsafe {
+                syscall!(
+                    SystemCall::Process as u64,
+                    ProcessOperation::GetProcessInfo as u64,
+                    buf.as_mut_ptr() as u64,
+                    buf.len() as u64,
+                    2
+                )
+            };
+            if r != 0 {
+                return Err(SystemCallError::from(r));
+            }
+
+            // The kernel doesn't copy anything if it doesn't fit (long
+            // command-lines), but tells us how much space it needs
+            let len = len as usize;
+            if len > buf.len() {
+                buf.resize(len, 0);
+                continue;
+            }
+            buf.truncate(len);
+            let static_buf = alloc::vec::Vec::leak(buf);
+            let deserialized: ProcessInfo = serde_cbor::from_slice(static_buf).unwrap();
+            return Ok(deserialized);
+        }
+    }
+
+    /// Where the executor stacks, the heap and the mapping region of the
+    /// process are (they move around unless the kernel runs with `noaslr`).
+    ///
+    /// Doesn't allocate, the allocator uses it to find the heap.
+    pub fn layout() -> Result<AddressLayout, SystemCallError> {
+        let mut layout = AddressLayout::FIXED;
+        let r = unsafe {
             syscall!(
                 SystemCall::Process as u64,
-                ProcessOperation::GetProcessInfo as u64,
-                buf.as_mut_ptr() as u64,
-                buf.len() as u64,
+                ProcessOperation::GetLayout as u64,
+                &mut layout as *mut AddressLayout as u64,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(layout)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// From now on, only allow the system calls that both `filter` and
+    /// the filters of earlier calls allow (see `filter`).
+    pub fn restrict_syscalls(filter: &SyscallFilter) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::Process as u64,
+                ProcessOperation::RestrictSyscalls as u64,
+                filter as *const SyscallFilter as u64,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Writes a checkpoint of the process to the file `pathname` (a
+    /// NUL-terminated path), see `restore`.
+    ///
+    /// Only a process that runs on one core can do this. Returns false
+    /// here and true in the process restored from the checkpoint.
+    pub fn checkpoint(pathname: u64) -> Result<bool, SystemCallError> {
+        let (r, restored) = unsafe {
+            syscall!(
+                SystemCall::Process as u64,
+                ProcessOperation::Checkpoint as u64,
+                pathname,
                 2
             )
         };
 
         if r == 0 {
-            let len = len as usize;
-            debug_assert!(len <= buf.len());
-            buf.resize(len, 0);
-            let static_buf = alloc::vec::Vec::leak(buf);
-            let deserialized: ProcessInfo = serde_cbor::from_slice(static_buf).unwrap();
-            Ok(deserialized)
+            Ok(restored != 0)
         } else {
             Err(SystemCallError::from(r))
         }
     }
 
+    /// Starts a new process from the checkpoint `image` (the contents of a
+    /// file `checkpoint` wrote), returns its pid.
+    ///
+    /// It continues where `checkpoint` returned, on a core of its own.
+    pub fn restore(image: &[u8]) -> Result<usize, SystemCallError> {
+        let (r, pid) = unsafe {
+            syscall!(
+                SystemCall::Process as u64,
+                ProcessOperation::Restore as u64,
+                image.as_ptr() as u64,
+                image.len() as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(pid as usize)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Sleeps (the whole core) until another core calls `futex_wake` on
+    /// `word` or `timeout` passed, if `word` is still `expected`.
+    ///
+    /// Returns `SystemCallError::WouldBlock` if `word` changed already and
+    /// `SystemCallError::TimedOut` after `timeout`. It can also return
+    /// without anyone waking it up, so check `word` again.
+    pub fn futex_wait(
+        word: &AtomicU32,
+        expected: u32,
+        timeout: Option<Duration>,
+    ) -> Result<(), SystemCallError> {
+        let timeout = timeout.map_or(u64::MAX, |t| {
+            t.as_nanos().try_into().unwrap_or(u64::MAX - 1)
+        });
+        let r = unsafe {
+            syscall!(
+                SystemCall::Process as u64,
+                ProcessOperation::FutexWait as u64,
+                word as *const AtomicU32 as u64,
+                expected as u64,
+                timeout,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Wakes up (at most) `count` cores sleeping on `word`, returns how many
+    /// woke up.
+    pub fn futex_wake(word: &AtomicU32, count: usize) -> Result<usize, SystemCallError> {
+        let (r, woken) = unsafe {
+            syscall!(
+                SystemCall::Process as u64,
+                ProcessOperation::FutexWake as u64,
+                word as *const AtomicU32 as u64,
+                count as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(woken as usize)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Gives the current core back to the kernel (in response to a
+    /// `upcall::CORE_REVOKED`), nothing must run on it anymore.
+    pub fn release_core() -> ! {
+        unsafe {
+            syscall!(
+                SystemCall::Process as u64,
+                ProcessOperation::ReleaseCore as u64,
+                1
+            );
+
+            // The core went back to the kernel:
+            unreachable!()
+        }
+    }
+
     /// Exit the process (pass an error `code` to exit).
     pub fn exit(code: u64) -> ! {
         unsafe {
@@ -5,15 +5,44 @@
 //! (topology, memory, device hardware etc.)
 
 use alloc::vec::Vec;
+use core::time::Duration;
 
 use crate::{syscall, *};
 
-use crate::system::{CoreId, CpuThread};
+use crate::abi::{AbiFeatures, AbiVersion, ABI_VERSION};
+use crate::system::{CoreFrequency, CoreId, CorePolicy, CoreStats, CpuThread, KeyEvent, NumaNode};
 
 pub struct System;
 
 impl System {
-    /// Query information about available hardware threads.
+    /// Query the version of the system call interface the kernel implements
+    /// and which of the optional parts it has.
+    ///
+    /// Returns `SystemCallError::IncompatibleAbi` if we can't use the
+    /// kernel, `abi::ABI_VERSION` is what we need.
+    pub fn abi_version() -> Result<(AbiVersion, AbiFeatures), SystemCallError> {
+        let (r, version, features) = unsafe {
+            syscall!(
+                SystemCall::System as u64,
+                SystemOperation::AbiVersion as u64,
+                u64::from(ABI_VERSION),
+                3
+            )
+        };
+
+        if r == 0 {
+            let version = AbiVersion::from(version);
+            if !ABI_VERSION.is_compatible(version) {
+                return Err(SystemCallError::IncompatibleAbi);
+            }
+            Ok((version, AbiFeatures::from_bits_truncate(features)))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Query information about available hardware threads (the ones the
+    /// kernel brought online).
     pub fn threads() -> Result<Vec<CpuThread>, SystemCallError> {
         let mut buf = alloc::vec![0; 5*4096];
         let (r, len) = unsafe {
@@ -37,17 +66,66 @@ impl System {
         }
     }
 
-    /// Prints some stats for the core.
-    pub fn stats() -> Result<(), SystemCallError> {
-        let r = unsafe { syscall!(SystemCall::System as u64, SystemOperation::Stats as u64, 1) };
+    /// Query the NUMA nodes of the system and the distances between them.
+    pub fn topology() -> Result<Vec<NumaNode>, SystemCallError> {
+        let mut buf = alloc::vec![0; 4096];
+        let (r, len) = unsafe {
+            syscall!(
+                SystemCall::System as u64,
+                SystemOperation::GetTopology as u64,
+                buf.as_mut_ptr() as u64,
+                buf.len() as u64,
+                2
+            )
+        };
 
         if r == 0 {
-            Ok(())
+            let len = len as usize;
+            if len > buf.len() {
+                return Err(SystemCallError::OutOfMemory);
+            }
+            buf.resize(len, 0);
+            let deserialized: Vec<NumaNode> = serde_cbor::from_slice(&buf).unwrap();
+            Ok(deserialized)
         } else {
             Err(SystemCallError::from(r))
         }
     }
 
+    /// Query the event counters (system calls, interrupts, context switches
+    /// and TLB shootdowns) of every core.
+    ///
+    /// Sum them up with `stats.iter().sum::<CoreStats>()` for system-wide
+    /// numbers.
+    pub fn stats() -> Result<Vec<CoreStats>, SystemCallError> {
+        let mut buf = alloc::vec![0; 4096];
+        loop {
+            let (r, len) = unsafe {
+                syscall!(
+                    SystemCall::System as u64,
+                    SystemOperation::Stats as u64,
+                    buf.as_mut_ptr() as u64,
+                    buf.len() as u64,
+                    2
+                )
+            };
+
+            if r != 0 {
+                return Err(SystemCallError::from(r));
+            }
+
+            let len = len as usize;
+            if len > buf.len() {
+                // Didn't fit, try again with a buffer that does
+                buf.resize(len, 0);
+                continue;
+            }
+            buf.truncate(len);
+            let deserialized: Vec<CoreStats> = serde_cbor::from_slice(&buf).unwrap();
+            return Ok(deserialized);
+        }
+    }
+
     /// Get the core id for the current running thread.
     pub fn core_id() -> Result<CoreId, SystemCallError> {
         let (r, id) = unsafe {
@@ -64,4 +142,298 @@ impl System {
             Err(SystemCallError::from(r))
         }
     }
+
+    /// Moves queued keyboard events into `events`.
+    ///
+    /// Returns how many events were read (this doesn't block, 0 means there
+    /// were no events).
+    pub fn read_key_events(events: &mut [KeyEvent]) -> Result<usize, SystemCallError> {
+        let (r, read) = unsafe {
+            syscall!(
+                SystemCall::System as u64,
+                SystemOperation::ReadKeyEvents as u64,
+                events.as_mut_ptr() as u64,
+                events.len() as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(read as usize)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Fills `buf` with random bytes from the kernel entropy pool.
+    ///
+    /// Returns how many bytes were written (less than `buf.len()` for large
+    /// buffers).
+    pub fn getrandom(buf: &mut [u8]) -> Result<usize, SystemCallError> {
+        let (r, written) = unsafe {
+            syscall!(
+                SystemCall::System as u64,
+                SystemOperation::GetRandom as u64,
+                buf.as_mut_ptr() as u64,
+                buf.len() as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(written as usize)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Takes core `gtid` offline (processes running on it get a
+    /// `upcall::CORE_REVOKED` and have to give it back first).
+    pub fn offline_core(gtid: usize) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::System as u64,
+                SystemOperation::OfflineCore as u64,
+                gtid as u64,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Brings offline core `gtid` back.
+    pub fn online_core(gtid: usize) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::System as u64,
+                SystemOperation::OnlineCore as u64,
+                gtid as u64,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Query the frequency of core `gtid`.
+    ///
+    /// Fails with `NotSupported` if the kernel can't change the frequency of
+    /// the cores (e.g., in a virtual machine).
+    pub fn frequency(gtid: usize) -> Result<CoreFrequency, SystemCallError> {
+        let mut buf = [0u8; 128];
+        let (r, len) = unsafe {
+            syscall!(
+                SystemCall::System as u64,
+                SystemOperation::GetFrequency as u64,
+                gtid as u64,
+                buf.as_mut_ptr() as u64,
+                buf.len() as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            let len = len as usize;
+            if len > buf.len() {
+                return Err(SystemCallError::OutOfMemory);
+            }
+            Ok(serde_cbor::from_slice(&buf[..len]).unwrap())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Pins core `gtid` at `mhz` (rounded down to a frequency the core
+    /// has), `None` hands the frequency back to the kernel governor.
+    ///
+    /// Only root can do this. The core switches the next time it takes an
+    /// interrupt (we send it one).
+    pub fn set_frequency(gtid: usize, mhz: Option<u32>) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::System as u64,
+                SystemOperation::SetFrequency as u64,
+                gtid as u64,
+                mhz.unwrap_or(0) as u64,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Query how the kernel picks a core for `Process::request_any_core`.
+    pub fn core_policy() -> Result<CorePolicy, SystemCallError> {
+        let (r, policy) = unsafe {
+            syscall!(
+                SystemCall::System as u64,
+                SystemOperation::GetCorePolicy as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            CorePolicy::from_u64(policy).ok_or(SystemCallError::InternalError)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Changes how the kernel picks a core for `Process::request_any_core`
+    /// and returns the policy that was in place before.
+    ///
+    /// Only root can do this. Cores that are already handed out stay where
+    /// they are.
+    pub fn set_core_policy(policy: CorePolicy) -> Result<CorePolicy, SystemCallError> {
+        let (r, previous) = unsafe {
+            syscall!(
+                SystemCall::System as u64,
+                SystemOperation::SetCorePolicy as u64,
+                policy as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            CorePolicy::from_u64(previous).ok_or(SystemCallError::InternalError)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Puts process `pid` (the calling one if `None`) in gang mode, or takes
+    /// it out again: the kernel interrupts all its cores together instead
+    /// of one at a time, so no core spins on a lock another one holds while
+    /// it's in the kernel.
+    ///
+    /// Only root can do this. `/proc/gangs` shows the processes in gang mode.
+    pub fn set_gang(pid: Option<usize>, gang: bool) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::System as u64,
+                SystemOperation::SetGang as u64,
+                pid.map_or(u64::MAX, |pid| pid as u64),
+                gang as u64,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Suspends the machine to RAM, it wakes up again after `sleep` (at
+    /// least a second, less than a day).
+    ///
+    /// Only root can do this, and only from the boot core (core 0). Returns
+    /// once the machine is back, devices may have lost state they don't
+    /// keep in their PCI configuration.
+    pub fn suspend(sleep: Duration) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::System as u64,
+                SystemOperation::Suspend as u64,
+                sleep.as_nanos() as u64,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Replaces the running kernel with `image` (an nrk kernel binary) and
+    /// starts it with `cmdline`, without going through the firmware.
+    ///
+    /// Only root can do this. Doesn't return unless the kernel couldn't
+    /// load the image.
+    pub fn kexec(image: &[u8], cmdline: &str) -> Result<(), SystemCallError> {
+        let mut cmdline_nul: Vec<u8> = Vec::with_capacity(cmdline.len() + 1);
+        cmdline_nul.extend_from_slice(cmdline.as_bytes());
+        cmdline_nul.push(0);
+
+        let r = unsafe {
+            syscall!(
+                SystemCall::System as u64,
+                SystemOperation::Kexec as u64,
+                image.as_ptr() as u64,
+                image.len() as u64,
+                cmdline_nul.as_ptr() as u64,
+                1
+            )
+        };
+
+        Err(SystemCallError::from(r))
+    }
+
+    /// Turns the machine off. Under QEMU (with the debug exit device) QEMU
+    /// exits with `exit_code`, so tests can tell whether they passed.
+    ///
+    /// Only root can do this and `exit_code` has to be below 128. Doesn't
+    /// return unless the arguments were wrong.
+    pub fn shutdown(exit_code: u8) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::System as u64,
+                SystemOperation::Shutdown as u64,
+                exit_code as u64,
+                1
+            )
+        };
+
+        Err(SystemCallError::from(r))
+    }
+
+    /// Resets the machine (QEMU exits with 0 if it runs with `-no-reboot`).
+    ///
+    /// Only root can do this. Doesn't return unless we're not allowed to.
+    pub fn reboot() -> Result<(), SystemCallError> {
+        let r = unsafe { syscall!(SystemCall::System as u64, SystemOperation::Reboot as u64, 1) };
+
+        Err(SystemCallError::from(r))
+    }
+
+    /// Reads kernel output (log records and process output) at `offset`
+    /// into `buf`, offsets count the bytes since boot.
+    ///
+    /// Returns how many bytes were read and the offset to continue at. The
+    /// kernel only keeps the most recent output, the read starts at the
+    /// oldest byte it still has if `offset` is older.
+    pub fn dmesg(buf: &mut [u8], offset: u64) -> Result<(usize, u64), SystemCallError> {
+        let (r, read, next) = unsafe {
+            syscall!(
+                SystemCall::System as u64,
+                SystemOperation::Dmesg as u64,
+                buf.as_mut_ptr() as u64,
+                buf.len() as u64,
+                offset,
+                3
+            )
+        };
+
+        if r == 0 {
+            Ok((read as usize, next))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
 }
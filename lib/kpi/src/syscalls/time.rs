@@ -0,0 +1,143 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! System calls to read clocks.
+//!
+//! `monotonic_ns`, `realtime_ns` and `resolution` read the time page (see
+//! `time`) if the kernel maps one and only make a system call otherwise.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::time::{Clock, TimePage, TimerFlags};
+use crate::{syscall, *};
+
+/// Address of the time page, `NO_TIME_PAGE` if the kernel doesn't map one
+/// and 0 if we didn't ask yet.
+static TIME_PAGE: AtomicU64 = AtomicU64::new(0);
+
+const NO_TIME_PAGE: u64 = 1;
+
+pub struct Time;
+
+impl Time {
+    /// Returns the current wall-clock time (since the UNIX epoch, in UTC).
+    pub fn wallclock() -> Result<Duration, SystemCallError> {
+        let (r, secs, nanos) =
+            unsafe { syscall!(SystemCall::Time as u64, TimeOperation::Wallclock as u64, 3) };
+
+        if r == 0 {
+            Ok(Duration::new(secs, nanos as u32))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Nanoseconds since boot, never goes backwards.
+    pub fn monotonic_ns() -> Result<u64, SystemCallError> {
+        if let Some(clock) = Time::clock() {
+            return Ok(clock.monotonic_ns(unsafe { x86::time::rdtsc() }));
+        }
+
+        let (r, ns) =
+            unsafe { syscall!(SystemCall::Time as u64, TimeOperation::Monotonic as u64, 2) };
+        if r == 0 {
+            Ok(ns)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Nanoseconds since the UNIX epoch (UTC).
+    ///
+    /// Fails with `NotSupported` if the kernel doesn't know the time.
+    pub fn realtime_ns() -> Result<u64, SystemCallError> {
+        if let Some(clock) = Time::clock() {
+            return clock
+                .realtime_ns(unsafe { x86::time::rdtsc() })
+                .ok_or(SystemCallError::NotSupported);
+        }
+
+        let now = Time::wallclock()?;
+        Ok(now.as_nanos() as u64)
+    }
+
+    /// The smallest step of `monotonic_ns` and `realtime_ns`.
+    pub fn resolution() -> Result<Duration, SystemCallError> {
+        if let Some(clock) = Time::clock() {
+            return Ok(Duration::from_nanos(clock.resolution_ns()));
+        }
+
+        let (r, ns) =
+            unsafe { syscall!(SystemCall::Time as u64, TimeOperation::Resolution as u64, 2) };
+        if r == 0 {
+            Ok(Duration::from_nanos(ns))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Starts a timer that goes off after `interval` (and every `interval`
+    /// with `TimerFlags::PERIODIC`), returns its id.
+    ///
+    /// It goes off as an `upcall::TIMER` upcall on `core` whenever the
+    /// process runs there. Intervals below `time::MIN_TIMER_INTERVAL_NS` are
+    /// rounded up.
+    pub fn timer_create(
+        interval: Duration,
+        flags: TimerFlags,
+        core: usize,
+    ) -> Result<u64, SystemCallError> {
+        let (r, timer) = unsafe {
+            syscall!(
+                SystemCall::Time as u64,
+                TimeOperation::TimerCreate as u64,
+                interval.as_nanos() as u64,
+                flags.bits(),
+                core as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(timer)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Stops `timer` (expirations that weren't delivered yet are dropped).
+    pub fn timer_cancel(timer: u64) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::Time as u64,
+                TimeOperation::TimerCancel as u64,
+                timer,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// The clock on the time page (if the kernel maps one).
+    fn clock() -> Option<Clock> {
+        let mut page = TIME_PAGE.load(Ordering::Relaxed);
+        if page == 0 {
+            let (r, base) =
+                unsafe { syscall!(SystemCall::Time as u64, TimeOperation::TimePage as u64, 2) };
+            page = if r == 0 { base } else { NO_TIME_PAGE };
+            TIME_PAGE.store(page, Ordering::Relaxed);
+        }
+
+        if page == NO_TIME_PAGE {
+            None
+        } else {
+            unsafe { &*(page as *const TimePage) }.read()
+        }
+    }
+}
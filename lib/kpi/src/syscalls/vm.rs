@@ -0,0 +1,106 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! System calls to create and run virtual machines (see `vm`).
+
+use crate::vm::{Exit, VcpuState};
+use crate::{syscall, *};
+
+pub struct Vm;
+
+impl Vm {
+    /// Creates a VM with `vcpus` vCPUs and nothing in its guest-physical
+    /// address space, returns a capability for it.
+    ///
+    /// Fails with `NotSupported` if the cores don't have VT-x with EPT
+    /// (see `AbiFeatures::VIRTUALIZATION`).
+    pub fn create(vcpus: usize) -> Result<u64, SystemCallError> {
+        let (r, handle) = unsafe {
+            syscall!(
+                SystemCall::Vm as u64,
+                VmOperation::Create as u64,
+                vcpus as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(handle)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Maps `frame` (a frame capability with `CapRights::MAP`) at
+    /// guest-physical address `gpa` of VM `vm` with `rights`.
+    ///
+    /// `gpa` has to be aligned to the size of the frame. The frame stays
+    /// with the process, the guest and the process share it until the VM is
+    /// destroyed.
+    pub fn map_guest(
+        vm: u64,
+        frame: u64,
+        gpa: u64,
+        rights: MemoryRights,
+    ) -> Result<(), SystemCallError> {
+        let (r, _) = unsafe {
+            syscall!(
+                SystemCall::Vm as u64,
+                VmOperation::MapGuest as u64,
+                vm,
+                frame,
+                gpa,
+                rights.bits(),
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Runs vCPU `vcpu` of VM `vm` with `state` on this core until it exits
+    /// to us, then updates `state` and tells why in `exit`.
+    pub fn run(
+        vm: u64,
+        vcpu: usize,
+        state: &mut VcpuState,
+        exit: &mut Exit,
+    ) -> Result<(), SystemCallError> {
+        let (r, _) = unsafe {
+            syscall!(
+                SystemCall::Vm as u64,
+                VmOperation::Run as u64,
+                vm,
+                vcpu as u64,
+                state as *mut VcpuState as u64,
+                exit as *mut Exit as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Destroys VM `vm` (a capability with `CapRights::WRITE`), its frames
+    /// are the process's alone again.
+    ///
+    /// Has to run on the core that ran its vCPUs (`PermissionError`
+    /// elsewhere).
+    pub fn destroy(vm: u64) -> Result<(), SystemCallError> {
+        let (r, _) = unsafe { syscall!(SystemCall::Vm as u64, VmOperation::Destroy as u64, vm, 2) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+}
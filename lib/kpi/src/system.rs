@@ -3,6 +3,8 @@
 
 //! Data structures to exchange system-wide information between kernel and user-space.
 
+use alloc::vec::Vec;
+
 use serde::{Deserialize, Serialize};
 
 /// A system global ID for a CPU hardware thread.
@@ -33,3 +35,229 @@ pub struct CpuThread {
     /// ID of the thread (relative to the core (usually either 0 or 1)).
     pub thread_id: ThreadId,
 }
+
+/// A NUMA node (as described by the ACPI SRAT and SLIT tables).
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub struct NumaNode {
+    /// ID of the node (matches `CpuThread::node_id`).
+    pub id: NodeId,
+    /// Bytes of memory local to the node (0 if unknown).
+    pub memory: usize,
+    /// Relative distance to every node, indexed by node id (10 is local).
+    pub distances: Vec<u8>,
+}
+
+/// A key press or release (read with `SystemOperation::ReadKeyEvents`).
+#[repr(C)]
+#[derive(Default, Eq, PartialEq, Debug, Copy, Clone)]
+pub struct KeyEvent {
+    /// The key (PS/2 scancode set 2 make code, extended keys have 0xe000
+    /// set), 0 for characters from a serial console.
+    pub code: u16,
+    /// True if the key was pressed, false if it was released.
+    pub pressed: bool,
+    /// The character the key produces with the current modifiers (US
+    /// layout), 0 for keys without one (and for releases).
+    pub ascii: u8,
+}
+
+/// The frequency of a core (read with `System::frequency`), in MHz.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone, Copy, Default)]
+pub struct CoreFrequency {
+    /// The core (hardware thread).
+    pub id: GlobalThreadId,
+    /// What the core ran at when it last checked its frequency.
+    pub current: u32,
+    /// What the kernel asked the core to run at.
+    pub target: u32,
+    /// The lowest frequency the core can run at.
+    pub min: u32,
+    /// The highest frequency without turbo.
+    pub base: u32,
+    /// The highest frequency (with turbo, if the core has it).
+    pub max: u32,
+    /// A process pinned the frequency, the kernel governor leaves it alone.
+    pub pinned: bool,
+}
+
+/// Idle states of a core, from the shallowest to the deepest (indexes
+/// `CoreStats::idle_entries` and `CoreStats::idle_ns`).
+#[derive(Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq, Debug, Copy, Clone)]
+pub enum IdleState {
+    /// Spins, wakes up right away.
+    Poll = 0,
+    /// Halted (`hlt`, or `mwait` for C1), the core clock stops.
+    C1 = 1,
+    /// Halted at a lower frequency and voltage.
+    C1E = 2,
+    /// Powered off, the core loses its caches.
+    C6 = 3,
+}
+
+impl IdleState {
+    pub const ALL: [IdleState; 4] = [
+        IdleState::Poll,
+        IdleState::C1,
+        IdleState::C1E,
+        IdleState::C6,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            IdleState::Poll => "poll",
+            IdleState::C1 => "C1",
+            IdleState::C1E => "C1E",
+            IdleState::C6 => "C6",
+        }
+    }
+}
+
+/// How the kernel picks a core for a process that doesn't ask for a
+/// particular one (`Process::request_any_core`), see
+/// `System::set_core_policy`.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Copy, Clone)]
+#[repr(u64)]
+pub enum CorePolicy {
+    /// Fill up one NUMA node (and the threads of one core) after the other.
+    Packed = 1,
+    /// The NUMA node with the fewest cores taken, and a core where the
+    /// fewest threads are taken.
+    Spread = 2,
+    /// A core where no other thread is taken, as long as there is one.
+    Smt = 3,
+}
+
+impl CorePolicy {
+    pub const ALL: [CorePolicy; 3] = [CorePolicy::Packed, CorePolicy::Spread, CorePolicy::Smt];
+
+    /// The policy with number `policy` (`policy as u64`).
+    pub fn from_u64(policy: u64) -> Option<CorePolicy> {
+        CorePolicy::ALL
+            .iter()
+            .copied()
+            .find(|p| *p as u64 == policy)
+    }
+
+    /// The policy called `name` (see `name`).
+    pub fn parse(name: &str) -> Option<CorePolicy> {
+        CorePolicy::ALL.iter().copied().find(|p| p.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CorePolicy::Packed => "packed",
+            CorePolicy::Spread => "spread",
+            CorePolicy::Smt => "smt",
+        }
+    }
+}
+
+/// Event counters of a core since boot (read with `System::stats`).
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone, Default)]
+pub struct CoreStats {
+    /// The core (hardware thread) the counters belong to.
+    pub id: GlobalThreadId,
+    /// System calls, indexed by `SystemCall` number.
+    pub syscalls: Vec<u64>,
+    /// Interrupts and exceptions, indexed by vector.
+    pub irqs: Vec<u64>,
+    /// How often the core started running a different executor.
+    pub context_switches: u64,
+    /// TLB shootdowns the core asked other cores to do.
+    pub tlb_shootdowns_sent: u64,
+    /// TLB shootdowns other cores asked the core to do.
+    pub tlb_shootdowns_received: u64,
+    /// Cycles spent in the TLB shootdown IPI handler (when it interrupted
+    /// a process).
+    pub tlb_shootdown_cycles: u64,
+    /// How often the core went idle, indexed by `IdleState`.
+    pub idle_entries: Vec<u64>,
+    /// Nanoseconds the core spent idle, indexed by `IdleState`.
+    pub idle_ns: Vec<u64>,
+}
+
+impl CoreStats {
+    /// Adds the counters of `other` to ours.
+    pub fn add(&mut self, other: &CoreStats) {
+        fn add_all(to: &mut Vec<u64>, from: &[u64]) {
+            if to.len() < from.len() {
+                to.resize(from.len(), 0);
+            }
+            for (a, b) in to.iter_mut().zip(from) {
+                *a += b;
+            }
+        }
+
+        add_all(&mut self.syscalls, &other.syscalls);
+        add_all(&mut self.irqs, &other.irqs);
+        self.context_switches += other.context_switches;
+        self.tlb_shootdowns_sent += other.tlb_shootdowns_sent;
+        self.tlb_shootdowns_received += other.tlb_shootdowns_received;
+        self.tlb_shootdown_cycles += other.tlb_shootdown_cycles;
+        add_all(&mut self.idle_entries, &other.idle_entries);
+        add_all(&mut self.idle_ns, &other.idle_ns);
+    }
+}
+
+impl<'a> core::iter::Sum<&'a CoreStats> for CoreStats {
+    /// The counters of all cores together (the `id` of the sum is 0).
+    fn sum<I: Iterator<Item = &'a CoreStats>>(iter: I) -> CoreStats {
+        let mut total = CoreStats::default();
+        for stats in iter {
+            total.add(stats);
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn core_policies() {
+        for policy in CorePolicy::ALL.iter().copied() {
+            assert_eq!(CorePolicy::from_u64(policy as u64), Some(policy));
+            assert_eq!(CorePolicy::parse(policy.name()), Some(policy));
+        }
+        assert_eq!(CorePolicy::from_u64(0), None);
+        assert_eq!(CorePolicy::parse("fair"), None);
+    }
+
+    #[test]
+    fn sum_stats() {
+        let a = CoreStats {
+            id: 1,
+            syscalls: alloc::vec![1, 2],
+            irqs: alloc::vec![0, 0, 3],
+            context_switches: 1,
+            tlb_shootdowns_sent: 2,
+            tlb_shootdowns_received: 0,
+            tlb_shootdown_cycles: 0,
+            idle_entries: alloc::vec![4, 1, 0, 0],
+            idle_ns: alloc::vec![100, 2000, 0, 0],
+        };
+        let b = CoreStats {
+            id: 2,
+            syscalls: alloc::vec![1, 0, 4],
+            irqs: alloc::vec![],
+            context_switches: 0,
+            tlb_shootdowns_sent: 0,
+            tlb_shootdowns_received: 2,
+            tlb_shootdown_cycles: 100,
+            idle_entries: alloc::vec![],
+            idle_ns: alloc::vec![],
+        };
+
+        let total: CoreStats = [a, b].iter().sum();
+        assert_eq!(total.id, 0);
+        assert_eq!(total.syscalls, alloc::vec![2, 2, 4]);
+        assert_eq!(total.irqs, alloc::vec![0, 0, 3]);
+        assert_eq!(total.context_switches, 1);
+        assert_eq!(total.tlb_shootdowns_sent, 2);
+        assert_eq!(total.tlb_shootdowns_received, 2);
+        assert_eq!(total.tlb_shootdown_cycles, 100);
+        assert_eq!(total.idle_entries, alloc::vec![4, 1, 0, 0]);
+        assert_eq!(total.idle_ns[IdleState::C1 as usize], 2000);
+    }
+}
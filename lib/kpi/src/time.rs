@@ -0,0 +1,180 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Clocks a process can read without a system call (see `syscalls::Time`).
+//!
+//! The kernel maps a read-only page with a `TimePage` into every process
+//! (much like the vDSO on Linux). It holds what the kernel knows about the
+//! TSC: its frequency (from the hypervisor, CPUID or calibration, not a
+//! guess), its value at boot and the wall-clock time at boot. Monotonic time
+//! is the TSC since boot converted with that frequency, wall-clock time adds
+//! the time at boot, so the kernel and all processes agree on both.
+//!
+//! Interval timers (`syscalls::Time::timer_create`) go off on the same
+//! monotonic clock, as an `upcall::TIMER` upcall on the core they're for.
+
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+use bitflags::*;
+
+/// How many interval timers there can be (in the whole system), timer ids
+/// are below this.
+pub const MAX_TIMERS: usize = 256;
+
+/// The shortest interval of an interval timer (in ns), shorter ones are
+/// rounded up.
+pub const MIN_TIMER_INTERVAL_NS: u64 = 10_000;
+
+bitflags! {
+    /// How an interval timer goes off.
+    pub struct TimerFlags: u64 {
+        /// Once after the interval (the default).
+        const ONESHOT = 0;
+        /// Every interval until it is cancelled.
+        const PERIODIC = 1 << 0;
+    }
+}
+
+/// What a process needs to turn TSC readings into time.
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct Clock {
+    /// Ticks of the TSC per second.
+    pub tsc_frequency: u64,
+    /// The TSC at boot (monotonic time 0).
+    pub tsc_base: u64,
+    /// Wall-clock time at boot in ns since the UNIX epoch (0 if the kernel
+    /// doesn't know the time).
+    pub realtime_base: u64,
+}
+
+impl Clock {
+    /// Nanoseconds since boot at TSC value `tsc`.
+    pub fn monotonic_ns(&self, tsc: u64) -> u64 {
+        let ticks = tsc.saturating_sub(self.tsc_base) as u128;
+        (ticks * 1_000_000_000 / self.tsc_frequency as u128) as u64
+    }
+
+    /// Nanoseconds since the UNIX epoch at TSC value `tsc`.
+    pub fn realtime_ns(&self, tsc: u64) -> Option<u64> {
+        if self.realtime_base == 0 {
+            return None;
+        }
+        Some(self.realtime_base + self.monotonic_ns(tsc))
+    }
+
+    /// The time between two ticks (at least 1 ns).
+    pub fn resolution_ns(&self) -> u64 {
+        let ns = (1_000_000_000 + self.tsc_frequency - 1) / self.tsc_frequency;
+        ns.max(1)
+    }
+}
+
+/// The page the kernel maps into processes, a `Clock` behind a sequence
+/// lock.
+#[derive(Debug)]
+#[repr(C)]
+pub struct TimePage {
+    /// Odd while the kernel updates the page.
+    seq: AtomicU64,
+    tsc_frequency: AtomicU64,
+    tsc_base: AtomicU64,
+    realtime_base: AtomicU64,
+}
+
+impl TimePage {
+    pub const fn new() -> TimePage {
+        TimePage {
+            seq: AtomicU64::new(0),
+            tsc_frequency: AtomicU64::new(0),
+            tsc_base: AtomicU64::new(0),
+            realtime_base: AtomicU64::new(0),
+        }
+    }
+
+    /// The clock on the page (`None` if the kernel didn't fill it in).
+    pub fn read(&self) -> Option<Clock> {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq % 2 == 1 {
+                core::hint::spin_loop();
+                continue;
+            }
+            let clock = Clock {
+                tsc_frequency: self.tsc_frequency.load(Ordering::Relaxed),
+                tsc_base: self.tsc_base.load(Ordering::Relaxed),
+                realtime_base: self.realtime_base.load(Ordering::Relaxed),
+            };
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return if clock.tsc_frequency == 0 {
+                    None
+                } else {
+                    Some(clock)
+                };
+            }
+        }
+    }
+
+    /// Replaces the clock on the page (only the kernel can).
+    pub fn write(&self, clock: &Clock) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.tsc_frequency
+            .store(clock.tsc_frequency, Ordering::Relaxed);
+        self.tsc_base.store(clock.tsc_base, Ordering::Relaxed);
+        self.realtime_base
+            .store(clock.realtime_base, Ordering::Relaxed);
+        self.seq.fetch_add(1, Ordering::Release);
+    }
+}
+
+impl Default for TimePage {
+    fn default() -> TimePage {
+        TimePage::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn clock() {
+        let clock = Clock {
+            tsc_frequency: 2_000_000_000,
+            tsc_base: 1000,
+            realtime_base: 0,
+        };
+        assert_eq!(clock.monotonic_ns(1000), 0);
+        assert_eq!(clock.monotonic_ns(3_000_001_000), 1_500_000_000);
+        // A core whose TSC is a bit behind doesn't go back before boot
+        assert_eq!(clock.monotonic_ns(10), 0);
+        assert_eq!(clock.realtime_ns(3_000_001_000), None);
+        assert_eq!(clock.resolution_ns(), 1);
+
+        let clock = Clock {
+            tsc_frequency: 14_318_180,
+            tsc_base: 0,
+            realtime_base: 1_609_459_200_000_000_000,
+        };
+        assert_eq!(
+            clock.realtime_ns(14_318_180),
+            Some(1_609_459_201_000_000_000)
+        );
+        assert_eq!(clock.resolution_ns(), 70);
+    }
+
+    #[test]
+    fn page() {
+        let page = TimePage::new();
+        assert_eq!(page.read(), None);
+
+        let clock = Clock {
+            tsc_frequency: 3_000_000_000,
+            tsc_base: 42,
+            realtime_base: 7,
+        };
+        page.write(&clock);
+        assert_eq!(page.read(), Some(clock));
+    }
+}
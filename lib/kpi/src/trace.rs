@@ -0,0 +1,110 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The system call traces the kernel records (see `Debug::trace_syscalls`).
+//!
+//! A trace is text, one `TraceEntry` per line, so it survives the serial
+//! console and can be handed back to a fresh kernel to replay it.
+
+use core::fmt;
+use core::str::FromStr;
+
+/// One system call of a traced process.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct TraceEntry {
+    /// The system call (see `SystemCall`).
+    pub function: u64,
+    /// The arguments, as passed (pointers point into the traced process).
+    pub args: [u64; 5],
+    /// The `SystemCallError` it returned (`Ok` if it worked).
+    pub error: u64,
+    /// The values it returned (if it worked).
+    pub ret: [u64; 2],
+    /// TSC when the kernel started handling it.
+    pub start: u64,
+    /// TSC when the kernel was done.
+    pub end: u64,
+}
+
+impl fmt::Display for TraceEntry {
+    /// `function a1 a2 a3 a4 a5 error r1 r2 start end` (in hex).
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.function)?;
+        for arg in self.args.iter() {
+            write!(f, " {:#x}", arg)?;
+        }
+        write!(
+            f,
+            " {:#x} {:#x} {:#x} {:#x} {:#x}",
+            self.error, self.ret[0], self.ret[1], self.start, self.end
+        )
+    }
+}
+
+/// A line that isn't a `TraceEntry`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct InvalidTraceEntry;
+
+impl FromStr for TraceEntry {
+    type Err = InvalidTraceEntry;
+
+    fn from_str(line: &str) -> Result<TraceEntry, InvalidTraceEntry> {
+        let mut values = [0u64; 11];
+        let mut fields = line.split_whitespace();
+        for value in values.iter_mut() {
+            let field = fields.next().ok_or(InvalidTraceEntry)?;
+            let hex = field.strip_prefix("0x").ok_or(InvalidTraceEntry)?;
+            *value = u64::from_str_radix(hex, 16).map_err(|_| InvalidTraceEntry)?;
+        }
+        if fields.next().is_some() {
+            return Err(InvalidTraceEntry);
+        }
+
+        Ok(TraceEntry {
+            function: values[0],
+            args: [values[1], values[2], values[3], values[4], values[5]],
+            error: values[6],
+            ret: [values[7], values[8]],
+            start: values[9],
+            end: values[10],
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn roundtrip() {
+        let entry = TraceEntry {
+            function: 3,
+            args: [1, 0x5000_0000, 0x1000, 0, 0],
+            error: 0,
+            ret: [0x1234_5000, 0x1000],
+            start: 100,
+            end: u64::MAX,
+        };
+        let line = entry.to_string();
+        assert_eq!(
+            line,
+            "0x3 0x1 0x50000000 0x1000 0x0 0x0 0x0 0x12345000 0x1000 0x64 0xffffffffffffffff"
+        );
+        assert_eq!(line.parse::<TraceEntry>(), Ok(entry));
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!("".parse::<TraceEntry>(), Err(InvalidTraceEntry));
+        assert_eq!("0x1 0x2".parse::<TraceEntry>(), Err(InvalidTraceEntry));
+        assert_eq!(
+            "3 1 0 0 0 0 0 0 0 0 0".parse::<TraceEntry>(),
+            Err(InvalidTraceEntry)
+        );
+        assert_eq!(
+            "0x3 0x1 0x0 0x0 0x0 0x0 0x0 0x0 0x0 0x0 0x0 0x0".parse::<TraceEntry>(),
+            Err(InvalidTraceEntry)
+        );
+    }
+}
@@ -3,4 +3,37 @@
 
 //! Upcall command passed as the 2nd argument to the upcall.
 
+use bitflags::*;
+
 pub const NEW_CORE: u64 = 0x99;
+
+/// The kernel wants the core back (3rd argument is its global thread id),
+/// give it up with `Process::release_core`.
+pub const CORE_REVOKED: u64 = 0x9a;
+
+/// A page fault in a region registered with
+/// `syscalls::VSpace::register_fault_region` (3rd argument is the faulting
+/// address), the details are in `arch::VirtualCpu::fault`.
+pub const PAGE_FAULT: u64 = 0x9b;
+
+/// A system call the `filter::SyscallFilter` of the process doesn't allow
+/// (3rd argument is `(system call << 32) | operation`), the call itself
+/// returns `SystemCallError::PermissionError` once the upcall resumes it.
+pub const SYSCALL_DENIED: u64 = 0x9c;
+
+/// An interval timer (see `syscalls::Time::timer_create`) expired (3rd
+/// argument is `(expirations << 32) | timer`), expirations that happen
+/// while upcalls are disabled are counted and delivered together.
+pub const TIMER: u64 = 0x9d;
+
+bitflags! {
+    /// What the access that caused a page fault did.
+    pub struct FaultAccess: u64 {
+        /// The page is mapped, but not for this kind of access.
+        const PROTECTION = 1 << 0;
+        /// It was a write (a read otherwise).
+        const WRITE = 1 << 1;
+        /// It was an instruction fetch.
+        const INSTRUCTION = 1 << 4;
+    }
+}
@@ -0,0 +1,252 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Virtual machines on VT-x (see `syscalls::Vm`).
+//!
+//! A process (the VMM) creates a VM with a few vCPUs and gets a capability
+//! for it. It backs the guest-physical address space with frames it
+//! allocated (`Vm::map_guest`) and runs a vCPU on one of its cores
+//! (`Vm::run`). The core stays in the guest until the guest does something
+//! the kernel doesn't handle itself, `run` then returns the state of the
+//! vCPU and why it stopped (an `Exit`). A vCPU always runs on the core that
+//! ran it first.
+//!
+//! The kernel handles `cpuid`, writes to `cr0` and `cr4` and the MSRs of the
+//! CPU state (EFER, PAT, the FS and GS base and the `syscall` and
+//! `sysenter` MSRs), everything else goes to the VMM:
+//!
+//!  - For `Io` and `Msr` exits, `rip` already points past the instruction.
+//!    The VMM puts the value of an `in` into `rax` (the low `size` bytes)
+//!    and the value of a `rdmsr` into `rdx:rax`.
+//!  - A `Mmio` exit (an access to guest-physical memory without a frame)
+//!    leaves `rip` at the instruction, the VMM has to decode and emulate it.
+//!  - `Interrupted` means the core has to handle an interrupt, just run the
+//!    vCPU again.
+//!
+//! The VMM raises interrupts in the guest with `VcpuState::interrupt`, it
+//! emulates the devices (and the interrupt controller) itself.
+
+/// Most vCPUs a VM can have.
+pub const MAX_VCPUS: usize = 8;
+
+/// Index of a register in `VcpuState::regs` (the order of the VMX exit
+/// qualifications).
+pub const RAX: usize = 0;
+pub const RCX: usize = 1;
+pub const RDX: usize = 2;
+pub const RBX: usize = 3;
+/// Ignored, the stack pointer is in the VMCS (`VcpuState::rsp`).
+pub const RSP: usize = 4;
+pub const RBP: usize = 5;
+pub const RSI: usize = 6;
+pub const RDI: usize = 7;
+pub const R8: usize = 8;
+pub const R9: usize = 9;
+pub const R10: usize = 10;
+pub const R11: usize = 11;
+pub const R12: usize = 12;
+pub const R13: usize = 13;
+pub const R14: usize = 14;
+pub const R15: usize = 15;
+
+/// Set in `VcpuState::interrupt` while an interrupt waits for the guest.
+pub const INTERRUPT_PENDING: u64 = 1 << 63;
+
+/// `Segment::access` of a segment the guest doesn't use.
+pub const SEGMENT_UNUSABLE: u32 = 1 << 16;
+/// `Segment::access` of a 16-bit code segment (present, execute/read).
+pub const SEGMENT_CODE16: u32 = 0x9b;
+/// `Segment::access` of a 16-bit data segment (present, read/write).
+pub const SEGMENT_DATA16: u32 = 0x93;
+/// `Segment::access` of a flat 32-bit code segment (4 KiB granularity).
+pub const SEGMENT_CODE32: u32 = 0xc09b;
+/// `Segment::access` of a flat 32-bit data segment (4 KiB granularity).
+pub const SEGMENT_DATA32: u32 = 0xc093;
+/// `Segment::access` of a 64-bit code segment.
+pub const SEGMENT_CODE64: u32 = 0xa09b;
+/// `Segment::access` of a busy TSS.
+pub const SEGMENT_TSS: u32 = 0x8b;
+
+/// A segment register (with its hidden part).
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+#[repr(C)]
+pub struct Segment {
+    pub base: u64,
+    pub limit: u32,
+    /// The access rights in the format of the VMCS: the attributes of the
+    /// descriptor (type, S, DPL, P, AVL, L, D/B, G) and `SEGMENT_UNUSABLE`.
+    pub access: u32,
+    pub selector: u64,
+}
+
+impl Segment {
+    pub const fn new(selector: u16, base: u64, limit: u32, access: u32) -> Segment {
+        Segment {
+            base,
+            limit,
+            access,
+            selector: selector as u64,
+        }
+    }
+
+    /// A segment the guest doesn't use.
+    pub const fn unusable() -> Segment {
+        Segment::new(0, 0, 0, SEGMENT_UNUSABLE)
+    }
+}
+
+/// The GDT or IDT register.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+#[repr(C)]
+pub struct DescriptorTable {
+    pub base: u64,
+    pub limit: u64,
+}
+
+/// The registers of a vCPU: `Vm::run` starts the guest with them and
+/// returns what the guest left in them.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+#[repr(C)]
+pub struct VcpuState {
+    /// The general-purpose registers (see `RAX`..`R15`).
+    pub regs: [u64; 16],
+    pub rsp: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+    pub cs: Segment,
+    pub ds: Segment,
+    pub es: Segment,
+    pub fs: Segment,
+    pub gs: Segment,
+    pub ss: Segment,
+    pub tr: Segment,
+    pub ldtr: Segment,
+    pub gdt: DescriptorTable,
+    pub idt: DescriptorTable,
+    /// An external interrupt for the guest (`INTERRUPT_PENDING` and the
+    /// vector). The kernel delivers it once the guest has interrupts
+    /// enabled and clears it then.
+    pub interrupt: u64,
+}
+
+impl VcpuState {
+    /// A vCPU in real mode that starts at `cs:ip`.
+    pub fn real_mode(cs: u16, ip: u16) -> VcpuState {
+        let data = Segment::new(0, 0, 0xffff, SEGMENT_DATA16);
+        VcpuState {
+            rip: ip as u64,
+            rflags: 0x2,
+            // ET, caches on
+            cr0: 0x10,
+            cs: Segment::new(cs, (cs as u64) << 4, 0xffff, SEGMENT_CODE16),
+            ds: data,
+            es: data,
+            fs: data,
+            gs: data,
+            ss: data,
+            tr: Segment::new(0, 0, 0xffff, SEGMENT_TSS),
+            ldtr: Segment::unusable(),
+            gdt: DescriptorTable {
+                base: 0,
+                limit: 0xffff,
+            },
+            idt: DescriptorTable {
+                base: 0,
+                limit: 0xffff,
+            },
+            ..Default::default()
+        }
+    }
+
+    /// A vCPU in flat 32-bit protected mode without paging that starts at
+    /// `entry`, with code selector 0x10 and data selector 0x18 (like the
+    /// 32-bit Linux boot protocol wants it).
+    pub fn protected_mode(entry: u32) -> VcpuState {
+        let data = Segment::new(0x18, 0, 0xffff_ffff, SEGMENT_DATA32);
+        VcpuState {
+            rip: entry as u64,
+            rflags: 0x2,
+            // PE, ET
+            cr0: 0x11,
+            cs: Segment::new(0x10, 0, 0xffff_ffff, SEGMENT_CODE32),
+            ds: data,
+            es: data,
+            fs: data,
+            gs: data,
+            ss: data,
+            tr: Segment::new(0, 0, 0xffff, SEGMENT_TSS),
+            ldtr: Segment::unusable(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Why a vCPU stopped.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u64)]
+pub enum ExitKind {
+    /// The core got an interrupt.
+    Interrupted = 1,
+    /// The guest executed `hlt`.
+    Hlt = 2,
+    /// The guest executed `in` or `out`.
+    Io = 3,
+    /// The guest accessed guest-physical memory without a frame.
+    Mmio = 4,
+    /// The guest accessed an MSR the kernel doesn't handle.
+    Msr = 5,
+    /// The guest triple-faulted.
+    Shutdown = 6,
+    /// The CPU didn't accept the state of the vCPU (see `Exit::reason`).
+    Failed = 7,
+    /// An exit the kernel doesn't know what to do with (see `Exit::reason`).
+    Unhandled = 8,
+    Unknown,
+}
+
+impl From<u64> for ExitKind {
+    fn from(kind: u64) -> ExitKind {
+        match kind {
+            1 => ExitKind::Interrupted,
+            2 => ExitKind::Hlt,
+            3 => ExitKind::Io,
+            4 => ExitKind::Mmio,
+            5 => ExitKind::Msr,
+            6 => ExitKind::Shutdown,
+            7 => ExitKind::Failed,
+            8 => ExitKind::Unhandled,
+            _ => ExitKind::Unknown,
+        }
+    }
+}
+
+/// What made `Vm::run` return.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+#[repr(C)]
+pub struct Exit {
+    /// An `ExitKind`.
+    pub kind: u64,
+    /// The port (`Io`), guest-physical address (`Mmio`) or MSR (`Msr`).
+    pub address: u64,
+    /// Bytes the `in` or `out` transfers (`Io`).
+    pub size: u64,
+    /// Is it `out`, `wrmsr` or a write to memory (1) or not (0)?
+    pub write: u64,
+    /// What `out` or `wrmsr` writes.
+    pub data: u64,
+    /// The VMX exit reason (or the VM-instruction error for `Failed`).
+    pub reason: u64,
+    /// The VMX exit qualification.
+    pub qualification: u64,
+}
+
+impl Exit {
+    pub fn kind(&self) -> ExitKind {
+        ExitKind::from(self.kind)
+    }
+}
@@ -31,6 +31,11 @@ pub struct VirtualCpu {
     pub is_disabled: bool,
     /// An upcall needs to be executed.
     pub has_pending_upcall: bool,
+    /// The page fault of the last `upcall::PAGE_FAULT` upcall.
+    pub fault: PageFault,
+    /// With shadow stacks: where the kernel put the frame to `iretq` to
+    /// `enabled_state` on the shadow stack of the last upcall, 0 without.
+    pub upcall_ssp: u64,
 }
 
 impl VirtualCpu {
@@ -48,6 +53,22 @@ impl VirtualCpu {
     }
 }
 
+/// A page fault the kernel forwarded (see `upcall::PAGE_FAULT`).
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct PageFault {
+    /// The address that was accessed.
+    pub address: u64,
+    /// The page fault error code (the bits of `upcall::FaultAccess`).
+    pub access: u64,
+}
+
+impl PageFault {
+    pub fn access(&self) -> crate::upcall::FaultAccess {
+        crate::upcall::FaultAccess::from_bits_truncate(self.access)
+    }
+}
+
 /// Memory area that is used by a CPU/scheduler to capture and save
 /// the current CPU register state.
 ///
@@ -3,16 +3,14 @@
 
 use alloc::vec::Vec;
 use core::cell::UnsafeCell;
-use core::ops::Add;
 use core::ptr;
 use core::sync::atomic::Ordering;
 use core::time::Duration;
 
 use log::trace;
-use rawtime::Instant;
 
 use crate::mutex::Mutex;
-use crate::threads::{ThreadId, YieldRequest};
+use crate::threads::ThreadId;
 use crate::tls2::{Environment, ThreadControlBlock};
 
 fn remove_item<V>(vec: &mut Vec<V>, item: &V) -> Option<V>
@@ -102,7 +100,8 @@ impl CondVarInner {
     fn cv_unschedule(&mut self, mtx: &Mutex, rid: &mut i32) {
         trace!("cv_unschedule");
         let yielder: &mut ThreadControlBlock = Environment::thread();
-        (yielder.upcalls.deschedule)(rid, Some(mtx));
+        // The upcalls only know about `sync::Mutex`
+        (yielder.upcalls.deschedule)(rid, None);
         mtx.exit();
     }
 
@@ -120,11 +119,11 @@ impl CondVarInner {
         let yielder: &mut ThreadControlBlock = Environment::thread();
 
         if mtx.is_spin() && mtx.is_kmutex() {
-            (yielder.upcalls.schedule)(&rid, Some(mtx));
+            (yielder.upcalls.schedule)(&rid, None);
             mtx.enter_nowrap();
         } else {
             self.cv_mutex_enter(mtx);
-            (yielder.upcalls.schedule)(&rid, Some(mtx));
+            (yielder.upcalls.schedule)(&rid, None);
         }
     }
 
@@ -172,24 +171,18 @@ impl CondVarInner {
     /// Returns false on time-out, or true if woken up by other event
     pub fn timed_wait(&mut self, mtx: &Mutex, d: Duration) -> bool {
         let mut rid: i32 = 0;
-        let wakup_time = Instant::now().add(d);
         let tid = Environment::tid();
         self.dbg_mutex = mtx as *const Mutex;
 
         self.waiters.push(tid);
         self.cv_unschedule(mtx, &mut rid);
-        // TODO: if an event wakes us up the scheduler will still wait until
-        // the timeout is reached due to the Timeout YieldRequest
         let yielder: &mut ThreadControlBlock = Environment::thread();
-        yielder.suspend(YieldRequest::Timeout(wakup_time));
+        let signaled = yielder.block_timeout(d);
         self.cv_schedule_enter(mtx, &rid);
         remove_item(&mut self.waiters, &tid);
 
-        trace!(
-            "timed_wait: cv_schedule_enter done Instant::now() < wakup_time = {}",
-            Instant::now() < wakup_time
-        );
-        Instant::now() < wakup_time
+        trace!("timed_wait: cv_schedule_enter done signaled = {}", signaled);
+        signaled
     }
 
     /// TODO(smp): see comment
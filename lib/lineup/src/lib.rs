@@ -14,11 +14,15 @@ extern crate alloc;
 
 pub mod condvar;
 pub mod mutex;
+pub mod preempt;
+mod run_queue;
 pub mod rwlock;
 pub mod scheduler;
 pub mod semaphore;
 pub mod stack;
+pub mod sync;
 pub mod threads;
+mod timer_wheel;
 pub mod tls2;
 pub mod upcalls;
 
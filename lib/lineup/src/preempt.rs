@@ -0,0 +1,194 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Preemption (time-slicing) of threads.
+//!
+//! The scheduler itself is cooperative: a thread runs until it yields.
+//! Preemption needs someone that interrupts threads, e.g., vibrio with the
+//! upcall of an interval timer. It makes the interrupted thread call
+//! [`preempted`] as if the thread had called it where it was interrupted.
+//!
+//! That is only safe if [`preemptible`] says so when the thread got
+//! interrupted:
+//!
+//!  - A thread runs (not the scheduler, and not a thread that is in the
+//!    middle of a switch to the scheduler).
+//!  - It doesn't hold a lock that code which isn't preemptible may need as
+//!    well: the locks of the scheduler (see [`Mutex`]) and anything that
+//!    takes [`disable`] (e.g., the memory allocator).
+//!
+//! Both are tracked in the `ThreadControlBlock`, so they move with the
+//! thread from core to core.
+
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::Ordering;
+
+use crate::tls2::{self, ThreadControlBlock};
+
+/// The thread that runs on this core (if any).
+fn current<'a>() -> Option<&'a ThreadControlBlock<'static>> {
+    unsafe {
+        let tcb = tls2::arch::get_tcb() as *const ThreadControlBlock;
+        if tcb.is_null() {
+            None
+        } else {
+            Some(&*tcb)
+        }
+    }
+}
+
+/// Can the code that runs right now be preempted?
+///
+/// Meant to be called from an upcall handler about the code it
+/// interrupted.
+pub fn preemptible() -> bool {
+    current().map_or(false, |tcb| {
+        tcb.running.load(Ordering::Acquire) && tcb.preempt_disabled.load(Ordering::Acquire) == 0
+    })
+}
+
+/// Lets the scheduler run another thread, called by the thread that got
+/// preempted (on its own stack).
+pub fn preempted() {
+    if let Some(tcb) = current() {
+        tcb.relinquish();
+    }
+}
+
+/// Keeps the current thread from being preempted until the guard is
+/// dropped (it can still yield).
+///
+/// Does nothing outside of a thread.
+pub fn disable() -> Guard {
+    let tcb = current().map(|tcb| {
+        tcb.preempt_disabled.fetch_add(1, Ordering::AcqRel);
+        tcb as *const ThreadControlBlock
+    });
+    Guard { tcb }
+}
+
+/// Preemption stays disabled while this exists (see [`disable`]).
+pub struct Guard {
+    tcb: Option<*const ThreadControlBlock<'static>>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(tcb) = self.tcb {
+            unsafe { &*tcb }
+                .preempt_disabled
+                .fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+/// A spin-lock that disables preemption while it's held.
+///
+/// The scheduler locks are taken from threads (e.g., `SmpScheduler::spawn`)
+/// and by the scheduler, it would spin forever on a lock a preempted thread
+/// holds.
+#[derive(Debug, Default)]
+pub struct Mutex<T> {
+    inner: spin::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Mutex<T> {
+        Mutex {
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let preempt = disable();
+        MutexGuard {
+            inner: self.inner.lock(),
+            _preempt: preempt,
+        }
+    }
+}
+
+/// Unlocks, then enables preemption again (in field order).
+pub struct MutexGuard<'a, T> {
+    inner: spin::MutexGuard<'a, T>,
+    _preempt: Guard,
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::sync::Arc;
+    use core::ptr;
+    use core::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::scheduler::SmpScheduler;
+    use crate::stack::DEFAULT_STACK_SIZE_BYTES;
+    use crate::tls2::{Environment, SchedulerControlBlock};
+
+    #[test]
+    fn outside_of_threads() {
+        let _r = env_logger::try_init();
+        assert!(!preemptible());
+        let guard = disable();
+        assert!(guard.tcb.is_none());
+        preempted();
+
+        let m = Mutex::new(1);
+        *m.lock() += 1;
+        assert_eq!(*m.lock(), 2);
+    }
+
+    #[test]
+    fn in_threads() {
+        let _r = env_logger::try_init();
+        let s: SmpScheduler = Default::default();
+        let checked = Arc::new(AtomicUsize::new(0));
+        let checked1 = checked.clone();
+
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                assert!(preemptible());
+                {
+                    let _outer = disable();
+                    let m = Mutex::new(());
+                    let _inner = m.lock();
+                    assert!(!preemptible());
+                }
+                assert!(preemptible());
+
+                // Still preemptible once we run again
+                preempted();
+                assert!(preemptible());
+                Environment::thread().relinquish();
+                assert!(preemptible());
+                checked1.fetch_add(1, Ordering::Relaxed);
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        while s.has_active_threads() {
+            s.run(&scb);
+            // The scheduler isn't
+            assert!(!preemptible());
+        }
+        assert_eq!(checked.load(Ordering::Relaxed), 1);
+    }
+}
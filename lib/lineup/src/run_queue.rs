@@ -0,0 +1,117 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The runnable threads of a core, one FIFO per `Priority`.
+//!
+//! `pop_front` takes the first thread of the highest priority that has
+//! one, threads of the same priority take turns.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use arr_macro::arr;
+
+use crate::threads::{Priority, ThreadId};
+
+pub(crate) struct RunQueue {
+    /// Runnable threads, indexed by `Priority`.
+    levels: [VecDeque<ThreadId>; Priority::COUNT],
+}
+
+impl RunQueue {
+    /// A queue that has room for `capacity` threads of `Priority::Normal`
+    /// before it allocates.
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        let mut levels = arr![VecDeque::new(); 4]; // Priority::COUNT
+        levels[Priority::Normal as usize].reserve(capacity);
+        RunQueue { levels }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.levels.iter().map(|level| level.len()).sum()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.levels.iter().all(|level| level.is_empty())
+    }
+
+    /// Adds `tid` behind the threads with the same `priority`.
+    pub(crate) fn push_back(&mut self, tid: ThreadId, priority: Priority) {
+        self.levels[priority as usize].push_back(tid);
+    }
+
+    /// Takes the next thread to run.
+    pub(crate) fn pop_front(&mut self) -> Option<ThreadId> {
+        self.levels
+            .iter_mut()
+            .rev()
+            .find_map(|level| level.pop_front())
+    }
+
+    /// Removes all entries of `tid`, returns false if there were none.
+    pub(crate) fn remove(&mut self, tid: ThreadId) -> bool {
+        let len = self.len();
+        for level in self.levels.iter_mut() {
+            level.retain(|&ltid| ltid != tid);
+        }
+        len != self.len()
+    }
+
+    /// Moves `tid` (if it's in the queue) to the back of `priority`.
+    pub(crate) fn requeue(&mut self, tid: ThreadId, priority: Priority) {
+        if self.remove(tid) {
+            self.push_back(tid, priority);
+        }
+    }
+
+    /// Takes the thread that would run last among those `f` accepts.
+    pub(crate) fn take_last<F: FnMut(&ThreadId) -> bool>(&mut self, mut f: F) -> Option<ThreadId> {
+        for level in self.levels.iter_mut() {
+            if let Some(pos) = level.iter().rposition(|tid| f(tid)) {
+                return level.remove(pos);
+            }
+        }
+        None
+    }
+
+    /// Removes all threads (with their priority).
+    pub(crate) fn drain(&mut self) -> Vec<(ThreadId, Priority)> {
+        let mut threads = Vec::with_capacity(self.len());
+        for (idx, level) in self.levels.iter_mut().enumerate() {
+            let priority = Priority::from_index(idx);
+            threads.extend(level.drain(..).map(|tid| (tid, priority)));
+        }
+        threads
+    }
+
+    /// The threads in the order they would run.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &ThreadId> {
+        self.levels.iter().rev().flat_map(|level| level.iter())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn run_queue_orders_by_priority() {
+    let mut queue = RunQueue::with_capacity(8);
+    queue.push_back(ThreadId(1), Priority::Normal);
+    queue.push_back(ThreadId(2), Priority::Low);
+    queue.push_back(ThreadId(3), Priority::Interrupt);
+    queue.push_back(ThreadId(4), Priority::Normal);
+    assert_eq!(queue.len(), 4);
+    assert_eq!(
+        queue.iter().copied().collect::<Vec<ThreadId>>(),
+        [ThreadId(3), ThreadId(1), ThreadId(4), ThreadId(2)]
+    );
+
+    queue.requeue(ThreadId(2), Priority::High);
+    queue.requeue(ThreadId(5), Priority::High);
+    assert_eq!(queue.take_last(|_tid| true), Some(ThreadId(4)));
+    assert!(queue.remove(ThreadId(1)));
+    assert!(!queue.remove(ThreadId(1)));
+
+    assert_eq!(queue.pop_front(), Some(ThreadId(3)));
+    assert_eq!(queue.drain(), [(ThreadId(2), Priority::High)]);
+    assert!(queue.is_empty());
+    assert_eq!(queue.pop_front(), None);
+}
@@ -4,24 +4,43 @@
 //! The core logic of the scheduler.
 //!
 //! Has the following properties:
-//! * Cooperative scheduling (threads can yield voluntarily)
-//! * Round robin scheduling (per-core)
+//! * Cooperative scheduling (threads can yield voluntarily), preemption if
+//!   someone interrupts threads (see `preempt`)
+//! * Priority scheduling (per-core), round robin among threads of the
+//!   same priority
+//! * Priority inheritance: a thread that waits for a `sync::Mutex` lends
+//!   its priority to the thread that holds it
 //! * Per core run and wait lists
-//! * Thread affinity can be defined upon thread creation (currently no migration)
-//! * Waitlist is sorted according to thread wake-up times.
-
-use alloc::collections::VecDeque;
+//! * Thread affinity can be defined upon thread creation, threads only
+//!   move to another core when their core goes away (see `migrate`) or,
+//!   with work stealing, when another core runs out of threads
+//! * Sleeping threads (and threads that block with a time-out) wait in a
+//!   per-core timer wheel, `run` wakes them up once their time is up.
+//! * Threads can handle upcalls: they're registered for a vector on a core
+//!   (see `register_upcall_handler`), an upcall handler `raise`s the vector
+//!   and `run` makes the thread runnable.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::any::Any;
 use core::ptr;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
 
 use arr_macro::arr;
-use fringe::generator::Generator;
+use fringe::generator::{Generator, State};
 use log::{error, trace};
 use rawtime::Instant;
 
+use crate::preempt::Mutex;
+use crate::run_queue::RunQueue;
 use crate::stack::LineupStack;
-use crate::threads::{Runnable, Thread, ThreadId, YieldRequest, YieldResume};
+use crate::threads::{
+    self, AffinityMask, JoinHandle, PanicHook, Priority, Runnable, Thread, ThreadId, YieldRequest,
+    YieldResume,
+};
+use crate::timer_wheel::TimerWheel;
 use crate::tls2::{self, SchedulerControlBlock, ThreadControlBlock};
 use crate::upcalls::Upcalls;
 use crate::{CoreId, IrqVector};
@@ -29,37 +48,58 @@ use crate::{CoreId, IrqVector};
 /// Scheduler per-core state.
 ///
 /// # Lock order
-/// `waiting` before `runnable`.
+/// `runnable` before `threads` before `waiting`.
 /// In case we need to lock across multiple `SchedulerCoreState`
 /// lower `core_id` should be locked first.
 struct SchedulerCoreState {
     /// Per-core list of runnable threads.
     ///
     /// Protected by a mutex since anyone could put threads here.
-    runnable: spin::Mutex<VecDeque<ThreadId>>,
+    runnable: Mutex<RunQueue>,
 
-    /// Per-core list of `waiting` threads.
+    /// Per-core timer wheel of `waiting` threads.
     ///
     /// Protected by a mutex because anyone could put threads here.
-    waiting: spin::Mutex<Vec<(Instant, ThreadId)>>,
+    waiting: Mutex<TimerWheel>,
+
+    /// `IDLE` while the core sleeps in `SmpScheduler::idle`.
+    idle: AtomicU32,
+
+    /// Threads that handle upcalls (by vector) on this core.
+    handlers: Mutex<hashbrown::HashMap<IrqVector, ThreadId>>,
+
+    /// The vectors in `handlers` (one bit each), `SmpScheduler::raise`
+    /// can't take locks.
+    registered: [AtomicU64; 4],
 }
 
 impl SchedulerCoreState {
     fn new() -> Self {
         SchedulerCoreState {
-            runnable: spin::Mutex::new(VecDeque::with_capacity(SmpScheduler::MAX_THREADS)),
-            waiting: spin::Mutex::new(Vec::with_capacity(SmpScheduler::MAX_THREADS)),
+            runnable: Mutex::new(RunQueue::with_capacity(SmpScheduler::MAX_THREADS)),
+            waiting: Mutex::new(TimerWheel::new()),
+            idle: AtomicU32::new(BUSY),
+            handlers: Mutex::new(hashbrown::HashMap::new()),
+            registered: Default::default(),
         }
     }
 }
 
+/// `SchedulerCoreState::idle` of a core that runs threads.
+const BUSY: u32 = 0;
+/// `SchedulerCoreState::idle` of a core that sleeps in the kernel.
+const IDLE: u32 = 1;
+
+/// How long `SmpScheduler::idle` sleeps at most.
+const IDLE_TIMEOUT: Duration = Duration::from_millis(10);
+
 pub struct SmpScheduler<'a> {
     /// All thread generators need to dispatch threads.
     ///
     /// These will be absent if currently in use.
-    generators: spin::Mutex<hashbrown::HashMap<ThreadId, Runnable<'a>>>,
+    generators: Mutex<hashbrown::HashMap<ThreadId, Runnable<'a>>>,
     /// All threads in the scheduler.
-    threads: spin::Mutex<hashbrown::HashMap<ThreadId, Thread>>,
+    threads: Mutex<hashbrown::HashMap<ThreadId, Thread>>,
     /// Scheduler upcalls (as set by the client).
     upcalls: Upcalls,
     /// Per-core scheduler state
@@ -69,8 +109,8 @@ pub struct SmpScheduler<'a> {
     per_core: [SchedulerCoreState; 96], // MAX_THREADS
     /// Contains a global counter of thread IDs
     tid_counter: AtomicUsize,
-    /// Maps interrupt vectors to ThreadId
-    irqvec_to_tid: spin::Mutex<hashbrown::HashMap<IrqVector, ThreadId>>,
+    /// Do idle cores steal runnable threads from other cores?
+    work_stealing: AtomicBool,
 }
 
 unsafe impl Send for SmpScheduler<'static> {}
@@ -87,23 +127,58 @@ impl<'a> SmpScheduler<'a> {
 
     pub fn with_upcalls(upcalls: Upcalls) -> Self {
         Self {
-            generators: spin::Mutex::new(hashbrown::HashMap::with_capacity(
-                SmpScheduler::MAX_THREADS,
-            )),
-            threads: spin::Mutex::new(hashbrown::HashMap::with_capacity(SmpScheduler::MAX_THREADS)),
+            generators: Mutex::new(hashbrown::HashMap::with_capacity(SmpScheduler::MAX_THREADS)),
+            threads: Mutex::new(hashbrown::HashMap::with_capacity(SmpScheduler::MAX_THREADS)),
             upcalls,
             tid_counter: AtomicUsize::new(0),
             per_core: arr![SchedulerCoreState::new(); 96], // MAX_THREADS
-            irqvec_to_tid: spin::Mutex::new(hashbrown::HashMap::with_capacity(8)),
+            work_stealing: AtomicBool::new(false),
+        }
+    }
+
+    /// Lets cores that have nothing to run steal runnable threads from
+    /// other cores (only threads whose `AffinityMask` allows it).
+    pub fn set_work_stealing(&self, enabled: bool) {
+        self.work_stealing.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Restricts the cores `tid` can be stolen by (it still runs on its
+    /// current core until then).
+    pub fn set_affinity_mask(&self, tid: ThreadId, mask: AffinityMask) {
+        if let Some(thread) = self.threads.lock().get_mut(&tid) {
+            thread.allowed = mask;
         }
     }
 
+    /// Changes the priority of `tid` (see `Priority`).
+    pub fn set_priority(&self, tid: ThreadId, priority: Priority) {
+        let changed = self.threads.lock().get_mut(&tid).map(|thread| {
+            thread.priority = priority;
+            (thread.affinity, thread.effective_priority())
+        });
+        if let Some((affinity, effective)) = changed {
+            self.per_core[affinity]
+                .runnable
+                .lock()
+                .requeue(tid, effective);
+        }
+    }
+
+    /// The priority `tid` currently runs with (including what it
+    /// inherited).
+    pub fn priority(&self, tid: ThreadId) -> Option<Priority> {
+        self.threads
+            .lock()
+            .get(&tid)
+            .map(|thread| thread.effective_priority())
+    }
+
     /// Returns true as long as we have 'active', unfinished thread.
     ///
     /// A thread that is currently blocked/waiting still counts as active.
     ///
-    /// TODO(correctness): Maybe we want to exclude interrupt threads:
-    /// e.g., self.threads.lock().len() - self.irqvec_to_tid.lock().len() > 0
+    /// TODO(correctness): Maybe we want to exclude upcall handler threads
+    /// (see `register_upcall_handler`).
     /// TODO(api): Probably needs a better API, maybe schedule() should just return
     /// the next time a thread becomes runnable if none are, or a set of IRQs to wait on...
     pub fn has_active_threads(&self) -> bool {
@@ -119,12 +194,28 @@ impl<'a> SmpScheduler<'a> {
         interrupt_vector: Option<IrqVector>,
         tls: *mut ThreadControlBlock<'static>,
     ) -> Option<ThreadId>
+    where
+        F: 'static + FnOnce(*mut u8) + Send,
+    {
+        self.spawn_thread(stack, f, arg, affinity, interrupt_vector, tls, None)
+    }
+
+    fn spawn_thread<F>(
+        &self,
+        stack: LineupStack,
+        f: F,
+        arg: *mut u8,
+        affinity: CoreId,
+        interrupt_vector: Option<IrqVector>,
+        tls: *mut ThreadControlBlock<'static>,
+        on_panic: Option<PanicHook>,
+    ) -> Option<ThreadId>
     where
         F: 'static + FnOnce(*mut u8) + Send,
     {
         let t = self.tid_counter.fetch_add(1, Ordering::Relaxed);
         let tid = ThreadId(t);
-        let (handle, generator) = unsafe {
+        let (mut handle, generator) = unsafe {
             Thread::new(
                 tid,
                 affinity,
@@ -136,30 +227,104 @@ impl<'a> SmpScheduler<'a> {
                 tls,
             )
         };
+        handle.on_panic = on_panic;
 
         self.add_thread(handle, generator).map(|tid| {
+            if let Some(vector) = interrupt_vector {
+                self.register_upcall_handler(affinity, vector, tid);
+            }
             self.mark_runnable(tid, affinity);
-            interrupt_vector.map(|vec| {
-                self.irqvec_to_tid.lock().insert(vec, tid);
-            });
             tid
         })
     }
 
-    pub fn spawn<F>(
+    /// Makes `tid` the thread that handles upcalls for `vector` on `core`
+    /// (instead of the one that did so far).
+    ///
+    /// Whenever `raise` sees `vector` on `core`, `tid` becomes runnable
+    /// (typically it handles what came in and `block`s until the next
+    /// time). It stays on `core` from now on.
+    pub fn register_upcall_handler(&self, core: CoreId, vector: IrqVector, tid: ThreadId) {
+        assert!(vector < 256, "Upcall vector {} out of range", vector);
+        self.set_affinity_mask(tid, AffinityMask::only(core));
+
+        let per_core = &self.per_core[core];
+        if let Some(previous) = per_core.handlers.lock().insert(vector, tid) {
+            log::debug!(
+                "{} replaces {} for vector {} on core {}",
+                tid,
+                previous,
+                vector,
+                core
+            );
+        }
+        per_core.registered[(vector / 64) as usize].fetch_or(1 << (vector % 64), Ordering::AcqRel);
+    }
+
+    /// Removes the thread that handles `vector` on `core` (if any), returns
+    /// which one it was.
+    pub fn unregister_upcall_handler(&self, core: CoreId, vector: IrqVector) -> Option<ThreadId> {
+        let per_core = &self.per_core[core];
+        let mut handlers = per_core.handlers.lock();
+        per_core.registered[(vector / 64) as usize]
+            .fetch_and(!(1 << (vector % 64)), Ordering::AcqRel);
+        handlers.remove(&vector)
+    }
+
+    /// Lets the thread that handles `vector` on the core of `scb` know it
+    /// came in, returns false if there is none.
+    ///
+    /// Meant to be called from an upcall handler: it doesn't take any locks,
+    /// the thread becomes runnable the next time `run` looks.
+    pub fn raise(&self, scb: &SchedulerControlBlock, vector: IrqVector) -> bool {
+        if vector >= 256 {
+            return false;
+        }
+        let registered =
+            self.per_core[scb.core_id].registered[(vector / 64) as usize].load(Ordering::Acquire);
+        if registered & (1 << (vector % 64)) == 0 {
+            return false;
+        }
+
+        scb.mark_pending(vector);
+        true
+    }
+
+    /// Spawns a thread that runs `f` on core `affinity`.
+    ///
+    /// The returned handle can wait for `f` to return (or panic).
+    pub fn spawn<F, T>(
         &self,
         stack_size: usize,
         f: F,
         arg: *mut u8,
         affinity: CoreId,
         irq_vec: Option<IrqVector>,
-    ) -> Option<ThreadId>
+    ) -> Option<JoinHandle<T>>
     where
-        F: 'static + FnOnce(*mut u8) + Send,
+        F: 'static + FnOnce(*mut u8) -> T + Send,
+        T: 'static + Send,
     {
         let stack = LineupStack::from_size(stack_size);
         let tls = unsafe { tls2::ThreadControlBlock::new_tls_area() };
-        self.spawn_with_args(stack, f, arg, affinity, irq_vec, tls)
+        let result = Arc::new(spin::Mutex::new(None));
+        let their_result = result.clone();
+        let panic_result = result.clone();
+        self.spawn_thread(
+            stack,
+            move |arg| {
+                let r = threads::catch_unwind(move || f(arg));
+                *their_result.lock() = Some(r);
+            },
+            arg,
+            affinity,
+            irq_vec,
+            tls,
+            Some(Box::new(move |payload| {
+                *panic_result.lock() = Some(Err(payload));
+            })),
+        )
+        .map(|tid| JoinHandle::new(tid, result))
     }
 
     fn add_thread(
@@ -186,8 +351,17 @@ impl<'a> SmpScheduler<'a> {
 
     /// Marks a thread as sunnable by inserting it into
     /// `runnable`.
+    ///
+    /// Wakes up the core of the thread if it sleeps in `idle`.
     fn mark_runnable(&self, tid: ThreadId, affinity: CoreId) {
-        self.per_core[affinity].runnable.lock().push_back(tid);
+        let priority = self.priority(tid).unwrap_or_default();
+        self.per_core[affinity]
+            .runnable
+            .lock()
+            .push_back(tid, priority);
+        if self.per_core[affinity].idle.swap(BUSY, Ordering::SeqCst) == IDLE {
+            tls2::arch::futex_wake(&self.per_core[affinity].idle, 1);
+        }
     }
 
     /// Make a thread no longer runnable.
@@ -196,33 +370,197 @@ impl<'a> SmpScheduler<'a> {
     /// This is O(n) but it happens rarely(?); only
     /// call it if tid is different from current thread.
     fn mark_unrunnable(&self, tid: ThreadId, affinity: CoreId) {
-        let mut runnable = self.per_core[affinity].runnable.lock();
-        runnable.retain(|&ltid| ltid != tid);
+        self.per_core[affinity].runnable.lock().remove(tid);
+    }
+
+    /// Lends the priority of `from` to `to` for the lock at `lock` (or
+    /// takes it back if `from` is `None`).
+    ///
+    /// Only `to` itself gets a higher priority, not the thread it may be
+    /// waiting for in turn.
+    fn lend_priority(&self, from: Option<ThreadId>, to: ThreadId, lock: usize) {
+        let changed = {
+            let mut threads = self.threads.lock();
+            let priority = from.and_then(|tid| threads.get(&tid).map(|t| t.effective_priority()));
+            match threads.get_mut(&to) {
+                Some(thread) => {
+                    let before = thread.effective_priority();
+                    let pos = thread.inherited.iter().position(|&(l, _p)| l == lock);
+                    match (pos, priority) {
+                        (Some(pos), Some(priority)) => {
+                            let inherited = &mut thread.inherited[pos].1;
+                            *inherited = (*inherited).max(priority);
+                        }
+                        (None, Some(priority)) => thread.inherited.push((lock, priority)),
+                        (Some(pos), None) => {
+                            thread.inherited.swap_remove(pos);
+                        }
+                        (None, None) => {}
+                    }
+                    let after = thread.effective_priority();
+                    if before != after {
+                        Some((thread.affinity, after))
+                    } else {
+                        None
+                    }
+                }
+                // It's gone already
+                None => None,
+            }
+        };
+
+        if let Some((affinity, priority)) = changed {
+            trace!("{} runs with {:?} now", to, priority);
+            self.per_core[affinity]
+                .runnable
+                .lock()
+                .requeue(to, priority);
+        }
     }
 
     /// Remove a thread from the waitlist.
     ///
-    /// TODO(performance): This has ugly runtime complexity.
-    /// Maybe better do this right and use a linked-list after all.
-    /// Another alternative: The only time when we have to do this
-    /// is when the CondVar does a timedwait and someone wakes us
-    /// up using `signal` and `broadcast` so we can remove calls
-    /// here except in these situation if we track it better
-    /// i.e. save in thread state if its waiting...
-    fn waitlist_remove(&self, tid: ThreadId, affinity: CoreId) {
-        let mut waiting = self.per_core[affinity].waiting.lock();
-        waiting.retain(|&(_instant, wtid)| wtid != tid);
+    /// Returns false if the thread wasn't waiting (e.g., because its
+    /// time-out expired already).
+    fn waitlist_remove(&self, tid: ThreadId, affinity: CoreId) -> bool {
+        self.per_core[affinity].waiting.lock().remove(tid)
     }
 
-    /// Insert thread in a sorted waitlist
+    /// Insert thread in the waitlist, it becomes runnable again at `until`.
     fn waitlist_insert(&self, tid: ThreadId, affinity: CoreId, until: Instant) {
         let mut waiting = self.per_core[affinity].waiting.lock();
-        let to_insert = (until, tid);
-        match waiting.binary_search_by(|probe| probe.cmp(&to_insert).reverse()) {
-            Err(pos) => waiting.insert(pos, to_insert),
-            Ok(_pos) => panic!("Thread already in waitlist?"),
+        waiting.insert(tid, until);
+        trace!("Waitlist has {} threads", waiting.len());
+    }
+
+    /// When the next waiting thread on `core` wakes up.
+    pub fn next_wakeup(&self, core: CoreId) -> Option<Instant> {
+        self.per_core[core].waiting.lock().next_expiry()
+    }
+
+    /// Moves all threads of core `from` to core `to` (e.g., because `from`
+    /// goes away).
+    ///
+    /// Must be called on `from` (once nothing runs there anymore).
+    ///
+    /// TODO(race): A thread on another core that wakes up a thread of
+    /// `from` while we move things may still put it on `from`'s runnable
+    /// list.
+    pub fn migrate(&self, from: CoreId, to: CoreId) {
+        for thread in self.threads.lock().values_mut() {
+            if thread.affinity == from {
+                thread.affinity = to;
+                if !thread.state.is_null() {
+                    unsafe {
+                        (*thread.state).current_core = to;
+                    }
+                }
+            }
+        }
+
+        let runnable = self.per_core[from].runnable.lock().drain();
+        let mut to_runnable = self.per_core[to].runnable.lock();
+        for (tid, priority) in runnable {
+            to_runnable.push_back(tid, priority);
+        }
+        drop(to_runnable);
+
+        let waiting: Vec<(Instant, ThreadId)> = self.per_core[from].waiting.lock().drain();
+        for (until, tid) in waiting {
+            self.waitlist_insert(tid, to, until);
+        }
+    }
+
+    /// Takes a runnable thread that may run on `core` from another core and
+    /// makes it runnable on `core`.
+    ///
+    /// We take the thread that would run last (it would have to wait the
+    /// longest) and skip threads whose generator is missing: they
+    /// are still running on their core (e.g., they were woken up before they
+    /// blocked).
+    fn steal(&self, core: CoreId) -> Option<ThreadId> {
+        let cores = self.per_core.len();
+        for victim in (1..cores).map(|offset| (core + offset) % cores) {
+            let mut runnable = self.per_core[victim].runnable.lock();
+            if runnable.is_empty() {
+                continue;
+            }
+
+            let mut threads = self.threads.lock();
+            let generators = self.generators.lock();
+            let stolen = runnable.take_last(|tid| {
+                generators.contains_key(tid)
+                    && threads.get(tid).map_or(false, |t| t.allowed.contains(core))
+            });
+            if let Some(tid) = stolen {
+                let thread = threads.get_mut(&tid).expect("Can't find thread state?");
+                thread.affinity = core;
+                if !thread.state.is_null() {
+                    unsafe {
+                        (*thread.state).current_core = core;
+                    }
+                }
+                drop(generators);
+                drop(threads);
+                drop(runnable);
+
+                trace!("Core {} stole {} from core {}", core, tid, victim);
+                self.mark_runnable(tid, core);
+                return Some(tid);
+            }
+        }
+
+        None
+    }
+
+    /// Removes the thread `tid` that ended (with `panic` if it panicked)
+    /// and wakes up the threads that join it.
+    fn exit_thread(
+        &self,
+        tid: ThreadId,
+        affinity: CoreId,
+        panic: Option<Box<dyn Any + Send + 'static>>,
+    ) {
+        self.mark_unrunnable(tid, affinity);
+        let thread = self
+            .threads
+            .lock()
+            .remove(&tid)
+            .expect("Can't remove thread?");
+        if let (Some(payload), Some(on_panic)) = (panic, thread.on_panic) {
+            on_panic(payload);
+        }
+
+        let vectors: Vec<IrqVector> = self.per_core[affinity]
+            .handlers
+            .lock()
+            .iter()
+            .filter(|&(_vector, &htid)| htid == tid)
+            .map(|(&vector, _htid)| vector)
+            .collect();
+        for vector in vectors {
+            self.unregister_upcall_handler(affinity, vector);
+        }
+
+        // Wake up all the waiters
+        for (sleeping_tid, sleeping_affinity) in thread.joinlist {
+            log::debug!(
+                "{} will return from join on core {}",
+                sleeping_tid,
+                sleeping_affinity
+            );
+            // A join with a time-out is woken up by whoever takes
+            // it out of the waitlist first
+            let timed = self
+                .threads
+                .lock()
+                .get_mut(&sleeping_tid)
+                .and_then(|t| t.joins.take())
+                .is_some();
+            if !timed || self.waitlist_remove(sleeping_tid, sleeping_affinity) {
+                self.mark_runnable(sleeping_tid, sleeping_affinity);
+            }
         }
-        trace!("Waitlist is {:?}", waiting);
     }
 
     /// Handles a yield request of the thread given by `tid`.
@@ -233,22 +571,30 @@ impl<'a> SmpScheduler<'a> {
         match result {
             None => {
                 trace!("Thread {} has terminated.", tid);
-                self.mark_unrunnable(tid, affinity);
-                let thread = self
-                    .threads
-                    .lock()
-                    .remove(&tid)
-                    .expect("Can't remove thread?");
-
-                // Wake up all the waiters
-                for (sleeping_tid, sleeping_affinity) in thread.joinlist {
-                    log::debug!(
-                        "{} will return from join on core {}",
-                        sleeping_tid,
-                        sleeping_affinity
-                    );
-                    self.mark_runnable(sleeping_tid, sleeping_affinity);
+                self.exit_thread(tid, affinity, None);
+                YieldResume::DoNotResume
+            }
+            Some(YieldRequest::SetPriority(rtid, priority)) => {
+                trace!("YieldRequest::SetPriority {:?} {:?}", rtid, priority);
+                self.set_priority(rtid, priority);
+                YieldResume::Completed
+            }
+            Some(YieldRequest::Inherit(holder, lock)) => {
+                trace!("YieldRequest::Inherit {:?} {:#x}", holder, lock);
+                if holder != tid {
+                    self.lend_priority(Some(tid), holder, lock);
                 }
+                YieldResume::Completed
+            }
+            Some(YieldRequest::Disinherit(holder, lock)) => {
+                trace!("YieldRequest::Disinherit {:?} {:#x}", holder, lock);
+                self.lend_priority(None, holder, lock);
+                YieldResume::Completed
+            }
+            Some(YieldRequest::Panicked(payload)) => {
+                trace!("Thread {} has panicked.", tid);
+                let payload = unsafe { Box::from_raw(payload) };
+                self.exit_thread(tid, affinity, Some(payload));
                 YieldResume::DoNotResume
             }
             Some(YieldRequest::None) => {
@@ -319,18 +665,26 @@ impl<'a> SmpScheduler<'a> {
                 // Already popped from running, force context switch
                 YieldResume::Interrupted
             }
-            Some(YieldRequest::JoinOn(wait_on_tid)) => {
+            Some(YieldRequest::JoinOn(wait_on_tid, until)) => {
                 trace!(
                     "The thread #{:?} is waiting for #{:?} to complete.",
                     tid,
                     wait_on_tid
                 );
 
-                match self.threads.lock().get_mut(&wait_on_tid) {
+                let mut threads = self.threads.lock();
+                match threads.get_mut(&wait_on_tid) {
                     // If we find `wait_on_tid` in self.threads, put ourselves
                     // on its thread join-waitlist
                     Some(running_thread) => {
                         running_thread.joinlist.push((tid, affinity));
+                        if let Some(until) = until {
+                            // Still holding `threads`, so it can't exit
+                            // before we're in the waitlist
+                            threads.get_mut(&tid).expect("Can't find thread").joins =
+                                Some(wait_on_tid);
+                            self.waitlist_insert(tid, affinity, until);
+                        }
                         // Return interrupted to force a context switch
                         // We will be woken up again in the thread exit
                         // logic (e.g., the None arm above)
@@ -356,7 +710,8 @@ impl<'a> SmpScheduler<'a> {
                         affinity,
                         irq_vector,
                     )
-                    .expect("Can't spawn the thread");
+                    .expect("Can't spawn the thread")
+                    .thread_id();
                 YieldResume::Spawned(tid)
             }
             Some(YieldRequest::SpawnWithArgs(
@@ -387,31 +742,93 @@ impl<'a> SmpScheduler<'a> {
 
     /// Finds threads with expired timeouts and re-inserts them from `waiting` into `runnable`
     ///
-    /// Acquires lock on `waiting` and `runnable`.
-    /// TODO(style): Maybe should avoid taking both locks here to avoid deadlock.
+    /// The threads resume with `YieldResume::TimedOut`, a thread that timed
+    /// out in a join is removed from the join-waitlist.
     /// TODO(efficiency): Should probably avoid taking `runnable` lock multiple times.
     fn check_wakeups(&self, affinity: CoreId) {
-        let mut waiting = self.per_core[affinity].waiting.lock();
-        while !waiting.is_empty() && waiting.last().unwrap().0 <= Instant::now() {
-            if let Some((_wakeup, tid)) = waiting.pop() {
-                self.mark_runnable(tid, affinity);
+        let expired = self.per_core[affinity]
+            .waiting
+            .lock()
+            .expire(Instant::now());
+        if expired.is_empty() {
+            return;
+        }
+
+        {
+            let mut threads = self.threads.lock();
+            for tid in expired.iter() {
+                let joins = match threads.get_mut(tid) {
+                    Some(thread) => {
+                        thread.return_with = Some(YieldResume::TimedOut);
+                        thread.joins
+                    }
+                    None => None,
+                };
+                // If the other thread is gone already, it's about to wake
+                // us up and finds out we're no longer waiting
+                if let Some(running_thread) = joins.and_then(|jtid| threads.get_mut(&jtid)) {
+                    running_thread.joinlist.retain(|&(jtid, _)| jtid != *tid);
+                    threads.get_mut(tid).unwrap().joins = None;
+                }
             }
         }
+
+        for tid in expired {
+            self.mark_runnable(tid, affinity);
+        }
     }
 
-    /// Check for an incoming interrupt.
+    /// Makes the threads that handle the upcalls `raise`d on this core
+    /// runnable.
     fn check_interrupt(&self, state: &SchedulerControlBlock) {
-        while !state.pending_irqs.is_empty() {
-            match state.pending_irqs.pop() {
-                Some(vec) => match self.irqvec_to_tid.lock().get(&vec) {
-                    Some(tid) => self.mark_runnable(*tid, state.core_id),
-                    None => error!("Don't have a thread to handle IRQ vector {}", vec),
-                },
-                None => unreachable!("Only one thread pops so this shouldn't happen"),
+        if !state.has_pending_upcalls() {
+            return;
+        }
+
+        for vector in state.take_pending() {
+            let handler = self.per_core[state.core_id]
+                .handlers
+                .lock()
+                .get(&vector)
+                .copied();
+            match handler {
+                Some(tid) => self.mark_runnable(tid, state.core_id),
+                // It was unregistered in the meantime
+                None => error!("Don't have a thread to handle upcall vector {}", vector),
             }
         }
     }
 
+    /// Puts the core to sleep in the kernel until a thread on it can run
+    /// again: its time-out expires, another core makes it runnable or an
+    /// interrupt arrives (but for `IDLE_TIMEOUT` at most).
+    ///
+    /// Meant to be called in between `run`s by a core that has nothing else
+    /// to do.
+    pub fn idle(&self, scb: &SchedulerControlBlock) {
+        let timeout = match self.next_wakeup(scb.core_id) {
+            Some(wakeup) => {
+                let now = Instant::now();
+                if wakeup <= now {
+                    return;
+                }
+                wakeup.duration_since(now).min(IDLE_TIMEOUT)
+            }
+            None => IDLE_TIMEOUT,
+        };
+
+        // `mark_runnable` wakes us up if it comes after this
+        let core = &self.per_core[scb.core_id];
+        core.idle.store(IDLE, Ordering::SeqCst);
+        if core.runnable.lock().is_empty()
+            && !scb.has_pending_upcalls()
+            && !scb.revoked.load(Ordering::Relaxed)
+        {
+            tls2::arch::futex_wait(&core.idle, IDLE, timeout);
+        }
+        core.idle.store(BUSY, Ordering::SeqCst);
+    }
+
     /// Dispatches one thread, runs it until it yields again.
     ///
     /// Also checks if any waiting threads need to be woken up.
@@ -434,8 +851,11 @@ impl<'a> SmpScheduler<'a> {
         }
 
         let mut prev_rumprun_lwp: *mut u8 = ptr::null_mut();
-        // Run until `runnable` is empty.
+        // Run until `runnable` is empty (or the core goes away).
         loop {
+            if scb.revoked.load(Ordering::Relaxed) {
+                break;
+            }
             self.check_interrupt(scb);
             self.check_wakeups(core_id);
 
@@ -450,8 +870,8 @@ impl<'a> SmpScheduler<'a> {
                         .expect("Can't find generator thread state?");
 
                     let mut resume_action: YieldResume = {
-                        let thread_map = self.threads.lock();
-                        let thread = thread_map.get(&tid).expect("Can't find thread state?");
+                        let mut thread_map = self.threads.lock();
+                        let thread = thread_map.get_mut(&tid).expect("Can't find thread state?");
                         trace!("Thread = {:?}", thread);
 
                         // TODO(api-ergonomics): `context_switch` should be a generic (non-rump specific) interface
@@ -464,7 +884,7 @@ impl<'a> SmpScheduler<'a> {
                         unsafe {
                             tls2::arch::set_tcb(thread.state);
                         }
-                        thread.return_with.unwrap_or(YieldResume::Completed)
+                        thread.return_with.take().unwrap_or(YieldResume::Completed)
                     };
 
                     // Run the thread until `handle_yield_request` decides on a context-switch
@@ -495,6 +915,10 @@ impl<'a> SmpScheduler<'a> {
                         if resume_action == YieldResume::DoNotResume {
                             // We're done with this thread for good
                             trace!("Dropping generator for {}", tid);
+                            if generator.state() == State::Runnable {
+                                // It panicked, its stack is all we keep
+                                drop(unsafe { generator.unsafe_unwrap() });
+                            }
                             break;
                         }
                     }
@@ -506,6 +930,9 @@ impl<'a> SmpScheduler<'a> {
                     }
                 }
                 None => {
+                    if self.work_stealing.load(Ordering::Relaxed) && self.steal(core_id).is_some() {
+                        continue;
+                    }
                     // Nothing to dispatch
                     // Maybe return the next event that will happen on that scheduler?
                     break;
@@ -806,7 +1233,7 @@ mod tests {
         assert!(exp_duration <= ref_duration + bound, "Lineup was too slow?");
     }
 
-    /// Test that waitlist inserts are inserted with correct order.
+    /// Test that waiting threads wake up in the correct order.
     #[test]
     fn waitlist_inserts_are_sorted() {
         let t0 = ThreadId(1);
@@ -835,17 +1262,424 @@ mod tests {
         s2.waitlist_insert(t0, 0, t0n);
 
         // Order should not depend on insertion order
+        let now = Instant::now();
+        let expired = s1.per_core[0].waiting.lock().expire(now);
         debug_assert_eq!(
-            *s1.per_core[0].waiting.lock(),
-            *s2.per_core[0].waiting.lock(),
+            expired,
+            s2.per_core[0].waiting.lock().expire(now),
             "List order depends on insert order?"
         );
 
-        let waitlist = s1.per_core[0].waiting.lock();
-        // Event with shortest wakeup time is last:
-        debug_assert!(waitlist[0].1 == ThreadId(3));
-        debug_assert!(waitlist[1].1 == ThreadId(2));
-        debug_assert!(waitlist[2].1 == ThreadId(1));
+        // Event with shortest wakeup time is first:
+        debug_assert_eq!(expired, [ThreadId(1), ThreadId(2), ThreadId(3)]);
+    }
+
+    /// Test that migrating moves runnable and waiting threads.
+    #[test]
+    fn migrate_moves_threads() {
+        let s: Arc<SmpScheduler> = Default::default();
+        let t0 = s
+            .spawn(DEFAULT_STACK_SIZE_BYTES, |_| {}, ptr::null_mut(), 1, None)
+            .unwrap()
+            .thread_id();
+        let t1 = ThreadId(42);
+        let until = Instant::now() + Duration::from_secs(60);
+        s.waitlist_insert(t1, 1, until);
+        s.waitlist_insert(ThreadId(43), 0, until + Duration::from_secs(60));
+
+        s.migrate(1, 0);
+
+        assert!(s.per_core[1].runnable.lock().is_empty());
+        assert!(s.per_core[1].waiting.lock().is_empty());
+        assert_eq!(
+            s.per_core[0].runnable.lock().iter().collect::<Vec<_>>(),
+            [&t0]
+        );
+        assert!(s.per_core[0].waiting.lock().contains(t1));
+        assert_eq!(s.next_wakeup(0), Some(until));
+        assert_eq!(s.threads.lock().get(&t0).unwrap().affinity, 0);
+
+        // The thread now runs on core 0
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        s.run(&scb);
+        assert!(s.threads.lock().get(&t0).is_none());
+    }
+
+    /// Test that an idle core steals runnable threads, but only those its
+    /// affinity mask allows.
+    #[test]
+    fn work_stealing() {
+        let s: Arc<SmpScheduler> = Default::default();
+        s.set_work_stealing(true);
+
+        let cores: Arc<ArrayQueue<CoreId>> = Arc::new(ArrayQueue::new(4));
+        let mut tids = Vec::new();
+        for _i in 0..4 {
+            let cores = cores.clone();
+            let tid = s
+                .spawn(
+                    DEFAULT_STACK_SIZE_BYTES,
+                    move |_| {
+                        let _r = cores.push(Environment::scheduler().core_id);
+                        assert_eq!(
+                            Environment::thread().current_core,
+                            Environment::scheduler().core_id
+                        );
+                    },
+                    ptr::null_mut(),
+                    1,
+                    None,
+                )
+                .unwrap()
+                .thread_id();
+            tids.push(tid);
+        }
+        s.set_affinity_mask(tids[3], AffinityMask::only(1));
+
+        // Only core 0 runs, it can take all but the pinned thread
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        s.run(&scb);
+        assert_eq!(cores.len(), 3);
+        while let Some(core) = cores.pop() {
+            assert_eq!(core, 0);
+        }
+        assert_eq!(
+            s.per_core[1].runnable.lock().iter().collect::<Vec<_>>(),
+            [&tids[3]]
+        );
+
+        // Without work stealing, threads stay where they are
+        s.set_work_stealing(false);
+        s.spawn(DEFAULT_STACK_SIZE_BYTES, |_| {}, ptr::null_mut(), 2, None);
+        s.run(&scb);
+        assert_eq!(s.per_core[2].runnable.lock().len(), 1);
+
+        for core in 1..3 {
+            let scb: SchedulerControlBlock = SchedulerControlBlock::new(core);
+            s.run(&scb);
+        }
+        assert!(!s.has_active_threads());
+    }
+
+    /// Test that a core runs the threads with the highest priority first.
+    #[test]
+    fn priorities() {
+        let s: Arc<SmpScheduler> = Default::default();
+        let order: Arc<ArrayQueue<Priority>> = Arc::new(ArrayQueue::new(4));
+
+        let priorities = [
+            Priority::Low,
+            Priority::Normal,
+            Priority::Interrupt,
+            Priority::High,
+        ];
+        for &priority in priorities.iter() {
+            let order = order.clone();
+            let tid = s
+                .spawn(
+                    DEFAULT_STACK_SIZE_BYTES,
+                    move |_| {
+                        let _r = order.push(priority);
+                    },
+                    ptr::null_mut(),
+                    0,
+                    None,
+                )
+                .unwrap()
+                .thread_id();
+            s.set_priority(tid, priority);
+            assert_eq!(s.priority(tid), Some(priority));
+        }
+
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        s.run(&scb);
+        assert_eq!(order.pop(), Some(Priority::Interrupt));
+        assert_eq!(order.pop(), Some(Priority::High));
+        assert_eq!(order.pop(), Some(Priority::Normal));
+        assert_eq!(order.pop(), Some(Priority::Low));
+    }
+
+    /// Test that a thread that holds a mutex runs with the priority of the
+    /// threads that wait for it.
+    #[test]
+    fn priority_inheritance() {
+        let _r = env_logger::try_init();
+
+        let s: Arc<SmpScheduler> = Default::default();
+        let mtx = Arc::new(crate::sync::Mutex::new());
+        let order: Arc<ArrayQueue<Priority>> = Arc::new(ArrayQueue::new(3));
+
+        let (m, o) = (mtx.clone(), order.clone());
+        let high = s
+            .spawn(
+                DEFAULT_STACK_SIZE_BYTES,
+                move |_| {
+                    // Let `low` take the mutex first
+                    Environment::thread().sleep(Duration::from_millis(1));
+                    m.enter();
+                    let _r = o.push(Priority::High);
+                    m.exit();
+                },
+                ptr::null_mut(),
+                0,
+                None,
+            )
+            .unwrap()
+            .thread_id();
+        s.set_priority(high, Priority::High);
+
+        let o = order.clone();
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                Environment::thread().sleep(Duration::from_millis(1));
+                let _r = o.push(Priority::Normal);
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+
+        let (m, o, s1) = (mtx.clone(), order.clone(), s.clone());
+        let low = s
+            .spawn(
+                DEFAULT_STACK_SIZE_BYTES,
+                move |_| {
+                    m.enter();
+                    Environment::thread().sleep(Duration::from_millis(5));
+                    // `high` waits for us now, without inheritance it would
+                    // keep the core to itself from here on
+                    assert_eq!(s1.priority(Environment::tid()), Some(Priority::High));
+                    Environment::thread().relinquish();
+                    let _r = o.push(Priority::Low);
+                    m.exit();
+                },
+                ptr::null_mut(),
+                0,
+                None,
+            )
+            .unwrap()
+            .thread_id();
+        s.set_priority(low, Priority::Low);
+
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        while s.has_active_threads() {
+            s.run(&scb);
+            s.idle(&scb);
+        }
+
+        assert_eq!(order.pop(), Some(Priority::Low));
+        assert_eq!(order.pop(), Some(Priority::High));
+        assert_eq!(order.pop(), Some(Priority::Normal));
+        assert_eq!(s.priority(low), None);
+    }
+
+    /// Test that raising a vector runs the thread registered for it on the
+    /// core.
+    #[test]
+    fn upcall_handlers() {
+        let s: Arc<SmpScheduler> = Default::default();
+        let handled = Arc::new(AtomicUsize::new(0));
+
+        let h = handled.clone();
+        let tid = s
+            .spawn(
+                DEFAULT_STACK_SIZE_BYTES,
+                move |_| loop {
+                    Environment::thread().block();
+                    if h.fetch_add(1, Ordering::Relaxed) + 1 == 2 {
+                        break;
+                    }
+                },
+                ptr::null_mut(),
+                1,
+                Some(42),
+            )
+            .unwrap()
+            .thread_id();
+        assert_eq!(s.priority(tid), Some(Priority::Interrupt));
+
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(1);
+        s.run(&scb);
+        assert_eq!(handled.load(Ordering::Relaxed), 0);
+
+        // Only vectors with a handler on the core are raised, a vector that
+        // comes in twice before `run` looks is handled once
+        assert!(!s.raise(&scb, 43));
+        assert!(!s.raise(&SchedulerControlBlock::new(0), 42));
+        assert!(s.raise(&scb, 42));
+        assert!(s.raise(&scb, 42));
+        s.run(&scb);
+        assert_eq!(handled.load(Ordering::Relaxed), 1);
+        assert!(!scb.has_pending_upcalls());
+
+        // The handler unregisters once it's done
+        assert!(s.raise(&scb, 42));
+        s.run(&scb);
+        assert_eq!(handled.load(Ordering::Relaxed), 2);
+        assert!(!s.has_active_threads());
+        assert!(!s.raise(&scb, 42));
+        assert_eq!(s.unregister_upcall_handler(1, 42), None);
+    }
+
+    /// Test that blocking and joining with a time-out return once their
+    /// time is up, or before if another thread wakes them up.
+    #[test]
+    fn timeouts() {
+        let _r = env_logger::try_init();
+
+        let s: Arc<SmpScheduler> = Default::default();
+        let results: Arc<ArrayQueue<(usize, bool)>> = Arc::new(ArrayQueue::new(4));
+
+        let r0 = results.clone();
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                // Nobody wakes us up
+                let woken = Environment::thread().block_timeout(Duration::from_millis(20));
+                let _r = r0.push((0, woken));
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+
+        let r1 = results.clone();
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                let woken = Environment::thread().block_timeout(Duration::from_secs(60));
+                let _r = r1.push((1, woken));
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+
+        let r2 = results.clone();
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                Environment::thread().make_runnable(ThreadId(1));
+                let t = Environment::thread();
+                let _r = r2.push((2, t.join_timeout(ThreadId(3), Duration::from_millis(10))));
+                let _r = r2.push((2, t.join_timeout(ThreadId(3), Duration::from_secs(60))));
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                Environment::thread().sleep(Duration::from_millis(50));
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+
+        let start = Instant::now();
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        while s.has_active_threads() {
+            s.run(&scb);
+            s.idle(&scb);
+        }
+        assert!(start.elapsed() < Duration::from_secs(60));
+        assert!(s.per_core[0].waiting.lock().is_empty());
+
+        let mut seen = Vec::new();
+        while let Some(r) = results.pop() {
+            seen.push(r);
+        }
+        seen.sort();
+        assert_eq!(seen, [(0, false), (1, true), (2, false), (2, true)]);
+    }
+
+    /// Test that joining a thread returns what its closure returned (or
+    /// what it panicked with).
+    #[test]
+    fn join_handles() {
+        let s: Arc<SmpScheduler> = Default::default();
+
+        let answer = s
+            .spawn(
+                DEFAULT_STACK_SIZE_BYTES,
+                |_| 6 * 7,
+                ptr::null_mut(),
+                1,
+                None,
+            )
+            .unwrap();
+        let oops = s
+            .spawn(
+                DEFAULT_STACK_SIZE_BYTES,
+                |_| -> usize { panic!("oops") },
+                ptr::null_mut(),
+                1,
+                None,
+            )
+            .unwrap();
+        let joiner = s
+            .spawn(
+                DEFAULT_STACK_SIZE_BYTES,
+                move |_| {
+                    let err = oops.join().expect_err("Didn't see the panic?");
+                    assert_eq!(err.downcast_ref::<&str>(), Some(&"oops"));
+                    answer.join().unwrap()
+                },
+                ptr::null_mut(),
+                0,
+                None,
+            )
+            .unwrap();
+
+        // The joiner waits on core 0 until core 1 ran the other threads
+        for &core in [0, 1, 0].iter() {
+            assert!(!joiner.is_finished());
+            let scb: SchedulerControlBlock = SchedulerControlBlock::new(core);
+            s.run(&scb);
+        }
+        assert!(joiner.is_finished());
+        assert_eq!(joiner.join().unwrap(), 42);
+        assert!(!s.has_active_threads());
+    }
+
+    /// An abandoned thread hands its payload to the `JoinHandle`, the other
+    /// threads keep running.
+    #[test]
+    fn abandon_thread() {
+        let s: Arc<SmpScheduler> = Default::default();
+
+        let abandoned = s
+            .spawn(
+                DEFAULT_STACK_SIZE_BYTES,
+                |_| -> usize { Environment::thread().abandon(Box::new("oops")) },
+                ptr::null_mut(),
+                0,
+                None,
+            )
+            .unwrap();
+        let survivor = s
+            .spawn(
+                DEFAULT_STACK_SIZE_BYTES,
+                move |_| {
+                    let err = abandoned.join().expect_err("Didn't see the panic?");
+                    assert_eq!(err.downcast_ref::<&str>(), Some(&"oops"));
+                    Environment::thread().relinquish();
+                    42
+                },
+                ptr::null_mut(),
+                0,
+                None,
+            )
+            .unwrap();
+
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        s.run(&scb);
+        assert_eq!(survivor.join().unwrap(), 42);
+        assert!(!s.has_active_threads());
     }
 
     /// Test that sleeping events wake up in the correct order
@@ -0,0 +1,209 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A condition variable for `sync::Mutex` that sleeps in the kernel while
+//! it waits.
+//!
+//! Waiters wait for a sequence number that `signal` and `broadcast`
+//! increment, so a signal can wake up more than one waiter (callers check
+//! their condition again anyways).
+
+use core::ops::Add;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crossbeam_utils::CachePadded;
+use rawtime::Instant;
+
+use super::Mutex;
+use crate::tls2::{Environment, ThreadControlBlock};
+
+#[derive(Debug)]
+pub struct Condvar {
+    seq: CachePadded<AtomicU32>,
+    /// Threads in one of the `wait` functions.
+    waiters: AtomicUsize,
+}
+
+impl Condvar {
+    pub fn new() -> Condvar {
+        Condvar {
+            seq: CachePadded::new(AtomicU32::new(0)),
+            waiters: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn wait(&self, mtx: &Mutex) {
+        self.wait_wrapped(mtx, None);
+    }
+
+    /// Waits without telling rump that we give up the CPU.
+    pub fn wait_nowrap(&self, mtx: &Mutex) {
+        let seq = self.seq.load(Ordering::Acquire);
+        self.waiters.fetch_add(1, Ordering::Relaxed);
+        mtx.exit();
+
+        self.wait_for(seq, None);
+
+        self.waiters.fetch_sub(1, Ordering::Relaxed);
+        mtx.enter_nowrap();
+    }
+
+    /// Returns false on time out.
+    pub fn timed_wait(&self, mtx: &Mutex, d: Duration) -> bool {
+        self.wait_wrapped(mtx, Some(Instant::now().add(d)))
+    }
+
+    pub fn signal(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        if self.has_waiters() {
+            super::unpark(&self.seq, 1);
+        }
+    }
+
+    pub fn broadcast(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        if self.has_waiters() {
+            super::unpark(&self.seq, usize::MAX);
+        }
+    }
+
+    pub fn has_waiters(&self) -> bool {
+        self.waiters.load(Ordering::Relaxed) > 0
+    }
+
+    /// Waits (until `deadline`), gives up the rump CPU while we wait.
+    fn wait_wrapped(&self, mtx: &Mutex, deadline: Option<Instant>) -> bool {
+        let yielder: &mut ThreadControlBlock = Environment::thread();
+        let mut rid = 0;
+
+        // Read it while we hold `mtx`, so we can't miss a signal
+        let seq = self.seq.load(Ordering::Acquire);
+        self.waiters.fetch_add(1, Ordering::Relaxed);
+        (yielder.upcalls.deschedule)(&mut rid, Some(mtx));
+        mtx.exit();
+
+        let signaled = self.wait_for(seq, deadline);
+
+        self.waiters.fetch_sub(1, Ordering::Relaxed);
+        if mtx.is_spin() && mtx.is_kmutex() {
+            (yielder.upcalls.schedule)(&rid, Some(mtx));
+            mtx.enter_nowrap();
+        } else {
+            mtx.enter_nowrap();
+            (yielder.upcalls.schedule)(&rid, Some(mtx));
+        }
+
+        signaled
+    }
+
+    /// Waits until `seq` changes, returns false if `deadline` passed first.
+    fn wait_for(&self, seq: u32, deadline: Option<Instant>) -> bool {
+        while self.seq.load(Ordering::Acquire) == seq {
+            if deadline.map_or(false, |d| Instant::now() >= d) {
+                return false;
+            }
+            super::park(&self.seq, seq, deadline);
+        }
+        true
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Condvar::new()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_sync_condvar() {
+    use alloc::sync::Arc;
+    use core::cell::UnsafeCell;
+    use core::ptr;
+
+    use crate::scheduler::SmpScheduler;
+    use crate::stack::DEFAULT_STACK_SIZE_BYTES;
+    use crate::tls2::SchedulerControlBlock;
+
+    struct UnsafeSyncCell<T> {
+        inner: UnsafeCell<T>,
+    }
+    unsafe impl<T: Send> Sync for UnsafeSyncCell<T> {}
+
+    let _r = env_logger::try_init();
+
+    let s: SmpScheduler = Default::default();
+    let cv = Arc::new(Condvar::new());
+    let mtx = Arc::new(Mutex::new_kmutex());
+    let ready = Arc::new(UnsafeSyncCell {
+        inner: UnsafeCell::new(false),
+    });
+
+    let (cv1, m1, r1) = (cv.clone(), mtx.clone(), ready.clone());
+    s.spawn(
+        DEFAULT_STACK_SIZE_BYTES,
+        move |_| {
+            m1.enter();
+            while !unsafe { *r1.inner.get() } {
+                cv1.wait(&m1);
+            }
+            m1.exit();
+        },
+        ptr::null_mut(),
+        0,
+        None,
+    );
+
+    let (cv2, m2, r2) = (cv.clone(), mtx.clone(), ready.clone());
+    s.spawn(
+        DEFAULT_STACK_SIZE_BYTES,
+        move |_| {
+            m2.enter();
+            unsafe { *r2.inner.get() = true };
+            cv2.signal();
+            m2.exit();
+        },
+        ptr::null_mut(),
+        0,
+        None,
+    );
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    s.run(&scb);
+    assert!(!cv.has_waiters());
+}
+
+#[cfg(test)]
+#[test]
+fn test_sync_condvar_timeout() {
+    use alloc::sync::Arc;
+    use core::ptr;
+
+    use crate::scheduler::SmpScheduler;
+    use crate::stack::DEFAULT_STACK_SIZE_BYTES;
+    use crate::tls2::SchedulerControlBlock;
+
+    let _r = env_logger::try_init();
+
+    let s: SmpScheduler = Default::default();
+    let cv = Arc::new(Condvar::new());
+    let mtx = Arc::new(Mutex::new());
+
+    s.spawn(
+        DEFAULT_STACK_SIZE_BYTES,
+        move |_| {
+            let start = Instant::now();
+            mtx.enter();
+            assert!(!cv.timed_wait(&mtx, Duration::from_millis(20)));
+            mtx.exit();
+            assert!(start.elapsed() >= Duration::from_millis(20));
+        },
+        ptr::null_mut(),
+        0,
+        None,
+    );
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    s.run(&scb);
+}
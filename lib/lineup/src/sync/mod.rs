@@ -0,0 +1,95 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Locks that put waiting threads to sleep in the kernel instead of
+//! spinning or yielding in a loop.
+//!
+//! All of them keep their state in a 32-bit word and wait for it to change
+//! in `park`: we spin briefly, let the other threads of the core run (the
+//! thread we wait for may be one of them) and then sleep in the kernel
+//! (`FutexWait`) until the thread that changes the word wakes us up
+//! (`FutexWake`). Sleeping in the kernel stops the whole core, so we only do
+//! it for `PARK_TIMEOUT` at a time before we give the other threads of the
+//! core another chance.
+//!
+//! A thread waiting for a `Mutex` lends its priority to the thread that
+//! holds it (see `Priority`), so a low-priority holder isn't kept from
+//! releasing the mutex by threads with a priority in between.
+
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use rawtime::Instant;
+
+use crate::threads::ThreadId;
+use crate::tls2::{arch, Environment};
+
+pub mod condvar;
+pub mod mutex;
+pub mod rwlock;
+
+pub use condvar::Condvar;
+pub use mutex::Mutex;
+pub use rwlock::RwLock;
+
+/// How often we check a word before we sleep.
+const SPIN_LIMIT: usize = 128;
+
+/// How long a core sleeps at most before its other threads run again.
+const PARK_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Waits (for a while) until `word` is no longer `expected` or `deadline`
+/// passed.
+///
+/// This can return before either happened, callers check again.
+fn park(word: &AtomicU32, expected: u32, deadline: Option<Instant>) {
+    for _i in 0..SPIN_LIMIT {
+        if word.load(Ordering::Acquire) != expected {
+            return;
+        }
+        spin_loop();
+    }
+
+    // Whoever changes the word might run on our core
+    Environment::thread().relinquish();
+    if word.load(Ordering::Acquire) != expected {
+        return;
+    }
+
+    let timeout = match deadline {
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                return;
+            }
+            deadline.duration_since(now).min(PARK_TIMEOUT)
+        }
+        None => PARK_TIMEOUT,
+    };
+    arch::futex_wait(word, expected, timeout);
+}
+
+/// Wakes up (at most) `count` cores sleeping in `park` on `word`.
+fn unpark(word: &AtomicU32, count: usize) {
+    arch::futex_wake(word, count);
+}
+
+/// Can we talk to the scheduler (we run in a lineup thread)?
+fn in_thread() -> bool {
+    Environment::has_thread() && Environment::thread().has_yielder()
+}
+
+/// Lends our priority to `holder` of the lock at `lock`.
+fn inherit(holder: ThreadId, lock: usize) {
+    if in_thread() {
+        Environment::thread().inherit(holder, lock);
+    }
+}
+
+/// Takes back what `holder` inherited for the lock at `lock`.
+fn disinherit(holder: ThreadId, lock: usize) {
+    if in_thread() {
+        Environment::thread().disinherit(holder, lock);
+    }
+}
@@ -0,0 +1,301 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A mutex that sleeps in the kernel while a thread on another core holds
+//! it.
+
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+
+use crossbeam_utils::CachePadded;
+
+use crate::threads::ThreadId;
+use crate::tls2::{Environment, ThreadControlBlock};
+
+/// Nobody holds the mutex.
+const UNLOCKED: u32 = 0;
+/// Somebody holds the mutex.
+const LOCKED: u32 = 1;
+/// Somebody holds the mutex and others might sleep on it.
+const CONTENDED: u32 = 2;
+
+/// `core` if nobody holds the mutex.
+const NO_CORE: usize = usize::MAX;
+
+/// `holder` if nobody (or no lineup thread) holds the mutex.
+const NO_HOLDER: usize = usize::MAX;
+
+#[derive(Debug)]
+pub struct Mutex {
+    state: CachePadded<AtomicU32>,
+    /// Core of the thread that holds the mutex.
+    core: AtomicUsize,
+    /// `ThreadId` of the thread that holds the mutex, it inherits the
+    /// priority of the threads that wait.
+    holder: AtomicUsize,
+    /// Rump lwp of the thread that holds the mutex.
+    lwp_ptr: AtomicPtr<u64>,
+    is_spin: bool,
+    is_kmutex: bool,
+}
+
+impl Mutex {
+    pub fn new_spin_kmutex() -> Self {
+        Mutex::new_with_flags(true, true)
+    }
+
+    pub fn new_kmutex() -> Self {
+        Mutex::new_with_flags(false, true)
+    }
+
+    pub fn new_spin() -> Self {
+        Mutex::new_with_flags(true, false)
+    }
+
+    pub fn new() -> Self {
+        Mutex::new_with_flags(false, false)
+    }
+
+    pub fn new_with_flags(is_spin: bool, is_kmutex: bool) -> Mutex {
+        Mutex {
+            state: CachePadded::new(AtomicU32::new(UNLOCKED)),
+            core: AtomicUsize::new(NO_CORE),
+            holder: AtomicUsize::new(NO_HOLDER),
+            lwp_ptr: AtomicPtr::new(ptr::null_mut()),
+            is_spin,
+            is_kmutex,
+        }
+    }
+
+    pub fn is_kmutex(&self) -> bool {
+        self.is_kmutex
+    }
+
+    pub fn is_spin(&self) -> bool {
+        self.is_spin
+    }
+
+    pub fn try_enter(&self) -> bool {
+        let acquired = self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+        if acquired {
+            self.set_owner();
+        }
+        acquired
+    }
+
+    /// Acquires the mutex, gives up the rump CPU while we wait.
+    pub fn enter(&self) {
+        if self.try_enter() {
+            return;
+        }
+
+        let yielder: &mut ThreadControlBlock = Environment::thread();
+        let mut rid = 0;
+        (yielder.upcalls.deschedule)(&mut rid, None);
+        self.wait();
+        (yielder.upcalls.schedule)(&rid, None);
+        self.set_owner();
+    }
+
+    /// Acquires the mutex (without telling rump we wait).
+    pub fn enter_nowrap(&self) {
+        if !self.try_enter() {
+            self.wait();
+            self.set_owner();
+        }
+    }
+
+    pub fn exit(&self) {
+        self.lwp_ptr.store(ptr::null_mut(), Ordering::Relaxed);
+        self.core.store(NO_CORE, Ordering::Relaxed);
+        let holder = self.holder.swap(NO_HOLDER, Ordering::Relaxed);
+
+        match self.state.swap(UNLOCKED, Ordering::Release) {
+            UNLOCKED => panic!(
+                "{:?} Called exit on already released mtx={:p}",
+                Environment::tid(),
+                self
+            ),
+            CONTENDED => {
+                super::unpark(&self.state, 1);
+                if holder != NO_HOLDER {
+                    super::disinherit(ThreadId(holder), self.id());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn owner(&self) -> *const u64 {
+        self.lwp_ptr.load(Ordering::Relaxed)
+    }
+
+    /// Identifies the mutex towards the scheduler.
+    fn id(&self) -> usize {
+        self as *const Mutex as usize
+    }
+
+    /// Waits until we hold the mutex.
+    fn wait(&self) {
+        let mut boosted = NO_HOLDER;
+        // We don't know whether anyone else sleeps, so we leave it
+        // `CONTENDED` once we got it
+        while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+            let holder = self.holder.load(Ordering::Relaxed);
+            if holder != boosted && holder != NO_HOLDER {
+                if boosted != NO_HOLDER {
+                    super::disinherit(ThreadId(boosted), self.id());
+                }
+                super::inherit(ThreadId(holder), self.id());
+                boosted = holder;
+            }
+
+            if self.core.load(Ordering::Relaxed) == Environment::core_id() {
+                // The holder can only give it back if we let it run
+                Environment::thread().relinquish();
+            } else {
+                super::park(&self.state, CONTENDED, None);
+            }
+        }
+
+        // The holder takes it back itself when it lets go, unless we lent
+        // it after that
+        if boosted != NO_HOLDER {
+            super::disinherit(ThreadId(boosted), self.id());
+        }
+    }
+
+    fn set_owner(&self) {
+        self.core.store(Environment::core_id(), Ordering::Relaxed);
+        if super::in_thread() {
+            self.holder.store(Environment::tid().0, Ordering::Relaxed);
+        }
+        self.lwp_ptr.store(
+            Environment::thread().rump_lwp.load(Ordering::SeqCst),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+impl Drop for Mutex {
+    fn drop(&mut self) {
+        assert_eq!(*self.state.get_mut(), UNLOCKED, "Dropped a held mutex");
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_sync_mutex() {
+    use alloc::sync::Arc;
+    use core::ptr;
+
+    use crate::scheduler::SmpScheduler;
+    use crate::stack::DEFAULT_STACK_SIZE_BYTES;
+    use crate::tls2::SchedulerControlBlock;
+
+    let _r = env_logger::try_init();
+
+    let s: SmpScheduler = Default::default();
+    let mtx = Arc::new(Mutex::new_kmutex());
+    let m1: Arc<Mutex> = mtx.clone();
+    let m2: Arc<Mutex> = mtx.clone();
+
+    s.spawn(
+        DEFAULT_STACK_SIZE_BYTES,
+        move |_| {
+            assert!(m2.try_enter());
+            Environment::thread().relinquish();
+            m2.exit();
+        },
+        ptr::null_mut(),
+        0,
+        None,
+    );
+
+    s.spawn(
+        DEFAULT_STACK_SIZE_BYTES,
+        move |_| {
+            assert!(!m1.try_enter());
+            // The holder runs on our core, this must not spin forever
+            m1.enter();
+            m1.exit();
+        },
+        ptr::null_mut(),
+        0,
+        None,
+    );
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    s.run(&scb);
+    assert!(mtx.try_enter());
+    mtx.exit();
+}
+
+#[cfg(test)]
+#[test]
+fn test_sync_mutex_smp() {
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::cell::UnsafeCell;
+    use core::ptr;
+    use std::thread;
+
+    use rawtime::Instant;
+
+    use crate::scheduler::SmpScheduler;
+    use crate::stack::DEFAULT_STACK_SIZE_BYTES;
+    use crate::tls2::SchedulerControlBlock;
+
+    struct UnsafeSyncCell<T> {
+        inner: UnsafeCell<T>,
+    }
+    unsafe impl<T: Send> Sync for UnsafeSyncCell<T> {}
+
+    let _r = env_logger::try_init();
+
+    let corecnt = 4;
+    let threads = 8;
+    let s: Arc<SmpScheduler> = Default::default();
+    let mtx = Arc::new(Mutex::new());
+    let counter = Arc::new(UnsafeSyncCell {
+        inner: UnsafeCell::new(0usize),
+    });
+
+    for idx in 0..threads {
+        let mtx = mtx.clone();
+        let counter = counter.clone();
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                for _i in 0..1000 {
+                    mtx.enter();
+                    unsafe { *counter.inner.get() += 1 };
+                    mtx.exit();
+                }
+            },
+            ptr::null_mut(),
+            idx % corecnt,
+            None,
+        );
+    }
+
+    let mut cores = Vec::with_capacity(corecnt);
+    for idx in 0..corecnt {
+        let s1 = s.clone();
+        cores.push(thread::spawn(move || {
+            let scb: SchedulerControlBlock = SchedulerControlBlock::new(idx);
+            let start = Instant::now();
+            while start.elapsed().as_secs() < 1 {
+                s1.run(&scb);
+            }
+        }));
+    }
+    for c in cores {
+        let _r = c.join().unwrap();
+    }
+
+    assert_eq!(unsafe { *counter.inner.get() }, threads * 1000);
+}
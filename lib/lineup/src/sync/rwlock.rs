@@ -0,0 +1,213 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A reader-writer lock that sleeps in the kernel while it waits.
+
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use crossbeam_utils::CachePadded;
+
+pub use crate::rwlock::RwLockIntent;
+
+/// Set in `state` if a writer holds the lock (the other bits count the
+/// readers).
+const WRITER: u32 = 1 << 31;
+
+#[derive(Debug)]
+pub struct RwLock {
+    state: CachePadded<AtomicU32>,
+    /// Threads waiting for the lock.
+    waiters: AtomicUsize,
+}
+
+impl RwLock {
+    pub fn new() -> RwLock {
+        RwLock {
+            state: CachePadded::new(AtomicU32::new(0)),
+            waiters: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn enter(&self, intent: RwLockIntent) {
+        while !self.try_enter(intent) {
+            self.waiters.fetch_add(1, Ordering::Relaxed);
+            let state = self.state.load(Ordering::Acquire);
+            if !Self::available(state, intent) {
+                super::park(&self.state, state, None);
+            }
+            self.waiters.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn try_enter(&self, intent: RwLockIntent) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        while Self::available(state, intent) {
+            let new = match intent {
+                RwLockIntent::Read => state + 1,
+                RwLockIntent::Write => WRITER,
+            };
+            match self
+                .state
+                .compare_exchange_weak(state, new, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => return true,
+                Err(current) => state = current,
+            }
+        }
+        false
+    }
+
+    /// Turns our read lock into a write lock if we're the only reader.
+    pub fn try_upgrade(&self) -> bool {
+        self.state
+            .compare_exchange(1, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Turns our write lock into a read lock.
+    pub fn downgrade(&self) {
+        let prev = self.state.swap(1, Ordering::Release);
+        assert_eq!(prev, WRITER, "Downgraded a lock we don't write");
+        // Other readers can come in now
+        self.wake_waiters();
+    }
+
+    pub fn exit(&self) {
+        let state = self.state.load(Ordering::Relaxed);
+        assert!(state != 0, "Called exit on a free rwlock={:p}", self);
+
+        let now_free = if state == WRITER {
+            self.state.store(0, Ordering::Release);
+            true
+        } else {
+            self.state.fetch_sub(1, Ordering::Release) == 1
+        };
+        if now_free {
+            self.wake_waiters();
+        }
+    }
+
+    pub fn held(&self, intent: RwLockIntent) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        match intent {
+            RwLockIntent::Read => state & !WRITER > 0,
+            RwLockIntent::Write => state & WRITER != 0,
+        }
+    }
+
+    fn available(state: u32, intent: RwLockIntent) -> bool {
+        match intent {
+            RwLockIntent::Read => state & WRITER == 0,
+            RwLockIntent::Write => state == 0,
+        }
+    }
+
+    fn wake_waiters(&self) {
+        if self.waiters.load(Ordering::Relaxed) > 0 {
+            super::unpark(&self.state, usize::MAX);
+        }
+    }
+}
+
+impl Default for RwLock {
+    fn default() -> Self {
+        RwLock::new()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_sync_rwlock() {
+    let _r = env_logger::try_init();
+
+    let rw = RwLock::new();
+    assert!(rw.try_enter(RwLockIntent::Read));
+    assert!(rw.try_enter(RwLockIntent::Read));
+    assert!(rw.held(RwLockIntent::Read));
+    assert!(!rw.try_enter(RwLockIntent::Write));
+    assert!(!rw.try_upgrade());
+
+    rw.exit();
+    assert!(rw.try_upgrade());
+    assert!(rw.held(RwLockIntent::Write));
+    assert!(!rw.held(RwLockIntent::Read));
+    assert!(!rw.try_enter(RwLockIntent::Read));
+
+    rw.downgrade();
+    assert!(rw.try_enter(RwLockIntent::Read));
+    rw.exit();
+    rw.exit();
+    assert!(!rw.held(RwLockIntent::Read));
+    assert!(!rw.held(RwLockIntent::Write));
+}
+
+#[cfg(test)]
+#[test]
+fn test_sync_rwlock_smp() {
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::cell::UnsafeCell;
+    use core::ptr;
+    use std::thread;
+
+    use rawtime::Instant;
+
+    use crate::scheduler::SmpScheduler;
+    use crate::stack::DEFAULT_STACK_SIZE_BYTES;
+    use crate::tls2::SchedulerControlBlock;
+
+    struct UnsafeSyncCell<T> {
+        inner: UnsafeCell<T>,
+    }
+    unsafe impl<T: Send> Sync for UnsafeSyncCell<T> {}
+
+    let _r = env_logger::try_init();
+
+    let corecnt = 4;
+    let threads = 8;
+    let s: Arc<SmpScheduler> = Default::default();
+    let rw = Arc::new(RwLock::new());
+    let counter = Arc::new(UnsafeSyncCell {
+        inner: UnsafeCell::new(0usize),
+    });
+
+    for idx in 0..threads {
+        let rw = rw.clone();
+        let counter = counter.clone();
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                for i in 0..1000 {
+                    if i % 4 == 0 {
+                        rw.enter(RwLockIntent::Write);
+                        unsafe { *counter.inner.get() += 1 };
+                    } else {
+                        rw.enter(RwLockIntent::Read);
+                        let _v = unsafe { *counter.inner.get() };
+                    }
+                    rw.exit();
+                }
+            },
+            ptr::null_mut(),
+            idx % corecnt,
+            None,
+        );
+    }
+
+    let mut cores = Vec::with_capacity(corecnt);
+    for idx in 0..corecnt {
+        let s1 = s.clone();
+        cores.push(thread::spawn(move || {
+            let scb: SchedulerControlBlock = SchedulerControlBlock::new(idx);
+            let start = Instant::now();
+            while start.elapsed().as_secs() < 1 {
+                s1.run(&scb);
+            }
+        }));
+    }
+    for c in cores {
+        let _r = c.join().unwrap();
+    }
+
+    assert_eq!(unsafe { *counter.inner.get() }, threads * 250);
+}
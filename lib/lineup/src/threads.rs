@@ -1,15 +1,19 @@
 // Copyright © 2021 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
+use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::any::Any;
 use core::hash::{Hash, Hasher};
+use core::sync::atomic::Ordering;
 use core::{fmt, mem, ptr};
 
 use fringe::generator::{Generator, Yielder};
 use rawtime::Instant;
 
 use crate::stack::LineupStack;
-use crate::tls2::{self, ThreadControlBlock};
+use crate::tls2::{self, Environment, ThreadControlBlock};
 use crate::upcalls::Upcalls;
 use crate::{CoreId, IrqVector};
 
@@ -33,6 +37,132 @@ impl fmt::Display for ThreadId {
     }
 }
 
+/// What a thread ended with: the value its closure returned or what it
+/// panicked with.
+pub type Result<T> = core::result::Result<T, Box<dyn Any + Send + 'static>>;
+
+/// Where a thread leaves its `Result` for the `JoinHandle`.
+pub(crate) type Packet<T> = Arc<spin::Mutex<Option<Result<T>>>>;
+
+/// Gets the payload of a thread that panicked without unwinding (see
+/// `ThreadControlBlock::abandon`).
+pub(crate) type PanicHook = Box<dyn FnOnce(Box<dyn Any + Send + 'static>) + Send>;
+
+/// Lets us wait for a thread to finish and get its result (see
+/// `SmpScheduler::spawn`).
+///
+/// Dropping the handle detaches the thread.
+pub struct JoinHandle<T> {
+    tid: ThreadId,
+    result: Packet<T>,
+}
+
+impl<T> JoinHandle<T> {
+    pub(crate) fn new(tid: ThreadId, result: Packet<T>) -> Self {
+        JoinHandle { tid, result }
+    }
+
+    pub fn thread_id(&self) -> ThreadId {
+        self.tid
+    }
+
+    /// Did the thread finish already?
+    pub fn is_finished(&self) -> bool {
+        self.result.lock().is_some()
+    }
+
+    /// Waits until the thread finished, returns what its closure returned
+    /// (or `Err` with the panic payload if it panicked).
+    ///
+    /// Has to be called from a lineup thread (unless `is_finished`), it
+    /// yields to the scheduler until the thread is done.
+    pub fn join(self) -> Result<T> {
+        if !self.is_finished() {
+            Environment::thread().join(self.tid);
+        }
+        self.result
+            .lock()
+            .take()
+            .expect("Thread finished without a result?")
+    }
+}
+
+/// Runs `f` and catches it if it panics.
+///
+/// Only tests can unwind, everywhere else a panic either ends the process
+/// or the thread gets abandoned (and its `PanicHook` gets the payload).
+#[cfg(test)]
+pub(crate) fn catch_unwind<T, F: FnOnce() -> T>(f: F) -> Result<T> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f))
+}
+
+#[cfg(not(test))]
+pub(crate) fn catch_unwind<T, F: FnOnce() -> T>(f: F) -> Result<T> {
+    Ok(f())
+}
+
+/// How urgently a thread wants to run.
+///
+/// A core always runs a runnable thread of the highest priority next,
+/// threads of the same priority take turns. Scheduling is cooperative: a
+/// thread that becomes runnable waits for the running thread to yield even
+/// if it has a higher priority.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Priority {
+    Low = 0,
+    Normal = 1,
+    High = 2,
+    /// Threads that handle an interrupt (see `SmpScheduler::spawn` with an
+    /// `IrqVector`).
+    Interrupt = 3,
+}
+
+impl Priority {
+    /// Number of priorities.
+    pub(crate) const COUNT: usize = 4;
+
+    pub(crate) fn from_index(idx: usize) -> Priority {
+        match idx {
+            0 => Priority::Low,
+            1 => Priority::Normal,
+            2 => Priority::High,
+            3 => Priority::Interrupt,
+            _ => unreachable!("Invalid priority {}", idx),
+        }
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+/// The cores a thread may run on (see `SmpScheduler::set_work_stealing`).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AffinityMask(u128);
+
+impl AffinityMask {
+    /// Any core.
+    pub const fn all() -> AffinityMask {
+        AffinityMask(u128::MAX)
+    }
+
+    /// Only `core`.
+    pub const fn only(core: CoreId) -> AffinityMask {
+        AffinityMask(1 << core)
+    }
+
+    /// Also allows `core`.
+    pub const fn with(self, core: CoreId) -> AffinityMask {
+        AffinityMask(self.0 | 1 << core)
+    }
+
+    pub const fn contains(&self, core: CoreId) -> bool {
+        core < 128 && self.0 & (1 << core) != 0
+    }
+}
+
 pub(crate) struct Thread {
     /// Thread ID
     pub(crate) id: ThreadId,
@@ -40,6 +170,16 @@ pub(crate) struct Thread {
     /// Current core affinity of the thread.
     pub(crate) affinity: CoreId,
 
+    /// Cores that may steal the thread from `affinity`.
+    pub(crate) allowed: AffinityMask,
+
+    /// Priority the thread was given.
+    pub(crate) priority: Priority,
+
+    /// Priorities lent to us by threads that wait for a lock we hold (with
+    /// the address of the lock, see `sync::Mutex`).
+    pub(crate) inherited: Vec<(usize, Priority)>,
+
     /// Storage area for resume result (is thread was put in waiting list).
     pub(crate) return_with: Option<YieldResume>,
 
@@ -49,6 +189,12 @@ pub(crate) struct Thread {
     /// Threads currently waiting (join, blocked) on us to exit.
     pub(crate) joinlist: Vec<(ThreadId, CoreId)>,
 
+    /// The thread we wait for in a join with a time-out.
+    pub(crate) joins: Option<ThreadId>,
+
+    /// Called if the thread gets abandoned after a panic.
+    pub(crate) on_panic: Option<PanicHook>,
+
     /// Storage to remember the pointer to the TCB
     ///
     /// TODO(correctness): It's not really static (it's on the thread's stack),
@@ -77,6 +223,14 @@ impl Hash for Thread {
 }
 
 impl Thread {
+    /// The priority we run with: our own or the highest one we inherited.
+    pub(crate) fn effective_priority(&self) -> Priority {
+        self.inherited
+            .iter()
+            .map(|&(_lock, priority)| priority)
+            .fold(self.priority, Priority::max)
+    }
+
     pub(crate) unsafe fn new<'a, F>(
         tid: ThreadId,
         affinity: CoreId,
@@ -101,9 +255,23 @@ impl Thread {
         let thread = Thread {
             id: tid,
             affinity,
+            // Interrupt threads stay where the interrupt arrives
+            allowed: if _interrupt_vector.is_some() {
+                AffinityMask::only(affinity)
+            } else {
+                AffinityMask::all()
+            },
+            priority: if _interrupt_vector.is_some() {
+                Priority::Interrupt
+            } else {
+                Priority::Normal
+            },
+            inherited: Vec::new(),
             return_with: None,
             _interrupt_vector,
             joinlist: Vec::with_capacity(crate::scheduler::SmpScheduler::MAX_THREADS),
+            joins: None,
+            on_panic: None,
             state: tcb,
         };
 
@@ -113,9 +281,17 @@ impl Thread {
                 &'static Yielder<YieldResume, YieldRequest>,
             >(yielder));
 
+            // We're on our own stack now (see `preempt`)
+            tls2::Environment::thread()
+                .running
+                .store(true, Ordering::Release);
+
             // rump lwp switchproc stuff here
             let r = f(arg);
 
+            tls2::Environment::thread()
+                .running
+                .store(false, Ordering::Release);
             // Reset TCB/TLS once thread completes
             tls2::arch::set_tcb(ptr::null_mut());
 
@@ -143,8 +319,9 @@ pub(crate) enum YieldRequest {
     Unrunnable(ThreadId),
     /// Make everything in the given list runnable.
     RunnableList(Vec<ThreadId>),
-    /// Wait until the thread with given ID is finished.
-    JoinOn(ThreadId),
+    /// Wait until the thread with given ID is finished (or until we reach
+    /// Instant).
+    JoinOn(ThreadId, Option<Instant>),
     /// Spawn a new thread that runs the provided function and argument.
     Spawn(
         Option<unsafe extern "C" fn(arg1: *mut u8) -> *mut u8>,
@@ -161,6 +338,17 @@ pub(crate) enum YieldRequest {
         Option<IrqVector>,
         *mut ThreadControlBlock<'static>,
     ),
+    /// Change the priority of the thread with given ID.
+    SetPriority(ThreadId, Priority),
+    /// Lend our priority to the thread with given ID, it holds the lock
+    /// (at the given address) we wait for.
+    Inherit(ThreadId, usize),
+    /// Take back the priority the thread with given ID inherited for the
+    /// lock at the given address.
+    Disinherit(ThreadId, usize),
+    /// The thread panicked (with the payload), throw it away without
+    /// resuming it.
+    Panicked(*mut (dyn Any + Send + 'static)),
 }
 
 /// Corresponding response to a thread after we yielded back to
@@ -171,6 +359,9 @@ pub(crate) enum YieldResume {
     Completed,
     /// The thread was done (and is resumed now after a context switch).
     Interrupted,
+    /// The thread waited until its time-out (and is resumed now after a
+    /// context switch).
+    TimedOut,
     /// A child thread was spawned with the given ThreadId.
     Spawned(ThreadId),
     /// Thread has completed (and has been removed from the scheduler state)
@@ -0,0 +1,179 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A timer wheel that keeps track of the threads sleeping on a core.
+//!
+//! A thread goes into the slot of the tick its deadline falls in (modulo
+//! `SLOTS`), so inserting and removing it doesn't depend on how many other
+//! threads sleep. Deadlines more than one turn of the wheel away share the
+//! slot with nearer ones, `expire` leaves them there until they're due.
+
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use rawtime::Instant;
+
+use crate::threads::ThreadId;
+
+/// How long one slot of the wheel lasts.
+const TICK: Duration = Duration::from_millis(1);
+
+/// Number of slots in the wheel.
+const SLOTS: usize = 128;
+
+pub(crate) struct TimerWheel {
+    /// The threads (with their deadline) in every slot.
+    slots: Vec<Vec<(Instant, ThreadId)>>,
+    /// The slot of every thread in the wheel.
+    index: hashbrown::HashMap<ThreadId, usize>,
+    /// The first tick `expire` hasn't fully handled yet.
+    current: u64,
+}
+
+impl TimerWheel {
+    pub(crate) fn new() -> Self {
+        let mut slots = Vec::with_capacity(SLOTS);
+        slots.resize_with(SLOTS, Vec::new);
+        TimerWheel {
+            slots,
+            index: hashbrown::HashMap::new(),
+            current: 0,
+        }
+    }
+
+    fn tick(at: Instant) -> u64 {
+        (at.duration_since(Instant::from_nanos(0)).as_nanos() / TICK.as_nanos()) as u64
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub(crate) fn contains(&self, tid: ThreadId) -> bool {
+        self.index.contains_key(&tid)
+    }
+
+    /// Adds `tid`, it expires once `until` passed.
+    ///
+    /// Deadlines in the past go into the current slot (they expire with the
+    /// next call to `expire`).
+    pub(crate) fn insert(&mut self, tid: ThreadId, until: Instant) {
+        assert!(!self.contains(tid), "Thread already in waitlist?");
+        let slot = (TimerWheel::tick(until).max(self.current) % SLOTS as u64) as usize;
+        self.slots[slot].push((until, tid));
+        self.index.insert(tid, slot);
+    }
+
+    /// Removes `tid`, returns false if it wasn't in the wheel (anymore).
+    pub(crate) fn remove(&mut self, tid: ThreadId) -> bool {
+        match self.index.remove(&tid) {
+            Some(slot) => {
+                self.slots[slot].retain(|&(_until, wtid)| wtid != tid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes all threads whose deadline is before `now`, returns them
+    /// (earliest deadline first).
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<ThreadId> {
+        let now_tick = TimerWheel::tick(now);
+        if self.is_empty() {
+            self.current = now_tick;
+            return Vec::new();
+        }
+
+        // We only have to look at each slot once, even if we're late
+        let last = now_tick.min(self.current + SLOTS as u64 - 1);
+        let mut expired = Vec::new();
+        for tick in self.current..=last {
+            let slot = &mut self.slots[(tick % SLOTS as u64) as usize];
+            let mut idx = 0;
+            while idx < slot.len() {
+                if slot[idx].0 <= now {
+                    expired.push(slot.swap_remove(idx));
+                } else {
+                    idx += 1;
+                }
+            }
+        }
+        // Threads in the slot of `now_tick` may not be due yet, so look at
+        // it again next time
+        self.current = self.current.max(now_tick);
+
+        expired.sort_unstable();
+        for (_until, tid) in expired.iter() {
+            self.index.remove(tid);
+        }
+        expired.into_iter().map(|(_until, tid)| tid).collect()
+    }
+
+    /// The earliest deadline in the wheel.
+    pub(crate) fn next_expiry(&self) -> Option<Instant> {
+        self.slots
+            .iter()
+            .flat_map(|slot| slot.iter().map(|&(until, _tid)| until))
+            .min()
+    }
+
+    /// Removes all threads (with their deadline).
+    pub(crate) fn drain(&mut self) -> Vec<(Instant, ThreadId)> {
+        self.index.clear();
+        self.slots
+            .iter_mut()
+            .flat_map(|slot| slot.drain(..))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn timer_wheel_expires_in_order() {
+    let mut wheel = TimerWheel::new();
+    let now = Instant::now();
+
+    // Insertion order doesn't matter, neither do multiple turns of the wheel
+    wheel.insert(ThreadId(3), now + Duration::from_millis(3));
+    wheel.insert(ThreadId(1), now);
+    wheel.insert(ThreadId(4), now + TICK * (SLOTS as u32 + 3));
+    wheel.insert(ThreadId(2), now + Duration::from_millis(2));
+    assert_eq!(wheel.len(), 4);
+    assert_eq!(wheel.next_expiry(), Some(now));
+
+    assert_eq!(wheel.expire(now), [ThreadId(1)]);
+    assert_eq!(
+        wheel.expire(now + Duration::from_millis(10)),
+        [ThreadId(2), ThreadId(3)]
+    );
+    assert!(wheel.contains(ThreadId(4)));
+    assert!(wheel.expire(now + TICK * SLOTS as u32).is_empty());
+    assert_eq!(wheel.expire(now + TICK * (SLOTS as u32 + 3)), [ThreadId(4)]);
+    assert!(wheel.is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn timer_wheel_remove_and_drain() {
+    let mut wheel = TimerWheel::new();
+    let now = Instant::now();
+
+    wheel.insert(ThreadId(1), now + Duration::from_millis(1));
+    wheel.insert(ThreadId(2), now + Duration::from_secs(60));
+    // Already due, expires with the next call
+    wheel.insert(ThreadId(3), Instant::from_nanos(0));
+
+    assert!(wheel.remove(ThreadId(1)));
+    assert!(!wheel.remove(ThreadId(1)));
+    assert_eq!(wheel.expire(now + Duration::from_millis(5)), [ThreadId(3)]);
+    assert_eq!(
+        wheel.drain(),
+        [(now + Duration::from_secs(60), ThreadId(2))]
+    );
+    assert!(wheel.is_empty());
+    assert_eq!(wheel.next_expiry(), None);
+}
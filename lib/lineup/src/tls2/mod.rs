@@ -15,30 +15,33 @@
 //! The spec: https://www.uclibc.org/docs/tls.pdf
 //! A random blog post: https://chao-tic.github.io/blog/2018/12/25/tls#introduction
 
+use alloc::boxed::Box;
 use alloc::vec::Vec;
 
+use core::any::Any;
 use core::ops::Add;
-use core::sync::atomic::{AtomicPtr, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
 use core::{mem, ptr};
 
 use fringe::generator::Yielder;
 
-use crossbeam_queue::ArrayQueue;
 use rawtime::{Duration, Instant};
 
 use crate::stack::LineupStack;
-use crate::threads::{ThreadId, YieldRequest, YieldResume};
+use crate::threads::{Priority, ThreadId, YieldRequest, YieldResume};
 use crate::upcalls::Upcalls;
 use crate::{CoreId, IrqVector};
 
-#[cfg(target_os = "nrk")]
+// Programs for the `x86_64-nrk-std` target are NetBSD programs as far as
+// `std` is concerned (`target_os = "netbsd"`), but they still run on nrk.
+#[cfg(any(target_os = "nrk", target_vendor = "nrk"))]
 pub mod nrk;
-#[cfg(target_os = "nrk")]
+#[cfg(any(target_os = "nrk", target_vendor = "nrk"))]
 pub use crate::tls2::nrk as arch;
 
-#[cfg(target_family = "unix")]
+#[cfg(all(target_family = "unix", not(target_vendor = "nrk")))]
 pub mod unix;
-#[cfg(target_family = "unix")]
+#[cfg(all(target_family = "unix", not(target_vendor = "nrk")))]
 pub use crate::tls2::unix as arch;
 
 use kpi::KERNEL_BASE;
@@ -91,6 +94,11 @@ pub struct ThreadControlBlock<'a> {
 
     /// The current errno variable (for libc compatibility).
     pub errno: i32,
+
+    /// Set while the thread runs on its own stack (see `preempt`).
+    pub(crate) running: AtomicBool,
+    /// How many `preempt::Guard`s the thread holds.
+    pub(crate) preempt_disabled: AtomicUsize,
 }
 
 impl<'a> ThreadControlBlock<'a> {
@@ -106,6 +114,8 @@ impl<'a> ThreadControlBlock<'a> {
             upcalls: Default::default(),
             rump_lwp: AtomicPtr::new(ptr::null_mut()),
             rumprun_lwp: ptr::null_mut(),
+            running: AtomicBool::new(false),
+            preempt_disabled: AtomicUsize::new(0),
         };
 
         let (initial_tdata, tls_layout) = arch::get_tls_info();
@@ -139,6 +149,33 @@ impl<'a> ThreadControlBlock<'a> {
         self.yielder.unwrap()
     }
 
+    /// Hands `request` to the scheduler, returns once it resumes us.
+    ///
+    /// We're not `running` in between: the scheduler runs and our stack is
+    /// only half switched at the beginning and the end.
+    fn switch(&self, request: YieldRequest) -> YieldResume {
+        self.running.store(false, Ordering::Release);
+        let resume = self.yielder().suspend(request);
+        self.running.store(true, Ordering::Release);
+        resume
+    }
+
+    /// Does the thread run under a scheduler (it can yield)?
+    pub fn has_yielder(&self) -> bool {
+        self.yielder.is_some()
+    }
+
+    /// Ends the thread after it panicked (with `payload`), the scheduler
+    /// hands `payload` to the thread's `JoinHandle`.
+    ///
+    /// Nothing on the thread's stack gets dropped (its memory is freed) and
+    /// locks the thread holds stay locked.
+    pub fn abandon(&self, payload: Box<dyn Any + Send + 'static>) -> ! {
+        let request = YieldRequest::Panicked(Box::into_raw(payload));
+        self.switch(request);
+        unreachable!("Resumed a thread we abandoned");
+    }
+
     pub fn set_lwp(&mut self, lwp_ptr: *mut u64) {
         self.rump_lwp.store(lwp_ptr, Ordering::SeqCst);
     }
@@ -153,7 +190,7 @@ impl<'a> ThreadControlBlock<'a> {
         tcb: *mut ThreadControlBlock<'static>,
     ) -> Option<ThreadId> {
         let request = YieldRequest::SpawnWithArgs(s, f, arg, core_id, irq_vector, tcb);
-        match self.yielder().suspend(request) {
+        match self.switch(request) {
             YieldResume::Spawned(tid) => Some(tid),
             _ => None,
         }
@@ -166,7 +203,7 @@ impl<'a> ThreadControlBlock<'a> {
         core_id: CoreId,
     ) -> Option<ThreadId> {
         let request = YieldRequest::Spawn(f, arg, core_id, None);
-        match self.yielder().suspend(request) {
+        match self.switch(request) {
             YieldResume::Spawned(tid) => Some(tid),
             _ => None,
         }
@@ -180,7 +217,7 @@ impl<'a> ThreadControlBlock<'a> {
         irq_vector: IrqVector,
     ) -> Option<ThreadId> {
         let request = YieldRequest::Spawn(f, arg, core_id, Some(irq_vector));
-        match self.yielder().suspend(request) {
+        match self.switch(request) {
             YieldResume::Spawned(tid) => Some(tid),
             _ => None,
         }
@@ -192,7 +229,7 @@ impl<'a> ThreadControlBlock<'a> {
         arg: *mut u8,
     ) -> Option<ThreadId> {
         let request = YieldRequest::Spawn(f, arg, self.current_core, None);
-        match self.yielder().suspend(request) {
+        match self.switch(request) {
             YieldResume::Spawned(tid) => Some(tid),
             _ => None,
         }
@@ -200,36 +237,70 @@ impl<'a> ThreadControlBlock<'a> {
 
     pub fn sleep(&self, d: Duration) {
         let request = YieldRequest::Timeout(Instant::now().add(d));
-        self.yielder().suspend(request);
+        self.switch(request);
     }
 
     pub fn block(&self) {
         let request = YieldRequest::Unrunnable(Environment::tid());
-        self.yielder().suspend(request);
+        self.switch(request);
+    }
+
+    /// Blocks until another thread makes us runnable or `d` passed.
+    ///
+    /// Returns false on time-out.
+    pub fn block_timeout(&self, d: Duration) -> bool {
+        let request = YieldRequest::Timeout(Instant::now().add(d));
+        self.switch(request) != YieldResume::TimedOut
     }
 
     pub fn make_runnable(&self, tid: ThreadId) {
         let request = YieldRequest::Runnable(tid);
-        self.yielder().suspend(request);
+        self.switch(request);
     }
 
     pub fn make_all_runnable(&self, tids: Vec<ThreadId>) {
         let request = YieldRequest::RunnableList(tids);
-        self.yielder().suspend(request);
+        self.switch(request);
     }
 
     pub fn make_unrunnable(&self, tid: ThreadId) {
         let request = YieldRequest::Unrunnable(tid);
-        self.yielder().suspend(request);
+        self.switch(request);
     }
 
     pub fn join(&self, tid: ThreadId) {
-        let request = YieldRequest::JoinOn(tid);
-        self.yielder().suspend(request);
+        let request = YieldRequest::JoinOn(tid, None);
+        self.switch(request);
+    }
+
+    /// Waits until `tid` is finished or `d` passed.
+    ///
+    /// Returns false on time-out.
+    pub fn join_timeout(&self, tid: ThreadId, d: Duration) -> bool {
+        let request = YieldRequest::JoinOn(tid, Some(Instant::now().add(d)));
+        self.switch(request) != YieldResume::TimedOut
+    }
+
+    /// Changes the priority of `tid` (see `Priority`).
+    pub fn set_priority(&self, tid: ThreadId, priority: Priority) {
+        let request = YieldRequest::SetPriority(tid, priority);
+        self.switch(request);
+    }
+
+    /// Lends our priority to `holder` while we wait for the lock at `lock`.
+    pub(crate) fn inherit(&self, holder: ThreadId, lock: usize) {
+        let request = YieldRequest::Inherit(holder, lock);
+        self.switch(request);
+    }
+
+    /// Takes back the priority `holder` inherited for the lock at `lock`.
+    pub(crate) fn disinherit(&self, holder: ThreadId, lock: usize) {
+        let request = YieldRequest::Disinherit(holder, lock);
+        self.switch(request);
     }
 
     pub(crate) fn suspend(&self, request: YieldRequest) {
-        self.yielder().suspend(request);
+        self.switch(request);
     }
 
     pub fn relinquish(&self) {
@@ -246,17 +317,22 @@ impl<'a> ThreadControlBlock<'a> {
 /// It's allocated and lives as part of the scheduler struct.
 #[derive(Debug)]
 pub struct SchedulerControlBlock {
-    /// Used by an upcall handler to raise an IRQ.
+    /// Vectors (one bit each) raised by an upcall handler that `run`
+    /// hasn't dispatched yet (see `SmpScheduler::raise`).
     ///
     /// We can't just update the scheduler state directly because
     /// someone might hold a spinlock on the runlists while being interrupted.
-    pub pending_irqs: ArrayQueue<u64>,
+    pending_upcalls: [AtomicU64; 4],
 
     /// Specific to a pointer of of upcall handlers set by the rumpkernel
     pub rump_upcalls: AtomicPtr<u64>,
 
     /// Core identifier of this scheduler state
     pub core_id: usize,
+
+    /// Set by an upcall handler once the core has to be given back,
+    /// `run()` returns as soon as it sees this.
+    pub revoked: AtomicBool,
 }
 
 impl SchedulerControlBlock {
@@ -264,14 +340,43 @@ impl SchedulerControlBlock {
     /// and no upcall handler is set.
     pub fn new(core_id: CoreId) -> Self {
         SchedulerControlBlock {
-            pending_irqs: ArrayQueue::new(4),
+            pending_upcalls: Default::default(),
             rump_upcalls: AtomicPtr::new(ptr::null_mut()),
             core_id,
+            revoked: AtomicBool::new(false),
         }
     }
 }
 
 impl SchedulerControlBlock {
+    /// Remembers that `vector` came in (it's raised only once until `run`
+    /// dispatches it).
+    pub(crate) fn mark_pending(&self, vector: IrqVector) {
+        let (word, bit) = ((vector / 64) as usize, vector % 64);
+        self.pending_upcalls[word].fetch_or(1 << bit, Ordering::AcqRel);
+    }
+
+    /// Takes the vectors that came in.
+    pub(crate) fn take_pending(&self) -> Vec<IrqVector> {
+        let mut vectors = Vec::new();
+        for (word, pending) in self.pending_upcalls.iter().enumerate() {
+            let mut bits = pending.swap(0, Ordering::AcqRel);
+            while bits != 0 {
+                let bit = bits.trailing_zeros() as u64;
+                vectors.push(word as u64 * 64 + bit);
+                bits &= bits - 1;
+            }
+        }
+        vectors
+    }
+
+    /// Did an upcall handler raise a vector `run` hasn't dispatched yet?
+    pub fn has_pending_upcalls(&self) -> bool {
+        self.pending_upcalls
+            .iter()
+            .any(|pending| pending.load(Ordering::Acquire) != 0)
+    }
+
     pub unsafe fn preinstall(&self) {
         arch::set_scb(self as *const SchedulerControlBlock);
     }
@@ -292,7 +397,7 @@ impl SchedulerControlBlock {
 pub struct Environment {}
 
 impl Environment {
-    #[cfg(target_os = "nrk")]
+    #[cfg(any(target_os = "nrk", target_vendor = "nrk"))]
     pub fn tid() -> ThreadId {
         unsafe {
             let tcb = x86::current::segmentation::fs_deref() as *const ThreadControlBlock;
@@ -301,7 +406,7 @@ impl Environment {
         }
     }
 
-    #[cfg(target_family = "unix")]
+    #[cfg(all(target_family = "unix", not(target_vendor = "nrk")))]
     pub fn tid() -> ThreadId {
         unsafe {
             let tcb = arch::get_tcb() as *mut ThreadControlBlock;
@@ -310,8 +415,14 @@ impl Environment {
         }
     }
 
+    /// Returns false if we don't run in a lineup thread (e.g., before the
+    /// scheduler runs), `tid` and `thread` panic or fault then.
+    pub fn has_thread() -> bool {
+        unsafe { !arch::get_tcb().is_null() }
+    }
+
     // TODO(correctness): this needs some hardending to avoid aliasing of ThreadState!
-    #[cfg(target_os = "nrk")]
+    #[cfg(any(target_os = "nrk", target_vendor = "nrk"))]
     pub fn thread<'a>() -> &'a mut ThreadControlBlock<'static> {
         unsafe {
             let tcb = x86::current::segmentation::fs_deref() as *mut ThreadControlBlock;
@@ -320,7 +431,7 @@ impl Environment {
         }
     }
 
-    #[cfg(target_family = "unix")]
+    #[cfg(all(target_family = "unix", not(target_vendor = "nrk")))]
     pub fn thread<'a>() -> &'a mut ThreadControlBlock<'static> {
         unsafe {
             let tcb = arch::get_tcb() as *mut ThreadControlBlock;
@@ -2,7 +2,10 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use core::alloc::Layout;
+use core::sync::atomic::AtomicU32;
+use core::time::Duration;
 
+use log::warn;
 use x86::bits64::segmentation;
 
 use super::{SchedulerControlBlock, ThreadControlBlock};
@@ -50,3 +53,17 @@ pub fn get_tls_info() -> (&'static [u8], Layout) {
         (&[], Layout::new::<ThreadControlBlock>())
     }
 }
+
+/// Puts the core to sleep until someone calls `futex_wake` on `word` or
+/// `timeout` passed (if `word` is still `expected`).
+pub(crate) fn futex_wait(word: &AtomicU32, expected: u32, timeout: Duration) {
+    // A changed word or a time-out just means the caller checks again
+    let _r = kpi::syscalls::Process::futex_wait(word, expected, Some(timeout));
+}
+
+/// Wakes up (at most) `count` cores sleeping on `word`.
+pub(crate) fn futex_wake(word: &AtomicU32, count: usize) {
+    if let Err(e) = kpi::syscalls::Process::futex_wake(word, count) {
+        warn!("Can't wake up futex {:p}: {:?}", word, e);
+    }
+}
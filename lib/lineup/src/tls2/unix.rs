@@ -7,6 +7,9 @@
 //! implement it.
 use core::alloc::Layout;
 use core::cell::Cell;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
 use core::{mem, ptr};
 
 use super::{SchedulerControlBlock, ThreadControlBlock};
@@ -37,9 +40,19 @@ pub(crate) unsafe fn set_scb(scb: *const SchedulerControlBlock) {
 }
 
 /// A poor way to estimate the TLS size on unix.
-#[cfg(target_family = "unix")]
+#[cfg(all(target_family = "unix", not(target_vendor = "nrk")))]
 pub fn get_tls_info() -> (&'static [u8], Layout) {
     // We only use this for tests, so we just estimate our TLS size...
     // Ideally we parse the ELF of our process to determine the static TLS size
     (&[], Layout::new::<ThreadControlBlock>())
 }
+
+/// There are no futexes in the tests, we spin instead.
+pub(crate) fn futex_wait(word: &AtomicU32, expected: u32, _timeout: Duration) {
+    if word.load(Ordering::Relaxed) == expected {
+        spin_loop();
+    }
+}
+
+/// Nobody sleeps in `futex_wait`.
+pub(crate) fn futex_wake(_word: &AtomicU32, _count: usize) {}
@@ -7,7 +7,7 @@
 //! In the current form, simply modelled to support rump upcalls.
 //! Should be generalized in the future.
 
-use crate::mutex;
+use crate::sync;
 use core::fmt;
 
 /// Notification up-calls from the scheduler to the application
@@ -15,8 +15,8 @@ use core::fmt;
 #[derive(Clone, Copy)]
 pub struct Upcalls {
     pub curlwp: fn() -> u64,
-    pub schedule: fn(&i32, Option<&mutex::Mutex>),
-    pub deschedule: fn(&mut i32, Option<&mutex::Mutex>),
+    pub schedule: fn(&i32, Option<&sync::Mutex>),
+    pub deschedule: fn(&mut i32, Option<&sync::Mutex>),
     pub context_switch: fn(*mut u8, *mut u8),
 }
 
@@ -46,7 +46,7 @@ fn noop_curlwp() -> u64 {
 }
 
 /// Dummy implementation of unschedule().
-fn noop_unschedule(_nlocks: &mut i32, _mtx: Option<&mutex::Mutex>) {}
+fn noop_unschedule(_nlocks: &mut i32, _mtx: Option<&sync::Mutex>) {}
 
 /// Dummy implementation of schedule().
-fn noop_schedule(_nlocks: &i32, _mtx: Option<&mutex::Mutex>) {}
+fn noop_schedule(_nlocks: &i32, _mtx: Option<&sync::Mutex>) {}
@@ -0,0 +1,175 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Async versions of the file and socket system calls.
+//!
+//! Socket operations that would block wait in the reactor until the socket
+//! is ready. The kernel completes file operations before it returns, so the
+//! file functions don't wait for anything: they're async so services can
+//! use them alongside sockets and swap in a different implementation later.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+use kpi::io::FileModes;
+use kpi::net::{PollEvents, PollFd, SocketAddr, SocketType};
+use kpi::syscalls::{Fs, Net};
+use kpi::SystemCallError;
+
+use super::reactor;
+
+/// Calls `op` until it doesn't return `WouldBlock`, waits for `events` on
+/// `fd` in between.
+struct Io<F> {
+    fd: u64,
+    events: PollEvents,
+    op: F,
+}
+
+impl<T, F> Future for Io<F>
+where
+    F: FnMut() -> Result<T, SystemCallError> + Unpin,
+{
+    type Output = Result<T, SystemCallError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match (self.op)() {
+            Err(SystemCallError::WouldBlock) => {
+                reactor::register_io(self.fd, self.events, cx.waker().clone());
+                Poll::Pending
+            }
+            r => Poll::Ready(r),
+        }
+    }
+}
+
+/// Waits until `fd` has one of `events`, returns the events it has.
+async fn ready(fd: u64, events: PollEvents) -> Result<PollEvents, SystemCallError> {
+    Io {
+        fd,
+        events,
+        op: || {
+            let mut fds = [PollFd::new(fd, events)];
+            Net::poll(&mut fds, Some(Duration::from_secs(0)))?;
+            let revents = fds[0].revents();
+            if revents.is_empty() {
+                Err(SystemCallError::WouldBlock)
+            } else {
+                Ok(revents)
+            }
+        },
+    }
+    .await
+}
+
+/// A socket of the kernel network stack.
+pub struct AsyncSocket {
+    fd: u64,
+}
+
+impl AsyncSocket {
+    pub fn new(ty: SocketType) -> Result<AsyncSocket, SystemCallError> {
+        Net::socket(ty).map(|fd| AsyncSocket { fd })
+    }
+
+    /// A UDP socket bound to `port`.
+    pub fn bind_udp(port: u16) -> Result<AsyncSocket, SystemCallError> {
+        let socket = AsyncSocket::new(SocketType::Udp)?;
+        Net::bind(socket.fd, port)?;
+        Ok(socket)
+    }
+
+    /// Connects to `addr`, for TCP this waits until the connection is
+    /// established.
+    pub async fn connect(ty: SocketType, addr: SocketAddr) -> Result<AsyncSocket, SystemCallError> {
+        let socket = AsyncSocket::new(ty)?;
+        Net::connect(socket.fd, addr)?;
+        if ty == SocketType::Tcp {
+            let events = ready(socket.fd, PollEvents::POLLOUT).await?;
+            if events.intersects(PollEvents::POLLERR | PollEvents::POLLHUP) {
+                let _r = socket.close();
+                return Err(SystemCallError::InternalError);
+            }
+        }
+        Ok(socket)
+    }
+
+    pub fn fd(&self) -> u64 {
+        self.fd
+    }
+
+    pub async fn send(&self, buf: &[u8]) -> Result<usize, SystemCallError> {
+        let fd = self.fd;
+        Io {
+            fd,
+            events: PollEvents::POLLOUT,
+            op: || Net::send(fd, buf),
+        }
+        .await
+    }
+
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize, SystemCallError> {
+        let fd = self.fd;
+        Io {
+            fd,
+            events: PollEvents::POLLOUT,
+            op: || Net::send_to(fd, buf, addr),
+        }
+        .await
+    }
+
+    /// Sends all of `buf`.
+    pub async fn send_all(&self, mut buf: &[u8]) -> Result<(), SystemCallError> {
+        while !buf.is_empty() {
+            let sent = self.send(buf).await?;
+            buf = &buf[sent..];
+        }
+        Ok(())
+    }
+
+    pub async fn recv(&self, buf: &mut [u8]) -> Result<usize, SystemCallError> {
+        self.recv_from(buf).await.map(|(len, _addr)| len)
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), SystemCallError> {
+        let fd = self.fd;
+        Io {
+            fd,
+            events: PollEvents::POLLIN,
+            op: || Net::recv_from(fd, buf),
+        }
+        .await
+    }
+
+    pub fn close(self) -> Result<(), SystemCallError> {
+        Net::close(self.fd)
+    }
+}
+
+/// Opens `path` (has to end with `\0`).
+pub async fn open(path: &str, flags: u64, modes: FileModes) -> Result<u64, SystemCallError> {
+    debug_assert!(path.ends_with('\0'));
+    Fs::open(path.as_ptr() as u64, flags, u64::from(modes))
+}
+
+pub async fn close(fd: u64) -> Result<(), SystemCallError> {
+    Fs::close(fd).map(|_r| ())
+}
+
+pub async fn read(fd: u64, buf: &mut [u8]) -> Result<usize, SystemCallError> {
+    Fs::read(fd, buf.as_mut_ptr() as u64, buf.len() as u64).map(|len| len as usize)
+}
+
+pub async fn write(fd: u64, buf: &[u8]) -> Result<usize, SystemCallError> {
+    Fs::write(fd, buf.as_ptr() as u64, buf.len() as u64).map(|len| len as usize)
+}
+
+pub async fn read_at(fd: u64, buf: &mut [u8], offset: i64) -> Result<usize, SystemCallError> {
+    Fs::read_at(fd, buf.as_mut_ptr() as u64, buf.len() as u64, offset).map(|len| len as usize)
+}
+
+pub async fn write_at(fd: u64, buf: &[u8], offset: i64) -> Result<usize, SystemCallError> {
+    Fs::write_at(fd, buf.as_ptr() as u64, buf.len() as u64, offset).map(|len| len as usize)
+}
@@ -0,0 +1,276 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A small executor for async code.
+//!
+//! Tasks run on the core (and in the lineup thread, if any) that calls
+//! `Executor::block_on`. A task that waits for a socket or a timer leaves
+//! its waker with the `reactor`, which the executor turns whenever it runs
+//! out of tasks: one `Poll` system call checks all sockets at once. If
+//! nothing is ready, the executor sleeps until the next timer is due or an
+//! interrupt arrives (the NIC raises one for new packets). In a lineup
+//! thread it lets the other threads of the core run instead.
+//!
+//! Wakers can be used from anywhere, an upcall handler can wake an
+//! executor with `Notifier::notify`.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use lineup::tls2::Environment;
+use rawtime::Instant;
+
+use crate::syscalls::Process;
+
+pub mod io;
+pub mod reactor;
+
+pub use reactor::{sleep, Sleep};
+
+/// Values of `Shared::idle`.
+const BUSY: u32 = 0;
+const IDLE: u32 = 1;
+
+/// How long an idle executor sleeps at most while it waits for sockets
+/// (the kernel doesn't tell us when they become ready).
+const IO_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How long an idle executor sleeps at most otherwise.
+const IDLE_TIMEOUT: Duration = Duration::from_millis(10);
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// State of an executor its wakers need.
+struct Shared {
+    /// Tasks that were woken up.
+    ready: spin::Mutex<VecDeque<Arc<Task>>>,
+    /// The future passed to `block_on` was woken up.
+    main_woken: AtomicBool,
+    /// `IDLE` while the executor sleeps (in `Process::futex_wait`).
+    idle: AtomicU32,
+}
+
+impl Shared {
+    fn notify(&self) {
+        if self.idle.swap(BUSY, Ordering::AcqRel) == IDLE {
+            let _r = Process::futex_wake(&self.idle, 1);
+        }
+    }
+}
+
+/// Wakes up an executor (e.g., from an upcall handler).
+#[derive(Clone)]
+pub struct Notifier(Arc<Shared>);
+
+impl Notifier {
+    /// Makes the executor check its tasks and the reactor again.
+    pub fn notify(&self) {
+        self.0.main_woken.store(true, Ordering::Release);
+        self.0.notify();
+    }
+}
+
+struct Task {
+    /// `None` once the future completed.
+    future: spin::Mutex<Option<BoxFuture>>,
+    /// Set while the task is in `Shared::ready`.
+    queued: AtomicBool,
+    executor: Arc<Shared>,
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.executor.ready.lock().push_back(self.clone());
+            self.executor.notify();
+        }
+    }
+}
+
+/// Wakes up the future passed to `block_on`.
+struct MainWaker(Arc<Shared>);
+
+impl Wake for MainWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.main_woken.store(true, Ordering::Release);
+        self.0.notify();
+    }
+}
+
+/// What a task returned (and who waits for it).
+struct Output<T> {
+    value: Option<T>,
+    waiter: Option<Waker>,
+}
+
+/// Waits for a task spawned with `Executor::spawn` and returns its output.
+///
+/// Dropping the handle detaches the task.
+pub struct TaskHandle<T> {
+    output: Arc<spin::Mutex<Output<T>>>,
+}
+
+impl<T> Future for TaskHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut output = self.output.lock();
+        match output.value.take() {
+            Some(value) => Poll::Ready(value),
+            None => {
+                output.waiter = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+pub struct Executor {
+    shared: Arc<Shared>,
+}
+
+impl Executor {
+    pub fn new() -> Executor {
+        Executor {
+            shared: Arc::new(Shared {
+                ready: spin::Mutex::new(VecDeque::new()),
+                main_woken: AtomicBool::new(true),
+                idle: AtomicU32::new(BUSY),
+            }),
+        }
+    }
+
+    pub fn notifier(&self) -> Notifier {
+        Notifier(self.shared.clone())
+    }
+
+    /// Adds a task, it runs in `block_on`.
+    pub fn spawn<F, T>(&self, future: F) -> TaskHandle<T>
+    where
+        F: Future<Output = T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let output = Arc::new(spin::Mutex::new(Output {
+            value: None,
+            waiter: None,
+        }));
+        let their_output = output.clone();
+        let future = async move {
+            let value = future.await;
+            let waiter = {
+                let mut output = their_output.lock();
+                output.value = Some(value);
+                output.waiter.take()
+            };
+            if let Some(waiter) = waiter {
+                waiter.wake();
+            }
+        };
+
+        let task = Arc::new(Task {
+            future: spin::Mutex::new(Some(Box::pin(future))),
+            queued: AtomicBool::new(false),
+            executor: self.shared.clone(),
+        });
+        Waker::from(task).wake();
+
+        TaskHandle { output }
+    }
+
+    /// Runs the spawned tasks until `future` completes, returns its output.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let main_waker = Waker::from(Arc::new(MainWaker(self.shared.clone())));
+        let mut main_cx = Context::from_waker(&main_waker);
+
+        loop {
+            if self.shared.main_woken.swap(false, Ordering::AcqRel) {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut main_cx) {
+                    return output;
+                }
+            }
+
+            if !self.run_ready() && !self.shared.main_woken.load(Ordering::Acquire) {
+                self.park();
+            }
+        }
+    }
+
+    /// Polls the tasks that are ready (once), returns false if there were
+    /// none.
+    fn run_ready(&self) -> bool {
+        // Tasks woken up while we run wait for the next round
+        let count = self.shared.ready.lock().len();
+        for _i in 0..count {
+            let task = match self.shared.ready.lock().pop_front() {
+                Some(task) => task,
+                None => break,
+            };
+            task.queued.store(false, Ordering::Release);
+
+            let mut slot = task.future.lock();
+            if let Some(mut future) = slot.take() {
+                let waker = Waker::from(task.clone());
+                let mut cx = Context::from_waker(&waker);
+                if future.as_mut().poll(&mut cx).is_pending() {
+                    *slot = Some(future);
+                }
+            }
+        }
+        count > 0
+    }
+
+    /// Waits until something happens.
+    fn park(&self) {
+        let (woken, next_timer, has_sources) = reactor::turn();
+        if woken > 0 {
+            return;
+        }
+
+        let max = if has_sources {
+            IO_POLL_INTERVAL
+        } else {
+            IDLE_TIMEOUT
+        };
+        let timeout = next_timer.map_or(max, |deadline| {
+            let now = Instant::now();
+            if deadline > now {
+                (deadline - now).min(max)
+            } else {
+                Duration::from_secs(0)
+            }
+        });
+
+        self.shared.idle.store(IDLE, Ordering::Release);
+        if self.shared.ready.lock().is_empty() && !self.shared.main_woken.load(Ordering::Acquire) {
+            if Environment::has_thread() && Environment::thread().has_yielder() {
+                // Sleeping would stop the other threads of the core
+                Environment::thread().relinquish();
+            } else if timeout > Duration::from_secs(0) {
+                let _r = Process::futex_wait(&self.shared.idle, IDLE, Some(timeout));
+            }
+        }
+        self.shared.idle.store(BUSY, Ordering::Release);
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Executor::new()
+    }
+}
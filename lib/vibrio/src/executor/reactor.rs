@@ -0,0 +1,116 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Keeps track of what pending futures wait for: sockets to become ready
+//! and timers to expire.
+
+use alloc::vec::Vec;
+use core::future::Future;
+use core::mem;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use kpi::net::{PollEvents, PollFd};
+use kpi::syscalls::Net;
+use rawtime::Instant;
+
+struct Reactor {
+    /// Sockets (and the events) futures wait for.
+    sources: Vec<(PollFd, Waker)>,
+    /// Deadlines futures wait for.
+    timers: Vec<(Instant, Waker)>,
+}
+
+static REACTOR: spin::Mutex<Reactor> = spin::Mutex::new(Reactor {
+    sources: Vec::new(),
+    timers: Vec::new(),
+});
+
+/// Wakes up `waker` once `fd` has one of `events` (or an error).
+pub(crate) fn register_io(fd: u64, events: PollEvents, waker: Waker) {
+    REACTOR
+        .lock()
+        .sources
+        .push((PollFd::new(fd, events), waker));
+}
+
+/// Wakes up `waker` once `deadline` passed.
+pub(crate) fn register_timer(deadline: Instant, waker: Waker) {
+    REACTOR.lock().timers.push((deadline, waker));
+}
+
+/// Wakes up the futures whose socket is ready or whose deadline passed.
+///
+/// Returns how many were woken up, the next deadline and whether there are
+/// still futures waiting for sockets.
+pub(crate) fn turn() -> (usize, Option<Instant>, bool) {
+    let mut woken = Vec::new();
+    let (next_timer, has_sources) = {
+        let mut reactor = REACTOR.lock();
+
+        if !reactor.sources.is_empty() {
+            let mut fds: Vec<PollFd> = reactor.sources.iter().map(|(fd, _w)| *fd).collect();
+            let sources = mem::take(&mut reactor.sources);
+            match Net::poll(&mut fds, Some(Duration::from_secs(0))) {
+                Ok(_ready) => {
+                    for (fd, (_fd, waker)) in fds.iter().zip(sources.into_iter()) {
+                        if fd.revents != 0 {
+                            woken.push(waker);
+                        } else {
+                            reactor.sources.push((*fd, waker));
+                        }
+                    }
+                }
+                // They'll see the error when they try again
+                Err(_e) => woken.extend(sources.into_iter().map(|(_fd, waker)| waker)),
+            }
+        }
+
+        let now = Instant::now();
+        let mut idx = 0;
+        while idx < reactor.timers.len() {
+            if reactor.timers[idx].0 <= now {
+                woken.push(reactor.timers.swap_remove(idx).1);
+            } else {
+                idx += 1;
+            }
+        }
+
+        (
+            reactor.timers.iter().map(|(deadline, _w)| *deadline).min(),
+            !reactor.sources.is_empty(),
+        )
+    };
+
+    let count = woken.len();
+    for waker in woken {
+        waker.wake();
+    }
+    (count, next_timer, has_sources)
+}
+
+/// Completes once `deadline` passed.
+pub struct Sleep {
+    deadline: Instant,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            Poll::Ready(())
+        } else {
+            register_timer(self.deadline, cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// Completes after `d`.
+pub fn sleep(d: Duration) -> Sleep {
+    Sleep {
+        deadline: Instant::now() + d,
+    }
+}
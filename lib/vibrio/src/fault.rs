@@ -0,0 +1,87 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Handles page faults in parts of the address space in user-space.
+//!
+//! `register_handler` asks the kernel to forward the faults in a range it
+//! can't resolve (`kpi::upcall::PAGE_FAULT`) and calls the handler for
+//! them. A handler typically maps memory at the faulting address (e.g., to
+//! fill in data lazily) and returns true, the access is tried again then.
+//!
+//! Handlers run in the upcall handler: upcalls are disabled and it's not a
+//! lineup thread, they can't block or yield (but they can make system
+//! calls). A page fault in a handler ends the process.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use kpi::arch::{SaveArea, VirtualCpu};
+use kpi::syscalls::VSpace;
+use kpi::upcall::FaultAccess;
+use kpi::SystemCallError;
+
+/// A page fault in a registered range.
+pub struct Fault<'a> {
+    /// The address that was accessed.
+    pub address: u64,
+    /// What the access did.
+    pub access: FaultAccess,
+    /// The registers of the thread that faulted, it continues with them
+    /// once the handler returns.
+    pub context: &'a mut SaveArea,
+}
+
+/// Returns false if it can't resolve the fault (the process ends then).
+pub type FaultHandler = fn(&mut Fault<'_>) -> bool;
+
+/// The registered ranges and their handlers.
+static HANDLERS: spin::Mutex<Vec<(Range<u64>, FaultHandler)>> = spin::Mutex::new(Vec::new());
+
+/// Calls `handler` for the page faults in `range` that the kernel can't
+/// resolve.
+pub fn register_handler(range: Range<u64>, handler: FaultHandler) -> Result<(), SystemCallError> {
+    // The kernel may forward faults as soon as it knows about the range
+    let mut handlers = HANDLERS.lock();
+    VSpace::register_fault_region(range.start, range.end - range.start)?;
+    handlers.push((range, handler));
+    Ok(())
+}
+
+/// Removes the handler `register_handler` added for the range that starts
+/// at `start`, faults there are fatal again.
+pub fn unregister_handler(start: u64) -> Result<(), SystemCallError> {
+    let mut handlers = HANDLERS.lock();
+    VSpace::unregister_fault_region(start)?;
+    handlers.retain(|(range, _handler)| range.start != start);
+    Ok(())
+}
+
+/// Handles a `kpi::upcall::PAGE_FAULT` upcall.
+pub(crate) fn dispatch(control: &mut VirtualCpu) {
+    let fault = control.fault;
+    let address = fault.address;
+    let handler = HANDLERS
+        .lock()
+        .iter()
+        .find(|(range, _handler)| range.contains(&address))
+        .map(|&(_range, handler)| handler);
+
+    // `VirtualCpu` is packed, so we work on a copy of the registers
+    let mut context = control.enabled_state;
+    let resolved = handler.map_or(false, |handler| {
+        handler(&mut Fault {
+            address,
+            access: fault.access(),
+            context: &mut context,
+        })
+    });
+    if !resolved {
+        panic!(
+            "Unhandled page fault at {:#x} ({:?}), rip = {:#x}",
+            address,
+            fault.access(),
+            { context.rip }
+        );
+    }
+    control.enabled_state = context;
+}
@@ -0,0 +1,380 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Communication between processes: doors (see `kpi::ipc`) and message
+//! rings in shared memory.
+//!
+//! A `Ring` is a bounded queue of fixed-size slots in frames that both
+//! processes map. Any number of producers can send, exactly one consumer
+//! receives. Every slot has a sequence number that tells whose turn it is
+//! (the scheme of Vyukov's bounded queue), so producers only contend on
+//! the tail and never wait for each other to finish copying.
+//!
+//! Sending and receiving don't enter the kernel unless the other side
+//! waits: a consumer that finds the ring empty (or a producer that finds it
+//! full) counts itself in the header and waits for a semaphore event, the
+//! other side signals the event for everyone it finds counted. A wakeup can
+//! be spurious, the waiter just checks the ring again.
+
+use core::ptr;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+use arrayvec::ArrayVec;
+use kpi::cap::CapRights;
+use kpi::event::EventFlags;
+use kpi::syscalls::{Capability, Event, PhysicalMemory, VSpace};
+use kpi::SystemCallError;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+pub use kpi::ipc::*;
+
+/// How many (base page) frames a ring can span.
+pub const MAX_RING_FRAMES: usize = 8;
+
+/// Identifies an initialized ring (and the version of its layout).
+const RING_MAGIC: u64 = u64::from_le_bytes(*b"nrkring1");
+
+const CACHE_LINE: usize = 64;
+
+/// A counter on a cache line of its own.
+#[repr(C, align(64))]
+struct Padded(AtomicU64);
+
+/// The start of the shared memory, followed by the slots.
+#[repr(C)]
+struct Header {
+    /// `RING_MAGIC` once the ring is initialized.
+    magic: AtomicU64,
+    /// Number of slots, a power of two.
+    slots: u64,
+    /// Maximum message size.
+    slot_size: u64,
+    /// The next slot to receive.
+    head: Padded,
+    /// The next slot to send.
+    tail: Padded,
+    /// How many producers wait for a free slot.
+    producers_waiting: Padded,
+    /// Whether the consumer waits for a message.
+    consumer_waiting: Padded,
+}
+
+/// The start of every slot, followed by the message.
+///
+/// A slot at position `pos` is free for the producer when `seq == pos` and
+/// holds a message for the consumer when `seq == pos + 1`.
+#[repr(C)]
+struct Slot {
+    seq: AtomicU64,
+    len: u64,
+}
+
+/// Bytes between two slots.
+const fn slot_stride(slot_size: usize) -> usize {
+    let size = core::mem::size_of::<Slot>() + slot_size;
+    (size + CACHE_LINE - 1) & !(CACHE_LINE - 1)
+}
+
+/// Bytes of shared memory a ring with `slots` slots of `slot_size` needs.
+pub const fn ring_size(slots: usize, slot_size: usize) -> usize {
+    core::mem::size_of::<Header>() + slots * slot_stride(slot_size)
+}
+
+/// Everything the other process needs to attach to a ring, as handles in
+/// that process (see `Ring::share`).
+///
+/// It fits in the payload of a door `Message`.
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+#[repr(C)]
+pub struct RingShare {
+    frames: [u64; MAX_RING_FRAMES],
+    nframes: u64,
+    /// Signaled when there are messages.
+    readable: u64,
+    /// Signaled when there are free slots.
+    writable: u64,
+}
+
+impl RingShare {
+    /// A door message to send the share to the other process.
+    pub fn message(&self) -> Message {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                self as *const RingShare as *const u8,
+                core::mem::size_of::<RingShare>(),
+            )
+        };
+        Message::new(bytes).expect("RingShare fits in a message")
+    }
+
+    /// The share in a door message (if it holds one).
+    pub fn from_message(msg: &Message) -> Option<RingShare> {
+        let data = msg.data();
+        if data.len() != core::mem::size_of::<RingShare>() {
+            return None;
+        }
+        let share = unsafe { ptr::read_unaligned(data.as_ptr() as *const RingShare) };
+        if share.nframes as usize > MAX_RING_FRAMES {
+            return None;
+        }
+        Some(share)
+    }
+}
+
+/// One end of a message ring in shared memory (see the module docs).
+pub struct Ring {
+    header: &'static Header,
+    slots: *mut u8,
+    frames: ArrayVec<u64, MAX_RING_FRAMES>,
+    readable: u64,
+    writable: u64,
+}
+
+// Safe: The slots are only accessed according to their sequence numbers.
+unsafe impl Send for Ring {}
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    /// Allocates and maps a ring with `slots` slots (a power of two) for
+    /// messages of up to `slot_size` bytes at `base`.
+    ///
+    /// Fails with `NotSupported` if the ring doesn't fit in
+    /// `MAX_RING_FRAMES` frames.
+    ///
+    /// # Safety
+    /// `base` must be page aligned and the `ring_size` bytes starting at
+    /// `base` must not be in use already.
+    pub unsafe fn create(
+        base: u64,
+        slots: usize,
+        slot_size: usize,
+    ) -> Result<Ring, SystemCallError> {
+        let size = ring_size(slots, slot_size);
+        if !slots.is_power_of_two() || size > MAX_RING_FRAMES * BASE_PAGE_SIZE {
+            return Err(SystemCallError::NotSupported);
+        }
+
+        let mut frames = ArrayVec::new();
+        for offset in (0..size).step_by(BASE_PAGE_SIZE) {
+            let (frame, _paddr) = PhysicalMemory::allocate_base_page()?;
+            VSpace::map_frame(frame, base + offset as u64)?;
+            frames.push(frame as u64);
+        }
+
+        let readable = Event::create(0, EventFlags::SEMAPHORE)?;
+        let writable = Event::create(0, EventFlags::SEMAPHORE)?;
+
+        let header = base as *mut Header;
+        ptr::write(
+            header,
+            Header {
+                magic: AtomicU64::new(0),
+                slots: slots as u64,
+                slot_size: slot_size as u64,
+                head: Padded(AtomicU64::new(0)),
+                tail: Padded(AtomicU64::new(0)),
+                producers_waiting: Padded(AtomicU64::new(0)),
+                consumer_waiting: Padded(AtomicU64::new(0)),
+            },
+        );
+        let ring = Ring {
+            header: &*header,
+            slots: (base as usize + core::mem::size_of::<Header>()) as *mut u8,
+            frames,
+            readable,
+            writable,
+        };
+        for pos in 0..slots {
+            ptr::write(
+                ring.slot(pos as u64),
+                Slot {
+                    seq: AtomicU64::new(pos as u64),
+                    len: 0,
+                },
+            );
+        }
+        ring.header.magic.store(RING_MAGIC, Ordering::Release);
+
+        Ok(ring)
+    }
+
+    /// Maps the ring that another process shared with us at `base`.
+    ///
+    /// Fails with `NotSupported` if the frames don't hold a ring.
+    ///
+    /// # Safety
+    /// `base` must be page aligned and the frames of `share` must fit at
+    /// `base` without overlapping anything that is in use.
+    pub unsafe fn attach(base: u64, share: &RingShare) -> Result<Ring, SystemCallError> {
+        let mut frames = ArrayVec::new();
+        for (i, frame) in share.frames[..share.nframes as usize].iter().enumerate() {
+            VSpace::map_frame(*frame as usize, base + (i * BASE_PAGE_SIZE) as u64)?;
+            frames.push(*frame);
+        }
+
+        let header = &*(base as *const Header);
+        if header.magic.load(Ordering::Acquire) != RING_MAGIC {
+            return Err(SystemCallError::NotSupported);
+        }
+        let size = ring_size(header.slots as usize, header.slot_size as usize);
+        if !header.slots.is_power_of_two() || size > frames.len() * BASE_PAGE_SIZE {
+            return Err(SystemCallError::NotSupported);
+        }
+
+        Ok(Ring {
+            header,
+            slots: (base as usize + core::mem::size_of::<Header>()) as *mut u8,
+            frames,
+            readable: share.readable,
+            writable: share.writable,
+        })
+    }
+
+    /// Gives process `pid` the frames and events of the ring (it can't pass
+    /// them on), returns what it needs to `attach`.
+    pub fn share(&self, pid: usize) -> Result<RingShare, SystemCallError> {
+        let mut share = RingShare {
+            nframes: self.frames.len() as u64,
+            ..Default::default()
+        };
+        let rights = CapRights::READ | CapRights::WRITE;
+        for (i, frame) in self.frames.iter().enumerate() {
+            share.frames[i] = Capability::transfer(*frame, pid, rights | CapRights::MAP)?;
+        }
+        share.readable = Capability::transfer(self.readable, pid, rights)?;
+        share.writable = Capability::transfer(self.writable, pid, rights)?;
+        Ok(share)
+    }
+
+    /// Maximum message size.
+    pub fn slot_size(&self) -> usize {
+        self.header.slot_size as usize
+    }
+
+    fn slot(&self, pos: u64) -> *mut Slot {
+        let index = (pos & (self.header.slots - 1)) as usize;
+        unsafe { self.slots.add(index * slot_stride(self.slot_size())) as *mut Slot }
+    }
+
+    /// Sends `msg` unless the ring is full (`WouldBlock`).
+    ///
+    /// Fails with `NotSupported` if `msg` is larger than `slot_size`.
+    pub fn try_send(&self, msg: &[u8]) -> Result<(), SystemCallError> {
+        if msg.len() > self.slot_size() {
+            return Err(SystemCallError::NotSupported);
+        }
+
+        let tail = &self.header.tail.0;
+        let mut pos = tail.load(Ordering::Relaxed);
+        let slot = loop {
+            let slot = self.slot(pos);
+            let seq = unsafe { (*slot).seq.load(Ordering::Acquire) };
+            match seq.wrapping_sub(pos) as i64 {
+                0 => match tail.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break slot,
+                    Err(current) => pos = current,
+                },
+                // The consumer didn't get to this slot yet
+                d if d < 0 => return Err(SystemCallError::WouldBlock),
+                // Another producer took it
+                _ => pos = tail.load(Ordering::Relaxed),
+            }
+        };
+
+        unsafe {
+            (*slot).len = msg.len() as u64;
+            let data = (slot as *mut u8).add(core::mem::size_of::<Slot>());
+            ptr::copy_nonoverlapping(msg.as_ptr(), data, msg.len());
+            (*slot).seq.store(pos.wrapping_add(1), Ordering::Release);
+        }
+        self.wake(&self.header.consumer_waiting.0, self.readable)
+    }
+
+    /// Receives the next message into `buf` unless the ring is empty
+    /// (`WouldBlock`), returns its length.
+    ///
+    /// Must only be called by the consumer. `buf` should have room for
+    /// `slot_size` bytes, longer messages get cut off.
+    pub fn try_recv(&self, buf: &mut [u8]) -> Result<usize, SystemCallError> {
+        let head = &self.header.head.0;
+        let pos = head.load(Ordering::Relaxed);
+        let slot = self.slot(pos);
+        if unsafe { (*slot).seq.load(Ordering::Acquire) } != pos.wrapping_add(1) {
+            return Err(SystemCallError::WouldBlock);
+        }
+
+        let len = unsafe {
+            let len = ((*slot).len as usize).min(buf.len());
+            let data = (slot as *const u8).add(core::mem::size_of::<Slot>());
+            ptr::copy_nonoverlapping(data, buf.as_mut_ptr(), len);
+            (*slot)
+                .seq
+                .store(pos.wrapping_add(self.header.slots), Ordering::Release);
+            len
+        };
+        head.store(pos.wrapping_add(1), Ordering::Relaxed);
+        self.wake(&self.header.producers_waiting.0, self.writable)?;
+        Ok(len)
+    }
+
+    /// Sends `msg`, waits for a free slot if the ring is full.
+    ///
+    /// This halts the core while waiting, not just the calling thread.
+    pub fn send(&self, msg: &[u8]) -> Result<(), SystemCallError> {
+        self.blocking(&self.header.producers_waiting.0, self.writable, || {
+            self.try_send(msg)
+        })
+    }
+
+    /// Receives the next message into `buf`, waits for one if the ring is
+    /// empty.
+    ///
+    /// This halts the core while waiting, not just the calling thread.
+    pub fn recv(&self, buf: &mut [u8]) -> Result<usize, SystemCallError> {
+        self.blocking(&self.header.consumer_waiting.0, self.readable, || {
+            self.try_recv(buf)
+        })
+    }
+
+    fn blocking<T>(
+        &self,
+        waiting: &AtomicU64,
+        event: u64,
+        mut op: impl FnMut() -> Result<T, SystemCallError>,
+    ) -> Result<T, SystemCallError> {
+        loop {
+            match op() {
+                Err(SystemCallError::WouldBlock) => {}
+                r => return r,
+            }
+
+            // Count ourselves before checking again, so the other side
+            // can't miss us in between
+            waiting.fetch_add(1, Ordering::Relaxed);
+            fence(Ordering::SeqCst);
+            match op() {
+                Err(SystemCallError::WouldBlock) => {}
+                // We leave a token in the event, next wait returns early
+                r => return r,
+            }
+            Event::wait(event)?;
+        }
+    }
+
+    /// Signals `event` for everyone counted in `waiting`.
+    fn wake(&self, waiting: &AtomicU64, event: u64) -> Result<(), SystemCallError> {
+        fence(Ordering::SeqCst);
+        if waiting.load(Ordering::Relaxed) > 0 {
+            let n = waiting.swap(0, Ordering::Relaxed);
+            if n > 0 {
+                Event::signal(event, n)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -10,6 +10,7 @@
     alloc_error_handler,
     panic_info_message,
     c_variadic,
+    global_asm,
     ptr_internals,
     llvm_asm,
     lang_items,
@@ -18,12 +19,23 @@
 extern crate alloc;
 extern crate kpi;
 
-pub use kpi::{io, syscalls};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub use kpi::{
+    abi, cap, event, filter, io, perf, process, syscalls, system, trace, vm, KprobeMode,
+    MemoryRights, SystemCall, SystemCallError,
+};
 
 extern crate arrayvec;
 extern crate lazy_static;
 
+pub mod executor;
+pub mod fault;
+pub mod ipc;
 pub mod mem;
+pub mod net;
+pub mod pthread;
+pub mod time;
 pub mod upcalls;
 pub mod vconsole;
 pub mod writer;
@@ -31,9 +43,44 @@ pub mod writer;
 #[cfg(feature = "rumprt")]
 pub mod rumprt;
 
+#[cfg(all(feature = "pthread", feature = "rumprt"))]
+compile_error!("`pthread` exports symbols of the libpthread that `rumprt` links against");
+
+/// Whether a panic ends the process (instead of only the lineup thread that
+/// panicked).
+static ABORT_ON_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// Set while the current thread handles a panic.
+#[thread_local]
+static mut PANICKING: bool = false;
+
+/// Makes a panic end the whole process, not just the thread that panicked.
+///
+/// By default a panic in a lineup thread only ends that thread (its
+/// `JoinHandle` gets the panic message). Nothing gets unwound: destructors
+/// of the values on the thread's stack don't run and locks the thread held
+/// stay locked. Panics outside of lineup threads always end the process.
+pub fn set_abort_on_panic(abort: bool) {
+    ABORT_ON_PANIC.store(abort, Ordering::Relaxed);
+}
+
+/// Can we get rid of the current thread instead of the whole process?
+fn can_abandon_thread() -> bool {
+    if ABORT_ON_PANIC.load(Ordering::Relaxed)
+        || !lineup::tls2::Environment::has_thread()
+        || !lineup::tls2::Environment::thread().has_yielder()
+    {
+        return false;
+    }
+    // A panic while we handle a panic ends the process
+    unsafe { !core::mem::replace(&mut PANICKING, true) }
+}
+
 #[cfg(target_os = "nrk")]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    // Whatever the thread printed last is likely interesting
+    writer::flush();
     sys_println!("System panic encountered");
     if let Some(message) = info.message() {
         sys_print!(": '{}'", message);
@@ -44,6 +91,15 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
         sys_println!("");
     }
 
+    if can_abandon_thread() {
+        let payload: alloc::string::String = match info.message() {
+            Some(message) => alloc::format!("{}", message),
+            None => alloc::string::String::from("explicit panic"),
+        };
+        sys_println!("Abandoning thread {}", lineup::tls2::Environment::tid());
+        lineup::tls2::Environment::thread().abandon(alloc::boxed::Box::new(payload));
+    }
+
     unsafe {
         let rsp = x86::bits64::registers::rsp();
         for i in 0..32 {
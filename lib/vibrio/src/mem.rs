@@ -15,7 +15,7 @@ use log::{error, warn};
 use spin::Mutex;
 use x86::bits64::paging::{PAddr, VAddr, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
 
-use kpi::process::{HEAP_PER_CORE_REGION, HEAP_START, MAX_CORES};
+use kpi::process::MAX_CORES;
 use kpi::SystemCallError;
 
 use slabmalloc::*;
@@ -33,7 +33,9 @@ macro_rules! round_up {
 /// Start of large-page allocation (end of Zone allocator supported sizes)
 const LPRANGE_START: usize = ZoneAllocator::MAX_ALLOC_SIZE + 1;
 
-#[cfg(target_os = "nrk")]
+/// Also used by programs for `x86_64-nrk-std`, `std` doesn't allocate
+/// through `malloc` then.
+#[cfg(any(target_os = "nrk", target_vendor = "nrk"))]
 #[global_allocator]
 static PER_CORE_MEM_PROVIDER: crate::mem::PerCoreAllocator = crate::mem::PerCoreAllocator::new();
 
@@ -68,7 +70,8 @@ impl Pager {
         warn!("NYI dealloc page {:p} {:#x}", ptr, page_size);
     }
 
-    pub(crate) fn allocate(&mut self, layout: Layout) -> Result<(VAddr, PAddr), SystemCallError> {
+    /// Maps new memory for `layout` (at the end of the region of this pager).
+    pub fn allocate(&mut self, layout: Layout) -> Result<(VAddr, PAddr), SystemCallError> {
         let size = round_up!(layout.size(), 4096) as u64;
         self.sbrk = round_up!(self.sbrk as usize, core::cmp::max(layout.align(), 4096)) as u64;
 
@@ -111,11 +114,14 @@ impl Pager {
 
 lazy_static! {
     /// A pager for GlobalAlloc.
+    ///
+    /// The heap starts wherever the kernel put it for us (kernels without
+    /// `Process::layout` always put it at `HEAP_START`).
     pub static ref PAGER: ArrayVec::<CachePadded<Mutex<Pager>>, MAX_CORES> = {
+        let layout = crate::syscalls::Process::layout().unwrap_or_default();
         let mut pagers = ArrayVec::<CachePadded<Mutex<Pager>>, { MAX_CORES }>::new();
         for i in 0..MAX_CORES {
-            let sbrk = (HEAP_START + (i * HEAP_PER_CORE_REGION)) as u64;
-            let limit = (HEAP_START + ((i + 1) * HEAP_PER_CORE_REGION)) as u64;
+            let (sbrk, limit) = layout.heap_region(i);
             pagers.push(CachePadded::new(Mutex::new(Pager { sbrk, limit })));
         }
         pagers
@@ -146,11 +152,10 @@ unsafe impl GlobalAlloc for SafeZoneAllocator {
                 match PAGER[core_id].lock().allocate_page() {
                     Some(ret) => return Some(ret),
                     None => {
+                        // Try the pagers of the other cores (once each)
                         core_id = (core_id + 1) % MAX_CORES;
-                        if core_id == (my_core_id - 1) % MAX_CORES {
+                        if core_id == my_core_id {
                             break;
-                        } else {
-                            continue;
                         }
                     }
                 }
@@ -167,11 +172,10 @@ unsafe impl GlobalAlloc for SafeZoneAllocator {
                 match PAGER[core_id].lock().allocate_large_page() {
                     Some(ret) => return Some(ret),
                     None => {
+                        // Try the pagers of the other cores (once each)
                         core_id = (core_id + 1) % MAX_CORES;
-                        if core_id == (my_core_id - 1) % MAX_CORES {
+                        if core_id == my_core_id {
                             break;
-                        } else {
-                            continue;
                         }
                     }
                 }
@@ -278,6 +282,165 @@ unsafe impl GlobalAlloc for SafeZoneAllocator {
     }
 }
 
+/// Largest size (and alignment) the per-core caches handle, bigger
+/// allocations go to the `ZoneAllocator` of the core.
+const CACHE_MAX_SIZE: usize = 2048;
+
+/// Size classes of the caches: 16, 32, ..., `CACHE_MAX_SIZE` bytes.
+const CACHE_CLASSES: usize = 8;
+
+/// How many objects move between a core and its `ZoneAllocator` or the
+/// depot at once.
+const BATCH: usize = 32;
+
+/// A free object in a cache (free objects are at least 16 bytes).
+struct FreeObject {
+    /// The next object in the same magazine.
+    next: *mut FreeObject,
+    /// The first object of the next magazine (depot only).
+    next_magazine: *mut FreeObject,
+}
+
+/// A list of free objects of one size class.
+struct Magazine {
+    head: *mut FreeObject,
+    len: usize,
+}
+
+impl Magazine {
+    const fn new() -> Magazine {
+        Magazine {
+            head: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, obj: *mut u8) {
+        let obj = obj as *mut FreeObject;
+        (*obj).next = self.head;
+        self.head = obj;
+        self.len += 1;
+    }
+
+    unsafe fn pop(&mut self) -> Option<*mut u8> {
+        if self.head.is_null() {
+            return None;
+        }
+        let obj = self.head;
+        self.head = (*obj).next;
+        self.len -= 1;
+        Some(obj as *mut u8)
+    }
+
+    /// Removes the first `BATCH` objects, returns the first of them (the
+    /// others are linked to it).
+    unsafe fn split_batch(&mut self) -> *mut FreeObject {
+        debug_assert!(self.len >= BATCH);
+        let first = self.head;
+        let mut last = first;
+        for _i in 1..BATCH {
+            last = (*last).next;
+        }
+        self.head = (*last).next;
+        (*last).next = ptr::null_mut();
+        self.len -= BATCH;
+        first
+    }
+}
+
+/// The free objects of a core, one magazine for every size class.
+///
+/// Only the core itself uses them, so the lock is uncontended.
+struct CoreCache {
+    magazines: [Magazine; CACHE_CLASSES],
+}
+
+unsafe impl Send for CoreCache {}
+
+/// Full magazines (of `BATCH` objects) for one size class, cores with too
+/// many free objects put them here and cores that run out take them from
+/// here.
+struct Depot {
+    magazines: *mut FreeObject,
+}
+
+unsafe impl Send for Depot {}
+
+impl Depot {
+    unsafe fn put(&mut self, magazine: *mut FreeObject) {
+        (*magazine).next_magazine = self.magazines;
+        self.magazines = magazine;
+    }
+
+    unsafe fn take(&mut self) -> Option<*mut FreeObject> {
+        if self.magazines.is_null() {
+            return None;
+        }
+        let magazine = self.magazines;
+        self.magazines = (*magazine).next_magazine;
+        Some(magazine)
+    }
+}
+
+lazy_static! {
+    static ref CORE_CACHES: ArrayVec<CachePadded<Mutex<CoreCache>>, MAX_CORES> = {
+        let mut caches = ArrayVec::<CachePadded<Mutex<CoreCache>>, { MAX_CORES }>::new();
+        for _i in 0..MAX_CORES {
+            caches.push(CachePadded::new(Mutex::new(CoreCache {
+                magazines: [
+                    Magazine::new(),
+                    Magazine::new(),
+                    Magazine::new(),
+                    Magazine::new(),
+                    Magazine::new(),
+                    Magazine::new(),
+                    Magazine::new(),
+                    Magazine::new(),
+                ],
+            })));
+        }
+        caches
+    };
+    static ref DEPOTS: ArrayVec<CachePadded<Mutex<Depot>>, CACHE_CLASSES> = {
+        let mut depots = ArrayVec::<CachePadded<Mutex<Depot>>, { CACHE_CLASSES }>::new();
+        for _i in 0..CACHE_CLASSES {
+            depots.push(CachePadded::new(Mutex::new(Depot {
+                magazines: ptr::null_mut(),
+            })));
+        }
+        depots
+    };
+}
+
+/// The size class for `layout` (`None` if it's too big for the caches).
+fn size_class(layout: Layout) -> Option<usize> {
+    let size = core::cmp::max(layout.size(), layout.align()).next_power_of_two();
+    if size <= CACHE_MAX_SIZE {
+        Some(core::cmp::max(size, 16).trailing_zeros() as usize - 4)
+    } else {
+        None
+    }
+}
+
+fn class_size(class: usize) -> usize {
+    16 << class
+}
+
+/// Whether `realloc` can keep an object of `layout` for `new_layout` (both
+/// are in the same size class of the caches).
+fn resizes_in_place(layout: Layout, new_layout: Layout) -> bool {
+    let class = size_class(layout);
+    class.is_some() && class == size_class(new_layout)
+}
+
+/// The global allocator: small objects come from per-core caches of free
+/// objects (magazines), everything else from a `ZoneAllocator` per core.
+///
+/// A core refills its cache with a batch of objects from the depot (objects
+/// other cores freed) or its `ZoneAllocator`, and moves batches to the
+/// depot if it has too many. So cores only synchronize once every `BATCH`
+/// allocations (of a size class), and objects freed on another core than
+/// they were allocated on never go back to the wrong `ZoneAllocator`.
 pub struct PerCoreAllocator;
 
 lazy_static! {
@@ -299,22 +462,195 @@ impl PerCoreAllocator {
     pub const fn new() -> PerCoreAllocator {
         PerCoreAllocator {}
     }
+
+    /// Fills the (empty) `magazine` of `class` on `core_id`.
+    unsafe fn refill(magazine: &mut Magazine, class: usize, core_id: usize) {
+        if let Some(batch) = DEPOTS[class].lock().take() {
+            magazine.head = batch;
+            magazine.len = BATCH;
+            return;
+        }
+
+        let size = class_size(class);
+        let layout = Layout::from_size_align_unchecked(size, size);
+        let sza = &PER_CORE_MEM_ALLOCATOR[core_id];
+        for _i in 0..BATCH {
+            let obj = sza.alloc(layout);
+            if obj.is_null() {
+                break;
+            }
+            magazine.push(obj);
+        }
+    }
 }
 
+// A thread that gets preempted (see `lineup::preempt`) while it holds one of
+// the allocator locks would leave the scheduler spinning on it.
 unsafe impl GlobalAlloc for PerCoreAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // Get the core_id we are currently running on. Then return alloc of that SafeZoneAllocator.
-        let sza = &PER_CORE_MEM_ALLOCATOR[Environment::core_id()];
-        sza.alloc(layout)
+        let _preempt = lineup::preempt::disable();
+        let core_id = Environment::core_id();
+        match size_class(layout) {
+            Some(class) => {
+                let mut cache = CORE_CACHES[core_id].lock();
+                let magazine = &mut cache.magazines[class];
+                if magazine.len == 0 {
+                    PerCoreAllocator::refill(magazine, class, core_id);
+                }
+                magazine.pop().unwrap_or(ptr::null_mut())
+            }
+            None => PER_CORE_MEM_ALLOCATOR[core_id].alloc(layout),
+        }
     }
 
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        let sza = &PER_CORE_MEM_ALLOCATOR[Environment::core_id()];
-        sza.realloc(ptr, layout, new_size)
+        let _preempt = lineup::preempt::disable();
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        match (size_class(layout), size_class(new_layout)) {
+            (None, None) => {
+                PER_CORE_MEM_ALLOCATOR[Environment::core_id()].realloc(ptr, layout, new_size)
+            }
+            _ if resizes_in_place(layout, new_layout) => ptr,
+            _ => {
+                let new_ptr = self.alloc(new_layout);
+                if !new_ptr.is_null() {
+                    ptr::copy_nonoverlapping(ptr, new_ptr, core::cmp::min(layout.size(), new_size));
+                    self.dealloc(ptr, layout);
+                }
+                new_ptr
+            }
+        }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let sza = &PER_CORE_MEM_ALLOCATOR[Environment::core_id()];
-        sza.dealloc(ptr, layout)
+        if ptr.is_null() {
+            return;
+        }
+
+        let _preempt = lineup::preempt::disable();
+        let core_id = Environment::core_id();
+        match size_class(layout) {
+            Some(class) => {
+                let mut cache = CORE_CACHES[core_id].lock();
+                let magazine = &mut cache.magazines[class];
+                magazine.push(ptr);
+                if magazine.len >= 2 * BATCH {
+                    let batch = magazine.split_batch();
+                    DEPOTS[class].lock().put(batch);
+                }
+            }
+            None => PER_CORE_MEM_ALLOCATOR[core_id].dealloc(ptr, layout),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn size_class_boundaries() {
+    let class = |size, align| size_class(Layout::from_size_align(size, align).unwrap());
+
+    // Everything up to 16 bytes shares the smallest class
+    assert_eq!(class(0, 1), Some(0));
+    assert_eq!(class(1, 1), Some(0));
+    assert_eq!(class(16, 8), Some(0));
+    assert_eq!(class(17, 8), Some(1));
+    assert_eq!(class(CACHE_MAX_SIZE, 8), Some(CACHE_CLASSES - 1));
+    assert_eq!(class(CACHE_MAX_SIZE + 1, 8), None);
+
+    // The alignment picks the class if it's bigger than the size
+    assert_eq!(class(8, 64), Some(2));
+    assert_eq!(class(1, CACHE_MAX_SIZE), Some(CACHE_CLASSES - 1));
+    assert_eq!(class(16, 2 * CACHE_MAX_SIZE), None);
+
+    // Objects of a class fit the size and are aligned for it
+    for size in 1..=CACHE_MAX_SIZE {
+        let mut align = 1;
+        while align <= CACHE_MAX_SIZE {
+            let class = class(size, align).unwrap();
+            assert!(class < CACHE_CLASSES);
+            assert!(class_size(class) >= size);
+            assert_eq!(class_size(class) % align, 0);
+            align *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn realloc_within_and_across_classes() {
+    let layout = |size, align| Layout::from_size_align(size, align).unwrap();
+
+    assert!(resizes_in_place(layout(20, 8), layout(32, 8)));
+    assert!(resizes_in_place(layout(32, 8), layout(17, 8)));
+    assert!(resizes_in_place(layout(8, 64), layout(64, 64)));
+    assert!(resizes_in_place(
+        layout(CACHE_MAX_SIZE / 2 + 1, 8),
+        layout(CACHE_MAX_SIZE, 8)
+    ));
+
+    assert!(!resizes_in_place(layout(32, 8), layout(33, 8)));
+    assert!(!resizes_in_place(layout(32, 8), layout(16, 8)));
+    assert!(!resizes_in_place(
+        layout(CACHE_MAX_SIZE, 8),
+        layout(CACHE_MAX_SIZE + 1, 8)
+    ));
+    // Objects of the `ZoneAllocator` are never in a class
+    assert!(!resizes_in_place(
+        layout(2 * CACHE_MAX_SIZE, 8),
+        layout(3 * CACHE_MAX_SIZE, 8)
+    ));
+}
+
+#[cfg(test)]
+#[test]
+fn magazine_and_depot_round_trip() {
+    use alloc::vec::Vec;
+
+    let mut objects: Vec<[u64; 2]> = Vec::with_capacity(3 * BATCH);
+    objects.resize(3 * BATCH, [0, 0]);
+    let base = objects.as_mut_ptr() as *mut u8;
+    let obj = |i: usize| unsafe { base.add(i * 16) };
+
+    let mut magazine = Magazine::new();
+    let mut depot = Depot {
+        magazines: ptr::null_mut(),
+    };
+    unsafe {
+        for i in 0..3 * BATCH {
+            magazine.push(obj(i));
+        }
+        assert_eq!(magazine.len, 3 * BATCH);
+
+        // A batch are the last objects we pushed, linked to each other
+        let first = magazine.split_batch();
+        assert_eq!(magazine.len, 2 * BATCH);
+        let mut len = 0;
+        let mut next = first;
+        while !next.is_null() {
+            assert_eq!(next as *mut u8, obj(3 * BATCH - 1 - len));
+            next = (*next).next;
+            len += 1;
+        }
+        assert_eq!(len, BATCH);
+        depot.put(first);
+        depot.put(magazine.split_batch());
+        assert_eq!(magazine.len, BATCH);
+
+        // Empty the magazine and refill it from the depot, like `refill`
+        for i in (0..BATCH).rev() {
+            assert_eq!(magazine.pop(), Some(obj(i)));
+        }
+        assert_eq!(magazine.len, 0);
+        assert_eq!(magazine.pop(), None);
+        for batch in [BATCH, 2 * BATCH].iter() {
+            magazine.head = depot.take().unwrap();
+            magazine.len = BATCH;
+            for i in (0..BATCH).rev() {
+                assert_eq!(magazine.pop(), Some(obj(batch + i)));
+            }
+            assert_eq!(magazine.len, 0);
+            assert_eq!(magazine.pop(), None);
+        }
+        assert_eq!(depot.take(), None);
     }
 }
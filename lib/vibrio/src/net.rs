@@ -0,0 +1,121 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Support for sockets of the kernel network stack.
+
+use core::time::Duration;
+
+use kpi::syscalls::{Net, VSpace};
+use kpi::SystemCallError;
+use lineup::tls2::Environment;
+use x86::bits64::paging::LARGE_PAGE_SIZE;
+
+pub use kpi::net::{
+    PollEvents, PollFd, SocketAddr, SocketType, VsockAddr, XdpDesc, XdpRings, VSOCK_CID_HOST,
+    VSOCK_FD, XDP_FRAME_SIZE, XDP_RING_SIZE,
+};
+
+/// How long the kernel stops the other lineup threads of the core at most
+/// while we wait.
+const POLL_SLICE: Duration = Duration::from_millis(10);
+
+/// Waits until at least one socket (or event, see `PollFd::event`) in `fds`
+/// is ready or `timeout` expired (`None` waits forever).
+///
+/// The core sleeps in the kernel until a socket might be ready. That stops
+/// the other lineup threads on the core as well, so we don't wait for
+/// longer than `POLL_SLICE` at a time and let them run in between.
+pub fn poll(fds: &mut [PollFd], timeout: Option<Duration>) -> Result<usize, SystemCallError> {
+    let start = rawtime::Instant::now();
+    loop {
+        let remaining = timeout.map(|t| t.checked_sub(start.elapsed()).unwrap_or_default());
+        let wait = remaining.map_or(POLL_SLICE, |r| r.min(POLL_SLICE));
+        let ready = Net::poll(fds, Some(wait))?;
+        if ready > 0 {
+            return Ok(ready);
+        }
+
+        if remaining == Some(Duration::from_secs(0)) {
+            return Ok(0);
+        }
+        if Environment::has_thread() && Environment::thread().has_yielder() {
+            Environment::thread().relinquish();
+        }
+    }
+}
+
+/// A zero-copy packet queue attached to the NIC.
+///
+/// Received UDP packets for the port end up in UMEM buffers that are
+/// read in place, see `kpi::net::XdpRings` for how the rings work.
+pub struct XdpSocket {
+    fd: u64,
+    rings: &'static XdpRings,
+    umem: &'static mut [u8],
+}
+
+impl XdpSocket {
+    /// Maps the rings and a UMEM region (one large page each) starting at
+    /// `base` and attaches them to UDP port `port`.
+    ///
+    /// # Safety
+    /// `base` must be large-page aligned and the two large pages starting
+    /// at `base` must not be in use already.
+    pub unsafe fn attach(base: u64, port: u16) -> Result<XdpSocket, SystemCallError> {
+        let rings_base = base;
+        let umem_base = base + LARGE_PAGE_SIZE as u64;
+        VSpace::map(rings_base, LARGE_PAGE_SIZE as u64)?;
+        VSpace::map(umem_base, LARGE_PAGE_SIZE as u64)?;
+
+        // Memory we get from `map` is zeroed, which is a valid (empty) ring:
+        let rings = &*(rings_base as *const XdpRings);
+        let umem = core::slice::from_raw_parts_mut(umem_base as *mut u8, LARGE_PAGE_SIZE);
+
+        // Give the kernel as many receive buffers as fit in the fill ring,
+        // the rest of UMEM is used for sending:
+        for frame in 0..XDP_RING_SIZE {
+            let pushed = rings
+                .fill
+                .push(XdpDesc::new((frame * XDP_FRAME_SIZE) as u64, 0));
+            debug_assert!(pushed);
+        }
+
+        let fd = Net::xdp_attach(rings_base, umem_base, LARGE_PAGE_SIZE as u64, port)?;
+        Ok(XdpSocket { fd, rings, umem })
+    }
+
+    pub fn rings(&self) -> &'static XdpRings {
+        self.rings
+    }
+
+    /// The UMEM buffer for a descriptor.
+    pub fn frame(&mut self, desc: XdpDesc) -> &mut [u8] {
+        let start = desc.addr as usize;
+        &mut self.umem[start..start + desc.len as usize]
+    }
+
+    /// Returns the next received packet (if any).
+    pub fn recv(&mut self) -> Option<XdpDesc> {
+        self.rings.rx.pop()
+    }
+
+    /// Gives a buffer back to the kernel for receiving.
+    pub fn release(&mut self, desc: XdpDesc) -> bool {
+        self.rings.fill.push(XdpDesc::new(desc.addr, 0))
+    }
+
+    /// Queues `desc` for sending (call `kick` to send).
+    pub fn send(&mut self, desc: XdpDesc) -> bool {
+        self.rings.tx.push(desc)
+    }
+
+    /// Sends queued packets and checks for new packets.
+    pub fn kick(&self) -> Result<(), SystemCallError> {
+        Net::xdp_kick(self.fd)
+    }
+
+    /// Detaches the queue from the NIC.
+    pub fn close(self) -> Result<(), SystemCallError> {
+        Net::close(self.fd)
+    }
+}
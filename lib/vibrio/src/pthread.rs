@@ -0,0 +1,931 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A pthread layer on top of lineup threads and `lineup::sync`.
+//!
+//! Covers threads (create, join, detach), mutexes, condition variables,
+//! read-write locks, `pthread_once` and thread-specific data (keys). Threads are lineup
+//! threads of the `PROCESS_SCHEDULER`, `pthread_create` puts them on the
+//! core of the caller and `pthread_setconcurrency` requests more cores from
+//! the kernel (idle cores take threads from busy ones). All functions have
+//! to be called from a lineup thread.
+//!
+//! The `pthread` feature exports the functions as C symbols. The mutex,
+//! condition variable and once types have the size of the NetBSD ones and
+//! work with their static initializers (which only set the magic number),
+//! so code compiled against the NetBSD headers links against this instead
+//! of libpthread (it can't be combined with `rumprt`, which links
+//! libpthread).
+//!
+//! Not supported: `pthread_exit` (threads end by returning from their start
+//! routine), cancellation, thread names and scheduling attributes.
+
+#![allow(non_camel_case_types)]
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicI32, AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+use core::{mem, ptr};
+
+use lazy_static::lazy_static;
+use lineup::sync::rwlock::RwLockIntent;
+use lineup::sync::{Condvar, Mutex, RwLock};
+use lineup::threads::JoinHandle;
+use lineup::tls2::Environment;
+use log::{trace, warn};
+use rawtime::Instant;
+use x86::bits64::paging::VAddr;
+
+use crate::syscalls::{Process, System};
+use crate::upcalls::{upcall_while_enabled, CORES_ONLINE, PROCESS_SCHEDULER};
+
+pub type c_int = i32;
+pub type c_uint = u32;
+pub type c_long = i64;
+pub type pthread_t = u64;
+pub type pthread_key_t = c_int;
+
+// The NetBSD values of the error codes we return
+const EPERM: c_int = 1;
+const ESRCH: c_int = 3;
+const EDEADLK: c_int = 11;
+const EBUSY: c_int = 16;
+const EINVAL: c_int = 22;
+const EAGAIN: c_int = 35;
+const ETIMEDOUT: c_int = 60;
+const ENOTSUP: c_int = 86;
+
+/// The only clock `pthread_cond_timedwait` supports (time since boot).
+const CLOCK_MONOTONIC: c_int = 3;
+
+/// What `pthread_join` returns for a thread that panicked.
+pub const PTHREAD_CANCELED: *mut c_void = 1 as *mut c_void;
+
+pub const PTHREAD_CREATE_JOINABLE: c_int = 0;
+pub const PTHREAD_CREATE_DETACHED: c_int = 1;
+
+pub const PTHREAD_MUTEX_NORMAL: c_int = 0;
+pub const PTHREAD_MUTEX_ERRORCHECK: c_int = 1;
+pub const PTHREAD_MUTEX_RECURSIVE: c_int = 2;
+
+pub const PTHREAD_KEYS_MAX: usize = 128;
+pub const PTHREAD_DESTRUCTOR_ITERATIONS: usize = 4;
+
+/// Stack size of a thread unless its attributes say otherwise.
+const DEFAULT_STACK_SIZE: usize = 64 * 4096;
+
+const MUTEX_MAGIC: c_uint = 0x3333_0000;
+const COND_MAGIC: c_uint = 0x5555_0005;
+const RWLOCK_MAGIC: c_uint = 0x9999_0009;
+
+/// The value of the (C) struct timespec.
+#[repr(C)]
+pub struct timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: c_long,
+}
+
+#[repr(C)]
+pub struct pthread_attr_t {
+    stacksize: usize,
+    detachstate: c_int,
+}
+
+#[repr(C)]
+pub struct pthread_mutexattr_t {
+    kind: c_int,
+    _reserved: [u32; 3],
+}
+
+#[repr(C)]
+pub struct pthread_condattr_t {
+    _reserved: [u64; 2],
+}
+
+#[repr(C)]
+pub struct pthread_mutex_t {
+    magic: c_uint,
+    kind: c_int,
+    /// Allocated on first use.
+    inner: AtomicPtr<Mutex>,
+    /// `pthread_t` + 1 of the thread that holds the mutex (0 if nobody
+    /// does).
+    owner: AtomicUsize,
+    /// How often the owner locked a `PTHREAD_MUTEX_RECURSIVE` mutex.
+    count: AtomicU32,
+    _reserved: [u32; 3],
+}
+
+pub const PTHREAD_MUTEX_INITIALIZER: pthread_mutex_t = pthread_mutex_t {
+    magic: MUTEX_MAGIC,
+    kind: PTHREAD_MUTEX_NORMAL,
+    inner: AtomicPtr::new(ptr::null_mut()),
+    owner: AtomicUsize::new(0),
+    count: AtomicU32::new(0),
+    _reserved: [0; 3],
+};
+
+#[repr(C)]
+pub struct pthread_cond_t {
+    magic: c_uint,
+    /// Allocated on first use.
+    inner: AtomicPtr<Condvar>,
+    _reserved: [u64; 3],
+}
+
+pub const PTHREAD_COND_INITIALIZER: pthread_cond_t = pthread_cond_t {
+    magic: COND_MAGIC,
+    inner: AtomicPtr::new(ptr::null_mut()),
+    _reserved: [0; 3],
+};
+
+#[repr(C)]
+pub struct pthread_rwlockattr_t {
+    _reserved: [u64; 2],
+}
+
+#[repr(C)]
+pub struct pthread_rwlock_t {
+    magic: c_uint,
+    /// Allocated on first use.
+    inner: AtomicPtr<RwLock>,
+    /// `pthread_t` + 1 of the thread that holds the lock for writing (0 if
+    /// nobody does).
+    writer: AtomicUsize,
+    _reserved: [u64; 5],
+}
+
+pub const PTHREAD_RWLOCK_INITIALIZER: pthread_rwlock_t = pthread_rwlock_t {
+    magic: RWLOCK_MAGIC,
+    inner: AtomicPtr::new(ptr::null_mut()),
+    writer: AtomicUsize::new(0),
+    _reserved: [0; 5],
+};
+
+#[repr(C)]
+pub struct pthread_once_t {
+    mutex: pthread_mutex_t,
+    done: AtomicI32,
+}
+
+pub const PTHREAD_ONCE_INIT: pthread_once_t = pthread_once_t {
+    mutex: PTHREAD_MUTEX_INITIALIZER,
+    done: AtomicI32::new(0),
+};
+
+/// Returns the object at `slot`, allocates it with `new` if there is none
+/// yet.
+fn get_or_alloc<T>(slot: &AtomicPtr<T>, new: fn() -> T) -> &T {
+    let current = slot.load(Ordering::Acquire);
+    if !current.is_null() {
+        return unsafe { &*current };
+    }
+
+    let fresh = Box::into_raw(Box::new(new()));
+    match slot.compare_exchange(ptr::null_mut(), fresh, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => unsafe { &*fresh },
+        Err(current) => {
+            // Somebody else was faster
+            unsafe { drop(Box::from_raw(fresh)) };
+            unsafe { &*current }
+        }
+    }
+}
+
+/// Frees the object at `slot` (if there is one).
+unsafe fn free<T>(slot: &AtomicPtr<T>) {
+    let current = slot.swap(ptr::null_mut(), Ordering::AcqRel);
+    if !current.is_null() {
+        drop(Box::from_raw(current));
+    }
+}
+
+lazy_static! {
+    /// Threads somebody can still join.
+    static ref JOINABLE: spin::Mutex<BTreeMap<pthread_t, JoinHandle<usize>>> =
+        spin::Mutex::new(BTreeMap::new());
+}
+
+/// What `pthread_setconcurrency` was called with last.
+static CONCURRENCY: AtomicI32 = AtomicI32::new(0);
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_attr_init(attr: *mut pthread_attr_t) -> c_int {
+    *attr = pthread_attr_t {
+        stacksize: DEFAULT_STACK_SIZE,
+        detachstate: PTHREAD_CREATE_JOINABLE,
+    };
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_attr_destroy(_attr: *mut pthread_attr_t) -> c_int {
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_attr_setdetachstate(
+    attr: *mut pthread_attr_t,
+    detachstate: c_int,
+) -> c_int {
+    match detachstate {
+        PTHREAD_CREATE_JOINABLE | PTHREAD_CREATE_DETACHED => {
+            (*attr).detachstate = detachstate;
+            0
+        }
+        _ => EINVAL,
+    }
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_attr_getdetachstate(
+    attr: *const pthread_attr_t,
+    detachstate: *mut c_int,
+) -> c_int {
+    *detachstate = (*attr).detachstate;
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_attr_setstacksize(
+    attr: *mut pthread_attr_t,
+    stacksize: usize,
+) -> c_int {
+    if stacksize < 4096 {
+        return EINVAL;
+    }
+    (*attr).stacksize = stacksize;
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_attr_getstacksize(
+    attr: *const pthread_attr_t,
+    stacksize: *mut usize,
+) -> c_int {
+    *stacksize = (*attr).stacksize;
+    0
+}
+
+/// Threads don't have a fixed stack address before they run, so this
+/// only reports the size.
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_attr_getstack(
+    attr: *const pthread_attr_t,
+    stackaddr: *mut *mut c_void,
+    stacksize: *mut usize,
+) -> c_int {
+    *stackaddr = ptr::null_mut();
+    *stacksize = (*attr).stacksize;
+    0
+}
+
+/// Stacks of lineup threads have no guard pages.
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_attr_getguardsize(
+    _attr: *const pthread_attr_t,
+    guardsize: *mut usize,
+) -> c_int {
+    *guardsize = 0;
+    0
+}
+
+/// We don't keep track of where the stacks of running threads are.
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_getattr_np(
+    _thread: pthread_t,
+    _attr: *mut pthread_attr_t,
+) -> c_int {
+    ENOTSUP
+}
+
+/// Starts a thread on the core of the caller.
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_create(
+    thread: *mut pthread_t,
+    attr: *const pthread_attr_t,
+    start_routine: extern "C" fn(*mut c_void) -> *mut c_void,
+    arg: *mut c_void,
+) -> c_int {
+    let (stacksize, detachstate) = if attr.is_null() {
+        (DEFAULT_STACK_SIZE, PTHREAD_CREATE_JOINABLE)
+    } else {
+        ((*attr).stacksize, (*attr).detachstate)
+    };
+
+    let arg = arg as usize;
+    let handle = match PROCESS_SCHEDULER.spawn(
+        stacksize,
+        move |_| {
+            let r = start_routine(arg as *mut c_void);
+            run_key_destructors();
+            r as usize
+        },
+        ptr::null_mut(),
+        Environment::scheduler().core_id,
+        None,
+    ) {
+        Some(handle) => handle,
+        None => return EAGAIN,
+    };
+
+    *thread = handle.thread_id().0 as pthread_t;
+    trace!("pthread_create {}", *thread);
+    if detachstate == PTHREAD_CREATE_JOINABLE {
+        JOINABLE.lock().insert(*thread, handle);
+    }
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_join(thread: pthread_t, retval: *mut *mut c_void) -> c_int {
+    if thread == pthread_self() {
+        return EDEADLK;
+    }
+    let handle = match JOINABLE.lock().remove(&thread) {
+        Some(handle) => handle,
+        None => return ESRCH,
+    };
+
+    match handle.join() {
+        Ok(r) => {
+            if !retval.is_null() {
+                *retval = r as *mut c_void;
+            }
+            0
+        }
+        // It panicked, which we treat like a cancellation
+        Err(_) => {
+            if !retval.is_null() {
+                *retval = PTHREAD_CANCELED;
+            }
+            0
+        }
+    }
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_detach(thread: pthread_t) -> c_int {
+    match JOINABLE.lock().remove(&thread) {
+        Some(_handle) => 0,
+        None => ESRCH,
+    }
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_self() -> pthread_t {
+    Environment::tid().0 as pthread_t
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_equal(t1: pthread_t, t2: pthread_t) -> c_int {
+    (t1 == t2) as c_int
+}
+
+/// Threads don't have names, this does nothing.
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_setname_np(
+    _thread: pthread_t,
+    _name: *const i8,
+    _arg: *mut c_void,
+) -> c_int {
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn sched_yield() -> c_int {
+    Environment::thread().relinquish();
+    0
+}
+
+/// Requests cores from the kernel until the process has `new_level` cores
+/// (or there are no more).
+///
+/// Threads still start on the core of their creator, idle cores steal them
+/// from there.
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_setconcurrency(new_level: c_int) -> c_int {
+    if new_level < 0 {
+        return EINVAL;
+    }
+    CONCURRENCY.store(new_level, Ordering::Relaxed);
+    if new_level == 0 {
+        return 0;
+    }
+
+    PROCESS_SCHEDULER.set_work_stealing(true);
+    let hwthreads = match System::threads() {
+        Ok(hwthreads) => hwthreads,
+        Err(_) => return EAGAIN,
+    };
+    let mut cores = CORES_ONLINE.load(Ordering::SeqCst);
+    for hwthread in hwthreads.iter() {
+        if cores >= new_level as usize {
+            break;
+        }
+        // Fails for cores we (or others) have already
+        if Process::request_core(
+            hwthread.id,
+            VAddr::from(upcall_while_enabled as *const fn() as u64),
+        )
+        .is_ok()
+        {
+            cores += 1;
+        }
+    }
+
+    if cores < new_level as usize {
+        warn!(
+            "pthread_setconcurrency: got {} of {} cores",
+            cores, new_level
+        );
+        return EAGAIN;
+    }
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_getconcurrency() -> c_int {
+    CONCURRENCY.load(Ordering::Relaxed)
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_mutexattr_init(attr: *mut pthread_mutexattr_t) -> c_int {
+    *attr = pthread_mutexattr_t {
+        kind: PTHREAD_MUTEX_NORMAL,
+        _reserved: [0; 3],
+    };
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_mutexattr_destroy(_attr: *mut pthread_mutexattr_t) -> c_int {
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_mutexattr_settype(
+    attr: *mut pthread_mutexattr_t,
+    kind: c_int,
+) -> c_int {
+    match kind {
+        PTHREAD_MUTEX_NORMAL | PTHREAD_MUTEX_ERRORCHECK | PTHREAD_MUTEX_RECURSIVE => {
+            (*attr).kind = kind;
+            0
+        }
+        _ => EINVAL,
+    }
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_mutexattr_gettype(
+    attr: *const pthread_mutexattr_t,
+    kind: *mut c_int,
+) -> c_int {
+    *kind = (*attr).kind;
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_mutex_init(
+    mutex: *mut pthread_mutex_t,
+    attr: *const pthread_mutexattr_t,
+) -> c_int {
+    ptr::write(mutex, PTHREAD_MUTEX_INITIALIZER);
+    if !attr.is_null() {
+        (*mutex).kind = (*attr).kind;
+    }
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_mutex_destroy(mutex: *mut pthread_mutex_t) -> c_int {
+    if (*mutex).owner.load(Ordering::Relaxed) != 0 {
+        return EBUSY;
+    }
+    free(&(*mutex).inner);
+    0
+}
+
+impl pthread_mutex_t {
+    fn inner(&self) -> &Mutex {
+        get_or_alloc(&self.inner, Mutex::new)
+    }
+
+    /// Handles locking a mutex we hold already, `None` if we don't.
+    unsafe fn relock(&self, me: usize) -> Option<c_int> {
+        if self.owner.load(Ordering::Relaxed) != me {
+            return None;
+        }
+        match self.kind {
+            PTHREAD_MUTEX_RECURSIVE => {
+                self.count.fetch_add(1, Ordering::Relaxed);
+                Some(0)
+            }
+            PTHREAD_MUTEX_ERRORCHECK => Some(EDEADLK),
+            // A normal mutex deadlocks
+            _ => None,
+        }
+    }
+
+    fn set_owner(&self, me: usize) {
+        self.owner.store(me, Ordering::Relaxed);
+        self.count.store(1, Ordering::Relaxed);
+    }
+}
+
+/// What `pthread_mutex_t::owner` is for us.
+unsafe fn me() -> usize {
+    pthread_self() as usize + 1
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_mutex_lock(mutex: *mut pthread_mutex_t) -> c_int {
+    let mutex = &*mutex;
+    let me = me();
+    if let Some(r) = mutex.relock(me) {
+        return r;
+    }
+    mutex.inner().enter();
+    mutex.set_owner(me);
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_mutex_trylock(mutex: *mut pthread_mutex_t) -> c_int {
+    let mutex = &*mutex;
+    let me = me();
+    if let Some(r) = mutex.relock(me) {
+        return if r == EDEADLK { EBUSY } else { r };
+    }
+    if !mutex.inner().try_enter() {
+        return EBUSY;
+    }
+    mutex.set_owner(me);
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_mutex_unlock(mutex: *mut pthread_mutex_t) -> c_int {
+    let mutex = &*mutex;
+    if mutex.owner.load(Ordering::Relaxed) != me() {
+        return EPERM;
+    }
+    if mutex.count.fetch_sub(1, Ordering::Relaxed) > 1 {
+        return 0;
+    }
+    mutex.owner.store(0, Ordering::Relaxed);
+    mutex.inner().exit();
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_condattr_init(_attr: *mut pthread_condattr_t) -> c_int {
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_condattr_destroy(_attr: *mut pthread_condattr_t) -> c_int {
+    0
+}
+
+/// `pthread_cond_timedwait` always takes time since boot, which is
+/// `CLOCK_MONOTONIC`.
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_condattr_setclock(
+    _attr: *mut pthread_condattr_t,
+    clock_id: c_int,
+) -> c_int {
+    if clock_id == CLOCK_MONOTONIC {
+        0
+    } else {
+        EINVAL
+    }
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_cond_init(
+    cond: *mut pthread_cond_t,
+    _attr: *const pthread_condattr_t,
+) -> c_int {
+    ptr::write(cond, PTHREAD_COND_INITIALIZER);
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_cond_destroy(cond: *mut pthread_cond_t) -> c_int {
+    let inner = (*cond).inner.load(Ordering::Acquire);
+    if !inner.is_null() && (*inner).has_waiters() {
+        return EBUSY;
+    }
+    free(&(*cond).inner);
+    0
+}
+
+impl pthread_cond_t {
+    fn inner(&self) -> &Condvar {
+        get_or_alloc(&self.inner, Condvar::new)
+    }
+
+    /// Waits (until `timeout`) with `mutex` released, returns `ETIMEDOUT`
+    /// if nobody signaled us in time.
+    unsafe fn wait(&self, mutex: &pthread_mutex_t, timeout: Option<Duration>) -> c_int {
+        let me = me();
+        if mutex.owner.load(Ordering::Relaxed) != me {
+            return EPERM;
+        }
+
+        // Recursive mutexes are released entirely while we wait
+        let count = mutex.count.load(Ordering::Relaxed);
+        mutex.owner.store(0, Ordering::Relaxed);
+        let signaled = match timeout {
+            Some(timeout) => self.inner().timed_wait(mutex.inner(), timeout),
+            None => {
+                self.inner().wait(mutex.inner());
+                true
+            }
+        };
+        mutex.owner.store(me, Ordering::Relaxed);
+        mutex.count.store(count, Ordering::Relaxed);
+
+        if signaled {
+            0
+        } else {
+            ETIMEDOUT
+        }
+    }
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_cond_wait(
+    cond: *mut pthread_cond_t,
+    mutex: *mut pthread_mutex_t,
+) -> c_int {
+    (*cond).wait(&*mutex, None)
+}
+
+/// Waits until `abstime` (time since boot, we don't have a wall clock).
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_cond_timedwait(
+    cond: *mut pthread_cond_t,
+    mutex: *mut pthread_mutex_t,
+    abstime: *const timespec,
+) -> c_int {
+    let abstime = &*abstime;
+    if abstime.tv_sec < 0 || !(0..1_000_000_000).contains(&abstime.tv_nsec) {
+        return EINVAL;
+    }
+    let deadline =
+        Instant::from_nanos((abstime.tv_sec as u128) * 1_000_000_000 + abstime.tv_nsec as u128);
+    let now = Instant::now();
+    let timeout = if deadline > now {
+        deadline - now
+    } else {
+        Duration::from_secs(0)
+    };
+    (*cond).wait(&*mutex, Some(timeout))
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_cond_signal(cond: *mut pthread_cond_t) -> c_int {
+    (*cond).inner().signal();
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_cond_broadcast(cond: *mut pthread_cond_t) -> c_int {
+    (*cond).inner().broadcast();
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_rwlockattr_init(_attr: *mut pthread_rwlockattr_t) -> c_int {
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_rwlockattr_destroy(_attr: *mut pthread_rwlockattr_t) -> c_int {
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_rwlock_init(
+    rwlock: *mut pthread_rwlock_t,
+    _attr: *const pthread_rwlockattr_t,
+) -> c_int {
+    ptr::write(rwlock, PTHREAD_RWLOCK_INITIALIZER);
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_rwlock_destroy(rwlock: *mut pthread_rwlock_t) -> c_int {
+    let inner = (*rwlock).inner.load(Ordering::Acquire);
+    if !inner.is_null() && ((*inner).held(RwLockIntent::Read) || (*inner).held(RwLockIntent::Write))
+    {
+        return EBUSY;
+    }
+    free(&(*rwlock).inner);
+    0
+}
+
+impl pthread_rwlock_t {
+    fn inner(&self) -> &RwLock {
+        get_or_alloc(&self.inner, RwLock::new)
+    }
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_rwlock_rdlock(rwlock: *mut pthread_rwlock_t) -> c_int {
+    let rwlock = &*rwlock;
+    if rwlock.writer.load(Ordering::Relaxed) == me() {
+        return EDEADLK;
+    }
+    rwlock.inner().enter(RwLockIntent::Read);
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_rwlock_tryrdlock(rwlock: *mut pthread_rwlock_t) -> c_int {
+    if (*rwlock).inner().try_enter(RwLockIntent::Read) {
+        0
+    } else {
+        EBUSY
+    }
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_rwlock_wrlock(rwlock: *mut pthread_rwlock_t) -> c_int {
+    let rwlock = &*rwlock;
+    let me = me();
+    if rwlock.writer.load(Ordering::Relaxed) == me {
+        return EDEADLK;
+    }
+    rwlock.inner().enter(RwLockIntent::Write);
+    rwlock.writer.store(me, Ordering::Relaxed);
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_rwlock_trywrlock(rwlock: *mut pthread_rwlock_t) -> c_int {
+    let rwlock = &*rwlock;
+    if !rwlock.inner().try_enter(RwLockIntent::Write) {
+        return EBUSY;
+    }
+    rwlock.writer.store(me(), Ordering::Relaxed);
+    0
+}
+
+/// Releases a read or write lock, we don't track which threads read so any
+/// thread can release a read lock.
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_rwlock_unlock(rwlock: *mut pthread_rwlock_t) -> c_int {
+    let rwlock = &*rwlock;
+    let inner = rwlock.inner();
+    if inner.held(RwLockIntent::Write) {
+        if rwlock.writer.load(Ordering::Relaxed) != me() {
+            return EPERM;
+        }
+        rwlock.writer.store(0, Ordering::Relaxed);
+    } else if !inner.held(RwLockIntent::Read) {
+        return EPERM;
+    }
+    inner.exit();
+    0
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_once(
+    once_control: *mut pthread_once_t,
+    init_routine: extern "C" fn(),
+) -> c_int {
+    let once = &mut *once_control;
+    if once.done.load(Ordering::Acquire) != 0 {
+        return 0;
+    }
+
+    pthread_mutex_lock(&mut once.mutex);
+    if once.done.load(Ordering::Relaxed) == 0 {
+        init_routine();
+        once.done.store(1, Ordering::Release);
+    }
+    pthread_mutex_unlock(&mut once.mutex);
+    0
+}
+
+/// A key handed out by `pthread_key_create`.
+#[derive(Clone, Copy)]
+struct Key {
+    used: bool,
+    destructor: Option<unsafe extern "C" fn(*mut c_void)>,
+}
+
+const FREE_KEY: Key = Key {
+    used: false,
+    destructor: None,
+};
+
+static KEYS: spin::Mutex<[Key; PTHREAD_KEYS_MAX]> = spin::Mutex::new([FREE_KEY; PTHREAD_KEYS_MAX]);
+
+/// The values of the keys for one thread, `tcb_pthread` of the thread points
+/// to them (allocated on the first `pthread_setspecific`).
+type KeyValues = [*mut c_void; PTHREAD_KEYS_MAX];
+
+fn key_index(key: pthread_key_t) -> Option<usize> {
+    let idx = key as usize;
+    if key >= 0 && idx < PTHREAD_KEYS_MAX && KEYS.lock()[idx].used {
+        Some(idx)
+    } else {
+        None
+    }
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_key_create(
+    key: *mut pthread_key_t,
+    destructor: Option<unsafe extern "C" fn(*mut c_void)>,
+) -> c_int {
+    let mut keys = KEYS.lock();
+    match keys.iter().position(|k| !k.used) {
+        Some(idx) => {
+            keys[idx] = Key {
+                used: true,
+                destructor,
+            };
+            *key = idx as pthread_key_t;
+            0
+        }
+        None => EAGAIN,
+    }
+}
+
+/// Frees `key`, values threads set for it stay where they are (and their
+/// destructor isn't called).
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_key_delete(key: pthread_key_t) -> c_int {
+    match key_index(key) {
+        Some(idx) => {
+            KEYS.lock()[idx] = FREE_KEY;
+            0
+        }
+        None => EINVAL,
+    }
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_getspecific(key: pthread_key_t) -> *mut c_void {
+    let values = Environment::thread().tcb_pthread as *const KeyValues;
+    match key_index(key) {
+        Some(idx) if !values.is_null() => (*values)[idx],
+        _ => ptr::null_mut(),
+    }
+}
+
+#[cfg_attr(feature = "pthread", no_mangle)]
+pub unsafe extern "C" fn pthread_setspecific(key: pthread_key_t, value: *const c_void) -> c_int {
+    let idx = match key_index(key) {
+        Some(idx) => idx,
+        None => return EINVAL,
+    };
+
+    let tcb = Environment::thread();
+    if tcb.tcb_pthread.is_null() {
+        let values: Box<KeyValues> = Box::new([ptr::null_mut(); PTHREAD_KEYS_MAX]);
+        tcb.tcb_pthread = Box::into_raw(values) as *mut u8;
+    }
+    (*(tcb.tcb_pthread as *mut KeyValues))[idx] = value as *mut c_void;
+    0
+}
+
+/// Calls the destructors of the keys the (exiting) thread has values for.
+///
+/// Destructors can set values again, so we do this a few times.
+unsafe fn run_key_destructors() {
+    let tcb = Environment::thread();
+    if tcb.tcb_pthread.is_null() {
+        return;
+    }
+    let values = tcb.tcb_pthread as *mut KeyValues;
+
+    for _round in 0..PTHREAD_DESTRUCTOR_ITERATIONS {
+        let mut called = false;
+        for idx in 0..PTHREAD_KEYS_MAX {
+            let value = mem::replace(&mut (*values)[idx], ptr::null_mut());
+            if value.is_null() {
+                continue;
+            }
+            let key = KEYS.lock()[idx];
+            if let (true, Some(destructor)) = (key.used, key.destructor) {
+                destructor(value);
+                called = true;
+            }
+        }
+        if !called {
+            break;
+        }
+    }
+
+    tcb.tcb_pthread = ptr::null_mut();
+    drop(Box::from_raw(values));
+}
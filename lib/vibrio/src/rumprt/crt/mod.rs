@@ -281,7 +281,7 @@ pub extern "C" fn main() {
     }
 
     // Split app args into individual parts
-    let parsed_args: Vec<&str> = pinfo.app_cmdline.rsplit(' ').collect();
+    let parsed_args: Vec<&str> = pinfo.app_cmdline.split_whitespace().collect();
     // Necessary to maintain references to the arg CStrings
     let mut ref_args: Vec<CString> = Vec::with_capacity(parsed_args.len() + 1);
     ref_args.push(CString::new("some.bin").unwrap()); // First arg is always bin name
@@ -345,6 +345,16 @@ pub extern "C" fn main() {
                     &tfsa,
                     core::mem::size_of::<tmpfs_args>(),
                 );
+
+                // Files in /data go to the kernel file system (e.g., for
+                // `redis-server --dir /data`)
+                let key = CStr::from_bytes_with_nul(b"/data\0");
+                let hostpath = CStr::from_bytes_with_nul(b"/\0");
+                let etfs_ret =
+                    rump_pub_etfs_register(key.unwrap().as_ptr(), hostpath.unwrap().as_ptr(), 4);
+                if etfs_ret != 0 {
+                    error!("Can't register /data with etfs: {}", etfs_ret);
+                }
             }
 
             #[cfg(feature = "virtio")]
@@ -404,6 +414,7 @@ pub extern "C" fn main() {
 
     loop {
         scheduler.run(&scb);
+        scheduler.idle(&scb);
     }
 
     core::mem::forget(scheduler);
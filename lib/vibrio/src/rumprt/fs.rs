@@ -1,8 +1,6 @@
 // Copyright © 2021 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-use core::convert::TryInto;
-
 use super::{c_int, c_size_t, c_void, rump_biodone_fn};
 use cstr_core::CStr;
 
@@ -108,6 +106,41 @@ pub unsafe extern "C" fn rumpuser_bio(
     unimplemented!("rumpuser_bio");
 }
 
+/// Calls `op` for every buffer in `ruiov` (at consecutive offsets starting
+/// with `off`) until one is short, stores the total in `retv`.
+unsafe fn iovec_io(
+    op: fn(u64, u64, u64, i64) -> Result<u64, kpi::SystemCallError>,
+    fd: c_int,
+    ruiov: *const rumpuser_iovec,
+    iovlen: c_size_t,
+    mut off: i64,
+    retv: *mut c_size_t,
+) -> c_int {
+    let mut total: c_size_t = 0;
+    for i in 0..iovlen {
+        let iov = &*ruiov.add(i);
+        if iov.iov_len == 0 {
+            continue;
+        }
+
+        match op(fd as u64, iov.iov_base as u64, iov.iov_len as u64, off) {
+            Ok(len) => {
+                total += len as c_size_t;
+                off += len as i64;
+                if len < iov.iov_len as u64 {
+                    break;
+                }
+            }
+            // Report what we managed to transfer before the error
+            Err(_) if total > 0 => break,
+            Err(_) => return super::errno::EINVAL as i32,
+        }
+    }
+
+    *retv = total;
+    0
+}
+
 /// int rumpuser_iovread(int fd, struct rumpuser_iovec *ruiov, size_t iovlen, int64_t off, size_t *retv)
 #[no_mangle]
 pub unsafe extern "C" fn rumpuser_iovread(
@@ -117,18 +150,7 @@ pub unsafe extern "C" fn rumpuser_iovread(
     off: i64,
     retv: *mut c_size_t,
 ) -> c_int {
-    match Fs::read_at(
-        fd as u64,
-        (*ruiov).iov_base as u64,
-        (*ruiov).iov_len as u64,
-        off,
-    ) {
-        Ok(len) => {
-            *retv = len.try_into().unwrap();
-            0
-        }
-        Err(_) => super::errno::EINVAL as i32,
-    }
+    iovec_io(Fs::read_at, fd, ruiov, iovlen, off, retv)
 }
 
 /// int rumpuser_iovwrite(int fd, struct rumpuser_iovec *ruiov, size_t iovlen, int64_t off, size_t *retv)
@@ -140,27 +162,22 @@ pub unsafe extern "C" fn rumpuser_iovwrite(
     off: i64,
     retv: *mut c_size_t,
 ) -> c_int {
-    match Fs::write_at(
-        fd as u64,
-        (*ruiov).iov_base as u64,
-        (*ruiov).iov_len as u64,
-        off,
-    ) {
-        Ok(len) => {
-            *retv = len.try_into().unwrap();
-            0
-        }
-        Err(_) => super::errno::EINVAL as i32,
-    }
+    iovec_io(Fs::write_at, fd, ruiov, iovlen, off, retv)
 }
 
 /// int rumpuser_syncfd(int fd, int flags, uint64_t start, uint64_t len)
+///
+/// We don't track ranges or write barriers, all flags (RUMPUSER_SYNCFD_*)
+/// wait for every write to the file.
 #[no_mangle]
 pub unsafe extern "C" fn rumpuser_syncfd(
-    _fd: c_int,
+    fd: c_int,
     _flags: c_int,
     _start: u64,
     _len: u64,
 ) -> c_int {
-    unimplemented!("rumpuser_syncfd");
+    match Fs::fsync(fd as u64) {
+        Ok(()) => 0,
+        Err(_) => super::errno::EBADF as c_int,
+    }
 }
@@ -7,9 +7,8 @@ use core::ops::Add;
 use log::trace;
 use rawtime::Duration;
 
-use lineup::condvar::CondVar;
-use lineup::mutex::Mutex;
-use lineup::rwlock::{RwLock, RwLockIntent};
+use lineup::sync::rwlock::RwLockIntent;
+use lineup::sync::{Condvar, Mutex, RwLock};
 use lineup::tls2::Environment;
 
 use super::{c_int, errno, threads};
@@ -154,21 +153,21 @@ pub unsafe extern "C" fn rumpuser_rw_held(typ: i64, rw: *mut RwLock, rvp: *mut i
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn rumpuser_cv_init(cv: *mut *mut CondVar) {
-    let alloc_cv: Box<CondVar> = Box::new(CondVar::new());
+pub unsafe extern "C" fn rumpuser_cv_init(cv: *mut *mut Condvar) {
+    let alloc_cv: Box<Condvar> = Box::new(Condvar::new());
     *cv = Box::into_raw(alloc_cv);
     trace!("rumpuser_cv_init {:p}", *cv);
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn rumpuser_cv_destroy(cv: *mut CondVar) {
+pub unsafe extern "C" fn rumpuser_cv_destroy(cv: *mut Condvar) {
     trace!("rumpuser_cv_destroy {:p}", cv);
     let to_free = Box::from_raw(cv);
     drop(to_free);
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn rumpuser_cv_wait(cv: *mut CondVar, mtx: *mut Mutex) {
+pub unsafe extern "C" fn rumpuser_cv_wait(cv: *mut Condvar, mtx: *mut Mutex) {
     trace!(
         "{:?} rumpuser_cv_wait {:p} {:p}",
         lineup::tls2::Environment::tid(),
@@ -180,7 +179,7 @@ pub unsafe extern "C" fn rumpuser_cv_wait(cv: *mut CondVar, mtx: *mut Mutex) {
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn rumpuser_cv_wait_nowrap(cv: *mut CondVar, mtx: *mut Mutex) {
+pub unsafe extern "C" fn rumpuser_cv_wait_nowrap(cv: *mut Condvar, mtx: *mut Mutex) {
     trace!(
         "{:?} rumpuser_cv_wait_nowrap {:p} {:p}",
         lineup::tls2::Environment::tid(),
@@ -199,7 +198,7 @@ pub unsafe extern "C" fn rumpuser_cv_wait_nowrap(cv: *mut CondVar, mtx: *mut Mut
 
 #[no_mangle]
 pub unsafe extern "C" fn rumpuser_cv_timedwait(
-    cv: *mut CondVar,
+    cv: *mut Condvar,
     mtx: *mut Mutex,
     sec: u64,
     nanos: u64,
@@ -222,21 +221,21 @@ pub unsafe extern "C" fn rumpuser_cv_timedwait(
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn rumpuser_cv_signal(cv: *mut CondVar) {
+pub unsafe extern "C" fn rumpuser_cv_signal(cv: *mut Condvar) {
     trace!("rumpuser_cv_signal {:p}", cv);
     (*cv).signal();
     trace!("rumpuser_cv_signal completed {:p}", cv);
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn rumpuser_cv_broadcast(cv: *mut CondVar) {
+pub unsafe extern "C" fn rumpuser_cv_broadcast(cv: *mut Condvar) {
     trace!("rumpuser_cv_broadcast {:p}", cv);
     (*cv).broadcast();
     trace!("rumpuser_cv_broadcast completed");
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn rumpuser_cv_has_waiters(cv: *mut CondVar, waiters: *mut i64) {
+pub unsafe extern "C" fn rumpuser_cv_has_waiters(cv: *mut Condvar, waiters: *mut i64) {
     trace!("rumpuser_cv_has_waiters {:p}", cv);
     *waiters = (*cv).has_waiters() as i64;
 }
@@ -30,7 +30,7 @@ use cstr_core::CStr;
 
 use log::{error, info, trace};
 
-use lineup::mutex::Mutex;
+use lineup::sync::Mutex;
 
 pub mod dev;
 pub mod errno;
@@ -337,30 +337,29 @@ pub unsafe extern "C" fn ___lwp_park60(
     }
 
     let retval = if !ts.is_null() {
-        unreachable!("___lwp_park60: executing with non-null ts for first time.");
         const TIMER_ABSTIME: c_int = 0x1;
 
         let sec = (*ts).tv_sec;
-        let nanos = (*ts).tv_sec as u64;
-        let now_before_sleeping = Instant::now();
+        let nanos = (*ts).tv_nsec as u64;
 
-        let until = if flags & TIMER_ABSTIME > 0 {
+        let timeout = if flags & TIMER_ABSTIME > 0 {
             let future = Instant::from_nanos((sec as u128) * 1_000_000_000 + nanos as u128);
-            // TODO(correctness): Checked substraction may be necessary to avoid overflows in case of reordering + IRQs
-            future - now_before_sleeping
+            let now = Instant::now();
+            if future > now {
+                future - now
+            } else {
+                Duration::from_secs(0)
+            }
         } else {
             Duration::from_secs(sec as u64).add(Duration::from_nanos(nanos))
         };
 
-        let wakeup_in = now_before_sleeping + until;
         let t = Environment::thread();
-        t.sleep(until);
-
-        if Instant::now() > wakeup_in {
+        if t.block_timeout(timeout) {
+            0
+        } else {
             super::errno::rumpuser_seterrno(super::errno::ETIMEDOUT);
             -1
-        } else {
-            0
         }
     } else {
         let t = Environment::thread();
@@ -136,11 +136,14 @@ pub unsafe extern "C" fn rumpuser_clock_sleep(enum_rumpclock: u32, sec: i64, nan
     let (until, retval) = match enum_rumpclock as u64 {
         super::RUMPUSER_CLOCK_ABSMONO => {
             let now = Instant::now();
-            (
-                // TODO: this may negative overflow panic on bad timed irq
-                Instant::from_nanos((sec as u128) * 1_000_000_000 + nanos as u128) - now,
-                0,
-            )
+            let deadline = Instant::from_nanos((sec as u128) * 1_000_000_000 + nanos as u128);
+            // poll/kevent timeouts often end up here with a deadline that
+            // passed already
+            if deadline > now {
+                (deadline - now, 0)
+            } else {
+                (Duration::from_secs(0), 0)
+            }
         }
         super::RUMPUSER_CLOCK_RELWALL => (
             Duration::from_secs(sec as u64).add(Duration::from_nanos(nanos)),
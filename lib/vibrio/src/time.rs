@@ -0,0 +1,181 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Measuring time with the kernel's clocks (see `kpi::time`).
+//!
+//! `rawtime::Instant` converts the TSC with a frequency it guesses, so the
+//! same run reports different durations on different machines. Use
+//! `Instant` from here for anything that gets reported (e.g., benchmark
+//! results), it reads the kernel's monotonic clock from the time page.
+//!
+//! `Timer`s are interval timers of the kernel, they count how often they
+//! went off. A periodic timer can also preempt the lineup threads of a core
+//! (see `set_time_slice`).
+
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use kpi::process::MAX_CORES;
+use kpi::syscalls::Time;
+use kpi::time::{TimerFlags, MAX_TIMERS};
+use kpi::SystemCallError;
+
+/// A point in monotonic time (since boot).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Instant {
+    ns: u64,
+}
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant {
+            ns: Time::monotonic_ns().expect("Can't read the monotonic clock"),
+        }
+    }
+
+    /// Time since boot.
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.ns)
+    }
+
+    /// Time from `earlier` to `self` (0 if `earlier` is later).
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.ns.saturating_sub(earlier.ns))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, other: Duration) -> Instant {
+        Instant {
+            ns: self.ns + other.as_nanos() as u64,
+        }
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, other: Instant) -> Duration {
+        self.duration_since(other)
+    }
+}
+
+/// Time since the UNIX epoch (UTC).
+pub fn realtime() -> Result<Duration, SystemCallError> {
+    Time::realtime_ns().map(Duration::from_nanos)
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NEVER: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO: AtomicBool = AtomicBool::new(false);
+
+/// How often each timer went off (by id).
+static EXPIRATIONS: [AtomicU64; MAX_TIMERS] = [NEVER; MAX_TIMERS];
+
+/// Does the timer preempt threads (by id)?
+static PREEMPTS: [AtomicBool; MAX_TIMERS] = [NO; MAX_TIMERS];
+
+/// The timer of `set_time_slice` of each core (its id + 1, 0 for none).
+static TIME_SLICES: [AtomicU64; MAX_CORES] = [NEVER; MAX_CORES];
+
+/// An interval timer, it goes off on the core it was created for.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Timer {
+    id: u64,
+    periodic: bool,
+}
+
+impl Timer {
+    fn create(
+        interval: Duration,
+        flags: TimerFlags,
+        core: usize,
+    ) -> Result<Timer, SystemCallError> {
+        let id = Time::timer_create(interval, flags, core)?;
+        // Ids of cancelled timers come back
+        EXPIRATIONS[id as usize].store(0, Ordering::Release);
+        PREEMPTS[id as usize].store(false, Ordering::Release);
+        Ok(Timer {
+            id,
+            periodic: flags.contains(TimerFlags::PERIODIC),
+        })
+    }
+
+    /// A timer that goes off once, `after` from now.
+    pub fn oneshot(after: Duration, core: usize) -> Result<Timer, SystemCallError> {
+        Timer::create(after, TimerFlags::ONESHOT, core)
+    }
+
+    /// A timer that goes off every `interval` until it is cancelled.
+    pub fn periodic(interval: Duration, core: usize) -> Result<Timer, SystemCallError> {
+        Timer::create(interval, TimerFlags::PERIODIC, core)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// How often it went off (that we know of, the kernel delivers
+    /// expirations when the process runs on the timer's core).
+    pub fn expirations(&self) -> u64 {
+        EXPIRATIONS[self.id as usize].load(Ordering::Acquire)
+    }
+
+    /// Stops the timer (cancelling a one-shot timer that went off is fine).
+    pub fn cancel(self) -> Result<(), SystemCallError> {
+        PREEMPTS[self.id as usize].store(false, Ordering::Release);
+        // The kernel is done with it (and may have handed out its id again)
+        if !self.periodic && self.expirations() > 0 {
+            return Ok(());
+        }
+        Time::timer_cancel(self.id)
+    }
+}
+
+/// Preempts the lineup threads on `core` after they ran for `slice` (stops
+/// preempting them for `None`).
+///
+/// A thread that runs when the timer goes off is preempted unless it can't
+/// be at the moment (see `lineup::preempt`). The kernel delivers timers
+/// when upcalls are enabled again, a thread that disables them for long
+/// runs longer. With shadow stacks for processes, threads aren't preempted.
+pub fn set_time_slice(slice: Option<Duration>, core: usize) -> Result<(), SystemCallError> {
+    let timer = match slice {
+        Some(slice) => {
+            let timer = Timer::periodic(slice, core)?;
+            PREEMPTS[timer.id as usize].store(true, Ordering::Release);
+            timer.id + 1
+        }
+        None => 0,
+    };
+
+    match TIME_SLICES[core].swap(timer, Ordering::AcqRel) {
+        0 => Ok(()),
+        previous => Timer {
+            id: previous - 1,
+            periodic: true,
+        }
+        .cancel(),
+    }
+}
+
+/// Handles the upcall for an interval timer (`kpi::upcall::TIMER`), returns
+/// true if it should preempt the code it interrupted.
+pub(crate) fn timer_expired(arg: u64) -> bool {
+    let (timer, expired) = ((arg & 0xffff_ffff) as usize, arg >> 32);
+    match EXPIRATIONS.get(timer) {
+        Some(count) => {
+            count.fetch_add(expired, Ordering::AcqRel);
+            PREEMPTS[timer].load(Ordering::Acquire)
+        }
+        None => false,
+    }
+}
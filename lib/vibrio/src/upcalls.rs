@@ -19,16 +19,24 @@ use log::trace;
 
 pub static CORES_ONLINE: AtomicUsize = AtomicUsize::new(1);
 
+/// How many system calls the kernel denied (`kpi::upcall::SYSCALL_DENIED`,
+/// see `Process::restrict_syscalls`).
+pub static SYSCALLS_DENIED: AtomicUsize = AtomicUsize::new(0);
+
 lazy_static! {
     pub static ref PROCESS_SCHEDULER: lineup::scheduler::SmpScheduler<'static> = {
         #[cfg(feature = "rumprt")]
         {
-            lineup::scheduler::SmpScheduler::with_upcalls(lineup::upcalls::Upcalls {
+            let s = lineup::scheduler::SmpScheduler::with_upcalls(lineup::upcalls::Upcalls {
                 curlwp: crate::rumprt::rumpkern_curlwp,
                 deschedule: crate::rumprt::rumpkern_unsched,
                 schedule: crate::rumprt::rumpkern_sched,
                 context_switch: crate::rumprt::prt::context_switch,
-            })
+            });
+            // rump picks its (virtual) CPU when a thread schedules, so its
+            // threads can run on any core (softints come in bursts)
+            s.set_work_stealing(true);
+            s
         }
         #[cfg(not(feature = "rumprt"))]
         {
@@ -73,26 +81,142 @@ pub fn upcall_while_enabled(control: &mut kpi::arch::VirtualCpu, cmd: u64, arg:
         }
 
         let scb: SchedulerControlBlock = SchedulerControlBlock::new(core_id as usize);
-        loop {
+        while !scb.revoked.load(Ordering::Relaxed) {
             sched.run(&scb);
+            sched.idle(&scb);
         }
+
+        // The kernel wants the core back, whatever is left moves to core 0
+        log::info!("Giving core ({}) back.", core_id);
+        sched.migrate(core_id as usize, 0);
+        CORES_ONLINE.fetch_sub(1, Ordering::SeqCst);
+        kpi::syscalls::Process::release_core();
     }
 
-    if cmd == 0x2a || cmd == 0x24 {
-        // TODO(correctness): this will use `gs` to access the SchedulerControlBlock
-        // that assumes that we have already called scheduler.run() and we preserve
-        // the SchedulerControlBlock register even if we return from run()
+    if cmd == kpi::upcall::CORE_REVOKED {
+        // Make `run()` return on this core (see `NEW_CORE`) we can't
+        // migrate from here since we might have interrupted the scheduler
         let scheduler = lineup::tls2::Environment::scheduler();
-        log::info!("got interrupt cmd={} arg={}", cmd, arg);
-        assert!(scheduler.pending_irqs.push(cmd).is_ok());
+        scheduler.revoked.store(true, Ordering::Relaxed);
+        trace!("upcall_while_enabled: core {} revoked", arg);
+        unsafe { resume(control) }
+    }
+
+    if cmd == kpi::upcall::PAGE_FAULT {
+        trace!("upcall_while_enabled: page fault at {:#x}", arg);
+        crate::fault::dispatch(control);
+        unsafe { resume(control) }
+    }
+
+    if cmd == kpi::upcall::SYSCALL_DENIED {
+        // Printing may be what got denied, the caller gets an error anyways
+        SYSCALLS_DENIED.fetch_add(1, Ordering::Relaxed);
+        trace!(
+            "upcall_while_enabled: system call {} (operation {}) denied",
+            arg >> 32,
+            arg & 0xffff_ffff
+        );
+        unsafe { resume(control) }
+    }
+
+    if cmd == kpi::upcall::TIMER {
+        trace!("upcall_while_enabled: timer {:#x}", arg);
+        if crate::time::timer_expired(arg) && lineup::preempt::preemptible() {
+            unsafe { preempt(control) }
+        }
+        unsafe { resume(control) }
+    }
+
+    // TODO(correctness): this will use `gs` to access the SchedulerControlBlock
+    // that assumes that we have already called scheduler.run() and we preserve
+    // the SchedulerControlBlock register even if we return from run()
+    let scheduler = lineup::tls2::Environment::scheduler();
+    if sched.raise(scheduler, cmd) {
+        trace!("upcall_while_enabled: raised vector {}", cmd);
     } else {
-        log::error!("got unknown interrupt... {}", cmd);
+        log::error!(
+            "got upcall {} (arg={}) but no thread handles it on core {}",
+            cmd,
+            arg,
+            scheduler.core_id
+        );
     }
 
     trace!("upcall_while_enabled: renable and resume...");
     unsafe { resume(control) }
 }
 
+/// Bytes below the stack pointer a function may use without moving it
+/// (the red zone of the System V ABI), we can't touch them.
+const RED_ZONE: u64 = 128;
+
+/// Makes the thread in `control.enabled_state` call
+/// `lineup::preempt::preempted` (through `vibrio_preempt`) once it resumes,
+/// as if it called it where it was interrupted.
+///
+/// With shadow stacks (`upcall_ssp`) the `iretq` of `resume` has to go to
+/// where the thread was, it isn't preempted then.
+unsafe fn preempt(control: &mut kpi::arch::VirtualCpu) {
+    if control.upcall_ssp != 0 {
+        return;
+    }
+
+    let state = &mut control.enabled_state;
+    let rsp = state.rsp - RED_ZONE - 8;
+    *(rsp as *mut u64) = state.rip;
+    state.rsp = rsp;
+    state.rip = vibrio_preempt as u64;
+}
+
+extern "C" {
+    fn vibrio_preempt();
+}
+
+#[no_mangle]
+extern "C" fn vibrio_preempted() {
+    lineup::preempt::preempted();
+}
+
+// Entered with the interrupted instruction pointer on the stack (below the
+// red zone), saves what a call doesn't, `vibrio_preempted` runs on an
+// aligned stack and `ret $128` goes back to where the thread was
+global_asm!(
+    "
+    .global vibrio_preempt
+vibrio_preempt:
+    pushfq
+    pushq %rax
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    pushq %rbp
+    movq %rsp, %rbp
+    andq $-64, %rsp
+    subq $512, %rsp
+    fxsaveq (%rsp)
+    callq vibrio_preempted
+    fxrstorq (%rsp)
+    movq %rbp, %rsp
+    popq %rbp
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rdi
+    popq %rsi
+    popq %rdx
+    popq %rcx
+    popq %rax
+    popfq
+    retq $128
+"
+);
+
 /// A trap (exception or fault) happened while disabled, this is bad and
 /// shouldn't happen (i.e., it means there is a bug) in the user-space
 /// scheduler logic or upcall handling.
@@ -109,6 +233,25 @@ pub unsafe fn resume(control: &mut kpi::arch::VirtualCpu) -> ! {
     //debug!("resume enabled_state {:p}", &control.enabled_state);
 
     llvm_asm! {"
+            // With shadow stacks, pop everything the upcall pushed on top of
+            // the frame the kernel left for us at `upcall_ssp` (the `iretq`
+            // below checks and pops it), `incsspq` takes at most 255
+            testq %rdi, %rdi
+            jz 3f
+            rdsspq %rax
+            subq %rax, %rdi
+            shrq $$3, %rdi
+        1:
+            cmpq $$255, %rdi
+            jbe 2f
+            movq $$255, %rax
+            incsspq %rax
+            subq $$255, %rdi
+            jmp 1b
+        2:
+            incsspq %rdi
+        3:
+
             // Restore gs
             //movq 18*8(%rsi), %rdi
             //wrgsbase %rdi
@@ -160,6 +303,7 @@ pub unsafe fn resume(control: &mut kpi::arch::VirtualCpu) -> ! {
     : /* No output */
     :
       "{rsi}" (&control.enabled_state)
+      "{rdi}" (control.upcall_ssp)
     :
     :
     };
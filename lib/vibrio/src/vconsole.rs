@@ -3,7 +3,10 @@
 
 //! A simple virtual console for user-space programs (getchar et. al.).
 //!
-//! Needs to be a proper serial driver.
+//! Input comes from the kernel's keyboard drivers (serial input still needs
+//! a proper serial driver).
+
+use kpi::system::KeyEvent;
 
 static COM1_IRQ: u64 = 4 + 32;
 
@@ -11,6 +14,16 @@ pub fn init() {
     crate::syscalls::Irq::irqalloc(COM1_IRQ, 0).ok();
 }
 
-fn _getchar() -> Option<char> {
-    None
+/// Returns the next character typed on the keyboard (doesn't block, `None`
+/// if nothing was typed).
+pub fn getchar() -> Option<char> {
+    let mut event = [KeyEvent::default(); 1];
+    loop {
+        match crate::syscalls::System::read_key_events(&mut event) {
+            Ok(1) if event[0].ascii != 0 => return Some(event[0].ascii as char),
+            // Key releases and keys without a character
+            Ok(1) => continue,
+            _ => return None,
+        }
+    }
 }
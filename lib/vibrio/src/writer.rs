@@ -3,8 +3,17 @@
 
 //! A simple printing infrastructure for user-space programs.
 //! We provide [`core::fmt::Write`] and [`log::Log`].
+//!
+//! `sys_print!` and `sys_println!` make a system call for every piece they
+//! write. `print!` and `println!` collect the output of a thread in a
+//! buffer and only make a system call once a line is complete (or the
+//! buffer is full), `flush` writes out what's left. `eprint!` and
+//! `eprintln!` aren't buffered, but still only make one system call for
+//! (short) messages.
 
-use core::{fmt, ops};
+use core::{fmt, ops, str};
+
+use lineup::tls2::Environment;
 
 use log::{Level, Metadata, Record};
 
@@ -28,6 +37,184 @@ macro_rules! sys_print {
 	})
 }
 
+/// print macro that writes to the (buffered) standard output.
+#[macro_export]
+macro_rules! print {
+    ( $($arg:tt)* ) => ({
+        $crate::writer::_print(format_args!($($arg)*));
+    })
+}
+
+/// println macro that writes to the (buffered) standard output.
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\r\n"));
+    ( $($arg:tt)* ) => ({
+        $crate::writer::_print(format_args!("{}\r\n", format_args!($($arg)*)));
+    })
+}
+
+/// print macro that writes to the (unbuffered) standard error.
+#[macro_export]
+macro_rules! eprint {
+    ( $($arg:tt)* ) => ({
+        $crate::writer::_eprint(format_args!($($arg)*));
+    })
+}
+
+/// println macro that writes to the (unbuffered) standard error.
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\r\n"));
+    ( $($arg:tt)* ) => ({
+        $crate::writer::_eprint(format_args!("{}\r\n", format_args!($($arg)*)));
+    })
+}
+
+/// Size of the output buffer of a thread.
+const BUFFER_SIZE: usize = 512;
+
+/// Output that hasn't been written yet.
+struct LineBuffer {
+    buf: [u8; BUFFER_SIZE],
+    len: usize,
+    /// Set while we write to the buffer (an upcall that prints while the
+    /// thread it interrupted does doesn't use the buffer).
+    busy: bool,
+}
+
+impl LineBuffer {
+    const fn new() -> LineBuffer {
+        LineBuffer {
+            buf: [0; BUFFER_SIZE],
+            len: 0,
+            busy: false,
+        }
+    }
+
+    fn write(&mut self, s: &str) {
+        if self.len + s.len() > BUFFER_SIZE {
+            self.flush();
+            if s.len() > BUFFER_SIZE {
+                print_str(s);
+                return;
+            }
+        }
+        self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len();
+
+        if s.as_bytes().contains(&b'\n') {
+            self.flush_lines();
+        }
+    }
+
+    /// Writes everything up to the last newline.
+    fn flush_lines(&mut self) {
+        if let Some(end) = self.buf[..self.len].iter().rposition(|&b| b == b'\n') {
+            self.write_out(end + 1);
+        }
+    }
+
+    fn flush(&mut self) {
+        self.write_out(self.len);
+    }
+
+    /// Writes the first `len` bytes and moves the rest to the front.
+    fn write_out(&mut self, len: usize) {
+        if len == 0 {
+            return;
+        }
+        // We only append whole strings and split after a newline
+        print_str(unsafe { str::from_utf8_unchecked(&self.buf[..len]) });
+        self.buf.copy_within(len..self.len, 0);
+        self.len -= len;
+    }
+}
+
+/// The output buffer of the current thread.
+#[thread_local]
+static mut STDOUT: LineBuffer = LineBuffer::new();
+
+fn print_str(s: &str) {
+    crate::syscalls::Process::print(s).expect("Can't write string");
+}
+
+/// Runs `f` with the buffer of the current thread, returns false if there
+/// is none we can use.
+fn with_stdout<F: FnOnce(&mut LineBuffer)>(f: F) -> bool {
+    // Thread-local variables need a thread (and its TLS area)
+    if !Environment::has_thread() {
+        return false;
+    }
+
+    let stdout = unsafe { &mut STDOUT };
+    if stdout.busy {
+        return false;
+    }
+    stdout.busy = true;
+    f(stdout);
+    stdout.busy = false;
+    true
+}
+
+/// Writes out what `print!` buffered for the current thread.
+pub fn flush() {
+    with_stdout(|stdout| stdout.flush());
+}
+
+/// The standard output (buffered per thread).
+pub struct Stdout;
+
+impl fmt::Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if !with_stdout(|stdout| stdout.write(s)) {
+            print_str(s);
+        }
+        Ok(())
+    }
+}
+
+/// Formats a message on the stack (so we can write it at once).
+struct StackBuffer {
+    buf: [u8; BUFFER_SIZE],
+    len: usize,
+}
+
+impl fmt::Write for StackBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.len + s.len() > BUFFER_SIZE {
+            print_str(unsafe { str::from_utf8_unchecked(&self.buf[..self.len]) });
+            self.len = 0;
+            if s.len() > BUFFER_SIZE {
+                print_str(s);
+                return Ok(());
+            }
+        }
+        self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    let _ = Stdout.write_fmt(args);
+}
+
+#[doc(hidden)]
+pub fn _eprint(args: fmt::Arguments) {
+    use core::fmt::Write;
+    let mut msg = StackBuffer {
+        buf: [0; BUFFER_SIZE],
+        len: 0,
+    };
+    let _ = msg.write_fmt(args);
+    if msg.len > 0 {
+        print_str(unsafe { str::from_utf8_unchecked(&msg.buf[..msg.len]) });
+    }
+}
+
 pub struct Writer;
 
 impl Writer {
@@ -88,7 +275,7 @@ impl log::Log for ULogger {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            sys_println!(
+            println!(
                 "[{}] - {}: {}",
                 record.level(),
                 record.target(),
@@ -97,5 +284,7 @@ impl log::Log for ULogger {
         }
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        flush();
+    }
 }
@@ -183,13 +183,27 @@ pub struct VMXNet3 {
 impl DmaObject for VMXNet3 {}
 
 impl VMXNet3 {
+    /// Creates the driver for the device at the default location on the
+    /// bus (where our QEMU set-up puts it), see also `new_at`.
     pub fn new(nrx: usize, trx: usize) -> Result<Pin<Box<VMXNet3>>, VMXNet3Error> {
-        // TODO: supply as arguments/type
         const BUS: u32 = 0x0;
         const DEV: u32 = 0x10;
         const FUN: u32 = 0x0;
 
-        let pci = BarAccess::new(BUS, DEV, FUN);
+        VMXNet3::new_at(BUS, DEV, FUN, nrx, trx)
+    }
+
+    /// Creates the driver for the device at `bus`:`dev`.`fun`.
+    ///
+    /// The BARs of the device must be mapped already.
+    pub fn new_at(
+        bus: u32,
+        dev: u32,
+        fun: u32,
+        nrx: usize,
+        trx: usize,
+    ) -> Result<Pin<Box<VMXNet3>>, VMXNet3Error> {
+        let pci = BarAccess::new(bus, dev, fun);
 
         let ntxqsets = BoundedUSize::<1, VMXNET3_MAX_TX_QUEUES>::new(trx);
         let nrxqsets = BoundedUSize::<1, VMXNET3_MAX_RX_QUEUES>::new(nrx);
@@ -0,0 +1,367 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A key-value benchmark in the style of LevelDB's `db_bench` on the native
+//! file system.
+//!
+//! The database is a single file of fixed-size records, the record of key
+//! `k` is at offset `k * RECORD_SIZE`. Writers call `Fs::fsync` after every
+//! `SYNC_EVERY` writes.
+//!
+//! Started with `initargs=<cores>X<benchmark>`, where benchmark is one of:
+//! * `fillseq`: Every core writes the keys of its part of the key space in
+//!   order.
+//! * `fillrandom`: Every core writes random keys.
+//! * `readseq`: Every core reads the database from start to end (with
+//!   `Fs::read`).
+//! * `readrandom`: Every core reads random keys.
+//! * `readwhilewriting`: Core 0 writes random keys, the other cores read
+//!   random keys.
+
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{error, info};
+use x86::bits64::paging::VAddr;
+
+use vibrio::io::{FileFlags, FileModes};
+use vibrio::syscalls::Fs;
+
+/// The database.
+const DB_FILE: &str = "/dbbench.db\0";
+
+/// How many keys the database has.
+#[cfg(not(feature = "smoke"))]
+const NUM_KEYS: u64 = 100_000;
+#[cfg(feature = "smoke")]
+const NUM_KEYS: u64 = 10_000;
+
+/// A record is the key (as little-endian u64) followed by the value.
+const KEY_SIZE: usize = 8;
+const VALUE_SIZE: usize = 100;
+const RECORD_SIZE: usize = 128;
+
+/// How many writes a writer does between two `Fs::fsync` calls.
+const SYNC_EVERY: usize = 100;
+
+const STACK_SIZE: usize = 32 * 4096;
+
+/// Bench threads that are ready to start.
+static POOR_MANS_BARRIER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Benchmark {
+    FillSeq,
+    FillRandom,
+    ReadSeq,
+    ReadRandom,
+    ReadWhileWriting,
+}
+
+impl Benchmark {
+    fn from_name(name: &str) -> Option<Benchmark> {
+        match name {
+            "fillseq" => Some(Benchmark::FillSeq),
+            "fillrandom" => Some(Benchmark::FillRandom),
+            "readseq" => Some(Benchmark::ReadSeq),
+            "readrandom" => Some(Benchmark::ReadRandom),
+            "readwhilewriting" => Some(Benchmark::ReadWhileWriting),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Benchmark::FillSeq => "fillseq",
+            Benchmark::FillRandom => "fillrandom",
+            Benchmark::ReadSeq => "readseq",
+            Benchmark::ReadRandom => "readrandom",
+            Benchmark::ReadWhileWriting => "readwhilewriting",
+        }
+    }
+
+    /// Does the database have to be filled before the benchmark starts?
+    fn needs_data(&self) -> bool {
+        !matches!(self, Benchmark::FillSeq | Benchmark::FillRandom)
+    }
+}
+
+/// xorshift64*, every core gets its own sequence of keys.
+struct Random(u64);
+
+impl Random {
+    fn new(core: usize) -> Random {
+        Random((core as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15))
+    }
+
+    fn next_key(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % NUM_KEYS
+    }
+}
+
+fn offset(key: u64) -> i64 {
+    (key * RECORD_SIZE as u64) as i64
+}
+
+/// Checks that `record` is the record of `key`.
+fn check_record(record: &[u8; RECORD_SIZE], key: u64) {
+    let mut stored = [0u8; KEY_SIZE];
+    stored.copy_from_slice(&record[..KEY_SIZE]);
+    assert_eq!(u64::from_le_bytes(stored), key, "Read the wrong record");
+}
+
+struct Writer {
+    fd: u64,
+    writes: usize,
+    record: [u8; RECORD_SIZE],
+}
+
+impl Writer {
+    fn new(fd: u64) -> Writer {
+        let mut record = [0u8; RECORD_SIZE];
+        for b in record[KEY_SIZE..KEY_SIZE + VALUE_SIZE].iter_mut() {
+            *b = 0xdb;
+        }
+        Writer {
+            fd,
+            writes: 0,
+            record,
+        }
+    }
+
+    fn put(&mut self, key: u64) {
+        self.record[..KEY_SIZE].copy_from_slice(&key.to_le_bytes());
+        let written = Fs::write_at(
+            self.fd,
+            self.record.as_ptr() as u64,
+            RECORD_SIZE as u64,
+            offset(key),
+        )
+        .expect("FileWriteAt syscall failed");
+        assert_eq!(written, RECORD_SIZE as u64);
+
+        self.writes += 1;
+        if self.writes % SYNC_EVERY == 0 {
+            self.sync();
+        }
+    }
+
+    fn sync(&self) {
+        Fs::fsync(self.fd).expect("FSync syscall failed");
+    }
+}
+
+/// Reads the record of a random key.
+fn get(fd: u64, key: u64, record: &mut [u8; RECORD_SIZE]) {
+    let read = Fs::read_at(
+        fd,
+        record.as_mut_ptr() as u64,
+        RECORD_SIZE as u64,
+        offset(key),
+    )
+    .expect("FileReadAt syscall failed");
+    assert_eq!(read, RECORD_SIZE as u64);
+    check_record(record, key);
+}
+
+fn open(flags: FileFlags) -> u64 {
+    Fs::open(
+        DB_FILE.as_ptr() as u64,
+        u64::from(flags),
+        u64::from(FileModes::S_IRWXU),
+    )
+    .expect("FileOpen syscall failed")
+}
+
+/// Reads the database in order, starts over at the end.
+struct Scanner {
+    fd: u64,
+    key: u64,
+}
+
+impl Scanner {
+    fn new() -> Scanner {
+        Scanner {
+            fd: open(FileFlags::O_RDONLY),
+            key: 0,
+        }
+    }
+
+    fn next(&mut self, record: &mut [u8; RECORD_SIZE]) {
+        let mut read = Fs::read(self.fd, record.as_mut_ptr() as u64, RECORD_SIZE as u64)
+            .expect("FileRead syscall failed");
+        if read == 0 {
+            // There is no seek, a new descriptor starts at the beginning
+            Fs::close(self.fd).expect("FileClose syscall failed");
+            self.fd = open(FileFlags::O_RDONLY);
+            self.key = 0;
+            read = Fs::read(self.fd, record.as_mut_ptr() as u64, RECORD_SIZE as u64)
+                .expect("FileRead syscall failed");
+        }
+        assert_eq!(read, RECORD_SIZE as u64);
+        check_record(record, self.key);
+        self.key += 1;
+    }
+}
+
+/// Runs `op` for the duration of the benchmark, reports how many times it
+/// ran every second.
+fn measure<F: FnMut()>(benchmark: Benchmark, kind: &str, cores: usize, core: usize, mut op: F) {
+    let duration = if cfg!(feature = "smoke") { 1 } else { 10 };
+
+    for iteration in 1..duration + 1 {
+        let mut ops = 0;
+        let start = vibrio::time::Instant::now();
+        while start.elapsed().as_secs() < 1 {
+            op();
+            ops += 1;
+        }
+        info!(
+            "{},{},{},{},{},{},{},{}",
+            core,
+            benchmark.name(),
+            kind,
+            cores,
+            VALUE_SIZE,
+            duration,
+            iteration,
+            ops
+        );
+    }
+}
+
+fn bencher(benchmark: Benchmark, fd: u64, cores: usize, core: usize) {
+    let mut record = [0u8; RECORD_SIZE];
+    let mut writer = Writer::new(fd);
+    let mut random = Random::new(core);
+    // Only used by readseq, opened before the barrier
+    let mut scanner = if benchmark == Benchmark::ReadSeq {
+        Some(Scanner::new())
+    } else {
+        None
+    };
+
+    POOR_MANS_BARRIER.fetch_add(1, Ordering::Relaxed);
+    while POOR_MANS_BARRIER.load(Ordering::Relaxed) < cores {
+        core::sync::atomic::spin_loop_hint();
+    }
+
+    match benchmark {
+        Benchmark::FillSeq => {
+            let share = NUM_KEYS / cores as u64;
+            let first = share * core as u64;
+            let mut key = first;
+            measure(benchmark, "write", cores, core, || {
+                writer.put(key);
+                key += 1;
+                if key == first + share {
+                    key = first;
+                }
+            });
+        }
+        Benchmark::FillRandom => {
+            measure(benchmark, "write", cores, core, || {
+                writer.put(random.next_key())
+            });
+        }
+        Benchmark::ReadSeq => {
+            let scanner = scanner.as_mut().unwrap();
+            measure(benchmark, "read", cores, core, || scanner.next(&mut record));
+        }
+        Benchmark::ReadRandom => {
+            measure(benchmark, "read", cores, core, || {
+                get(fd, random.next_key(), &mut record)
+            });
+        }
+        Benchmark::ReadWhileWriting if core == 0 => {
+            measure(benchmark, "write", cores, core, || {
+                writer.put(random.next_key())
+            });
+        }
+        Benchmark::ReadWhileWriting => {
+            measure(benchmark, "read", cores, core, || {
+                get(fd, random.next_key(), &mut record)
+            });
+        }
+    }
+    writer.sync();
+}
+
+/// Asks the kernel for `ncores` cores (including the one we run on),
+/// returns how many we got.
+fn request_cores(ncores: usize) -> usize {
+    let hwthreads = vibrio::syscalls::System::threads().expect("Can't get system topology");
+
+    let mut cores = 1; // We already have core 0
+    for hwthread in hwthreads.iter().take(ncores) {
+        if hwthread.id != 0 {
+            match vibrio::syscalls::Process::request_core(
+                hwthread.id,
+                VAddr::from(vibrio::upcalls::upcall_while_enabled as *const fn() as u64),
+            ) {
+                Ok(_) => cores += 1,
+                Err(e) => {
+                    error!("Can't spawn on {:?}: {:?}", hwthread.id, e);
+                    break;
+                }
+            }
+        }
+    }
+    cores
+}
+
+/// Runs the benchmark given by `args` (`<cores>X<benchmark>`).
+pub fn bench(args: &str) {
+    let parts: Vec<&str> = args.split('X').collect();
+    let (ncores, benchmark) = match parts.as_slice() {
+        [cores, name] => (cores.parse::<usize>().ok(), Benchmark::from_name(name)),
+        _ => (None, None),
+    };
+    let (ncores, benchmark) = match (ncores, benchmark) {
+        (Some(ncores), Some(benchmark)) => (ncores, benchmark),
+        _ => panic!(
+            "dbbench: expected initargs=<cores>X<benchmark>, got {}",
+            args
+        ),
+    };
+    assert!(
+        benchmark != Benchmark::ReadWhileWriting || ncores > 1,
+        "readwhilewriting needs a core for the writer and at least one for readers"
+    );
+
+    let fd = open(FileFlags::O_RDWR | FileFlags::O_CREAT);
+    if benchmark.needs_data() {
+        let mut writer = Writer::new(fd);
+        for key in 0..NUM_KEYS {
+            writer.put(key);
+        }
+        writer.sync();
+    }
+
+    let cores = request_cores(ncores);
+    info!("Spawned {} cores", cores);
+    info!("thread_id,benchmark,op,ncores,value_size,duration_total,duration,operations");
+
+    let s = &vibrio::upcalls::PROCESS_SCHEDULER;
+    for core in 0..cores {
+        s.spawn(
+            STACK_SIZE,
+            move |_| bencher(benchmark, fd, cores, core),
+            ptr::null_mut(),
+            core,
+            None,
+        )
+        .expect("Can't spawn bench thread");
+    }
+
+    let scb = lineup::tls2::SchedulerControlBlock::new(0);
+    while s.has_active_threads() {
+        s.run(&scb);
+    }
+
+    Fs::close(fd).expect("FileClose syscall failed");
+}
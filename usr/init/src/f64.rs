@@ -1,897 +0,0 @@
-// Copyright © 2021 VMware, Inc. All Rights Reserved.
-// Copyright © 2014-2021 The Rust Project, All Rights Reserved.
-// SPDX-License-Identifier: Apache-2.0 OR MIT
-
-//! A temporary port of f64 math functions to make histograms works
-//!
-//! Will become unnecessary with https://github.com/rust-lang/rfcs/issues/2505
-
-use core::intrinsics;
-use libm;
-
-pub use core::f64::{
-    consts, DIGITS, EPSILON, INFINITY, MANTISSA_DIGITS, MAX, MAX_10_EXP, MAX_EXP, MIN, MIN_10_EXP,
-    MIN_EXP, MIN_POSITIVE, NAN, NEG_INFINITY, RADIX,
-};
-
-#[lang = "f64_runtime"]
-impl f64 {
-    /// Returns the largest integer less than or equal to a number.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let f = 3.7_f64;
-    /// let g = 3.0_f64;
-    /// let h = -3.7_f64;
-    ///
-    /// assert_eq!(f.floor(), 3.0);
-    /// assert_eq!(g.floor(), 3.0);
-    /// assert_eq!(h.floor(), -4.0);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn floor(self) -> f64 {
-        unsafe { libm::floor(self) }
-    }
-
-    /// Returns the smallest integer greater than or equal to a number.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let f = 3.01_f64;
-    /// let g = 4.0_f64;
-    ///
-    /// assert_eq!(f.ceil(), 4.0);
-    /// assert_eq!(g.ceil(), 4.0);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn ceil(self) -> f64 {
-        unsafe { libm::ceil(self) }
-    }
-
-    /// Returns the nearest integer to a number. Round half-way cases away from
-    /// `0.0`.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let f = 3.3_f64;
-    /// let g = -3.3_f64;
-    ///
-    /// assert_eq!(f.round(), 3.0);
-    /// assert_eq!(g.round(), -3.0);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn round(self) -> f64 {
-        unsafe { intrinsics::roundf64(self) }
-    }
-
-    /// Returns the integer part of a number.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let f = 3.7_f64;
-    /// let g = 3.0_f64;
-    /// let h = -3.7_f64;
-    ///
-    /// assert_eq!(f.trunc(), 3.0);
-    /// assert_eq!(g.trunc(), 3.0);
-    /// assert_eq!(h.trunc(), -3.0);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn trunc(self) -> f64 {
-        unsafe { intrinsics::truncf64(self) }
-    }
-
-    /// Returns the fractional part of a number.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let x = 3.6_f64;
-    /// let y = -3.6_f64;
-    /// let abs_difference_x = (x.fract() - 0.6).abs();
-    /// let abs_difference_y = (y.fract() - (-0.6)).abs();
-    ///
-    /// assert!(abs_difference_x < 1e-10);
-    /// assert!(abs_difference_y < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn fract(self) -> f64 {
-        self - self.trunc()
-    }
-
-    /// Computes the absolute value of `self`. Returns `NAN` if the
-    /// number is `NAN`.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use std::f64;
-    ///
-    /// let x = 3.5_f64;
-    /// let y = -3.5_f64;
-    ///
-    /// let abs_difference_x = (x.abs() - x).abs();
-    /// let abs_difference_y = (y.abs() - (-y)).abs();
-    ///
-    /// assert!(abs_difference_x < 1e-10);
-    /// assert!(abs_difference_y < 1e-10);
-    ///
-    /// assert!(f64::NAN.abs().is_nan());
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn abs(self) -> f64 {
-        unsafe { intrinsics::fabsf64(self) }
-    }
-
-    /// Returns a number that represents the sign of `self`.
-    ///
-    /// - `1.0` if the number is positive, `+0.0` or `INFINITY`
-    /// - `-1.0` if the number is negative, `-0.0` or `NEG_INFINITY`
-    /// - `NAN` if the number is `NAN`
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use std::f64;
-    ///
-    /// let f = 3.5_f64;
-    ///
-    /// assert_eq!(f.signum(), 1.0);
-    /// assert_eq!(f64::NEG_INFINITY.signum(), -1.0);
-    ///
-    /// assert!(f64::NAN.signum().is_nan());
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn signum(self) -> f64 {
-        if self.is_nan() {
-            NAN
-        } else {
-            1.0_f64.copysign(self)
-        }
-    }
-
-    /// Returns a number composed of the magnitude of `self` and the sign of
-    /// `sign`.
-    ///
-    /// Equal to `self` if the sign of `self` and `sign` are the same, otherwise
-    /// equal to `-self`. If `self` is a `NAN`, then a `NAN` with the sign of
-    /// `sign` is returned.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use std::f64;
-    ///
-    /// let f = 3.5_f64;
-    ///
-    /// assert_eq!(f.copysign(0.42), 3.5_f64);
-    /// assert_eq!(f.copysign(-0.42), -3.5_f64);
-    /// assert_eq!((-f).copysign(0.42), 3.5_f64);
-    /// assert_eq!((-f).copysign(-0.42), -3.5_f64);
-    ///
-    /// assert!(f64::NAN.copysign(1.0).is_nan());
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn copysign(self, sign: f64) -> f64 {
-        unsafe { intrinsics::copysignf64(self, sign) }
-    }
-
-    /// Fused multiply-add. Computes `(self * a) + b` with only one rounding
-    /// error, yielding a more accurate result than an unfused multiply-add.
-    ///
-    /// Using `mul_add` can be more performant than an unfused multiply-add if
-    /// the target architecture has a dedicated `fma` CPU instruction.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let m = 10.0_f64;
-    /// let x = 4.0_f64;
-    /// let b = 60.0_f64;
-    ///
-    /// // 100.0
-    /// let abs_difference = (m.mul_add(x, b) - ((m * x) + b)).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn mul_add(self, a: f64, b: f64) -> f64 {
-        unsafe { intrinsics::fmaf64(self, a, b) }
-    }
-
-    /// Calculates Euclidean division, the matching method for `rem_euclid`.
-    ///
-    /// This computes the integer `n` such that
-    /// `self = n * rhs + self.rem_euclid(rhs)`.
-    /// In other words, the result is `self / rhs` rounded to the integer `n`
-    /// such that `self >= n * rhs`.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let a: f64 = 7.0;
-    /// let b = 4.0;
-    /// assert_eq!(a.div_euclid(b), 1.0); // 7.0 > 4.0 * 1.0
-    /// assert_eq!((-a).div_euclid(b), -2.0); // -7.0 >= 4.0 * -2.0
-    /// assert_eq!(a.div_euclid(-b), -1.0); // 7.0 >= -4.0 * -1.0
-    /// assert_eq!((-a).div_euclid(-b), 2.0); // -7.0 >= -4.0 * 2.0
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn div_euclid(self, rhs: f64) -> f64 {
-        let q = (self / rhs).trunc();
-        if self % rhs < 0.0 {
-            return if rhs > 0.0 { q - 1.0 } else { q + 1.0 };
-        }
-        q
-    }
-
-    /// Calculates the least nonnegative remainder of `self (mod rhs)`.
-    ///
-    /// In particular, the return value `r` satisfies `0.0 <= r < rhs.abs()` in
-    /// most cases. However, due to a floating point round-off error it can
-    /// result in `r == rhs.abs()`, violating the mathematical definition, if
-    /// `self` is much smaller than `rhs.abs()` in magnitude and `self < 0.0`.
-    /// This result is not an element of the function's codomain, but it is the
-    /// closest floating point number in the real numbers and thus fulfills the
-    /// property `self == self.div_euclid(rhs) * rhs + self.rem_euclid(rhs)`
-    /// approximatively.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let a: f64 = 7.0;
-    /// let b = 4.0;
-    /// assert_eq!(a.rem_euclid(b), 3.0);
-    /// assert_eq!((-a).rem_euclid(b), 1.0);
-    /// assert_eq!(a.rem_euclid(-b), 3.0);
-    /// assert_eq!((-a).rem_euclid(-b), 1.0);
-    /// // limitation due to round-off error
-    /// assert!((-std::f64::EPSILON).rem_euclid(3.0) != 0.0);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn rem_euclid(self, rhs: f64) -> f64 {
-        let r = self % rhs;
-        if r < 0.0 {
-            r + rhs.abs()
-        } else {
-            r
-        }
-    }
-
-    /// Raises a number to an integer power.
-    ///
-    /// Using this function is generally faster than using `powf`
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let x = 2.0_f64;
-    /// let abs_difference = (x.powi(2) - (x * x)).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn powi(self, n: i32) -> f64 {
-        unsafe { intrinsics::powif64(self, n) }
-    }
-
-    /// Raises a number to a floating point power.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let x = 2.0_f64;
-    /// let abs_difference = (x.powf(2.0) - (x * x)).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn powf(self, n: f64) -> f64 {
-        unsafe { intrinsics::powf64(self, n) }
-    }
-
-    /// Returns the square root of a number.
-    ///
-    /// Returns NaN if `self` is a negative number.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let positive = 4.0_f64;
-    /// let negative = -4.0_f64;
-    ///
-    /// let abs_difference = (positive.sqrt() - 2.0).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// assert!(negative.sqrt().is_nan());
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn sqrt(self) -> f64 {
-        unsafe { intrinsics::sqrtf64(self) }
-    }
-
-    /// Returns `e^(self)`, (the exponential function).
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let one = 1.0_f64;
-    /// // e^1
-    /// let e = one.exp();
-    ///
-    /// // ln(e) - 1 == 0
-    /// let abs_difference = (e.ln() - 1.0).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn exp(self) -> f64 {
-        unsafe { intrinsics::expf64(self) }
-    }
-
-    /// Returns `2^(self)`.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let f = 2.0_f64;
-    ///
-    /// // 2^2 - 4 == 0
-    /// let abs_difference = (f.exp2() - 4.0).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn exp2(self) -> f64 {
-        unsafe { intrinsics::exp2f64(self) }
-    }
-
-    /// Returns the natural logarithm of the number.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let one = 1.0_f64;
-    /// // e^1
-    /// let e = one.exp();
-    ///
-    /// // ln(e) - 1 == 0
-    /// let abs_difference = (e.ln() - 1.0).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn ln(self) -> f64 {
-        self.log_wrapper(|n| unsafe { intrinsics::logf64(n) })
-    }
-
-    /// Returns the logarithm of the number with respect to an arbitrary base.
-    ///
-    /// The result may not be correctly rounded owing to implementation details;
-    /// `self.log2()` can produce more accurate results for base 2, and
-    /// `self.log10()` can produce more accurate results for base 10.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let twenty_five = 25.0_f64;
-    ///
-    /// // log5(25) - 2 == 0
-    /// let abs_difference = (twenty_five.log(5.0) - 2.0).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn log(self, base: f64) -> f64 {
-        self.ln() / base.ln()
-    }
-
-    /// Returns the base 2 logarithm of the number.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let four = 4.0_f64;
-    ///
-    /// // log2(4) - 2 == 0
-    /// let abs_difference = (four.log2() - 2.0).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn log2(self) -> f64 {
-        self.log_wrapper(|n| {
-            #[cfg(target_os = "android")]
-            return crate::sys::android::log2f64(n);
-            #[cfg(not(target_os = "android"))]
-            return unsafe { intrinsics::log2f64(n) };
-        })
-    }
-
-    /// Returns the base 10 logarithm of the number.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let hundred = 100.0_f64;
-    ///
-    /// // log10(100) - 2 == 0
-    /// let abs_difference = (hundred.log10() - 2.0).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn log10(self) -> f64 {
-        self.log_wrapper(|n| unsafe { intrinsics::log10f64(n) })
-    }
-
-    /// Returns the cubic root of a number.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let x = 8.0_f64;
-    ///
-    /// // x^(1/3) - 2 == 0
-    /// let abs_difference = (x.cbrt() - 2.0).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn cbrt(self) -> f64 {
-        unsafe { libm::cbrt(self) }
-    }
-
-    /// Calculates the length of the hypotenuse of a right-angle triangle given
-    /// legs of length `x` and `y`.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let x = 2.0_f64;
-    /// let y = 3.0_f64;
-    ///
-    /// // sqrt(x^2 + y^2)
-    /// let abs_difference = (x.hypot(y) - (x.powi(2) + y.powi(2)).sqrt()).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn hypot(self, other: f64) -> f64 {
-        unsafe { libm::hypot(self, other) }
-    }
-
-    /// Computes the sine of a number (in radians).
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use std::f64;
-    ///
-    /// let x = f64::consts::FRAC_PI_2;
-    ///
-    /// let abs_difference = (x.sin() - 1.0).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn sin(self) -> f64 {
-        unsafe { intrinsics::sinf64(self) }
-    }
-
-    /// Computes the cosine of a number (in radians).
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use std::f64;
-    ///
-    /// let x = 2.0 * f64::consts::PI;
-    ///
-    /// let abs_difference = (x.cos() - 1.0).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn cos(self) -> f64 {
-        unsafe { intrinsics::cosf64(self) }
-    }
-
-    /// Computes the tangent of a number (in radians).
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use std::f64;
-    ///
-    /// let x = f64::consts::FRAC_PI_4;
-    /// let abs_difference = (x.tan() - 1.0).abs();
-    ///
-    /// assert!(abs_difference < 1e-14);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn tan(self) -> f64 {
-        unsafe { libm::tan(self) }
-    }
-
-    /// Computes the arcsine of a number. Return value is in radians in
-    /// the range [-pi/2, pi/2] or NaN if the number is outside the range
-    /// [-1, 1].
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use std::f64;
-    ///
-    /// let f = f64::consts::FRAC_PI_2;
-    ///
-    /// // asin(sin(pi/2))
-    /// let abs_difference = (f.sin().asin() - f64::consts::FRAC_PI_2).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn asin(self) -> f64 {
-        unsafe { libm::asin(self) }
-    }
-
-    /// Computes the arccosine of a number. Return value is in radians in
-    /// the range [0, pi] or NaN if the number is outside the range
-    /// [-1, 1].
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use std::f64;
-    ///
-    /// let f = f64::consts::FRAC_PI_4;
-    ///
-    /// // acos(cos(pi/4))
-    /// let abs_difference = (f.cos().acos() - f64::consts::FRAC_PI_4).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn acos(self) -> f64 {
-        unsafe { libm::acos(self) }
-    }
-
-    /// Computes the arctangent of a number. Return value is in radians in the
-    /// range [-pi/2, pi/2];
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let f = 1.0_f64;
-    ///
-    /// // atan(tan(1))
-    /// let abs_difference = (f.tan().atan() - 1.0).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn atan(self) -> f64 {
-        unsafe { libm::atan(self) }
-    }
-
-    /// Computes the four quadrant arctangent of `self` (`y`) and `other` (`x`) in radians.
-    ///
-    /// * `x = 0`, `y = 0`: `0`
-    /// * `x >= 0`: `arctan(y/x)` -> `[-pi/2, pi/2]`
-    /// * `y >= 0`: `arctan(y/x) + pi` -> `(pi/2, pi]`
-    /// * `y < 0`: `arctan(y/x) - pi` -> `(-pi, -pi/2)`
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use std::f64;
-    ///
-    /// // Positive angles measured counter-clockwise
-    /// // from positive x axis
-    /// // -pi/4 radians (45 deg clockwise)
-    /// let x1 = 3.0_f64;
-    /// let y1 = -3.0_f64;
-    ///
-    /// // 3pi/4 radians (135 deg counter-clockwise)
-    /// let x2 = -3.0_f64;
-    /// let y2 = 3.0_f64;
-    ///
-    /// let abs_difference_1 = (y1.atan2(x1) - (-f64::consts::FRAC_PI_4)).abs();
-    /// let abs_difference_2 = (y2.atan2(x2) - (3.0 * f64::consts::FRAC_PI_4)).abs();
-    ///
-    /// assert!(abs_difference_1 < 1e-10);
-    /// assert!(abs_difference_2 < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn atan2(self, other: f64) -> f64 {
-        unsafe { libm::atan2(self, other) }
-    }
-
-    /// Simultaneously computes the sine and cosine of the number, `x`. Returns
-    /// `(sin(x), cos(x))`.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use std::f64;
-    ///
-    /// let x = f64::consts::FRAC_PI_4;
-    /// let f = x.sin_cos();
-    ///
-    /// let abs_difference_0 = (f.0 - x.sin()).abs();
-    /// let abs_difference_1 = (f.1 - x.cos()).abs();
-    ///
-    /// assert!(abs_difference_0 < 1e-10);
-    /// assert!(abs_difference_1 < 1e-10);
-    /// ```
-    #[inline]
-    pub fn sin_cos(self) -> (f64, f64) {
-        (self.sin(), self.cos())
-    }
-
-    /// Returns `e^(self) - 1` in a way that is accurate even if the
-    /// number is close to zero.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let x = 7.0_f64;
-    ///
-    /// // e^(ln(7)) - 1
-    /// let abs_difference = (x.ln().exp_m1() - 6.0).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn exp_m1(self) -> f64 {
-        unsafe { libm::expm1(self) }
-    }
-
-    /// Returns `ln(1+n)` (natural logarithm) more accurately than if
-    /// the operations were performed separately.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use std::f64;
-    ///
-    /// let x = f64::consts::E - 1.0;
-    ///
-    /// // ln(1 + (e - 1)) == ln(e) == 1
-    /// let abs_difference = (x.ln_1p() - 1.0).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn ln_1p(self) -> f64 {
-        unsafe { libm::log1p(self) }
-    }
-
-    /// Hyperbolic sine function.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use std::f64;
-    ///
-    /// let e = f64::consts::E;
-    /// let x = 1.0_f64;
-    ///
-    /// let f = x.sinh();
-    /// // Solving sinh() at 1 gives `(e^2-1)/(2e)`
-    /// let g = ((e * e) - 1.0) / (2.0 * e);
-    /// let abs_difference = (f - g).abs();
-    ///
-    /// assert!(abs_difference < 1e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn sinh(self) -> f64 {
-        unsafe { libm::sinh(self) }
-    }
-
-    /// Hyperbolic cosine function.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use std::f64;
-    ///
-    /// let e = f64::consts::E;
-    /// let x = 1.0_f64;
-    /// let f = x.cosh();
-    /// // Solving cosh() at 1 gives this result
-    /// let g = ((e * e) + 1.0) / (2.0 * e);
-    /// let abs_difference = (f - g).abs();
-    ///
-    /// // Same result
-    /// assert!(abs_difference < 1.0e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn cosh(self) -> f64 {
-        unsafe { libm::cosh(self) }
-    }
-
-    /// Hyperbolic tangent function.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use std::f64;
-    ///
-    /// let e = f64::consts::E;
-    /// let x = 1.0_f64;
-    ///
-    /// let f = x.tanh();
-    /// // Solving tanh() at 1 gives `(1 - e^(-2))/(1 + e^(-2))`
-    /// let g = (1.0 - e.powi(-2)) / (1.0 + e.powi(-2));
-    /// let abs_difference = (f - g).abs();
-    ///
-    /// assert!(abs_difference < 1.0e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn tanh(self) -> f64 {
-        unsafe { libm::tanh(self) }
-    }
-
-    /// Inverse hyperbolic sine function.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let x = 1.0_f64;
-    /// let f = x.sinh().asinh();
-    ///
-    /// let abs_difference = (f - x).abs();
-    ///
-    /// assert!(abs_difference < 1.0e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn asinh(self) -> f64 {
-        if self == NEG_INFINITY {
-            NEG_INFINITY
-        } else {
-            (self + ((self * self) + 1.0).sqrt()).ln().copysign(self)
-        }
-    }
-
-    /// Inverse hyperbolic cosine function.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// let x = 1.0_f64;
-    /// let f = x.cosh().acosh();
-    ///
-    /// let abs_difference = (f - x).abs();
-    ///
-    /// assert!(abs_difference < 1.0e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn acosh(self) -> f64 {
-        if self < 1.0 {
-            NAN
-        } else {
-            (self + ((self * self) - 1.0).sqrt()).ln()
-        }
-    }
-
-    /// Inverse hyperbolic tangent function.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use std::f64;
-    ///
-    /// let e = f64::consts::E;
-    /// let f = e.tanh().atanh();
-    ///
-    /// let abs_difference = (f - e).abs();
-    ///
-    /// assert!(abs_difference < 1.0e-10);
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn atanh(self) -> f64 {
-        0.5 * ((2.0 * self) / (1.0 - self)).ln_1p()
-    }
-
-    /// Restrict a value to a certain interval unless it is NaN.
-    ///
-    /// Returns `max` if `self` is greater than `max`, and `min` if `self` is
-    /// less than `min`. Otherwise this returns `self`.
-    ///
-    /// Not that this function returns NaN if the initial value was NaN as
-    /// well.
-    ///
-    /// # Panics
-    ///
-    /// Panics if `min > max`, `min` is NaN, or `max` is NaN.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// #![feature(clamp)]
-    /// assert!((-3.0f64).clamp(-2.0, 1.0) == -2.0);
-    /// assert!((0.0f64).clamp(-2.0, 1.0) == 0.0);
-    /// assert!((2.0f64).clamp(-2.0, 1.0) == 1.0);
-    /// assert!((std::f64::NAN).clamp(-2.0, 1.0).is_nan());
-    /// ```
-    #[must_use = "method returns a new number and does not mutate the original value"]
-    #[inline]
-    pub fn clamp(self, min: f64, max: f64) -> f64 {
-        assert!(min <= max);
-        let mut x = self;
-        if x < min {
-            x = min;
-        }
-        if x > max {
-            x = max;
-        }
-        x
-    }
-
-    // Solaris/Illumos requires a wrapper around log, log2, and log10 functions
-    // because of their non-standard behavior (e.g., log(-n) returns -Inf instead
-    // of expected NaN).
-    fn log_wrapper<F: Fn(f64) -> f64>(self, log_fn: F) -> f64 {
-        if !cfg!(target_os = "solaris") {
-            log_fn(self)
-        } else {
-            if self.is_finite() {
-                if self > 0.0 {
-                    log_fn(self)
-                } else if self == 0.0 {
-                    NEG_INFINITY // log(0) = -Inf
-                } else {
-                    NAN // log(-n) = NaN
-                }
-            } else if self.is_nan() {
-                self // log(NaN) = NaN
-            } else if self > 0.0 {
-                self // log(Inf) = Inf
-            } else {
-                NAN // log(-Inf) = NaN
-            }
-        }
-    }
-}
@@ -71,7 +71,7 @@ impl Bench for DRBH {
         let mut iops = 0;
         let mut iterations = 0;
         while iterations <= duration {
-            let start = rawtime::Instant::now();
+            let start = vibrio::time::Instant::now();
             while start.elapsed().as_secs() < 1 {
                 for i in 0..64 {
                     // Read a page from the shared file at offset 0.
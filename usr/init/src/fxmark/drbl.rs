@@ -73,7 +73,7 @@ impl Bench for DRBL {
         let mut iops = 0;
         let mut iterations = 0;
         while iterations <= duration {
-            let start = rawtime::Instant::now();
+            let start = vibrio::time::Instant::now();
             while start.elapsed().as_secs() < 1 {
                 for i in 0..64 {
                     if vibrio::syscalls::Fs::read_at(fd, page.as_ptr() as u64, PAGE_SIZE, 0)
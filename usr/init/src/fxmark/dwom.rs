@@ -91,7 +91,7 @@ impl Bench for DWOM {
         let mut iops = 0;
         let mut iterations = 0;
         while iterations <= duration {
-            let start = rawtime::Instant::now();
+            let start = vibrio::time::Instant::now();
             while start.elapsed().as_secs() < 1 {
                 for i in 0..64 {
                     if vibrio::syscalls::Fs::write_at(
@@ -98,7 +98,7 @@ impl Bench for MIX {
         let mut random_num: u16 = 0;
 
         while iterations <= duration {
-            let start = rawtime::Instant::now();
+            let start = vibrio::time::Instant::now();
             while start.elapsed().as_secs() < 1 {
                 for i in 0..64 {
                     unsafe { rdrand16(&mut random_num) };
@@ -147,7 +147,7 @@ impl Bench for MIX {
         }
 
         if core == 0 {
-            let start = rawtime::Instant::now();
+            let start = vibrio::time::Instant::now();
             while start.elapsed().as_secs() < 1 {}
             for i in 0..*self.open_files.borrow() {
                 let fd = self.fds.borrow()[i];
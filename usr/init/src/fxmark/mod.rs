@@ -23,15 +23,19 @@ mod drbl;
 mod dwol;
 mod dwom;
 mod mix;
+mod mwc;
 mod mwrl;
 mod mwrm;
+mod mwu;
 use crate::fxmark::drbh::DRBH;
 use crate::fxmark::drbl::DRBL;
 use crate::fxmark::dwol::DWOL;
 use crate::fxmark::dwom::DWOM;
 use crate::fxmark::mix::MIX;
+use crate::fxmark::mwc::{MWCL, MWCM};
 use crate::fxmark::mwrl::MWRL;
 use crate::fxmark::mwrm::MWRM;
+use crate::fxmark::mwu::{MWUL, MWUM};
 
 const PAGE_SIZE: u64 = 1008;
 
@@ -130,6 +134,8 @@ where
     fn fxmark_bencher(&self, cores: usize, benchmark: &str, write_ratio: usize, open_files: usize) {
         let bench_duration_secs = if cfg!(feature = "smoke") { 1 } else { 10 };
         let core_id = Environment::scheduler().core_id;
+        #[cfg(feature = "perf")]
+        let mut misses = crate::perf::Misses::start();
         let iops = self.bench.run(
             &POOR_MANS_BARRIER,
             bench_duration_secs,
@@ -150,6 +156,11 @@ where
                 iops[iteration as usize]
             );
         }
+        #[cfg(feature = "perf")]
+        if let Some(misses) = misses.as_mut() {
+            let (llc, dtlb) = misses.delta();
+            info!("{},{},misses,{},{}", core_id, benchmark, llc, dtlb);
+        }
     }
 }
 
@@ -302,6 +313,50 @@ pub fn bench(ncores: Option<usize>, open_files: usize, benchmark: String, write_
         start::<MWRM>(maximum, microbench);
     }
 
+    if benchmark == "mwcl" {
+        let microbench = Arc::new(MicroBench::<MWCL>::new(
+            maximum,
+            "mwcl",
+            write_ratio,
+            open_files,
+        ));
+        microbench.bench.init(cores.clone(), open_files);
+        start::<MWCL>(maximum, microbench);
+    }
+
+    if benchmark == "mwcm" {
+        let microbench = Arc::new(MicroBench::<MWCM>::new(
+            maximum,
+            "mwcm",
+            write_ratio,
+            open_files,
+        ));
+        microbench.bench.init(cores.clone(), open_files);
+        start::<MWCM>(maximum, microbench);
+    }
+
+    if benchmark == "mwul" {
+        let microbench = Arc::new(MicroBench::<MWUL>::new(
+            maximum,
+            "mwul",
+            write_ratio,
+            open_files,
+        ));
+        microbench.bench.init(cores.clone(), open_files);
+        start::<MWUL>(maximum, microbench);
+    }
+
+    if benchmark == "mwum" {
+        let microbench = Arc::new(MicroBench::<MWUM>::new(
+            maximum,
+            "mwum",
+            write_ratio,
+            open_files,
+        ));
+        microbench.bench.init(cores.clone(), open_files);
+        start::<MWUM>(maximum, microbench);
+    }
+
     if benchmark == "mix" {
         let microbench = Arc::new(MicroBench::<MIX>::new(
             maximum,
@@ -0,0 +1,97 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! MWCL/MWCM: every core creates empty files, in a directory of its own
+//! (`MWCL`) or in one directory all cores share (`MWCM`).
+
+use crate::fxmark::mwrm::calculate_throughput;
+use crate::fxmark::Bench;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use vibrio::io::*;
+use vibrio::time::Instant;
+
+pub type MWCL = MWC<false>;
+pub type MWCM = MWC<true>;
+
+#[derive(Clone)]
+pub struct MWC<const SHARED: bool> {
+    total_files: usize,
+    total_cores: RefCell<usize>,
+}
+
+impl<const SHARED: bool> Default for MWC<SHARED> {
+    fn default() -> MWC<SHARED> {
+        MWC {
+            // Same limit as MWRM, every created file stays around until the
+            // process exits.
+            total_files: 10_000,
+            total_cores: RefCell::new(0),
+        }
+    }
+}
+
+/// The name of the `iter`th file of `core` (in the directory of `core` or in
+/// the shared one).
+pub(crate) fn file_name(core: usize, iter: usize, shared: bool) -> String {
+    if shared {
+        format!("/fxmark/file-{}-{}.txt\0", core, iter)
+    } else {
+        format!("/{}/file-{}-{}.txt\0", core, core, iter)
+    }
+}
+
+impl<const SHARED: bool> Bench for MWC<SHARED> {
+    fn init(&self, cores: Vec<usize>, _open_files: usize) {
+        *self.total_cores.borrow_mut() = cores.len();
+    }
+
+    fn run(
+        &self,
+        POOR_MANS_BARRIER: &AtomicUsize,
+        duration: u64,
+        core: usize,
+        _write_ratio: usize,
+    ) -> Vec<usize> {
+        let mut iops_per_second = Vec::with_capacity(duration as usize);
+        let files_per_core = self.total_files / *self.total_cores.borrow();
+        let file_names: Vec<String> = (0..files_per_core)
+            .map(|iter| file_name(core, iter, SHARED))
+            .collect();
+        let mut iops = 0;
+
+        // Synchronize with all cores
+        POOR_MANS_BARRIER.fetch_sub(1, Ordering::Release);
+        while POOR_MANS_BARRIER.load(Ordering::Acquire) != 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+
+        let start = Instant::now();
+        for file_name in file_names.iter() {
+            let fd = vibrio::syscalls::Fs::open(
+                file_name.as_ptr() as u64,
+                u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+                u64::from(FileModes::S_IRWXU),
+            )
+            .expect("FileOpen syscall failed");
+            let ret = vibrio::syscalls::Fs::close(fd).expect("FileClose syscall failed");
+            assert_eq!(ret, 0);
+            iops += 1;
+        }
+        let stop = Instant::now();
+        let throughput = calculate_throughput(iops, stop - start);
+
+        // Just to avoid changing the throughput reporting code
+        // which expects `duration` number of readings.
+        for _i in 0..duration + 1 {
+            iops_per_second.push(throughput);
+        }
+        POOR_MANS_BARRIER.fetch_add(1, Ordering::Relaxed);
+        iops_per_second.clone()
+    }
+}
+
+unsafe impl<const SHARED: bool> Sync for MWC<SHARED> {}
@@ -57,7 +57,7 @@ impl Bench for MWRL {
             format!("/{}/file-{}.txt\0", core, 1),
         ];
         while iterations <= duration {
-            let start = rawtime::Instant::now();
+            let start = vibrio::time::Instant::now();
             while start.elapsed().as_secs() < 1 {
                 for i in 0..64 {
                     let old_name = iter % 2;
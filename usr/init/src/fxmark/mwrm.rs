@@ -10,8 +10,8 @@ use core::slice::from_raw_parts_mut;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use core::time::Duration;
 use log::info;
-use rawtime::Instant;
 use vibrio::io::*;
+use vibrio::time::Instant;
 
 #[derive(Clone)]
 pub struct MWRM {
@@ -0,0 +1,96 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! MWUL/MWUM: every core unlinks empty files, in a directory of its own
+//! (`MWUL`) or in one directory all cores share (`MWUM`).
+
+use crate::fxmark::mwc::file_name;
+use crate::fxmark::mwrm::calculate_throughput;
+use crate::fxmark::Bench;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use vibrio::io::*;
+use vibrio::time::Instant;
+
+pub type MWUL = MWU<false>;
+pub type MWUM = MWU<true>;
+
+#[derive(Clone)]
+pub struct MWU<const SHARED: bool> {
+    total_files: usize,
+    total_cores: RefCell<usize>,
+}
+
+impl<const SHARED: bool> Default for MWU<SHARED> {
+    fn default() -> MWU<SHARED> {
+        MWU {
+            total_files: 10_000,
+            total_cores: RefCell::new(0),
+        }
+    }
+}
+
+impl<const SHARED: bool> Bench for MWU<SHARED> {
+    fn init(&self, cores: Vec<usize>, _open_files: usize) {
+        let core_nums = cores.len();
+        *self.total_cores.borrow_mut() = core_nums;
+        let files_per_core = self.total_files / core_nums;
+        for core in cores {
+            for iter in 0..files_per_core {
+                let file_name = file_name(core, iter, SHARED);
+                let fd = vibrio::syscalls::Fs::open(
+                    file_name.as_ptr() as u64,
+                    u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+                    u64::from(FileModes::S_IRWXU),
+                )
+                .expect("FileOpen syscall failed");
+
+                // Close the file.
+                let ret = vibrio::syscalls::Fs::close(fd).expect("FileClose syscall failed");
+                assert_eq!(ret, 0);
+            }
+        }
+    }
+
+    fn run(
+        &self,
+        POOR_MANS_BARRIER: &AtomicUsize,
+        duration: u64,
+        core: usize,
+        _write_ratio: usize,
+    ) -> Vec<usize> {
+        let mut iops_per_second = Vec::with_capacity(duration as usize);
+        let files_per_core = self.total_files / *self.total_cores.borrow();
+        let file_names: Vec<String> = (0..files_per_core)
+            .map(|iter| file_name(core, iter, SHARED))
+            .collect();
+        let mut iops = 0;
+
+        // Synchronize with all cores
+        POOR_MANS_BARRIER.fetch_sub(1, Ordering::Release);
+        while POOR_MANS_BARRIER.load(Ordering::Acquire) != 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+
+        let start = Instant::now();
+        for file_name in file_names.iter() {
+            vibrio::syscalls::Fs::delete(file_name.as_ptr() as u64)
+                .expect("FileDelete syscall failed");
+            iops += 1;
+        }
+        let stop = Instant::now();
+        let throughput = calculate_throughput(iops, stop - start);
+
+        // Just to avoid changing the throughput reporting code
+        // which expects `duration` number of readings.
+        for _i in 0..duration + 1 {
+            iops_per_second.push(throughput);
+        }
+        POOR_MANS_BARRIER.fetch_add(1, Ordering::Relaxed);
+        iops_per_second.clone()
+    }
+}
+
+unsafe impl<const SHARED: bool> Sync for MWU<SHARED> {}
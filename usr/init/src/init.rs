@@ -24,9 +24,10 @@ use core::panic::PanicInfo;
 use core::ptr;
 use core::slice::from_raw_parts_mut;
 use core::str::FromStr;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use vibrio::io::FileType;
+use vibrio::process::ProcessInfo;
 #[cfg(feature = "rumprt")]
 use vibrio::rumprt;
 use vibrio::{sys_print, sys_println};
@@ -39,16 +40,22 @@ use log::{debug, error, info, Level, Metadata, Record, SetLoggerError};
 #[cfg(any(feature = "bench-vmops", feature = "bench-vmops-unmaplat"))]
 mod vmops;
 
-mod f64;
+#[cfg(feature = "dbbench")]
+mod dbbench;
 #[cfg(feature = "fxmark")]
 mod fxmark;
-mod histogram;
+#[cfg(feature = "perf")]
+mod perf;
+mod tests;
 
 #[thread_local]
 pub static mut TLS_TEST: [&str; 2] = ["abcd", "efgh"];
 
 fn print_test() {
     let _r = vibrio::syscalls::Process::print("test\r\n");
+    vibrio::print!("buffered ");
+    vibrio::println!("{}", "test");
+    vibrio::eprintln!("unbuffered test");
     info!("print_test OK");
 }
 
@@ -81,98 +88,2586 @@ fn alloc_test() {
     info!("alloc_test OK");
 }
 
+/// Allocates objects of every size class of the allocator (and bigger ones)
+/// on all cores, frees half of them on another core than the one they come
+/// from and checks that none of them got corrupted.
+fn alloc_smp_work(core_id: usize) {
+    use alloc::vec::Vec;
+    static SHARED: spin::Mutex<Vec<Vec<u8>>> = spin::Mutex::new(Vec::new());
+
+    let pattern = core_id as u8;
+    for round in 0..1024 {
+        let size = 8 << (round % 11);
+        let mut mine: Vec<u8> = Vec::with_capacity(size);
+        mine.resize(size, pattern);
+        let mut shared = mine.clone();
+
+        // Grows and shrinks within and across size classes (`realloc`)
+        mine.push(pattern);
+        mine.resize(2 * size + 1, pattern);
+        shared.truncate(size / 2);
+        shared.shrink_to_fit();
+        assert!(mine.iter().all(|b| *b == pattern));
+
+        // Swap it for one of (most likely) another core
+        let other = {
+            let mut objects = SHARED.lock();
+            let other = objects.pop();
+            objects.push(shared);
+            other
+        };
+        if let Some(other) = other {
+            assert!(other.iter().all(|b| *b == other[0]));
+        }
+    }
+}
+
 fn scheduler_smp_test() {
     use lineup::threads::ThreadId;
     use lineup::tls2::Environment;
     let s = &vibrio::upcalls::PROCESS_SCHEDULER;
 
-    let threads = vibrio::syscalls::System::threads().expect("Can't get system topology");
+    let threads = vibrio::syscalls::System::threads().expect("Can't get system topology");
+
+    for thread in threads.iter() {
+        if thread.id != 0 {
+            let r = vibrio::syscalls::Process::request_core(
+                thread.id,
+                VAddr::from(vibrio::upcalls::upcall_while_enabled as *const fn() as u64),
+            );
+            match r {
+                Ok(ctoken) => {
+                    info!("Spawned core on {:?} <-> {}", ctoken, thread.id);
+                }
+                Err(_e) => {
+                    panic!("Failed to spawn to core {}", thread.id);
+                    continue;
+                }
+            }
+        }
+    }
+
+    for thread in threads {
+        s.spawn(
+            32 * 4096,
+            move |_| {
+                let core_id = lineup::tls2::Environment::scheduler().core_id;
+                alloc_smp_work(core_id);
+                info!("Hello from core {}", core_id);
+            },
+            ptr::null_mut(),
+            thread.id,
+            None,
+        );
+    }
+
+    // Run scheduler on core 0
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    loop {
+        s.run(&scb);
+    }
+}
+
+fn scheduler_test() {
+    use lineup::threads::ThreadId;
+    let mut s: lineup::scheduler::SmpScheduler = Default::default();
+
+    s.spawn(
+        32 * 4096,
+        move |_| {
+            unsafe {
+                info!("Hello from t1");
+                assert_eq!(TLS_TEST[0], "abcd");
+                assert_eq!(TLS_TEST[1], "efgh");
+                TLS_TEST[0] = "xxxx";
+                TLS_TEST[1] = "xxxx";
+                assert_eq!(TLS_TEST[0], "xxxx");
+            }
+            assert_eq!(lineup::tls2::Environment::scheduler().core_id, 2);
+            assert_eq!(lineup::tls2::Environment::thread().current_core, 2);
+            assert_eq!(lineup::tls2::Environment::tid(), ThreadId(0));
+        },
+        ptr::null_mut(),
+        2,
+        None,
+    );
+
+    s.spawn(
+        32 * 4096,
+        move |_| {
+            unsafe {
+                assert_eq!(TLS_TEST[0], "abcd");
+                assert_eq!(TLS_TEST[1], "efgh");
+                info!("Hello from t2");
+            }
+            assert_eq!(lineup::tls2::Environment::scheduler().core_id, 2);
+            assert_eq!(lineup::tls2::Environment::thread().current_core, 2);
+            assert_eq!(lineup::tls2::Environment::tid(), ThreadId(1));
+        },
+        ptr::null_mut(),
+        2,
+        None,
+    );
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(2);
+    s.run(&scb);
+
+    info!("scheduler_test OK");
+}
+
+/// Serves a UDP and a TCP echo socket from a single lineup thread
+/// (sockets from the kernel network stack).
+fn net_socket_test() {
+    use core::time::Duration;
+
+    use vibrio::net::{PollEvents, PollFd, SocketType};
+    use vibrio::syscalls::Net;
+
+    let mut s: lineup::scheduler::SmpScheduler = Default::default();
+
+    s.spawn(
+        32 * 4096,
+        move |_| {
+            let udp = Net::socket(SocketType::Udp).expect("Can't create UDP socket");
+            Net::bind(udp, 8889).expect("Can't bind UDP socket");
+            let tcp = Net::socket(SocketType::Tcp).expect("Can't create TCP socket");
+            Net::bind(tcp, 6970).expect("Can't listen on TCP socket");
+            info!("net_socket_test: serving udp:8889 and tcp:6970");
+
+            let mut fds = [
+                PollFd::new(udp, PollEvents::POLLIN),
+                PollFd::new(tcp, PollEvents::POLLIN),
+            ];
+            let mut buf = [0u8; 1024];
+            let (mut udp_done, mut tcp_done) = (false, false);
+
+            while !udp_done || !tcp_done {
+                let ready = vibrio::net::poll(&mut fds, Some(Duration::from_secs(25)))
+                    .expect("poll failed");
+                assert!(ready > 0, "Timed out waiting for sockets");
+
+                if fds[0].revents().contains(PollEvents::POLLIN) {
+                    let (len, from) = Net::recv_from(udp, &mut buf).expect("UDP recv failed");
+                    Net::send_to(udp, &buf[..len], from).expect("UDP send failed");
+                    udp_done = true;
+                }
+
+                if fds[1].revents().contains(PollEvents::POLLIN) {
+                    let len = Net::recv(tcp, &mut buf).expect("TCP recv failed");
+                    if len == 0 {
+                        tcp_done = true;
+                    } else {
+                        let mut sent = 0;
+                        while sent < len {
+                            match Net::send(tcp, &buf[sent..len]) {
+                                Ok(n) => sent += n,
+                                Err(vibrio::SystemCallError::WouldBlock) => {
+                                    lineup::tls2::Environment::thread().relinquish()
+                                }
+                                Err(e) => panic!("TCP send failed {:?}", e),
+                            }
+                        }
+                    }
+                }
+            }
+
+            Net::close(udp).expect("Can't close UDP socket");
+            Net::close(tcp).expect("Can't close TCP socket");
+        },
+        ptr::null_mut(),
+        0,
+        None,
+    );
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    s.run(&scb);
+
+    info!("net_socket_test OK");
+}
+
+fn net_ping_test() {
+    use core::time::Duration;
+
+    use vibrio::io::{FileFlags, FileModes};
+    use vibrio::net::{PollEvents, PollFd, SocketType};
+    use vibrio::syscalls::Net;
+
+    // The first request(s) may get lost while we resolve the host's address
+    let rtt = (0..5)
+        .find_map(|_| Net::ping([172, 31, 0, 20], Duration::from_secs(1)).ok())
+        .expect("No reply from 172.31.0.20");
+    info!("net_ping_test: reply from 172.31.0.20 in {:?}", rtt);
+
+    // The kernel should have learned the host's address now
+    let fd = vibrio::syscalls::Fs::open(
+        "/proc/net/arp\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDONLY),
+        u64::from(FileModes::S_IRUSR),
+    )
+    .expect("Can't open /proc/net/arp");
+    let mut buf = [0u8; 1024];
+    let len = vibrio::syscalls::Fs::read(fd, buf.as_mut_ptr() as u64, buf.len() as u64)
+        .expect("Can't read /proc/net/arp");
+    vibrio::syscalls::Fs::close(fd).expect("Can't close /proc/net/arp");
+    let arp = core::str::from_utf8(&buf[..len as usize]).expect("Not UTF-8");
+    for line in arp.lines() {
+        info!("net_ping_test: arp {}", line);
+    }
+
+    // Keep the stack busy so the kernel answers the host's pings
+    let udp = Net::socket(SocketType::Udp).expect("Can't create UDP socket");
+    let mut fds = [PollFd::new(udp, PollEvents::POLLIN)];
+    let start = vibrio::time::Instant::now();
+    while start.elapsed() < Duration::from_secs(10) {
+        Net::poll(&mut fds, Some(Duration::from_secs(0))).expect("poll failed");
+    }
+    Net::close(udp).expect("Can't close UDP socket");
+
+    info!("net_ping_test OK");
+}
+
+fn vsock_test() {
+    use alloc::vec::Vec;
+    use core::time::Duration;
+
+    use vibrio::net::{PollEvents, PollFd, VsockAddr, VSOCK_CID_HOST};
+    use vibrio::syscalls::Net;
+    use vibrio::SystemCallError;
+
+    /// Waits until `fd` has one of `events`.
+    fn wait(fd: u64, events: PollEvents) -> PollEvents {
+        let mut fds = [PollFd::new(fd, events)];
+        match vibrio::net::poll(&mut fds, Some(Duration::from_secs(30))) {
+            Ok(0) => panic!("vsock_test: timed out waiting for {:?}", events),
+            Ok(_) => fds[0].revents(),
+            Err(e) => panic!("vsock_test: poll failed: {:?}", e),
+        }
+    }
+
+    let cid = match Net::vsock_local_cid() {
+        Ok(cid) => cid,
+        Err(SystemCallError::NotSupported) => {
+            info!("vsock_test: no vsock device");
+            info!("vsock_test OK");
+            return;
+        }
+        Err(e) => panic!("Can't get the CID: {:?}", e),
+    };
+
+    // The host connects to us and gets its line back
+    let listener = Net::vsock_listen(5000).expect("Can't listen");
+    assert_eq!(
+        Net::vsock_accept(listener),
+        Err(SystemCallError::WouldBlock)
+    );
+    info!("vsock_test: listening on {}:5000", cid);
+    wait(listener, PollEvents::POLLIN);
+    let (conn, peer) = Net::vsock_accept(listener).expect("Can't accept");
+    assert_eq!(peer.cid, VSOCK_CID_HOST);
+    info!("vsock_test: connection from port {}", peer.port);
+
+    let mut line = Vec::new();
+    let mut buf = [0u8; 64];
+    while !line.ends_with(b"\n") {
+        wait(conn, PollEvents::POLLIN);
+        match Net::vsock_recv(conn, &mut buf).expect("Can't receive") {
+            0 => panic!("vsock_test: host hung up"),
+            len => line.extend_from_slice(&buf[..len]),
+        }
+    }
+    let mut sent = 0;
+    while sent < line.len() {
+        wait(conn, PollEvents::POLLOUT);
+        sent += Net::vsock_send(conn, &line[sent..]).expect("Can't send");
+    }
+    Net::vsock_close(conn).expect("Can't close");
+    Net::vsock_close(listener).expect("Can't close listener");
+
+    // And we connect to the host
+    let conn = Net::vsock_connect(VsockAddr::new(VSOCK_CID_HOST, 5001)).expect("Can't connect");
+    let revents = wait(conn, PollEvents::POLLOUT);
+    assert!(!revents.contains(PollEvents::POLLHUP), "Host refused");
+    assert_eq!(Net::vsock_send(conn, b"hello host\n"), Ok(11));
+    Net::vsock_close(conn).expect("Can't close");
+
+    info!("vsock_test OK");
+}
+
+fn time_test() {
+    use core::time::Duration;
+    use vibrio::syscalls::Time;
+
+    let now = Time::wallclock().expect("Can't read wall-clock time");
+    // Don't change this line without changing
+    // `s04_userspace_wallclock` in integration-test.rs:
+    info!("time_test: wallclock {}", now.as_secs());
+    // Some time after 2021-01-01
+    assert!(now.as_secs() > 1_609_459_200);
+
+    let later = Time::wallclock().expect("Can't read wall-clock time");
+    assert!(later >= now, "Wall-clock time went backwards");
+
+    // The time page and the system calls agree
+    let realtime = Time::realtime_ns().expect("Can't read realtime clock");
+    assert!(realtime >= later.as_nanos() as u64);
+    assert!(realtime - (later.as_nanos() as u64) < 1_000_000_000);
+    let resolution = Time::resolution().expect("Can't read clock resolution");
+    assert!(resolution > Duration::from_nanos(0) && resolution < Duration::from_micros(1));
+
+    let start = Time::monotonic_ns().expect("Can't read monotonic clock");
+    let wall_start = Time::wallclock().expect("Can't read wall-clock time");
+    while Time::wallclock().expect("Can't read wall-clock time") - wall_start
+        < Duration::from_millis(100)
+    {
+        core::hint::spin_loop();
+    }
+    let elapsed = Time::monotonic_ns().expect("Can't read monotonic clock") - start;
+    assert!((100_000_000..110_000_000).contains(&elapsed));
+    info!("time_test: monotonic OK");
+
+    info!("time_test OK");
+}
+
+fn getrandom_test() {
+    use vibrio::syscalls::System;
+
+    let mut a = [0u8; 300];
+    let mut b = [0u8; 300];
+    assert_eq!(
+        System::getrandom(&mut a).expect("getrandom failed"),
+        a.len()
+    );
+    assert_eq!(
+        System::getrandom(&mut b).expect("getrandom failed"),
+        b.len()
+    );
+    assert_ne!(a[..], b[..], "Got the same random bytes twice");
+    assert!(a.iter().any(|byte| *byte != 0));
+
+    info!("getrandom_test OK");
+}
+
+fn topology_test() {
+    use vibrio::syscalls::System;
+
+    let threads = System::threads().expect("Can't get threads");
+    let nodes = System::topology().expect("Can't get topology");
+    info!("nodes = {:?}", nodes);
+    info!("threads online = {}", threads.len());
+
+    for (id, node) in nodes.iter().enumerate() {
+        assert_eq!(node.id, id);
+        assert_eq!(node.distances.len(), nodes.len());
+        assert_eq!(node.distances[id], 10);
+        assert!(
+            threads.iter().any(|t| t.node_id == id),
+            "Node without threads"
+        );
+    }
+    assert!(threads.iter().all(|t| t.node_id < nodes.len()));
+
+    info!("topology_test OK");
+}
+
+fn hotplug_test() {
+    use core::time::Duration;
+
+    use vibrio::syscalls::{Process, System};
+    use vibrio::upcalls::{CORES_ONLINE, PROCESS_SCHEDULER};
+
+    static DONE: AtomicBool = AtomicBool::new(false);
+    const CORE: usize = 2;
+
+    Process::request_core(
+        CORE,
+        VAddr::from(vibrio::upcalls::upcall_while_enabled as *const fn() as u64),
+    )
+    .expect("Can't request core");
+    while CORES_ONLINE.load(Ordering::SeqCst) != 2 {
+        core::hint::spin_loop();
+    }
+
+    // A thread that's still around when the core goes away
+    let s = &PROCESS_SCHEDULER;
+    s.spawn(
+        32 * 4096,
+        move |_| {
+            while !DONE.load(Ordering::SeqCst) {
+                lineup::tls2::Environment::thread().sleep(Duration::from_millis(5));
+            }
+            assert_eq!(lineup::tls2::Environment::thread().current_core, 0);
+            info!("hotplug_test: thread finished on core 0");
+        },
+        ptr::null_mut(),
+        CORE,
+        None,
+    );
+
+    System::offline_core(CORE).expect("Can't take core offline");
+    assert_eq!(CORES_ONLINE.load(Ordering::SeqCst), 1);
+    assert!(System::threads().unwrap().iter().all(|t| t.id != CORE));
+
+    DONE.store(true, Ordering::SeqCst);
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    while s.has_active_threads() {
+        s.run(&scb);
+    }
+
+    System::online_core(CORE).expect("Can't bring core back");
+    assert!(System::threads().unwrap().iter().any(|t| t.id == CORE));
+
+    info!("hotplug_test OK");
+}
+
+fn nmi_test() {
+    use vibrio::syscalls::{Debug, System};
+
+    let threads = System::threads().expect("Can't get threads");
+    let answered = Debug::nmi_all().expect("Can't send NMIs");
+    info!("nmi_test: {} cores answered", answered);
+    assert_eq!(answered, threads.len() - 1);
+
+    info!("nmi_test OK");
+}
+
+fn log_filter_test() {
+    use vibrio::syscalls::Debug;
+    use vibrio::SystemCallError;
+
+    Debug::set_log_filter("info,nrk::arch::x86_64::syscall=trace").expect("Can't set log filter");
+    assert_eq!(
+        Debug::set_log_filter("info,nrk=loud"),
+        Err(SystemCallError::BadFlags)
+    );
+    Debug::set_log_filter("info").expect("Can't set log filter");
+
+    info!("log_filter_test OK");
+}
+
+fn dmesg_test() {
+    use alloc::string::String;
+    use alloc::vec;
+    use vibrio::syscalls::System;
+
+    info!("dmesg_test: marker");
+
+    let mut log = String::new();
+    let mut buf = vec![0u8; 4096];
+    let mut offset = 0;
+    loop {
+        let (read, next) = System::dmesg(&mut buf, offset).expect("Can't read dmesg");
+        if read == 0 {
+            break;
+        }
+        assert!(next - offset >= read as u64, "Offsets go forward");
+        log.push_str(&String::from_utf8_lossy(&buf[..read]));
+        offset = next;
+    }
+    info!(
+        "dmesg_test: read {} bytes (up to offset {})",
+        log.len(),
+        offset
+    );
+
+    assert!(
+        log.contains("dmesg_test: marker"),
+        "Process output is in the log"
+    );
+    assert!(log.contains("] - nrk"), "Kernel log records are in the log");
+    // Reading past the end returns nothing and where the end is now
+    let (read, end) = System::dmesg(&mut buf, u64::MAX).expect("Can't read dmesg");
+    assert_eq!(read, 0);
+    assert!(end >= offset);
+
+    info!("dmesg_test OK");
+}
+
+fn stats_test() {
+    use vibrio::syscalls::System;
+    use vibrio::system::{CoreStats, IdleState};
+
+    let before: CoreStats = System::stats().expect("Can't get stats").iter().sum();
+    for _i in 0..10 {
+        System::core_id().expect("Can't get core id");
+    }
+    let stats = System::stats().expect("Can't get stats");
+    let after: CoreStats = stats.iter().sum();
+
+    let threads = System::threads().expect("Can't get threads");
+    assert_eq!(stats.len(), threads.len(), "Every core has counters");
+
+    let syscalls = |s: &CoreStats| s.syscalls.iter().sum::<u64>();
+    info!(
+        "stats_test: {} system calls, {} interrupts, {} context switches",
+        syscalls(&after),
+        after.irqs.iter().sum::<u64>(),
+        after.context_switches
+    );
+    assert!(syscalls(&after) >= syscalls(&before) + 11);
+    assert!(after.context_switches >= 1, "We got scheduled");
+    assert_eq!(after.tlb_shootdowns_sent, after.tlb_shootdowns_received);
+
+    for state in IdleState::ALL.iter() {
+        info!(
+            "stats_test: idle {} entered {} times, for {} ns",
+            state.name(),
+            after.idle_entries[*state as usize],
+            after.idle_ns[*state as usize]
+        );
+    }
+    assert!(
+        after.idle_entries.iter().sum::<u64>() >= 1,
+        "The other core went idle"
+    );
+
+    info!("stats_test OK");
+}
+
+fn syscall_latency_test() {
+    use alloc::string::String;
+    use vibrio::io::{FileFlags, FileModes};
+    use vibrio::syscalls::{Fs, VSpace};
+
+    let base: u64 = 0x5000_0000;
+    for _i in 0..100 {
+        unsafe {
+            VSpace::map(base, 0x1000).expect("Map syscall failed");
+            VSpace::unmap(base, 0x1000).expect("Unmap syscall failed");
+        }
+    }
+
+    let fd = Fs::open(
+        "/proc/syscall_latency\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDONLY),
+        u64::from(FileModes::S_IRUSR),
+    )
+    .expect("Can't open /proc/syscall_latency");
+    let mut contents = String::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        let len = Fs::read(fd, buf.as_mut_ptr() as u64, buf.len() as u64)
+            .expect("Can't read /proc/syscall_latency");
+        if len == 0 {
+            break;
+        }
+        contents.push_str(core::str::from_utf8(&buf[..len as usize]).expect("Not UTF-8"));
+    }
+    Fs::close(fd).expect("Can't close /proc/syscall_latency");
+
+    for line in contents.lines() {
+        info!("syscall_latency_test: {}", line);
+    }
+    for op in &["VSpace::Map ", "VSpace::Unmap "] {
+        let line = contents
+            .lines()
+            .find(|l| l.starts_with(op))
+            .expect("No histogram for map/unmap");
+        let count: u64 = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|c| c.parse().ok())
+            .expect("Can't parse count");
+        assert!(count >= 100, "Recorded every call");
+    }
+
+    info!("syscall_latency_test OK");
+}
+
+fn kprobe_test() {
+    use vibrio::syscalls::{Debug, VSpace};
+    use vibrio::{KprobeMode, SystemCallError};
+
+    let before = Debug::kprobe("pt_alloc", KprobeMode::Count).expect("Can't enable kprobe");
+    // Every 2 MiB region we touch for the first time needs a new page-table
+    let base: u64 = 0x6000_0000;
+    for i in 0..4 {
+        unsafe {
+            VSpace::map(base + i * 0x20_0000, 0x1000).expect("Map syscall failed");
+        }
+    }
+    let hits = Debug::kprobe("pt_alloc", KprobeMode::Off).expect("Can't disable kprobe");
+    info!("kprobe_test: pt_alloc was hit {} times", hits - before);
+    assert!(hits - before >= 4, "Counted the page-table allocations");
+
+    Debug::kprobe("tcache_refill", KprobeMode::Trace).expect("Can't enable kprobe");
+    Debug::kprobe("tcache_refill", KprobeMode::Off).expect("Can't disable kprobe");
+
+    assert_eq!(
+        Debug::kprobe("no_such_probe", KprobeMode::Count),
+        Err(SystemCallError::NotSupported)
+    );
+    assert_eq!(
+        Debug::kprobe("pt_alloc", KprobeMode::Unknown),
+        Err(SystemCallError::BadFlags)
+    );
+
+    info!("kprobe_test OK");
+}
+
+fn sched_graph_test() {
+    use vibrio::syscalls::Debug;
+
+    Debug::dump_sched_graph().expect("Can't dump scheduler graph");
+    info!("sched_graph_test OK");
+}
+
+fn futex_test() {
+    use core::sync::atomic::AtomicU32;
+    use core::time::Duration;
+    use vibrio::syscalls::Process;
+    use vibrio::SystemCallError;
+
+    let word = AtomicU32::new(1);
+
+    // The word changed already
+    assert_eq!(
+        Process::futex_wait(&word, 0, None),
+        Err(SystemCallError::WouldBlock)
+    );
+
+    // Nobody wakes us up (the wait can also end early)
+    let start = vibrio::time::Instant::now();
+    let r = Process::futex_wait(&word, 1, Some(Duration::from_millis(50)));
+    info!("futex_test: waited {:?}: {:?}", start.elapsed(), r);
+    assert!(r == Ok(()) || r == Err(SystemCallError::TimedOut));
+
+    assert_eq!(Process::futex_wake(&word, 1), Ok(0));
+
+    // A `sync::Mutex` never sleeps if nobody else holds it
+    let mtx = lineup::sync::Mutex::new();
+    mtx.enter_nowrap();
+    assert!(!mtx.try_enter());
+    mtx.exit();
+    assert!(mtx.try_enter());
+    mtx.exit();
+
+    info!("futex_test OK");
+}
+
+static PTHREAD_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn pthread_test_worker(arg: *mut core::ffi::c_void) -> *mut core::ffi::c_void {
+    use vibrio::pthread::*;
+
+    let mutex = arg as *mut pthread_mutex_t;
+    for _i in 0..50 {
+        unsafe {
+            assert_eq!(pthread_mutex_lock(mutex), 0);
+            let v = PTHREAD_COUNTER.load(Ordering::Relaxed);
+            sched_yield();
+            PTHREAD_COUNTER.store(v + 1, Ordering::Relaxed);
+            assert_eq!(pthread_mutex_unlock(mutex), 0);
+        }
+    }
+    unsafe { pthread_self() as *mut core::ffi::c_void }
+}
+
+fn pthread_test() {
+    use core::ffi::c_void;
+    use vibrio::pthread::*;
+
+    let s = &vibrio::upcalls::PROCESS_SCHEDULER;
+    s.spawn(
+        32 * 4096,
+        move |_| unsafe {
+            let mut mutex = PTHREAD_MUTEX_INITIALIZER;
+            let mut threads = [0 as pthread_t; 4];
+            for t in threads.iter_mut() {
+                assert_eq!(
+                    pthread_create(
+                        t,
+                        ptr::null(),
+                        pthread_test_worker,
+                        &mut mutex as *mut _ as *mut c_void
+                    ),
+                    0
+                );
+            }
+            for t in threads.iter() {
+                let mut ret = ptr::null_mut();
+                assert_eq!(pthread_join(*t, &mut ret), 0);
+                assert_eq!(ret as pthread_t, *t);
+            }
+            assert_eq!(PTHREAD_COUNTER.load(Ordering::Relaxed), 200);
+            assert_eq!(pthread_join(threads[0], ptr::null_mut()), 3); // ESRCH
+            assert_eq!(pthread_mutex_destroy(&mut mutex), 0);
+
+            // Recursive mutexes count, error checking ones complain
+            let mut attr = core::mem::zeroed();
+            pthread_mutexattr_init(&mut attr);
+            pthread_mutexattr_settype(&mut attr, PTHREAD_MUTEX_RECURSIVE);
+            let mut rmutex = core::mem::zeroed();
+            pthread_mutex_init(&mut rmutex, &attr);
+            assert_eq!(pthread_mutex_lock(&mut rmutex), 0);
+            assert_eq!(pthread_mutex_trylock(&mut rmutex), 0);
+            assert_eq!(pthread_mutex_unlock(&mut rmutex), 0);
+            assert_eq!(pthread_mutex_destroy(&mut rmutex), 16); // EBUSY
+            assert_eq!(pthread_mutex_unlock(&mut rmutex), 0);
+            assert_eq!(pthread_mutex_unlock(&mut rmutex), 1); // EPERM
+            pthread_mutexattr_settype(&mut attr, PTHREAD_MUTEX_ERRORCHECK);
+            let mut emutex = core::mem::zeroed();
+            pthread_mutex_init(&mut emutex, &attr);
+            assert_eq!(pthread_mutex_lock(&mut emutex), 0);
+            assert_eq!(pthread_mutex_lock(&mut emutex), 11); // EDEADLK
+            assert_eq!(pthread_mutex_unlock(&mut emutex), 0);
+
+            // Nobody signals, so we time out
+            let mut cond = PTHREAD_COND_INITIALIZER;
+            let now = rawtime::Instant::now()
+                .duration_since(rawtime::Instant::from_nanos(0))
+                .as_nanos()
+                + 10_000_000;
+            let abstime = timespec {
+                tv_sec: (now / 1_000_000_000) as i64,
+                tv_nsec: (now % 1_000_000_000) as i64,
+            };
+            assert_eq!(pthread_mutex_lock(&mut emutex), 0);
+            assert_eq!(pthread_cond_timedwait(&mut cond, &mut emutex, &abstime), 60); // ETIMEDOUT
+            assert_eq!(pthread_mutex_unlock(&mut emutex), 0);
+            assert_eq!(pthread_cond_destroy(&mut cond), 0);
+
+            // Values are per thread
+            let mut key = 0;
+            assert_eq!(pthread_key_create(&mut key, None), 0);
+            assert_eq!(pthread_setspecific(key, 0xdead as *const c_void), 0);
+            assert_eq!(pthread_getspecific(key) as usize, 0xdead);
+            extern "C" fn get_key(key: *mut c_void) -> *mut c_void {
+                unsafe { pthread_getspecific(key as pthread_key_t) }
+            }
+            let mut t = 0;
+            assert_eq!(
+                pthread_create(&mut t, ptr::null(), get_key, key as usize as *mut c_void),
+                0
+            );
+            let mut ret = ptr::null_mut();
+            assert_eq!(pthread_join(t, &mut ret), 0);
+            assert!(ret.is_null());
+            assert_eq!(pthread_key_delete(key), 0);
+        },
+        ptr::null_mut(),
+        0,
+        None,
+    );
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    while s.has_active_threads() {
+        s.run(&scb);
+    }
+
+    info!("pthread_test OK");
+}
+
+fn thread_panic_test() {
+    use alloc::string::String;
+
+    let s = &vibrio::upcalls::PROCESS_SCHEDULER;
+    vibrio::set_abort_on_panic(false);
+
+    let oops = s
+        .spawn(
+            32 * 4096,
+            |_| -> usize { panic!("oops") },
+            ptr::null_mut(),
+            0,
+            None,
+        )
+        .expect("Can't spawn thread");
+    let survivor = s
+        .spawn(
+            32 * 4096,
+            move |_| {
+                let err = oops.join().expect_err("Thread didn't panic?");
+                assert_eq!(
+                    err.downcast_ref::<String>().map(|e| e.as_str()),
+                    Some("oops")
+                );
+                42
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        )
+        .expect("Can't spawn thread");
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    while s.has_active_threads() {
+        s.run(&scb);
+    }
+    vibrio::set_abort_on_panic(true);
+    assert_eq!(survivor.join().expect("Survivor panicked"), 42);
+
+    info!("thread_panic_test OK");
+}
+
+fn async_test() {
+    use core::time::Duration;
+    use vibrio::executor::{io, sleep, Executor};
+    use vibrio::io::{FileFlags, FileModes};
+
+    let executor = Executor::new();
+    let slow = executor.spawn(async {
+        let start = vibrio::time::Instant::now();
+        sleep(Duration::from_millis(20)).await;
+        start.elapsed()
+    });
+    let file = executor.spawn(async {
+        let flags = u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT);
+        let fd = io::open("/async_test\0", flags, FileModes::S_IRWXU)
+            .await
+            .expect("Can't open file");
+        assert_eq!(io::write_at(fd, b"async", 0).await, Ok(5));
+        let mut buf = [0u8; 5];
+        assert_eq!(io::read_at(fd, &mut buf, 0).await, Ok(5));
+        io::close(fd).await.expect("Can't close file");
+        buf
+    });
+
+    let (slept, contents) = executor.block_on(async { (slow.await, file.await) });
+    info!("async_test: slept {:?}", slept);
+    assert!(slept >= Duration::from_millis(20));
+    assert_eq!(&contents, b"async");
+
+    info!("async_test OK");
+}
+
+fn userfault_test() {
+    use vibrio::fault::{self, Fault};
+    use vibrio::syscalls::VSpace;
+
+    static FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+    fn map_on_demand(fault: &mut Fault) -> bool {
+        FAULTS.fetch_add(1, Ordering::Relaxed);
+        VSpace::map(fault.address & !0xfff, 0x1000).is_ok()
+    }
+
+    let base: u64 = 0x5200_0000;
+    fault::register_handler(base..base + 0x2000, map_on_demand)
+        .expect("Can't register fault handler");
+
+    for page in 0..2 {
+        let ptr = (base + page * 0x1000) as *mut u64;
+        unsafe {
+            ptr.write_volatile(0xdead_beef + page);
+            assert_eq!(ptr.read_volatile(), 0xdead_beef + page);
+        }
+    }
+    // The pages are mapped now, accessing them again doesn't fault
+    assert_eq!(
+        unsafe { ((base + 0x1000) as *const u64).read_volatile() },
+        0xdead_beef + 1
+    );
+    assert_eq!(FAULTS.load(Ordering::Relaxed), 2);
+
+    fault::unregister_handler(base).expect("Can't unregister fault handler");
+    assert!(fault::unregister_handler(base).is_err());
+
+    info!("userfault_test OK");
+}
+
+/// Checks the files the kernel unpacked from the initrd (we were started
+/// from it as `/bin/init`).
+fn initrd_test() {
+    use vibrio::io::{FileFlags, FileModes, FileType};
+    use vibrio::syscalls::Fs;
+
+    let info = Fs::getinfo("/bin/init\0".as_ptr() as u64).expect("No /bin/init");
+    assert_eq!(info.ftype, FileType::File.into());
+    assert!(info.fsize > 0);
+    let info = Fs::getinfo("/bin\0".as_ptr() as u64).expect("No /bin");
+    assert_eq!(info.ftype, FileType::Directory.into());
+
+    let expected = b"hello from the initrd\n";
+    let fd = Fs::open(
+        "/initrd_test.txt\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDONLY),
+        u64::from(FileModes::S_IRUSR),
+    )
+    .expect("Can't open /initrd_test.txt");
+    let mut buf = [0u8; 64];
+    let len = Fs::read(fd, buf.as_mut_ptr() as u64, buf.len() as u64)
+        .expect("Can't read /initrd_test.txt");
+    assert_eq!(&buf[..len as usize], &expected[..]);
+    Fs::close(fd).expect("Can't close /initrd_test.txt");
+
+    info!("initrd_test OK");
+}
+
+/// Writes a file often enough to wrap around the file-system logs and checks
+/// the replicas kept up (`/proc/replicas`).
+fn replicas_test() {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use vibrio::io::{FileFlags, FileModes};
+    use vibrio::syscalls::Fs;
+
+    let fd = Fs::open(
+        "/replicas_test.txt\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        u64::from(FileModes::S_IRWXU),
+    )
+    .expect("Can't open /replicas_test.txt");
+    let data = [0xaau8; 64];
+    for _i in 0..100_000 {
+        Fs::write_at(fd, data.as_ptr() as u64, data.len() as u64, 0)
+            .expect("Can't write /replicas_test.txt");
+    }
+    Fs::close(fd).expect("Can't close /replicas_test.txt");
+
+    let fd = Fs::open(
+        "/proc/replicas\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDONLY),
+        u64::from(FileModes::S_IRUSR),
+    )
+    .expect("Can't open /proc/replicas");
+    let mut contents = String::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        let len = Fs::read(fd, buf.as_mut_ptr() as u64, buf.len() as u64)
+            .expect("Can't read /proc/replicas");
+        if len == 0 {
+            break;
+        }
+        contents.push_str(core::str::from_utf8(&buf[..len as usize]).expect("Not UTF-8"));
+    }
+    Fs::close(fd).expect("Can't close /proc/replicas");
+
+    for line in contents.lines() {
+        info!("replicas_test: {}", line);
+    }
+    let node0: Vec<u64> = contents
+        .lines()
+        .nth(1)
+        .expect("No replica for node 0")
+        .split_whitespace()
+        .map(|c| c.parse().expect("Can't parse /proc/replicas"))
+        .collect();
+    // node, cores, syncs, us_since_sync, gc_requests, lag
+    assert_eq!(node0[0], 0);
+    assert_eq!(node0[1], 2, "Both cores registered");
+    assert!(node0[2] > 0, "Replica synchronized");
+    assert_eq!(node0[5], 0, "The only replica is never behind");
+
+    info!("replicas_test OK");
+}
+
+/// Replaces a page another core uses and checks that core gets the new page
+/// right away, both when the kernel copies from it and in user-space
+/// (`Unmap` waits for the other cores to flush their TLB).
+fn shootdown_test() {
+    use vibrio::io::{FileFlags, FileModes};
+    use vibrio::syscalls::{Fs, Process, VSpace};
+    use vibrio::upcalls::{CORES_ONLINE, PROCESS_SCHEDULER};
+
+    static STAGE: AtomicUsize = AtomicUsize::new(0);
+    const CORE: usize = 1;
+    const BASE: u64 = 0x5200_0000;
+
+    Process::request_core(
+        CORE,
+        VAddr::from(vibrio::upcalls::upcall_while_enabled as *const fn() as u64),
+    )
+    .expect("Can't request core");
+    while CORES_ONLINE.load(Ordering::SeqCst) != 2 {
+        core::hint::spin_loop();
+    }
+
+    let fd = Fs::open(
+        "/shootdown_test.txt\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        u64::from(FileModes::S_IRWXU),
+    )
+    .expect("Can't open /shootdown_test.txt");
+    unsafe {
+        VSpace::map(BASE, 0x1000).expect("Map syscall failed");
+        ptr::write_bytes(BASE as *mut u8, 0xaa, 0x1000);
+    }
+
+    let s = &PROCESS_SCHEDULER;
+    s.spawn(
+        32 * 4096,
+        move |_| {
+            let page = BASE as *const u8;
+            // Get the old page into our TLB
+            assert_eq!(unsafe { page.read_volatile() }, 0xaa);
+            STAGE.store(1, Ordering::SeqCst);
+            while STAGE.load(Ordering::SeqCst) != 2 {
+                core::hint::spin_loop();
+            }
+
+            Fs::write_at(fd, BASE, 64, 0).expect("Can't write /shootdown_test.txt");
+            let mut buf = [0u8; 64];
+            Fs::read_at(fd, buf.as_mut_ptr() as u64, 64, 0)
+                .expect("Can't read /shootdown_test.txt");
+            assert!(buf.iter().all(|b| *b == 0xbb), "Kernel used the old page");
+            info!("shootdown_test: kernel copied from the new page");
+
+            // The unmap returned before we got here
+            assert_eq!(
+                unsafe { page.read_volatile() },
+                0xbb,
+                "Core {} used the old page",
+                CORE
+            );
+            info!("shootdown_test: core {} sees the new page", CORE);
+        },
+        ptr::null_mut(),
+        CORE,
+        None,
+    );
+
+    while STAGE.load(Ordering::SeqCst) != 1 {
+        core::hint::spin_loop();
+    }
+    unsafe {
+        VSpace::unmap(BASE, 0x1000).expect("Unmap syscall failed");
+        VSpace::map(BASE, 0x1000).expect("Map syscall failed");
+        ptr::write_bytes(BASE as *mut u8, 0xbb, 0x1000);
+    }
+    STAGE.store(2, Ordering::SeqCst);
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    while s.has_active_threads() {
+        s.run(&scb);
+    }
+    Fs::close(fd).expect("Can't close /shootdown_test.txt");
+
+    info!("shootdown_test OK");
+}
+
+/// Our pid, from `/proc/processes` (we're the only process).
+fn our_pid() -> usize {
+    use alloc::string::String;
+    use vibrio::io::{FileFlags, FileModes};
+    use vibrio::syscalls::Fs;
+
+    // Our pid is in the first line after the header
+    let fd = Fs::open(
+        "/proc/processes\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDONLY),
+        u64::from(FileModes::S_IRUSR),
+    )
+    .expect("Can't open /proc/processes");
+    let mut buf = [0u8; 1024];
+    let len = Fs::read(fd, buf.as_mut_ptr() as u64, buf.len() as u64)
+        .expect("Can't read /proc/processes");
+    Fs::close(fd).expect("Can't close /proc/processes");
+    let processes = String::from(core::str::from_utf8(&buf[..len as usize]).expect("Not UTF-8"));
+    processes
+        .lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().next())
+        .and_then(|pid| pid.parse().ok())
+        .expect("Can't find our pid")
+}
+
+/// Restricts and transfers file and frame capabilities (to ourselves, we're
+/// the only process) and checks the kernel enforces their rights.
+fn caps_test() {
+    use core::ptr;
+    use vibrio::cap::{CapKind, CapRights};
+    use vibrio::io::{FileFlags, FileModes};
+    use vibrio::syscalls::{Capability, Fs, PhysicalMemory, VSpace};
+    use vibrio::SystemCallError;
+
+    let pid = our_pid();
+
+    let fd = Fs::open(
+        "/caps_test.txt\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        u64::from(FileModes::S_IRWXU),
+    )
+    .expect("Can't open /caps_test.txt");
+    let rw = CapRights::READ | CapRights::WRITE | CapRights::GRANT;
+    assert_eq!(Capability::identify(fd), Ok((CapKind::File, rw)));
+
+    let data = [0xaau8; 64];
+    Fs::write_at(fd, data.as_ptr() as u64, 64, 0).expect("Can't write /caps_test.txt");
+    Capability::restrict(fd, CapRights::READ | CapRights::GRANT).expect("Can't restrict");
+    assert_eq!(
+        Fs::write_at(fd, data.as_ptr() as u64, 64, 0),
+        Err(SystemCallError::PermissionError)
+    );
+    // Rights don't come back
+    Capability::restrict(fd, rw).expect("Can't restrict");
+    assert_eq!(
+        Capability::identify(fd),
+        Ok((CapKind::File, CapRights::READ | CapRights::GRANT))
+    );
+
+    let copy = Capability::transfer(fd, pid, CapRights::READ).expect("Can't transfer");
+    assert_ne!(copy, fd);
+    assert_eq!(
+        Capability::transfer(copy, pid, CapRights::READ),
+        Err(SystemCallError::PermissionError)
+    );
+    Fs::close(fd).expect("Can't close /caps_test.txt");
+    assert_eq!(
+        Capability::identify(fd),
+        Err(SystemCallError::BadFileDescriptor)
+    );
+    let mut read = [0u8; 64];
+    Fs::read_at(copy, read.as_mut_ptr() as u64, 64, 0).expect("Can't read /caps_test.txt");
+    assert_eq!(read, data);
+    Fs::close(copy).expect("Can't close /caps_test.txt");
+    info!("caps_test: file capabilities OK");
+
+    const BASE: u64 = 0x5100_0000;
+    let (frame, _paddr) = PhysicalMemory::allocate_base_page().expect("Can't allocate a page");
+    let frame = frame as u64;
+    assert_eq!(
+        Capability::identify(frame),
+        Ok((CapKind::Frame, CapRights::all()))
+    );
+    let shared =
+        Capability::transfer(frame, pid, CapRights::READ | CapRights::MAP).expect("Can't transfer");
+    unsafe {
+        VSpace::map_frame(frame as usize, BASE).expect("Can't map the frame");
+        VSpace::map_frame(shared as usize, BASE + 0x1000).expect("Can't map the shared frame");
+        ptr::write_volatile(BASE as *mut u64, 0xdead_beef);
+        assert_eq!(
+            ptr::read_volatile((BASE + 0x1000) as *const u64),
+            0xdead_beef
+        );
+    }
+    Capability::restrict(shared, CapRights::READ).expect("Can't restrict");
+    unsafe {
+        assert_eq!(
+            VSpace::map_frame(shared as usize, BASE + 0x2000).map(|_r| ()),
+            Err(SystemCallError::PermissionError)
+        );
+    }
+    info!("caps_test: frame capabilities OK");
+
+    info!("caps_test OK");
+}
+
+/// Calls an echo server on another core through a door, once with a frame
+/// capability in the request that the server sends back with its reply.
+fn ipc_test() {
+    use vibrio::cap::{CapKind, CapRights};
+    use vibrio::ipc::Message;
+    use vibrio::syscalls::{Capability, Ipc, PhysicalMemory, Process, VSpace};
+    use vibrio::upcalls::{CORES_ONLINE, PROCESS_SCHEDULER};
+    use vibrio::SystemCallError;
+
+    const CORE: usize = 1;
+    const CALLS: usize = 16;
+    const BASE: u64 = 0x5300_0000;
+
+    let server = Ipc::create("echo").expect("Can't create door");
+    assert_eq!(Ipc::create("echo"), Err(SystemCallError::PermissionError));
+    assert_eq!(
+        Ipc::connect("nope"),
+        Err(SystemCallError::BadFileDescriptor)
+    );
+    let door = Ipc::connect("echo").expect("Can't connect to door");
+    assert_eq!(
+        Capability::identify(door),
+        Ok((CapKind::Door, CapRights::CALL | CapRights::GRANT))
+    );
+
+    Process::request_core(
+        CORE,
+        VAddr::from(vibrio::upcalls::upcall_while_enabled as *const fn() as u64),
+    )
+    .expect("Can't request core");
+    while CORES_ONLINE.load(Ordering::SeqCst) != 2 {
+        core::hint::spin_loop();
+    }
+
+    let s = &PROCESS_SCHEDULER;
+    s.spawn(
+        32 * 4096,
+        move |_| {
+            let mut request = Message::empty();
+            let mut reply = Message::empty();
+            let mut call = None;
+            loop {
+                let id = Ipc::reply(server, call.map(|id| (id, &reply)), Some(&mut request))
+                    .expect("Can't serve door");
+                if request.data() == b"stop" {
+                    Ipc::reply(server, Some((id, &Message::empty())), None).expect("Can't reply");
+                    break;
+                }
+
+                reply = if request.cap != 0 {
+                    // Tell the client what it wrote to the frame
+                    let value = unsafe {
+                        VSpace::map_frame(request.cap as usize, BASE + 0x1000)
+                            .expect("Can't map the frame");
+                        ptr::read_volatile((BASE + 0x1000) as *const u64)
+                    };
+                    Message::new(&value.to_le_bytes())
+                        .unwrap()
+                        .with_cap(request.cap)
+                } else {
+                    let mut echo = request;
+                    echo.payload.make_ascii_uppercase();
+                    echo
+                };
+                call = Some(id);
+            }
+        },
+        ptr::null_mut(),
+        CORE,
+        None,
+    );
+
+    let request = Message::new(b"hello door").unwrap();
+    for _i in 0..CALLS {
+        let reply = Ipc::call(door, &request).expect("Can't call door");
+        assert_eq!(reply.data(), b"HELLO DOOR");
+        assert_eq!(reply.cap, 0);
+    }
+    info!("ipc_test: {} calls OK", CALLS);
+
+    let (frame, _paddr) = PhysicalMemory::allocate_base_page().expect("Can't allocate a page");
+    let frame = frame as u64;
+    unsafe {
+        VSpace::map_frame(frame as usize, BASE).expect("Can't map the frame");
+        ptr::write_volatile(BASE as *mut u64, 0xfeed_f00d);
+    }
+    let reply = Ipc::call(door, &Message::empty().with_cap(frame)).expect("Can't call door");
+    assert_eq!(reply.data(), &0xfeed_f00du64.to_le_bytes());
+    // We get our own handle for the frame back
+    assert_ne!(reply.cap, frame);
+    assert_eq!(
+        Capability::identify(reply.cap).map(|(kind, _rights)| kind),
+        Ok(CapKind::Frame)
+    );
+    info!("ipc_test: capability transfer OK");
+
+    Ipc::call(door, &Message::new(b"stop").unwrap()).expect("Can't call door");
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    while s.has_active_threads() {
+        s.run(&scb);
+    }
+
+    info!("ipc_test OK");
+}
+
+/// Signals and waits for events: counters, semaphores, polling, timers and
+/// a server on another core that hears about calls to its door.
+fn event_test() {
+    use core::time::Duration;
+    use vibrio::event::EventFlags;
+    use vibrio::ipc::Message;
+    use vibrio::net::{PollEvents, PollFd};
+    use vibrio::syscalls::{Event, Ipc, Net, Process};
+    use vibrio::upcalls::{CORES_ONLINE, PROCESS_SCHEDULER};
+    use vibrio::SystemCallError;
+
+    const CORE: usize = 1;
+
+    let counter = Event::create(0, EventFlags::empty()).expect("Can't create event");
+    assert_eq!(Event::try_wait(counter), Err(SystemCallError::WouldBlock));
+    Event::signal(counter, 2).expect("Can't signal event");
+    Event::signal(counter, 3).expect("Can't signal event");
+    assert_eq!(Event::wait(counter), Ok(5));
+
+    let semaphore = Event::create(2, EventFlags::SEMAPHORE).expect("Can't create event");
+    assert_eq!(Event::wait(semaphore), Ok(1));
+    assert_eq!(Event::wait(semaphore), Ok(1));
+    assert_eq!(Event::try_wait(semaphore), Err(SystemCallError::WouldBlock));
+    info!("event_test: counters OK");
+
+    let mut fds = [PollFd::event(
+        counter,
+        PollEvents::POLLIN | PollEvents::POLLOUT,
+    )];
+    assert_eq!(Net::poll(&mut fds, Some(Duration::from_secs(0))), Ok(1));
+    assert_eq!(fds[0].revents(), PollEvents::POLLOUT);
+    Event::signal(counter, 1).expect("Can't signal event");
+    assert_eq!(Net::poll(&mut fds, Some(Duration::from_secs(0))), Ok(1));
+    assert_eq!(fds[0].revents(), PollEvents::POLLIN | PollEvents::POLLOUT);
+    assert_eq!(Event::wait(counter), Ok(1));
+    info!("event_test: poll OK");
+
+    let start = vibrio::time::Instant::now();
+    Event::set_timer(counter, Some(Duration::from_millis(50))).expect("Can't set timer");
+    assert_eq!(Event::wait(counter), Ok(1));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    Event::set_timer(counter, Some(Duration::from_millis(10))).expect("Can't set timer");
+    Event::set_timer(counter, None).expect("Can't cancel timer");
+    info!("event_test: timer OK");
+
+    // The core sleeps in `poll` until the timer goes off
+    let mut fds = [PollFd::event(counter, PollEvents::POLLIN)];
+    let start = vibrio::time::Instant::now();
+    Event::set_timer(counter, Some(Duration::from_millis(50))).expect("Can't set timer");
+    assert_eq!(
+        vibrio::net::poll(&mut fds, Some(Duration::from_secs(5))),
+        Ok(1)
+    );
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert_eq!(Event::wait(counter), Ok(1));
+    assert_eq!(
+        vibrio::net::poll(&mut fds, Some(Duration::from_millis(20))),
+        Ok(0)
+    );
+    info!("event_test: blocking poll OK");
+
+    // The server waits for the event, not in `Ipc::reply`
+    let server = Ipc::create("event").expect("Can't create door");
+    let arrived = Event::create(0, EventFlags::empty()).expect("Can't create event");
+    Ipc::notify(server, arrived).expect("Can't set door notification");
+    let door = Ipc::connect("event").expect("Can't connect to door");
+
+    Process::request_core(
+        CORE,
+        VAddr::from(vibrio::upcalls::upcall_while_enabled as *const fn() as u64),
+    )
+    .expect("Can't request core");
+    while CORES_ONLINE.load(Ordering::SeqCst) != 2 {
+        core::hint::spin_loop();
+    }
+
+    let s = &PROCESS_SCHEDULER;
+    s.spawn(
+        32 * 4096,
+        move |_| {
+            assert_eq!(Event::wait(arrived), Ok(1));
+            let mut request = Message::empty();
+            let id = Ipc::reply(server, None, Some(&mut request)).expect("Can't receive call");
+            assert_eq!(request.data(), b"knock");
+            Ipc::reply(server, Some((id, &request)), None).expect("Can't reply");
+        },
+        ptr::null_mut(),
+        CORE,
+        None,
+    );
+
+    let reply = Ipc::call(door, &Message::new(b"knock").unwrap()).expect("Can't call door");
+    assert_eq!(reply.data(), b"knock");
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    while s.has_active_threads() {
+        s.run(&scb);
+    }
+    info!("event_test: door notification OK");
+
+    info!("event_test OK");
+}
+
+/// Sends messages through a ring from two producers on another core (in
+/// the ring's second mapping, like another process would) to a consumer on
+/// this core, with a ring small enough that both sides have to wait.
+fn ring_test() {
+    use alloc::boxed::Box;
+    use core::convert::TryInto;
+    use vibrio::ipc::{Ring, RingShare};
+    use vibrio::syscalls::Process;
+    use vibrio::upcalls::{CORES_ONLINE, PROCESS_SCHEDULER};
+    use vibrio::SystemCallError;
+
+    const CORE: usize = 1;
+    const BASE: u64 = 0x5200_0000;
+    const MESSAGES: u64 = 1000;
+
+    let ring = unsafe { Ring::create(BASE, 8, 16).expect("Can't create ring") };
+    let mut buf = [0u8; 16];
+    assert_eq!(ring.try_recv(&mut buf), Err(SystemCallError::WouldBlock));
+    assert_eq!(
+        ring.try_send(&[0u8; 17]),
+        Err(SystemCallError::NotSupported)
+    );
+
+    // The share goes through a door message between processes
+    let share = ring.share(our_pid()).expect("Can't share ring");
+    let share = RingShare::from_message(&share.message()).expect("Not a ring share");
+    let other: &'static Ring = Box::leak(Box::new(
+        unsafe { Ring::attach(BASE + 0x10_0000, &share) }.expect("Can't attach ring"),
+    ));
+
+    for i in 0..8u64 {
+        other.try_send(&i.to_le_bytes()).expect("Can't send");
+    }
+    assert_eq!(other.try_send(&[0]), Err(SystemCallError::WouldBlock));
+    for i in 0..8u64 {
+        assert_eq!(ring.try_recv(&mut buf), Ok(8));
+        assert_eq!(buf[..8], i.to_le_bytes());
+    }
+    info!("ring_test: send and receive OK");
+
+    Process::request_core(
+        CORE,
+        VAddr::from(vibrio::upcalls::upcall_while_enabled as *const fn() as u64),
+    )
+    .expect("Can't request core");
+    while CORES_ONLINE.load(Ordering::SeqCst) != 2 {
+        core::hint::spin_loop();
+    }
+
+    // Producer `p` sends (p, 0), (p, 1), ...
+    let s = &PROCESS_SCHEDULER;
+    for producer in 0..2u64 {
+        s.spawn(
+            32 * 4096,
+            move |_| {
+                for i in 0..MESSAGES {
+                    let mut msg = [0u8; 16];
+                    msg[..8].copy_from_slice(&producer.to_le_bytes());
+                    msg[8..].copy_from_slice(&i.to_le_bytes());
+                    other.send(&msg).expect("Can't send");
+                }
+            },
+            ptr::null_mut(),
+            CORE,
+            None,
+        );
+    }
+
+    let mut next = [0u64; 2];
+    for _i in 0..2 * MESSAGES {
+        assert_eq!(ring.recv(&mut buf), Ok(16));
+        let producer = u64::from_le_bytes(buf[..8].try_into().unwrap()) as usize;
+        let i = u64::from_le_bytes(buf[8..].try_into().unwrap());
+        assert_eq!(i, next[producer], "Messages of a producer out of order");
+        next[producer] += 1;
+    }
+    assert_eq!(next, [MESSAGES; 2]);
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    while s.has_active_threads() {
+        s.run(&scb);
+    }
+    info!("ring_test: {} messages from 2 producers OK", 2 * MESSAGES);
+
+    info!("ring_test OK");
+}
+
+/// Checks that interval timers go off (once, or every interval until we
+/// cancel them) and that a time slice preempts a thread that never yields.
+fn itimer_test() {
+    use core::time::Duration;
+    use vibrio::time::{set_time_slice, Instant, Timer};
+    use vibrio::upcalls::PROCESS_SCHEDULER;
+
+    fn wait_for(timer: &Timer, expirations: u64) {
+        let start = Instant::now();
+        while timer.expirations() < expirations {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "Timer didn't go off"
+            );
+            core::hint::spin_loop();
+        }
+    }
+
+    let oneshot = Timer::oneshot(Duration::from_millis(5), 0).expect("Can't create timer");
+    wait_for(&oneshot, 1);
+    oneshot.cancel().expect("Can't cancel timer");
+    info!("itimer_test: oneshot OK");
+
+    let periodic = Timer::periodic(Duration::from_millis(1), 0).expect("Can't create timer");
+    wait_for(&periodic, 10);
+    periodic.cancel().expect("Can't cancel timer");
+    info!("itimer_test: periodic OK");
+
+    // The first thread spins until the second one ran, only preemption
+    // gets the second one to run
+    static SECOND_RAN: AtomicBool = AtomicBool::new(false);
+    set_time_slice(Some(Duration::from_millis(2)), 0).expect("Can't set time slice");
+    let s = &PROCESS_SCHEDULER;
+    s.spawn(
+        32 * 4096,
+        |_| {
+            let start = Instant::now();
+            while !SECOND_RAN.load(Ordering::Acquire) {
+                assert!(
+                    start.elapsed() < Duration::from_secs(5),
+                    "Spinning thread wasn't preempted"
+                );
+                core::hint::spin_loop();
+            }
+        },
+        ptr::null_mut(),
+        0,
+        None,
+    );
+    s.spawn(
+        32 * 4096,
+        |_| SECOND_RAN.store(true, Ordering::Release),
+        ptr::null_mut(),
+        0,
+        None,
+    );
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    while s.has_active_threads() {
+        s.run(&scb);
+    }
+    set_time_slice(None, 0).expect("Can't stop time slices");
+    assert!(SECOND_RAN.load(Ordering::Acquire));
+    info!("itimer_test: preemption OK");
+
+    info!("itimer_test OK");
+}
+
+/// Pins the frequency of our core and hands it back to the kernel (if the
+/// kernel can change frequencies, it can't in a virtual machine).
+fn cpufreq_test() {
+    use vibrio::syscalls::System;
+    use vibrio::SystemCallError;
+
+    let core = System::core_id().expect("Can't get core id");
+    let frequency = match System::frequency(core) {
+        Ok(frequency) => frequency,
+        Err(SystemCallError::NotSupported) => {
+            assert_eq!(
+                System::set_frequency(core, Some(1000)),
+                Err(SystemCallError::NotSupported)
+            );
+            info!("cpufreq_test: not supported");
+            info!("cpufreq_test OK");
+            return;
+        }
+        Err(e) => panic!("Can't get the frequency: {:?}", e),
+    };
+    info!(
+        "cpufreq_test: core {} at {} MHz ({}-{} MHz, base {} MHz)",
+        core, frequency.current, frequency.min, frequency.max, frequency.base
+    );
+
+    System::set_frequency(core, Some(frequency.min)).expect("Can't pin the frequency");
+    let pinned = System::frequency(core).expect("Can't get the frequency");
+    assert!(pinned.pinned);
+    assert_eq!(pinned.target, frequency.min);
+    assert_eq!(
+        System::set_frequency(core, Some(frequency.max + 1000)),
+        Err(SystemCallError::BadFlags)
+    );
+
+    System::set_frequency(core, None).expect("Can't unpin the frequency");
+    assert!(
+        !System::frequency(core)
+            .expect("Can't get the frequency")
+            .pinned
+    );
+
+    info!("cpufreq_test OK");
+}
+
+/// Suspends the machine for two seconds (if it can) and checks that the
+/// wall-clock counts the time we slept.
+fn suspend_test() {
+    use core::time::Duration;
+    use vibrio::syscalls::{System, Time};
+    use vibrio::SystemCallError;
+
+    assert_eq!(
+        System::suspend(Duration::from_millis(10)),
+        Err(SystemCallError::BadFlags)
+    );
+
+    let before = Time::wallclock().expect("Can't read wall-clock time");
+    let start = Time::monotonic_ns().expect("Can't read monotonic clock");
+    match System::suspend(Duration::from_secs(2)) {
+        Ok(()) => {
+            let slept = Time::wallclock().expect("Can't read wall-clock time") - before;
+            let elapsed = Time::monotonic_ns().expect("Can't read monotonic clock") - start;
+            info!(
+                "suspend_test: resumed after {:?} ({} ns awake)",
+                slept, elapsed
+            );
+            assert!(slept >= Duration::from_secs(1));
+        }
+        Err(SystemCallError::NotSupported) => info!("suspend_test: not supported"),
+        Err(e) => panic!("Can't suspend: {:?}", e),
+    }
+
+    info!("suspend_test OK");
+}
+
+/// Starts the kernel in `/nrk` (again) with kexec, the new kernel runs this
+/// test a second time with `kexeced` in the command line.
+fn kexec_test(pinfo: &ProcessInfo) {
+    use alloc::string::String;
+    use alloc::vec;
+    use vibrio::io::{FileFlags, FileModes};
+    use vibrio::syscalls::{Fs, System};
+
+    if pinfo.cmdline.contains("kexeced") {
+        info!("kexec_test: running on the new kernel");
+        info!("kexec_test OK");
+        return;
+    }
+
+    let info = Fs::getinfo("/nrk\0".as_ptr() as u64).expect("No /nrk");
+    let fd = Fs::open(
+        "/nrk\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDONLY),
+        u64::from(FileModes::S_IRUSR),
+    )
+    .expect("Can't open /nrk");
+    let mut image = vec![0u8; info.fsize as usize];
+    let mut read = 0;
+    while read < image.len() {
+        let len = Fs::read(
+            fd,
+            image[read..].as_mut_ptr() as u64,
+            (image.len() - read) as u64,
+        )
+        .expect("Can't read /nrk");
+        assert!(len > 0, "/nrk is shorter than it claims");
+        read += len as usize;
+    }
+    Fs::close(fd).expect("Can't close /nrk");
+
+    info!("kexec_test: starting {} bytes of kernel", image.len());
+    // The new kernel has to pick this test again
+    let mut cmdline = String::from("./kernel initargs=kexeced");
+    if !pinfo.tests.is_empty() {
+        cmdline += " tests=";
+        cmdline += pinfo.tests;
+    }
+    let error = System::kexec(&image, &cmdline);
+    panic!("kexec failed: {:?}", error);
+}
+
+/// Exit code `shutdown_test` turns the machine off with.
+const SHUTDOWN_TEST_CODE: u8 = 42;
+
+/// Turns the machine off, QEMU should exit with `SHUTDOWN_TEST_CODE`.
+fn shutdown_test() {
+    use vibrio::syscalls::System;
+    use vibrio::SystemCallError;
+
+    assert_eq!(System::shutdown(128), Err(SystemCallError::BadFlags));
+
+    info!("shutdown_test: powering off");
+    let error = System::shutdown(SHUTDOWN_TEST_CODE);
+    panic!("shutdown failed: {:?}", error);
+}
+
+/// Resets the machine, QEMU runs with `-no-reboot` and exits instead.
+fn reboot_test() {
+    use vibrio::syscalls::System;
+
+    info!("reboot_test: rebooting");
+    let error = System::reboot();
+    panic!("reboot failed: {:?}", error);
+}
+
+/// Confines us to reading files and printing and checks that everything
+/// else fails (and that we hear about it in an upcall).
+///
+/// The filter stays, so this runs after all other tests.
+fn seccomp_test() {
+    use vibrio::filter::SyscallFilter;
+    use vibrio::io::{FileFlags, FileModes};
+    use vibrio::syscalls::{Fs, Process, System};
+    use vibrio::upcalls::SYSCALLS_DENIED;
+    use vibrio::SystemCallError;
+
+    let data = [0xabu8; 64];
+    let fd = Fs::open(
+        "/seccomp_test.txt\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        u64::from(FileModes::S_IRWXU),
+    )
+    .expect("Can't open /seccomp_test.txt");
+    Fs::write_at(fd, data.as_ptr() as u64, 64, 0).expect("Can't write /seccomp_test.txt");
+    Fs::close(fd).expect("Can't close /seccomp_test.txt");
+
+    Process::restrict_syscalls(&SyscallFilter::read_and_print())
+        .expect("Can't restrict system calls");
+    info!("seccomp_test: restricted");
+
+    // Reading still works
+    let fd = Fs::open(
+        "/seccomp_test.txt\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDONLY),
+        u64::from(FileModes::S_IRUSR),
+    )
+    .expect("Can't open /seccomp_test.txt");
+    let mut read = [0u8; 64];
+    Fs::read_at(fd, read.as_mut_ptr() as u64, 64, 0).expect("Can't read /seccomp_test.txt");
+    assert_eq!(read, data);
+
+    // Writing doesn't, neither does opening a file to write or create it
+    assert_eq!(
+        Fs::write_at(fd, data.as_ptr() as u64, 64, 0),
+        Err(SystemCallError::PermissionError)
+    );
+    assert_eq!(
+        Fs::open(
+            "/seccomp_test2.txt\0".as_ptr() as u64,
+            u64::from(FileFlags::O_RDONLY | FileFlags::O_CREAT),
+            u64::from(FileModes::S_IRWXU),
+        ),
+        Err(SystemCallError::PermissionError)
+    );
+    assert_eq!(
+        System::stats().map(|_s| ()),
+        Err(SystemCallError::PermissionError)
+    );
+    assert_eq!(SYSCALLS_DENIED.load(Ordering::Relaxed), 3);
+    Fs::close(fd).expect("Can't close /seccomp_test.txt");
+
+    // There's no way back
+    Process::restrict_syscalls(&SyscallFilter::all()).expect_err("Can restrict system calls again");
+    assert_eq!(
+        System::stats().map(|_s| ()),
+        Err(SystemCallError::PermissionError)
+    );
+    assert_eq!(SYSCALLS_DENIED.load(Ordering::Relaxed), 5);
+
+    info!("seccomp_test OK");
+}
 
-    for thread in threads.iter() {
-        if thread.id != 0 {
-            let r = vibrio::syscalls::Process::request_core(
-                thread.id,
-                VAddr::from(vibrio::upcalls::upcall_while_enabled as *const fn() as u64),
-            );
-            match r {
-                Ok(ctoken) => {
-                    info!("Spawned core on {:?} <-> {}", ctoken, thread.id);
-                }
-                Err(_e) => {
-                    panic!("Failed to spawn to core {}", thread.id);
-                    continue;
-                }
-            }
+/// Maps, unmaps and allocates memory while the kernel injects faults
+/// (`faults=<seed>`): some of it fails, but the kernel (and we) keep going.
+fn faults_test() {
+    use alloc::string::String;
+    use vibrio::io::{FileFlags, FileModes};
+    use vibrio::syscalls::{Fs, PhysicalMemory, VSpace};
+    use vibrio::SystemCallError;
+
+    let base: u64 = 0x5400_0000;
+    let mut failed = 0;
+    for i in 0..200u64 {
+        match unsafe { VSpace::map(base, 0x1000) } {
+            Ok(_r) => unsafe {
+                ptr::write_volatile(base as *mut u64, i);
+                assert_eq!(ptr::read_volatile(base as *const u64), i);
+                VSpace::unmap(base, 0x1000).expect("Unmap syscall failed");
+            },
+            Err(SystemCallError::OutOfMemory) => failed += 1,
+            Err(e) => panic!("Map syscall failed: {:?}", e),
+        }
+    }
+    for _i in 0..50 {
+        match PhysicalMemory::allocate_base_page() {
+            Ok(_frame) => {}
+            Err(SystemCallError::OutOfMemory) => failed += 1,
+            Err(e) => panic!("Can't allocate a page: {:?}", e),
         }
     }
+    info!("faults_test: {} allocations failed", failed);
+    assert!(
+        failed > 0,
+        "No faults injected, did we boot with faults=<seed>?"
+    );
 
-    for thread in threads {
-        s.spawn(
-            32 * 4096,
-            move |_| {
-                info!(
-                    "Hello from core {}",
-                    lineup::tls2::Environment::scheduler().core_id
-                );
-            },
-            ptr::null_mut(),
-            thread.id,
-            None,
+    let fd = Fs::open(
+        "/proc/faults\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDONLY),
+        u64::from(FileModes::S_IRUSR),
+    )
+    .expect("Can't open /proc/faults");
+    let mut contents = String::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let len = Fs::read(fd, buf.as_mut_ptr() as u64, buf.len() as u64)
+            .expect("Can't read /proc/faults");
+        if len == 0 {
+            break;
+        }
+        contents.push_str(core::str::from_utf8(&buf[..len as usize]).expect("Not UTF-8"));
+    }
+    Fs::close(fd).expect("Can't close /proc/faults");
+
+    for line in contents.lines() {
+        info!("faults_test: {}", line);
+    }
+    for fault in &["allocation", "interrupt"] {
+        let injected: u64 = contents
+            .lines()
+            .find_map(|line| line.strip_prefix(fault))
+            .and_then(|count| count.trim().parse().ok())
+            .expect("Fault missing in /proc/faults");
+        assert!(injected > 0, "No {} faults injected", fault);
+    }
+
+    info!("faults_test OK");
+}
+
+/// Runs a few real-mode instructions in a VM (if the cores have VT-x): a
+/// `cpuid` the kernel handles, an `out` and an `in` we handle and a `hlt`.
+fn vm_test() {
+    use vibrio::syscalls::{PhysicalMemory, VSpace, Vm};
+    use vibrio::vm::{Exit, ExitKind, VcpuState, RAX, RBX};
+    use vibrio::{MemoryRights, SystemCallError};
+
+    const BASE: u64 = 0x5500_0000;
+    #[rustfmt::skip]
+    const CODE: &[u8] = &[
+        0x66, 0xb8, 0x00, 0x00, 0x00, 0x40, // mov eax, 0x4000_0000
+        0x0f, 0xa2,                         // cpuid
+        0xb0, b'x',                         // mov al, 'x'
+        0xba, 0xf8, 0x03,                   // mov dx, 0x3f8
+        0xee,                               // out dx, al
+        0xec,                               // in al, dx
+        0xa2, 0x00, 0x01,                   // mov [0x100], al
+        0xf4,                               // hlt
+    ];
+
+    let vm = match Vm::create(1) {
+        Ok(vm) => vm,
+        Err(SystemCallError::NotSupported) => {
+            info!("vm_test: not supported");
+            info!("vm_test OK");
+            return;
+        }
+        Err(e) => panic!("Can't create a VM: {:?}", e),
+    };
+    let (frame, _paddr) = PhysicalMemory::allocate_base_page().expect("Can't allocate a page");
+    unsafe {
+        VSpace::map_frame(frame, BASE).expect("Can't map the frame");
+        ptr::copy_nonoverlapping(CODE.as_ptr(), BASE as *mut u8, CODE.len());
+    }
+    Vm::map_guest(vm, frame as u64, 0, MemoryRights::all()).expect("Can't map guest memory");
+    assert_eq!(
+        Vm::map_guest(vm, frame as u64, 0, MemoryRights::all()),
+        Err(SystemCallError::VSpaceAlreadyMapped)
+    );
+
+    let mut state = VcpuState::real_mode(0, 0);
+    let mut exit = Exit::default();
+    Vm::run(vm, 0, &mut state, &mut exit).expect("Can't run the vCPU");
+    assert_eq!(exit.kind(), ExitKind::Io, "{:x?}", exit);
+    assert_eq!((exit.address, exit.size, exit.write), (0x3f8, 1, 1));
+    assert_eq!(exit.data, b'x' as u64);
+    // "NrkN"
+    assert_eq!(state.regs[RBX], 0x4e6b_724e);
+    info!("vm_test: out OK");
+
+    Vm::run(vm, 0, &mut state, &mut exit).expect("Can't run the vCPU");
+    assert_eq!(exit.kind(), ExitKind::Io, "{:x?}", exit);
+    assert_eq!((exit.address, exit.write), (0x3f8, 0));
+    state.regs[RAX] = 0x42;
+    Vm::run(vm, 0, &mut state, &mut exit).expect("Can't run the vCPU");
+    assert_eq!(exit.kind(), ExitKind::Hlt, "{:x?}", exit);
+    assert_eq!(state.rip, CODE.len() as u64);
+    assert_eq!(
+        unsafe { ptr::read_volatile((BASE + 0x100) as *const u8) },
+        0x42
+    );
+    info!("vm_test: in OK");
+
+    assert_eq!(
+        Vm::run(vm, 1, &mut state, &mut exit),
+        Err(SystemCallError::BadFlags)
+    );
+
+    // The frame is still ours
+    Vm::destroy(vm).expect("Can't destroy the VM");
+    assert!(Vm::run(vm, 0, &mut state, &mut exit).is_err());
+    assert_eq!(
+        unsafe { ptr::read_volatile((BASE + 0x100) as *const u8) },
+        0x42
+    );
+    info!("vm_test: destroy OK");
+    info!("vm_test OK");
+}
+
+/// Evicts memory to the zswap pool (pages that compress) and the swap device
+/// (pages that don't) and checks that it comes back the same, on user-space
+/// accesses and when the kernel writes to it.
+fn swap_test() {
+    use vibrio::io::{FileFlags, FileModes};
+    use vibrio::syscalls::{Fs, VSpace};
+
+    const PAGES: usize = 64;
+    const WORDS: usize = 4096 / 8;
+
+    /// Even pages are runs of the same word, odd pages random words.
+    fn word(page: usize, w: usize) -> u64 {
+        if page % 2 == 0 {
+            (page as u64) << 32 | (w / 64) as u64
+        } else {
+            let mut x = (page * WORDS + w) as u64 + 1;
+            x = x.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+            x ^ (x >> 29)
+        }
+    }
+
+    /// Reads `/proc/swap` into `buf`, returns the number of `key`.
+    fn stat(buf: &mut [u8], key: &str) -> u64 {
+        let fd = Fs::open(
+            "/proc/swap\0".as_ptr() as u64,
+            u64::from(FileFlags::O_RDONLY),
+            u64::from(FileModes::S_IRUSR),
+        )
+        .expect("Can't open /proc/swap");
+        let len =
+            Fs::read(fd, buf.as_mut_ptr() as u64, buf.len() as u64).expect("Can't read /proc/swap");
+        Fs::close(fd).expect("Can't close /proc/swap");
+
+        let contents = core::str::from_utf8(&buf[..len as usize]).expect("Not UTF-8");
+        contents
+            .lines()
+            .find_map(|line| line.strip_prefix(key))
+            .and_then(|count| count.trim().parse().ok())
+            .expect("Key missing in /proc/swap")
+    }
+
+    let size = (PAGES * 4096) as u64;
+    let (base, _paddr) = unsafe { VSpace::map_anywhere(size).expect("Can't map memory") };
+    let memory = unsafe { from_raw_parts_mut(base.as_u64() as *mut u64, PAGES * WORDS) };
+    for (i, w) in memory.iter_mut().enumerate() {
+        *w = word(i / WORDS, i % WORDS);
+    }
+
+    assert_eq!(VSpace::page_out(base.as_u64(), size), Ok(PAGES as u64));
+    // They're gone already
+    assert_eq!(VSpace::page_out(base.as_u64(), size), Ok(0));
+
+    let mut buf = [0u8; 512];
+    assert_eq!(stat(&mut buf, "pool_pages"), PAGES as u64 / 2);
+    assert_eq!(stat(&mut buf, "device_pages"), PAGES as u64 / 2);
+    info!("swap_test: evicted {} pages", PAGES);
+
+    for (i, w) in memory.iter().enumerate() {
+        assert_eq!(*w, word(i / WORDS, i % WORDS), "Word {} changed", i);
+    }
+    assert_eq!(stat(&mut buf, "pool_pages"), 0);
+    assert!(stat(&mut buf, "loads") >= PAGES as u64);
+    info!("swap_test: faulted in {} pages", PAGES);
+
+    // The kernel brings a page back before it writes to it
+    assert_eq!(VSpace::page_out(base.as_u64(), 4096), Ok(1));
+    let page = unsafe { from_raw_parts_mut(base.as_u64() as *mut u8, 4096) };
+    let loads = stat(page, "loads");
+    assert!(loads > PAGES as u64, "Loads: {}", loads);
+
+    // And an evicted page can be unmapped like any other
+    assert_eq!(VSpace::page_out(base.as_u64() + 4096, 4096), Ok(1));
+    unsafe { VSpace::unmap(base.as_u64() + 4096, 4096).expect("Can't unmap evicted page") };
+
+    info!("swap_test OK");
+}
+
+/// Creates, renames and deletes files when the file-system journal is empty
+/// (first boot) and checks that they are back on the next boot.
+fn fsjournal_test() {
+    use vibrio::io::{FileFlags, FileModes, FileType};
+    use vibrio::syscalls::Fs;
+
+    let rw = u64::from(FileModes::S_IRUSR | FileModes::S_IWUSR);
+    if Fs::getinfo("/fsjournal\0".as_ptr() as u64).is_err() {
+        Fs::mkdir_simple(
+            "/fsjournal\0".as_ptr() as u64,
+            u64::from(FileModes::S_IRWXU),
+        )
+        .expect("Can't create /fsjournal");
+        for name in ["/fsjournal/a\0", "/fsjournal/gone\0"].iter() {
+            let fd = Fs::open(
+                name.as_ptr() as u64,
+                u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+                rw,
+            )
+            .expect("Can't create file");
+            Fs::write_at(fd, name.as_ptr() as u64, name.len() as u64, 0).expect("Can't write");
+            Fs::close(fd).expect("Can't close");
+        }
+        Fs::rename(
+            "/fsjournal/a\0".as_ptr() as u64,
+            "/fsjournal/b\0".as_ptr() as u64,
+        )
+        .expect("Can't rename /fsjournal/a");
+        Fs::delete("/fsjournal/gone\0".as_ptr() as u64).expect("Can't delete /fsjournal/gone");
+        info!("fsjournal_test: created");
+    } else {
+        let info = Fs::getinfo("/fsjournal\0".as_ptr() as u64).expect("No /fsjournal");
+        assert_eq!(info.ftype, FileType::Directory.into());
+        // The metadata is back, the contents aren't
+        let info = Fs::getinfo("/fsjournal/b\0".as_ptr() as u64).expect("No /fsjournal/b");
+        assert_eq!(info.ftype, FileType::File.into());
+        assert_eq!(info.fsize, 0);
+        assert!(Fs::getinfo("/fsjournal/a\0".as_ptr() as u64).is_err());
+        assert!(Fs::getinfo("/fsjournal/gone\0".as_ptr() as u64).is_err());
+
+        let fd = Fs::open(
+            "/fsjournal/b\0".as_ptr() as u64,
+            u64::from(FileFlags::O_RDWR),
+            rw,
+        )
+        .expect("Can't open /fsjournal/b");
+        Fs::close(fd).expect("Can't close /fsjournal/b");
+        info!("fsjournal_test: replayed");
+    }
+
+    info!("fsjournal_test OK");
+}
+
+/// Takes a checkpoint of init and restores it: the new process continues
+/// with the memory and the open files we had at the checkpoint.
+fn checkpoint_test() {
+    use alloc::vec::Vec;
+    use vibrio::io::{FileFlags, FileModes};
+    use vibrio::syscalls::{Fs, Process, VSpace};
+
+    const PAGES: usize = 8;
+    const WORDS: usize = 4096 / 8;
+    const BEFORE: &[u8] = b"before the checkpoint\n";
+    const AFTER: &[u8] = b"after the restore\n";
+    const IMAGE: &str = "/init.ckpt\0";
+
+    let word = |i: usize| (i as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+
+    // The last page stays clean, it isn't in the checkpoint
+    let size = (PAGES * 4096) as u64;
+    let (base, _paddr) = unsafe { VSpace::map_anywhere(size).expect("Can't map memory") };
+    let memory = unsafe { from_raw_parts_mut(base.as_u64() as *mut u64, PAGES * WORDS) };
+    for (i, w) in memory[..(PAGES - 1) * WORDS].iter_mut().enumerate() {
+        *w = word(i);
+    }
+
+    let log = Fs::open(
+        "/checkpoint.log\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        u64::from(FileModes::S_IRUSR | FileModes::S_IWUSR),
+    )
+    .expect("Can't open /checkpoint.log");
+    assert_eq!(
+        Fs::write(log, BEFORE.as_ptr() as u64, BEFORE.len() as u64),
+        Ok(BEFORE.len() as u64)
+    );
+
+    let restored = Process::checkpoint(IMAGE.as_ptr() as u64).expect("Can't take checkpoint");
+    if restored {
+        for (i, w) in memory.iter().enumerate() {
+            let expected = if i < (PAGES - 1) * WORDS { word(i) } else { 0 };
+            assert_eq!(*w, expected, "Word {} changed", i);
+        }
+
+        // The file is still open, at the same offset
+        assert_eq!(
+            Fs::write(log, AFTER.as_ptr() as u64, AFTER.len() as u64),
+            Ok(AFTER.len() as u64)
         );
+        let mut buf = [0u8; 64];
+        let len = Fs::read_at(log, buf.as_mut_ptr() as u64, buf.len() as u64, 0)
+            .expect("Can't read /checkpoint.log") as usize;
+        assert_eq!(&buf[..BEFORE.len()], BEFORE);
+        assert_eq!(&buf[BEFORE.len()..len], AFTER);
+        info!("checkpoint_test OK");
+        return;
     }
 
-    // Run scheduler on core 0
-    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
-    loop {
-        s.run(&scb);
+    let len = Fs::getinfo(IMAGE.as_ptr() as u64)
+        .expect("No checkpoint")
+        .fsize as usize;
+    info!("checkpoint_test: took checkpoint ({} bytes)", len);
+    let mut image: Vec<u8> = Vec::with_capacity(len);
+    image.resize(len, 0);
+    let fd = Fs::open(
+        IMAGE.as_ptr() as u64,
+        u64::from(FileFlags::O_RDONLY),
+        u64::from(FileModes::S_IRUSR),
+    )
+    .expect("Can't open checkpoint");
+    let mut read = 0;
+    while read < len {
+        let n = Fs::read(fd, image[read..].as_mut_ptr() as u64, (len - read) as u64)
+            .expect("Can't read checkpoint");
+        assert!(n > 0, "Checkpoint got shorter");
+        read += n as usize;
+    }
+    Fs::close(fd).expect("Can't close checkpoint");
+
+    let pid = Process::restore(&image).expect("Can't restore checkpoint");
+    info!("checkpoint_test: restored as {}", pid);
+    // The restored process finishes the tests (and exits)
+    Process::release_core();
+}
+
+/// Switches the core policy and lets the kernel pick a core for us.
+fn core_policy_test() {
+    use vibrio::syscalls::{Process, System};
+    use vibrio::system::CorePolicy;
+    use vibrio::upcalls::CORES_ONLINE;
+
+    assert_eq!(System::core_policy(), Ok(CorePolicy::Packed));
+    assert_eq!(
+        System::set_core_policy(CorePolicy::Spread),
+        Ok(CorePolicy::Packed)
+    );
+    assert_eq!(System::core_policy(), Ok(CorePolicy::Spread));
+
+    let token = Process::request_any_core(VAddr::from(
+        vibrio::upcalls::upcall_while_enabled as *const fn() as u64,
+    ))
+    .expect("Can't request a core");
+    let me = System::core_id().expect("Can't get core id");
+    assert_ne!(token.gtid(), me);
+    assert!(System::threads()
+        .expect("Can't get threads")
+        .iter()
+        .any(|t| t.id == token.gtid()));
+    info!("core_policy_test: got core {}", token.gtid());
+    while CORES_ONLINE.load(Ordering::SeqCst) != 2 {
+        core::hint::spin_loop();
     }
+
+    System::set_core_policy(CorePolicy::Packed).expect("Can't set core policy");
+    info!("core_policy_test OK");
 }
 
-fn scheduler_test() {
-    use lineup::threads::ThreadId;
-    let mut s: lineup::scheduler::SmpScheduler = Default::default();
+/// Puts init in gang mode with two cores that contend for a spinlock and
+/// checks that the kernel stops both cores together (`/proc/gangs`).
+fn gang_test() {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use vibrio::io::{FileFlags, FileModes};
+    use vibrio::syscalls::{Fs, Process, System};
+    use vibrio::upcalls::{CORES_ONLINE, PROCESS_SCHEDULER};
+
+    static LOCK: AtomicBool = AtomicBool::new(false);
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    static DONE: AtomicBool = AtomicBool::new(false);
+    static FINISHED: AtomicBool = AtomicBool::new(false);
+    const CORE: usize = 1;
+
+    fn contend(rounds: usize) {
+        for _i in 0..rounds {
+            while LOCK
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                core::hint::spin_loop();
+            }
+            COUNTER.fetch_add(1, Ordering::Relaxed);
+            LOCK.store(false, Ordering::Release);
+        }
+    }
+
+    /// Lines of `/proc/gangs` without the header (pid, cores, stops).
+    fn gangs() -> Vec<Vec<u64>> {
+        let fd = Fs::open(
+            "/proc/gangs\0".as_ptr() as u64,
+            u64::from(FileFlags::O_RDONLY),
+            u64::from(FileModes::S_IRUSR),
+        )
+        .expect("Can't open /proc/gangs");
+        let mut contents = String::new();
+        let mut buf = [0u8; 256];
+        loop {
+            let len = Fs::read(fd, buf.as_mut_ptr() as u64, buf.len() as u64)
+                .expect("Can't read /proc/gangs");
+            if len == 0 {
+                break;
+            }
+            contents.push_str(core::str::from_utf8(&buf[..len as usize]).expect("Not UTF-8"));
+        }
+        Fs::close(fd).expect("Can't close /proc/gangs");
+
+        contents
+            .lines()
+            .skip(1)
+            .map(|line| {
+                line.split_whitespace()
+                    .map(|c| c.parse().expect("Can't parse /proc/gangs"))
+                    .collect()
+            })
+            .collect()
+    }
+
+    System::set_gang(None, true).expect("Can't enter gang mode");
+    Process::request_core(
+        CORE,
+        VAddr::from(vibrio::upcalls::upcall_while_enabled as *const fn() as u64),
+    )
+    .expect("Can't request core");
+    while CORES_ONLINE.load(Ordering::SeqCst) != 2 {
+        core::hint::spin_loop();
+    }
 
+    let s = &PROCESS_SCHEDULER;
     s.spawn(
         32 * 4096,
         move |_| {
-            unsafe {
-                info!("Hello from t1");
-                assert_eq!(TLS_TEST[0], "abcd");
-                assert_eq!(TLS_TEST[1], "efgh");
-                TLS_TEST[0] = "xxxx";
-                TLS_TEST[1] = "xxxx";
-                assert_eq!(TLS_TEST[0], "xxxx");
+            while !DONE.load(Ordering::SeqCst) {
+                contend(1000);
             }
-            assert_eq!(lineup::tls2::Environment::scheduler().core_id, 2);
-            assert_eq!(lineup::tls2::Environment::thread().current_core, 2);
-            assert_eq!(lineup::tls2::Environment::tid(), ThreadId(0));
+            FINISHED.store(true, Ordering::SeqCst);
         },
         ptr::null_mut(),
-        2,
+        CORE,
         None,
     );
 
+    // The timer of our core stops the gang every now and then
+    let mut stops = 0;
+    for _i in 0..1000 {
+        contend(100_000);
+        let gang = gangs();
+        assert_eq!(gang.len(), 1, "Only init is in gang mode");
+        assert_eq!(gang[0][1], 2, "Both cores in the gang");
+        stops = gang[0][2];
+        if stops >= 3 {
+            break;
+        }
+    }
+    DONE.store(true, Ordering::SeqCst);
+    while !FINISHED.load(Ordering::SeqCst) {
+        core::hint::spin_loop();
+    }
+    info!(
+        "gang_test: stopped {} times, {} increments",
+        stops,
+        COUNTER.load(Ordering::Relaxed)
+    );
+    assert!(stops >= 3, "Gang wasn't stopped");
+
+    System::set_gang(None, false).expect("Can't leave gang mode");
+    assert!(gangs().is_empty());
+
+    info!("gang_test OK");
+}
+
+/// Checks that the stack, the heap and anonymous mappings are where the
+/// kernel says they are (see `AddressLayout`).
+fn aslr_test() {
+    use alloc::boxed::Box;
+    use vibrio::process::{AddressLayout, HEAP_PER_CORE_REGION, MAX_CORES, MMAP_SIZE};
+    use vibrio::syscalls::{Process, VSpace};
+
+    let layout = Process::layout().expect("Can't get the layout");
+    let pinfo = Process::process_info().expect("Can't read process info");
+    assert_eq!(pinfo.layout, layout);
+    info!("aslr_test: {:x?}", layout);
+
+    let on_stack = 0u64;
+    let stack = &on_stack as *const u64 as u64;
+    assert!(stack >= layout.stack_start && stack < layout.heap_start);
+
+    let on_heap = Box::new(0u64);
+    let heap = &*on_heap as *const u64 as u64;
+    let heap_end = layout.heap_start + ((MAX_CORES + 1) * HEAP_PER_CORE_REGION) as u64;
+    assert!(heap >= layout.heap_start && heap < heap_end);
+
+    let (small, _paddr) = unsafe { VSpace::map_anywhere(0x1000) }.expect("Can't map");
+    let (large, _paddr) = unsafe { VSpace::map_anywhere(0x20_0000) }.expect("Can't map");
+    let mmap_end = layout.mmap_start + MMAP_SIZE as u64;
+    for vaddr in [small.as_u64(), large.as_u64()].iter() {
+        assert!(*vaddr >= layout.mmap_start && *vaddr < mmap_end);
+    }
+    assert_eq!(large.as_u64() % 0x20_0000, 0);
+    assert_ne!(small, large);
+    unsafe {
+        *large.as_mut_ptr::<u64>() = 0xdead;
+        VSpace::unmap(small.as_u64(), 0x1000).expect("Can't unmap");
+        VSpace::unmap(large.as_u64(), 0x20_0000).expect("Can't unmap");
+    }
+
+    if layout == AddressLayout::FIXED {
+        info!("aslr_test: fixed");
+    } else {
+        info!("aslr_test: randomized");
+    }
+    info!("aslr_test OK");
+}
+
+/// Runs as an unprivileged user (`inituser=1000:100`): we can't get more
+/// cores, can use our own files and only read the ones of root.
+fn creds_test() {
+    use vibrio::io::{FileFlags, FileModes};
+    use vibrio::process::Credentials;
+    use vibrio::syscalls::{Fs, Process};
+    use vibrio::SystemCallError;
+
+    let pinfo = Process::process_info().expect("Can't read process info");
+    assert_eq!(
+        pinfo.creds,
+        Credentials {
+            uid: 1000,
+            gid: 100
+        }
+    );
+    assert!(!pinfo.creds.is_privileged());
+    info!(
+        "creds_test: running as {}:{}",
+        pinfo.creds.uid, pinfo.creds.gid
+    );
+
+    assert_eq!(
+        Process::request_core(
+            1,
+            VAddr::from(vibrio::upcalls::upcall_while_enabled as *const fn() as u64),
+        )
+        .map(|_t| ()),
+        Err(SystemCallError::PermissionError)
+    );
+
+    // Our own file
+    let data = [0xcdu8; 64];
+    let fd = Fs::open(
+        "/creds_test.txt\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        u64::from(FileModes::S_IRUSR | FileModes::S_IWUSR),
+    )
+    .expect("Can't open /creds_test.txt");
+    Fs::write_at(fd, data.as_ptr() as u64, 64, 0).expect("Can't write /creds_test.txt");
+    let mut read = [0u8; 64];
+    Fs::read_at(fd, read.as_mut_ptr() as u64, 64, 0).expect("Can't read /creds_test.txt");
+    assert_eq!(read, data);
+    Fs::close(fd).expect("Can't close /creds_test.txt");
+
+    // Files of root are readable by everyone, but that's it
+    let fd = Fs::open(
+        "/proc/processes\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDONLY),
+        u64::from(FileModes::S_IRUSR),
+    )
+    .expect("Can't open /proc/processes");
+    Fs::read_at(fd, read.as_mut_ptr() as u64, 64, 0).expect("Can't read /proc/processes");
+    Fs::close(fd).expect("Can't close /proc/processes");
+    assert_eq!(
+        Fs::open(
+            "/proc/processes\0".as_ptr() as u64,
+            u64::from(FileFlags::O_RDWR),
+            u64::from(FileModes::S_IRUSR),
+        ),
+        Err(SystemCallError::PermissionError)
+    );
+    assert_eq!(
+        Fs::delete("/proc/processes\0".as_ptr() as u64),
+        Err(SystemCallError::PermissionError)
+    );
+
+    info!("creds_test OK");
+}
+
+fn heap_tracking_test() {
+    use alloc::string::String;
+    use vibrio::io::{FileFlags, FileModes};
+    use vibrio::syscalls::Fs;
+
+    let fd = Fs::open(
+        "/proc/heap\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDONLY),
+        u64::from(FileModes::S_IRUSR),
+    )
+    .expect("Can't open /proc/heap");
+    let mut contents = String::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        let len =
+            Fs::read(fd, buf.as_mut_ptr() as u64, buf.len() as u64).expect("Can't read /proc/heap");
+        if len == 0 {
+            break;
+        }
+        contents.push_str(core::str::from_utf8(&buf[..len as usize]).expect("Not UTF-8"));
+    }
+    Fs::close(fd).expect("Can't close /proc/heap");
+
+    for line in contents.lines() {
+        info!("heap_tracking_test: {}", line);
+    }
+    // At least our process (created by the kernel) is still around
+    assert!(
+        contents.lines().any(|l| l.starts_with("Kernel ")),
+        "No live kernel allocations"
+    );
+    assert!(contents.lines().any(|l| l.starts_with("missed ")));
+
+    info!("heap_tracking_test OK");
+}
+
+/// Data the traced calls write (at the same address when we replay them).
+static mut TRACE_DATA: [u8; 256] = [0; 256];
+
+/// Records the system calls of a small workload or, if we booted with a
+/// trace (`/proc/syscall_replay`), replays them and compares the results.
+fn syscall_trace_test() {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use vibrio::io::{FileFlags, FileModes};
+    use vibrio::syscalls::{Debug, Fs, VSpace};
+    use vibrio::trace::TraceEntry;
+    use vibrio::SystemCallError;
+
+    let base: u64 = 0x5100_0000;
+    let path = "/syscall_trace_test\0";
+    unsafe {
+        for (i, b) in TRACE_DATA.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+    }
+
+    let replay = Fs::open(
+        "/proc/syscall_replay\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDONLY),
+        u64::from(FileModes::S_IRUSR),
+    );
+    let fd = match replay {
+        Ok(fd) => fd,
+        Err(_e) => {
+            Debug::trace_syscalls(true).expect("Can't trace system calls");
+            unsafe {
+                VSpace::map(base, 0x2000).expect("Map syscall failed");
+                let fd = Fs::open(
+                    path.as_ptr() as u64,
+                    u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+                    u64::from(FileModes::S_IRWXU),
+                )
+                .expect("Can't open file");
+                Fs::write_at(fd, TRACE_DATA.as_ptr() as u64, 256, 0).expect("Can't write");
+                Fs::read_at(fd, base, 256, 0).expect("Can't read");
+                Fs::close(fd).expect("Can't close file");
+                VSpace::unmap(base, 0x2000).expect("Unmap syscall failed");
+            }
+            Debug::trace_syscalls(false).expect("Can't stop tracing system calls");
+            info!("syscall_trace_test recorded");
+            return;
+        }
+    };
+
+    let mut contents = String::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        let len = Fs::read(fd, buf.as_mut_ptr() as u64, buf.len() as u64)
+            .expect("Can't read /proc/syscall_replay");
+        if len == 0 {
+            break;
+        }
+        contents.push_str(core::str::from_utf8(&buf[..len as usize]).expect("Not UTF-8"));
+    }
+    Fs::close(fd).expect("Can't close /proc/syscall_replay");
+
+    let trace: Vec<TraceEntry> = contents
+        .lines()
+        .map(|line| line.parse().expect("Not a trace entry"))
+        .collect();
+    let (mut replayed, mut diverged) = (0, 0);
+    for entry in trace.iter() {
+        // The file-system and vspace calls, the rest is this test
+        // talking to the kernel
+        let function = vibrio::SystemCall::new(entry.function);
+        if function != vibrio::SystemCall::FileIO && function != vibrio::SystemCall::VSpace {
+            continue;
+        }
+
+        let again = unsafe { Debug::reissue(entry) };
+        replayed += 1;
+        if again.error != entry.error {
+            diverged += 1;
+            error!(
+                "syscall_trace_test: {:?} now returns {:?} (recorded {:?})",
+                entry,
+                SystemCallError::from(again.error),
+                SystemCallError::from(entry.error)
+            );
+        } else if again.error == SystemCallError::Ok as u64 && again.ret != entry.ret {
+            info!(
+                "syscall_trace_test: {:?} now returns {:x?} (recorded {:x?})",
+                entry, again.ret, entry.ret
+            );
+        }
+    }
+
+    info!(
+        "syscall_trace_test: replayed {} calls, {} diverged",
+        replayed, diverged
+    );
+    assert_eq!(diverged, 0, "Replay diverged from the trace");
+    info!("syscall_trace_test OK");
+}
+
+fn perf_test() {
+    use vibrio::perf::{PerfEvent, PerfScope};
+    use vibrio::syscalls::Perf;
+    use vibrio::SystemCallError;
+
+    fn work(n: u64) -> u64 {
+        let mut sum = 0u64;
+        for i in 0..n {
+            sum = sum.wrapping_add(unsafe { ptr::read_volatile(&i) });
+        }
+        sum
+    }
+
+    match Perf::start(0, PerfEvent::INSTRUCTIONS, PerfScope::Process) {
+        Err(SystemCallError::NotSupported) => {
+            info!("perf_test: no performance counters");
+            info!("perf_test OK");
+            return;
+        }
+        r => r.expect("Can't start counter"),
+    }
+    assert_eq!(
+        Perf::start(0, PerfEvent::CYCLES, PerfScope::Process),
+        Err(SystemCallError::PermissionError)
+    );
+
+    work(100_000);
+    let first = Perf::read(0).expect("Can't read counter");
+    assert!(first >= 100_000);
+    work(100_000);
+    let second = Perf::read(0).expect("Can't read counter");
+    assert!(second >= first + 100_000);
+
+    let instructions = Perf::stop(0).expect("Can't stop counter");
+    assert!(instructions >= second);
+    assert_eq!(Perf::read(0), Err(SystemCallError::BadFlags));
+    info!("perf_test: {} instructions", instructions);
+
+    // Counts the kernel too
+    Perf::start(1, PerfEvent::CYCLES, PerfScope::Core).expect("Can't start counter");
+    work(1_000);
+    assert!(Perf::stop(1).expect("Can't stop counter") > 0);
+
+    info!("perf_test OK");
+}
+
+fn net_xdp_test() {
+    use vibrio::net::{XdpDesc, XdpSocket};
+
+    let mut s: lineup::scheduler::SmpScheduler = Default::default();
+
     s.spawn(
         32 * 4096,
         move |_| {
-            unsafe {
-                assert_eq!(TLS_TEST[0], "abcd");
-                assert_eq!(TLS_TEST[1], "efgh");
-                info!("Hello from t2");
+            let mut xdp =
+                unsafe { XdpSocket::attach(0x4000_0000, 8890).expect("Can't attach XDP queue") };
+            info!("net_xdp_test: attached to udp:8890");
+
+            let desc = loop {
+                xdp.kick().expect("kick failed");
+                if let Some(desc) = xdp.recv() {
+                    break desc;
+                }
+                lineup::tls2::Environment::thread().relinquish();
+            };
+
+            // Echo the packet back by swapping source and destination of the
+            // Ethernet, IPv4 and UDP headers (this keeps the checksums valid):
+            let frame = xdp.frame(desc);
+            assert!(frame.len() >= 42, "Received truncated packet");
+            for i in 0..6 {
+                frame.swap(i, 6 + i);
             }
-            assert_eq!(lineup::tls2::Environment::scheduler().core_id, 2);
-            assert_eq!(lineup::tls2::Environment::thread().current_core, 2);
-            assert_eq!(lineup::tls2::Environment::tid(), ThreadId(1));
+            for i in 0..4 {
+                frame.swap(26 + i, 30 + i);
+            }
+            for i in 0..2 {
+                frame.swap(34 + i, 36 + i);
+            }
+
+            assert!(xdp.send(XdpDesc::new(desc.addr, desc.len)));
+            loop {
+                xdp.kick().expect("kick failed");
+                if let Some(done) = xdp.rings().completion.pop() {
+                    assert!(xdp.release(done));
+                    break;
+                }
+                lineup::tls2::Environment::thread().relinquish();
+            }
+
+            xdp.close().expect("Can't close XDP queue");
         },
         ptr::null_mut(),
-        2,
+        0,
         None,
     );
 
-    let scb: SchedulerControlBlock = SchedulerControlBlock::new(2);
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
     s.run(&scb);
 
-    info!("scheduler_test OK");
+    info!("net_xdp_test OK");
 }
 
 #[cfg(feature = "rumprt")]
@@ -211,7 +2706,7 @@ fn test_rump_tmpfs() {
     scheduler.spawn(
         32 * 4096,
         |_yielder| unsafe {
-            let start = rawtime::Instant::now();
+            let start = vibrio::time::Instant::now();
             rump_boot_setsigmodel(0);
             let ri = rump_init();
             assert_eq!(ri, 0);
@@ -327,7 +2822,7 @@ pub fn test_rump_net() {
     scheduler.spawn(
         32 * 4096,
         |_yielder| unsafe {
-            let start = rawtime::Instant::now();
+            let start = vibrio::time::Instant::now();
             rump_boot_setsigmodel(1);
             let ri = rump_init(ready);
             assert_eq!(ri, 0);
@@ -417,6 +2912,8 @@ pub fn test_rump_net() {
     let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
     loop {
         scheduler.run(&scb);
+        // Sleep instead of spinning while the thread sleeps in `nanosleep`
+        scheduler.idle(&scb);
     }
 }
 
@@ -437,7 +2934,8 @@ fn test_fs_invalid_addresses() {
         u64::from(FileModes::S_IRWXU),
     )
     .expect("FileOpen syscall failed");
-    assert_eq!(fd, 0);
+    let (kind, _rights) = vibrio::syscalls::Capability::identify(fd).expect("Not a handle");
+    assert_eq!(kind, vibrio::cap::CapKind::File);
 
     let ret = vibrio::syscalls::Fs::write(fd, 0x0, 256).expect_err("FileWrite syscall should fail");
     let fileinfo = vibrio::syscalls::Fs::getinfo(0x0).expect_err("FileOpen syscall should fail");
@@ -464,6 +2962,15 @@ fn test_fs_invalid_addresses() {
     let _ret = vibrio::syscalls::Fs::write(fd, base_small - 1, 256)
         .expect_err("FileWrite syscall should fail");
 
+    // The kernel only writes to memory we can write to
+    unsafe {
+        vibrio::syscalls::VSpace::protect(base_small, size_small, vibrio::MemoryRights::READ)
+            .expect("Protect syscall failed");
+    }
+    let _ret =
+        vibrio::syscalls::Fs::read(fd, base_small, 256).expect_err("FileRead syscall should fail");
+    let _ret = vibrio::syscalls::Fs::write(fd, base_small, 256).expect("FileWrite syscall failed");
+
     // Test address validity large pages.
     let base_large: u64 = 0x8000000;
     let size_large: u64 = 0x200000;
@@ -484,6 +2991,17 @@ fn test_fs_invalid_addresses() {
         .expect_err("FileWrite syscall should fail");
     let _ret = vibrio::syscalls::Fs::write(fd, base_large - 1, 256)
         .expect_err("FileWrite syscall should fail");
+    let _ret = vibrio::syscalls::Fs::write(fd, base_large, u64::MAX)
+        .expect_err("FileWrite syscall should fail");
+
+    // Kernel memory (starting at KERNEL_BASE) is mapped, just not for us.
+    let kernel: u64 = 0x4000_0000_0000;
+    let _ret =
+        vibrio::syscalls::Fs::write(fd, kernel, 256).expect_err("FileWrite syscall should fail");
+    let _ret =
+        vibrio::syscalls::Fs::read(fd, kernel, 256).expect_err("FileRead syscall should fail");
+    let _ret = vibrio::syscalls::Fs::write_direct(kernel, 256, 0)
+        .expect_err("WriteDirect syscall should fail");
 
     // Close the opened file.
     let ret = vibrio::syscalls::Fs::close(fd).expect("FileClose syscall failed");
@@ -502,7 +3020,8 @@ fn fs_test() {
             u64::from(FileModes::S_IRWXU),
         )
         .expect("FileOpen syscall failed");
-        assert_eq!(fd, 0);
+        let (kind, _rights) = vibrio::syscalls::Capability::identify(fd).expect("Not a handle");
+        assert_eq!(kind, vibrio::cap::CapKind::File);
 
         // Allocate a buffer and write data into it, which is later written to the file.
         vibrio::syscalls::VSpace::map(base, size).expect("Map syscall failed");
@@ -538,6 +3057,10 @@ fn fs_test() {
         let ret = vibrio::syscalls::Fs::write_at(fd, slice.as_ptr() as u64, 256, 4096 * 255)
             .expect("FileWriteAt syscall failed");
 
+        // Wait for the writes to be applied, fails for a closed file.
+        vibrio::syscalls::Fs::fsync(fd).expect("FSync syscall failed");
+        assert!(vibrio::syscalls::Fs::fsync(fd + 1).is_err());
+
         // Close the file.
         let ret = vibrio::syscalls::Fs::close(fd).expect("FileClose syscall failed");
         assert_eq!(ret, 0);
@@ -580,7 +3103,7 @@ fn fs_write_test() {
         let mut iterations = 10;
         let mut iops = 0;
         while iterations > 0 {
-            let start = rawtime::Instant::now();
+            let start = vibrio::time::Instant::now();
             while start.elapsed().as_secs() < 1 {
                 Fs::write_direct(slice.as_ptr() as u64, 4096, 0).expect("Failed");
                 iops += 1;
@@ -613,8 +3136,21 @@ pub extern "C" fn _start() -> ! {
             .map(|()| log::set_max_level(Level::Debug.to_level_filter()))
             .expect("Can't set-up logging");
     }
+    // A test fails if any of its threads panics
+    vibrio::set_abort_on_panic(true);
 
     debug!("Initialized logging");
+    match vibrio::syscalls::System::abi_version() {
+        Ok((version, features)) => debug!("Kernel interface {} ({:?})", version, features),
+        Err(e) => {
+            error!(
+                "Can't use this kernel (we need interface {}): {:?}",
+                vibrio::abi::ABI_VERSION,
+                e
+            );
+            vibrio::syscalls::Process::exit(1);
+        }
+    }
     install_vcpu_area();
 
     let pinfo = vibrio::syscalls::Process::process_info().expect("Can't read process info");
@@ -639,44 +3175,18 @@ pub extern "C" fn _start() -> ! {
     #[cfg(feature = "bench-vmops-unmaplat")]
     vmops::unmaplat::bench(ncores);
 
-    #[cfg(feature = "test-print")]
-    print_test();
-
-    #[cfg(feature = "test-upcall")]
-    upcall_test();
-
-    #[cfg(feature = "test-map")]
-    map_test();
-
-    #[cfg(feature = "test-alloc")]
-    alloc_test();
-
-    #[cfg(feature = "test-scheduler")]
-    scheduler_test();
-
-    #[cfg(feature = "test-scheduler-smp")]
-    scheduler_smp_test();
-
-    #[cfg(feature = "rumprt")]
-    {
-        // Run either, test-rump-net or test-rump-tmpfs
-        // TODO: Can't run both together at the moment, I suspect it is due to
-        // the IRQ thread being statically 'hacked' as thread#1 in virbio/upcalls.rs
-        #[cfg(all(not(feature = "test-rump-net"), feature = "test-rump-tmpfs"))]
-        test_rump_tmpfs();
-        #[cfg(all(not(feature = "test-rump-tmpfs"), feature = "test-rump-net"))]
-        test_rump_net();
-    }
-
-    #[cfg(feature = "test-fs")]
-    fs_test();
-
     #[cfg(feature = "fs-write")]
     fs_write_test();
 
     #[cfg(feature = "fxmark")]
     fxmark::bench(ncores, open_files, benchmark, write_ratio);
 
+    #[cfg(feature = "dbbench")]
+    //python3 ./run.py --kfeature test-userspace-smp --ufeatures dbbench --qemu-cores 2 --cmd initargs=2Xfillrandom
+    dbbench::bench(pinfo.cmdline);
+
+    tests::run(&pinfo);
+
     vibrio::vconsole::init();
 
     debug!("Done with init tests, if we came here probably everything is good.");
@@ -0,0 +1,54 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Counts cache and TLB misses of the benchmarks (with the `perf` feature).
+//!
+//! The benchmarks print them as
+//! `thread_id,benchmark,misses,llc_misses,dtlb_load_misses`.
+
+use log::warn;
+
+use vibrio::perf::{PerfEvent, PerfScope};
+use vibrio::syscalls::Perf;
+
+const EVENTS: [PerfEvent; 2] = [PerfEvent::LLC_MISSES, PerfEvent::DTLB_LOAD_MISSES];
+
+/// LLC and DTLB load misses of the process on the current core.
+pub struct Misses {
+    last: [u64; 2],
+}
+
+impl Misses {
+    /// Starts counting (`None` if the core can't).
+    pub fn start() -> Option<Misses> {
+        for (counter, event) in EVENTS.iter().enumerate() {
+            if let Err(e) = Perf::start(counter, *event, PerfScope::Process) {
+                warn!("Can't count {:?}: {:?}", event, e);
+                for started in 0..counter {
+                    let _ = Perf::stop(started);
+                }
+                return None;
+            }
+        }
+        Some(Misses { last: [0; 2] })
+    }
+
+    /// The (LLC, DTLB load) misses since the last call (or `start`).
+    pub fn delta(&mut self) -> (u64, u64) {
+        let mut delta = [0; 2];
+        for (counter, last) in self.last.iter_mut().enumerate() {
+            let now = Perf::read(counter).unwrap_or(*last);
+            delta[counter] = now - *last;
+            *last = now;
+        }
+        (delta[0], delta[1])
+    }
+}
+
+impl Drop for Misses {
+    fn drop(&mut self) {
+        for counter in 0..EVENTS.len() {
+            let _ = Perf::stop(counter);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The tests init knows and which of them we run.
+//!
+//! The kernel command-line picks them by name (`tests=map,fs,scheduler`), a
+//! name is the `test-*` feature without `test-`. Without `tests=` we run the
+//! tests whose feature is enabled. Every test is in every init binary, so a
+//! single build runs all of them (a few at a time).
+//!
+//! Tests run in the order of [`TESTS`], not in the one of the command-line.
+//! Some don't combine: `kexec`, `shutdown` and `reboot` don't return,
+//! `creds` drops our privileges and `seccomp` takes away most system calls,
+//! so they are at the end.
+//!
+//! We print `[test] <name>: started` and `[test] <name>: ok` around every
+//! test and `[tests] <n> passed` once all of them passed. A test fails by
+//! panicking, which ends init (and the kernel), so the last `started` line
+//! tells which one it was.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use log::error;
+use vibrio::process::ProcessInfo;
+use vibrio::sys_println;
+
+/// A test init can run.
+struct Test {
+    /// What `tests=` calls it.
+    name: &'static str,
+    /// We run it if the command-line doesn't pick any tests (its feature is
+    /// enabled).
+    default: bool,
+    run: fn(&ProcessInfo),
+}
+
+macro_rules! entry {
+    ($name:tt, $feature:tt, $run:expr) => {
+        Test {
+            name: $name,
+            default: cfg!(feature = $feature),
+            run: $run,
+        }
+    };
+}
+
+/// All tests, in the order we run them.
+static TESTS: &[Test] = &[
+    entry!("print", "test-print", |_| crate::print_test()),
+    entry!("upcall", "test-upcall", |_| crate::upcall_test()),
+    entry!("map", "test-map", |_| crate::map_test()),
+    entry!("alloc", "test-alloc", |_| crate::alloc_test()),
+    entry!("scheduler", "test-scheduler", |_| crate::scheduler_test()),
+    entry!("scheduler-smp", "test-scheduler-smp", |_| {
+        crate::scheduler_smp_test()
+    }),
+    entry!("rump-tmpfs", "test-rump-tmpfs", rump_tmpfs),
+    entry!("rump-net", "test-rump-net", rump_net),
+    entry!("fs", "test-fs", |_| crate::fs_test()),
+    entry!("net-socket", "test-net-socket", |_| crate::net_socket_test(
+    )),
+    entry!("net-xdp", "test-net-xdp", |_| crate::net_xdp_test()),
+    entry!("net-ping", "test-net-ping", |_| crate::net_ping_test()),
+    entry!("vsock", "test-vsock", |_| crate::vsock_test()),
+    entry!("time", "test-time", |_| crate::time_test()),
+    entry!("getrandom", "test-getrandom", |_| crate::getrandom_test()),
+    entry!("topology", "test-topology", |_| crate::topology_test()),
+    entry!("hotplug", "test-hotplug", |_| crate::hotplug_test()),
+    entry!("nmi", "test-nmi", |_| crate::nmi_test()),
+    entry!("perf", "test-perf", |_| crate::perf_test()),
+    entry!("log-filter", "test-log-filter", |_| crate::log_filter_test(
+    )),
+    entry!("dmesg", "test-dmesg", |_| crate::dmesg_test()),
+    entry!("stats", "test-stats", |_| crate::stats_test()),
+    entry!("syscall-latency", "test-syscall-latency", |_| {
+        crate::syscall_latency_test()
+    }),
+    entry!("kprobes", "test-kprobes", |_| crate::kprobe_test()),
+    entry!("sched-graph", "test-sched-graph", |_| {
+        crate::sched_graph_test()
+    }),
+    entry!("heap-tracking", "test-heap-tracking", |_| {
+        crate::heap_tracking_test()
+    }),
+    entry!("syscall-trace", "test-syscall-trace", |_| {
+        crate::syscall_trace_test()
+    }),
+    entry!("futex", "test-futex", |_| crate::futex_test()),
+    entry!("pthread", "test-pthread", |_| crate::pthread_test()),
+    entry!("thread-panic", "test-thread-panic", |_| {
+        crate::thread_panic_test()
+    }),
+    entry!("async", "test-async", |_| crate::async_test()),
+    entry!("userfault", "test-userfault", |_| crate::userfault_test()),
+    entry!("initrd", "test-initrd", |_| crate::initrd_test()),
+    entry!("replicas", "test-replicas", |_| crate::replicas_test()),
+    entry!("shootdown", "test-shootdown", |_| crate::shootdown_test()),
+    entry!("caps", "test-caps", |_| crate::caps_test()),
+    entry!("ipc", "test-ipc", |_| crate::ipc_test()),
+    entry!("event", "test-event", |_| crate::event_test()),
+    entry!("ring", "test-ring", |_| crate::ring_test()),
+    entry!("itimer", "test-itimer", |_| crate::itimer_test()),
+    entry!("cpufreq", "test-cpufreq", |_| crate::cpufreq_test()),
+    entry!("suspend", "test-suspend", |_| crate::suspend_test()),
+    entry!("faults", "test-faults", |_| crate::faults_test()),
+    entry!("vm", "test-vm", |_| crate::vm_test()),
+    entry!("swap", "test-swap", |_| crate::swap_test()),
+    entry!("fsjournal", "test-fsjournal", |_| crate::fsjournal_test()),
+    entry!("checkpoint", "test-checkpoint", |_| crate::checkpoint_test(
+    )),
+    entry!("core-policy", "test-core-policy", |_| {
+        crate::core_policy_test()
+    }),
+    entry!("gang", "test-gang", |_| crate::gang_test()),
+    entry!("kexec", "test-kexec", crate::kexec_test),
+    entry!("shutdown", "test-shutdown", |_| crate::shutdown_test()),
+    entry!("reboot", "test-reboot", |_| crate::reboot_test()),
+    entry!("creds", "test-creds", |_| crate::creds_test()),
+    entry!("aslr", "test-aslr", |_| crate::aslr_test()),
+    entry!("seccomp", "test-seccomp", |_| crate::seccomp_test()),
+];
+
+#[cfg(feature = "rumprt")]
+fn rump_tmpfs(_pinfo: &ProcessInfo) {
+    crate::test_rump_tmpfs();
+}
+
+#[cfg(not(feature = "rumprt"))]
+fn rump_tmpfs(_pinfo: &ProcessInfo) {
+    panic!("rump-tmpfs needs init built with rumprt");
+}
+
+#[cfg(feature = "rumprt")]
+fn rump_net(_pinfo: &ProcessInfo) {
+    crate::test_rump_net();
+}
+
+#[cfg(not(feature = "rumprt"))]
+fn rump_net(_pinfo: &ProcessInfo) {
+    panic!("rump-net needs init built with rumprt");
+}
+
+/// Runs the tests the command-line picked (or the default ones).
+pub fn run(pinfo: &ProcessInfo) {
+    let tests = match select(pinfo.tests) {
+        Ok(tests) => tests,
+        Err(e) => {
+            error!("Can't run tests={}: {}", pinfo.tests, e);
+            vibrio::syscalls::Process::exit(1);
+        }
+    };
+
+    for test in tests.iter() {
+        sys_println!("[test] {}: started", test.name);
+        (test.run)(pinfo);
+        sys_println!("[test] {}: ok", test.name);
+    }
+    if !tests.is_empty() {
+        sys_println!("[tests] {} passed", tests.len());
+    }
+}
+
+/// The tests `names` (separated by commas) picks, in the order we run them.
+///
+/// We pick the default tests if `names` is empty.
+fn select(names: &str) -> Result<Vec<&'static Test>, String> {
+    for name in names.split(',').filter(|name| !name.is_empty()) {
+        if !TESTS.iter().any(|test| test.name == name) {
+            let known: Vec<&str> = TESTS.iter().map(|test| test.name).collect();
+            return Err(alloc::format!(
+                "unknown test '{}' (we know {})",
+                name,
+                known.join(",")
+            ));
+        }
+    }
+    let picked: Vec<&'static Test> = if names.is_empty() {
+        TESTS.iter().filter(|test| test.default).collect()
+    } else {
+        TESTS
+            .iter()
+            .filter(|test| names.split(',').any(|name| name == test.name))
+            .collect()
+    };
+
+    // TODO: Can't run both together at the moment, I suspect it is due to
+    // the IRQ thread being statically 'hacked' as thread#1 in virbio/upcalls.rs
+    let rump = picked
+        .iter()
+        .filter(|test| test.name.starts_with("rump-"))
+        .count();
+    if rump > 1 {
+        return Err(String::from("rump-tmpfs and rump-net can't run together"));
+    }
+
+    Ok(picked)
+}
@@ -14,43 +14,117 @@ use x86::bits64::paging::{PAddr, VAddr, BASE_PAGE_SIZE, PML4_SLOT_SIZE};
 use lineup::threads::ThreadId;
 use lineup::tls2::{Environment, SchedulerControlBlock};
 
-use crate::histogram;
-
 pub mod queue;
 pub mod unmaplat;
 
+/// Bench threads that arrived at `barrier` so far (over all phases).
 static POOR_MANS_BARRIER: AtomicUsize = AtomicUsize::new(0);
-static LATENCY_HISTOGRAM: spin::Mutex<Option<histogram::Histogram>> = spin::Mutex::new(None);
+/// Latency of all cores, indexed by `Phase`.
+static LATENCY_HISTOGRAM: spin::Mutex<Vec<histogram::Histogram>> = spin::Mutex::new(Vec::new());
+
+/// The operations we measure, every bench thread runs them in this order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Map a frame at a new address.
+    MapOnly,
+    /// Change the rights of a mapped page (read-only <-> read-write).
+    Protect,
+    /// Unmap a page and map it again at the same address.
+    Remap,
+}
+
+impl Phase {
+    const ALL: [Phase; 3] = [Phase::MapOnly, Phase::Protect, Phase::Remap];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Phase::MapOnly => "maponly",
+            Phase::Protect => "protect",
+            Phase::Remap => "remap",
+        }
+    }
+}
 
-unsafe extern "C" fn maponly_bencher_trampoline(arg1: *mut u8) -> *mut u8 {
+unsafe extern "C" fn bencher_trampoline(arg1: *mut u8) -> *mut u8 {
     let cores = arg1 as usize;
-    maponly_bencher(cores);
+    bencher(cores);
     ptr::null_mut()
 }
 
-fn maponly_bencher(cores: usize) {
-    use vibrio::io::*;
+/// Waits until all `cores` bench threads called it `round` times.
+fn barrier(cores: usize, round: usize) {
+    POOR_MANS_BARRIER.fetch_add(1, Ordering::Relaxed);
+    while POOR_MANS_BARRIER.load(Ordering::Relaxed) < cores * round {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+fn bencher(cores: usize) {
     use vibrio::syscalls::*;
+    use vibrio::MemoryRights;
     info!("Trying to allocate a frame");
     let (frame_id, paddr) =
         PhysicalMemory::allocate_base_page().expect("Can't allocate a memory obj");
     info!("Got frame_id {:#?}", frame_id);
 
+    // Every thread has its own PML4 slot, maponly fills the lower half of it
     let vspace_offset = lineup::tls2::Environment::tid().0 + 1;
-    let mut base: u64 = (PML4_SLOT_SIZE + (PML4_SLOT_SIZE * vspace_offset)) as u64;
+    let slot_base: u64 = (PML4_SLOT_SIZE + (PML4_SLOT_SIZE * vspace_offset)) as u64;
+    let page_base: u64 = slot_base + (PML4_SLOT_SIZE / 2) as u64;
     let size: u64 = BASE_PAGE_SIZE as u64;
-    info!("start mapping at {:#x}", base);
 
+    for (round, &phase) in Phase::ALL.iter().enumerate() {
+        // Protect and remap work on a single page that's mapped already
+        if phase == Phase::Protect {
+            unsafe { VSpace::map_frame(frame_id, page_base).expect("Map syscall failed") };
+        }
+
+        // Synchronize with all cores
+        barrier(cores, round + 1);
+
+        match phase {
+            Phase::MapOnly => {
+                let mut base = slot_base;
+                info!("start mapping at {:#x}", base);
+                run_phase(phase, cores, || {
+                    unsafe { VSpace::map_frame(frame_id, base).expect("Map syscall failed") };
+                    base += size;
+                });
+            }
+            Phase::Protect => {
+                let mut writeable = true;
+                run_phase(phase, cores, || {
+                    writeable = !writeable;
+                    let rights = if writeable {
+                        MemoryRights::READ | MemoryRights::WRITE
+                    } else {
+                        MemoryRights::READ
+                    };
+                    unsafe {
+                        VSpace::protect(page_base, size, rights).expect("Protect syscall failed")
+                    };
+                });
+            }
+            Phase::Remap => {
+                run_phase(phase, cores, || unsafe {
+                    VSpace::unmap(page_base, size).expect("Unmap syscall failed");
+                    VSpace::map_frame(frame_id, page_base).expect("Map syscall failed");
+                });
+            }
+        }
+    }
+}
+
+/// Runs `op` for the duration of the benchmark, reports how many times it ran
+/// every second (or its latency).
+fn run_phase<F: FnMut()>(phase: Phase, cores: usize, mut op: F) {
     #[cfg(feature = "latency")]
     pub const LATENCY_MEASUREMENTS: usize = 100_000;
     #[cfg(feature = "latency")]
     let mut latency: Vec<Duration> = Vec::with_capacity(LATENCY_MEASUREMENTS);
 
-    // Synchronize with all cores
-    POOR_MANS_BARRIER.fetch_sub(1, Ordering::Relaxed);
-    while POOR_MANS_BARRIER.load(Ordering::Relaxed) != 0 {
-        core::sync::atomic::spin_loop_hint();
-    }
+    #[cfg(feature = "perf")]
+    let mut misses = crate::perf::Misses::start();
 
     let mut vops = 0;
     let mut iteration = 0;
@@ -64,11 +138,11 @@ fn maponly_bencher(cores: usize) {
     };
 
     'outer: while iteration <= bench_duration_secs {
-        let start = rawtime::Instant::now();
+        let start = vibrio::time::Instant::now();
         while start.elapsed().as_secs() < 1 {
             #[cfg(feature = "latency")]
-            let before = rawtime::Instant::now();
-            unsafe { VSpace::map_frame(frame_id, base).expect("Map syscall failed") };
+            let before = vibrio::time::Instant::now();
+            op();
             #[cfg(feature = "latency")]
             {
                 // Skip 4s for warmup
@@ -81,32 +155,59 @@ fn maponly_bencher(cores: usize) {
             }
 
             vops += 1;
-            base += BASE_PAGE_SIZE as u64;
         }
         #[cfg(not(feature = "latency"))]
         info!(
-            "{},maponly,{},{},{},{},{}",
+            "{},{},{},{},{},{},{}",
             Environment::scheduler().core_id,
+            phase.name(),
             cores,
             4096,
             bench_duration_secs * 1000,
             iteration * 1000,
             vops
         );
+        #[cfg(feature = "perf")]
+        if let Some(misses) = misses.as_mut() {
+            let (llc, dtlb) = misses.delta();
+            info!(
+                "{},{},misses,{},{}",
+                Environment::scheduler().core_id,
+                phase.name(),
+                llc,
+                dtlb
+            );
+        }
         vops = 0;
         iteration += 1;
     }
 
     #[cfg(feature = "latency")]
     {
+        let mut core_histogram = histogram::Histogram::new();
         let mut hlock = LATENCY_HISTOGRAM.lock();
-        for (idx, duration) in latency.iter().enumerate() {
-            let mut h = hlock.as_mut().unwrap();
-            h.increment(duration.as_nanos().try_into().unwrap());
+        for duration in latency.iter() {
+            let nanos: u64 = duration.as_nanos().try_into().unwrap();
+            let _r = hlock[phase as usize].increment(nanos);
+            let _r = core_histogram.increment(nanos);
         }
-    }
+        drop(hlock);
 
-    POOR_MANS_BARRIER.fetch_add(1, Ordering::Relaxed);
+        info!(
+            "Core latency percentiles: {},{},{},{},{},{},{},{},{},{},{}",
+            Environment::scheduler().core_id,
+            phase.name(),
+            cores,
+            4096,
+            core_histogram.percentile(1.0).unwrap(),
+            core_histogram.percentile(25.0).unwrap(),
+            core_histogram.percentile(50.0).unwrap(),
+            core_histogram.percentile(75.0).unwrap(),
+            core_histogram.percentile(99.0).unwrap(),
+            core_histogram.percentile(99.9).unwrap(),
+            core_histogram.percentile(100.0).unwrap(),
+        );
+    }
 }
 
 pub fn bench(ncores: Option<usize>) {
@@ -114,7 +215,7 @@ pub fn bench(ncores: Option<usize>) {
 
     LATENCY_HISTOGRAM
         .lock()
-        .replace(histogram::Histogram::new());
+        .extend(Phase::ALL.iter().map(|_phase| histogram::Histogram::new()));
 
     let hwthreads = vibrio::syscalls::System::threads().expect("Can't get system topology");
     let s = &vibrio::upcalls::PROCESS_SCHEDULER;
@@ -148,16 +249,12 @@ pub fn bench(ncores: Option<usize>) {
             for idx in maximum..maximum + 1 {
                 let mut thandles = Vec::with_capacity(idx);
                 // Set up barrier
-                POOR_MANS_BARRIER.store(idx, Ordering::SeqCst);
+                POOR_MANS_BARRIER.store(0, Ordering::SeqCst);
 
                 for core_id in 0..idx {
                     thandles.push(
                         Environment::thread()
-                            .spawn_on_core(
-                                Some(maponly_bencher_trampoline),
-                                idx as *mut u8,
-                                core_id,
-                            )
+                            .spawn_on_core(Some(bencher_trampoline), idx as *mut u8, core_id)
                             .expect("Can't spawn bench thread?"),
                     );
                 }
@@ -179,22 +276,24 @@ pub fn bench(ncores: Option<usize>) {
     #[cfg(feature = "latency")]
     {
         let hlock = LATENCY_HISTOGRAM.lock();
-        let h = hlock.as_ref().unwrap();
 
         info!("benchmark,ncores,memsize,p1,p25,p50,p75,p99,p99.9,p100");
-        // Don't adjust this line without changing `s06_vmops_latency_benchmark`
-        info!(
-            "Latency percentiles: {},{},{},{},{},{},{},{},{},{}",
-            "maponly",
-            cores,
-            4096,
-            h.percentile(1.0).unwrap(),
-            h.percentile(25.0).unwrap(),
-            h.percentile(50.0).unwrap(),
-            h.percentile(75.0).unwrap(),
-            h.percentile(99.0).unwrap(),
-            h.percentile(99.9).unwrap(),
-            h.percentile(100.0).unwrap(),
-        );
+        for &phase in Phase::ALL.iter() {
+            let h = &hlock[phase as usize];
+            // Don't adjust this line without changing `s06_vmops_latency_benchmark`
+            info!(
+                "Latency percentiles: {},{},{},{},{},{},{},{},{},{}",
+                phase.name(),
+                cores,
+                4096,
+                h.percentile(1.0).unwrap(),
+                h.percentile(25.0).unwrap(),
+                h.percentile(50.0).unwrap(),
+                h.percentile(75.0).unwrap(),
+                h.percentile(99.0).unwrap(),
+                h.percentile(99.9).unwrap(),
+                h.percentile(100.0).unwrap(),
+            );
+        }
     }
 }
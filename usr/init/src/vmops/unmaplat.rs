@@ -18,8 +18,6 @@ use lineup::tls2::{Environment, SchedulerControlBlock};
 
 use super::queue::{Queue, QueueReceiver, QueueSender};
 
-use crate::histogram;
-
 static POOR_MANS_BARRIER: AtomicUsize = AtomicUsize::new(0);
 static EXIT: AtomicBool = AtomicBool::new(false);
 static LATENCY_HISTOGRAM: spin::Mutex<Option<histogram::Histogram>> = spin::Mutex::new(None);
@@ -121,7 +119,14 @@ fn unmap_bencher(cores: usize) {
             h.increment(*duration);
         }
     } else {
-        vibrio::syscalls::System::stats();
+        let core = vibrio::syscalls::System::core_id().expect("Can't get core id");
+        let stats = vibrio::syscalls::System::stats().expect("Can't get stats");
+        if let Some(stats) = stats.iter().find(|s| s.id == core) {
+            info!(
+                "IRQ handler time: {} cycles ({} shootdowns)",
+                stats.tlb_shootdown_cycles, stats.tlb_shootdowns_received
+            );
+        }
     }
 
     POOR_MANS_BARRIER.fetch_add(1, Ordering::Relaxed);
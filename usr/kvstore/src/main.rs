@@ -0,0 +1,255 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A memcached-compatible key-value server on the sockets of the kernel
+//! network stack.
+//!
+//! The argument (`initargs`) is the number of cores to use. Every core runs
+//! `CONNECTIONS_PER_CORE` lineup threads that each listen on the TCP port
+//! and serve one connection at a time, core 0 also serves UDP.
+#![no_std]
+#![no_main]
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ptr;
+
+use log::{debug, error, info, warn, Level};
+use x86::bits64::paging::VAddr;
+
+use lineup::tls2::{Environment, SchedulerControlBlock};
+use vibrio::net::{PollEvents, PollFd, SocketType};
+use vibrio::syscalls::Net;
+use vibrio::SystemCallError;
+
+mod protocol;
+mod store;
+#[cfg(feature = "fs-log")]
+mod update_log;
+
+use protocol::Progress;
+use store::Store;
+
+/// The memcached port.
+const PORT: u16 = 11211;
+
+/// How many TCP connections a core can serve at the same time.
+const CONNECTIONS_PER_CORE: usize = 16;
+
+/// Every UDP datagram (request or response) starts with this header
+/// (request id, sequence number, number of datagrams, reserved).
+const UDP_HEADER_SIZE: usize = 8;
+
+/// How much of the response goes in one UDP datagram.
+const UDP_PAYLOAD_SIZE: usize = 1400;
+
+const STACK_SIZE: usize = 32 * 4096;
+
+/// Sends all of `buf` on the TCP connection `fd`, returns false if the
+/// connection is gone.
+fn send_all(fd: u64, mut buf: &[u8]) -> bool {
+    while !buf.is_empty() {
+        match Net::send(fd, buf) {
+            Ok(sent) => buf = &buf[sent..],
+            Err(SystemCallError::WouldBlock) => Environment::thread().relinquish(),
+            Err(e) => {
+                debug!("Can't send on {}: {:?}", fd, e);
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Handles the requests of the connection on `fd` until the client closes
+/// it.
+fn serve_connection(store: &Store, fd: u64, input: &mut Vec<u8>, output: &mut Vec<u8>) {
+    let mut fds = [PollFd::new(fd, PollEvents::POLLIN)];
+    let mut buf = [0u8; 4096];
+
+    loop {
+        vibrio::net::poll(&mut fds, None).expect("poll failed");
+        let len = match Net::recv(fd, &mut buf) {
+            Ok(0) => return,
+            Ok(len) => len,
+            Err(SystemCallError::WouldBlock) => continue,
+            Err(e) => {
+                debug!("Can't receive on {}: {:?}", fd, e);
+                return;
+            }
+        };
+        input.extend_from_slice(&buf[..len]);
+
+        let mut consumed = 0;
+        let mut quit = false;
+        while consumed < input.len() {
+            match protocol::process(store, &input[consumed..], output) {
+                Progress::Incomplete => break,
+                Progress::Consumed(len) => consumed += len,
+                Progress::Quit => {
+                    quit = true;
+                    break;
+                }
+            }
+        }
+        input.drain(..consumed);
+
+        if !send_all(fd, output) || quit {
+            return;
+        }
+        output.clear();
+    }
+}
+
+fn serve_tcp(store: &Store) {
+    let mut input = Vec::with_capacity(4096);
+    let mut output = Vec::with_capacity(4096);
+
+    loop {
+        // A listening socket turns into the connection, so we need a new
+        // one for the next client
+        let fd = Net::socket(SocketType::Tcp).expect("Can't create TCP socket");
+        Net::bind(fd, PORT).expect("Can't listen on TCP socket");
+
+        serve_connection(store, fd, &mut input, &mut output);
+        input.clear();
+        output.clear();
+
+        if let Err(e) = Net::close(fd) {
+            warn!("Can't close TCP socket {}: {:?}", fd, e);
+        }
+    }
+}
+
+fn serve_udp(store: &Store) {
+    let fd = Net::socket(SocketType::Udp).expect("Can't create UDP socket");
+    Net::bind(fd, PORT).expect("Can't bind UDP socket");
+
+    let mut fds = [PollFd::new(fd, PollEvents::POLLIN)];
+    let mut buf = [0u8; 4096];
+    let mut output = Vec::with_capacity(4096);
+    let mut datagram = Vec::with_capacity(UDP_HEADER_SIZE + UDP_PAYLOAD_SIZE);
+
+    loop {
+        vibrio::net::poll(&mut fds, None).expect("poll failed");
+        let (len, from) = match Net::recv_from(fd, &mut buf) {
+            Ok(r) => r,
+            Err(SystemCallError::WouldBlock) => continue,
+            Err(e) => {
+                warn!("Can't receive on UDP socket: {:?}", e);
+                continue;
+            }
+        };
+        if len < UDP_HEADER_SIZE {
+            continue;
+        }
+
+        // A request has to fit in a single datagram
+        let request = &buf[UDP_HEADER_SIZE..len];
+        let mut consumed = 0;
+        while consumed < request.len() {
+            match protocol::process(store, &request[consumed..], &mut output) {
+                Progress::Consumed(len) => consumed += len,
+                Progress::Incomplete | Progress::Quit => break,
+            }
+        }
+
+        let chunks = output.chunks(UDP_PAYLOAD_SIZE);
+        let total = chunks.len() as u16;
+        for (seq, chunk) in chunks.enumerate() {
+            datagram.clear();
+            datagram.extend_from_slice(&buf[0..2]);
+            datagram.extend_from_slice(&(seq as u16).to_be_bytes());
+            datagram.extend_from_slice(&total.to_be_bytes());
+            datagram.extend_from_slice(&[0, 0]);
+            datagram.extend_from_slice(chunk);
+            if let Err(e) = Net::send_to(fd, &datagram, from) {
+                warn!("Can't send on UDP socket: {:?}", e);
+            }
+        }
+        output.clear();
+    }
+}
+
+/// Asks the kernel for `ncores` cores (including the one we run on),
+/// returns how many we got.
+fn request_cores(ncores: usize) -> usize {
+    let hwthreads = vibrio::syscalls::System::threads().expect("Can't get system topology");
+
+    let mut cores = 1; // We already have core 0
+    for hwthread in hwthreads.iter().take(ncores) {
+        if hwthread.id != 0 {
+            match vibrio::syscalls::Process::request_core(
+                hwthread.id,
+                VAddr::from(vibrio::upcalls::upcall_while_enabled as *const fn() as u64),
+            ) {
+                Ok(_) => cores += 1,
+                Err(e) => {
+                    error!("Can't spawn on {:?}: {:?}", hwthread.id, e);
+                    break;
+                }
+            }
+        }
+    }
+    cores
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    log::set_logger(&vibrio::writer::LOGGER)
+        .map(|()| log::set_max_level(Level::Info.to_level_filter()))
+        .expect("Can't set-up logging");
+    if let Err(e) = vibrio::syscalls::System::abi_version() {
+        error!(
+            "Can't use this kernel (we need interface {}): {:?}",
+            vibrio::abi::ABI_VERSION,
+            e
+        );
+        vibrio::syscalls::Process::exit(1);
+    }
+
+    let ctl =
+        vibrio::syscalls::Process::vcpu_control_area().expect("Can't read vcpu control area.");
+    ctl.resume_with_upcall =
+        VAddr::from(vibrio::upcalls::upcall_while_enabled as *const fn() as u64);
+
+    let pinfo = vibrio::syscalls::Process::process_info().expect("Can't read process info");
+    let ncores = request_cores(pinfo.cmdline.parse().unwrap_or(1));
+
+    // Lives until the process exits
+    let store: &'static Store = Box::leak(Box::new(Store::new()));
+
+    let s = &vibrio::upcalls::PROCESS_SCHEDULER;
+    for core in 0..ncores {
+        for _i in 0..CONNECTIONS_PER_CORE {
+            s.spawn(
+                STACK_SIZE,
+                move |_| serve_tcp(store),
+                ptr::null_mut(),
+                core,
+                None,
+            )
+            .expect("Can't spawn TCP server thread");
+        }
+    }
+    s.spawn(
+        STACK_SIZE,
+        move |_| serve_udp(store),
+        ptr::null_mut(),
+        0,
+        None,
+    )
+    .expect("Can't spawn UDP server thread");
+    info!(
+        "kvstore: serving tcp:{} and udp:{} on {} cores",
+        PORT, PORT, ncores
+    );
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    while s.has_active_threads() {
+        s.run(&scb);
+    }
+
+    vibrio::syscalls::Process::exit(0);
+}
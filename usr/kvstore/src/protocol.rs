@@ -0,0 +1,171 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The memcached text protocol.
+//!
+//! Supports `get`, `set`, `add`, `replace`, `delete`, `flush_all`,
+//! `version` and `quit`. The expiration time of items is ignored (they
+//! never expire), other commands get an `ERROR`.
+
+use alloc::format;
+use alloc::vec::Vec;
+use core::str;
+
+use crate::store::{Item, Policy, Store};
+
+/// Longest command line we accept (without the data block of a `set`).
+const MAX_LINE: usize = 2048;
+
+/// Longest key memcached allows.
+const MAX_KEY: usize = 250;
+
+/// Biggest value we store.
+const MAX_VALUE: usize = 1024 * 1024;
+
+/// What `process` did with the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    /// The input doesn't have a full request yet.
+    Incomplete,
+    /// Handled a request of that many bytes.
+    Consumed(usize),
+    /// The client wants to close the connection.
+    Quit,
+}
+
+/// Handles the first request in `input` (if it's complete) and appends the
+/// response to `out`.
+pub fn process(store: &Store, input: &[u8], out: &mut Vec<u8>) -> Progress {
+    let line_len = match input.iter().position(|&b| b == b'\n') {
+        Some(pos) => pos + 1,
+        None if input.len() > MAX_LINE => {
+            out.extend_from_slice(b"CLIENT_ERROR line too long\r\n");
+            return Progress::Consumed(input.len());
+        }
+        None => return Progress::Incomplete,
+    };
+    let line = trim_line(&input[..line_len]);
+    let mut args = line.split(|&b| b == b' ').filter(|arg| !arg.is_empty());
+
+    match args.next().unwrap_or(b"") {
+        b"get" | b"gets" => {
+            for key in args {
+                store.get(key, |item| {
+                    if let Some(item) = item {
+                        out.extend_from_slice(b"VALUE ");
+                        out.extend_from_slice(key);
+                        out.extend_from_slice(
+                            format!(" {} {}\r\n", item.flags, item.data.len()).as_bytes(),
+                        );
+                        out.extend_from_slice(&item.data);
+                        out.extend_from_slice(b"\r\n");
+                    }
+                });
+            }
+            out.extend_from_slice(b"END\r\n");
+            Progress::Consumed(line_len)
+        }
+        cmd @ (b"set" | b"add" | b"replace") => {
+            let policy = match cmd {
+                b"add" => Policy::IfAbsent,
+                b"replace" => Policy::IfPresent,
+                _ => Policy::Always,
+            };
+            let args: Vec<&[u8]> = args.collect();
+            let (key, flags, len, noreply) = match parse_storage(&args) {
+                Some(parsed) => parsed,
+                None => {
+                    out.extend_from_slice(b"CLIENT_ERROR bad command line format\r\n");
+                    return Progress::Consumed(line_len);
+                }
+            };
+
+            // The data block follows the line and ends with "\r\n"
+            let total = line_len + len + 2;
+            if input.len() < total {
+                return Progress::Incomplete;
+            }
+            if &input[line_len + len..total] != b"\r\n" {
+                out.extend_from_slice(b"CLIENT_ERROR bad data chunk\r\n");
+                return Progress::Consumed(total);
+            }
+
+            let item = Item {
+                flags,
+                data: input[line_len..line_len + len].to_vec(),
+            };
+            let stored = store.store(key, item, policy);
+            if !noreply {
+                if stored {
+                    out.extend_from_slice(b"STORED\r\n");
+                } else {
+                    out.extend_from_slice(b"NOT_STORED\r\n");
+                }
+            }
+            Progress::Consumed(total)
+        }
+        b"delete" => {
+            let key = args.next();
+            let noreply = args.next() == Some(&b"noreply"[..]);
+            match key {
+                Some(key) => {
+                    let deleted = store.delete(key);
+                    if !noreply {
+                        if deleted {
+                            out.extend_from_slice(b"DELETED\r\n");
+                        } else {
+                            out.extend_from_slice(b"NOT_FOUND\r\n");
+                        }
+                    }
+                }
+                None => out.extend_from_slice(b"ERROR\r\n"),
+            }
+            Progress::Consumed(line_len)
+        }
+        b"flush_all" => {
+            store.clear();
+            if !args.any(|arg| arg == b"noreply") {
+                out.extend_from_slice(b"OK\r\n");
+            }
+            Progress::Consumed(line_len)
+        }
+        b"version" => {
+            out.extend_from_slice(b"VERSION 1.4.0-nrk\r\n");
+            Progress::Consumed(line_len)
+        }
+        b"quit" => Progress::Quit,
+        _ => {
+            out.extend_from_slice(b"ERROR\r\n");
+            Progress::Consumed(line_len)
+        }
+    }
+}
+
+/// Strips the line ending ("\r\n" or "\n").
+fn trim_line(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+fn parse_number<T: str::FromStr>(arg: &[u8]) -> Option<T> {
+    str::from_utf8(arg).ok()?.parse().ok()
+}
+
+/// Parses `<key> <flags> <exptime> <bytes> [noreply]`.
+fn parse_storage<'a>(args: &[&'a [u8]]) -> Option<(&'a [u8], u32, usize, bool)> {
+    if args.len() < 4 || args.len() > 5 || args[0].len() > MAX_KEY {
+        return None;
+    }
+    let flags = parse_number(args[1])?;
+    let _exptime: i64 = parse_number(args[2])?;
+    let len = parse_number(args[3])?;
+    if len > MAX_VALUE {
+        return None;
+    }
+    let noreply = match args.get(4) {
+        Some(&b"noreply") => true,
+        Some(_) => return None,
+        None => false,
+    };
+    Some((args[0], flags, len, noreply))
+}
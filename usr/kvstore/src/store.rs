@@ -0,0 +1,101 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The items, in a fixed number of shards so cores that work on different
+//! keys don't contend on a lock.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+/// How many shards the store has.
+const SHARDS: usize = 64;
+
+/// A stored value.
+#[derive(Debug, Clone)]
+pub struct Item {
+    /// Opaque flags of the client (returned with the value).
+    pub flags: u32,
+    pub data: Vec<u8>,
+}
+
+pub struct Store {
+    shards: Vec<Mutex<BTreeMap<Vec<u8>, Item>>>,
+    #[cfg(feature = "fs-log")]
+    log: crate::update_log::UpdateLog,
+}
+
+/// FNV-1a, to pick the shard of a key.
+fn hash(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+impl Store {
+    pub fn new() -> Store {
+        Store {
+            shards: (0..SHARDS).map(|_| Mutex::new(BTreeMap::new())).collect(),
+            #[cfg(feature = "fs-log")]
+            log: crate::update_log::UpdateLog::create(),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> &Mutex<BTreeMap<Vec<u8>, Item>> {
+        &self.shards[hash(key) as usize % SHARDS]
+    }
+
+    /// Calls `f` with the item of `key` (if there is one).
+    pub fn get<R, F: FnOnce(Option<&Item>) -> R>(&self, key: &[u8], f: F) -> R {
+        f(self.shard(key).lock().get(key))
+    }
+
+    /// Stores `item` if `policy` allows it given whether `key` exists
+    /// already, returns false if it didn't.
+    pub fn store(&self, key: &[u8], item: Item, policy: Policy) -> bool {
+        let mut shard = self.shard(key).lock();
+        let exists = shard.contains_key(key);
+        let allowed = match policy {
+            Policy::Always => true,
+            Policy::IfAbsent => !exists,
+            Policy::IfPresent => exists,
+        };
+        if allowed {
+            #[cfg(feature = "fs-log")]
+            self.log.set(key, &item);
+            shard.insert(key.to_vec(), item);
+        }
+        allowed
+    }
+
+    /// Removes `key`, returns false if it didn't exist.
+    pub fn delete(&self, key: &[u8]) -> bool {
+        let removed = self.shard(key).lock().remove(key).is_some();
+        #[cfg(feature = "fs-log")]
+        if removed {
+            self.log.delete(key);
+        }
+        removed
+    }
+
+    /// Removes all items.
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.lock().clear();
+        }
+        #[cfg(feature = "fs-log")]
+        self.log.clear();
+    }
+}
+
+/// When `Store::store` stores an item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// `set`
+    Always,
+    /// `add`
+    IfAbsent,
+    /// `replace`
+    IfPresent,
+}
@@ -0,0 +1,68 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Appends the updates to the store to a file (`fs-log` feature).
+//!
+//! The records are the requests in memcached text format (`set`, `delete`
+//! and `flush_all`), so the file could be replayed against any memcached.
+
+use alloc::format;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+use vibrio::io::{FileFlags, FileModes};
+use vibrio::syscalls::Fs;
+
+use crate::store::Item;
+
+const LOG_FILE: &str = "/kvstore.log\0";
+
+pub struct UpdateLog {
+    /// The file descriptor, the lock keeps the records of concurrent
+    /// updates apart.
+    fd: Mutex<u64>,
+}
+
+impl UpdateLog {
+    pub fn create() -> UpdateLog {
+        let fd = Fs::open(
+            LOG_FILE.as_ptr() as u64,
+            u64::from(FileFlags::O_WRONLY | FileFlags::O_CREAT),
+            u64::from(FileModes::S_IRWXU),
+        )
+        .expect("Can't open update log");
+        UpdateLog { fd: Mutex::new(fd) }
+    }
+
+    fn append(&self, record: &[u8]) {
+        let fd = self.fd.lock();
+        let mut written = 0;
+        while written < record.len() {
+            let buf = &record[written..];
+            written += Fs::write(*fd, buf.as_ptr() as u64, buf.len() as u64)
+                .expect("Can't write update log") as usize;
+        }
+    }
+
+    pub fn set(&self, key: &[u8], item: &Item) {
+        let mut record: Vec<u8> = Vec::with_capacity(key.len() + item.data.len() + 32);
+        record.extend_from_slice(b"set ");
+        record.extend_from_slice(key);
+        record.extend_from_slice(format!(" {} 0 {}\r\n", item.flags, item.data.len()).as_bytes());
+        record.extend_from_slice(&item.data);
+        record.extend_from_slice(b"\r\n");
+        self.append(&record);
+    }
+
+    pub fn delete(&self, key: &[u8]) {
+        let mut record: Vec<u8> = Vec::with_capacity(key.len() + 9);
+        record.extend_from_slice(b"delete ");
+        record.extend_from_slice(key);
+        record.extend_from_slice(b"\r\n");
+        self.append(&record);
+    }
+
+    pub fn clear(&self) {
+        self.append(b"flush_all\r\n");
+    }
+}
@@ -0,0 +1,23 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// The libraries `std` (and the `libc` crate) link against on NetBSD.
+const NETBSD_LIBS: &[&str] = &["c", "m", "rt", "util", "pthread", "execinfo", "gcc_s"];
+
+fn main() {
+    // Programs for x86_64-nrk-std get the symbols of these libraries from
+    // us, the linker only has to find something with their name
+    if env::var("CARGO_CFG_TARGET_VENDOR").as_deref() == Ok("nrk") {
+        let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+        for lib in NETBSD_LIBS {
+            fs::write(out_dir.join(format!("lib{}.a", lib)), b"!<arch>\n")
+                .expect("Can't write empty archive");
+        }
+        println!("cargo:rustc-link-search=native={}", out_dir.display());
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,98 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! `errno` and the error codes we set.
+
+use vibrio::SystemCallError;
+
+use crate::c_int;
+
+pub const EPERM: c_int = 1;
+pub const ENOENT: c_int = 2;
+pub const EINTR: c_int = 4;
+pub const EIO: c_int = 5;
+pub const EBADF: c_int = 9;
+pub const ENOMEM: c_int = 12;
+pub const EFAULT: c_int = 14;
+pub const EEXIST: c_int = 17;
+pub const EINVAL: c_int = 22;
+pub const ENOTTY: c_int = 25;
+pub const ESPIPE: c_int = 29;
+pub const ERANGE: c_int = 34;
+pub const EAGAIN: c_int = 35;
+pub const EINPROGRESS: c_int = 36;
+pub const ENOTSOCK: c_int = 38;
+pub const ENOPROTOOPT: c_int = 42;
+pub const EAFNOSUPPORT: c_int = 47;
+pub const ENOTCONN: c_int = 57;
+pub const ETIMEDOUT: c_int = 60;
+pub const ECONNREFUSED: c_int = 61;
+pub const ENOSYS: c_int = 78;
+pub const ENOTSUP: c_int = 86;
+
+/// The `errno` of the current thread.
+#[thread_local]
+static mut ERRNO: c_int = 0;
+
+/// Returns the location of `errno` (the NetBSD headers define `errno` as
+/// `(*__errno())`).
+#[no_mangle]
+pub unsafe extern "C" fn __errno() -> *mut c_int {
+    &mut ERRNO as *mut c_int
+}
+
+/// Sets `errno`, returns -1 (what most functions return on errors).
+pub fn set_errno(e: c_int) -> c_int {
+    unsafe { ERRNO = e };
+    -1
+}
+
+/// The error code for a failed system call.
+pub fn from_syscall_error(e: SystemCallError) -> c_int {
+    match e {
+        SystemCallError::NotSupported => ENOTSUP,
+        SystemCallError::VSpaceAlreadyMapped => EINVAL,
+        SystemCallError::OutOfMemory => ENOMEM,
+        SystemCallError::BadAddress => EFAULT,
+        SystemCallError::BadFileDescriptor => EBADF,
+        SystemCallError::BadFlags => EINVAL,
+        SystemCallError::PermissionError => EPERM,
+        SystemCallError::OffsetError => EINVAL,
+        SystemCallError::WouldBlock => EAGAIN,
+        SystemCallError::TimedOut => ETIMEDOUT,
+        SystemCallError::IncompatibleAbi => ENOSYS,
+        SystemCallError::Ok
+        | SystemCallError::NotLogged
+        | SystemCallError::InternalError
+        | SystemCallError::Unknown => EIO,
+    }
+}
+
+/// What `strerror` says about `e`.
+pub(crate) fn message(e: c_int) -> &'static str {
+    match e {
+        EPERM => "Operation not permitted",
+        ENOENT => "No such file or directory",
+        EINTR => "Interrupted system call",
+        EIO => "Input/output error",
+        EBADF => "Bad file descriptor",
+        ENOMEM => "Cannot allocate memory",
+        EFAULT => "Bad address",
+        EEXIST => "File exists",
+        EINVAL => "Invalid argument",
+        ENOTTY => "Inappropriate ioctl for device",
+        ESPIPE => "Illegal seek",
+        ERANGE => "Result too large or too small",
+        EAGAIN => "Resource temporarily unavailable",
+        EINPROGRESS => "Operation now in progress",
+        ENOTSOCK => "Socket operation on non-socket",
+        ENOPROTOOPT => "Protocol option not available",
+        EAFNOSUPPORT => "Address family not supported by protocol family",
+        ENOTCONN => "Socket is not connected",
+        ETIMEDOUT => "Connection timed out",
+        ECONNREFUSED => "Connection refused",
+        ENOSYS => "Function not implemented",
+        ENOTSUP => "Not supported",
+        _ => "Unknown error",
+    }
+}
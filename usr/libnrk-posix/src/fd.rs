@@ -0,0 +1,134 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The descriptor table.
+//!
+//! Files and sockets have their own descriptors in the kernel, so we give
+//! out our own and remember what they stand for. 0, 1 and 2 are stdin
+//! (always at its end), stdout and stderr (both go to the console).
+
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use vibrio::net::{SocketAddr, SocketType};
+
+use crate::c_int;
+use crate::errno;
+
+/// The first descriptor we hand out for files and sockets.
+const FD_OFFSET: c_int = 3;
+
+#[derive(Clone)]
+pub(crate) enum Descriptor {
+    /// One of stdin, stdout or stderr.
+    Console(c_int),
+    File(File),
+    Socket(Socket),
+}
+
+#[derive(Clone)]
+pub(crate) struct File {
+    /// Descriptor of the file in the kernel.
+    pub fd: u64,
+    /// Where we opened it (with the terminating NUL), the kernel only tells
+    /// us the size of files by name.
+    pub path: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Socket {
+    /// Descriptor of the socket in the kernel.
+    pub fd: u64,
+    pub ty: SocketType,
+    /// The port from `bind`.
+    pub port: Option<u16>,
+    /// Whether `listen` was called (the kernel socket waits for a peer).
+    pub listening: bool,
+    /// The endpoint from `connect` or `accept`.
+    pub peer: Option<SocketAddr>,
+    pub nonblocking: bool,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+}
+
+impl Socket {
+    pub(crate) fn new(fd: u64, ty: SocketType, nonblocking: bool) -> Socket {
+        Socket {
+            fd,
+            ty,
+            port: None,
+            listening: false,
+            peer: None,
+            nonblocking,
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+}
+
+/// Entry `i` is descriptor `i + FD_OFFSET`.
+static TABLE: spin::Mutex<Vec<Option<Descriptor>>> = spin::Mutex::new(Vec::new());
+
+/// Adds `descriptor` to the table, returns the lowest free descriptor.
+pub(crate) fn insert(descriptor: Descriptor) -> c_int {
+    let mut table = TABLE.lock();
+    let idx = match table.iter().position(|d| d.is_none()) {
+        Some(idx) => {
+            table[idx] = Some(descriptor);
+            idx
+        }
+        None => {
+            table.push(Some(descriptor));
+            table.len() - 1
+        }
+    };
+    idx as c_int + FD_OFFSET
+}
+
+/// Returns (a copy of) what `fd` stands for, `EBADF` if it's not open.
+pub(crate) fn get(fd: c_int) -> Result<Descriptor, c_int> {
+    if (0..FD_OFFSET).contains(&fd) {
+        return Ok(Descriptor::Console(fd));
+    }
+    TABLE
+        .lock()
+        .get((fd - FD_OFFSET) as usize)
+        .and_then(|d| d.clone())
+        .ok_or(errno::EBADF)
+}
+
+/// Returns the socket `fd`, `ENOTSOCK` if it is something else.
+pub(crate) fn socket(fd: c_int) -> Result<Socket, c_int> {
+    match get(fd)? {
+        Descriptor::Socket(socket) => Ok(socket),
+        _ => Err(errno::ENOTSOCK),
+    }
+}
+
+/// Changes the socket `fd` with `f`.
+pub(crate) fn update_socket<R>(fd: c_int, f: impl FnOnce(&mut Socket) -> R) -> Result<R, c_int> {
+    if (0..FD_OFFSET).contains(&fd) {
+        return Err(errno::ENOTSOCK);
+    }
+    let mut table = TABLE.lock();
+    match table.get_mut((fd - FD_OFFSET) as usize) {
+        Some(Some(Descriptor::Socket(socket))) => Ok(f(socket)),
+        Some(Some(_)) => Err(errno::ENOTSOCK),
+        _ => Err(errno::EBADF),
+    }
+}
+
+/// Removes `fd` from the table, the standard streams stay.
+pub(crate) fn remove(fd: c_int) -> Result<Descriptor, c_int> {
+    if (0..FD_OFFSET).contains(&fd) {
+        return Ok(Descriptor::Console(fd));
+    }
+    if fd < 0 {
+        return Err(errno::EBADF);
+    }
+    TABLE
+        .lock()
+        .get_mut((fd - FD_OFFSET) as usize)
+        .and_then(|d| d.take())
+        .ok_or(errno::EBADF)
+}
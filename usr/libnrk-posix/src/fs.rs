@@ -0,0 +1,462 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Files (fcntl.h, unistd.h, sys/stat.h and `rename` from stdio.h).
+//!
+//! `read`, `write` and `close` also work on sockets.
+
+use alloc::vec::Vec;
+use core::{slice, str};
+
+use vibrio::io::{FileFlags, FileModes, FileType};
+use vibrio::syscalls::{Fs, Process};
+
+use crate::errno::{self, from_syscall_error, set_errno};
+use crate::fd::{self, Descriptor, File};
+use crate::string::strlen;
+use crate::{c_char, c_int, c_long, c_ulong, c_void, mode_t, off_t, size_t, socket, ssize_t};
+
+pub const O_RDONLY: c_int = 0x0000;
+pub const O_WRONLY: c_int = 0x0001;
+pub const O_RDWR: c_int = 0x0002;
+pub const O_ACCMODE: c_int = 0x0003;
+pub const O_APPEND: c_int = 0x0008;
+pub const O_CREAT: c_int = 0x0200;
+pub const O_TRUNC: c_int = 0x0400;
+pub const O_EXCL: c_int = 0x0800;
+pub const O_NONBLOCK: c_int = 0x0004;
+pub const O_CLOEXEC: c_int = 0x0040_0000;
+
+pub const F_GETFD: c_int = 1;
+pub const F_SETFD: c_int = 2;
+pub const F_GETFL: c_int = 3;
+pub const F_SETFL: c_int = 4;
+pub const FD_CLOEXEC: c_int = 1;
+
+pub const FIOCLEX: c_ulong = 0x2000_6601;
+pub const FIONCLEX: c_ulong = 0x2000_6602;
+pub const FIONBIO: c_ulong = 0x8004_667e;
+
+pub const S_IFMT: mode_t = 0o170000;
+pub const S_IFDIR: mode_t = 0o040000;
+pub const S_IFREG: mode_t = 0o100000;
+
+pub const STDIN_FILENO: c_int = 0;
+pub const STDOUT_FILENO: c_int = 1;
+pub const STDERR_FILENO: c_int = 2;
+
+pub const SEEK_SET: c_int = 0;
+pub const SEEK_CUR: c_int = 1;
+pub const SEEK_END: c_int = 2;
+
+/// The NetBSD `struct stat`.
+#[repr(C)]
+pub struct stat {
+    pub st_dev: u64,
+    pub st_mode: mode_t,
+    pub st_ino: u64,
+    pub st_nlink: u32,
+    pub st_uid: u32,
+    pub st_gid: u32,
+    pub st_rdev: u64,
+    pub st_atime: i64,
+    pub st_atimensec: c_long,
+    pub st_mtime: i64,
+    pub st_mtimensec: c_long,
+    pub st_ctime: i64,
+    pub st_ctimensec: c_long,
+    pub st_birthtime: i64,
+    pub st_birthtimensec: c_long,
+    pub st_size: off_t,
+    pub st_blocks: i64,
+    pub st_blksize: u32,
+    pub st_flags: u32,
+    pub st_gen: u32,
+    pub st_spare: [u32; 2],
+}
+
+/// The kernel descriptor of the file `fd`.
+fn file(fd: c_int) -> Result<u64, c_int> {
+    match fd::get(fd)? {
+        Descriptor::File(file) => Ok(file.fd),
+        _ => Err(errno::ESPIPE),
+    }
+}
+
+fn to_flags(flags: c_int) -> Result<FileFlags, c_int> {
+    let mut nrk_flags = match flags & O_ACCMODE {
+        O_RDONLY => FileFlags::O_RDONLY,
+        O_WRONLY => FileFlags::O_WRONLY,
+        O_RDWR => FileFlags::O_RDWR,
+        _ => return Err(errno::EINVAL),
+    };
+    if flags & O_CREAT != 0 {
+        nrk_flags |= FileFlags::O_CREAT;
+    }
+    if flags & O_TRUNC != 0 {
+        nrk_flags |= FileFlags::O_TRUNC;
+    }
+    if flags & O_APPEND != 0 {
+        nrk_flags |= FileFlags::O_APPEND;
+    }
+    if flags & O_EXCL != 0 {
+        return Err(errno::ENOTSUP);
+    }
+    // We never block on files and never `exec`, so `O_NONBLOCK` and
+    // `O_CLOEXEC` don't change anything
+    Ok(nrk_flags)
+}
+
+fn to_modes(mode: mode_t) -> FileModes {
+    FileModes::from_posix(mode as u64)
+}
+
+/// Returns `r` as ssize_t or sets errno.
+fn ssize_result(r: Result<u64, c_int>) -> ssize_t {
+    match r {
+        Ok(len) => len as ssize_t,
+        Err(e) => set_errno(e) as ssize_t,
+    }
+}
+
+/// `int open(const char *path, int flags, ...)`, the mode follows if
+/// `flags` has `O_CREAT`.
+#[no_mangle]
+pub unsafe extern "C" fn open(path: *const c_char, flags: c_int, mut args: ...) -> c_int {
+    let mode: mode_t = if flags & O_CREAT != 0 {
+        args.arg::<mode_t>()
+    } else {
+        0
+    };
+
+    let nrk_flags = match to_flags(flags) {
+        Ok(f) => f,
+        Err(e) => return set_errno(e),
+    };
+    match Fs::open(path as u64, u64::from(nrk_flags), u64::from(to_modes(mode))) {
+        Ok(fd) => fd::insert(Descriptor::File(File {
+            fd,
+            path: c_path(path),
+        })),
+        Err(e) => set_errno(from_syscall_error(e)),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn creat(path: *const c_char, mode: mode_t) -> c_int {
+    open(path, O_WRONLY | O_CREAT | O_TRUNC, mode)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn close(fd: c_int) -> c_int {
+    let r = match fd::remove(fd) {
+        Ok(Descriptor::Console(_)) => Ok(()),
+        Ok(Descriptor::File(file)) => Fs::close(file.fd).map(|_| ()),
+        Ok(Descriptor::Socket(socket)) => vibrio::syscalls::Net::close(socket.fd),
+        Err(e) => return set_errno(e),
+    };
+    match r {
+        Ok(()) => 0,
+        Err(e) => set_errno(from_syscall_error(e)),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn read(fd: c_int, buf: *mut c_void, count: size_t) -> ssize_t {
+    let r = match fd::get(fd) {
+        Ok(Descriptor::Console(STDIN_FILENO)) => Ok(0),
+        Ok(Descriptor::Console(_)) => Err(errno::EBADF),
+        Ok(Descriptor::File(_)) if count == 0 => Ok(0),
+        Ok(Descriptor::File(file)) => {
+            Fs::read(file.fd, buf as u64, count as u64).map_err(from_syscall_error)
+        }
+        Ok(Descriptor::Socket(socket)) => {
+            socket::recv_on(&socket, slice::from_raw_parts_mut(buf as *mut u8, count), 0)
+                .map(|(len, _from)| len as u64)
+        }
+        Err(e) => Err(e),
+    };
+    ssize_result(r)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn write(fd: c_int, buf: *const c_void, count: size_t) -> ssize_t {
+    let r = match fd::get(fd) {
+        Ok(Descriptor::Console(STDIN_FILENO)) => Err(errno::EBADF),
+        Ok(Descriptor::Console(_)) => console_write(slice::from_raw_parts(buf as *const u8, count)),
+        Ok(Descriptor::File(_)) if count == 0 => Ok(0),
+        Ok(Descriptor::File(file)) => {
+            Fs::write(file.fd, buf as u64, count as u64).map_err(from_syscall_error)
+        }
+        Ok(Descriptor::Socket(socket)) => socket::send_on(
+            &socket,
+            slice::from_raw_parts(buf as *const u8, count),
+            0,
+            None,
+        )
+        .map(|len| len as u64),
+        Err(e) => Err(e),
+    };
+    ssize_result(r)
+}
+
+/// The NetBSD `struct iovec`.
+#[repr(C)]
+pub struct iovec {
+    pub iov_base: *mut c_void,
+    pub iov_len: size_t,
+}
+
+/// Reads into one buffer after the other, stops early if a buffer isn't
+/// filled completely.
+#[no_mangle]
+pub unsafe extern "C" fn readv(fd: c_int, iov: *const iovec, iovcnt: c_int) -> ssize_t {
+    let mut total = 0;
+    for v in slice::from_raw_parts(iov, iovcnt.max(0) as usize) {
+        let len = read(fd, v.iov_base, v.iov_len);
+        if len < 0 {
+            return if total > 0 { total } else { len };
+        }
+        total += len;
+        if (len as size_t) < v.iov_len {
+            break;
+        }
+    }
+    total
+}
+
+/// Writes one buffer after the other, stops early if a buffer isn't
+/// written completely.
+#[no_mangle]
+pub unsafe extern "C" fn writev(fd: c_int, iov: *const iovec, iovcnt: c_int) -> ssize_t {
+    let mut total = 0;
+    for v in slice::from_raw_parts(iov, iovcnt.max(0) as usize) {
+        let len = write(fd, v.iov_base, v.iov_len);
+        if len < 0 {
+            return if total > 0 { total } else { len };
+        }
+        total += len;
+        if (len as size_t) < v.iov_len {
+            break;
+        }
+    }
+    total
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pread(
+    fd: c_int,
+    buf: *mut c_void,
+    count: size_t,
+    offset: off_t,
+) -> ssize_t {
+    if count == 0 {
+        return 0;
+    }
+    if offset < 0 {
+        return set_errno(errno::EINVAL) as ssize_t;
+    }
+    ssize_result(
+        file(fd).and_then(|f| {
+            Fs::read_at(f, buf as u64, count as u64, offset).map_err(from_syscall_error)
+        }),
+    )
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pwrite(
+    fd: c_int,
+    buf: *const c_void,
+    count: size_t,
+    offset: off_t,
+) -> ssize_t {
+    if count == 0 {
+        return 0;
+    }
+    if offset < 0 {
+        return set_errno(errno::EINVAL) as ssize_t;
+    }
+    ssize_result(file(fd).and_then(|f| {
+        Fs::write_at(f, buf as u64, count as u64, offset).map_err(from_syscall_error)
+    }))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn fsync(fd: c_int) -> c_int {
+    let r = match fd::get(fd) {
+        Ok(Descriptor::File(file)) => Fs::fsync(file.fd).map_err(from_syscall_error),
+        Ok(_) => Err(errno::EINVAL),
+        Err(e) => Err(e),
+    };
+    match r {
+        Ok(()) => 0,
+        Err(e) => set_errno(e),
+    }
+}
+
+/// The file system has no metadata worth leaving out, this is `fsync`.
+#[no_mangle]
+pub unsafe extern "C" fn fdatasync(fd: c_int) -> c_int {
+    fsync(fd)
+}
+
+/// The kernel keeps the offsets of files and can't move them.
+#[no_mangle]
+pub unsafe extern "C" fn lseek(fd: c_int, _offset: off_t, _whence: c_int) -> off_t {
+    match fd::get(fd) {
+        Ok(Descriptor::File(_)) => set_errno(errno::ENOTSUP) as off_t,
+        Ok(_) => set_errno(errno::ESPIPE) as off_t,
+        Err(e) => set_errno(e) as off_t,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unlink(path: *const c_char) -> c_int {
+    match Fs::delete(path as u64) {
+        Ok(_) => 0,
+        Err(_) => set_errno(errno::ENOENT),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rename(old: *const c_char, new: *const c_char) -> c_int {
+    match Fs::rename(old as u64, new as u64) {
+        Ok(_) => 0,
+        Err(e) => set_errno(from_syscall_error(e)),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mkdir(path: *const c_char, mode: mode_t) -> c_int {
+    match Fs::mkdir_simple(path as u64, u64::from(to_modes(mode))) {
+        Ok(_) => 0,
+        Err(e) => set_errno(from_syscall_error(e)),
+    }
+}
+
+/// Makes the socket `fd` (non-)blocking, files never block.
+fn set_nonblocking(fd: c_int, nonblocking: bool) -> Result<(), c_int> {
+    match fd::get(fd)? {
+        Descriptor::Socket(_) => fd::update_socket(fd, |s| s.nonblocking = nonblocking),
+        _ => Ok(()),
+    }
+}
+
+/// `int fcntl(int fd, int cmd, ...)`, for the close-on-exec and
+/// `O_NONBLOCK` flags (we never `exec`, so the first one doesn't matter).
+#[no_mangle]
+pub unsafe extern "C" fn fcntl(fd: c_int, cmd: c_int, mut args: ...) -> c_int {
+    let descriptor = match fd::get(fd) {
+        Ok(descriptor) => descriptor,
+        Err(e) => return set_errno(e),
+    };
+    match cmd {
+        F_GETFD => FD_CLOEXEC,
+        F_SETFD => 0,
+        F_GETFL => match descriptor {
+            Descriptor::Socket(socket) if socket.nonblocking => O_RDWR | O_NONBLOCK,
+            _ => O_RDWR,
+        },
+        F_SETFL => match set_nonblocking(fd, args.arg::<c_int>() & O_NONBLOCK != 0) {
+            Ok(()) => 0,
+            Err(e) => set_errno(e),
+        },
+        _ => set_errno(errno::EINVAL),
+    }
+}
+
+/// `int ioctl(int fd, unsigned long request, ...)`, only for what `fcntl`
+/// does too.
+#[no_mangle]
+pub unsafe extern "C" fn ioctl(fd: c_int, request: c_ulong, mut args: ...) -> c_int {
+    if let Err(e) = fd::get(fd) {
+        return set_errno(e);
+    }
+    match request {
+        FIOCLEX | FIONCLEX => 0,
+        FIONBIO => match set_nonblocking(fd, *args.arg::<*const c_int>() != 0) {
+            Ok(()) => 0,
+            Err(e) => set_errno(e),
+        },
+        _ => set_errno(errno::ENOTTY),
+    }
+}
+
+/// Fills in `buf` for the file at `path` (a NUL-terminated name).
+///
+/// The kernel only knows type and size, files belong to root, can be read
+/// and written by their owner and have no time stamps.
+unsafe fn stat_path(path: *const u8, buf: *mut stat) -> c_int {
+    let info = match Fs::getinfo(path as u64) {
+        Ok(info) => info,
+        Err(e) => return set_errno(from_syscall_error(e)),
+    };
+    let mode = if info.ftype == u64::from(FileType::Directory) {
+        S_IFDIR | 0o700
+    } else {
+        S_IFREG | 0o600
+    };
+
+    *buf = stat {
+        st_dev: 0,
+        st_mode: mode,
+        st_ino: 0,
+        st_nlink: 1,
+        st_uid: 0,
+        st_gid: 0,
+        st_rdev: 0,
+        st_atime: 0,
+        st_atimensec: 0,
+        st_mtime: 0,
+        st_mtimensec: 0,
+        st_ctime: 0,
+        st_ctimensec: 0,
+        st_birthtime: 0,
+        st_birthtimensec: 0,
+        st_size: info.fsize as off_t,
+        st_blocks: ((info.fsize + 511) / 512) as i64,
+        st_blksize: 4096,
+        st_flags: 0,
+        st_gen: 0,
+        st_spare: [0; 2],
+    };
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn stat(path: *const c_char, buf: *mut stat) -> c_int {
+    stat_path(path as *const u8, buf)
+}
+
+/// There are no links, this is `stat`.
+#[no_mangle]
+pub unsafe extern "C" fn lstat(path: *const c_char, buf: *mut stat) -> c_int {
+    stat_path(path as *const u8, buf)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn fstat(fd: c_int, buf: *mut stat) -> c_int {
+    match fd::get(fd) {
+        Ok(Descriptor::File(file)) => stat_path(file.path.as_ptr(), buf),
+        Ok(_) => set_errno(errno::ENOTSUP),
+        Err(e) => set_errno(e),
+    }
+}
+
+/// Returns the name at `path` with its terminating NUL.
+unsafe fn c_path(path: *const c_char) -> Vec<u8> {
+    slice::from_raw_parts(path as *const u8, strlen(path) + 1).to_vec()
+}
+
+/// Prints `buf` on the console, stops at the first byte that isn't UTF-8.
+fn console_write(buf: &[u8]) -> Result<u64, c_int> {
+    let text = match str::from_utf8(buf) {
+        Ok(text) => text,
+        Err(e) if e.valid_up_to() > 0 => unsafe {
+            str::from_utf8_unchecked(&buf[..e.valid_up_to()])
+        },
+        Err(_) => return Err(errno::EINVAL),
+    };
+    Process::print(text).map_err(from_syscall_error)?;
+    Ok(text.len() as u64)
+}
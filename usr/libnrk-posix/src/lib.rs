@@ -0,0 +1,56 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A subset of the POSIX C interface on top of vibrio.
+//!
+//! Exports files and sockets (through one descriptor table), `poll`,
+//! `mmap`, clocks, `malloc`, the environment and the pthread functions of
+//! `vibrio::pthread` as C symbols, so simple C programs can run on nrk
+//! without the rump kernel. Constants, structures and error codes have the
+//! NetBSD values, like the rest of our C toolchain.
+//!
+//! This is also the libc of Rust programs for `x86_64-nrk-std`: `std`
+//! builds as if for NetBSD there (we export the versioned NetBSD names it
+//! links against, see `netbsd`) and the `crt` feature provides `_start`.
+//!
+//! The functions set `errno` (see `__errno`) and return -1 (or
+//! `MAP_FAILED`, NULL) on errors. This can't be linked together with
+//! vibrio's `rumprt` which exports the same symbols.
+#![no_std]
+#![feature(c_variadic, thread_local)]
+#![allow(non_camel_case_types)]
+
+extern crate alloc;
+
+pub mod errno;
+mod fd;
+pub mod fs;
+pub mod mman;
+pub mod netbsd;
+pub mod poll;
+pub mod signal;
+pub mod socket;
+#[cfg(feature = "crt")]
+mod start;
+pub mod stdlib;
+pub mod string;
+pub mod time;
+pub mod unistd;
+mod unsupported;
+
+pub use vibrio::pthread;
+
+pub type c_char = i8;
+pub type c_short = i16;
+pub type c_int = i32;
+pub type c_uint = u32;
+pub type c_long = i64;
+pub type c_ulong = u64;
+pub type c_void = core::ffi::c_void;
+pub type size_t = usize;
+pub type ssize_t = isize;
+pub type off_t = i64;
+pub type mode_t = u32;
+pub type clockid_t = c_int;
+
+pub use vibrio::pthread::timespec;
@@ -0,0 +1,111 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Memory mappings (sys/mman.h).
+//!
+//! Only anonymous mappings, the kernel places them in the mapping region of
+//! the process (`VSpace::map_anywhere`, randomized like the heap). `munmap`
+//! gives the memory back to the kernel but the addresses aren't used again.
+
+use vibrio::syscalls::VSpace;
+use vibrio::MemoryRights;
+
+use crate::errno::{self, from_syscall_error, set_errno};
+use crate::{c_int, c_void, off_t, size_t};
+
+pub const PROT_NONE: c_int = 0x00;
+pub const PROT_READ: c_int = 0x01;
+pub const PROT_WRITE: c_int = 0x02;
+pub const PROT_EXEC: c_int = 0x04;
+
+pub const MAP_SHARED: c_int = 0x0001;
+pub const MAP_PRIVATE: c_int = 0x0002;
+pub const MAP_FIXED: c_int = 0x0010;
+pub const MAP_ANON: c_int = 0x1000;
+
+pub const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+const PAGE_SIZE: usize = 4096;
+
+/// Mapped memory is always readable, so `PROT_NONE` is the same as
+/// `PROT_READ`.
+fn to_rights(prot: c_int) -> MemoryRights {
+    let mut rights = MemoryRights::READ;
+    if prot & PROT_WRITE != 0 {
+        rights |= MemoryRights::WRITE;
+    }
+    if prot & PROT_EXEC != 0 {
+        rights |= MemoryRights::EXECUTE;
+    }
+    rights
+}
+
+/// `void *mmap(void *addr, size_t len, int prot, int flags, int fd, off_t offset)`
+///
+/// `addr` is only a hint (we ignore it), `MAP_FIXED` and file mappings
+/// aren't supported.
+#[no_mangle]
+pub unsafe extern "C" fn mmap(
+    _addr: *mut c_void,
+    len: size_t,
+    prot: c_int,
+    flags: c_int,
+    fd: c_int,
+    _offset: off_t,
+) -> *mut c_void {
+    if len == 0 {
+        set_errno(errno::EINVAL);
+        return MAP_FAILED;
+    }
+    if flags & MAP_ANON == 0 || fd != -1 || flags & MAP_FIXED != 0 {
+        set_errno(errno::ENOTSUP);
+        return MAP_FAILED;
+    }
+
+    let len = match len.checked_add(PAGE_SIZE - 1) {
+        Some(end) => end & !(PAGE_SIZE - 1),
+        None => {
+            set_errno(errno::EINVAL);
+            return MAP_FAILED;
+        }
+    };
+    let base = match VSpace::map_anywhere(len as u64) {
+        Ok((vaddr, _paddr)) => vaddr.as_u64(),
+        Err(e) => {
+            set_errno(from_syscall_error(e));
+            return MAP_FAILED;
+        }
+    };
+
+    let rights = to_rights(prot);
+    if rights != MemoryRights::READ | MemoryRights::WRITE {
+        if let Err(e) = VSpace::protect(base, len as u64, rights) {
+            set_errno(from_syscall_error(e));
+            return MAP_FAILED;
+        }
+    }
+
+    base as *mut c_void
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn munmap(addr: *mut c_void, len: size_t) -> c_int {
+    if addr as usize % PAGE_SIZE != 0 || len == 0 {
+        return set_errno(errno::EINVAL);
+    }
+    match VSpace::unmap(addr as u64, len as u64) {
+        Ok(_) => 0,
+        Err(e) => set_errno(from_syscall_error(e)),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mprotect(addr: *mut c_void, len: size_t, prot: c_int) -> c_int {
+    if addr as usize % PAGE_SIZE != 0 {
+        return set_errno(errno::EINVAL);
+    }
+    match VSpace::protect(addr as u64, len as u64, to_rights(prot)) {
+        Ok(()) => 0,
+        Err(e) => set_errno(from_syscall_error(e)),
+    }
+}
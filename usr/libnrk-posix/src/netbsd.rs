@@ -0,0 +1,87 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The versioned names of the NetBSD libc.
+//!
+//! NetBSD renamed functions whose arguments changed (e.g., `stat` with the
+//! 64-bit `time_t` became `__stat50`), and its headers (and the `libc`
+//! crate that `std` uses on `x86_64-nrk-std`) refer to the new names. The
+//! structures already have the current layout, so these forward to the
+//! plain functions.
+
+use crate::fs::{self, stat};
+use crate::signal::{self, sigaction, sigset_t, stack_t};
+use crate::{c_char, c_int, clockid_t, socket, time, timespec};
+
+#[no_mangle]
+pub unsafe extern "C" fn __stat50(path: *const c_char, buf: *mut stat) -> c_int {
+    fs::stat(path, buf)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __lstat50(path: *const c_char, buf: *mut stat) -> c_int {
+    fs::lstat(path, buf)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __fstat50(fd: c_int, buf: *mut stat) -> c_int {
+    fs::fstat(fd, buf)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __socket30(domain: c_int, ty: c_int, protocol: c_int) -> c_int {
+    socket::socket(domain, ty, protocol)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __clock_gettime50(clock: clockid_t, tp: *mut timespec) -> c_int {
+    time::clock_gettime(clock, tp)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __nanosleep50(req: *const timespec, rem: *mut timespec) -> c_int {
+    time::nanosleep(req, rem)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sigaction14(
+    signum: c_int,
+    act: *const sigaction,
+    oldact: *mut sigaction,
+) -> c_int {
+    signal::sigaction(signum, act, oldact)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sigaltstack14(ss: *const stack_t, old_ss: *mut stack_t) -> c_int {
+    signal::sigaltstack(ss, old_ss)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sigemptyset14(set: *mut sigset_t) -> c_int {
+    signal::sigemptyset(set)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sigfillset14(set: *mut sigset_t) -> c_int {
+    signal::sigfillset(set)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sigaddset14(set: *mut sigset_t, signum: c_int) -> c_int {
+    signal::sigaddset(set, signum)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sigdelset14(set: *mut sigset_t, signum: c_int) -> c_int {
+    signal::sigdelset(set, signum)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __sigprocmask14(
+    how: c_int,
+    set: *const sigset_t,
+    oldset: *mut sigset_t,
+) -> c_int {
+    signal::sigprocmask(how, set, oldset)
+}
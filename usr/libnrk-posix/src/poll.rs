@@ -0,0 +1,131 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Waiting for descriptors (poll.h).
+//!
+//! The console and files are always ready, sockets are asked about with
+//! `vibrio::net::poll`.
+
+use alloc::vec::Vec;
+use core::slice;
+use core::time::Duration;
+
+use vibrio::net::{PollEvents, PollFd};
+
+use crate::errno::{from_syscall_error, set_errno};
+use crate::fd::{self, Descriptor};
+use crate::{c_int, c_short, c_uint};
+
+pub type nfds_t = c_uint;
+
+pub const POLLIN: c_short = 0x0001;
+pub const POLLPRI: c_short = 0x0002;
+pub const POLLOUT: c_short = 0x0004;
+pub const POLLERR: c_short = 0x0008;
+pub const POLLHUP: c_short = 0x0010;
+pub const POLLNVAL: c_short = 0x0020;
+pub const POLLRDNORM: c_short = 0x0040;
+pub const POLLWRNORM: c_short = POLLOUT;
+
+#[repr(C)]
+pub struct pollfd {
+    pub fd: c_int,
+    pub events: c_short,
+    pub revents: c_short,
+}
+
+/// Returns the readiness of `pfd` if it doesn't need the kernel, otherwise
+/// the `PollFd` to ask with and whether it's a listening socket.
+fn check(pfd: &pollfd) -> Result<c_short, (PollFd, bool)> {
+    let wanted = pfd.events | POLLERR | POLLHUP;
+    if pfd.fd < 0 {
+        return Ok(0);
+    }
+    match fd::get(pfd.fd) {
+        Err(_) => Ok(POLLNVAL),
+        // Reading stdin returns EOF right away.
+        Ok(Descriptor::Console(0)) => Ok(wanted & (POLLIN | POLLRDNORM)),
+        Ok(Descriptor::Console(_)) => Ok(wanted & POLLOUT),
+        Ok(Descriptor::File(_)) => Ok(wanted & (POLLIN | POLLRDNORM | POLLOUT)),
+        // The kernel socket of a listening socket becomes the connection
+        // (`accept` can go ahead once it's established).
+        Ok(Descriptor::Socket(socket)) if socket.listening => {
+            Err((PollFd::new(socket.fd, PollEvents::POLLOUT), true))
+        }
+        Ok(Descriptor::Socket(socket)) => {
+            let mut events = PollEvents::empty();
+            if pfd.events & (POLLIN | POLLRDNORM) != 0 {
+                events |= PollEvents::POLLIN;
+            }
+            if pfd.events & POLLOUT != 0 {
+                events |= PollEvents::POLLOUT;
+            }
+            Err((PollFd::new(socket.fd, events), false))
+        }
+    }
+}
+
+/// Translates what the kernel says about a socket back to `revents`.
+fn from_kernel(pfd: &pollfd, kfd: &PollFd, listening: bool) -> c_short {
+    let revents = kfd.revents();
+    let mut r = 0;
+    if revents.contains(PollEvents::POLLIN) {
+        r |= pfd.events & (POLLIN | POLLRDNORM);
+    }
+    if revents.contains(PollEvents::POLLOUT) {
+        r |= if listening {
+            pfd.events & (POLLIN | POLLRDNORM)
+        } else {
+            pfd.events & POLLOUT
+        };
+    }
+    if revents.contains(PollEvents::POLLERR) {
+        r |= POLLERR;
+    }
+    if revents.contains(PollEvents::POLLHUP) {
+        r |= POLLHUP;
+    }
+    r
+}
+
+/// Returns how many of `fds` are ready after waiting at most `timeout`
+/// milliseconds for one (forever if it's negative).
+///
+/// Other threads run while we wait when we're called from a lineup thread.
+#[no_mangle]
+pub unsafe extern "C" fn poll(fds: *mut pollfd, nfds: nfds_t, timeout: c_int) -> c_int {
+    let fds = slice::from_raw_parts_mut(fds, nfds as usize);
+    let timeout = if timeout < 0 {
+        None
+    } else {
+        Some(Duration::from_millis(timeout as u64))
+    };
+
+    let mut sockets: Vec<(usize, PollFd, bool)> = Vec::new();
+    for (idx, pfd) in fds.iter_mut().enumerate() {
+        match check(pfd) {
+            Ok(revents) => pfd.revents = revents,
+            Err((kfd, listening)) => sockets.push((idx, kfd, listening)),
+        }
+    }
+
+    // The other descriptors don't change, only the sockets are worth
+    // waiting for (without any, this just sleeps for `timeout`)
+    let ready = fds.iter().filter(|pfd| pfd.revents != 0).count();
+    if ready == 0 || !sockets.is_empty() {
+        let wait = if ready > 0 {
+            Some(Duration::from_secs(0))
+        } else {
+            timeout
+        };
+        let mut kfds: Vec<PollFd> = sockets.iter().map(|(_, kfd, _)| *kfd).collect();
+        if let Err(e) = vibrio::net::poll(&mut kfds, wait) {
+            return set_errno(from_syscall_error(e));
+        }
+        for ((idx, _, listening), kfd) in sockets.iter().zip(kfds.iter()) {
+            fds[*idx].revents = from_kernel(&fds[*idx], kfd, *listening);
+        }
+    }
+
+    fds.iter().filter(|pfd| pfd.revents != 0).count() as c_int
+}
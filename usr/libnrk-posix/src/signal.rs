@@ -0,0 +1,163 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Signals (signal.h).
+//!
+//! nrk doesn't deliver signals. We remember the installed actions so
+//! programs that look at them (like `std`, which installs a `SIGSEGV`
+//! handler on an alternate stack) see what they set, but the handlers never
+//! run.
+
+use core::ptr;
+
+use crate::errno::{self, set_errno};
+use crate::{c_int, c_void, size_t};
+
+pub type sighandler_t = usize;
+
+pub const SIG_DFL: sighandler_t = 0;
+pub const SIG_IGN: sighandler_t = 1;
+pub const SIG_ERR: sighandler_t = !0;
+
+/// Signals are numbered from 1 to `NSIG - 1`.
+pub const NSIG: c_int = 64;
+
+pub const SS_ONSTACK: c_int = 0x1;
+pub const SS_DISABLE: c_int = 0x4;
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct sigset_t {
+    pub bits: [u32; 4],
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct sigaction {
+    pub sa_sigaction: sighandler_t,
+    pub sa_mask: sigset_t,
+    pub sa_flags: c_int,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct stack_t {
+    pub ss_sp: *mut c_void,
+    pub ss_size: size_t,
+    pub ss_flags: c_int,
+}
+
+const DEFAULT_ACTION: sigaction = sigaction {
+    sa_sigaction: SIG_DFL,
+    sa_mask: sigset_t { bits: [0; 4] },
+    sa_flags: 0,
+};
+
+/// The installed actions, entry `i` is for signal `i`.
+static ACTIONS: spin::Mutex<[sigaction; NSIG as usize]> =
+    spin::Mutex::new([DEFAULT_ACTION; NSIG as usize]);
+
+fn valid(signum: c_int) -> bool {
+    (1..NSIG).contains(&signum)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sigaction(
+    signum: c_int,
+    act: *const sigaction,
+    oldact: *mut sigaction,
+) -> c_int {
+    if !valid(signum) {
+        return set_errno(errno::EINVAL);
+    }
+    let mut actions = ACTIONS.lock();
+    if !oldact.is_null() {
+        *oldact = actions[signum as usize];
+    }
+    if !act.is_null() {
+        actions[signum as usize] = *act;
+    }
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn signal(signum: c_int, handler: sighandler_t) -> sighandler_t {
+    if !valid(signum) {
+        set_errno(errno::EINVAL);
+        return SIG_ERR;
+    }
+    let mut actions = ACTIONS.lock();
+    let old = actions[signum as usize].sa_sigaction;
+    actions[signum as usize] = sigaction {
+        sa_sigaction: handler,
+        ..DEFAULT_ACTION
+    };
+    old
+}
+
+/// Accepts any stack, there's never one in use (we report it as disabled).
+#[no_mangle]
+pub unsafe extern "C" fn sigaltstack(_ss: *const stack_t, old_ss: *mut stack_t) -> c_int {
+    if !old_ss.is_null() {
+        *old_ss = stack_t {
+            ss_sp: ptr::null_mut(),
+            ss_size: 0,
+            ss_flags: SS_DISABLE,
+        };
+    }
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sigemptyset(set: *mut sigset_t) -> c_int {
+    (*set).bits = [0; 4];
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sigfillset(set: *mut sigset_t) -> c_int {
+    (*set).bits = [!0; 4];
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sigaddset(set: *mut sigset_t, signum: c_int) -> c_int {
+    if !valid(signum) {
+        return set_errno(errno::EINVAL);
+    }
+    let bit = (signum - 1) as usize;
+    (*set).bits[bit / 32] |= 1 << (bit % 32);
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sigdelset(set: *mut sigset_t, signum: c_int) -> c_int {
+    if !valid(signum) {
+        return set_errno(errno::EINVAL);
+    }
+    let bit = (signum - 1) as usize;
+    (*set).bits[bit / 32] &= !(1 << (bit % 32));
+    0
+}
+
+/// Nothing is blocked, the old mask is always empty.
+#[no_mangle]
+pub unsafe extern "C" fn sigprocmask(
+    _how: c_int,
+    _set: *const sigset_t,
+    oldset: *mut sigset_t,
+) -> c_int {
+    if !oldset.is_null() {
+        sigemptyset(oldset);
+    }
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pthread_sigmask(
+    how: c_int,
+    set: *const sigset_t,
+    oldset: *mut sigset_t,
+) -> c_int {
+    sigprocmask(how, set, oldset)
+}
@@ -0,0 +1,528 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! IPv4 TCP and UDP sockets (sys/socket.h, netinet/in.h) on the kernel
+//! network stack.
+//!
+//! Kernel sockets never block, for blocking sockets we wait with
+//! `vibrio::net::poll` (the other lineup threads of the core run in
+//! between). A listening TCP socket of the kernel turns into the
+//! connection once a peer connects, `accept` hands it out and listens on a
+//! new one. So at most one connection waits to be accepted, peers that
+//! connect while there is one are refused.
+
+use core::mem::size_of;
+use core::time::Duration;
+use core::{cmp, ptr, slice};
+
+use vibrio::net::{PollEvents, PollFd, SocketAddr, SocketType};
+use vibrio::syscalls::Net;
+use vibrio::SystemCallError;
+
+use crate::errno::{self, from_syscall_error, set_errno};
+use crate::fd::{self, Descriptor, Socket};
+use crate::{c_int, c_uint, c_void, size_t, ssize_t};
+
+pub type socklen_t = c_uint;
+pub type sa_family_t = u8;
+pub type in_port_t = u16;
+
+pub const AF_INET: c_int = 2;
+
+pub const SOCK_STREAM: c_int = 1;
+pub const SOCK_DGRAM: c_int = 2;
+pub const SOCK_CLOEXEC: c_int = 0x1000_0000;
+pub const SOCK_NONBLOCK: c_int = 0x2000_0000;
+
+pub const SOL_SOCKET: c_int = 0xffff;
+pub const SO_REUSEADDR: c_int = 0x0004;
+pub const SO_KEEPALIVE: c_int = 0x0008;
+pub const SO_BROADCAST: c_int = 0x0020;
+pub const SO_ERROR: c_int = 0x1007;
+pub const SO_TYPE: c_int = 0x1008;
+pub const SO_SNDTIMEO: c_int = 0x100b;
+pub const SO_RCVTIMEO: c_int = 0x100c;
+
+pub const IPPROTO_TCP: c_int = 6;
+pub const TCP_NODELAY: c_int = 0x01;
+
+pub const MSG_PEEK: c_int = 0x0002;
+pub const MSG_DONTWAIT: c_int = 0x0080;
+pub const MSG_NOSIGNAL: c_int = 0x0400;
+
+#[repr(C)]
+pub struct sockaddr {
+    pub sa_len: u8,
+    pub sa_family: sa_family_t,
+    pub sa_data: [i8; 14],
+}
+
+#[repr(C)]
+pub struct in_addr {
+    /// In network byte order.
+    pub s_addr: u32,
+}
+
+#[repr(C)]
+pub struct sockaddr_in {
+    pub sin_len: u8,
+    pub sin_family: sa_family_t,
+    /// In network byte order.
+    pub sin_port: in_port_t,
+    pub sin_addr: in_addr,
+    pub sin_zero: [i8; 8],
+}
+
+/// The NetBSD `struct timeval` (for `SO_RCVTIMEO` and `SO_SNDTIMEO`).
+#[repr(C)]
+pub struct timeval {
+    pub tv_sec: i64,
+    pub tv_usec: c_int,
+}
+
+/// Returns 0 or sets errno.
+fn result(r: Result<(), c_int>) -> c_int {
+    match r {
+        Ok(()) => 0,
+        Err(e) => set_errno(e),
+    }
+}
+
+unsafe fn to_endpoint(addr: *const sockaddr, len: socklen_t) -> Result<SocketAddr, c_int> {
+    if addr.is_null() || (len as usize) < size_of::<sockaddr_in>() {
+        return Err(errno::EINVAL);
+    }
+    let sin = &*(addr as *const sockaddr_in);
+    if sin.sin_family as c_int != AF_INET {
+        return Err(errno::EAFNOSUPPORT);
+    }
+    Ok(SocketAddr::new(
+        sin.sin_addr.s_addr.to_ne_bytes(),
+        u16::from_be(sin.sin_port),
+    ))
+}
+
+/// Writes `endpoint` to `addr` (as much as fits in `*len`).
+unsafe fn from_endpoint(endpoint: SocketAddr, addr: *mut sockaddr, len: *mut socklen_t) {
+    if addr.is_null() || len.is_null() {
+        return;
+    }
+    let sin = sockaddr_in {
+        sin_len: size_of::<sockaddr_in>() as u8,
+        sin_family: AF_INET as sa_family_t,
+        sin_port: endpoint.port.to_be(),
+        sin_addr: in_addr {
+            s_addr: u32::from_ne_bytes(endpoint.ip),
+        },
+        sin_zero: [0; 8],
+    };
+    let fits = cmp::min(*len as usize, size_of::<sockaddr_in>());
+    ptr::copy_nonoverlapping(
+        &sin as *const sockaddr_in as *const u8,
+        addr as *mut u8,
+        fits,
+    );
+    *len = size_of::<sockaddr_in>() as socklen_t;
+}
+
+/// Copies the option `value` to `val` (as much as fits in `*len`).
+unsafe fn write_option<T>(value: T, val: *mut c_void, len: *mut socklen_t) {
+    let fits = cmp::min(*len as usize, size_of::<T>());
+    ptr::copy_nonoverlapping(&value as *const T as *const u8, val as *mut u8, fits);
+    *len = size_of::<T>() as socklen_t;
+}
+
+/// Waits until the kernel socket `fd` has one of `events` (or an error or
+/// hang-up), `EAGAIN` if `timeout` expires first.
+fn wait(fd: u64, events: PollEvents, timeout: Option<Duration>) -> Result<PollEvents, c_int> {
+    let mut fds = [PollFd::new(fd, events)];
+    match vibrio::net::poll(&mut fds, timeout) {
+        Ok(0) => Err(errno::EAGAIN),
+        Ok(_) => Ok(PollEvents::from_bits_truncate(fds[0].revents)),
+        Err(e) => Err(from_syscall_error(e)),
+    }
+}
+
+/// How long a socket waits, non-blocking ones only check once.
+fn timeout(socket: &Socket, flags: c_int, timeout: Option<Duration>) -> Option<Duration> {
+    if socket.nonblocking || flags & MSG_DONTWAIT != 0 {
+        Some(Duration::from_secs(0))
+    } else {
+        timeout
+    }
+}
+
+/// Sends `buf` on `socket` (to `to` for UDP), waits for space if it's
+/// blocking.
+pub(crate) fn send_on(
+    socket: &Socket,
+    buf: &[u8],
+    flags: c_int,
+    to: Option<SocketAddr>,
+) -> Result<usize, c_int> {
+    loop {
+        let r = match to {
+            Some(to) => Net::send_to(socket.fd, buf, to),
+            None => Net::send(socket.fd, buf),
+        };
+        match r {
+            Ok(len) => return Ok(len),
+            Err(SystemCallError::WouldBlock) => {
+                wait(
+                    socket.fd,
+                    PollEvents::POLLOUT,
+                    timeout(socket, flags, socket.write_timeout),
+                )?;
+            }
+            Err(e) => return Err(from_syscall_error(e)),
+        }
+    }
+}
+
+/// Receives into `buf` from `socket`, waits for data if it's blocking.
+pub(crate) fn recv_on(
+    socket: &Socket,
+    buf: &mut [u8],
+    flags: c_int,
+) -> Result<(usize, SocketAddr), c_int> {
+    if flags & MSG_PEEK != 0 {
+        return Err(errno::ENOTSUP);
+    }
+    loop {
+        match Net::recv_from(socket.fd, buf) {
+            Ok(r) => return Ok(r),
+            Err(SystemCallError::WouldBlock) => {
+                wait(
+                    socket.fd,
+                    PollEvents::POLLIN,
+                    timeout(socket, flags, socket.read_timeout),
+                )?;
+            }
+            Err(e) => return Err(from_syscall_error(e)),
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn socket(domain: c_int, ty: c_int, _protocol: c_int) -> c_int {
+    if domain != AF_INET {
+        return set_errno(errno::EAFNOSUPPORT);
+    }
+    let socket_type = match ty & !(SOCK_CLOEXEC | SOCK_NONBLOCK) {
+        SOCK_STREAM => SocketType::Tcp,
+        SOCK_DGRAM => SocketType::Udp,
+        _ => return set_errno(errno::ENOTSUP),
+    };
+
+    match Net::socket(socket_type) {
+        Ok(kfd) => fd::insert(Descriptor::Socket(Socket::new(
+            kfd,
+            socket_type,
+            ty & SOCK_NONBLOCK != 0,
+        ))),
+        Err(e) => set_errno(from_syscall_error(e)),
+    }
+}
+
+/// Only the port of `addr` matters, the kernel has a single address.
+///
+/// TCP sockets start listening on the port in `listen`.
+#[no_mangle]
+pub unsafe extern "C" fn bind(fd: c_int, addr: *const sockaddr, len: socklen_t) -> c_int {
+    let r = to_endpoint(addr, len).and_then(|endpoint| {
+        let socket = fd::socket(fd)?;
+        if socket.ty == SocketType::Udp {
+            Net::bind(socket.fd, endpoint.port).map_err(from_syscall_error)?;
+        }
+        fd::update_socket(fd, |s| s.port = Some(endpoint.port))
+    });
+    result(r)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn listen(fd: c_int, _backlog: c_int) -> c_int {
+    let r = fd::socket(fd).and_then(|socket| {
+        if socket.ty != SocketType::Tcp {
+            return Err(errno::ENOTSUP);
+        }
+        if socket.listening {
+            return Ok(());
+        }
+        let port = socket.port.ok_or(errno::EINVAL)?;
+        Net::bind(socket.fd, port).map_err(from_syscall_error)?;
+        fd::update_socket(fd, |s| s.listening = true)
+    });
+    result(r)
+}
+
+/// The kernel doesn't tell us who connected, `addr` is always 0.0.0.0:0.
+#[no_mangle]
+pub unsafe extern "C" fn accept4(
+    fd: c_int,
+    addr: *mut sockaddr,
+    len: *mut socklen_t,
+    flags: c_int,
+) -> c_int {
+    loop {
+        let listener = match fd::socket(fd) {
+            Ok(socket) if socket.listening => socket,
+            Ok(_) => return set_errno(errno::EINVAL),
+            Err(e) => return set_errno(e),
+        };
+        let port = listener.port.unwrap_or(0);
+
+        // Sending works once the peer is connected
+        if let Err(e) = wait(
+            listener.fd,
+            PollEvents::POLLOUT,
+            timeout(&listener, 0, listener.read_timeout),
+        ) {
+            return set_errno(e);
+        }
+
+        // Listen on a new kernel socket and hand out the connected one
+        let next = match Net::socket(SocketType::Tcp) {
+            Ok(next) => next,
+            Err(e) => return set_errno(from_syscall_error(e)),
+        };
+        if let Err(e) = Net::bind(next, port) {
+            let _r = Net::close(next);
+            return set_errno(from_syscall_error(e));
+        }
+        match fd::update_socket(fd, |s| {
+            let ours = s.fd == listener.fd;
+            if ours {
+                s.fd = next;
+            }
+            ours
+        }) {
+            Ok(true) => {}
+            // Another thread accepted this connection
+            Ok(false) => {
+                let _r = Net::close(next);
+                continue;
+            }
+            Err(e) => {
+                let _r = Net::close(next);
+                return set_errno(e);
+            }
+        }
+
+        from_endpoint(SocketAddr::default(), addr, len);
+        return fd::insert(Descriptor::Socket(Socket::new(
+            listener.fd,
+            SocketType::Tcp,
+            flags & SOCK_NONBLOCK != 0,
+        )));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn accept(fd: c_int, addr: *mut sockaddr, len: *mut socklen_t) -> c_int {
+    accept4(fd, addr, len, 0)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn connect(fd: c_int, addr: *const sockaddr, len: socklen_t) -> c_int {
+    let r = to_endpoint(addr, len).and_then(|endpoint| {
+        let socket = fd::socket(fd)?;
+        Net::connect(socket.fd, endpoint).map_err(from_syscall_error)?;
+        fd::update_socket(fd, |s| s.peer = Some(endpoint))?;
+        if socket.ty == SocketType::Udp {
+            return Ok(());
+        }
+        if socket.nonblocking {
+            return Err(errno::EINPROGRESS);
+        }
+
+        match wait(socket.fd, PollEvents::POLLOUT, socket.write_timeout) {
+            Ok(events) if events.contains(PollEvents::POLLOUT) => Ok(()),
+            Ok(_) => Err(errno::ECONNREFUSED),
+            Err(errno::EAGAIN) => Err(errno::ETIMEDOUT),
+            Err(e) => Err(e),
+        }
+    });
+    result(r)
+}
+
+/// The kernel can only close sockets entirely.
+#[no_mangle]
+pub unsafe extern "C" fn shutdown(fd: c_int, _how: c_int) -> c_int {
+    result(fd::socket(fd).and(Err(errno::ENOTSUP)))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn send(fd: c_int, buf: *const c_void, len: size_t, flags: c_int) -> ssize_t {
+    sendto(fd, buf, len, flags, ptr::null(), 0)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sendto(
+    fd: c_int,
+    buf: *const c_void,
+    len: size_t,
+    flags: c_int,
+    addr: *const sockaddr,
+    addrlen: socklen_t,
+) -> ssize_t {
+    let r = fd::socket(fd).and_then(|socket| {
+        let to = if addr.is_null() {
+            None
+        } else {
+            Some(to_endpoint(addr, addrlen)?)
+        };
+        send_on(
+            &socket,
+            slice::from_raw_parts(buf as *const u8, len),
+            flags,
+            to,
+        )
+    });
+    match r {
+        Ok(len) => len as ssize_t,
+        Err(e) => set_errno(e) as ssize_t,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn recv(fd: c_int, buf: *mut c_void, len: size_t, flags: c_int) -> ssize_t {
+    recvfrom(fd, buf, len, flags, ptr::null_mut(), ptr::null_mut())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn recvfrom(
+    fd: c_int,
+    buf: *mut c_void,
+    len: size_t,
+    flags: c_int,
+    addr: *mut sockaddr,
+    addrlen: *mut socklen_t,
+) -> ssize_t {
+    let r = fd::socket(fd).and_then(|socket| {
+        recv_on(
+            &socket,
+            slice::from_raw_parts_mut(buf as *mut u8, len),
+            flags,
+        )
+    });
+    match r {
+        Ok((len, from)) => {
+            from_endpoint(from, addr, addrlen);
+            len as ssize_t
+        }
+        Err(e) => set_errno(e) as ssize_t,
+    }
+}
+
+/// Timeouts are supported, the other options we know are accepted and
+/// ignored.
+#[no_mangle]
+pub unsafe extern "C" fn setsockopt(
+    fd: c_int,
+    level: c_int,
+    name: c_int,
+    val: *const c_void,
+    len: socklen_t,
+) -> c_int {
+    let r = fd::socket(fd).and_then(|_socket| match (level, name) {
+        (SOL_SOCKET, SO_RCVTIMEO) | (SOL_SOCKET, SO_SNDTIMEO) => {
+            if (len as usize) < size_of::<timeval>() {
+                return Err(errno::EINVAL);
+            }
+            let tv = &*(val as *const timeval);
+            if tv.tv_sec < 0 || !(0..1_000_000).contains(&tv.tv_usec) {
+                return Err(errno::EINVAL);
+            }
+            let timeout = Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+            // Zero means no timeout
+            let timeout = Some(timeout).filter(|t| *t != Duration::from_secs(0));
+            fd::update_socket(fd, |s| {
+                if name == SO_RCVTIMEO {
+                    s.read_timeout = timeout;
+                } else {
+                    s.write_timeout = timeout;
+                }
+            })
+        }
+        (SOL_SOCKET, SO_REUSEADDR)
+        | (SOL_SOCKET, SO_KEEPALIVE)
+        | (SOL_SOCKET, SO_BROADCAST)
+        | (IPPROTO_TCP, TCP_NODELAY) => Ok(()),
+        _ => Err(errno::ENOPROTOOPT),
+    });
+    result(r)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn getsockopt(
+    fd: c_int,
+    level: c_int,
+    name: c_int,
+    val: *mut c_void,
+    len: *mut socklen_t,
+) -> c_int {
+    let r = fd::socket(fd).and_then(|socket| {
+        match (level, name) {
+            // A connection that hung up before it could send was refused
+            (SOL_SOCKET, SO_ERROR) => {
+                let mut fds = [PollFd::new(socket.fd, PollEvents::POLLOUT)];
+                Net::poll(&mut fds, Some(Duration::from_secs(0))).map_err(from_syscall_error)?;
+                let events = PollEvents::from_bits_truncate(fds[0].revents);
+                let error = if socket.ty == SocketType::Tcp
+                    && socket.peer.is_some()
+                    && events.contains(PollEvents::POLLHUP)
+                    && !events.contains(PollEvents::POLLOUT)
+                {
+                    errno::ECONNREFUSED
+                } else {
+                    0
+                };
+                write_option::<c_int>(error, val, len);
+            }
+            (SOL_SOCKET, SO_TYPE) => {
+                let ty = match socket.ty {
+                    SocketType::Tcp => SOCK_STREAM,
+                    SocketType::Udp => SOCK_DGRAM,
+                };
+                write_option::<c_int>(ty, val, len);
+            }
+            (SOL_SOCKET, SO_RCVTIMEO) | (SOL_SOCKET, SO_SNDTIMEO) => {
+                let timeout = if name == SO_RCVTIMEO {
+                    socket.read_timeout
+                } else {
+                    socket.write_timeout
+                }
+                .unwrap_or_default();
+                let tv = timeval {
+                    tv_sec: timeout.as_secs() as i64,
+                    tv_usec: timeout.subsec_micros() as c_int,
+                };
+                write_option(tv, val, len);
+            }
+            (IPPROTO_TCP, TCP_NODELAY) => write_option::<c_int>(0, val, len),
+            _ => return Err(errno::ENOPROTOOPT),
+        }
+        Ok(())
+    });
+    result(r)
+}
+
+/// The address is always 0.0.0.0, the port is the one from `bind`.
+#[no_mangle]
+pub unsafe extern "C" fn getsockname(fd: c_int, addr: *mut sockaddr, len: *mut socklen_t) -> c_int {
+    let r = fd::socket(fd).map(|socket| {
+        let endpoint = SocketAddr::new([0; 4], socket.port.unwrap_or(0));
+        from_endpoint(endpoint, addr, len);
+    });
+    result(r)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn getpeername(fd: c_int, addr: *mut sockaddr, len: *mut socklen_t) -> c_int {
+    let r = fd::socket(fd).and_then(|socket| {
+        let peer = socket.peer.ok_or(errno::ENOTCONN)?;
+        from_endpoint(peer, addr, len);
+        Ok(())
+    });
+    result(r)
+}
@@ -0,0 +1,87 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The program entry point (what `crt0.o` is for a C library).
+//!
+//! Sets up vibrio, then runs `main(argc, argv, envp)` in a lineup thread
+//! (so it can use `pthread_*` and friends) and exits with what it returns.
+
+use alloc::vec::Vec;
+use core::ptr;
+
+use lineup::tls2::SchedulerControlBlock;
+use log::{error, Level};
+use vibrio::syscalls::{Process, System};
+use x86::bits64::paging::VAddr;
+
+use crate::stdlib::{environ, exit};
+use crate::{c_char, c_int};
+
+/// Stack of the thread that runs `main`.
+const MAIN_STACK_SIZE: usize = 2 * 1024 * 1024;
+
+extern "C" {
+    fn main(argc: c_int, argv: *const *const c_char, envp: *const *const c_char) -> c_int;
+}
+
+/// The arguments: `init` followed by the words of `appcmd=`.
+fn arguments() -> Vec<*const c_char> {
+    let pinfo = Process::process_info().expect("Can't read process info");
+    let mut argv: Vec<*const c_char> = core::iter::once("init")
+        .chain(pinfo.app_cmdline.split_whitespace())
+        .map(|arg| {
+            let mut arg: Vec<u8> = arg.as_bytes().to_vec();
+            arg.push(0);
+            arg.leak().as_ptr() as *const c_char
+        })
+        .collect();
+    argv.push(ptr::null());
+    argv
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    log::set_logger(&vibrio::writer::LOGGER)
+        .map(|()| log::set_max_level(Level::Info.to_level_filter()))
+        .expect("Can't set-up logging");
+    if let Err(e) = System::abi_version() {
+        error!(
+            "Can't use this kernel (we need interface {}): {:?}",
+            vibrio::abi::ABI_VERSION,
+            e
+        );
+        Process::exit(1);
+    }
+
+    let ctl = Process::vcpu_control_area().expect("Can't read vcpu control area.");
+    ctl.resume_with_upcall =
+        VAddr::from(vibrio::upcalls::upcall_while_enabled as *const fn() as u64);
+
+    let argv = arguments().leak();
+    let argc = (argv.len() - 1) as c_int;
+    let argv = argv.as_ptr() as usize;
+
+    let s = &vibrio::upcalls::PROCESS_SCHEDULER;
+    s.spawn(
+        MAIN_STACK_SIZE,
+        move |_| unsafe {
+            let ret = main(
+                argc,
+                argv as *const *const c_char,
+                environ as *const *const c_char,
+            );
+            exit(ret)
+        },
+        ptr::null_mut(),
+        0,
+        None,
+    )
+    .expect("Can't spawn the main thread");
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    while s.has_active_threads() {
+        s.run(&scb);
+    }
+
+    Process::exit(0);
+}
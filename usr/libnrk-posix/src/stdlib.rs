@@ -0,0 +1,243 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Heap memory, the environment and process termination (stdlib.h).
+
+use alloc::alloc::{alloc, alloc_zeroed, dealloc, realloc as rust_realloc, Layout};
+use alloc::vec::Vec;
+use core::{cmp, ptr, slice};
+
+use vibrio::syscalls::Process;
+
+use crate::errno::{self, set_errno};
+use crate::string::strlen;
+use crate::{c_char, c_int, c_void, size_t};
+
+/// Every allocation starts with a header (`[size, offset]` in the 16 bytes
+/// before the pointer we return), `free` needs the layout.
+const HEADER_SIZE: usize = 16;
+
+unsafe fn allocate(size: size_t, align: usize, zeroed: bool) -> *mut c_void {
+    let offset = cmp::max(align, HEADER_SIZE);
+    let layout = match size
+        .checked_add(offset)
+        .and_then(|total| Layout::from_size_align(total, offset).ok())
+    {
+        Some(layout) => layout,
+        None => return ptr::null_mut(),
+    };
+
+    let base = if zeroed {
+        alloc_zeroed(layout)
+    } else {
+        alloc(layout)
+    };
+    if base.is_null() {
+        return ptr::null_mut();
+    }
+    let ptr = base.add(offset);
+    *(ptr as *mut usize).sub(2) = layout.size();
+    *(ptr as *mut usize).sub(1) = offset;
+    ptr as *mut c_void
+}
+
+/// Returns the start and layout of the allocation `ptr` points into.
+unsafe fn allocation(ptr: *mut c_void) -> (*mut u8, Layout) {
+    let size = *(ptr as *mut usize).sub(2);
+    let offset = *(ptr as *mut usize).sub(1);
+    (
+        (ptr as *mut u8).sub(offset),
+        Layout::from_size_align_unchecked(size, offset),
+    )
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn malloc(size: size_t) -> *mut c_void {
+    allocate(size, HEADER_SIZE, false)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn calloc(nmemb: size_t, size: size_t) -> *mut c_void {
+    match nmemb.checked_mul(size) {
+        Some(size) => allocate(size, HEADER_SIZE, true),
+        None => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn posix_memalign(
+    memptr: *mut *mut c_void,
+    align: size_t,
+    size: size_t,
+) -> c_int {
+    if !align.is_power_of_two() || align % core::mem::size_of::<usize>() != 0 {
+        return errno::EINVAL;
+    }
+    let ptr = allocate(size, align, false);
+    if ptr.is_null() {
+        return errno::ENOMEM;
+    }
+    *memptr = ptr;
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: size_t) -> *mut c_void {
+    if ptr.is_null() {
+        return malloc(size);
+    }
+    let (base, layout) = allocation(ptr);
+    let offset = layout.align();
+    let total = match size.checked_add(offset) {
+        Some(total) => total,
+        None => return ptr::null_mut(),
+    };
+
+    let base = rust_realloc(base, layout, total);
+    if base.is_null() {
+        return ptr::null_mut();
+    }
+    let ptr = base.add(offset);
+    *(ptr as *mut usize).sub(2) = total;
+    ptr as *mut c_void
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn free(ptr: *mut c_void) {
+    if !ptr.is_null() {
+        let (base, layout) = allocation(ptr);
+        dealloc(base, layout);
+    }
+}
+
+/// The environment, a NULL terminated list of `NAME=value` strings.
+///
+/// Starts out empty, programs don't get an environment from the kernel.
+#[no_mangle]
+pub static mut environ: *const *mut c_char =
+    &EMPTY_ENVIRONMENT as *const [usize; 1] as *const *mut c_char;
+
+/// A single NULL.
+static EMPTY_ENVIRONMENT: [usize; 1] = [0];
+
+/// Backs `environ` once `setenv` was called (ends with NULL too).
+static mut ENVIRONMENT: Vec<*mut c_char> = Vec::new();
+
+/// Protects `ENVIRONMENT` and `environ`.
+static ENVIRONMENT_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+unsafe fn c_str<'a>(s: *const c_char) -> &'a [u8] {
+    slice::from_raw_parts(s as *const u8, strlen(s))
+}
+
+/// Returns the index of `name` in `ENVIRONMENT` and where its value starts.
+unsafe fn find(name: &[u8]) -> Option<(usize, *mut c_char)> {
+    ENVIRONMENT
+        .iter()
+        .take_while(|e| !e.is_null())
+        .enumerate()
+        .find_map(|(idx, &entry)| {
+            let entry_name = c_str(entry).split(|&b| b == b'=').next().unwrap_or(&[]);
+            if entry_name == name {
+                Some((idx, entry.add(name.len() + 1)))
+            } else {
+                None
+            }
+        })
+}
+
+fn valid_name(name: &[u8]) -> bool {
+    !name.is_empty() && !name.contains(&b'=')
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn getenv(name: *const c_char) -> *mut c_char {
+    let _guard = ENVIRONMENT_LOCK.lock();
+    find(c_str(name)).map_or(ptr::null_mut(), |(_idx, value)| value)
+}
+
+/// Sets `name` to `value`, the entries are leaked (earlier results of
+/// `getenv` stay valid).
+#[no_mangle]
+pub unsafe extern "C" fn setenv(
+    name: *const c_char,
+    value: *const c_char,
+    overwrite: c_int,
+) -> c_int {
+    let (name, value) = (c_str(name), c_str(value));
+    if !valid_name(name) {
+        return set_errno(errno::EINVAL);
+    }
+
+    let mut entry: Vec<u8> = Vec::with_capacity(name.len() + value.len() + 2);
+    entry.extend_from_slice(name);
+    entry.push(b'=');
+    entry.extend_from_slice(value);
+    entry.push(0);
+    let entry = entry.leak().as_mut_ptr() as *mut c_char;
+
+    let _guard = ENVIRONMENT_LOCK.lock();
+    if ENVIRONMENT.is_empty() {
+        ENVIRONMENT.push(ptr::null_mut());
+    }
+    match find(name) {
+        Some(_) if overwrite == 0 => {}
+        Some((idx, _value)) => ENVIRONMENT[idx] = entry,
+        None => {
+            let end = ENVIRONMENT.len() - 1;
+            ENVIRONMENT.insert(end, entry);
+        }
+    }
+    environ = ENVIRONMENT.as_ptr();
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unsetenv(name: *const c_char) -> c_int {
+    let name = c_str(name);
+    if !valid_name(name) {
+        return set_errno(errno::EINVAL);
+    }
+
+    let _guard = ENVIRONMENT_LOCK.lock();
+    if let Some((idx, _value)) = find(name) {
+        ENVIRONMENT.remove(idx);
+        environ = ENVIRONMENT.as_ptr();
+    }
+    0
+}
+
+/// The functions registered with `atexit`.
+static AT_EXIT: spin::Mutex<Vec<extern "C" fn()>> = spin::Mutex::new(Vec::new());
+
+#[no_mangle]
+pub unsafe extern "C" fn atexit(f: extern "C" fn()) -> c_int {
+    AT_EXIT.lock().push(f);
+    0
+}
+
+/// Runs the `atexit` functions (last registered first), then exits.
+#[no_mangle]
+pub unsafe extern "C" fn exit(status: c_int) -> ! {
+    loop {
+        let f = AT_EXIT.lock().pop();
+        match f {
+            Some(f) => f(),
+            None => break,
+        }
+    }
+    _exit(status)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _exit(status: c_int) -> ! {
+    Process::exit(status as u64)
+}
+
+/// There are no signals to raise, so this exits like the shell reports a
+/// `SIGABRT`.
+#[no_mangle]
+pub unsafe extern "C" fn abort() -> ! {
+    let _ = Process::print("abort()\n");
+    _exit(128 + 6)
+}
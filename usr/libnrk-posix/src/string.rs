@@ -0,0 +1,54 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Strings (string.h), `memcpy` and friends come from compiler-builtins.
+
+use core::{ptr, slice};
+
+use crate::errno::{self, message};
+use crate::{c_char, c_int, c_void, size_t};
+
+#[no_mangle]
+pub unsafe extern "C" fn strlen(s: *const c_char) -> size_t {
+    let mut len = 0;
+    while *s.add(len) != 0 {
+        len += 1;
+    }
+    len
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn memchr(s: *const c_void, c: c_int, n: size_t) -> *mut c_void {
+    let bytes = slice::from_raw_parts(s as *const u8, n);
+    match bytes.iter().position(|&b| b == c as u8) {
+        Some(idx) => s.add(idx) as *mut c_void,
+        None => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn memrchr(s: *const c_void, c: c_int, n: size_t) -> *mut c_void {
+    let bytes = slice::from_raw_parts(s as *const u8, n);
+    match bytes.iter().rposition(|&b| b == c as u8) {
+        Some(idx) => s.add(idx) as *mut c_void,
+        None => ptr::null_mut(),
+    }
+}
+
+/// Copies the message for `e` into `buf`, returns `ERANGE` if it doesn't
+/// fit (`buf` then holds as much of it as fits).
+#[no_mangle]
+pub unsafe extern "C" fn strerror_r(e: c_int, buf: *mut c_char, len: size_t) -> c_int {
+    if len == 0 {
+        return errno::ERANGE;
+    }
+    let msg = message(e).as_bytes();
+    let n = core::cmp::min(msg.len(), len - 1);
+    ptr::copy_nonoverlapping(msg.as_ptr(), buf as *mut u8, n);
+    *buf.add(n) = 0;
+    if n < msg.len() {
+        errno::ERANGE
+    } else {
+        0
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Clocks (time.h).
+
+use core::time::Duration;
+
+use lineup::tls2::Environment;
+use vibrio::syscalls::Time;
+
+use crate::errno::{self, from_syscall_error, set_errno};
+use crate::{c_int, c_long, clockid_t, timespec};
+
+pub const CLOCK_REALTIME: clockid_t = 0;
+pub const CLOCK_MONOTONIC: clockid_t = 3;
+
+#[no_mangle]
+pub unsafe extern "C" fn clock_gettime(clock: clockid_t, tp: *mut timespec) -> c_int {
+    let ns = match clock {
+        CLOCK_REALTIME => Time::realtime_ns(),
+        CLOCK_MONOTONIC => Time::monotonic_ns(),
+        _ => return set_errno(errno::EINVAL),
+    };
+
+    match ns {
+        Ok(ns) => {
+            *tp = to_timespec(Duration::from_nanos(ns));
+            0
+        }
+        Err(e) => set_errno(from_syscall_error(e)),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn clock_getres(clock: clockid_t, res: *mut timespec) -> c_int {
+    if clock != CLOCK_REALTIME && clock != CLOCK_MONOTONIC {
+        return set_errno(errno::EINVAL);
+    }
+
+    match Time::resolution() {
+        Ok(resolution) => {
+            if !res.is_null() {
+                *res = to_timespec(resolution);
+            }
+            0
+        }
+        Err(e) => set_errno(from_syscall_error(e)),
+    }
+}
+
+fn to_timespec(t: Duration) -> timespec {
+    timespec {
+        tv_sec: t.as_secs() as i64,
+        tv_nsec: t.subsec_nanos() as c_long,
+    }
+}
+
+/// Sleeps for `req`, we're never interrupted so `rem` is always 0.
+///
+/// Other threads run in the meantime if we're called from a lineup thread,
+/// otherwise this spins.
+#[no_mangle]
+pub unsafe extern "C" fn nanosleep(req: *const timespec, rem: *mut timespec) -> c_int {
+    let (secs, nanos) = ((*req).tv_sec, (*req).tv_nsec);
+    if secs < 0 || !(0..1_000_000_000).contains(&nanos) {
+        return set_errno(errno::EINVAL);
+    }
+    let duration = Duration::new(secs as u64, nanos as u32);
+
+    if Environment::has_thread() {
+        Environment::thread().sleep(duration);
+    } else {
+        let start = vibrio::time::Instant::now();
+        while start.elapsed() < duration {
+            core::hint::spin_loop();
+        }
+    }
+
+    if !rem.is_null() {
+        *rem = timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+    }
+    0
+}
@@ -0,0 +1,156 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Process and system information (unistd.h, sys/sysctl.h).
+
+use core::{ptr, slice};
+
+use vibrio::syscalls::System;
+
+use crate::errno::{self, from_syscall_error, set_errno};
+use crate::fd::{self, Descriptor};
+use crate::{c_char, c_int, c_long, c_uint, c_void, size_t};
+
+pub type pid_t = i32;
+pub type uid_t = u32;
+pub type gid_t = u32;
+
+pub const _SC_PAGESIZE: c_int = 28;
+pub const _SC_NPROCESSORS_CONF: c_int = 1001;
+pub const _SC_NPROCESSORS_ONLN: c_int = 1002;
+
+pub const CTL_KERN: c_int = 1;
+pub const KERN_ARND: c_int = 81;
+
+/// We don't have process ids, every program is the first process.
+const PID: pid_t = 1;
+
+#[no_mangle]
+pub unsafe extern "C" fn sysconf(name: c_int) -> c_long {
+    match name {
+        _SC_PAGESIZE => 4096,
+        _SC_NPROCESSORS_CONF | _SC_NPROCESSORS_ONLN => match System::threads() {
+            Ok(threads) => threads.len() as c_long,
+            Err(e) => set_errno(from_syscall_error(e)) as c_long,
+        },
+        _ => set_errno(errno::EINVAL) as c_long,
+    }
+}
+
+/// The working directory is always the root.
+#[no_mangle]
+pub unsafe extern "C" fn getcwd(buf: *mut c_char, size: size_t) -> *mut c_char {
+    if size < 2 {
+        set_errno(errno::ERANGE);
+        return ptr::null_mut();
+    }
+    *buf = b'/' as c_char;
+    *buf.add(1) = 0;
+    buf
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chdir(_path: *const c_char) -> c_int {
+    set_errno(errno::ENOTSUP)
+}
+
+/// Only stdin, stdout and stderr (the console) are terminals.
+#[no_mangle]
+pub unsafe extern "C" fn isatty(fd: c_int) -> c_int {
+    match fd::get(fd) {
+        Ok(Descriptor::Console(_)) => 1,
+        Ok(_) => {
+            set_errno(errno::ENOTTY);
+            0
+        }
+        Err(e) => {
+            set_errno(e);
+            0
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn getpid() -> pid_t {
+    PID
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn getppid() -> pid_t {
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn getuid() -> uid_t {
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn geteuid() -> uid_t {
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn getgid() -> gid_t {
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn getegid() -> gid_t {
+    0
+}
+
+/// Fills `buf` with random bytes from the kernel.
+#[no_mangle]
+pub unsafe extern "C" fn getentropy(buf: *mut c_void, len: size_t) -> c_int {
+    if len > 256 {
+        return set_errno(errno::EINVAL);
+    }
+    match fill_random(slice::from_raw_parts_mut(buf as *mut u8, len)) {
+        Ok(()) => 0,
+        Err(e) => set_errno(e),
+    }
+}
+
+fn fill_random(mut buf: &mut [u8]) -> Result<(), c_int> {
+    while !buf.is_empty() {
+        let written = System::getrandom(buf).map_err(from_syscall_error)?;
+        buf = &mut buf[written..];
+    }
+    Ok(())
+}
+
+/// `int sysctl(const int *name, u_int namelen, void *oldp, size_t *oldlenp,
+/// const void *newp, size_t newlen)`
+///
+/// Only reads `kern.arandom` (what `std` seeds its hash maps with), nothing
+/// can be set.
+#[no_mangle]
+pub unsafe extern "C" fn sysctl(
+    name: *const c_int,
+    namelen: c_uint,
+    oldp: *mut c_void,
+    oldlenp: *mut size_t,
+    newp: *const c_void,
+    _newlen: size_t,
+) -> c_int {
+    if !newp.is_null() {
+        return set_errno(errno::EPERM);
+    }
+    match slice::from_raw_parts(name, namelen as usize) {
+        [CTL_KERN, KERN_ARND] => {
+            if oldp.is_null() || oldlenp.is_null() {
+                return set_errno(errno::EINVAL);
+            }
+            let len = core::cmp::min(*oldlenp, 256);
+            match fill_random(slice::from_raw_parts_mut(oldp as *mut u8, len)) {
+                Ok(()) => {
+                    *oldlenp = len;
+                    0
+                }
+                Err(e) => set_errno(e),
+            }
+        }
+        _ => set_errno(errno::ENOTSUP),
+    }
+}
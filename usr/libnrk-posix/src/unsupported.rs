@@ -0,0 +1,70 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Functions programs (mostly `std`) link against but that we can't
+//! provide: unwinding, the list of loaded objects and name resolution.
+
+use core::ptr;
+
+use crate::{c_char, c_int, c_void};
+
+/// `_URC_END_OF_STACK`
+const END_OF_STACK: c_int = 5;
+
+/// `EAI_FAIL`
+const EAI_FAIL: c_int = 4;
+
+/// We don't unwind, so backtraces are always empty.
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_Backtrace(_trace: *mut c_void, _data: *mut c_void) -> c_int {
+    END_OF_STACK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_GetIP(_ctx: *mut c_void) -> usize {
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_GetIPInfo(_ctx: *mut c_void, ip_before_insn: *mut c_int) -> usize {
+    *ip_before_insn = 0;
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_FindEnclosingFunction(_pc: *mut c_void) -> *mut c_void {
+    ptr::null_mut()
+}
+
+/// Programs are built with `panic=abort`, there's nothing to resume.
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_Resume(_exception: *mut c_void) -> ! {
+    crate::stdlib::abort()
+}
+
+/// Programs are linked statically, there are no shared objects to report.
+#[no_mangle]
+pub unsafe extern "C" fn dl_iterate_phdr(_callback: *mut c_void, _data: *mut c_void) -> c_int {
+    0
+}
+
+/// Resolving names isn't supported, addresses have to be numeric (`std`
+/// parses those itself).
+#[no_mangle]
+pub unsafe extern "C" fn getaddrinfo(
+    _node: *const c_char,
+    _service: *const c_char,
+    _hints: *const c_void,
+    res: *mut *mut c_void,
+) -> c_int {
+    *res = ptr::null_mut();
+    EAI_FAIL
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn freeaddrinfo(_res: *mut c_void) {}
+
+#[no_mangle]
+pub unsafe extern "C" fn gai_strerror(_errcode: c_int) -> *const c_char {
+    b"Name resolution not supported\0".as_ptr() as *const c_char
+}
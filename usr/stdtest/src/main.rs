@@ -0,0 +1,119 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A regular Rust program for `x86_64-nrk-std`.
+//!
+//! Exercises the parts of `std` that nrk supports and prints `stdtest OK`
+//! if they work. With the argument `net` (`appcmd=net`) it serves one TCP
+//! echo connection on port 6971 first.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Our libc (and `_start`)
+use nrk_posix as _;
+
+const PORT: u16 = 6971;
+
+fn collections() {
+    let squares: HashMap<u64, u64> = (0..1000).map(|i| (i, i * i)).collect();
+    assert_eq!(squares[&12], 144);
+
+    let mut numbers: Vec<u64> = (0..1000).rev().collect();
+    numbers.sort_unstable();
+    assert_eq!(numbers.iter().sum::<u64>(), 499_500);
+    assert_eq!(format!("{:?}", &numbers[..3]), "[0, 1, 2]");
+    println!("stdtest: collections OK");
+}
+
+fn threads() {
+    let counter = Arc::new(Mutex::new(0));
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let counter = counter.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    *counter.lock().unwrap() += 1;
+                }
+                i
+            })
+        })
+        .collect();
+    let ids: Vec<usize> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(ids, vec![0, 1, 2, 3]);
+    assert_eq!(*counter.lock().unwrap(), 400);
+
+    let lock = RwLock::new(1);
+    {
+        let (r1, r2) = (lock.read().unwrap(), lock.read().unwrap());
+        assert_eq!(*r1 + *r2, 2);
+    }
+    *lock.write().unwrap() += 1;
+    assert_eq!(*lock.read().unwrap(), 2);
+    println!("stdtest: threads OK");
+}
+
+fn files() {
+    fs::write("/stdtest.txt", b"hello nrk").expect("Can't write file");
+    let mut content = String::new();
+    fs::File::open("/stdtest.txt")
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .expect("Can't read file");
+    assert_eq!(content, "hello nrk");
+
+    let metadata = fs::metadata("/stdtest.txt").expect("Can't stat file");
+    assert!(metadata.is_file());
+    assert_eq!(metadata.len(), 9);
+
+    fs::create_dir("/stdtest.dir").expect("Can't create directory");
+    assert!(fs::metadata("/stdtest.dir").unwrap().is_dir());
+    fs::remove_file("/stdtest.txt").expect("Can't remove file");
+    assert!(fs::metadata("/stdtest.txt").is_err());
+    println!("stdtest: files OK");
+}
+
+fn time() {
+    let start = Instant::now();
+    thread::sleep(Duration::from_millis(10));
+    assert!(start.elapsed() >= Duration::from_millis(10));
+
+    // Some time after 2021-01-01
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    assert!(now.as_secs() > 1_609_459_200);
+    println!("stdtest: time OK");
+}
+
+/// Echoes everything the first client sends until it hangs up.
+fn echo() {
+    let listener = TcpListener::bind(("0.0.0.0", PORT)).expect("Can't bind");
+    println!("stdtest: serving tcp:{}", PORT);
+
+    let (mut stream, _peer) = listener.accept().expect("Can't accept");
+    let mut buf = [0u8; 1024];
+    loop {
+        match stream.read(&mut buf).expect("Can't read from connection") {
+            0 => break,
+            len => stream
+                .write_all(&buf[..len])
+                .expect("Can't write to connection"),
+        }
+    }
+    println!("stdtest: net OK");
+}
+
+fn main() {
+    collections();
+    threads();
+    files();
+    time();
+    if env::args().any(|arg| arg == "net") {
+        echo();
+    }
+    println!("stdtest OK");
+}